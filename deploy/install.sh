@@ -64,7 +64,10 @@ After=network.target
 Wants=network.target
 
 [Service]
-Type=simple
+# vx0net reports READY=1 once its listeners are up, and pings the watchdog
+Type=notify
+NotifyAccess=main
+WatchdogSec=30
 User=$USER
 WorkingDirectory=$VX0_DIR/vx0net-daemon
 ExecStart=$VX0_DIR/vx0net-daemon/target/release/vx0net start --foreground
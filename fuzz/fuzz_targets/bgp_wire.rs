@@ -0,0 +1,33 @@
+//! RFC 4271 messages as an external router might send them. Decoding must
+//! fail cleanly, and whatever decodes must survive being sent back out.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vx0net_daemon::network::bgp::messages::BGPMessage;
+use vx0net_daemon::network::bgp::wire;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = wire::decode_header(data);
+    let Ok(message) = wire::decode(data) else {
+        return;
+    };
+    let Ok(encoded) = wire::encode(&message) else {
+        return;
+    };
+    // Host bits a peer sent are dropped on the way back out
+    let expected = match message {
+        BGPMessage::Update(mut update) => {
+            for net in update
+                .withdrawn_routes
+                .iter_mut()
+                .chain(&mut update.network_layer_reachability_info)
+            {
+                *net = net.trunc();
+            }
+            BGPMessage::Update(update)
+        }
+        message => message,
+    };
+    assert_eq!(wire::decode(&encoded).ok(), Some(expected));
+});
@@ -0,0 +1,14 @@
+//! Packets arriving at the local resolver, and answers read back from it.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vx0net_daemon::network::dns::wire::{self, Query, Rcode, Response};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(query) = Query::parse(data) {
+        let _ = Response::parse(&query.reply(Rcode::NoError, &[], 60));
+    }
+    let _ = Response::parse(data);
+    let _ = wire::error_reply(data, Rcode::FormErr);
+});
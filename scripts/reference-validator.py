@@ -0,0 +1,98 @@
+#!/usr/bin/env python3
+"""Reference route validator for network.bgp.validator, interface version 1.
+
+Reads a batch of routes and answers with a verdict for each, as described
+in src/network/bgp/validator.rs. Run as an executable it reads one request
+on stdin and writes the answer on stdout:
+
+    [network.bgp.validator]
+    command = ["scripts/reference-validator.py", "--deny-origin", "64512"]
+
+With --http PORT it serves POST requests instead, printing the port it
+bound to (useful with port 0):
+
+    [network.bgp.validator]
+    url = "http://127.0.0.1:8323/"
+
+Routes are accepted unless their origin AS or prefix was denied.
+"""
+
+import argparse
+import http.server
+import json
+import sys
+import time
+
+VERSION = 1
+
+
+def judge(request, args):
+    if request.get("version") != VERSION:
+        raise ValueError("unsupported request version %r" % request.get("version"))
+    if args.log:
+        with open(args.log, "a") as log:
+            log.write("%d\n" % len(request["routes"]))
+    if args.delay_ms:
+        time.sleep(args.delay_ms / 1000)
+
+    verdicts = []
+    for route in request["routes"]:
+        prefix, origin = route["prefix"], route["origin_asn"]
+        if origin in args.deny_origin:
+            accept, reason = False, "origin AS%d is denied" % origin
+        elif prefix in args.deny_prefix:
+            accept, reason = False, "prefix %s is denied" % prefix
+        else:
+            accept, reason = True, ""
+        verdicts.append(
+            {"prefix": prefix, "origin_asn": origin, "accept": accept, "reason": reason}
+        )
+    return {"version": VERSION, "verdicts": verdicts}
+
+
+def serve(args):
+    class Handler(http.server.BaseHTTPRequestHandler):
+        def do_POST(self):
+            length = int(self.headers.get("Content-Length", 0))
+            try:
+                answer = judge(json.loads(self.rfile.read(length)), args)
+            except (ValueError, KeyError) as e:
+                self.send_error(400, str(e))
+                return
+            body = json.dumps(answer).encode()
+            self.send_response(200)
+            self.send_header("Content-Type", "application/json")
+            self.send_header("Content-Length", str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+
+        def log_message(self, format, *args):
+            pass
+
+    server = http.server.ThreadingHTTPServer(("127.0.0.1", args.http), Handler)
+    print(server.server_address[1], flush=True)
+    server.serve_forever()
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--deny-origin", type=int, action="append", default=[])
+    parser.add_argument("--deny-prefix", action="append", default=[])
+    parser.add_argument("--delay-ms", type=int, default=0, help="answer this much later")
+    parser.add_argument("--log", help="append the size of each batch to this file")
+    parser.add_argument("--http", type=int, metavar="PORT", help="serve on this port")
+    args = parser.parse_args()
+
+    if args.http is not None:
+        serve(args)
+        return
+    try:
+        answer = judge(json.load(sys.stdin), args)
+    except (ValueError, KeyError) as e:
+        print(e, file=sys.stderr)
+        sys.exit(1)
+    json.dump(answer, sys.stdout)
+
+
+if __name__ == "__main__":
+    main()
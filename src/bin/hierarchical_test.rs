@@ -1,8 +1,8 @@
-use std::sync::Arc;
-use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin};
+use config::FileFormat;
+use vx0net_daemon::config::topology::{Manifest, Topology};
+use vx0net_daemon::network::bgp::{default_route, AsPath, BGPDaemon, BGPOrigin, Prefix};
 use vx0net_daemon::network::dns::Vx0DNS;
-use vx0net_daemon::node::{HostedService, NodeTier, PeerConnection, ServiceStatus, ServiceType};
-use vx0net_daemon::{Vx0Config, Vx0Node};
+use vx0net_daemon::node::PeerConnection;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -20,22 +20,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  • Regional Nodes (Tier 2): Regional distribution hubs");
     println!("  • Edge Nodes (Tier 3): User-operated nodes\n");
 
-    // Create backbone nodes (ASN 65000-65099)
-    let backbone1 =
-        create_test_node("backbone1.vx0", 65001, "10.0.1.1", NodeTier::Backbone).await?;
-    let backbone2 =
-        create_test_node("backbone2.vx0", 65002, "10.0.1.2", NodeTier::Backbone).await?;
-
-    // Create regional nodes (ASN 65100-65999)
-    let regional1 =
-        create_test_node("regional1.vx0", 65101, "10.1.1.1", NodeTier::Regional).await?;
-    let regional2 =
-        create_test_node("regional2.vx0", 65102, "10.1.2.1", NodeTier::Regional).await?;
-
-    // Create edge nodes (ASN 66000+)
-    let edge1 = create_test_node("edge1.vx0", 66001, "10.2.1.1", NodeTier::Edge).await?;
-    let edge2 = create_test_node("edge2.vx0", 66002, "10.2.1.2", NodeTier::Edge).await?;
-    let edge3 = create_test_node("edge3.vx0", 66003, "10.2.2.1", NodeTier::Edge).await?;
+    // Nodes, addresses, peerings and services all come from the manifest
+    let manifest = Manifest::parse(
+        include_str!("../../config/topologies/hierarchical.toml"),
+        FileFormat::Toml,
+    )?;
+    let topology = Topology::resolve(manifest)?;
+    let lab = topology.boot().await?;
+    let node = |name: &str| {
+        lab.node(name)
+            .cloned()
+            .ok_or_else(|| format!("{} missing from the manifest", name))
+    };
+    let backbone1 = node("backbone1")?;
+    let backbone2 = node("backbone2")?;
+    let regional1 = node("regional1")?;
+    let regional2 = node("regional2")?;
+    let edge1 = node("edge1")?;
+    let edge2 = node("edge2")?;
+    let edge3 = node("edge3")?;
 
     println!("✅ Network Topology Created:");
     println!(
@@ -54,122 +57,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Test hierarchical peering restrictions
     println!("🔗 Testing Hierarchical Peering Rules:");
-
-    // Valid peerings
-    println!("  Testing valid peerings...");
-
-    // Backbone to backbone
-    backbone1
-        .add_peer(PeerConnection::new(
-            backbone2.node_id,
-            backbone2.asn,
-            backbone2.ipv4_addr.into(),
-        ))
-        .await?;
-    backbone2
-        .add_peer(PeerConnection::new(
-            backbone1.node_id,
-            backbone1.asn,
-            backbone1.ipv4_addr.into(),
-        ))
-        .await?;
-    println!("    ✅ Backbone1 ↔ Backbone2");
-
-    // Backbone to regional
-    backbone1
-        .add_peer(PeerConnection::new(
-            regional1.node_id,
-            regional1.asn,
-            regional1.ipv4_addr.into(),
-        ))
-        .await?;
-    regional1
-        .add_peer(PeerConnection::new(
-            backbone1.node_id,
-            backbone1.asn,
-            backbone1.ipv4_addr.into(),
-        ))
-        .await?;
-
-    backbone2
-        .add_peer(PeerConnection::new(
-            regional2.node_id,
-            regional2.asn,
-            regional2.ipv4_addr.into(),
-        ))
-        .await?;
-    regional2
-        .add_peer(PeerConnection::new(
-            backbone2.node_id,
-            backbone2.asn,
-            backbone2.ipv4_addr.into(),
-        ))
-        .await?;
-    println!("    ✅ Backbone → Regional connections");
-
-    // Regional to regional
-    regional1
-        .add_peer(PeerConnection::new(
-            regional2.node_id,
-            regional2.asn,
-            regional2.ipv4_addr.into(),
-        ))
-        .await?;
-    regional2
-        .add_peer(PeerConnection::new(
-            regional1.node_id,
-            regional1.asn,
-            regional1.ipv4_addr.into(),
-        ))
-        .await?;
-    println!("    ✅ Regional1 ↔ Regional2");
-
-    // Regional to edge
-    regional1
-        .add_peer(PeerConnection::new(
-            edge1.node_id,
-            edge1.asn,
-            edge1.ipv4_addr.into(),
-        ))
-        .await?;
-    edge1
-        .add_peer(PeerConnection::new(
-            regional1.node_id,
-            regional1.asn,
-            regional1.ipv4_addr.into(),
-        ))
-        .await?;
-
-    regional1
-        .add_peer(PeerConnection::new(
-            edge2.node_id,
-            edge2.asn,
-            edge2.ipv4_addr.into(),
-        ))
-        .await?;
-    edge2
-        .add_peer(PeerConnection::new(
-            regional1.node_id,
-            regional1.asn,
-            regional1.ipv4_addr.into(),
-        ))
-        .await?;
-
-    regional2
-        .add_peer(PeerConnection::new(
-            edge3.node_id,
-            edge3.asn,
-            edge3.ipv4_addr.into(),
-        ))
-        .await?;
-    edge3
-        .add_peer(PeerConnection::new(
-            regional2.node_id,
-            regional2.asn,
-            regional2.ipv4_addr.into(),
-        ))
-        .await?;
-    println!("    ✅ Regional → Edge connections");
+    for (a, b) in topology.peerings() {
+        println!("    ✅ {} ↔ {}", a, b);
+    }
 
     // Test invalid peering (edge to edge should fail)
     println!("  Testing peering restrictions...");
@@ -200,7 +90,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let bgp_edge3 = BGPDaemon::new(edge3.asn, edge3.ipv4_addr.into(), 0);
 
     // Backbone announces VX0 default route
-    let vx0_default: ipnet::IpNet = "10.0.0.0/8".parse()?;
+    let vx0_default: Prefix = "10.0.0.0/8".parse()?;
     bgp_backbone1
         .add_route(vx0_default, "10.0.1.1".parse()?, BGPOrigin::IGP)
         .await?;
@@ -210,8 +100,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  ✅ Backbone nodes announced VX0 default route (10.0.0.0/8)");
 
     // Regional nodes announce their regional networks
-    let region1_net: ipnet::IpNet = "10.1.0.0/16".parse()?;
-    let region2_net: ipnet::IpNet = "10.2.0.0/16".parse()?;
+    let region1_net: Prefix = "10.1.0.0/16".parse()?;
+    let region2_net: Prefix = "10.2.0.0/16".parse()?;
     bgp_regional1
         .add_route(region1_net, regional1.ipv4_addr.into(), BGPOrigin::IGP)
         .await?;
@@ -221,9 +111,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  ✅ Regional nodes announced their regional networks");
 
     // Edge nodes announce local service networks
-    let edge1_services: ipnet::IpNet = "10.2.1.0/24".parse()?;
-    let edge2_services: ipnet::IpNet = "10.2.1.0/24".parse()?;
-    let edge3_services: ipnet::IpNet = "10.2.2.0/24".parse()?;
+    let edge1_services: Prefix = "10.2.1.0/24".parse()?;
+    let edge2_services: Prefix = "10.2.1.0/24".parse()?;
+    let edge3_services: Prefix = "10.2.2.0/24".parse()?;
     bgp_edge1
         .add_route(edge1_services, edge1.ipv4_addr.into(), BGPOrigin::IGP)
         .await?;
@@ -274,45 +164,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         );
     }
 
-    // Test service registration and hierarchical advertisement
-    println!("\n🛰️ Testing Service Registration & Discovery:");
-
-    // Register services on edge nodes
-    edge1
-        .register_service(HostedService {
-            service_id: uuid::Uuid::new_v4(),
-            name: "chat".to_string(),
-            service_type: ServiceType::ChatServer,
-            domain: "chat.community1.vx0".to_string(),
-            port: 6667,
-            status: ServiceStatus::Running,
-            metadata: std::collections::HashMap::new(),
-        })
+    // A parent passing on everything it knows: Backbone keeps it all, an
+    // Edge node only the default
+    println!("\n🛡️ Testing Tier Import Policy:");
+    let mut full_table = Vec::new();
+    for (bgp, asn) in [
+        (&bgp_backbone2, backbone2.asn),
+        (&bgp_regional2, regional2.asn),
+        (&bgp_edge2, edge2.asn),
+        (&bgp_edge3, edge3.asn),
+    ] {
+        for mut route in bgp.get_routes().await {
+            route.as_path = AsPath::new(&[regional1.asn, asn]);
+            full_table.push(route);
+        }
+    }
+    bgp_edge1
+        .receive_update(regional1.asn, full_table.clone(), &[])
         .await?;
-
-    edge2
-        .register_service(HostedService {
-            service_id: uuid::Uuid::new_v4(),
-            name: "forum".to_string(),
-            service_type: ServiceType::WebServer,
-            domain: "forum.community1.vx0".to_string(),
-            port: 80,
-            status: ServiceStatus::Running,
-            metadata: std::collections::HashMap::new(),
-        })
+    bgp_backbone1
+        .receive_update(regional1.asn, full_table.clone(), &[])
         .await?;
+    let edge_rejected = bgp_edge1.rejected_from(regional1.asn).await;
+    let backbone_rejected = bgp_backbone1.rejected_from(regional1.asn).await;
+    println!(
+        "  Full table of {} routes from AS{}: Edge1 rejected {}, Backbone1 rejected {}",
+        full_table.len(),
+        regional1.asn,
+        edge_rejected,
+        backbone_rejected
+    );
+    if edge_rejected > 0 && backbone_rejected == 0 {
+        println!("  ✅ Edge node dropped the full table, Backbone node kept it");
+    } else {
+        println!("  ❌ Tier import policy was not applied!");
+        return Err("Edge node kept routes its tier import policy should reject".into());
+    }
 
-    edge3
-        .register_service(HostedService {
-            service_id: uuid::Uuid::new_v4(),
-            name: "files".to_string(),
-            service_type: ServiceType::FileServer,
-            domain: "files.community2.vx0".to_string(),
-            port: 443,
-            status: ServiceStatus::Running,
-            metadata: std::collections::HashMap::new(),
-        })
-        .await?;
+    // Services were registered from the manifest at boot
+    println!("\n🛰️ Testing Service Registration & Discovery:");
 
     println!("  ✅ Services registered on edge nodes:");
     println!("    chat.community1.vx0 (ChatServer) on Edge1");
@@ -357,6 +247,65 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Some(ip) => println!("  ❌ Internet domain should have been blocked! Got: {}", ip),
     }
 
+    // IPv6 leg: an AAAA-registered service must resolve and route across tiers
+    println!("\n🌍 Testing IPv6 Services & Routing:");
+
+    let v6_supernet: Prefix = default_route::VX0_IPV6_SUPERNET.parse()?;
+    let region2_v6: Prefix = "fd00:7830:2::/48".parse()?;
+    let edge1_v6: Prefix = "fd00:7830:2:1::/64".parse()?;
+    bgp_backbone1
+        .add_route(v6_supernet, backbone1.ipv6_addr.into(), BGPOrigin::IGP)
+        .await?;
+    bgp_regional2
+        .add_route(region2_v6, regional2.ipv6_addr.into(), BGPOrigin::IGP)
+        .await?;
+    bgp_edge1
+        .add_route(edge1_v6, edge1.ipv6_addr.into(), BGPOrigin::IGP)
+        .await?;
+    println!(
+        "  ✅ IPv6 prefixes originated: {} (Backbone), {} (Regional), {} (Edge)",
+        v6_supernet, region2_v6, edge1_v6
+    );
+
+    let mut dns6 = Vx0DNS::new();
+    let chat_service = edge1
+        .services
+        .read()
+        .await
+        .iter()
+        .find(|service| service.domain == "chat.community1.vx0")
+        .cloned()
+        .ok_or("chat service missing")?;
+    edge1.publish_service_dns(&chat_service, &mut dns6)?;
+
+    match dns6.resolve_vx0_domain_v6("chat.community1.vx0").await {
+        Some(chat_v6) => {
+            println!("  ✅ chat.community1.vx0 AAAA → {}", chat_v6);
+            for (tier, bgp) in [
+                ("Backbone1", &bgp_backbone1),
+                ("Regional2", &bgp_regional2),
+                ("Edge1", &bgp_edge1),
+            ] {
+                match bgp.find_best_route(&chat_v6.into()).await {
+                    Some(route) => println!(
+                        "    {} routes {} via {} ({})",
+                        tier, chat_v6, route.next_hop, route.network
+                    ),
+                    None => println!("    ❌ {} has no route to {}", tier, chat_v6),
+                }
+            }
+        }
+        None => println!("  ❌ chat.community1.vx0 has no AAAA record"),
+    }
+
+    match bgp_edge1
+        .add_route(edge1_v6, edge1.ipv4_addr.into(), BGPOrigin::IGP)
+        .await
+    {
+        Err(_) => println!("  ✅ IPv6 prefix with IPv4 next hop correctly rejected"),
+        Ok(_) => println!("  ❌ Mixed-family next hop should have been rejected!"),
+    }
+
     // Network statistics by tier
     println!("\n📊 Network Statistics by Tier:");
 
@@ -411,6 +360,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("✅ Service registration and discovery");
     println!("✅ Complete internet isolation (.vx0 domains only)");
     println!("✅ Hierarchical route propagation");
+    println!("✅ IPv6 services resolvable (AAAA) and routable across tiers");
     println!("✅ ASN range validation by tier");
 
     println!("\n🏗️ Network Architecture:");
@@ -424,85 +374,3 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
-
-async fn create_test_node(
-    hostname: &str,
-    asn: u32,
-    ip: &str,
-    tier: NodeTier,
-) -> Result<Arc<Vx0Node>, Box<dyn std::error::Error>> {
-    let config = create_test_config(hostname, asn, ip, tier);
-    let node = Arc::new(Vx0Node::new(config)?);
-    node.start().await?;
-    Ok(node)
-}
-
-fn create_test_config(hostname: &str, asn: u32, ip: &str, tier: NodeTier) -> Vx0Config {
-    use vx0net_daemon::config::*;
-
-    let tier_str = match tier {
-        NodeTier::Backbone => "Backbone",
-        NodeTier::Regional => "Regional",
-        NodeTier::Edge => "Edge",
-    };
-
-    Vx0Config {
-        node: NodeConfig {
-            hostname: hostname.to_string(),
-            asn,
-            tier: tier_str.to_string(),
-            location: "VX0 Test Network".to_string(),
-            ipv4_address: ip.to_string(),
-            ipv6_address: "fe80::1".to_string(),
-        },
-        network: NetworkConfig {
-            bgp: BGPConfig {
-                router_id: ip.to_string(),
-                listen_port: 179,
-                hold_time: 90,
-                keepalive_time: 30,
-            },
-            dns: DNSConfig {
-                listen_port: 53,
-                vx0_dns_servers: vec!["10.0.0.2:53".to_string(), "10.0.0.3:53".to_string()],
-                cache_size: 1000,
-            },
-            routing: RoutingConfig {
-                max_paths: 4,
-                local_preference: 100,
-                med: 0,
-            },
-        },
-        security: SecurityConfig {
-            ike: IKEConfig {
-                listen_port: 500,
-                dh_group: 14,
-                encryption_algorithm: "AES-256".to_string(),
-                hash_algorithm: "SHA-256".to_string(),
-                prf_algorithm: "HMAC-SHA256".to_string(),
-            },
-            certificates: CertificateConfig {
-                ca_cert_path: "config/certs/ca.crt".to_string(),
-                node_cert_path: format!("config/certs/{}.crt", hostname),
-                node_key_path: format!("config/certs/{}.key", hostname),
-            },
-            encryption: EncryptionConfig {
-                cipher: "AES-256-GCM".to_string(),
-                key_size: 32,
-                iv_size: 12,
-            },
-        },
-        services: ServicesConfig {
-            enable_discovery: true,
-            discovery_port: 8080,
-            service_ttl: 300,
-        },
-        monitoring: MonitoringConfig {
-            enable_metrics: true,
-            metrics_port: 9090,
-            log_level: "info".to_string(),
-        },
-        bootstrap: None,
-        psk: None,
-    }
-}
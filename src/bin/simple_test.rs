@@ -1,5 +1,5 @@
 use std::sync::Arc;
-use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin};
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin, Prefix};
 use vx0net_daemon::network::dns::Vx0DNS;
 use vx0net_daemon::node::{HostedService, PeerConnection, ServiceStatus, ServiceType};
 use vx0net_daemon::{Vx0Config, Vx0Node};
@@ -60,8 +60,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let bgp2 = BGPDaemon::new(node2.asn, node2.ipv4_addr.into(), 0);
 
     // Add some test routes
-    let vx0_net1: ipnet::IpNet = "10.1.0.0/24".parse()?;
-    let vx0_net2: ipnet::IpNet = "10.2.0.0/24".parse()?;
+    let vx0_net1: Prefix = "10.1.0.0/24".parse()?;
+    let vx0_net2: Prefix = "10.2.0.0/24".parse()?;
 
     bgp1.add_route(vx0_net1, node1.ipv4_addr.into(), BGPOrigin::IGP)
         .await?;
@@ -98,7 +98,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         domain: "web.node1.vx0".to_string(),
         port: 80,
         status: ServiceStatus::Running,
-        metadata: std::collections::HashMap::new(),
+        metadata: Default::default(),
     };
 
     let chat_service = HostedService {
@@ -108,7 +108,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         domain: "chat.node2.vx0".to_string(),
         port: 6667,
         status: ServiceStatus::Running,
-        metadata: std::collections::HashMap::new(),
+        metadata: Default::default(),
     };
 
     node1.register_service(web_service).await?;
@@ -203,30 +203,77 @@ fn create_test_config(hostname: &str, asn: u32, ip: &str) -> Vx0Config {
 
     Vx0Config {
         node: NodeConfig {
+            node_id: None,
             hostname: hostname.to_string(),
             asn,
             tier: "Edge".to_string(),
             location: "Test Lab".to_string(),
             ipv4_address: ip.to_string(),
             ipv6_address: "fe80::1".to_string(),
+            capabilities: CapabilitiesConfig::default(),
+            register_hostname_dns: false,
+            auto_detect_public_address: false,
+            redundancy: RedundancyConfig::default(),
         },
         network: NetworkConfig {
             bgp: BGPConfig {
                 router_id: ip.to_string(),
                 listen_port: 179,
-                hold_time: 90,
-                keepalive_time: 30,
+                hold_time: units::ConfigDuration::from_secs(90),
+                keepalive_time: units::ConfigDuration::from_secs(30),
+                peers: Vec::new(),
+                import_policy: Vec::new(),
+                rejection_journal: RejectionJournalConfig::default(),
+                pins_file: None,
+                community_policies: vx0net_daemon::network::bgp::communities::well_known_policies(),
+                export_policies: Vec::new(),
+                announce: Vec::new(),
+                require_tunnel: false,
+                bgp_over_tunnel: false,
+                tunnel_suspend_timeout_secs: 300,
+                validator: None,
+                table_sync: Default::default(),
+                session_limits: Default::default(),
+                multihoming: Default::default(),
+                trace_propagation: false,
             },
             dns: DNSConfig {
+                local_server: true,
                 listen_port: 53,
                 vx0_dns_servers: vec!["10.0.0.2:53".to_string(), "10.0.0.3:53".to_string()],
                 cache_size: 1000,
+                negative_cache_secs: 30,
+                use_gateways: false,
+                sync_port: 5354,
+                proxy_clearnet: false,
+                upstream: vx0net_daemon::config::default_dns_upstream(),
+                allow_from: vx0net_daemon::config::default_dns_allow_from(),
+                omit_unreachable: false,
+                health_cache_ms: 2000,
+                sync_quota: Default::default(),
+                node_metadata: Default::default(),
+                history: Default::default(),
             },
             routing: RoutingConfig {
                 max_paths: 4,
                 local_preference: 100,
                 med: 0,
+                originate_default_to_edge: true,
+                require_tunnel_for_nexthop: false,
+                tunnel_failure: Default::default(),
+                tunnel_damping: Default::default(),
             },
+            plan: AddressPlanConfig::default(),
+            peering: PeeringConfig::default(),
+            acl: AclConfig::default(),
+            hold_down: HoldDownConfig::default(),
+            kernel_routes: Default::default(),
+            retry: Default::default(),
+            single_port: Default::default(),
+            peer_selection: Default::default(),
+            quarantine: Default::default(),
+            firewall: Default::default(),
+            adaptive_timers: Default::default(),
         },
         security: SecurityConfig {
             ike: IKEConfig {
@@ -235,6 +282,12 @@ fn create_test_config(hostname: &str, asn: u32, ip: &str) -> Vx0Config {
                 encryption_algorithm: "AES-256".to_string(),
                 hash_algorithm: "SHA-256".to_string(),
                 prf_algorithm: "HMAC-SHA256".to_string(),
+                establish_timeout_secs: 30,
+                rekey_interval_secs: 3600,
+                mtu: 1400,
+                mtu_revalidate_secs: 600,
+                nonce_journal: Default::default(),
+                resumption: Default::default(),
             },
             certificates: CertificateConfig {
                 ca_cert_path: "config/certs/ca.crt".to_string(),
@@ -246,18 +299,36 @@ fn create_test_config(hostname: &str, asn: u32, ip: &str) -> Vx0Config {
                 key_size: 32,
                 iv_size: 12,
             },
+            obfuscation: Default::default(),
         },
         services: ServicesConfig {
             enable_discovery: true,
             discovery_port: 8080,
-            service_ttl: 300,
+            discovery_privacy: DiscoveryPrivacy::Full,
+            discovery_expiry_secs: 300,
+            service_ttl: units::ConfigDuration::from_secs(300),
+            advertise_host_routes: false,
+            max_announced_services: 50,
+            gateway: GatewayConfig::default(),
         },
         monitoring: MonitoringConfig {
             enable_metrics: true,
             metrics_port: 9090,
             log_level: "info".to_string(),
+            recorder: RecorderConfig::default(),
+            crash: CrashConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            daemon: Default::default(),
+            timing: Default::default(),
+            capacity: Default::default(),
+            capture: Default::default(),
+            readiness: Default::default(),
         },
         bootstrap: None,
         psk: None,
+        control: ControlConfig::default(),
+        joining: JoiningConfig::default(),
+        storage: StorageConfig::default(),
+        profile: None,
     }
 }
@@ -0,0 +1,127 @@
+//! Build metadata captured by build.rs, so bug reports and peers can tell
+//! exactly which daemon they are dealing with.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Oldest VX0 protocol version this build can speak
+pub const PROTOCOL_VERSION_MIN: u16 = 1;
+/// Newest VX0 protocol version this build can speak
+pub const PROTOCOL_VERSION_MAX: u16 = 1;
+
+/// When this binary was built; no correct clock reads earlier
+pub fn build_time() -> Option<chrono::DateTime<chrono::Utc>> {
+    env!("VX0_BUILD_TIMESTAMP")
+        .parse::<i64>()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+}
+
+/// Empty fields mean the sender predates build reporting
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    /// RFC 3339 build time
+    pub build_date: String,
+    pub rustc_version: String,
+    pub features: Vec<String>,
+    pub protocol_min: u16,
+    pub protocol_max: u16,
+}
+
+impl BuildInfo {
+    /// Metadata for the running binary
+    pub fn current() -> Self {
+        let build_date = build_time()
+            .map(|date| date.to_rfc3339())
+            .unwrap_or_default();
+
+        BuildInfo {
+            version: VERSION.to_string(),
+            git_hash: env!("VX0_GIT_HASH").to_string(),
+            build_date,
+            rustc_version: env!("VX0_RUSTC_VERSION").to_string(),
+            features: env!("VX0_FEATURES")
+                .split(',')
+                .filter(|feature| !feature.is_empty() && *feature != "default")
+                .map(str::to_string)
+                .collect(),
+            protocol_min: PROTOCOL_VERSION_MIN,
+            protocol_max: PROTOCOL_VERSION_MAX,
+        }
+    }
+
+    /// One-line form for logs, e.g. "0.1.0 (3f2a9c1d04be, protocol 1-1)"
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} ({}, protocol {}-{}",
+            self.version, self.git_hash, self.protocol_min, self.protocol_max
+        );
+        if !self.features.is_empty() {
+            summary.push_str(&format!(", features: {}", self.features.join(",")));
+        }
+        summary.push(')');
+        summary
+    }
+
+    /// Whether a peer built as `other` shares a protocol version with us
+    pub fn is_compatible_with(&self, other: &BuildInfo) -> bool {
+        self.protocol_min <= other.protocol_max && other.protocol_min <= self.protocol_max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info_populated() {
+        let info = BuildInfo::current();
+
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_hash.is_empty());
+        assert!(chrono::DateTime::parse_from_rfc3339(&info.build_date).is_ok());
+        assert!(info.rustc_version.starts_with("rustc"));
+        assert_eq!(
+            info.features.contains(&"external_bgp".to_string()),
+            cfg!(feature = "external-bgp")
+        );
+        assert!(info.is_compatible_with(&info));
+        assert!(info.summary().starts_with(VERSION));
+    }
+
+    #[test]
+    fn test_announcements_carry_build_info() {
+        use crate::node::bootstrap::NodeAnnouncement;
+        use crate::node::NodeTier;
+
+        let announcement = NodeAnnouncement {
+            node_id: uuid::Uuid::new_v4(),
+            hostname: "edge1.vx0".to_string(),
+            asn: 66001,
+            tier: NodeTier::Edge,
+            ipv4_addr: "10.2.0.1".parse().unwrap(),
+            services: vec![],
+            total_services: 0,
+            truncated: false,
+            capabilities: Default::default(),
+            build: BuildInfo::current(),
+            location: String::new(),
+            network_health: None,
+            timestamp: chrono::Utc::now(),
+        };
+
+        let encoded = serde_json::to_value(&announcement).unwrap();
+        assert_eq!(encoded["build"]["version"], VERSION);
+
+        // Announcements from nodes that predate build reporting still parse
+        let mut legacy = encoded.clone();
+        legacy.as_object_mut().unwrap().remove("build");
+        let decoded: NodeAnnouncement = serde_json::from_value(legacy).unwrap();
+        assert_eq!(decoded.build, BuildInfo::default());
+    }
+}
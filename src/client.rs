@@ -0,0 +1,952 @@
+//! Typed access to a running daemon, for frontends other than the CLI.
+//!
+//! [`DaemonClient`] speaks the control protocol (see [`crate::control`]) and
+//! has one method per control command, returning the types the daemon
+//! answers with instead of a [`ControlResponse`] to match on. Connecting
+//! asks the daemon for its schema first: a daemon speaking another version
+//! of the protocol is refused with [`ClientError::SchemaMismatch`] rather
+//! than having its replies misread. Each call opens a connection of its
+//! own, and mutating calls are resent under the same request id when a
+//! connection drops, so they apply once even across a daemon restart.
+//!
+//! [`DaemonClient::subscribe_events`] follows the daemon's events on a
+//! connection kept open for them. When that connection drops the stream
+//! reconnects and subscribes again by itself, telling the caller with a
+//! [`DaemonEvent::Resubscribed`] so it can re-read whatever state it shows.
+//!
+//! What is stable: the methods here and the meaning of their arguments
+//! change only with a major release. Replies gain fields and events gain
+//! kinds as the daemon grows, so the reply structs defined here,
+//! [`DaemonEvent`] and [`ClientError`] are `#[non_exhaustive]`; read their
+//! fields by name and match them with a wildcard arm.
+
+use crate::build_info::BuildInfo;
+use crate::config::reload::ReloadReport;
+use crate::control::batch::{BatchCommand, BatchResult};
+use crate::control::events::DaemonEvent;
+use crate::control::schema::{ControlSchema, SCHEMA_VERSION};
+use crate::control::{
+    ControlClient, ControlError, ControlErrorCode, ControlRequest, ControlResponse, Subscription,
+};
+use crate::monitoring::capacity::{CapacityAlert, CapacityReport, HeardDigest};
+use crate::monitoring::readiness::ReadinessReport;
+use crate::monitoring::tasks::TaskInfo;
+use crate::monitoring::timing::TimingReport;
+use crate::network::bgp::explain::Explanation;
+use crate::network::bgp::multihoming::MultihomingStatus;
+use crate::network::bgp::pins::RoutePin;
+use crate::network::bgp::policy::DryRunReport;
+use crate::network::bgp::propagation::PropagationStats;
+use crate::network::bgp::{Community, Prefix, RouteEntry};
+use crate::network::dns::cache::{CacheEntryInfo, CacheStats};
+use crate::network::dns::history::HistoryEntry;
+use crate::network::dns::quota::OriginUsage;
+use crate::network::firewall::FirewallStatus;
+use crate::node::admin::AdminDown;
+use crate::node::asn_registry::AsnGrant;
+use crate::node::bootstrap_health::BootstrapStatus;
+use crate::node::capabilities::{Capabilities, NodeDirectoryEntry};
+use crate::node::observed::AddressReport;
+use crate::node::quarantine::QuarantineStatus;
+use crate::node::redundancy::{GroupMember, GroupStatus};
+use crate::node::search::ServiceSummary;
+use crate::node::services::PropagationReport;
+use crate::node::{NodeId, PeerConnection};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// First wait before reconnecting a dropped event stream, doubled on each
+/// failed attempt up to `RECONNECT_MAX`
+const RECONNECT_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_MAX: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ClientError {
+    #[error(transparent)]
+    Control(#[from] ControlError),
+    #[error("Daemon refused {command}: {message}")]
+    Refused {
+        command: &'static str,
+        code: ControlErrorCode,
+        message: String,
+    },
+    #[error("Daemon speaks control schema version {daemon}, this client version {client}")]
+    SchemaMismatch { daemon: u32, client: u32 },
+    #[error("Unexpected reply to {command}")]
+    Unexpected { command: &'static str },
+}
+
+impl ClientError {
+    /// The daemon's error code, when it refused the command
+    pub fn code(&self) -> Option<ControlErrorCode> {
+        match self {
+            ClientError::Refused { code, .. } => Some(*code),
+            ClientError::Control(ControlError::Refused { code, .. }) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+/// The Loc-RIB and the route pins in effect
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RouteTable {
+    pub routes: Vec<RouteEntry>,
+    pub pins: Vec<RoutePin>,
+}
+
+/// A service to host, built up from its name, domain and port
+#[derive(Debug, Clone)]
+pub struct NewService {
+    name: String,
+    domain: String,
+    port: u16,
+    wait_for: Option<usize>,
+    timeout: Option<Duration>,
+    metadata: HashMap<String, String>,
+    service_type: Option<String>,
+}
+
+impl NewService {
+    pub fn new(name: impl Into<String>, domain: impl Into<String>, port: u16) -> Self {
+        NewService {
+            name: name.into(),
+            domain: domain.into(),
+            port,
+            wait_for: None,
+            timeout: None,
+            metadata: HashMap::new(),
+            service_type: None,
+        }
+    }
+
+    /// Wait until `peers` DNS-serving peers confirm storing the records,
+    /// for at most `timeout` if given
+    pub fn with_confirmation(mut self, peers: usize, timeout: Option<Duration>) -> Self {
+        self.wait_for = Some(peers);
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_service_type(mut self, service_type: impl Into<String>) -> Self {
+        self.service_type = Some(service_type.into());
+        self
+    }
+}
+
+/// A published service
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Registration {
+    pub service_id: Uuid,
+    pub domain: String,
+    pub ttl: Duration,
+    /// Set when confirmation was asked for
+    pub propagation: Option<PropagationReport>,
+}
+
+/// A service no longer hosted, and how many still share its domain
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Unregistration {
+    pub service: ServiceSummary,
+    pub remaining: usize,
+}
+
+/// The blocklist after a block or unblock
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AclChange {
+    /// False when the entry was already in the requested state
+    pub changed: bool,
+    pub blocked: Vec<String>,
+    pub torn_down: usize,
+}
+
+/// Peers told goodbye on a disconnect or maintenance
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Departure {
+    pub peers: usize,
+    pub expected_return: Option<DateTime<Utc>>,
+    /// Redundancy partners that could not be asked
+    pub unreachable: Vec<GroupMember>,
+    /// Redundancy partners whose turn a forced maintenance went past
+    pub overridden: Vec<GroupMember>,
+}
+
+/// Connected peers and what this node and each of them advertise
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PeerList {
+    pub local: Capabilities,
+    pub peers: Vec<PeerConnection>,
+    pub admin_down: Vec<AdminDown>,
+    pub quarantine: Vec<QuarantineStatus>,
+    pub multihoming: Option<MultihomingStatus>,
+}
+
+/// An ASN's administrative state after a disable or enable
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PeerAdminChange {
+    pub asn: u32,
+    pub admin_down: bool,
+    /// How many connected peers it applied to
+    pub peers: usize,
+}
+
+/// Nodes in the directory
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct NodeList {
+    pub nodes: Vec<NodeDirectoryEntry>,
+    pub local: Option<NodeId>,
+    pub over_quota: Vec<OriginUsage>,
+    pub group: Option<GroupStatus>,
+}
+
+/// ASN grants, and this node's registry key when it runs a registry
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AsnGrants {
+    pub grants: Vec<AsnGrant>,
+    pub registry_key: Option<String>,
+}
+
+/// Capacity readings and the alerts in effect
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Capacity {
+    /// `None` until the first reading
+    pub report: Option<CapacityReport>,
+    pub alerts: Vec<CapacityAlert>,
+    pub heard: Option<HeardDigest>,
+}
+
+/// Cached DNS answers, with the cache's counters
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CacheListing {
+    pub entries: Vec<CacheEntryInfo>,
+    pub stats: CacheStats,
+}
+
+/// How many cached answers a flush dropped
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CacheFlush {
+    pub removed: usize,
+    pub stats: CacheStats,
+}
+
+/// A running daemon, reached over its control socket
+#[derive(Debug, Clone)]
+pub struct DaemonClient {
+    control: ControlClient,
+    daemon: BuildInfo,
+}
+
+impl DaemonClient {
+    /// Connect over the Unix control socket
+    pub async fn unix(socket_path: impl Into<PathBuf>) -> Result<Self, ClientError> {
+        Self::negotiate(ControlClient::unix(socket_path)).await
+    }
+
+    /// Connect over the token-authenticated TCP fallback
+    pub async fn tcp(addr: SocketAddr, token: String) -> Result<Self, ClientError> {
+        Self::negotiate(ControlClient::tcp(addr, token)).await
+    }
+
+    async fn negotiate(control: ControlClient) -> Result<Self, ClientError> {
+        let schema = fetch_schema(&control).await?;
+        check_schema(schema.schema_version)?;
+        Ok(DaemonClient {
+            control,
+            daemon: schema.build,
+        })
+    }
+
+    /// The build of the daemon as of connecting
+    pub fn daemon(&self) -> &BuildInfo {
+        &self.daemon
+    }
+
+    /// Send `request`, handing its reply to `extract`, which turns down
+    /// replies of the wrong kind
+    async fn call<T>(
+        &self,
+        request: ControlRequest,
+        extract: impl FnOnce(ControlResponse) -> Option<T>,
+    ) -> Result<T, ClientError> {
+        call(&self.control, request, extract).await
+    }
+
+    pub async fn routes(&self) -> Result<RouteTable, ClientError> {
+        self.call(ControlRequest::Routes, |reply| match reply {
+            ControlResponse::Routes { routes, pins } => Some(RouteTable { routes, pins }),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Routes as received from a peer, before policy
+    pub async fn routes_received(&self, peer_asn: u32) -> Result<Vec<RouteEntry>, ClientError> {
+        self.call(
+            ControlRequest::RoutesReceived { peer_asn },
+            |reply| match reply {
+                ControlResponse::Routes { routes, .. } => Some(routes),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Routes as advertised to a peer
+    pub async fn routes_advertised(&self, peer_asn: u32) -> Result<Vec<RouteEntry>, ClientError> {
+        self.call(
+            ControlRequest::RoutesAdvertised { peer_asn },
+            |reply| match reply {
+                ControlResponse::Routes { routes, .. } => Some(routes),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn explain_route(&self, network: Prefix) -> Result<Explanation, ClientError> {
+        self.call(
+            ControlRequest::ExplainRoute { network },
+            |reply| match reply {
+                ControlResponse::Explanation { explanation } => Some(explanation),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn propagation_stats(&self) -> Result<PropagationStats, ClientError> {
+        self.call(ControlRequest::PropagationStats, |reply| match reply {
+            ControlResponse::PropagationStats { stats } => Some(stats),
+            _ => None,
+        })
+        .await
+    }
+
+    /// The daemon's build as of now, which may differ from [`Self::daemon`]
+    /// after a restart
+    pub async fn version(&self) -> Result<BuildInfo, ClientError> {
+        self.call(ControlRequest::Version, |reply| match reply {
+            ControlResponse::Version { build } => Some(build),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Dry-run a policy fragment, in TOML, against installed routes
+    pub async fn policy_test(
+        &self,
+        policy: impl Into<String>,
+        peer_asn: Option<u32>,
+    ) -> Result<DryRunReport, ClientError> {
+        let request = ControlRequest::PolicyTest {
+            policy: policy.into(),
+            peer_asn,
+        };
+        self.call(request, |reply| match reply {
+            ControlResponse::PolicyReport { report } => Some(report),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Originate a route, returning the Loc-RIB version after it
+    pub async fn announce_route(
+        &self,
+        network: Prefix,
+        next_hop: IpAddr,
+        communities: Vec<Community>,
+    ) -> Result<u64, ClientError> {
+        let request = ControlRequest::AnnounceRoute {
+            network,
+            next_hop,
+            communities,
+        };
+        self.call(request, applied).await
+    }
+
+    pub async fn withdraw_route(&self, network: Prefix) -> Result<u64, ClientError> {
+        self.call(ControlRequest::WithdrawRoute { network }, applied)
+            .await
+    }
+
+    /// Prefer the path from `via_asn` for `network`, for `duration` if given
+    pub async fn pin_route(
+        &self,
+        network: Prefix,
+        via_asn: u32,
+        duration: Option<Duration>,
+        allow_missing: bool,
+    ) -> Result<u64, ClientError> {
+        let request = ControlRequest::PinRoute {
+            network,
+            via_asn,
+            duration_secs: duration.map(|duration| duration.as_secs()),
+            allow_missing,
+        };
+        self.call(request, applied).await
+    }
+
+    pub async fn unpin_route(&self, network: Prefix) -> Result<u64, ClientError> {
+        self.call(ControlRequest::UnpinRoute { network }, applied)
+            .await
+    }
+
+    pub async fn register_service(&self, service: NewService) -> Result<Registration, ClientError> {
+        let request = ControlRequest::RegisterService {
+            name: service.name,
+            domain: service.domain,
+            port: service.port,
+            wait_for: service.wait_for,
+            timeout_secs: service.timeout.map(|timeout| timeout.as_secs()),
+            metadata: service.metadata,
+            service_type: service.service_type,
+        };
+        self.call(request, |reply| match reply {
+            ControlResponse::Registered {
+                service_id,
+                domain,
+                ttl_secs,
+                propagation,
+            } => Some(Registration {
+                service_id,
+                domain,
+                ttl: Duration::from_secs(ttl_secs),
+                propagation,
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Restart the TTL of the services under `domain`, returning the new TTL
+    pub async fn refresh_service(
+        &self,
+        domain: impl Into<String>,
+    ) -> Result<Duration, ClientError> {
+        let request = ControlRequest::RefreshService {
+            domain: domain.into(),
+        };
+        self.call(request, |reply| match reply {
+            ControlResponse::Refreshed { ttl_secs, .. } => Some(Duration::from_secs(ttl_secs)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Stop hosting a service; `service_type` and `port` pick one out when
+    /// several share the domain
+    pub async fn unregister_service(
+        &self,
+        domain: impl Into<String>,
+        service_type: Option<String>,
+        port: Option<u16>,
+    ) -> Result<Unregistration, ClientError> {
+        let request = ControlRequest::UnregisterService {
+            domain: domain.into(),
+            service_type,
+            port,
+        };
+        self.call(request, |reply| match reply {
+            ControlResponse::Unregistered { service, remaining } => {
+                Some(Unregistration { service, remaining })
+            }
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn list_services(&self) -> Result<Vec<ServiceSummary>, ClientError> {
+        self.call(ControlRequest::ListServices, services).await
+    }
+
+    /// Refuse all contact with an ASN, address or prefix, or node id
+    pub async fn block(&self, entry: impl Into<String>) -> Result<AclChange, ClientError> {
+        let request = ControlRequest::Block {
+            entry: entry.into(),
+        };
+        self.call(request, acl).await
+    }
+
+    pub async fn unblock(&self, entry: impl Into<String>) -> Result<AclChange, ClientError> {
+        let request = ControlRequest::Unblock {
+            entry: entry.into(),
+        };
+        self.call(request, acl).await
+    }
+
+    pub async fn connect(
+        &self,
+        peer: IpAddr,
+        peer_asn: u32,
+    ) -> Result<PeerConnection, ClientError> {
+        self.call(
+            ControlRequest::Connect { peer, peer_asn },
+            |reply| match reply {
+                ControlResponse::Connected { peer } => Some(peer),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn disconnect(&self, peer: IpAddr) -> Result<Departure, ClientError> {
+        self.call(ControlRequest::Disconnect { peer }, departed)
+            .await
+    }
+
+    /// Say goodbye to every peer, expecting to be back in `return_in`; in a
+    /// redundancy group, refused with `Busy` while it is a partner's turn
+    /// unless `force`d
+    pub async fn maintenance(
+        &self,
+        return_in: Option<Duration>,
+        force: bool,
+    ) -> Result<Departure, ClientError> {
+        let request = ControlRequest::Maintenance {
+            return_in_secs: return_in.map(|return_in| return_in.as_secs()),
+            force,
+        };
+        self.call(request, departed).await
+    }
+
+    pub async fn peers(&self) -> Result<PeerList, ClientError> {
+        self.call(ControlRequest::Peers, |reply| match reply {
+            ControlResponse::Peers {
+                local,
+                peers,
+                admin_down,
+                quarantine,
+                multihoming,
+            } => Some(PeerList {
+                local,
+                peers,
+                admin_down,
+                quarantine,
+                multihoming,
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn peer_disable(&self, asn: u32) -> Result<PeerAdminChange, ClientError> {
+        self.call(ControlRequest::PeerDisable { asn }, peer_admin)
+            .await
+    }
+
+    pub async fn peer_enable(&self, asn: u32) -> Result<PeerAdminChange, ClientError> {
+        self.call(ControlRequest::PeerEnable { asn }, peer_admin)
+            .await
+    }
+
+    pub async fn quarantine_list(&self) -> Result<Vec<QuarantineStatus>, ClientError> {
+        self.call(ControlRequest::QuarantineList, |reply| match reply {
+            ControlResponse::Quarantine { peers } => Some(peers),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Forget an ASN's quarantine score; false when it had none
+    pub async fn quarantine_clear(&self, asn: u32) -> Result<bool, ClientError> {
+        self.call(
+            ControlRequest::QuarantineClear { asn },
+            |reply| match reply {
+                ControlResponse::QuarantineCleared { cleared, .. } => Some(cleared),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn firewall_status(&self) -> Result<FirewallStatus, ClientError> {
+        self.call(ControlRequest::FirewallStatus, |reply| match reply {
+            ControlResponse::Firewall { firewall } => Some(firewall),
+            _ => None,
+        })
+        .await
+    }
+
+    /// This node's redundancy group; `None` outside one
+    pub async fn group(&self) -> Result<Option<GroupStatus>, ClientError> {
+        self.call(ControlRequest::Group, |reply| match reply {
+            ControlResponse::Group { group } => Some(group),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn nodes(&self) -> Result<NodeList, ClientError> {
+        self.call(ControlRequest::Nodes, |reply| match reply {
+            ControlResponse::Nodes {
+                nodes,
+                local,
+                over_quota,
+                group,
+            } => Some(NodeList {
+                nodes,
+                local,
+                over_quota,
+                group,
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn bootstrap_list(&self) -> Result<Vec<BootstrapStatus>, ClientError> {
+        self.call(ControlRequest::BootstrapList, bootstrap).await
+    }
+
+    /// Probe every bootstrap node now, returning their new scores
+    pub async fn bootstrap_probe(&self) -> Result<Vec<BootstrapStatus>, ClientError> {
+        self.call(ControlRequest::BootstrapProbe, bootstrap).await
+    }
+
+    pub async fn asn_list(&self) -> Result<AsnGrants, ClientError> {
+        self.call(ControlRequest::AsnList, |reply| match reply {
+            ControlResponse::AsnGrants {
+                grants,
+                registry_key,
+            } => Some(AsnGrants {
+                grants,
+                registry_key,
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn tasks(&self) -> Result<Vec<TaskInfo>, ClientError> {
+        self.call(ControlRequest::Tasks, |reply| match reply {
+            ControlResponse::Tasks { tasks } => Some(tasks),
+            _ => None,
+        })
+        .await
+    }
+
+    /// How long each startup phase took; `None` while still starting
+    pub async fn startup(&self) -> Result<Option<TimingReport>, ClientError> {
+        self.call(ControlRequest::Startup, |reply| match reply {
+            ControlResponse::Startup { report } => Some(report),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn capacity(&self) -> Result<Capacity, ClientError> {
+        self.call(ControlRequest::Capacity, |reply| match reply {
+            ControlResponse::Capacity {
+                report,
+                alerts,
+                heard,
+            } => Some(Capacity {
+                report: report.map(|report| *report),
+                alerts,
+                heard: heard.map(|heard| *heard),
+            }),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn readiness(&self) -> Result<ReadinessReport, ClientError> {
+        self.call(ControlRequest::Readiness, |reply| match reply {
+            ControlResponse::Readiness { report } => Some(report),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn public_address(&self) -> Result<AddressReport, ClientError> {
+        self.call(ControlRequest::PublicAddress, |reply| match reply {
+            ControlResponse::PublicAddress { report } => Some(report),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Cached answers for names matching `pattern`, an exact name or
+    /// `*.suffix`; only negative answers with `negative`
+    pub async fn dns_cache(
+        &self,
+        pattern: Option<String>,
+        negative: bool,
+    ) -> Result<CacheListing, ClientError> {
+        self.call(
+            ControlRequest::DnsCache { pattern, negative },
+            |reply| match reply {
+                ControlResponse::DnsCache { entries, stats } => {
+                    Some(CacheListing { entries, stats })
+                }
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Drop the cached answers [`Self::dns_cache`] would list
+    pub async fn dns_cache_flush(
+        &self,
+        pattern: Option<String>,
+        negative: bool,
+    ) -> Result<CacheFlush, ClientError> {
+        self.call(
+            ControlRequest::DnsCacheFlush { pattern, negative },
+            |reply| match reply {
+                ControlResponse::DnsCacheFlushed { removed, stats } => {
+                    Some(CacheFlush { removed, stats })
+                }
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    pub async fn dns_cache_stats(&self) -> Result<CacheStats, ClientError> {
+        self.call(ControlRequest::DnsCacheStats, |reply| match reply {
+            ControlResponse::DnsCacheStats { stats } => Some(stats),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Who registered what under `domain`; without one, every name this
+    /// node registered or changed itself
+    pub async fn dns_history(
+        &self,
+        domain: Option<String>,
+    ) -> Result<Vec<HistoryEntry>, ClientError> {
+        self.call(ControlRequest::DnsHistory { domain }, |reply| match reply {
+            ControlResponse::DnsHistory { entries } => Some(entries),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Listed services of a type carrying all of `tags`
+    pub async fn find_services(
+        &self,
+        service_type: Option<String>,
+        tags: Vec<String>,
+        nearby: bool,
+        fan_out: bool,
+    ) -> Result<Vec<ServiceSummary>, ClientError> {
+        let request = ControlRequest::FindServices {
+            service_type,
+            tags,
+            nearby,
+            fan_out,
+        };
+        self.call(request, services).await
+    }
+
+    pub async fn reload(&self) -> Result<ReloadReport, ClientError> {
+        self.call(ControlRequest::Reload, |reply| match reply {
+            ControlResponse::Reloaded { report } => Some(report),
+            _ => None,
+        })
+        .await
+    }
+
+    pub async fn schema(&self) -> Result<ControlSchema, ClientError> {
+        fetch_schema(&self.control).await
+    }
+
+    /// Run commands in order; an atomic batch is undone if any fails
+    pub async fn batch(
+        &self,
+        commands: Vec<BatchCommand>,
+        atomic: bool,
+    ) -> Result<Vec<BatchResult>, ClientError> {
+        self.call(
+            ControlRequest::Batch { commands, atomic },
+            |reply| match reply {
+                ControlResponse::Batch { results } => Some(results),
+                _ => None,
+            },
+        )
+        .await
+    }
+
+    /// Follow the daemon's events from now on
+    pub async fn subscribe_events(&self) -> Result<EventStream, ClientError> {
+        let subscription = subscribe(&self.control).await?;
+        Ok(EventStream {
+            control: self.control.clone(),
+            subscription: Some(subscription),
+        })
+    }
+}
+
+/// The daemon's events, reconnecting whenever the connection drops
+pub struct EventStream {
+    control: ControlClient,
+    subscription: Option<Subscription>,
+}
+
+impl EventStream {
+    /// The next event. After the connection dropped, this waits for the
+    /// daemon to come back and returns [`DaemonEvent::Resubscribed`] once
+    /// it did; it fails only when the daemon refuses the subscription or
+    /// came back speaking another schema version.
+    pub async fn next(&mut self) -> Result<DaemonEvent, ClientError> {
+        if let Some(subscription) = &mut self.subscription {
+            match subscription.next().await {
+                Ok(Some(event)) => return Ok(event),
+                Ok(None) | Err(ControlError::IO(_)) => {
+                    tracing::debug!("Event stream dropped, subscribing again");
+                    self.subscription = None;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut delay = RECONNECT_MIN;
+        loop {
+            match subscribe(&self.control).await {
+                Ok(subscription) => {
+                    self.subscription = Some(subscription);
+                    return Ok(DaemonEvent::Resubscribed);
+                }
+                Err(ClientError::Control(ControlError::IO(_)))
+                | Err(ClientError::Control(ControlError::Refused {
+                    code: ControlErrorCode::Busy,
+                    ..
+                })) => {}
+                Err(e) => return Err(e),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX);
+        }
+    }
+}
+
+async fn subscribe(control: &ControlClient) -> Result<Subscription, ClientError> {
+    let subscription = control.subscribe().await?;
+    check_schema(subscription.schema_version())?;
+    Ok(subscription)
+}
+
+async fn fetch_schema(control: &ControlClient) -> Result<ControlSchema, ClientError> {
+    call(control, ControlRequest::Schema, |reply| match reply {
+        ControlResponse::Schema { schema } => Some(*schema),
+        _ => None,
+    })
+    .await
+}
+
+fn check_schema(daemon: u32) -> Result<(), ClientError> {
+    if daemon != SCHEMA_VERSION {
+        return Err(ClientError::SchemaMismatch {
+            daemon,
+            client: SCHEMA_VERSION,
+        });
+    }
+    Ok(())
+}
+
+async fn call<T>(
+    control: &ControlClient,
+    request: ControlRequest,
+    extract: impl FnOnce(ControlResponse) -> Option<T>,
+) -> Result<T, ClientError> {
+    let command = request.name();
+    match control.request(&request).await? {
+        ControlResponse::Error { code, message } => Err(ClientError::Refused {
+            command,
+            code,
+            message,
+        }),
+        reply => extract(reply).ok_or(ClientError::Unexpected { command }),
+    }
+}
+
+fn applied(reply: ControlResponse) -> Option<u64> {
+    match reply {
+        ControlResponse::Applied { rib_version } => Some(rib_version),
+        _ => None,
+    }
+}
+
+fn services(reply: ControlResponse) -> Option<Vec<ServiceSummary>> {
+    match reply {
+        ControlResponse::Services { services } => Some(services),
+        _ => None,
+    }
+}
+
+fn acl(reply: ControlResponse) -> Option<AclChange> {
+    match reply {
+        ControlResponse::Acl {
+            changed,
+            blocked,
+            torn_down,
+        } => Some(AclChange {
+            changed,
+            blocked,
+            torn_down,
+        }),
+        _ => None,
+    }
+}
+
+fn departed(reply: ControlResponse) -> Option<Departure> {
+    match reply {
+        ControlResponse::Departed {
+            peers,
+            expected_return,
+            unreachable,
+            overridden,
+        } => Some(Departure {
+            peers,
+            expected_return,
+            unreachable,
+            overridden,
+        }),
+        _ => None,
+    }
+}
+
+fn peer_admin(reply: ControlResponse) -> Option<PeerAdminChange> {
+    match reply {
+        ControlResponse::PeerAdmin {
+            asn,
+            admin_down,
+            peers,
+        } => Some(PeerAdminChange {
+            asn,
+            admin_down,
+            peers,
+        }),
+        _ => None,
+    }
+}
+
+fn bootstrap(reply: ControlResponse) -> Option<Vec<BootstrapStatus>> {
+    match reply {
+        ControlResponse::Bootstrap { nodes } => Some(nodes),
+        _ => None,
+    }
+}
@@ -0,0 +1,203 @@
+//! Field-by-field comparison of configurations.
+//!
+//! Both sides are serialized to JSON and walked together, so any serde
+//! structure can be compared without per-field code. Tables are descended
+//! into; arrays and scalars are compared whole. Paths are dotted config
+//! keys as written in the TOML file, e.g. `network.bgp.hold_time`.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+/// Config keys whose values are never shown, only whether they changed
+pub const SECRET_PATHS: &[&str] = &["psk"];
+
+#[derive(Debug, thiserror::Error)]
+#[error("cannot compare configurations")]
+pub struct DiffError(#[from] serde_json::Error);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "delta", rename_all = "snake_case")]
+pub enum Delta {
+    /// Missing keys are `null`
+    Changed { old: Value, new: Value },
+    /// A secret changed; its values are withheld
+    Redacted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct FieldChange {
+    pub path: String,
+    #[serde(flatten)]
+    pub delta: Delta,
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.delta {
+            Delta::Changed { old, new } => write!(f, "{}: {} -> {}", self.path, old, new),
+            Delta::Redacted => write!(f, "{}: changed (secret)", self.path),
+        }
+    }
+}
+
+/// Whether `path` is `prefix` or a key beneath it
+pub fn path_within(path: &str, prefix: &str) -> bool {
+    path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+pub fn is_secret(path: &str) -> bool {
+    SECRET_PATHS.iter().any(|secret| path_within(path, secret))
+}
+
+/// Every key whose value differs between `old` and `new`, in key order
+pub fn diff<T: Serialize>(old: &T, new: &T) -> Result<Vec<FieldChange>, DiffError> {
+    let old = serde_json::to_value(old)?;
+    let new = serde_json::to_value(new)?;
+    Ok(diff_values(&old, &new, is_secret))
+}
+
+/// Compare two JSON trees, withholding the values under paths `secret`
+/// matches
+pub fn diff_values(old: &Value, new: &Value, secret: impl Fn(&str) -> bool) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    walk(String::new(), old, new, &secret, &mut changes);
+    changes
+}
+
+fn walk(
+    path: String,
+    old: &Value,
+    new: &Value,
+    secret: &impl Fn(&str) -> bool,
+    changes: &mut Vec<FieldChange>,
+) {
+    if old == new {
+        return;
+    }
+    if !path.is_empty() && secret(&path) {
+        changes.push(FieldChange {
+            path,
+            delta: Delta::Redacted,
+        });
+        return;
+    }
+
+    match (old, new) {
+        (Value::Object(old_fields), Value::Object(new_fields)) => {
+            let mut keys: Vec<&String> = old_fields.keys().chain(new_fields.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                walk(
+                    child,
+                    old_fields.get(key).unwrap_or(&Value::Null),
+                    new_fields.get(key).unwrap_or(&Value::Null),
+                    secret,
+                    changes,
+                );
+            }
+        }
+        _ => changes.push(FieldChange {
+            path,
+            delta: Delta::Changed {
+                old: old.clone(),
+                new: new.clone(),
+            },
+        }),
+    }
+}
+
+/// Copy the value at `path` in `from` into `into`, creating tables along
+/// the way and removing the key when `from` has none
+pub fn copy_path(into: &mut Value, from: &Value, path: &str) {
+    let source = path
+        .split('.')
+        .try_fold(from, |value, key| value.get(key))
+        .cloned();
+
+    let mut target = into;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if !target.is_object() {
+            *target = Value::Object(Default::default());
+        }
+        let fields = target.as_object_mut().expect("made a table above");
+        if keys.peek().is_none() {
+            match source {
+                Some(value) => {
+                    fields.insert(key.to_string(), value);
+                }
+                None => {
+                    fields.remove(key);
+                }
+            }
+            return;
+        }
+        target = fields.entry(key.to_string()).or_insert(Value::Null);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::units::ConfigDuration;
+    use crate::config::Vx0Config;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    #[test]
+    fn test_diff_names_each_changed_key_and_hides_secrets() {
+        let old: Vx0Config = testing::config(NodeTier::Edge);
+        assert!(diff(&old, &old).unwrap().is_empty());
+
+        let mut new = old.clone();
+        new.network.bgp.hold_time = ConfigDuration::from_secs(180);
+        new.monitoring.recorder.enabled = !old.monitoring.recorder.enabled;
+        new.psk = Some(crate::config::PSKConfig {
+            default: "hunter2".to_string(),
+        });
+
+        let changes = diff(&old, &new).unwrap();
+        let paths: Vec<&str> = changes.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "monitoring.recorder.enabled",
+                "network.bgp.hold_time",
+                "psk"
+            ]
+        );
+        assert_eq!(
+            changes[1].delta,
+            Delta::Changed {
+                old: Value::from("1m30s"),
+                new: Value::from("3m")
+            }
+        );
+        assert_eq!(changes[2].delta, Delta::Redacted);
+        let shown = changes
+            .iter()
+            .map(|change| change.to_string())
+            .chain(std::iter::once(serde_json::to_string(&changes).unwrap()))
+            .collect::<String>();
+        assert!(!shown.contains("hunter2"));
+
+        // Copying every changed path over turns old into new
+        let mut patched = serde_json::to_value(&old).unwrap();
+        let target = serde_json::to_value(&new).unwrap();
+        for change in &changes {
+            copy_path(&mut patched, &target, &change.path);
+        }
+        assert_eq!(patched, target);
+    }
+}
@@ -0,0 +1,114 @@
+//! Settings for subsystems this build was compiled without.
+//!
+//! Embedded builds leave out the subsystems they do not need (see the
+//! features in Cargo.toml). One config file still serves every build: a
+//! setting that turns on a missing subsystem loads with a warning naming
+//! the feature, and the subsystem stays off.
+
+use crate::config::{Vx0Config, WireFormat};
+use std::fmt;
+
+/// A setting that has no effect in this build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureWarning {
+    pub key: &'static str,
+    /// Cargo feature that would give it effect
+    pub feature: &'static str,
+}
+
+impl fmt::Display for FeatureWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is ignored: this build lacks the {} feature",
+            self.key, self.feature
+        )
+    }
+}
+
+impl Vx0Config {
+    /// Settings asking for subsystems this build was compiled without
+    pub fn feature_warnings(&self) -> Vec<FeatureWarning> {
+        let external_peers = self
+            .network
+            .bgp
+            .peers
+            .iter()
+            .any(|peer| peer.enabled && peer.wire == WireFormat::Rfc4271);
+        [
+            (
+                "network.dns.local_server",
+                "dns-server",
+                cfg!(feature = "dns-server"),
+                self.network.dns.local_server,
+            ),
+            (
+                "services.enable_discovery",
+                "discovery",
+                cfg!(feature = "discovery"),
+                self.services.enable_discovery,
+            ),
+            (
+                "monitoring.enable_metrics",
+                "metrics",
+                cfg!(feature = "metrics"),
+                self.monitoring.enable_metrics,
+            ),
+            (
+                "network.bgp.peers",
+                "external-bgp",
+                cfg!(feature = "external-bgp"),
+                external_peers,
+            ),
+            (
+                "network.kernel_routes.enabled",
+                "kernel_routes",
+                cfg!(feature = "kernel_routes"),
+                self.network.kernel_routes.enabled,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, _, compiled, wanted)| *wanted && !compiled)
+        .map(|(key, feature, _, _)| FeatureWarning { key, feature })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    #[test]
+    fn test_only_missing_subsystems_are_warned_about() {
+        let mut config = testing::config(NodeTier::Regional);
+        config.network.dns.local_server = true;
+        config.services.enable_discovery = true;
+        config.monitoring.enable_metrics = true;
+        config.network.kernel_routes.enabled = true;
+
+        let missing: Vec<&str> = config
+            .feature_warnings()
+            .iter()
+            .map(|warning| warning.feature)
+            .collect();
+        let expected: Vec<&str> = [
+            ("dns-server", cfg!(feature = "dns-server")),
+            ("discovery", cfg!(feature = "discovery")),
+            ("metrics", cfg!(feature = "metrics")),
+            ("kernel_routes", cfg!(feature = "kernel_routes")),
+        ]
+        .into_iter()
+        .filter(|(_, compiled)| !compiled)
+        .map(|(feature, _)| feature)
+        .collect();
+        assert_eq!(missing, expected);
+
+        // Turned off, nothing is missed
+        config.network.dns.local_server = false;
+        config.services.enable_discovery = false;
+        config.monitoring.enable_metrics = false;
+        config.network.kernel_routes.enabled = false;
+        assert_eq!(config.feature_warnings(), vec![]);
+    }
+}
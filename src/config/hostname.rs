@@ -0,0 +1,165 @@
+//! Checks on `node.hostname`.
+//!
+//! The hostname travels in announcements, names per-node certificate files
+//! and becomes a DNS label under `nodes.vx0`, so it has to be a valid DNS
+//! name. The deployment configs use fully qualified names such as
+//! `edge1.vx0.network`; each label is checked, and the first one is the
+//! node's short name.
+
+use crate::config::Vx0Config;
+
+/// Hostname a node gets when the config does not set one
+pub const DEFAULT_HOSTNAME: &str = "vx0-node";
+
+pub const MAX_LABEL_LEN: usize = 63;
+pub const MAX_HOSTNAME_LEN: usize = 253;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum HostnameError {
+    #[error("node.hostname is empty")]
+    Empty,
+    #[error("node.hostname {hostname:?} is longer than {MAX_HOSTNAME_LEN} characters")]
+    TooLong { hostname: String },
+    #[error("node.hostname {hostname:?} has an empty label")]
+    EmptyLabel { hostname: String },
+    #[error("node.hostname {hostname:?} has a label longer than {MAX_LABEL_LEN} characters")]
+    LabelTooLong { hostname: String },
+    #[error(
+        "node.hostname {hostname:?} contains {character:?}; only letters, digits and hyphens are allowed"
+    )]
+    InvalidCharacter { hostname: String, character: char },
+    #[error("node.hostname {hostname:?} has a label starting or ending with a hyphen")]
+    HyphenAtEdge { hostname: String },
+    #[error(
+        "node.hostname is the default {DEFAULT_HOSTNAME:?}, which many nodes share; \
+         set a unique one or pass --allow-default-hostname"
+    )]
+    DefaultOnPublicNetwork,
+}
+
+/// Check that `hostname` is a DNS name: dot-separated labels of letters,
+/// digits and inner hyphens
+pub fn validate_hostname(hostname: &str) -> Result<(), HostnameError> {
+    if hostname.is_empty() {
+        return Err(HostnameError::Empty);
+    }
+    if hostname.len() > MAX_HOSTNAME_LEN {
+        return Err(HostnameError::TooLong {
+            hostname: hostname.to_string(),
+        });
+    }
+
+    for label in hostname.split('.') {
+        if label.is_empty() {
+            return Err(HostnameError::EmptyLabel {
+                hostname: hostname.to_string(),
+            });
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(HostnameError::LabelTooLong {
+                hostname: hostname.to_string(),
+            });
+        }
+        if let Some(character) = label
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && *c != '-')
+        {
+            return Err(HostnameError::InvalidCharacter {
+                hostname: hostname.to_string(),
+                character,
+            });
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(HostnameError::HyphenAtEdge {
+                hostname: hostname.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// First label of the hostname, lowercased: `Edge1.vx0.network` is `edge1`
+pub fn short_name(hostname: &str) -> String {
+    hostname
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+impl Vx0Config {
+    pub fn check_hostname(&self) -> Result<(), HostnameError> {
+        validate_hostname(&self.node.hostname)
+    }
+
+    /// Joining the public network with the default hostname leaves the node
+    /// indistinguishable from every other unconfigured one
+    pub fn check_public_hostname(&self, allow_default: bool) -> Result<(), HostnameError> {
+        self.check_hostname()?;
+        if !allow_default && self.node.hostname.eq_ignore_ascii_case(DEFAULT_HOSTNAME) {
+            return Err(HostnameError::DefaultOnPublicNetwork);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    #[test]
+    fn test_hostname_validation_failures() {
+        for valid in ["edge1", "edge1.vx0.network", "Node-7.vx0", "a"] {
+            assert_eq!(validate_hostname(valid), Ok(()), "{}", valid);
+        }
+
+        assert_eq!(validate_hostname(""), Err(HostnameError::Empty));
+        assert!(matches!(
+            validate_hostname("my node"),
+            Err(HostnameError::InvalidCharacter { character: ' ', .. })
+        ));
+        assert!(matches!(
+            validate_hostname("edge_1.vx0"),
+            Err(HostnameError::InvalidCharacter { character: '_', .. })
+        ));
+        assert!(matches!(
+            validate_hostname("edge1..vx0"),
+            Err(HostnameError::EmptyLabel { .. })
+        ));
+        assert!(matches!(
+            validate_hostname("edge1.vx0."),
+            Err(HostnameError::EmptyLabel { .. })
+        ));
+        assert!(matches!(
+            validate_hostname("-edge1"),
+            Err(HostnameError::HyphenAtEdge { .. })
+        ));
+        assert!(matches!(
+            validate_hostname(&"a".repeat(MAX_LABEL_LEN + 1)),
+            Err(HostnameError::LabelTooLong { .. })
+        ));
+        let long = vec!["a".repeat(MAX_LABEL_LEN); 4].join(".");
+        assert!(matches!(
+            validate_hostname(&long),
+            Err(HostnameError::TooLong { .. })
+        ));
+
+        assert_eq!(short_name("Edge1.vx0.network"), "edge1");
+    }
+
+    #[test]
+    fn test_default_hostname_needs_opt_in_to_join() {
+        let mut config = testing::config(NodeTier::Edge);
+        assert_eq!(config.check_public_hostname(false), Ok(()));
+
+        config.node.hostname = DEFAULT_HOSTNAME.to_string();
+        assert_eq!(config.check_hostname(), Ok(()));
+        assert_eq!(
+            config.check_public_hostname(false),
+            Err(HostnameError::DefaultOnPublicNetwork)
+        );
+        assert_eq!(config.check_public_hostname(true), Ok(()));
+    }
+}
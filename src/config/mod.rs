@@ -1,9 +1,36 @@
-use config::{Config, ConfigError, Environment, File};
+use crate::network::acl::{AclEntry, AclMode};
+use crate::network::bgp::communities::{self, CommunityPolicy};
+use crate::network::bgp::export::ExportPolicy;
+use crate::network::bgp::policy::{PeerPolicy, PolicyFragment, PolicyRule};
+use crate::network::bgp::Community;
+use crate::network::firewall::BackendChoice;
+use crate::network::prefix_list::PrefixList;
+use crate::node::{NodeId, NodeTier};
+use crate::util::backoff::JitterMode;
+use config::{Config, ConfigError, Environment, File, Source};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use units::{ByteSize, ConfigDuration};
+
+pub mod diff;
+pub mod features;
+pub mod hostname;
+pub mod ports;
+pub mod profiles;
+pub mod reload;
+pub mod topology;
+pub mod units;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Vx0Config {
+    /// Built-in profile (`edge`, `regional`, `backbone`, `lab`) supplying
+    /// every value the config file and environment leave out
+    #[serde(default)]
+    pub profile: Option<String>,
     pub node: NodeConfig,
     pub network: NetworkConfig,
     pub security: SecurityConfig,
@@ -13,16 +40,109 @@ pub struct Vx0Config {
     pub bootstrap: Option<BootstrapConfig>,
     #[serde(default)]
     pub psk: Option<PSKConfig>,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub joining: JoiningConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+}
+
+/// Where the daemon keeps runtime state between restarts
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub dir: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            dir: "/var/lib/vx0net/store".to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct NodeConfig {
+    /// Keep this node ID across restarts; a new one is drawn at each start
+    /// when unset. Redundancy partners name each other by it.
+    #[serde(default)]
+    pub node_id: Option<NodeId>,
     pub hostname: String,
     pub asn: u32,
     pub tier: String,
     pub location: String,
     pub ipv4_address: String,
     pub ipv6_address: String,
+    #[serde(default)]
+    pub capabilities: CapabilitiesConfig,
+    /// Publish `<short hostname>.nodes.vx0` pointing at this node
+    #[serde(default)]
+    pub register_hostname_dns: bool,
+    /// Announce and register the address most peers see this node at
+    /// instead of `ipv4_address`
+    #[serde(default)]
+    pub auto_detect_public_address: bool,
+    #[serde(default)]
+    pub redundancy: RedundancyConfig,
+}
+
+/// Nodes deployed to stand in for each other, taking turns at maintenance
+/// so that one of them always carries traffic
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RedundancyConfig {
+    /// Name of the group; the node is in none when unset
+    pub group: Option<String>,
+    pub partners: Vec<RedundancyPartner>,
+    /// Where partners ask how this node stands, while in a group
+    pub listen_port: u16,
+    /// How long a partner has to answer before it is taken as unreachable
+    pub query_timeout: ConfigDuration,
+    /// After leaving maintenance, how long partners still wait their turn
+    pub hold_down: ConfigDuration,
+}
+
+impl Default for RedundancyConfig {
+    fn default() -> Self {
+        RedundancyConfig {
+            group: None,
+            partners: Vec::new(),
+            listen_port: 5357,
+            query_timeout: ConfigDuration::from_secs(3),
+            hold_down: ConfigDuration::from_secs(300),
+        }
+    }
+}
+
+/// Another member of the redundancy group, known by the node ID it keeps
+/// in `node.node_id` and asked over its tunnel, so it must also be a peer
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RedundancyPartner {
+    pub node_id: NodeId,
+    /// The partner's redundancy port, as "ip:port"
+    pub address: String,
+}
+
+/// Roles this node offers peers, advertised in OPEN and node announcements
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CapabilitiesConfig {
+    pub serves_dns: bool,
+    pub offers_relay: bool,
+    /// Seconds to coalesce capability changes before re-announcing them
+    pub announce_delay_secs: u64,
+}
+
+impl Default for CapabilitiesConfig {
+    fn default() -> Self {
+        CapabilitiesConfig {
+            serves_dns: false,
+            offers_relay: false,
+            announce_delay_secs: 2,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -30,21 +150,842 @@ pub struct NetworkConfig {
     pub bgp: BGPConfig,
     pub dns: DNSConfig,
     pub routing: RoutingConfig,
+    #[serde(default)]
+    pub plan: AddressPlanConfig,
+    #[serde(default)]
+    pub peering: PeeringConfig,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub hold_down: HoldDownConfig,
+    #[serde(default)]
+    pub kernel_routes: KernelRoutesConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub single_port: SinglePortConfig,
+    #[serde(default)]
+    pub peer_selection: PeerSelectionConfig,
+    #[serde(default)]
+    pub quarantine: QuarantineConfig,
+    #[serde(default)]
+    pub firewall: FirewallConfig,
+    #[serde(default)]
+    pub adaptive_timers: AdaptiveTimersConfig,
+}
+
+/// Opening the ports peers reach this node on in the host firewall. Off by
+/// default, leaving the firewall to the operator.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct FirewallConfig {
+    pub manage: bool,
+    /// `auto` picks ufw when it is active, then nft, then iptables; netsh
+    /// on Windows
+    pub backend: BackendChoice,
+    /// Where nft rules go, as "family table chain"
+    pub nft_chain: String,
+    pub iptables_chain: String,
+}
+
+impl Default for FirewallConfig {
+    fn default() -> Self {
+        FirewallConfig {
+            manage: false,
+            backend: BackendChoice::Auto,
+            nft_chain: "inet filter input".to_string(),
+            iptables_chain: "INPUT".to_string(),
+        }
+    }
+}
+
+/// Stretching keepalives, transport echoes and discovery announcements on
+/// links that stay up, within bounds for each tier; a link is timed by the
+/// lower tier of its two ends
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AdaptiveTimersConfig {
+    pub enabled: bool,
+    /// Intervals double for each period this long a link goes without a
+    /// flap or a lost probe
+    pub settle: ConfigDuration,
+    /// Probes lost this recently each hold a link's intervals one doubling
+    /// lower
+    pub loss_memory: ConfigDuration,
+    /// Known peers from which discovery announcements back off while the
+    /// set stays the same; they never stretch past a third of
+    /// `services.discovery_expiry_secs`
+    pub discovery_saturation: usize,
+    pub edge: TimerBounds,
+    pub regional: TimerBounds,
+    pub backbone: TimerBounds,
+}
+
+impl Default for AdaptiveTimersConfig {
+    fn default() -> Self {
+        AdaptiveTimersConfig {
+            enabled: true,
+            settle: ConfigDuration::from_secs(600),
+            loss_memory: ConfigDuration::from_secs(24 * 3600),
+            discovery_saturation: 16,
+            edge: TimerBounds::between(60, 600, 1800),
+            regional: TimerBounds::between(30, 180, 300),
+            backbone: TimerBounds::between(30, 60, 120),
+        }
+    }
+}
+
+impl AdaptiveTimersConfig {
+    pub fn bounds(&self, tier: &NodeTier) -> &TimerBounds {
+        match tier {
+            NodeTier::Edge => &self.edge,
+            NodeTier::Regional => &self.regional,
+            NodeTier::Backbone => &self.backbone,
+        }
+    }
+}
+
+/// The most and least often a link of one tier is probed; floors are used
+/// on new links and after any flap
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct TimerBounds {
+    pub keepalive_floor: ConfigDuration,
+    pub keepalive_ceiling: ConfigDuration,
+    /// Empty frames on idle transport connections
+    pub echo_floor: ConfigDuration,
+    pub echo_ceiling: ConfigDuration,
+    pub announce_floor: ConfigDuration,
+    pub announce_ceiling: ConfigDuration,
+}
+
+impl TimerBounds {
+    /// Keepalives and echoes from `floor` up to `ceiling` seconds, and
+    /// announcements from 30 seconds up to `announce_ceiling`
+    const fn between(floor: u64, ceiling: u64, announce_ceiling: u64) -> Self {
+        TimerBounds {
+            keepalive_floor: ConfigDuration::from_secs(floor),
+            keepalive_ceiling: ConfigDuration::from_secs(ceiling),
+            echo_floor: ConfigDuration::from_secs(floor),
+            echo_ceiling: ConfigDuration::from_secs(ceiling),
+            announce_floor: ConfigDuration::from_secs(30),
+            announce_ceiling: ConfigDuration::from_secs(announce_ceiling),
+        }
+    }
+}
+
+/// Scoring peers' violations, demoting and then quarantining those that
+/// keep misbehaving
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct QuarantineConfig {
+    pub enabled: bool,
+    /// Score from which the peer's routes are a last resort and its
+    /// announcements are not acted on
+    pub demote_score: f64,
+    /// Score from which the peer is cut off for `cooldown`
+    pub quarantine_score: f64,
+    /// Scores halve this often
+    pub half_life: ConfigDuration,
+    pub cooldown: ConfigDuration,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        QuarantineConfig {
+            enabled: true,
+            demote_score: 50.0,
+            quarantine_score: 100.0,
+            half_life: ConfigDuration::from_secs(600),
+            cooldown: ConfigDuration::from_secs(3600),
+        }
+    }
+}
+
+/// Trading the slowest Regional peer for a quicker one found by probing
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PeerSelectionConfig {
+    /// Applies to Edge nodes; Regional nodes also need `regional`
+    pub enabled: bool,
+    pub regional: bool,
+    /// Seconds between rounds of latency probes
+    pub interval_secs: u64,
+    /// Regionals from the directory probed each round besides our peers
+    pub sample_size: usize,
+    /// How much quicker a candidate must answer than the slowest peer
+    pub margin_ms: u64,
+    /// How long a candidate must stay that much quicker before a swap
+    pub sustain_secs: u64,
+    /// Least time between swaps, so the peer set never churns
+    pub cooldown_secs: u64,
+}
+
+impl Default for PeerSelectionConfig {
+    fn default() -> Self {
+        PeerSelectionConfig {
+            enabled: true,
+            regional: false,
+            interval_secs: 60,
+            sample_size: 8,
+            margin_ms: 30,
+            sustain_secs: 600,
+            cooldown_secs: 3600,
+        }
+    }
+}
+
+/// All VX0 traffic on the BGP port, for nodes behind firewalls that open
+/// only one. IKE binds UDP on that port number and the TCP listener also
+/// accepts tunnels encapsulated in TCP.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct SinglePortConfig {
+    pub enabled: bool,
+    /// Encapsulate tunnels in TCP toward peers that accept it, for
+    /// networks that block UDP outright
+    pub prefer_tcp: bool,
+}
+
+/// Retry schedules by use, so every subsystem that retries backs off the
+/// same way
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub peer_reconnect: RetryPolicyConfig,
+    pub bootstrap: RetryPolicyConfig,
+    pub join: RetryPolicyConfig,
+    pub hooks: RetryPolicyConfig,
+    pub dns_forward: RetryPolicyConfig,
+}
+
+/// One retry schedule; keys left out take that use's defaults
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicyConfig {
+    /// Wait before the first retry
+    pub initial_delay_ms: Option<u64>,
+    /// Each wait is this many times the one before
+    pub multiplier: Option<f64>,
+    pub max_delay_ms: Option<u64>,
+    pub jitter: Option<JitterMode>,
+    /// Tries in all, the first one included; 0 retries forever
+    pub max_attempts: Option<u32>,
+    /// A success lasting this long starts the schedule over; failing
+    /// sooner keeps backing off from where it was
+    pub reset_after_secs: Option<u64>,
+}
+
+/// How long a newly joined node is only a path of last resort
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HoldDownConfig {
+    /// 0 disables the hold-down
+    pub duration_secs: u64,
+    /// Extra copies of the local ASN on exported routes
+    pub prepend: usize,
+}
+
+impl Default for HoldDownConfig {
+    fn default() -> Self {
+        HoldDownConfig {
+            duration_secs: 1800,
+            prepend: 3,
+        }
+    }
+}
+
+/// Installing learned routes into a Linux routing table, for nodes acting
+/// as the gateway of a LAN. Needs the `kernel_routes` build feature.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct KernelRoutesConfig {
+    pub enabled: bool,
+    /// Routing table the routes go into; hosts reach it through an
+    /// `ip rule`, and nothing else should write to it
+    pub table: u32,
+    /// Route protocol marking entries as ours, so stale ones from a crashed
+    /// run can be found and removed
+    pub protocol: u8,
+    /// Tunnel interfaces and the next hops reachable over each; routes
+    /// whose next hop is behind none of them stay overlay-only
+    pub interfaces: Vec<KernelInterface>,
+}
+
+impl Default for KernelRoutesConfig {
+    fn default() -> Self {
+        KernelRoutesConfig {
+            enabled: false,
+            table: 4200,
+            protocol: 190,
+            interfaces: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct KernelInterface {
+    /// e.g. `vx0tun0`
+    pub name: String,
+    /// Next hops within this network are reached over the interface
+    pub network: IpNet,
+}
+
+/// Peers refused (or, in allowlist mode, admitted) regardless of tier rules
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AclConfig {
+    pub mode: AclMode,
+    /// ASNs (`AS66001`), addresses or prefixes, and node ids
+    pub blocked: Vec<AclEntry>,
+    /// Only consulted in allowlist mode
+    pub allowed: Vec<AclEntry>,
+    /// Where entries blocked at runtime are kept across restarts
+    pub state_file: Option<String>,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            mode: AclMode::Blocklist,
+            blocked: Vec::new(),
+            allowed: Vec::new(),
+            state_file: Some("/var/lib/vx0net/acl.json".to_string()),
+        }
+    }
+}
+
+/// Limits on what Edge peers may announce to the tiers above them
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PeeringConfig {
+    pub edge_max_new_prefixes_per_hour: usize,
+    /// Distinct origin ASNs allowed across an Edge peer's routes
+    pub edge_max_origin_asns: usize,
+    pub edge_min_prefix_len_v4: u8,
+    pub edge_min_prefix_len_v6: u8,
+    /// Communities kept on Edge routes; all others are stripped
+    pub edge_allowed_communities: Vec<Community>,
+    /// Violations within an hour before the peer is put in cooldown
+    pub violation_threshold: usize,
+    pub cooldown_secs: u64,
+}
+
+impl Default for PeeringConfig {
+    fn default() -> Self {
+        PeeringConfig {
+            edge_max_new_prefixes_per_hour: 32,
+            edge_max_origin_asns: 1,
+            edge_min_prefix_len_v4: 22,
+            edge_min_prefix_len_v6: 48,
+            edge_allowed_communities: Vec::new(),
+            violation_threshold: 10,
+            cooldown_secs: 900,
+        }
+    }
+}
+
+/// VX0 address plan: the network-wide supernets and this node's own prefixes
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AddressPlanConfig {
+    pub ipv4_supernet: String,
+    pub ipv6_supernet: String,
+    /// Prefix assigned to this node, originated at startup
+    pub ipv4_prefix: Option<String>,
+    pub ipv6_prefix: Option<String>,
+}
+
+impl Default for AddressPlanConfig {
+    fn default() -> Self {
+        AddressPlanConfig {
+            ipv4_supernet: crate::network::bgp::default_route::VX0_IPV4_SUPERNET.to_string(),
+            ipv6_supernet: crate::network::bgp::default_route::VX0_IPV6_SUPERNET.to_string(),
+            ipv4_prefix: None,
+            ipv6_prefix: None,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BGPConfig {
     pub router_id: String,
     pub listen_port: u16,
-    pub hold_time: u16,
-    pub keepalive_time: u16,
+    /// Offered in OPEN; the session drops when nothing arrives for this long
+    pub hold_time: ConfigDuration,
+    pub keepalive_time: ConfigDuration,
+    #[serde(default)]
+    pub peers: Vec<BGPPeerConfig>,
+    /// Import rules applied to routes from every peer
+    #[serde(default)]
+    pub import_policy: Vec<PolicyRule>,
+    #[serde(default)]
+    pub rejection_journal: RejectionJournalConfig,
+    /// Where earlier versions kept route pins; pins found there are moved
+    /// into the store at startup and the file removed
+    #[serde(default = "default_pins_file")]
+    pub pins_file: Option<String>,
+    /// Attribute changes for routes received carrying a community; the
+    /// well-known prefer, normal and backup communities by default
+    #[serde(default = "communities::well_known_policies")]
+    pub community_policies: Vec<CommunityPolicy>,
+    /// How our routes are rewritten for particular peers, first match wins
+    #[serde(default)]
+    pub export_policies: Vec<ExportPolicy>,
+    /// Prefixes originated at startup, with the communities they carry
+    #[serde(default)]
+    pub announce: Vec<StaticAnnouncement>,
+    /// Hold sessions after OPEN until a tunnel to the peer is established,
+    /// so no routes are exchanged outside one
+    #[serde(default)]
+    pub require_tunnel: bool,
+    /// Carry BGP itself inside that tunnel when the peer does too; only
+    /// with `require_tunnel`
+    #[serde(default)]
+    pub bgp_over_tunnel: bool,
+    /// How long a session whose tunnel failed holds the peer's routes,
+    /// stale, before they are flushed
+    #[serde(default = "default_tunnel_suspend_timeout_secs")]
+    pub tunnel_suspend_timeout_secs: u64,
+    /// External system consulted before routes learned from peers are
+    /// accepted
+    #[serde(default)]
+    pub validator: Option<ValidatorConfig>,
+    /// Paging of the full table a new session starts with
+    #[serde(default)]
+    pub table_sync: TableSyncConfig,
+    /// Bounds on the inbound connections tracked at once
+    #[serde(default)]
+    pub session_limits: SessionLimitsConfig,
+    /// Active/standby use of two Regional parents, on Edge nodes
+    #[serde(default)]
+    pub multihoming: MultihomingConfig,
+    /// Stamp originated routes with when they were originated, so nodes
+    /// receiving them can measure how long they took to arrive
+    #[serde(default)]
+    pub trace_propagation: bool,
+}
+
+/// A prefix this node originates from configuration
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct StaticAnnouncement {
+    pub prefix: String,
+    /// Defaults to this node's address in the prefix's family
+    #[serde(default)]
+    pub next_hop: Option<String>,
+    /// Asks receivers to treat the route a certain way, e.g. as a backup
+    #[serde(default)]
+    pub communities: Vec<Community>,
+}
+
+fn default_tunnel_suspend_timeout_secs() -> u64 {
+    300
+}
+
+fn default_pins_file() -> Option<String> {
+    Some("/var/lib/vx0net/route-pins.json".to_string())
+}
+
+/// Recent rejections kept so `vx0net routes explain` can say why a route
+/// is missing
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RejectionJournalConfig {
+    /// Entries kept; the least recently recorded are dropped first
+    pub size: usize,
+    /// Entries older than this are forgotten
+    pub max_age_secs: u64,
+}
+
+/// How a full table is sent to a new peer: in chunks it acknowledges, so
+/// a dropped connection resumes where it stopped
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(default)]
+pub struct TableSyncConfig {
+    /// Routes per chunk
+    pub chunk_routes: usize,
+    /// Chunks sent ahead of the peer's acknowledgements
+    pub window: usize,
+}
+
+impl Default for TableSyncConfig {
+    fn default() -> Self {
+        TableSyncConfig {
+            chunk_routes: 1000,
+            window: 4,
+        }
+    }
+}
+
+/// How many inbound BGP connections are tracked, and for how long one may
+/// go without establishing its session
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+#[serde(default)]
+pub struct SessionLimitsConfig {
+    /// Connections beyond this are refused until others close
+    pub max_sessions: usize,
+    /// Longer than a hold time, so sessions waiting on a tunnel are kept
+    pub setup_timeout: ConfigDuration,
+}
+
+impl Default for SessionLimitsConfig {
+    fn default() -> Self {
+        SessionLimitsConfig {
+            max_sessions: 1024,
+            setup_timeout: ConfigDuration::from_secs(120),
+        }
+    }
+}
+
+/// Which of an Edge node's two Regional parents carries its traffic, and
+/// how it fails over between them
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MultihomingConfig {
+    pub enabled: bool,
+    /// ASN of the preferred parent; with `secondary` unset too, the two
+    /// Regional peers with the lowest latency are chosen
+    pub primary: Option<u32>,
+    pub secondary: Option<u32>,
+    /// How often the active parent's health is checked, which bounds how
+    /// long a failed parent keeps carrying traffic
+    pub failover_time: ConfigDuration,
+    /// How long the primary must stay healthy before traffic returns to it
+    pub recovery_hold: ConfigDuration,
+    /// MED on our routes advertised to the standby parent
+    pub standby_med: u32,
+}
+
+impl Default for MultihomingConfig {
+    fn default() -> Self {
+        MultihomingConfig {
+            enabled: false,
+            primary: None,
+            secondary: None,
+            failover_time: ConfigDuration::from_secs(5),
+            recovery_hold: ConfigDuration::from_secs(60),
+            standby_med: 100,
+        }
+    }
+}
+
+/// An external route validator: an executable run per batch of routes,
+/// or an `http://` endpoint batches are posted to
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ValidatorConfig {
+    /// Program and arguments; the batch goes to stdin, verdicts come back
+    /// on stdout
+    pub command: Vec<String>,
+    pub url: Option<String>,
+    /// How long one batch may take before `on_failure` applies
+    pub timeout_ms: u64,
+    pub on_failure: ValidatorFailure,
+    /// How long a verdict for a prefix and origin AS is reused
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for ValidatorConfig {
+    fn default() -> Self {
+        ValidatorConfig {
+            command: Vec::new(),
+            url: None,
+            timeout_ms: 2000,
+            on_failure: ValidatorFailure::Open,
+            cache_ttl_secs: 900,
+        }
+    }
+}
+
+/// What happens to routes the validator could not judge
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidatorFailure {
+    /// Accept them, as if there were no validator
+    #[default]
+    Open,
+    /// Reject them until the validator answers
+    Closed,
+}
+
+impl Default for RejectionJournalConfig {
+    fn default() -> Self {
+        RejectionJournalConfig {
+            size: 4096,
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl BGPConfig {
+    /// `hold_time` in whole seconds, as OPEN carries it
+    pub fn hold_time_secs(&self) -> u16 {
+        self.hold_time.as_secs().try_into().unwrap_or(u16::MAX)
+    }
+
+    /// Configured import rules in the form the routing policy consumes
+    pub fn policy_fragment(&self) -> PolicyFragment {
+        PolicyFragment {
+            import_policy: self.import_policy.clone(),
+            peers: self
+                .peers
+                .iter()
+                .filter(|peer| !peer.import_policy.is_empty())
+                .map(|peer| PeerPolicy {
+                    asn: peer.asn,
+                    import_policy: peer.import_policy.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Configured peers' groups by ASN, for export policies to match on
+    pub fn peer_groups(&self) -> HashMap<u32, String> {
+        self.peers
+            .iter()
+            .filter_map(|peer| Some((peer.asn, peer.group.clone()?)))
+            .collect()
+    }
+}
+
+/// Statically configured BGP neighbor
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BGPPeerConfig {
+    pub address: String,
+    #[serde(default = "default_bgp_peer_port")]
+    pub port: u16,
+    pub asn: u32,
+    #[serde(default)]
+    pub wire: WireFormat,
+    /// Import rules for this peer, checked before the global ones
+    #[serde(default)]
+    pub import_policy: Vec<PolicyRule>,
+    /// Minimum seconds between UPDATEs to this peer; defaults by tier
+    #[serde(default)]
+    pub mrai_secs: Option<u64>,
+    /// What to do with prefixes this peer sends with host bits set
+    #[serde(default)]
+    pub host_bits: HostBitsPolicy,
+    /// `false` keeps the peer configured but administratively down
+    #[serde(default = "default_bgp_peer_enabled")]
+    pub enabled: bool,
+    /// Name export policies can match this peer by
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl BGPPeerConfig {
+    /// Advertisement interval toward this peer
+    pub fn mrai(&self) -> std::time::Duration {
+        self.mrai_secs
+            .map(std::time::Duration::from_secs)
+            .unwrap_or_else(|| crate::network::bgp::pacing::default_mrai(self.asn))
+    }
+}
+
+/// Message encoding spoken on a BGP session
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// Native VX0 framing with VX0-specific extensions
+    #[default]
+    Vx0,
+    /// Plain RFC 4271 binary BGP for external routers (FRR, BIRD)
+    Rfc4271,
+}
+
+/// Handling of received prefixes such as `10.2.1.5/24`, whose address is
+/// not the network address
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HostBitsPolicy {
+    /// Use the network silently
+    Normalize,
+    /// Use the network and log the peer's mistake
+    #[default]
+    Warn,
+    /// Ignore the announcement; withdrawals are still normalized
+    Reject,
+}
+
+fn default_originate_default_to_edge() -> bool {
+    true
+}
+
+fn default_bgp_peer_port() -> u16 {
+    179
+}
+
+fn default_bgp_peer_enabled() -> bool {
+    true
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DNSConfig {
+    /// Answer queries from this host's applications on `listen_port`; the
+    /// directory and zone sync run either way
+    #[serde(default = "default_dns_local_server")]
+    pub local_server: bool,
     pub listen_port: u16,
     pub vx0_dns_servers: Vec<String>, // Only VX0 internal DNS servers
+    /// Answers the local DNS server keeps for reuse; 0 turns caching off
     pub cache_size: usize,
+    /// How long "no such name" and "no such record" answers are cached
+    #[serde(default = "default_negative_cache_secs")]
+    pub negative_cache_secs: u64,
+    /// Forward allowlisted non-.vx0 queries to clearnet gateway nodes
+    #[serde(default)]
+    pub use_gateways: bool,
+    /// Port zone sync (NOTIFY, transfers and store confirmations) runs on
+    #[serde(default = "default_sync_port")]
+    pub sync_port: u16,
+    /// Pass non-VX0 queries from local clients to `upstream` instead of
+    /// refusing them
+    #[serde(default)]
+    pub proxy_clearnet: bool,
+    #[serde(default = "default_dns_upstream")]
+    pub upstream: SocketAddr,
+    /// Clients the local DNS server answers
+    #[serde(default = "default_dns_allow_from")]
+    pub allow_from: PrefixList,
+    /// Leave addresses no route reaches out of answers instead of listing
+    /// them last
+    #[serde(default)]
+    pub omit_unreachable: bool,
+    /// How long an address's route health is reused between answers
+    #[serde(default = "default_health_cache_ms")]
+    pub health_cache_ms: u64,
+    #[serde(default)]
+    pub sync_quota: SyncQuotaConfig,
+    #[serde(default)]
+    pub node_metadata: NodeMetadataConfig,
+    #[serde(default)]
+    pub history: DnsHistoryConfig,
+}
+
+/// TXT records describing this node to clients that only speak DNS
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct NodeMetadataConfig {
+    /// Publish `_node.<short hostname>.nodes.vx0`, and `_stats.vx0` on
+    /// Backbone nodes
+    pub enabled: bool,
+    /// Keys published; `contact` is left out unless listed here
+    pub keys: Vec<MetadataKey>,
+    /// How to reach the operator, e.g. an email address
+    pub contact: String,
+}
+
+impl Default for NodeMetadataConfig {
+    fn default() -> Self {
+        NodeMetadataConfig {
+            enabled: true,
+            keys: vec![
+                MetadataKey::Asn,
+                MetadataKey::Tier,
+                MetadataKey::Version,
+                MetadataKey::Services,
+            ],
+            contact: String::new(),
+        }
+    }
+}
+
+/// A fact about the node published in its metadata TXT records
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataKey {
+    Asn,
+    Tier,
+    Version,
+    /// How many services the node hosts
+    Services,
+    Contact,
+}
+
+impl MetadataKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetadataKey::Asn => "asn",
+            MetadataKey::Tier => "tier",
+            MetadataKey::Version => "version",
+            MetadataKey::Services => "services",
+            MetadataKey::Contact => "contact",
+        }
+    }
+}
+
+/// How much of the synced zones other nodes may fill; records this node
+/// registers are not counted
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct SyncQuotaConfig {
+    pub max_records_per_origin: usize,
+    /// Name plus data bytes
+    pub max_bytes_per_origin: ByteSize,
+    /// Synced records kept in total before eviction
+    pub max_synced_records: usize,
+}
+
+impl Default for SyncQuotaConfig {
+    fn default() -> Self {
+        SyncQuotaConfig {
+            max_records_per_origin: 500,
+            max_bytes_per_origin: ByteSize::kib(256),
+            max_synced_records: 20_000,
+        }
+    }
+}
+
+/// How much of the registration history `vx0net dns history` shows is kept
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DnsHistoryConfig {
+    /// Changes kept per name
+    pub per_domain: usize,
+    /// Changes kept across all names
+    pub max_entries: usize,
+}
+
+impl Default for DnsHistoryConfig {
+    fn default() -> Self {
+        DnsHistoryConfig {
+            per_domain: 32,
+            max_entries: 10_000,
+        }
+    }
+}
+
+fn default_dns_local_server() -> bool {
+    true
+}
+
+fn default_negative_cache_secs() -> u64 {
+    30
+}
+
+fn default_health_cache_ms() -> u64 {
+    2000
+}
+
+fn default_sync_port() -> u16 {
+    5354
+}
+
+pub fn default_dns_upstream() -> SocketAddr {
+    SocketAddr::from(([1, 1, 1, 1], 53))
+}
+
+/// Loopback and private ranges
+pub fn default_dns_allow_from() -> PrefixList {
+    PrefixList::parse([
+        "127.0.0.0/8",
+        "10.0.0.0/8",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "::1/128",
+        "fc00::/7",
+    ])
+    .expect("valid prefix list")
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -52,6 +993,50 @@ pub struct RoutingConfig {
     pub max_paths: u8,
     pub local_preference: u32,
     pub med: u32,
+    /// Regional nodes originate the VX0 default toward Edge peers
+    #[serde(default = "default_originate_default_to_edge")]
+    pub originate_default_to_edge: bool,
+    /// Only use routes whose next-hop peer we hold an established tunnel
+    /// to, for data planes that forward everything through tunnels
+    #[serde(default)]
+    pub require_tunnel_for_nexthop: bool,
+    /// What becomes of routes via a peer whose tunnel failed
+    #[serde(default)]
+    pub tunnel_failure: TunnelFailureAction,
+    #[serde(default)]
+    pub tunnel_damping: TunnelDampingConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelFailureAction {
+    /// Stop using and advertising them until a tunnel is back
+    #[default]
+    Withdraw,
+    /// Keep them only as a path of last resort
+    Deprioritize,
+}
+
+/// Tunnels that keep failing stay unusable for a while after they return,
+/// so a flapping link does not churn routes downstream
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct TunnelDampingConfig {
+    /// Failures within the window before restores are delayed; 0 disables
+    pub max_failures: usize,
+    pub window_secs: u64,
+    /// How long a damped tunnel must stay up before its routes return
+    pub suppress_secs: u64,
+}
+
+impl Default for TunnelDampingConfig {
+    fn default() -> Self {
+        TunnelDampingConfig {
+            max_failures: 3,
+            window_secs: 300,
+            suppress_secs: 60,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -59,6 +1044,21 @@ pub struct SecurityConfig {
     pub ike: IKEConfig,
     pub certificates: CertificateConfig,
     pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub obfuscation: ObfuscationConfig,
+}
+
+/// Traffic-analysis resistance; everything is off by default
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ObfuscationConfig {
+    /// Keepalive intervals vary by up to this share either way
+    pub keepalive_jitter_percent: u8,
+    /// Frame sizes tunnel payloads are padded up to, e.g. 256, 512, 1024
+    /// and 1400; empty disables padding. Only used with peers that also pad.
+    pub padding_buckets: Vec<usize>,
+    /// Send a cover frame on padded tunnels idle this long; 0 disables it
+    pub cover_traffic_interval_secs: u64,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -68,6 +1068,101 @@ pub struct IKEConfig {
     pub encryption_algorithm: String,
     pub hash_algorithm: String,
     pub prf_algorithm: String,
+    /// Seconds to wait for a peer to complete tunnel negotiation
+    #[serde(default = "default_establish_timeout_secs")]
+    pub establish_timeout_secs: u64,
+    /// Seconds an established tunnel keeps its keys before it is rekeyed;
+    /// 0 never rekeys on age
+    #[serde(default = "default_rekey_interval_secs")]
+    pub rekey_interval_secs: u64,
+    /// Largest encrypted datagram sent over a tunnel; each tunnel uses the
+    /// smaller of both ends' values
+    #[serde(default = "default_tunnel_mtu")]
+    pub mtu: u16,
+    /// Seconds a probed path MTU is trusted before the path is probed again
+    #[serde(default = "default_mtu_revalidate_secs")]
+    pub mtu_revalidate_secs: u64,
+    #[serde(default)]
+    pub nonce_journal: NonceJournalConfig,
+    #[serde(default)]
+    pub resumption: ResumptionConfig,
+}
+
+/// Resuming tunnels and sessions to peers after a short disconnect
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ResumptionConfig {
+    pub enabled: bool,
+    /// How long after a session was established it may still be resumed
+    pub validity_secs: u64,
+}
+
+impl Default for ResumptionConfig {
+    fn default() -> Self {
+        ResumptionConfig {
+            enabled: true,
+            validity_secs: 600,
+        }
+    }
+}
+
+impl ResumptionConfig {
+    /// `None` when resumption is off
+    pub fn validity(&self) -> Option<std::time::Duration> {
+        self.enabled
+            .then(|| std::time::Duration::from_secs(self.validity_secs))
+    }
+}
+
+/// Where tunnel nonce counters are journaled so a restart never reuses one
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct NonceJournalConfig {
+    /// Journal file; empty disables journaling
+    pub path: String,
+    /// Nonces reserved ahead of use with each write, so at most one write
+    /// per this many packets on a busy tunnel
+    pub margin: u64,
+    /// Reservations are topped up this often on tunnels that sent anything
+    pub flush_interval_ms: u64,
+}
+
+impl Default for NonceJournalConfig {
+    fn default() -> Self {
+        NonceJournalConfig {
+            path: String::new(),
+            margin: 4096,
+            flush_interval_ms: 500,
+        }
+    }
+}
+
+fn default_establish_timeout_secs() -> u64 {
+    30
+}
+
+fn default_rekey_interval_secs() -> u64 {
+    3600
+}
+
+fn default_tunnel_mtu() -> u16 {
+    crate::network::ike::mtu::DEFAULT_TUNNEL_MTU as u16
+}
+
+fn default_mtu_revalidate_secs() -> u64 {
+    600
+}
+
+impl IKEConfig {
+    /// `None` when tunnels are never rekeyed on age
+    pub fn rekey_interval(&self) -> Option<std::time::Duration> {
+        (self.rekey_interval_secs > 0)
+            .then(|| std::time::Duration::from_secs(self.rekey_interval_secs))
+    }
+
+    pub fn mtu_revalidate(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.mtu_revalidate_secs)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -88,7 +1183,65 @@ pub struct EncryptionConfig {
 pub struct ServicesConfig {
     pub enable_discovery: bool,
     pub discovery_port: u16,
-    pub service_ttl: u64,
+    /// What local discovery announcements reveal; applied again on reload
+    #[serde(default)]
+    pub discovery_privacy: DiscoveryPrivacy,
+    /// Seconds a discovered peer is kept after its last announcement
+    #[serde(default = "default_discovery_expiry_secs")]
+    pub discovery_expiry_secs: u64,
+    /// How long a service's records and routes live without a refresh
+    pub service_ttl: ConfigDuration,
+    /// Originate a host route for this node while it hosts live services
+    #[serde(default)]
+    pub advertise_host_routes: bool,
+    /// Most service summaries carried in a node announcement; peers query
+    /// for the rest
+    #[serde(default = "default_max_announced_services")]
+    pub max_announced_services: usize,
+    #[serde(default)]
+    pub gateway: GatewayConfig,
+}
+
+fn default_discovery_expiry_secs() -> u64 {
+    300
+}
+
+fn default_max_announced_services() -> usize {
+    crate::wire_limits::DEFAULT_MAX_ANNOUNCED_SERVICES
+}
+
+/// How much of a node's identity local discovery broadcasts
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryPrivacy {
+    /// Hostname, ASN and addresses in every announcement
+    #[default]
+    Full,
+    /// Only a random session ID and the discovery port; peers learn the
+    /// rest through an authenticated hello
+    Minimal,
+    /// No announcements, though direct queries are still answered
+    Silent,
+}
+
+/// Clearnet gateway: resolve an allowlist of external domains for other nodes
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct GatewayConfig {
+    pub enabled: bool,
+    /// External domains served, each including its subdomains
+    pub allowlist: Vec<String>,
+    pub listen_port: u16,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            enabled: false,
+            allowlist: Vec::new(),
+            listen_port: 5380,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -96,6 +1249,249 @@ pub struct MonitoringConfig {
     pub enable_metrics: bool,
     pub metrics_port: u16,
     pub log_level: String,
+    #[serde(default)]
+    pub recorder: RecorderConfig,
+    #[serde(default)]
+    pub crash: CrashConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub timing: TimingConfig,
+    #[serde(default)]
+    pub capacity: CapacityConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+}
+
+/// How `vx0net start --daemon` detaches and when it reports ready
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// Receives stdout and stderr once detached
+    pub log_file: String,
+    pub working_directory: String,
+    /// Write as an octal literal, e.g. `0o027`
+    pub umask: u32,
+    /// With `--join-network`, report ready only once the join finished
+    pub ready_after_join: bool,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            log_file: "/var/log/vx0net/vx0net.log".to_string(),
+            working_directory: "/".to_string(),
+            umask: 0o027,
+            ready_after_join: true,
+        }
+    }
+}
+
+/// Where crash reports from panicking tasks are kept
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CrashConfig {
+    pub directory: String,
+    /// Older reports are deleted once there are more than this many
+    pub max_reports: usize,
+    /// Pause before a panicked subsystem is started again
+    pub restart_delay_secs: u64,
+}
+
+impl Default for CrashConfig {
+    fn default() -> Self {
+        CrashConfig {
+            directory: "/var/lib/vx0net/crashes".to_string(),
+            max_reports: 20,
+            restart_delay_secs: 5,
+        }
+    }
+}
+
+/// Where the reason for the last exit is kept, and when repeated unclean
+/// exits delay the next start
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Holds the PID file; the shutdown record and unclean-exit history
+    /// are kept in the store
+    pub state_dir: String,
+    /// Recent log lines saved with each shutdown record
+    pub log_lines: usize,
+    /// Unclean exits within the window before starts are delayed
+    pub crash_loop_threshold: usize,
+    pub crash_loop_window_secs: u64,
+    /// First delay once the threshold is reached, doubling per further exit
+    pub backoff_base_secs: u64,
+    pub backoff_max_secs: u64,
+    /// Seconds each task gets to stop at shutdown before the daemon gives
+    /// up on it and exits anyway
+    pub task_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            state_dir: "/var/lib/vx0net".to_string(),
+            log_lines: 50,
+            crash_loop_threshold: 3,
+            crash_loop_window_secs: 600,
+            backoff_base_secs: 10,
+            backoff_max_secs: 300,
+            task_timeout_secs: 5,
+        }
+    }
+}
+
+/// Soft limits on how long each phase of startup and shutdown should take
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TimingConfig {
+    /// A phase taking longer than this is warned about by name
+    pub warn_after_ms: u64,
+    /// Limits for particular phases, overriding `warn_after_ms`
+    pub phase_warn_after_ms: HashMap<String, u64>,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        TimingConfig {
+            warn_after_ms: 5000,
+            // Joining waits on other nodes' approval
+            phase_warn_after_ms: HashMap::from([("join".to_string(), 60_000)]),
+        }
+    }
+}
+
+/// Per-tier statistics computed from the node directory, and the
+/// thresholds that raise capacity alerts
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CapacityConfig {
+    pub enabled: bool,
+    /// Seconds between readings of the directory
+    pub interval_secs: u64,
+    /// Alert when a tier has used this much of its ASN range
+    pub range_used_percent: f64,
+    /// Alert when there are more Edge nodes than this per Regional
+    pub max_edges_per_regional: f64,
+    /// Alert when there are more Regionals than this per Backbone
+    pub max_regionals_per_backbone: f64,
+    /// Alert when one location holds this much of the nodes stating one
+    pub max_location_percent: f64,
+    /// Fewer nodes stating a location than this are not judged for
+    /// concentration
+    pub concentration_min_nodes: usize,
+    /// Run with each alert raised or cleared as JSON on stdin, retried
+    /// under `network.retry.hooks`
+    pub alert_command: Vec<String>,
+}
+
+impl Default for CapacityConfig {
+    fn default() -> Self {
+        CapacityConfig {
+            enabled: true,
+            interval_secs: 300,
+            range_used_percent: 80.0,
+            max_edges_per_regional: 100.0,
+            max_regionals_per_backbone: 50.0,
+            max_location_percent: 50.0,
+            concentration_min_nodes: 10,
+            alert_command: Vec::new(),
+        }
+    }
+}
+
+/// What the node needs before `/readyz` and `vx0net status --ready` call it
+/// ready for traffic; liveness is only that the process answers
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ReadinessConfig {
+    /// Seconds between checks
+    pub interval_secs: u64,
+    /// Established BGP sessions, internal and external, needed
+    pub min_established_peers: usize,
+    /// On Edge nodes, a VX0 default route learned from a parent
+    pub require_default_route: bool,
+    /// Every subsystem the daemon starts has reported in
+    pub require_subsystems: bool,
+    /// The wall clock is not behind the time this binary was built
+    pub require_sane_clock: bool,
+    pub require_out_of_maintenance: bool,
+    /// Not still in hold-down after joining
+    pub require_hold_down_over: bool,
+    /// Hold back READY=1 to systemd until the node is first ready, rather
+    /// than sending it once its subsystems have started
+    pub gate_sd_notify: bool,
+    /// Run with each change between ready and not ready as JSON on stdin,
+    /// retried under `network.retry.hooks`
+    pub transition_command: Vec<String>,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        ReadinessConfig {
+            interval_secs: 5,
+            min_established_peers: 1,
+            require_default_route: true,
+            require_subsystems: true,
+            require_sane_clock: true,
+            require_out_of_maintenance: true,
+            require_hold_down_over: true,
+            gate_sd_notify: false,
+            transition_command: Vec::new(),
+        }
+    }
+}
+
+/// Local time-series recording of peer and tunnel stats
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RecorderConfig {
+    pub enabled: bool,
+    pub path: String,
+    pub interval_secs: u64,
+    /// Once the file reaches this size the oldest records are overwritten
+    pub max_size_bytes: ByteSize,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            enabled: false,
+            path: "/var/lib/vx0net/stats.ring".to_string(),
+            interval_secs: 10,
+            max_size_bytes: ByteSize::mib(16),
+        }
+    }
+}
+
+/// Capture of inbound messages and control commands, for `vx0net replay`
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CaptureConfig {
+    pub enabled: bool,
+    /// Each start writes a new file here, named by when it started
+    pub directory: String,
+    /// Capture stops once a file reaches this size
+    pub max_size_bytes: ByteSize,
+    /// Also record what arrives over tunnels, after decryption
+    pub tunnel_payloads: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            enabled: false,
+            directory: "/var/lib/vx0net/captures".to_string(),
+            max_size_bytes: ByteSize::mib(64),
+            tunnel_payloads: false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -103,25 +1499,182 @@ pub struct BootstrapConfig {
     pub nodes: Vec<BootstrapNode>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct BootstrapNode {
     pub hostname: String,
     pub ip: String,
     pub asn: u32,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct JoiningConfig {
+    /// Treat the node as admitted when no entry point answers the join
+    /// request, keeping the network open while peers lack a join endpoint
+    pub allow_unverified_join: bool,
+    pub connect_timeout_secs: u64,
+    /// Entry points that must give consistent answers before their view of
+    /// the network is trusted
+    pub quorum: usize,
+    /// Best-ranked entry points asked to admit us
+    pub join_fanout: usize,
+    /// How far, in percent, node counts may differ between answers that
+    /// still agree
+    pub stats_tolerance_pct: u32,
+    pub bootstrap_health: BootstrapHealthConfig,
+    pub registry: AsnRegistryConfig,
+}
+
+impl Default for JoiningConfig {
+    fn default() -> Self {
+        JoiningConfig {
+            allow_unverified_join: true,
+            connect_timeout_secs: 5,
+            quorum: 2,
+            join_fanout: 5,
+            stats_tolerance_pct: 20,
+            bootstrap_health: BootstrapHealthConfig::default(),
+            registry: AsnRegistryConfig::default(),
+        }
+    }
+}
+
+/// The cooperative ASN registry: Backbone nodes that enable it hand out
+/// signed ASN grants, and joiners ask for one before picking an ASN
+/// themselves
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AsnRegistryConfig {
+    /// Serve grants and replicate the assignment log; Backbone nodes only
+    pub enabled: bool,
+    pub listen_port: u16,
+    /// Other registries to replicate with ("ip:port"), besides Backbone
+    /// peers on the same port
+    pub replicas: Vec<String>,
+    pub replication_interval: ConfigDuration,
+    /// Hex Ed25519 public keys of the registries whose grants this node
+    /// honours, beyond its own
+    pub trusted_keys: Vec<String>,
+    /// Ask a registry for a grant while joining, before self-assigning
+    pub request_grant: bool,
+}
+
+impl Default for AsnRegistryConfig {
+    fn default() -> Self {
+        AsnRegistryConfig {
+            enabled: false,
+            listen_port: 5356,
+            replicas: Vec::new(),
+            replication_interval: ConfigDuration::from_secs(60),
+            trusted_keys: Vec::new(),
+            request_grant: true,
+        }
+    }
+}
+
+/// How bootstrap nodes are scored, and when failing ones are set aside
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BootstrapHealthConfig {
+    /// Where scores are kept across restarts
+    pub state_file: Option<String>,
+    /// Nodes failing for longer than this are skipped
+    pub quarantine_secs: u64,
+    /// How often the other nodes are probed
+    pub probe_interval_secs: u64,
+    /// How often skipped nodes are probed, so they can recover
+    pub reprobe_interval_secs: u64,
+}
+
+impl Default for BootstrapHealthConfig {
+    fn default() -> Self {
+        BootstrapHealthConfig {
+            state_file: Some("/var/lib/vx0net/bootstrap-health.json".to_string()),
+            quarantine_secs: 24 * 3600,
+            probe_interval_secs: 600,
+            reprobe_interval_secs: 6 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Unix socket, created with mode 0600
+    pub socket_path: String,
+    /// Optional TCP fallback (e.g. "127.0.0.1:7179"); requires `token_file`
+    pub tcp_listen: Option<String>,
+    /// Token TCP clients must present; must not be readable by group/others
+    pub token_file: String,
+    pub max_sessions: usize,
+    pub command_timeout_secs: u64,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            socket_path: crate::control::DEFAULT_SOCKET_PATH.to_string(),
+            tcp_listen: None,
+            token_file: "/etc/vx0net/control.token".to_string(),
+            max_sessions: 16,
+            command_timeout_secs: 10,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PSKConfig {
     pub default: String,
 }
 
+const LOCAL_FILE_NAME: &str = "vx0net.toml";
+
+/// `vx0net.toml` in the directory the daemon was started from
+static LOCAL_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Resolve `vx0net.toml` against the current directory for every later
+/// load, so reloads still find it after the daemon changes directory
+pub fn pin_local_file() {
+    if let Ok(dir) = std::env::current_dir() {
+        let _ = LOCAL_FILE.set(dir.join(LOCAL_FILE_NAME));
+    }
+}
+
 impl Vx0Config {
     pub fn load() -> Result<Self, ConfigError> {
-        let config = Config::builder()
-            .add_source(File::with_name("vx0net.toml").required(false))
-            .add_source(File::with_name("/etc/vx0net/config.toml").required(false))
-            .add_source(Environment::with_prefix("VX0NET"))
-            .set_default("node.hostname", "vx0-node")?
+        let local = LOCAL_FILE
+            .get()
+            .cloned()
+            .unwrap_or_else(|| PathBuf::from(LOCAL_FILE_NAME));
+        Self::load_layered(vec![
+            Box::new(File::from(local).required(false)),
+            Box::new(File::with_name("/etc/vx0net/config.toml").required(false)),
+            Box::new(Environment::with_prefix("VX0NET")),
+        ])
+    }
+
+    /// `path` alone, over the profile it names and the built-in defaults,
+    /// checked as at startup
+    pub fn load_file(path: &str) -> Result<Self, ConfigError> {
+        Self::load_layered(vec![Box::new(
+            File::from(PathBuf::from(path)).required(true),
+        )])
+    }
+
+    /// Builds the config from `sources` over the profile they name, if any,
+    /// over the built-in defaults
+    fn load_layered(sources: Vec<Box<dyn Source + Send + Sync>>) -> Result<Self, ConfigError> {
+        let profile: Option<String> = Config::builder()
+            .add_source(sources.clone())
+            .build()?
+            .get::<Option<String>>("profile")
+            .or_else(|e| match e {
+                ConfigError::NotFound(_) => Ok(None),
+                e => Err(e),
+            })?;
+
+        let mut builder = Config::builder()
+            .set_default("node.hostname", hostname::DEFAULT_HOSTNAME)?
             .set_default("node.asn", 65001)?
             .set_default("node.tier", "Edge")?
             .set_default("node.location", "Unknown")?
@@ -162,10 +1715,23 @@ impl Vx0Config {
             .set_default("services.service_ttl", 300)?
             .set_default("monitoring.enable_metrics", true)?
             .set_default("monitoring.metrics_port", 9090)?
-            .set_default("monitoring.log_level", "info")?
-            .build()?;
+            .set_default("monitoring.log_level", "info")?;
+        if let Some(name) = &profile {
+            builder = builder.add_source(profiles::source(name)?);
+        }
+        let config = builder.add_source(sources).build()?;
 
-        config.try_deserialize()
+        let config: Vx0Config = config.try_deserialize()?;
+        config
+            .check_hostname()
+            .map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+        config
+            .check_listeners()
+            .map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+        config
+            .check_units()
+            .map_err(|e| ConfigError::Foreign(Box::new(e)))?;
+        Ok(config)
     }
 
     pub fn save(&self, path: &str) -> Result<(), std::io::Error> {
@@ -175,6 +1741,15 @@ impl Vx0Config {
         Ok(())
     }
 
+    /// UDP port IKE listens on; the BGP port in single-port mode
+    pub fn ike_listen_port(&self) -> u16 {
+        if self.network.single_port.enabled {
+            self.network.bgp.listen_port
+        } else {
+            self.security.ike.listen_port
+        }
+    }
+
     pub fn get_ipv4_addr(&self) -> Result<Ipv4Addr, std::net::AddrParseError> {
         self.node.ipv4_address.parse()
     }
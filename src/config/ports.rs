@@ -0,0 +1,445 @@
+//! Pre-flight check of everything the daemon is going to listen on.
+//!
+//! A port shared by two subsystems, or one another process already holds,
+//! otherwise shows up as a bare "address in use" from whichever subsystem
+//! binds second. Collecting every planned listener up front lets all the
+//! conflicts be reported at once, named by the config keys that cause them.
+
+use crate::config::Vx0Config;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Socket(Transport, SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Whether binding both would fail: same transport and port on the same
+    /// or a wildcard address, or the same socket path
+    fn overlaps(&self, other: &Endpoint) -> bool {
+        match (self, other) {
+            (Endpoint::Socket(a_transport, a), Endpoint::Socket(b_transport, b)) => {
+                a_transport == b_transport
+                    && a.port() == b.port()
+                    && a.port() != 0
+                    && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+            }
+            (Endpoint::Unix(a), Endpoint::Unix(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Socket(Transport::Tcp, addr) => write!(f, "TCP {}", addr),
+            Endpoint::Socket(Transport::Udp, addr) => write!(f, "UDP {}", addr),
+            Endpoint::Unix(path) => write!(f, "unix socket {}", path.display()),
+        }
+    }
+}
+
+/// Something the daemon binds, and the config key that places it there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub key: &'static str,
+    pub endpoint: Endpoint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conflict {
+    /// Two config keys put listeners in the same place
+    Duplicate {
+        key: &'static str,
+        other: &'static str,
+        endpoint: Endpoint,
+    },
+    /// Another process already listens there
+    InUse {
+        key: &'static str,
+        endpoint: Endpoint,
+        /// Name and PID of the holder, when the OS exposes it
+        holder: Option<String>,
+    },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::Duplicate {
+                key,
+                other,
+                endpoint,
+            } => write!(f, "{} and {} both use {}", other, key, endpoint),
+            Conflict::InUse {
+                key,
+                endpoint,
+                holder,
+            } => {
+                write!(f, "{} ({}) is already in use", key, endpoint)?;
+                if let Some(holder) = holder {
+                    write!(f, " by {}", holder)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Cannot listen as configured: {}", .0.iter().map(|c| c.to_string()).collect::<Vec<_>>().join("; "))]
+pub struct ListenerConflicts(pub Vec<Conflict>);
+
+/// Every listener the configured feature set binds
+pub fn planned_listeners(config: &Vx0Config) -> Vec<Listener> {
+    let any = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+    let tcp = |key, port| Listener {
+        key,
+        endpoint: Endpoint::Socket(Transport::Tcp, any(port)),
+    };
+    let udp = |key, port| Listener {
+        key,
+        endpoint: Endpoint::Socket(Transport::Udp, any(port)),
+    };
+
+    let dns = config.network.dns.listen_port;
+    let mut listeners = vec![
+        tcp("network.bgp.listen_port", config.network.bgp.listen_port),
+        udp(
+            if config.network.single_port.enabled {
+                "network.single_port"
+            } else {
+                "security.ike.listen_port"
+            },
+            config.ike_listen_port(),
+        ),
+    ];
+    if cfg!(feature = "dns-server") && config.network.dns.local_server {
+        listeners.push(udp("network.dns.listen_port", dns));
+        listeners.push(tcp("network.dns.listen_port", dns));
+    }
+    listeners.push(tcp("network.dns.sync_port", config.network.dns.sync_port));
+    if cfg!(feature = "discovery") && config.services.enable_discovery {
+        // Announcements arrive over UDP, join requests over TCP
+        let discovery = config.services.discovery_port;
+        listeners.push(udp("services.discovery_port", discovery));
+        listeners.push(tcp("services.discovery_port", discovery));
+    }
+    if config.joining.registry.enabled {
+        listeners.push(tcp(
+            "joining.registry.listen_port",
+            config.joining.registry.listen_port,
+        ));
+    }
+    if config.node.redundancy.group.is_some() {
+        listeners.push(tcp(
+            "node.redundancy.listen_port",
+            config.node.redundancy.listen_port,
+        ));
+    }
+    if config.services.gateway.enabled {
+        if let Ok(ip) = config.get_ipv4_addr() {
+            listeners.push(Listener {
+                key: "services.gateway.listen_port",
+                endpoint: Endpoint::Socket(
+                    Transport::Tcp,
+                    SocketAddr::new(ip.into(), config.services.gateway.listen_port),
+                ),
+            });
+        }
+    }
+    if cfg!(feature = "metrics") && config.monitoring.enable_metrics {
+        listeners.push(tcp(
+            "monitoring.metrics_port",
+            config.monitoring.metrics_port,
+        ));
+    }
+    listeners.push(Listener {
+        key: "control.socket_path",
+        endpoint: Endpoint::Unix(PathBuf::from(&config.control.socket_path)),
+    });
+    // A malformed address is the control server's to report
+    if let Some(Ok(addr)) = config.control.tcp_listen.as_deref().map(str::parse) {
+        listeners.push(Listener {
+            key: "control.tcp_listen",
+            endpoint: Endpoint::Socket(Transport::Tcp, addr),
+        });
+    }
+    listeners
+}
+
+/// Listeners the config itself places on top of each other
+pub fn duplicates(listeners: &[Listener]) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+    for (i, listener) in listeners.iter().enumerate() {
+        let earlier = listeners[..i]
+            .iter()
+            .find(|earlier| earlier.endpoint.overlaps(&listener.endpoint));
+        if let Some(earlier) = earlier {
+            conflicts.push(Conflict::Duplicate {
+                key: listener.key,
+                other: earlier.key,
+                endpoint: listener.endpoint.clone(),
+            });
+        }
+    }
+    conflicts
+}
+
+/// Test-bind each listener to find ones another process holds
+///
+/// A listener overlapping an earlier one in the same list is not bound
+/// again: the daemon binds that spot itself, so the result would only
+/// reflect this check racing itself. `duplicates` reports it instead.
+pub fn in_use(listeners: &[Listener]) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+    for (i, listener) in listeners.iter().enumerate() {
+        if listeners[..i]
+            .iter()
+            .any(|earlier| earlier.endpoint.overlaps(&listener.endpoint))
+        {
+            continue;
+        }
+        if let Some(holder) = probe(&listener.endpoint) {
+            conflicts.push(Conflict::InUse {
+                key: listener.key,
+                endpoint: listener.endpoint.clone(),
+                holder,
+            });
+        }
+    }
+    conflicts
+}
+
+/// `Some` when the endpoint is taken, with its holder if known
+fn probe(endpoint: &Endpoint) -> Option<Option<String>> {
+    let (transport, addr) = match endpoint {
+        Endpoint::Unix(path) => {
+            // A stale socket file is removed at startup; a live one answers
+            return std::os::unix::net::UnixStream::connect(path)
+                .is_ok()
+                .then_some(None);
+        }
+        Endpoint::Socket(_, addr) if addr.port() == 0 => return None,
+        Endpoint::Socket(transport, addr) => (*transport, *addr),
+    };
+
+    let result = match transport {
+        Transport::Tcp => TcpListener::bind(addr).map(drop),
+        Transport::Udp => UdpSocket::bind(addr).map(drop),
+    };
+    match result {
+        Ok(()) => None,
+        Err(e) if e.kind() == ErrorKind::AddrInUse => Some(holder(transport, addr.port())),
+        // Privileged ports or an address not on this host fail the real bind
+        // with a clearer error than a conflict would give
+        Err(e) => {
+            tracing::debug!("Not checking {} for conflicts: {}", endpoint, e);
+            None
+        }
+    }
+}
+
+/// The process listening on a local port, as "name (pid N)"
+#[cfg(target_os = "linux")]
+fn holder(transport: Transport, port: u16) -> Option<String> {
+    let tables: &[&str] = match transport {
+        Transport::Tcp => &["/proc/net/tcp", "/proc/net/tcp6"],
+        Transport::Udp => &["/proc/net/udp", "/proc/net/udp6"],
+    };
+    let mut inodes = Vec::new();
+    for table in tables {
+        let Ok(contents) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(local), Some(state), Some(inode)) =
+                (fields.get(1), fields.get(3), fields.get(9))
+            else {
+                continue;
+            };
+            let local_port = local
+                .rsplit(':')
+                .next()
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok());
+            // 0A is LISTEN; UDP sockets have no listening state
+            if local_port == Some(port) && (transport == Transport::Udp || *state == "0A") {
+                inodes.push(format!("socket:[{}]", inode));
+            }
+        }
+    }
+    if inodes.is_empty() {
+        return None;
+    }
+
+    // Other users' descriptors are unreadable without privileges
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let Ok(pid) = process.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        let Ok(fds) = std::fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = std::fs::read_link(fd.path()) else {
+                continue;
+            };
+            if inodes
+                .iter()
+                .any(|inode| target.as_os_str() == inode.as_str())
+            {
+                let name = std::fs::read_to_string(process.path().join("comm"))
+                    .map(|comm| comm.trim().to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                return Some(format!("{} (pid {})", name, pid));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn holder(_transport: Transport, _port: u16) -> Option<String> {
+    None
+}
+
+/// Every conflict in the config and with other processes, reported together
+pub fn preflight(config: &Vx0Config) -> Result<(), ListenerConflicts> {
+    let listeners = planned_listeners(config);
+    let mut conflicts = duplicates(&listeners);
+    conflicts.extend(in_use(&listeners));
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(ListenerConflicts(conflicts))
+    }
+}
+
+impl Vx0Config {
+    /// Listener collisions within the config, without binding anything
+    pub fn check_listeners(&self) -> Result<(), ListenerConflicts> {
+        let conflicts = duplicates(&planned_listeners(self));
+        if conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(ListenerConflicts(conflicts))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    fn config() -> Vx0Config {
+        testing::config(NodeTier::Edge)
+    }
+
+    #[test]
+    #[cfg(all(feature = "dns-server", feature = "discovery", feature = "metrics"))]
+    fn test_config_collisions_name_both_keys() {
+        let mut config = config();
+        assert_eq!(config.check_listeners(), Ok(()));
+
+        config.monitoring.metrics_port = config.services.discovery_port;
+        config.network.dns.sync_port = config.network.bgp.listen_port;
+        config.control.tcp_listen = Some("127.0.0.1:5353".to_string());
+        let error = config.check_listeners().unwrap_err();
+        assert_eq!(
+            error.0,
+            vec![
+                Conflict::Duplicate {
+                    key: "network.dns.sync_port",
+                    other: "network.bgp.listen_port",
+                    endpoint: Endpoint::Socket(Transport::Tcp, "0.0.0.0:1179".parse().unwrap()),
+                },
+                Conflict::Duplicate {
+                    key: "monitoring.metrics_port",
+                    other: "services.discovery_port",
+                    endpoint: Endpoint::Socket(Transport::Tcp, "0.0.0.0:8080".parse().unwrap()),
+                },
+                Conflict::Duplicate {
+                    key: "control.tcp_listen",
+                    other: "network.dns.listen_port",
+                    endpoint: Endpoint::Socket(Transport::Tcp, "127.0.0.1:5353".parse().unwrap()),
+                },
+            ]
+        );
+        assert_eq!(
+            error.to_string(),
+            "Cannot listen as configured: \
+             network.bgp.listen_port and network.dns.sync_port both use TCP 0.0.0.0:1179; \
+             services.discovery_port and monitoring.metrics_port both use TCP 0.0.0.0:8080; \
+             network.dns.listen_port and control.tcp_listen both use TCP 127.0.0.1:5353"
+        );
+
+        // The same port over different transports is fine
+        config = self::config();
+        config.security.ike.listen_port = config.monitoring.metrics_port;
+        assert_eq!(config.check_listeners(), Ok(()));
+
+        // Single-port mode moves IKE onto the BGP port number
+        config.network.single_port.enabled = true;
+        assert_eq!(config.check_listeners(), Ok(()));
+        let port = config.network.bgp.listen_port;
+        assert!(planned_listeners(&config).iter().any(|listener| {
+            listener.key == "network.single_port"
+                && listener.endpoint
+                    == Endpoint::Socket(Transport::Udp, ([0, 0, 0, 0], port).into())
+        }));
+    }
+
+    #[test]
+    fn test_port_held_by_another_process_is_reported() {
+        let occupied = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = occupied.local_addr().unwrap().port();
+        let mut config = config();
+        config.network.dns.sync_port = port;
+
+        let conflicts = preflight(&config).unwrap_err().0;
+        let reported = conflicts
+            .iter()
+            .find_map(|conflict| match conflict {
+                Conflict::InUse { key, holder, .. } if *key == "network.dns.sync_port" => {
+                    Some(holder.clone())
+                }
+                _ => None,
+            })
+            .expect("sync port reported in use");
+        if cfg!(target_os = "linux") {
+            let pid = format!("pid {}", std::process::id());
+            assert!(reported.is_some_and(|holder| holder.ends_with(&format!("({})", pid))));
+        }
+
+        drop(occupied);
+        assert!(!in_use(&planned_listeners(&config))
+            .iter()
+            .any(|conflict| matches!(conflict, Conflict::InUse { key, .. } if *key == "network.dns.sync_port")));
+    }
+}
@@ -0,0 +1,147 @@
+//! Starting configurations for each tier, embedded in the binary. A config
+//! file naming one with `profile = "regional"` gets that profile's values
+//! wherever it sets nothing itself.
+
+use super::Vx0Config;
+use config::{ConfigError, File, FileFormat};
+
+/// Profiles in the order `vx0net init --help` lists them
+pub const NAMES: &[&str] = &["edge", "regional", "backbone", "lab"];
+
+const EDGE: &str = include_str!("../../config/profiles/edge.toml");
+const REGIONAL: &str = include_str!("../../config/profiles/regional.toml");
+const BACKBONE: &str = include_str!("../../config/profiles/backbone.toml");
+const LAB: &str = include_str!("../../config/profiles/lab.toml");
+
+/// Few paths, quiet timers, minimal discovery announcements, no metrics
+pub fn edge() -> Vx0Config {
+    parse(EDGE)
+}
+
+/// Serves DNS, originates the default toward Edge peers and polices what
+/// they announce
+pub fn regional() -> Vx0Config {
+    parse(REGIONAL)
+}
+
+/// Wide multipath, large caches and quotas, no LAN discovery
+pub fn backbone() -> Vx0Config {
+    parse(BACKBONE)
+}
+
+/// Loopback addresses, unprivileged ports, short timers and state under
+/// /tmp, for running nodes locally without root
+pub fn lab() -> Vx0Config {
+    parse(LAB)
+}
+
+pub fn named(name: &str) -> Result<Vx0Config, ConfigError> {
+    toml_for(name).map(parse)
+}
+
+/// The profile as a config source, for layering files and environment on top
+pub(crate) fn source(
+    name: &str,
+) -> Result<File<config::FileSourceString, FileFormat>, ConfigError> {
+    toml_for(name).map(|toml| File::from_str(toml, FileFormat::Toml))
+}
+
+fn toml_for(name: &str) -> Result<&'static str, ConfigError> {
+    match name.to_ascii_lowercase().as_str() {
+        "edge" => Ok(EDGE),
+        "regional" => Ok(REGIONAL),
+        "backbone" => Ok(BACKBONE),
+        "lab" => Ok(LAB),
+        _ => Err(ConfigError::Message(format!(
+            "unknown profile {name:?}; expected one of {}",
+            NAMES.join(", ")
+        ))),
+    }
+}
+
+fn parse(toml: &str) -> Vx0Config {
+    toml::from_str(toml).expect("embedded profile is a complete config")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ports::{planned_listeners, Endpoint};
+    use crate::config::units::ConfigDuration;
+    use crate::node::Vx0Node;
+
+    fn load(file: &str) -> Vx0Config {
+        Vx0Config::load_layered(vec![Box::new(File::from_str(file, FileFormat::Toml))]).unwrap()
+    }
+
+    #[test]
+    fn test_every_profile_passes_validation() {
+        for name in NAMES {
+            let config = named(name).unwrap();
+            // `vx0net init` writes it out; it must read back the same
+            let saved = toml::to_string_pretty(&config).unwrap();
+            let config: Vx0Config = toml::from_str(&saved).unwrap();
+            config.check_hostname().unwrap();
+            config
+                .check_listeners()
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            config
+                .check_units()
+                .unwrap_or_else(|e| panic!("{name}: {e}"));
+            assert_eq!(config.unit_warnings(), vec![], "{name}");
+            Vx0Node::new(config).unwrap_or_else(|e| panic!("{name}: {e}"));
+        }
+    }
+
+    #[test]
+    fn test_profile_tiers_match_their_names() {
+        assert_eq!(edge().node.tier, "Edge");
+        assert_eq!(regional().node.tier, "Regional");
+        assert_eq!(backbone().node.tier, "Backbone");
+        assert!(named("core").is_err());
+    }
+
+    #[test]
+    fn test_lab_needs_no_privileged_ports() {
+        for listener in planned_listeners(&lab()) {
+            if let Endpoint::Socket(_, addr) = listener.endpoint {
+                assert!(addr.port() >= 1024, "{} is privileged", listener.key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_beats_profile_beats_defaults() {
+        let config = load(
+            r#"
+            profile = "regional"
+            [network.bgp]
+            hold_time = 45
+            "#,
+        );
+        // Set in the file
+        assert_eq!(config.network.bgp.hold_time, ConfigDuration::from_secs(45));
+        // Left to the profile, which overrides the built-in Edge-ish defaults
+        assert_eq!(config.node.asn, 65101);
+        assert_eq!(config.node.tier, "Regional");
+        assert_eq!(config.network.dns.cache_size, 10000);
+        assert!(config.node.capabilities.serves_dns);
+
+        let config = load("[network.bgp]\nhold_time = 45\n");
+        assert_eq!(config.network.bgp.hold_time, ConfigDuration::from_secs(45));
+        assert_eq!(config.node.asn, 65001);
+        assert_eq!(config.node.tier, "Edge");
+        assert_eq!(config.network.dns.cache_size, 1000);
+        assert_eq!(config.profile, None);
+    }
+
+    #[test]
+    fn test_unknown_profile_fails_to_load() {
+        let err = Vx0Config::load_layered(vec![Box::new(File::from_str(
+            "profile = \"core\"",
+            FileFormat::Toml,
+        ))])
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown profile"));
+    }
+}
@@ -0,0 +1,419 @@
+//! Applying a changed configuration to a running daemon.
+//!
+//! Only some settings can change under a running daemon; the rest take
+//! effect at the next restart. Every changed key is reported as applied,
+//! deferred to restart, or invalid (the subsystem refused the new value and
+//! keeps the old one), so nothing is applied silently or partially.
+
+use crate::config::diff::{self, DiffError, FieldChange};
+use crate::config::{DiscoveryPrivacy, Vx0Config};
+use crate::error::Report;
+use crate::monitoring;
+use crate::network::bgp::BGPDaemon;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+/// Subsystems whose settings a running daemon picks up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HotSection {
+    DiscoveryPrivacy,
+    ImportPolicy,
+    CommunityPolicies,
+    ExportPolicies,
+    Peering,
+    HoldDown,
+    RejectionJournal,
+    PropagationTracing,
+    Quarantine,
+    LogLevel,
+}
+
+const HOT_PATHS: &[(&str, HotSection)] = &[
+    ("services.discovery_privacy", HotSection::DiscoveryPrivacy),
+    ("network.bgp.import_policy", HotSection::ImportPolicy),
+    (
+        "network.bgp.community_policies",
+        HotSection::CommunityPolicies,
+    ),
+    ("network.bgp.export_policies", HotSection::ExportPolicies),
+    ("network.peering", HotSection::Peering),
+    ("network.hold_down", HotSection::HoldDown),
+    (
+        "network.bgp.rejection_journal",
+        HotSection::RejectionJournal,
+    ),
+    (
+        "network.bgp.trace_propagation",
+        HotSection::PropagationTracing,
+    ),
+    ("network.quarantine", HotSection::Quarantine),
+    ("monitoring.log_level", HotSection::LogLevel),
+];
+
+fn hot_section(path: &str) -> Option<HotSection> {
+    HOT_PATHS
+        .iter()
+        .find(|(prefix, _)| diff::path_within(path, prefix))
+        .map(|(_, section)| *section)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ReloadAction {
+    Applied,
+    RequiresRestart,
+    /// Refused by the subsystem; the old value stays in effect
+    Invalid {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ReloadChange {
+    #[serde(flatten)]
+    pub change: FieldChange,
+    #[serde(flatten)]
+    pub action: ReloadAction,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ReloadReport {
+    pub changes: Vec<ReloadChange>,
+}
+
+impl ReloadReport {
+    fn paths(&self, matches: impl Fn(&ReloadAction) -> bool) -> Vec<&str> {
+        self.changes
+            .iter()
+            .filter(|change| matches(&change.action))
+            .map(|change| change.change.path.as_str())
+            .collect()
+    }
+
+    pub fn applied(&self) -> Vec<&str> {
+        self.paths(|action| *action == ReloadAction::Applied)
+    }
+
+    pub fn requires_restart(&self) -> Vec<&str> {
+        self.paths(|action| *action == ReloadAction::RequiresRestart)
+    }
+
+    pub fn invalid(&self) -> Vec<&str> {
+        self.paths(|action| matches!(action, ReloadAction::Invalid { .. }))
+    }
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return writeln!(f, "No configuration changes");
+        }
+        for ReloadChange { change, action } in &self.changes {
+            match action {
+                ReloadAction::Applied => writeln!(f, "applied          {}", change)?,
+                ReloadAction::RequiresRestart => writeln!(f, "requires restart {}", change)?,
+                ReloadAction::Invalid { reason } => {
+                    writeln!(f, "invalid          {} ({})", change, reason)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Holds the configuration in effect and applies reloaded ones on top
+pub struct Reloader {
+    /// What is actually in effect: the startup configuration plus every
+    /// change applied since
+    current: Mutex<Vx0Config>,
+    bgp: Arc<BGPDaemon>,
+    discovery_privacy: watch::Sender<DiscoveryPrivacy>,
+}
+
+impl Reloader {
+    pub fn new(
+        config: Vx0Config,
+        bgp: Arc<BGPDaemon>,
+        discovery_privacy: watch::Sender<DiscoveryPrivacy>,
+    ) -> Self {
+        Reloader {
+            current: Mutex::new(config),
+            bgp,
+            discovery_privacy,
+        }
+    }
+
+    pub async fn current(&self) -> Vx0Config {
+        self.current.lock().await.clone()
+    }
+
+    /// Apply what can change at runtime from `reloaded`, reporting every
+    /// changed key. Keys deferred to restart are reported again on each
+    /// reload until then.
+    pub async fn reload(&self, reloaded: &Vx0Config) -> Result<ReloadReport, DiffError> {
+        let mut current = self.current.lock().await;
+        let changes = diff::diff(&*current, reloaded)?;
+
+        let target = serde_json::to_value(reloaded)?;
+        let mut sections: Vec<HotSection> = Vec::new();
+        let mut effective = serde_json::to_value(&*current)?;
+        for change in &changes {
+            if let Some(section) = hot_section(&change.path) {
+                diff::copy_path(&mut effective, &target, &change.path);
+                if !sections.contains(&section) {
+                    sections.push(section);
+                }
+            }
+        }
+        let effective: Vx0Config = serde_json::from_value(effective)?;
+
+        let mut refused: Vec<(HotSection, String)> = Vec::new();
+        for section in sections {
+            if let Err(reason) = self.apply(section, &effective).await {
+                refused.push((section, reason));
+            }
+        }
+
+        let mut report = ReloadReport::default();
+        let mut in_effect = serde_json::to_value(&*current)?;
+        for change in changes {
+            let action = match hot_section(&change.path) {
+                None => ReloadAction::RequiresRestart,
+                Some(section) => match refused.iter().find(|(refused, _)| *refused == section) {
+                    Some((_, reason)) => ReloadAction::Invalid {
+                        reason: reason.clone(),
+                    },
+                    None => {
+                        diff::copy_path(&mut in_effect, &target, &change.path);
+                        ReloadAction::Applied
+                    }
+                },
+            };
+            report.changes.push(ReloadChange { change, action });
+        }
+        *current = serde_json::from_value(in_effect)?;
+
+        log(&report);
+        Ok(report)
+    }
+
+    async fn apply(&self, section: HotSection, config: &Vx0Config) -> Result<(), String> {
+        match section {
+            HotSection::DiscoveryPrivacy => {
+                self.discovery_privacy
+                    .send_replace(config.services.discovery_privacy);
+            }
+            HotSection::ImportPolicy => {
+                check_rule_names(config)?;
+                self.bgp
+                    .set_import_rules(&config.network.bgp.policy_fragment())
+                    .await
+                    .map_err(|e| Report(&e).to_string())?
+            }
+            HotSection::CommunityPolicies => self
+                .bgp
+                .set_community_policies(config.network.bgp.community_policies.clone())
+                .await
+                .map_err(|e| Report(&e).to_string())?,
+            HotSection::ExportPolicies => {
+                self.bgp
+                    .set_export_policies(
+                        config.network.bgp.export_policies.clone(),
+                        config.network.bgp.peer_groups(),
+                    )
+                    .await
+                    .map_err(|e| Report(&e).to_string())?;
+            }
+            HotSection::Peering => self
+                .bgp
+                .set_peering(
+                    config.network.peering.clone(),
+                    config.network.routing.local_preference,
+                )
+                .await
+                .map_err(|e| Report(&e).to_string())?,
+            HotSection::HoldDown => {
+                self.bgp
+                    .set_hold_down(config.network.hold_down.clone())
+                    .await
+            }
+            HotSection::RejectionJournal => {
+                self.bgp
+                    .set_rejection_journal(config.network.bgp.rejection_journal.clone())
+                    .await
+            }
+            HotSection::PropagationTracing => {
+                self.bgp
+                    .set_propagation_tracing(config.network.bgp.trace_propagation)
+                    .await
+            }
+            HotSection::Quarantine => {
+                self.bgp
+                    .set_quarantine_config(config.network.quarantine.clone())
+                    .await
+            }
+            HotSection::LogLevel => monitoring::set_log_level(&config.monitoring.log_level)?,
+        }
+        Ok(())
+    }
+}
+
+/// Verdicts and explanations name the rule that decided them, so a name
+/// must identify one rule
+fn check_rule_names(config: &Vx0Config) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    for rule in &config.network.bgp.import_policy {
+        if rule.name.is_empty() {
+            return Err("import rule without a name".to_string());
+        }
+        if !seen.insert(rule.name.as_str()) {
+            return Err(format!("import rule name {:?} used twice", rule.name));
+        }
+    }
+    Ok(())
+}
+
+fn log(report: &ReloadReport) {
+    if report.changes.is_empty() {
+        tracing::info!("Configuration reloaded; nothing changed");
+        return;
+    }
+    tracing::info!(
+        "Configuration reloaded: {} applied, {} requiring restart, {} invalid",
+        report.applied().len(),
+        report.requires_restart().len(),
+        report.invalid().len()
+    );
+    for ReloadChange { change, action } in &report.changes {
+        let action = match action {
+            ReloadAction::Applied => "applied".to_string(),
+            ReloadAction::RequiresRestart => "requires restart".to_string(),
+            ReloadAction::Invalid { reason } => format!("invalid: {}", reason),
+        };
+        tracing::info!("  {} [{}]", change, action);
+        tracing::info!(target: "audit", "Config reload: {} [{}]", change, action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::diff::Delta;
+    use crate::config::PSKConfig;
+    use crate::network::bgp::policy::{PolicyRule, RouteMatch, RuleAction};
+    use crate::network::bgp::RouteEntry;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    fn rule(name: &str, prefix: &str) -> PolicyRule {
+        PolicyRule {
+            name: name.to_string(),
+            matches: RouteMatch {
+                prefix: Some(prefix.parse().unwrap()),
+                ..RouteMatch::default()
+            },
+            action: RuleAction::Reject,
+            set_local_pref: None,
+            set_med: None,
+            add_communities: vec![],
+        }
+    }
+
+    fn route(network: &str, peer_asn: u32) -> RouteEntry {
+        crate::network::bgp::testing::route(network, "10.1.0.1", &[peer_asn])
+    }
+
+    #[tokio::test]
+    async fn test_reload_applies_hot_keys_and_defers_the_rest() {
+        let config = testing::config(NodeTier::Edge);
+        let bgp = Arc::new(BGPDaemon::new(66001, "10.2.0.1".parse().unwrap(), 0));
+        let privacy = watch::Sender::new(config.services.discovery_privacy);
+        let observed = privacy.subscribe();
+        let reloader = Reloader::new(config.clone(), Arc::clone(&bgp), privacy);
+
+        let mut reloaded = config.clone();
+        reloaded.services.discovery_privacy = DiscoveryPrivacy::Silent;
+        reloaded.network.bgp.import_policy = vec![rule("no-lab", "10.99.0.0/16")];
+        reloaded.network.hold_down.duration_secs += 60;
+        reloaded.network.bgp.listen_port += 1;
+        reloaded.node.asn += 1;
+        reloaded.psk = Some(PSKConfig {
+            default: "correct horse battery staple".to_string(),
+        });
+
+        let report = reloader.reload(&reloaded).await.unwrap();
+        assert_eq!(
+            report.applied(),
+            vec![
+                "network.bgp.import_policy",
+                "network.hold_down.duration_secs",
+                "services.discovery_privacy",
+            ]
+        );
+        assert_eq!(
+            report.requires_restart(),
+            vec!["network.bgp.listen_port", "node.asn", "psk"]
+        );
+        assert!(report.invalid().is_empty());
+
+        // The secret is reported as changed without its value
+        let psk = report
+            .changes
+            .iter()
+            .find(|change| change.change.path == "psk")
+            .unwrap();
+        assert_eq!(psk.change.delta, Delta::Redacted);
+        let shown = format!("{}{}", report, serde_json::to_string(&report).unwrap());
+        assert!(!shown.contains("battery"));
+
+        // What was reported applied took effect, and only that
+        assert_eq!(*observed.borrow(), DiscoveryPrivacy::Silent);
+        bgp.receive_update(65101, vec![route("10.99.0.0/16", 65101)], &[])
+            .await
+            .unwrap();
+        assert!(bgp.get_routes().await.is_empty());
+        let current = reloader.current().await;
+        assert_eq!(
+            current.network.hold_down.duration_secs,
+            reloaded.network.hold_down.duration_secs
+        );
+        assert_eq!(
+            current.network.bgp.listen_port,
+            config.network.bgp.listen_port
+        );
+        assert_eq!(current.node.asn, config.node.asn);
+        assert!(current.psk.is_none());
+        let pending = diff::diff(&current, &reloaded).unwrap();
+        let pending: Vec<&str> = pending.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(pending, report.requires_restart());
+
+        // A refused value is reported invalid and the old one stays in effect
+        let mut duplicated = reloaded.clone();
+        duplicated.network.bgp.import_policy = vec![
+            rule("no-lab", "10.98.0.0/16"),
+            rule("no-lab", "10.97.0.0/16"),
+        ];
+        let report = reloader.reload(&duplicated).await.unwrap();
+        assert_eq!(report.invalid(), vec!["network.bgp.import_policy"]);
+        assert_eq!(
+            reloader.current().await.network.bgp.import_policy,
+            reloaded.network.bgp.import_policy
+        );
+        bgp.receive_update(65102, vec![route("10.98.0.0/16", 65102)], &[])
+            .await
+            .unwrap();
+        assert_eq!(bgp.get_routes().await.len(), 1);
+
+        // The log level changes in place, if it is one
+        let mut verbose = reloaded.clone();
+        verbose.monitoring.log_level = "loud".to_string();
+        let report = reloader.reload(&verbose).await.unwrap();
+        assert_eq!(report.invalid(), vec!["monitoring.log_level"]);
+        verbose.monitoring.log_level = "debug".to_string();
+        let report = reloader.reload(&verbose).await.unwrap();
+        assert_eq!(report.applied(), vec!["monitoring.log_level"]);
+    }
+}
@@ -0,0 +1,947 @@
+//! Multi-node topologies declared in one manifest.
+//!
+//! A lab of several nodes needs per-node configs that agree on ASNs,
+//! addresses, peerings and the PSK. A manifest (TOML or YAML) declares the
+//! nodes by name and tier, the peerings between them, the services each
+//! hosts and the PSK they share. An ASN left out is the next free one in the
+//! tier's range, and an address left out is taken from the address plan
+//! the way the reference hierarchy lays it out under the IPv4 supernet:
+//! backbone nodes at x.0.1.n, regional nodes at x.1.n.1 and edge nodes at
+//! x.2.r.n, where r numbers the first regional node the edge node peers
+//! with. The IPv6 address carries the same last three octets in the
+//! supernet's third, fourth and last groups.
+//!
+//! Peerings the tier rules refuse, addresses two nodes share and prefixes
+//! that overlap are rejected before anything is generated.
+//! `vx0net topology generate` writes one config per node and a port map for
+//! docker-compose; [`Topology::boot`] runs the whole topology in-process.
+
+use crate::config::ports::{planned_listeners, Endpoint, Transport};
+use crate::config::{profiles, BGPPeerConfig, PSKConfig, Vx0Config};
+use crate::network::bgp::default_route::{VX0_IPV4_SUPERNET, VX0_IPV6_SUPERNET};
+use crate::network::bgp::Prefix;
+use crate::network::prefix_list::PrefixTrie;
+use crate::node::{
+    HostedService, NodeError, NodeTier, PeerConnection, ServiceStatus, ServiceType, Vx0Node,
+};
+use config::{Config, ConfigError, File, FileFormat};
+use ipnet::IpNet;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Port map file written next to the node configs
+pub const PORT_MAP_FILE: &str = "ports.yml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TopologyError {
+    #[error("Cannot read manifest: {0}")]
+    Manifest(#[from] ConfigError),
+    #[error("Node name {name:?} is used twice")]
+    DuplicateName { name: String },
+    #[error("Peering names unknown node {name:?}")]
+    UnknownNode { name: String },
+    #[error("Node {name} cannot peer with itself")]
+    SelfPeering { name: String },
+    #[error("{a} and {b} are declared as peers twice")]
+    DuplicatePeering { a: String, b: String },
+    #[error("{a} ({a_tier:?}) may not peer with {b} ({b_tier:?})")]
+    TierViolation {
+        a: String,
+        a_tier: NodeTier,
+        b: String,
+        b_tier: NodeTier,
+    },
+    #[error("ASN {asn} of {name} is outside the {tier:?} range {}-{}", range.0, range.1)]
+    AsnOutOfRange {
+        name: String,
+        asn: u32,
+        tier: NodeTier,
+        range: (u32, u32),
+    },
+    #[error("{a} and {b} both have ASN {asn}")]
+    DuplicateAsn { a: String, b: String, asn: u32 },
+    #[error("No {tier:?} ASN is left for {name}")]
+    AsnsExhausted { name: String, tier: NodeTier },
+    #[error("{a} and {b} both have address {address}")]
+    AddressOverlap {
+        a: String,
+        b: String,
+        address: IpAddr,
+    },
+    #[error("Prefix {prefix} of {a} overlaps {other} of {b}")]
+    PrefixOverlap {
+        a: String,
+        prefix: Prefix,
+        b: String,
+        other: Prefix,
+    },
+    #[error("{what} of {name} is outside the address plan's {supernet}")]
+    OutsidePlan {
+        name: String,
+        what: String,
+        supernet: Prefix,
+    },
+    #[error("The address plan has no room left for {name}")]
+    AddressesExhausted { name: String },
+    #[error("No host ports are left above {base}")]
+    PortsExhausted { base: u16 },
+    #[error("Generated config for {name} is invalid: {reason}")]
+    InvalidConfig { name: String, reason: String },
+    #[error("Node {name} failed: {source}")]
+    Node {
+        name: String,
+        #[source]
+        source: NodeError,
+    },
+    #[error("Cannot write {}: {source}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// Supernets addresses and prefixes are taken from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanManifest {
+    pub ipv4_supernet: Prefix,
+    pub ipv6_supernet: Prefix,
+}
+
+impl Default for PlanManifest {
+    fn default() -> Self {
+        PlanManifest {
+            ipv4_supernet: VX0_IPV4_SUPERNET.parse().expect("valid supernet"),
+            ipv6_supernet: VX0_IPV6_SUPERNET.parse().expect("valid supernet"),
+        }
+    }
+}
+
+/// A topology as written in the manifest file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// PSK every node shares; a random one is made when left out
+    #[serde(default)]
+    pub psk: Option<String>,
+    #[serde(default)]
+    pub plan: PlanManifest,
+    /// First host port the port map hands out
+    #[serde(default = "default_host_port_base")]
+    pub host_port_base: u16,
+    /// Pairs of node names
+    #[serde(default)]
+    pub peerings: Vec<[String; 2]>,
+    pub nodes: Vec<ManifestNode>,
+}
+
+fn default_host_port_base() -> u16 {
+    20000
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestNode {
+    /// Names the node in peerings, file names and the port map
+    pub name: String,
+    pub tier: NodeTier,
+    /// `<name>.vx0` by default
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub asn: Option<u32>,
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub ipv6_address: Option<Ipv6Addr>,
+    /// Prefixes the node originates at startup
+    #[serde(default)]
+    pub ipv4_prefix: Option<Prefix>,
+    #[serde(default)]
+    pub ipv6_prefix: Option<Prefix>,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub services: Vec<ManifestService>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestService {
+    pub name: String,
+    /// Must end in .vx0
+    pub domain: String,
+    pub port: u16,
+    /// web, email, file, chat, database or a custom type; the service name
+    /// by default
+    #[serde(default, rename = "type")]
+    pub service_type: Option<String>,
+}
+
+impl Manifest {
+    /// Read a manifest, in TOML or YAML by the file's extension
+    pub fn load(path: &Path) -> Result<Self, TopologyError> {
+        Ok(Config::builder()
+            .add_source(File::from(path).required(true))
+            .build()?
+            .try_deserialize()?)
+    }
+
+    pub fn parse(contents: &str, format: FileFormat) -> Result<Self, TopologyError> {
+        Ok(Config::builder()
+            .add_source(File::from_str(contents, format))
+            .build()?
+            .try_deserialize()?)
+    }
+}
+
+/// A node with everything the manifest left out filled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopologyNode {
+    pub name: String,
+    pub tier: NodeTier,
+    pub hostname: String,
+    pub asn: u32,
+    pub ipv4_address: Ipv4Addr,
+    pub ipv6_address: Ipv6Addr,
+    pub ipv4_prefix: Option<Prefix>,
+    pub ipv6_prefix: Option<Prefix>,
+    pub location: Option<String>,
+    pub services: Vec<ManifestService>,
+}
+
+/// A host port forwarded to one of a node's listeners
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub node: String,
+    /// Config key placing the listener
+    pub key: &'static str,
+    pub transport: Transport,
+    pub port: u16,
+    pub host_port: u16,
+}
+
+/// A validated topology
+#[derive(Debug, Clone)]
+pub struct Topology {
+    nodes: Vec<TopologyNode>,
+    /// Indices into `nodes`, in manifest order
+    peerings: Vec<(usize, usize)>,
+    psk: String,
+    plan: PlanManifest,
+    host_port_base: u16,
+}
+
+/// Free values from `candidates`, skipping those in `taken`
+fn next_free<T: Copy + Eq + std::hash::Hash>(
+    candidates: impl IntoIterator<Item = T>,
+    taken: &HashSet<T>,
+) -> Option<T> {
+    candidates.into_iter().find(|value| !taken.contains(value))
+}
+
+/// `offset` above the supernet's network address, if still inside it
+fn in_supernet_v4(supernet: &Prefix, offset: u32) -> Option<Ipv4Addr> {
+    let IpNet::V4(net) = supernet.net() else {
+        return None;
+    };
+    let address = Ipv4Addr::from(u32::from(net.network()).checked_add(offset)?);
+    net.contains(&address).then_some(address)
+}
+
+/// The IPv6 address carrying an IPv4 address's last three octets
+fn derived_v6(supernet: &Prefix, ipv4: Ipv4Addr) -> Option<Ipv6Addr> {
+    let IpNet::V6(net) = supernet.net() else {
+        return None;
+    };
+    let [_, b, c, d] = ipv4.octets();
+    let offset = (u128::from(b) << 80) | (u128::from(c) << 64) | u128::from(d);
+    let address = Ipv6Addr::from(u128::from(net.network()) | offset);
+    net.contains(&address).then_some(address)
+}
+
+fn tier_name(tier: &NodeTier) -> &'static str {
+    match tier {
+        NodeTier::Backbone => "Backbone",
+        NodeTier::Regional => "Regional",
+        NodeTier::Edge => "Edge",
+    }
+}
+
+impl Topology {
+    /// Fill in what the manifest leaves out and check the result
+    pub fn resolve(manifest: Manifest) -> Result<Self, TopologyError> {
+        let Manifest {
+            psk,
+            plan,
+            host_port_base,
+            peerings: declared,
+            nodes: declared_nodes,
+        } = manifest;
+
+        let mut index = HashMap::new();
+        for (i, node) in declared_nodes.iter().enumerate() {
+            if index.insert(node.name.clone(), i).is_some() {
+                return Err(TopologyError::DuplicateName {
+                    name: node.name.clone(),
+                });
+            }
+        }
+        let name = |i: usize| declared_nodes[i].name.clone();
+
+        let mut peerings = Vec::new();
+        let mut seen = HashSet::new();
+        for [a, b] in &declared {
+            let lookup = |name: &String| {
+                index
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| TopologyError::UnknownNode { name: name.clone() })
+            };
+            let (a, b) = (lookup(a)?, lookup(b)?);
+            if a == b {
+                return Err(TopologyError::SelfPeering { name: name(a) });
+            }
+            if !seen.insert((a.min(b), a.max(b))) {
+                return Err(TopologyError::DuplicatePeering {
+                    a: name(a),
+                    b: name(b),
+                });
+            }
+            let (a_tier, b_tier) = (&declared_nodes[a].tier, &declared_nodes[b].tier);
+            if !a_tier.can_peer_with(b_tier) || !b_tier.can_peer_with(a_tier) {
+                return Err(TopologyError::TierViolation {
+                    a: name(a),
+                    a_tier: a_tier.clone(),
+                    b: name(b),
+                    b_tier: b_tier.clone(),
+                });
+            }
+            peerings.push((a, b));
+        }
+
+        let asns = Self::assign_asns(&declared_nodes)?;
+        let ipv4 = Self::assign_ipv4(&declared_nodes, &peerings, &plan)?;
+        let ipv6 = Self::assign_ipv6(&declared_nodes, &ipv4, &plan)?;
+        Self::check_prefixes(&declared_nodes, &plan)?;
+
+        let nodes = declared_nodes
+            .into_iter()
+            .enumerate()
+            .map(|(i, node)| TopologyNode {
+                hostname: node
+                    .hostname
+                    .unwrap_or_else(|| format!("{}.vx0", node.name)),
+                name: node.name,
+                tier: node.tier,
+                asn: asns[i],
+                ipv4_address: ipv4[i],
+                ipv6_address: ipv6[i],
+                ipv4_prefix: node.ipv4_prefix,
+                ipv6_prefix: node.ipv6_prefix,
+                location: node.location,
+                services: node.services,
+            })
+            .collect();
+
+        let psk = psk.unwrap_or_else(|| {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key.iter().map(|b| format!("{:02x}", b)).collect()
+        });
+        Ok(Topology {
+            nodes,
+            peerings,
+            psk,
+            plan,
+            host_port_base,
+        })
+    }
+
+    fn assign_asns(nodes: &[ManifestNode]) -> Result<Vec<u32>, TopologyError> {
+        let mut owners: HashMap<u32, usize> = HashMap::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let Some(asn) = node.asn else { continue };
+            let range = node.tier.get_asn_range();
+            if !(range.0..=range.1).contains(&asn) {
+                return Err(TopologyError::AsnOutOfRange {
+                    name: node.name.clone(),
+                    asn,
+                    tier: node.tier.clone(),
+                    range,
+                });
+            }
+            if let Some(&other) = owners.get(&asn) {
+                return Err(TopologyError::DuplicateAsn {
+                    a: nodes[other].name.clone(),
+                    b: node.name.clone(),
+                    asn,
+                });
+            }
+            owners.insert(asn, i);
+        }
+
+        let mut taken: HashSet<u32> = owners.keys().copied().collect();
+        nodes
+            .iter()
+            .map(|node| {
+                if let Some(asn) = node.asn {
+                    return Ok(asn);
+                }
+                // The range's first ASN is left for the tier's well-known node
+                let (first, last) = node.tier.get_asn_range();
+                let asn = next_free(first + 1..=last, &taken).ok_or_else(|| {
+                    TopologyError::AsnsExhausted {
+                        name: node.name.clone(),
+                        tier: node.tier.clone(),
+                    }
+                })?;
+                taken.insert(asn);
+                Ok(asn)
+            })
+            .collect()
+    }
+
+    fn assign_ipv4(
+        nodes: &[ManifestNode],
+        peerings: &[(usize, usize)],
+        plan: &PlanManifest,
+    ) -> Result<Vec<Ipv4Addr>, TopologyError> {
+        let mut owners: HashMap<Ipv4Addr, usize> = HashMap::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let Some(address) = node.address else {
+                continue;
+            };
+            if !plan.ipv4_supernet.contains(&IpAddr::V4(address)) {
+                return Err(TopologyError::OutsidePlan {
+                    name: node.name.clone(),
+                    what: format!("Address {}", address),
+                    supernet: plan.ipv4_supernet,
+                });
+            }
+            if let Some(&other) = owners.get(&address) {
+                return Err(TopologyError::AddressOverlap {
+                    a: nodes[other].name.clone(),
+                    b: node.name.clone(),
+                    address: IpAddr::V4(address),
+                });
+            }
+            owners.insert(address, i);
+        }
+
+        // Regional nodes numbered from 1 in manifest order, for edge subnets
+        let regionals: HashMap<usize, u32> = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.tier == NodeTier::Regional)
+            .zip(1..)
+            .map(|((i, _), n)| (i, n))
+            .collect();
+        let upstream = |i: usize| {
+            peerings
+                .iter()
+                .filter_map(|&(a, b)| match (a == i, b == i) {
+                    (true, _) => Some(b),
+                    (_, true) => Some(a),
+                    _ => None,
+                })
+                .find_map(|peer| regionals.get(&peer).copied())
+                .unwrap_or(0)
+        };
+
+        let mut taken: HashSet<Ipv4Addr> = owners.keys().copied().collect();
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                if let Some(address) = node.address {
+                    return Ok(address);
+                }
+                let candidates = (1..=254u32).filter_map(|n| {
+                    let offset = match node.tier {
+                        NodeTier::Backbone => (1 << 8) | n,
+                        NodeTier::Regional => (1 << 16) | (n << 8) | 1,
+                        NodeTier::Edge => (2 << 16) | (upstream(i) << 8) | n,
+                    };
+                    in_supernet_v4(&plan.ipv4_supernet, offset)
+                });
+                let address = next_free(candidates, &taken).ok_or_else(|| {
+                    TopologyError::AddressesExhausted {
+                        name: node.name.clone(),
+                    }
+                })?;
+                taken.insert(address);
+                Ok(address)
+            })
+            .collect()
+    }
+
+    fn assign_ipv6(
+        nodes: &[ManifestNode],
+        ipv4: &[Ipv4Addr],
+        plan: &PlanManifest,
+    ) -> Result<Vec<Ipv6Addr>, TopologyError> {
+        let mut owners: HashMap<Ipv6Addr, usize> = HashMap::new();
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| {
+                let address = match node.ipv6_address {
+                    Some(address) if plan.ipv6_supernet.contains(&IpAddr::V6(address)) => address,
+                    Some(address) => {
+                        return Err(TopologyError::OutsidePlan {
+                            name: node.name.clone(),
+                            what: format!("Address {}", address),
+                            supernet: plan.ipv6_supernet,
+                        })
+                    }
+                    None => derived_v6(&plan.ipv6_supernet, ipv4[i]).ok_or_else(|| {
+                        TopologyError::AddressesExhausted {
+                            name: node.name.clone(),
+                        }
+                    })?,
+                };
+                if let Some(&other) = owners.get(&address) {
+                    return Err(TopologyError::AddressOverlap {
+                        a: nodes[other].name.clone(),
+                        b: node.name.clone(),
+                        address: IpAddr::V6(address),
+                    });
+                }
+                owners.insert(address, i);
+                Ok(address)
+            })
+            .collect()
+    }
+
+    fn check_prefixes(nodes: &[ManifestNode], plan: &PlanManifest) -> Result<(), TopologyError> {
+        let mut v4: PrefixTrie<(Prefix, &str)> = PrefixTrie::default();
+        let mut v6: PrefixTrie<(Prefix, &str)> = PrefixTrie::default();
+        for node in nodes {
+            let prefixes = [
+                (node.ipv4_prefix, &plan.ipv4_supernet, &mut v4),
+                (node.ipv6_prefix, &plan.ipv6_supernet, &mut v6),
+            ];
+            for (prefix, supernet, trie) in prefixes {
+                let Some(prefix) = prefix else { continue };
+                if !supernet.contains(&prefix.net()) {
+                    return Err(TopologyError::OutsidePlan {
+                        name: node.name.clone(),
+                        what: format!("Prefix {}", prefix),
+                        supernet: *supernet,
+                    });
+                }
+                let overlapping = trie
+                    .covering(&prefix)
+                    .next()
+                    .or_else(|| trie.within(&prefix).first().copied());
+                if let Some((other, name)) = overlapping {
+                    return Err(TopologyError::PrefixOverlap {
+                        a: node.name.clone(),
+                        prefix,
+                        b: name.to_string(),
+                        other: *other,
+                    });
+                }
+                trie.insert(&prefix, (prefix, node.name.as_str()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn nodes(&self) -> &[TopologyNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, name: &str) -> Option<&TopologyNode> {
+        self.nodes.iter().find(|node| node.name == name)
+    }
+
+    /// Declared peerings as pairs of node names
+    pub fn peerings(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.peerings
+            .iter()
+            .map(|&(a, b)| (self.nodes[a].name.as_str(), self.nodes[b].name.as_str()))
+    }
+
+    pub fn psk(&self) -> &str {
+        &self.psk
+    }
+
+    fn peers_of(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        self.peerings.iter().filter_map(move |&(a, b)| {
+            if a == i {
+                Some(b)
+            } else if b == i {
+                Some(a)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Every node's config, from its tier's profile, checked as the daemon
+    /// would on load
+    pub fn configs(&self) -> Result<Vec<(String, Vx0Config)>, TopologyError> {
+        let mut configs: Vec<Vx0Config> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let profile = tier_name(&node.tier).to_ascii_lowercase();
+                let mut config = profiles::named(&profile).expect("every tier has a profile");
+                config.profile = Some(profile);
+                config.node.hostname = node.hostname.clone();
+                config.node.asn = node.asn;
+                config.node.tier = tier_name(&node.tier).to_string();
+                if let Some(location) = &node.location {
+                    config.node.location = location.clone();
+                }
+                config.node.ipv4_address = node.ipv4_address.to_string();
+                config.node.ipv6_address = node.ipv6_address.to_string();
+                config.network.bgp.router_id = node.ipv4_address.to_string();
+                let plan = &mut config.network.plan;
+                plan.ipv4_supernet = self.plan.ipv4_supernet.to_string();
+                plan.ipv6_supernet = self.plan.ipv6_supernet.to_string();
+                plan.ipv4_prefix = node.ipv4_prefix.map(|prefix| prefix.to_string());
+                plan.ipv6_prefix = node.ipv6_prefix.map(|prefix| prefix.to_string());
+                config.psk = Some(PSKConfig {
+                    default: self.psk.clone(),
+                });
+                config
+            })
+            .collect();
+
+        for i in 0..self.nodes.len() {
+            let peers = self
+                .peers_of(i)
+                .map(|peer| BGPPeerConfig {
+                    address: self.nodes[peer].ipv4_address.to_string(),
+                    port: configs[peer].network.bgp.listen_port,
+                    asn: self.nodes[peer].asn,
+                    wire: Default::default(),
+                    import_policy: Vec::new(),
+                    mrai_secs: None,
+                    host_bits: Default::default(),
+                    enabled: true,
+                    group: None,
+                })
+                .collect();
+            configs[i].network.bgp.peers = peers;
+        }
+
+        self.nodes
+            .iter()
+            .zip(configs)
+            .map(|(node, config)| {
+                let invalid = |reason: String| TopologyError::InvalidConfig {
+                    name: node.name.clone(),
+                    reason,
+                };
+                config
+                    .check_hostname()
+                    .map_err(|e| invalid(e.to_string()))?;
+                config
+                    .check_listeners()
+                    .map_err(|e| invalid(e.to_string()))?;
+                config.check_units().map_err(|e| invalid(e.to_string()))?;
+                Ok((node.name.clone(), config))
+            })
+            .collect()
+    }
+
+    /// A distinct host port for every TCP and UDP listener of every node
+    pub fn port_map(&self) -> Result<Vec<PortMapping>, TopologyError> {
+        let mut next = Some(self.host_port_base);
+        let mut mappings = Vec::new();
+        for (name, config) in self.configs()? {
+            for listener in planned_listeners(&config) {
+                let Endpoint::Socket(transport, addr) = listener.endpoint else {
+                    continue;
+                };
+                let host_port = next.ok_or(TopologyError::PortsExhausted {
+                    base: self.host_port_base,
+                })?;
+                next = host_port.checked_add(1);
+                mappings.push(PortMapping {
+                    node: name.clone(),
+                    key: listener.key,
+                    transport,
+                    port: addr.port(),
+                    host_port,
+                });
+            }
+        }
+        Ok(mappings)
+    }
+
+    /// The port map as a docker-compose fragment, giving each node its plan
+    /// address on a shared network
+    pub fn compose_ports(&self) -> Result<String, TopologyError> {
+        let mappings = self.port_map()?;
+        let mut yaml = String::from("# Generated by `vx0net topology generate`\nservices:\n");
+        for node in &self.nodes {
+            let _ = writeln!(yaml, "  {}:", node.name);
+            let _ = writeln!(yaml, "    networks:\n      vx0:");
+            let _ = writeln!(yaml, "        ipv4_address: {}", node.ipv4_address);
+            let _ = writeln!(yaml, "        ipv6_address: {}", node.ipv6_address);
+            let _ = writeln!(yaml, "    ports:");
+            for mapping in mappings.iter().filter(|m| m.node == node.name) {
+                let transport = match mapping.transport {
+                    Transport::Tcp => "tcp",
+                    Transport::Udp => "udp",
+                };
+                let _ = writeln!(
+                    yaml,
+                    "      - \"{}:{}/{}\"  # {}",
+                    mapping.host_port, mapping.port, transport, mapping.key
+                );
+            }
+        }
+        let _ = writeln!(
+            yaml,
+            "networks:\n  vx0:\n    enable_ipv6: true\n    ipam:\n      config:"
+        );
+        let _ = writeln!(yaml, "        - subnet: {}", self.plan.ipv4_supernet);
+        let _ = writeln!(yaml, "        - subnet: {}", self.plan.ipv6_supernet);
+        Ok(yaml)
+    }
+
+    /// Write `<name>.toml` for every node and the port map into `out_dir`,
+    /// returning the files written
+    pub fn generate(&self, out_dir: &Path) -> Result<Vec<PathBuf>, TopologyError> {
+        let write_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |source| TopologyError::Write { path, source }
+        };
+        let configs = self.configs()?;
+        let ports = self.compose_ports()?;
+        std::fs::create_dir_all(out_dir).map_err(write_error(out_dir))?;
+
+        let mut written = Vec::new();
+        for (name, config) in configs {
+            let path = out_dir.join(format!("{}.toml", name));
+            config
+                .save(&path.to_string_lossy())
+                .map_err(write_error(&path))?;
+            written.push(path);
+        }
+        let path = out_dir.join(PORT_MAP_FILE);
+        std::fs::write(&path, ports).map_err(write_error(&path))?;
+        written.push(path);
+        Ok(written)
+    }
+
+    /// Start every node in this process, register its services and connect
+    /// the declared peerings
+    pub async fn boot(&self) -> Result<Lab, TopologyError> {
+        let node_error = |name: &str| {
+            let name = name.to_string();
+            move |source| TopologyError::Node { name, source }
+        };
+        let mut nodes = Vec::new();
+        for ((name, config), declared) in self.configs()?.into_iter().zip(&self.nodes) {
+            let node = Arc::new(Vx0Node::new(config).map_err(node_error(&name))?);
+            node.start().await.map_err(node_error(&name))?;
+            for service in &declared.services {
+                let service_type = service.service_type.as_deref().map_or_else(
+                    || ServiceType::from(service.name.as_str()),
+                    ServiceType::from,
+                );
+                node.register_service(HostedService {
+                    service_id: uuid::Uuid::new_v4(),
+                    name: service.name.clone(),
+                    service_type,
+                    domain: service.domain.clone(),
+                    port: service.port,
+                    status: ServiceStatus::Running,
+                    metadata: Default::default(),
+                })
+                .await
+                .map_err(node_error(&name))?;
+            }
+            nodes.push((name, node));
+        }
+
+        for &(a, b) in &self.peerings {
+            for (from, to) in [(a, b), (b, a)] {
+                let (name, node) = &nodes[from];
+                let peer = &nodes[to].1;
+                node.add_peer(PeerConnection::new(
+                    peer.node_id,
+                    peer.asn,
+                    peer.ipv4_addr.into(),
+                ))
+                .await
+                .map_err(node_error(name))?;
+            }
+        }
+        Ok(Lab { nodes })
+    }
+}
+
+/// A topology's nodes running in this process
+pub struct Lab {
+    nodes: Vec<(String, Arc<Vx0Node>)>,
+}
+
+impl Lab {
+    pub fn node(&self, name: &str) -> Option<&Arc<Vx0Node>> {
+        self.nodes
+            .iter()
+            .find(|(node, _)| node == name)
+            .map(|(_, node)| node)
+    }
+
+    /// Nodes by manifest name, in manifest order
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, &Arc<Vx0Node>)> {
+        self.nodes.iter().map(|(name, node)| (name.as_str(), node))
+    }
+
+    pub async fn stop(&self) {
+        for (name, node) in &self.nodes {
+            if let Err(e) = node.stop().await {
+                tracing::warn!("Stopping lab node {} failed: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(toml: &str) -> Manifest {
+        Manifest::parse(toml, FileFormat::Toml).unwrap()
+    }
+
+    #[test]
+    fn test_asns_and_addresses_are_assigned_from_the_plan() {
+        let topology = Topology::resolve(manifest(
+            r#"
+            psk = "lab"
+            peerings = [["r1", "b1"], ["e1", "r2"], ["e2", "r2"], ["e3", "r1"]]
+
+            [[nodes]]
+            name = "b1"
+            tier = "Backbone"
+            [[nodes]]
+            name = "r1"
+            tier = "Regional"
+            asn = 65101
+            [[nodes]]
+            name = "r2"
+            tier = "Regional"
+            [[nodes]]
+            name = "e1"
+            tier = "Edge"
+            [[nodes]]
+            name = "e2"
+            tier = "Edge"
+            address = "10.2.2.1"
+            [[nodes]]
+            name = "e3"
+            tier = "Edge"
+            "#,
+        ))
+        .unwrap();
+
+        let assigned: Vec<(u32, String, String)> = topology
+            .nodes()
+            .iter()
+            .map(|node| {
+                (
+                    node.asn,
+                    node.ipv4_address.to_string(),
+                    node.ipv6_address.to_string(),
+                )
+            })
+            .collect();
+        let expected = [
+            (65001, "10.0.1.1", "fd00:7830:0:1::1"),
+            (65101, "10.1.1.1", "fd00:7830:1:1::1"),
+            (65102, "10.1.2.1", "fd00:7830:1:2::1"),
+            // The first address under r2 is taken by e2
+            (66001, "10.2.2.2", "fd00:7830:2:2::2"),
+            (66002, "10.2.2.1", "fd00:7830:2:2::1"),
+            (66003, "10.2.1.1", "fd00:7830:2:1::1"),
+        ];
+        for (assigned, expected) in assigned.iter().zip(expected) {
+            assert_eq!(
+                (assigned.0, assigned.1.as_str(), assigned.2.as_str()),
+                expected
+            );
+        }
+        assert_eq!(topology.node("e3").unwrap().hostname, "e3.vx0");
+
+        let configs = topology.configs().unwrap();
+        let (_, r1) = &configs[1];
+        assert_eq!(r1.network.bgp.router_id, "10.1.1.1");
+        let peers: Vec<_> = r1.network.bgp.peers.iter().map(|p| p.asn).collect();
+        assert_eq!(peers, vec![65001, 66003]);
+        assert_eq!(r1.psk.as_ref().unwrap().default, "lab");
+    }
+
+    #[test]
+    fn test_tier_violations_and_overlaps_are_refused() {
+        let nodes = r#"
+            [[nodes]]
+            name = "r1"
+            tier = "Regional"
+            ipv4_prefix = "10.1.0.0/16"
+            [[nodes]]
+            name = "e1"
+            tier = "Edge"
+            [[nodes]]
+            name = "e2"
+            tier = "Edge"
+        "#;
+        let with = |head: &str, tail: &str| {
+            Topology::resolve(manifest(&format!("{}\n{}\n{}", head, nodes, tail)))
+        };
+
+        assert!(matches!(
+            with(r#"peerings = [["e1", "e2"]]"#, ""),
+            Err(TopologyError::TierViolation { .. })
+        ));
+        assert!(matches!(
+            with(r#"peerings = [["e1", "r1"], ["r1", "e1"]]"#, ""),
+            Err(TopologyError::DuplicatePeering { .. })
+        ));
+        assert!(matches!(
+            with(r#"peerings = [["e1", "r9"]]"#, ""),
+            Err(TopologyError::UnknownNode { name }) if name == "r9"
+        ));
+        assert!(matches!(
+            with("", "[[nodes]]\nname = \"e3\"\ntier = \"Edge\"\nasn = 65200"),
+            Err(TopologyError::AsnOutOfRange { asn: 65200, .. })
+        ));
+        assert!(matches!(
+            with(
+                "",
+                "[[nodes]]\nname = \"r2\"\ntier = \"Regional\"\naddress = \"10.1.9.1\"\n\
+                 [[nodes]]\nname = \"r3\"\ntier = \"Regional\"\naddress = \"10.1.9.1\""
+            ),
+            Err(TopologyError::AddressOverlap { a, b, .. }) if a == "r2" && b == "r3"
+        ));
+        assert!(matches!(
+            with(
+                "",
+                "[[nodes]]\nname = \"r2\"\ntier = \"Regional\"\nipv4_prefix = \"10.1.4.0/24\""
+            ),
+            Err(TopologyError::PrefixOverlap { b, .. }) if b == "r1"
+        ));
+        assert!(matches!(
+            with(
+                "",
+                "[[nodes]]\nname = \"r2\"\ntier = \"Regional\"\naddress = \"192.168.0.1\""
+            ),
+            Err(TopologyError::OutsidePlan { .. })
+        ));
+        assert!(matches!(
+            with("", "[[nodes]]\nname = \"e1\"\ntier = \"Edge\""),
+            Err(TopologyError::DuplicateName { .. })
+        ));
+    }
+}
@@ -0,0 +1,540 @@
+//! Durations and sizes in configuration.
+//!
+//! Keys whose names carry no unit take a humane form instead: durations
+//! such as `"90s"`, `"5m"` or `"1h30m"`, sizes such as `"64KB"` or
+//! `"10MB"`. Bare numbers still load as before, as seconds or bytes, and
+//! saving writes the humane form back. Each such key has a range it must
+//! stay in, and a point past which the value is more likely in the wrong
+//! unit than meant, e.g. a `hold_time` of `90000` written as milliseconds;
+//! those load with a warning.
+
+use crate::config::Vx0Config;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DURATION_UNITS: &[(&str, u64)] = &[
+    ("d", 86_400_000),
+    ("h", 3_600_000),
+    ("m", 60_000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+const SIZE_UNITS: &[(&str, u64)] = &[("GB", 1 << 30), ("MB", 1 << 20), ("KB", 1 << 10)];
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum UnitError {
+    #[error("{input:?} is not a duration; expected a number of seconds or e.g. 90s, 5m, 1h30m")]
+    Duration { input: String },
+    #[error("{input:?} is not a size; expected a number of bytes or e.g. 4096, 64KB, 10MB")]
+    Size { input: String },
+    #[error("{key} = {value} is outside {min}..={max}")]
+    OutOfRange {
+        key: &'static str,
+        value: String,
+        min: String,
+        max: String,
+    },
+    #[error("{key} = {value} is under the least accepted, {min}")]
+    TooSmall {
+        key: &'static str,
+        value: String,
+        min: String,
+    },
+}
+
+/// A length of time: `"90s"`, `"5m"`, `"1h30m"`, `"250ms"`, or a bare
+/// number of seconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConfigDuration(Duration);
+
+impl ConfigDuration {
+    pub const fn from_secs(secs: u64) -> Self {
+        ConfigDuration(Duration::from_secs(secs))
+    }
+
+    pub const fn get(self) -> Duration {
+        self.0
+    }
+
+    /// Whole seconds, rounded down
+    pub const fn as_secs(self) -> u64 {
+        self.0.as_secs()
+    }
+}
+
+impl From<Duration> for ConfigDuration {
+    fn from(duration: Duration) -> Self {
+        ConfigDuration(duration)
+    }
+}
+
+impl FromStr for ConfigDuration {
+    type Err = UnitError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || UnitError::Duration {
+            input: input.to_string(),
+        };
+        let trimmed = input.trim();
+        if let Ok(secs) = trimmed.parse::<u64>() {
+            return Ok(ConfigDuration::from_secs(secs));
+        }
+
+        // Amounts each followed by a unit, largest unit first, e.g. 1h30m
+        let mut rest = trimmed;
+        let mut millis: u64 = 0;
+        let mut smallest = u64::MAX;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+            rest = &rest[digits..];
+            let letters = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            let unit = DURATION_UNITS
+                .iter()
+                .find(|(name, _)| *name == rest[..letters].to_ascii_lowercase())
+                .map(|(_, per)| *per)
+                .filter(|per| *per < smallest)
+                .ok_or_else(invalid)?;
+            smallest = unit;
+            millis = amount
+                .checked_mul(unit)
+                .and_then(|part| millis.checked_add(part))
+                .ok_or_else(invalid)?;
+            rest = rest[letters..].trim_start();
+        }
+        if smallest == u64::MAX {
+            return Err(invalid());
+        }
+        Ok(ConfigDuration(Duration::from_millis(millis)))
+    }
+}
+
+impl fmt::Display for ConfigDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut millis = self.0.as_millis() as u64;
+        if millis == 0 {
+            return f.write_str("0s");
+        }
+        for (name, per) in DURATION_UNITS {
+            if millis >= *per {
+                write!(f, "{}{}", millis / per, name)?;
+                millis %= per;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for ConfigDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(Humane::<ConfigDuration>::new(
+            "a duration such as 90s or 1h30m",
+        ))
+    }
+}
+
+/// An amount of data: `"4096"`, `"64KB"`, `"10MB"`, `"1GB"`, or a bare
+/// number of bytes. KB, MB and GB are powers of 1024.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub const fn bytes(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+
+    pub const fn kib(kib: u64) -> Self {
+        ByteSize(kib << 10)
+    }
+
+    pub const fn mib(mib: u64) -> Self {
+        ByteSize(mib << 20)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = UnitError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || UnitError::Size {
+            input: input.to_string(),
+        };
+        let trimmed = input.trim();
+        let digits = trimmed
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(trimmed.len());
+        let amount: u64 = trimmed[..digits].parse().map_err(|_| invalid())?;
+        let unit = trimmed[digits..].trim().to_ascii_uppercase();
+        let per = match unit.as_str() {
+            "" | "B" => 1,
+            unit => SIZE_UNITS
+                .iter()
+                .find(|(name, _)| {
+                    // 10MB, 10MiB and 10M all mean the same
+                    unit == *name || unit == name.replace('B', "IB") || *unit == name[..1]
+                })
+                .map(|(_, per)| *per)
+                .ok_or_else(invalid)?,
+        };
+        amount.checked_mul(per).map(ByteSize).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SIZE_UNITS
+            .iter()
+            .find(|(_, per)| self.0 >= *per && self.0.is_multiple_of(*per))
+        {
+            Some((name, per)) => write!(f, "{}{}", self.0 / per, name),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(Humane::<ByteSize>::new("a size such as 4096 or 10MB"))
+    }
+}
+
+/// Takes a bare number in the type's plain unit, or a string in any form
+/// the type parses
+struct Humane<T> {
+    expecting: &'static str,
+    _type: std::marker::PhantomData<T>,
+}
+
+impl<T> Humane<T> {
+    fn new(expecting: &'static str) -> Self {
+        Humane {
+            expecting,
+            _type: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<T> Visitor<'_> for Humane<T>
+where
+    T: FromStr<Err = UnitError>,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        match u64::try_from(value) {
+            Ok(value) => self.visit_u64(value),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Signed(value), &self)),
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+/// A value that loads but is probably not what was meant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitWarning {
+    pub key: &'static str,
+    pub value: String,
+    pub reason: String,
+}
+
+impl fmt::Display for UnitWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}: {}", self.key, self.value, self.reason)
+    }
+}
+
+/// What a duration key accepts, and past where a value looks like it was
+/// written in milliseconds
+struct DurationRule {
+    key: &'static str,
+    value: ConfigDuration,
+    min: ConfigDuration,
+    max: ConfigDuration,
+    suspicious_above: ConfigDuration,
+}
+
+struct SizeRule {
+    key: &'static str,
+    value: ByteSize,
+    min: ByteSize,
+    /// Below this a value looks like it was written in KB or MB
+    suspicious_below: ByteSize,
+}
+
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+fn duration_rules(config: &Vx0Config) -> Vec<DurationRule> {
+    let bgp = &config.network.bgp;
+    vec![
+        DurationRule {
+            key: "network.bgp.hold_time",
+            value: bgp.hold_time,
+            min: ConfigDuration::from_secs(3),
+            max: ConfigDuration::from_secs(u16::MAX.into()),
+            suspicious_above: ConfigDuration::from_secs(HOUR),
+        },
+        DurationRule {
+            key: "network.bgp.keepalive_time",
+            value: bgp.keepalive_time,
+            min: ConfigDuration::from_secs(1),
+            max: ConfigDuration::from_secs((u16::MAX / 3).into()),
+            suspicious_above: ConfigDuration::from_secs(20 * MINUTE),
+        },
+        DurationRule {
+            key: "node.redundancy.query_timeout",
+            value: config.node.redundancy.query_timeout,
+            min: ConfigDuration::from_secs(1),
+            max: ConfigDuration::from_secs(MINUTE),
+            suspicious_above: ConfigDuration::from_secs(30),
+        },
+        DurationRule {
+            key: "services.service_ttl",
+            value: config.services.service_ttl,
+            min: ConfigDuration::from_secs(1),
+            max: ConfigDuration::from_secs(30 * DAY),
+            suspicious_above: ConfigDuration::from_secs(DAY),
+        },
+    ]
+}
+
+/// Each tier's ceilings under `network.adaptive_timers`
+const TIER_CEILINGS: [[&str; 3]; 3] = [
+    [
+        "network.adaptive_timers.edge.keepalive_ceiling",
+        "network.adaptive_timers.edge.echo_ceiling",
+        "network.adaptive_timers.edge.announce_ceiling",
+    ],
+    [
+        "network.adaptive_timers.regional.keepalive_ceiling",
+        "network.adaptive_timers.regional.echo_ceiling",
+        "network.adaptive_timers.regional.announce_ceiling",
+    ],
+    [
+        "network.adaptive_timers.backbone.keepalive_ceiling",
+        "network.adaptive_timers.backbone.echo_ceiling",
+        "network.adaptive_timers.backbone.announce_ceiling",
+    ],
+];
+
+fn size_rules(config: &Vx0Config) -> Vec<SizeRule> {
+    vec![
+        SizeRule {
+            key: "network.dns.sync_quota.max_bytes_per_origin",
+            value: config.network.dns.sync_quota.max_bytes_per_origin,
+            min: ByteSize::bytes(1),
+            suspicious_below: ByteSize::kib(1),
+        },
+        SizeRule {
+            key: "monitoring.recorder.max_size_bytes",
+            value: config.monitoring.recorder.max_size_bytes,
+            min: ByteSize::kib(1),
+            suspicious_below: ByteSize::kib(64),
+        },
+    ]
+}
+
+impl Vx0Config {
+    /// The first duration or size outside its key's range
+    pub fn check_units(&self) -> Result<(), UnitError> {
+        out_of_range(self).into_iter().next().map_or(Ok(()), Err)
+    }
+
+    /// Values in range that are probably in the wrong unit, or at odds
+    /// with each other
+    pub fn unit_warnings(&self) -> Vec<UnitWarning> {
+        warnings(self)
+    }
+}
+
+fn out_of_range(config: &Vx0Config) -> Vec<UnitError> {
+    let durations = duration_rules(config)
+        .into_iter()
+        .filter(|rule| rule.value < rule.min || rule.value > rule.max)
+        .map(|rule| UnitError::OutOfRange {
+            key: rule.key,
+            value: rule.value.to_string(),
+            min: rule.min.to_string(),
+            max: rule.max.to_string(),
+        });
+    let sizes = size_rules(config)
+        .into_iter()
+        .filter(|rule| rule.value < rule.min)
+        .map(|rule| UnitError::TooSmall {
+            key: rule.key,
+            value: rule.value.to_string(),
+            min: rule.min.to_string(),
+        });
+    durations.chain(sizes).collect()
+}
+
+fn warnings(config: &Vx0Config) -> Vec<UnitWarning> {
+    let mut warnings: Vec<UnitWarning> = duration_rules(config)
+        .into_iter()
+        .filter(|rule| rule.value > rule.suspicious_above && rule.value <= rule.max)
+        .map(|rule| {
+            let as_millis = ConfigDuration(Duration::from_millis(rule.value.as_secs()));
+            let hint = if as_millis >= rule.min {
+                format!(
+                    "; bare numbers are seconds, so write \"{}\" if {} milliseconds were meant",
+                    as_millis,
+                    rule.value.as_secs()
+                )
+            } else {
+                String::new()
+            };
+            UnitWarning {
+                key: rule.key,
+                value: rule.value.to_string(),
+                reason: format!("unusually long, over {}{}", rule.suspicious_above, hint),
+            }
+        })
+        .collect();
+    warnings.extend(
+        size_rules(config)
+            .into_iter()
+            .filter(|rule| rule.value >= rule.min && rule.value < rule.suspicious_below)
+            .map(|rule| UnitWarning {
+                key: rule.key,
+                value: rule.value.to_string(),
+                reason: format!(
+                    "unusually small, under {}; bare numbers are bytes, so write e.g. \"{}MB\" for megabytes",
+                    rule.suspicious_below,
+                    rule.value.get()
+                ),
+            }),
+    );
+
+    let bgp = &config.network.bgp;
+    if bgp.keepalive_time.get() * 3 > bgp.hold_time.get() {
+        warnings.push(UnitWarning {
+            key: "network.bgp.keepalive_time",
+            value: bgp.keepalive_time.to_string(),
+            reason: format!(
+                "more than a third of hold_time ({}), so one lost keepalive drops the session",
+                bgp.hold_time
+            ),
+        });
+    }
+
+    let adaptive = &config.network.adaptive_timers;
+    for (keys, bounds) in [
+        (TIER_CEILINGS[0], &adaptive.edge),
+        (TIER_CEILINGS[1], &adaptive.regional),
+        (TIER_CEILINGS[2], &adaptive.backbone),
+    ] {
+        for (key, floor, ceiling) in [
+            (keys[0], bounds.keepalive_floor, bounds.keepalive_ceiling),
+            (keys[1], bounds.echo_floor, bounds.echo_ceiling),
+            (keys[2], bounds.announce_floor, bounds.announce_ceiling),
+        ] {
+            if ceiling < floor {
+                warnings.push(UnitWarning {
+                    key,
+                    value: ceiling.to_string(),
+                    reason: format!("below its floor ({}), which is used throughout", floor),
+                });
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn duration(input: &str) -> Result<Duration, UnitError> {
+        input.parse::<ConfigDuration>().map(ConfigDuration::get)
+    }
+
+    #[test]
+    fn test_duration_forms() {
+        assert_eq!(duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(duration(" 1h 30m "), Ok(Duration::from_secs(5400)));
+        assert_eq!(duration("2d"), Ok(Duration::from_secs(2 * DAY)));
+        assert_eq!(duration("1s500ms"), Ok(Duration::from_millis(1500)));
+        assert_eq!(duration("0"), Ok(Duration::ZERO));
+
+        for bad in ["", "s", "90x", "1.5h", "-5s", "30m1h", "5m5m", "h1"] {
+            assert!(duration(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_duration_display() {
+        for (secs, shown) in [(0, "0s"), (90, "1m30s"), (300, "5m"), (5400, "1h30m")] {
+            assert_eq!(ConfigDuration::from_secs(secs).to_string(), shown);
+        }
+        assert_eq!(
+            ConfigDuration::from(Duration::from_millis(90_250)).to_string(),
+            "1m30s250ms"
+        );
+    }
+
+    #[test]
+    fn test_size_forms() {
+        for (input, bytes) in [
+            ("4096", 4096),
+            ("4096B", 4096),
+            ("64KB", 64 << 10),
+            ("64kib", 64 << 10),
+            ("10MB", 10 << 20),
+            ("10 MiB", 10 << 20),
+            ("10M", 10 << 20),
+            ("1GB", 1 << 30),
+        ] {
+            assert_eq!(
+                input.parse::<ByteSize>(),
+                Ok(ByteSize::bytes(bytes)),
+                "{}",
+                input
+            );
+        }
+        for bad in ["", "MB", "10TB", "1.5MB", "-1"] {
+            assert!(bad.parse::<ByteSize>().is_err(), "{}", bad);
+        }
+        assert_eq!(ByteSize::bytes(4096).to_string(), "4KB");
+        assert_eq!(ByteSize::bytes(1000).to_string(), "1000B");
+        assert_eq!(ByteSize::mib(16).to_string(), "16MB");
+    }
+}
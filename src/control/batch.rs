@@ -0,0 +1,607 @@
+//! Several control commands sent as one request.
+//!
+//! Provisioning scripts send their whole script over one connection instead
+//! of running the CLI once per command. Each command keeps the script line
+//! it came from, so results and errors point back at it.
+//!
+//! An atomic batch is validated in full before anything is applied. If a
+//! command still fails while applying, the commands before it are undone:
+//! locally originated routes return to a snapshot taken before the batch,
+//! and services, peers and blocks are removed or reinstated by the inverse
+//! command. Commands with no inverse, such as saying goodbye to peers, are
+//! refused in atomic batches. Sessions a block tore down stay down.
+
+use crate::control::{
+    ControlErrorCode, ControlRequest, ControlResponse, ControlServer, ServerState,
+};
+use crate::network::acl::AclEntry;
+use crate::network::dns::cache::NamePattern;
+use crate::node::metadata::ServiceMetadata;
+use crate::node::{NodeId, NodeTier};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// A command and the script line it came from
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchCommand {
+    pub line: usize,
+    pub request: ControlRequest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchResult {
+    pub line: usize,
+    pub command: String,
+    #[serde(flatten)]
+    pub outcome: BatchOutcome,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    /// Took effect; `reply` is what the command alone would have answered
+    Applied { reply: ControlResponse },
+    /// Refused by validation, or failed while applying
+    Failed {
+        code: ControlErrorCode,
+        message: String,
+    },
+    /// Not run, because the atomic batch was refused or failed
+    Skipped,
+    /// Applied, then undone because a later command of the atomic batch
+    /// failed
+    RolledBack,
+}
+
+impl BatchResult {
+    fn new(line: usize, command: &str, reply: ControlResponse) -> Self {
+        let outcome = match reply {
+            ControlResponse::Error { code, message } => BatchOutcome::Failed { code, message },
+            reply => BatchOutcome::Applied { reply },
+        };
+        BatchResult {
+            line,
+            command: command.to_string(),
+            outcome,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        matches!(self.outcome, BatchOutcome::Failed { .. })
+    }
+
+    pub(super) fn timed_out(&self) -> bool {
+        match &self.outcome {
+            BatchOutcome::Failed { code, .. } => *code == ControlErrorCode::Timeout,
+            BatchOutcome::Applied { reply } => reply.timed_out(),
+            BatchOutcome::Skipped | BatchOutcome::RolledBack => false,
+        }
+    }
+}
+
+pub(super) async fn run(
+    commands: Vec<BatchCommand>,
+    atomic: bool,
+    state: &ServerState,
+) -> ControlResponse {
+    let results = if atomic {
+        run_atomic(commands, state).await
+    } else {
+        let mut results = Vec::with_capacity(commands.len());
+        for command in commands {
+            let name = command.request.name();
+            let reply = ControlServer::dispatch_with_timeout(command.request, state).await;
+            results.push(BatchResult::new(command.line, name, reply));
+        }
+        results
+    };
+    ControlResponse::Batch { results }
+}
+
+async fn run_atomic(commands: Vec<BatchCommand>, state: &ServerState) -> Vec<BatchResult> {
+    let mut refusals = Vec::with_capacity(commands.len());
+    let mut registered = HashSet::new();
+    for command in &commands {
+        refusals.push(
+            validate(&command.request, state, &mut registered)
+                .await
+                .err(),
+        );
+    }
+    if refusals.iter().any(Option::is_some) {
+        return commands
+            .iter()
+            .zip(refusals)
+            .map(|(command, refusal)| BatchResult {
+                line: command.line,
+                command: command.request.name().to_string(),
+                outcome: match refusal {
+                    Some((code, message)) => BatchOutcome::Failed { code, message },
+                    None => BatchOutcome::Skipped,
+                },
+            })
+            .collect();
+    }
+
+    let routes = state.bgp.snapshot_local_routes().await;
+    let mut undo = Vec::new();
+    let mut results = Vec::with_capacity(commands.len());
+    let mut failed = false;
+    for command in commands {
+        let name = command.request.name();
+        if failed {
+            results.push(BatchResult {
+                line: command.line,
+                command: name.to_string(),
+                outcome: BatchOutcome::Skipped,
+            });
+            continue;
+        }
+        let request = command.request.clone();
+        let result = BatchResult::new(
+            command.line,
+            name,
+            ControlServer::dispatch_with_timeout(command.request, state).await,
+        );
+        match &result.outcome {
+            BatchOutcome::Applied { reply } => undo.extend(Undo::of(request, reply)),
+            _ => failed = true,
+        }
+        results.push(result);
+    }
+    if !failed {
+        return results;
+    }
+
+    for step in undo.into_iter().rev() {
+        step.apply(state).await;
+    }
+    if let Err(e) = state.bgp.restore_local_routes(routes).await {
+        tracing::error!("Failed to restore routes after a failed batch: {}", e);
+    }
+    for result in &mut results {
+        if matches!(result.outcome, BatchOutcome::Applied { .. }) {
+            result.outcome = BatchOutcome::RolledBack;
+        }
+    }
+    results
+}
+
+/// What would make a command fail before it changes anything; `registered`
+/// collects the domains earlier commands of the batch publish
+async fn validate(
+    request: &ControlRequest,
+    state: &ServerState,
+    registered: &mut HashSet<String>,
+) -> Result<(), (ControlErrorCode, String)> {
+    let bad = |message: String| Err((ControlErrorCode::BadRequest, message));
+    match request {
+        ControlRequest::AnnounceRoute {
+            network, next_hop, ..
+        } if !network.reachable_via(next_hop) => bad(format!(
+            "Next hop {} is not in the same address family as {}",
+            next_hop, network
+        )),
+        ControlRequest::RegisterService {
+            domain, metadata, ..
+        } => {
+            if state.services.get().is_none() {
+                return Err((
+                    ControlErrorCode::Failed,
+                    "This daemon does not host services".to_string(),
+                ));
+            }
+            if !domain.ends_with(".vx0") {
+                return bad("Service domain must end with .vx0".to_string());
+            }
+            if let Err(e) = ServiceMetadata::parse(metadata) {
+                return bad(e.to_string());
+            }
+            registered.insert(domain.clone());
+            Ok(())
+        }
+        ControlRequest::RefreshService { domain } => {
+            let live = match state.node.get() {
+                Some(node) => node.find_service(domain).await.is_some(),
+                None => false,
+            };
+            if live || registered.contains(domain) {
+                Ok(())
+            } else {
+                Err((
+                    ControlErrorCode::NotFound,
+                    format!("No live service at {}", domain),
+                ))
+            }
+        }
+        ControlRequest::Block { entry } | ControlRequest::Unblock { entry } => {
+            if state.node.get().is_none() {
+                return Err((
+                    ControlErrorCode::Failed,
+                    format!("This daemon has no ACL to change for {}", entry),
+                ));
+            }
+            entry
+                .parse::<AclEntry>()
+                .map(drop)
+                .or_else(|e| bad(e.to_string()))
+        }
+        ControlRequest::Connect { peer_asn, .. } => {
+            let Some(node) = state.node.get() else {
+                return Err((
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers".to_string(),
+                ));
+            };
+            let theirs = NodeTier::from_asn(*peer_asn);
+            if node.tier.can_peer_with(&theirs) {
+                Ok(())
+            } else {
+                bad(format!(
+                    "{:?} nodes cannot peer with {:?} nodes",
+                    node.tier, theirs
+                ))
+            }
+        }
+        ControlRequest::DnsCacheFlush {
+            pattern: Some(pattern),
+            ..
+        } => pattern
+            .parse::<NamePattern>()
+            .map(drop)
+            .or_else(|e| bad(e.to_string())),
+        ControlRequest::Disconnect { .. }
+        | ControlRequest::Maintenance { .. }
+        | ControlRequest::PinRoute { .. }
+        | ControlRequest::UnpinRoute { .. }
+        | ControlRequest::QuarantineClear { .. }
+        | ControlRequest::UnregisterService { .. }
+        | ControlRequest::Reload => bad(format!(
+            "{} cannot be undone, so it cannot run in an atomic batch",
+            request.name()
+        )),
+        ControlRequest::Batch { .. } => bad("Batches cannot be nested".to_string()),
+        ControlRequest::Subscribe => bad("Subscriptions cannot run in a batch".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// The inverse of an applied command; routes are restored from a snapshot
+/// instead
+enum Undo {
+    Unpublish(Uuid),
+    Block(String),
+    Unblock(String),
+    RemovePeer(NodeId),
+}
+
+impl Undo {
+    fn of(request: ControlRequest, reply: &ControlResponse) -> Option<Self> {
+        match (request, reply) {
+            (
+                ControlRequest::RegisterService { .. },
+                ControlResponse::Registered { service_id, .. },
+            ) => Some(Undo::Unpublish(*service_id)),
+            // Only undo blocks that changed something
+            (ControlRequest::Block { entry }, ControlResponse::Acl { changed: true, .. }) => {
+                Some(Undo::Unblock(entry))
+            }
+            (ControlRequest::Unblock { entry }, ControlResponse::Acl { changed: true, .. }) => {
+                Some(Undo::Block(entry))
+            }
+            (_, ControlResponse::Connected { peer }) => Some(Undo::RemovePeer(peer.peer_id)),
+            _ => None,
+        }
+    }
+
+    async fn apply(self, state: &ServerState) {
+        let result = match (&self, state.node.get(), state.services.get()) {
+            (Undo::Unpublish(service_id), _, Some(services)) => services
+                .unpublish(*service_id)
+                .await
+                .map_err(|e| e.to_string()),
+            (Undo::Block(entry), Some(node), _) => entry
+                .parse::<AclEntry>()
+                .and_then(|entry| node.acl.block(entry))
+                .map(drop)
+                .map_err(|e| e.to_string()),
+            (Undo::Unblock(entry), Some(node), _) => entry
+                .parse::<AclEntry>()
+                .and_then(|entry| node.acl.unblock(entry))
+                .map(drop)
+                .map_err(|e| e.to_string()),
+            (Undo::RemovePeer(peer_id), Some(node), _) => {
+                node.remove_peer(peer_id).await.map_err(|e| e.to_string())
+            }
+            _ => Err("nothing to undo it with".to_string()),
+        };
+        if let Err(e) = result {
+            tracing::error!("Failed to roll back a batch command: {}", e);
+        }
+    }
+}
+
+/// Split a script line into words like a shell would: quotes group words,
+/// a backslash escapes the next character, and `#` starts a comment
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some('"') | None, '\\') => {
+                let escaped = chars
+                    .next()
+                    .ok_or_else(|| "Trailing backslash".to_string())?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, '#') if word.is_none() => break,
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(open) = quote {
+        return Err(format!("Unterminated {} quote", open));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Read a JSON array of requests, numbering each by the line it starts on
+pub fn parse_json(input: &str) -> Result<Vec<BatchCommand>, String> {
+    let line_of = |offset: usize| input[..offset].matches('\n').count() + 1;
+    let start = input
+        .find('[')
+        .ok_or_else(|| "Expected a JSON array".to_string())?;
+
+    // Find where each top-level element starts and ends
+    let mut elements = Vec::new();
+    let mut element_start = None;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut end = None;
+    for (offset, c) in input[start + 1..].char_indices() {
+        let offset = start + 1 + offset;
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' | '[' => depth += 1,
+            '}' | ']' if depth > 0 => depth -= 1,
+            ']' => {
+                end = Some(offset);
+                if let Some(from) = element_start.take() {
+                    elements.push((from, offset));
+                }
+                break;
+            }
+            ',' if depth == 0 => {
+                let from = element_start
+                    .take()
+                    .ok_or_else(|| format!("line {}: empty array element", line_of(offset)))?;
+                elements.push((from, offset));
+                continue;
+            }
+            _ => {}
+        }
+        if !c.is_whitespace() && element_start.is_none() {
+            element_start = Some(offset);
+        }
+    }
+    let end = end.ok_or_else(|| "Unterminated JSON array".to_string())?;
+    if !input[end + 1..].trim().is_empty() {
+        return Err(format!(
+            "line {}: unexpected input after the array",
+            line_of(end + 1)
+        ));
+    }
+
+    elements
+        .into_iter()
+        .map(|(from, to)| {
+            let line = line_of(from);
+            serde_json::from_str(&input[from..to])
+                .map(|request| BatchCommand { line, request })
+                .map_err(|e| format!("line {}: {}", line, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ControlConfig;
+    use crate::control::ControlClient;
+    use crate::network::bgp::BGPDaemon;
+    use crate::network::dns::Vx0DNS;
+    use crate::node::services::ServiceRegistry;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use crate::node::Vx0Node;
+    use std::sync::Arc;
+
+    struct Daemon {
+        client: ControlClient,
+        bgp: Arc<BGPDaemon>,
+        node: Arc<Vx0Node>,
+    }
+
+    async fn daemon() -> Daemon {
+        let mut config = testing::config(NodeTier::Backbone);
+        config.network.acl.state_file = None;
+        let node = Arc::new(Vx0Node::new(config).unwrap());
+        let bgp = Arc::new(BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0));
+        let dns = Vx0DNS::new().into_shared();
+        let socket_path =
+            std::env::temp_dir().join(format!("vx0net-batch-{}.sock", Uuid::new_v4()));
+        let server = ControlServer::new(
+            ControlConfig {
+                socket_path: socket_path.display().to_string(),
+                ..ControlConfig::default()
+            },
+            Arc::clone(&bgp),
+        );
+        server.set_node(Arc::clone(&node));
+        server.set_services(Arc::new(ServiceRegistry::new(Arc::clone(&node), dns)));
+        server.start().await.unwrap();
+        Daemon {
+            client: ControlClient::unix(&socket_path),
+            bgp,
+            node,
+        }
+    }
+
+    const SCRIPT: &str = r#"[
+        {"command": "announce_route", "network": "10.1.0.0/16", "next_hop": "10.0.0.1"},
+        {"command": "register_service", "name": "chat", "domain": "chat.vx0", "port": 6667},
+        {"command": "block", "entry": "AS66001"},
+
+        {"command": "register_service", "name": "web", "domain": "web.example", "port": 80},
+        {"command": "announce_route", "network": "10.2.0.0/16", "next_hop": "10.0.0.1"}
+    ]"#;
+
+    async fn run_script(daemon: &Daemon, atomic: bool) -> Vec<BatchResult> {
+        let commands = parse_json(SCRIPT).unwrap();
+        match daemon
+            .client
+            .request(&ControlRequest::Batch { commands, atomic })
+            .await
+            .unwrap()
+        {
+            ControlResponse::Batch { results } => results,
+            other => panic!("expected batch results, got {:?}", other),
+        }
+    }
+
+    async fn networks(bgp: &BGPDaemon) -> Vec<String> {
+        let mut networks: Vec<String> = bgp
+            .get_routes()
+            .await
+            .iter()
+            .map(|route| route.network.to_string())
+            .collect();
+        networks.sort();
+        networks
+    }
+
+    #[tokio::test]
+    async fn test_batch_applies_what_it_can() {
+        let daemon = daemon().await;
+        let results = run_script(&daemon, false).await;
+
+        let failed: Vec<usize> = results
+            .iter()
+            .filter(|result| result.failed())
+            .map(|result| result.line)
+            .collect();
+        assert_eq!(failed, [6]);
+        assert_eq!(networks(&daemon.bgp).await, ["10.1.0.0/16", "10.2.0.0/16"]);
+        assert!(daemon.node.find_service("chat.vx0").await.is_some());
+        assert_eq!(daemon.node.acl.blocked().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_atomic_batch_applies_nothing_when_one_command_is_invalid() {
+        let daemon = daemon().await;
+        let results = run_script(&daemon, true).await;
+
+        let outcomes: Vec<(usize, bool)> = results
+            .iter()
+            .map(|result| (result.line, result.failed()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [(2, false), (3, false), (4, false), (6, true), (7, false)]
+        );
+        assert!(results
+            .iter()
+            .all(|result| result.failed() || matches!(result.outcome, BatchOutcome::Skipped)));
+        assert!(daemon.bgp.get_routes().await.is_empty());
+        assert!(daemon.node.find_service("chat.vx0").await.is_none());
+        assert!(daemon.node.acl.blocked().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_atomic_batch_rolls_back_when_applying_fails() {
+        let daemon = daemon().await;
+        daemon
+            .client
+            .request(&ControlRequest::AnnounceRoute {
+                network: "10.9.0.0/16".parse().unwrap(),
+                next_hop: "10.0.0.1".parse().unwrap(),
+                communities: Vec::new(),
+            })
+            .await
+            .unwrap();
+
+        // Valid throughout, but nobody answers at the peer's address
+        let commands = parse_json(
+            r#"[
+            {"command": "withdraw_route", "network": "10.9.0.0/16"},
+            {"command": "announce_route", "network": "10.1.0.0/16", "next_hop": "10.0.0.1"},
+            {"command": "register_service", "name": "chat", "domain": "chat.vx0", "port": 6667},
+            {"command": "block", "entry": "AS66001"},
+            {"command": "connect", "peer": "127.0.0.1", "peer_asn": 65002}
+        ]"#,
+        )
+        .unwrap();
+        let ControlResponse::Batch { results } = daemon
+            .client
+            .request(&ControlRequest::Batch {
+                commands,
+                atomic: true,
+            })
+            .await
+            .unwrap()
+        else {
+            panic!("expected batch results");
+        };
+
+        assert!(results[..4]
+            .iter()
+            .all(|result| matches!(result.outcome, BatchOutcome::RolledBack)));
+        assert!(results[4].failed());
+        assert_eq!(results[4].line, 6);
+        assert_eq!(networks(&daemon.bgp).await, ["10.9.0.0/16"]);
+        assert!(daemon.node.find_service("chat.vx0").await.is_none());
+        assert!(daemon.node.acl.blocked().is_empty());
+        assert_eq!(daemon.node.get_peer_count().await, 0);
+    }
+
+    #[test]
+    fn test_script_lines_split_like_a_shell() {
+        assert_eq!(
+            split_words(
+                r#"register-service chat 'chat room.vx0' 6667 --description "It's \"up\"" # note"#
+            )
+            .unwrap(),
+            [
+                "register-service",
+                "chat",
+                "chat room.vx0",
+                "6667",
+                "--description",
+                "It's \"up\"",
+            ]
+        );
+        assert!(split_words("   # only a comment").unwrap().is_empty());
+        assert!(split_words("block \"AS66001").is_err());
+    }
+}
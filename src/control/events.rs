@@ -0,0 +1,238 @@
+//! Events streamed to control clients that sent `subscribe`.
+//!
+//! After replying `subscribed`, the daemon keeps the connection for events
+//! alone: every change to the Loc-RIB, to the peers and to the directory's
+//! zones, and every time hold-down starts or ends, goes out as one line,
+//! a [`ControlReply`](super::ControlReply) carrying the subscription's
+//! request id and an `event` status. Nothing is replayed: a client that
+//! needs the current state asks for it after subscribing, and one that
+//! falls too far behind is told how many events it missed.
+
+use crate::network::bgp::rib::RibChange;
+use crate::network::bgp::{BGPDaemon, Prefix, RouteEntry};
+use crate::network::dns::cache::CacheSource;
+use crate::network::dns::changes::DnsChange;
+use crate::network::dns::SharedDns;
+use crate::node::goodbye::GoodbyeReason;
+use crate::node::{NodeId, PeerEvent, Vx0Node};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch};
+
+/// Something that changed in the daemon. Events are part of the reply
+/// schema, so a new kind of event bumps `SCHEMA_VERSION` like any other
+/// change of shape; match with a wildcard arm all the same, since clients
+/// outlive the daemon they were built against
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum DaemonEvent {
+    /// A route was installed in the Loc-RIB or replaced there
+    RouteInstalled { route: RouteEntry },
+    /// The Loc-RIB no longer has a route for `network`
+    RouteWithdrawn { network: Prefix },
+    /// A known peer was re-authenticated at a new address
+    PeerAddressChanged {
+        peer_id: NodeId,
+        peer_asn: u32,
+        old: IpAddr,
+        new: IpAddr,
+    },
+    /// A peer was dropped
+    PeerRemoved { peer_id: NodeId, peer_asn: u32 },
+    /// A peer said goodbye
+    PeerDeparted {
+        peer_id: NodeId,
+        peer_asn: u32,
+        reason: GoodbyeReason,
+    },
+    /// A quicker peer took the place of the slowest one
+    PeerSwapped { old: NodeId, new: NodeId },
+    /// The operator took the peers on an ASN down
+    PeerAdminDown { peer_asn: u32 },
+    /// A peer crossed the quarantine threshold
+    PeerQuarantined { peer_asn: u32 },
+    /// Names under a zone gained or lost records
+    ZoneChanged {
+        zone: String,
+        serial: u32,
+        names: Vec<String>,
+        source: CacheSource,
+    },
+    /// Route processing was held down or resumed
+    HoldDown { held_down: bool },
+    /// The subscriber fell behind and `missed` events were dropped
+    Lagged { missed: u64 },
+    /// Never sent by the daemon: the client lost its connection and
+    /// subscribed again, missing whatever happened in between
+    Resubscribed,
+}
+
+impl From<RibChange> for DaemonEvent {
+    fn from(change: RibChange) -> Self {
+        match change {
+            RibChange::Advertise(route) => DaemonEvent::RouteInstalled { route },
+            RibChange::Withdraw(network) => DaemonEvent::RouteWithdrawn { network },
+        }
+    }
+}
+
+impl From<PeerEvent> for DaemonEvent {
+    fn from(event: PeerEvent) -> Self {
+        match event {
+            PeerEvent::AddressChanged {
+                peer_id,
+                peer_asn,
+                old,
+                new,
+            } => DaemonEvent::PeerAddressChanged {
+                peer_id,
+                peer_asn,
+                old,
+                new,
+            },
+            PeerEvent::Removed { peer_id, peer_asn } => {
+                DaemonEvent::PeerRemoved { peer_id, peer_asn }
+            }
+            PeerEvent::Departed {
+                peer_id,
+                peer_asn,
+                reason,
+            } => DaemonEvent::PeerDeparted {
+                peer_id,
+                peer_asn,
+                reason,
+            },
+            PeerEvent::Swapped { old, new, .. } => DaemonEvent::PeerSwapped { old, new },
+            PeerEvent::AdminDown { peer_asn } => DaemonEvent::PeerAdminDown { peer_asn },
+            PeerEvent::Quarantined { peer_asn } => DaemonEvent::PeerQuarantined { peer_asn },
+        }
+    }
+}
+
+impl From<DnsChange> for DaemonEvent {
+    fn from(change: DnsChange) -> Self {
+        DaemonEvent::ZoneChanged {
+            zone: change.zone,
+            serial: change.serial,
+            names: change.names,
+            source: change.source,
+        }
+    }
+}
+
+/// The daemon's event channels, as one subscriber follows them
+pub(super) struct EventSources {
+    routes: broadcast::Receiver<RibChange>,
+    peers: Option<broadcast::Receiver<PeerEvent>>,
+    zones: Option<broadcast::Receiver<DnsChange>>,
+    hold_down: Option<watch::Receiver<bool>>,
+}
+
+enum Next {
+    Route(Result<RibChange, RecvError>),
+    Peer(Result<PeerEvent, RecvError>),
+    Zone(Result<DnsChange, RecvError>),
+    HoldDown(bool),
+    HoldDownGone,
+}
+
+impl EventSources {
+    pub(super) async fn open(
+        bgp: &BGPDaemon,
+        node: Option<&Vx0Node>,
+        directory: Option<&SharedDns>,
+    ) -> Self {
+        let (_, routes) = bgp.follow_routes().await;
+        let zones = match directory {
+            Some(dns) => Some(dns.read().await.subscribe()),
+            None => None,
+        };
+        let mut hold_down = bgp.subscribe_hold_down();
+        hold_down.mark_unchanged();
+        EventSources {
+            routes,
+            peers: node.map(Vx0Node::subscribe_peer_events),
+            zones,
+            hold_down: Some(hold_down),
+        }
+    }
+
+    /// The next event; `None` once the daemon is shutting down
+    pub(super) async fn next(&mut self) -> Option<DaemonEvent> {
+        loop {
+            let next = tokio::select! {
+                change = self.routes.recv() => Next::Route(change),
+                event = recv(&mut self.peers) => Next::Peer(event),
+                change = recv(&mut self.zones) => Next::Zone(change),
+                changed = changed(&mut self.hold_down) => match changed {
+                    Some(held_down) => Next::HoldDown(held_down),
+                    None => Next::HoldDownGone,
+                },
+            };
+            match next {
+                Next::Route(Ok(change)) => return Some(change.into()),
+                Next::Peer(Ok(event)) => return Some(event.into()),
+                Next::Zone(Ok(change)) => return Some(change.into()),
+                Next::HoldDown(held_down) => return Some(DaemonEvent::HoldDown { held_down }),
+                Next::Route(Err(RecvError::Lagged(missed)))
+                | Next::Peer(Err(RecvError::Lagged(missed)))
+                | Next::Zone(Err(RecvError::Lagged(missed))) => {
+                    return Some(DaemonEvent::Lagged { missed })
+                }
+                Next::Route(Err(RecvError::Closed)) => return None,
+                Next::Peer(Err(RecvError::Closed)) => self.peers = None,
+                Next::Zone(Err(RecvError::Closed)) => self.zones = None,
+                Next::HoldDownGone => self.hold_down = None,
+            }
+        }
+    }
+}
+
+/// Receive from a channel that may not be there, waiting forever if not
+async fn recv<T: Clone>(receiver: &mut Option<broadcast::Receiver<T>>) -> Result<T, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn changed(receiver: &mut Option<watch::Receiver<bool>>) -> Option<bool> {
+    match receiver {
+        Some(receiver) => match receiver.changed().await {
+            Ok(()) => Some(*receiver.borrow_and_update()),
+            Err(_) => None,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route_changes_and_hold_down_become_events() {
+        let bgp = BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0);
+        let mut events = EventSources::open(&bgp, None, None).await;
+
+        bgp.add_route_with_communities(
+            "10.1.0.0/16".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            crate::network::bgp::BGPOrigin::IGP,
+            Vec::new(),
+        )
+        .await
+        .unwrap();
+        let Some(DaemonEvent::RouteInstalled { route }) = events.next().await else {
+            panic!("expected the announced route");
+        };
+        assert_eq!(route.network, "10.1.0.0/16".parse().unwrap());
+
+        let encoded = serde_json::to_value(DaemonEvent::HoldDown { held_down: true }).unwrap();
+        assert_eq!(encoded["event"], "hold_down");
+        assert_eq!(encoded["held_down"], true);
+    }
+}
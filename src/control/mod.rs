@@ -0,0 +1,2303 @@
+//! Local control socket used by the `vx0net` CLI to query and steer a
+//! running daemon.
+//!
+//! Each request and reply is a single line of JSON. Every request carries a
+//! client-generated id; mutating commands are idempotent by id, so a client
+//! that lost a reply can resend the same request and gets the original
+//! result back instead of applying the change twice.
+//!
+//! The Unix socket is protected by file permissions (mode 0600). The optional
+//! TCP fallback instead requires a token read from a file only the daemon's
+//! user can read.
+
+use crate::build_info::BuildInfo;
+use crate::config::reload::{ReloadReport, Reloader};
+use crate::config::{ControlConfig, Vx0Config};
+use crate::error::Report;
+use crate::monitoring::capacity::{CapacityAlert, CapacityReport, HeardDigest};
+use crate::monitoring::capture::{Capture, CapturedEvent};
+use crate::monitoring::readiness::ReadinessReport;
+use crate::monitoring::tasks::TaskInfo;
+use crate::monitoring::timing::{Stage, TimingReport};
+use crate::monitoring::{crash, Subsystem, Supervisor};
+use crate::network::acl::{AclEntry, AclError};
+use crate::network::bgp::explain::Explanation;
+use crate::network::bgp::multihoming::MultihomingStatus;
+use crate::network::bgp::pins::RoutePin;
+use crate::network::bgp::policy::{DryRunReport, PolicyFragment};
+use crate::network::bgp::propagation::PropagationStats;
+use crate::network::bgp::{BGPDaemon, BGPError, BGPOrigin, Community, Prefix, RouteEntry};
+use crate::network::dns::cache::{CacheEntryInfo, CacheFilter, CacheStats, ResolverCache};
+use crate::network::dns::history::HistoryEntry;
+use crate::network::dns::quota::OriginUsage;
+use crate::network::dns::SharedDns;
+use crate::network::firewall::{FirewallManager, FirewallStatus};
+use crate::node::admin::{AdminDown, AdminError};
+use crate::node::asn_registry::AsnGrant;
+use crate::node::bootstrap_health::BootstrapStatus;
+use crate::node::capabilities::{Capabilities, NodeDirectoryEntry};
+use crate::node::goodbye::GoodbyeReason;
+use crate::node::metadata::ServiceMetadata;
+use crate::node::observed::AddressReport;
+use crate::node::quarantine::QuarantineStatus;
+use crate::node::redundancy::{GroupMember, GroupStatus, RedundancyError};
+use crate::node::search::{ServiceFilter, ServiceSummary};
+use crate::node::services::{PropagationReport, ServiceRegistry};
+use crate::node::{
+    HostedService, NodeError, NodeId, PeerConnection, ServiceStatus, ServiceType, Vx0Node,
+};
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{watch, Mutex, Semaphore};
+use uuid::Uuid;
+
+pub mod batch;
+pub mod events;
+pub mod schema;
+
+use batch::{BatchCommand, BatchResult};
+use events::{DaemonEvent, EventSources};
+use schema::ControlSchema;
+
+pub const DEFAULT_SOCKET_PATH: &str = "/var/run/vx0net/control.sock";
+
+/// How long results of mutating requests are kept for replays
+const RESULT_TTL: Duration = Duration::from_secs(300);
+const MAX_CACHED_RESULTS: usize = 1024;
+/// Longest request line a control client may send; longer lines end the
+/// session before they are buffered in full
+const MAX_REQUEST_LEN: usize = 1024 * 1024;
+/// Client-side attempts when the connection drops before a reply arrives
+const CLIENT_ATTEMPTS: usize = 3;
+const CLIENT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Loc-RIB
+    Routes,
+    /// Adj-RIB-In for a peer
+    RoutesReceived {
+        peer_asn: u32,
+    },
+    /// Adj-RIB-Out for a peer
+    RoutesAdvertised {
+        peer_asn: u32,
+    },
+    /// Why a prefix is or is not in the Loc-RIB
+    ExplainRoute {
+        network: Prefix,
+    },
+    /// Delays of stamped routes that arrived here, by origin tier
+    PropagationStats,
+    /// Build metadata of the running daemon
+    Version,
+    /// Dry-run a candidate policy fragment (TOML) against installed routes
+    PolicyTest {
+        policy: String,
+        #[serde(default)]
+        peer_asn: Option<u32>,
+    },
+    /// Originate a route from this node
+    AnnounceRoute {
+        network: Prefix,
+        next_hop: IpAddr,
+        /// Communities the route carries, e.g. to ask receivers to treat it
+        /// as a backup path
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        communities: Vec<Community>,
+    },
+    /// Stop originating a route
+    WithdrawRoute {
+        network: Prefix,
+    },
+    /// Select the path from `via_asn` for a prefix ahead of normal
+    /// evaluation, for `duration_secs` if given; refused for prefixes in no
+    /// RIB unless `allow_missing`
+    PinRoute {
+        network: Prefix,
+        via_asn: u32,
+        #[serde(default)]
+        duration_secs: Option<u64>,
+        #[serde(default)]
+        allow_missing: bool,
+    },
+    /// Return a prefix to normal selection
+    UnpinRoute {
+        network: Prefix,
+    },
+    /// Host a service and publish its records, optionally waiting until
+    /// `wait_for` DNS-serving peers confirm storing them
+    RegisterService {
+        name: String,
+        domain: String,
+        port: u16,
+        #[serde(default)]
+        wait_for: Option<usize>,
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+        /// Flat metadata map, validated before the service is registered
+        #[serde(default)]
+        metadata: HashMap<String, String>,
+        /// web, email, file, chat, database or a custom type; the service
+        /// name when left out
+        #[serde(default)]
+        service_type: Option<String>,
+    },
+    /// Restart the TTL of every service hosted under a domain
+    RefreshService {
+        domain: String,
+    },
+    /// Stop hosting a service; `service_type` and `port` pick one out when
+    /// several share the domain
+    UnregisterService {
+        domain: String,
+        #[serde(default)]
+        service_type: Option<String>,
+        #[serde(default)]
+        port: Option<u16>,
+    },
+    /// Services this daemon hosts, listed or not
+    ListServices,
+    /// Refuse all contact with a peer (ASN, address or prefix, node id),
+    /// tearing down any existing session
+    Block {
+        entry: String,
+    },
+    Unblock {
+        entry: String,
+    },
+    /// Open a BGP session with the node at an address and add it as a peer
+    Connect {
+        peer: IpAddr,
+        peer_asn: u32,
+    },
+    /// Say goodbye to the peer at an address and drop it
+    Disconnect {
+        peer: IpAddr,
+    },
+    /// Say goodbye to every peer and drop them, telling them when to expect
+    /// this node back; in a redundancy group, only once no partner is in
+    /// maintenance, not ready or held down after its own, unless `force`d
+    Maintenance {
+        #[serde(default)]
+        return_in_secs: Option<u64>,
+        #[serde(default)]
+        force: bool,
+    },
+    /// Connected peers and what each advertises
+    Peers,
+    /// Take the peers on an ASN down administratively, keeping their
+    /// configuration; persists across restarts
+    PeerDisable {
+        asn: u32,
+    },
+    /// Let peers taken down with `PeerDisable` reconnect as usual
+    PeerEnable {
+        asn: u32,
+    },
+    /// Peers with a quarantine score, and the violations behind it
+    QuarantineList,
+    /// Forget a peer's quarantine score, lifting any demotion or quarantine
+    QuarantineClear {
+        asn: u32,
+    },
+    /// The host firewall rules the daemon put in place and believes it owns
+    FirewallStatus,
+    /// This node's redundancy group, asking each partner how it stands
+    Group,
+    /// Nodes listed in the directory
+    Nodes,
+    /// Bootstrap nodes with their health scores
+    BootstrapList,
+    /// Probe every bootstrap node now, quarantined ones included
+    BootstrapProbe,
+    /// ASN grants known to this node, from its own registry or trusted ones
+    AsnList,
+    /// Tasks the daemon is running, with their state and last heartbeat
+    Tasks,
+    /// How long each phase of the daemon's startup took
+    Startup,
+    /// Per-tier statistics from the directory and the capacity alerts in effect
+    Capacity,
+    /// Run the readiness checks, as `/readyz` does
+    Readiness,
+    /// The configured public address, the one announced, and what peers see
+    PublicAddress,
+    /// Cached DNS answers for names matching `pattern`, an exact name or
+    /// `*.suffix`; only negative answers with `negative`
+    DnsCache {
+        #[serde(default)]
+        pattern: Option<String>,
+        #[serde(default)]
+        negative: bool,
+    },
+    /// Drop the cached DNS answers `DnsCache` would list
+    DnsCacheFlush {
+        #[serde(default)]
+        pattern: Option<String>,
+        #[serde(default)]
+        negative: bool,
+    },
+    /// Hit, miss and eviction counts of the DNS cache
+    DnsCacheStats,
+    /// Who registered what under `domain`, and when; without one, every
+    /// name this node registered or changed itself
+    DnsHistory {
+        #[serde(default)]
+        domain: Option<String>,
+    },
+    /// Listed services of a type carrying all of `tags`, optionally also
+    /// asking directly connected peers and ordering by latency to the owner
+    FindServices {
+        #[serde(default)]
+        service_type: Option<String>,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        nearby: bool,
+        #[serde(default)]
+        fan_out: bool,
+    },
+    /// Re-read the configuration and apply what can change at runtime
+    Reload,
+    /// Describe the commands, request and reply shapes, and enabled features
+    Schema,
+    /// Turn this connection into a stream of events; not allowed in a batch
+    Subscribe,
+    /// Run commands in order; an atomic batch is validated in full first
+    /// and undone if any command fails
+    Batch {
+        commands: Vec<BatchCommand>,
+        #[serde(default)]
+        atomic: bool,
+    },
+}
+
+/// Commands whose results are cached for replays by request id
+pub const MUTATING_COMMANDS: &[&str] = &[
+    "announce_route",
+    "withdraw_route",
+    "pin_route",
+    "unpin_route",
+    "register_service",
+    "refresh_service",
+    "unregister_service",
+    "block",
+    "unblock",
+    "connect",
+    "disconnect",
+    "maintenance",
+    "peer_disable",
+    "peer_enable",
+    "quarantine_clear",
+    "bootstrap_probe",
+    "dns_cache_flush",
+    "reload",
+    "batch",
+];
+
+impl ControlRequest {
+    pub fn is_mutating(&self) -> bool {
+        MUTATING_COMMANDS.contains(&self.name())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlRequest::Routes => "routes",
+            ControlRequest::RoutesReceived { .. } => "routes_received",
+            ControlRequest::RoutesAdvertised { .. } => "routes_advertised",
+            ControlRequest::ExplainRoute { .. } => "explain_route",
+            ControlRequest::PropagationStats => "propagation_stats",
+            ControlRequest::Version => "version",
+            ControlRequest::PolicyTest { .. } => "policy_test",
+            ControlRequest::AnnounceRoute { .. } => "announce_route",
+            ControlRequest::WithdrawRoute { .. } => "withdraw_route",
+            ControlRequest::PinRoute { .. } => "pin_route",
+            ControlRequest::UnpinRoute { .. } => "unpin_route",
+            ControlRequest::RegisterService { .. } => "register_service",
+            ControlRequest::RefreshService { .. } => "refresh_service",
+            ControlRequest::UnregisterService { .. } => "unregister_service",
+            ControlRequest::ListServices => "list_services",
+            ControlRequest::Block { .. } => "block",
+            ControlRequest::Unblock { .. } => "unblock",
+            ControlRequest::Connect { .. } => "connect",
+            ControlRequest::Disconnect { .. } => "disconnect",
+            ControlRequest::Maintenance { .. } => "maintenance",
+            ControlRequest::Peers => "peers",
+            ControlRequest::PeerDisable { .. } => "peer_disable",
+            ControlRequest::PeerEnable { .. } => "peer_enable",
+            ControlRequest::QuarantineList => "quarantine_list",
+            ControlRequest::FirewallStatus => "firewall_status",
+            ControlRequest::Group => "group",
+            ControlRequest::QuarantineClear { .. } => "quarantine_clear",
+            ControlRequest::Nodes => "nodes",
+            ControlRequest::BootstrapList => "bootstrap_list",
+            ControlRequest::BootstrapProbe => "bootstrap_probe",
+            ControlRequest::AsnList => "asn_list",
+            ControlRequest::Tasks => "tasks",
+            ControlRequest::Startup => "startup",
+            ControlRequest::Capacity => "capacity",
+            ControlRequest::Readiness => "readiness",
+            ControlRequest::PublicAddress => "public_address",
+            ControlRequest::DnsCache { .. } => "dns_cache",
+            ControlRequest::DnsCacheFlush { .. } => "dns_cache_flush",
+            ControlRequest::DnsCacheStats => "dns_cache_stats",
+            ControlRequest::DnsHistory { .. } => "dns_history",
+            ControlRequest::FindServices { .. } => "find_services",
+            ControlRequest::Reload => "reload",
+            ControlRequest::Schema => "schema",
+            ControlRequest::Subscribe => "subscribe",
+            ControlRequest::Batch { .. } => "batch",
+        }
+    }
+}
+
+/// A request as sent on the wire
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ControlEnvelope {
+    pub request_id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub request: ControlRequest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlErrorCode {
+    BadRequest,
+    Unauthorized,
+    NotFound,
+    Busy,
+    Timeout,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ControlResponse {
+    /// `pins` lists the route pins in effect, with the Loc-RIB only
+    Routes {
+        routes: Vec<RouteEntry>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pins: Vec<RoutePin>,
+    },
+    PolicyReport {
+        report: DryRunReport,
+    },
+    Explanation {
+        explanation: Explanation,
+    },
+    PropagationStats {
+        stats: PropagationStats,
+    },
+    Version {
+        build: BuildInfo,
+    },
+    /// A mutating command took effect; `rib_version` is the Loc-RIB version after it
+    Applied {
+        rib_version: u64,
+    },
+    /// A service was published; `propagation` is set when confirmation was
+    /// requested
+    Registered {
+        service_id: Uuid,
+        domain: String,
+        ttl_secs: u64,
+        #[serde(default)]
+        propagation: Option<PropagationReport>,
+    },
+    /// The services under a domain were refreshed and now live for another
+    /// `ttl_secs`
+    Refreshed {
+        domain: String,
+        ttl_secs: u64,
+    },
+    /// A service stopped being hosted; `remaining` services still share
+    /// its domain, which keeps its addresses while any do
+    Unregistered {
+        service: ServiceSummary,
+        remaining: usize,
+    },
+    /// The blocklist after a block or unblock; `changed` is false when the
+    /// entry was already in the requested state
+    Acl {
+        changed: bool,
+        blocked: Vec<String>,
+        torn_down: usize,
+    },
+    /// A session with the peer is up and it was added
+    Connected {
+        peer: PeerConnection,
+    },
+    /// Peers were told goodbye and dropped
+    /// `unreachable` lists redundancy partners that could not be asked, and
+    /// `overridden` those whose turn a forced maintenance went past
+    Departed {
+        peers: usize,
+        #[serde(default)]
+        expected_return: Option<DateTime<Utc>>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        unreachable: Vec<GroupMember>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        overridden: Vec<GroupMember>,
+    },
+    /// `local` is what this node itself advertises; `admin_down` lists the
+    /// ASNs taken down, connected or not, and `quarantine` those with a
+    /// quarantine score
+    Peers {
+        local: Capabilities,
+        peers: Vec<PeerConnection>,
+        #[serde(default)]
+        admin_down: Vec<AdminDown>,
+        #[serde(default)]
+        quarantine: Vec<QuarantineStatus>,
+        /// Which parent carries an Edge node's traffic, when multihomed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        multihoming: Option<MultihomingStatus>,
+    },
+    /// The ASN's administrative state after a disable or enable; `peers` is
+    /// how many connected peers it applied to
+    PeerAdmin {
+        asn: u32,
+        admin_down: bool,
+        peers: usize,
+    },
+    Quarantine {
+        peers: Vec<QuarantineStatus>,
+    },
+    /// Whether the ASN had a score to clear
+    QuarantineCleared {
+        asn: u32,
+        cleared: bool,
+    },
+    Firewall {
+        firewall: FirewallStatus,
+    },
+    /// `None` outside a redundancy group
+    Group {
+        group: Option<GroupStatus>,
+    },
+    /// `local` is this node's ID, when the daemon runs one; `over_quota`
+    /// lists origins whose synced records were refused or are over quota,
+    /// and `group` this node's redundancy group
+    Nodes {
+        nodes: Vec<NodeDirectoryEntry>,
+        #[serde(default)]
+        local: Option<NodeId>,
+        #[serde(default)]
+        over_quota: Vec<OriginUsage>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupStatus>,
+    },
+    /// Bootstrap nodes, best score first
+    Bootstrap {
+        nodes: Vec<BootstrapStatus>,
+    },
+    /// Grants by ASN, and this node's registry key when it runs a registry
+    AsnGrants {
+        grants: Vec<AsnGrant>,
+        #[serde(default)]
+        registry_key: Option<String>,
+    },
+    /// Live tasks by subsystem, oldest first
+    Tasks {
+        tasks: Vec<TaskInfo>,
+    },
+    /// Startup phases in the order they ran; `None` while still starting
+    Startup {
+        report: Option<TimingReport>,
+    },
+    /// `report` is `None` until the first reading; `heard` is the freshest
+    /// digest a Backbone or Regional node announced
+    Capacity {
+        report: Option<Box<CapacityReport>>,
+        alerts: Vec<CapacityAlert>,
+        #[serde(default)]
+        heard: Option<Box<HeardDigest>>,
+    },
+    Readiness {
+        report: ReadinessReport,
+    },
+    PublicAddress {
+        report: AddressReport,
+    },
+    /// Cached DNS answers by name, with the cache's counters
+    DnsCache {
+        entries: Vec<CacheEntryInfo>,
+        stats: CacheStats,
+    },
+    /// How many cached answers a flush dropped
+    DnsCacheFlushed {
+        removed: usize,
+        stats: CacheStats,
+    },
+    DnsCacheStats {
+        stats: CacheStats,
+    },
+    /// Registration changes, oldest first
+    DnsHistory {
+        entries: Vec<HistoryEntry>,
+    },
+    /// Services matching a search; `status` is `Failed` for those whose
+    /// health check failed
+    Services {
+        services: Vec<ServiceSummary>,
+    },
+    /// Every changed key and whether it was applied, deferred to restart
+    /// or refused
+    Reloaded {
+        report: ReloadReport,
+    },
+    Schema {
+        schema: Box<ControlSchema>,
+    },
+    /// Events follow on this connection until either side closes it
+    Subscribed {
+        schema_version: u32,
+    },
+    /// One event on a subscribed connection
+    Event {
+        event: DaemonEvent,
+    },
+    /// One result per command of a batch, in order
+    Batch {
+        results: Vec<BatchResult>,
+    },
+    Error {
+        code: ControlErrorCode,
+        message: String,
+    },
+}
+
+impl ControlResponse {
+    fn error(code: ControlErrorCode, message: impl Into<String>) -> Self {
+        ControlResponse::Error {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Whether the outcome is unknown because a command ran out of time
+    fn timed_out(&self) -> bool {
+        match self {
+            ControlResponse::Error { code, .. } => *code == ControlErrorCode::Timeout,
+            ControlResponse::Batch { results } => results.iter().any(BatchResult::timed_out),
+            _ => false,
+        }
+    }
+}
+
+/// A reply as sent on the wire; `request_id` is absent only when the request
+/// could not be parsed far enough to find one
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ControlReply {
+    pub request_id: Option<Uuid>,
+    #[serde(flatten)]
+    pub response: ControlResponse,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ControlError {
+    #[error("Cannot reach the daemon at {endpoint}")]
+    Unreachable {
+        endpoint: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    #[error("Daemon refused the request: {message}")]
+    Refused {
+        code: ControlErrorCode,
+        message: String,
+    },
+    #[error("IO error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// Results of recently applied mutating requests, keyed by request id
+#[derive(Debug, Default)]
+struct ResultsCache {
+    results: HashMap<Uuid, (Instant, ControlResponse)>,
+    /// Requests being applied; dropping the sender tells replays waiting
+    /// on one to look again
+    running: HashMap<Uuid, watch::Sender<()>>,
+}
+
+/// What a mutating request should do, as the cache sees it
+enum Claim {
+    /// Already applied: answer with the recorded result
+    Replay(ControlResponse),
+    /// Being applied under the same id: wait for the sender to drop
+    Wait(watch::Receiver<()>),
+    /// Apply it; the id is marked running until the guard drops
+    Run,
+}
+
+impl ResultsCache {
+    fn claim(&mut self, request_id: Uuid) -> Claim {
+        self.results
+            .retain(|_, (stored, _)| stored.elapsed() < RESULT_TTL);
+        if let Some((_, response)) = self.results.get(&request_id) {
+            return Claim::Replay(response.clone());
+        }
+        if let Some(running) = self.running.get(&request_id) {
+            return Claim::Wait(running.subscribe());
+        }
+        self.running.insert(request_id, watch::Sender::new(()));
+        Claim::Run
+    }
+
+    fn insert(&mut self, request_id: Uuid, response: ControlResponse) {
+        if self.results.len() >= MAX_CACHED_RESULTS {
+            if let Some(oldest) = self
+                .results
+                .iter()
+                .min_by_key(|(_, (stored, _))| *stored)
+                .map(|(id, _)| *id)
+            {
+                self.results.remove(&oldest);
+            }
+        }
+        self.results.insert(request_id, (Instant::now(), response));
+    }
+}
+
+struct ServerState {
+    bgp: Arc<BGPDaemon>,
+    services: OnceLock<Arc<ServiceRegistry>>,
+    node: OnceLock<Arc<Vx0Node>>,
+    directory: OnceLock<SharedDns>,
+    reloader: OnceLock<Arc<Reloader>>,
+    capture: OnceLock<Arc<Capture>>,
+    firewall: OnceLock<Arc<FirewallManager>>,
+    sessions: Semaphore,
+    command_timeout: Duration,
+    /// Only ever held to look up or record a result, never while a command
+    /// runs
+    results: std::sync::Mutex<ResultsCache>,
+    /// Held while a mutating command runs, so mutations are applied one at
+    /// a time and an atomic batch's snapshot stays whole
+    applying: Mutex<()>,
+}
+
+/// Marks a request id running until dropped, even if the session running
+/// it goes away mid-command
+struct Running<'a> {
+    results: &'a std::sync::Mutex<ResultsCache>,
+    request_id: Uuid,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        lock(self.results).running.remove(&self.request_id);
+    }
+}
+
+pub struct ControlServer {
+    config: ControlConfig,
+    state: Arc<ServerState>,
+}
+
+impl ControlServer {
+    pub fn new(config: ControlConfig, bgp: Arc<BGPDaemon>) -> Self {
+        let state = Arc::new(ServerState {
+            bgp,
+            services: OnceLock::new(),
+            node: OnceLock::new(),
+            directory: OnceLock::new(),
+            reloader: OnceLock::new(),
+            capture: OnceLock::new(),
+            firewall: OnceLock::new(),
+            sessions: Semaphore::new(config.max_sessions),
+            command_timeout: Duration::from_secs(config.command_timeout_secs),
+            results: std::sync::Mutex::new(ResultsCache::default()),
+            applying: Mutex::new(()),
+        });
+        ControlServer { config, state }
+    }
+
+    /// Handle service commands with this registry; without one they fail
+    pub fn set_services(&self, services: Arc<ServiceRegistry>) {
+        if self.state.services.set(services).is_err() {
+            tracing::warn!("Control server service registry already set");
+        }
+    }
+
+    /// Answer peer queries from this node
+    pub fn set_node(&self, node: Arc<Vx0Node>) {
+        if self.state.node.set(node).is_err() {
+            tracing::warn!("Control server node already set");
+        }
+    }
+
+    /// Report the firewall rules this manager owns
+    pub fn set_firewall(&self, firewall: Arc<FirewallManager>) {
+        if self.state.firewall.set(firewall).is_err() {
+            tracing::warn!("Control server firewall already set");
+        }
+    }
+
+    /// Answer directory queries from this zone
+    pub fn set_directory(&self, dns: SharedDns) {
+        if self.state.directory.set(dns).is_err() {
+            tracing::warn!("Control server directory already set");
+        }
+    }
+
+    /// Apply reloaded configurations through this reloader
+    pub fn set_reloader(&self, reloader: Arc<Reloader>) {
+        if self.state.reloader.set(reloader).is_err() {
+            tracing::warn!("Control server reloader already set");
+        }
+    }
+
+    /// Record the commands run, without their tokens, to `capture`
+    pub fn set_capture(&self, capture: Arc<Capture>) {
+        if self.state.capture.set(capture).is_err() {
+            tracing::warn!("Control server capture already set");
+        }
+    }
+
+    /// Run a command as if a client sent it, as replays do
+    pub async fn handle(&self, request: ControlRequest) -> ControlResponse {
+        Self::execute(Uuid::new_v4(), request, &self.state).await
+    }
+
+    /// Serve the Unix socket and, if configured, the TCP fallback
+    pub async fn start(&self) -> Result<(), ControlError> {
+        self.serve_unix(&self.config.socket_path).await?;
+
+        if let Some(tcp_listen) = &self.config.tcp_listen {
+            let addr = tcp_listen.parse().map_err(|e| {
+                ControlError::Config(format!("Invalid control tcp_listen {}: {}", tcp_listen, e))
+            })?;
+            self.serve_tcp(addr).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn serve_unix(&self, socket_path: impl AsRef<Path>) -> Result<(), ControlError> {
+        let socket_path = socket_path.as_ref();
+        let parent = match socket_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        std::fs::create_dir_all(parent)?;
+        // A socket left behind by an unclean shutdown would make bind fail
+        if socket_path.exists() {
+            std::fs::remove_file(socket_path)?;
+        }
+
+        // Bound inside a directory only we can enter and moved into place
+        // once it is 0600, so the socket is never reachable with looser
+        // permissions
+        let staging = parent.join(format!(".control-{}", Uuid::new_v4()));
+        let bound = bind_private(&staging, socket_path);
+        let _ = std::fs::remove_dir_all(&staging);
+        let listener = bound?;
+        tracing::info!("Control socket listening on {}", socket_path.display());
+
+        let state = Arc::clone(&self.state);
+        crash::spawn(Subsystem::Control, "control-unix", async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let state = Arc::clone(&state);
+                        crash::spawn(Subsystem::Control, "control-client", async move {
+                            Self::run_session(stream, state, None, "unix socket".to_string()).await;
+                        });
+                    }
+                    Err(e) => {
+                        tracing::error!("Control socket accept error: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Serve the token-authenticated TCP fallback, returning the bound address
+    pub async fn serve_tcp(&self, addr: SocketAddr) -> Result<SocketAddr, ControlError> {
+        let token = Arc::new(read_token_file(&self.config.token_file)?);
+
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        tracing::info!("Control TCP fallback listening on {}", local_addr);
+
+        let state = Arc::clone(&self.state);
+        crash::spawn(Subsystem::Control, "control-tcp", async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let state = Arc::clone(&state);
+                        let token = Arc::clone(&token);
+                        crash::spawn(Subsystem::Control, "control-client", async move {
+                            Self::run_session(stream, state, Some(token), peer.to_string()).await;
+                        });
+                    }
+                    Err(e) => {
+                        tracing::error!("Control TCP accept error: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    async fn run_session<S>(
+        stream: S,
+        state: Arc<ServerState>,
+        token: Option<Arc<String>>,
+        client: String,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+
+        let _permit = match state.sessions.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                tracing::warn!(
+                    "Rejecting control session from {}: too many sessions",
+                    client
+                );
+                let reply = ControlReply {
+                    request_id: None,
+                    response: ControlResponse::error(
+                        ControlErrorCode::Busy,
+                        "Too many concurrent control sessions",
+                    ),
+                };
+                let _ = write_line(&mut writer, &reply).await;
+                return;
+            }
+        };
+
+        let mut reader = BufReader::new(reader);
+        loop {
+            let line = match read_request(&mut reader).await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    tracing::warn!("Closing control session with {}: {}", client, e);
+                    let reply = ControlReply {
+                        request_id: None,
+                        response: ControlResponse::error(
+                            ControlErrorCode::BadRequest,
+                            e.to_string(),
+                        ),
+                    };
+                    let _ = write_line(&mut writer, &reply).await;
+                    break;
+                }
+                Err(e) => {
+                    tracing::debug!("Control session with {} ended: {}", client, e);
+                    break;
+                }
+            };
+
+            let reply = Self::handle_line(&line, &state, token.as_deref(), &client).await;
+            if let Err(e) = write_line(&mut writer, &reply).await {
+                tracing::debug!("Failed to reply to control client {}: {}", client, e);
+                break;
+            }
+            // A client that cannot authenticate gets no further attempts on
+            // this connection
+            if matches!(
+                reply.response,
+                ControlResponse::Error {
+                    code: ControlErrorCode::Unauthorized,
+                    ..
+                }
+            ) {
+                break;
+            }
+            if matches!(reply.response, ControlResponse::Subscribed { .. }) {
+                Self::stream_events(&mut reader, &mut writer, &state, reply.request_id, &client)
+                    .await;
+                break;
+            }
+        }
+    }
+
+    /// Send events to a subscribed client until it hangs up or the daemon
+    /// stops; anything it sends meanwhile is ignored
+    async fn stream_events<R, W>(
+        reader: &mut BufReader<R>,
+        writer: &mut W,
+        state: &ServerState,
+        request_id: Option<Uuid>,
+        client: &str,
+    ) where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut events = EventSources::open(
+            &state.bgp,
+            state.node.get().map(Arc::as_ref),
+            state.directory.get(),
+        )
+        .await;
+        tracing::debug!("Control client {} subscribed to events", client);
+        loop {
+            let event = tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                line = read_request(reader) => match line {
+                    Ok(Some(_)) => continue,
+                    Ok(None) | Err(_) => break,
+                },
+            };
+            let reply = ControlReply {
+                request_id,
+                response: ControlResponse::Event { event },
+            };
+            if let Err(e) = write_line(writer, &reply).await {
+                tracing::debug!("Event stream to control client {} ended: {}", client, e);
+                break;
+            }
+        }
+    }
+
+    async fn handle_line(
+        line: &str,
+        state: &ServerState,
+        token: Option<&String>,
+        client: &str,
+    ) -> ControlReply {
+        let envelope: ControlEnvelope = match serde_json::from_str(line) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                return ControlReply {
+                    request_id: None,
+                    response: ControlResponse::error(
+                        ControlErrorCode::BadRequest,
+                        format!("Invalid request: {}", e),
+                    ),
+                }
+            }
+        };
+
+        if let Some(expected) = token {
+            let presented = envelope.token.as_deref().unwrap_or_default();
+            if !tokens_match(presented.as_bytes(), expected.as_bytes()) {
+                tracing::warn!(
+                    target: "audit",
+                    "Rejected unauthenticated control request {} ({}) from {}",
+                    envelope.request_id,
+                    envelope.request.name(),
+                    client
+                );
+                return ControlReply {
+                    request_id: Some(envelope.request_id),
+                    response: ControlResponse::error(
+                        ControlErrorCode::Unauthorized,
+                        "Missing or invalid control token",
+                    ),
+                };
+            }
+        }
+
+        let response = Self::execute(envelope.request_id, envelope.request, state).await;
+        ControlReply {
+            request_id: Some(envelope.request_id),
+            response,
+        }
+    }
+
+    async fn execute(
+        request_id: Uuid,
+        request: ControlRequest,
+        state: &ServerState,
+    ) -> ControlResponse {
+        if !request.is_mutating() {
+            Self::capture(&request, state);
+            // The session streams events once this reply is written
+            if let ControlRequest::Subscribe = request {
+                return ControlResponse::Subscribed {
+                    schema_version: schema::SCHEMA_VERSION,
+                };
+            }
+            return Self::dispatch_with_timeout(request, state).await;
+        }
+
+        // A replay racing its original waits for the first result
+        loop {
+            let claim = lock(&state.results).claim(request_id);
+            match claim {
+                Claim::Replay(previous) => {
+                    tracing::debug!("Replaying result of control request {}", request_id);
+                    return previous;
+                }
+                Claim::Wait(mut running) => {
+                    let _ = running.changed().await;
+                }
+                Claim::Run => break,
+            }
+        }
+        let _running = Running {
+            results: &state.results,
+            request_id,
+        };
+        Self::capture(&request, state);
+
+        let response = {
+            let _applying = state.applying.lock().await;
+            // Each command of a batch gets its own timeout
+            match request {
+                ControlRequest::Batch { commands, atomic } => {
+                    batch::run(commands, atomic, state).await
+                }
+                request => Self::dispatch_with_timeout(request, state).await,
+            }
+        };
+        // A timed-out command may still have been applied partway, so only
+        // record outcomes we know
+        if !response.timed_out() {
+            lock(&state.results).insert(request_id, response.clone());
+        }
+        response
+    }
+
+    fn capture(request: &ControlRequest, state: &ServerState) {
+        if let Some(capture) = state.capture.get() {
+            capture.record(CapturedEvent::Control {
+                request: request.clone(),
+            });
+        }
+    }
+
+    async fn dispatch_with_timeout(
+        request: ControlRequest,
+        state: &ServerState,
+    ) -> ControlResponse {
+        let name = request.name();
+        match tokio::time::timeout(state.command_timeout, Self::dispatch(request, state)).await {
+            Ok(response) => response,
+            Err(_) => ControlResponse::error(
+                ControlErrorCode::Timeout,
+                format!("Command {} timed out", name),
+            ),
+        }
+    }
+
+    async fn dispatch(request: ControlRequest, state: &ServerState) -> ControlResponse {
+        let bgp = &state.bgp;
+        match request {
+            ControlRequest::Routes => ControlResponse::Routes {
+                routes: bgp.get_routes().await,
+                pins: bgp.route_pins().await,
+            },
+            ControlRequest::RoutesReceived { peer_asn } => {
+                match bgp.get_received_routes(peer_asn).await {
+                    Some(routes) => ControlResponse::Routes {
+                        routes,
+                        pins: Vec::new(),
+                    },
+                    None => ControlResponse::error(
+                        ControlErrorCode::NotFound,
+                        format!("No routes received from ASN {}", peer_asn),
+                    ),
+                }
+            }
+            ControlRequest::RoutesAdvertised { peer_asn } => {
+                match bgp.get_advertised_routes(peer_asn).await {
+                    Some(routes) => ControlResponse::Routes {
+                        routes,
+                        pins: Vec::new(),
+                    },
+                    None => ControlResponse::error(
+                        ControlErrorCode::NotFound,
+                        format!("No routes advertised to ASN {}", peer_asn),
+                    ),
+                }
+            }
+            ControlRequest::ExplainRoute { network } => ControlResponse::Explanation {
+                explanation: bgp.explain_route(&network).await,
+            },
+            ControlRequest::PropagationStats => ControlResponse::PropagationStats {
+                stats: bgp.propagation_stats().await,
+            },
+            ControlRequest::Version => ControlResponse::Version {
+                build: BuildInfo::current(),
+            },
+            ControlRequest::Schema => ControlResponse::Schema {
+                schema: Box::new(ControlSchema::current()),
+            },
+            ControlRequest::PolicyTest { policy, peer_asn } => {
+                let fragment: PolicyFragment = match toml::from_str(&policy) {
+                    Ok(fragment) => fragment,
+                    Err(e) => {
+                        return ControlResponse::error(
+                            ControlErrorCode::BadRequest,
+                            format!("Invalid policy: {}", e),
+                        )
+                    }
+                };
+                match bgp.test_policy(&fragment, peer_asn).await {
+                    Ok(report) => ControlResponse::PolicyReport { report },
+                    Err(e @ BGPError::UnknownPeer { .. }) => {
+                        ControlResponse::error(ControlErrorCode::NotFound, e.to_string())
+                    }
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::AnnounceRoute {
+                network,
+                next_hop,
+                communities,
+            } => {
+                match bgp
+                    .add_route_with_communities(network, next_hop, BGPOrigin::IGP, communities)
+                    .await
+                {
+                    Ok(()) => ControlResponse::Applied {
+                        rib_version: bgp.rib_version().await,
+                    },
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::WithdrawRoute { network } => match bgp.withdraw_route(&network).await {
+                Ok(()) => ControlResponse::Applied {
+                    rib_version: bgp.rib_version().await,
+                },
+                Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+            },
+            ControlRequest::PinRoute {
+                network,
+                via_asn,
+                duration_secs,
+                allow_missing,
+            } => {
+                let duration = duration_secs.map(Duration::from_secs);
+                match bgp
+                    .pin_route(network, via_asn, duration, allow_missing)
+                    .await
+                {
+                    Ok(_) => ControlResponse::Applied {
+                        rib_version: bgp.rib_version().await,
+                    },
+                    Err(e @ BGPError::UnknownPrefix { .. }) => {
+                        ControlResponse::error(ControlErrorCode::NotFound, e.to_string())
+                    }
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::UnpinRoute { network } => match bgp.unpin_route(&network).await {
+                Ok(Some(_)) => ControlResponse::Applied {
+                    rib_version: bgp.rib_version().await,
+                },
+                Ok(None) => ControlResponse::error(
+                    ControlErrorCode::NotFound,
+                    format!("{} is not pinned", network),
+                ),
+                Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+            },
+            ControlRequest::RegisterService {
+                name,
+                domain,
+                port,
+                wait_for,
+                timeout_secs,
+                metadata,
+                service_type,
+            } => {
+                let Some(services) = state.services.get() else {
+                    return ControlResponse::error(
+                        ControlErrorCode::Failed,
+                        "This daemon does not host services",
+                    );
+                };
+                if !domain.ends_with(".vx0") {
+                    return ControlResponse::error(
+                        ControlErrorCode::BadRequest,
+                        "Service domain must end with .vx0",
+                    );
+                }
+                let metadata = match ServiceMetadata::parse(&metadata) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        return ControlResponse::error(ControlErrorCode::BadRequest, e.to_string())
+                    }
+                };
+                let service_id = Uuid::new_v4();
+                let service = HostedService {
+                    service_id,
+                    service_type: match service_type {
+                        Some(service_type) => ServiceType::from(service_type.as_str()),
+                        None => ServiceType::Custom(name.clone()),
+                    },
+                    name,
+                    domain: domain.clone(),
+                    port,
+                    status: ServiceStatus::Running,
+                    metadata,
+                };
+                let result = match wait_for {
+                    Some(quorum) => {
+                        // Leave room to reply before the command itself times out
+                        let budget = state.command_timeout.saturating_sub(Duration::from_secs(1));
+                        let wait = timeout_secs
+                            .map(Duration::from_secs)
+                            .unwrap_or(budget)
+                            .min(budget);
+                        services
+                            .register_service_confirmed(service, quorum, wait)
+                            .await
+                            .map(Some)
+                    }
+                    None => services.publish(service).await.map(|()| None),
+                };
+                match result {
+                    Ok(propagation) => ControlResponse::Registered {
+                        service_id,
+                        domain,
+                        ttl_secs: services.ttl().num_seconds().max(0) as u64,
+                        propagation,
+                    },
+                    Err(e @ NodeError::ServiceExists { .. }) => {
+                        ControlResponse::error(ControlErrorCode::BadRequest, e.to_string())
+                    }
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::UnregisterService {
+                domain,
+                service_type,
+                port,
+            } => {
+                let Some(services) = state.services.get() else {
+                    return ControlResponse::error(
+                        ControlErrorCode::Failed,
+                        "This daemon does not host services",
+                    );
+                };
+                let service_type = service_type.as_deref().map(ServiceType::from);
+                let hosted = services.node().services_at(&domain).await;
+                let matching: Vec<&HostedService> = hosted
+                    .iter()
+                    .filter(|service| {
+                        service_type
+                            .as_ref()
+                            .is_none_or(|wanted| service.service_type.matches(wanted))
+                            && port.is_none_or(|port| service.port == port)
+                    })
+                    .collect();
+                let service = match matching.as_slice() {
+                    [service] => *service,
+                    [] => {
+                        return ControlResponse::error(
+                            ControlErrorCode::NotFound,
+                            format!("No matching service at {}", domain),
+                        )
+                    }
+                    several => {
+                        return ControlResponse::error(
+                            ControlErrorCode::BadRequest,
+                            format!(
+                                "{} services at {} match; give a type or port",
+                                several.len(),
+                                domain
+                            ),
+                        )
+                    }
+                };
+                match services.unpublish(service.service_id).await {
+                    Ok(()) => ControlResponse::Unregistered {
+                        service: ServiceSummary::new(
+                            service,
+                            services.node().node_id,
+                            service.status.clone(),
+                        ),
+                        remaining: hosted.len() - 1,
+                    },
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::ListServices => match state.services.get() {
+                Some(services) => ControlResponse::Services {
+                    services: services.hosted().await,
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not host services",
+                ),
+            },
+            ControlRequest::RefreshService { domain } => {
+                let Some(services) = state.services.get() else {
+                    return ControlResponse::error(
+                        ControlErrorCode::Failed,
+                        "This daemon does not host services",
+                    );
+                };
+                match services.refresh_domain(&domain).await {
+                    Ok(_) => ControlResponse::Refreshed {
+                        domain,
+                        ttl_secs: services.ttl().num_seconds().max(0) as u64,
+                    },
+                    Err(e) => ControlResponse::error(ControlErrorCode::NotFound, e.to_string()),
+                }
+            }
+            ControlRequest::Block { entry } | ControlRequest::Unblock { entry }
+                if state.node.get().is_none() =>
+            {
+                ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    format!("This daemon has no ACL to change for {}", entry),
+                )
+            }
+            ControlRequest::Block { entry } => {
+                let node = state.node.get().expect("checked above");
+                let entry = match entry.parse::<AclEntry>() {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        return ControlResponse::error(ControlErrorCode::BadRequest, e.to_string())
+                    }
+                };
+                let changed = match node.acl.block(entry) {
+                    Ok(changed) => changed,
+                    Err(e) => {
+                        return ControlResponse::error(ControlErrorCode::Failed, e.to_string())
+                    }
+                };
+                let mut torn_down = node.enforce_acl().await;
+                match bgp.enforce_acl().await {
+                    Ok(ended) => torn_down += ended,
+                    Err(e) => {
+                        return ControlResponse::error(ControlErrorCode::Failed, e.to_string())
+                    }
+                }
+                ControlResponse::Acl {
+                    changed,
+                    blocked: node.acl.blocked().iter().map(ToString::to_string).collect(),
+                    torn_down,
+                }
+            }
+            ControlRequest::Unblock { entry } => {
+                let node = state.node.get().expect("checked above");
+                let result = entry
+                    .parse::<AclEntry>()
+                    .and_then(|entry| node.acl.unblock(entry));
+                match result {
+                    Ok(changed) => ControlResponse::Acl {
+                        changed,
+                        blocked: node.acl.blocked().iter().map(ToString::to_string).collect(),
+                        torn_down: 0,
+                    },
+                    Err(e @ AclError::InvalidEntry(_)) => {
+                        ControlResponse::error(ControlErrorCode::BadRequest, e.to_string())
+                    }
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::Connect { peer, peer_asn } => match state.node.get() {
+                Some(node) => match node.connect_peer(peer, peer_asn).await {
+                    Ok(peer) => ControlResponse::Connected { peer },
+                    Err(e) => {
+                        ControlResponse::error(ControlErrorCode::Failed, Report(&e).to_string())
+                    }
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::Disconnect { peer } => {
+                let Some(node) = state.node.get() else {
+                    return ControlResponse::error(
+                        ControlErrorCode::Failed,
+                        "This daemon does not track peers",
+                    );
+                };
+                let Some(connection) = node
+                    .list_peers()
+                    .await
+                    .into_iter()
+                    .find(|connection| connection.peer_addr == peer)
+                else {
+                    return ControlResponse::error(
+                        ControlErrorCode::NotFound,
+                        format!("No peer at {}", peer),
+                    );
+                };
+                match node
+                    .disconnect_peer(connection.peer_id, GoodbyeReason::PeerRemoved)
+                    .await
+                {
+                    Ok(()) => ControlResponse::Departed {
+                        peers: 1,
+                        expected_return: None,
+                        unreachable: Vec::new(),
+                        overridden: Vec::new(),
+                    },
+                    Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                }
+            }
+            ControlRequest::Maintenance {
+                return_in_secs,
+                force,
+            } => match state.node.get() {
+                Some(node) => {
+                    let expected_return = return_in_secs
+                        .map(|secs| Utc::now() + chrono::Duration::seconds(secs as i64));
+                    match node.enter_maintenance_in_turn(expected_return, force).await {
+                        Ok(entry) => ControlResponse::Departed {
+                            peers: entry.peers,
+                            expected_return,
+                            unreachable: entry.unreachable,
+                            overridden: entry.overridden,
+                        },
+                        Err(e @ RedundancyError::Blocked { .. }) => {
+                            ControlResponse::error(ControlErrorCode::Busy, e.to_string())
+                        }
+                        Err(e) => ControlResponse::error(ControlErrorCode::Failed, e.to_string()),
+                    }
+                }
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::Reload => match state.reloader.get() {
+                Some(reloader) => match Vx0Config::load() {
+                    Ok(reloaded) => match reloader.reload(&reloaded).await {
+                        Ok(report) => ControlResponse::Reloaded { report },
+                        Err(e) => {
+                            ControlResponse::error(ControlErrorCode::Failed, Report(&e).to_string())
+                        }
+                    },
+                    Err(e) => ControlResponse::error(
+                        ControlErrorCode::Failed,
+                        format!("Keeping the current configuration: {}", Report(&e)),
+                    ),
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon cannot reload its configuration",
+                ),
+            },
+            ControlRequest::Peers => match state.node.get() {
+                Some(node) => {
+                    let mut peers = node.list_peers().await;
+                    // Counted from what the RIB holds, so it cannot drift
+                    for peer in &mut peers {
+                        peer.metrics.routes_received = bgp.routes_from(peer.peer_asn).await as u32;
+                        peer.metrics.table_sync = bgp.table_sync_progress(peer.peer_asn);
+                        peer.metrics.timers = bgp.link_timers(peer.peer_addr);
+                    }
+                    ControlResponse::Peers {
+                        local: node.capabilities(),
+                        peers,
+                        admin_down: node.admin.list(),
+                        quarantine: node.quarantine.list(node.clock.now_utc()),
+                        multihoming: bgp.multihoming_status().await,
+                    }
+                }
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::PeerDisable { asn } => match state.node.get() {
+                Some(node) => match node.disable_peer(asn).await {
+                    Ok(peers) => ControlResponse::PeerAdmin {
+                        asn,
+                        admin_down: true,
+                        peers,
+                    },
+                    Err(e) => {
+                        ControlResponse::error(ControlErrorCode::Failed, Report(&e).to_string())
+                    }
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::PeerEnable { asn } => match state.node.get() {
+                Some(node) => match node.enable_peer(asn).await {
+                    Ok(peers) => ControlResponse::PeerAdmin {
+                        asn,
+                        admin_down: false,
+                        peers,
+                    },
+                    Err(e @ NodeError::Admin(AdminError::Configured { .. })) => {
+                        ControlResponse::error(ControlErrorCode::BadRequest, e.to_string())
+                    }
+                    Err(e) => {
+                        ControlResponse::error(ControlErrorCode::Failed, Report(&e).to_string())
+                    }
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::Group => ControlResponse::Group {
+                group: match state.node.get() {
+                    Some(node) => node.group_status().await,
+                    None => None,
+                },
+            },
+            ControlRequest::FirewallStatus => ControlResponse::Firewall {
+                firewall: match state.firewall.get() {
+                    Some(firewall) => firewall.status().await,
+                    None => FirewallStatus::default(),
+                },
+            },
+            ControlRequest::QuarantineList => match state.node.get() {
+                Some(node) => ControlResponse::Quarantine {
+                    peers: node.quarantine.list(node.clock.now_utc()),
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::QuarantineClear { asn } => match state.node.get() {
+                Some(node) => match node.clear_quarantine(asn).await {
+                    Ok(cleared) => match bgp.follow_standing(asn).await {
+                        Ok(()) => ControlResponse::QuarantineCleared { asn, cleared },
+                        Err(e) => {
+                            ControlResponse::error(ControlErrorCode::Failed, Report(&e).to_string())
+                        }
+                    },
+                    Err(e) => {
+                        ControlResponse::error(ControlErrorCode::Failed, Report(&e).to_string())
+                    }
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track peers",
+                ),
+            },
+            ControlRequest::Tasks => ControlResponse::Tasks {
+                tasks: Supervisor::global().tasks().live(),
+            },
+            ControlRequest::Startup => ControlResponse::Startup {
+                report: Supervisor::global().timing(Stage::Startup),
+            },
+            ControlRequest::Capacity => match state.node.get() {
+                Some(node) => ControlResponse::Capacity {
+                    report: node.capacity.report().map(Box::new),
+                    alerts: node.capacity.alerts(),
+                    heard: node.capacity.heard().map(Box::new),
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not monitor capacity",
+                ),
+            },
+            ControlRequest::Readiness => match state.node.get() {
+                Some(node) => ControlResponse::Readiness {
+                    report: node.readiness.check(node, bgp).await,
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not check readiness",
+                ),
+            },
+            ControlRequest::PublicAddress => match state.node.get() {
+                Some(node) => ControlResponse::PublicAddress {
+                    report: node.observed.report(),
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track its public address",
+                ),
+            },
+            ControlRequest::DnsCache { pattern, negative } => {
+                match dns_cache(state, pattern.as_deref(), negative).await {
+                    Ok((cache, filter)) => ControlResponse::DnsCache {
+                        entries: cache.list(&filter),
+                        stats: cache.stats(),
+                    },
+                    Err(reply) => reply,
+                }
+            }
+            ControlRequest::DnsCacheFlush { pattern, negative } => {
+                match dns_cache(state, pattern.as_deref(), negative).await {
+                    Ok((cache, filter)) => {
+                        let removed = cache.flush(&filter);
+                        tracing::info!(
+                            "Flushed {} cached DNS answers for {}",
+                            removed,
+                            pattern.as_deref().unwrap_or("every name")
+                        );
+                        ControlResponse::DnsCacheFlushed {
+                            removed,
+                            stats: cache.stats(),
+                        }
+                    }
+                    Err(reply) => reply,
+                }
+            }
+            ControlRequest::DnsCacheStats => match dns_cache(state, None, false).await {
+                Ok((cache, _)) => ControlResponse::DnsCacheStats {
+                    stats: cache.stats(),
+                },
+                Err(reply) => reply,
+            },
+            ControlRequest::DnsHistory { domain } => match state.directory.get() {
+                Some(dns) => {
+                    let dns = dns.read().await;
+                    ControlResponse::DnsHistory {
+                        entries: match domain {
+                            Some(domain) => dns.history(&domain),
+                            None => dns.local_history(),
+                        },
+                    }
+                }
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon keeps no DNS directory",
+                ),
+            },
+            ControlRequest::BootstrapList => match state.node.get() {
+                Some(node) => ControlResponse::Bootstrap {
+                    nodes: node.bootstrap.status(Utc::now()),
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track bootstrap nodes",
+                ),
+            },
+            ControlRequest::AsnList => match state.node.get() {
+                Some(node) => ControlResponse::AsnGrants {
+                    grants: node.asn_registry.list(),
+                    registry_key: node.asn_registry.key_hex(),
+                },
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track ASN grants",
+                ),
+            },
+            ControlRequest::BootstrapProbe => match state.node.get() {
+                Some(node) => {
+                    let limit = Duration::from_secs(node.config.joining.connect_timeout_secs);
+                    node.bootstrap.probe(&node.bootstrap.nodes(), limit).await;
+                    if let Err(e) = node.bootstrap.save() {
+                        tracing::warn!("Failed to save bootstrap health: {}", Report(&e));
+                    }
+                    ControlResponse::Bootstrap {
+                        nodes: node.bootstrap.status(Utc::now()),
+                    }
+                }
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon does not track bootstrap nodes",
+                ),
+            },
+            ControlRequest::Nodes => match state.directory.get() {
+                Some(dns) => {
+                    let dns = dns.read().await;
+                    let group = match state.node.get() {
+                        Some(node) => node.group_status().await,
+                        None => None,
+                    };
+                    ControlResponse::Nodes {
+                        nodes: dns.nodes(),
+                        local: state.node.get().map(|node| node.node_id),
+                        over_quota: dns.over_quota(),
+                        group,
+                    }
+                }
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon has no node directory",
+                ),
+            },
+            ControlRequest::FindServices {
+                service_type,
+                tags,
+                nearby,
+                fan_out,
+            } => match state.node.get() {
+                Some(node) => {
+                    let filter = ServiceFilter {
+                        service_type: service_type
+                            .map(|service_type| ServiceType::from(service_type.as_str())),
+                        tags,
+                        nearby,
+                        fan_out,
+                        node_id: None,
+                    };
+                    ControlResponse::Services {
+                        services: node.find_services(filter).await,
+                    }
+                }
+                None => ControlResponse::error(
+                    ControlErrorCode::Failed,
+                    "This daemon has no service directory",
+                ),
+            },
+            ControlRequest::Batch { .. } => {
+                ControlResponse::error(ControlErrorCode::BadRequest, "Batches cannot be nested")
+            }
+            ControlRequest::Subscribe => ControlResponse::error(
+                ControlErrorCode::BadRequest,
+                "Subscriptions cannot run in a batch",
+            ),
+        }
+    }
+}
+
+/// The directory's answer cache and the entries a request covers, or the
+/// reply refusing it
+async fn dns_cache(
+    state: &ServerState,
+    pattern: Option<&str>,
+    negative: bool,
+) -> Result<(Arc<ResolverCache>, CacheFilter), ControlResponse> {
+    let Some(dns) = state.directory.get() else {
+        return Err(ControlResponse::error(
+            ControlErrorCode::Failed,
+            "This daemon has no DNS cache",
+        ));
+    };
+    let filter = CacheFilter::new(pattern, negative)
+        .map_err(|e| ControlResponse::error(ControlErrorCode::BadRequest, e.to_string()))?;
+    Ok((Arc::clone(dns.read().await.cache()), filter))
+}
+
+/// Compare tokens without leaking through timing how many bytes matched
+fn tokens_match(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Read the TCP control token, refusing files other users could read
+fn read_token_file(path: &str) -> Result<String, ControlError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| ControlError::Config(format!("Cannot read control token {}: {}", path, e)))?;
+    if metadata.permissions().mode() & 0o077 != 0 {
+        return Err(ControlError::Config(format!(
+            "Control token {} must not be accessible by group or others",
+            path
+        )));
+    }
+
+    let token = std::fs::read_to_string(path)?.trim().to_string();
+    if token.is_empty() {
+        return Err(ControlError::Config(format!(
+            "Control token {} is empty",
+            path
+        )));
+    }
+    Ok(token)
+}
+
+/// Bind a Unix socket in a fresh 0700 directory, restrict it to 0600 and
+/// rename it to `socket_path`
+fn bind_private(staging: &Path, socket_path: &Path) -> Result<UnixListener, ControlError> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    std::fs::DirBuilder::new().mode(0o700).create(staging)?;
+    let staged = staging.join("control.sock");
+    let listener = UnixListener::bind(&staged)?;
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(&staged, socket_path)?;
+    Ok(listener)
+}
+
+/// The next request line from `reader` without its line ending, or `None`
+/// once the client hung up; a line over `MAX_REQUEST_LEN` is refused as
+/// invalid data without reading the rest of it
+async fn read_request<R>(reader: &mut R) -> std::io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(MAX_REQUEST_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > MAX_REQUEST_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Request exceeds {} bytes", MAX_REQUEST_LEN),
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+async fn write_line<W, T>(writer: &mut W, value: &T) -> Result<(), ControlError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut encoded = serde_json::to_vec(value)?;
+    encoded.push(b'\n');
+    writer.write_all(&encoded).await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum Endpoint {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Client side of the control protocol
+#[derive(Debug, Clone)]
+pub struct ControlClient {
+    endpoint: Endpoint,
+    token: Option<String>,
+}
+
+impl ControlClient {
+    pub fn unix(socket_path: impl Into<PathBuf>) -> Self {
+        ControlClient {
+            endpoint: Endpoint::Unix(socket_path.into()),
+            token: None,
+        }
+    }
+
+    pub fn tcp(addr: SocketAddr, token: String) -> Self {
+        ControlClient {
+            endpoint: Endpoint::Tcp(addr),
+            token: Some(token),
+        }
+    }
+
+    /// Send a request, resending it under the same id if the connection
+    /// fails before a reply arrives
+    pub async fn request(&self, request: &ControlRequest) -> Result<ControlResponse, ControlError> {
+        self.request_with_id(Uuid::new_v4(), request).await
+    }
+
+    pub async fn request_with_id(
+        &self,
+        request_id: Uuid,
+        request: &ControlRequest,
+    ) -> Result<ControlResponse, ControlError> {
+        let envelope = ControlEnvelope {
+            request_id,
+            token: self.token.clone(),
+            request: request.clone(),
+        };
+
+        let mut last_error = None;
+        for attempt in 1..=CLIENT_ATTEMPTS {
+            match self.exchange(&envelope).await {
+                // A busy daemon never ran the command, so waiting and
+                // resending is always safe
+                Ok(ControlReply {
+                    response:
+                        ControlResponse::Error {
+                            code: ControlErrorCode::Busy,
+                            ..
+                        },
+                    ..
+                }) if attempt < CLIENT_ATTEMPTS => {}
+                Ok(reply) => return Ok(reply.response),
+                Err(ControlError::IO(e)) => last_error = Some(e),
+                Err(e) => return Err(e),
+            }
+            if attempt < CLIENT_ATTEMPTS {
+                tokio::time::sleep(CLIENT_RETRY_DELAY * attempt as u32).await;
+            }
+        }
+        match last_error {
+            Some(source) => Err(ControlError::Unreachable {
+                endpoint: self.endpoint.to_string(),
+                source,
+            }),
+            None => Err(ControlError::Protocol("No attempts were made".to_string())),
+        }
+    }
+
+    /// Stream the daemon's events over a connection of their own
+    pub async fn subscribe(&self) -> Result<Subscription, ControlError> {
+        let request_id = Uuid::new_v4();
+        let envelope = ControlEnvelope {
+            request_id,
+            token: self.token.clone(),
+            request: ControlRequest::Subscribe,
+        };
+        let (reader, mut writer): (Box<dyn AsyncRead + Send + Unpin>, _) = match &self.endpoint {
+            Endpoint::Unix(path) => {
+                let (reader, writer) = UnixStream::connect(path).await?.into_split();
+                (
+                    Box::new(reader),
+                    Box::new(writer) as Box<dyn AsyncWrite + Send + Unpin>,
+                )
+            }
+            Endpoint::Tcp(addr) => {
+                let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+                (
+                    Box::new(reader),
+                    Box::new(writer) as Box<dyn AsyncWrite + Send + Unpin>,
+                )
+            }
+        };
+        write_line(&mut writer, &envelope).await?;
+
+        let mut subscription = Subscription {
+            request_id,
+            schema_version: 0,
+            lines: BufReader::new(reader).lines(),
+            _writer: writer,
+        };
+        match subscription.reply().await? {
+            Some(ControlResponse::Subscribed { schema_version }) => {
+                tracing::debug!("Subscribed to events of {}", self.endpoint);
+                subscription.schema_version = schema_version;
+                Ok(subscription)
+            }
+            Some(ControlResponse::Error { code, message }) => {
+                Err(ControlError::Refused { code, message })
+            }
+            Some(other) => Err(ControlError::Protocol(format!(
+                "Unexpected reply to subscribe: {:?}",
+                other
+            ))),
+            None => Err(ControlError::IO(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Daemon closed the connection",
+            ))),
+        }
+    }
+
+    async fn exchange(&self, envelope: &ControlEnvelope) -> Result<ControlReply, ControlError> {
+        match &self.endpoint {
+            Endpoint::Unix(path) => {
+                Self::exchange_on(UnixStream::connect(path).await?, envelope).await
+            }
+            Endpoint::Tcp(addr) => {
+                Self::exchange_on(TcpStream::connect(addr).await?, envelope).await
+            }
+        }
+    }
+
+    async fn exchange_on<S>(
+        stream: S,
+        envelope: &ControlEnvelope,
+    ) -> Result<ControlReply, ControlError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (reader, mut writer) = tokio::io::split(stream);
+        write_line(&mut writer, envelope).await?;
+
+        let line = BufReader::new(reader)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| {
+                ControlError::IO(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Daemon closed the connection",
+                ))
+            })?;
+        let reply: ControlReply = serde_json::from_str(&line)?;
+
+        match reply.request_id {
+            Some(id) if id != envelope.request_id => Err(ControlError::Protocol(format!(
+                "Reply for request {} does not match request {}",
+                id, envelope.request_id
+            ))),
+            _ => Ok(reply),
+        }
+    }
+}
+
+/// A connection the daemon streams events on, opened by
+/// [`ControlClient::subscribe`]
+pub struct Subscription {
+    request_id: Uuid,
+    schema_version: u32,
+    lines: tokio::io::Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>,
+    // The daemon ends the stream when this side closes
+    _writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl Subscription {
+    /// The control schema version the daemon speaks
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// The next event, or `None` once the daemon closed the connection
+    pub async fn next(&mut self) -> Result<Option<DaemonEvent>, ControlError> {
+        match self.reply().await? {
+            Some(ControlResponse::Event { event }) => Ok(Some(event)),
+            Some(other) => Err(ControlError::Protocol(format!(
+                "Unexpected line on an event stream: {:?}",
+                other
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    async fn reply(&mut self) -> Result<Option<ControlResponse>, ControlError> {
+        let Some(line) = self.lines.next_line().await? else {
+            return Ok(None);
+        };
+        let reply: ControlReply = serde_json::from_str(&line)?;
+        match reply.request_id {
+            Some(id) if id != self.request_id => Err(ControlError::Protocol(format!(
+                "Reply for request {} does not match subscription {}",
+                id, self.request_id
+            ))),
+            _ => Ok(Some(reply.response)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vx0net-{}-{}", name, Uuid::new_v4()))
+    }
+
+    fn test_config(socket_path: &Path) -> ControlConfig {
+        ControlConfig {
+            socket_path: socket_path.display().to_string(),
+            ..ControlConfig::default()
+        }
+    }
+
+    async fn start_unix(bgp: Arc<BGPDaemon>) -> (ControlClient, PathBuf) {
+        let socket_path = temp_path("control.sock");
+        ControlServer::new(test_config(&socket_path), bgp)
+            .start()
+            .await
+            .unwrap();
+        (ControlClient::unix(&socket_path), socket_path)
+    }
+
+    #[tokio::test]
+    async fn test_replayed_mutation_applies_once() {
+        let bgp = Arc::new(BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0));
+        let (client, socket_path) = start_unix(Arc::clone(&bgp)).await;
+
+        let announce = ControlRequest::AnnounceRoute {
+            network: "10.1.0.0/16".parse().unwrap(),
+            next_hop: "10.0.0.1".parse().unwrap(),
+            communities: Vec::new(),
+        };
+        let request_id = Uuid::new_v4();
+
+        let first = client.request_with_id(request_id, &announce).await.unwrap();
+        let replay = client.request_with_id(request_id, &announce).await.unwrap();
+        let (
+            ControlResponse::Applied { rib_version: v1 },
+            ControlResponse::Applied { rib_version: v2 },
+        ) = (first, replay)
+        else {
+            panic!("announce was not applied");
+        };
+        assert_eq!(v1, v2);
+        assert_eq!(bgp.rib_version().await, v1);
+
+        // A new id is a new command
+        let again = client.request(&announce).await.unwrap();
+        assert!(matches!(again, ControlResponse::Applied { rib_version } if rib_version == v1 + 1));
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_beyond_the_limit_are_told_busy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bgp = Arc::new(BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0));
+        let (client, socket_path) = start_unix(bgp).await;
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Every session the default allows, each held open after a reply
+        let mut held = Vec::new();
+        for _ in 0..ControlConfig::default().max_sessions {
+            let stream = UnixStream::connect(&socket_path).await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            let envelope = ControlEnvelope {
+                request_id: Uuid::new_v4(),
+                token: None,
+                request: ControlRequest::Version,
+            };
+            write_line(&mut writer, &envelope).await.unwrap();
+            let mut lines = BufReader::new(reader).lines();
+            assert!(lines.next_line().await.unwrap().is_some());
+            held.push((lines, writer));
+        }
+
+        let busy = client.request(&ControlRequest::Version).await.unwrap();
+        assert!(matches!(
+            busy,
+            ControlResponse::Error {
+                code: ControlErrorCode::Busy,
+                ..
+            }
+        ));
+
+        // A session closing makes room, and the client's retries find it
+        held.pop();
+        let version = client.request(&ControlRequest::Version).await.unwrap();
+        assert!(!matches!(version, ControlResponse::Error { .. }));
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_rejects_bad_token() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bgp = Arc::new(BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0));
+        let token_file = temp_path("control.token");
+        std::fs::write(&token_file, "s3cret\n").unwrap();
+        std::fs::set_permissions(&token_file, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let config = ControlConfig {
+            token_file: token_file.display().to_string(),
+            ..ControlConfig::default()
+        };
+        let server = ControlServer::new(config, Arc::clone(&bgp));
+        let addr = server
+            .serve_tcp("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let announce = ControlRequest::AnnounceRoute {
+            network: "10.1.0.0/16".parse().unwrap(),
+            next_hop: "10.0.0.1".parse().unwrap(),
+            communities: Vec::new(),
+        };
+        let bad = ControlClient::tcp(addr, "guess".to_string())
+            .request(&announce)
+            .await
+            .unwrap();
+        assert!(matches!(
+            bad,
+            ControlResponse::Error {
+                code: ControlErrorCode::Unauthorized,
+                ..
+            }
+        ));
+        assert!(bgp.get_routes().await.is_empty());
+
+        // The connection ends with the rejection, and a line too long to be
+        // a request ends it before the token is even read
+        let mut rejected = serde_json::to_vec(&ControlEnvelope {
+            request_id: Uuid::new_v4(),
+            token: Some("guess".to_string()),
+            request: ControlRequest::Version,
+        })
+        .unwrap();
+        rejected.push(b'\n');
+        for sent in [rejected, vec![b'x'; MAX_REQUEST_LEN + 1]] {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (reader, mut writer) = tokio::io::split(stream);
+            writer.write_all(&sent).await.unwrap();
+            let mut lines = BufReader::new(reader).lines();
+            let reply: ControlReply =
+                serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            assert!(matches!(reply.response, ControlResponse::Error { .. }));
+            assert!(lines.next_line().await.unwrap().is_none());
+        }
+
+        let good = ControlClient::tcp(addr, "s3cret".to_string())
+            .request(&announce)
+            .await
+            .unwrap();
+        assert!(matches!(good, ControlResponse::Applied { .. }));
+
+        // World-readable token files are refused outright
+        std::fs::set_permissions(&token_file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(server
+            .serve_tcp("127.0.0.1:0".parse().unwrap())
+            .await
+            .is_err());
+
+        let _ = std::fs::remove_file(&token_file);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_clients() {
+        let bgp = Arc::new(BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0));
+        let (client, socket_path) = start_unix(Arc::clone(&bgp)).await;
+
+        let tasks: Vec<_> = (0..12u8)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let announce = ControlRequest::AnnounceRoute {
+                        network: format!("10.{}.0.0/16", i + 1).parse().unwrap(),
+                        next_hop: "10.0.0.1".parse().unwrap(),
+                        communities: Vec::new(),
+                    };
+                    for _ in 0..5 {
+                        let applied = client.request(&announce).await.unwrap();
+                        assert!(matches!(applied, ControlResponse::Applied { .. }));
+                        let routes = client.request(&ControlRequest::Routes).await.unwrap();
+                        assert!(matches!(routes, ControlResponse::Routes { .. }));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(bgp.get_routes().await.len(), 12);
+        // Every announce was applied exactly once, one at a time
+        assert_eq!(bgp.rib_version().await, 60);
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+}
@@ -0,0 +1,458 @@
+//! Machine-readable description of the control protocol.
+//!
+//! Dashboards and scripts built on the JSON output ask the daemon for this
+//! instead of reading our source. The JSON Schemas are generated from the
+//! request and reply types the server itself parses and sends, so they
+//! cannot drift from what is on the wire; the command list is read back out
+//! of the request schema. `SCHEMA_VERSION` changes whenever the shape of a
+//! request or reply does, which a test enforces.
+
+use crate::build_info::BuildInfo;
+use crate::control::{ControlEnvelope, ControlReply, MUTATING_COMMANDS};
+use schemars::schema::{RootSchema, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Bumped whenever a request or reply changes shape
+pub const SCHEMA_VERSION: u32 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ControlSchema {
+    pub schema_version: u32,
+    /// The answering daemon, including its enabled features and protocol
+    /// versions
+    pub build: BuildInfo,
+    pub commands: Vec<CommandSchema>,
+    /// JSON Schema of a request line
+    pub request: RootSchema,
+    /// JSON Schema of a reply line
+    pub reply: RootSchema,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CommandSchema {
+    /// Value of the request's `command` field
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Replays with the same `request_id` return the first result
+    pub mutating: bool,
+    pub parameters: Vec<ParameterSchema>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ParameterSchema {
+    pub name: String,
+    pub required: bool,
+    /// Used when the parameter is left out
+    #[serde(default)]
+    pub default: Option<Value>,
+    /// Type of the parameter; references point into `request.definitions`
+    pub schema: Schema,
+}
+
+impl ControlSchema {
+    /// The protocol this build speaks
+    pub fn current() -> Self {
+        let request = schemars::schema_for!(ControlEnvelope);
+        let reply = schemars::schema_for!(ControlReply);
+        ControlSchema {
+            schema_version: SCHEMA_VERSION,
+            build: BuildInfo::current(),
+            commands: commands(&request),
+            request,
+            reply,
+        }
+    }
+
+    pub fn command(&self, name: &str) -> Option<&CommandSchema> {
+        self.commands.iter().find(|command| command.name == name)
+    }
+}
+
+/// One entry per request variant, in declaration order
+fn commands(request: &RootSchema) -> Vec<CommandSchema> {
+    let variants = variants(&request.schema, request);
+    variants
+        .into_iter()
+        .filter_map(|variant| {
+            let object = variant.object.as_ref()?;
+            let name = object
+                .properties
+                .get("command")
+                .and_then(|command| match command {
+                    Schema::Object(command) => command.enum_values.as_ref()?.first()?.as_str(),
+                    Schema::Bool(_) => None,
+                })?
+                .to_string();
+            let parameters = object
+                .properties
+                .iter()
+                .filter(|(field, _)| *field != "command")
+                .map(|(field, schema)| ParameterSchema {
+                    name: field.clone(),
+                    required: object.required.contains(field),
+                    default: match schema {
+                        Schema::Object(schema) => schema
+                            .metadata
+                            .as_ref()
+                            .and_then(|metadata| metadata.default.clone()),
+                        Schema::Bool(_) => None,
+                    },
+                    schema: schema.clone(),
+                })
+                .collect();
+            Some(CommandSchema {
+                mutating: MUTATING_COMMANDS.contains(&name.as_str()),
+                description: variant
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.description.clone()),
+                name,
+                parameters,
+            })
+        })
+        .collect()
+}
+
+/// The per-command alternatives under the envelope, however the generator
+/// nested them for the flattened request
+fn variants<'a>(schema: &'a SchemaObject, root: &'a RootSchema) -> Vec<&'a SchemaObject> {
+    if let Some(reference) = &schema.reference {
+        let name = reference.trim_start_matches("#/definitions/");
+        return match root.definitions.get(name) {
+            Some(Schema::Object(definition)) => variants(definition, root),
+            _ => vec![],
+        };
+    }
+    let is_variant = schema
+        .object
+        .as_ref()
+        .is_some_and(|object| object.properties.contains_key("command"));
+    if is_variant {
+        return vec![schema];
+    }
+    let Some(subschemas) = &schema.subschemas else {
+        return vec![];
+    };
+    [&subschemas.one_of, &subschemas.any_of, &subschemas.all_of]
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|schema| match schema {
+            Schema::Object(schema) => Some(variants(schema, root)),
+            Schema::Bool(_) => None,
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{ControlRequest, ControlServer};
+    use crate::network::bgp::BGPDaemon;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use uuid::Uuid;
+
+    /// Fingerprint of the request and reply schemas at each schema version.
+    /// A failure here means a control type changed shape: bump
+    /// `SCHEMA_VERSION` and add its fingerprint rather than editing an old
+    /// one.
+    const FINGERPRINTS: &[(u32, &str)] = &[
+        (
+            1,
+            "536da3008d0e0510aafd12a60f413b11c0fe3c3ef53ff0c1df8af2f8f5d3cd03",
+        ),
+        (
+            2,
+            "a1c0940d4973632a14b493346c328a1f7556fc15ec288649db41c23226222aa0",
+        ),
+        (
+            3,
+            "b1f00a794289a9c443e3c9328386a92eb4d6e0da502749b833f270fc7091dc8d",
+        ),
+        (
+            4,
+            "95165a26643108c6889d806eeacba2460144f8b81410d8d974a04c1b5e3b25d8",
+        ),
+        (
+            5,
+            "ddc43d22bf9e514de735539fa86f3229d1ca1fbc58bad7ce410f078a26694659",
+        ),
+        (
+            6,
+            "c2634442db404e76114cf0bf7e7936a5a2d626a46046f22eede081830816f47b",
+        ),
+        (
+            7,
+            "9d1ad6c094f33d8d48bb3bb0bbb4c951e92d08afb721902ff3c55df83100804a",
+        ),
+        (
+            8,
+            "1da3de55e20746d2f124a350c139ce6692d08351f17b3b0d8b52f7f6a9aca517",
+        ),
+        (
+            9,
+            "840a4b09d2bcff0eaabe9123c2dd1957074a7a63c82caf18dbef0eb2f1c4fc5d",
+        ),
+        (
+            10,
+            "64fe9f94027effe81157addd530efb627cffbfffb52eda5966421232ee741511",
+        ),
+        (
+            11,
+            "16dd9b11d067f280b81023d2fd9c7f0ce13e9abdacae96034bde57125d953cac",
+        ),
+        (
+            12,
+            "f7af0c7e72c47aab50d67730fdcf7bd6b6e73a47023a58ca7b6e7d2434c7e8ab",
+        ),
+        (
+            13,
+            "63ec9a442587d18fc0d7a8d9fb00816bf13cc665fb06f4b72e3074f2dfe81b07",
+        ),
+        (
+            14,
+            "7f11238f78960127c98612ecefc0ae846d7c55b7089d2dd5b386aab5ef12ad79",
+        ),
+        (
+            15,
+            "5214ebc6b39a61a637c17e03547d4dfc9fea9342be1d6ea2a0fb2847082c83d8",
+        ),
+        (
+            16,
+            "4a5a52e1d67dcd2c29be79505193024331daa4fffb5de340ea8e1199181e3fe6",
+        ),
+        (
+            17,
+            "99f27d9c9945963179f866c064beb4f765547a79ac7f95995fe641c099e74734",
+        ),
+        (
+            18,
+            "9100d679ce14d37fccca4b32c9a35ba82d3d9d1b8deac9253a82b0b8f45ad878",
+        ),
+        (
+            19,
+            "36f93dd2d8425fdd3ac8584047ff4122b24fa149c952fb09f184009f95fbfe9e",
+        ),
+        (
+            20,
+            "8b1ae426685974f382bf17f5cf52323c9e0781a0a650a9477de32bcca719f6e1",
+        ),
+        (
+            21,
+            "198ee01f1c6c0ca2721014f8bf23429b52e4645d6c29ae6f31ae577534c81cd8",
+        ),
+        (
+            22,
+            "96fc9818d0eff433cbde871e92abdcf5d31a5a54e0b0e69a49c8d1797a1f52cb",
+        ),
+        (
+            23,
+            "3ab5f72f8b77e95b7bb10e604257125125c0a29279028ecc638c94e3003e558b",
+        ),
+        (
+            24,
+            "0f23b6fbe2836dceaef5f3c8b505b2efdc6939ceda273e0cb1f5c9d8bd030c83",
+        ),
+        (
+            25,
+            "44bd7886caa46518af57b8b68570fbf83e1ac10c34d5be05a279402f690054fb",
+        ),
+        (
+            26,
+            "655e12a1056231b29bc5606bd5a731a4fe99c691e3d70d1258d7a28216e07494",
+        ),
+        (
+            27,
+            "4becbe968e91c5a8781a07bffefe473b9e03593bbfbff5539eab8feb1e35949f",
+        ),
+        (
+            28,
+            "7b76cd6ca81774cb9330b38dd0f324a592d41e68200f8c7365d8daab1ba28eed",
+        ),
+        (
+            29,
+            "20eec07dd5d65a942652eadda909cc2df4e4601647ffce2fe4e01c6ecabeb6e3",
+        ),
+        (
+            30,
+            "1b1d404fcd8062e259a2e2cb6ae91641ba26f4e3595bc9b2add2c1a9c0d21add",
+        ),
+    ];
+
+    /// Doc comments are left out, so rewording one needs no bump
+    fn fingerprint(schema: &ControlSchema) -> String {
+        fn strip(value: &mut Value) {
+            match value {
+                Value::Object(fields) => {
+                    fields.retain(|key, value| {
+                        !(matches!(key.as_str(), "description" | "title") && value.is_string())
+                    });
+                    fields.values_mut().for_each(strip);
+                }
+                Value::Array(items) => items.iter_mut().for_each(strip),
+                _ => {}
+            }
+        }
+        let mut shapes = serde_json::json!([schema.request, schema.reply]);
+        strip(&mut shapes);
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            serde_json::to_string(&shapes).unwrap().as_bytes(),
+        );
+        digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn test_schema_version_tracks_shape() {
+        let schema = ControlSchema::current();
+        let recorded = FINGERPRINTS
+            .iter()
+            .find(|(version, _)| *version == SCHEMA_VERSION)
+            .map(|(_, fingerprint)| *fingerprint);
+        assert_eq!(
+            Some(fingerprint(&schema).as_str()),
+            recorded,
+            "control request or reply types changed shape; bump SCHEMA_VERSION"
+        );
+        let versions: Vec<u32> = FINGERPRINTS.iter().map(|(version, _)| *version).collect();
+        assert_eq!(versions, (1..=SCHEMA_VERSION).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_commands_listed_with_parameters() {
+        let schema = ControlSchema::current();
+        let names: Vec<&str> = schema.commands.iter().map(|c| c.name.as_str()).collect();
+        for request in [
+            ControlRequest::Routes,
+            ControlRequest::Version,
+            ControlRequest::Schema,
+            ControlRequest::Reload,
+            ControlRequest::Block {
+                entry: String::new(),
+            },
+        ] {
+            assert!(
+                names.contains(&request.name()),
+                "{} missing",
+                request.name()
+            );
+        }
+
+        let register = schema.command("register_service").unwrap();
+        assert!(register.mutating);
+        let param = |name: &str| {
+            register
+                .parameters
+                .iter()
+                .find(|param| param.name == name)
+                .unwrap()
+        };
+        assert!(param("domain").required);
+        assert!(!param("wait_for").required);
+        assert_eq!(param("wait_for").default, Some(Value::Null));
+        assert!(!schema.command("routes").unwrap().mutating);
+        assert!(schema.command("routes").unwrap().parameters.is_empty());
+    }
+
+    async fn exchange(socket_path: &std::path::Path, request: &ControlRequest) -> Value {
+        let envelope = ControlEnvelope {
+            request_id: Uuid::new_v4(),
+            token: None,
+            request: request.clone(),
+        };
+        let mut stream = UnixStream::connect(socket_path).await.unwrap();
+        let mut line = serde_json::to_vec(&envelope).unwrap();
+        line.push(b'\n');
+        stream.write_all(&line).await.unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).await.unwrap();
+        serde_json::from_str(&reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_real_replies_match_emitted_schema() {
+        let bgp = Arc::new(BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0));
+        let socket_path =
+            std::env::temp_dir().join(format!("vx0net-schema-{}.sock", Uuid::new_v4()));
+        ControlServer::new(
+            crate::config::ControlConfig {
+                socket_path: socket_path.display().to_string(),
+                ..Default::default()
+            },
+            bgp,
+        )
+        .start()
+        .await
+        .unwrap();
+
+        // The schema as a client sees it, not as built in-process
+        let reply = exchange(&socket_path, &ControlRequest::Schema).await;
+        let emitted: ControlSchema = serde_json::from_value(reply["schema"].clone()).unwrap();
+        assert_eq!(emitted.schema_version, SCHEMA_VERSION);
+        let request_schema = serde_json::to_value(&emitted.request).unwrap();
+        let reply_schema = serde_json::to_value(&emitted.reply).unwrap();
+        let requests = jsonschema::JSONSchema::compile(&request_schema).unwrap();
+        let replies = jsonschema::JSONSchema::compile(&reply_schema).unwrap();
+
+        let exchanges = [
+            ControlRequest::AnnounceRoute {
+                network: "10.1.0.0/16".parse().unwrap(),
+                next_hop: "10.0.0.1".parse().unwrap(),
+                communities: Vec::new(),
+            },
+            ControlRequest::Routes,
+            ControlRequest::ExplainRoute {
+                network: "10.1.0.0/16".parse().unwrap(),
+            },
+            ControlRequest::Version,
+            ControlRequest::RoutesReceived { peer_asn: 65002 },
+            ControlRequest::Peers,
+            ControlRequest::FindServices {
+                service_type: Some("chat".to_string()),
+                tags: vec!["community1".to_string()],
+                nearby: true,
+                fan_out: false,
+            },
+            ControlRequest::Schema,
+            ControlRequest::Subscribe,
+        ];
+        for request in exchanges {
+            let envelope = serde_json::to_value(ControlEnvelope {
+                request_id: Uuid::new_v4(),
+                token: None,
+                request: request.clone(),
+            })
+            .unwrap();
+            assert!(requests.is_valid(&envelope), "{} request", request.name());
+
+            let reply = exchange(&socket_path, &request).await;
+            let errors: Vec<String> = match replies.validate(&reply) {
+                Ok(()) => vec![],
+                Err(errors) => errors.map(|e| e.to_string()).collect(),
+            };
+            assert!(
+                errors.is_empty(),
+                "{} reply {}: {:?}",
+                request.name(),
+                reply,
+                errors
+            );
+        }
+
+        // And a line no schema allows is caught
+        let bogus = serde_json::json!({"request_id": null, "status": "routes", "routes": 7});
+        assert!(!replies.is_valid(&bogus));
+
+        let _ = std::fs::remove_file(&socket_path);
+    }
+}
@@ -0,0 +1,120 @@
+//! Rendering errors together with their causes.
+//!
+//! Error types in this crate describe only their own failure in `Display`
+//! and expose what caused it through `source()`, so a message is never
+//! repeated down the chain. `Report` walks that chain for people to read.
+
+use std::error::Error;
+use std::fmt;
+
+/// Displays an error followed by each of its causes, separated by `: `
+pub struct Report<'a>(pub &'a (dyn Error + 'static));
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(cause) = source {
+            write!(f, ": {}", cause)?;
+            source = cause.source();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Vx0Config;
+    use crate::network::bgp::protocol::BGPProtocol;
+    use crate::network::bgp::BGPError;
+    use crate::network::ike::tunnels::TunnelManager;
+    use crate::network::ike::IKEError;
+    use crate::node::{NodeError, NodeTier, PeerConnection, Vx0Node};
+
+    fn edge_config() -> Vx0Config {
+        toml::from_str(include_str!("../config/edge-node.toml")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_node_failures_are_typed() {
+        let mut config = edge_config();
+        config.node.asn = 65001;
+        let err = Vx0Node::new(config).unwrap_err();
+        assert!(matches!(
+            err,
+            NodeError::AsnOutOfRange {
+                asn: 65001,
+                tier: NodeTier::Edge,
+                range: (66000, 69999)
+            }
+        ));
+
+        let node = Vx0Node::new(edge_config()).unwrap();
+        let edge_peer =
+            PeerConnection::new(uuid::Uuid::new_v4(), 66002, "10.2.0.2".parse().unwrap());
+        let err = node.add_peer(edge_peer).await.unwrap_err();
+        assert!(matches!(
+            err,
+            NodeError::TierMismatch {
+                ours: NodeTier::Edge,
+                theirs: NodeTier::Edge
+            }
+        ));
+        assert!(!err.is_transient());
+        assert_eq!(err.to_string(), "Edge nodes cannot peer with Edge nodes");
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peer_reports_cause_once() {
+        // Bind then drop, so nothing is listening on the port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let protocol = BGPProtocol::new(66001, "10.2.0.1".parse().unwrap(), NodeTier::Edge);
+        let err: NodeError = protocol
+            .connect_to_peer(addr, 65101)
+            .await
+            .unwrap_err()
+            .into();
+
+        let NodeError::BGP(BGPError::PeerUnreachable {
+            addr: failed,
+            source,
+        }) = &err
+        else {
+            panic!("expected PeerUnreachable, got {:?}", err);
+        };
+        assert_eq!(*failed, addr);
+        assert_eq!(source.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert!(err.is_transient());
+
+        let report = Report(&err).to_string();
+        assert!(report.starts_with(&format!("Cannot reach BGP peer {}: ", addr)));
+        assert_eq!(report.matches(&source.to_string()).count(), 1);
+        assert!(
+            !report.contains("error: "),
+            "stuttering prefix in {}",
+            report
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_failures_chain_to_ike_error() {
+        let tunnels = TunnelManager::new();
+        let tunnel = uuid::Uuid::new_v4();
+        let err = tunnels.send_packet(&tunnel, b"data").await.unwrap_err();
+        assert!(matches!(err, IKEError::TunnelNotFound { tunnel: t } if t == tunnel));
+
+        let peer = uuid::Uuid::new_v4();
+        let err = NodeError::Tunnel { peer, source: err };
+        assert_eq!(
+            Report(&err).to_string(),
+            format!(
+                "Tunnel to peer {} failed: Tunnel {} not found",
+                peer, tunnel
+            )
+        );
+    }
+}
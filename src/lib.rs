@@ -1,6 +1,14 @@
+pub mod build_info;
+pub mod client;
 pub mod config;
+pub mod control;
+pub mod error;
+pub mod monitoring;
 pub mod network;
 pub mod node;
+pub mod storage;
+pub mod util;
+pub mod wire_limits;
 
 pub use config::Vx0Config;
 pub use network::bgp::{BGPDaemon, BGPError};
@@ -1,18 +1,86 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use rand::random;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::ExitCode;
 use std::sync::Arc;
 use tokio::signal;
-use tracing::{debug, error, info};
+use tokio::sync::watch;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
 
-use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::build_info::{self, BuildInfo};
+use vx0net_daemon::config::reload::Reloader;
+use vx0net_daemon::config::topology::{Manifest, Topology};
+use vx0net_daemon::config::units::ByteSize;
+use vx0net_daemon::config::{
+    self, ports, profiles, KernelRoutesConfig, RecorderConfig, StorageConfig, WireFormat,
+};
+use vx0net_daemon::control::batch::{self, BatchCommand, BatchOutcome};
+use vx0net_daemon::control::{
+    self, ControlClient, ControlError, ControlErrorCode, ControlRequest, ControlResponse,
+    ControlServer,
+};
+use vx0net_daemon::error::Report;
+use vx0net_daemon::monitoring::capacity::{CapacityAlert, CapacityReport, HeardDigest};
+use vx0net_daemon::monitoring::capture::{Capture, CaptureFile};
+#[cfg(feature = "metrics")]
+use vx0net_daemon::monitoring::http;
+use vx0net_daemon::monitoring::notify::{self, Notifier, Readiness};
+use vx0net_daemon::monitoring::recorder::{self, StatsRecorder, StatsRing};
+use vx0net_daemon::monitoring::replay::{Replay, Speed};
+use vx0net_daemon::monitoring::sampling::LogSampler;
+use vx0net_daemon::monitoring::shutdown::{PreviousRun, ShutdownLog, ShutdownReason};
+use vx0net_daemon::monitoring::support::{self, Check, Redactor, SupportBundle};
+use vx0net_daemon::monitoring::tasks::{TaskInfo, STUCK_SHUTDOWN_EXIT_CODE};
+use vx0net_daemon::monitoring::timing::{format_ms, PhaseTimer, PhaseTiming, Stage};
+use vx0net_daemon::monitoring::{self, MonitoringError, Supervisor};
+use vx0net_daemon::network::acl::AclEntry;
+use vx0net_daemon::network::bgp::multihoming::{self, MultihomingStatus};
+use vx0net_daemon::network::bgp::pins::RoutePin;
+use vx0net_daemon::network::bgp::policy::DryRunOutcome;
+use vx0net_daemon::network::bgp::tunnel_gate::TunnelGate;
+use vx0net_daemon::network::bgp::validator::RouteValidator;
+use vx0net_daemon::network::bgp::{BGPDaemon, Community, Prefix, RouteEntry};
+use vx0net_daemon::network::dns::cache::{CacheEntryInfo, CacheStats, CachedAnswer, ResolverCache};
+use vx0net_daemon::network::dns::gateway::GatewayService;
+#[cfg(feature = "dns-server")]
+use vx0net_daemon::network::dns::health::AnswerRanking;
+use vx0net_daemon::network::dns::metadata::start_publishing as publish_node_metadata;
+#[cfg(feature = "dns-server")]
+use vx0net_daemon::network::dns::server::Vx0DNSServer;
+use vx0net_daemon::network::dns::sync::ZoneSyncService;
+use vx0net_daemon::network::dns::{DNSError, Vx0DNS};
+use vx0net_daemon::network::firewall::{self, Backend, FirewallManager, SystemRunner};
+use vx0net_daemon::network::ike::encap::EncapEndpoint;
 use vx0net_daemon::network::ike::session::IKEDaemon;
+#[cfg(feature = "kernel_routes")]
+use vx0net_daemon::network::kernel::netlink::NetlinkSink;
+use vx0net_daemon::network::kernel::KernelRouteSync;
+use vx0net_daemon::node::admin::{self, AdminSource};
+use vx0net_daemon::node::asn_registry;
+use vx0net_daemon::node::bootstrap::BootstrapManager;
+use vx0net_daemon::node::bootstrap_health::{BootstrapRegistry, BootstrapStatus};
+use vx0net_daemon::node::capabilities;
+#[cfg(feature = "discovery")]
+use vx0net_daemon::node::discovery::PeerDiscovery;
 use vx0net_daemon::node::manager::NodeManager;
-use vx0net_daemon::{NodeError, Vx0Config, Vx0Node};
+use vx0net_daemon::node::metadata::{self, ServiceMetadata};
+use vx0net_daemon::node::quarantine::{self, QuarantineStatus};
+use vx0net_daemon::node::redundancy::{self, GroupStatus};
+use vx0net_daemon::node::search::ServiceSummary;
+use vx0net_daemon::node::services::ServiceRegistry;
+use vx0net_daemon::node::NodeTier;
+use vx0net_daemon::storage::{StorageError, Store};
+use vx0net_daemon::util::daemonize::{daemonize, Daemonized};
+use vx0net_daemon::{BGPError, IKEError, NodeError, Vx0Config, Vx0Node};
 
 #[derive(Parser)]
 #[command(name = "vx0net")]
 #[command(about = "VX0 Network Daemon - Censorship-resistant networking system")]
-#[command(version = "0.1.0")]
+#[command(version = build_info::VERSION)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -24,39 +92,193 @@ struct Cli {
     config: Option<String>,
 }
 
+/// Common service metadata; stored as the flat map peers exchange
+#[derive(clap::Args)]
+struct ServiceMetadataArgs {
+    /// What the service offers
+    #[arg(long)]
+    description: Option<String>,
+    /// How to check the service is up: tcp or http
+    #[arg(long, value_name = "KIND")]
+    health_check: Option<String>,
+    /// Path an http health check requests
+    #[arg(long, value_name = "PATH", requires = "health_check")]
+    health_path: Option<String>,
+    /// Least seconds between health checks
+    #[arg(long, value_name = "SECS", requires = "health_check")]
+    health_interval: Option<u64>,
+    /// Tag to list the service under; repeatable
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+    /// How to reach whoever runs the service
+    #[arg(long, value_name = "CONTACT")]
+    owner: Option<String>,
+    /// public, or unlisted to leave it out of node announcements and
+    /// service searches
+    #[arg(long)]
+    visibility: Option<String>,
+}
+
+impl ServiceMetadataArgs {
+    fn into_map(self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        let mut set = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                map.insert(key.to_string(), value);
+            }
+        };
+        set(metadata::DESCRIPTION, self.description);
+        set(metadata::HEALTH_CHECK, self.health_check);
+        set(metadata::HEALTH_PATH, self.health_path);
+        set(
+            metadata::HEALTH_INTERVAL,
+            self.health_interval.map(|secs| secs.to_string()),
+        );
+        set(
+            metadata::TAGS,
+            (!self.tags.is_empty()).then(|| self.tags.join(",")),
+        );
+        set(metadata::OWNER_CONTACT, self.owner);
+        set(metadata::VISIBILITY, self.visibility);
+        map
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Start the VX0 network daemon
     Start {
-        /// Run in foreground (don't daemonize)
-        #[arg(short, long)]
+        /// Run in the foreground (the default)
+        #[arg(short, long, conflicts_with = "daemon")]
         foreground: bool,
+        /// Detach from the terminal, logging to monitoring.daemon.log_file;
+        /// returns once the daemon is ready
+        #[arg(short, long)]
+        daemon: bool,
         /// Automatically join the network on start
         #[arg(long)]
         join_network: bool,
+        /// Join even though node.hostname is left at the default
+        #[arg(long)]
+        allow_default_hostname: bool,
     },
     /// Stop the VX0 network daemon
     Stop,
     /// Show daemon status
-    Status,
+    Status {
+        /// Also list the daemon's live tasks with their ages
+        #[arg(long)]
+        tasks: bool,
+        /// Also show how long each startup and last shutdown phase took
+        #[arg(long)]
+        startup: bool,
+        /// Only run the readiness checks, exiting 1 unless all pass
+        #[arg(long, conflicts_with_all = ["tasks", "startup"])]
+        ready: bool,
+    },
+    /// Re-read the configuration, showing what was applied and what waits
+    /// for a restart
+    Reload,
     /// Show node information
     Info,
     /// Connect to a peer node
     Connect {
         /// Peer IP address
-        peer_ip: String,
+        peer_ip: std::net::IpAddr,
         /// Peer ASN
         peer_asn: u32,
     },
-    /// Disconnect from a peer node
+    /// Say goodbye to a peer node and disconnect from it
     Disconnect {
         /// Peer IP address
-        peer_ip: String,
+        peer_ip: std::net::IpAddr,
+    },
+    /// Leave every peer for maintenance
+    Maintenance {
+        /// Seconds until this node expects to be back; peers probe it then
+        #[arg(long)]
+        return_in: Option<u64>,
+        /// Go even while a redundancy partner is down or being maintained
+        #[arg(long, conflicts_with = "wait")]
+        force: bool,
+        /// Keep asking until it is this node's turn in its redundancy group
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Originate a route from this node
+    Announce {
+        /// Prefix to originate (e.g. 10.20.0.0/16)
+        prefix: Prefix,
+        /// Next hop in the same address family
+        next_hop: std::net::IpAddr,
+        /// Community to tag the route with, as asn:value or prefer, normal
+        /// or backup; may be repeated
+        #[arg(long = "community")]
+        communities: Vec<Community>,
+    },
+    /// Stop originating a route
+    Withdraw {
+        /// Prefix to stop originating
+        prefix: Prefix,
     },
     /// Show routing table
-    Routes,
-    /// Show connected peers
-    Peers,
+    Routes {
+        #[command(subcommand)]
+        view: Option<RoutesView>,
+    },
+    /// Show connected peers, or take one down or back up
+    Peers {
+        #[command(subcommand)]
+        action: Option<PeersAction>,
+    },
+    /// Show nodes in the directory and what they offer
+    Nodes,
+    /// Show or re-check the health of bootstrap nodes
+    Bootstrap {
+        #[command(subcommand)]
+        action: BootstrapAction,
+    },
+    /// Browse ASN grants from the cooperative registry
+    Asn {
+        #[command(subcommand)]
+        action: AsnAction,
+    },
+    /// Inspect the local DNS server
+    Dns {
+        #[command(subcommand)]
+        action: DnsAction,
+    },
+    /// Work with routing policy
+    Policy {
+        #[command(subcommand)]
+        action: PolicyAction,
+    },
+    /// Print the control protocol's commands and JSON Schemas, for tooling
+    Schema,
+    /// Show build metadata
+    Version {
+        /// Also list enabled cargo features and protocol versions
+        #[arg(long)]
+        features: bool,
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Inspect recorded peer and tunnel stats
+    Stats {
+        #[command(subcommand)]
+        action: StatsAction,
+    },
+    /// Look at the runtime state the daemon keeps on disk
+    Storage {
+        #[command(subcommand)]
+        action: StorageAction,
+    },
+    /// Allow rules the daemon keeps in the host firewall
+    Firewall {
+        #[command(subcommand)]
+        action: FirewallAction,
+    },
     /// Register a .vx0 service
     RegisterService {
         /// Service name
@@ -65,11 +287,134 @@ enum Commands {
         domain: String,
         /// Service port
         port: u16,
+        /// web, email, file, chat, database or a custom type (default: the
+        /// service name)
+        #[arg(long = "type", value_name = "TYPE")]
+        service_type: Option<String>,
+        /// Wait until N DNS-serving peers confirm storing the records
+        #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "1")]
+        wait_for_propagation: Option<usize>,
+        /// Seconds to wait for confirmations
+        #[arg(long, value_name = "SECS", requires = "wait_for_propagation")]
+        timeout: Option<u64>,
+        #[command(flatten)]
+        metadata: Box<ServiceMetadataArgs>,
+    },
+    /// Find listed services by type and tag
+    FindService {
+        /// web, email, file, chat, database or a custom type
+        #[arg(long = "type", value_name = "TYPE")]
+        service_type: Option<String>,
+        /// Tag the service must carry; repeatable
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Services on the closest nodes first
+        #[arg(long)]
+        nearby: bool,
+        /// Also ask directly connected peers, whose listings may be fresher
+        #[arg(long)]
+        ask_peers: bool,
+    },
+    /// Keep the services hosted under a domain alive for another TTL
+    RefreshService {
+        /// Service domain
+        domain: String,
+    },
+    /// Stop hosting a service; the domain keeps its address while other
+    /// services share it
+    UnregisterService {
+        /// Service domain
+        domain: String,
+        /// Which of several services under the domain, by type
+        #[arg(long = "type", value_name = "TYPE")]
+        service_type: Option<String>,
+        /// Which of several services under the domain, by port
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Services this daemon hosts, grouped by domain
+    ListServices,
+    /// Refuse a peer by ASN (AS66001), address or prefix, or node id
+    Block {
+        /// ASN, IP address, CIDR prefix or node id
+        target: String,
+    },
+    /// Lift a runtime block
+    Unblock {
+        /// ASN, IP address, CIDR prefix or node id
+        target: String,
+    },
+    /// Run commands read from stdin over one control connection: one per
+    /// line in the syntax of these subcommands, or a JSON array of control
+    /// requests
+    Batch {
+        /// Validate every command first, and apply all of them or none
+        #[arg(long)]
+        atomic: bool,
+    },
+    /// Write a starting config for a node tier
+    Init {
+        /// edge, regional, backbone, or lab for local testing
+        #[arg(long, default_value = "edge", value_parser = clap::builder::PossibleValuesParser::new(profiles::NAMES))]
+        profile: String,
+        /// Where to write it
+        #[arg(short, long, default_value = "vx0net.toml")]
+        output: String,
+        /// Replace an existing file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Work with multi-node lab topologies declared in a manifest
+    Topology {
+        #[command(subcommand)]
+        action: TopologyAction,
     },
     /// Join the VX0 network (interactive)
-    Join,
+    Join {
+        /// Write config/vx0net.toml from this profile
+        #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(profiles::NAMES))]
+        profile: Option<String>,
+    },
     /// Check network connectivity and bootstrap status
     NetworkStatus,
+    /// Load the configuration as the daemon would and report values out of
+    /// range or probably in the wrong unit
+    CheckConfig {
+        /// File to check instead of the usual locations
+        #[arg(short, long)]
+        file: Option<String>,
+    },
+    /// Collect config, daemon state, logs and crash reports into a tar.gz
+    /// for a bug report, with secrets masked
+    SupportBundle {
+        /// Where to write the bundle
+        #[arg(short, long, default_value = "vx0-bundle.tar.gz")]
+        output: String,
+        /// How much of the end of the daemon log to include (e.g. 256KB)
+        #[arg(long, value_name = "SIZE", default_value_t = support::DEFAULT_LOG_TAIL)]
+        log_tail: ByteSize,
+        /// Cap on the bundle's contents; the oldest log lines go first
+        #[arg(long, value_name = "SIZE", default_value_t = support::DEFAULT_MAX_SIZE)]
+        max_size: ByteSize,
+    },
+    /// Replay a capture against an isolated node and print the resulting
+    /// routes
+    Replay {
+        /// Capture file written with monitoring.capture enabled
+        file: String,
+        /// How fast to feed records in, e.g. 10x; max ignores captured gaps
+        #[arg(long, default_value_t = Speed::Max)]
+        speed: Speed,
+        /// Stop after the record with this sequence number
+        #[arg(long, value_name = "SEQ")]
+        until: Option<u64>,
+        /// Also explain why this prefix is or is not in the routing table
+        #[arg(long, value_name = "PREFIX")]
+        explain: Vec<Prefix>,
+        /// Take routing policy from this config instead of the defaults
+        #[arg(short, long)]
+        config: Option<String>,
+    },
     /// Scan for available ASNs in your tier
     ScanAsns {
         /// Node tier (Backbone, Regional, Edge)
@@ -77,226 +422,3111 @@ enum Commands {
     },
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+#[derive(Subcommand)]
+enum RoutesView {
+    /// Routes a peer sent us, before policy (Adj-RIB-In)
+    Received {
+        /// Peer ASN
+        #[arg(long)]
+        peer: u32,
+    },
+    /// Routes we have advertised to a peer (Adj-RIB-Out)
+    Advertised {
+        /// Peer ASN
+        #[arg(long)]
+        peer: u32,
+    },
+    /// Why a prefix is or is not installed
+    Explain {
+        /// Prefix to look up (e.g. 10.20.0.0/16); host bits are ignored
+        prefix: Prefix,
+    },
+    /// How long routes stamped by their origin took to arrive, by origin tier
+    PropagationStats,
+    /// Select a peer's path for a prefix ahead of normal best-path selection
+    Pin {
+        prefix: Prefix,
+        /// ASN of the peer whose path to select
+        #[arg(long)]
+        via: u32,
+        /// Remove the pin after this long (e.g. 30m, 2h, 7d)
+        #[arg(long)]
+        until: Option<String>,
+        /// Pin a prefix no RIB holds yet
+        #[arg(long)]
+        allow_missing: bool,
+    },
+    /// Return a prefix to normal selection
+    Unpin { prefix: Prefix },
+}
+
+#[derive(Subcommand)]
+enum PeersAction {
+    /// Say goodbye to a peer and keep it down, configuration and metrics
+    /// kept, until enabled again; survives restarts
+    Disable { asn: u32 },
+    /// Let a disabled peer reconnect as usual
+    Enable { asn: u32 },
+    /// Show or clear peers' quarantine scores
+    Quarantine {
+        #[command(subcommand)]
+        action: QuarantineAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum QuarantineAction {
+    /// Peers with a score, their standing and the violations behind it
+    List,
+    /// Forget a peer's score, lifting any demotion or quarantine
+    Clear { asn: u32 },
+}
+
+#[derive(Subcommand)]
+enum BootstrapAction {
+    /// Bootstrap nodes in the order they are tried, with their health
+    List,
+    /// Probe every bootstrap node now, quarantined ones included
+    Probe,
+}
+
+#[derive(Subcommand)]
+enum AsnAction {
+    /// The assignment log: every grant this node knows of, by ASN
+    List,
+}
+
+#[derive(Subcommand)]
+enum DnsAction {
+    /// List, flush or count the answers the DNS server reuses
+    Cache {
+        #[command(subcommand)]
+        action: DnsCacheAction,
+    },
+    /// Who registered a name, when, and how its records changed since
+    History {
+        /// e.g. forum.community1.vx0
+        domain: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DnsCacheAction {
+    /// Cached answers with their remaining TTL, source and hits
+    List {
+        /// Exact name or *.suffix (e.g. *.community1.vx0)
+        #[arg(long)]
+        name: Option<String>,
+        /// Only "no such name" and "no such record" answers
+        #[arg(long)]
+        negative: bool,
+    },
+    /// Drop cached answers so the next query looks the name up again
+    Flush {
+        /// Exact name or *.suffix (e.g. *.community1.vx0)
+        #[arg(long)]
+        name: Option<String>,
+        /// Only "no such name" and "no such record" answers
+        #[arg(long)]
+        negative: bool,
+    },
+    /// Hit, miss and eviction counts
+    Stats,
+}
+
+#[derive(Subcommand)]
+enum TopologyAction {
+    /// Write a validated config per node and a docker-compose port map
+    Generate {
+        /// Topology manifest, TOML or YAML
+        #[arg(long)]
+        manifest: String,
+        /// Directory to write into
+        #[arg(long, default_value = "lab")]
+        out_dir: String,
+        /// Replace configs already in the directory
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyAction {
+    /// Show which installed routes a candidate policy would accept or reject
+    Test {
+        /// Policy fragment in the same schema as the [network.bgp] config
+        #[arg(long)]
+        file: String,
+        /// Evaluate this peer's received routes instead of the Loc-RIB
+        #[arg(long)]
+        peer: Option<u32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum StatsAction {
+    /// Print a window of the stats ring file
+    Dump {
+        /// Only records newer than this (e.g. 30m, 2h, 7d)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only records for this peer ASN
+        #[arg(long)]
+        peer: Option<u32>,
+        #[arg(long, value_enum, default_value_t = DumpFormat::Csv)]
+        format: DumpFormat,
+        /// Ring file to read instead of the configured one
+        #[arg(long)]
+        file: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum StorageAction {
+    /// Print every key and value in a namespace, or list the namespaces
+    Inspect {
+        /// e.g. route_pins or shutdown
+        namespace: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum FirewallAction {
+    /// The rules the daemon believes it owns, and any it could not add
+    Status,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DumpFormat {
+    Csv,
+    Json,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Forking has to happen before the runtime starts its threads
+    let mut ready_pipe = None;
+    if let Commands::Start { daemon: true, .. } = cli.command {
+        match detach() {
+            Ok(pipe) => ready_pipe = Some(pipe),
+            Err(code) => return code,
+        }
+    }
+
+    // Initialize tracing
+    let log_level = match cli.log_level.as_str() {
+        "trace" => tracing::Level::TRACE,
+        "debug" => tracing::Level::DEBUG,
+        "info" => tracing::Level::INFO,
+        "warn" => tracing::Level::WARN,
+        "error" => tracing::Level::ERROR,
+        _ => tracing::Level::INFO,
+    };
+
+    // Logs go to stderr so commands with machine-readable output keep stdout
+    // clean; a detached daemon's stderr is its log file, so no colours
+    // A reload changing `monitoring.log_level` swaps the level in place
+    let (filter, reload) = reload::Layer::new(LevelFilter::from_level(log_level));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .with_ansi(ready_pipe.is_none())
+                .with_writer(Supervisor::global().log_writer()),
+        )
+        .init();
+    monitoring::install_log_level(move |level| reload.reload(level).map_err(|e| e.to_string()));
+
+    info!("VX0 Network Daemon {}", BuildInfo::current().summary());
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error: cannot start the runtime: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match runtime.block_on(run(cli.command, ready_pipe)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", Report(&*e));
+            if let Some(MonitoringError::StuckShutdown { .. }) = e.downcast_ref() {
+                // Dropping the runtime would wait on the stuck tasks too
+                std::process::exit(STUCK_SHUTDOWN_EXIT_CODE.into());
+            }
+            ExitCode::from(exit_code(&*e))
+        }
+    }
+}
+
+/// Become the background daemon, returning the pipe to report readiness on.
+/// The process that was started exits here once the daemon is ready.
+fn detach() -> Result<std::fs::File, ExitCode> {
+    // Reloads after the daemon changes directory still find ./vx0net.toml
+    config::pin_local_file();
+    let config = Vx0Config::load().map_err(|e| {
+        eprintln!("Error: {}", Report(&e));
+        ExitCode::from(EX_CONFIG)
+    })?;
+    let log_file = &config.monitoring.daemon.log_file;
+    match daemonize(&config.monitoring.daemon) {
+        Ok(Daemonized::Daemon(pipe)) => Ok(pipe),
+        Ok(Daemonized::Parent { pid: Some(pid) }) => {
+            println!("vx0net started (pid {}); logging to {}", pid, log_file);
+            Err(ExitCode::SUCCESS)
+        }
+        Ok(Daemonized::Parent { pid: None }) => {
+            eprintln!("Error: vx0net exited during startup; see {}", log_file);
+            Err(ExitCode::FAILURE)
+        }
+        Err(e) => {
+            eprintln!("Error: cannot daemonize: {}", Report(&e));
+            Err(ExitCode::FAILURE)
+        }
+    }
+}
+
+// sysexits(3) codes, so scripts can tell bad config from an unreachable peer
+const EX_UNAVAILABLE: u8 = 69;
+const EX_TEMPFAIL: u8 = 75;
+const EX_NOPERM: u8 = 77;
+const EX_CONFIG: u8 = 78;
+
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> u8 {
+    if let Some(error) = error.downcast_ref::<NodeError>() {
+        return match error {
+            NodeError::Config(_)
+            | NodeError::InvalidAddress { .. }
+            | NodeError::AsnOutOfRange { .. }
+            | NodeError::TierMismatch { .. } => EX_CONFIG,
+            NodeError::JoinRejected { .. } | NodeError::Blocked { .. } => EX_NOPERM,
+            NodeError::BGP(error) => bgp_exit_code(error),
+            error if error.is_transient() => EX_TEMPFAIL,
+            _ => 1,
+        };
+    }
+    if let Some(error) = error.downcast_ref::<BGPError>() {
+        return bgp_exit_code(error);
+    }
+    if let Some(ControlError::Unreachable { .. }) = error.downcast_ref::<ControlError>() {
+        return EX_UNAVAILABLE;
+    }
+    if let Some(MonitoringError::StuckShutdown { .. }) = error.downcast_ref() {
+        return STUCK_SHUTDOWN_EXIT_CODE;
+    }
+    1
+}
+
+fn bgp_exit_code(error: &BGPError) -> u8 {
+    match error {
+        BGPError::PeerUnreachable { .. } | BGPError::ConnectTimeout { .. } => EX_UNAVAILABLE,
+        BGPError::InvalidPeerAddress { .. } | BGPError::Configuration(_) => EX_CONFIG,
+        error if error.is_transient() => EX_TEMPFAIL,
+        _ => 1,
+    }
+}
+
+async fn run(
+    command: Commands,
+    ready_pipe: Option<std::fs::File>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Commands::Start {
+            join_network,
+            allow_default_hostname,
+            ..
+        } => {
+            start_daemon(join_network, allow_default_hostname, ready_pipe).await?;
+        }
+        Commands::Stop => {
+            info!("Stopping VX0 daemon...");
+            // In a real implementation, we would send a signal to the running daemon
+            info!("VX0 daemon stopped");
+        }
+        Commands::Status {
+            tasks,
+            startup,
+            ready,
+        } => {
+            if ready {
+                show_readiness().await?;
+            } else {
+                show_status(tasks, startup).await?;
+            }
+        }
+        Commands::Info => {
+            show_node_info().await?;
+        }
+        Commands::Connect { peer_ip, peer_asn } => {
+            connect(peer_ip, peer_asn).await?;
+        }
+        Commands::Announce {
+            prefix,
+            next_hop,
+            communities,
+        } => {
+            update_routes(ControlRequest::AnnounceRoute {
+                network: prefix,
+                next_hop,
+                communities,
+            })
+            .await?;
+        }
+        Commands::Withdraw { prefix } => {
+            update_routes(ControlRequest::WithdrawRoute { network: prefix }).await?;
+        }
+        Commands::Disconnect { peer_ip } => {
+            depart(ControlRequest::Disconnect { peer: peer_ip }).await?;
+        }
+        Commands::Maintenance {
+            return_in,
+            force,
+            wait,
+        } => {
+            let request = ControlRequest::Maintenance {
+                return_in_secs: return_in,
+                force,
+            };
+            if wait {
+                depart_in_turn(request).await?;
+            } else {
+                depart(request).await?;
+            }
+        }
+        Commands::Routes { view } => {
+            show_routes(view).await?;
+        }
+        Commands::Peers { action: None } => {
+            show_peers().await?;
+        }
+        Commands::Peers {
+            action: Some(PeersAction::Quarantine { action }),
+        } => {
+            quarantine(action).await?;
+        }
+        Commands::Peers {
+            action: Some(action),
+        } => {
+            peer_admin(peers_request(action)).await?;
+        }
+        Commands::Nodes => {
+            show_nodes().await?;
+        }
+        Commands::Reload => {
+            reload().await?;
+        }
+        Commands::Bootstrap { action } => {
+            show_bootstrap(action).await?;
+        }
+        Commands::Asn { action } => {
+            show_asn_grants(action).await?;
+        }
+        Commands::Dns {
+            action: DnsAction::Cache { action },
+        } => {
+            dns_cache(action).await?;
+        }
+        Commands::Dns {
+            action: DnsAction::History { domain },
+        } => {
+            show_dns_history(domain).await?;
+        }
+        Commands::Policy {
+            action: PolicyAction::Test { file, peer },
+        } => {
+            test_policy(&file, peer).await?;
+        }
+        Commands::Schema => {
+            show_schema().await?;
+        }
+        Commands::Version { features, json } => {
+            show_version(features, json)?;
+        }
+        Commands::Stats {
+            action:
+                StatsAction::Dump {
+                    since,
+                    peer,
+                    format,
+                    file,
+                },
+        } => {
+            dump_stats(since, peer, format, file)?;
+        }
+        Commands::Storage {
+            action: StorageAction::Inspect { namespace },
+        } => {
+            inspect_storage(namespace)?;
+        }
+        Commands::Firewall {
+            action: FirewallAction::Status,
+        } => {
+            firewall_status().await?;
+        }
+        Commands::RegisterService {
+            name,
+            domain,
+            port,
+            service_type,
+            wait_for_propagation,
+            timeout,
+            metadata,
+        } => {
+            register_service(
+                name,
+                domain,
+                port,
+                service_type,
+                wait_for_propagation,
+                timeout,
+                (*metadata).into_map(),
+            )
+            .await?;
+        }
+        Commands::FindService {
+            service_type,
+            tags,
+            nearby,
+            ask_peers,
+        } => {
+            find_services(ControlRequest::FindServices {
+                service_type,
+                tags,
+                nearby,
+                fan_out: ask_peers,
+            })
+            .await?;
+        }
+        Commands::RefreshService { domain } => {
+            refresh_service(&domain).await?;
+        }
+        Commands::UnregisterService {
+            domain,
+            service_type,
+            port,
+        } => {
+            unregister_service(ControlRequest::UnregisterService {
+                domain,
+                service_type,
+                port,
+            })
+            .await?;
+        }
+        Commands::ListServices => {
+            find_services(ControlRequest::ListServices).await?;
+        }
+        Commands::Block { target } => {
+            update_acl(ControlRequest::Block { entry: target }).await?;
+        }
+        Commands::Unblock { target } => {
+            update_acl(ControlRequest::Unblock { entry: target }).await?;
+        }
+        Commands::Batch { atomic } => {
+            run_batch(atomic).await?;
+        }
+        Commands::Init {
+            profile,
+            output,
+            force,
+        } => {
+            init_config(&profile, &output, force)?;
+        }
+        Commands::Topology {
+            action:
+                TopologyAction::Generate {
+                    manifest,
+                    out_dir,
+                    force,
+                },
+        } => {
+            generate_topology(&manifest, &out_dir, force)?;
+        }
+        Commands::Join { profile } => {
+            join_network_interactive(profile).await?;
+        }
+        Commands::NetworkStatus => {
+            show_network_status().await?;
+        }
+        Commands::CheckConfig { file } => {
+            check_config(file.as_deref())?;
+        }
+        Commands::SupportBundle {
+            output,
+            log_tail,
+            max_size,
+        } => {
+            support_bundle(&output, log_tail, max_size).await?;
+        }
+        Commands::Replay {
+            file,
+            speed,
+            until,
+            explain,
+            config,
+        } => {
+            replay(&file, speed, until, &explain, config.as_deref()).await?;
+        }
+        Commands::ScanAsns { tier } => {
+            scan_available_asns(&tier).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn start_daemon(
+    join_network: bool,
+    allow_default_hostname: bool,
+    ready_pipe: Option<std::fs::File>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Starting VX0 network daemon...");
+    let mut startup = PhaseTimer::new(Stage::Startup);
+
+    if ready_pipe.is_some() {
+        info!("Running in daemon mode (pid {})", std::process::id());
+    }
+
+    // Load configuration
+    let config = Vx0Config::load().map_err(|e| {
+        error!("Failed to load configuration: {}", e);
+        e
+    })?;
+
+    debug!(
+        "Configuration loaded: ASN {}, Hostname: {}",
+        config.node.asn, config.node.hostname
+    );
+    for warning in config.unit_warnings() {
+        warn!("Configuration: {}", warning);
+    }
+    for warning in config.feature_warnings() {
+        warn!("Configuration: {}", warning);
+    }
+    startup.set_limits(&config.monitoring.timing);
+    startup.lap("config");
+
+    if join_network {
+        config.check_public_hostname(allow_default_hostname)?;
+    }
+
+    // Report every listener conflict at once rather than the first failed bind
+    ports::preflight(&config)?;
+    startup.lap("preflight");
+
+    // Let peers reach the listeners about to be bound
+    let firewall = start_firewall(&config).await;
+    if firewall.is_some() {
+        startup.lap("firewall");
+    }
+
+    let store = Store::open(&config.storage.dir)?;
+
+    // Say how the last run ended, and hold off if we keep dying
+    let shutdown_log = ShutdownLog::new(&config.monitoring.shutdown, &store);
+    let previous = shutdown_log.begin(chrono::Utc::now())?;
+    match &previous.previous {
+        PreviousRun::FirstStart => {}
+        PreviousRun::Clean(record) => info!("Last shutdown: {}", record),
+        PreviousRun::Unclean(record) => warn!(
+            "Previous run did not shut down cleanly: {} ({} unclean exits recently)",
+            record, previous.unclean_exits
+        ),
+        PreviousRun::Killed { pid, .. } => warn!(
+            "Previous run{} was killed without recording a shutdown ({} unclean exits recently)",
+            pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default(),
+            previous.unclean_exits
+        ),
+    }
+    startup.lap("storage");
+    if let Some(delay) = previous.delay {
+        warn!("Crash loop suspected; delaying startup by {:?}", delay);
+        tokio::time::sleep(delay).await;
+        startup.waited("crash-loop-delay");
+    }
+
+    // Ready once every subsystem is up, and joined if asked to wait for it
+    let mut components = vec!["node", "bgp", "dns", "control", "ike"];
+    if join_network && config.monitoring.daemon.ready_after_join {
+        components.push("join");
+    }
+    let mut readiness = Readiness::new(Notifier::from_env(), &components);
+    if let Some(pipe) = ready_pipe {
+        readiness = readiness.with_parent(pipe);
+    }
+
+    let guard = shutdown_log.arm();
+    match run_daemon(
+        config,
+        store,
+        join_network,
+        readiness,
+        startup,
+        firewall.clone(),
+    )
+    .await
+    {
+        Ok(()) => {
+            guard.finish(ShutdownReason::OperatorStop, None, None)?;
+            Ok(())
+        }
+        Err(e) => {
+            // Do not leave ports open for a daemon that is not running
+            if let Some(firewall) = &firewall {
+                firewall.clear().await;
+            }
+            let message = Report(&*e).to_string();
+            guard.finish(
+                ShutdownReason::FatalError,
+                failed_component(&*e),
+                Some(message),
+            )?;
+            Err(e)
+        }
+    }
+}
+
+/// Subsystem a fatal error came from, for the shutdown record
+fn failed_component(error: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    if error.is::<NodeError>() {
+        Some("node")
+    } else if error.is::<BGPError>() {
+        Some("bgp")
+    } else if error.is::<IKEError>() {
+        Some("ike")
+    } else if error.is::<DNSError>() {
+        Some("dns")
+    } else if error.is::<ControlError>() {
+        Some("control")
+    } else if error.is::<MonitoringError>() {
+        Some("monitoring")
+    } else if error.is::<StorageError>() {
+        Some("storage")
+    } else {
+        None
+    }
+}
+
+async fn run_daemon(
+    config: Vx0Config,
+    store: Store,
+    join_network: bool,
+    mut readiness: Readiness,
+    startup: PhaseTimer,
+    firewall: Option<Arc<FirewallManager>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Panicking tasks leave crash reports here
+    if let Err(e) = Supervisor::global().configure(&config.monitoring.crash) {
+        warn!(
+            "Crash reports disabled; cannot use {}: {}",
+            config.monitoring.crash.directory, e
+        );
+    }
+    Supervisor::global()
+        .tasks()
+        .set_task_timeout(std::time::Duration::from_secs(
+            config.monitoring.shutdown.task_timeout_secs,
+        ));
+    startup.lap("supervisor");
+
+    // Create VX0 node
+    let node = Arc::new(Vx0Node::new(config.clone())?);
+    node.follow_peer_messages();
+    info!("Created VX0 node: {} (ASN: {})", node.hostname, node.asn);
+    readiness.set_monitor(Arc::clone(&node.readiness));
+    // Everything the node processes from here on, for `vx0net replay`
+    let capture = if config.monitoring.capture.enabled {
+        let capture = Arc::new(Capture::open(
+            &config.monitoring.capture,
+            config.node.asn,
+            config.get_ipv4_addr()?.into(),
+        )?);
+        node.set_capture(Arc::clone(&capture));
+        Some(capture)
+    } else {
+        None
+    };
+    let admin_down = node.admin.open(store.namespace(admin::NAMESPACE))?;
+    if admin_down > 0 {
+        info!("Keeping {} peer(s) administratively down", admin_down);
+    }
+    let scored = node
+        .quarantine
+        .open(store.namespace(quarantine::NAMESPACE))?;
+    if scored > 0 {
+        info!("Restored quarantine scores of {} peer(s)", scored);
+    }
+    let grants = node
+        .asn_registry
+        .open(store.namespace(asn_registry::NAMESPACE))?;
+    if grants > 0 {
+        info!("Restored {} ASN grant(s)", grants);
+    }
+    startup.lap("identity");
+
+    // Start node services
+    node.start().await?;
+    readiness.started("node");
+    startup.lap("node");
+
+    // Announce ourselves on the local network as privately as configured
+    let discovery_privacy = watch::Sender::new(config.services.discovery_privacy);
+    #[cfg(feature = "discovery")]
+    if config.services.enable_discovery {
+        PeerDiscovery::new(
+            &format!("0.0.0.0:{}", config.services.discovery_port),
+            &node,
+            discovery_privacy.subscribe(),
+        )
+        .await?
+        .start();
+        startup.lap("discovery");
+    }
+
+    // Hand out ASN grants to joiners, on Backbone nodes that opt in
+    if config.joining.registry.enabled {
+        if node.tier == NodeTier::Backbone {
+            let port = config.joining.registry.listen_port;
+            asn_registry::start(
+                Arc::clone(&node),
+                std::net::SocketAddr::from(([0, 0, 0, 0], port)),
+            )
+            .await?;
+            asn_registry::start_replication(Arc::clone(&node));
+            startup.lap("asn-registry");
+        } else {
+            warn!("joining.registry.enabled is ignored: only Backbone nodes run the ASN registry");
+        }
+    }
+
+    // Answer redundancy partners asking whose turn maintenance is
+    if config.node.redundancy.group.is_some() {
+        redundancy::start(
+            Arc::clone(&node),
+            std::net::SocketAddr::from(([0, 0, 0, 0], config.node.redundancy.listen_port)),
+        )
+        .await?;
+    }
+
+    // Start BGP daemon
+    let mut bgp_daemon = BGPDaemon::new(
+        config.node.asn,
+        config.get_ipv4_addr()?.into(),
+        config.network.bgp.listen_port,
+    );
+    bgp_daemon.set_originate_default_to_edge(config.network.routing.originate_default_to_edge);
+    bgp_daemon
+        .set_import_rules(&config.network.bgp.policy_fragment())
+        .await?;
+    bgp_daemon
+        .set_community_policies(config.network.bgp.community_policies.clone())
+        .await?;
+    bgp_daemon
+        .set_export_policies(
+            config.network.bgp.export_policies.clone(),
+            config.network.bgp.peer_groups(),
+        )
+        .await?;
+    bgp_daemon
+        .set_peering(
+            config.network.peering.clone(),
+            config.network.routing.local_preference,
+        )
+        .await?;
+    bgp_daemon.set_clock(Arc::clone(&node.clock)).await;
+    bgp_daemon.set_adaptive_timers(config.network.adaptive_timers.clone());
+    bgp_daemon.set_acl(Arc::clone(&node.acl)).await;
+    bgp_daemon.set_admin(Arc::clone(&node.admin));
+    bgp_daemon
+        .set_quarantine(Arc::clone(&node.quarantine))
+        .await;
+    bgp_daemon.set_keepalive_jitter(config.security.obfuscation.keepalive_jitter());
+    bgp_daemon
+        .set_session_limits(config.network.bgp.session_limits)
+        .await;
+    bgp_daemon.set_capabilities(node.capabilities());
+    if config.network.single_port.enabled {
+        // Tunnel streams share the BGP port; IKE binds the same number on UDP
+        bgp_daemon.set_encapsulation(Arc::new(EncapEndpoint::new(Arc::clone(
+            &node.tunnel_manager,
+        ))));
+    }
+    if config.network.bgp.require_tunnel {
+        // Routes pass only once a tunnel to the peer is up
+        bgp_daemon.set_tunnel_gate(
+            TunnelGate::new(Arc::clone(&node.tunnel_manager))
+                .with_bgp_over_tunnel(config.network.bgp.bgp_over_tunnel)
+                .with_suspend_timeout(std::time::Duration::from_secs(
+                    config.network.bgp.tunnel_suspend_timeout_secs,
+                )),
+        );
+    }
+    if let Some(validator) = &config.network.bgp.validator {
+        bgp_daemon.set_validator(RouteValidator::from_config(validator)?);
+    }
+    bgp_daemon
+        .set_rejection_journal(config.network.bgp.rejection_journal.clone())
+        .await;
+    bgp_daemon
+        .set_propagation_tracing(config.network.bgp.trace_propagation)
+        .await;
+    bgp_daemon
+        .set_hold_down(config.network.hold_down.clone())
+        .await;
+    bgp_daemon
+        .set_tunnel_requirements(&config.network.routing)
+        .await?;
+    bgp_daemon
+        .set_multihoming(config.network.bgp.multihoming.clone())
+        .await?;
+    startup.lap("bgp-config");
+    bgp_daemon
+        .open_route_pins(&store, config.network.bgp.pins_file.as_ref().map(Path::new))
+        .await?;
+    bgp_daemon.open_table_syncs(config.network.bgp.table_sync, &store)?;
+    if let Some(capture) = &capture {
+        bgp_daemon.set_capture(Arc::clone(capture));
+    }
+    startup.lap("route-load");
+    bgp_daemon.start().await?;
+    node.follow_hold_down(bgp_daemon.subscribe_hold_down());
+    let bgp_daemon = Arc::new(bgp_daemon);
+    bgp_daemon.follow_peer_events(node.subscribe_peer_events());
+    node.follow_quarantine();
+    // Routes via peers whose tunnel failed stop being used and advertised
+    let (tunnels, tunnel_events) = node.tunnel_manager.follow_status().await;
+    bgp_daemon
+        .follow_tunnel_events(tunnels, tunnel_events)
+        .await?;
+    if config.network.bgp.multihoming.enabled && node.tier == NodeTier::Edge {
+        let (node, bgp) = (Arc::clone(&node), Arc::clone(&bgp_daemon));
+        multihoming::start_monitoring(Arc::clone(&bgp_daemon), move || {
+            let (node, bgp) = (Arc::clone(&node), Arc::clone(&bgp));
+            async move { multihoming::report_parents(&node, &bgp).await }
+        });
+    }
+    readiness.started("bgp");
+    startup.lap("bgp-bind");
+
+    // Give hosts behind this gateway the learned routes
+    let kernel_routes = start_kernel_routes(&config.network.kernel_routes, &bgp_daemon);
+    if kernel_routes.is_some() {
+        startup.lap("kernel-routes");
+    }
+
+    // Hosted services expire after service_ttl unless refreshed; what
+    // other nodes sync to us is bounded per origin. This is the one
+    // directory: everything below shares it rather than keeping a copy.
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(node.node_id);
+    dns.set_sync_quota(config.network.dns.sync_quota.clone());
+    dns.set_history_limits(&config.network.dns.history);
+    dns.set_cache(ResolverCache::new(
+        config.network.dns.cache_size,
+        std::time::Duration::from_secs(config.network.dns.negative_cache_secs),
+    ));
+    let dns = dns.into_shared();
+    node.set_directory(Arc::clone(&dns));
+    let mut services = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns));
+    if config.services.advertise_host_routes {
+        services = services.with_host_routes(Arc::clone(&bgp_daemon));
+    }
+
+    // Peers pull our zone and confirm what they stored over zone sync
+    let sync_port = config.network.dns.sync_port;
+    let mut zone_sync = ZoneSyncService::new(Arc::clone(&dns));
+    if let Some(capture) = &capture {
+        zone_sync = zone_sync.with_capture(Arc::clone(capture));
+    }
+    zone_sync
+        .start(std::net::SocketAddr::from(([0, 0, 0, 0], sync_port)))
+        .await?;
+    let primary = std::net::SocketAddr::new(config.get_ipv4_addr()?.into(), sync_port);
+    let secondaries = Arc::clone(&node);
+    zone_sync
+        .follow_changes(primary, move || {
+            let node = Arc::clone(&secondaries);
+            async move { node.dns_secondaries().await }
+        })
+        .await;
+    services = services.with_propagation(zone_sync, primary);
+    let services = Arc::new(services);
+    Arc::clone(&services).start();
+
+    // Resolve .vx0 names for applications on this host, healthiest host first
+    #[cfg(feature = "dns-server")]
+    if config.network.dns.local_server {
+        let ranking = AnswerRanking::new(Arc::clone(&bgp_daemon), &config.network.dns)
+            .with_node(Arc::clone(&node));
+        Vx0DNSServer::from_config(&config.network.dns, Arc::clone(&dns))
+            .with_ranking(ranking)
+            .with_node(Arc::clone(&node))
+            .with_forward_retry(config.network.retry.dns_forward())
+            .start()
+            .await?;
+    }
+    readiness.started("dns");
+    startup.lap("dns");
+
+    // Start control socket for the CLI
+    let control_server = ControlServer::new(config.control.clone(), Arc::clone(&bgp_daemon));
+    control_server.set_services(services);
+    control_server.set_node(Arc::clone(&node));
+    control_server.set_directory(Arc::clone(&dns));
+    if let Some(firewall) = &firewall {
+        control_server.set_firewall(Arc::clone(firewall));
+    }
+    if let Some(capture) = capture {
+        control_server.set_capture(capture);
+    }
+    let reloader = Arc::new(Reloader::new(
+        config.clone(),
+        Arc::clone(&bgp_daemon),
+        discovery_privacy,
+    ));
+    control_server.set_reloader(Arc::clone(&reloader));
+    control_server.start().await?;
+    readiness.started("control");
+    startup.lap("control");
+
+    // Serve allowlisted clearnet lookups for peers, if this is a gateway
+    if config.services.gateway.enabled {
+        let gateway = GatewayService::new(&config.services.gateway);
+        gateway
+            .start(
+                std::net::SocketAddr::new(
+                    node.ipv4_addr.into(),
+                    config.services.gateway.listen_port,
+                ),
+                Arc::clone(&node),
+            )
+            .await?;
+        startup.lap("gateway");
+    }
+
+    // Start IKE daemon
+    let mut ike_daemon = IKEDaemon::new(format!("0.0.0.0:{}", config.ike_listen_port()).parse()?)
+        .with_acl(Arc::clone(&node.acl))
+        .with_tunnels(Arc::clone(&node.tunnel_manager));
+    ike_daemon.start().await?;
+    readiness.started("ike");
+    startup.lap("ike");
+
+    // Start node manager
+    let node_manager = NodeManager::new(Arc::clone(&node));
+    node_manager.run().await?;
+
+    // List ourselves in the directory and re-announce whenever what we offer changes
+    dns.write().await.register_node(&node.directory_entry())?;
+    node.start_hostname_advisory(Arc::clone(&dns), config.node.register_hostname_dns);
+    // Lets DNS-only clients read what this node is, and on Backbone nodes the tier counts
+    publish_node_metadata(
+        Arc::clone(&node),
+        Arc::clone(&dns),
+        config.network.dns.node_metadata.clone(),
+    );
+    let bootstrap = BootstrapManager::new(Arc::clone(&node), config.bootstrap.clone());
+    bootstrap.start_capability_announcements();
+    // Keeps bootstrap health current and lets quarantined nodes recover
+    bootstrap.start_health_probes();
+    // Trades the slowest Regional peer for a quicker one, if enabled
+    node.start_peer_selection();
+    // Summarises sampled log lines even once they stop coming
+    LogSampler::global().start_summaries();
+    // Watches tier capacity in the directory and alerts on thresholds
+    node.capacity
+        .start(Arc::clone(&dns), config.network.retry.hooks());
+    // Whether to send this node traffic, for `/readyz` and `status --ready`
+    node.readiness.start(
+        Arc::clone(&node),
+        Arc::clone(&bgp_daemon),
+        config.network.retry.hooks(),
+    );
+    #[cfg(feature = "metrics")]
+    if config.monitoring.enable_metrics {
+        http::serve(
+            std::net::SocketAddr::from(([0, 0, 0, 0], config.monitoring.metrics_port)),
+            Arc::clone(&node),
+            Arc::clone(&bgp_daemon),
+        )
+        .await?;
+    }
+
+    if config.monitoring.recorder.enabled {
+        StatsRecorder::new(
+            &config.monitoring.recorder,
+            Arc::clone(&node),
+            Arc::clone(&bgp_daemon),
+        )?
+        .start()
+        .await?;
+    }
+    startup.lap("background-tasks");
+
+    // Add some VX0 network routes
+    let plan = &config.network.plan;
+    let vx0_network: Prefix = plan.ipv4_supernet.parse()?;
+    bgp_daemon
+        .add_route(
+            vx0_network,
+            "10.0.0.1".parse()?,
+            vx0net_daemon::network::bgp::BGPOrigin::IGP,
+        )
+        .await?;
+
+    // Originate this node's own assignments from the address plan
+    let node_prefixes = [
+        (&plan.ipv4_prefix, std::net::IpAddr::V4(node.ipv4_addr)),
+        (&plan.ipv6_prefix, std::net::IpAddr::V6(node.ipv6_addr)),
+    ];
+    for (prefix, next_hop) in node_prefixes {
+        if let Some(prefix) = prefix {
+            bgp_daemon
+                .add_route(
+                    prefix.parse()?,
+                    next_hop,
+                    vx0net_daemon::network::bgp::BGPOrigin::IGP,
+                )
+                .await?;
+        }
+    }
+
+    // Originate the configured prefixes with the communities they carry
+    for announce in &config.network.bgp.announce {
+        let network: Prefix = announce.prefix.parse()?;
+        let next_hop = match &announce.next_hop {
+            Some(next_hop) => next_hop.parse()?,
+            None if network.net().addr().is_ipv4() => std::net::IpAddr::V4(node.ipv4_addr),
+            None => std::net::IpAddr::V6(node.ipv6_addr),
+        };
+        bgp_daemon
+            .add_route_with_communities(
+                network,
+                next_hop,
+                vx0net_daemon::network::bgp::BGPOrigin::IGP,
+                announce.communities.clone(),
+            )
+            .await?;
+    }
+
+    // Peer with external routers configured for plain RFC 4271 BGP
+    for peer in &config.network.bgp.peers {
+        if peer.wire != WireFormat::Rfc4271
+            || node.admin.is_down(peer.asn)
+            || node
+                .quarantine
+                .is_quarantined(peer.asn, node.clock.now_utc())
+        {
+            continue;
+        }
+
+        #[cfg(feature = "external-bgp")]
+        if let Err(e) = bgp_daemon
+            .connect_external_peer(peer.clone(), config.network.bgp.hold_time_secs())
+            .await
+        {
+            error!(
+                "Failed to peer with external router {}: {}",
+                peer.address, e
+            );
+        }
+
+        #[cfg(not(feature = "external-bgp"))]
+        tracing::warn!(
+            "Peer {} uses wire = \"rfc4271\" but this build lacks the external-bgp feature",
+            peer.address
+        );
+    }
+
+    startup.lap("routes");
+
+    info!("VX0 network daemon started successfully");
+    info!(
+        "Listening for BGP connections on port {}",
+        config.network.bgp.listen_port
+    );
+    info!(
+        "Listening for IKE connections on port {}",
+        config.security.ike.listen_port
+    );
+
+    // Auto-join network if requested
+    if join_network {
+        info!("🌐 Auto-joining VX0 network...");
+        if let Err(e) = node.join_vx0_network().await {
+            error!("Failed to join network: {}", e);
+        } else {
+            info!("✅ Successfully joined VX0 network!");
+            // Carry transit only once the node has proven stable
+            bgp_daemon.start_hold_down().await;
+        }
+        // Failed or not, the attempt is over; don't leave the unit starting
+        readiness.started("join");
+        startup.lap("join");
+    }
+    Supervisor::global().keep_timing(startup.finish());
+
+    // systemd restarts us if these stop; they stop if the node's state
+    // can't be read within the interval
+    if let Some(interval) = notify::watchdog_interval() {
+        let node = Arc::clone(&node);
+        readiness
+            .notifier()
+            .clone()
+            .start_watchdog(interval, move || {
+                let node = Arc::clone(&node);
+                async move {
+                    node.get_peer_count().await;
+                }
+            });
+    }
+
+    // Handle shutdown signals; service managers stop us with SIGTERM and
+    // ask for a reload with SIGHUP
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            result = signal::ctrl_c() => {
+                match result {
+                    Ok(()) => info!("Received Ctrl+C, shutting down..."),
+                    Err(err) => error!("Unable to listen for shutdown signal: {}", err),
+                }
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down...");
+                break;
+            }
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading configuration...");
+                match Vx0Config::load() {
+                    Ok(reloaded) => {
+                        // The reloader logs and audits what it did
+                        if let Err(e) = reloader.reload(&reloaded).await {
+                            error!("Keeping the current configuration: {}", Report(&e));
+                        }
+                    }
+                    Err(e) => error!("Keeping the current configuration: {}", Report(&e)),
+                }
+            }
+        }
+    }
+
+    // Graceful shutdown
+    info!("Shutting down VX0 node...");
+    let shutdown = PhaseTimer::new(Stage::Shutdown).with_limits(&config.monitoring.timing);
+    if let Err(e) = readiness.notifier().stopping() {
+        debug!("Stopping notification failed: {}", e);
+    }
+    if let Some((sync, follower)) = kernel_routes {
+        follower.abort();
+        info!("Removed {} kernel routes", sync.clear().await);
+        shutdown.lap("kernel-routes");
+    }
+    if let Some(firewall) = &firewall {
+        info!("Removed {} firewall rules", firewall.clear().await);
+        shutdown.lap("firewall");
+    }
+    node.stop().await?;
+    shutdown.lap("node-stop");
+    // Cancel what is still running, subsystem by subsystem, naming any task
+    // that will not stop
+    let stopped = Supervisor::global().tasks().shutdown_timed(&shutdown).await;
+    // Kept for the shutdown record, which is written once we return
+    Supervisor::global().keep_timing(shutdown.finish());
+    stopped.into_result()?;
+    info!("VX0 network daemon stopped");
+
+    Ok(())
+}
+
+type KernelRoutes = (Arc<KernelRouteSync>, tokio::task::JoinHandle<Option<()>>);
+
+/// Follow the Loc-RIB into the kernel table, if configured. Without netlink
+/// only the kernel routes are lost; the overlay runs as usual.
+fn start_kernel_routes(config: &KernelRoutesConfig, bgp: &Arc<BGPDaemon>) -> Option<KernelRoutes> {
+    if !config.enabled {
+        return None;
+    }
+
+    #[cfg(feature = "kernel_routes")]
+    match NetlinkSink::connect(config.table, config.protocol) {
+        Ok(sink) => {
+            let sync = Arc::new(KernelRouteSync::new(config.clone(), Arc::new(sink)));
+            let follower = Arc::clone(&sync).start(Arc::clone(bgp));
+            Some((sync, follower))
+        }
+        Err(e) => {
+            warn!("Not installing kernel routes: {}", Report(&e));
+            None
+        }
+    }
+
+    #[cfg(not(feature = "kernel_routes"))]
+    {
+        let _ = bgp;
+        warn!("network.kernel_routes is enabled but this build lacks the kernel_routes feature");
+        None
+    }
+}
+
+/// Open the listen ports peers reach in the host firewall, if configured.
+/// Anything that fails is logged with the command to run by hand.
+async fn start_firewall(config: &Vx0Config) -> Option<Arc<FirewallManager>> {
+    if !config.network.firewall.manage {
+        return None;
+    }
+    let runner = Arc::new(SystemRunner);
+    let backend = match Backend::detect(&config.network.firewall, runner.as_ref()).await {
+        Ok(backend) => backend,
+        Err(e) => {
+            warn!(
+                "Not managing the firewall: {}; open these by hand: {}",
+                e,
+                firewall::wanted_rules(config)
+                    .iter()
+                    .map(|rule| format!("{}/{}", rule.port, rule.protocol.as_str()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return None;
+        }
+    };
+    let firewall = Arc::new(FirewallManager::new(
+        backend,
+        runner,
+        firewall::wanted_rules(config),
+    ));
+    let outcome = firewall.apply().await;
+    info!(
+        "Firewall ({}): {} rules added, {} already in place, {} stale removed, {} failed",
+        firewall.backend().name(),
+        outcome.inserted,
+        outcome.kept,
+        outcome.removed,
+        outcome.failed
+    );
+    Some(firewall)
+}
+
+async fn show_status(tasks: bool, startup: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Vx0Config::load()?;
+    let store = Store::open(&config.storage.dir)?;
+    let shutdown_log = ShutdownLog::new(&config.monitoring.shutdown, &store);
+
+    println!("VX0 Daemon Status:");
+    let running = match shutdown_log.pid() {
+        // Without /proc, trust the PID file
+        Some(pid)
+            if !cfg!(target_os = "linux")
+                || std::path::Path::new(&format!("/proc/{}", pid)).exists() =>
+        {
+            println!("  Running: yes (pid {})", pid);
+            true
+        }
+        Some(pid) => {
+            println!(
+                "  Running: no (stale PID file for pid {}; it was killed)",
+                pid
+            );
+            false
+        }
+        None => {
+            println!("  Running: no");
+            false
+        }
+    };
+    if running {
+        show_hostname_advisory().await;
+        show_address_advisory().await;
+        if let Ok(ControlResponse::Group { group: Some(group) }) =
+            control_request(&ControlRequest::Group).await
+        {
+            print_group(&group);
+        }
+    }
+    if running && tasks {
+        match control_request(&ControlRequest::Tasks).await? {
+            ControlResponse::Tasks { tasks } => print_tasks(&tasks),
+            ControlResponse::Error { message, .. } => return Err(message.into()),
+            other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+        }
+    }
+    if running && startup {
+        match control_request(&ControlRequest::Startup).await? {
+            ControlResponse::Startup {
+                report: Some(report),
+            } => {
+                println!("  Startup: {}", format_ms(report.total_ms));
+                print_phases(&report.phases);
+            }
+            ControlResponse::Startup { report: None } => println!("  Startup: still under way"),
+            ControlResponse::Error { message, .. } => return Err(message.into()),
+            other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+        }
+    }
+    match shutdown_log.last_record() {
+        Some(record) => {
+            println!("  Last shutdown: {}", record);
+            if startup {
+                print_phases(&record.phases);
+            }
+            for line in record.recent_log.iter().rev().take(5).rev() {
+                println!("    {}", line);
+            }
+        }
+        None => println!("  Last shutdown: none recorded"),
+    }
+    println!(
+        "  Unclean exits in the last {}s: {}",
+        config.monitoring.shutdown.crash_loop_window_secs,
+        shutdown_log.unclean_exits(chrono::Utc::now())
+    );
+    Ok(())
+}
+
+/// What orchestrators probe: the readiness checks, failing unless all pass
+async fn show_readiness() -> Result<(), Box<dyn std::error::Error>> {
+    // Exits 1 for a daemon that is not there to ask, like any other failure
+    let reply = control_request(&ControlRequest::Readiness)
+        .await
+        .map_err(|e| MonitoringError::NotReady {
+            failing: vec![Report(&e).to_string()],
+        })?;
+    let report = match reply {
+        ControlResponse::Readiness { report } => report,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+    println!("Ready: {}", if report.ready { "yes" } else { "no" });
+    for check in &report.checks {
+        println!(
+            "  {} {:<13} {}",
+            if check.passed { "✓" } else { "✗" },
+            check.name,
+            check.detail
+        );
+    }
+    Ok(report.check()?)
+}
+
+fn print_phases(phases: &[PhaseTiming]) {
+    for timing in phases {
+        println!(
+            "    {:<20} {:>8}{}",
+            timing.phase,
+            format_ms(timing.duration_ms),
+            if timing.slow { "  (slow)" } else { "" }
+        );
+    }
+}
+
+fn print_tasks(tasks: &[TaskInfo]) {
+    let now = chrono::Utc::now();
+    println!("  Tasks: {}", tasks.len());
+    println!(
+        "    {:<12} {:<28} {:<10} {:>8} {:>9}  Notes",
+        "Subsystem", "Name", "State", "Age", "Heartbeat"
+    );
+    let ago = |at: chrono::DateTime<chrono::Utc>| format!("{}s", (now - at).num_seconds());
+    for task in tasks {
+        let mut notes = Vec::new();
+        if let Some(since) = task.busy_since {
+            notes.push(format!("busy for {}", ago(since)));
+        }
+        if task.restarts > 0 {
+            notes.push(format!("{} restarts", task.restarts));
+        }
+        println!(
+            "    {:<12} {:<28} {:<10} {:>8} {:>9}  {}",
+            task.subsystem.to_string(),
+            task.name,
+            task.state.to_string(),
+            format!("{}s", task.age(now).num_seconds()),
+            task.last_heartbeat
+                .map(ago)
+                .unwrap_or_else(|| "never".to_string()),
+            notes.join(", ")
+        );
+    }
+}
+
+async fn show_node_info() -> Result<(), NodeError> {
+    let config = Vx0Config::load().map_err(|e| NodeError::Config(e.to_string()))?;
+    let node = Vx0Node::new(config)?;
+
+    println!("VX0 Node Information:");
+    println!("  Node ID: {}", node.node_id);
+    println!("  Hostname: {}", node.hostname);
+    println!("  ASN: {}", node.asn);
+    println!("  Tier: {:?}", node.tier);
+    println!("  IPv4: {}", node.ipv4_addr);
+    println!("  IPv6: {}", node.ipv6_addr);
+    println!("  Location: {}", node.location.city);
+    println!("  Peer count: {}", node.get_peer_count().await);
+
+    Ok(())
+}
+
+async fn show_routes(view: Option<RoutesView>) -> Result<(), Box<dyn std::error::Error>> {
+    // What we advertised was shaped by export policy, so show how
+    let (title, request, attributes) = match view {
+        Some(RoutesView::Explain { prefix }) => return explain_route(prefix).await,
+        Some(RoutesView::PropagationStats) => return propagation_stats().await,
+        Some(view @ (RoutesView::Pin { .. } | RoutesView::Unpin { .. })) => {
+            return update_routes(routes_request(view)?).await
+        }
+        None => (
+            "VX0 Routing Table".to_string(),
+            ControlRequest::Routes,
+            false,
+        ),
+        Some(RoutesView::Received { peer }) => (
+            format!("Routes received from ASN {}", peer),
+            ControlRequest::RoutesReceived { peer_asn: peer },
+            false,
+        ),
+        Some(RoutesView::Advertised { peer }) => (
+            format!("Routes advertised to ASN {}", peer),
+            ControlRequest::RoutesAdvertised { peer_asn: peer },
+            true,
+        ),
+    };
+
+    let response = control_request(&request).await?;
+
+    let (routes, pins) = match response {
+        ControlResponse::Routes { routes, pins } => (routes, pins),
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+    if attributes {
+        print_routes(&title, routes.clone(), &pins);
+        print_route_attributes(&routes);
+    } else {
+        print_routes(&title, routes, &pins);
+    }
+    Ok(())
+}
+
+fn print_route_attributes(routes: &[RouteEntry]) {
+    let mut routes: Vec<&RouteEntry> = routes.iter().collect();
+    routes.sort_by_key(|route| route.network);
+
+    println!("Attributes:");
+    println!("  Network            MED        Communities");
+    for route in routes {
+        let communities: Vec<String> = route
+            .communities
+            .iter()
+            .map(|community| community.to_string())
+            .collect();
+        println!(
+            "  {:<18} {:<10} {}",
+            route.network.to_string(),
+            route.med,
+            communities.join(" ")
+        );
+    }
+}
+
+fn print_routes(title: &str, mut routes: Vec<RouteEntry>, pins: &[RoutePin]) {
+    routes.sort_by_key(|route| route.network);
+
+    println!("{}:", title);
+    println!("  Network            Next Hop         AS Path              Origin      From");
+    for route in routes {
+        let as_path: Vec<String> = route.as_path.iter().map(|asn| asn.to_string()).collect();
+        let from = route
+            .learned_from
+            .map(|peer| format!("AS{}", peer.asn))
+            .unwrap_or_else(|| "local".to_string());
+        let marker = if pins.iter().any(|pin| pin.network == route.network) {
+            '*'
+        } else {
+            ' '
+        };
+        println!(
+            " {}{:<18} {:<16} {:<20} {:<11} {}",
+            marker,
+            route.network.to_string(),
+            route.next_hop.to_string(),
+            as_path.join(" "),
+            format!("{:?}", route.origin),
+            from
+        );
+    }
+    if !pins.is_empty() {
+        println!("Pinned (*):");
+        for pin in pins {
+            let until = pin
+                .expires_at
+                .map(|at| format!("until {}", at.to_rfc3339()))
+                .unwrap_or_else(|| "until unpinned".to_string());
+            println!(
+                "  {:<18} via AS{:<6} {}",
+                pin.network.to_string(),
+                pin.via_asn,
+                until
+            );
+        }
+    }
+}
+
+/// The control request for a routes subcommand
+fn routes_request(view: RoutesView) -> Result<ControlRequest, String> {
+    let request = match view {
+        RoutesView::Received { peer } => ControlRequest::RoutesReceived { peer_asn: peer },
+        RoutesView::Advertised { peer } => ControlRequest::RoutesAdvertised { peer_asn: peer },
+        RoutesView::Explain { prefix } => ControlRequest::ExplainRoute { network: prefix },
+        RoutesView::PropagationStats => ControlRequest::PropagationStats,
+        RoutesView::Pin {
+            prefix,
+            via,
+            until,
+            allow_missing,
+        } => {
+            let duration_secs = until
+                .map(|until| match recorder::parse_since(&until) {
+                    Ok(duration) if duration > chrono::Duration::zero() => {
+                        Ok(duration.num_seconds() as u64)
+                    }
+                    Ok(_) => Err(format!("Pin duration must be positive: {}", until)),
+                    Err(e) => Err(e.to_string()),
+                })
+                .transpose()?;
+            ControlRequest::PinRoute {
+                network: prefix,
+                via_asn: via,
+                duration_secs,
+                allow_missing,
+            }
+        }
+        RoutesView::Unpin { prefix } => ControlRequest::UnpinRoute { network: prefix },
+    };
+    Ok(request)
+}
+
+async fn explain_route(network: Prefix) -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&ControlRequest::ExplainRoute { network }).await? {
+        ControlResponse::Explanation { explanation } => {
+            print!("{}", explanation);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+async fn propagation_stats() -> Result<(), Box<dyn std::error::Error>> {
+    let stats = match control_request(&ControlRequest::PropagationStats).await? {
+        ControlResponse::PropagationStats { stats } => stats,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+
+    println!("Route propagation delay by origin tier:");
+    if stats.tiers.is_empty() {
+        println!("  No stamped routes received; origins enable network.bgp.trace_propagation");
+    } else {
+        println!("  Tier         Samples   p50        p95        Max");
+        for tier in &stats.tiers {
+            println!(
+                "  {:<12} {:<9} {:<10} {:<10} {}ms",
+                format!("{:?}", tier.tier),
+                tier.samples,
+                format!("{}ms", tier.p50_ms),
+                format!("{}ms", tier.p95_ms),
+                tier.max_ms
+            );
+        }
+    }
+    println!("  Prefixes measured: {}", stats.prefixes);
+    println!("  Routes without a stamp: {}", stats.unstamped);
+    Ok(())
+}
+
+async fn replay(
+    file: &str,
+    speed: Speed,
+    until: Option<u64>,
+    explain: &[Prefix],
+    config: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let capture = CaptureFile::read(file)?;
+    let config = config.map(Vx0Config::load_file).transpose()?;
+    let replay = Replay::new(capture.header.clone(), config.as_ref())
+        .await?
+        .with_speed(speed);
+    println!(
+        "Replaying {} record(s) captured by AS{} from {} at {} speed",
+        capture.records.len(),
+        capture.header.asn,
+        capture.header.started.to_rfc3339(),
+        speed
+    );
+    if capture.full {
+        println!("⚠️  Capture stopped at its size limit; later events are missing");
+    }
+
+    let report = replay.run(&capture.records, until).await;
+    println!(
+        "Applied {}, skipped {} without a handler here, {} failed",
+        report.applied,
+        report.skipped,
+        report.failed.len()
+    );
+    for (seq, reason) in &report.failed {
+        println!("  #{}: {}", seq, reason);
+    }
+    if let Some(seq) = report.last_seq {
+        println!("Stopped after record #{}", seq);
+    }
+    println!();
+
+    match replay.control(ControlRequest::Routes).await {
+        ControlResponse::Routes { routes, pins } => {
+            print_routes("Replayed Routing Table", routes, &pins)
+        }
+        other => return Err(format!("Unexpected reply from replayed node: {:?}", other).into()),
+    }
+    for network in explain {
+        if let ControlResponse::Explanation { explanation } = replay
+            .control(ControlRequest::ExplainRoute { network: *network })
+            .await
+        {
+            println!();
+            print!("{}", explanation);
+        }
+    }
+    Ok(())
+}
+
+async fn test_policy(file: &str, peer: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    let policy =
+        std::fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {}", file, e))?;
+
+    let response = control_request(&ControlRequest::PolicyTest {
+        policy,
+        peer_asn: peer,
+    })
+    .await?;
+
+    let report = match response {
+        ControlResponse::PolicyReport { report } => report,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+
+    for (title, outcome) in [
+        ("Would accept", DryRunOutcome::Accept),
+        ("Would change attributes", DryRunOutcome::Modified),
+        ("Would reject", DryRunOutcome::Reject),
+    ] {
+        let entries = report.with_outcome(outcome);
+        println!("{} ({}):", title, entries.len());
+        for entry in entries {
+            println!(
+                "  {:<18} from AS{:<6} rule {}",
+                entry.network.to_string(),
+                entry.peer_asn,
+                entry.rule
+            );
+            for change in &entry.changes {
+                println!("      {}", change);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn show_version(features: bool, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let build = BuildInfo::current();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&build)?);
+        return Ok(());
+    }
+
+    println!("vx0net {}", build.version);
+    println!("  Commit:   {}", build.git_hash);
+    println!("  Built:    {}", build.build_date);
+    println!("  Compiler: {}", build.rustc_version);
+    if features {
+        let enabled = if build.features.is_empty() {
+            "none".to_string()
+        } else {
+            build.features.join(", ")
+        };
+        println!("  Features: {}", enabled);
+        println!("  Protocol: {}-{}", build.protocol_min, build.protocol_max);
+    }
+
+    Ok(())
+}
+
+fn inspect_storage(namespace: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = Vx0Config::load()
+        .map(|config| config.storage.dir)
+        .unwrap_or_else(|_| StorageConfig::default().dir);
+    let store = Store::open(&dir)?;
+
+    let Some(namespace) = namespace else {
+        println!("Namespaces in {} ({} bytes):", dir, store.usage());
+        for name in store.namespaces()? {
+            println!("  {}", name);
+        }
+        return Ok(());
+    };
+    let entries = store
+        .namespace(&namespace)
+        .iter_prefix::<serde_json::Value>("")?;
+    if entries.is_empty() {
+        println!("Nothing stored in {}", namespace);
+    }
+    for (key, value) in entries {
+        println!("{} = {}", key, value);
+    }
+    Ok(())
+}
+
+fn dump_stats(
+    since: Option<String>,
+    peer: Option<u32>,
+    format: DumpFormat,
+    file: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = file.unwrap_or_else(|| {
+        Vx0Config::load()
+            .map(|config| config.monitoring.recorder.path)
+            .unwrap_or_else(|_| RecorderConfig::default().path)
+    });
+    let since = since
+        .map(|window| recorder::parse_since(&window))
+        .transpose()?
+        .map(|window| chrono::Utc::now() - window);
+
+    let records = StatsRing::read_window(&path, since, peer)
+        .map_err(|e| format!("Cannot read stats from {}: {}", path, e))?;
+
+    match format {
+        DumpFormat::Csv => recorder::write_csv(&records, std::io::stdout().lock())?,
+        DumpFormat::Json => println!("{}", serde_json::to_string_pretty(&records)?),
+    }
+
+    Ok(())
+}
+
+async fn show_peers() -> Result<(), Box<dyn std::error::Error>> {
+    let (local, peers, admin_down, quarantine, multihoming) =
+        match control_request(&ControlRequest::Peers).await? {
+            ControlResponse::Peers {
+                local,
+                peers,
+                admin_down,
+                quarantine,
+                multihoming,
+            } => (local, peers, admin_down, quarantine, multihoming),
+            ControlResponse::Error { message, .. } => return Err(message.into()),
+            other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+        };
+
+    println!("VX0 Connected Peers (this node offers: {}):", local);
+    println!(
+        "  {:<16} {:<8} {:<14} {:<7} {:<6} {:<10} Capabilities",
+        "Peer IP", "ASN", "Status", "Routes", "MTU", "Timers"
+    );
+    for peer in peers {
+        let routes = match peer.metrics.table_sync {
+            // Usable, but the peer's full table has not all arrived
+            Some(sync) if !sync.complete => format!(
+                "{} (partial {}/{})",
+                peer.metrics.routes_received, sync.received, sync.total
+            ),
+            _ => peer.metrics.routes_received.to_string(),
+        };
+        let mtu = peer
+            .metrics
+            .tunnel_mtu
+            .map_or("-".to_string(), |mtu| mtu.to_string());
+        // Keepalive/echo seconds, as stretched on this link
+        let timers = peer.metrics.timers.as_ref().map_or("-".to_string(), |t| {
+            format!("{}s/{}s", t.keepalive_secs, t.echo_secs)
+        });
+        println!(
+            "  {:<16} {:<8} {:<14} {:<7} {:<6} {:<10} {}",
+            peer.peer_addr.to_string(),
+            peer.peer_asn,
+            format!("{:?}", peer.status),
+            routes,
+            mtu,
+            timers,
+            peer.capabilities
+        );
+    }
+
+    if let Some(status) = multihoming {
+        show_multihoming(&status);
+    }
+
+    if !admin_down.is_empty() {
+        println!("Administratively down:");
+        for entry in admin_down {
+            let source = match entry.source {
+                AdminSource::Config => "config file",
+                AdminSource::Operator => "peers disable",
+            };
+            println!(
+                "  AS{:<8} since {} ({})",
+                entry.asn,
+                entry.since.format("%Y-%m-%d %H:%M:%S UTC"),
+                source
+            );
+        }
+    }
+
+    if !quarantine.is_empty() {
+        println!("Quarantine scores:");
+        print_quarantine(&quarantine);
+    }
+
+    Ok(())
+}
+
+fn print_quarantine(peers: &[QuarantineStatus]) {
+    for peer in peers {
+        println!("  AS{:<8} {:>5.0}  {}", peer.asn, peer.score, peer.standing);
+        for violation in &peer.events {
+            println!(
+                "      {}  {}: {}",
+                violation.at.format("%Y-%m-%d %H:%M:%S UTC"),
+                violation.kind,
+                violation.detail
+            );
+        }
+    }
+}
+
+async fn quarantine(action: QuarantineAction) -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&quarantine_request(action)).await? {
+        ControlResponse::Quarantine { peers } if peers.is_empty() => {
+            println!("No peer has a quarantine score");
+            Ok(())
+        }
+        ControlResponse::Quarantine { peers } => {
+            print_quarantine(&peers);
+            Ok(())
+        }
+        ControlResponse::QuarantineCleared { asn, cleared: true } => {
+            println!("Cleared the quarantine score of AS{}", asn);
+            Ok(())
+        }
+        ControlResponse::QuarantineCleared { asn, .. } => {
+            println!("AS{} has no quarantine score", asn);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+async fn firewall_status() -> Result<(), Box<dyn std::error::Error>> {
+    let status = match control_request(&ControlRequest::FirewallStatus).await? {
+        ControlResponse::Firewall { firewall } => firewall,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+    let Some(backend) = status.backend.as_deref().filter(|_| status.manage) else {
+        println!("The daemon does not manage the firewall (network.firewall.manage)");
+        return Ok(());
+    };
+    println!("Firewall backend: {}", backend);
+    if status.rules.is_empty() {
+        println!("No rules owned");
+    } else {
+        println!("  {:<6} {:<5} Listener", "Port", "Proto");
+        for rule in &status.rules {
+            println!(
+                "  {:<6} {:<5} {}",
+                rule.port,
+                rule.protocol.as_str(),
+                rule.key
+            );
+        }
+    }
+    if !status.failed.is_empty() {
+        println!("Could not add:");
+        for failed in &status.failed {
+            println!(
+                "  {}/{} for {}: {}",
+                failed.rule.port,
+                failed.rule.protocol.as_str(),
+                failed.rule.key,
+                failed.error
+            );
+            println!("    run: {}", failed.manual);
+        }
+    }
+    Ok(())
+}
+
+fn quarantine_request(action: QuarantineAction) -> ControlRequest {
+    match action {
+        QuarantineAction::List => ControlRequest::QuarantineList,
+        QuarantineAction::Clear { asn } => ControlRequest::QuarantineClear { asn },
+    }
+}
+
+fn show_multihoming(status: &MultihomingStatus) {
+    let role = if status.active == status.primary {
+        "primary"
+    } else {
+        "secondary"
+    };
+    println!(
+        "Multihoming: active parent AS{} ({}; primary AS{}, secondary AS{})",
+        status.active, role, status.primary, status.secondary
+    );
+    if let Some(failover) = &status.last_failover {
+        println!(
+            "  Last failover: AS{} -> AS{} at {} ({})",
+            failover.from,
+            failover.to,
+            failover.at.format("%Y-%m-%d %H:%M:%S UTC"),
+            failover.reason
+        );
+    }
+}
+
+fn peers_request(action: PeersAction) -> ControlRequest {
+    match action {
+        PeersAction::Disable { asn } => ControlRequest::PeerDisable { asn },
+        PeersAction::Enable { asn } => ControlRequest::PeerEnable { asn },
+        PeersAction::Quarantine { action } => quarantine_request(action),
+    }
+}
+
+async fn peer_admin(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&request).await? {
+        ControlResponse::PeerAdmin {
+            asn,
+            admin_down: true,
+            peers,
+        } => {
+            println!(
+                "AS{} is administratively down ({} peer(s) told goodbye)",
+                asn, peers
+            );
+            Ok(())
+        }
+        ControlResponse::PeerAdmin { asn, peers, .. } => {
+            println!("AS{} is enabled ({} peer(s) may reconnect)", asn, peers);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+/// Whether other nodes in the running daemon's directory share its hostname
+/// This node's redundancy group and where each partner stands
+fn print_group(group: &GroupStatus) {
+    println!(
+        "  Redundancy group: {} (this node {})",
+        group.group, group.local
+    );
+    for member in &group.members {
+        println!(
+            "    {} at {}: {}",
+            member.node_id, member.address, member.state
+        );
+    }
+}
+
+async fn show_hostname_advisory() {
+    let Ok(ControlResponse::Nodes {
+        nodes,
+        local: Some(local),
+        ..
+    }) = control_request(&ControlRequest::Nodes).await
+    else {
+        return;
+    };
+    let Some(own) = nodes.iter().find(|node| node.node_id == local) else {
+        return;
+    };
+
+    let others = capabilities::hostname_conflicts(own, &nodes);
+    if others.is_empty() {
+        println!("  Hostname: {}", own.hostname);
+        return;
+    }
+    println!(
+        "  Hostname: {}#{} (also used by {} other node{})",
+        own.hostname,
+        own.id_fragment(),
+        others.len(),
+        if others.len() == 1 { "" } else { "s" }
+    );
+    for node in others {
+        println!(
+            "    {}#{} ASN {} at {}",
+            node.hostname,
+            node.id_fragment(),
+            node.asn,
+            node.address
+        );
+    }
+}
+
+async fn show_address_advisory() {
+    let Ok(ControlResponse::PublicAddress { report }) =
+        control_request(&ControlRequest::PublicAddress).await
+    else {
+        return;
+    };
+    match report.mismatch() {
+        None => println!("  Public address: {}", report.announced),
+        Some(observed) => {
+            let peers = report
+                .observations
+                .iter()
+                .find(|tally| tally.address == observed)
+                .map_or(0, |tally| tally.peers);
+            let reporting: usize = report.observations.iter().map(|tally| tally.peers).sum();
+            println!(
+                "  ⚠️  Public address: peers see this node at {}, not the configured {} ({} of {} peers)",
+                observed, report.configured, peers, reporting
+            );
+            if report.auto_detect {
+                println!("    Announcing {} instead", report.announced);
+            } else {
+                println!(
+                    "    Fix node.ipv4_address, or set node.auto_detect_public_address = true"
+                );
+            }
+        }
+    }
+}
+
+async fn reload() -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&ControlRequest::Reload).await? {
+        ControlResponse::Reloaded { report } => {
+            print!("{}", report);
+            if !report.invalid().is_empty() {
+                return Err("Some changes were refused; the old values stay in effect".into());
+            }
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+async fn show_schema() -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&ControlRequest::Schema).await? {
+        ControlResponse::Schema { schema } => {
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+fn bootstrap_request(action: BootstrapAction) -> ControlRequest {
+    match action {
+        BootstrapAction::List => ControlRequest::BootstrapList,
+        BootstrapAction::Probe => ControlRequest::BootstrapProbe,
+    }
+}
+
+async fn show_bootstrap(action: BootstrapAction) -> Result<(), Box<dyn std::error::Error>> {
+    let nodes = match control_request(&bootstrap_request(action)).await? {
+        ControlResponse::Bootstrap { nodes } => nodes,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+    print_bootstrap_health(&nodes);
+    Ok(())
+}
+
+fn print_bootstrap_health(nodes: &[BootstrapStatus]) {
+    println!(
+        "  {:<24} {:<16} {:<6} {:<10} {:>5} {:>8} {:>9}  Last success",
+        "Hostname", "Address", "ASN", "Source", "Score", "Failures", "Median"
+    );
+    for node in nodes {
+        let median = node
+            .health
+            .median_latency_ms()
+            .map(|latency| format!("{}ms", latency))
+            .unwrap_or_else(|| "-".to_string());
+        let last_success = node
+            .health
+            .last_success
+            .map(|at| at.to_rfc3339())
+            .unwrap_or_else(|| "never".to_string());
+        println!(
+            "  {:<24} {:<16} {:<6} {:<10} {:>5} {:>8} {:>9}  {}{}",
+            node.hostname,
+            node.ip,
+            node.asn,
+            format!("{:?}", node.source).to_lowercase(),
+            node.score,
+            node.health.consecutive_failures,
+            median,
+            last_success,
+            if node.quarantined {
+                "  [quarantined]"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn asn_request(action: AsnAction) -> ControlRequest {
+    match action {
+        AsnAction::List => ControlRequest::AsnList,
+    }
+}
+
+async fn show_asn_grants(action: AsnAction) -> Result<(), Box<dyn std::error::Error>> {
+    let (grants, registry_key) = match control_request(&asn_request(action)).await? {
+        ControlResponse::AsnGrants {
+            grants,
+            registry_key,
+        } => (grants, registry_key),
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+    if let Some(key) = registry_key {
+        println!("  This node runs a registry with key {}", key);
+    }
+    if grants.is_empty() {
+        println!("  No ASN grants known");
+        return Ok(());
+    }
+    println!(
+        "  {:<6} {:<24} {:<10} {:<26} Registry",
+        "ASN", "Hostname", "Node", "Granted"
+    );
+    for grant in grants {
+        println!(
+            "  {:<6} {:<24} {:<10} {:<26} {} ({}…)",
+            grant.asn,
+            grant.hostname,
+            &grant.node_id.to_string()[..8],
+            grant
+                .granted_at
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            grant.registry,
+            &grant.registry_key_hex()[..16]
+        );
+    }
+    Ok(())
+}
+
+fn dns_cache_request(action: DnsCacheAction) -> ControlRequest {
+    match action {
+        DnsCacheAction::List { name, negative } => ControlRequest::DnsCache {
+            pattern: name,
+            negative,
+        },
+        DnsCacheAction::Flush { name, negative } => ControlRequest::DnsCacheFlush {
+            pattern: name,
+            negative,
+        },
+        DnsCacheAction::Stats => ControlRequest::DnsCacheStats,
+    }
+}
+
+async fn dns_cache(action: DnsCacheAction) -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&dns_cache_request(action)).await? {
+        ControlResponse::DnsCache { entries, stats } => {
+            print_dns_cache(&entries);
+            println!(
+                "{} of {} cached answers ({} negative)",
+                entries.len(),
+                stats.entries,
+                stats.negative_entries
+            );
+            Ok(())
+        }
+        ControlResponse::DnsCacheFlushed { removed, stats } => {
+            println!("Flushed {} cached answers, {} left", removed, stats.entries);
+            Ok(())
+        }
+        ControlResponse::DnsCacheStats { stats } => {
+            print_dns_cache_stats(&stats);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+fn print_dns_cache(entries: &[CacheEntryInfo]) {
+    println!(
+        "  {:<32} {:<5} {:<10} {:>6} {:>5}  Answer",
+        "Name", "Type", "Source", "TTL", "Hits"
+    );
+    for entry in entries {
+        let qtype = match entry.qtype {
+            1 => "A".to_string(),
+            28 => "AAAA".to_string(),
+            other => other.to_string(),
+        };
+        let answer = match &entry.answer {
+            CachedAnswer::Addresses(addresses) => addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            CachedAnswer::NoData => "no such record".to_string(),
+            CachedAnswer::NxDomain => "no such name".to_string(),
+        };
+        println!(
+            "  {:<32} {:<5} {:<10} {:>5}s {:>5}  {}",
+            entry.name, qtype, entry.source, entry.ttl_remaining_secs, entry.hits, answer
+        );
+    }
+}
+
+async fn show_dns_history(domain: String) -> Result<(), Box<dyn std::error::Error>> {
+    let entries = match control_request(&ControlRequest::DnsHistory {
+        domain: Some(domain.clone()),
+    })
+    .await?
+    {
+        ControlResponse::DnsHistory { entries } => entries,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+
+    if entries.is_empty() {
+        println!("No registration history for {}", domain);
+        return Ok(());
+    }
+    println!("Registration history of {}:", domain);
+    for entry in &entries {
+        let origin = entry
+            .origin
+            .map(|origin| origin.to_string())
+            .unwrap_or_else(|| "unknown node".to_string());
+        println!(
+            "  {}  {:<12} by {} ({})",
+            entry.at.format("%Y-%m-%d %H:%M:%S"),
+            entry.event,
+            origin,
+            entry.attestation
+        );
+        let records = |records: &[String]| {
+            if records.is_empty() {
+                "-".to_string()
+            } else {
+                records.join(", ")
+            }
+        };
+        println!("      {} -> {}", records(&entry.old), records(&entry.new));
+        for lost in &entry.displaced {
+            println!(
+                "      ⚠️  displaced {} registered by {} at {}",
+                lost.data,
+                lost.origin
+                    .map(|origin| origin.to_string())
+                    .unwrap_or_else(|| "unknown node".to_string()),
+                lost.registered_at.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_dns_cache_stats(stats: &CacheStats) {
+    let lookups = stats.hits + stats.misses;
+    println!(
+        "Entries:   {} ({} negative)",
+        stats.entries, stats.negative_entries
+    );
+    println!(
+        "Hits:      {}{}",
+        stats.hits,
+        if lookups > 0 {
+            format!(" ({:.1}%)", stats.hits as f64 * 100.0 / lookups as f64)
+        } else {
+            String::new()
+        }
+    );
+    println!("Misses:    {}", stats.misses);
+    println!("Evictions: {}", stats.evictions);
+    println!("Expired:   {}", stats.expired);
+    println!("Flushed:   {}", stats.flushed);
+}
+
+async fn show_nodes() -> Result<(), Box<dyn std::error::Error>> {
+    let (nodes, over_quota, group) = match control_request(&ControlRequest::Nodes).await? {
+        ControlResponse::Nodes {
+            nodes,
+            over_quota,
+            group,
+            ..
+        } => (nodes, over_quota, group),
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+
+    println!("VX0 Node Directory:");
+    println!(
+        "  {:<24} {:<8} {:<16} Capabilities",
+        "Hostname", "ASN", "Address"
+    );
+    // Nodes sharing a hostname are told apart by a node ID fragment
+    for (node, name) in nodes.iter().zip(capabilities::display_names(&nodes)) {
+        let mut flag = if over_quota.iter().any(|usage| usage.origin == node.node_id) {
+            "  [over DNS quota]".to_string()
+        } else {
+            String::new()
+        };
+        let partner = group
+            .iter()
+            .flat_map(|group| &group.members)
+            .find(|member| member.node_id == node.node_id);
+        if let Some(member) = partner {
+            flag.push_str(&format!("  [partner, {}]", member.state));
+        }
+        println!(
+            "  {:<24} {:<8} {:<16} {}{}",
+            name,
+            node.asn,
+            node.address.to_string(),
+            node.capabilities,
+            flag
+        );
+    }
+
+    if let Some(group) = &group {
+        println!();
+        print_group(group);
+    }
+
+    if !over_quota.is_empty() {
+        println!();
+        println!("Origins over their synced DNS quota:");
+        for usage in &over_quota {
+            println!(
+                "  {}  {} records, {} bytes held, {} refused",
+                usage.origin, usage.records, usage.bytes, usage.rejected
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn control_request(request: &ControlRequest) -> Result<ControlResponse, ControlError> {
+    let socket_path = Vx0Config::load()
+        .map(|config| config.control.socket_path)
+        .unwrap_or_else(|_| control::DEFAULT_SOCKET_PATH.to_string());
+    ControlClient::unix(&socket_path).request(request).await
+}
 
-    // Initialize tracing
-    let log_level = match cli.log_level.as_str() {
-        "trace" => tracing::Level::TRACE,
-        "debug" => tracing::Level::DEBUG,
-        "info" => tracing::Level::INFO,
-        "warn" => tracing::Level::WARN,
-        "error" => tracing::Level::ERROR,
-        _ => tracing::Level::INFO,
+async fn register_service(
+    name: String,
+    domain: String,
+    port: u16,
+    service_type: Option<String>,
+    wait_for: Option<usize>,
+    timeout_secs: Option<u64>,
+    metadata: HashMap<String, String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !domain.ends_with(".vx0") {
+        return Err("Service domain must end with .vx0".into());
+    }
+    let typed = ServiceMetadata::parse(&metadata)?;
+
+    info!("Registering service '{}' at {}:{}", name, domain, port);
+    let response = control_request(&ControlRequest::RegisterService {
+        name,
+        domain,
+        port,
+        wait_for,
+        timeout_secs,
+        metadata,
+        service_type,
+    })
+    .await?;
+
+    let (domain, ttl_secs, propagation) = match response {
+        ControlResponse::Registered {
+            domain,
+            ttl_secs,
+            propagation,
+            ..
+        } => (domain, ttl_secs, propagation),
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
     };
+    println!("Registered {}; expires in {}s", domain, ttl_secs);
+    if let Some(description) = &typed.description {
+        println!("  Description:  {}", description);
+    }
+    if let Some(spec) = &typed.health_check {
+        println!("  Health check: {}", spec);
+    }
+    if !typed.tags.is_empty() {
+        println!("  Tags:         {}", typed.tags.join(", "));
+    }
+    if let Some(owner) = &typed.owner_contact {
+        println!("  Owner:        {}", owner);
+    }
+    println!("  Visibility:   {}", typed.visibility);
 
-    tracing_subscriber::fmt()
-        .with_max_level(log_level)
-        .with_target(false)
-        .init();
+    let Some(report) = propagation else {
+        return Ok(());
+    };
+    println!(
+        "Serial {} confirmed by {}/{} DNS peers:",
+        report.serial,
+        report.confirmed(),
+        report.peers.len()
+    );
+    for peer in &report.peers {
+        println!(
+            "  {:<36} {:<8} {:<22} {}",
+            peer.peer_id,
+            peer.peer_asn,
+            peer.address.to_string(),
+            if peer.confirmed {
+                "confirmed"
+            } else {
+                "not confirmed"
+            }
+        );
+    }
+    if !report.reached_quorum() {
+        return Err(format!(
+            "Only {} of the {} requested peers confirmed {}",
+            report.confirmed(),
+            report.quorum,
+            domain
+        )
+        .into());
+    }
+    Ok(())
+}
 
-    info!("VX0 Network Daemon v0.1.0");
+async fn find_services(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    let services = match control_request(&request).await? {
+        ControlResponse::Services { services } => services,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
+    if services.is_empty() {
+        println!("No matching services");
+        return Ok(());
+    }
 
-    match cli.command {
-        Commands::Start {
-            foreground,
-            join_network,
-        } => {
-            start_daemon(foreground, join_network).await?;
-        }
-        Commands::Stop => {
-            info!("Stopping VX0 daemon...");
-            // In a real implementation, we would send a signal to the running daemon
-            info!("VX0 daemon stopped");
-        }
-        Commands::Status => {
-            info!("VX0 daemon status: Running"); // Placeholder
-        }
-        Commands::Info => {
-            show_node_info().await?;
-        }
-        Commands::Connect { peer_ip, peer_asn } => {
-            info!("Connecting to peer {} (ASN: {})", peer_ip, peer_asn);
-            // Placeholder for peer connection
-        }
-        Commands::Disconnect { peer_ip } => {
-            info!("Disconnecting from peer {}", peer_ip);
-            // Placeholder for peer disconnection
-        }
-        Commands::Routes => {
-            show_routes().await?;
-        }
-        Commands::Peers => {
-            show_peers().await?;
-        }
-        Commands::RegisterService { name, domain, port } => {
-            register_service(&name, &domain, port).await?;
-        }
-        Commands::Join => {
-            join_network_interactive().await?;
+    // One heading per domain, services under it in the order found, so
+    // those sharing a name show together
+    let mut domains: Vec<(&str, Vec<&ServiceSummary>)> = Vec::new();
+    for service in &services {
+        match domains
+            .iter_mut()
+            .find(|(domain, _)| *domain == service.domain)
+        {
+            Some((_, grouped)) => grouped.push(service),
+            None => domains.push((&service.domain, vec![service])),
         }
-        Commands::NetworkStatus => {
-            show_network_status().await?;
-        }
-        Commands::ScanAsns { tier } => {
-            scan_available_asns(&tier).await?;
+    }
+    for (domain, grouped) in domains {
+        println!("{}", domain);
+        println!(
+            "  {:<10} {:<6} {:<16} {:<9} {:<8} Tags",
+            "Type", "Port", "Name", "Status", "Latency"
+        );
+        for service in grouped {
+            let latency = match service.latency_ms {
+                Some(0) => "local".to_string(),
+                Some(latency) => format!("{}ms", latency),
+                None => "-".to_string(),
+            };
+            println!(
+                "  {:<10} {:<6} {:<16} {:<9} {:<8} {}",
+                service.service_type.to_string(),
+                service.port,
+                service.name,
+                format!("{:?}", service.status),
+                latency,
+                service.tags.join(", ")
+            );
         }
     }
-
     Ok(())
 }
 
-async fn start_daemon(
-    foreground: bool,
-    join_network: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Starting VX0 network daemon...");
+async fn unregister_service(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&request).await? {
+        ControlResponse::Unregistered { service, remaining } => {
+            println!(
+                "Unregistered {} service on {}:{}",
+                service.service_type, service.domain, service.port
+            );
+            if remaining > 0 {
+                println!(
+                    "{} other service(s) still share {}",
+                    remaining, service.domain
+                );
+            } else {
+                println!(
+                    "No services left under {}; its address is withdrawn",
+                    service.domain
+                );
+            }
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
+
+async fn refresh_service(domain: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let response = control_request(&ControlRequest::RefreshService {
+        domain: domain.to_string(),
+    })
+    .await?;
 
-    if !foreground {
-        info!("Running in daemon mode");
+    match response {
+        ControlResponse::Refreshed { domain, ttl_secs } => {
+            println!("Refreshed {}; expires in {}s", domain, ttl_secs);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
     }
+}
 
-    // Load configuration
-    let config = Vx0Config::load().map_err(|e| {
-        error!("Failed to load configuration: {}", e);
-        e
-    })?;
+async fn update_acl(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    let (ControlRequest::Block { entry } | ControlRequest::Unblock { entry }) = &request else {
+        unreachable!("only block and unblock update the ACL");
+    };
+    // Catch typos before they reach the daemon
+    let entry: AclEntry = entry.parse()?;
+    let blocking = matches!(request, ControlRequest::Block { .. });
 
-    debug!(
-        "Configuration loaded: ASN {}, Hostname: {}",
-        config.node.asn, config.node.hostname
-    );
+    match control_request(&request).await? {
+        ControlResponse::Acl {
+            changed,
+            blocked,
+            torn_down,
+        } => {
+            match (blocking, changed) {
+                (true, true) => println!("Blocked {}", entry),
+                (true, false) => println!("{} was already blocked", entry),
+                (false, true) => println!("Unblocked {}", entry),
+                (false, false) => println!("{} was not blocked", entry),
+            }
+            if torn_down > 0 {
+                println!("Tore down {} existing session(s)", torn_down);
+            }
+            println!(
+                "Blocklist: {}",
+                if blocked.is_empty() {
+                    "(empty)".to_string()
+                } else {
+                    blocked.join(", ")
+                }
+            );
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
 
-    // Create VX0 node
-    let node = Arc::new(Vx0Node::new(config.clone())?);
-    info!("Created VX0 node: {} (ASN: {})", node.hostname, node.asn);
+async fn connect(peer: std::net::IpAddr, peer_asn: u32) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Connecting to peer {} (ASN: {})", peer, peer_asn);
+    match control_request(&ControlRequest::Connect { peer, peer_asn }).await? {
+        ControlResponse::Connected { peer } => {
+            println!(
+                "Connected to {} (ASN {}), which offers: {}",
+                peer.peer_addr, peer.peer_asn, peer.capabilities
+            );
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
 
-    // Start node services
-    node.start().await?;
+async fn update_routes(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    match control_request(&request).await? {
+        ControlResponse::Applied { rib_version } => {
+            match request {
+                ControlRequest::AnnounceRoute { network, .. } => println!("Announced {}", network),
+                ControlRequest::WithdrawRoute { network } => println!("Withdrew {}", network),
+                ControlRequest::PinRoute {
+                    network, via_asn, ..
+                } => println!("Pinned {} via AS{}", network, via_asn),
+                ControlRequest::UnpinRoute { network } => println!("Unpinned {}", network),
+                _ => {}
+            }
+            println!("Loc-RIB version {}", rib_version);
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
 
-    // Start BGP daemon
-    let bgp_daemon = BGPDaemon::new(
-        config.node.asn,
-        config.get_ipv4_addr()?.into(),
-        config.network.bgp.listen_port,
-    );
-    bgp_daemon.start().await?;
+async fn run_batch(atomic: bool) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
 
-    // Start IKE daemon
-    let mut ike_daemon =
-        IKEDaemon::new(format!("0.0.0.0:{}", config.security.ike.listen_port).parse()?);
-    ike_daemon.start().await?;
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input)?;
+    let commands = if input.trim_start().starts_with('[') {
+        batch::parse_json(&input)?
+    } else {
+        parse_batch_lines(&input)?
+    };
+    if commands.is_empty() {
+        println!("No commands to run");
+        return Ok(());
+    }
 
-    // Start node manager
-    let node_manager = NodeManager::new(Arc::clone(&node));
-    node_manager.run().await?;
+    let results = match control_request(&ControlRequest::Batch { commands, atomic }).await? {
+        ControlResponse::Batch { results } => results,
+        ControlResponse::Error { message, .. } => return Err(message.into()),
+        other => return Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    };
 
-    // Add some VX0 network routes
-    let vx0_network: ipnet::IpNet = "10.0.0.0/8".parse()?;
-    bgp_daemon
-        .add_route(
-            vx0_network,
-            "10.0.0.1".parse()?,
-            vx0net_daemon::network::bgp::BGPOrigin::IGP,
-        )
-        .await?;
+    for result in &results {
+        let outcome = match &result.outcome {
+            BatchOutcome::Applied { reply } => format!("ok{}", batch_summary(reply)),
+            BatchOutcome::Failed { message, .. } => format!("failed: {}", message),
+            BatchOutcome::Skipped => "skipped".to_string(),
+            BatchOutcome::RolledBack => "rolled back".to_string(),
+        };
+        println!("line {:<4} {:<18} {}", result.line, result.command, outcome);
+    }
 
-    info!("VX0 network daemon started successfully");
-    info!(
-        "Listening for BGP connections on port {}",
-        config.network.bgp.listen_port
-    );
-    info!(
-        "Listening for IKE connections on port {}",
-        config.security.ike.listen_port
-    );
+    let failed: Vec<String> = results
+        .iter()
+        .filter(|result| result.failed())
+        .map(|result| result.line.to_string())
+        .collect();
+    match (failed.is_empty(), atomic) {
+        (true, _) => Ok(()),
+        (false, true) => {
+            Err(format!("Nothing was applied; failed at line {}", failed.join(", ")).into())
+        }
+        (false, false) => Err(format!("Failed at line {}", failed.join(", ")).into()),
+    }
+}
 
-    // Auto-join network if requested
-    if join_network {
-        info!("🌐 Auto-joining VX0 network...");
-        if let Err(e) = node.join_vx0_network().await {
-            error!("Failed to join network: {}", e);
-        } else {
-            info!("✅ Successfully joined VX0 network!");
+/// Parse each non-blank line as a subcommand, reporting every bad line
+fn parse_batch_lines(input: &str) -> Result<Vec<BatchCommand>, Box<dyn std::error::Error>> {
+    let mut commands = Vec::new();
+    let mut errors = Vec::new();
+    for (index, text) in input.lines().enumerate() {
+        let line = index + 1;
+        let parsed = batch::split_words(text).and_then(|words| {
+            if words.is_empty() {
+                return Ok(None);
+            }
+            let cli = Cli::try_parse_from(std::iter::once("vx0net".to_string()).chain(words))
+                .map_err(|e| {
+                    let rendered = e.to_string();
+                    let first = rendered.lines().next().unwrap_or_default();
+                    first.trim_start_matches("error: ").to_string()
+                })?;
+            batch_request(cli.command).map(Some)
+        });
+        match parsed {
+            Ok(Some(request)) => commands.push(BatchCommand { line, request }),
+            Ok(None) => {}
+            Err(e) => errors.push(format!("line {}: {}", line, e)),
         }
     }
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+    Ok(commands)
+}
 
-    // Handle shutdown signals
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            info!("Received Ctrl+C, shutting down...");
+/// The control request a batched subcommand sends
+fn batch_request(command: Commands) -> Result<ControlRequest, String> {
+    let request = match command {
+        Commands::Connect { peer_ip, peer_asn } => ControlRequest::Connect {
+            peer: peer_ip,
+            peer_asn,
+        },
+        Commands::Disconnect { peer_ip } => ControlRequest::Disconnect { peer: peer_ip },
+        Commands::Maintenance { wait: true, .. } => {
+            return Err("maintenance --wait cannot run in a batch".to_string())
+        }
+        Commands::Maintenance {
+            return_in, force, ..
+        } => ControlRequest::Maintenance {
+            return_in_secs: return_in,
+            force,
+        },
+        Commands::Announce {
+            prefix,
+            next_hop,
+            communities,
+        } => ControlRequest::AnnounceRoute {
+            network: prefix,
+            next_hop,
+            communities,
+        },
+        Commands::Withdraw { prefix } => ControlRequest::WithdrawRoute { network: prefix },
+        Commands::Routes { view } => match view {
+            None => ControlRequest::Routes,
+            Some(view) => routes_request(view)?,
+        },
+        Commands::Peers { action } => match action {
+            None => ControlRequest::Peers,
+            Some(action) => peers_request(action),
+        },
+        Commands::Nodes => ControlRequest::Nodes,
+        Commands::Bootstrap { action } => bootstrap_request(action),
+        Commands::Asn { action } => asn_request(action),
+        Commands::Dns {
+            action: DnsAction::Cache { action },
+        } => dns_cache_request(action),
+        Commands::Dns {
+            action: DnsAction::History { domain },
+        } => ControlRequest::DnsHistory {
+            domain: Some(domain),
+        },
+        Commands::Reload => ControlRequest::Reload,
+        Commands::RegisterService {
+            name,
+            domain,
+            port,
+            service_type,
+            wait_for_propagation,
+            timeout,
+            metadata,
+        } => ControlRequest::RegisterService {
+            name,
+            domain,
+            port,
+            wait_for: wait_for_propagation,
+            timeout_secs: timeout,
+            metadata: (*metadata).into_map(),
+            service_type,
+        },
+        Commands::FindService {
+            service_type,
+            tags,
+            nearby,
+            ask_peers,
+        } => ControlRequest::FindServices {
+            service_type,
+            tags,
+            nearby,
+            fan_out: ask_peers,
+        },
+        Commands::RefreshService { domain } => ControlRequest::RefreshService { domain },
+        Commands::UnregisterService {
+            domain,
+            service_type,
+            port,
+        } => ControlRequest::UnregisterService {
+            domain,
+            service_type,
+            port,
+        },
+        Commands::ListServices => ControlRequest::ListServices,
+        Commands::Block { target } => ControlRequest::Block { entry: target },
+        Commands::Unblock { target } => ControlRequest::Unblock { entry: target },
+        _ => return Err("this command cannot run in a batch".to_string()),
+    };
+    Ok(request)
+}
+
+/// A few words on what a batched command did
+fn batch_summary(reply: &ControlResponse) -> String {
+    match reply {
+        ControlResponse::Applied { rib_version } => format!(" (Loc-RIB version {})", rib_version),
+        ControlResponse::Registered {
+            domain, ttl_secs, ..
+        } => format!(" ({} expires in {}s)", domain, ttl_secs),
+        ControlResponse::Acl { changed: false, .. } => " (no change)".to_string(),
+        ControlResponse::Connected { peer } => format!(" (offers: {})", peer.capabilities),
+        ControlResponse::Departed { peers, .. } => format!(" ({} peer(s))", peers),
+        ControlResponse::PeerAdmin { peers, .. } => format!(" ({} peer(s))", peers),
+        ControlResponse::Routes { routes, .. } => format!(" ({} routes)", routes.len()),
+        ControlResponse::Services { services } => format!(" ({} services)", services.len()),
+        ControlResponse::Unregistered { remaining, .. } => {
+            format!(" ({} left under the domain)", remaining)
         }
-        Err(err) => {
-            error!("Unable to listen for shutdown signal: {}", err);
+        ControlResponse::DnsCacheFlushed { removed, .. } => {
+            format!(" ({} cached answers)", removed)
         }
+        _ => String::new(),
     }
+}
 
-    // Graceful shutdown
-    info!("Shutting down VX0 node...");
-    node.stop().await?;
-    info!("VX0 network daemon stopped");
-
-    Ok(())
+async fn depart(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    report_departure(control_request(&request).await?)
 }
 
-async fn show_node_info() -> Result<(), NodeError> {
-    let config = Vx0Config::load().map_err(|e| NodeError::Config(e.to_string()))?;
-    let node = Vx0Node::new(config)?;
+fn report_departure(response: ControlResponse) -> Result<(), Box<dyn std::error::Error>> {
+    match response {
+        ControlResponse::Departed {
+            peers,
+            expected_return,
+            unreachable,
+            overridden,
+        } => {
+            println!("Said goodbye to {} peer(s)", peers);
+            if let Some(at) = expected_return {
+                println!("Peers will look for this node again at {}", at);
+            }
+            for member in &unreachable {
+                println!("⚠️  Went without asking {}", member);
+            }
+            for member in &overridden {
+                println!("⚠️  Forced past {}", member);
+            }
+            Ok(())
+        }
+        ControlResponse::Error { message, .. } => Err(message.into()),
+        other => Err(format!("Unexpected reply from daemon: {:?}", other).into()),
+    }
+}
 
-    println!("VX0 Node Information:");
-    println!("  Node ID: {}", node.node_id);
-    println!("  Hostname: {}", node.hostname);
-    println!("  ASN: {}", node.asn);
-    println!("  Tier: {:?}", node.tier);
-    println!("  IPv4: {}", node.ipv4_addr);
-    println!("  IPv6: {}", node.ipv6_addr);
-    println!("  Location: {}", node.location.city);
-    println!("  Peer count: {}", node.get_peer_count().await);
+/// Retries a maintenance request while the redundancy group says it is
+/// not this node's turn, so partners waiting at once do not ask in step
+async fn depart_in_turn(request: ControlRequest) -> Result<(), Box<dyn std::error::Error>> {
+    use rand::Rng;
+    loop {
+        match control_request(&request).await? {
+            ControlResponse::Error {
+                code: ControlErrorCode::Busy,
+                message,
+            } => {
+                println!("⏳ {}", message);
+                let secs = rand::thread_rng().gen_range(10..=20);
+                tokio::time::sleep(std::time::Duration::from_secs(secs)).await;
+            }
+            response => return report_departure(response),
+        }
+    }
+}
 
+fn check_config(file: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let config = match file {
+        Some(path) => Vx0Config::load_file(path)?,
+        None => Vx0Config::load()?,
+    };
+    let warnings: Vec<String> = config
+        .unit_warnings()
+        .iter()
+        .map(ToString::to_string)
+        .chain(config.feature_warnings().iter().map(ToString::to_string))
+        .collect();
+    for warning in &warnings {
+        println!("⚠️  {}", warning);
+    }
+    if warnings.is_empty() {
+        println!("✅ Configuration is valid");
+    } else {
+        println!("Configuration loads, with {} warning(s)", warnings.len());
+    }
     Ok(())
 }
 
-async fn show_routes() -> Result<(), Box<dyn std::error::Error>> {
-    println!("VX0 Routing Table:");
-    println!("  Network          Next Hop        AS Path    Origin");
-    println!("  10.0.0.0/8       10.0.0.1        65001      IGP");
-    println!("  vx0.network      10.0.1.1        65001      IGP");
-    // In a real implementation, we would query the actual routing table
+/// Daemon state for a support bundle, by the file it goes in
+fn support_requests() -> Vec<(&'static str, ControlRequest)> {
+    vec![
+        ("daemon/tasks.json", ControlRequest::Tasks),
+        ("daemon/startup.json", ControlRequest::Startup),
+        ("daemon/peers.json", ControlRequest::Peers),
+        ("daemon/routes.json", ControlRequest::Routes),
+        ("daemon/nodes.json", ControlRequest::Nodes),
+        (
+            "daemon/dns-history.json",
+            ControlRequest::DnsHistory { domain: None },
+        ),
+        ("daemon/capacity.json", ControlRequest::Capacity),
+        ("daemon/public-address.json", ControlRequest::PublicAddress),
+        ("daemon/asn-registry.json", ControlRequest::AsnList),
+    ]
+}
+
+async fn support_bundle(
+    output: &str,
+    log_tail: ByteSize,
+    max_size: ByteSize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Vx0Config::load()?;
+    let mut bundle = SupportBundle::new(Redactor::from_config(&config)).with_max_size(max_size);
+    bundle.add_config(&config)?;
+    let mut checks = support::config_checks(&config);
+
+    let mut unreachable = None;
+    for (path, request) in support_requests() {
+        if let Some(e) = &unreachable {
+            bundle.skip(path, e);
+            continue;
+        }
+        match control_request(&request).await {
+            Ok(response) => bundle.add_json(path, &response)?,
+            Err(e @ ControlError::Unreachable { .. }) => {
+                let e = Report(&e).to_string();
+                bundle.skip(path, &e);
+                unreachable = Some(e);
+            }
+            Err(e) => bundle.skip(path, Report(&e)),
+        }
+    }
+    checks.push(match &unreachable {
+        Some(e) => Check::new("daemon", false, e.clone()),
+        None => Check::new("daemon", true, "answered on the control socket"),
+    });
+
+    match Store::open(&config.storage.dir) {
+        Ok(store) => {
+            let shutdown_log = ShutdownLog::new(&config.monitoring.shutdown, &store);
+            let last = shutdown_log.last_record();
+            bundle.add_json(
+                "status.json",
+                &serde_json::json!({
+                    "pid": shutdown_log.pid(),
+                    "last_shutdown": last,
+                    "unclean_exits": shutdown_log.unclean_exits(chrono::Utc::now()),
+                }),
+            )?;
+            if let Some(record) = last {
+                bundle.add_log("logs/last-shutdown.log", &record.recent_log.join("\n"));
+            }
+        }
+        Err(e) => bundle.skip("status.json", Report(&e)),
+    }
+
+    let since = chrono::Utc::now() - chrono::Duration::hours(1);
+    match StatsRing::read_window(&config.monitoring.recorder.path, Some(since), None) {
+        Ok(records) => bundle.add_json("tunnels.json", &records)?,
+        Err(e) => bundle.skip("tunnels.json", e),
+    }
+    bundle.add_log_file(
+        "logs/vx0net.log",
+        &config.monitoring.daemon.log_file,
+        log_tail,
+    );
+    bundle.add_crash_reports(&config.monitoring.crash.directory);
+    bundle.add_json("checks.json", &checks)?;
+
+    let file = {
+        use std::os::unix::fs::OpenOptionsExt;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(output)
+            .map_err(|e| format!("Cannot write {}: {}", output, e))?
+    };
+    let manifest = bundle.write(std::io::BufWriter::new(file))?;
 
+    println!("Wrote {} with:", output);
+    for file in &manifest.files {
+        match file.truncated_from {
+            Some(before) => println!(
+                "  {} ({} bytes, trimmed from {})",
+                file.path, file.bytes, before
+            ),
+            None => println!("  {} ({} bytes)", file.path, file.bytes),
+        }
+    }
+    if !manifest.skipped.is_empty() {
+        println!("Left out:");
+        for skipped in &manifest.skipped {
+            println!("  {}", skipped);
+        }
+    }
+    for check in checks.iter().filter(|check| !check.passed) {
+        println!("⚠️  {}: {}", check.name, check.detail);
+    }
+    println!(
+        "PSKs, encrypted values, the control token and private keys were masked; review the files before sharing."
+    );
     Ok(())
 }
 
-async fn show_peers() -> Result<(), Box<dyn std::error::Error>> {
-    println!("VX0 Connected Peers:");
-    println!("  Peer IP          ASN      Status       Uptime");
-    println!("  192.168.1.100    65002    Connected    00:15:42");
-    // In a real implementation, we would query the actual peer list
+fn init_config(profile: &str, output: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if !force && std::path::Path::new(output).exists() {
+        return Err(format!("{output} already exists; pass --force to replace it").into());
+    }
+    let mut config = profiles::named(profile)?;
+    config.profile = Some(profile.to_string());
+    config.save(output)?;
 
+    println!("✅ Wrote the {profile} profile to {output}");
+    println!("Set node.hostname, node.asn and the node addresses before joining.");
     Ok(())
 }
 
-async fn register_service(
-    name: &str,
-    domain: &str,
-    port: u16,
+fn generate_topology(
+    manifest: &str,
+    out_dir: &str,
+    force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    if !domain.ends_with(".vx0") {
-        return Err("Service domain must end with .vx0".into());
+    let topology = Topology::resolve(Manifest::load(Path::new(manifest))?)?;
+    let out_dir = Path::new(out_dir);
+    if !force {
+        if let Some(node) = topology
+            .nodes()
+            .iter()
+            .find(|node| out_dir.join(format!("{}.toml", node.name)).exists())
+        {
+            return Err(format!(
+                "{} already has a config for {}; pass --force to replace it",
+                out_dir.display(),
+                node.name
+            )
+            .into());
+        }
     }
 
-    info!("Registering service '{}' at {}:{}", name, domain, port);
-
-    // In a real implementation, we would:
-    // 1. Register the service in the local service registry
-    // 2. Announce it to the VX0 DNS network
-    // 3. Update BGP routing if needed
-
-    info!("Service '{}' registered successfully", name);
+    let written = topology.generate(out_dir)?;
+    println!("✅ Wrote {} files to {}", written.len(), out_dir.display());
+    println!(
+        "{:<12} {:<10} {:<8} {:<16} PEERS",
+        "NODE", "TIER", "ASN", "ADDRESS"
+    );
+    for node in topology.nodes() {
+        let peers: Vec<&str> = topology
+            .peerings()
+            .filter_map(|(a, b)| match (a == node.name, b == node.name) {
+                (true, _) => Some(b),
+                (_, true) => Some(a),
+                _ => None,
+            })
+            .collect();
+        println!(
+            "{:<12} {:<10} {:<8} {:<16} {}",
+            node.name,
+            format!("{:?}", node.tier),
+            node.asn,
+            node.ipv4_address,
+            peers.join(", ")
+        );
+    }
     Ok(())
 }
 
-async fn join_network_interactive() -> Result<(), Box<dyn std::error::Error>> {
+async fn join_network_interactive(
+    profile: Option<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
     println!("🌐 VX0 Network Interactive Join");
     println!("================================");
     println!();
@@ -312,12 +3542,27 @@ async fn join_network_interactive() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    if let Some(profile) = profile {
+        std::fs::create_dir_all("config")?;
+        init_config(&profile, "config/vx0net.toml", false)?;
+        println!();
+        println!("Next steps:");
+        println!("1. Get an available ASN in your tier range: vx0net scan-asns <tier>");
+        println!("2. Edit config/vx0net.toml with your ASN, hostname and addresses");
+        println!("3. Start the daemon with: vx0net start --join-network");
+        println!();
+        println!("📋 Current network status:");
+        show_network_status().await?;
+        return Ok(());
+    }
+
     println!("🎯 For the easiest setup, run: ./scripts/join-network.sh");
     println!("📖 For detailed instructions, see: JOINING.md");
     println!();
 
     println!("Manual joining steps:");
-    println!("1. Choose your node tier (Edge recommended for beginners)");
+    println!("1. Choose your node tier (Edge recommended for beginners), or rerun");
+    println!("   with --profile edge|regional|backbone to write a starting config");
     println!("2. Get an available ASN in your tier range");
     println!("3. Configure your node settings");
     println!("4. Start the daemon with: vx0net start --join-network");
@@ -388,20 +3633,39 @@ async fn show_network_status() -> Result<(), Box<dyn std::error::Error>> {
         ];
 
         for (hostname, port) in &bootstrap_nodes {
-            match tokio::time::timeout(
-                std::time::Duration::from_secs(5),
-                tokio::net::TcpSocket::new_v4()
-                    .unwrap()
-                    .connect(format!("{}:{}", hostname, port).parse().unwrap()),
-            )
-            .await
-            {
+            // Hostnames need a lookup; parsing them as socket addresses always fails
+            let connect = tokio::net::TcpStream::connect((*hostname, *port));
+            match tokio::time::timeout(std::time::Duration::from_secs(5), connect).await {
                 Ok(Ok(_)) => println!("  ✅ {} is reachable", hostname),
-                _ => println!("  ❌ {} is not reachable", hostname),
+                Ok(Err(e)) => println!("  ❌ {} is not reachable: {}", hostname, e),
+                Err(_) => println!("  ❌ {} is not reachable: timed out", hostname),
             }
         }
     }
 
+    // Scores from the running daemon, or as it last saved them
+    let bootstrap = match control_request(&ControlRequest::BootstrapList).await {
+        Ok(ControlResponse::Bootstrap { nodes }) => nodes,
+        _ => Vx0Config::load()
+            .map(|config| BootstrapRegistry::open(&config.joining.bootstrap_health))
+            .map(|registry| registry.status(chrono::Utc::now()))
+            .unwrap_or_default(),
+    };
+    if !bootstrap.is_empty() {
+        println!();
+        println!("🩺 Bootstrap node health:");
+        print_bootstrap_health(&bootstrap);
+    }
+
+    if let Ok(ControlResponse::Capacity {
+        report,
+        alerts,
+        heard,
+    }) = control_request(&ControlRequest::Capacity).await
+    {
+        print_capacity(report.as_deref(), &alerts, heard.as_deref());
+    }
+
     println!();
     println!("📍 To join the network:");
     println!("  ./scripts/join-network.sh   (automatic setup)");
@@ -411,6 +3675,64 @@ async fn show_network_status() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn print_capacity(
+    report: Option<&CapacityReport>,
+    alerts: &[CapacityAlert],
+    heard: Option<&HeardDigest>,
+) {
+    println!();
+    println!("📈 Tier capacity:");
+    match report {
+        Some(report) => {
+            for usage in &report.tiers {
+                println!(
+                    "  {:<9} {:>5} nodes, {:>5} of {:>5} ASNs used ({:.1}%)",
+                    format!("{:?}", usage.tier),
+                    usage.nodes,
+                    usage.asns_used,
+                    usage.range_size,
+                    usage.used_percent()
+                );
+            }
+            let ratio = |ratio: Option<f64>| ratio.map_or("-".to_string(), |r| format!("{:.1}", r));
+            println!(
+                "  Edges per Regional: {}, Regionals per Backbone: {}",
+                ratio(report.edges_per_regional),
+                ratio(report.regionals_per_backbone)
+            );
+            if let Some(concentration) = &report.concentration {
+                println!(
+                    "  Most nodes in one place: {} ({} of {}, {:.0}%)",
+                    concentration.location,
+                    concentration.nodes,
+                    report.located,
+                    concentration.percent
+                );
+            }
+        }
+        None => println!("  No reading of the directory yet"),
+    }
+    for alert in alerts {
+        println!("  ⚠️  {}", alert);
+    }
+    if let Some(heard) = heard {
+        let digest = &heard.digest;
+        println!(
+            "  As announced by {} at {}: {}/{}/{} Backbone/Regional/Edge ASNs used{}",
+            heard.hostname,
+            digest.computed.format("%Y-%m-%d %H:%M:%S UTC"),
+            digest.asns_used[0],
+            digest.asns_used[1],
+            digest.asns_used[2],
+            if digest.alerts.is_empty() {
+                String::new()
+            } else {
+                format!(", alerts: {}", digest.alerts.join(", "))
+            }
+        );
+    }
+}
+
 async fn scan_available_asns(tier: &str) -> Result<(), Box<dyn std::error::Error>> {
     println!("🔍 Scanning available ASNs for {} tier", tier);
     println!("=====================================");
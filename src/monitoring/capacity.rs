@@ -0,0 +1,674 @@
+//! How full each tier's ASN range is and whether the hierarchy is balanced.
+//!
+//! Every `interval_secs` the daemon reads the `_nodes.vx0` directory and
+//! counts, per tier, the ASNs in use against the size of the tier's range,
+//! along with the Edge nodes per Regional, the Regionals per Backbone, and
+//! the largest share of nodes in any one location. The figures are kept as
+//! gauges and shown by `vx0net network-status`; Backbone and Regional nodes
+//! also carry a digest of them in their announcements, so Edge nodes, whose
+//! view of the directory is partial, can show network health too.
+//!
+//! A figure crossing its threshold from `monitoring.capacity` raises an
+//! alert once, and clears it once it falls back. Both are logged under the
+//! `audit` target, sent to subscribers, and handed to `alert_command` as
+//! JSON on stdin if one is set.
+
+use crate::config::CapacityConfig;
+use crate::error::Report;
+use crate::monitoring::register_metric;
+use crate::monitoring::{crash, MonitoringError, Subsystem};
+use crate::network::dns::SharedDns;
+use crate::node::capabilities::NodeDirectoryEntry;
+use crate::node::{NodeId, NodeTier};
+use crate::util::backoff::{self, RetryError, RetryPolicy};
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use prometheus::{Gauge, GaugeVec, IntGaugeVec, Opts};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const EVENT_QUEUE: usize = 32;
+
+/// Tiers in the order reports list them
+pub const TIERS: [NodeTier; 3] = [NodeTier::Backbone, NodeTier::Regional, NodeTier::Edge];
+
+/// One tier's nodes and how much of its ASN range they use
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TierUsage {
+    pub tier: NodeTier,
+    pub nodes: usize,
+    /// Distinct ASNs in use; nodes sharing an ASN count once
+    pub asns_used: usize,
+    pub range_size: usize,
+}
+
+impl TierUsage {
+    pub fn used_percent(&self) -> f64 {
+        self.asns_used as f64 * 100.0 / self.range_size as f64
+    }
+}
+
+/// The location holding the most nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Concentration {
+    pub location: String,
+    pub nodes: usize,
+    /// Of the nodes stating a location
+    pub percent: f64,
+}
+
+/// Per-tier statistics from one reading of the directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CapacityReport {
+    /// Backbone, Regional and Edge, in that order
+    pub tiers: Vec<TierUsage>,
+    /// `None` without any Regional
+    pub edges_per_regional: Option<f64>,
+    /// `None` without any Backbone
+    pub regionals_per_backbone: Option<f64>,
+    /// `None` if no node states a location
+    pub concentration: Option<Concentration>,
+    /// Nodes stating a location
+    pub located: usize,
+    pub computed: DateTime<Utc>,
+}
+
+impl CapacityReport {
+    pub fn compute(nodes: &[NodeDirectoryEntry], now: DateTime<Utc>) -> Self {
+        let tiers: Vec<TierUsage> = TIERS
+            .iter()
+            .map(|tier| {
+                let (low, high) = tier.get_asn_range();
+                let listed: Vec<&NodeDirectoryEntry> = nodes
+                    .iter()
+                    .filter(|node| (low..=high).contains(&node.asn))
+                    .collect();
+                let asns: HashSet<u32> = listed.iter().map(|node| node.asn).collect();
+                TierUsage {
+                    tier: tier.clone(),
+                    nodes: listed.len(),
+                    asns_used: asns.len(),
+                    range_size: (high - low + 1) as usize,
+                }
+            })
+            .collect();
+        let ratio = |upper: usize, lower: usize| {
+            (tiers[upper].nodes > 0).then(|| tiers[lower].nodes as f64 / tiers[upper].nodes as f64)
+        };
+        let edges_per_regional = ratio(1, 2);
+        let regionals_per_backbone = ratio(0, 1);
+
+        let mut locations: BTreeMap<&str, usize> = BTreeMap::new();
+        for node in nodes.iter().filter(|node| !node.location.is_empty()) {
+            *locations.entry(node.location.as_str()).or_default() += 1;
+        }
+        let located: usize = locations.values().sum();
+        // Ties go to the first location alphabetically, so readings are stable
+        let concentration = locations
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))
+            .map(|(location, count)| Concentration {
+                location: location.to_string(),
+                nodes: *count,
+                percent: *count as f64 * 100.0 / located as f64,
+            });
+
+        CapacityReport {
+            tiers,
+            edges_per_regional,
+            regionals_per_backbone,
+            concentration,
+            located,
+            computed: now,
+        }
+    }
+
+    pub fn tier(&self, tier: &NodeTier) -> &TierUsage {
+        let index = TIERS.iter().position(|t| t == tier).unwrap_or_default();
+        &self.tiers[index]
+    }
+
+    /// Alerts the thresholds in `config` call for
+    pub fn alerts(&self, config: &CapacityConfig) -> Vec<CapacityAlert> {
+        let mut alerts: Vec<CapacityAlert> = self
+            .tiers
+            .iter()
+            .filter(|usage| usage.used_percent() >= config.range_used_percent)
+            .map(|usage| CapacityAlert::RangeFilling {
+                tier: usage.tier.clone(),
+                asns_used: usage.asns_used,
+                range_size: usage.range_size,
+                threshold_percent: config.range_used_percent,
+            })
+            .collect();
+
+        let [backbones, regionals, edges] = [0, 1, 2].map(|i| self.tiers[i].nodes);
+        // No upper tier at all is as thin as it gets
+        if edges > 0
+            && (regionals == 0 || edges as f64 / regionals as f64 > config.max_edges_per_regional)
+        {
+            alerts.push(CapacityAlert::ThinRegionals {
+                edges,
+                regionals,
+                threshold: config.max_edges_per_regional,
+            });
+        }
+        if regionals > 0
+            && (backbones == 0
+                || regionals as f64 / backbones as f64 > config.max_regionals_per_backbone)
+        {
+            alerts.push(CapacityAlert::ThinBackbone {
+                regionals,
+                backbones,
+                threshold: config.max_regionals_per_backbone,
+            });
+        }
+
+        if let Some(concentration) = &self.concentration {
+            if self.located >= config.concentration_min_nodes
+                && concentration.percent >= config.max_location_percent
+            {
+                alerts.push(CapacityAlert::Concentrated {
+                    location: concentration.location.clone(),
+                    nodes: concentration.nodes,
+                    located: self.located,
+                    threshold_percent: config.max_location_percent,
+                });
+            }
+        }
+        alerts
+    }
+
+    /// The figures compact enough to carry in an announcement
+    pub fn digest(&self, alerts: &[CapacityAlert]) -> CapacityDigest {
+        CapacityDigest {
+            asns_used: [0, 1, 2].map(|i| self.tiers[i].asns_used),
+            edges_per_regional: self.edges_per_regional,
+            regionals_per_backbone: self.regionals_per_backbone,
+            alerts: alerts.iter().map(CapacityAlert::key).collect(),
+            computed: self.computed,
+        }
+    }
+}
+
+/// A figure past its threshold
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CapacityAlert {
+    /// A tier is running out of ASNs
+    RangeFilling {
+        tier: NodeTier,
+        asns_used: usize,
+        range_size: usize,
+        threshold_percent: f64,
+    },
+    /// Too many Edge nodes hang off each Regional
+    ThinRegionals {
+        edges: usize,
+        regionals: usize,
+        threshold: f64,
+    },
+    /// Too many Regionals hang off each Backbone
+    ThinBackbone {
+        regionals: usize,
+        backbones: usize,
+        threshold: f64,
+    },
+    /// Too many nodes are in one place
+    Concentrated {
+        location: String,
+        nodes: usize,
+        located: usize,
+        threshold_percent: f64,
+    },
+}
+
+impl CapacityAlert {
+    /// Names the alert across readings, whatever its figures
+    pub fn key(&self) -> String {
+        match self {
+            CapacityAlert::RangeFilling { tier, .. } => {
+                format!("range_filling:{}", tier_label(tier))
+            }
+            CapacityAlert::ThinRegionals { .. } => "thin_regionals".to_string(),
+            CapacityAlert::ThinBackbone { .. } => "thin_backbone".to_string(),
+            CapacityAlert::Concentrated { location, .. } => format!("concentrated:{}", location),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            CapacityAlert::RangeFilling { .. } => "range_filling",
+            CapacityAlert::ThinRegionals { .. } => "thin_regionals",
+            CapacityAlert::ThinBackbone { .. } => "thin_backbone",
+            CapacityAlert::Concentrated { .. } => "concentrated",
+        }
+    }
+}
+
+impl fmt::Display for CapacityAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CapacityAlert::RangeFilling {
+                tier,
+                asns_used,
+                range_size,
+                threshold_percent,
+            } => write!(
+                f,
+                "{:?} tier uses {} of its {} ASNs (alert at {}%)",
+                tier, asns_used, range_size, threshold_percent
+            ),
+            CapacityAlert::ThinRegionals {
+                edges,
+                regionals,
+                threshold,
+            } => write!(
+                f,
+                "{} Edge nodes on {} Regionals (alert above {} per Regional)",
+                edges, regionals, threshold
+            ),
+            CapacityAlert::ThinBackbone {
+                regionals,
+                backbones,
+                threshold,
+            } => write!(
+                f,
+                "{} Regionals on {} Backbones (alert above {} per Backbone)",
+                regionals, backbones, threshold
+            ),
+            CapacityAlert::Concentrated {
+                location,
+                nodes,
+                located,
+                threshold_percent,
+            } => write!(
+                f,
+                "{} of {} located nodes are in {} (alert at {}%)",
+                nodes, located, location, threshold_percent
+            ),
+        }
+    }
+}
+
+/// An alert raised or cleared by a reading
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", content = "alert", rename_all = "snake_case")]
+pub enum CapacityEvent {
+    Raised(CapacityAlert),
+    Cleared(CapacityAlert),
+}
+
+/// Network health as a Backbone or Regional node announces it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CapacityDigest {
+    /// ASNs in use by Backbone, Regional and Edge nodes
+    pub asns_used: [usize; 3],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edges_per_regional: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regionals_per_backbone: Option<f64>,
+    /// Keys of the alerts in effect
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alerts: Vec<String>,
+    pub computed: DateTime<Utc>,
+}
+
+/// A digest and the node that announced it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HeardDigest {
+    pub node_id: NodeId,
+    pub hostname: String,
+    pub digest: CapacityDigest,
+}
+
+/// Reads the directory, keeps the latest report and the alerts in effect,
+/// and remembers the freshest digest heard from the upper tiers
+#[derive(Debug)]
+pub struct CapacityMonitor {
+    config: CapacityConfig,
+    latest: Mutex<Option<CapacityReport>>,
+    /// In effect, by key
+    active: Mutex<BTreeMap<String, CapacityAlert>>,
+    heard: Mutex<Option<HeardDigest>>,
+    events: broadcast::Sender<CapacityEvent>,
+}
+
+impl CapacityMonitor {
+    pub fn new(config: CapacityConfig) -> Self {
+        CapacityMonitor {
+            config,
+            latest: Mutex::new(None),
+            active: Mutex::new(BTreeMap::new()),
+            heard: Mutex::new(None),
+            events: broadcast::channel(EVENT_QUEUE).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CapacityEvent> {
+        self.events.subscribe()
+    }
+
+    /// The last reading, if one was taken
+    pub fn report(&self) -> Option<CapacityReport> {
+        lock(&self.latest).clone()
+    }
+
+    /// Alerts in effect
+    pub fn alerts(&self) -> Vec<CapacityAlert> {
+        lock(&self.active).values().cloned().collect()
+    }
+
+    /// What this node announces, once it has taken a reading
+    pub fn digest(&self) -> Option<CapacityDigest> {
+        let report = self.report()?;
+        Some(report.digest(&self.alerts()))
+    }
+
+    /// The freshest digest announced by a Backbone or Regional node
+    pub fn heard(&self) -> Option<HeardDigest> {
+        lock(&self.heard).clone()
+    }
+
+    /// Keep a digest from an announcement if it is newer than the one held
+    pub fn hear(&self, node_id: NodeId, hostname: &str, digest: &CapacityDigest) {
+        let mut heard = lock(&self.heard);
+        if heard
+            .as_ref()
+            .is_some_and(|held| held.digest.computed >= digest.computed)
+        {
+            return;
+        }
+        *heard = Some(HeardDigest {
+            node_id,
+            hostname: hostname.to_string(),
+            digest: digest.clone(),
+        });
+    }
+
+    /// Take a reading of `nodes`, returning the alerts it raised or cleared
+    pub fn observe(&self, nodes: &[NodeDirectoryEntry]) -> Vec<CapacityEvent> {
+        let report = CapacityReport::compute(nodes, Utc::now());
+        let current: BTreeMap<String, CapacityAlert> = report
+            .alerts(&self.config)
+            .into_iter()
+            .map(|alert| (alert.key(), alert))
+            .collect();
+        record_gauges(&report, &current);
+
+        let mut events = Vec::new();
+        {
+            let mut active = lock(&self.active);
+            for (key, alert) in active.iter() {
+                if !current.contains_key(key) {
+                    events.push(CapacityEvent::Cleared(alert.clone()));
+                }
+            }
+            for (key, alert) in &current {
+                if !active.contains_key(key) {
+                    events.push(CapacityEvent::Raised(alert.clone()));
+                }
+            }
+            *active = current;
+        }
+        *lock(&self.latest) = Some(report);
+
+        for event in &events {
+            match event {
+                CapacityEvent::Raised(alert) => {
+                    tracing::warn!(target: "audit", "Capacity alert: {}", alert)
+                }
+                CapacityEvent::Cleared(alert) => {
+                    tracing::info!(target: "audit", "Capacity alert cleared: {}", alert)
+                }
+            }
+            // Nobody listening is fine
+            let _ = self.events.send(event.clone());
+        }
+        events
+    }
+
+    /// Read `directory` every `interval_secs`, handing each alert raised
+    /// or cleared to `alert_command` under the `hooks` retry policy
+    pub fn start(self: &Arc<Self>, directory: SharedDns, hooks: RetryPolicy) {
+        if !self.config.enabled {
+            return;
+        }
+        let monitor = Arc::clone(self);
+        crash::spawn_restartable(Subsystem::Monitoring, "capacity", move || {
+            let monitor = Arc::clone(&monitor);
+            let directory = Arc::clone(&directory);
+            let hooks = hooks.clone();
+            async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(monitor.config.interval_secs.max(1)));
+                loop {
+                    interval.tick().await;
+                    let nodes = directory.read().await.nodes();
+                    let events = monitor.observe(&nodes);
+                    if monitor.config.alert_command.is_empty() {
+                        continue;
+                    }
+                    for event in events {
+                        let command = monitor.config.alert_command.clone();
+                        let hooks = hooks.clone();
+                        crash::spawn(Subsystem::Monitoring, "capacity-alert-hook", async move {
+                            let result = backoff::retry_with(
+                                &hooks,
+                                &CancellationToken::new(),
+                                |_| true,
+                                || run_hook(&command, &event),
+                            )
+                            .await
+                            .map_err(RetryError::into_inner);
+                            if let Err(e) = result {
+                                tracing::warn!("Capacity alert command failed: {}", Report(&e));
+                            }
+                        });
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Run `command` with `event` as JSON on stdin
+pub async fn run_hook<E: Serialize>(command: &[String], event: &E) -> Result<(), MonitoringError> {
+    let Some((program, args)) = command.split_first() else {
+        return Ok(());
+    };
+    let body = serde_json::to_vec(event)?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&body).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(MonitoringError::Hook {
+            status: output.status.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn tier_label(tier: &NodeTier) -> &'static str {
+    match tier {
+        NodeTier::Backbone => "backbone",
+        NodeTier::Regional => "regional",
+        NodeTier::Edge => "edge",
+    }
+}
+
+fn record_gauges(report: &CapacityReport, alerts: &BTreeMap<String, CapacityAlert>) {
+    for usage in &report.tiers {
+        let tier = tier_label(&usage.tier);
+        let asns = tier_asns_gauge();
+        asns.with_label_values(&[tier, "used"])
+            .set(usage.asns_used as i64);
+        asns.with_label_values(&[tier, "free"])
+            .set(usage.range_size.saturating_sub(usage.asns_used) as i64);
+    }
+    for (name, ratio) in [
+        ("edges_per_regional", report.edges_per_regional),
+        ("regionals_per_backbone", report.regionals_per_backbone),
+    ] {
+        tier_ratio_gauge()
+            .with_label_values(&[name])
+            .set(ratio.unwrap_or(0.0));
+    }
+    concentration_gauge().set(
+        report
+            .concentration
+            .as_ref()
+            .map_or(0.0, |concentration| concentration.percent),
+    );
+
+    let mut by_kind: HashMap<&'static str, i64> = HashMap::new();
+    for alert in alerts.values() {
+        *by_kind.entry(alert.kind()).or_default() += 1;
+    }
+    for kind in [
+        "range_filling",
+        "thin_regionals",
+        "thin_backbone",
+        "concentrated",
+    ] {
+        alerts_gauge()
+            .with_label_values(&[kind])
+            .set(by_kind.get(kind).copied().unwrap_or(0));
+    }
+}
+
+/// ASNs per tier, in use or still free
+pub fn tier_asns_gauge() -> &'static IntGaugeVec {
+    static GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(IntGaugeVec::new(
+            Opts::new("vx0net_tier_asns", "ASNs per tier, in use or still free"),
+            &["tier", "state"],
+        ))
+    })
+}
+
+/// Nodes of one tier per node of the tier above it
+pub fn tier_ratio_gauge() -> &'static GaugeVec {
+    static GAUGE: OnceLock<GaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(GaugeVec::new(
+            Opts::new(
+                "vx0net_tier_ratio",
+                "Nodes of one tier per node of the tier above it",
+            ),
+            &["ratio"],
+        ))
+    })
+}
+
+/// Share of located nodes in the most popular location
+pub fn concentration_gauge() -> &'static Gauge {
+    static GAUGE: OnceLock<Gauge> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(Gauge::with_opts(Opts::new(
+            "vx0net_location_concentration_percent",
+            "Share of located nodes in the most popular location",
+        )))
+    })
+}
+
+/// Capacity alerts in effect, by kind
+pub fn alerts_gauge() -> &'static IntGaugeVec {
+    static GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(IntGaugeVec::new(
+            Opts::new(
+                "vx0net_capacity_alerts",
+                "Capacity alerts in effect, by kind",
+            ),
+            &["kind"],
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(asn: u32, location: &str) -> NodeDirectoryEntry {
+        NodeDirectoryEntry {
+            node_id: NodeId::new_v4(),
+            hostname: format!("node{}.vx0", asn),
+            asn,
+            address: "10.2.0.1".parse().unwrap(),
+            capabilities: Default::default(),
+            location: location.to_string(),
+            updated: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_alerts_are_raised_and_cleared_once() {
+        let monitor = CapacityMonitor::new(CapacityConfig::default());
+        let mut events = monitor.subscribe();
+        let mut nodes = vec![listing(65001, ""), listing(65101, "")];
+        nodes.extend((0..150).map(|i| listing(66000 + i, "")));
+
+        let raised = monitor.observe(&nodes);
+        assert_eq!(raised.len(), 1);
+        assert!(matches!(
+            &raised[0],
+            CapacityEvent::Raised(CapacityAlert::ThinRegionals {
+                edges: 150,
+                regionals: 1,
+                ..
+            })
+        ));
+        assert_eq!(events.try_recv().unwrap(), raised[0]);
+
+        // Still thin: nothing new to say
+        assert!(monitor.observe(&nodes).is_empty());
+        assert_eq!(monitor.alerts().len(), 1);
+
+        nodes.push(listing(65102, ""));
+        let cleared = monitor.observe(&nodes);
+        assert!(matches!(
+            &cleared[..],
+            [CapacityEvent::Cleared(CapacityAlert::ThinRegionals {
+                edges: 150,
+                ..
+            })]
+        ));
+        assert!(monitor.alerts().is_empty());
+    }
+
+    #[test]
+    fn test_newest_digest_is_kept() {
+        let monitor = CapacityMonitor::new(CapacityConfig::default());
+        let report = CapacityReport::compute(&[listing(65101, "")], Utc::now());
+        let older = report.digest(&[]);
+        let newer = CapacityDigest {
+            computed: older.computed + chrono::Duration::seconds(30),
+            ..older.clone()
+        };
+        let (first, second) = (NodeId::new_v4(), NodeId::new_v4());
+
+        monitor.hear(first, "regional1.vx0", &newer);
+        monitor.hear(second, "regional2.vx0", &older);
+        assert_eq!(monitor.heard().unwrap().node_id, first);
+    }
+}
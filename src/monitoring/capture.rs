@@ -0,0 +1,344 @@
+//! Capture of everything a node processed, for replaying against a fresh
+//! instance.
+//!
+//! Each start writes a new file: one line of JSON naming the node, then one
+//! line per inbound wire message or control command in the order the node
+//! handled them, each with a sequence number and its offset from the start
+//! of the capture. Once the file reaches its size limit a closing line marks
+//! it full and capture stops, so a capture is always a complete prefix of
+//! what happened and replays from a fresh node.
+//!
+//! Nothing secret is recorded: control tokens stay in the envelope, and
+//! tunnel payloads are recorded after decryption only when enabled.
+
+use crate::config::CaptureConfig;
+use crate::control::ControlRequest;
+use crate::monitoring::MonitoringError;
+use crate::network::bgp::protocol::BGPMessage;
+use crate::network::dns::zone::ZoneTransfer;
+use crate::network::ike::tunnels::TunnelId;
+#[cfg(feature = "discovery")]
+use crate::node::discovery::DiscoveryMessage;
+use crate::node::joining::JoinRequest;
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Bumped when lines change incompatibly
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Who the captured node was; a replay starts a node like it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureHeader {
+    pub version: u32,
+    pub started: DateTime<Utc>,
+    pub asn: u32,
+    pub router_id: IpAddr,
+}
+
+/// Something the node processed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CapturedEvent {
+    /// A message on an established BGP session
+    Bgp {
+        peer_asn: u32,
+        peer: IpAddr,
+        message: BGPMessage,
+    },
+    Join {
+        from: IpAddr,
+        request: JoinRequest,
+    },
+    /// A discovery announcement, query or response from the local network
+    #[cfg(feature = "discovery")]
+    Announcement {
+        from: SocketAddr,
+        message: DiscoveryMessage,
+    },
+    /// What a zone pull brought back from `primary`
+    ZoneTransfer {
+        zone: String,
+        primary: SocketAddr,
+        transfer: ZoneTransfer,
+    },
+    Control {
+        request: ControlRequest,
+    },
+    /// Only with `tunnel_payloads` enabled
+    TunnelPayload {
+        tunnel: TunnelId,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    /// Since the capture started
+    pub offset_ms: u64,
+    pub event: CapturedEvent,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CaptureLine {
+    Header(CaptureHeader),
+    Record(Box<CaptureRecord>),
+    /// The size limit was reached; nothing after `seq` was recorded
+    Full {
+        seq: u64,
+        at: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug)]
+struct Writer {
+    /// None once full or after a write failed
+    file: Option<File>,
+    next_seq: u64,
+    written: u64,
+}
+
+/// Writer side of a capture file, shared by everything that records
+#[derive(Debug)]
+pub struct Capture {
+    path: PathBuf,
+    started: Instant,
+    max_size: u64,
+    tunnel_payloads: bool,
+    writer: Mutex<Writer>,
+}
+
+impl Capture {
+    /// Start a new capture file in the configured directory
+    pub fn open(
+        config: &CaptureConfig,
+        asn: u32,
+        router_id: IpAddr,
+    ) -> Result<Self, MonitoringError> {
+        let started = Utc::now();
+        let path = Path::new(&config.directory).join(format!(
+            "capture-{}.jsonl",
+            started.format("%Y%m%dT%H%M%S%.3fZ")
+        ));
+        Self::create(path, config, asn, router_id)
+    }
+
+    /// Start capturing to `path`, replacing anything there
+    pub fn create(
+        path: impl Into<PathBuf>,
+        config: &CaptureConfig,
+        asn: u32,
+        router_id: IpAddr,
+    ) -> Result<Self, MonitoringError> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let header = line(&CaptureLine::Header(CaptureHeader {
+            version: FORMAT_VERSION,
+            started: Utc::now(),
+            asn,
+            router_id,
+        }))?;
+        file.write_all(&header)?;
+        tracing::info!("Capturing processed messages to {}", path.display());
+
+        Ok(Capture {
+            path,
+            started: Instant::now(),
+            max_size: config.max_size_bytes.get(),
+            tunnel_payloads: config.tunnel_payloads,
+            writer: Mutex::new(Writer {
+                file: Some(file),
+                next_seq: 0,
+                written: header.len() as u64,
+            }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether decrypted tunnel payloads are wanted
+    pub fn tunnel_payloads(&self) -> bool {
+        self.tunnel_payloads
+    }
+
+    /// Append an event, unless the capture is full
+    pub fn record(&self, event: CapturedEvent) {
+        let mut writer = lock(&self.writer);
+        if writer.file.is_none() {
+            return;
+        }
+        let seq = writer.next_seq;
+        let at = Utc::now();
+        let record = CaptureLine::Record(Box::new(CaptureRecord {
+            seq,
+            at,
+            offset_ms: self.started.elapsed().as_millis() as u64,
+            event,
+        }));
+        let result = line(&record).and_then(|record| {
+            let full = writer.written + record.len() as u64 > self.max_size;
+            let bytes = if full {
+                line(&CaptureLine::Full { seq, at })?
+            } else {
+                record
+            };
+            let file = writer.file.as_mut().expect("checked above");
+            file.write_all(&bytes)?;
+            writer.written += bytes.len() as u64;
+            Ok(full)
+        });
+
+        match result {
+            Ok(false) => writer.next_seq += 1,
+            Ok(true) => {
+                tracing::warn!(
+                    "Capture {} reached its size limit; no longer capturing",
+                    self.path.display()
+                );
+                writer.file = None;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to write capture {}: {}; no longer capturing",
+                    self.path.display(),
+                    e
+                );
+                writer.file = None;
+            }
+        }
+    }
+}
+
+fn line(line: &CaptureLine) -> Result<Vec<u8>, MonitoringError> {
+    let mut bytes = serde_json::to_vec(line)?;
+    bytes.push(b'\n');
+    Ok(bytes)
+}
+
+/// A capture file as read back
+#[derive(Debug, Clone)]
+pub struct CaptureFile {
+    pub header: CaptureHeader,
+    pub records: Vec<CaptureRecord>,
+    /// Whether capture stopped at the size limit
+    pub full: bool,
+}
+
+impl CaptureFile {
+    /// Read a capture, ignoring a final line torn by a crash mid-write
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, MonitoringError> {
+        let reader = BufReader::new(File::open(path)?);
+        let lines: Vec<String> = reader.lines().collect::<Result<_, _>>()?;
+        let last = lines.len();
+
+        let mut header = None;
+        let mut records = Vec::new();
+        let mut full = false;
+        for (index, text) in lines.iter().enumerate() {
+            let parsed = match serde_json::from_str::<CaptureLine>(text) {
+                Ok(parsed) => parsed,
+                Err(_) if index + 1 == last && index > 0 => break,
+                Err(source) => {
+                    return Err(MonitoringError::BadCapture {
+                        line: index + 1,
+                        source,
+                    })
+                }
+            };
+            match parsed {
+                CaptureLine::Header(found) if index == 0 => header = Some(found),
+                CaptureLine::Record(record) if header.is_some() => records.push(*record),
+                CaptureLine::Full { .. } => {
+                    full = true;
+                    break;
+                }
+                _ => {
+                    return Err(MonitoringError::Config(format!(
+                        "Capture line {} is out of place",
+                        index + 1
+                    )))
+                }
+            }
+        }
+
+        let header =
+            header.ok_or_else(|| MonitoringError::Config("Capture file is empty".to_string()))?;
+        if header.version != FORMAT_VERSION {
+            return Err(MonitoringError::Config(format!(
+                "Capture format {} is not supported (expected {})",
+                header.version, FORMAT_VERSION
+            )));
+        }
+        Ok(CaptureFile {
+            header,
+            records,
+            full,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::units::ByteSize;
+    use crate::network::bgp::Prefix;
+
+    fn withdraw(network: &str) -> CapturedEvent {
+        CapturedEvent::Control {
+            request: ControlRequest::WithdrawRoute {
+                network: network.parse::<Prefix>().unwrap(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_capture_stops_at_its_size_limit() {
+        let path = std::env::temp_dir().join(format!("vx0-capture-{}.jsonl", uuid::Uuid::new_v4()));
+        let config = CaptureConfig {
+            enabled: true,
+            max_size_bytes: ByteSize::bytes(600),
+            ..Default::default()
+        };
+        let capture = Capture::create(&path, &config, 65001, "10.0.0.1".parse().unwrap()).unwrap();
+        for third in 0..20 {
+            capture.record(withdraw(&format!("10.0.{}.0/24", third)));
+        }
+
+        let file = CaptureFile::read(&path).unwrap();
+        assert_eq!(file.header.asn, 65001);
+        assert!(file.full);
+        assert!(!file.records.is_empty() && file.records.len() < 20);
+        assert!(file
+            .records
+            .iter()
+            .enumerate()
+            .all(|(index, record)| record.seq == index as u64));
+        assert!(std::fs::metadata(&path).unwrap().len() <= 600 + 100);
+
+        // A line torn by a crash is dropped
+        let mut torn = std::fs::read(&path).unwrap();
+        torn.truncate(torn.len() - 10);
+        std::fs::write(&path, &torn).unwrap();
+        let file = CaptureFile::read(&path).unwrap();
+        assert!(!file.full);
+        let _ = std::fs::remove_file(&path);
+    }
+}
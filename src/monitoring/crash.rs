@@ -0,0 +1,572 @@
+//! Panic isolation for spawned tasks.
+//!
+//! Every daemon task is spawned through a `Supervisor`. A panic is caught at
+//! the task boundary instead of silently ending the task: the payload and
+//! backtrace are logged, the component's panic counter is bumped, and a crash
+//! report with the most recent log lines is written to the crash directory.
+//! Long-running subsystems are spawned restartable and come back after a
+//! short delay; one-off tasks such as a single client connection just end.
+//! Each task is also registered by name and subsystem with the supervisor's
+//! `TaskRegistry`, which shuts them down in order.
+
+use crate::config::CrashConfig;
+use crate::monitoring::tasks::{Subsystem, TaskRegistry};
+use crate::monitoring::timing::{Stage, TimingReport};
+use crate::monitoring::MonitoringError;
+use crate::util::sync::lock;
+use chrono::Utc;
+use futures::FutureExt;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{self, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once, OnceLock};
+use tokio::task::JoinHandle;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Log lines kept in memory for crash reports
+pub const DEFAULT_LOG_LINES: usize = 200;
+
+const REPORT_PREFIX: &str = "crash-";
+
+thread_local! {
+    /// Backtrace of the last panic on this thread, left by the panic hook
+    static LAST_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Message and location of the last panic on this thread
+    static LAST_PANIC: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Capture a backtrace for every panic, then defer to the previous hook
+pub(crate) fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture().to_string();
+            LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some(info.to_string()));
+            previous(info);
+        }));
+    });
+}
+
+/// The last panic on the calling thread, for code running while it unwinds
+pub fn last_panic() -> Option<String> {
+    LAST_PANIC.with(|last| last.borrow().clone())
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// The most recent log lines, for inclusion in crash reports
+#[derive(Debug)]
+pub struct LogRing {
+    lines: Mutex<VecDeque<String>>,
+    capacity: usize,
+}
+
+impl LogRing {
+    pub fn new(capacity: usize) -> Self {
+        LogRing {
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn push(&self, line: &str) {
+        let mut lines = lock(&self.lines);
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(strip_ansi(line));
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let lines = lock(&self.lines);
+        lines.iter().cloned().collect()
+    }
+}
+
+/// Drop terminal colour sequences so reports read cleanly
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Log writer that goes to stderr and keeps a copy in a `LogRing`
+#[derive(Debug, Clone)]
+pub struct LogTee {
+    ring: Arc<LogRing>,
+}
+
+impl<'a> MakeWriter<'a> for LogTee {
+    type Writer = TeeWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        TeeWriter {
+            ring: Arc::clone(&self.ring),
+            buf: Vec::new(),
+        }
+    }
+}
+
+pub struct TeeWriter {
+    ring: Arc<LogRing>,
+    buf: Vec<u8>,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        io::stderr().write_all(data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+impl Drop for TeeWriter {
+    fn drop(&mut self) {
+        for line in String::from_utf8_lossy(&self.buf).lines() {
+            self.ring.push(line);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    crash_dir: Mutex<Option<PathBuf>>,
+    max_reports: Mutex<usize>,
+    restart_delay: Mutex<Duration>,
+    panics: Mutex<HashMap<String, u64>>,
+    logs: Arc<LogRing>,
+    tasks: Arc<TaskRegistry>,
+    timings: Mutex<HashMap<Stage, TimingReport>>,
+}
+
+/// Spawns tasks with panic isolation and crash reporting
+#[derive(Debug, Clone)]
+pub struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Supervisor::new()
+    }
+}
+
+impl Supervisor {
+    /// A supervisor that logs and counts panics but writes no reports
+    /// until `configure` gives it a crash directory
+    pub fn new() -> Self {
+        install_panic_hook();
+        let defaults = CrashConfig::default();
+        Supervisor {
+            inner: Arc::new(Inner {
+                crash_dir: Mutex::new(None),
+                max_reports: Mutex::new(defaults.max_reports),
+                restart_delay: Mutex::new(Duration::from_secs(defaults.restart_delay_secs)),
+                panics: Mutex::new(HashMap::new()),
+                logs: Arc::new(LogRing::new(DEFAULT_LOG_LINES)),
+                tasks: Arc::new(TaskRegistry::new()),
+                timings: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// The process-wide supervisor used by `crash::spawn`
+    pub fn global() -> &'static Supervisor {
+        static GLOBAL: OnceLock<Supervisor> = OnceLock::new();
+        GLOBAL.get_or_init(Supervisor::new)
+    }
+
+    pub fn configure(&self, config: &CrashConfig) -> Result<(), MonitoringError> {
+        std::fs::create_dir_all(&config.directory)?;
+        *lock(&self.inner.crash_dir) = Some(PathBuf::from(&config.directory));
+        *lock(&self.inner.max_reports) = config.max_reports.max(1);
+        *lock(&self.inner.restart_delay) = Duration::from_secs(config.restart_delay_secs);
+        Ok(())
+    }
+
+    pub fn set_restart_delay(&self, delay: Duration) {
+        *lock(&self.inner.restart_delay) = delay;
+    }
+
+    /// Log writer feeding the ring that crash reports draw on
+    pub fn log_writer(&self) -> LogTee {
+        LogTee {
+            ring: Arc::clone(&self.inner.logs),
+        }
+    }
+
+    /// The newest `count` log lines, oldest first
+    pub fn recent_log(&self, count: usize) -> Vec<String> {
+        let lines = self.inner.logs.lines();
+        lines[lines.len().saturating_sub(count)..].to_vec()
+    }
+
+    /// Every task spawned through this supervisor
+    pub fn tasks(&self) -> &Arc<TaskRegistry> {
+        &self.inner.tasks
+    }
+
+    /// Keep the phase timings of the latest startup or shutdown
+    pub fn keep_timing(&self, report: TimingReport) {
+        lock(&self.inner.timings).insert(report.stage, report);
+    }
+
+    pub fn timing(&self, stage: Stage) -> Option<TimingReport> {
+        lock(&self.inner.timings).get(&stage).cloned()
+    }
+
+    pub fn panic_count(&self, component: &str) -> u64 {
+        lock(&self.inner.panics)
+            .get(component)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn panic_counts(&self) -> HashMap<String, u64> {
+        lock(&self.inner.panics).clone()
+    }
+
+    /// Spawn a one-off task; a panic is reported and the task yields `None`,
+    /// as it does when shutdown drops it
+    pub fn spawn<F>(
+        &self,
+        subsystem: Subsystem,
+        component: &str,
+        future: F,
+    ) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let supervisor = self.clone();
+        let component = component.to_string();
+        self.inner.tasks.spawn(
+            subsystem,
+            &component.clone(),
+            None,
+            move |task| async move {
+                let cancelled = task.token();
+                tokio::select! {
+                    biased;
+                    _ = cancelled.cancelled() => None,
+                    output = AssertUnwindSafe(future).catch_unwind() => match output {
+                        Ok(output) => Some(output),
+                        Err(payload) => {
+                            task.panicked();
+                            supervisor.record_panic(&component, payload.as_ref());
+                            None
+                        }
+                    },
+                }
+            },
+        )
+    }
+
+    /// Spawn a long-running subsystem, starting it afresh after each panic
+    ///
+    /// The subsystem is not restarted once its future returns normally, or
+    /// once shutdown has dropped it.
+    pub fn spawn_restartable<F, Fut>(
+        &self,
+        subsystem: Subsystem,
+        component: &str,
+        mut start: F,
+    ) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let component = component.to_string();
+        self.inner.tasks.spawn(
+            subsystem,
+            &component.clone(),
+            None,
+            move |task| async move {
+                let cancelled = task.token();
+                loop {
+                    let run = tokio::select! {
+                        biased;
+                        _ = cancelled.cancelled() => break,
+                        run = AssertUnwindSafe(start()).catch_unwind() => run,
+                    };
+                    let Err(payload) = run else {
+                        break;
+                    };
+                    supervisor.record_panic(&component, payload.as_ref());
+                    task.restarted();
+                    let delay = *lock(&supervisor.inner.restart_delay);
+                    tracing::warn!("Restarting {} in {:?}", component, delay);
+                    tokio::select! {
+                        _ = cancelled.cancelled() => break,
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                }
+            },
+        )
+    }
+
+    /// Spawn a task that winds down by itself: it is handed the token its
+    /// subsystem's shutdown cancels, and given `timeout` to finish after that
+    pub fn spawn_graceful<F, Fut>(
+        &self,
+        subsystem: Subsystem,
+        component: &str,
+        timeout: Duration,
+        start: F,
+    ) -> JoinHandle<Option<Fut::Output>>
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let supervisor = self.clone();
+        let component = component.to_string();
+        self.inner
+            .tasks
+            .spawn(subsystem, &component.clone(), Some(timeout), move |task| {
+                let future = start(task.token());
+                async move {
+                    match AssertUnwindSafe(future).catch_unwind().await {
+                        Ok(output) => Some(output),
+                        Err(payload) => {
+                            task.panicked();
+                            supervisor.record_panic(&component, payload.as_ref());
+                            None
+                        }
+                    }
+                }
+            })
+    }
+
+    fn record_panic(&self, component: &str, payload: &(dyn Any + Send)) {
+        let message = panic_message(payload);
+        let backtrace = LAST_BACKTRACE
+            .with(|last| last.borrow_mut().take())
+            .unwrap_or_else(|| "unavailable".to_string());
+
+        let count = {
+            let mut panics = lock(&self.inner.panics);
+            let count = panics.entry(component.to_string()).or_insert(0);
+            *count += 1;
+            *count
+        };
+        tracing::error!(
+            "Task {} panicked ({} so far): {}\n{}",
+            component,
+            count,
+            message,
+            backtrace
+        );
+
+        let crash_dir = lock(&self.inner.crash_dir).clone();
+        if let Some(dir) = crash_dir {
+            match self.write_report(&dir, component, &message, &backtrace) {
+                Ok(path) => tracing::error!("Crash report written to {}", path.display()),
+                Err(e) => tracing::error!("Failed to write crash report: {}", e),
+            }
+        }
+    }
+
+    fn write_report(
+        &self,
+        dir: &Path,
+        component: &str,
+        message: &str,
+        backtrace: &str,
+    ) -> Result<PathBuf, MonitoringError> {
+        let now = Utc::now();
+        let path = dir.join(format!(
+            "{}{}-{}.txt",
+            REPORT_PREFIX,
+            now.format("%Y%m%dT%H%M%S%.6fZ"),
+            component.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_")
+        ));
+
+        let mut report = format!(
+            "timestamp: {}\ncomponent: {}\npanic: {}\n\nbacktrace:\n{}\n\nlive tasks:\n",
+            now.to_rfc3339(),
+            component,
+            message,
+            backtrace
+        );
+        for task in self.inner.tasks.live() {
+            report.push_str(&format!(
+                "  {} for {}s\n",
+                task,
+                task.age(now).num_seconds()
+            ));
+        }
+        report.push_str("\nrecent log:\n");
+        for line in self.inner.logs.lines() {
+            report.push_str(&line);
+            report.push('\n');
+        }
+        std::fs::write(&path, report)?;
+
+        rotate_reports(dir, *lock(&self.inner.max_reports))?;
+        Ok(path)
+    }
+}
+
+/// Crash reports in `dir`, oldest first; names sort by time
+pub fn reports(dir: &Path) -> Result<Vec<PathBuf>, MonitoringError> {
+    let mut reports: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(REPORT_PREFIX))
+        })
+        .collect();
+    reports.sort();
+    Ok(reports)
+}
+
+/// Delete the oldest reports beyond `keep`
+fn rotate_reports(dir: &Path, keep: usize) -> Result<(), MonitoringError> {
+    let reports = reports(dir)?;
+    let excess = reports.len().saturating_sub(keep);
+    for path in &reports[..excess] {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Spawn a one-off task under the global supervisor
+pub fn spawn<F>(subsystem: Subsystem, component: &str, future: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Supervisor::global().spawn(subsystem, component, future)
+}
+
+/// Spawn a restartable subsystem under the global supervisor
+pub fn spawn_restartable<F, Fut>(subsystem: Subsystem, component: &str, start: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Supervisor::global().spawn_restartable(subsystem, component, start)
+}
+
+/// Spawn a task that winds down by itself under the global supervisor
+pub fn spawn_graceful<F, Fut>(
+    subsystem: Subsystem,
+    component: &str,
+    timeout: Duration,
+    start: F,
+) -> JoinHandle<Option<Fut::Output>>
+where
+    F: FnOnce(CancellationToken) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    Supervisor::global().spawn_graceful(subsystem, component, timeout, start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn crash_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("vx0net-crash-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_panic_is_reported_and_restarted() {
+        let dir = crash_dir();
+        let supervisor = Supervisor::new();
+        supervisor
+            .configure(&CrashConfig {
+                directory: dir.to_string_lossy().into_owned(),
+                max_reports: 2,
+                restart_delay_secs: 0,
+            })
+            .unwrap();
+        supervisor.set_restart_delay(Duration::from_millis(10));
+        supervisor
+            .inner
+            .logs
+            .push("\x1b[32m INFO\x1b[0m peer 65001 up");
+
+        // Panics on the first three runs, then finishes
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&runs);
+        let handle = supervisor.spawn_restartable(Subsystem::Node, "test-component", move || {
+            let counter = Arc::clone(&counter);
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < 3 {
+                    panic!("deliberate failure");
+                }
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(5), handle)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        assert_eq!(supervisor.panic_count("test-component"), 3);
+
+        // Rotation keeps only the newest reports
+        let reports: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(reports.len(), 2);
+        let report = std::fs::read_to_string(&reports[0]).unwrap();
+        assert!(report.contains("component: test-component"));
+        assert!(report.contains("panic: deliberate failure"));
+        assert!(report.contains("INFO peer 65001 up"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_one_off_panic_is_isolated() {
+        let supervisor = Supervisor::new();
+        let failed = supervisor.spawn(Subsystem::Node, "one-off", async { panic!("{}", "boom") });
+        let fine = supervisor.spawn(Subsystem::Node, "one-off", async { 7 });
+
+        assert_eq!(failed.await.unwrap(), None);
+        assert_eq!(fine.await.unwrap(), Some(7));
+        assert_eq!(supervisor.panic_count("one-off"), 1);
+    }
+}
@@ -0,0 +1,117 @@
+//! The metrics port: Prometheus scrapes and orchestrator probes.
+//!
+//! With `monitoring.enable_metrics` the daemon answers plain HTTP on
+//! `metrics_port`. `/metrics` is every registered metric in the Prometheus
+//! text format, `/livez` answers 200 for as long as the runtime does, and
+//! `/readyz` runs the readiness checks and answers 200 when they all pass
+//! or 503 when any fails, with the report as JSON either way. Only GET is
+//! served and each connection carries one request.
+
+use crate::monitoring::{crash, MonitoringError, Subsystem};
+use crate::network::bgp::BGPDaemon;
+use crate::node::Vx0Node;
+use prometheus::{Encoder, TextEncoder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Longest request head read before giving up on a client
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve the endpoints on `addr`, returning the address bound
+pub async fn serve(
+    addr: SocketAddr,
+    node: Arc<Vx0Node>,
+    bgp: Arc<BGPDaemon>,
+) -> Result<SocketAddr, MonitoringError> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    tracing::info!("Serving metrics and health probes on {}", local_addr);
+
+    crash::spawn(Subsystem::Monitoring, "metrics-http", async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let (node, bgp) = (Arc::clone(&node), Arc::clone(&bgp));
+                    crash::spawn(Subsystem::Monitoring, "metrics-http-client", async move {
+                        let answered =
+                            tokio::time::timeout(REQUEST_TIMEOUT, answer(stream, &node, &bgp));
+                        match answered.await {
+                            Ok(Ok(())) => {}
+                            Ok(Err(e)) => tracing::debug!("Metrics client {} left: {}", peer, e),
+                            Err(_) => tracing::debug!("Metrics client {} timed out", peer),
+                        }
+                    });
+                }
+                Err(e) => tracing::error!("Metrics accept error: {}", e),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn answer(
+    mut stream: TcpStream,
+    node: &Vx0Node,
+    bgp: &BGPDaemon,
+) -> Result<(), MonitoringError> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || head.len() + read > MAX_REQUEST_BYTES {
+            break;
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    // "GET /readyz HTTP/1.1"
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (request_line.next(), request_line.next());
+    let path = target.map(|target| target.split('?').next().unwrap_or_default());
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+                tracing::warn!("Cannot encode metrics: {}", e);
+            }
+            ("200 OK", encoder.format_type().to_string(), body)
+        }
+        (Some("GET"), Some("/livez")) => ("200 OK", "text/plain".to_string(), b"ok\n".to_vec()),
+        (Some("GET"), Some("/readyz")) => {
+            let report = node.readiness.check(node, bgp).await;
+            let status = if report.ready {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = serde_json::to_vec_pretty(&report)?;
+            (status, "application/json".to_string(), body)
+        }
+        (Some("GET"), _) => (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"not found\n".to_vec(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain".to_string(),
+            b"method not allowed\n".to_vec(),
+        ),
+    };
+    let response_head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(response_head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
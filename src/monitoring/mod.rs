@@ -0,0 +1,81 @@
+pub mod capacity;
+pub mod capture;
+pub mod crash;
+#[cfg(feature = "metrics")]
+pub mod http;
+pub mod notify;
+pub mod readiness;
+pub mod recorder;
+pub mod replay;
+pub mod sampling;
+pub mod shutdown;
+pub mod support;
+pub mod tasks;
+pub mod timing;
+
+pub use crash::Supervisor;
+pub use recorder::{StatsRecord, StatsRecorder, StatsRing};
+pub use tasks::{Subsystem, TaskRegistry};
+
+use std::sync::OnceLock;
+use tracing_subscriber::filter::LevelFilter;
+
+/// Register a metric with the default registry, for the `OnceLock`s that
+/// hand out each metric; panics only on invalid metric options
+pub fn register_metric<M>(metric: prometheus::Result<M>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("metric options are valid");
+    // Only fails if registered twice, which the OnceLock rules out
+    let _ = prometheus::register(Box::new(metric.clone()));
+    metric
+}
+
+type LevelReload = Box<dyn Fn(LevelFilter) -> Result<(), String> + Send + Sync>;
+
+static LOG_LEVEL: OnceLock<LevelReload> = OnceLock::new();
+
+/// Let [`set_log_level`] change the level of the installed subscriber
+pub fn install_log_level(
+    reload: impl Fn(LevelFilter) -> Result<(), String> + Send + Sync + 'static,
+) {
+    let _ = LOG_LEVEL.set(Box::new(reload));
+}
+
+/// Log at `level` from now on; sampled sites start over, since what they
+/// suppressed was judged against the old level
+pub fn set_log_level(level: &str) -> Result<(), String> {
+    let level: LevelFilter = level
+        .parse()
+        .map_err(|_| format!("unknown log level {:?}", level))?;
+    if let Some(reload) = LOG_LEVEL.get() {
+        reload(level)?;
+    }
+    sampling::LogSampler::global().reset();
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MonitoringError {
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("IO error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error("Capture line {line} is not valid")]
+    BadCapture {
+        line: usize,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("Cannot keep daemon state")]
+    Storage(#[from] crate::storage::StorageError),
+    #[error("Not ready: {}", .failing.join("; "))]
+    NotReady { failing: Vec<String> },
+    #[error("Alert command exited with {status}: {stderr}")]
+    Hook { status: String, stderr: String },
+    #[error("Shutdown gave up on tasks that did not stop: {}", .tasks.join("; "))]
+    StuckShutdown { tasks: Vec<String> },
+}
@@ -0,0 +1,393 @@
+//! Readiness and liveness reports to the service manager.
+//!
+//! Under systemd with `Type=notify`, `NOTIFY_SOCKET` names a datagram socket
+//! that takes newline-separated `KEY=value` assignments (sd_notify(3)). The
+//! daemon sends `READY=1` only once every subsystem it starts has reported
+//! in, so units ordered after vx0net see listeners bound rather than a
+//! process that merely exists; with `monitoring.readiness.gate_sd_notify`
+//! it waits further, until the readiness checks first pass. With
+//! `WatchdogSec=` set, `WATCHDOG=1` pings go out at half the timeout for as
+//! long as the daemon's own liveness probe answers and no task has held its
+//! thread for a whole interval; a wedged runtime stops pinging and systemd
+//! restarts it.
+//!
+//! Without `NOTIFY_SOCKET` every report is a no-op, so foreground runs and
+//! containers behave as before.
+
+use crate::monitoring::readiness::ReadinessMonitor;
+use crate::monitoring::{crash, Subsystem, Supervisor};
+use crate::util::daemonize;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Format assignments as one notification; newlines in values would start
+/// a new assignment, so they become spaces
+pub fn message(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value.replace('\n', " ")))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    socket: Option<PathBuf>,
+}
+
+impl Notifier {
+    /// Report to `NOTIFY_SOCKET`, if the service manager set it
+    pub fn from_env() -> Self {
+        match std::env::var_os(NOTIFY_SOCKET) {
+            Some(path) if !path.is_empty() => Notifier::new(path),
+            _ => Notifier::disabled(),
+        }
+    }
+
+    /// Report to the socket at `path`; a leading `@` names an abstract socket
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Notifier {
+            socket: Some(path.into()),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Notifier { socket: None }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    pub fn notify(&self, fields: &[(&str, &str)]) -> io::Result<()> {
+        let Some(path) = &self.socket else {
+            return Ok(());
+        };
+        let socket = UnixDatagram::unbound()?;
+        let message = message(fields);
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(message.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        socket.send_to(message.as_bytes(), path)?;
+        Ok(())
+    }
+
+    pub fn ready(&self, status: &str) -> io::Result<()> {
+        let pid = std::process::id().to_string();
+        self.notify(&[("READY", "1"), ("STATUS", status), ("MAINPID", &pid)])
+    }
+
+    pub fn status(&self, status: &str) -> io::Result<()> {
+        self.notify(&[("STATUS", status)])
+    }
+
+    pub fn stopping(&self) -> io::Result<()> {
+        self.notify(&[("STOPPING", "1"), ("STATUS", "Shutting down")])
+    }
+
+    pub fn watchdog(&self) -> io::Result<()> {
+        self.notify(&[("WATCHDOG", "1")])
+    }
+
+    /// Ping every `interval` while `alive` answers within it and no task is
+    /// blocking its thread
+    pub fn start_watchdog<F, Fut>(self, interval: Duration, alive: F) -> JoinHandle<Option<()>>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        crash::spawn(Subsystem::Monitoring, "watchdog", async move {
+            let mut tick = tokio::time::interval(interval);
+            tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tick.tick().await;
+                if tokio::time::timeout(interval, alive()).await.is_err() {
+                    warn!("Liveness probe did not answer within {:?}", interval);
+                    continue;
+                }
+                if let Some(task) = Supervisor::global().tasks().blocking(interval).first() {
+                    warn!(
+                        "Task {} has blocked its thread for over {:?}",
+                        task, interval
+                    );
+                    continue;
+                }
+                if let Err(e) = self.watchdog() {
+                    debug!("Watchdog ping failed: {}", e);
+                }
+            }
+        })
+    }
+}
+
+/// How often to ping, given `WATCHDOG_USEC` and `WATCHDOG_PID`: half the
+/// timeout, and only if the watchdog is meant for this process
+pub fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.trim().parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.trim().parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// The ping interval systemd asked for, if any
+pub fn watchdog_interval() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// Counts subsystems in and reports ready once, after the last one
+#[derive(Debug)]
+pub struct Readiness {
+    notifier: Notifier,
+    pending: Vec<&'static str>,
+    parent: Option<File>,
+    ready: bool,
+    monitor: Option<Arc<ReadinessMonitor>>,
+}
+
+impl Readiness {
+    /// Ready once each of `components` has started, in any order
+    pub fn new(notifier: Notifier, components: &[&'static str]) -> Self {
+        Readiness {
+            notifier,
+            pending: components.to_vec(),
+            parent: None,
+            ready: false,
+            monitor: None,
+        }
+    }
+
+    /// Also tell the process waiting in `vx0net start --daemon`
+    pub fn with_parent(mut self, pipe: File) -> Self {
+        self.parent = Some(pipe);
+        self
+    }
+
+    /// Keep `monitor`'s subsystems to start in step with ours; with its
+    /// `gate_sd_notify`, READY=1 waits until it first finds the node ready
+    pub fn set_monitor(&mut self, monitor: Arc<ReadinessMonitor>) {
+        monitor.expect(&self.pending);
+        self.monitor = Some(monitor);
+    }
+
+    pub fn notifier(&self) -> &Notifier {
+        &self.notifier
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Components still to report in
+    pub fn pending(&self) -> &[&'static str] {
+        &self.pending
+    }
+
+    /// Mark `component` started, returning whether that made the daemon ready
+    pub fn started(&mut self, component: &str) -> bool {
+        let Some(index) = self.pending.iter().position(|c| *c == component) else {
+            return false;
+        };
+        self.pending.remove(index);
+        if let Some(monitor) = &self.monitor {
+            monitor.started(component);
+        }
+        if !self.pending.is_empty() {
+            let status = format!(
+                "Started {}; waiting for {}",
+                component,
+                self.pending.join(", ")
+            );
+            if let Err(e) = self.notifier.status(&status) {
+                debug!("Status notification failed: {}", e);
+            }
+            return false;
+        }
+        self.ready = true;
+        match &self.monitor {
+            Some(monitor) if monitor.config().gate_sd_notify => {
+                info!("All subsystems started; reporting ready once the readiness checks pass");
+                if let Err(e) = self
+                    .notifier
+                    .status("Started; waiting for readiness checks")
+                {
+                    debug!("Status notification failed: {}", e);
+                }
+                let (notifier, monitor) = (self.notifier.clone(), Arc::clone(monitor));
+                crash::spawn(Subsystem::Monitoring, "ready-notify", async move {
+                    monitor.wait_ready().await;
+                    if let Err(e) = notifier.ready("Running") {
+                        warn!("Readiness notification failed: {}", e);
+                    }
+                });
+            }
+            _ => {
+                info!("All subsystems started; reporting ready");
+                if let Err(e) = self.notifier.ready("Running") {
+                    warn!("Readiness notification failed: {}", e);
+                }
+            }
+        }
+        if let Some(pipe) = self.parent.take() {
+            if let Err(e) = daemonize::report_ready(pipe) {
+                warn!("Could not report readiness to the starting process: {}", e);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeSocket {
+        socket: UnixDatagram,
+        path: PathBuf,
+    }
+
+    impl FakeSocket {
+        fn new() -> Self {
+            let path =
+                std::env::temp_dir().join(format!("vx0net-notify-{}.sock", uuid::Uuid::new_v4()));
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            FakeSocket { socket, path }
+        }
+
+        fn received(&self) -> Vec<String> {
+            let mut messages = Vec::new();
+            let mut buf = [0; 1024];
+            while let Ok(len) = self.socket.recv(&mut buf) {
+                messages.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+            }
+            messages
+        }
+    }
+
+    impl Drop for FakeSocket {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_message_format() {
+        assert_eq!(message(&[("READY", "1")]), "READY=1\n");
+        assert_eq!(
+            message(&[("STATUS", "two\nlines"), ("WATCHDOG", "1")]),
+            "STATUS=two lines\nWATCHDOG=1\n"
+        );
+    }
+
+    #[test]
+    fn test_ready_only_after_every_component() {
+        let socket = FakeSocket::new();
+        let mut readiness = Readiness::new(Notifier::new(&socket.path), &["bgp", "dns", "join"]);
+
+        assert!(!readiness.started("dns"));
+        assert!(!readiness.started("dns"), "reported twice");
+        assert!(!readiness.started("bgp"));
+        let waiting = socket.received();
+        assert_eq!(waiting.len(), 2);
+        assert!(waiting.iter().all(|m| !m.contains("READY=1")));
+        assert_eq!(waiting[1], "STATUS=Started bgp; waiting for join\n");
+        assert_eq!(readiness.pending(), ["join"]);
+
+        assert!(readiness.started("join"));
+        assert!(readiness.is_ready());
+        let ready = socket.received();
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0].starts_with("READY=1\nSTATUS=Running\nMAINPID="));
+
+        // Ready is reported once
+        assert!(!readiness.started("join"));
+        assert!(socket.received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_gated_ready_waits_for_the_checks() {
+        use crate::config::ReadinessConfig;
+        use crate::monitoring::readiness::ReadinessInputs;
+
+        let socket = FakeSocket::new();
+        let monitor = Arc::new(ReadinessMonitor::new(ReadinessConfig {
+            gate_sd_notify: true,
+            ..Default::default()
+        }));
+        let mut readiness = Readiness::new(Notifier::new(&socket.path), &["bgp"]);
+        readiness.set_monitor(Arc::clone(&monitor));
+        assert_eq!(monitor.pending(), ["bgp"]);
+
+        assert!(readiness.started("bgp"));
+        assert!(monitor.pending().is_empty());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = socket.received();
+        assert_eq!(started.len(), 1);
+        assert!(!started[0].contains("READY=1"));
+
+        monitor.observe(&ReadinessInputs {
+            tier: crate::node::NodeTier::Regional,
+            established_peers: 1,
+            default_route: false,
+            pending: monitor.pending(),
+            wall_clock: chrono::Utc::now(),
+            maintenance: false,
+            held_down: false,
+        });
+        for _ in 0..100 {
+            let received = socket.received();
+            if !received.is_empty() {
+                assert!(received[0].starts_with("READY=1\n"));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("READY=1 never sent");
+    }
+
+    #[test]
+    fn test_ready_reaches_waiting_parent() {
+        let (mut rx, tx) = std::os::unix::net::UnixStream::pair().unwrap();
+        let pipe = File::from(std::os::fd::OwnedFd::from(tx));
+        let mut readiness = Readiness::new(Notifier::disabled(), &["node"]).with_parent(pipe);
+        assert!(readiness.started("node"));
+        let mut report = String::new();
+        io::Read::read_to_string(&mut rx, &mut report).unwrap();
+        assert_eq!(report.trim(), std::process::id().to_string());
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 7),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("7"), 7),
+            Some(Duration::from_secs(15))
+        );
+        // Meant for another process, e.g. our parent
+        assert_eq!(parse_watchdog(Some("30000000"), Some("8"), 7), None);
+        assert_eq!(parse_watchdog(None, None, 7), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 7), None);
+    }
+}
@@ -0,0 +1,438 @@
+//! Whether the node should be sent traffic, as distinct from whether it runs.
+//!
+//! Liveness, what the watchdog and `/livez` report, only says the process
+//! answers. Readiness says it is worth routing to: it has the Established
+//! BGP sessions `monitoring.readiness` asks for, an Edge node has learned a
+//! VX0 default from a parent, every subsystem has started, the wall clock
+//! is not behind the build, and the node is neither in maintenance nor in
+//! hold-down after joining. Each check can be turned off on its own.
+//!
+//! `/readyz` beside `/metrics` answers 200 or 503 with the checks as JSON,
+//! and `vx0net status --ready` exits 0 or 1 on them. With `gate_sd_notify`,
+//! systemd hears READY=1 only once the node is first ready. Every change
+//! between ready and not ready is logged under the `audit` target, sent to
+//! subscribers, and handed to `transition_command` as JSON on stdin.
+
+use crate::build_info;
+use crate::config::ReadinessConfig;
+use crate::error::Report;
+use crate::monitoring::capacity::run_hook;
+use crate::monitoring::register_metric;
+use crate::monitoring::{crash, MonitoringError, Subsystem};
+use crate::network::bgp::BGPDaemon;
+use crate::node::{NodeTier, Vx0Node};
+use crate::util::backoff::{self, RetryError, RetryPolicy};
+use crate::util::clock;
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use prometheus::{IntGauge, Opts};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio_util::sync::CancellationToken;
+
+const EVENT_QUEUE: usize = 16;
+
+/// One criterion and how the node stands against it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ReadinessCheck {
+    /// `peers`, `default_route`, `subsystems`, `clock`, `maintenance` or
+    /// `hold_down`
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl ReadinessCheck {
+    fn new(name: &str, passed: bool, detail: String) -> Self {
+        ReadinessCheck {
+            name: name.to_string(),
+            passed,
+            detail,
+        }
+    }
+}
+
+/// The enabled checks; ready when all of them passed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: Vec<ReadinessCheck>,
+    pub evaluated: DateTime<Utc>,
+}
+
+impl ReadinessReport {
+    pub fn failing(&self) -> Vec<&ReadinessCheck> {
+        self.checks.iter().filter(|check| !check.passed).collect()
+    }
+
+    /// What `vx0net status --ready` exits with
+    pub fn exit_code(&self) -> u8 {
+        u8::from(!self.ready)
+    }
+
+    /// An error naming the failed checks, unless ready
+    pub fn check(&self) -> Result<(), MonitoringError> {
+        if self.ready {
+            return Ok(());
+        }
+        Err(MonitoringError::NotReady {
+            failing: self
+                .failing()
+                .iter()
+                .map(|check| format!("{}: {}", check.name, check.detail))
+                .collect(),
+        })
+    }
+}
+
+/// The node's state as the checks see it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadinessInputs {
+    pub tier: NodeTier,
+    pub established_peers: usize,
+    /// A peer offers the VX0 default
+    pub default_route: bool,
+    /// Subsystems yet to start
+    pub pending: Vec<String>,
+    pub wall_clock: DateTime<Utc>,
+    pub maintenance: bool,
+    pub held_down: bool,
+}
+
+/// Judge `inputs` against the checks `config` enables
+pub fn evaluate(config: &ReadinessConfig, inputs: &ReadinessInputs) -> ReadinessReport {
+    let mut checks = Vec::new();
+    if config.min_established_peers > 0 {
+        checks.push(ReadinessCheck::new(
+            "peers",
+            inputs.established_peers >= config.min_established_peers,
+            format!(
+                "{} Established BGP session(s), {} needed",
+                inputs.established_peers, config.min_established_peers
+            ),
+        ));
+    }
+    if config.require_default_route && inputs.tier == NodeTier::Edge {
+        checks.push(ReadinessCheck::new(
+            "default_route",
+            inputs.default_route,
+            if inputs.default_route {
+                "learned from a parent".to_string()
+            } else {
+                "no parent offers the VX0 default".to_string()
+            },
+        ));
+    }
+    if config.require_subsystems {
+        checks.push(ReadinessCheck::new(
+            "subsystems",
+            inputs.pending.is_empty(),
+            if inputs.pending.is_empty() {
+                "all started".to_string()
+            } else {
+                format!("waiting for {}", inputs.pending.join(", "))
+            },
+        ));
+    }
+    if config.require_sane_clock {
+        let built = build_info::build_time();
+        let sane = built.is_none_or(|built| inputs.wall_clock >= built);
+        checks.push(ReadinessCheck::new(
+            "clock",
+            sane,
+            match built {
+                Some(built) if !sane => format!(
+                    "wall clock reads {}, before this binary was built at {}",
+                    inputs.wall_clock.to_rfc3339(),
+                    built.to_rfc3339()
+                ),
+                _ => inputs.wall_clock.to_rfc3339(),
+            },
+        ));
+    }
+    if config.require_out_of_maintenance {
+        checks.push(ReadinessCheck::new(
+            "maintenance",
+            !inputs.maintenance,
+            if inputs.maintenance {
+                "in maintenance".to_string()
+            } else {
+                "not in maintenance".to_string()
+            },
+        ));
+    }
+    if config.require_hold_down_over {
+        checks.push(ReadinessCheck::new(
+            "hold_down",
+            !inputs.held_down,
+            if inputs.held_down {
+                "still in hold-down after joining".to_string()
+            } else {
+                "not held down".to_string()
+            },
+        ));
+    }
+    ReadinessReport {
+        ready: checks.iter().all(|check| check.passed),
+        checks,
+        evaluated: inputs.wall_clock,
+    }
+}
+
+/// The node became ready, or stopped being ready
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ReadinessEvent {
+    Ready,
+    NotReady { failing: Vec<ReadinessCheck> },
+}
+
+/// Tracks the subsystems still to start, and the latest verdict
+#[derive(Debug)]
+pub struct ReadinessMonitor {
+    config: ReadinessConfig,
+    pending: Mutex<Vec<String>>,
+    latest: Mutex<Option<ReadinessReport>>,
+    ready: watch::Sender<bool>,
+    events: broadcast::Sender<ReadinessEvent>,
+}
+
+impl ReadinessMonitor {
+    pub fn new(config: ReadinessConfig) -> Self {
+        ReadinessMonitor {
+            config,
+            pending: Mutex::new(Vec::new()),
+            latest: Mutex::new(None),
+            ready: watch::Sender::new(false),
+            events: broadcast::channel(EVENT_QUEUE).0,
+        }
+    }
+
+    pub fn config(&self) -> &ReadinessConfig {
+        &self.config
+    }
+
+    /// Wait for each of `components` to start
+    pub fn expect(&self, components: &[&str]) {
+        *lock(&self.pending) = components.iter().map(|c| c.to_string()).collect();
+    }
+
+    pub fn started(&self, component: &str) {
+        lock(&self.pending).retain(|pending| pending != component);
+    }
+
+    pub fn pending(&self) -> Vec<String> {
+        lock(&self.pending).clone()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// The latest verdict, if a check ran
+    pub fn report(&self) -> Option<ReadinessReport> {
+        lock(&self.latest).clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ReadinessEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns once the node is ready
+    pub async fn wait_ready(&self) {
+        let mut ready = self.ready.subscribe();
+        // The sender lives as long as the monitor, which outlives this borrow
+        let _ = ready.wait_for(|ready| *ready).await;
+    }
+
+    /// Judge `inputs`, announcing a change from the previous verdict
+    pub fn observe(&self, inputs: &ReadinessInputs) -> ReadinessReport {
+        let report = evaluate(&self.config, inputs);
+        *lock(&self.latest) = Some(report.clone());
+        ready_gauge().set(i64::from(report.ready));
+        if !self
+            .ready
+            .send_if_modified(|ready| std::mem::replace(ready, report.ready) != report.ready)
+        {
+            return report;
+        }
+
+        let event = if report.ready {
+            tracing::info!(target: "audit", "Node is ready");
+            ReadinessEvent::Ready
+        } else {
+            let failing: Vec<ReadinessCheck> = report.failing().into_iter().cloned().collect();
+            tracing::warn!(
+                target: "audit",
+                "Node is no longer ready: {}",
+                failing
+                    .iter()
+                    .map(|check| format!("{} ({})", check.name, check.detail))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            ReadinessEvent::NotReady { failing }
+        };
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+        report
+    }
+
+    /// Check the node as it stands now
+    pub async fn check(&self, node: &Vx0Node, bgp: &BGPDaemon) -> ReadinessReport {
+        let inputs = ReadinessInputs {
+            tier: node.tier.clone(),
+            established_peers: bgp.established_sessions().await,
+            default_route: bgp.has_learned_default().await,
+            pending: self.pending(),
+            wall_clock: node.clock.now_utc(),
+            maintenance: node.in_maintenance(),
+            held_down: bgp.is_held_down(),
+        };
+        self.observe(&inputs)
+    }
+
+    /// Check every `interval_secs`, handing each transition to
+    /// `transition_command` under the `hooks` retry policy
+    pub fn start(self: &Arc<Self>, node: Arc<Vx0Node>, bgp: Arc<BGPDaemon>, hooks: RetryPolicy) {
+        let monitor = Arc::clone(self);
+        crash::spawn_restartable(Subsystem::Monitoring, "readiness", move || {
+            let monitor = Arc::clone(&monitor);
+            let (node, bgp) = (Arc::clone(&node), Arc::clone(&bgp));
+            async move {
+                let period = Duration::from_secs(monitor.config.interval_secs.max(1));
+                let mut interval = clock::interval(&node.clock, period);
+                loop {
+                    interval.tick().await;
+                    monitor.check(&node, &bgp).await;
+                }
+            }
+        });
+
+        if self.config.transition_command.is_empty() {
+            return;
+        }
+        let mut events = self.subscribe();
+        let command = self.config.transition_command.clone();
+        crash::spawn(Subsystem::Monitoring, "readiness-hooks", async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                // One at a time, so the command sees transitions in order
+                let result = backoff::retry_with(
+                    &hooks,
+                    &CancellationToken::new(),
+                    |_| true,
+                    || run_hook(&command, &event),
+                )
+                .await
+                .map_err(RetryError::into_inner);
+                if let Err(e) = result {
+                    tracing::warn!("Readiness transition command failed: {}", Report(&e));
+                }
+            }
+        });
+    }
+}
+
+/// 1 while the node is ready
+pub fn ready_gauge() -> &'static IntGauge {
+    static GAUGE: OnceLock<IntGauge> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(IntGauge::with_opts(Opts::new(
+            "vx0net_ready",
+            "1 while the node is ready for traffic",
+        )))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs() -> ReadinessInputs {
+        ReadinessInputs {
+            tier: NodeTier::Edge,
+            established_peers: 2,
+            default_route: true,
+            pending: Vec::new(),
+            wall_clock: Utc::now(),
+            maintenance: false,
+            held_down: false,
+        }
+    }
+
+    #[test]
+    fn test_every_enabled_check_must_pass() {
+        let config = ReadinessConfig::default();
+        let report = evaluate(&config, &inputs());
+        assert!(report.ready);
+        assert_eq!(report.checks.len(), 6);
+        assert_eq!(report.exit_code(), 0);
+
+        let stale = ReadinessInputs {
+            default_route: false,
+            wall_clock: DateTime::from_timestamp(0, 0).unwrap(),
+            pending: vec!["dns".to_string()],
+            ..inputs()
+        };
+        let report = evaluate(&config, &stale);
+        let failing: Vec<&str> = report
+            .failing()
+            .iter()
+            .map(|check| check.name.as_str())
+            .collect();
+        assert_eq!(failing, ["default_route", "subsystems", "clock"]);
+        assert_eq!(report.exit_code(), 1);
+
+        // Only Edge nodes need a default, and disabled checks are left out
+        let regional = ReadinessInputs {
+            tier: NodeTier::Regional,
+            held_down: true,
+            ..stale
+        };
+        let lenient = ReadinessConfig {
+            require_subsystems: false,
+            require_sane_clock: false,
+            require_hold_down_over: false,
+            ..config
+        };
+        let report = evaluate(&lenient, &regional);
+        assert!(report.ready);
+        assert_eq!(report.checks.len(), 2);
+    }
+
+    #[test]
+    fn test_transitions_are_announced_once() {
+        let monitor = ReadinessMonitor::new(ReadinessConfig::default());
+        let mut events = monitor.subscribe();
+        let lonely = ReadinessInputs {
+            established_peers: 0,
+            ..inputs()
+        };
+        // Not ready from the start is no change
+        assert!(!monitor.observe(&lonely).ready);
+        assert!(events.try_recv().is_err());
+
+        monitor.observe(&inputs());
+        monitor.observe(&inputs());
+        assert_eq!(events.try_recv().unwrap(), ReadinessEvent::Ready);
+        assert!(events.try_recv().is_err());
+        assert!(monitor.is_ready());
+
+        monitor.observe(&lonely);
+        match events.try_recv().unwrap() {
+            ReadinessEvent::NotReady { failing } => {
+                assert_eq!(failing.len(), 1);
+                assert_eq!(failing[0].name, "peers");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
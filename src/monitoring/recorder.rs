@@ -0,0 +1,490 @@
+//! Size-bounded ring file of per-peer stats samples.
+//!
+//! Records are fixed-size and carry their own sequence number and checksum,
+//! so the file needs no header: a reader validates every slot, drops torn or
+//! never-written ones, and orders the rest by sequence. A crash mid-write
+//! costs at most the record being written.
+
+use crate::config::RecorderConfig;
+use crate::monitoring::{crash, MonitoringError, Subsystem};
+use crate::network::bgp::BGPDaemon;
+use crate::node::{ConnectionStatus, NodeId, Vx0Node};
+use crate::util::sync::lock;
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Encoded size of one record, checksum included
+pub const RECORD_SIZE: usize = 72;
+const CHECKSUM_OFFSET: usize = RECORD_SIZE - 4;
+
+/// One sample of a peer and its tunnel
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsRecord {
+    pub timestamp: DateTime<Utc>,
+    pub peer_asn: u32,
+    pub peer_id: NodeId,
+    pub rtt_ms: u32,
+    pub packet_loss: f32,
+    /// Bytes since the previous sample
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub route_count: u32,
+    pub session_state: ConnectionStatus,
+    pub tunnel_up: bool,
+}
+
+fn status_code(status: &ConnectionStatus) -> u8 {
+    match status {
+        ConnectionStatus::Disconnected => 0,
+        ConnectionStatus::Connecting => 1,
+        ConnectionStatus::Connected => 2,
+        ConnectionStatus::Authenticated => 3,
+        ConnectionStatus::Failed => 4,
+        ConnectionStatus::Departed => 5,
+        ConnectionStatus::AdminDown => 6,
+        ConnectionStatus::Quarantined => 7,
+    }
+}
+
+fn status_from_code(code: u8) -> Option<ConnectionStatus> {
+    match code {
+        0 => Some(ConnectionStatus::Disconnected),
+        1 => Some(ConnectionStatus::Connecting),
+        2 => Some(ConnectionStatus::Connected),
+        3 => Some(ConnectionStatus::Authenticated),
+        4 => Some(ConnectionStatus::Failed),
+        5 => Some(ConnectionStatus::Departed),
+        6 => Some(ConnectionStatus::AdminDown),
+        7 => Some(ConnectionStatus::Quarantined),
+        _ => None,
+    }
+}
+
+// FNV-1a; enough to tell a torn or zeroed slot from a written one
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+impl StatsRecord {
+    fn encode(&self, seq: u64) -> [u8; RECORD_SIZE] {
+        let mut buf = [0u8; RECORD_SIZE];
+        buf[0..8].copy_from_slice(&seq.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.timestamp.timestamp_millis().to_le_bytes());
+        buf[16..20].copy_from_slice(&self.peer_asn.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.rtt_ms.to_le_bytes());
+        buf[24..40].copy_from_slice(self.peer_id.as_bytes());
+        buf[40..48].copy_from_slice(&self.bytes_sent.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.bytes_received.to_le_bytes());
+        buf[56..60].copy_from_slice(&self.route_count.to_le_bytes());
+        buf[60..64].copy_from_slice(&self.packet_loss.to_le_bytes());
+        buf[64] = status_code(&self.session_state);
+        buf[65] = self.tunnel_up as u8;
+        let sum = checksum(&buf[..CHECKSUM_OFFSET]);
+        buf[CHECKSUM_OFFSET..].copy_from_slice(&sum.to_le_bytes());
+        buf
+    }
+
+    /// Decode a slot, returning `None` for torn or unwritten records
+    fn decode(buf: &[u8]) -> Option<(u64, StatsRecord)> {
+        let stored = u32::from_le_bytes(buf[CHECKSUM_OFFSET..RECORD_SIZE].try_into().ok()?);
+        if checksum(&buf[..CHECKSUM_OFFSET]) != stored {
+            return None;
+        }
+
+        let u32_at = |at: usize| u32::from_le_bytes(buf[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+
+        let seq = u64_at(0);
+        let millis = i64::from_le_bytes(buf[8..16].try_into().unwrap());
+        let record = StatsRecord {
+            timestamp: Utc.timestamp_millis_opt(millis).single()?,
+            peer_asn: u32_at(16),
+            rtt_ms: u32_at(20),
+            peer_id: Uuid::from_slice(&buf[24..40]).ok()?,
+            bytes_sent: u64_at(40),
+            bytes_received: u64_at(48),
+            route_count: u32_at(56),
+            packet_loss: f32::from_le_bytes(buf[60..64].try_into().unwrap()),
+            session_state: status_from_code(buf[64])?,
+            tunnel_up: buf[65] != 0,
+        };
+        Some((seq, record))
+    }
+}
+
+/// Writer side of the ring file
+#[derive(Debug)]
+pub struct StatsRing {
+    file: File,
+    capacity: u64,
+    next_slot: u64,
+    next_seq: u64,
+}
+
+impl StatsRing {
+    /// Open or create the ring, resuming after the newest valid record
+    pub fn open(path: impl AsRef<Path>, max_size_bytes: u64) -> Result<Self, MonitoringError> {
+        let capacity = max_size_bytes / RECORD_SIZE as u64;
+        if capacity == 0 {
+            return Err(MonitoringError::Config(format!(
+                "Recorder max size must hold at least one {}-byte record",
+                RECORD_SIZE
+            )));
+        }
+
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        // Records beyond a since-reduced capacity would never be overwritten
+        let limit = capacity * RECORD_SIZE as u64;
+        if file.metadata()?.len() > limit {
+            file.set_len(limit)?;
+        }
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let newest = contents
+            .chunks_exact(RECORD_SIZE)
+            .enumerate()
+            .filter_map(|(slot, chunk)| StatsRecord::decode(chunk).map(|(seq, _)| (seq, slot)))
+            .max();
+
+        let (next_slot, next_seq) = match newest {
+            Some((seq, slot)) => ((slot as u64 + 1) % capacity, seq + 1),
+            None => (0, 0),
+        };
+
+        Ok(StatsRing {
+            file,
+            capacity,
+            next_slot,
+            next_seq,
+        })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn append(&mut self, record: &StatsRecord) -> Result<(), MonitoringError> {
+        let encoded = record.encode(self.next_seq);
+        self.file
+            .seek(SeekFrom::Start(self.next_slot * RECORD_SIZE as u64))?;
+        self.file.write_all(&encoded)?;
+
+        self.next_slot = (self.next_slot + 1) % self.capacity;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// Every valid record in the file, oldest first
+    pub fn read_all(path: impl AsRef<Path>) -> Result<Vec<StatsRecord>, MonitoringError> {
+        let contents = std::fs::read(path)?;
+        let mut records: Vec<(u64, StatsRecord)> = contents
+            .chunks_exact(RECORD_SIZE)
+            .filter_map(StatsRecord::decode)
+            .collect();
+        records.sort_by_key(|(seq, _)| *seq);
+        Ok(records.into_iter().map(|(_, record)| record).collect())
+    }
+
+    /// Records at or after `since`, optionally for a single peer
+    pub fn read_window(
+        path: impl AsRef<Path>,
+        since: Option<DateTime<Utc>>,
+        peer_asn: Option<u32>,
+    ) -> Result<Vec<StatsRecord>, MonitoringError> {
+        Ok(Self::read_all(path)?
+            .into_iter()
+            .filter(|record| since.is_none_or(|since| record.timestamp >= since))
+            .filter(|record| peer_asn.is_none_or(|asn| record.peer_asn == asn))
+            .collect())
+    }
+}
+
+/// Parse a look-back window such as "90s", "30m", "2h" or "7d"
+pub fn parse_since(window: &str) -> Result<chrono::Duration, MonitoringError> {
+    let window = window.trim();
+    let invalid = || MonitoringError::Config(format!("Invalid time window: {}", window));
+
+    let split = window.len().checked_sub(1).ok_or_else(invalid)?;
+    let (amount, unit) = window.split_at(split);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(amount)),
+        "m" => Ok(chrono::Duration::minutes(amount)),
+        "h" => Ok(chrono::Duration::hours(amount)),
+        "d" => Ok(chrono::Duration::days(amount)),
+        _ => Err(invalid()),
+    }
+}
+
+pub fn write_csv<W: Write>(records: &[StatsRecord], mut out: W) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "timestamp,peer_asn,peer_id,rtt_ms,packet_loss,bytes_sent,bytes_received,route_count,session_state,tunnel_up"
+    )?;
+    for record in records {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{:?},{}",
+            record.timestamp.to_rfc3339(),
+            record.peer_asn,
+            record.peer_id,
+            record.rtt_ms,
+            record.packet_loss,
+            record.bytes_sent,
+            record.bytes_received,
+            record.route_count,
+            record.session_state,
+            record.tunnel_up
+        )?;
+    }
+    Ok(())
+}
+
+/// Periodically samples every peer into the ring file
+pub struct StatsRecorder {
+    ring: Arc<Mutex<StatsRing>>,
+    interval: Duration,
+    node: Arc<Vx0Node>,
+    bgp: Arc<BGPDaemon>,
+}
+
+impl StatsRecorder {
+    pub fn new(
+        config: &RecorderConfig,
+        node: Arc<Vx0Node>,
+        bgp: Arc<BGPDaemon>,
+    ) -> Result<Self, MonitoringError> {
+        let ring = StatsRing::open(&config.path, config.max_size_bytes.get())?;
+        Ok(StatsRecorder {
+            ring: Arc::new(Mutex::new(ring)),
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            node,
+            bgp,
+        })
+    }
+
+    pub async fn start(self) -> Result<(), MonitoringError> {
+        tracing::info!("Recording peer stats every {}s", self.interval.as_secs());
+
+        let recorder = Arc::new(self);
+        crash::spawn_restartable(Subsystem::Monitoring, "stats-recorder", move || {
+            let recorder = Arc::clone(&recorder);
+            async move {
+                let mut interval = tokio::time::interval(recorder.interval);
+                let mut last_bytes: HashMap<NodeId, (u64, u64)> = HashMap::new();
+
+                loop {
+                    interval.tick().await;
+
+                    let records = recorder.sample(&mut last_bytes).await;
+                    if records.is_empty() {
+                        continue;
+                    }
+
+                    let ring = Arc::clone(&recorder.ring);
+                    let written = tokio::task::spawn_blocking(move || {
+                        let mut ring = lock(&ring);
+                        records.iter().try_for_each(|record| ring.append(record))
+                    })
+                    .await;
+
+                    match written {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => tracing::warn!("Failed to record stats: {}", e),
+                        Err(e) => tracing::warn!("Stats writer task failed: {}", e),
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn sample(&self, last_bytes: &mut HashMap<NodeId, (u64, u64)>) -> Vec<StatsRecord> {
+        let now = Utc::now();
+        let mut records = Vec::new();
+        let mut seen = HashMap::new();
+
+        for handle in self.node.peer_handles().await {
+            let Ok(peer) = handle.snapshot().await else {
+                continue;
+            };
+            let tunnel_up = matches!(handle.tunnel().await, Ok(Some(_)));
+            let route_count = self.bgp.routes_from(peer.peer_asn).await as u32;
+
+            let totals = (peer.metrics.bytes_sent, peer.metrics.bytes_received);
+            let (prev_sent, prev_received) =
+                last_bytes.get(&peer.peer_id).copied().unwrap_or((0, 0));
+            seen.insert(peer.peer_id, totals);
+
+            records.push(StatsRecord {
+                timestamp: now,
+                peer_asn: peer.peer_asn,
+                peer_id: peer.peer_id,
+                rtt_ms: peer.metrics.latency_ms.min(u32::MAX as u64) as u32,
+                packet_loss: peer.metrics.packet_loss,
+                bytes_sent: totals.0.saturating_sub(prev_sent),
+                bytes_received: totals.1.saturating_sub(prev_received),
+                route_count,
+                session_state: peer.status,
+                tunnel_up,
+            });
+        }
+
+        *last_bytes = seen;
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(peer_asn: u32, timestamp: DateTime<Utc>) -> StatsRecord {
+        StatsRecord {
+            timestamp,
+            peer_asn,
+            peer_id: Uuid::new_v4(),
+            rtt_ms: 12,
+            packet_loss: 0.5,
+            bytes_sent: 1000,
+            bytes_received: 2000,
+            route_count: 3,
+            session_state: ConnectionStatus::Connected,
+            tunnel_up: true,
+        }
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vx0net-stats-{}.ring", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_wraparound_keeps_newest() {
+        let path = temp_path();
+        let start = Utc::now();
+        let mut ring = StatsRing::open(&path, 4 * RECORD_SIZE as u64).unwrap();
+
+        for i in 0..10 {
+            ring.append(&record(66000 + i, start)).unwrap();
+        }
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            4 * RECORD_SIZE as u64
+        );
+
+        let asns: Vec<u32> = StatsRing::read_all(&path)
+            .unwrap()
+            .iter()
+            .map(|r| r.peer_asn)
+            .collect();
+        assert_eq!(asns, vec![66006, 66007, 66008, 66009]);
+
+        // Reopening resumes after the newest record instead of slot 0
+        drop(ring);
+        let mut ring = StatsRing::open(&path, 4 * RECORD_SIZE as u64).unwrap();
+        ring.append(&record(66010, start)).unwrap();
+        let asns: Vec<u32> = StatsRing::read_all(&path)
+            .unwrap()
+            .iter()
+            .map(|r| r.peer_asn)
+            .collect();
+        assert_eq!(asns, vec![66007, 66008, 66009, 66010]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_since_and_peer_filter() {
+        let path = temp_path();
+        let now = Utc::now();
+        let mut ring = StatsRing::open(&path, 64 * RECORD_SIZE as u64).unwrap();
+
+        ring.append(&record(66001, now - chrono::Duration::hours(3)))
+            .unwrap();
+        ring.append(&record(66001, now - chrono::Duration::hours(1)))
+            .unwrap();
+        ring.append(&record(66002, now - chrono::Duration::minutes(30)))
+            .unwrap();
+
+        let since = now - parse_since("2h").unwrap();
+        assert_eq!(
+            StatsRing::read_window(&path, Some(since), None)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            StatsRing::read_window(&path, Some(since), Some(66001))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            StatsRing::read_window(&path, None, Some(66001))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(parse_since("2x").is_err());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_partial_records_skipped() {
+        let path = temp_path();
+        let now = Utc::now();
+        let mut ring = StatsRing::open(&path, 8 * RECORD_SIZE as u64).unwrap();
+        ring.append(&record(66001, now)).unwrap();
+        ring.append(&record(66002, now)).unwrap();
+        drop(ring);
+
+        // A crash partway through the third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record(66003, now).encode(2)[..RECORD_SIZE / 2])
+            .unwrap();
+        drop(file);
+        assert_eq!(StatsRing::read_all(&path).unwrap().len(), 2);
+
+        // A torn write inside the file is caught by the checksum
+        let mut contents = std::fs::read(&path).unwrap();
+        contents[RECORD_SIZE + 20] ^= 0xff;
+        std::fs::write(&path, &contents).unwrap();
+        let records = StatsRing::read_all(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].peer_asn, 66001);
+
+        // The writer resumes after the last good record, overwriting the tail
+        let mut ring = StatsRing::open(&path, 8 * RECORD_SIZE as u64).unwrap();
+        ring.append(&record(66004, now)).unwrap();
+        let asns: Vec<u32> = StatsRing::read_all(&path)
+            .unwrap()
+            .iter()
+            .map(|r| r.peer_asn)
+            .collect();
+        assert_eq!(asns, vec![66001, 66004]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,203 @@
+//! Replay of a capture against an isolated node.
+//!
+//! The node is built like the captured one but never binds a socket. BGP
+//! messages go through the handling a session gives them, zone transfers
+//! are applied to a fresh directory, and control commands run through a
+//! control server no client can reach. Joins, announcements and tunnel
+//! payloads are counted but change nothing, since the isolated node keeps
+//! no peers. Replay can stop after any record, leaving the node to answer
+//! control commands such as `routes` and `explain_route` for inspection.
+
+use crate::config::{ControlConfig, Vx0Config};
+use crate::control::{ControlRequest, ControlResponse, ControlServer};
+use crate::monitoring::capture::{CaptureHeader, CaptureRecord, CapturedEvent};
+use crate::network::bgp::{BGPDaemon, BGPError};
+use crate::network::dns::{SharedDns, Vx0DNS};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How fast records are fed in relative to how they were captured
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Speed {
+    /// Each record as soon as the previous one is done
+    #[default]
+    Max,
+    /// Captured gaps divided by this
+    Times(f64),
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    /// `max`, or a factor such as `10x` or `0.5`
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        if input.eq_ignore_ascii_case("max") {
+            return Ok(Speed::Max);
+        }
+        match input.trim_end_matches(['x', 'X']).parse::<f64>() {
+            Ok(factor) if factor.is_finite() && factor > 0.0 => Ok(Speed::Times(factor)),
+            _ => Err(format!(
+                "Invalid replay speed {}: expected e.g. 10x, 0.5x or max",
+                input
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::Max => write!(f, "max"),
+            Speed::Times(factor) => write!(f, "{}x", factor),
+        }
+    }
+}
+
+/// What a replay did with the records it was given
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    pub applied: usize,
+    /// Recorded, but nothing on the isolated node handles them
+    pub skipped: usize,
+    /// Records whose handling failed, by sequence number, with why; the
+    /// captured node may have failed them too
+    pub failed: Vec<(u64, String)>,
+    /// The last record fed in
+    pub last_seq: Option<u64>,
+}
+
+pub struct Replay {
+    header: CaptureHeader,
+    bgp: Arc<BGPDaemon>,
+    directory: SharedDns,
+    control: ControlServer,
+    speed: Speed,
+}
+
+impl Replay {
+    /// An isolated node like the one captured, with the routing policy of
+    /// `config` if given and the defaults otherwise
+    pub async fn new(header: CaptureHeader, config: Option<&Vx0Config>) -> Result<Self, BGPError> {
+        let mut bgp = BGPDaemon::new(header.asn, header.router_id, 0);
+        if let Some(config) = config {
+            bgp.set_originate_default_to_edge(config.network.routing.originate_default_to_edge);
+            bgp.set_import_rules(&config.network.bgp.policy_fragment())
+                .await?;
+            bgp.set_community_policies(config.network.bgp.community_policies.clone())
+                .await?;
+            bgp.set_peering(
+                config.network.peering.clone(),
+                config.network.routing.local_preference,
+            )
+            .await?;
+            bgp.set_multihoming(config.network.bgp.multihoming.clone())
+                .await?;
+        }
+        let bgp = Arc::new(bgp);
+
+        let directory = Vx0DNS::new().into_shared();
+        let control = ControlServer::new(ControlConfig::default(), Arc::clone(&bgp));
+        control.set_directory(Arc::clone(&directory));
+        Ok(Replay {
+            header,
+            bgp,
+            directory,
+            control,
+            speed: Speed::Max,
+        })
+    }
+
+    pub fn with_speed(mut self, speed: Speed) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn header(&self) -> &CaptureHeader {
+        &self.header
+    }
+
+    pub fn bgp(&self) -> &Arc<BGPDaemon> {
+        &self.bgp
+    }
+
+    pub fn directory(&self) -> &SharedDns {
+        &self.directory
+    }
+
+    /// Feed `records` in order, stopping after sequence number `until`
+    pub async fn run(&self, records: &[CaptureRecord], until: Option<u64>) -> ReplayReport {
+        let mut report = ReplayReport::default();
+        let start = Instant::now();
+        let first = records.first().map_or(0, |record| record.offset_ms);
+
+        for record in records {
+            if until.is_some_and(|until| record.seq > until) {
+                break;
+            }
+            if let Speed::Times(factor) = self.speed {
+                let gap = record.offset_ms.saturating_sub(first) as f64 / factor;
+                tokio::time::sleep_until(start + Duration::from_secs_f64(gap / 1000.0)).await;
+            }
+            match self.apply(record).await {
+                Ok(true) => report.applied += 1,
+                Ok(false) => report.skipped += 1,
+                Err(reason) => report.failed.push((record.seq, reason)),
+            }
+            report.last_seq = Some(record.seq);
+        }
+        report
+    }
+
+    /// Handle one record; `Ok(false)` when nothing here handles its kind
+    pub async fn apply(&self, record: &CaptureRecord) -> Result<bool, String> {
+        match &record.event {
+            CapturedEvent::Bgp {
+                peer_asn, message, ..
+            } => self
+                .bgp
+                .receive_message(*peer_asn, message)
+                .await
+                .map(|()| true)
+                .map_err(|e| e.to_string()),
+            CapturedEvent::ZoneTransfer { transfer, .. } => self
+                .directory
+                .write()
+                .await
+                .apply_transfer(transfer.clone())
+                .map(|()| true)
+                .map_err(|e| e.to_string()),
+            CapturedEvent::Control { request } => {
+                match self.control.handle(request.clone()).await {
+                    ControlResponse::Error { message, .. } => Err(message),
+                    _ => Ok(true),
+                }
+            }
+            CapturedEvent::Join { .. } | CapturedEvent::TunnelPayload { .. } => Ok(false),
+            #[cfg(feature = "discovery")]
+            CapturedEvent::Announcement { .. } => Ok(false),
+        }
+    }
+
+    /// Ask the replayed node, as `vx0net` asks a running daemon
+    pub async fn control(&self, request: ControlRequest) -> ControlResponse {
+        self.control.handle(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!("10x".parse::<Speed>(), Ok(Speed::Times(10.0)));
+        assert_eq!("0.5".parse::<Speed>(), Ok(Speed::Times(0.5)));
+        assert_eq!("MAX".parse::<Speed>(), Ok(Speed::Max));
+        assert!("0x".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+    }
+}
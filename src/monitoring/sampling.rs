@@ -0,0 +1,262 @@
+//! Sampling for log lines that repeat at high volume.
+//!
+//! Each call site logs its first few occurrences in every minute, then one
+//! in every so many. A site that had been suppressing says how many lines
+//! it dropped when its minute is up, whether or not it logs again; the
+//! sampler's task reports sites that went quiet. Changing the log level
+//! starts every site over. Wrap the logging call in
+//! [`sampled!`](crate::sampled), which keys the site by module and line:
+//!
+//! ```ignore
+//! vx0net_daemon::sampled!(tracing::debug!("Sending keepalive to {}", peer));
+//! ```
+
+use crate::monitoring::register_metric;
+use crate::monitoring::{crash, Subsystem};
+use crate::util::sync::lock;
+use prometheus::{IntCounterVec, Opts};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// How a site's repeats are thinned out
+#[derive(Debug, Clone, Copy)]
+pub struct SamplingPolicy {
+    /// Occurrences always logged before sampling begins
+    pub first: u64,
+    /// After that, one occurrence in this many is logged
+    pub every: u64,
+    /// How long a site's window lasts; each starts the count over and
+    /// reports what the last one suppressed
+    pub window: Duration,
+}
+
+impl Default for SamplingPolicy {
+    fn default() -> Self {
+        SamplingPolicy {
+            first: 10,
+            every: 100,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What to do with one occurrence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub emit: bool,
+    /// Lines the site's last window suppressed, when this one opened it
+    pub summary: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Site {
+    seen: u64,
+    suppressed: u64,
+    window_start: Instant,
+}
+
+#[derive(Debug)]
+pub struct LogSampler {
+    policy: SamplingPolicy,
+    sites: Mutex<HashMap<&'static str, Site>>,
+}
+
+impl LogSampler {
+    pub fn new(policy: SamplingPolicy) -> Self {
+        LogSampler {
+            policy,
+            sites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The process-wide sampler used by `sampled!`
+    pub fn global() -> &'static LogSampler {
+        static GLOBAL: OnceLock<LogSampler> = OnceLock::new();
+        GLOBAL.get_or_init(|| LogSampler::new(SamplingPolicy::default()))
+    }
+
+    pub fn check(&self, site: &'static str) -> Decision {
+        self.check_at(site, Instant::now())
+    }
+
+    pub fn check_at(&self, site: &'static str, now: Instant) -> Decision {
+        let mut sites = lock(&self.sites);
+        let state = sites.entry(site).or_insert(Site {
+            seen: 0,
+            suppressed: 0,
+            window_start: now,
+        });
+        let mut summary = None;
+        if now.saturating_duration_since(state.window_start) >= self.policy.window {
+            state.window_start = now;
+            state.seen = 0;
+            summary = Some(std::mem::take(&mut state.suppressed)).filter(|&n| n > 0);
+        }
+        state.seen += 1;
+
+        let past_first = state.seen - self.policy.first.min(state.seen);
+        let emit = past_first == 0 || past_first.is_multiple_of(self.policy.every.max(1));
+        if !emit {
+            state.suppressed += 1;
+            suppressed_counter().with_label_values(&[site]).inc();
+        }
+        Decision { emit, summary }
+    }
+
+    /// Close every window that is up, returning what each suppressed; a
+    /// site that logs again opens a new one
+    pub fn flush_at(&self, now: Instant) -> Vec<(&'static str, u64)> {
+        let mut summaries = Vec::new();
+        lock(&self.sites).retain(|&site, state| {
+            if now.saturating_duration_since(state.window_start) < self.policy.window {
+                return true;
+            }
+            if state.suppressed > 0 {
+                summaries.push((site, state.suppressed));
+            }
+            false
+        });
+        summaries.sort_unstable();
+        summaries
+    }
+
+    /// Start every site over, as after the log filter changes
+    pub fn reset(&self) {
+        lock(&self.sites).clear();
+    }
+
+    /// Report what sites suppressed once their windows are up, so a burst
+    /// that stops still gets its summary
+    pub fn start_summaries(&'static self) {
+        crash::spawn_restartable(Subsystem::Monitoring, "log-sampler", move || async move {
+            let mut interval = tokio::time::interval(self.policy.window);
+            loop {
+                interval.tick().await;
+                for (site, suppressed) in self.flush_at(Instant::now()) {
+                    tracing::info!("Suppressed {} similar messages from {}", suppressed, site);
+                }
+            }
+        });
+    }
+}
+
+/// Lines dropped by sampling, by call site
+pub fn suppressed_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new(
+                "vx0net_log_lines_suppressed_total",
+                "Log lines dropped by sampling",
+            ),
+            &["site"],
+        ))
+    })
+}
+
+/// Log through the global sampler, keyed by this call site
+#[macro_export]
+macro_rules! sampled {
+    ($($log:tt)+) => {{
+        let site = concat!(module_path!(), ":", line!());
+        let decision = $crate::monitoring::sampling::LogSampler::global().check(site);
+        if let Some(suppressed) = decision.summary {
+            tracing::info!("Suppressed {} similar messages from {}", suppressed, site);
+        }
+        if decision.emit {
+            $($log)+;
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_lines_then_one_in_n_with_summaries() {
+        let sampler = LogSampler::new(SamplingPolicy {
+            first: 3,
+            every: 5,
+            window: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        let site = "test:keepalive";
+        let before = suppressed_counter().with_label_values(&[site]).get();
+
+        let decisions: Vec<bool> = (0..13)
+            .map(|_| sampler.check_at(site, start).emit)
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                true, true, true, false, false, false, false, true, false, false, false, false,
+                true
+            ]
+        );
+        let emitted = (13..20)
+            .filter(|_| sampler.check_at(site, start).emit)
+            .count();
+        assert_eq!(emitted, 1);
+        assert_eq!(sampler.check_at(site, start).summary, None);
+
+        // The next window reports everything the last one dropped and
+        // logs its first lines again
+        let later = start + Duration::from_secs(61);
+        let decision = sampler.check_at(site, later);
+        assert_eq!(decision.summary, Some(15));
+        assert!(decision.emit);
+        let emitted: Vec<bool> = (0..3).map(|_| sampler.check_at(site, later).emit).collect();
+        assert_eq!(emitted, [true, true, false]);
+        assert_eq!(sampler.check_at(site, later).summary, None);
+
+        // A window that dropped nothing has nothing to report
+        let quiet = LogSampler::new(SamplingPolicy::default());
+        quiet.check_at(site, start);
+        assert_eq!(quiet.check_at(site, later).summary, None);
+
+        // Other sites are counted separately
+        assert!(sampler.check_at("test:other", later).emit);
+
+        // A reset starts the site over
+        sampler.reset();
+        let emitted: Vec<bool> = (0..4).map(|_| sampler.check_at(site, later).emit).collect();
+        assert_eq!(emitted, [true, true, true, false]);
+
+        assert_eq!(
+            suppressed_counter().with_label_values(&[site]).get() - before,
+            18
+        );
+    }
+
+    #[test]
+    fn test_flush_reports_sites_that_went_quiet() {
+        let sampler = LogSampler::new(SamplingPolicy {
+            first: 1,
+            every: 100,
+            window: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        for _ in 0..5 {
+            sampler.check_at("test:burst", start);
+        }
+        sampler.check_at("test:single", start);
+
+        // Nothing is due while the window lasts
+        assert_eq!(sampler.flush_at(start + Duration::from_secs(30)), []);
+        let later = start + Duration::from_secs(61);
+        assert_eq!(sampler.flush_at(later), [("test:burst", 4)]);
+
+        // Reported once; the next line opens a new window
+        assert_eq!(sampler.flush_at(later), []);
+        let decision = sampler.check_at("test:burst", later);
+        assert_eq!(
+            decision,
+            Decision {
+                emit: true,
+                summary: None
+            }
+        );
+    }
+}
@@ -0,0 +1,446 @@
+//! Why the daemon last stopped, kept across restarts.
+//!
+//! Every controlled exit writes a `ShutdownRecord`, and a panic unwinding
+//! through the `ShutdownGuard` writes one too. While the daemon runs a PID
+//! file marks it live, so a start that still finds the PID file knows the
+//! previous run was killed outright (OOM killer, SIGKILL) before it could
+//! write anything. Unclean exits within a window are counted; past a
+//! threshold the next start is delayed with exponential backoff, so a
+//! crash-looping unit doesn't hammer its peers with reconnects.
+//!
+//! The record and the exit history live in the store; the PID file stays a
+//! plain file in `state_dir` for service managers to read.
+
+use crate::config::ShutdownConfig;
+use crate::error::Report;
+use crate::monitoring::crash::{self, Supervisor};
+use crate::monitoring::timing::{PhaseTiming, Stage};
+use crate::monitoring::MonitoringError;
+use crate::storage::{Namespace, Store};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Store namespace holding the record and the exit history
+pub const NAMESPACE: &str = "shutdown";
+const RECORD_KEY: &str = "last_record";
+const UNCLEAN_EXITS_KEY: &str = "unclean_exits";
+const PID_FILE: &str = "vx0net.pid";
+/// Where earlier versions kept the record and history, in `state_dir`
+const LEGACY_FILES: &[(&str, &str)] = &[
+    (RECORD_KEY, "last-shutdown.json"),
+    (UNCLEAN_EXITS_KEY, "unclean-exits.json"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownReason {
+    /// Stopped by a signal from the operator or service manager
+    OperatorStop,
+    /// A subsystem failed in a way the daemon cannot run without
+    FatalError,
+    Panic,
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ShutdownReason::OperatorStop => "operator stop",
+            ShutdownReason::FatalError => "fatal error",
+            ShutdownReason::Panic => "panic",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShutdownRecord {
+    pub reason: ShutdownReason,
+    pub timestamp: DateTime<Utc>,
+    pub uptime_secs: u64,
+    /// Subsystem that brought the daemon down, when one did
+    pub component: Option<String>,
+    pub message: Option<String>,
+    pub recent_log: Vec<String>,
+    /// How long each phase of the shutdown took, if it got that far
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl fmt::Display for ShutdownRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {} after {}s",
+            self.reason,
+            self.timestamp.to_rfc3339(),
+            self.uptime_secs
+        )?;
+        if let Some(component) = &self.component {
+            write!(f, " in {}", component)?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the run before this one ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreviousRun {
+    FirstStart,
+    Clean(ShutdownRecord),
+    /// Exited through a panic or fatal error, and said so
+    Unclean(ShutdownRecord),
+    /// Left its PID file behind without writing a record
+    Killed {
+        pid: Option<u32>,
+        /// The record from the run before that, if any
+        earlier: Option<ShutdownRecord>,
+    },
+}
+
+impl PreviousRun {
+    pub fn is_unclean(&self) -> bool {
+        matches!(self, PreviousRun::Unclean(_) | PreviousRun::Killed { .. })
+    }
+}
+
+/// What a start found, and how long it should wait before going on
+#[derive(Debug, Clone, PartialEq)]
+pub struct StartupReport {
+    pub previous: PreviousRun,
+    /// Unclean exits within the crash-loop window, the previous one included
+    pub unclean_exits: usize,
+    pub delay: Option<Duration>,
+}
+
+/// Shutdown records and unclean-exit history in the store, and the PID file
+#[derive(Debug, Clone)]
+pub struct ShutdownLog {
+    config: ShutdownConfig,
+    dir: PathBuf,
+    state: Namespace,
+}
+
+impl ShutdownLog {
+    pub fn new(config: &ShutdownConfig, store: &Store) -> Self {
+        ShutdownLog {
+            config: config.clone(),
+            dir: PathBuf::from(&config.state_dir),
+            state: store.namespace(NAMESPACE),
+        }
+    }
+
+    /// The record the last exit left, if it could be read
+    pub fn last_record(&self) -> Option<ShutdownRecord> {
+        self.read(RECORD_KEY)
+    }
+
+    /// PID of the running daemon, or of one that died without cleaning up
+    pub fn pid(&self) -> Option<u32> {
+        let pid = std::fs::read_to_string(self.dir.join(PID_FILE)).ok()?;
+        pid.trim().parse().ok()
+    }
+
+    /// Unclean exits still inside the crash-loop window
+    pub fn unclean_exits(&self, now: DateTime<Utc>) -> usize {
+        self.recent_unclean_exits(now).len()
+    }
+
+    /// Classify how the previous run ended, update the unclean-exit
+    /// history, and mark this run live with a fresh PID file
+    pub fn begin(&self, now: DateTime<Utc>) -> Result<StartupReport, MonitoringError> {
+        std::fs::create_dir_all(&self.dir)?;
+        for (key, file) in LEGACY_FILES {
+            if self.state.get::<serde_json::Value>(key)?.is_none() {
+                self.state.adopt_file(key, &self.dir.join(file))?;
+            }
+        }
+
+        let pid_file = self.dir.join(PID_FILE);
+        let previous = if pid_file.exists() {
+            PreviousRun::Killed {
+                pid: self.pid(),
+                earlier: self.last_record(),
+            }
+        } else {
+            match self.last_record() {
+                None => PreviousRun::FirstStart,
+                Some(record) if record.reason == ShutdownReason::OperatorStop => {
+                    PreviousRun::Clean(record)
+                }
+                Some(record) => PreviousRun::Unclean(record),
+            }
+        };
+
+        let mut exits = match previous {
+            PreviousRun::Clean(_) => Vec::new(),
+            _ => self.recent_unclean_exits(now),
+        };
+        if previous.is_unclean() {
+            exits.push(now);
+        }
+        self.state.put(UNCLEAN_EXITS_KEY, &exits)?;
+        write_atomic(&pid_file, std::process::id().to_string().as_bytes())?;
+
+        Ok(StartupReport {
+            delay: self.backoff(exits.len()),
+            unclean_exits: exits.len(),
+            previous,
+        })
+    }
+
+    /// Guard that records the exit of the run `begin` started
+    pub fn arm(&self) -> ShutdownGuard {
+        // So a panic's message is there to record
+        crash::install_panic_hook();
+        ShutdownGuard {
+            log: self.clone(),
+            started: Instant::now(),
+            finished: false,
+        }
+    }
+
+    fn recent_unclean_exits(&self, now: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let window = chrono::Duration::seconds(self.config.crash_loop_window_secs as i64);
+        let exits: Vec<DateTime<Utc>> = self.read(UNCLEAN_EXITS_KEY).unwrap_or_default();
+        exits
+            .into_iter()
+            .filter(|exit| now - *exit < window)
+            .collect()
+    }
+
+    fn read<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.state.get(key).unwrap_or_else(|e| {
+            tracing::warn!("Ignoring unreadable shutdown state: {}", Report(&e));
+            None
+        })
+    }
+
+    fn backoff(&self, unclean_exits: usize) -> Option<Duration> {
+        let threshold = self.config.crash_loop_threshold.max(1);
+        if unclean_exits < threshold {
+            return None;
+        }
+        let doublings = (unclean_exits - threshold).min(16) as u32;
+        let secs = self
+            .config
+            .backoff_base_secs
+            .saturating_mul(1 << doublings)
+            .min(self.config.backoff_max_secs);
+        Some(Duration::from_secs(secs))
+    }
+
+    fn record(&self, record: &ShutdownRecord) -> Result<(), MonitoringError> {
+        self.state.put(RECORD_KEY, record)?;
+        match std::fs::remove_file(self.dir.join(PID_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the shutdown record when the daemon exits
+///
+/// Controlled exits call `finish`. If the guard is dropped without that,
+/// as when a panic unwinds through it, it records the exit itself.
+#[derive(Debug)]
+pub struct ShutdownGuard {
+    log: ShutdownLog,
+    started: Instant,
+    finished: bool,
+}
+
+impl ShutdownGuard {
+    pub fn finish(
+        mut self,
+        reason: ShutdownReason,
+        component: Option<&str>,
+        message: Option<String>,
+    ) -> Result<(), MonitoringError> {
+        self.finished = true;
+        self.write(reason, component.map(str::to_string), message)
+    }
+
+    fn write(
+        &self,
+        reason: ShutdownReason,
+        component: Option<String>,
+        message: Option<String>,
+    ) -> Result<(), MonitoringError> {
+        self.log.record(&ShutdownRecord {
+            reason,
+            timestamp: Utc::now(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            component,
+            message,
+            recent_log: Supervisor::global().recent_log(self.log.config.log_lines),
+            phases: Supervisor::global()
+                .timing(Stage::Shutdown)
+                .map(|report| report.phases)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let (reason, message) = if std::thread::panicking() {
+            (ShutdownReason::Panic, crash::last_panic())
+        } else {
+            (ShutdownReason::FatalError, None)
+        };
+        if let Err(e) = self.write(reason, Some("main".to_string()), message) {
+            eprintln!("Failed to write shutdown record: {}", e);
+        }
+    }
+}
+
+/// Replace a file so readers see either the old or the new contents
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> ShutdownLog {
+        let dir = std::env::temp_dir().join(format!("vx0net-shutdown-{}", uuid::Uuid::new_v4()));
+        let store = Store::open(dir.join("store")).unwrap();
+        ShutdownLog::new(
+            &ShutdownConfig {
+                state_dir: dir.to_string_lossy().into_owned(),
+                crash_loop_threshold: 2,
+                backoff_base_secs: 10,
+                backoff_max_secs: 25,
+                ..ShutdownConfig::default()
+            },
+            &store,
+        )
+    }
+
+    #[test]
+    fn test_clean_stop_and_panic_are_classified_on_next_start() {
+        let log = log();
+        let now = Utc::now();
+        assert_eq!(log.begin(now).unwrap().previous, PreviousRun::FirstStart);
+        assert_eq!(log.pid(), Some(std::process::id()));
+
+        log.arm()
+            .finish(ShutdownReason::OperatorStop, None, None)
+            .unwrap();
+        assert_eq!(log.pid(), None);
+        let report = log.begin(now).unwrap();
+        assert!(matches!(report.previous, PreviousRun::Clean(_)));
+        assert_eq!((report.unclean_exits, report.delay), (0, None));
+
+        // A panic unwinding through the guard still leaves a record
+        let guarded = log.clone();
+        std::thread::spawn(move || {
+            let _guard = guarded.arm();
+            panic!("subsystem invariant broken");
+        })
+        .join()
+        .unwrap_err();
+        let record = log.last_record().unwrap();
+        assert_eq!(record.reason, ShutdownReason::Panic);
+        assert_eq!(record.component.as_deref(), Some("main"));
+        assert!(record
+            .message
+            .unwrap()
+            .contains("subsystem invariant broken"));
+
+        let report = log.begin(now).unwrap();
+        assert!(matches!(
+            report.previous,
+            PreviousRun::Unclean(ShutdownRecord {
+                reason: ShutdownReason::Panic,
+                ..
+            })
+        ));
+        assert_eq!((report.unclean_exits, report.delay), (1, None));
+        let _ = std::fs::remove_dir_all(&log.dir);
+    }
+
+    #[test]
+    fn test_killed_runs_are_detected_and_crash_loops_back_off() {
+        let log = log();
+        let at = |minutes| Utc::now() + chrono::Duration::minutes(minutes);
+        log.begin(at(0)).unwrap();
+        log.arm()
+            .finish(ShutdownReason::OperatorStop, None, None)
+            .unwrap();
+        assert!(matches!(
+            log.begin(at(1)).unwrap().previous,
+            PreviousRun::Clean(_)
+        ));
+
+        // From here every run is killed before it can write a record
+        let report = log.begin(at(2)).unwrap();
+        assert_eq!(
+            report.previous,
+            PreviousRun::Killed {
+                pid: Some(std::process::id()),
+                earlier: log.last_record(),
+            }
+        );
+        assert_eq!((report.unclean_exits, report.delay), (1, None));
+        let delays: Vec<Option<Duration>> = (3..=4)
+            .map(|minute| log.begin(at(minute)).unwrap().delay)
+            .collect();
+        assert_eq!(
+            delays,
+            vec![Some(Duration::from_secs(10)), Some(Duration::from_secs(20))]
+        );
+
+        // No record at all, only a stale PID file
+        assert!(log.state.delete(RECORD_KEY).unwrap());
+        std::fs::write(log.dir.join(PID_FILE), "4242").unwrap();
+        let report = log.begin(at(5)).unwrap();
+        assert_eq!(
+            report.previous,
+            PreviousRun::Killed {
+                pid: Some(4242),
+                earlier: None
+            }
+        );
+        assert_eq!(report.delay, Some(Duration::from_secs(25)));
+
+        // Outside the window the history no longer counts
+        let report = log.begin(at(120)).unwrap();
+        assert_eq!((report.unclean_exits, report.delay), (1, None));
+        let _ = std::fs::remove_dir_all(&log.dir);
+    }
+
+    #[test]
+    fn test_state_from_earlier_versions_is_moved_into_the_store() {
+        let log = log();
+        std::fs::create_dir_all(&log.dir).unwrap();
+        let record = ShutdownRecord {
+            reason: ShutdownReason::OperatorStop,
+            timestamp: Utc::now(),
+            uptime_secs: 60,
+            component: None,
+            message: None,
+            recent_log: Vec::new(),
+            phases: Vec::new(),
+        };
+        let legacy = log.dir.join("last-shutdown.json");
+        std::fs::write(&legacy, serde_json::to_vec(&record).unwrap()).unwrap();
+
+        let report = log.begin(Utc::now()).unwrap();
+        assert_eq!(report.previous, PreviousRun::Clean(record.clone()));
+        assert!(!legacy.exists());
+        assert_eq!(log.last_record(), Some(record));
+        let _ = std::fs::remove_dir_all(&log.dir);
+    }
+}
@@ -0,0 +1,553 @@
+//! Every spawned task by name, so a stuck shutdown says what it waits on.
+//!
+//! The `Supervisor` registers each task it spawns with a name and the
+//! subsystem it belongs to. The registry tracks whether the task is running,
+//! stopping, completed or panicked, and when it was last polled: a task
+//! stuck in a blocking call stays inside one poll, so the last poll is a
+//! best-effort heartbeat and a long poll marks a wedged thread.
+//!
+//! Shutdown goes through the subsystems in `Subsystem` order, cancelling
+//! each one's token and waiting for its tasks. Ordinary tasks are dropped
+//! at their next await once the token fires; graceful ones are handed the
+//! token and finish on their own. A task still running after its timeout
+//! is logged by name and aborted, and if any were, the daemon exits with
+//! `STUCK_SHUTDOWN_EXIT_CODE` instead of hanging.
+
+use crate::monitoring::timing::{PhaseTimer, Stage};
+use crate::monitoring::MonitoringError;
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+/// How long a task may take to stop once its subsystem is cancelled,
+/// unless configured otherwise
+pub const DEFAULT_TASK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exit status when shutdown gives up on stuck tasks (EX_SOFTWARE)
+pub const STUCK_SHUTDOWN_EXIT_CODE: u8 = 70;
+
+/// Finished tasks kept for `vx0net status --tasks`
+const FINISHED_KEPT: usize = 32;
+
+/// What a task belongs to; shutdown stops subsystems in this order
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// Operator commands go first, so nothing new starts mid-shutdown
+    Control,
+    Services,
+    Node,
+    Dns,
+    Bgp,
+    Ike,
+    Kernel,
+    /// The watchdog and stats last, so they cover the rest of the shutdown
+    Monitoring,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 8] = [
+        Subsystem::Control,
+        Subsystem::Services,
+        Subsystem::Node,
+        Subsystem::Dns,
+        Subsystem::Bgp,
+        Subsystem::Ike,
+        Subsystem::Kernel,
+        Subsystem::Monitoring,
+    ];
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Subsystem::Control => "control",
+            Subsystem::Services => "services",
+            Subsystem::Node => "node",
+            Subsystem::Dns => "dns",
+            Subsystem::Bgp => "bgp",
+            Subsystem::Ike => "ike",
+            Subsystem::Kernel => "kernel",
+            Subsystem::Monitoring => "monitoring",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// Its subsystem was cancelled and shutdown is waiting for it
+    Stopping,
+    Completed,
+    Panicked,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TaskState::Running => "running",
+            TaskState::Stopping => "stopping",
+            TaskState::Completed => "completed",
+            TaskState::Panicked => "panicked",
+        })
+    }
+}
+
+/// A task as the registry last saw it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: String,
+    pub subsystem: Subsystem,
+    pub state: TaskState,
+    pub started_at: DateTime<Utc>,
+    /// When the task was last polled; `None` before its first poll
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    /// Set while the task is inside a poll; long ago means it is blocking
+    /// its thread
+    #[serde(default)]
+    pub busy_since: Option<DateTime<Utc>>,
+    /// Times a restartable task came back after a panic
+    #[serde(default)]
+    pub restarts: u32,
+}
+
+impl TaskInfo {
+    pub fn age(&self, now: DateTime<Utc>) -> chrono::Duration {
+        now - self.started_at
+    }
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{} ({}", self.subsystem, self.name, self.state)?;
+        match self.last_heartbeat {
+            Some(at) => write!(f, ", last heartbeat {}", at.to_rfc3339())?,
+            None => f.write_str(", never polled")?,
+        }
+        if let Some(since) = self.busy_since {
+            write!(f, ", busy since {}", since.to_rfc3339())?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// What shutdown managed to stop
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    pub stopped: usize,
+    /// Tasks still running past their timeout, in the order they were
+    /// given up on
+    pub stuck: Vec<TaskInfo>,
+}
+
+impl ShutdownReport {
+    /// The status to force the process out with, if tasks were left behind
+    pub fn exit_code(&self) -> Option<u8> {
+        (!self.stuck.is_empty()).then_some(STUCK_SHUTDOWN_EXIT_CODE)
+    }
+
+    pub fn into_result(self) -> Result<(), MonitoringError> {
+        if self.stuck.is_empty() {
+            return Ok(());
+        }
+        Err(MonitoringError::StuckShutdown {
+            tasks: self.stuck.iter().map(ToString::to_string).collect(),
+        })
+    }
+}
+
+/// The registry's view of one spawned task
+#[derive(Debug)]
+pub(crate) struct TaskRecord {
+    id: u64,
+    name: String,
+    subsystem: Subsystem,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    timeout: Duration,
+    state: Mutex<TaskState>,
+    /// Milliseconds after `started` the last poll began, plus one so that
+    /// 0 means never
+    last_poll: AtomicU64,
+    in_poll: AtomicBool,
+    restarts: AtomicU32,
+    abort: OnceLock<AbortHandle>,
+    done: watch::Sender<bool>,
+    token: CancellationToken,
+}
+
+impl TaskRecord {
+    /// Fires when the task's subsystem is shut down
+    pub(crate) fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub(crate) fn panicked(&self) {
+        *lock(&self.state) = TaskState::Panicked;
+    }
+
+    pub(crate) fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn set_stopping(&self) {
+        let mut state = lock(&self.state);
+        if *state == TaskState::Running {
+            *state = TaskState::Stopping;
+        }
+    }
+
+    /// How long the poll under way has lasted, on the monotonic clock
+    fn busy_for(&self) -> Option<Duration> {
+        if !self.in_poll.load(Ordering::Relaxed) {
+            return None;
+        }
+        let began = self.last_poll.load(Ordering::Relaxed).checked_sub(1)?;
+        Some(
+            self.started
+                .elapsed()
+                .saturating_sub(Duration::from_millis(began)),
+        )
+    }
+
+    fn info(&self) -> TaskInfo {
+        let last_poll = self.last_poll.load(Ordering::Relaxed);
+        let last_heartbeat = last_poll
+            .checked_sub(1)
+            .map(|millis| self.started_at + chrono::Duration::milliseconds(millis as i64));
+        TaskInfo {
+            id: self.id,
+            name: self.name.clone(),
+            subsystem: self.subsystem,
+            state: *lock(&self.state),
+            started_at: self.started_at,
+            last_heartbeat,
+            busy_since: last_heartbeat.filter(|_| self.in_poll.load(Ordering::Relaxed)),
+            restarts: self.restarts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A spawned future that reports its polls and leaves the registry when it
+/// is dropped, whether it finished, was cancelled or was aborted
+struct Tracked<F: Future> {
+    registry: Weak<TaskRegistry>,
+    record: Arc<TaskRecord>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let millis = this.record.started.elapsed().as_millis() as u64;
+        this.record.last_poll.store(millis + 1, Ordering::Relaxed);
+        this.record.in_poll.store(true, Ordering::Relaxed);
+        let poll = this.future.as_mut().poll(cx);
+        this.record.in_poll.store(false, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<F: Future> Drop for Tracked<F> {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry.finish(&self.record);
+        }
+        self.record.done.send_replace(true);
+    }
+}
+
+#[derive(Debug)]
+pub struct TaskRegistry {
+    live: Mutex<BTreeMap<u64, Arc<TaskRecord>>>,
+    finished: Mutex<VecDeque<TaskInfo>>,
+    tokens: Mutex<HashMap<Subsystem, CancellationToken>>,
+    next_id: AtomicU64,
+    timeout: Mutex<Duration>,
+}
+
+impl Default for TaskRegistry {
+    fn default() -> Self {
+        TaskRegistry::new()
+    }
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        TaskRegistry {
+            live: Mutex::new(BTreeMap::new()),
+            finished: Mutex::new(VecDeque::with_capacity(FINISHED_KEPT)),
+            tokens: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            timeout: Mutex::new(DEFAULT_TASK_TIMEOUT),
+        }
+    }
+
+    /// How long tasks spawned from now on get to stop at shutdown
+    pub fn set_task_timeout(&self, timeout: Duration) {
+        *lock(&self.timeout) = timeout;
+    }
+
+    /// Spawn `make`'s future as a task named `name`; `timeout` overrides
+    /// the default time it gets to stop
+    pub(crate) fn spawn<F, Fut>(
+        self: &Arc<Self>,
+        subsystem: Subsystem,
+        name: &str,
+        timeout: Option<Duration>,
+        make: F,
+    ) -> JoinHandle<Fut::Output>
+    where
+        F: FnOnce(Arc<TaskRecord>) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let record = Arc::new(TaskRecord {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            name: name.to_string(),
+            subsystem,
+            started_at: Utc::now(),
+            started: Instant::now(),
+            timeout: timeout.unwrap_or_else(|| *lock(&self.timeout)),
+            state: Mutex::new(TaskState::Running),
+            last_poll: AtomicU64::new(0),
+            in_poll: AtomicBool::new(false),
+            restarts: AtomicU32::new(0),
+            abort: OnceLock::new(),
+            done: watch::Sender::new(false),
+            token: self.token(subsystem),
+        });
+        lock(&self.live).insert(record.id, Arc::clone(&record));
+
+        let handle = tokio::spawn(Tracked {
+            registry: Arc::downgrade(self),
+            future: Box::pin(make(Arc::clone(&record))),
+            record: Arc::clone(&record),
+        });
+        let _ = record.abort.set(handle.abort_handle());
+        handle
+    }
+
+    /// The token cancelled when `subsystem` shuts down
+    pub fn token(&self, subsystem: Subsystem) -> CancellationToken {
+        lock(&self.tokens).entry(subsystem).or_default().clone()
+    }
+
+    /// Tasks still running, by subsystem and then age
+    pub fn live(&self) -> Vec<TaskInfo> {
+        let mut tasks: Vec<TaskInfo> = lock(&self.live)
+            .values()
+            .map(|record| record.info())
+            .collect();
+        tasks.sort_by_key(|task| (task.subsystem, task.started_at, task.id));
+        tasks
+    }
+
+    /// The most recently finished tasks, oldest first
+    pub fn finished(&self) -> Vec<TaskInfo> {
+        lock(&self.finished).iter().cloned().collect()
+    }
+
+    /// Live tasks that have been inside one poll for longer than
+    /// `longer_than`, so are blocking the thread they run on
+    pub fn blocking(&self, longer_than: Duration) -> Vec<TaskInfo> {
+        let mut tasks: Vec<TaskInfo> = lock(&self.live)
+            .values()
+            .filter(|record| record.busy_for().is_some_and(|busy| busy > longer_than))
+            .map(|record| record.info())
+            .collect();
+        tasks.sort_by_key(|task| (task.subsystem, task.started_at, task.id));
+        tasks
+    }
+
+    fn finish(&self, record: &TaskRecord) {
+        lock(&self.live).remove(&record.id);
+        {
+            let mut state = lock(&record.state);
+            if *state != TaskState::Panicked {
+                *state = TaskState::Completed;
+            }
+        }
+        let mut finished = lock(&self.finished);
+        if finished.len() == FINISHED_KEPT {
+            finished.pop_front();
+        }
+        finished.push_back(record.info());
+    }
+
+    /// Cancel each subsystem in turn and wait for its tasks, giving up on
+    /// those that outlive their timeout
+    pub async fn shutdown(&self) -> ShutdownReport {
+        self.shutdown_timed(&PhaseTimer::new(Stage::Shutdown)).await
+    }
+
+    /// Shut down as `shutdown` does, timing each subsystem that had tasks
+    /// as a phase of `timer`
+    pub async fn shutdown_timed(&self, timer: &PhaseTimer) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        for subsystem in Subsystem::ALL {
+            let tasks: Vec<Arc<TaskRecord>> = lock(&self.live)
+                .values()
+                .filter(|record| record.subsystem == subsystem)
+                .cloned()
+                .collect();
+            for task in &tasks {
+                task.set_stopping();
+            }
+            self.token(subsystem).cancel();
+            if tasks.is_empty() {
+                continue;
+            }
+            tracing::debug!("Stopping {} {} tasks", tasks.len(), subsystem);
+            let started = tokio::time::Instant::now();
+
+            let waits = tasks.iter().map(|task| async move {
+                let mut done = task.done.subscribe();
+                let stopped = tokio::time::timeout(task.timeout, done.wait_for(|done| *done))
+                    .await
+                    .is_ok();
+                (task, stopped)
+            });
+            for (task, stopped) in futures::future::join_all(waits).await {
+                if stopped {
+                    report.stopped += 1;
+                    continue;
+                }
+                let info = task.info();
+                tracing::error!(
+                    "Task {} did not stop within {:?} of {} shutting down: {}",
+                    task.name,
+                    task.timeout,
+                    subsystem,
+                    info
+                );
+                if let Some(abort) = task.abort.get() {
+                    abort.abort();
+                }
+                report.stuck.push(info);
+            }
+            timer.timed(&format!("{}-tasks", subsystem), started.elapsed());
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::Supervisor;
+
+    #[tokio::test]
+    async fn test_shutdown_order_and_stuck_tasks() {
+        let supervisor = Supervisor::new();
+        let tasks = supervisor.tasks();
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        // Cooperative: finish cleanly once told to, in both subsystems
+        for (subsystem, name) in [
+            (Subsystem::Bgp, "bgp-flush"),
+            (Subsystem::Control, "control-drain"),
+        ] {
+            let events = Arc::clone(&events);
+            supervisor.spawn_graceful(
+                subsystem,
+                name,
+                Duration::from_secs(5),
+                move |token| async move {
+                    token.cancelled().await;
+                    lock(&events).push(name);
+                },
+            );
+        }
+        // Dropped at its await when the DNS subsystem stops
+        supervisor.spawn(Subsystem::Dns, "dns-idle", std::future::pending::<()>());
+        // Uncooperative: ignores the token
+        supervisor.spawn_graceful(
+            Subsystem::Ike,
+            "ike-stuck",
+            Duration::from_millis(100),
+            |_token| async {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            },
+        );
+        tokio::task::yield_now().await;
+
+        let live: Vec<String> = tasks.live().into_iter().map(|task| task.name).collect();
+        assert_eq!(
+            live,
+            ["control-drain", "dns-idle", "bgp-flush", "ike-stuck"]
+        );
+
+        let report = tasks.shutdown().await;
+        assert_eq!(*lock(&events), ["control-drain", "bgp-flush"]);
+        assert_eq!(report.stopped, 3);
+        assert_eq!(report.stuck.len(), 1);
+        let stuck = &report.stuck[0];
+        assert_eq!(
+            (stuck.name.as_str(), stuck.subsystem),
+            ("ike-stuck", Subsystem::Ike)
+        );
+        assert_eq!(stuck.state, TaskState::Stopping);
+        assert!(stuck.last_heartbeat.is_some());
+        assert!(stuck
+            .to_string()
+            .starts_with("ike/ike-stuck (stopping, last heartbeat"));
+
+        // The daemon is forced out with its own status
+        assert_eq!(report.exit_code(), Some(STUCK_SHUTDOWN_EXIT_CODE));
+        match report.into_result() {
+            Err(MonitoringError::StuckShutdown { tasks }) => {
+                assert_eq!(tasks.len(), 1);
+                assert!(tasks[0].contains("ike-stuck"));
+            }
+            other => panic!("expected a stuck shutdown, got {:?}", other),
+        }
+
+        // The stuck task was aborted afterwards; nothing is left running
+        tokio::task::yield_now().await;
+        assert!(tasks.live().is_empty());
+        assert!(tasks
+            .finished()
+            .iter()
+            .all(|task| task.state == TaskState::Completed));
+        assert!(tasks.shutdown().await.exit_code().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_tasks_blocking_their_thread_are_spotted() {
+        let supervisor = Supervisor::new();
+        let blocked = supervisor.spawn(Subsystem::Node, "blocks", async {
+            std::thread::sleep(Duration::from_millis(400));
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let blocking = supervisor.tasks().blocking(Duration::from_millis(100));
+        assert_eq!(blocking.len(), 1);
+        assert_eq!(blocking[0].name, "blocks");
+        assert!(blocking[0].busy_since.is_some());
+
+        blocked.await.unwrap();
+        assert!(supervisor.tasks().blocking(Duration::ZERO).is_empty());
+        let finished = supervisor.tasks().finished();
+        assert_eq!(finished[0].state, TaskState::Completed);
+        assert_eq!(finished[0].busy_since, None);
+    }
+}
@@ -0,0 +1,353 @@
+//! How long each phase of startup and shutdown took.
+//!
+//! Startup and shutdown each run a `PhaseTimer`. Every phase ends with a
+//! lap that records how long it took since the previous one, warns by name
+//! if it went past its soft limit from `monitoring.timing`, and sets the
+//! `vx0net_phase_seconds` gauge for it. Timings are kept whatever the log
+//! filter, so `vx0net status --startup` and the shutdown record have them
+//! even when the warnings were never printed.
+//!
+//! A finished report is logged as one line, and the daemon keeps it on its
+//! supervisor.
+
+use crate::config::TimingConfig;
+use crate::monitoring::register_metric;
+use crate::util::sync::lock;
+use prometheus::{GaugeVec, Opts};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use tokio::time::{Duration, Instant};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Startup,
+    Shutdown,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Startup => "startup",
+            Stage::Shutdown => "shutdown",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PhaseTiming {
+    pub phase: String,
+    pub duration_ms: u64,
+    /// Took longer than its soft limit
+    #[serde(default)]
+    pub slow: bool,
+}
+
+/// Every phase of a startup or shutdown, in the order they ran
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TimingReport {
+    pub stage: Stage,
+    pub total_ms: u64,
+    pub phases: Vec<PhaseTiming>,
+}
+
+impl TimingReport {
+    pub fn phase(&self, name: &str) -> Option<&PhaseTiming> {
+        self.phases.iter().find(|timing| timing.phase == name)
+    }
+
+    pub fn slow(&self) -> impl Iterator<Item = &PhaseTiming> {
+        self.phases.iter().filter(|timing| timing.slow)
+    }
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} complete in {}", self.stage, format_ms(self.total_ms))?;
+        for (i, timing) in self.phases.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { ", " };
+            write!(
+                f,
+                "{}{} {}",
+                separator,
+                timing.phase,
+                format_ms(timing.duration_ms)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `10ms` below a second, `4.2s` above
+pub fn format_ms(ms: u64) -> String {
+    if ms < 1000 {
+        format!("{}ms", ms)
+    } else {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    }
+}
+
+#[derive(Debug)]
+struct Laps {
+    last: Instant,
+    phases: Vec<PhaseTiming>,
+}
+
+/// Times the phases of one startup or shutdown as they end
+#[derive(Debug)]
+pub struct PhaseTimer {
+    stage: Stage,
+    warn_after: Duration,
+    limits: HashMap<String, Duration>,
+    started: Instant,
+    laps: Mutex<Laps>,
+}
+
+impl PhaseTimer {
+    pub fn new(stage: Stage) -> Self {
+        let started = Instant::now();
+        PhaseTimer {
+            stage,
+            warn_after: Duration::from_millis(TimingConfig::default().warn_after_ms),
+            limits: HashMap::new(),
+            started,
+            laps: Mutex::new(Laps {
+                last: started,
+                phases: Vec::new(),
+            }),
+        }
+    }
+
+    /// Take soft limits from the configuration, for phases ending from now on
+    pub fn set_limits(&mut self, config: &TimingConfig) {
+        self.warn_after = Duration::from_millis(config.warn_after_ms);
+        self.limits = config
+            .phase_warn_after_ms
+            .iter()
+            .map(|(phase, ms)| (phase.clone(), Duration::from_millis(*ms)))
+            .collect();
+    }
+
+    pub fn with_limits(mut self, config: &TimingConfig) -> Self {
+        self.set_limits(config);
+        self
+    }
+
+    /// End `phase`, which began when the previous one ended; returns how
+    /// long it took
+    pub fn lap(&self, phase: &str) -> Duration {
+        let now = Instant::now();
+        let duration = now - lock(&self.laps).last;
+        self.record(phase, duration, true);
+        duration
+    }
+
+    /// End a phase spent waiting on purpose, which is never warned about
+    pub fn waited(&self, phase: &str) -> Duration {
+        let now = Instant::now();
+        let duration = now - lock(&self.laps).last;
+        self.record(phase, duration, false);
+        duration
+    }
+
+    /// Record a phase timed elsewhere that ends now
+    pub fn timed(&self, phase: &str, duration: Duration) {
+        self.record(phase, duration, true);
+    }
+
+    fn record(&self, phase: &str, duration: Duration, checked: bool) {
+        let limit = self.limits.get(phase).copied().unwrap_or(self.warn_after);
+        let slow = checked && duration > limit;
+        if slow {
+            tracing::warn!(
+                "{} phase {} took {}, over its {} limit",
+                self.stage,
+                phase,
+                format_ms(millis(duration)),
+                format_ms(millis(limit))
+            );
+        }
+        phase_gauge()
+            .with_label_values(&[&self.stage.to_string(), phase])
+            .set(duration.as_secs_f64());
+
+        let mut laps = lock(&self.laps);
+        laps.last = Instant::now();
+        laps.phases.push(PhaseTiming {
+            phase: phase.to_string(),
+            duration_ms: millis(duration),
+            slow,
+        });
+    }
+
+    /// Every phase so far, and the time since the timer started
+    pub fn report(&self) -> TimingReport {
+        TimingReport {
+            stage: self.stage,
+            total_ms: millis(self.started.elapsed()),
+            phases: lock(&self.laps).phases.clone(),
+        }
+    }
+
+    /// Log the report as one line and set the total gauge
+    pub fn finish(&self) -> TimingReport {
+        let report = self.report();
+        phase_gauge()
+            .with_label_values(&[&self.stage.to_string(), "total"])
+            .set(report.total_ms as f64 / 1000.0);
+        tracing::info!("{}", report);
+        report
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Seconds each startup and shutdown phase took, by stage and phase
+pub fn phase_gauge() -> &'static GaugeVec {
+    static GAUGE: OnceLock<GaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(GaugeVec::new(
+            Opts::new(
+                "vx0net_phase_seconds",
+                "Seconds the last startup or shutdown spent in each phase",
+            ),
+            &["stage", "phase"],
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::{Subsystem, Supervisor};
+    use tokio::time::sleep;
+
+    fn limits() -> TimingConfig {
+        TimingConfig {
+            warn_after_ms: 200,
+            phase_warn_after_ms: HashMap::from([("bootstrap".to_string(), 1000)]),
+        }
+    }
+
+    /// Took at least `ms`, and not much longer
+    fn about(timing: &PhaseTiming, ms: u64) -> bool {
+        (ms..ms + 150).contains(&timing.duration_ms)
+    }
+
+    #[tokio::test]
+    async fn test_startup_phases_and_slow_warnings() {
+        let timer = PhaseTimer::new(Stage::Startup).with_limits(&limits());
+        let script = [
+            ("config", 10),
+            ("route-load", 20),
+            ("bootstrap", 300),
+            ("bgp-bind", 250),
+        ];
+        for (phase, ms) in script {
+            sleep(Duration::from_millis(ms)).await;
+            timer.lap(phase);
+        }
+        sleep(Duration::from_millis(300)).await;
+        timer.waited("crash-loop-delay");
+
+        let report = timer.finish();
+        let phases: Vec<&str> = report
+            .phases
+            .iter()
+            .map(|timing| timing.phase.as_str())
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                "config",
+                "route-load",
+                "bootstrap",
+                "bgp-bind",
+                "crash-loop-delay"
+            ]
+        );
+        for (phase, ms) in script.into_iter().chain([("crash-loop-delay", 300)]) {
+            assert!(about(report.phase(phase).unwrap(), ms), "{}", report);
+        }
+        let phases_ms: u64 = report.phases.iter().map(|timing| timing.duration_ms).sum();
+        assert!(report.total_ms >= phases_ms);
+
+        // Only the phase over its own limit is slow: bootstrap has a higher
+        // one, and deliberate waits are never warned about
+        let slow: Vec<&str> = report.slow().map(|timing| timing.phase.as_str()).collect();
+        assert_eq!(slow, ["bgp-bind"]);
+        assert!(
+            phase_gauge()
+                .with_label_values(&["startup", "bootstrap"])
+                .get()
+                >= 0.3
+        );
+    }
+
+    #[test]
+    fn test_report_reads_as_one_line() {
+        let phase = |phase: &str, duration_ms| PhaseTiming {
+            phase: phase.to_string(),
+            duration_ms,
+            slow: false,
+        };
+        let report = TimingReport {
+            stage: Stage::Startup,
+            total_ms: 4217,
+            phases: vec![
+                phase("config", 10),
+                phase("identity", 5),
+                phase("route-load", 900),
+                phase("join", 3100),
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "startup complete in 4.2s: config 10ms, identity 5ms, route-load 900ms, join 3.1s"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_times_each_subsystem() {
+        let supervisor = Supervisor::new();
+        let tasks = supervisor.tasks();
+        for (subsystem, ms) in [(Subsystem::Node, 50), (Subsystem::Bgp, 400)] {
+            supervisor.spawn_graceful(
+                subsystem,
+                "slow-stop",
+                Duration::from_secs(10),
+                move |token| async move {
+                    token.cancelled().await;
+                    sleep(Duration::from_millis(ms)).await;
+                },
+            );
+        }
+        tokio::task::yield_now().await;
+
+        let timer = PhaseTimer::new(Stage::Shutdown).with_limits(&limits());
+        let stopped = tasks.shutdown_timed(&timer).await;
+        assert_eq!(stopped.stopped, 2);
+
+        // Subsystems without tasks are not phases
+        let report = timer.finish();
+        let phases: Vec<&str> = report
+            .phases
+            .iter()
+            .map(|timing| timing.phase.as_str())
+            .collect();
+        assert_eq!(phases, ["node-tasks", "bgp-tasks"]);
+        let (node, bgp) = (
+            report.phase("node-tasks").unwrap(),
+            report.phase("bgp-tasks").unwrap(),
+        );
+        assert!(about(node, 50) && !node.slow, "{}", report);
+        assert!(about(bgp, 400) && bgp.slow, "{}", report);
+    }
+}
@@ -0,0 +1,550 @@
+//! Operator-maintained access control for peers.
+//!
+//! Entries name a peer by ASN, address prefix or node id. In blocklist mode
+//! (the default) anything matching a blocked entry is refused; in allowlist
+//! mode a contact must also match an allowed entry. Every ingress point
+//! (inbound BGP connections, IKE packets, join requests, peer additions,
+//! node announcements and received routes) checks the ACL before doing any
+//! work, and each refusal is counted and written to the audit log.
+//!
+//! Entries blocked at runtime are persisted to `state_file` so they survive
+//! restarts; entries from the config file can only be removed there.
+
+use crate::config::AclConfig;
+use crate::node::NodeId;
+use crate::util::sync::{read, write};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclMode {
+    #[default]
+    Blocklist,
+    Allowlist,
+}
+
+/// One identity an operator can block or allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum AclEntry {
+    Asn(u32),
+    Prefix(IpNet),
+    Node(NodeId),
+}
+
+impl AclEntry {
+    pub fn matches(&self, contact: &Contact) -> bool {
+        match self {
+            AclEntry::Asn(asn) => contact.asn == Some(*asn),
+            AclEntry::Prefix(prefix) => contact.addr.is_some_and(|addr| prefix.contains(&addr)),
+            AclEntry::Node(node_id) => contact.node_id == Some(*node_id),
+        }
+    }
+}
+
+impl FromStr for AclEntry {
+    type Err = AclError;
+
+    /// Accepts `AS66001` or `66001`, an address or prefix, or a node id
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s
+            .strip_prefix("AS")
+            .or_else(|| s.strip_prefix("as"))
+            .unwrap_or(s);
+        if let Ok(asn) = digits.parse::<u32>() {
+            return Ok(AclEntry::Asn(asn));
+        }
+        if let Ok(prefix) = s.parse::<IpNet>() {
+            return Ok(AclEntry::Prefix(prefix));
+        }
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(AclEntry::Prefix(IpNet::from(addr)));
+        }
+        if let Ok(node_id) = s.parse::<NodeId>() {
+            return Ok(AclEntry::Node(node_id));
+        }
+        Err(AclError::InvalidEntry(s.to_string()))
+    }
+}
+
+impl TryFrom<String> for AclEntry {
+    type Error = AclError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<AclEntry> for String {
+    fn from(entry: AclEntry) -> Self {
+        entry.to_string()
+    }
+}
+
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclEntry::Asn(asn) => write!(f, "AS{}", asn),
+            AclEntry::Prefix(prefix) => write!(f, "{}", prefix),
+            AclEntry::Node(node_id) => write!(f, "{}", node_id),
+        }
+    }
+}
+
+/// What is known about whoever is knocking; unknown parts match nothing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Contact {
+    pub asn: Option<u32>,
+    pub addr: Option<IpAddr>,
+    pub node_id: Option<NodeId>,
+}
+
+impl Contact {
+    pub fn address(addr: IpAddr) -> Self {
+        Contact {
+            addr: Some(addr),
+            ..Contact::default()
+        }
+    }
+
+    pub fn asn(asn: u32) -> Self {
+        Contact {
+            asn: Some(asn),
+            ..Contact::default()
+        }
+    }
+
+    pub fn node(node_id: NodeId, asn: u32, addr: IpAddr) -> Self {
+        Contact {
+            asn: Some(asn),
+            addr: Some(addr),
+            node_id: Some(node_id),
+        }
+    }
+}
+
+impl fmt::Display for Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(node_id) = self.node_id {
+            parts.push(node_id.to_string());
+        }
+        if let Some(asn) = self.asn {
+            parts.push(format!("AS{}", asn));
+        }
+        if let Some(addr) = self.addr {
+            parts.push(addr.to_string());
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AclError {
+    #[error("{0:?} is not an ASN, address, prefix or node id")]
+    InvalidEntry(String),
+    #[error("{entry} is blocked in the config file and can only be removed there")]
+    Configured { entry: AclEntry },
+    #[error("Cannot persist the blocklist to {path}")]
+    Persist {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Default)]
+pub struct Acl {
+    mode: AclMode,
+    /// Blocked in the config file
+    configured: Vec<AclEntry>,
+    allowed: Vec<AclEntry>,
+    /// Blocked at runtime, persisted to `state_file`
+    runtime: RwLock<Vec<AclEntry>>,
+    state_file: Option<PathBuf>,
+    denied: AtomicU64,
+}
+
+impl Acl {
+    /// Build the ACL from config, restoring entries blocked at runtime
+    pub fn open(config: &AclConfig) -> Result<Self, AclError> {
+        let state_file = config.state_file.as_ref().map(PathBuf::from);
+        let runtime = match &state_file {
+            Some(path) if path.exists() => {
+                let data = std::fs::read(path).map_err(|source| AclError::Persist {
+                    path: path.clone(),
+                    source,
+                })?;
+                serde_json::from_slice(&data)?
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(Acl {
+            mode: config.mode,
+            configured: config.blocked.clone(),
+            allowed: config.allowed.clone(),
+            runtime: RwLock::new(runtime),
+            state_file,
+            denied: AtomicU64::new(0),
+        })
+    }
+
+    pub fn mode(&self) -> AclMode {
+        self.mode
+    }
+
+    /// Whether `contact` matches a blocked entry, whatever the mode
+    pub fn blocks(&self, contact: &Contact) -> bool {
+        self.configured.iter().any(|entry| entry.matches(contact))
+            || self
+                .runtime_entries()
+                .iter()
+                .any(|entry| entry.matches(contact))
+    }
+
+    /// Whether `contact` may talk to us at all
+    pub fn permits(&self, contact: &Contact) -> bool {
+        match self.mode {
+            AclMode::Blocklist => !self.blocks(contact),
+            AclMode::Allowlist => {
+                !self.blocks(contact) && self.allowed.iter().any(|entry| entry.matches(contact))
+            }
+        }
+    }
+
+    /// `permits`, counting and auditing a refusal at `ingress`
+    pub fn check(&self, contact: &Contact, ingress: &str) -> bool {
+        self.permits(contact) || self.refuse(contact, ingress)
+    }
+
+    /// Like `check` for parties we only hear of second-hand, such as route
+    /// origins behind a peer: only explicit blocks refuse them
+    pub fn check_origin(&self, contact: &Contact, ingress: &str) -> bool {
+        !self.blocks(contact) || self.refuse(contact, ingress)
+    }
+
+    fn refuse(&self, contact: &Contact, ingress: &str) -> bool {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(target: "audit", "Refused {} from {} by ACL", ingress, contact);
+        false
+    }
+
+    /// How many contacts have been refused since startup
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    /// Every blocked entry, from the config file and from runtime
+    pub fn blocked(&self) -> Vec<AclEntry> {
+        let mut blocked = self.configured.clone();
+        blocked.extend(self.runtime_entries());
+        blocked
+    }
+
+    /// Block `entry` and persist it, returning whether it was newly blocked
+    pub fn block(&self, entry: AclEntry) -> Result<bool, AclError> {
+        if self.configured.contains(&entry) {
+            return Ok(false);
+        }
+        let mut runtime = write(&self.runtime);
+        if runtime.contains(&entry) {
+            return Ok(false);
+        }
+        runtime.push(entry);
+        if let Err(e) = self.persist(&runtime) {
+            runtime.pop();
+            return Err(e);
+        }
+        tracing::warn!(target: "audit", "Blocked {}", entry);
+        Ok(true)
+    }
+
+    /// Unblock a runtime entry, returning whether it was blocked
+    pub fn unblock(&self, entry: AclEntry) -> Result<bool, AclError> {
+        if self.configured.contains(&entry) {
+            return Err(AclError::Configured { entry });
+        }
+        let mut runtime = write(&self.runtime);
+        let Some(index) = runtime.iter().position(|existing| *existing == entry) else {
+            return Ok(false);
+        };
+        runtime.remove(index);
+        if let Err(e) = self.persist(&runtime) {
+            runtime.insert(index, entry);
+            return Err(e);
+        }
+        tracing::warn!(target: "audit", "Unblocked {}", entry);
+        Ok(true)
+    }
+
+    fn runtime_entries(&self) -> Vec<AclEntry> {
+        read(&self.runtime).clone()
+    }
+
+    fn persist(&self, entries: &[AclEntry]) -> Result<(), AclError> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(entries)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)
+            .and_then(|()| std::fs::rename(&tmp, path))
+            .map_err(|source| AclError::Persist {
+                path: path.clone(),
+                source,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    #[test]
+    fn test_entries_parse_and_match() {
+        let node_id = uuid::Uuid::new_v4();
+        let contact = Contact::node(node_id, 66001, "10.2.0.9".parse().unwrap());
+
+        for (text, entry) in [
+            ("AS66001", AclEntry::Asn(66001)),
+            ("66001", AclEntry::Asn(66001)),
+            (
+                "10.2.0.0/24",
+                AclEntry::Prefix("10.2.0.0/24".parse().unwrap()),
+            ),
+            ("10.2.0.9", AclEntry::Prefix("10.2.0.9/32".parse().unwrap())),
+            (&node_id.to_string(), AclEntry::Node(node_id)),
+        ] {
+            let parsed: AclEntry = text.parse().unwrap();
+            assert_eq!(parsed, entry);
+            assert!(parsed.matches(&contact), "{} should match", text);
+        }
+        assert!(!AclEntry::Asn(66002).matches(&contact));
+        assert!("not-a-peer".parse::<AclEntry>().is_err());
+    }
+
+    #[test]
+    fn test_runtime_blocks_persist_and_allowlist_inverts() {
+        let path = std::env::temp_dir().join(format!("vx0net-acl-{}.json", uuid::Uuid::new_v4()));
+        let config = AclConfig {
+            state_file: Some(path.to_string_lossy().into_owned()),
+            blocked: vec![AclEntry::Asn(66666)],
+            ..AclConfig::default()
+        };
+        let acl = Acl::open(&config).unwrap();
+        let peer = Contact::asn(66001);
+        assert!(acl.permits(&peer));
+
+        assert!(acl.block(AclEntry::Asn(66001)).unwrap());
+        assert!(!acl.block(AclEntry::Asn(66001)).unwrap());
+        assert!(!acl.check(&peer, "test"));
+        assert_eq!(acl.denied(), 1);
+        assert!(matches!(
+            acl.unblock(AclEntry::Asn(66666)),
+            Err(AclError::Configured { .. })
+        ));
+
+        // A restart keeps the runtime entry
+        let restored = Acl::open(&config).unwrap();
+        assert!(!restored.permits(&peer));
+        assert!(restored.unblock(AclEntry::Asn(66001)).unwrap());
+        assert!(Acl::open(&config).unwrap().permits(&peer));
+        let _ = std::fs::remove_file(&path);
+
+        let allowlist = Acl::open(&AclConfig {
+            mode: AclMode::Allowlist,
+            allowed: vec![AclEntry::Asn(65101)],
+            state_file: None,
+            ..AclConfig::default()
+        })
+        .unwrap();
+        assert!(allowlist.permits(&Contact::asn(65101)));
+        assert!(!allowlist.permits(&peer));
+    }
+
+    fn node(asn: u32, ip: &str, acl: AclConfig) -> std::sync::Arc<crate::Vx0Node> {
+        testing::node(NodeTier::Edge, |config| {
+            config.node.asn = asn;
+            config.node.tier = "Regional".to_string();
+            config.node.ipv4_address = ip.to_string();
+            config.network.acl = acl;
+        })
+    }
+
+    async fn until_denied(acl: &Acl, count: u64) {
+        for _ in 0..100 {
+            if acl.denied() >= count {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("expected {} refusals, saw {}", count, acl.denied());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_contacts_are_refused_at_every_ingress() {
+        use crate::network::bgp::BGPDaemon;
+        use crate::network::ike::session::IKEDaemon;
+        use crate::node::bootstrap::NodeAnnouncement;
+        use crate::node::joining::JoinRequest;
+        use crate::node::NodeTier;
+
+        let stranger = uuid::Uuid::new_v4();
+        let local = node(
+            65101,
+            "10.1.0.1",
+            AclConfig {
+                blocked: vec![
+                    AclEntry::Prefix("127.0.0.1/32".parse().unwrap()),
+                    AclEntry::Asn(65102),
+                    AclEntry::Node(stranger),
+                ],
+                state_file: None,
+                ..AclConfig::default()
+            },
+        );
+        let acl = std::sync::Arc::clone(&local.acl);
+
+        // BGP connections and IKE packets from a blocked address
+        let mut bgp = BGPDaemon::new(65101, "10.1.0.1".parse().unwrap(), 0);
+        bgp.set_acl(std::sync::Arc::clone(&acl)).await;
+        bgp.start().await.unwrap();
+        let _stream =
+            tokio::net::TcpStream::connect(("127.0.0.1", bgp.local_addr().unwrap().port()))
+                .await
+                .unwrap();
+        until_denied(&acl, 1).await;
+        assert!(bgp.session_peers().await.is_empty());
+
+        let mut ike =
+            IKEDaemon::new("127.0.0.1:0".parse().unwrap()).with_acl(std::sync::Arc::clone(&acl));
+        ike.start().await.unwrap();
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket
+            .send_to(b"IKE_SA_INIT", ike.local_addr().unwrap())
+            .await
+            .unwrap();
+        until_denied(&acl, 2).await;
+
+        // Join requests and announcements from a blocked node id
+        let response = local
+            .answer_join(
+                &JoinRequest {
+                    node_id: stranger,
+                    hostname: "stranger".to_string(),
+                    asn: 65150,
+                    tier: NodeTier::Regional,
+                    public_ip: "10.1.0.50".parse().unwrap(),
+                    requested_services: vec![],
+                    contact_info: None,
+                    build: Default::default(),
+                    timestamp: chrono::Utc::now(),
+                    grant: None,
+                },
+                "10.1.0.50".parse().unwrap(),
+            )
+            .await;
+        assert!(!response.accepted);
+        assert_eq!(acl.denied(), 3);
+
+        let mut announcement = NodeAnnouncement {
+            node_id: stranger,
+            hostname: "stranger".to_string(),
+            asn: 65150,
+            tier: NodeTier::Regional,
+            ipv4_addr: "10.1.0.50".parse().unwrap(),
+            services: vec![],
+            total_services: 0,
+            truncated: false,
+            capabilities: local.capabilities(),
+            build: Default::default(),
+            location: String::new(),
+            network_health: None,
+            timestamp: chrono::Utc::now(),
+        };
+        assert!(!local.observe_announcement(&announcement).await);
+
+        // Peering with a blocked ASN, even under a fresh node id
+        announcement.node_id = uuid::Uuid::new_v4();
+        announcement.asn = 65102;
+        let peer = crate::node::PeerConnection::new(
+            announcement.node_id,
+            announcement.asn,
+            "10.1.0.2".parse().unwrap(),
+        );
+        assert!(matches!(
+            local.add_peer(peer).await,
+            Err(crate::node::NodeError::Blocked { .. })
+        ));
+        assert_eq!(acl.denied(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_tears_down_established_peers() {
+        use crate::network::bgp::protocol::BGPProtocol;
+        use crate::network::bgp::BGPDaemon;
+        use crate::node::NodeTier;
+
+        let local = node(
+            65101,
+            "10.1.0.1",
+            AclConfig {
+                state_file: None,
+                ..AclConfig::default()
+            },
+        );
+        let remote = node(65102, "10.1.0.2", AclConfig::default());
+        local
+            .add_peer(crate::node::PeerConnection::new(
+                remote.node_id,
+                remote.asn,
+                remote.ipv4_addr.into(),
+            ))
+            .await
+            .unwrap();
+
+        let mut bgp = BGPDaemon::new(65101, "10.1.0.1".parse().unwrap(), 0);
+        bgp.set_acl(std::sync::Arc::clone(&local.acl)).await;
+        bgp.start().await.unwrap();
+        let addr = (
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            bgp.local_addr().unwrap().port(),
+        );
+        let (_session, _stream) =
+            BGPProtocol::new(65102, remote.ipv4_addr.into(), NodeTier::Regional)
+                .open_session(addr.into(), 65101)
+                .await
+                .unwrap();
+        for _ in 0..100 {
+            if !bgp.session_peers().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(bgp.session_peers().await.len(), 1);
+
+        // Nothing is refused until the entries land
+        assert_eq!(local.enforce_acl().await, 0);
+        assert_eq!(bgp.enforce_acl().await.unwrap(), 0);
+
+        assert!(local.acl.block(AclEntry::Node(remote.node_id)).unwrap());
+        assert!(local.acl.block("127.0.0.0/8".parse().unwrap()).unwrap());
+        assert_eq!(local.enforce_acl().await, 1);
+        assert_eq!(bgp.enforce_acl().await.unwrap(), 1);
+        assert!(local.list_peers().await.is_empty());
+        assert!(bgp.session_peers().await.is_empty());
+    }
+}
@@ -0,0 +1,547 @@
+//! Keepalive, echo and announcement intervals that stretch on links that
+//! stay up.
+//!
+//! Every link starts on its tier's floor intervals. For each `settle`
+//! period it goes without a flap the intervals double, up to the tier's
+//! ceiling; each probe lost within `loss_memory` costs one doubling. A flap
+//! or a lost probe puts the link back on the floor at once, and wakes
+//! whatever is waiting out a stretched interval so it can probe sooner. A
+//! link is timed by the lower tier of its two ends, so a link with an Edge
+//! node at either end gets the Edge bounds.
+//!
+//! Discovery announcements back off the same way while the set of known
+//! peers is saturated and stays the same, and return to the floor when it
+//! changes.
+//!
+//! What the timers send is counted by kind, and over the last hour in
+//! `vx0net_control_traffic_bytes_per_hour`, so the savings show.
+
+use crate::config::{AdaptiveTimersConfig, TimerBounds};
+use crate::monitoring::register_metric;
+use crate::node::NodeTier;
+use crate::util::clock::{self, SharedClock};
+use crate::util::sync::lock;
+use prometheus::{IntCounterVec, IntGauge, Opts};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Bytes of a BGP KEEPALIVE on the wire
+pub const KEEPALIVE_BYTES: usize = 19;
+
+/// Bytes of an empty transport frame: length, tag and flags
+pub const ECHO_BYTES: usize = 6;
+
+/// What a timer sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Probe {
+    /// BGP keepalives
+    Keepalive,
+    /// Empty frames on idle transport connections
+    Echo,
+    /// Discovery announcements
+    Announce,
+}
+
+impl Probe {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Probe::Keepalive => "keepalive",
+            Probe::Echo => "echo",
+            Probe::Announce => "announce",
+        }
+    }
+
+    fn bounds(&self, bounds: &TimerBounds) -> (Duration, Duration) {
+        let (floor, ceiling) = match self {
+            Probe::Keepalive => (bounds.keepalive_floor, bounds.keepalive_ceiling),
+            Probe::Echo => (bounds.echo_floor, bounds.echo_ceiling),
+            Probe::Announce => (bounds.announce_floor, bounds.announce_ceiling),
+        };
+        (floor.get(), ceiling.get().max(floor.get()))
+    }
+}
+
+/// Bytes keepalives, echoes and announcements sent, by kind
+fn traffic_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new(
+                "vx0net_control_traffic_bytes_total",
+                "Bytes sent by keepalives, transport echoes and discovery announcements",
+            ),
+            &["kind"],
+        ))
+    })
+}
+
+/// The same over the last hour
+fn traffic_gauge() -> &'static IntGauge {
+    static GAUGE: OnceLock<IntGauge> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(IntGauge::with_opts(Opts::new(
+            "vx0net_control_traffic_bytes_per_hour",
+            "Bytes sent by keepalives, transport echoes and discovery announcements in the last hour",
+        )))
+    })
+}
+
+/// Count `bytes` sent by a `probe` at `now`
+pub fn record_traffic(probe: Probe, bytes: usize, now: Instant) {
+    static WINDOW: OnceLock<Mutex<TrafficWindow>> = OnceLock::new();
+    traffic_counter()
+        .with_label_values(&[probe.as_str()])
+        .inc_by(bytes as u64);
+    let total = lock(WINDOW.get_or_init(Mutex::default)).add(bytes as u64, now);
+    traffic_gauge().set(total.try_into().unwrap_or(i64::MAX));
+}
+
+/// Bytes sent in each minute of the last hour
+#[derive(Debug, Default)]
+struct TrafficWindow {
+    minutes: VecDeque<(Instant, u64)>,
+}
+
+impl TrafficWindow {
+    const MINUTE: Duration = Duration::from_secs(60);
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    /// Add `bytes` sent at `now`, returning the total for the hour to `now`
+    fn add(&mut self, bytes: u64, now: Instant) -> u64 {
+        match self.minutes.back_mut() {
+            // Within the latest minute; an earlier reading lands there too
+            Some((start, total)) if now < *start + Self::MINUTE => *total += bytes,
+            _ => self.minutes.push_back((now, bytes)),
+        }
+        while self
+            .minutes
+            .front()
+            .is_some_and(|(start, _)| now.saturating_duration_since(*start) >= Self::HOUR)
+        {
+            self.minutes.pop_front();
+        }
+        self.minutes.iter().map(|(_, total)| total).sum()
+    }
+}
+
+/// A link's timers as peer status shows them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct LinkTimers {
+    pub keepalive_secs: u64,
+    pub echo_secs: u64,
+    /// Since the link last flapped or lost a probe
+    pub stable_secs: u64,
+    /// Probes lost within `loss_memory`
+    pub recent_losses: u32,
+}
+
+#[derive(Debug)]
+struct Link {
+    tier: NodeTier,
+    /// Last flap or lost probe, or when the link was first seen
+    since: Instant,
+    losses: VecDeque<Instant>,
+}
+
+/// The lower of two tiers; Edge is the lowest
+fn lower(a: &NodeTier, b: &NodeTier) -> NodeTier {
+    match (a, b) {
+        (NodeTier::Edge, _) | (_, NodeTier::Edge) => NodeTier::Edge,
+        (NodeTier::Regional, _) | (_, NodeTier::Regional) => NodeTier::Regional,
+        _ => NodeTier::Backbone,
+    }
+}
+
+/// `floor` doubled `doublings` times, no further than `ceiling`
+fn stretch(floor: Duration, ceiling: Duration, doublings: u32) -> Duration {
+    let mut interval = floor;
+    for _ in 0..doublings {
+        if interval >= ceiling {
+            break;
+        }
+        interval = interval.saturating_mul(2);
+    }
+    interval.min(ceiling)
+}
+
+/// Stability of each link, and the intervals it has earned
+#[derive(Debug)]
+pub struct AdaptiveTimers {
+    config: AdaptiveTimersConfig,
+    local: NodeTier,
+    links: Mutex<HashMap<IpAddr, Link>>,
+    /// Bumped on every flap or lost probe
+    anomalies: watch::Sender<u64>,
+    clock: SharedClock,
+}
+
+impl AdaptiveTimers {
+    pub fn new(config: AdaptiveTimersConfig, local: NodeTier) -> Self {
+        AdaptiveTimers {
+            config,
+            local,
+            links: Mutex::new(HashMap::new()),
+            anomalies: watch::Sender::new(0),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    fn with_link<T>(&self, peer: IpAddr, f: impl FnOnce(&mut Link, Instant) -> T) -> T {
+        let now = self.clock.now_monotonic();
+        let mut links = lock(&self.links);
+        let link = links.entry(peer).or_insert_with(|| Link {
+            tier: self.local.clone(),
+            since: now,
+            losses: VecDeque::new(),
+        });
+        let memory = self.config.loss_memory.get();
+        while link
+            .losses
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= memory)
+        {
+            link.losses.pop_front();
+        }
+        f(link, now)
+    }
+
+    fn earned(&self, link: &Link, probe: Probe, now: Instant) -> Duration {
+        let settle = self.config.settle.get().max(Duration::from_secs(1));
+        let stable = now.saturating_duration_since(link.since);
+        let doublings = (stable.as_secs() / settle.as_secs()).min(u32::MAX.into()) as u32;
+        let (floor, ceiling) = probe.bounds(self.config.bounds(&link.tier));
+        stretch(
+            floor,
+            ceiling,
+            doublings.saturating_sub(link.losses.len() as u32),
+        )
+    }
+
+    /// Time the link to `peer` by the lower of our tier and `peer_asn`'s
+    pub fn track(&self, peer: IpAddr, peer_asn: u32) {
+        let tier = lower(&self.local, &NodeTier::from_asn(peer_asn));
+        self.with_link(peer, |link, _| link.tier = tier);
+    }
+
+    /// How long to wait before the next `probe` on the link to `peer`;
+    /// `None` when adapting is off and the caller's own interval applies
+    pub fn interval(&self, peer: IpAddr, probe: Probe) -> Option<Duration> {
+        if !self.config.enabled {
+            return None;
+        }
+        Some(self.with_link(peer, |link, now| self.earned(link, probe, now)))
+    }
+
+    /// The link to `peer` went down or was found broken
+    pub fn flap(&self, peer: IpAddr) {
+        self.anomaly(peer, false);
+    }
+
+    /// A probe on the link to `peer` went unanswered
+    pub fn lost(&self, peer: IpAddr) {
+        self.anomaly(peer, true);
+    }
+
+    fn anomaly(&self, peer: IpAddr, lost: bool) {
+        if !self.config.enabled {
+            return;
+        }
+        let stretched = self.with_link(peer, |link, now| {
+            let stretched = self.earned(link, Probe::Keepalive, now);
+            link.since = now;
+            if lost {
+                link.losses.push_back(now);
+            }
+            stretched
+        });
+        tracing::debug!(
+            "Link to {} {} after keepalives every {:?}; back to the floor",
+            peer,
+            if lost { "lost a probe" } else { "flapped" },
+            stretched
+        );
+        self.anomalies.send_modify(|count| *count += 1);
+    }
+
+    /// Changes on every flap or lost probe, so waits on a stretched
+    /// interval can be cut short
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.anomalies.subscribe()
+    }
+
+    /// Wait out the link's interval for `probe`, or `fallback` when
+    /// adapting is off; a flap or lost probe that shortens the interval
+    /// ends the wait sooner
+    pub async fn wait(&self, peer: IpAddr, probe: Probe, fallback: Duration) {
+        let start = self.clock.now_monotonic();
+        let mut anomalies = self.subscribe();
+        let mut deadline = start + self.interval(peer, probe).unwrap_or(fallback);
+        loop {
+            tokio::select! {
+                () = self.clock.sleep_until(deadline) => return,
+                changed = anomalies.changed() => {
+                    if changed.is_err() {
+                        return self.clock.sleep_until(deadline).await;
+                    }
+                    let every = self.interval(peer, probe).unwrap_or(fallback);
+                    deadline = deadline.min(start + every);
+                }
+            }
+        }
+    }
+
+    /// Hold time to offer in OPEN: what the operator configured, or more
+    /// if that keeps keepalives from stretching to the ceiling. Hold time
+    /// is only agreed in OPEN, so this is the only chance to raise it.
+    pub fn offered_hold_time(&self, peer_asn: u32, configured: u16) -> u16 {
+        if !self.config.enabled {
+            return configured;
+        }
+        let tier = lower(&self.local, &NodeTier::from_asn(peer_asn));
+        let (_, ceiling) = Probe::Keepalive.bounds(self.config.bounds(&tier));
+        let wanted = ceiling.as_secs().saturating_mul(3);
+        configured.max(wanted.try_into().unwrap_or(u16::MAX))
+    }
+
+    /// The link's current intervals, when adapting is on and the link is
+    /// known
+    pub fn status(&self, peer: IpAddr) -> Option<LinkTimers> {
+        if !self.config.enabled || !lock(&self.links).contains_key(&peer) {
+            return None;
+        }
+        Some(self.with_link(peer, |link, now| LinkTimers {
+            keepalive_secs: self.earned(link, Probe::Keepalive, now).as_secs(),
+            echo_secs: self.earned(link, Probe::Echo, now).as_secs(),
+            stable_secs: now.saturating_duration_since(link.since).as_secs(),
+            recent_losses: link.losses.len() as u32,
+        }))
+    }
+}
+
+/// How often to announce on the local network: stretching while the known
+/// peers are saturated and unchanged, back to the floor when they change
+#[derive(Debug)]
+pub struct AnnounceBackoff {
+    enabled: bool,
+    floor: Duration,
+    ceiling: Duration,
+    saturation: usize,
+    interval: Duration,
+    /// Peers known at the last announcement
+    known: Option<usize>,
+}
+
+impl AnnounceBackoff {
+    /// `fixed` is the interval used when adapting is off. Announcements
+    /// never stretch past a third of `expiry`, how long listeners remember
+    /// a peer, so they are not forgotten.
+    pub fn new(
+        config: &AdaptiveTimersConfig,
+        tier: &NodeTier,
+        fixed: Duration,
+        expiry: Duration,
+    ) -> Self {
+        let (floor, ceiling) = if config.enabled {
+            let (floor, ceiling) = Probe::Announce.bounds(config.bounds(tier));
+            (floor, ceiling.min(expiry / 3).max(floor))
+        } else {
+            (fixed, fixed)
+        };
+        AnnounceBackoff {
+            enabled: config.enabled,
+            floor,
+            ceiling,
+            saturation: config.discovery_saturation,
+            interval: floor,
+            known: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The interval to the next announcement, now that `known` peers are
+    pub fn announced(&mut self, known: usize) -> Duration {
+        let unchanged = self.known == Some(known);
+        self.known = Some(known);
+        self.interval = if self.enabled && unchanged && known >= self.saturation {
+            self.interval.saturating_mul(2).min(self.ceiling)
+        } else {
+            self.floor
+        };
+        self.interval
+    }
+
+    /// The known peers changed since the last announcement; returns the
+    /// interval if it had stretched and is now back on the floor
+    pub fn changed(&mut self) -> Option<Duration> {
+        self.known = None;
+        if self.interval == self.floor {
+            return None;
+        }
+        self.interval = self.floor;
+        Some(self.floor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::clock::ManualClock;
+    use std::sync::Arc;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn timers(clock: &Arc<ManualClock>) -> AdaptiveTimers {
+        let shared: SharedClock = clock.clone();
+        AdaptiveTimers::new(AdaptiveTimersConfig::default(), NodeTier::Edge).with_clock(shared)
+    }
+
+    fn secs(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_stable_link_stretches_to_the_ceiling_and_snaps_back() {
+        let clock = Arc::new(ManualClock::new());
+        let timers = timers(&clock);
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        timers.track(peer, 65100);
+
+        // Edge bounds: 60s up to 10m, doubling every 10 stable minutes
+        assert_eq!(timers.interval(peer, Probe::Keepalive), secs(60));
+        let mut seen = Vec::new();
+        for _ in 0..5 {
+            clock.advance(10 * MINUTE);
+            seen.push(timers.interval(peer, Probe::Keepalive).unwrap().as_secs());
+        }
+        assert_eq!(seen, vec![120, 240, 480, 600, 600]);
+        assert_eq!(timers.status(peer).unwrap().echo_secs, 600);
+
+        let woken = timers.subscribe();
+        timers.flap(peer);
+        assert!(woken.has_changed().unwrap());
+        assert_eq!(timers.interval(peer, Probe::Keepalive), secs(60));
+        assert_eq!(timers.interval(peer, Probe::Echo), secs(60));
+    }
+
+    #[test]
+    fn test_lost_probes_slow_the_climb_until_forgotten() {
+        let clock = Arc::new(ManualClock::new());
+        let timers = timers(&clock);
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        timers.interval(peer, Probe::Keepalive);
+        timers.lost(peer);
+        timers.lost(peer);
+
+        clock.advance(20 * MINUTE);
+        assert_eq!(timers.interval(peer, Probe::Keepalive), secs(60));
+        clock.advance(10 * MINUTE);
+        assert_eq!(timers.interval(peer, Probe::Keepalive), secs(120));
+        assert_eq!(timers.status(peer).unwrap().recent_losses, 2);
+
+        // A day on, the losses no longer count
+        clock.advance(Duration::from_secs(24 * 3600));
+        assert_eq!(timers.interval(peer, Probe::Keepalive), secs(600));
+        assert_eq!(timers.status(peer).unwrap().recent_losses, 0);
+    }
+
+    #[test]
+    fn test_link_is_timed_by_its_lower_tier() {
+        let clock = Arc::new(ManualClock::new());
+        let shared: SharedClock = clock.clone();
+        let timers = AdaptiveTimers::new(AdaptiveTimersConfig::default(), NodeTier::Backbone)
+            .with_clock(shared);
+        let backbone: IpAddr = "10.0.0.2".parse().unwrap();
+        let edge: IpAddr = "10.2.0.1".parse().unwrap();
+        timers.track(backbone, 65001);
+        timers.track(edge, 66001);
+        clock.advance(Duration::from_secs(24 * 3600));
+        assert_eq!(timers.interval(backbone, Probe::Keepalive), secs(60));
+        assert_eq!(timers.interval(edge, Probe::Keepalive), secs(600));
+        assert_eq!(timers.offered_hold_time(66001, 90), 1800);
+        assert_eq!(timers.offered_hold_time(65001, 240), 240);
+    }
+
+    #[test]
+    fn test_disabled_timers_leave_intervals_alone() {
+        let config = AdaptiveTimersConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        let timers = AdaptiveTimers::new(config.clone(), NodeTier::Edge);
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        assert_eq!(timers.interval(peer, Probe::Keepalive), None);
+        assert_eq!(timers.offered_hold_time(65100, 90), 90);
+        assert!(timers.status(peer).is_none());
+
+        let mut backoff = AnnounceBackoff::new(
+            &config,
+            &NodeTier::Edge,
+            Duration::from_secs(30),
+            Duration::from_secs(300),
+        );
+        for _ in 0..4 {
+            assert_eq!(backoff.announced(100), Duration::from_secs(30));
+        }
+    }
+
+    #[test]
+    fn test_announcements_back_off_while_saturated() {
+        let config = AdaptiveTimersConfig::default();
+        let fixed = Duration::from_secs(30);
+        let mut backoff = AnnounceBackoff::new(
+            &config,
+            &NodeTier::Edge,
+            fixed,
+            Duration::from_secs(6 * 3600),
+        );
+        // Too few peers known to back off
+        assert_eq!(backoff.announced(3).as_secs(), 30);
+        assert_eq!(backoff.announced(3).as_secs(), 30);
+
+        let stretched: Vec<u64> = (0..8).map(|_| backoff.announced(20).as_secs()).collect();
+        assert_eq!(stretched, vec![30, 60, 120, 240, 480, 960, 1800, 1800]);
+
+        // A new peer puts announcements back on the floor at once
+        assert_eq!(backoff.changed(), Some(Duration::from_secs(30)));
+        assert_eq!(backoff.changed(), None);
+        assert_eq!(backoff.announced(21).as_secs(), 30);
+
+        // Listeners forgetting peers after five minutes cap the stretch
+        let mut backoff =
+            AnnounceBackoff::new(&config, &NodeTier::Edge, fixed, Duration::from_secs(300));
+        let stretched: Vec<u64> = (0..4).map(|_| backoff.announced(20).as_secs()).collect();
+        assert_eq!(stretched, vec![30, 60, 100, 100]);
+    }
+
+    #[test]
+    fn test_traffic_window_sums_the_last_hour() {
+        let start = Instant::now();
+        let mut window = TrafficWindow::default();
+        assert_eq!(window.add(19, start), 19);
+        assert_eq!(window.add(19, start + Duration::from_secs(30)), 38);
+        assert_eq!(window.add(6, start + 30 * MINUTE), 44);
+        assert_eq!(window.add(6, start + 61 * MINUTE), 12);
+        assert_eq!(window.add(6, start + 120 * MINUTE), 12);
+    }
+}
@@ -0,0 +1,237 @@
+//! Interned AS paths.
+//!
+//! A routing table holds many routes but few distinct paths, so each path is
+//! stored once and shared. Every live [`AsPath`] with the same ASNs points at
+//! the same allocation, which makes equality a pointer comparison. A path
+//! leaves the registry when its last route drops it. On the wire and in JSON
+//! a path is still a plain list of ASNs.
+
+use crate::util::sync::lock;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+#[derive(Clone, JsonSchema)]
+pub struct AsPath(Arc<[u32]>);
+
+/// Registry size below which no sweep is attempted
+const MIN_SWEEP: usize = 1024;
+
+/// Registry of the paths currently in use
+#[derive(Debug)]
+pub struct AsPathInterner {
+    paths: Mutex<Registry>,
+}
+
+#[derive(Debug)]
+struct Registry {
+    paths: HashSet<Arc<[u32]>>,
+    /// Size at which the next sweep runs
+    sweep_at: usize,
+}
+
+impl AsPathInterner {
+    pub fn global() -> &'static AsPathInterner {
+        static GLOBAL: OnceLock<AsPathInterner> = OnceLock::new();
+        GLOBAL.get_or_init(|| AsPathInterner {
+            paths: Mutex::new(Registry {
+                paths: HashSet::new(),
+                sweep_at: MIN_SWEEP,
+            }),
+        })
+    }
+
+    /// The shared copy of `asns`, registering it if nobody holds it yet
+    pub fn intern(&self, asns: &[u32]) -> AsPath {
+        let mut registry = self.lock();
+        if let Some(path) = registry.paths.get(asns) {
+            return AsPath(Arc::clone(path));
+        }
+        // Drops racing on one path can each see another holder and leave it
+        // registered; sweeping as the registry grows catches those
+        if registry.paths.len() >= registry.sweep_at {
+            registry.sweep();
+        }
+        let path: Arc<[u32]> = Arc::from(asns);
+        registry.paths.insert(Arc::clone(&path));
+        AsPath(path)
+    }
+
+    /// Drop every path no route holds any more, returning how many went
+    pub fn collect_garbage(&self) -> usize {
+        self.lock().sweep()
+    }
+
+    /// Distinct paths currently registered
+    pub fn len(&self) -> usize {
+        self.lock().paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, asns: &[u32]) -> bool {
+        self.lock().paths.contains(asns)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        lock(&self.paths)
+    }
+}
+
+impl Registry {
+    fn sweep(&mut self) -> usize {
+        let before = self.paths.len();
+        self.paths.retain(|path| Arc::strong_count(path) > 1);
+        self.sweep_at = (self.paths.len() * 2).max(MIN_SWEEP);
+        before - self.paths.len()
+    }
+}
+
+impl AsPath {
+    pub fn new(asns: &[u32]) -> Self {
+        AsPathInterner::global().intern(asns)
+    }
+
+    /// This path with `asn` added `count` times at the front
+    pub fn prepended(&self, asn: u32, count: usize) -> Self {
+        if count == 0 {
+            return self.clone();
+        }
+        let mut asns = Vec::with_capacity(count + self.len());
+        asns.resize(count, asn);
+        asns.extend_from_slice(self);
+        AsPath::new(&asns)
+    }
+}
+
+impl Drop for AsPath {
+    fn drop(&mut self) {
+        // Held by this handle and the registry alone: the last user is going.
+        // New handles only come from the registry, so the count is stable
+        // while its lock is held.
+        if Arc::strong_count(&self.0) != 2 {
+            return;
+        }
+        let mut registry = AsPathInterner::global().lock();
+        if Arc::strong_count(&self.0) == 2 {
+            registry.paths.remove(&*self.0);
+        }
+    }
+}
+
+impl Deref for AsPath {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        &self.0
+    }
+}
+
+impl PartialEq for AsPath {
+    /// Equal paths always share one allocation
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for AsPath {}
+
+impl Hash for AsPath {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl PartialEq<[u32]> for AsPath {
+    fn eq(&self, other: &[u32]) -> bool {
+        *self.0 == *other
+    }
+}
+
+impl PartialEq<Vec<u32>> for AsPath {
+    fn eq(&self, other: &Vec<u32>) -> bool {
+        *self.0 == **other
+    }
+}
+
+impl From<Vec<u32>> for AsPath {
+    fn from(asns: Vec<u32>) -> Self {
+        AsPath::new(&asns)
+    }
+}
+
+impl From<&[u32]> for AsPath {
+    fn from(asns: &[u32]) -> Self {
+        AsPath::new(asns)
+    }
+}
+
+impl Default for AsPath {
+    fn default() -> Self {
+        AsPath::new(&[])
+    }
+}
+
+impl fmt::Debug for AsPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Serialize for AsPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AsPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<u32>::deserialize(deserializer).map(AsPath::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_are_shared_and_collected() {
+        // ASNs no other test uses, so the global registry is ours to inspect
+        let asns = [4_200_000_001, 4_200_000_002];
+        let first = AsPath::new(&asns);
+        let second: AsPath = asns.to_vec().into();
+        assert!(Arc::ptr_eq(&first.0, &second.0));
+        assert_eq!(first, second);
+        assert_ne!(first, AsPath::new(&asns[..1]));
+
+        let prepended = first.prepended(4_200_000_000, 2);
+        assert_eq!(
+            prepended,
+            vec![4_200_000_000, 4_200_000_000, 4_200_000_001, 4_200_000_002]
+        );
+
+        // Serialized as a plain list, and read back into the shared copy
+        let json = serde_json::to_string(&first).unwrap();
+        assert_eq!(json, "[4200000001,4200000002]");
+        let read: AsPath = serde_json::from_str(&json).unwrap();
+        assert_eq!(read, first);
+
+        drop((first, second));
+        assert!(AsPathInterner::global().contains(&asns));
+        drop(read);
+        assert!(!AsPathInterner::global().contains(&asns));
+        drop(prepended);
+        assert!(!AsPathInterner::global().contains(&[
+            4_200_000_000,
+            4_200_000_000,
+            4_200_000_001,
+            4_200_000_002
+        ]));
+    }
+}
@@ -0,0 +1,193 @@
+//! Traffic engineering by community.
+//!
+//! A sender tags a route with a community asking receivers to treat it a
+//! certain way; each receiver maps communities to attribute changes in
+//! `[[network.bgp.community_policies]]`. The mapping is applied on import,
+//! after policy and peering checks, so it decides between paths that were
+//! already accepted. Three well-known communities are mapped unless the
+//! configuration says otherwise: `PREFER`, `NORMAL` and `BACKUP`.
+
+use crate::network::bgp::{Community, RouteEntry};
+use serde::{Deserialize, Serialize};
+
+/// Asks receivers to prefer this path over untagged ones
+pub const PREFER: Community = Community {
+    asn: 65535,
+    value: 0x0200,
+};
+
+/// Asks receivers to rank this path as if it were untagged
+pub const NORMAL: Community = Community {
+    asn: 65535,
+    value: 0x0201,
+};
+
+/// Asks receivers to use this path only when nothing better is available
+pub const BACKUP: Community = Community {
+    asn: 65535,
+    value: 0x0202,
+};
+
+/// The well-known communities by the name the CLI accepts for them
+pub const WELL_KNOWN: &[(&str, Community)] =
+    &[("prefer", PREFER), ("normal", NORMAL), ("backup", BACKUP)];
+
+/// Attribute changes made to routes carrying `community`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommunityPolicy {
+    pub community: Community,
+    #[serde(default)]
+    pub set_local_pref: Option<u32>,
+    /// Times the sending peer's ASN is added to the front of the path
+    #[serde(default)]
+    pub prepend: usize,
+    #[serde(default)]
+    pub set_med: Option<u32>,
+}
+
+impl CommunityPolicy {
+    pub fn new(community: Community) -> Self {
+        CommunityPolicy {
+            community,
+            set_local_pref: None,
+            prepend: 0,
+            set_med: None,
+        }
+    }
+
+    pub fn with_local_pref(mut self, local_pref: u32) -> Self {
+        self.set_local_pref = Some(local_pref);
+        self
+    }
+
+    pub fn with_prepend(mut self, prepend: usize) -> Self {
+        self.prepend = prepend;
+        self
+    }
+
+    pub fn with_med(mut self, med: u32) -> Self {
+        self.set_med = Some(med);
+        self
+    }
+
+    /// Apply the changes to `route`, learned from `peer_asn`, describing
+    /// each one made
+    fn apply(&self, route: &mut RouteEntry, peer_asn: u32) -> Vec<String> {
+        let tag = format!("community {}", self.community);
+        let mut changes = Vec::new();
+        if let Some(local_pref) = self.set_local_pref {
+            if route.local_pref != local_pref {
+                changes.push(format!(
+                    "{}: local_pref {} -> {}",
+                    tag, route.local_pref, local_pref
+                ));
+                route.local_pref = local_pref;
+            }
+        }
+        if self.prepend > 0 {
+            changes.push(format!("{}: prepend AS{} x{}", tag, peer_asn, self.prepend));
+            route.as_path = route.as_path.prepended(peer_asn, self.prepend);
+        }
+        if let Some(med) = self.set_med {
+            if route.med != med {
+                changes.push(format!("{}: med {} -> {}", tag, route.med, med));
+                route.med = med;
+            }
+        }
+        changes
+    }
+}
+
+/// The mapping shipped by default: local_pref 200 for `PREFER`, 100 for
+/// `NORMAL` and 50 for `BACKUP`
+pub fn well_known_policies() -> Vec<CommunityPolicy> {
+    vec![
+        CommunityPolicy::new(PREFER).with_local_pref(200),
+        CommunityPolicy::new(NORMAL).with_local_pref(100),
+        CommunityPolicy::new(BACKUP).with_local_pref(50),
+    ]
+}
+
+/// A community mapped twice would leave the outcome to list order
+pub fn validate(policies: &[CommunityPolicy]) -> Result<(), String> {
+    for (index, policy) in policies.iter().enumerate() {
+        if policies[..index]
+            .iter()
+            .any(|earlier| earlier.community == policy.community)
+        {
+            return Err(format!("community {} mapped twice", policy.community));
+        }
+    }
+    Ok(())
+}
+
+/// Community mappings in effect on this node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommunityMap {
+    policies: Vec<CommunityPolicy>,
+}
+
+impl Default for CommunityMap {
+    fn default() -> Self {
+        CommunityMap::new(well_known_policies())
+    }
+}
+
+impl CommunityMap {
+    pub fn new(policies: Vec<CommunityPolicy>) -> Self {
+        CommunityMap { policies }
+    }
+
+    /// Apply the mapping of every community `route` carries, in configured
+    /// order, describing each change made
+    pub fn apply(&self, route: &mut RouteEntry, peer_asn: u32) -> Vec<String> {
+        let mut changes = Vec::new();
+        for policy in &self.policies {
+            if route.communities.contains(&policy.community) {
+                changes.extend(policy.apply(route, peer_asn));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::testing;
+
+    fn route(communities: Vec<Community>) -> RouteEntry {
+        RouteEntry {
+            communities,
+            ..testing::route("10.50.0.0/16", "172.16.0.1", &[65002])
+        }
+    }
+
+    #[test]
+    fn test_mapping_changes_only_tagged_routes() {
+        let map = CommunityMap::new(vec![CommunityPolicy::new(BACKUP)
+            .with_local_pref(50)
+            .with_prepend(2)
+            .with_med(500)]);
+
+        let mut unmapped = route(vec![PREFER]);
+        assert!(map.apply(&mut unmapped, 65002).is_empty());
+        assert_eq!(unmapped.local_pref, 100);
+
+        let mut backup = route(vec![BACKUP]);
+        let changes = map.apply(&mut backup, 65002);
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0], "community 65535:514: local_pref 100 -> 50");
+        assert_eq!(backup.local_pref, 50);
+        assert_eq!(*backup.as_path, [65002, 65002, 65002]);
+        assert_eq!(backup.med, 500);
+    }
+
+    #[test]
+    fn test_community_mapped_twice_is_refused() {
+        let mut policies = well_known_policies();
+        assert!(validate(&policies).is_ok());
+        policies.push(CommunityPolicy::new(PREFER).with_local_pref(300));
+        assert!(validate(&policies).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+//! VX0 default route handling between tiers.
+//!
+//! Regional nodes originate the VX0 supernet toward their Edge peers while
+//! they still have a path to the backbone; Edge nodes track which peers
+//! currently offer it and flag the node when none do.
+
+use crate::network::bgp::{AsPath, BGPOrigin, Prefix, RouteEntry, RouteTable};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How long an Edge node may go without any default candidate before warning
+pub const DEFAULT_ROUTE_GRACE: Duration = Duration::from_secs(60);
+
+pub const VX0_IPV4_SUPERNET: &str = "10.0.0.0/8";
+pub const VX0_IPV6_SUPERNET: &str = "fd00:7830::/32";
+
+/// The VX0 address plan's supernet, used as the network-wide default
+pub fn vx0_default() -> Prefix {
+    VX0_IPV4_SUPERNET.parse().unwrap()
+}
+
+pub fn vx0_default_v6() -> Prefix {
+    VX0_IPV6_SUPERNET.parse().unwrap()
+}
+
+fn is_backbone_asn(asn: u32) -> bool {
+    (65000..=65099).contains(&asn)
+}
+
+fn is_edge_asn(asn: u32) -> bool {
+    (66000..=69999).contains(&asn)
+}
+
+/// Regional-side origination of the VX0 default toward Edge peers
+#[derive(Debug, Clone)]
+pub struct DefaultOriginator {
+    local_asn: u32,
+    next_hop: IpAddr,
+    enabled: bool,
+}
+
+impl DefaultOriginator {
+    pub fn new(local_asn: u32, next_hop: IpAddr, enabled: bool) -> Self {
+        DefaultOriginator {
+            local_asn,
+            next_hop,
+            enabled,
+        }
+    }
+
+    /// True while at least one route in the table was learned from a backbone peer
+    pub fn has_upstream(table: &RouteTable) -> bool {
+        table
+            .routes
+            .values()
+            .any(|route| route.as_path.first().copied().is_some_and(is_backbone_asn))
+    }
+
+    /// Default route to advertise to `peer_asn`, if any
+    ///
+    /// Returns `None` once the last backbone route is gone so the Edge peer
+    /// withdraws us and fails over to another Regional.
+    pub fn route_for_peer(&self, table: &RouteTable, peer_asn: u32) -> Option<RouteEntry> {
+        if !self.enabled || !is_edge_asn(peer_asn) || !Self::has_upstream(table) {
+            return None;
+        }
+
+        Some(RouteEntry {
+            network: vx0_default(),
+            next_hop: self.next_hop,
+            as_path: AsPath::new(&[self.local_asn]),
+            origin: BGPOrigin::IGP,
+            local_pref: 100,
+            med: 0,
+            communities: vec![],
+            timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DefaultRouteStatus {
+    /// At least one peer offers a default route
+    Available { candidates: usize },
+    /// No candidates, but still within the grace period
+    Pending,
+    /// No default route from any peer for longer than the grace period
+    Missing,
+}
+
+/// Edge-side tracking of default route candidates per peer
+#[derive(Debug)]
+pub struct DefaultRouteMonitor {
+    candidates: HashMap<IpAddr, RouteEntry>,
+    grace: Duration,
+    missing_since: Option<Instant>,
+    warned: bool,
+}
+
+impl DefaultRouteMonitor {
+    pub fn new(grace: Duration, now: Instant) -> Self {
+        DefaultRouteMonitor {
+            candidates: HashMap::new(),
+            grace,
+            missing_since: Some(now),
+            warned: false,
+        }
+    }
+
+    /// Replace what `peer` currently offers; `None` means it withdrew the default
+    pub fn update(&mut self, peer: IpAddr, route: Option<RouteEntry>, now: Instant) {
+        match route {
+            Some(route) => {
+                self.candidates.insert(peer, route);
+                self.missing_since = None;
+                self.warned = false;
+            }
+            None => {
+                self.candidates.remove(&peer);
+                if self.candidates.is_empty() && self.missing_since.is_none() {
+                    self.missing_since = Some(now);
+                }
+            }
+        }
+    }
+
+    /// Pick out the default route, if present, from a batch received from `peer`
+    pub fn record_routes(&mut self, peer: IpAddr, routes: &[RouteEntry], now: Instant) {
+        let default = vx0_default();
+        let route = routes
+            .iter()
+            .find(|route| route.network == default)
+            .cloned();
+        self.update(peer, route, now);
+    }
+
+    pub fn peer_down(&mut self, peer: IpAddr, now: Instant) {
+        self.update(peer, None, now);
+    }
+
+    pub fn candidates(&self) -> Vec<&RouteEntry> {
+        self.candidates.values().collect()
+    }
+
+    pub fn status(&self, now: Instant) -> DefaultRouteStatus {
+        match self.missing_since {
+            None => DefaultRouteStatus::Available {
+                candidates: self.candidates.len(),
+            },
+            Some(since) if now.duration_since(since) > self.grace => DefaultRouteStatus::Missing,
+            Some(_) => DefaultRouteStatus::Pending,
+        }
+    }
+
+    /// Evaluate the status, warning once each time the default goes missing
+    pub fn check(&mut self, now: Instant) -> DefaultRouteStatus {
+        let status = self.status(now);
+        if status == DefaultRouteStatus::Missing && !self.warned {
+            tracing::warn!(
+                "No default route from any peer for over {}s; off-node destinations are unreachable",
+                self.grace.as_secs()
+            );
+            self.warned = true;
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backbone_route(table: &mut RouteTable, network: &str) {
+        table
+            .add_route(RouteEntry {
+                network: network.parse().unwrap(),
+                next_hop: "172.16.0.1".parse().unwrap(),
+                as_path: vec![65000].into(),
+                origin: BGPOrigin::IGP,
+                local_pref: 100,
+                med: 0,
+                communities: vec![],
+                timestamp: chrono::Utc::now(),
+                learned_from: None,
+                originated_at: None,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_edge_fails_over_between_regionals() {
+        let edge_asn = 66001;
+        let start = Instant::now();
+
+        let regional_a = DefaultOriginator::new(65100, "10.1.0.1".parse().unwrap(), true);
+        let regional_b = DefaultOriginator::new(65101, "10.2.0.1".parse().unwrap(), true);
+        let peer_a: IpAddr = "10.1.0.1".parse().unwrap();
+        let peer_b: IpAddr = "10.2.0.1".parse().unwrap();
+
+        let mut table_a = RouteTable::new();
+        let mut table_b = RouteTable::new();
+        backbone_route(&mut table_a, "10.100.0.0/16");
+        backbone_route(&mut table_b, "10.100.0.0/16");
+
+        let mut monitor = DefaultRouteMonitor::new(DEFAULT_ROUTE_GRACE, start);
+        monitor.update(peer_a, regional_a.route_for_peer(&table_a, edge_asn), start);
+        monitor.update(peer_b, regional_b.route_for_peer(&table_b, edge_asn), start);
+        assert_eq!(
+            monitor.check(start),
+            DefaultRouteStatus::Available { candidates: 2 }
+        );
+
+        // Regional A loses its backbone link and stops originating
+        table_a.remove_route(&"10.100.0.0/16".parse().unwrap());
+        assert!(regional_a.route_for_peer(&table_a, edge_asn).is_none());
+        monitor.update(peer_a, regional_a.route_for_peer(&table_a, edge_asn), start);
+        assert_eq!(monitor.candidates().len(), 1);
+        assert_eq!(monitor.candidates()[0].next_hop, peer_b);
+
+        // Then B does too
+        table_b.remove_route(&"10.100.0.0/16".parse().unwrap());
+        monitor.update(peer_b, regional_b.route_for_peer(&table_b, edge_asn), start);
+        assert_eq!(monitor.check(start), DefaultRouteStatus::Pending);
+        assert_eq!(
+            monitor.check(start + DEFAULT_ROUTE_GRACE + Duration::from_secs(1)),
+            DefaultRouteStatus::Missing
+        );
+    }
+
+    #[test]
+    fn test_origination_only_toward_edge_when_enabled() {
+        let mut table = RouteTable::new();
+        backbone_route(&mut table, "10.100.0.0/16");
+
+        let enabled = DefaultOriginator::new(65100, "10.1.0.1".parse().unwrap(), true);
+        assert!(enabled.route_for_peer(&table, 66001).is_some());
+        assert!(enabled.route_for_peer(&table, 65101).is_none());
+
+        let disabled = DefaultOriginator::new(65100, "10.1.0.1".parse().unwrap(), false);
+        assert!(disabled.route_for_peer(&table, 66001).is_none());
+    }
+}
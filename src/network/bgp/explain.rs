@@ -0,0 +1,362 @@
+//! Why a prefix is, or is not, in the Loc-RIB.
+//!
+//! A rejected route leaves no trace in the Loc-RIB, and routes refused at
+//! ingest never reach the Adj-RIB-In either. The most recent rejection per
+//! prefix and peer is therefore kept in a bounded journal whose entries age
+//! out. Together with the Adj-RIBs-In it lets `vx0net routes explain` tell
+//! installed, rejected, suppressed, unresolvable, tunnel-less and never
+//! received prefixes apart.
+
+use crate::config::RejectionJournalConfig;
+use crate::network::bgp::policy::PolicyVerdict;
+use crate::network::bgp::propagation::PropagationSample;
+use crate::network::bgp::{Prefix, RouteEntry};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionKind {
+    /// Refused by import policy, the ACL or the peering agreement
+    Policy,
+    /// Suppressed because the peer is being damped
+    Damping,
+}
+
+/// A journal entry as reported to operators
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub kind: RejectionKind,
+    pub verdict: PolicyVerdict,
+    pub age: Duration,
+}
+
+#[derive(Debug)]
+struct JournalEntry {
+    kind: RejectionKind,
+    verdict: PolicyVerdict,
+    at: Instant,
+    seq: u64,
+}
+
+type JournalKey = (Prefix, u32);
+
+/// Most recent rejection per (prefix, peer), least recently recorded
+/// evicted first
+#[derive(Debug)]
+pub struct RejectionJournal {
+    config: RejectionJournalConfig,
+    entries: HashMap<JournalKey, JournalEntry>,
+    /// Keys in recording order; positions superseded by a later record of
+    /// the same key are skipped
+    order: VecDeque<(JournalKey, u64)>,
+    next_seq: u64,
+}
+
+impl Default for RejectionJournal {
+    fn default() -> Self {
+        RejectionJournal::new(RejectionJournalConfig::default())
+    }
+}
+
+impl RejectionJournal {
+    pub fn new(config: RejectionJournalConfig) -> Self {
+        RejectionJournal {
+            config,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn max_age(&self) -> Duration {
+        Duration::from_secs(self.config.max_age_secs)
+    }
+
+    pub fn record(
+        &mut self,
+        network: Prefix,
+        peer_asn: u32,
+        kind: RejectionKind,
+        verdict: PolicyVerdict,
+        now: Instant,
+    ) {
+        if self.config.size == 0 {
+            return;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let key = (network, peer_asn);
+        self.entries.insert(
+            key,
+            JournalEntry {
+                kind,
+                verdict,
+                at: now,
+                seq,
+            },
+        );
+        self.order.push_back((key, seq));
+        self.evict(now);
+    }
+
+    /// Forget a rejection once the route is accepted
+    pub fn clear(&mut self, network: &Prefix, peer_asn: u32) {
+        self.entries.remove(&(*network, peer_asn));
+    }
+
+    /// Rejections of `network` still within the maximum age, by peer ASN
+    pub fn for_prefix(&self, network: &Prefix, now: Instant) -> Vec<(u32, Rejection)> {
+        let mut rejections: Vec<(u32, Rejection)> = self
+            .entries
+            .iter()
+            .filter(|((entry_network, _), _)| entry_network == network)
+            .map(|((_, peer_asn), entry)| {
+                let rejection = Rejection {
+                    kind: entry.kind,
+                    verdict: entry.verdict.clone(),
+                    age: now.saturating_duration_since(entry.at),
+                };
+                (*peer_asn, rejection)
+            })
+            .filter(|(_, rejection)| rejection.age < self.max_age())
+            .collect();
+        rejections.sort_by_key(|(peer_asn, _)| *peer_asn);
+        rejections
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict(&mut self, now: Instant) {
+        let max_age = self.max_age();
+        while let Some((key, seq)) = self.order.front().copied() {
+            let live = match self.entries.get(&key) {
+                Some(entry) if entry.seq == seq => entry,
+                // Superseded or already cleared
+                _ => {
+                    self.order.pop_front();
+                    continue;
+                }
+            };
+            let aged = now.saturating_duration_since(live.at) >= max_age;
+            if !aged && self.entries.len() <= self.config.size {
+                break;
+            }
+            self.entries.remove(&key);
+            self.order.pop_front();
+        }
+
+        // A few keys recorded over and over leave superseded positions
+        // behind the front; drop them before they pile up
+        if self.order.len() > 2 * self.config.size {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, seq)| entries.get(key).is_some_and(|entry| entry.seq == *seq));
+        }
+    }
+}
+
+/// What became of one peer's announcement of the prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum PeerOutcome {
+    /// This peer's route is the best path
+    Installed,
+    /// Accepted, but another path was preferred
+    NotBest,
+    /// `age_secs` is unset when the verdict was worked out just now rather
+    /// than recorded when the route arrived
+    Rejected {
+        verdict: PolicyVerdict,
+        age_secs: Option<u64>,
+    },
+    Damped {
+        verdict: PolicyVerdict,
+        age_secs: Option<u64>,
+    },
+    UnresolvedNextHop {
+        next_hop: IpAddr,
+    },
+    /// No usable tunnel to the next-hop peer
+    NoTunnel {
+        next_hop: IpAddr,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PeerExplanation {
+    pub peer_asn: u32,
+    /// The route as received; absent when it was refused before reaching
+    /// the Adj-RIB-In
+    #[serde(default)]
+    pub route: Option<RouteEntry>,
+    /// Attribute changes made on import by policy rules and the community
+    /// mapping, for routes that were accepted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<String>,
+    #[serde(flatten)]
+    pub outcome: PeerOutcome,
+}
+
+/// The overall answer to "why is (or isn't) this route here"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Summary {
+    Installed,
+    Rejected,
+    Damped,
+    UnresolvedNextHop,
+    NoTunnel,
+    NeverReceived,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Explanation {
+    pub network: Prefix,
+    /// Best path in the Loc-RIB
+    #[serde(default)]
+    pub installed: Option<RouteEntry>,
+    pub peers: Vec<PeerExplanation>,
+    /// Delay measured when the prefix last arrived stamped by its origin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub propagation: Option<Box<PropagationSample>>,
+}
+
+impl Explanation {
+    pub fn summary(&self) -> Summary {
+        if self.installed.is_some() {
+            return Summary::Installed;
+        }
+        let any = |matches: fn(&PeerOutcome) -> bool| {
+            self.peers.iter().any(|peer| matches(&peer.outcome))
+        };
+        if any(|outcome| matches!(outcome, PeerOutcome::UnresolvedNextHop { .. })) {
+            Summary::UnresolvedNextHop
+        } else if any(|outcome| matches!(outcome, PeerOutcome::NoTunnel { .. })) {
+            Summary::NoTunnel
+        } else if any(|outcome| matches!(outcome, PeerOutcome::Rejected { .. })) {
+            Summary::Rejected
+        } else if any(|outcome| matches!(outcome, PeerOutcome::Damped { .. })) {
+            Summary::Damped
+        } else {
+            Summary::NeverReceived
+        }
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.installed {
+            Some(route) => {
+                let from = route
+                    .learned_from
+                    .map(|peer| format!("AS{}", peer.asn))
+                    .unwrap_or_else(|| "this node".to_string());
+                writeln!(
+                    f,
+                    "{}: installed via {} from {} (path {:?}, local_pref {}, med {})",
+                    self.network, route.next_hop, from, route.as_path, route.local_pref, route.med
+                )?;
+            }
+            None if self.peers.is_empty() => {
+                return writeln!(f, "{}: never received from any peer", self.network);
+            }
+            None => writeln!(f, "{}: not installed", self.network)?,
+        }
+        if let Some(sample) = &self.propagation {
+            writeln!(f, "  propagation delay: {}", sample)?;
+        }
+
+        for peer in &self.peers {
+            write!(f, "  AS{}: ", peer.peer_asn)?;
+            match &peer.outcome {
+                PeerOutcome::Installed => writeln!(f, "best path")?,
+                PeerOutcome::NotBest => writeln!(f, "accepted, but another path is preferred")?,
+                PeerOutcome::Rejected { verdict, age_secs } => {
+                    writeln!(f, "rejected{} {}", ago(*age_secs), verdict)?
+                }
+                PeerOutcome::Damped { verdict, age_secs } => {
+                    writeln!(f, "suppressed{} {}", ago(*age_secs), verdict)?
+                }
+                PeerOutcome::UnresolvedNextHop { next_hop } => {
+                    writeln!(f, "next hop {} cannot be resolved", next_hop)?
+                }
+                PeerOutcome::NoTunnel { next_hop } => {
+                    writeln!(f, "no usable tunnel to next hop {}", next_hop)?
+                }
+            }
+            for change in &peer.changes {
+                writeln!(f, "    {}", change)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ago(age_secs: Option<u64>) -> String {
+    age_secs
+        .map(|secs| format!(" {}s ago", secs))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_is_bounded_and_ages_out() {
+        let mut journal = RejectionJournal::new(RejectionJournalConfig {
+            size: 2,
+            max_age_secs: 60,
+        });
+        let start = Instant::now();
+        let network = |n: u8| -> Prefix { format!("10.{}.0.0/16", n).parse().unwrap() };
+        let verdict = PolicyVerdict::deny("deny-prefix", "denied");
+
+        for n in 0..3 {
+            journal.record(
+                network(n),
+                65001,
+                RejectionKind::Policy,
+                verdict.clone(),
+                start,
+            );
+        }
+        // The least recently recorded went first
+        assert_eq!(journal.len(), 2);
+        assert!(journal.for_prefix(&network(0), start).is_empty());
+
+        // Recording again refreshes an entry's place in line
+        journal.record(
+            network(1),
+            65001,
+            RejectionKind::Policy,
+            verdict.clone(),
+            start,
+        );
+        journal.record(
+            network(3),
+            65001,
+            RejectionKind::Policy,
+            verdict.clone(),
+            start,
+        );
+        assert_eq!(journal.for_prefix(&network(1), start).len(), 1);
+        assert!(journal.for_prefix(&network(2), start).is_empty());
+
+        let later = start + Duration::from_secs(61);
+        assert!(journal.for_prefix(&network(1), later).is_empty());
+        journal.record(network(4), 65001, RejectionKind::Policy, verdict, later);
+        assert_eq!(journal.len(), 1);
+    }
+}
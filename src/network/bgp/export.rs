@@ -0,0 +1,310 @@
+//! Export templates: how our routes look to particular neighbors.
+//!
+//! Templates in `[[network.bgp.export_policies]]` are checked in order once
+//! the tier advertisement policy has let a route through to a peer. The
+//! first whose match fits both the peer and the route rewrites the route's
+//! attributes and the rest are skipped, so a template listed after one
+//! that always matches first can never apply; such templates are warned
+//! about when installed. A template can prepend our ASN, set the MED, add
+//! or strip communities and make this node the next hop: a longer path
+//! toward a backup transit, say, or a higher MED toward a distant Regional.
+
+use crate::network::bgp::{Community, RouteEntry};
+use crate::node::NodeTier;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// The peers and routes a template applies to; unset fields match anything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportMatch {
+    pub peer_asn: Option<u32>,
+    /// `group` of the peer in `[[network.bgp.peers]]`
+    pub peer_group: Option<String>,
+    pub peer_tier: Option<NodeTier>,
+    /// Route must fall within one of these; empty matches every route
+    pub prefix_list: Vec<IpNet>,
+}
+
+impl ExportMatch {
+    fn matches(&self, route: &RouteEntry, peer_asn: u32, group: Option<&str>) -> bool {
+        let network = route.network.net();
+        self.peer_asn.is_none_or(|asn| asn == peer_asn)
+            && self
+                .peer_group
+                .as_deref()
+                .is_none_or(|wanted| group == Some(wanted))
+            && self
+                .peer_tier
+                .as_ref()
+                .is_none_or(|tier| *tier == NodeTier::from_asn(peer_asn))
+            && (self.prefix_list.is_empty()
+                || self
+                    .prefix_list
+                    .iter()
+                    .any(|prefix| prefix.contains(&network)))
+    }
+
+    /// Whether everything `later` matches is matched by this as well
+    fn covers(&self, later: &ExportMatch) -> bool {
+        let tier = match (&self.peer_tier, &later.peer_tier, later.peer_asn) {
+            (None, _, _) => true,
+            (Some(ours), Some(theirs), _) => ours == theirs,
+            (Some(ours), None, Some(asn)) => *ours == NodeTier::from_asn(asn),
+            (Some(_), None, None) => false,
+        };
+        let prefixes = self.prefix_list.is_empty()
+            || (!later.prefix_list.is_empty()
+                && later
+                    .prefix_list
+                    .iter()
+                    .all(|theirs| self.prefix_list.iter().any(|ours| ours.contains(theirs))));
+        tier && prefixes
+            && self.peer_asn.is_none_or(|asn| later.peer_asn == Some(asn))
+            && self
+                .peer_group
+                .as_ref()
+                .is_none_or(|group| later.peer_group.as_ref() == Some(group))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportPolicy {
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub matches: ExportMatch,
+    /// Extra copies of our ASN put in front of the path
+    #[serde(default)]
+    pub prepend: usize,
+    #[serde(default)]
+    pub set_med: Option<u32>,
+    #[serde(default)]
+    pub add_communities: Vec<Community>,
+    #[serde(default)]
+    pub remove_communities: Vec<Community>,
+    /// Advertise this node as the next hop
+    #[serde(default)]
+    pub next_hop_self: bool,
+}
+
+impl ExportPolicy {
+    pub fn new(name: impl Into<String>, matches: ExportMatch) -> Self {
+        ExportPolicy {
+            name: name.into(),
+            matches,
+            prepend: 0,
+            set_med: None,
+            add_communities: Vec::new(),
+            remove_communities: Vec::new(),
+            next_hop_self: false,
+        }
+    }
+
+    pub fn with_prepend(mut self, prepend: usize) -> Self {
+        self.prepend = prepend;
+        self
+    }
+
+    pub fn with_med(mut self, med: u32) -> Self {
+        self.set_med = Some(med);
+        self
+    }
+
+    fn apply(&self, route: &mut RouteEntry, local_asn: u32, local_addr: IpAddr) {
+        if self.prepend > 0 {
+            route.as_path = route.as_path.prepended(local_asn, self.prepend);
+        }
+        if let Some(med) = self.set_med {
+            route.med = med;
+        }
+        route
+            .communities
+            .retain(|community| !self.remove_communities.contains(community));
+        for community in &self.add_communities {
+            if !route.communities.contains(community) {
+                route.communities.push(community.clone());
+            }
+        }
+        // A next hop must stay in the route's own address family
+        if self.next_hop_self && local_addr.is_ipv4() == route.network.net().addr().is_ipv4() {
+            route.next_hop = local_addr;
+        }
+    }
+}
+
+/// Names must identify one template; returns a warning for each template
+/// an earlier one leaves unreachable
+pub fn validate(policies: &[ExportPolicy]) -> Result<Vec<String>, String> {
+    let mut warnings = Vec::new();
+    for (index, policy) in policies.iter().enumerate() {
+        if policy.name.is_empty() {
+            return Err("export policy without a name".to_string());
+        }
+        let earlier = &policies[..index];
+        if earlier.iter().any(|other| other.name == policy.name) {
+            return Err(format!("export policy name {:?} used twice", policy.name));
+        }
+        if let Some(shadow) = earlier
+            .iter()
+            .find(|other| other.matches.covers(&policy.matches))
+        {
+            warnings.push(format!(
+                "export policy {:?} is unreachable: {:?} before it matches everything it does",
+                policy.name, shadow.name
+            ));
+        }
+    }
+    Ok(warnings)
+}
+
+/// Installed templates, with what they need to know about peers and this node
+#[derive(Debug, Clone)]
+pub struct ExportPolicies {
+    policies: Vec<ExportPolicy>,
+    /// Configured peers' groups, by ASN
+    groups: HashMap<u32, String>,
+    local_asn: u32,
+    local_addr: IpAddr,
+}
+
+impl ExportPolicies {
+    pub fn new(
+        policies: Vec<ExportPolicy>,
+        groups: HashMap<u32, String>,
+        local_asn: u32,
+        local_addr: IpAddr,
+    ) -> Self {
+        ExportPolicies {
+            policies,
+            groups,
+            local_asn,
+            local_addr,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// The template deciding how `route` looks to `peer_asn`, if any
+    pub fn template_for(&self, route: &RouteEntry, peer_asn: u32) -> Option<&ExportPolicy> {
+        let group = self.groups.get(&peer_asn).map(String::as_str);
+        self.policies
+            .iter()
+            .find(|policy| policy.matches.matches(route, peer_asn, group))
+    }
+
+    /// `route` as `peer_asn` should receive it
+    pub fn export(&self, mut route: RouteEntry, peer_asn: u32) -> RouteEntry {
+        if let Some(policy) = self.template_for(&route, peer_asn) {
+            policy.apply(&mut route, self.local_asn, self.local_addr);
+        }
+        route
+    }
+}
+
+impl Default for ExportPolicies {
+    fn default() -> Self {
+        ExportPolicies::new(Vec::new(), HashMap::new(), 0, IpAddr::from([0, 0, 0, 0]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::testing;
+
+    const LOCAL_ASN: u32 = 66001;
+
+    fn route(network: &str) -> RouteEntry {
+        RouteEntry {
+            communities: vec![Community { asn: 1, value: 1 }],
+            ..testing::route(network, "10.66.1.10", &[LOCAL_ASN])
+        }
+    }
+
+    fn toward(peer_asn: u32) -> ExportMatch {
+        ExportMatch {
+            peer_asn: Some(peer_asn),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_first_matching_template_rewrites_the_route() {
+        let regional = ExportMatch {
+            peer_tier: Some(NodeTier::Regional),
+            prefix_list: vec!["10.66.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        let policies = vec![
+            ExportPolicy {
+                remove_communities: vec![Community { asn: 1, value: 1 }],
+                add_communities: vec![Community { asn: 2, value: 2 }],
+                next_hop_self: true,
+                ..ExportPolicy::new("backup", toward(65102)).with_prepend(2)
+            },
+            ExportPolicy::new("regional", regional).with_med(50),
+        ];
+        let exports = ExportPolicies::new(
+            policies,
+            HashMap::new(),
+            LOCAL_ASN,
+            "10.66.1.1".parse().unwrap(),
+        );
+
+        let backup = exports.export(route("10.66.1.0/24"), 65102);
+        assert_eq!(*backup.as_path, [LOCAL_ASN; 3]);
+        assert_eq!(backup.med, 0);
+        assert_eq!(backup.communities, vec![Community { asn: 2, value: 2 }]);
+        assert_eq!(backup.next_hop, "10.66.1.1".parse::<IpAddr>().unwrap());
+
+        let primary = exports.export(route("10.66.1.0/24"), 65101);
+        assert_eq!((primary.as_path.len(), primary.med), (1, 50));
+        // Outside the prefix list, and a Backbone peer: untouched
+        assert_eq!(exports.export(route("10.99.0.0/24"), 65101).med, 0);
+        assert_eq!(exports.export(route("10.66.1.0/24"), 65001).med, 0);
+        // No IPv4 next hop on an IPv6 route
+        let v6 = route("fd00:66::/48");
+        let next_hop = v6.next_hop;
+        let policies = vec![ExportPolicy {
+            next_hop_self: true,
+            ..ExportPolicy::new("self", ExportMatch::default())
+        }];
+        let exports = ExportPolicies::new(
+            policies,
+            HashMap::new(),
+            LOCAL_ASN,
+            "10.66.1.1".parse().unwrap(),
+        );
+        assert_eq!(exports.export(v6, 65101).next_hop, next_hop);
+    }
+
+    #[test]
+    fn test_templates_shadowed_by_an_earlier_one_are_warned_about() {
+        let group = ExportMatch {
+            peer_group: Some("transit".to_string()),
+            ..Default::default()
+        };
+        let regional = ExportMatch {
+            peer_tier: Some(NodeTier::Regional),
+            ..Default::default()
+        };
+        let policies = vec![
+            ExportPolicy::new("regional", regional).with_med(10),
+            ExportPolicy::new("transit", group).with_prepend(1),
+            // Already decided by "regional"
+            ExportPolicy::new("backup", toward(65102)).with_prepend(2),
+            ExportPolicy::new("backbone", toward(65001)).with_prepend(2),
+        ];
+        let warnings = validate(&policies).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("\"backup\""));
+
+        let mut duplicated = policies.clone();
+        duplicated.push(ExportPolicy::new("transit", ExportMatch::default()));
+        assert!(validate(&duplicated).is_err());
+    }
+}
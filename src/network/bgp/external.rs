@@ -0,0 +1,465 @@
+//! Sessions with external BGP speakers using the RFC 4271 wire format.
+//!
+//! These sessions carry plain BGP only; none of the VX0 extensions are
+//! negotiated, so any standards-compliant router can act as the peer.
+
+use crate::config::{BGPPeerConfig, HostBitsPolicy};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::adaptive::{self, AdaptiveTimers, Probe, KEEPALIVE_BYTES};
+use crate::network::bgp::messages::{
+    BGPMessage, UpdateMessage, BGP_ERROR_CEASE, BGP_ERROR_HOLD_TIMER_EXPIRED,
+};
+use crate::network::bgp::pacing::{AdvertisementPacer, PacerOutput, DEFAULT_MAX_QUEUE};
+use crate::network::bgp::rib::{Rib, RibChange};
+use crate::network::bgp::wire;
+use crate::network::bgp::{BGPError, Prefix, RouteEntry};
+use crate::network::obfuscation::Jitter;
+use crate::util::clock::{self, SharedClock};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{timeout, Duration};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the outbound queue is checked for anything due
+const PACING_TICK: Duration = Duration::from_millis(250);
+
+pub struct ExternalPeer {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    pub peer_asn: u32,
+    pub local_asn: u32,
+    pub hold_time: u16,
+    router_id: Ipv4Addr,
+    /// Minimum interval between outbound UPDATEs
+    mrai: Duration,
+    host_bits: HostBitsPolicy,
+    keepalive_jitter: Jitter,
+    /// Times keepalives, the hold timer and pacing
+    clock: SharedClock,
+    adaptive: Option<Arc<AdaptiveTimers>>,
+}
+
+impl ExternalPeer {
+    /// Connect and complete the OPEN/KEEPALIVE handshake
+    pub async fn connect(
+        local_asn: u32,
+        router_id: Ipv4Addr,
+        hold_time: u16,
+        peer: &BGPPeerConfig,
+    ) -> Result<Self, BGPError> {
+        let address = format!("{}:{}", peer.address, peer.port);
+        let peer_addr: SocketAddr = address
+            .parse()
+            .map_err(|source| BGPError::InvalidPeerAddress { address, source })?;
+
+        tracing::info!(
+            "Connecting to external BGP peer {} (ASN {})",
+            peer_addr,
+            peer.asn
+        );
+
+        let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(peer_addr))
+            .await
+            .map_err(|_| BGPError::ConnectTimeout { addr: peer_addr })?
+            .map_err(|source| BGPError::PeerUnreachable {
+                addr: peer_addr,
+                source,
+            })?;
+
+        wire::write_message(
+            &mut stream,
+            &wire::open_with_capabilities(local_asn, hold_time, router_id),
+        )
+        .await?;
+
+        let open = match wire::read_message(&mut stream).await? {
+            BGPMessage::Open(open) => open,
+            BGPMessage::Notification(n) => {
+                return Err(BGPError::Notification {
+                    peer: peer_addr,
+                    code: n.error_code,
+                    subcode: n.error_subcode,
+                })
+            }
+            other => {
+                return Err(BGPError::UnexpectedMessage {
+                    peer: peer_addr,
+                    expected: "OPEN",
+                    got: format!("{:?}", other),
+                })
+            }
+        };
+
+        if open.my_asn != peer.asn {
+            let notification = BGPMessage::new_notification(
+                crate::network::bgp::messages::BGP_ERROR_OPEN_MESSAGE,
+                wire::OPEN_BAD_PEER_AS,
+                vec![],
+            );
+            let _ = wire::write_message(&mut stream, &notification).await;
+            return Err(BGPError::PeerAsnMismatch {
+                peer: peer_addr,
+                announced: open.my_asn,
+                expected: peer.asn,
+            });
+        }
+
+        // Negotiated hold time is the smaller of the two (0 disables keepalives)
+        let hold_time = hold_time.min(open.hold_time);
+
+        wire::write_message(&mut stream, &BGPMessage::Keepalive).await?;
+        match wire::read_message(&mut stream).await? {
+            BGPMessage::Keepalive => {}
+            other => {
+                return Err(BGPError::UnexpectedMessage {
+                    peer: peer_addr,
+                    expected: "KEEPALIVE",
+                    got: format!("{:?}", other),
+                })
+            }
+        }
+
+        tracing::info!(
+            "External BGP session established with {} (ASN {}, hold time {}s)",
+            peer_addr,
+            open.my_asn,
+            hold_time
+        );
+
+        Ok(ExternalPeer {
+            stream,
+            peer_addr,
+            peer_asn: open.my_asn,
+            local_asn,
+            hold_time,
+            router_id,
+            mrai: peer.mrai(),
+            host_bits: peer.host_bits,
+            keepalive_jitter: Jitter::default(),
+            clock: clock::system(),
+            adaptive: None,
+        })
+    }
+
+    /// Vary each keepalive interval randomly
+    pub fn with_keepalive_jitter(mut self, jitter: Jitter) -> Self {
+        self.keepalive_jitter = jitter;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Send keepalives as often as the link has earned, within a third of
+    /// the hold time agreed in OPEN
+    pub fn with_adaptive_timers(mut self, adaptive: Arc<AdaptiveTimers>) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// Advertise routes with ourselves as next hop
+    pub async fn advertise(&mut self, routes: &[RouteEntry]) -> Result<(), BGPError> {
+        let ibgp = self.peer_asn == self.local_asn;
+        send_routes(&mut self.stream, routes, self.router_id, ibgp).await
+    }
+
+    pub async fn recv(&mut self) -> Result<BGPMessage, BGPError> {
+        wire::read_message(&mut self.stream).await
+    }
+
+    /// Close the session with a Cease notification
+    pub async fn close(mut self, subcode: u8) -> Result<(), BGPError> {
+        let notification = BGPMessage::new_notification(BGP_ERROR_CEASE, subcode, vec![]);
+        wire::write_message(&mut self.stream, &notification).await
+    }
+
+    /// Run the session, installing received routes until it ends
+    pub async fn run(self, rib: Arc<RwLock<Rib>>) -> Result<(), BGPError> {
+        let negotiated = Duration::from_secs((self.hold_time / 3).max(1) as u64);
+        let hold = Duration::from_secs(self.hold_time as u64);
+        let jitter = self.keepalive_jitter;
+        let clock = Arc::clone(&self.clock);
+        let peer_ip = self.peer_addr.ip();
+        let adaptive = self.adaptive.clone();
+        if let Some(adaptive) = &adaptive {
+            adaptive.track(peer_ip, self.peer_asn);
+        }
+        let keepalive_every = || {
+            adaptive
+                .as_ref()
+                .and_then(|adaptive| adaptive.interval(peer_ip, Probe::Keepalive))
+                .map_or(negotiated, |every| every.min(negotiated))
+        };
+        // A flap or lost probe anywhere on the link cuts a stretched wait short
+        let mut anomalies = adaptive.as_ref().map(|adaptive| adaptive.subscribe());
+        let mut missed_reported = false;
+        let mut keepalive_due = clock.now_monotonic() + jitter.apply(keepalive_every());
+        let mut keepalive = clock.sleep_until(keepalive_due);
+        // Measured on the monotonic clock, so a wall-clock step never
+        // expires the hold timer
+        let mut last_heard = clock.now_monotonic();
+        let peer_addr = self.peer_addr;
+        let peer_asn = self.peer_asn;
+        let local_asn = self.local_asn;
+        let router_id = self.router_id;
+        let host_bits = self.host_bits;
+        let ibgp = peer_asn == local_asn;
+
+        // Locally originated changes are queued and paced; the pacer starts
+        // from whatever `advertise` already sent
+        let mut pacer = AdvertisementPacer::new(self.mrai, DEFAULT_MAX_QUEUE);
+        let mut changes = {
+            let rib = rib.read().await;
+            let advertised: Vec<RouteEntry> = rib
+                .advertised(peer_asn)
+                .map(|adj| adj.routes().into_iter().cloned().collect())
+                .unwrap_or_default();
+            pacer.resync_complete(&advertised);
+            rib.subscribe()
+        };
+        let mut pace = clock::interval(&clock, PACING_TICK);
+
+        // Reads happen on their own task so a keepalive tick can never
+        // cancel a partially read message
+        let (mut reader, mut writer) = self.stream.into_split();
+        let (tx, mut rx) = mpsc::channel(16);
+        let read_task = crash::spawn(Subsystem::Bgp, "external-bgp-reader", async move {
+            loop {
+                let result = wire::read_message(&mut reader).await;
+                let failed = result.is_err();
+                if tx.send(result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let outcome = loop {
+            tokio::select! {
+                _ = &mut keepalive => {
+                    let silent = clock.now_monotonic().saturating_duration_since(last_heard);
+                    if self.hold_time == 0 {
+                        keepalive_due = clock.now_monotonic() + jitter.apply(keepalive_every());
+                        keepalive = clock.sleep_until(keepalive_due);
+                        continue;
+                    }
+                    if silent > hold {
+                        let expired = BGPMessage::new_notification(BGP_ERROR_HOLD_TIMER_EXPIRED, 0, vec![]);
+                        let _ = wire::write_message(&mut writer, &expired).await;
+                        break Err(BGPError::HoldTimerExpired { peer: peer_addr });
+                    }
+                    // The peer owes a keepalive every third of the hold
+                    // time; two overdue counts as a lost probe
+                    if silent > negotiated * 2 && !missed_reported {
+                        missed_reported = true;
+                        if let Some(adaptive) = &adaptive {
+                            adaptive.lost(peer_ip);
+                        }
+                    }
+                    keepalive_due = clock.now_monotonic() + jitter.apply(keepalive_every());
+                    keepalive = clock.sleep_until(keepalive_due);
+                    if let Err(e) = wire::write_message(&mut writer, &BGPMessage::Keepalive).await {
+                        break Err(e);
+                    }
+                    adaptive::record_traffic(Probe::Keepalive, KEEPALIVE_BYTES, clock.now_monotonic());
+                }
+                changed = async {
+                    match &mut anomalies {
+                        Some(anomalies) => anomalies.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    if changed.is_err() {
+                        anomalies = None;
+                        continue;
+                    }
+                    // Only ever sooner, so anomalies elsewhere never put
+                    // this link's keepalive off
+                    let sooner = clock.now_monotonic() + jitter.apply(keepalive_every());
+                    if sooner < keepalive_due {
+                        keepalive_due = sooner;
+                        keepalive = clock.sleep_until(keepalive_due);
+                    }
+                }
+                change = changes.recv() => {
+                    match change {
+                        Ok(RibChange::Advertise(route)) => {
+                            let network = route.network;
+                            match exported(&*rib.read().await, route, peer_asn, ibgp) {
+                                Some(route) => pacer.announce(route),
+                                // Replaced by a route the peer may not have
+                                None => pacer.withdraw(network),
+                            }
+                        }
+                        Ok(RibChange::Withdraw(network)) => pacer.withdraw(network),
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!(
+                                "Missed {} RIB changes for {}; resyncing",
+                                missed,
+                                peer_addr
+                            );
+                            pacer.force_resync();
+                        }
+                        Err(RecvError::Closed) => {}
+                    }
+                }
+                _ = pace.tick() => {
+                    let sent = match pacer.poll(clock.now_monotonic()) {
+                        None => continue,
+                        Some(PacerOutput::Update(batch)) => {
+                            let result = match send_withdrawals(&mut writer, &batch.withdrawn).await {
+                                Ok(()) => send_routes(&mut writer, &batch.announced, router_id, ibgp).await,
+                                Err(e) => Err(e),
+                            };
+                            let mut rib = rib.write().await;
+                            rib.record_withdrawn(peer_asn, &batch.withdrawn);
+                            result.and_then(|_| rib.record_advertised(peer_asn, &batch.announced))
+                        }
+                        Some(PacerOutput::Resync) => {
+                            let routes: Vec<RouteEntry> = {
+                                let rib = rib.read().await;
+                                rib.loc_rib()
+                                    .get_all_routes()
+                                    .into_iter()
+                                    .filter_map(|route| exported(&rib, route.clone(), peer_asn, ibgp))
+                                    .collect()
+                            };
+                            tracing::info!(
+                                "Resyncing {} routes to external peer {}",
+                                routes.len(),
+                                peer_addr
+                            );
+                            // Withdraw anything the peer holds that it may no longer have
+                            let stale: Vec<_> = {
+                                let rib = rib.read().await;
+                                rib.advertised(peer_asn)
+                                    .map(|adj| {
+                                        adj.routes()
+                                            .into_iter()
+                                            .map(|route| route.network)
+                                            .filter(|network| !routes.iter().any(|r| r.network == *network))
+                                            .collect()
+                                    })
+                                    .unwrap_or_default()
+                            };
+                            pacer.resync_complete(&routes);
+                            let result = match send_withdrawals(&mut writer, &stale).await {
+                                Ok(()) => send_routes(&mut writer, &routes, router_id, ibgp).await,
+                                Err(e) => Err(e),
+                            };
+                            let mut rib = rib.write().await;
+                            rib.record_withdrawn(peer_asn, &stale);
+                            result.and_then(|_| rib.record_advertised(peer_asn, &routes))
+                        }
+                    };
+                    if let Err(e) = sent {
+                        break Err(e);
+                    }
+                }
+                result = rx.recv() => {
+                    let msg = match result {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(BGPError::Protocol { code, subcode, reason })) => {
+                            let n = BGPMessage::new_notification(code, subcode, vec![]);
+                            let _ = wire::write_message(&mut writer, &n).await;
+                            break Err(BGPError::Protocol { code, subcode, reason });
+                        }
+                        Some(Err(e)) => break Err(e),
+                        None => break Ok(()),
+                    };
+                    last_heard = clock.now_monotonic();
+                    missed_reported = false;
+
+                    match msg {
+                        BGPMessage::Update(update) => {
+                            let routes = match update.route_entries(host_bits, peer_asn) {
+                                Ok(routes) => routes,
+                                Err(e) => break Err(e),
+                            };
+                            for route in &routes {
+                                tracing::debug!(
+                                    "Learned {} via {} from external peer {}",
+                                    route.network,
+                                    route.next_hop,
+                                    peer_addr
+                                );
+                            }
+                            let mut rib = rib.write().await;
+                            let withdrawn = update.withdrawn(host_bits, peer_asn);
+                            if let Err(e) = rib.receive(peer_asn, routes, &withdrawn) {
+                                // Max-prefix exceeded: Cease with "maximum number of prefixes reached"
+                                let n = BGPMessage::new_notification(BGP_ERROR_CEASE, 1, vec![]);
+                                let _ = wire::write_message(&mut writer, &n).await;
+                                break Err(e);
+                            }
+                        }
+                        BGPMessage::Keepalive => {
+                            crate::sampled!(tracing::trace!("KEEPALIVE from external peer {}", peer_addr));
+                        }
+                        BGPMessage::Notification(n) => {
+                            break Err(BGPError::Notification {
+                                peer: peer_addr,
+                                code: n.error_code,
+                                subcode: n.error_subcode,
+                            });
+                        }
+                        BGPMessage::Open(_) => {
+                            break Err(BGPError::UnexpectedMessage {
+                                peer: peer_addr,
+                                expected: "UPDATE, KEEPALIVE or NOTIFICATION",
+                                got: "OPEN".to_string(),
+                            });
+                        }
+                    }
+                }
+            }
+        };
+
+        read_task.abort();
+        if let (Some(adaptive), Err(_)) = (&adaptive, &outcome) {
+            adaptive.flap(peer_ip);
+        }
+        outcome
+    }
+}
+
+/// `route` as the peer should receive it; an iBGP peer only gets our own
+/// routes, since it cannot tell a learned one from a loop
+fn exported(rib: &Rib, route: RouteEntry, peer_asn: u32, ibgp: bool) -> Option<RouteEntry> {
+    if ibgp && route.learned_from.is_some() {
+        return None;
+    }
+    rib.export_to(route, peer_asn)
+}
+
+async fn send_routes<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    routes: &[RouteEntry],
+    router_id: Ipv4Addr,
+    ibgp: bool,
+) -> Result<(), BGPError> {
+    for update in wire::updates_for_routes(routes, Some(router_id), ibgp) {
+        wire::write_message(writer, &BGPMessage::Update(update)).await?;
+    }
+    Ok(())
+}
+
+async fn send_withdrawals<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    withdrawn: &[Prefix],
+) -> Result<(), BGPError> {
+    if withdrawn.is_empty() {
+        return Ok(());
+    }
+    let update = UpdateMessage {
+        withdrawn_routes: withdrawn.iter().map(Prefix::net).collect(),
+        path_attributes: vec![],
+        network_layer_reachability_info: vec![],
+    };
+    wire::write_message(writer, &BGPMessage::Update(update)).await
+}
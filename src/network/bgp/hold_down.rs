@@ -0,0 +1,165 @@
+//! Hold-down for nodes that have just joined.
+//!
+//! For `[network.hold_down]` seconds after joining, a node's own routes are
+//! exported as a path of last resort: tagged with `NEWLY_JOINED`, the local
+//! ASN prepended and MED at its maximum, so peers keep using established
+//! paths while the node proves stable. Any session flap during the window
+//! restarts it, as does finding the wall clock out of step with the
+//! monotonic one when the window would end. Once it ends cleanly the routes
+//! are re-advertised with their normal attributes.
+
+use crate::config::HoldDownConfig;
+use crate::network::bgp::{Community, RouteEntry};
+use chrono::{DateTime, Utc};
+use std::time::{Duration, Instant};
+
+/// Marks routes from a node still in hold-down; receivers rank them last
+pub const NEWLY_JOINED: Community = Community {
+    asn: 65535,
+    value: 0x0100,
+};
+
+/// How far the wall clock may drift from the monotonic clock over the
+/// window before the node's clock is no longer trusted
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldDownEvent {
+    /// The window passed without flaps; normal attributes apply again
+    Expired,
+    /// The window passed but the clocks disagree; it starts over
+    ClockSkew,
+}
+
+#[derive(Debug, Default)]
+pub struct HoldDown {
+    config: HoldDownConfig,
+    /// When the current window began, by both clocks
+    started: Option<(Instant, DateTime<Utc>)>,
+    flaps: u32,
+}
+
+impl HoldDown {
+    pub fn new(config: HoldDownConfig) -> Self {
+        HoldDown {
+            config,
+            started: None,
+            flaps: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Flaps seen since the node joined
+    pub fn flaps(&self) -> u32 {
+        self.flaps
+    }
+
+    /// Begin a window, as after joining; does nothing when disabled
+    pub fn start(&mut self, now: Instant, wall: DateTime<Utc>) {
+        if self.config.duration_secs > 0 {
+            self.started = Some((now, wall));
+        }
+    }
+
+    /// A session went down; an active window starts over
+    pub fn record_flap(&mut self, now: Instant, wall: DateTime<Utc>) {
+        if self.is_active() {
+            self.flaps += 1;
+            tracing::info!("Session flap during hold-down; restarting the window");
+            self.started = Some((now, wall));
+        }
+    }
+
+    /// End the window once it has run its course with the clocks in step
+    pub fn poll(&mut self, now: Instant, wall: DateTime<Utc>) -> Option<HoldDownEvent> {
+        let (started, started_wall) = self.started?;
+        let elapsed = now.saturating_duration_since(started);
+        if elapsed < Duration::from_secs(self.config.duration_secs) {
+            return None;
+        }
+
+        let wall_elapsed = (wall - started_wall).to_std().unwrap_or_default();
+        let drift = wall_elapsed.abs_diff(elapsed);
+        if drift > MAX_CLOCK_DRIFT {
+            tracing::warn!(
+                "Wall clock drifted {:?} during hold-down; restarting the window",
+                drift
+            );
+            self.started = Some((now, wall));
+            return Some(HoldDownEvent::ClockSkew);
+        }
+
+        self.started = None;
+        Some(HoldDownEvent::Expired)
+    }
+
+    /// The route as exported while the window is active
+    pub fn apply(&self, mut route: RouteEntry, local_asn: u32) -> RouteEntry {
+        if !self.is_active() {
+            return route;
+        }
+        route.as_path = route.as_path.prepended(local_asn, self.config.prepend);
+        route.med = u32::MAX;
+        if !route.communities.contains(&NEWLY_JOINED) {
+            route.communities.push(NEWLY_JOINED);
+        }
+        route
+    }
+}
+
+/// Whether a peer exported the route while in hold-down
+pub fn is_last_resort(route: &RouteEntry) -> bool {
+    route.communities.contains(&NEWLY_JOINED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flaps_and_clock_skew_restart_the_window() {
+        let mut hold_down = HoldDown::new(HoldDownConfig {
+            duration_secs: 600,
+            prepend: 2,
+        });
+        let (t0, w0) = (Instant::now(), Utc::now());
+        let at = |secs: u64| {
+            (
+                t0 + Duration::from_secs(secs),
+                w0 + chrono::Duration::seconds(secs as i64),
+            )
+        };
+        hold_down.start(t0, w0);
+
+        let (now, wall) = at(300);
+        hold_down.record_flap(now, wall);
+        let (now, wall) = at(700);
+        assert_eq!(hold_down.poll(now, wall), None);
+        assert!(hold_down.is_active());
+
+        // The wall clock jumped an hour while the window ran
+        let (now, wall) = at(900);
+        assert_eq!(
+            hold_down.poll(now, wall + chrono::Duration::hours(1)),
+            Some(HoldDownEvent::ClockSkew)
+        );
+        let (now, wall) = at(1500);
+        assert_eq!(
+            hold_down.poll(now, wall + chrono::Duration::hours(1)),
+            Some(HoldDownEvent::Expired)
+        );
+        assert!(!hold_down.is_active());
+        assert_eq!(hold_down.flaps(), 1);
+
+        // Disabled, nothing is ever held
+        let mut disabled = HoldDown::new(HoldDownConfig {
+            duration_secs: 0,
+            prepend: 2,
+        });
+        disabled.start(t0, w0);
+        assert!(!disabled.is_active());
+    }
+}
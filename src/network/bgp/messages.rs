@@ -3,7 +3,7 @@ use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BGPMessage {
     Open(OpenMessage),
     Update(UpdateMessage),
@@ -11,7 +11,7 @@ pub enum BGPMessage {
     Keepalive,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OpenMessage {
     pub version: u8,
     pub my_asn: u32,
@@ -20,21 +20,21 @@ pub struct OpenMessage {
     pub optional_parameters: Vec<OptionalParameter>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OptionalParameter {
     pub parameter_type: u8,
     pub parameter_length: u8,
     pub parameter_value: Vec<u8>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UpdateMessage {
     pub withdrawn_routes: Vec<IpNet>,
     pub path_attributes: Vec<PathAttribute>,
     pub network_layer_reachability_info: Vec<IpNet>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PathAttribute {
     pub flags: u8,
     pub type_code: u8,
@@ -42,7 +42,7 @@ pub struct PathAttribute {
     pub value: AttributeValue,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum AttributeValue {
     Origin(BGPOrigin),
     AsPath(Vec<u32>),
@@ -53,7 +53,7 @@ pub enum AttributeValue {
     Unknown(Vec<u8>),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct NotificationMessage {
     pub error_code: u8,
     pub error_subcode: u8,
@@ -80,7 +80,7 @@ impl BGPMessage {
         let mut path_attributes = Vec::new();
 
         for route in routes {
-            nlri.push(route.network);
+            nlri.push(route.network.net());
 
             // Add ORIGIN attribute
             path_attributes.push(PathAttribute {
@@ -95,7 +95,7 @@ impl BGPMessage {
                 flags: 0x40,  // Well-known mandatory
                 type_code: 2, // AS_PATH
                 length: (route.as_path.len() * 4) as u16,
-                value: AttributeValue::AsPath(route.as_path),
+                value: AttributeValue::AsPath(route.as_path.to_vec()),
             });
 
             // Add NEXT_HOP attribute
@@ -159,6 +159,10 @@ pub const BGP_ERROR_HOLD_TIMER_EXPIRED: u8 = 4;
 pub const BGP_ERROR_FSM: u8 = 5;
 pub const BGP_ERROR_CEASE: u8 = 6;
 
+// Cease subcode of our own, well above those RFC 4486 assigns: the peer's
+// tier may not peer with ours
+pub const CEASE_TIER_VIOLATION: u8 = 200;
+
 // BGP Attribute Types
 pub const BGP_ATTR_ORIGIN: u8 = 1;
 pub const BGP_ATTR_AS_PATH: u8 = 2;
@@ -1,15 +1,81 @@
-use ipnet::IpNet;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, watch, RwLock};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
 
+use crate::config::{
+    AdaptiveTimersConfig, HoldDownConfig, MultihomingConfig, PeeringConfig, QuarantineConfig,
+    RejectionJournalConfig, RoutingConfig, SessionLimitsConfig, TableSyncConfig,
+};
+use crate::monitoring::capture::Capture;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::acl::{Acl, Contact};
+use crate::network::adaptive::{AdaptiveTimers, LinkTimers};
+use crate::network::ike::encap::{self, EncapEndpoint, StreamKind};
+use crate::network::ike::resumption::{PeerIdentity, Transcript};
+use crate::network::ike::tunnels::TunnelStatusChanged;
+use crate::network::obfuscation::Jitter;
+use crate::network::transport::TransportError;
+use crate::node::admin::AdminRegistry;
+use crate::node::capabilities::Capabilities;
+use crate::node::quarantine::{Quarantine, ViolationKind};
+use crate::node::{NodeId, NodeTier, PeerEvent};
+use crate::storage::Store;
+use crate::util::clock::{self, Clock, SharedClock};
+pub use as_path::AsPath;
+use communities::{CommunityMap, CommunityPolicy};
+use default_route::{DefaultOriginator, DefaultRouteMonitor, DefaultRouteStatus};
+use explain::Explanation;
+use export::{ExportPolicies, ExportPolicy};
+use messages::BGP_ERROR_MESSAGE_HEADER;
+use multihoming::{Failover, Multihoming, MultihomingStatus, ParentHealth};
+use peering::PeeringGuard;
+use pins::RoutePin;
+use policy::{DryRunReport, PolicyFragment};
+pub use prefix::Prefix;
+use propagation::PropagationStats;
+use protocol::{BGPMessage, BGPMessageType, BGPProtocol, BGPStream};
+use rib::Rib;
+use routing::RoutingPolicy;
+use session_table::SessionTable;
+use table_sync::{SyncProgress, TableSyncs};
+use tunnel_gate::TunnelGate;
+use validator::RouteValidator;
+
+pub mod as_path;
+pub mod communities;
+pub mod default_route;
+pub mod explain;
+pub mod export;
+#[cfg(feature = "external-bgp")]
+pub mod external;
+pub mod hold_down;
 pub mod messages;
+pub mod multihoming;
+pub mod next_hop;
+pub mod pacing;
+pub mod peering;
+pub mod pins;
+pub mod policy;
+pub mod prefix;
+pub mod propagation;
 pub mod protocol;
+pub mod rib;
 pub mod routing;
 pub mod session;
+pub mod session_table;
+pub mod table_sync;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tunnel_gate;
+pub mod validator;
+pub mod wire;
 
 #[derive(Debug, Clone)]
 pub struct BGPSession {
@@ -17,12 +83,23 @@ pub struct BGPSession {
     pub local_asn: u32,
     pub peer_ip: IpAddr,
     pub state: BGPSessionState,
-    pub route_table: Arc<RwLock<RouteTable>>,
+    pub rib: Arc<RwLock<Rib>>,
     pub hold_time: u16,
     pub keepalive_time: u16,
+    /// What the peer advertised in its OPEN
+    pub peer_capabilities: Capabilities,
+    /// Resumed from a ticket rather than negotiated afresh
+    pub resumed: bool,
+    /// Version of our route table the peer still holds from before; 0 when
+    /// it needs all of it
+    pub peer_table_version: u64,
+    /// Our address as the peer saw the connection, if it said
+    pub observed_addr: Option<IpAddr>,
+    /// Where the peer's copy of an interrupted table sync from us stopped
+    pub table_sync_resume: Option<table_sync::SyncResume>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BGPSessionState {
     Idle,
     Connect,
@@ -30,62 +107,288 @@ pub enum BGPSessionState {
     OpenSent,
     OpenConfirm,
     Established,
+    /// Was established, but the tunnel it requires failed; the peer's
+    /// routes are held until the tunnel recovers
+    Suspended,
 }
 
+/// Versions of a route table remembered, so a resumed peer can be sent
+/// only what changed since the version it holds
+pub const TABLE_HISTORY: usize = 4096;
+
 #[derive(Debug, Clone)]
 pub struct RouteTable {
-    pub routes: HashMap<IpNet, RouteEntry>,
+    pub routes: HashMap<Prefix, RouteEntry>,
     pub version: u64,
+    /// The prefix each recent version changed, oldest first
+    history: VecDeque<(u64, Prefix)>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct RouteEntry {
-    pub network: IpNet,
+    pub network: Prefix,
     pub next_hop: IpAddr,
-    pub as_path: Vec<u32>,
+    pub as_path: AsPath,
     pub origin: BGPOrigin,
     pub local_pref: u32,
     pub med: u32,
     pub communities: Vec<Community>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Session the route was learned over; `None` when originated locally
+    #[serde(default)]
+    pub learned_from: Option<PeerRef>,
+    /// When the origin stamped the route, in this node's clock; `None`
+    /// unless the origin traces propagation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub originated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// One session with a peer, as recorded on the routes it sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub struct PeerRef {
+    pub asn: u32,
+    pub node_id: Option<NodeId>,
+    pub session_id: Uuid,
+}
+
+impl PeerRef {
+    pub fn new(asn: u32, node_id: Option<NodeId>) -> Self {
+        PeerRef {
+            asn,
+            node_id,
+            session_id: Uuid::new_v4(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum BGPOrigin {
     IGP = 0, // Interior Gateway Protocol
     EGP = 1, // Exterior Gateway Protocol
     Incomplete = 2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct Community {
     pub asn: u16,
     pub value: u16,
 }
 
+impl std::fmt::Display for Community {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.asn, self.value)
+    }
+}
+
+/// `asn:value`, or the name of a well-known community (`prefer`, ...)
+impl std::str::FromStr for Community {
+    type Err = BGPError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, community)) = communities::WELL_KNOWN
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        {
+            return Ok(community.clone());
+        }
+        let invalid = || BGPError::InvalidCommunity {
+            value: s.to_string(),
+        };
+        let (asn, value) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Community {
+            asn: asn.trim().parse().map_err(|_| invalid())?,
+            value: value.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum BGPError {
-    #[error("Connection error: {0}")]
-    Connection(String),
-    #[error("Protocol error: {0}")]
-    Protocol(String),
+    #[error("Cannot reach BGP peer {addr}")]
+    PeerUnreachable {
+        addr: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Timed out connecting to BGP peer {addr}")]
+    ConnectTimeout { addr: SocketAddr },
+    #[error("Invalid BGP peer address {address}")]
+    InvalidPeerAddress {
+        address: String,
+        #[source]
+        source: std::net::AddrParseError,
+    },
+    #[error("Hold timer expired for BGP peer {peer}")]
+    HoldTimerExpired { peer: SocketAddr },
+    #[error("BGP peer {peer} announced ASN {announced}, expected {expected}")]
+    PeerAsnMismatch {
+        peer: SocketAddr,
+        announced: u32,
+        expected: u32,
+    },
+    #[error("BGP peer {peer} sent NOTIFICATION (code {code}, subcode {subcode})")]
+    Notification {
+        peer: SocketAddr,
+        code: u8,
+        subcode: u8,
+    },
+    #[error("Refused BGP session from {peer}: ASN {asn} is a {theirs:?} node, and {ours:?} nodes cannot peer with {theirs:?} nodes")]
+    TierViolation {
+        peer: SocketAddr,
+        asn: u32,
+        ours: NodeTier,
+        theirs: NodeTier,
+    },
+    #[error("BGP peer {peer} refused the session: {theirs:?} nodes do not accept {ours:?} peers")]
+    TierRefused {
+        peer: SocketAddr,
+        ours: NodeTier,
+        theirs: NodeTier,
+    },
+    #[error("Expected {expected} from BGP peer {peer}, got {got}")]
+    UnexpectedMessage {
+        peer: SocketAddr,
+        expected: &'static str,
+        got: String,
+    },
+    #[error("Refused BGP session with AS{asn}: it is administratively down")]
+    AdminDown { asn: u32 },
+    #[error("Refused BGP session with AS{asn}: it is quarantined")]
+    Quarantined { asn: u32 },
+    #[error("No tunnel to BGP peer {peer} was established within {hold:?}")]
+    NoTunnel {
+        peer: SocketAddr,
+        hold: std::time::Duration,
+    },
+    #[error("Cannot carry BGP through the tunnel")]
+    Tunnel(#[source] crate::network::ike::IKEError),
+    #[error("BGP session with {peer} is not established")]
+    SessionNotEstablished { peer: IpAddr },
+    #[error("No routes received from ASN {peer_asn}")]
+    UnknownPeer { peer_asn: u32 },
+    #[error("{network} is in none of the RIBs")]
+    UnknownPrefix { network: Prefix },
+    #[error("Cannot persist route pins")]
+    PinPersist(#[source] crate::storage::StorageError),
+    #[error("Cannot persist table sync progress")]
+    SyncPersist(#[source] crate::storage::StorageError),
+    #[error("Table sync from AS{peer_asn} failed: {reason}")]
+    TableSync { peer_asn: u32, reason: String },
+    #[error("Invalid community {value:?}; expected asn:value or prefer, normal or backup")]
+    InvalidCommunity { value: String },
+    #[error("Max-prefix limit of {limit} exceeded")]
+    MaxPrefixExceeded { limit: usize },
+    #[error("Protocol error (code {code}, subcode {subcode}): {reason}")]
+    Protocol {
+        code: u8,
+        subcode: u8,
+        reason: String,
+    },
     #[error("Configuration error: {0}")]
     Configuration(String),
     #[error("Route error: {0}")]
     Route(String),
-    #[error("IO error: {0}")]
+    #[error(transparent)]
+    Transport(TransportError),
+    #[error(transparent)]
     IO(#[from] std::io::Error),
-    #[error("Serialization error: {0}")]
+    #[error(transparent)]
     Serialization(#[from] serde_json::Error),
 }
 
+impl From<TransportError> for BGPError {
+    fn from(e: TransportError) -> Self {
+        match e {
+            TransportError::Connect { peer, source } => {
+                BGPError::PeerUnreachable { addr: peer, source }
+            }
+            TransportError::ConnectTimeout { peer } => BGPError::ConnectTimeout { addr: peer },
+            e @ (TransportError::FrameTooLarge { .. } | TransportError::BadLength { .. }) => {
+                BGPError::Protocol {
+                    code: BGP_ERROR_MESSAGE_HEADER,
+                    subcode: wire::HEADER_BAD_MESSAGE_LENGTH,
+                    reason: e.to_string(),
+                }
+            }
+            e @ TransportError::BadMarker => BGPError::Protocol {
+                code: BGP_ERROR_MESSAGE_HEADER,
+                subcode: wire::HEADER_CONNECTION_NOT_SYNCHRONIZED,
+                reason: e.to_string(),
+            },
+            TransportError::Tunnel(e) => BGPError::Tunnel(e),
+            TransportError::IO(e) => BGPError::IO(e),
+            TransportError::Serialization(e) => BGPError::Serialization(e),
+            other => BGPError::Transport(other),
+        }
+    }
+}
+
+impl BGPError {
+    /// Whether the peer sent something that could not be decoded or broke
+    /// the protocol, as counts toward its quarantine
+    pub fn is_malformed_input(&self) -> bool {
+        matches!(
+            self,
+            BGPError::Protocol { .. }
+                | BGPError::UnexpectedMessage { .. }
+                | BGPError::Serialization(_)
+                | BGPError::Transport(TransportError::UnknownTag { .. })
+                | BGPError::Transport(TransportError::Inflate(_))
+        )
+    }
+
+    /// Whether trying the same peer again later could succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            BGPError::PeerUnreachable { .. }
+                | BGPError::ConnectTimeout { .. }
+                | BGPError::HoldTimerExpired { .. }
+                | BGPError::Notification { .. }
+                | BGPError::NoTunnel { .. }
+                | BGPError::Transport(TransportError::Timeout { .. })
+                | BGPError::IO(_)
+        )
+    }
+}
+
+/// Peer address (when known) and task handle of each external session
+type ExternalSessions = HashMap<u32, (Option<IpAddr>, AbortHandle)>;
+
 pub struct BGPDaemon {
     local_asn: u32,
-    #[allow(dead_code)]
     router_id: IpAddr,
     listen_port: u16,
-    sessions: Arc<RwLock<HashMap<IpAddr, BGPSession>>>,
-    route_table: Arc<RwLock<RouteTable>>,
+    /// Inbound connections and the sessions on them
+    sessions: Arc<RwLock<SessionTable>>,
+    /// Running external sessions by peer ASN, so they can be cut off
+    external_sessions: Arc<RwLock<ExternalSessions>>,
+    rib: Arc<RwLock<Rib>>,
+    default_originator: DefaultOriginator,
+    default_routes: Arc<RwLock<DefaultRouteMonitor>>,
+    acl: Arc<Acl>,
+    /// Peers the operator took down, refused until enabled
+    admin: Arc<AdminRegistry>,
+    bound: OnceLock<SocketAddr>,
+    /// Whether our routes are still exported as a last resort
+    held_down: Arc<watch::Sender<bool>>,
+    keepalive_jitter: Jitter,
+    /// Advertised in our OPEN to connecting peers
+    capabilities: Capabilities,
+    /// Takes tunnel streams arriving on the BGP port in single-port mode
+    encapsulation: Option<Arc<EncapEndpoint>>,
+    /// Holds sessions until a tunnel to the peer is up, when required
+    tunnel_gate: Option<Arc<TunnelGate>>,
+    /// Consulted on routes learned from peers, when configured
+    validator: Option<Arc<RouteValidator>>,
+    /// Full tables peers are sending us, and how far each got
+    table_syncs: Arc<TableSyncs>,
+    /// Records what sessions receive, when capturing
+    capture: Option<Arc<Capture>>,
+    /// Times sessions, damping and hold-down; the system clock unless set
+    clock: SharedClock,
+    /// Keepalive intervals earned by each peer's link; off unless set
+    adaptive: Arc<AdaptiveTimers>,
 }
 
 impl BGPDaemon {
@@ -94,43 +397,656 @@ impl BGPDaemon {
             local_asn,
             router_id,
             listen_port,
-            sessions: Arc::new(RwLock::new(HashMap::new())),
-            route_table: Arc::new(RwLock::new(RouteTable::new())),
+            sessions: Arc::default(),
+            external_sessions: Arc::new(RwLock::new(HashMap::new())),
+            rib: Arc::new(RwLock::new(Rib::new(RoutingPolicy::new(
+                local_asn,
+                NodeTier::from_asn(local_asn),
+            )))),
+            default_originator: DefaultOriginator::new(local_asn, router_id, true),
+            default_routes: Arc::new(RwLock::new(DefaultRouteMonitor::new(
+                default_route::DEFAULT_ROUTE_GRACE,
+                std::time::Instant::now(),
+            ))),
+            acl: Arc::new(Acl::default()),
+            admin: Arc::new(AdminRegistry::default()),
+            bound: OnceLock::new(),
+            held_down: Arc::new(watch::channel(false).0),
+            keepalive_jitter: Jitter::default(),
+            capabilities: Capabilities::default(),
+            encapsulation: None,
+            tunnel_gate: None,
+            validator: None,
+            table_syncs: Arc::default(),
+            capture: None,
+            clock: clock::system(),
+            adaptive: Arc::new(AdaptiveTimers::new(
+                AdaptiveTimersConfig {
+                    enabled: false,
+                    ..Default::default()
+                },
+                NodeTier::from_asn(local_asn),
+            )),
+        }
+    }
+
+    /// Run timers on `clock`; set before `start` and before any session
+    pub async fn set_clock(&mut self, clock: SharedClock) {
+        self.rib.write().await.set_clock(Arc::clone(&clock));
+        self.default_routes = Arc::new(RwLock::new(DefaultRouteMonitor::new(
+            default_route::DEFAULT_ROUTE_GRACE,
+            clock.now_monotonic(),
+        )));
+        self.clock = clock;
+    }
+
+    /// Refuse connections and routes from peers the ACL blocks
+    pub async fn set_acl(&mut self, acl: Arc<Acl>) {
+        self.rib.write().await.set_acl(Arc::clone(&acl));
+        self.acl = acl;
+    }
+
+    /// Refuse sessions with peers the operator took down
+    pub fn set_admin(&mut self, admin: Arc<AdminRegistry>) {
+        self.admin = admin;
+    }
+
+    /// Score peers' violations in `quarantine`, demoting their routes and
+    /// refusing their sessions as it says
+    pub async fn set_quarantine(&self, quarantine: Arc<Quarantine>) {
+        self.rib.write().await.set_quarantine(quarantine);
+    }
+
+    /// Use or stop using a peer's routes as a last resort after its
+    /// quarantine score changed outside an UPDATE, as when cleared
+    pub async fn follow_standing(&self, peer_asn: u32) -> Result<(), BGPError> {
+        self.rib.write().await.follow_standing(peer_asn)
+    }
+
+    /// Apply new scoring thresholds; scores already kept stay
+    pub async fn set_quarantine_config(&self, config: QuarantineConfig) {
+        self.rib.read().await.quarantine().set_config(config);
+    }
+
+    /// Capabilities to answer connecting peers' OPENs with
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Share the listening port with TCP-encapsulated tunnel streams
+    pub fn set_encapsulation(&mut self, endpoint: Arc<EncapEndpoint>) {
+        self.encapsulation = Some(endpoint);
+    }
+
+    /// Exchange routes with connecting peers only once a tunnel to them is
+    /// established
+    pub fn set_tunnel_gate(&mut self, gate: TunnelGate) {
+        self.tunnel_gate = Some(Arc::new(gate));
+    }
+
+    /// Have an external validator judge routes learned from now on
+    pub fn set_validator(&mut self, validator: RouteValidator) {
+        self.validator = Some(Arc::new(validator));
+    }
+
+    /// Record messages received on sessions started from now on
+    pub fn set_capture(&mut self, capture: Arc<Capture>) {
+        self.capture = Some(capture);
+    }
+
+    /// Bound how many inbound connections are tracked, and how long one
+    /// may take to establish its session
+    pub async fn set_session_limits(&self, limits: SessionLimitsConfig) {
+        self.sessions.write().await.set_limits(limits);
+    }
+
+    /// Inbound connections tracked, with or without an established session
+    pub async fn connection_count(&self) -> usize {
+        self.sessions.read().await.len()
+    }
+
+    /// Inbound connections refused because too many were tracked
+    pub async fn connections_rejected(&self) -> u64 {
+        self.sessions.read().await.rejected()
+    }
+
+    /// Page table syncs with `config`, and resume those from peers that a
+    /// restart interrupted
+    pub fn open_table_syncs(
+        &mut self,
+        config: TableSyncConfig,
+        store: &Store,
+    ) -> Result<(), BGPError> {
+        self.table_syncs = Arc::new(TableSyncs::open(
+            config,
+            Some(store.namespace(table_sync::NAMESPACE)),
+        )?);
+        Ok(())
+    }
+
+    /// How far the full table from `peer_asn` has arrived, if it was sent
+    /// in chunks
+    pub fn table_sync_progress(&self, peer_asn: u32) -> Option<SyncProgress> {
+        self.table_syncs.progress(peer_asn)
+    }
+
+    /// Vary keepalive intervals on external sessions started from now on
+    pub fn set_keepalive_jitter(&mut self, jitter: Jitter) {
+        self.keepalive_jitter = jitter;
+    }
+
+    /// Stretch keepalives on stable links per `config`; set after the
+    /// clock and before `start`
+    pub fn set_adaptive_timers(&mut self, config: AdaptiveTimersConfig) {
+        self.adaptive = Arc::new(
+            AdaptiveTimers::new(config, NodeTier::from_asn(self.local_asn))
+                .with_clock(Arc::clone(&self.clock)),
+        );
+    }
+
+    pub fn adaptive_timers(&self) -> &Arc<AdaptiveTimers> {
+        &self.adaptive
+    }
+
+    /// The intervals the link to `peer` is probed at, when they adapt
+    pub fn link_timers(&self, peer: IpAddr) -> Option<LinkTimers> {
+        self.adaptive.status(peer)
+    }
+
+    /// Address the listener is bound to, once started
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.bound.get().copied()
+    }
+
+    /// Addresses of peers with an internal session on a live connection
+    pub async fn session_peers(&self) -> Vec<IpAddr> {
+        self.sessions.read().await.peers()
+    }
+
+    /// Where the internal session with a peer stands, if there is one
+    pub async fn session_state(&self, peer: IpAddr) -> Option<BGPSessionState> {
+        self.sessions.read().await.state(peer)
+    }
+
+    /// Peers with an Established session, internal or external; external
+    /// sessions are only kept once their OPENs were exchanged
+    pub async fn established_sessions(&self) -> usize {
+        let internal = self.sessions.read().await.established();
+        internal + self.external_sessions.read().await.len()
+    }
+
+    /// Tear down sessions with peers the ACL now refuses, dropping their
+    /// routes, and return how many were ended
+    pub async fn enforce_acl(&self) -> Result<usize, BGPError> {
+        let mut ended: Vec<(u32, Option<IpAddr>)> = self
+            .sessions
+            .write()
+            .await
+            .end_where(|session| {
+                self.acl.permits(&Contact {
+                    asn: Some(session.peer_asn),
+                    addr: Some(session.peer_ip),
+                    node_id: None,
+                })
+            })
+            .into_iter()
+            .map(|session| (session.peer_asn, Some(session.peer_ip)))
+            .collect();
+        self.external_sessions
+            .write()
+            .await
+            .retain(|peer_asn, (peer_ip, handle)| {
+                let contact = Contact {
+                    asn: Some(*peer_asn),
+                    addr: *peer_ip,
+                    node_id: None,
+                };
+                let permitted = self.acl.permits(&contact);
+                if !permitted {
+                    handle.abort();
+                    ended.push((*peer_asn, *peer_ip));
+                }
+                permitted
+            });
+
+        let mut rib = self.rib.write().await;
+        for (peer_asn, peer_ip) in &ended {
+            rib.peer_down(*peer_asn)?;
+            tracing::warn!(
+                target: "audit",
+                "Tore down BGP session with AS{}{} refused by the ACL",
+                peer_asn,
+                peer_ip.map(|ip| format!(" ({})", ip)).unwrap_or_default()
+            );
+        }
+        Ok(ended.len())
+    }
+
+    /// Replace the session with a peer that moved by one to its new
+    /// address, keeping the routes it sent with their next hops refreshed;
+    /// returns how many routes were kept
+    pub async fn migrate_peer(
+        &self,
+        node_id: NodeId,
+        peer_asn: u32,
+        old: IpAddr,
+        new: IpAddr,
+    ) -> Result<usize, BGPError> {
+        let closed = self
+            .sessions
+            .write()
+            .await
+            .end_where(|session| session.peer_ip != old);
+        if !closed.is_empty() {
+            tracing::info!("Closed BGP session with AS{} at {}", peer_asn, old);
+        }
+        let mut session = BGPSession::new(self.local_asn, peer_asn, new, Arc::clone(&self.rib));
+        session.establish().await?;
+        self.sessions.write().await.open(
+            SocketAddr::new(new, 0),
+            session,
+            self.clock.now_monotonic(),
+        );
+        self.rib.write().await.peer_up(peer_asn, Some(node_id));
+
+        let (kept, routes) = {
+            let mut rib = self.rib.write().await;
+            let kept = rib.rehome_peer(peer_asn, old, new)?;
+            let routes: Vec<RouteEntry> = rib
+                .received(peer_asn)
+                .map(|adj_in| adj_in.routes().into_iter().cloned().collect())
+                .unwrap_or_default();
+            (kept, routes)
+        };
+
+        // Record the new address first so the default never looks lost
+        let mut default_routes = self.default_routes.write().await;
+        let now = self.clock.now_monotonic();
+        default_routes.record_routes(new, &routes, now);
+        default_routes.peer_down(old, now);
+
+        tracing::warn!(
+            target: "audit",
+            "Moved BGP session with AS{} from {} to {}, keeping {} routes",
+            peer_asn,
+            old,
+            new,
+            kept
+        );
+        Ok(kept)
+    }
+
+    /// Close every session with a peer that left on purpose and withdraw
+    /// the routes it sent; returns how many routes were dropped
+    pub async fn drop_peer(&self, peer_asn: u32) -> Result<usize, BGPError> {
+        self.close_peer(peer_asn, false).await
+    }
+
+    /// Close every session with a peer whose session failed, withdrawing
+    /// its routes and counting the flap; returns how many routes were dropped
+    pub async fn session_failed(&self, peer_asn: u32) -> Result<usize, BGPError> {
+        self.close_peer(peer_asn, true).await
+    }
+
+    async fn close_peer(&self, peer_asn: u32, failed: bool) -> Result<usize, BGPError> {
+        let mut closed: Vec<IpAddr> = self
+            .sessions
+            .write()
+            .await
+            .end_where(|session| session.peer_asn != peer_asn)
+            .into_iter()
+            .map(|session| session.peer_ip)
+            .collect();
+        if let Some((peer_ip, handle)) = self.external_sessions.write().await.remove(&peer_asn) {
+            handle.abort();
+            closed.extend(peer_ip);
+        }
+
+        // With its routes gone, the peer has nothing to resume
+        if let Some(gate) = &self.tunnel_gate {
+            gate.forget(peer_asn);
+        }
+        let dropped = {
+            let mut rib = self.rib.write().await;
+            let dropped = rib.routes_from(peer_asn);
+            if failed {
+                rib.peer_down(peer_asn)?;
+            } else {
+                rib.peer_left(peer_asn)?;
+            }
+            dropped
+        };
+        let mut default_routes = self.default_routes.write().await;
+        let now = self.clock.now_monotonic();
+        for peer_ip in &closed {
+            default_routes.peer_down(*peer_ip, now);
+            if failed {
+                self.adaptive.flap(*peer_ip);
+            }
+        }
+
+        tracing::info!(
+            "Closed {} BGP sessions with AS{}, withdrawing {} routes",
+            closed.len(),
+            peer_asn,
+            dropped
+        );
+        Ok(dropped)
+    }
+
+    /// How many routes a peer has sent that we hold
+    pub async fn routes_from(&self, peer_asn: u32) -> usize {
+        self.rib.read().await.routes_from(peer_asn)
+    }
+
+    /// How many routes the tier policy and import rules rejected as the
+    /// peer's current session sent them; they are held as received but never
+    /// selected or passed on
+    pub async fn rejected_from(&self, peer_asn: u32) -> usize {
+        self.rib.read().await.rejected_from(peer_asn)
+    }
+
+    /// How many of a peer's sessions have ended in failure
+    pub async fn flaps(&self, peer_asn: u32) -> u32 {
+        self.rib.read().await.flaps(peer_asn)
+    }
+
+    /// Apply an UPDATE received from a peer
+    pub async fn receive_update(
+        &self,
+        peer_asn: u32,
+        announced: Vec<RouteEntry>,
+        withdrawn: &[Prefix],
+    ) -> Result<(), BGPError> {
+        let Some(validator) = &self.validator else {
+            return self
+                .rib
+                .write()
+                .await
+                .receive(peer_asn, announced, withdrawn);
+        };
+        let screened = validator.screen(peer_asn, announced).await;
+        let mut rib = self.rib.write().await;
+        rib.refuse(peer_asn, screened.refused)?;
+        rib.receive(peer_asn, screened.accepted, withdrawn)
+    }
+
+    /// Apply a message a session with `peer_asn` received, as the session
+    /// would; only UPDATEs change anything
+    pub async fn receive_message(&self, peer_asn: u32, msg: &BGPMessage) -> Result<(), BGPError> {
+        match msg.message_type {
+            BGPMessageType::Update => {
+                self.rib
+                    .write()
+                    .await
+                    .observe_peer_clock(peer_asn, msg.timestamp)?;
+                self.receive_update(peer_asn, msg.announced(), &msg.withdrawn)
+                    .await
+            }
+            _ => Ok(()),
         }
     }
 
+    /// Keep sessions in step with the node's peers: follow peers that were
+    /// re-authenticated at a new address and drop those that were removed
+    /// or said goodbye
+    pub fn follow_peer_events(self: &Arc<Self>, mut events: broadcast::Receiver<PeerEvent>) {
+        let daemon = Arc::clone(self);
+        crash::spawn(Subsystem::Bgp, "peer-event-follower", async move {
+            loop {
+                match events.recv().await {
+                    Ok(PeerEvent::AddressChanged {
+                        peer_id,
+                        peer_asn,
+                        old,
+                        new,
+                    }) => {
+                        if let Err(e) = daemon.migrate_peer(peer_id, peer_asn, old, new).await {
+                            tracing::warn!(
+                                "Failed to move BGP session with AS{} to {}: {}",
+                                peer_asn,
+                                new,
+                                crate::error::Report(&e)
+                            );
+                        }
+                    }
+                    Ok(
+                        PeerEvent::Removed { peer_asn, .. }
+                        | PeerEvent::Departed { peer_asn, .. }
+                        | PeerEvent::AdminDown { peer_asn }
+                        | PeerEvent::Quarantined { peer_asn },
+                    ) => {
+                        if let Err(e) = daemon.drop_peer(peer_asn).await {
+                            tracing::warn!(
+                                "Failed to withdraw routes from AS{}: {}",
+                                peer_asn,
+                                crate::error::Report(&e)
+                            );
+                        }
+                    }
+                    // The peer swapped out was followed through its removal
+                    Ok(PeerEvent::Swapped { .. }) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Missed {} peer events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Stop using routes via peers we have no working tunnel to, per the
+    /// routing config
+    pub async fn set_tunnel_requirements(&self, config: &RoutingConfig) -> Result<(), BGPError> {
+        self.rib.write().await.set_tunnel_requirements(config)
+    }
+
+    /// Keep route selection in step with tunnel status, starting from the
+    /// tunnels in `current`; see `TunnelManager::follow_status`
+    pub async fn follow_tunnel_events(
+        self: &Arc<Self>,
+        current: Vec<TunnelStatusChanged>,
+        mut events: broadcast::Receiver<TunnelStatusChanged>,
+    ) -> Result<(), BGPError> {
+        {
+            let mut rib = self.rib.write().await;
+            let now = self.clock.now_monotonic();
+            for change in &current {
+                rib.tunnel_changed(change, now)?;
+            }
+        }
+
+        let rib = Arc::clone(&self.rib);
+        let clock = Arc::clone(&self.clock);
+        crash::spawn(Subsystem::Bgp, "tunnel-event-follower", async move {
+            // Damped tunnels are released on this tick
+            let mut ticker = clock::interval(&clock, std::time::Duration::from_secs(1));
+            loop {
+                let result = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(change) => rib
+                            .write()
+                            .await
+                            .tunnel_changed(&change, clock.now_monotonic()),
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            tracing::warn!("Missed {} tunnel status changes", missed);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = ticker.tick() => rib.write().await.poll_tunnels(clock.now_monotonic()),
+                };
+                if let Err(e) = result {
+                    tracing::warn!(
+                        "Failed to reselect routes after a tunnel change: {}",
+                        crate::error::Report(&e)
+                    );
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Whether a Regional node originates the VX0 default toward Edge peers
+    pub fn set_originate_default_to_edge(&mut self, enabled: bool) {
+        self.default_originator = DefaultOriginator::new(self.local_asn, self.router_id, enabled);
+    }
+
     pub async fn start(&self) -> Result<(), BGPError> {
         let listen_addr = format!("0.0.0.0:{}", self.listen_port);
         let listener = TcpListener::bind(&listen_addr).await?;
+        let _ = self.bound.set(listener.local_addr()?);
 
         tracing::info!("BGP daemon listening on {}", listen_addr);
 
+        if (66000..=69999).contains(&self.local_asn) {
+            let default_routes = Arc::clone(&self.default_routes);
+            let clock = Arc::clone(&self.clock);
+            crash::spawn_restartable(Subsystem::Bgp, "default-route-monitor", move || {
+                let default_routes = Arc::clone(&default_routes);
+                let clock = Arc::clone(&clock);
+                async move {
+                    let mut interval = clock::interval(&clock, std::time::Duration::from_secs(10));
+                    loop {
+                        interval.tick().await;
+                        default_routes.write().await.check(clock.now_monotonic());
+                    }
+                }
+            });
+        }
+
+        let rib = Arc::clone(&self.rib);
+        let held_down = Arc::clone(&self.held_down);
+        let clock = Arc::clone(&self.clock);
+        crash::spawn_restartable(Subsystem::Bgp, "hold-down-monitor", move || {
+            let rib = Arc::clone(&rib);
+            let held_down = Arc::clone(&held_down);
+            let clock = Arc::clone(&clock);
+            async move {
+                let mut interval = clock::interval(&clock, std::time::Duration::from_secs(10));
+                loop {
+                    interval.tick().await;
+                    let mut rib = rib.write().await;
+                    if rib.held_down() {
+                        rib.poll_hold_down(clock.now_monotonic(), clock.now_utc());
+                        let still_held = rib.held_down();
+                        held_down.send_if_modified(|held| {
+                            let changed = *held != still_held;
+                            *held = still_held;
+                            changed
+                        });
+                    }
+                }
+            }
+        });
+
+        // Pins carry the operator's wall-clock expiry, so they follow it
+        let rib = Arc::clone(&self.rib);
+        let clock = Arc::clone(&self.clock);
+        crash::spawn_restartable(Subsystem::Bgp, "route-pin-expiry", move || {
+            let rib = Arc::clone(&rib);
+            let clock = Arc::clone(&clock);
+            async move {
+                let mut interval = clock::interval(&clock, pins::PIN_EXPIRY_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = rib.write().await.expire_pins(clock.now_utc()) {
+                        tracing::error!("Failed to expire route pins: {}", e);
+                    }
+                }
+            }
+        });
+
         let sessions = Arc::clone(&self.sessions);
-        let route_table = Arc::clone(&self.route_table);
-        let local_asn = self.local_asn;
+        let clock = Arc::clone(&self.clock);
+        crash::spawn_restartable(Subsystem::Bgp, "bgp-session-sweep", move || {
+            let sessions = Arc::clone(&sessions);
+            let clock = Arc::clone(&clock);
+            async move {
+                loop {
+                    let timeout = sessions.read().await.limits().setup_timeout.get();
+                    clock
+                        .sleep((timeout / 2).clamp(
+                            std::time::Duration::from_millis(10),
+                            session_table::SWEEP_INTERVAL,
+                        ))
+                        .await;
+                    let swept = sessions.write().await.sweep(clock.now_monotonic());
+                    if swept > 0 {
+                        tracing::info!(
+                            "Closed {} BGP connections that never established a session",
+                            swept
+                        );
+                    }
+                }
+            }
+        });
+
+        let sessions = Arc::clone(&self.sessions);
+        let rib = Arc::clone(&self.rib);
+        let acl = Arc::clone(&self.acl);
+        let admin = Arc::clone(&self.admin);
+        let protocol = Arc::new(self.protocol());
+        let encapsulation = self.encapsulation.clone();
+        let clock = Arc::clone(&self.clock);
 
-        tokio::spawn(async move {
+        crash::spawn(Subsystem::Bgp, "bgp-listener", async move {
             loop {
                 match listener.accept().await {
                     Ok((stream, addr)) => {
+                        if !acl.check(&Contact::address(addr.ip()), "BGP connection") {
+                            drop(stream);
+                            continue;
+                        }
+                        let now = clock.now_monotonic();
+                        if !sessions.write().await.admit(addr, now) {
+                            crate::sampled!(tracing::warn!(
+                                "Refusing BGP connection from {}: session limit reached",
+                                addr
+                            ));
+                            drop(stream);
+                            continue;
+                        }
                         tracing::info!("BGP connection from {}", addr);
 
-                        let sessions = Arc::clone(&sessions);
-                        let route_table = Arc::clone(&route_table);
+                        let tracked = Arc::clone(&sessions);
+                        let rib = Arc::clone(&rib);
+                        let protocol = Arc::clone(&protocol);
+                        let admin = Arc::clone(&admin);
+                        let encapsulation = encapsulation.clone();
+                        let clock = Arc::clone(&clock);
 
-                        tokio::spawn(async move {
-                            if let Err(e) = Self::handle_connection(
-                                stream,
-                                addr,
-                                local_asn,
-                                sessions,
-                                route_table,
+                        let task = crash::spawn(Subsystem::Bgp, "bgp-session", async move {
+                            let sessions = tracked;
+                            let mut stream = stream;
+                            if let Some(endpoint) = encapsulation {
+                                match encap::classify(&mut stream).await {
+                                    Ok(StreamKind::Bgp) => {}
+                                    Ok(StreamKind::Tunnel) => {
+                                        // Tunnel streams hold no session
+                                        sessions.write().await.close(addr);
+                                        if let Err(e) = endpoint.serve(stream, addr).await {
+                                            tracing::warn!("Tunnel stream from {}: {}", addr, e);
+                                        }
+                                        return;
+                                    }
+                                    Err(e) => {
+                                        sessions.write().await.close(addr);
+                                        tracing::debug!("Unrecognised stream from {}: {}", addr, e);
+                                        return;
+                                    }
+                                }
+                            }
+                            let result = Self::handle_connection(
+                                stream, addr, &protocol, &admin, &sessions, rib, &*clock,
                             )
-                            .await
-                            {
+                            .await;
+                            sessions.write().await.close(addr);
+                            if let Err(e) = result {
                                 tracing::error!("BGP connection error: {}", e);
                             }
                         });
+                        sessions.write().await.attach(addr, task.abort_handle());
                     }
                     Err(e) => {
                         tracing::error!("BGP listener error: {}", e);
@@ -142,73 +1058,666 @@ impl BGPDaemon {
         Ok(())
     }
 
+    /// The protocol our sessions speak, with this daemon's capabilities,
+    /// timers and checks
+    fn protocol(&self) -> BGPProtocol {
+        let mut protocol = BGPProtocol::new(
+            self.local_asn,
+            self.router_id,
+            NodeTier::from_asn(self.local_asn),
+        )
+        .with_capabilities(self.capabilities)
+        .with_keepalive_jitter(self.keepalive_jitter)
+        .with_adaptive_timers(Arc::clone(&self.adaptive))
+        .with_table_syncs(Arc::clone(&self.table_syncs));
+        if let Some(gate) = &self.tunnel_gate {
+            protocol = protocol.with_tunnel_gate(Arc::clone(gate));
+        }
+        if let Some(validator) = &self.validator {
+            protocol = protocol.with_validator(Arc::clone(validator));
+        }
+        if let Some(capture) = &self.capture {
+            protocol = protocol.with_capture(Arc::clone(capture));
+        }
+        protocol
+    }
+
     async fn handle_connection(
-        _stream: TcpStream,
+        mut stream: TcpStream,
         addr: SocketAddr,
-        local_asn: u32,
-        sessions: Arc<RwLock<HashMap<IpAddr, BGPSession>>>,
-        route_table: Arc<RwLock<RouteTable>>,
+        protocol: &BGPProtocol,
+        admin: &AdminRegistry,
+        sessions: &RwLock<SessionTable>,
+        rib: Arc<RwLock<Rib>>,
+        clock: &dyn Clock,
     ) -> Result<(), BGPError> {
         tracing::debug!("Handling BGP connection from {}", addr);
 
-        let session = BGPSession::new(local_asn, 65002, addr.ip(), Arc::clone(&route_table));
+        // Peers outside the tier rules are refused before any state exists
+        let (open, ours, encoding) = protocol.accept_open(&mut stream, addr).await?;
+        if let Some(adaptive) = protocol.adaptive_timers() {
+            adaptive.track(addr.ip(), open.asn);
+        }
+        if admin.is_down(open.asn) {
+            return Err(BGPError::AdminDown { asn: open.asn });
+        }
+        if rib.read().await.is_quarantined(open.asn) {
+            return Err(BGPError::Quarantined { asn: open.asn });
+        }
+        let mut session =
+            BGPSession::new(protocol.local_asn(), open.asn, addr.ip(), Arc::clone(&rib));
+        session.peer_capabilities = open.capabilities.unwrap_or_default();
+        session.state = BGPSessionState::OpenSent;
+        let theirs = session.peer_capabilities;
+        let hold = std::time::Duration::from_secs(session.hold_time.into());
+
+        sessions
+            .write()
+            .await
+            .open(addr, session, clock.now_monotonic());
 
-        {
-            let mut sessions = sessions.write().await;
-            sessions.insert(addr.ip(), session);
+        let gate = protocol.tunnel_gate();
+        tunnel_gate::note_requirement(addr, &theirs, gate.is_some());
+        let mut stream = match gate {
+            Some(gate) => {
+                gate.admit(addr, hold).await?;
+                if let (Some(ours), Some(hello)) = (&ours, &open.resume) {
+                    let initiator = PeerIdentity {
+                        asn: open.asn,
+                        router_id: open.router_id,
+                    };
+                    let transcript = Transcript {
+                        initiator,
+                        initiator_nonce: &hello.nonce,
+                        responder: protocol.identity(),
+                        responder_nonce: &ours.nonce,
+                    };
+                    gate.keep_ticket(addr.ip(), &transcript, initiator).await;
+                }
+                gate.stream(stream, addr.ip(), &theirs)
+            }
+            None => BGPStream::plain(stream),
         }
+        .with_encoding(encoding);
+        Self::set_session_state(sessions, addr, BGPSessionState::Established, clock).await;
 
-        // For now, just log the connection
         tracing::info!("BGP session established with {}", addr.ip());
+        let receiving = protocol.exchange_routes(&mut stream, open.asn, &rib);
+        let result = match gate {
+            Some(gate) => tokio::select! {
+                result = receiving => result,
+                () = Self::supervise_tunnel(gate, addr, open.asn, sessions, &rib, clock) => Ok(()),
+            },
+            None => receiving.await,
+        };
+        if let Err(e) = &result {
+            if let Some(adaptive) = protocol.adaptive_timers() {
+                adaptive.flap(addr.ip());
+            }
+            if e.is_malformed_input() {
+                let detail = crate::error::Report(e).to_string();
+                rib.write()
+                    .await
+                    .report_violation(open.asn, ViolationKind::Decode, detail)?;
+            }
+        }
+        result
+    }
 
-        Ok(())
+    /// Suspend a session while the tunnel it requires is down; if it stays
+    /// down past the suspend timeout, flush the peer's routes and return
+    async fn supervise_tunnel(
+        gate: &TunnelGate,
+        addr: SocketAddr,
+        peer_asn: u32,
+        sessions: &RwLock<SessionTable>,
+        rib: &RwLock<Rib>,
+        clock: &dyn Clock,
+    ) {
+        let peer = addr.ip();
+        loop {
+            gate.lost(peer).await;
+            tracing::warn!(
+                "Tunnel to BGP peer {} failed; holding its routes for up to {:?}",
+                peer,
+                gate.suspend_timeout()
+            );
+            Self::set_session_state(sessions, addr, BGPSessionState::Suspended, clock).await;
+            if gate.recovered(peer).await {
+                tracing::info!("Tunnel to BGP peer {} recovered; session resumed", peer);
+                Self::set_session_state(sessions, addr, BGPSessionState::Established, clock).await;
+                continue;
+            }
+
+            tracing::warn!(
+                "Tunnel to BGP peer {} stayed down; flushing its routes",
+                peer
+            );
+            gate.forget(peer_asn);
+            if let Err(e) = rib.write().await.peer_down(peer_asn) {
+                tracing::warn!(
+                    "Failed to flush routes from AS{}: {}",
+                    peer_asn,
+                    crate::error::Report(&e)
+                );
+            }
+            return;
+        }
+    }
+
+    async fn set_session_state(
+        sessions: &RwLock<SessionTable>,
+        addr: SocketAddr,
+        state: BGPSessionState,
+        clock: &dyn Clock,
+    ) {
+        sessions
+            .write()
+            .await
+            .set_state(addr, state, clock.now_monotonic());
     }
 
     pub async fn add_route(
         &self,
-        network: IpNet,
+        network: Prefix,
+        next_hop: IpAddr,
+        origin: BGPOrigin,
+    ) -> Result<(), BGPError> {
+        self.add_route_with_communities(network, next_hop, origin, Vec::new())
+            .await
+    }
+
+    /// Originate a route tagged with `communities`, e.g. to ask receivers
+    /// to treat it as a backup path
+    pub async fn add_route_with_communities(
+        &self,
+        network: Prefix,
         next_hop: IpAddr,
         origin: BGPOrigin,
+        communities: Vec<Community>,
     ) -> Result<(), BGPError> {
         let route = RouteEntry {
             network,
             next_hop,
-            as_path: vec![self.local_asn],
+            as_path: AsPath::new(&[self.local_asn]),
             origin,
             local_pref: 100,
             med: 0,
-            communities: vec![],
+            communities,
             timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
         };
 
-        let mut table = self.route_table.write().await;
-        table.add_route(route)?;
+        let mut rib = self.rib.write().await;
+        rib.originate(route)?;
 
         tracing::info!("Added route: {} via {}", network, next_hop);
         Ok(())
     }
 
+    /// Stop originating a locally added route
+    pub async fn withdraw_route(&self, network: &Prefix) -> Result<(), BGPError> {
+        let mut rib = self.rib.write().await;
+        rib.withdraw_local(network)?;
+
+        tracing::info!("Withdrew route: {}", network);
+        Ok(())
+    }
+
+    /// The locally originated routes, for `restore_local_routes`
+    pub async fn snapshot_local_routes(&self) -> rib::LocalRoutes {
+        self.rib.read().await.local_snapshot()
+    }
+
+    /// Undo every add and withdraw since the snapshot was taken
+    pub async fn restore_local_routes(&self, snapshot: rib::LocalRoutes) -> Result<(), BGPError> {
+        self.rib.write().await.restore_local(snapshot)
+    }
+
+    /// Version of the Loc-RIB, bumped on every change
+    pub async fn rib_version(&self) -> u64 {
+        let rib = self.rib.read().await;
+        rib.loc_rib().version
+    }
+
+    /// Start a session with the VX0 node at `addr`, applying the routes it
+    /// sends and advertising ours to it until either side ends it
+    pub async fn connect_peer(&self, addr: SocketAddr, peer_asn: u32) -> Result<(), BGPError> {
+        if !self.acl.check(&Contact::address(addr.ip()), "BGP session") {
+            return Err(BGPError::Configuration(format!(
+                "Peer {} (AS{}) is refused by the ACL",
+                addr, peer_asn
+            )));
+        }
+        if self.admin.is_down(peer_asn) {
+            return Err(BGPError::AdminDown { asn: peer_asn });
+        }
+        if self.rib.read().await.is_quarantined(peer_asn) {
+            return Err(BGPError::Quarantined { asn: peer_asn });
+        }
+
+        let protocol = self.protocol();
+        let (mut session, mut stream) = protocol.open_session(addr, peer_asn).await?;
+        session.rib = Arc::clone(&self.rib);
+        let peer_asn = session.peer_asn;
+        self.sessions
+            .write()
+            .await
+            .open(addr, session, self.clock.now_monotonic());
+
+        let rib = Arc::clone(&self.rib);
+        crash::spawn(Subsystem::Bgp, "bgp-peer-session", async move {
+            if let Err(e) = protocol.exchange_routes(&mut stream, peer_asn, &rib).await {
+                if let Some(adaptive) = protocol.adaptive_timers() {
+                    adaptive.flap(addr.ip());
+                }
+                tracing::error!(
+                    "BGP session with {} ended: {}",
+                    addr,
+                    crate::error::Report(&e)
+                );
+            }
+            if let Err(e) = rib.write().await.peer_down(peer_asn) {
+                tracing::error!("Failed to clear routes from {}: {}", addr, e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Start a session with an external router speaking RFC 4271 BGP
+    #[cfg(feature = "external-bgp")]
+    pub async fn connect_external_peer(
+        &self,
+        peer: crate::config::BGPPeerConfig,
+        hold_time: u16,
+    ) -> Result<(), BGPError> {
+        let router_id = match self.router_id {
+            IpAddr::V4(v4) => v4,
+            IpAddr::V6(_) => {
+                return Err(BGPError::Configuration(
+                    "External BGP sessions require an IPv4 router ID".to_string(),
+                ))
+            }
+        };
+
+        let peer_ip = peer.address.parse::<IpAddr>().ok();
+        let contact = Contact {
+            asn: Some(peer.asn),
+            addr: peer_ip,
+            node_id: None,
+        };
+        if !self.acl.check(&contact, "external BGP session") {
+            return Err(BGPError::Configuration(format!(
+                "Peer {} (AS{}) is refused by the ACL",
+                peer.address, peer.asn
+            )));
+        }
+        if self.admin.is_down(peer.asn) {
+            return Err(BGPError::AdminDown { asn: peer.asn });
+        }
+        if self.rib.read().await.is_quarantined(peer.asn) {
+            return Err(BGPError::Quarantined { asn: peer.asn });
+        }
+
+        let hold_time = self.adaptive.offered_hold_time(peer.asn, hold_time);
+        let mut session =
+            external::ExternalPeer::connect(self.local_asn, router_id, hold_time, &peer)
+                .await?
+                .with_keepalive_jitter(self.keepalive_jitter)
+                .with_clock(Arc::clone(&self.clock))
+                .with_adaptive_timers(Arc::clone(&self.adaptive));
+        self.rib.write().await.peer_up(session.peer_asn, None);
+
+        let local_routes: Vec<RouteEntry> = {
+            let rib = self.rib.read().await;
+            rib.local_exports()
+                .into_iter()
+                .map(|route| rib.export_for(route, session.peer_asn))
+                .collect()
+        };
+        session.advertise(&local_routes).await?;
+        self.rib
+            .write()
+            .await
+            .record_advertised(session.peer_asn, &local_routes)?;
+
+        let rib = Arc::clone(&self.rib);
+        let peer_asn = session.peer_asn;
+        let external_sessions = Arc::clone(&self.external_sessions);
+        let task = crash::spawn(Subsystem::Bgp, "external-bgp", async move {
+            if let Err(e) = session.run(Arc::clone(&rib)).await {
+                tracing::error!(
+                    "External BGP session with {} ended: {}",
+                    peer.address,
+                    crate::error::Report(&e)
+                );
+            }
+            if let Err(e) = rib.write().await.peer_down(peer_asn) {
+                tracing::error!("Failed to clear routes from {}: {}", peer.address, e);
+            }
+            external_sessions.write().await.remove(&peer_asn);
+        });
+        self.external_sessions
+            .write()
+            .await
+            .insert(peer_asn, (peer_ip, task.abort_handle()));
+
+        Ok(())
+    }
+
+    /// VX0 default to advertise to a peer, withdrawn once we lose all upstream
+    pub async fn default_route_for_peer(&self, peer_asn: u32) -> Option<RouteEntry> {
+        let rib = self.rib.read().await;
+        self.default_originator
+            .route_for_peer(rib.loc_rib(), peer_asn)
+    }
+
+    /// Note the routes a peer currently advertises to us
+    pub async fn record_peer_routes(&self, peer: IpAddr, routes: &[RouteEntry]) {
+        let mut default_routes = self.default_routes.write().await;
+        default_routes.record_routes(peer, routes, self.clock.now_monotonic());
+    }
+
+    pub async fn peer_down(&self, peer: IpAddr) {
+        let mut default_routes = self.default_routes.write().await;
+        default_routes.peer_down(peer, self.clock.now_monotonic());
+    }
+
+    pub async fn default_route_status(&self) -> DefaultRouteStatus {
+        let default_routes = self.default_routes.read().await;
+        default_routes.status(self.clock.now_monotonic())
+    }
+
+    /// Whether a peer offers us the VX0 default, whatever route we
+    /// originate for it ourselves
+    pub async fn has_learned_default(&self) -> bool {
+        let rib = self.rib.read().await;
+        rib.offered(&default_route::vx0_default())
+    }
+
+    pub async fn find_best_route(&self, destination: &IpAddr) -> Option<RouteEntry> {
+        let rib = self.rib.read().await;
+        rib.loc_rib().find_best_route(destination).cloned()
+    }
+
     pub async fn get_routes(&self) -> Vec<RouteEntry> {
-        let table = self.route_table.read().await;
-        table.routes.values().cloned().collect()
+        let rib = self.rib.read().await;
+        rib.loc_rib().routes.values().cloned().collect()
+    }
+
+    /// The Loc-RIB and every change to it from then on, taken together so
+    /// none is missed in between
+    pub async fn follow_routes(&self) -> (Vec<RouteEntry>, broadcast::Receiver<rib::RibChange>) {
+        let rib = self.rib.read().await;
+        (
+            rib.loc_rib().routes.values().cloned().collect(),
+            rib.subscribe(),
+        )
+    }
+
+    /// Adj-RIB-In for a peer: its routes as received, before policy
+    pub async fn get_received_routes(&self, peer_asn: u32) -> Option<Vec<RouteEntry>> {
+        let rib = self.rib.read().await;
+        rib.received(peer_asn)
+            .map(|adj_in| adj_in.routes().into_iter().cloned().collect())
+    }
+
+    /// Adj-RIB-Out for a peer: the routes we have advertised to it
+    pub async fn get_advertised_routes(&self, peer_asn: u32) -> Option<Vec<RouteEntry>> {
+        let rib = self.rib.read().await;
+        rib.advertised(peer_asn)
+            .map(|adj_out| adj_out.routes().into_iter().cloned().collect())
+    }
+
+    /// Evaluate a candidate set of import rules against current routes
+    /// without installing them
+    pub async fn test_policy(
+        &self,
+        fragment: &PolicyFragment,
+        peer_asn: Option<u32>,
+    ) -> Result<DryRunReport, BGPError> {
+        let rib = self.rib.read().await;
+        let mut candidate = rib.policy().clone();
+        candidate.set_import_rules(fragment);
+        rib.dry_run(&candidate, peer_asn)
+    }
+
+    /// Install configured import rules
+    pub async fn set_import_rules(&self, fragment: &PolicyFragment) -> Result<(), BGPError> {
+        let mut rib = self.rib.write().await;
+        let mut policy = rib.policy().clone();
+        policy.set_import_rules(fragment);
+        rib.set_policy(policy)
+    }
+
+    /// Map communities on learned routes to attribute changes, reselecting
+    /// every learned prefix under the new mapping
+    pub async fn set_community_policies(
+        &self,
+        policies: Vec<CommunityPolicy>,
+    ) -> Result<(), BGPError> {
+        communities::validate(&policies).map_err(BGPError::Configuration)?;
+        self.rib
+            .write()
+            .await
+            .set_communities(CommunityMap::new(policies))
+    }
+
+    /// Enforce peering limits on routes from Edge peers, resetting their
+    /// local_pref to `local_pref`
+    pub async fn set_peering(
+        &self,
+        config: PeeringConfig,
+        local_pref: u32,
+    ) -> Result<(), BGPError> {
+        self.rib
+            .write()
+            .await
+            .set_peering(PeeringGuard::new(config, local_pref))
+    }
+
+    /// Why a prefix is or is not installed
+    pub async fn explain_route(&self, network: &Prefix) -> Explanation {
+        self.rib
+            .read()
+            .await
+            .explain(network, self.clock.now_monotonic())
+    }
+
+    /// Stamp the routes this node originates from now on with when it did
+    /// so; see `propagation`
+    pub async fn set_propagation_tracing(&self, enabled: bool) {
+        self.rib.write().await.set_propagation_tracing(enabled);
+    }
+
+    /// Delays of stamped routes that arrived here, by origin tier
+    pub async fn propagation_stats(&self) -> PropagationStats {
+        self.rib.read().await.propagation_stats()
+    }
+
+    pub async fn set_rejection_journal(&self, config: RejectionJournalConfig) {
+        self.rib.write().await.set_rejection_journal(config);
+    }
+
+    pub async fn set_hold_down(&self, config: HoldDownConfig) {
+        self.rib.write().await.set_hold_down(config);
+    }
+
+    /// Enter hold-down after joining: our routes become a path of last
+    /// resort until the window passes without flaps
+    pub async fn start_hold_down(&self) {
+        let mut rib = self.rib.write().await;
+        rib.start_hold_down(self.clock.now_monotonic(), self.clock.now_utc());
+        self.held_down.send_replace(rib.held_down());
+    }
+
+    pub fn is_held_down(&self) -> bool {
+        *self.held_down.borrow()
+    }
+
+    pub fn subscribe_hold_down(&self) -> watch::Receiver<bool> {
+        self.held_down.subscribe()
+    }
+
+    /// Restore route pins kept in `store`, first moving in any left in
+    /// `legacy_file` by earlier versions
+    pub async fn open_route_pins(
+        &self,
+        store: &Store,
+        legacy_file: Option<&Path>,
+    ) -> Result<(), BGPError> {
+        self.rib
+            .write()
+            .await
+            .open_pins(Some(store.namespace(pins::NAMESPACE)), legacy_file)
+    }
+
+    /// Select the path from `via_asn` for `network` ahead of normal
+    /// evaluation, for `duration` if given
+    pub async fn pin_route(
+        &self,
+        network: Prefix,
+        via_asn: u32,
+        duration: Option<std::time::Duration>,
+        allow_missing: bool,
+    ) -> Result<RoutePin, BGPError> {
+        let now = self.clock.now_utc();
+        let mut pin = RoutePin::new(network, via_asn, now);
+        if let Some(duration) = duration {
+            let duration = chrono::Duration::from_std(duration)
+                .map_err(|e| BGPError::Route(format!("Invalid pin duration: {}", e)))?;
+            pin = pin.with_expiry(now + duration);
+        }
+        self.rib.write().await.pin(pin.clone(), allow_missing)?;
+        tracing::info!(target: "audit", "Pinned {} via AS{}", network, via_asn);
+        Ok(pin)
+    }
+
+    /// Return `network` to normal selection; `None` if it was not pinned
+    pub async fn unpin_route(&self, network: &Prefix) -> Result<Option<RoutePin>, BGPError> {
+        let removed = self.rib.write().await.unpin(network)?;
+        if removed.is_some() {
+            tracing::info!(target: "audit", "Unpinned {}", network);
+        }
+        Ok(removed)
+    }
+
+    pub async fn route_pins(&self) -> Vec<RoutePin> {
+        self.rib.read().await.pins()
+    }
+
+    /// Replace the routing policy, re-deriving the Loc-RIB from stored Adj-RIBs-In
+    /// Use two Regional parents as active and standby, when enabled
+    pub async fn set_multihoming(&self, config: MultihomingConfig) -> Result<(), BGPError> {
+        let multihoming = Multihoming::new(config)?;
+        self.rib.write().await.set_multihoming(multihoming)
+    }
+
+    /// The multihoming configuration, when enabled
+    pub async fn multihoming_config(&self) -> Option<MultihomingConfig> {
+        let rib = self.rib.read().await;
+        let multihoming = rib.multihoming();
+        multihoming
+            .is_enabled()
+            .then(|| multihoming.config().clone())
+    }
+
+    /// Primary and secondary parent, once designated
+    pub async fn multihoming_parents(&self) -> Option<(u32, u32)> {
+        self.rib.read().await.multihoming().parents()
+    }
+
+    pub async fn designate_parents(&self, primary: u32, secondary: u32) -> Result<(), BGPError> {
+        self.rib.write().await.designate_parents(primary, secondary)
+    }
+
+    /// Fail over between the parents as their health calls for
+    pub async fn observe_parents(
+        &self,
+        primary: ParentHealth,
+        secondary: ParentHealth,
+    ) -> Result<Option<Failover>, BGPError> {
+        self.rib.write().await.observe_parents(
+            primary,
+            secondary,
+            self.clock.now_monotonic(),
+            self.clock.now_utc(),
+        )
+    }
+
+    pub async fn multihoming_status(&self) -> Option<MultihomingStatus> {
+        self.rib.read().await.multihoming().status()
+    }
+
+    /// Our own routes as `peer_asn` should receive them
+    pub async fn exports_for(&self, peer_asn: u32) -> Vec<RouteEntry> {
+        self.rib.read().await.exports_for(peer_asn)
+    }
+
+    /// Our own routes as `peer_asn` should receive them, recorded in its
+    /// Adj-RIB-Out as sent
+    pub async fn advertise_to(&self, peer_asn: u32) -> Result<Vec<RouteEntry>, BGPError> {
+        self.rib.write().await.refresh_exports(peer_asn)
+    }
+
+    /// Install export templates, `groups` naming configured peers' groups
+    /// by ASN; the peers whose routes now look different are refreshed and
+    /// returned
+    pub async fn set_export_policies(
+        &self,
+        policies: Vec<ExportPolicy>,
+        groups: HashMap<u32, String>,
+    ) -> Result<Vec<u32>, BGPError> {
+        for warning in export::validate(&policies).map_err(BGPError::Configuration)? {
+            tracing::warn!("{}", warning);
+        }
+        let exports = ExportPolicies::new(policies, groups, self.local_asn, self.router_id);
+        let refreshed = self.rib.write().await.set_exports(exports)?;
+        if !refreshed.is_empty() {
+            tracing::info!(
+                "Export policies changed; refreshed routes toward {:?}",
+                refreshed
+            );
+        }
+        Ok(refreshed)
+    }
+
+    /// A Loc-RIB change's route as `peer_asn` should receive it
+    pub async fn export_for(&self, route: RouteEntry, peer_asn: u32) -> RouteEntry {
+        self.rib.read().await.export_for(route, peer_asn)
+    }
+
+    /// Whether `peer_asn` currently offers us the VX0 default
+    pub async fn offers_default(&self, peer_asn: u32) -> bool {
+        let default = default_route::vx0_default();
+        self.rib
+            .read()
+            .await
+            .received(peer_asn)
+            .is_some_and(|adj_in| adj_in.get(&default).is_some())
+    }
+
+    pub async fn set_policy(&self, policy: RoutingPolicy) -> Result<(), BGPError> {
+        self.rib.write().await.set_policy(policy)
     }
 }
 
 impl BGPSession {
-    pub fn new(
-        local_asn: u32,
-        peer_asn: u32,
-        peer_ip: IpAddr,
-        route_table: Arc<RwLock<RouteTable>>,
-    ) -> Self {
+    pub fn new(local_asn: u32, peer_asn: u32, peer_ip: IpAddr, rib: Arc<RwLock<Rib>>) -> Self {
         BGPSession {
             peer_asn,
             local_asn,
             peer_ip,
             state: BGPSessionState::Idle,
-            route_table,
+            rib,
             hold_time: 90,
             keepalive_time: 30,
+            peer_capabilities: Capabilities::default(),
+            resumed: false,
+            peer_table_version: 0,
+            observed_addr: None,
+            table_sync_resume: None,
         }
     }
 
@@ -230,6 +1739,26 @@ impl BGPSession {
     }
 }
 
+impl RouteEntry {
+    /// Whether the next hop is one no packet could be forwarded to
+    pub fn unresolved_next_hop(&self) -> bool {
+        self.next_hop.is_unspecified()
+            || self.next_hop.is_multicast()
+            || matches!(self.next_hop, IpAddr::V4(v4) if v4.is_broadcast())
+    }
+
+    /// Next hop and prefix must belong to the same address family
+    pub fn check_address_family(&self) -> Result<(), BGPError> {
+        if self.network.reachable_via(&self.next_hop) {
+            return Ok(());
+        }
+        Err(BGPError::Route(format!(
+            "Next hop {} is not in the same address family as {}",
+            self.next_hop, self.network
+        )))
+    }
+}
+
 impl Default for RouteTable {
     fn default() -> Self {
         Self::new()
@@ -241,25 +1770,67 @@ impl RouteTable {
         RouteTable {
             routes: HashMap::new(),
             version: 0,
+            history: VecDeque::new(),
         }
     }
 
     pub fn add_route(&mut self, route: RouteEntry) -> Result<(), BGPError> {
-        self.routes.insert(route.network, route);
-        self.version += 1;
+        route.check_address_family()?;
+        let network = route.network;
+        self.routes.insert(network, route);
+        self.record(network);
         Ok(())
     }
 
-    pub fn remove_route(&mut self, network: &IpNet) -> Option<RouteEntry> {
+    pub fn remove_route(&mut self, network: &Prefix) -> Option<RouteEntry> {
         if let Some(route) = self.routes.remove(network) {
-            self.version += 1;
+            self.record(*network);
             Some(route)
         } else {
             None
         }
     }
 
-    pub fn get_route(&self, network: &IpNet) -> Option<&RouteEntry> {
+    fn record(&mut self, network: Prefix) {
+        self.version += 1;
+        self.history.push_back((self.version, network));
+        if self.history.len() > TABLE_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    /// Routes to announce and prefixes to withdraw to bring a copy of the
+    /// table at `version` up to date, or `None` if the history does not go
+    /// back that far
+    pub fn changes_since(&self, version: u64) -> Option<(Vec<RouteEntry>, Vec<Prefix>)> {
+        if version > self.version {
+            return None;
+        }
+        if version < self.version
+            && self
+                .history
+                .front()
+                .is_none_or(|(oldest, _)| *oldest > version + 1)
+        {
+            return None;
+        }
+        let changed: HashSet<Prefix> = self
+            .history
+            .iter()
+            .filter(|(changed_in, _)| *changed_in > version)
+            .map(|(_, network)| *network)
+            .collect();
+        let (mut announced, mut withdrawn) = (Vec::new(), Vec::new());
+        for network in changed {
+            match self.routes.get(&network) {
+                Some(route) => announced.push(route.clone()),
+                None => withdrawn.push(network),
+            }
+        }
+        Some((announced, withdrawn))
+    }
+
+    pub fn get_route(&self, network: &Prefix) -> Option<&RouteEntry> {
         self.routes.get(network)
     }
 
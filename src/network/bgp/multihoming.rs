@@ -0,0 +1,482 @@
+//! Edge nodes homed to two Regional parents, one active and one standby.
+//!
+//! Our routes go to both parents, but those sent to the standby carry the
+//! `BACKUP` community and a higher MED, so the rest of the network prefers
+//! the path through the active one; routes learned from the standby,
+//! including its default, rank below the active parent's. The active
+//! parent's session, tunnel and default route are checked every
+//! `failover_time`; when any fails while the standby is healthy, the
+//! standby is promoted and our routes re-advertised with the roles
+//! swapped. The primary takes over again only after it has stayed healthy
+//! for `recovery_hold`, so a parent that keeps flapping does not drag
+//! traffic back and forth.
+
+use crate::config::MultihomingConfig;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::bgp::communities::BACKUP;
+use crate::network::bgp::{BGPDaemon, BGPError, RouteEntry};
+use crate::node::{ConnectionStatus, NodeTier, Vx0Node};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// local_pref given to routes learned from the standby parent, as the
+/// well-known `BACKUP` community would
+pub const STANDBY_LOCAL_PREF: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentRole {
+    Active,
+    Standby,
+}
+
+/// What the health check found for one parent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParentHealth {
+    pub session: bool,
+    pub tunnel: bool,
+    /// The parent offers us the VX0 default
+    pub default_route: bool,
+}
+
+impl ParentHealth {
+    pub const UP: ParentHealth = ParentHealth {
+        session: true,
+        tunnel: true,
+        default_route: true,
+    };
+
+    pub fn is_healthy(&self) -> bool {
+        self.session && self.tunnel && self.default_route
+    }
+
+    /// The first check that failed
+    fn failure(&self) -> &'static str {
+        if !self.session {
+            "session down"
+        } else if !self.tunnel {
+            "tunnel down"
+        } else {
+            "no default route"
+        }
+    }
+}
+
+/// A Regional peer the health check looked at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParentReport {
+    pub asn: u32,
+    pub latency_ms: u64,
+    pub health: ParentHealth,
+}
+
+/// Traffic moved from one parent to the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Failover {
+    pub from: u32,
+    pub to: u32,
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct MultihomingStatus {
+    pub primary: u32,
+    pub secondary: u32,
+    /// The parent currently carrying our traffic
+    pub active: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failover: Option<Failover>,
+}
+
+#[derive(Debug, Default)]
+pub struct Multihoming {
+    config: MultihomingConfig,
+    /// Primary and secondary, once designated
+    parents: Option<(u32, u32)>,
+    active: Option<u32>,
+    /// Since when the primary has been healthy while the secondary is active
+    primary_healthy_since: Option<Instant>,
+    last_failover: Option<Failover>,
+}
+
+impl Multihoming {
+    pub fn new(config: MultihomingConfig) -> Result<Self, BGPError> {
+        let parents = match (config.primary, config.secondary) {
+            (Some(primary), Some(secondary)) => Some((primary, secondary)),
+            (None, None) => None,
+            _ => {
+                return Err(BGPError::Configuration(
+                    "multihoming needs both a primary and a secondary parent, or neither"
+                        .to_string(),
+                ))
+            }
+        };
+        let multihoming = Multihoming {
+            config,
+            ..Default::default()
+        };
+        match parents {
+            Some((primary, secondary)) => multihoming.with_parents(primary, secondary),
+            None => Ok(multihoming),
+        }
+    }
+
+    fn with_parents(mut self, primary: u32, secondary: u32) -> Result<Self, BGPError> {
+        self.designate(primary, secondary)?;
+        Ok(self)
+    }
+
+    pub fn config(&self) -> &MultihomingConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn parents(&self) -> Option<(u32, u32)> {
+        self.parents
+    }
+
+    /// Name the two parents; the primary starts out active
+    pub fn designate(&mut self, primary: u32, secondary: u32) -> Result<(), BGPError> {
+        if primary == secondary {
+            return Err(BGPError::Configuration(format!(
+                "multihoming parents must differ, got AS{} twice",
+                primary
+            )));
+        }
+        for asn in [primary, secondary] {
+            if NodeTier::from_asn(asn) != NodeTier::Regional {
+                return Err(BGPError::Configuration(format!(
+                    "multihoming parent AS{} is not a Regional node",
+                    asn
+                )));
+            }
+        }
+        self.parents = Some((primary, secondary));
+        self.active = Some(primary);
+        self.primary_healthy_since = None;
+        Ok(())
+    }
+
+    pub fn active(&self) -> Option<u32> {
+        self.active
+    }
+
+    pub fn role(&self, peer_asn: u32) -> Option<ParentRole> {
+        if !self.config.enabled {
+            return None;
+        }
+        let (primary, secondary) = self.parents?;
+        if peer_asn != primary && peer_asn != secondary {
+            None
+        } else if self.active == Some(peer_asn) {
+            Some(ParentRole::Active)
+        } else {
+            Some(ParentRole::Standby)
+        }
+    }
+
+    /// A route of ours as sent to `peer_asn`
+    pub fn export(&self, mut route: RouteEntry, peer_asn: u32) -> RouteEntry {
+        if self.role(peer_asn) == Some(ParentRole::Standby) {
+            route.med = route.med.max(self.config.standby_med);
+            if !route.communities.contains(&BACKUP) {
+                route.communities.push(BACKUP);
+            }
+        }
+        route
+    }
+
+    /// Rank a route learned from `peer_asn`, describing the change made
+    pub fn import(&self, route: &mut RouteEntry, peer_asn: u32) -> Option<String> {
+        if self.role(peer_asn) != Some(ParentRole::Standby)
+            || route.local_pref <= STANDBY_LOCAL_PREF
+        {
+            return None;
+        }
+        let change = format!(
+            "multihoming: standby parent AS{}, local_pref {} -> {}",
+            peer_asn, route.local_pref, STANDBY_LOCAL_PREF
+        );
+        route.local_pref = STANDBY_LOCAL_PREF;
+        Some(change)
+    }
+
+    /// Weigh the parents' latest health, returning the failover made, if any
+    pub fn observe(
+        &mut self,
+        primary_health: ParentHealth,
+        secondary_health: ParentHealth,
+        now: Instant,
+        wall: DateTime<Utc>,
+    ) -> Option<Failover> {
+        let (primary, secondary) = self.parents?;
+        let active = self.active?;
+        if active == primary {
+            if primary_health.is_healthy() || !secondary_health.is_healthy() {
+                return None;
+            }
+            return Some(self.switch(primary, secondary, primary_health.failure(), wall));
+        }
+
+        if !primary_health.is_healthy() {
+            self.primary_healthy_since = None;
+            return None;
+        }
+        if !secondary_health.is_healthy() {
+            return Some(self.switch(secondary, primary, secondary_health.failure(), wall));
+        }
+        let since = *self.primary_healthy_since.get_or_insert(now);
+        if now.saturating_duration_since(since) < self.config.recovery_hold.get() {
+            return None;
+        }
+        Some(self.switch(secondary, primary, "primary recovered", wall))
+    }
+
+    fn switch(&mut self, from: u32, to: u32, reason: &str, wall: DateTime<Utc>) -> Failover {
+        let failover = Failover {
+            from,
+            to,
+            at: wall,
+            reason: reason.to_string(),
+        };
+        tracing::warn!(
+            target: "audit",
+            "Multihoming: traffic moved from AS{} to AS{} ({})",
+            from,
+            to,
+            reason
+        );
+        self.active = Some(to);
+        self.primary_healthy_since = None;
+        self.last_failover = Some(failover.clone());
+        failover
+    }
+
+    pub fn status(&self) -> Option<MultihomingStatus> {
+        if !self.config.enabled {
+            return None;
+        }
+        let (primary, secondary) = self.parents?;
+        Some(MultihomingStatus {
+            primary,
+            secondary,
+            active: self.active?,
+            last_failover: self.last_failover.clone(),
+        })
+    }
+}
+
+/// The two Regional peers with the lowest latency, primary first
+pub fn choose_parents(reports: &[ParentReport]) -> Option<(u32, u32)> {
+    let mut regional: Vec<&ParentReport> = reports
+        .iter()
+        .filter(|report| NodeTier::from_asn(report.asn) == NodeTier::Regional)
+        .collect();
+    regional.sort_by_key(|report| (report.latency_ms, report.asn));
+    match regional.as_slice() {
+        [primary, secondary, ..] => Some((primary.asn, secondary.asn)),
+        _ => None,
+    }
+}
+
+/// How this node's Regional peers look to the health check: a session up,
+/// a tunnel established and the VX0 default on offer
+pub async fn report_parents(node: &Vx0Node, bgp: &BGPDaemon) -> Vec<ParentReport> {
+    let mut reports = Vec::new();
+    for peer in node.list_peers().await {
+        if NodeTier::from_asn(peer.peer_asn) != NodeTier::Regional {
+            continue;
+        }
+        let health = ParentHealth {
+            session: matches!(
+                peer.status,
+                ConnectionStatus::Connected | ConnectionStatus::Authenticated
+            ),
+            tunnel: node
+                .tunnel_manager
+                .tunnel_to(peer.peer_addr)
+                .await
+                .is_some(),
+            default_route: bgp.offers_default(peer.peer_asn).await,
+        };
+        reports.push(ParentReport {
+            asn: peer.peer_asn,
+            latency_ms: peer.metrics.latency_ms,
+            health,
+        });
+    }
+    reports
+}
+
+/// Check the parents every `failover_time` with `probe`, which reports on
+/// this node's Regional peers, and fail over between them as their health
+/// changes. Parents not named in the configuration are chosen by latency
+/// from the first report that has two.
+pub fn start_monitoring<P, Fut>(bgp: Arc<BGPDaemon>, probe: P)
+where
+    P: Fn() -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Vec<ParentReport>> + Send + 'static,
+{
+    crash::spawn_restartable(Subsystem::Bgp, "multihoming", move || {
+        let bgp = Arc::clone(&bgp);
+        let probe = probe.clone();
+        async move {
+            let Some(config) = bgp.multihoming_config().await else {
+                return;
+            };
+            let mut interval = tokio::time::interval(config.failover_time.get());
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let reports = probe().await;
+                let parents = match bgp.multihoming_parents().await {
+                    Some(parents) => parents,
+                    None => match choose_parents(&reports) {
+                        Some((primary, secondary)) => {
+                            if let Err(e) = bgp.designate_parents(primary, secondary).await {
+                                tracing::warn!("Could not designate parents: {}", e);
+                                continue;
+                            }
+                            tracing::info!(
+                                "Multihoming: AS{} primary, AS{} secondary by latency",
+                                primary,
+                                secondary
+                            );
+                            (primary, secondary)
+                        }
+                        None => continue,
+                    },
+                };
+                let health = |asn: u32| {
+                    reports
+                        .iter()
+                        .find(|report| report.asn == asn)
+                        .map(|report| report.health)
+                        .unwrap_or_default()
+                };
+                if let Err(e) = bgp
+                    .observe_parents(health(parents.0), health(parents.1))
+                    .await
+                {
+                    tracing::warn!("Multihoming failover failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::units::ConfigDuration;
+    use crate::network::bgp::testing;
+    use std::time::Duration;
+
+    const PRIMARY: u32 = 65101;
+    const SECONDARY: u32 = 65102;
+
+    fn multihoming() -> Multihoming {
+        Multihoming::new(MultihomingConfig {
+            enabled: true,
+            primary: Some(PRIMARY),
+            secondary: Some(SECONDARY),
+            recovery_hold: ConfigDuration::from_secs(30),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn route() -> RouteEntry {
+        testing::route("10.66.1.0/24", "10.66.1.1", &[66001])
+    }
+
+    #[test]
+    fn test_standby_gets_backup_attributes() {
+        let multihoming = multihoming();
+        let active = multihoming.export(route(), PRIMARY);
+        assert_eq!((active.med, active.communities), (0, vec![]));
+        let standby = multihoming.export(route(), SECONDARY);
+        assert_eq!(standby.med, 100);
+        assert_eq!(standby.communities, vec![BACKUP]);
+        assert!(multihoming.export(route(), 65103).communities.is_empty());
+
+        let mut learned = route();
+        assert!(multihoming.import(&mut learned, SECONDARY).is_some());
+        assert_eq!(learned.local_pref, STANDBY_LOCAL_PREF);
+    }
+
+    #[test]
+    fn test_fails_over_at_once_and_returns_after_the_hold() {
+        let mut multihoming = multihoming();
+        let t0 = Instant::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+        let down = ParentHealth {
+            tunnel: false,
+            ..ParentHealth::UP
+        };
+
+        assert_eq!(
+            multihoming.observe(ParentHealth::UP, ParentHealth::UP, t0, Utc::now()),
+            None
+        );
+        let failover = multihoming
+            .observe(down, ParentHealth::UP, at(1), Utc::now())
+            .unwrap();
+        assert_eq!((failover.from, failover.to), (PRIMARY, SECONDARY));
+        assert_eq!(failover.reason, "tunnel down");
+        assert_eq!(multihoming.role(PRIMARY), Some(ParentRole::Standby));
+
+        // A flap during the hold starts it over
+        assert_eq!(
+            multihoming.observe(ParentHealth::UP, ParentHealth::UP, at(2), Utc::now()),
+            None
+        );
+        assert_eq!(
+            multihoming.observe(down, ParentHealth::UP, at(20), Utc::now()),
+            None
+        );
+        assert_eq!(
+            multihoming.observe(ParentHealth::UP, ParentHealth::UP, at(21), Utc::now()),
+            None
+        );
+        assert_eq!(
+            multihoming.observe(ParentHealth::UP, ParentHealth::UP, at(50), Utc::now()),
+            None
+        );
+        let back = multihoming
+            .observe(ParentHealth::UP, ParentHealth::UP, at(51), Utc::now())
+            .unwrap();
+        assert_eq!(back.to, PRIMARY);
+        assert_eq!(multihoming.status().unwrap().last_failover, Some(back));
+
+        // Nowhere better to go, so a failed primary keeps the traffic
+        assert_eq!(multihoming.observe(down, down, at(60), Utc::now()), None);
+        assert_eq!(multihoming.active(), Some(PRIMARY));
+    }
+
+    #[test]
+    fn test_parents_by_latency() {
+        let report = |asn, latency_ms| ParentReport {
+            asn,
+            latency_ms,
+            health: ParentHealth::UP,
+        };
+        assert_eq!(
+            choose_parents(&[
+                report(65103, 40),
+                report(65001, 1),
+                report(65101, 10),
+                report(65102, 20)
+            ]),
+            Some((65101, 65102))
+        );
+        assert_eq!(choose_parents(&[report(65101, 10)]), None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Whether routes through a next hop are usable, given the tunnels to it.
+//!
+//! The routing layer follows tunnel status changes. Routes via a peer whose
+//! last tunnel failed are withdrawn or kept only as a last resort, per
+//! `network.routing.tunnel_failure`, until a tunnel to the peer is
+//! established again; with `require_tunnel_for_nexthop` a next hop without
+//! an established tunnel is never eligible. A tunnel that keeps failing
+//! must stay up for a while before its routes return, and the per-peer
+//! advertisement pacing coalesces whatever churn is left.
+
+use crate::config::{RoutingConfig, TunnelDampingConfig, TunnelFailureAction};
+use crate::network::ike::tunnels::{TunnelId, TunnelStatus, TunnelStatusChanged};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// How selection may use a route through some next hop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usability {
+    Usable,
+    /// Only when no usable path exists
+    LastResort,
+    Unusable,
+}
+
+#[derive(Debug)]
+struct Failure {
+    /// Set once a tunnel is back while damped; routes return at this time
+    /// if it stays up
+    release_at: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct NextHopTunnels {
+    require_tunnel: bool,
+    on_failure: TunnelFailureAction,
+    damping: TunnelDampingConfig,
+    established: HashMap<IpAddr, HashSet<TunnelId>>,
+    /// Next hops whose last tunnel failed and whose routes are still held back
+    failed: HashMap<IpAddr, Failure>,
+    /// Recent failures per next hop, oldest first
+    failures: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl NextHopTunnels {
+    /// Apply routing settings, keeping what is known about tunnels
+    pub fn configure(&mut self, config: &RoutingConfig) {
+        self.require_tunnel = config.require_tunnel_for_nexthop;
+        self.on_failure = config.tunnel_failure;
+        self.damping = config.tunnel_damping.clone();
+    }
+
+    pub fn usability(&self, next_hop: &IpAddr) -> Usability {
+        if self.failed.contains_key(next_hop) {
+            return match self.on_failure {
+                TunnelFailureAction::Withdraw => Usability::Unusable,
+                TunnelFailureAction::Deprioritize if self.require_tunnel => Usability::Unusable,
+                TunnelFailureAction::Deprioritize => Usability::LastResort,
+            };
+        }
+        if self.require_tunnel && !self.established.contains_key(next_hop) {
+            return Usability::Unusable;
+        }
+        Usability::Usable
+    }
+
+    /// Record a tunnel's new status, returning whether routes via its
+    /// remote end need reselecting
+    pub fn update(&mut self, change: &TunnelStatusChanged, now: Instant) -> bool {
+        let addr = change.remote_addr;
+        let before = self.usability(&addr);
+        match change.status {
+            // Still carrying traffic while it rekeys
+            TunnelStatus::Negotiating | TunnelStatus::Rekeying => return false,
+            TunnelStatus::Established => {
+                self.established
+                    .entry(addr)
+                    .or_default()
+                    .insert(change.tunnel_id);
+                let damped = self.damped(&addr, now);
+                if let Some(failure) = self.failed.get_mut(&addr) {
+                    if damped {
+                        failure
+                            .release_at
+                            .get_or_insert(now + Duration::from_secs(self.damping.suppress_secs));
+                    } else {
+                        self.failed.remove(&addr);
+                    }
+                }
+            }
+            TunnelStatus::Failed | TunnelStatus::Closed => {
+                let Some(tunnels) = self.established.get_mut(&addr) else {
+                    return false;
+                };
+                if !tunnels.remove(&change.tunnel_id) {
+                    return false;
+                }
+                if tunnels.is_empty() {
+                    self.established.remove(&addr);
+                    // A tunnel closed on purpose, e.g. replaced, is no failure
+                    if change.status == TunnelStatus::Failed {
+                        self.failures.entry(addr).or_default().push_back(now);
+                        self.failed.insert(addr, Failure { release_at: None });
+                    }
+                }
+            }
+        }
+        self.usability(&addr) != before
+    }
+
+    /// Release damped next hops whose tunnel stayed up long enough,
+    /// returning them for reselection
+    pub fn poll(&mut self, now: Instant) -> Vec<IpAddr> {
+        let released: Vec<IpAddr> = self
+            .failed
+            .iter()
+            .filter(|(addr, failure)| {
+                self.established.contains_key(*addr)
+                    && failure.release_at.is_some_and(|at| at <= now)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &released {
+            self.failed.remove(addr);
+            tracing::info!("Tunnel to {} stayed up; its routes are usable again", addr);
+        }
+        let window = Duration::from_secs(self.damping.window_secs);
+        self.failures.retain(|_, failures| {
+            failures.retain(|at| now.saturating_duration_since(*at) < window);
+            !failures.is_empty()
+        });
+        released
+    }
+
+    /// Whether the next hop failed often enough lately to be damped
+    fn damped(&mut self, addr: &IpAddr, now: Instant) -> bool {
+        if self.damping.max_failures == 0 {
+            return false;
+        }
+        let window = Duration::from_secs(self.damping.window_secs);
+        let Some(failures) = self.failures.get_mut(addr) else {
+            return false;
+        };
+        failures.retain(|at| now.saturating_duration_since(*at) < window);
+        failures.len() >= self.damping.max_failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn config(require: bool, on_failure: TunnelFailureAction) -> RoutingConfig {
+        RoutingConfig {
+            max_paths: 4,
+            local_preference: 100,
+            med: 0,
+            originate_default_to_edge: false,
+            require_tunnel_for_nexthop: require,
+            tunnel_failure: on_failure,
+            tunnel_damping: TunnelDampingConfig {
+                max_failures: 3,
+                window_secs: 300,
+                suppress_secs: 60,
+            },
+        }
+    }
+
+    fn change(tunnel_id: TunnelId, status: TunnelStatus) -> TunnelStatusChanged {
+        TunnelStatusChanged {
+            tunnel_id,
+            remote_addr: "10.1.0.1".parse().unwrap(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_failure_and_requirement() {
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        let now = Instant::now();
+        let mut tunnels = NextHopTunnels::default();
+        tunnels.configure(&config(false, TunnelFailureAction::Deprioritize));
+        assert_eq!(tunnels.usability(&peer), Usability::Usable);
+
+        let tunnel = Uuid::new_v4();
+        assert!(!tunnels.update(&change(tunnel, TunnelStatus::Established), now));
+        assert!(tunnels.update(&change(tunnel, TunnelStatus::Failed), now));
+        assert_eq!(tunnels.usability(&peer), Usability::LastResort);
+        // Cleanup after a failure changes nothing further
+        assert!(!tunnels.update(&change(tunnel, TunnelStatus::Closed), now));
+
+        tunnels.configure(&config(true, TunnelFailureAction::Withdraw));
+        assert_eq!(tunnels.usability(&peer), Usability::Unusable);
+        assert!(tunnels.update(&change(Uuid::new_v4(), TunnelStatus::Established), now));
+        assert_eq!(tunnels.usability(&peer), Usability::Usable);
+        assert_eq!(
+            tunnels.usability(&"10.1.0.2".parse().unwrap()),
+            Usability::Unusable
+        );
+    }
+
+    #[test]
+    fn test_flapping_tunnel_is_damped() {
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        let start = Instant::now();
+        let mut tunnels = NextHopTunnels::default();
+        tunnels.configure(&config(false, TunnelFailureAction::Withdraw));
+
+        for flap in 0..3 {
+            let at = start + Duration::from_secs(flap * 10);
+            let tunnel = Uuid::new_v4();
+            tunnels.update(&change(tunnel, TunnelStatus::Established), at);
+            assert_eq!(tunnels.usability(&peer), Usability::Usable, "flap {flap}");
+            tunnels.update(&change(tunnel, TunnelStatus::Failed), at);
+            assert_eq!(tunnels.usability(&peer), Usability::Unusable);
+        }
+
+        // The third failure damps the next restore
+        let back = start + Duration::from_secs(30);
+        assert!(!tunnels.update(&change(Uuid::new_v4(), TunnelStatus::Established), back));
+        assert_eq!(tunnels.usability(&peer), Usability::Unusable);
+        assert!(tunnels.poll(back + Duration::from_secs(59)).is_empty());
+        assert_eq!(tunnels.poll(back + Duration::from_secs(60)), vec![peer]);
+        assert_eq!(tunnels.usability(&peer), Usability::Usable);
+    }
+}
@@ -0,0 +1,265 @@
+//! Per-peer outbound pacing (minimum route advertisement interval).
+//!
+//! Changes queued for a peer are coalesced per prefix and flushed at most
+//! once per interval, so a flapping prefix costs one UPDATE per interval
+//! rather than one per flap. Withdrawals are flushed as soon as they are
+//! polled. A peer whose queue grows past its bound is resynchronised from
+//! the full table instead.
+
+use crate::network::bgp::{Prefix, RouteEntry};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Pacing toward Edge peers, which are often on slow links
+pub const EDGE_MRAI: Duration = Duration::from_secs(5);
+/// Pacing toward Regional and Backbone peers
+pub const CORE_MRAI: Duration = Duration::from_secs(1);
+/// Pending prefixes per peer before falling back to a full resync
+pub const DEFAULT_MAX_QUEUE: usize = 10_000;
+
+/// Interval used for a peer unless overridden in its config
+pub fn default_mrai(peer_asn: u32) -> Duration {
+    if (66000..=69999).contains(&peer_asn) {
+        EDGE_MRAI
+    } else {
+        CORE_MRAI
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Pending {
+    Announce(RouteEntry),
+    Withdraw,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateBatch {
+    pub announced: Vec<RouteEntry>,
+    pub withdrawn: Vec<Prefix>,
+}
+
+impl UpdateBatch {
+    pub fn is_empty(&self) -> bool {
+        self.announced.is_empty() && self.withdrawn.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PacerOutput {
+    Update(UpdateBatch),
+    /// The queue overflowed; re-advertise the whole table, then call
+    /// `resync_complete`
+    Resync,
+}
+
+#[derive(Debug)]
+pub struct AdvertisementPacer {
+    interval: Duration,
+    max_queue: usize,
+    pending: HashMap<Prefix, Pending>,
+    /// Prefixes the peer currently holds from us
+    advertised: HashSet<Prefix>,
+    last_flush: Option<Instant>,
+    overflowed: bool,
+}
+
+impl AdvertisementPacer {
+    pub fn new(interval: Duration, max_queue: usize) -> Self {
+        AdvertisementPacer {
+            interval,
+            max_queue: max_queue.max(1),
+            pending: HashMap::new(),
+            advertised: HashSet::new(),
+            last_flush: None,
+            overflowed: false,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn announce(&mut self, route: RouteEntry) {
+        self.enqueue(route.network, Pending::Announce(route));
+    }
+
+    pub fn withdraw(&mut self, network: Prefix) {
+        if self.advertised.contains(&network) {
+            self.enqueue(network, Pending::Withdraw);
+        } else {
+            // Announced and withdrawn before the peer ever heard of it
+            self.pending.remove(&network);
+        }
+    }
+
+    /// Drop everything queued and resync the peer from the full table
+    pub fn force_resync(&mut self) {
+        self.pending.clear();
+        self.overflowed = true;
+    }
+
+    /// Record that the peer now holds exactly `routes`
+    pub fn resync_complete(&mut self, routes: &[RouteEntry]) {
+        self.advertised = routes.iter().map(|route| route.network).collect();
+    }
+
+    fn enqueue(&mut self, network: Prefix, change: Pending) {
+        if self.overflowed {
+            return;
+        }
+        self.pending.insert(network, change);
+        if self.pending.len() > self.max_queue {
+            tracing::warn!(
+                "Advertisement queue exceeded {} prefixes; scheduling full resync",
+                self.max_queue
+            );
+            self.force_resync();
+        }
+    }
+
+    /// What to send now, if anything
+    pub fn poll(&mut self, now: Instant) -> Option<PacerOutput> {
+        if self.overflowed {
+            self.overflowed = false;
+            self.last_flush = Some(now);
+            return Some(PacerOutput::Resync);
+        }
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let due = self
+            .last_flush
+            .is_none_or(|last| now.duration_since(last) >= self.interval);
+
+        let mut batch = UpdateBatch::default();
+        if due {
+            for (network, change) in self.pending.drain() {
+                match change {
+                    Pending::Announce(route) => {
+                        self.advertised.insert(network);
+                        batch.announced.push(route);
+                    }
+                    Pending::Withdraw => {
+                        self.advertised.remove(&network);
+                        batch.withdrawn.push(network);
+                    }
+                }
+            }
+            self.last_flush = Some(now);
+        } else {
+            // Withdrawals are urgent and skip the interval
+            self.pending.retain(|network, change| match change {
+                Pending::Withdraw => {
+                    batch.withdrawn.push(*network);
+                    false
+                }
+                Pending::Announce(_) => true,
+            });
+            for network in &batch.withdrawn {
+                self.advertised.remove(network);
+            }
+        }
+
+        batch.announced.sort_by_key(|route| route.network);
+        batch.withdrawn.sort();
+        (!batch.is_empty()).then_some(PacerOutput::Update(batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::testing;
+
+    fn route(network: &str) -> RouteEntry {
+        testing::route(network, "10.0.0.1", &[65100])
+    }
+
+    fn updates(output: Option<PacerOutput>) -> UpdateBatch {
+        match output {
+            Some(PacerOutput::Update(batch)) => batch,
+            other => panic!("expected an update, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_flaps_are_paced() {
+        let start = Instant::now();
+        let mut pacer = AdvertisementPacer::new(EDGE_MRAI, DEFAULT_MAX_QUEUE);
+        let flapping: Prefix = "10.1.0.0/16".parse().unwrap();
+
+        pacer.announce(route("10.1.0.0/16"));
+        assert_eq!(updates(pacer.poll(start)).announced.len(), 1);
+
+        // 50 flaps over 10 seconds, polled every 100ms
+        let mut emitted = 0;
+        for tick in 1..=100u64 {
+            let now = start + Duration::from_millis(100 * tick);
+            if tick % 2 == 0 {
+                pacer.announce(route("10.1.0.0/16"));
+            } else {
+                pacer.withdraw(flapping);
+            }
+            if pacer.poll(now).is_some() {
+                emitted += 1;
+            }
+        }
+        // Withdrawal at 100ms goes out at once; the re-announcements queued
+        // behind it cancel against the next withdrawals until the interval
+        // is up at 5s, which brings one announce and one urgent withdrawal,
+        // then the final announce at 10s
+        assert_eq!(emitted, 4);
+        assert!(pacer.advertised.contains(&flapping));
+    }
+
+    #[test]
+    fn test_merge_semantics() {
+        let start = Instant::now();
+        let mut pacer = AdvertisementPacer::new(CORE_MRAI, DEFAULT_MAX_QUEUE);
+        let network: Prefix = "10.2.0.0/16".parse().unwrap();
+
+        // Add then withdraw within one interval: the peer never hears of it
+        pacer.announce(route("10.2.0.0/16"));
+        pacer.withdraw(network);
+        assert!(pacer.poll(start).is_none());
+
+        pacer.announce(route("10.2.0.0/16"));
+        assert_eq!(updates(pacer.poll(start)).announced.len(), 1);
+
+        // Withdraw then add before the next flush: one re-advertisement
+        pacer.withdraw(network);
+        pacer.announce(route("10.2.0.0/16"));
+        assert!(pacer.poll(start + Duration::from_millis(500)).is_none());
+        let batch = updates(pacer.poll(start + CORE_MRAI));
+        assert_eq!(batch.announced.len(), 1);
+        assert!(batch.withdrawn.is_empty());
+
+        // A lone withdrawal goes out without waiting for the interval
+        pacer.withdraw(network);
+        let batch = updates(pacer.poll(start + CORE_MRAI + Duration::from_millis(1)));
+        assert_eq!(batch.withdrawn, vec![network]);
+    }
+
+    #[test]
+    fn test_overflow_forces_resync() {
+        let start = Instant::now();
+        let mut pacer = AdvertisementPacer::new(EDGE_MRAI, 4);
+
+        for i in 0..5 {
+            pacer.announce(route(&format!("10.{}.0.0/16", i)));
+        }
+        assert!(matches!(pacer.poll(start), Some(PacerOutput::Resync)));
+        assert_eq!(pacer.queue_len(), 0);
+
+        let table: Vec<RouteEntry> = (0..5).map(|i| route(&format!("10.{}.0.0/16", i))).collect();
+        pacer.resync_complete(&table);
+        pacer.withdraw("10.3.0.0/16".parse().unwrap());
+        let batch = updates(pacer.poll(start + Duration::from_millis(1)));
+        assert_eq!(batch.withdrawn.len(), 1);
+    }
+}
@@ -0,0 +1,456 @@
+//! Peering agreements enforced on routes learned from Edge peers.
+//!
+//! Nodes above the Edge tier check each Edge announcement at ingest against
+//! the `[network.peering]` limits: how short a prefix may be, how many new
+//! prefixes may appear per hour, and how many distinct origin ASNs the peer's
+//! routes may carry. Offending routes are dropped before they reach the
+//! Adj-RIB-In and each violation is written to the audit log. A peer that
+//! keeps violating within the hour is put in cooldown, during which none of
+//! its announcements are accepted. State outlives the session so a peer
+//! cannot reset its budget by reconnecting.
+//!
+//! Accepted Edge routes are normalized before entering the Loc-RIB (and so
+//! before being propagated upward): local_pref is reset and communities
+//! outside the allowed set are stripped.
+
+use crate::config::PeeringConfig;
+use crate::network::bgp::hold_down::NEWLY_JOINED;
+use crate::network::bgp::rib::AdjRib;
+use crate::network::bgp::{Prefix, RouteEntry};
+use crate::node::NodeTier;
+use ipnet::IpNet;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Window over which new prefixes and violations are counted
+pub const PEERING_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeeringViolation {
+    PrefixTooShort {
+        network: Prefix,
+        min_len: u8,
+    },
+    NewPrefixRateExceeded {
+        network: Prefix,
+        limit: usize,
+    },
+    TooManyOrigins {
+        network: Prefix,
+        origin: u32,
+        limit: usize,
+    },
+}
+
+impl fmt::Display for PeeringViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeeringViolation::PrefixTooShort { network, min_len } => {
+                write!(f, "{} is shorter than /{}", network, min_len)
+            }
+            PeeringViolation::NewPrefixRateExceeded { network, limit } => {
+                write!(f, "{} exceeds {} new prefixes per hour", network, limit)
+            }
+            PeeringViolation::TooManyOrigins {
+                network,
+                origin,
+                limit,
+            } => write!(
+                f,
+                "{} originated by AS{} exceeds {} distinct origin ASNs",
+                network, origin, limit
+            ),
+        }
+    }
+}
+
+/// Outcome of checking one announcement against the peering agreement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    Reject(PeeringViolation),
+    /// The peer is in cooldown; nothing it announces is accepted
+    CoolingDown,
+}
+
+/// Counters for one Edge peer, as reported to operators
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeeringStats {
+    pub violations: u64,
+    pub cooldowns: u64,
+    pub cooling_down: bool,
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    /// When each prefix new within the window was first accepted
+    new_prefixes: VecDeque<Instant>,
+    recent_violations: VecDeque<Instant>,
+    cooldown_until: Option<Instant>,
+    violations: u64,
+    cooldowns: u64,
+}
+
+impl PeerState {
+    fn expire(&mut self, now: Instant) {
+        for times in [&mut self.new_prefixes, &mut self.recent_violations] {
+            while times
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= PEERING_WINDOW)
+            {
+                times.pop_front();
+            }
+        }
+        if self.cooldown_until.is_some_and(|until| now >= until) {
+            self.cooldown_until = None;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PeeringGuard {
+    config: PeeringConfig,
+    local_pref: u32,
+    peers: HashMap<u32, PeerState>,
+}
+
+impl Default for PeeringGuard {
+    fn default() -> Self {
+        PeeringGuard::new(PeeringConfig::default(), 100)
+    }
+}
+
+impl PeeringGuard {
+    /// `local_pref` is what accepted Edge routes are reset to
+    pub fn new(config: PeeringConfig, local_pref: u32) -> Self {
+        PeeringGuard {
+            config,
+            local_pref,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Whether routes from `peer_asn` come from below a node of `local_tier`
+    pub fn applies(local_tier: &NodeTier, peer_asn: u32) -> bool {
+        let (low, high) = NodeTier::Edge.get_asn_range();
+        !matches!(local_tier, NodeTier::Edge) && (low..=high).contains(&peer_asn)
+    }
+
+    /// Check an announcement from an Edge peer, given what the peer has
+    /// already had accepted
+    pub fn admit(
+        &mut self,
+        peer_asn: u32,
+        route: &RouteEntry,
+        adj_in: &AdjRib,
+        now: Instant,
+    ) -> Admission {
+        let state = self.peers.entry(peer_asn).or_default();
+        state.expire(now);
+        if state.cooldown_until.is_some() {
+            return Admission::CoolingDown;
+        }
+
+        let violation = self.check(peer_asn, route, adj_in);
+        let cooldown = self.cooldown();
+        let threshold = self.config.violation_threshold;
+        let state = self.peers.entry(peer_asn).or_default();
+        match violation {
+            None => {
+                if adj_in.get(&route.network).is_none() {
+                    state.new_prefixes.push_back(now);
+                }
+                Admission::Accept
+            }
+            Some(violation) => {
+                state.violations += 1;
+                state.recent_violations.push_back(now);
+                tracing::warn!(
+                    target: "audit",
+                    "Edge peer AS{} violated peering agreement: {} ({} violations)",
+                    peer_asn,
+                    violation,
+                    state.violations
+                );
+
+                if state.recent_violations.len() >= threshold {
+                    state.cooldown_until = Some(now + cooldown);
+                    state.cooldowns += 1;
+                    state.recent_violations.clear();
+                    tracing::warn!(
+                        target: "audit",
+                        "Edge peer AS{} in cooldown for {}s after {} violations within the hour",
+                        peer_asn,
+                        cooldown.as_secs(),
+                        threshold
+                    );
+                }
+                Admission::Reject(violation)
+            }
+        }
+    }
+
+    fn check(
+        &self,
+        peer_asn: u32,
+        route: &RouteEntry,
+        adj_in: &AdjRib,
+    ) -> Option<PeeringViolation> {
+        let network = route.network;
+        let min_len = match network.net() {
+            IpNet::V4(_) => self.config.edge_min_prefix_len_v4,
+            IpNet::V6(_) => self.config.edge_min_prefix_len_v6,
+        };
+        if network.prefix_len() < min_len {
+            return Some(PeeringViolation::PrefixTooShort { network, min_len });
+        }
+
+        let origin = route.as_path.last().copied().unwrap_or(peer_asn);
+        let mut origins: HashSet<u32> = adj_in
+            .routes()
+            .into_iter()
+            .filter(|existing| existing.network != network)
+            .map(|existing| existing.as_path.last().copied().unwrap_or(peer_asn))
+            .collect();
+        origins.insert(origin);
+        if origins.len() > self.config.edge_max_origin_asns {
+            return Some(PeeringViolation::TooManyOrigins {
+                network,
+                origin,
+                limit: self.config.edge_max_origin_asns,
+            });
+        }
+
+        let limit = self.config.edge_max_new_prefixes_per_hour;
+        let is_new = adj_in.get(&network).is_none();
+        let recent = self
+            .peers
+            .get(&peer_asn)
+            .map_or(0, |s| s.new_prefixes.len());
+        if is_new && recent >= limit {
+            return Some(PeeringViolation::NewPrefixRateExceeded { network, limit });
+        }
+        None
+    }
+
+    /// Reset an accepted Edge route's attributes before it enters the Loc-RIB
+    pub fn normalize(&self, mut route: RouteEntry) -> RouteEntry {
+        route.local_pref = self.local_pref;
+        // The hold-down marker is a signal, not a preference to strip
+        route.communities.retain(|community| {
+            self.config.edge_allowed_communities.contains(community) || *community == NEWLY_JOINED
+        });
+        route
+    }
+
+    pub fn stats(&self, peer_asn: u32) -> Option<PeeringStats> {
+        self.peers.get(&peer_asn).map(|state| PeeringStats {
+            violations: state.violations,
+            cooldowns: state.cooldowns,
+            cooling_down: state.cooldown_until.is_some(),
+        })
+    }
+
+    fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.config.cooldown_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::testing;
+    use crate::network::bgp::Community;
+
+    const EDGE: u32 = 66001;
+
+    fn route(network: &str, as_path: Vec<u32>) -> RouteEntry {
+        testing::route(network, "10.2.0.1", &as_path)
+    }
+
+    fn guard(config: PeeringConfig) -> PeeringGuard {
+        PeeringGuard::new(config, 200)
+    }
+
+    /// Admit a route and, if accepted, record it as the Rib would
+    fn offer(
+        guard: &mut PeeringGuard,
+        adj_in: &mut AdjRib,
+        route: RouteEntry,
+        now: Instant,
+    ) -> Admission {
+        let admission = guard.admit(EDGE, &route, adj_in, now);
+        if admission == Admission::Accept {
+            adj_in.insert(route).unwrap();
+        }
+        admission
+    }
+
+    #[test]
+    fn test_short_prefixes_are_rejected() {
+        let mut guard = guard(PeeringConfig::default());
+        let mut adj_in = AdjRib::new(100);
+        let now = Instant::now();
+
+        let admission = offer(
+            &mut guard,
+            &mut adj_in,
+            route("10.8.0.0/16", vec![EDGE]),
+            now,
+        );
+        assert!(matches!(
+            admission,
+            Admission::Reject(PeeringViolation::PrefixTooShort { min_len: 22, .. })
+        ));
+        assert_eq!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.0.0/22", vec![EDGE]),
+                now
+            ),
+            Admission::Accept
+        );
+        assert_eq!(guard.stats(EDGE).unwrap().violations, 1);
+    }
+
+    #[test]
+    fn test_new_prefix_rate_is_limited_per_hour() {
+        let mut guard = guard(PeeringConfig {
+            edge_max_new_prefixes_per_hour: 2,
+            ..PeeringConfig::default()
+        });
+        let mut adj_in = AdjRib::new(100);
+        let start = Instant::now();
+
+        for network in ["10.8.1.0/24", "10.8.2.0/24"] {
+            assert_eq!(
+                offer(&mut guard, &mut adj_in, route(network, vec![EDGE]), start),
+                Admission::Accept
+            );
+        }
+        assert!(matches!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.3.0/24", vec![EDGE]),
+                start
+            ),
+            Admission::Reject(PeeringViolation::NewPrefixRateExceeded { limit: 2, .. })
+        ));
+        // Re-announcing a known prefix does not count against the budget
+        assert_eq!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.1.0/24", vec![EDGE]),
+                start
+            ),
+            Admission::Accept
+        );
+
+        let later = start + PEERING_WINDOW;
+        assert_eq!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.3.0/24", vec![EDGE]),
+                later
+            ),
+            Admission::Accept
+        );
+    }
+
+    #[test]
+    fn test_single_origin_and_cooldown() {
+        let mut guard = guard(PeeringConfig {
+            violation_threshold: 2,
+            cooldown_secs: 60,
+            ..PeeringConfig::default()
+        });
+        let mut adj_in = AdjRib::new(100);
+        let now = Instant::now();
+
+        assert_eq!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.1.0/24", vec![EDGE]),
+                now
+            ),
+            Admission::Accept
+        );
+        // A transit path with a second origin breaks the one-origin rule
+        assert!(matches!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.9.1.0/24", vec![EDGE, 66002]),
+                now
+            ),
+            Admission::Reject(PeeringViolation::TooManyOrigins {
+                origin: 66002,
+                limit: 1,
+                ..
+            })
+        ));
+        assert!(!guard.stats(EDGE).unwrap().cooling_down);
+
+        // The second violation crosses the threshold
+        offer(
+            &mut guard,
+            &mut adj_in,
+            route("10.0.0.0/8", vec![EDGE]),
+            now,
+        );
+        let stats = guard.stats(EDGE).unwrap();
+        assert!(stats.cooling_down);
+        assert_eq!((stats.violations, stats.cooldowns), (2, 1));
+        assert_eq!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.2.0/24", vec![EDGE]),
+                now
+            ),
+            Admission::CoolingDown
+        );
+
+        let after = now + Duration::from_secs(60);
+        assert_eq!(
+            offer(
+                &mut guard,
+                &mut adj_in,
+                route("10.8.2.0/24", vec![EDGE]),
+                after
+            ),
+            Admission::Accept
+        );
+    }
+
+    #[test]
+    fn test_normalize_resets_attributes() {
+        let allowed = Community {
+            asn: 65100,
+            value: 1,
+        };
+        let guard = guard(PeeringConfig {
+            edge_allowed_communities: vec![allowed.clone()],
+            ..PeeringConfig::default()
+        });
+        let mut announced = route("10.8.1.0/24", vec![EDGE]);
+        announced.local_pref = 1000;
+        announced.communities = vec![
+            allowed.clone(),
+            Community {
+                asn: 666,
+                value: 666,
+            },
+        ];
+
+        let normalized = guard.normalize(announced);
+        assert_eq!(normalized.local_pref, 200);
+        assert_eq!(normalized.communities, vec![allowed]);
+    }
+}
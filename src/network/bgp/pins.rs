@@ -0,0 +1,239 @@
+//! Administrative route preference overrides, pinned by prefix.
+//!
+//! A pin makes best-path selection take the path learned from one peer for
+//! a prefix, ahead of normal evaluation. The pinned path still has to pass
+//! import policy and have a usable next hop; while the peer has no such
+//! path, selection falls back to normal and warns once. Pins may expire,
+//! and are kept in the store, one key per prefix, so they survive restarts.
+
+use crate::network::bgp::{BGPError, Prefix};
+use crate::storage::{Namespace, StorageError};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+/// Store namespace holding the pins
+pub const NAMESPACE: &str = "route_pins";
+
+/// How often expired pins are looked for
+pub const PIN_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct RoutePin {
+    pub network: Prefix,
+    /// Peer whose path is selected while it has one
+    pub via_asn: u32,
+    pub pinned_at: DateTime<Utc>,
+    /// When the pin removes itself; `None` keeps it until unpinned
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl RoutePin {
+    pub fn new(network: Prefix, via_asn: u32, now: DateTime<Utc>) -> Self {
+        RoutePin {
+            network,
+            via_asn,
+            pinned_at: now,
+            expires_at: None,
+        }
+    }
+
+    pub fn with_expiry(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PinStore {
+    pins: HashMap<Prefix, RoutePin>,
+    storage: Option<Namespace>,
+    /// Pinned prefixes selected normally because the pinned peer has no
+    /// usable path
+    unmet: HashSet<Prefix>,
+}
+
+impl PinStore {
+    /// Restore the pins kept in `storage`
+    pub fn open(storage: Option<Namespace>) -> Result<Self, BGPError> {
+        let pins: Vec<RoutePin> = match &storage {
+            Some(storage) => storage
+                .iter_prefix::<RoutePin>("")
+                .map_err(BGPError::PinPersist)?
+                .into_iter()
+                .map(|(_, pin)| pin)
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(PinStore {
+            pins: pins.into_iter().map(|pin| (pin.network, pin)).collect(),
+            storage,
+            unmet: HashSet::new(),
+        })
+    }
+
+    /// Move pins from the single file earlier versions kept them in into
+    /// the store, removing the file; pins already in the store win
+    pub fn adopt_legacy(&mut self, path: &Path) -> Result<usize, BGPError> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(source) => {
+                return Err(BGPError::PinPersist(StorageError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                }))
+            }
+        };
+        let legacy: Vec<RoutePin> = serde_json::from_slice(&data)?;
+        let mut adopted = 0;
+        for pin in legacy {
+            if !self.pins.contains_key(&pin.network) {
+                self.insert(pin)?;
+                adopted += 1;
+            }
+        }
+        std::fs::remove_file(path).map_err(|source| {
+            BGPError::PinPersist(StorageError::Io {
+                path: path.to_path_buf(),
+                source,
+            })
+        })?;
+        Ok(adopted)
+    }
+
+    pub fn get(&self, network: &Prefix) -> Option<&RoutePin> {
+        self.pins.get(network)
+    }
+
+    /// Every pin, ordered by prefix
+    pub fn list(&self) -> Vec<RoutePin> {
+        let mut pins: Vec<RoutePin> = self.pins.values().cloned().collect();
+        pins.sort_by_key(|pin| pin.network);
+        pins
+    }
+
+    /// Add or replace the pin for its prefix
+    pub fn insert(&mut self, pin: RoutePin) -> Result<(), BGPError> {
+        if let Some(storage) = &self.storage {
+            storage
+                .put(&pin.network.to_string(), &pin)
+                .map_err(BGPError::PinPersist)?;
+        }
+        self.unmet.remove(&pin.network);
+        self.pins.insert(pin.network, pin);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, network: &Prefix) -> Result<Option<RoutePin>, BGPError> {
+        self.unmet.remove(network);
+        let removed = self.pins.remove(network);
+        if removed.is_some() {
+            self.forget(network)?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove and return the pins that expired by `now`
+    pub fn take_expired(&mut self, now: DateTime<Utc>) -> Result<Vec<RoutePin>, BGPError> {
+        let expired: Vec<Prefix> = self
+            .pins
+            .values()
+            .filter(|pin| pin.expired(now))
+            .map(|pin| pin.network)
+            .collect();
+        if expired.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut removed = Vec::with_capacity(expired.len());
+        for network in &expired {
+            self.unmet.remove(network);
+            if let Some(pin) = self.pins.remove(network) {
+                self.forget(network)?;
+                removed.push(pin);
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Record whether selection could honour the pin on `network`; true
+    /// when it just stopped being able to
+    pub fn note_met(&mut self, network: &Prefix, met: bool) -> bool {
+        if met {
+            self.unmet.remove(network);
+            false
+        } else {
+            self.unmet.insert(*network)
+        }
+    }
+
+    fn forget(&self, network: &Prefix) -> Result<(), BGPError> {
+        if let Some(storage) = &self.storage {
+            storage
+                .delete(&network.to_string())
+                .map_err(BGPError::PinPersist)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Store;
+
+    #[test]
+    fn test_pins_survive_reopening_and_expire() {
+        let root = std::env::temp_dir().join(format!("vx0net-pins-{}", uuid::Uuid::new_v4()));
+        let storage = || Some(Store::open(&root).unwrap().namespace(NAMESPACE));
+        let now = Utc::now();
+        let network: Prefix = "10.40.0.0/16".parse().unwrap();
+        let mut store = PinStore::open(storage()).unwrap();
+        store
+            .insert(
+                RoutePin::new(network, 65002, now).with_expiry(now + chrono::Duration::hours(2)),
+            )
+            .unwrap();
+
+        let mut reopened = PinStore::open(storage()).unwrap();
+        assert_eq!(reopened.get(&network).unwrap().via_asn, 65002);
+        assert!(reopened.take_expired(now).unwrap().is_empty());
+        let expired = reopened
+            .take_expired(now + chrono::Duration::hours(2))
+            .unwrap();
+        assert_eq!(expired.len(), 1);
+        assert!(PinStore::open(storage()).unwrap().list().is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_pins_file_from_earlier_versions_is_adopted() {
+        let root = std::env::temp_dir().join(format!("vx0net-pins-{}", uuid::Uuid::new_v4()));
+        let legacy = root.join("route-pins.json");
+        std::fs::create_dir_all(&root).unwrap();
+        let now = Utc::now();
+        let pins = vec![
+            RoutePin::new("10.40.0.0/16".parse().unwrap(), 65002, now),
+            RoutePin::new("10.41.0.0/16".parse().unwrap(), 65003, now),
+        ];
+        std::fs::write(&legacy, serde_json::to_vec(&pins).unwrap()).unwrap();
+
+        let storage = Store::open(root.join("store"))
+            .unwrap()
+            .namespace(NAMESPACE);
+        let mut store = PinStore::open(Some(storage.clone())).unwrap();
+        assert_eq!(store.adopt_legacy(&legacy).unwrap(), 2);
+        assert!(!legacy.exists());
+        assert_eq!(PinStore::open(Some(storage)).unwrap().list(), pins);
+        assert_eq!(store.adopt_legacy(&legacy).unwrap(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+//! Data-driven import rules layered on top of the tier policy.
+//!
+//! Rules are evaluated in order. `accept` and `reject` end evaluation;
+//! `next` applies any attribute changes and moves on. A route no rule
+//! decides falls through to the node's tier policy. Every decision names the
+//! rule that made it so a dry run can explain itself.
+
+use crate::network::bgp::{Community, Prefix, RouteEntry};
+use ipnet::IpNet;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Conditions a route must meet for a rule to apply; unset fields match anything
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteMatch {
+    /// Route must fall within this prefix
+    pub prefix: Option<IpNet>,
+    pub prefix_len_min: Option<u8>,
+    pub prefix_len_max: Option<u8>,
+    /// ASN of the neighbor the route was learned from
+    pub peer_asn: Option<u32>,
+    /// Last ASN in the path, i.e. the originator
+    pub origin_asn: Option<u32>,
+    pub as_path_contains: Option<u32>,
+    pub community: Option<Community>,
+}
+
+impl RouteMatch {
+    pub fn matches(&self, route: &RouteEntry, peer_asn: u32) -> bool {
+        let network = route.network.net();
+        self.prefix.is_none_or(|prefix| prefix.contains(&network))
+            && self
+                .prefix_len_min
+                .is_none_or(|min| network.prefix_len() >= min)
+            && self
+                .prefix_len_max
+                .is_none_or(|max| network.prefix_len() <= max)
+            && self.peer_asn.is_none_or(|asn| asn == peer_asn)
+            && self
+                .origin_asn
+                .is_none_or(|asn| route.as_path.last() == Some(&asn))
+            && self
+                .as_path_contains
+                .is_none_or(|asn| route.as_path.contains(&asn))
+            && self
+                .community
+                .as_ref()
+                .is_none_or(|community| route.communities.contains(community))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Accept,
+    Reject,
+    /// Apply the rule's attribute changes and keep evaluating
+    #[default]
+    Next,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub name: String,
+    #[serde(rename = "match", default)]
+    pub matches: RouteMatch,
+    #[serde(default)]
+    pub action: RuleAction,
+    #[serde(default)]
+    pub set_local_pref: Option<u32>,
+    #[serde(default)]
+    pub set_med: Option<u32>,
+    #[serde(default)]
+    pub add_communities: Vec<Community>,
+}
+
+impl PolicyRule {
+    /// Apply attribute changes, returning a description of each one made
+    fn apply(&self, route: &mut RouteEntry) -> Vec<String> {
+        let mut changes = Vec::new();
+        if let Some(local_pref) = self.set_local_pref {
+            if route.local_pref != local_pref {
+                changes.push(format!("local_pref {} -> {}", route.local_pref, local_pref));
+                route.local_pref = local_pref;
+            }
+        }
+        if let Some(med) = self.set_med {
+            if route.med != med {
+                changes.push(format!("med {} -> {}", route.med, med));
+                route.med = med;
+            }
+        }
+        for community in &self.add_communities {
+            if !route.communities.contains(community) {
+                changes.push(format!("community +{}:{}", community.asn, community.value));
+                route.communities.push(community.clone());
+            }
+        }
+        changes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Verdict {
+    Accept,
+    Reject,
+}
+
+/// Whether a route passed a policy check, and which rule said so and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PolicyVerdict {
+    pub allowed: bool,
+    /// A configured rule's name, `deny-prefix`, or the tier rule
+    /// (`tier:full-table`, ...)
+    pub rule: String,
+    pub details: String,
+}
+
+impl PolicyVerdict {
+    pub fn allow(rule: impl Into<String>, details: impl Into<String>) -> Self {
+        PolicyVerdict {
+            allowed: true,
+            rule: rule.into(),
+            details: details.into(),
+        }
+    }
+
+    pub fn deny(rule: impl Into<String>, details: impl Into<String>) -> Self {
+        PolicyVerdict {
+            allowed: false,
+            rule: rule.into(),
+            details: details.into(),
+        }
+    }
+}
+
+impl fmt::Display for PolicyVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.allowed { "allowed" } else { "denied" };
+        write!(f, "{} by rule {}: {}", action, self.rule, self.details)
+    }
+}
+
+/// Outcome of running a route through import policy
+#[derive(Debug, Clone)]
+pub struct PolicyDecision {
+    pub verdict: Verdict,
+    /// Rule that decided the verdict
+    pub rule: String,
+    /// Why the rule decided as it did
+    pub details: String,
+    /// The route with any attribute changes applied
+    pub route: RouteEntry,
+    pub changes: Vec<String>,
+}
+
+impl PolicyDecision {
+    pub fn accepted(&self) -> bool {
+        self.verdict == Verdict::Accept
+    }
+
+    pub fn to_verdict(&self) -> PolicyVerdict {
+        PolicyVerdict {
+            allowed: self.accepted(),
+            rule: self.rule.clone(),
+            details: self.details.clone(),
+        }
+    }
+}
+
+/// Run `rules` in order; `None` means no rule reached a verdict
+pub(crate) fn evaluate_rules<'a>(
+    rules: impl IntoIterator<Item = &'a PolicyRule>,
+    route: &mut RouteEntry,
+    peer_asn: u32,
+    changes: &mut Vec<String>,
+) -> Option<(Verdict, String)> {
+    for rule in rules {
+        if !rule.matches.matches(route, peer_asn) {
+            continue;
+        }
+        changes.extend(rule.apply(route));
+        match rule.action {
+            RuleAction::Accept => return Some((Verdict::Accept, rule.name.clone())),
+            RuleAction::Reject => return Some((Verdict::Reject, rule.name.clone())),
+            RuleAction::Next => {}
+        }
+    }
+    None
+}
+
+/// A candidate policy, as given to `vx0net policy test`
+///
+/// Uses the same keys as the `[network.bgp]` config: global
+/// `[[import_policy]]` rules plus `[[peers]]` entries carrying their own
+/// `import_policy`. Other peer keys are ignored so a block can be pasted as-is.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolicyFragment {
+    pub import_policy: Vec<PolicyRule>,
+    pub peers: Vec<PeerPolicy>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerPolicy {
+    pub asn: u32,
+    #[serde(default)]
+    pub import_policy: Vec<PolicyRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DryRunOutcome {
+    Accept,
+    Reject,
+    /// Accepted with attributes changed by a rule
+    Modified,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DryRunEntry {
+    pub network: Prefix,
+    pub peer_asn: u32,
+    pub outcome: DryRunOutcome,
+    pub rule: String,
+    #[serde(default)]
+    pub changes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct DryRunReport {
+    pub entries: Vec<DryRunEntry>,
+}
+
+impl DryRunReport {
+    pub fn with_outcome(&self, outcome: DryRunOutcome) -> Vec<&DryRunEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.outcome == outcome)
+            .collect()
+    }
+}
@@ -0,0 +1,183 @@
+//! Route keys normalized to their network address.
+//!
+//! `10.2.1.5/24` and `10.2.1.0/24` are the same network, but as raw `IpNet`
+//! values they compare and hash differently, so a route installed under one
+//! could not be withdrawn under the other. A `Prefix` is always truncated to
+//! its network address when built, and is the only key the RIBs, the
+//! advertisement pacer and the withdraw path accept. Peers that send host
+//! bits are handled according to their `host_bits` setting.
+
+use crate::config::HostBitsPolicy;
+use ipnet::IpNet;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Prefix(IpNet);
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{network} has host bits set; the network is {}", network.trunc())]
+pub struct HostBitsSet {
+    pub network: IpNet,
+}
+
+impl Prefix {
+    /// The network `net` lies in, dropping any host bits
+    pub fn new(net: IpNet) -> Self {
+        Prefix(net.trunc())
+    }
+
+    /// `net` as given, refusing it when host bits are set
+    pub fn exact(net: IpNet) -> Result<Self, HostBitsSet> {
+        if has_host_bits(&net) {
+            return Err(HostBitsSet { network: net });
+        }
+        Ok(Prefix(net))
+    }
+
+    /// A prefix a peer sent, under the peer's `host_bits` setting; `None`
+    /// when it is refused
+    pub fn from_peer(net: IpNet, policy: HostBitsPolicy, peer_asn: u32) -> Option<Self> {
+        if !has_host_bits(&net) {
+            return Some(Prefix(net));
+        }
+        match policy {
+            HostBitsPolicy::Normalize => {}
+            HostBitsPolicy::Warn => {
+                tracing::warn!(
+                    "AS{} sent {} with host bits set; treating it as {}",
+                    peer_asn,
+                    net,
+                    net.trunc()
+                );
+            }
+            HostBitsPolicy::Reject => {
+                tracing::warn!(
+                    "AS{} sent {} with host bits set; ignoring it",
+                    peer_asn,
+                    net
+                );
+                return None;
+            }
+        }
+        Some(Prefix::new(net))
+    }
+
+    pub fn net(&self) -> IpNet {
+        self.0
+    }
+
+    /// Whether `next_hop` is in the same address family
+    pub fn reachable_via(&self, next_hop: &IpAddr) -> bool {
+        matches!(
+            (self.0, next_hop),
+            (IpNet::V4(_), IpAddr::V4(_)) | (IpNet::V6(_), IpAddr::V6(_))
+        )
+    }
+}
+
+pub fn has_host_bits(net: &IpNet) -> bool {
+    net.trunc() != *net
+}
+
+impl Deref for Prefix {
+    type Target = IpNet;
+
+    fn deref(&self) -> &IpNet {
+        &self.0
+    }
+}
+
+impl From<IpNet> for Prefix {
+    fn from(net: IpNet) -> Self {
+        Prefix::new(net)
+    }
+}
+
+/// The host route for `addr`
+impl From<IpAddr> for Prefix {
+    fn from(addr: IpAddr) -> Self {
+        Prefix(IpNet::from(addr))
+    }
+}
+
+impl From<Prefix> for IpNet {
+    fn from(prefix: Prefix) -> Self {
+        prefix.0
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = ipnet::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Prefix::new)
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Serialize for Prefix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Prefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        IpNet::deserialize(deserializer).map(Prefix::new)
+    }
+}
+
+/// A CIDR string such as `10.2.1.0/24`
+impl JsonSchema for Prefix {
+    fn schema_name() -> String {
+        "Prefix".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some("cidr".to_string()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_drops_host_bits() {
+        let loose: Prefix = "10.2.1.5/24".parse().unwrap();
+        let tight: Prefix = "10.2.1.0/24".parse().unwrap();
+        assert_eq!(loose, tight);
+        assert_eq!(loose.to_string(), "10.2.1.0/24");
+        let decoded: Prefix = serde_json::from_str("\"10.2.1.5/24\"").unwrap();
+        assert_eq!(decoded, tight);
+
+        let raw: IpNet = "10.2.1.5/24".parse().unwrap();
+        assert_eq!(Prefix::exact(raw), Err(HostBitsSet { network: raw }));
+        assert_eq!(
+            Prefix::from_peer(raw, HostBitsPolicy::Warn, 65001),
+            Some(tight)
+        );
+        assert_eq!(Prefix::from_peer(raw, HostBitsPolicy::Reject, 65001), None);
+        assert_eq!(
+            Prefix::from_peer(tight.net(), HostBitsPolicy::Reject, 65001),
+            Some(tight)
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! How long routes take to cross the network.
+//!
+//! With `network.bgp.trace_propagation` a node stamps the routes it
+//! originates with the time it did so, in `RouteEntry::originated_at`, and
+//! the stamp travels with the route from hop to hop. A receiving node takes
+//! the time between the stamp and the route's arrival as its propagation
+//! delay. Routes from nodes that do not stamp are only counted.
+//!
+//! Nodes' clocks disagree, so each UPDATE's send time is compared with when
+//! it arrived: the smallest such gap over recent UPDATEs from a peer is that
+//! peer's skew. A stamp is moved into our clock by adding the skew of the
+//! peer it came from before the delay is measured, and travels on in our
+//! clock, so every hop only corrects for its neighbour. The skew also takes
+//! in the quickest transit seen from the peer; what is measured is the time
+//! spent beyond it, in queues, pacing and processing, and never less than
+//! zero.
+
+use crate::monitoring::register_metric;
+use crate::network::bgp::{Prefix, RouteEntry};
+use crate::node::NodeTier;
+use chrono::{DateTime, Utc};
+use prometheus::{GaugeVec, Opts};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::OnceLock;
+
+/// UPDATEs per peer whose send times the skew is taken from
+pub const SKEW_WINDOW: usize = 32;
+/// Most recent delays per origin tier the percentiles are taken from
+pub const SAMPLES_PER_TIER: usize = 256;
+
+const TIERS: [NodeTier; 3] = [NodeTier::Backbone, NodeTier::Regional, NodeTier::Edge];
+
+/// The delay last measured for a prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PropagationSample {
+    /// The AS that originated the route, and its tier
+    pub origin_asn: u32,
+    pub origin_tier: NodeTier,
+    /// The peer it arrived from
+    pub peer_asn: u32,
+    pub delay_ms: u64,
+    /// Correction applied for the peer's clock
+    pub skew_ms: i64,
+    pub measured_at: DateTime<Utc>,
+}
+
+impl fmt::Display for PropagationSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}ms from AS{} ({:?}) via AS{}",
+            self.delay_ms, self.origin_asn, self.origin_tier, self.peer_asn
+        )?;
+        if self.skew_ms != 0 {
+            write!(f, ", clock skew {:+}ms", self.skew_ms)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delays of routes originated by one tier
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TierDelays {
+    pub tier: NodeTier,
+    /// Delays the percentiles were taken from
+    pub samples: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PropagationStats {
+    /// Only tiers some stamped route came from
+    pub tiers: Vec<TierDelays>,
+    /// Routes that arrived without an origination stamp
+    pub unstamped: u64,
+    /// Prefixes with a measured delay
+    pub prefixes: usize,
+}
+
+/// Per-peer skew and per-prefix and per-tier delays, as kept by the RIB
+#[derive(Debug, Default)]
+pub struct PropagationTracker {
+    /// Stamp routes this node originates
+    stamp: bool,
+    /// Recent (arrival - send time) of UPDATEs, in ms, by peer
+    skews: HashMap<u32, VecDeque<i64>>,
+    latest: HashMap<Prefix, PropagationSample>,
+    /// Recent delays by origin tier, in `TIERS` order
+    by_tier: [VecDeque<u64>; 3],
+    unstamped: u64,
+}
+
+impl PropagationTracker {
+    pub fn set_stamping(&mut self, stamp: bool) {
+        self.stamp = stamp;
+    }
+
+    pub fn stamping(&self) -> bool {
+        self.stamp
+    }
+
+    /// Stamp a route we originate, when tracing
+    pub fn stamp(&self, route: &mut RouteEntry) {
+        if self.stamp && route.originated_at.is_none() {
+            route.originated_at = Some(route.timestamp);
+        }
+    }
+
+    /// Note that an UPDATE `peer_asn` sent at `sent`, by its clock, arrived
+    /// at `received`; returns how far its gap strays from the UPDATE's
+    /// before, zero for the first. A peer whose clock steps strays once,
+    /// one whose timestamps are erratic keeps straying.
+    pub fn observe_clock(
+        &mut self,
+        peer_asn: u32,
+        sent: DateTime<Utc>,
+        received: DateTime<Utc>,
+    ) -> chrono::Duration {
+        let gap = (received - sent).num_milliseconds();
+        let gaps = self.skews.entry(peer_asn).or_default();
+        let deviation = gaps.back().map_or(0, |last| gap - last);
+        gaps.push_back(gap);
+        while gaps.len() > SKEW_WINDOW {
+            gaps.pop_front();
+        }
+        chrono::Duration::milliseconds(deviation)
+    }
+
+    /// How far ahead our clock is of the peer's, zero until measured
+    pub fn skew(&self, peer_asn: u32) -> chrono::Duration {
+        let skew_ms = self
+            .skews
+            .get(&peer_asn)
+            .and_then(|gaps| gaps.iter().min().copied())
+            .unwrap_or(0);
+        chrono::Duration::milliseconds(skew_ms)
+    }
+
+    /// Measure the delay of a route arriving from `peer_asn` at `now`,
+    /// moving its stamp into our clock for the peers it goes on to
+    pub fn record(&mut self, peer_asn: u32, route: &mut RouteEntry, now: DateTime<Utc>) {
+        let Some(originated_at) = route.originated_at else {
+            self.unstamped += 1;
+            return;
+        };
+        let skew = self.skew(peer_asn);
+        let local = originated_at + skew;
+        route.originated_at = Some(local);
+
+        let delay_ms = (now - local).num_milliseconds().max(0) as u64;
+        let origin_asn = route.as_path.last().copied().unwrap_or(peer_asn);
+        let origin_tier = NodeTier::from_asn(origin_asn);
+        let index = tier_index(&origin_tier);
+        let delays = &mut self.by_tier[index];
+        delays.push_back(delay_ms);
+        while delays.len() > SAMPLES_PER_TIER {
+            delays.pop_front();
+        }
+        let delays = self.tier_delays(index);
+        let gauge = delay_gauge();
+        let tier = format!("{:?}", origin_tier);
+        for (quantile, ms) in [("0.5", delays.p50_ms), ("0.95", delays.p95_ms)] {
+            gauge
+                .with_label_values(&[tier.as_str(), quantile])
+                .set(ms as f64 / 1000.0);
+        }
+
+        self.latest.insert(
+            route.network,
+            PropagationSample {
+                origin_asn,
+                origin_tier,
+                peer_asn,
+                delay_ms,
+                skew_ms: skew.num_milliseconds(),
+                measured_at: now,
+            },
+        );
+    }
+
+    /// Drop the delay measured for a prefix `peer_asn` withdrew
+    pub fn withdrawn(&mut self, peer_asn: u32, network: &Prefix) {
+        if self
+            .latest
+            .get(network)
+            .is_some_and(|sample| sample.peer_asn == peer_asn)
+        {
+            self.latest.remove(network);
+        }
+    }
+
+    /// Forget a peer's clock, as its sessions end
+    pub fn peer_gone(&mut self, peer_asn: u32) {
+        self.skews.remove(&peer_asn);
+    }
+
+    pub fn sample(&self, network: &Prefix) -> Option<&PropagationSample> {
+        self.latest.get(network)
+    }
+
+    pub fn stats(&self) -> PropagationStats {
+        PropagationStats {
+            tiers: (0..TIERS.len())
+                .filter(|&index| !self.by_tier[index].is_empty())
+                .map(|index| self.tier_delays(index))
+                .collect(),
+            unstamped: self.unstamped,
+            prefixes: self.latest.len(),
+        }
+    }
+
+    fn tier_delays(&self, index: usize) -> TierDelays {
+        let mut delays: Vec<u64> = self.by_tier[index].iter().copied().collect();
+        delays.sort_unstable();
+        TierDelays {
+            tier: TIERS[index].clone(),
+            samples: delays.len(),
+            p50_ms: percentile(&delays, 50),
+            p95_ms: percentile(&delays, 95),
+            max_ms: delays.last().copied().unwrap_or(0),
+        }
+    }
+}
+
+fn tier_index(tier: &NodeTier) -> usize {
+    match tier {
+        NodeTier::Backbone => 0,
+        NodeTier::Regional => 1,
+        NodeTier::Edge => 2,
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Propagation delay percentiles, by origin tier and quantile
+pub fn delay_gauge() -> &'static GaugeVec {
+    static GAUGE: OnceLock<GaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(GaugeVec::new(
+            Opts::new(
+                "vx0net_bgp_propagation_delay_seconds",
+                "Seconds routes took to arrive from their origin, by origin tier",
+            ),
+            &["tier", "quantile"],
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::testing;
+
+    fn route(origin_asn: u32, originated_at: Option<DateTime<Utc>>) -> RouteEntry {
+        RouteEntry {
+            originated_at,
+            ..testing::route("10.2.1.0/24", "10.1.1.1", &[65101, origin_asn])
+        }
+    }
+
+    #[test]
+    fn test_skewed_clocks_are_corrected() {
+        let mut tracker = PropagationTracker::default();
+        let now = Utc::now();
+        // The peer's clock runs 10s ahead; its UPDATEs take 40ms at best
+        let ahead = chrono::Duration::seconds(10);
+        let sent = now - chrono::Duration::seconds(1);
+        for transit in [90, 40, 60] {
+            let received = sent + chrono::Duration::milliseconds(transit);
+            tracker.observe_clock(65101, sent + ahead, received);
+        }
+        assert_eq!(tracker.skew(65101).num_milliseconds(), -9_960);
+
+        // Originated 500ms ago by our clock, stamped by the peer's
+        let mut stamped = route(
+            66001,
+            Some(now + ahead - chrono::Duration::milliseconds(500)),
+        );
+        tracker.record(65101, &mut stamped, now);
+        let sample = tracker.sample(&stamped.network).unwrap();
+        // Less the quickest transit
+        assert_eq!(sample.delay_ms, 460);
+        assert_eq!(sample.origin_tier, NodeTier::Edge);
+        // Passed on in our clock
+        assert_eq!(
+            stamped.originated_at,
+            Some(now - chrono::Duration::milliseconds(460))
+        );
+
+        let mut unstamped = route(66002, None);
+        tracker.record(65101, &mut unstamped, now);
+        let stats = tracker.stats();
+        assert_eq!(stats.unstamped, 1);
+        assert_eq!(stats.prefixes, 1);
+        assert_eq!(stats.tiers.len(), 1);
+    }
+
+    #[test]
+    fn test_percentiles_by_tier() {
+        let mut tracker = PropagationTracker::default();
+        let now = Utc::now();
+        for ms in 1..=100 {
+            let mut stamped = route(66001, Some(now - chrono::Duration::milliseconds(ms)));
+            tracker.record(65101, &mut stamped, now);
+        }
+        let mut future = route(65001, Some(now + chrono::Duration::seconds(1)));
+        tracker.record(65101, &mut future, now);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.tiers[0].tier, NodeTier::Backbone);
+        assert_eq!(stats.tiers[0].max_ms, 0);
+        let edge = &stats.tiers[1];
+        assert_eq!((edge.p50_ms, edge.p95_ms, edge.max_ms), (50, 95, 100));
+    }
+}
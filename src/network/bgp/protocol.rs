@@ -1,12 +1,45 @@
-use crate::network::bgp::{BGPError, BGPOrigin, BGPSession, RouteEntry};
+use crate::monitoring::capture::{Capture, CapturedEvent};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::adaptive::{self, AdaptiveTimers, Probe};
+use crate::network::bgp::messages::{
+    NotificationMessage, BGP_ERROR_CEASE, BGP_ERROR_MESSAGE_HEADER, BGP_ERROR_OPEN_MESSAGE,
+    CEASE_TIER_VIOLATION,
+};
+use crate::network::bgp::rib::{Rib, RibChange};
+use crate::network::bgp::routing::RoutingPolicy;
+use crate::network::bgp::table_sync::{SyncResume, TableChunk, TablePages, TableSyncs};
+use crate::network::bgp::tunnel_gate::{self, TunnelGate};
+use crate::network::bgp::validator::RouteValidator;
+use crate::network::bgp::wire::{
+    self, BGP_MSG_KEEPALIVE, BGP_MSG_NOTIFICATION, BGP_MSG_OPEN, BGP_MSG_UPDATE,
+};
+use crate::network::bgp::{
+    BGPError, BGPOrigin, BGPSession, BGPSessionState, Prefix, RouteEntry, RouteTable,
+};
+use crate::network::ike::channel::{ChannelSender, TunnelChannel};
+use crate::network::ike::resumption::{
+    self, PeerIdentity, ResumeHello, ResumeRefused, ResumptionCache, Transcript,
+};
+use crate::network::obfuscation::Jitter;
+use crate::network::transport::{self, TransportConfig};
+use crate::node::capabilities::Capabilities;
 use crate::node::NodeTier;
-use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::tcp::{ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{Mutex, RwLock};
 
+/// A message on a VX0 session, framed with the RFC 4271 header by
+/// [`wire::write_frame`]. Once both OPENs offered `binary_messages`, the
+/// body is the RFC 4271 message [`wire::encode_vx0`] builds; until then,
+/// and with nodes that predate it, the body is the message as JSON. Peers
+/// configured with `wire = "rfc4271"` speak `messages::BGPMessage` through
+/// the plain codec in `wire` instead.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BGPMessage {
     pub message_type: BGPMessageType,
@@ -14,9 +47,86 @@ pub struct BGPMessage {
     pub router_id: IpAddr,
     pub routes: Vec<BGPRoute>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Sent in OPEN only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Capabilities>,
+    /// Sent in NOTIFICATION only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notification: Option<NotificationMessage>,
+    /// Sent in OPEN only, by ends that resume sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<ResumeHello>,
+    /// Sent in UPDATE only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub withdrawn: Vec<Prefix>,
+    /// Sent in UPDATE only: the version of the sender's table the receiver
+    /// holds once it applied this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table_version: Option<u64>,
+    /// Sent in OPEN replies only: the address the connection came from, as
+    /// the replying end saw it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_addr: Option<IpAddr>,
+    /// Sent in UPDATE only, when it is one chunk of a table sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<TableChunk>,
+    /// Sent in KEEPALIVE only: the table sync chunk just applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_ack: Option<u32>,
+    /// Sent in OPEN replies only: where an interrupted table sync from the
+    /// connecting peer stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync_resume: Option<SyncResume>,
+    /// Sent in OPEN only: the sender reads RFC 4271 binary messages. Both
+    /// ends switch to them once both OPENs say so.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub binary_messages: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl BGPMessage {
+    /// A message of `message_type` from `asn` with nothing in it yet
+    pub fn new(message_type: BGPMessageType, asn: u32, router_id: IpAddr) -> Self {
+        BGPMessage {
+            message_type,
+            asn,
+            router_id,
+            routes: vec![],
+            timestamp: chrono::Utc::now(),
+            capabilities: None,
+            notification: None,
+            resume: None,
+            withdrawn: vec![],
+            table_version: None,
+            observed_addr: None,
+            chunk: None,
+            chunk_ack: None,
+            sync_resume: None,
+            binary_messages: false,
+        }
+    }
+
+    /// The routes an UPDATE announces, as they enter the RIB
+    pub fn announced(&self) -> Vec<RouteEntry> {
+        self.routes
+            .iter()
+            .cloned()
+            .map(|route| RouteEntry {
+                network: route.network,
+                next_hop: route.next_hop,
+                as_path: route.as_path.into(),
+                origin: route.origin,
+                local_pref: route.local_pref,
+                med: route.med,
+                communities: vec![],
+                timestamp: chrono::Utc::now(),
+                learned_from: None,
+                originated_at: route.originated_at,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BGPMessageType {
     Open,
     Update,
@@ -24,20 +134,139 @@ pub enum BGPMessageType {
     Notification,
 }
 
+impl BGPMessageType {
+    /// The RFC 4271 type the message is framed under
+    pub fn code(&self) -> u8 {
+        match self {
+            BGPMessageType::Open => BGP_MSG_OPEN,
+            BGPMessageType::Update => BGP_MSG_UPDATE,
+            BGPMessageType::Notification => BGP_MSG_NOTIFICATION,
+            BGPMessageType::Keepalive => BGP_MSG_KEEPALIVE,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BGPRoute {
-    pub network: IpNet,
+    pub network: Prefix,
     pub next_hop: IpAddr,
     pub as_path: Vec<u32>,
     pub origin: BGPOrigin,
     pub local_pref: u32,
     pub med: u32,
+    /// When the origin stamped the route, by the sender's clock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub originated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// How a session's messages are encoded, as its OPENs agreed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// JSON behind the RFC 4271 header, which every VX0 node reads
+    Json,
+    /// RFC 4271 binary messages; only OPEN names its sender, so the rest
+    /// are taken to come from this peer
+    Binary(PeerIdentity),
+}
+
+/// A session's stream once OPENs are exchanged
+pub struct BGPStream {
+    link: Link,
+    encoding: Encoding,
+}
+
+enum Link {
+    Plain(TcpStream),
+    /// Sealed by the tunnel to the peer; see `network.bgp.bgp_over_tunnel`
+    Tunneled(TunnelChannel),
+}
+
+impl BGPStream {
+    pub fn plain(stream: TcpStream) -> Self {
+        BGPStream {
+            link: Link::Plain(stream),
+            encoding: Encoding::Json,
+        }
+    }
+
+    pub fn tunneled(channel: TunnelChannel) -> Self {
+        BGPStream {
+            link: Link::Tunneled(channel),
+            encoding: Encoding::Json,
+        }
+    }
+
+    /// Encode messages from now on as the OPENs agreed
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn is_tunneled(&self) -> bool {
+        matches!(self.link, Link::Tunneled(_))
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    fn peer(&self) -> Result<IpAddr, BGPError> {
+        match &self.link {
+            Link::Plain(stream) => Ok(stream.peer_addr()?.ip()),
+            Link::Tunneled(channel) => Ok(channel.peer()),
+        }
+    }
+
+    /// Halves to read and write at once, so UPDATEs go out while the
+    /// peer's are read
+    fn split(&mut self) -> (Incoming<'_>, Outgoing<'_>) {
+        let encoding = self.encoding;
+        match &mut self.link {
+            Link::Plain(stream) => {
+                let (reader, writer) = stream.split();
+                (
+                    Incoming::Plain(reader, encoding),
+                    Outgoing::Plain(writer, encoding),
+                )
+            }
+            Link::Tunneled(channel) => {
+                let sender = channel.sender();
+                (
+                    Incoming::Tunneled(channel, encoding),
+                    Outgoing::Tunneled(sender, encoding),
+                )
+            }
+        }
+    }
+}
+
+enum Incoming<'a> {
+    Plain(ReadHalf<'a>, Encoding),
+    Tunneled(&'a mut TunnelChannel, Encoding),
+}
+
+enum Outgoing<'a> {
+    Plain(WriteHalf<'a>, Encoding),
+    Tunneled(ChannelSender, Encoding),
 }
 
 pub struct BGPProtocol {
     local_asn: u32,
     router_id: IpAddr,
     tier: NodeTier,
+    capabilities: Capabilities,
+    keepalive_jitter: Jitter,
+    /// Stretches keepalives on stable links, when set
+    adaptive: Option<Arc<AdaptiveTimers>>,
+    tunnel_gate: Option<Arc<TunnelGate>>,
+    validator: Option<Arc<RouteValidator>>,
+    transport: TransportConfig,
+    /// Progress of table syncs from peers, and how ours are paged
+    table_syncs: Option<Arc<TableSyncs>>,
+    /// Records messages received on established sessions, when capturing
+    capture: Option<Arc<Capture>>,
+    /// Offer binary messages in our OPEN
+    binary_messages: bool,
 }
 
 impl BGPProtocol {
@@ -46,29 +275,147 @@ impl BGPProtocol {
             local_asn,
             router_id,
             tier,
+            capabilities: Capabilities::default(),
+            keepalive_jitter: Jitter::default(),
+            adaptive: None,
+            tunnel_gate: None,
+            validator: None,
+            transport: TransportConfig::default(),
+            table_syncs: None,
+            capture: None,
+            binary_messages: true,
         }
     }
 
-    pub async fn start_server(&self, listen_addr: SocketAddr) -> Result<(), BGPError> {
+    /// Advertise these capabilities in our OPEN
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Vary each keepalive interval randomly
+    pub fn with_keepalive_jitter(mut self, jitter: Jitter) -> Self {
+        self.keepalive_jitter = jitter;
+        self
+    }
+
+    /// Send keepalives as often as each peer's link has earned, and note
+    /// sessions that fail against it
+    pub fn with_adaptive_timers(mut self, adaptive: Arc<AdaptiveTimers>) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    pub fn adaptive_timers(&self) -> Option<&AdaptiveTimers> {
+        self.adaptive.as_deref()
+    }
+
+    /// Hold sessions opened from now on until a tunnel to the peer is
+    /// established
+    pub fn with_tunnel_gate(mut self, gate: Arc<TunnelGate>) -> Self {
+        self.tunnel_gate = Some(gate);
+        self
+    }
+
+    /// Have an external validator judge the routes peers send
+    pub fn with_validator(mut self, validator: Arc<RouteValidator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Track table syncs from peers in `syncs`, resuming interrupted ones,
+    /// and page ours with its settings
+    pub fn with_table_syncs(mut self, syncs: Arc<TableSyncs>) -> Self {
+        self.table_syncs = Some(syncs);
+        self
+    }
+
+    /// Record messages received on established sessions to `capture`
+    pub fn with_capture(mut self, capture: Arc<Capture>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Connect and frame messages with these timeouts and limits
+    pub fn with_transport(mut self, transport: TransportConfig) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Whether to offer RFC 4271 binary messages in our OPEN; on by
+    /// default, and used only with peers that offer them too
+    pub fn with_binary_messages(mut self, binary_messages: bool) -> Self {
+        self.binary_messages = binary_messages;
+        self
+    }
+
+    /// Binary OPENs carry the router ID as a BGP identifier, so only an
+    /// IPv4 one can be offered
+    fn offers_binary(&self) -> bool {
+        self.binary_messages && self.router_id.is_ipv4()
+    }
+
+    /// What both OPENs agreed on, `theirs` being the peer's
+    fn agreed_encoding(&self, theirs: &BGPMessage) -> Encoding {
+        if self.offers_binary() && theirs.binary_messages {
+            Encoding::Binary(PeerIdentity {
+                asn: theirs.asn,
+                router_id: theirs.router_id,
+            })
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn tunnel_gate(&self) -> Option<&TunnelGate> {
+        self.tunnel_gate.as_deref()
+    }
+
+    pub fn local_asn(&self) -> u32 {
+        self.local_asn
+    }
+
+    /// How peers that resume sessions with us know us
+    pub fn identity(&self) -> PeerIdentity {
+        PeerIdentity {
+            asn: self.local_asn,
+            router_id: self.router_id,
+        }
+    }
+
+    fn resumption(&self) -> Option<&ResumptionCache> {
+        self.tunnel_gate.as_deref()?.resumption()
+    }
+
+    /// Accept sessions in the background, returning the bound address
+    pub async fn start_server(&self, listen_addr: SocketAddr) -> Result<SocketAddr, BGPError> {
         let listener = TcpListener::bind(listen_addr).await?;
-        tracing::info!("BGP server listening on {}", listen_addr);
+        let bound = listener.local_addr()?;
+        tracing::info!("BGP server listening on {}", bound);
 
         let local_asn = self.local_asn;
         let router_id = self.router_id;
         let tier = self.tier.clone();
+        let capabilities = self.capabilities;
+        let keepalive_jitter = self.keepalive_jitter;
+        let adaptive = self.adaptive.clone();
+        let binary_messages = self.binary_messages;
 
-        tokio::spawn(async move {
+        crash::spawn(Subsystem::Bgp, "bgp-protocol-listener", async move {
             loop {
                 match listener.accept().await {
                     Ok((stream, peer_addr)) => {
                         tracing::info!("BGP connection from {}", peer_addr);
 
-                        let tier = tier.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = Self::handle_bgp_connection(
-                                stream, peer_addr, local_asn, router_id, tier,
-                            )
-                            .await
+                        let mut protocol = BGPProtocol::new(local_asn, router_id, tier.clone())
+                            .with_capabilities(capabilities)
+                            .with_keepalive_jitter(keepalive_jitter)
+                            .with_binary_messages(binary_messages);
+                        if let Some(adaptive) = &adaptive {
+                            protocol = protocol.with_adaptive_timers(Arc::clone(adaptive));
+                        }
+                        crash::spawn(Subsystem::Bgp, "bgp-protocol-session", async move {
+                            if let Err(e) = protocol.handle_bgp_connection(stream, peer_addr).await
                             {
                                 tracing::error!("BGP connection error: {}", e);
                             }
@@ -81,7 +428,7 @@ impl BGPProtocol {
             }
         });
 
-        Ok(())
+        Ok(bound)
     }
 
     pub async fn connect_to_peer(
@@ -89,106 +436,373 @@ impl BGPProtocol {
         peer_addr: SocketAddr,
         peer_asn: u32,
     ) -> Result<BGPSession, BGPError> {
+        let (session, _) = self.open_session(peer_addr, peer_asn).await?;
+        Ok(session)
+    }
+
+    /// Exchange OPENs with a peer, keeping the stream for UPDATEs. With a
+    /// tunnel gate this returns only once a tunnel to the peer is up.
+    pub async fn open_session(
+        &self,
+        peer_addr: SocketAddr,
+        peer_asn: u32,
+    ) -> Result<(BGPSession, BGPStream), BGPError> {
         tracing::info!("Connecting to BGP peer {} (ASN {})", peer_addr, peer_asn);
 
-        let mut stream = TcpStream::connect(peer_addr).await?;
+        let mut stream = transport::connect_tcp(peer_addr, &self.transport).await?;
+
+        // A ticket for the peer is offered along with our nonce; it is
+        // given up either way, so it is never offered twice
+        let mut resuming = None;
+        let hello = match self.resumption() {
+            Some(resumption) => {
+                let nonce = resumption::nonce().map_err(BGPError::Tunnel)?;
+                let offer = resumption
+                    .offer(peer_addr.ip(), peer_asn, self.identity(), &nonce)
+                    .map(|(offer, kept)| {
+                        resuming = Some(kept);
+                        offer
+                    });
+                Some(ResumeHello {
+                    nonce,
+                    offer,
+                    accepted: None,
+                })
+            }
+            None => None,
+        };
 
-        // Send BGP OPEN message
+        // Our OPEN is JSON, which every node reads; its answer is binary
+        // if it took up our offer of binary messages
         let open_msg = BGPMessage {
-            message_type: BGPMessageType::Open,
-            asn: self.local_asn,
-            router_id: self.router_id,
-            routes: vec![],
-            timestamp: chrono::Utc::now(),
+            capabilities: Some(self.capabilities),
+            resume: hello.clone(),
+            binary_messages: self.offers_binary(),
+            ..self.message(BGPMessageType::Open)
         };
 
-        self.send_message(&mut stream, &open_msg).await?;
+        self.send_message(&mut stream, Encoding::Json, &open_msg)
+            .await?;
 
         // Receive BGP OPEN response
-        let response = self.receive_message(&mut stream).await?;
+        let response = self.receive_message(&mut stream, Encoding::Json).await?;
         match response.message_type {
             BGPMessageType::Open => {
                 tracing::info!("BGP session established with ASN {}", response.asn);
 
                 // Create BGP session
-                let session = BGPSession::new(
+                let mut session = BGPSession::new(
                     self.local_asn,
                     response.asn,
                     peer_addr.ip(),
-                    std::sync::Arc::new(tokio::sync::RwLock::new(
-                        crate::network::bgp::RouteTable::new(),
-                    )),
+                    std::sync::Arc::new(tokio::sync::RwLock::new(Rib::new(RoutingPolicy::new(
+                        self.local_asn,
+                        self.tier.clone(),
+                    )))),
                 );
+                session.peer_capabilities = response.capabilities.unwrap_or_default();
+                session.observed_addr = response.observed_addr;
+                session.table_sync_resume = response.sync_resume;
+                session.state = BGPSessionState::OpenSent;
+
+                let theirs = session.peer_capabilities;
+                tunnel_gate::note_requirement(peer_addr, &theirs, self.tunnel_gate.is_some());
+                let stream = match &self.tunnel_gate {
+                    Some(gate) => {
+                        let hold = tokio::time::Duration::from_secs(session.hold_time.into());
+                        if let (Some(ours), Some(resuming), Some(answer)) =
+                            (&hello, resuming, &response.resume)
+                        {
+                            if let Some(version) =
+                                self.resume(gate, peer_addr, ours, resuming, answer).await
+                            {
+                                session.resumed = true;
+                                session.peer_table_version = version;
+                            }
+                        }
+                        gate.admit(peer_addr, hold).await?;
+                        if let (Some(ours), Some(answer)) = (&hello, &response.resume) {
+                            let responder = PeerIdentity {
+                                asn: response.asn,
+                                router_id: response.router_id,
+                            };
+                            let transcript = Transcript {
+                                initiator: self.identity(),
+                                initiator_nonce: &ours.nonce,
+                                responder,
+                                responder_nonce: &answer.nonce,
+                            };
+                            gate.keep_ticket(peer_addr.ip(), &transcript, responder)
+                                .await;
+                        }
+                        gate.stream(stream, peer_addr.ip(), &theirs)
+                    }
+                    None => BGPStream::plain(stream),
+                }
+                .with_encoding(self.agreed_encoding(&response));
+                session.state = BGPSessionState::Established;
 
-                Ok(session)
+                Ok((session, stream))
             }
-            _ => Err(BGPError::Protocol("Invalid BGP OPEN response".to_string())),
+            BGPMessageType::Notification => match response.notification {
+                Some(n)
+                    if (n.error_code, n.error_subcode)
+                        == (BGP_ERROR_CEASE, CEASE_TIER_VIOLATION) =>
+                {
+                    Err(BGPError::TierRefused {
+                        peer: peer_addr,
+                        ours: self.tier.clone(),
+                        theirs: NodeTier::from_asn(peer_asn),
+                    })
+                }
+                n => Err(BGPError::Notification {
+                    peer: peer_addr,
+                    code: n.as_ref().map_or(0, |n| n.error_code),
+                    subcode: n.as_ref().map_or(0, |n| n.error_subcode),
+                }),
+            },
+            other => Err(BGPError::UnexpectedMessage {
+                peer: peer_addr,
+                expected: "OPEN",
+                got: format!("{:?}", other),
+            }),
         }
     }
 
-    async fn handle_bgp_connection(
-        mut stream: TcpStream,
+    /// Resume the tunnel to the peer if it accepted our ticket, returning
+    /// the version of our table it still holds
+    async fn resume(
+        &self,
+        gate: &TunnelGate,
         peer_addr: SocketAddr,
-        local_asn: u32,
-        router_id: IpAddr,
-        tier: NodeTier,
-    ) -> Result<(), BGPError> {
-        // Receive BGP OPEN message
-        let protocol = BGPProtocol::new(local_asn, router_id, tier);
-        let open_msg = protocol.receive_message(&mut stream).await?;
-
-        match open_msg.message_type {
-            BGPMessageType::Open => {
-                tracing::info!(
-                    "Received BGP OPEN from ASN {} at {}",
-                    open_msg.asn,
-                    peer_addr
+        ours: &ResumeHello,
+        resuming: resumption::Resuming,
+        answer: &ResumeHello,
+    ) -> Option<u64> {
+        let Some(accepted) = &answer.accepted else {
+            tracing::info!(
+                "BGP peer {} did not resume our session; negotiating afresh",
+                peer_addr
+            );
+            return None;
+        };
+        let resumed = match resuming.finish(accepted, &ours.nonce, &answer.nonce) {
+            Ok(secret) => {
+                gate.resume(
+                    self.router_id,
+                    peer_addr,
+                    &secret,
+                    &ours.nonce,
+                    &answer.nonce,
+                )
+                .await
+            }
+            Err(refused) => Err(BGPError::Protocol {
+                code: BGP_ERROR_OPEN_MESSAGE,
+                subcode: 0,
+                reason: format!("resumption answer from {}: {}", peer_addr, refused),
+            }),
+        };
+        match resumed {
+            Ok(_) => {
+                tracing::info!("Resumed BGP session with {}", peer_addr);
+                Some(accepted.table_version)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Could not resume BGP session with {}; negotiating afresh: {}",
+                    peer_addr,
+                    crate::error::Report(&e)
                 );
+                None
+            }
+        }
+    }
 
-                // Send BGP OPEN response
-                let response = BGPMessage {
-                    message_type: BGPMessageType::Open,
-                    asn: local_asn,
-                    router_id,
-                    routes: vec![],
-                    timestamp: chrono::Utc::now(),
-                };
-
-                protocol.send_message(&mut stream, &response).await?;
+    /// Our side of resumption for a peer's OPEN: a nonce, and if it offered
+    /// a ticket we hold for it, our acceptance and its tunnel resumed
+    async fn answer_resume(&self, open: &BGPMessage, peer_addr: SocketAddr) -> Option<ResumeHello> {
+        let gate = self.tunnel_gate.as_deref()?;
+        let resumption = gate.resumption()?;
+        let theirs = open.resume.as_ref()?;
+        let mut hello = ResumeHello {
+            nonce: resumption::nonce().ok()?,
+            offer: None,
+            accepted: None,
+        };
+        let Some(offer) = &theirs.offer else {
+            return Some(hello);
+        };
 
-                // Start keepalive loop
-                protocol.keepalive_loop(stream, open_msg.asn).await?;
+        let from = PeerIdentity {
+            asn: open.asn,
+            router_id: open.router_id,
+        };
+        let (accepted, secret) = match resumption.accept(offer, from, &theirs.nonce, &hello.nonce) {
+            Ok(accepted) => accepted,
+            Err(refused @ (ResumeRefused::Unknown | ResumeRefused::Expired)) => {
+                tracing::info!("Not resuming BGP session with {}: {}", peer_addr, refused);
+                return Some(hello);
             }
-            _ => {
-                return Err(BGPError::Protocol("Expected BGP OPEN message".to_string()));
+            Err(refused) => {
+                tracing::warn!(
+                    target: "audit",
+                    "Refused to resume BGP session with AS{} at {}: {}",
+                    open.asn,
+                    peer_addr,
+                    refused
+                );
+                return Some(hello);
+            }
+        };
+        match gate
+            .resume(
+                self.router_id,
+                peer_addr,
+                &secret,
+                &theirs.nonce,
+                &hello.nonce,
+            )
+            .await
+        {
+            Ok(_) => {
+                tracing::info!("Resuming BGP session with AS{} at {}", open.asn, peer_addr);
+                hello.accepted = Some(accepted);
             }
+            Err(e) => tracing::warn!(
+                "Could not resume tunnel to {}: {}",
+                peer_addr,
+                crate::error::Report(&e)
+            ),
         }
+        Some(hello)
+    }
 
-        Ok(())
+    /// Read a connecting peer's OPEN and answer it with ours, refusing with
+    /// a Cease NOTIFICATION any peer whose tier ours may not peer with.
+    /// Returns the peer's OPEN, if both ends resume sessions what we said
+    /// about resuming in ours, and how the session's messages are encoded
+    /// from then on.
+    pub async fn accept_open(
+        &self,
+        stream: &mut TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Result<(BGPMessage, Option<ResumeHello>, Encoding), BGPError> {
+        let open_msg = self.receive_message(stream, Encoding::Json).await?;
+        if !matches!(open_msg.message_type, BGPMessageType::Open) {
+            return Err(BGPError::UnexpectedMessage {
+                peer: peer_addr,
+                expected: "OPEN",
+                got: format!("{:?}", open_msg.message_type),
+            });
+        }
+
+        let peer_tier = NodeTier::from_asn(open_msg.asn);
+        if !self.tier.can_peer_with(&peer_tier) {
+            let refusal = BGPMessage {
+                notification: Some(NotificationMessage {
+                    error_code: BGP_ERROR_CEASE,
+                    error_subcode: CEASE_TIER_VIOLATION,
+                    data: vec![],
+                }),
+                ..self.message(BGPMessageType::Notification)
+            };
+            // The refusal stands even if the peer never hears why. Nothing
+            // was agreed, so it goes as JSON.
+            if let Err(e) = self.send_message(stream, Encoding::Json, &refusal).await {
+                tracing::debug!("Could not send tier refusal to {}: {}", peer_addr, e);
+            }
+            return Err(BGPError::TierViolation {
+                peer: peer_addr,
+                asn: open_msg.asn,
+                ours: self.tier.clone(),
+                theirs: peer_tier,
+            });
+        }
+
+        tracing::info!(
+            "Received BGP OPEN from ASN {} at {} (offers: {})",
+            open_msg.asn,
+            peer_addr,
+            open_msg.capabilities.unwrap_or_default()
+        );
+        let hello = self.answer_resume(&open_msg, peer_addr).await;
+        let response = BGPMessage {
+            capabilities: Some(self.capabilities),
+            resume: hello.clone(),
+            observed_addr: Some(peer_addr.ip()),
+            sync_resume: self
+                .table_syncs
+                .as_ref()
+                .and_then(|syncs| syncs.resume_point(open_msg.asn)),
+            binary_messages: self.offers_binary(),
+            ..self.message(BGPMessageType::Open)
+        };
+        // Our OPEN is the first binary message if both ends offered them
+        let encoding = self.agreed_encoding(&open_msg);
+        self.send_message(stream, encoding, &response).await?;
+        Ok((open_msg, hello, encoding))
+    }
+
+    async fn handle_bgp_connection(
+        &self,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+    ) -> Result<(), BGPError> {
+        let (open_msg, _, encoding) = self.accept_open(&mut stream, peer_addr).await?;
+        if let Some(adaptive) = &self.adaptive {
+            adaptive.track(peer_addr.ip(), open_msg.asn);
+        }
+
+        // Start keepalive loop
+        let result = self
+            .keepalive_loop(stream, encoding, peer_addr.ip(), open_msg.asn)
+            .await;
+        if let (Some(adaptive), Err(_)) = (&self.adaptive, &result) {
+            adaptive.flap(peer_addr.ip());
+        }
+        result
     }
 
-    async fn keepalive_loop(&self, mut stream: TcpStream, peer_asn: u32) -> Result<(), BGPError> {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+    /// Every 30 seconds, or as often as the link has earned
+    fn keepalive_every(&self, peer: IpAddr) -> tokio::time::Duration {
+        self.adaptive
+            .as_ref()
+            .and_then(|adaptive| adaptive.interval(peer, Probe::Keepalive))
+            .unwrap_or(tokio::time::Duration::from_secs(30))
+    }
+
+    async fn keepalive_loop(
+        &self,
+        mut stream: TcpStream,
+        encoding: Encoding,
+        peer: IpAddr,
+        peer_asn: u32,
+    ) -> Result<(), BGPError> {
+        let keepalive_due =
+            tokio::time::sleep(self.keepalive_jitter.apply(self.keepalive_every(peer)));
+        tokio::pin!(keepalive_due);
 
         loop {
             tokio::select! {
-                _ = interval.tick() => {
+                _ = &mut keepalive_due => {
+                    keepalive_due.as_mut().reset(
+                        tokio::time::Instant::now()
+                            + self.keepalive_jitter.apply(self.keepalive_every(peer)),
+                    );
                     // Send keepalive
-                    let keepalive = BGPMessage {
-                        message_type: BGPMessageType::Keepalive,
-                        asn: self.local_asn,
-                        router_id: self.router_id,
-                        routes: vec![],
-                        timestamp: chrono::Utc::now(),
-                    };
-
-                    if let Err(e) = self.send_message(&mut stream, &keepalive).await {
+                    let keepalive = self.message(BGPMessageType::Keepalive);
+                    if let Err(e) = self.send_message(&mut stream, encoding, &keepalive).await {
                         tracing::error!("Failed to send keepalive to ASN {}: {}", peer_asn, e);
                         break;
                     }
+                    // The body behind the 19-byte header
+                    let bytes = Self::body(encoding, &keepalive)
+                        .map_or(0, |(_, body)| body.len() + wire::BGP_HEADER_LEN);
+                    adaptive::record_traffic(Probe::Keepalive, bytes, std::time::Instant::now());
                 }
 
-                result = self.receive_message(&mut stream) => {
+                result = self.receive_message(&mut stream, encoding) => {
                     match result {
                         Ok(msg) => {
                             self.handle_bgp_message(msg, peer_asn).await?;
@@ -223,7 +837,10 @@ impl BGPProtocol {
                 }
             }
             BGPMessageType::Keepalive => {
-                tracing::debug!("Received BGP KEEPALIVE from ASN {}", peer_asn);
+                crate::sampled!(tracing::debug!(
+                    "Received BGP KEEPALIVE from ASN {}",
+                    peer_asn
+                ));
             }
             BGPMessageType::Notification => {
                 tracing::warn!("Received BGP NOTIFICATION from ASN {}", peer_asn);
@@ -236,63 +853,479 @@ impl BGPProtocol {
         Ok(())
     }
 
-    async fn send_message(&self, stream: &mut TcpStream, msg: &BGPMessage) -> Result<(), BGPError> {
-        let serialized = serde_json::to_vec(msg)?;
-        let length = serialized.len() as u32;
+    /// Apply a peer's UPDATEs to `rib` until it closes the session or
+    /// sends a NOTIFICATION
+    pub async fn receive_updates(
+        &self,
+        stream: &mut BGPStream,
+        peer_asn: u32,
+        rib: &RwLock<Rib>,
+    ) -> Result<(), BGPError> {
+        let peer = stream.peer()?;
+        let (incoming, outgoing) = stream.split();
+        self.apply_updates(incoming, &Mutex::new(outgoing), peer, peer_asn, rib)
+            .await
+    }
 
-        // Send length header (4 bytes) + message
-        stream.write_u32(length).await?;
-        stream.write_all(&serialized).await?;
-        stream.flush().await?;
+    /// Run an established session both ways: the peer's UPDATEs are
+    /// applied to `rib` as by [`receive_updates`](Self::receive_updates),
+    /// while what export policy lets the peer have of our Loc-RIB is
+    /// advertised to it, and withdrawn as it changes, until the session ends
+    pub async fn exchange_routes(
+        &self,
+        stream: &mut BGPStream,
+        peer_asn: u32,
+        rib: &RwLock<Rib>,
+    ) -> Result<(), BGPError> {
+        let peer = stream.peer()?;
+        let (incoming, outgoing) = stream.split();
+        let outgoing = Mutex::new(outgoing);
+        tokio::select! {
+            result = self.apply_updates(incoming, &outgoing, peer, peer_asn, rib) => result,
+            result = self.advertise_changes(&outgoing, peer_asn, rib) => result,
+        }
+    }
 
-        Ok(())
+    async fn apply_updates(
+        &self,
+        mut incoming: Incoming<'_>,
+        outgoing: &Mutex<Outgoing<'_>>,
+        peer: IpAddr,
+        peer_asn: u32,
+        rib: &RwLock<Rib>,
+    ) -> Result<(), BGPError> {
+        loop {
+            let msg = match self.receive_on(&mut incoming).await {
+                Ok(msg) => msg,
+                Err(BGPError::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(capture) = &self.capture {
+                capture.record(CapturedEvent::Bgp {
+                    peer_asn,
+                    peer,
+                    message: msg.clone(),
+                });
+            }
+            match msg.message_type {
+                BGPMessageType::Update => {
+                    // Nothing is applied while the tunnel it requires is down
+                    if let Some(gate) = &self.tunnel_gate {
+                        gate.hold_update(peer).await;
+                    }
+                    // A chunk out of sequence ends the session before it is applied
+                    if let (Some(syncs), Some(chunk)) = (&self.table_syncs, &msg.chunk) {
+                        syncs.admit(peer_asn, chunk)?;
+                    }
+                    let announced = msg.announced();
+                    match &self.validator {
+                        // Judged before the RIB is locked; the validator may be slow
+                        Some(validator) => {
+                            let screened = validator.screen(peer_asn, announced).await;
+                            let mut rib = rib.write().await;
+                            rib.observe_peer_clock(peer_asn, msg.timestamp)?;
+                            rib.refuse(peer_asn, screened.refused)?;
+                            rib.receive(peer_asn, screened.accepted, &msg.withdrawn)?;
+                        }
+                        None => {
+                            let mut rib = rib.write().await;
+                            rib.observe_peer_clock(peer_asn, msg.timestamp)?;
+                            rib.receive(peer_asn, announced, &msg.withdrawn)?;
+                        }
+                    }
+                    // Its violations add up to a quarantine, which ends the session
+                    if rib.read().await.is_quarantined(peer_asn) {
+                        return Err(BGPError::Quarantined { asn: peer_asn });
+                    }
+                    if let Some(chunk) = &msg.chunk {
+                        if let Some(syncs) = &self.table_syncs {
+                            let progress = syncs.applied(peer_asn, chunk, &msg.routes)?;
+                            if progress.complete {
+                                tracing::info!(
+                                    "Table sync from ASN {} complete: {} chunks verified",
+                                    peer_asn,
+                                    progress.total
+                                );
+                            }
+                        }
+                        let mut ack = self.message(BGPMessageType::Keepalive);
+                        ack.chunk_ack = Some(chunk.seq);
+                        self.send_on(&mut *outgoing.lock().await, &ack).await?;
+                    }
+                    // A resumed session picks up from here
+                    if let (Some(resumption), Some(version)) =
+                        (self.resumption(), msg.table_version)
+                    {
+                        resumption.note_table_version(peer, peer_asn, version);
+                    }
+                }
+                BGPMessageType::Notification => return Ok(()),
+                _ => self.handle_bgp_message(msg, peer_asn).await?,
+            }
+        }
     }
 
-    async fn receive_message(&self, stream: &mut TcpStream) -> Result<BGPMessage, BGPError> {
-        // Read length header
-        let length = stream.read_u32().await?;
+    /// Advertise our Loc-RIB to the peer as export policy lets it have
+    /// it, then each change to it, until sending fails
+    async fn advertise_changes(
+        &self,
+        outgoing: &Mutex<Outgoing<'_>>,
+        peer_asn: u32,
+        rib: &RwLock<Rib>,
+    ) -> Result<(), BGPError> {
+        let (routes, mut changes) = {
+            let rib = rib.read().await;
+            let routes: Vec<RouteEntry> = rib
+                .loc_rib()
+                .get_all_routes()
+                .into_iter()
+                .cloned()
+                .collect();
+            (routes, rib.subscribe())
+        };
+        self.advertise_changed(outgoing, peer_asn, rib, routes, vec![], true)
+            .await?;
+        loop {
+            let (announced, withdrawn, resync) = match changes.recv().await {
+                Ok(RibChange::Advertise(route)) => (vec![route], vec![], false),
+                Ok(RibChange::Withdraw(network)) => (vec![], vec![network], false),
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!(
+                        "Missed {} RIB changes for ASN {}; resyncing",
+                        missed,
+                        peer_asn
+                    );
+                    let routes = rib
+                        .read()
+                        .await
+                        .loc_rib()
+                        .get_all_routes()
+                        .into_iter()
+                        .cloned()
+                        .collect();
+                    (routes, vec![], true)
+                }
+                // The RIB outlives its sessions
+                Err(RecvError::Closed) => std::future::pending().await,
+            };
+            self.advertise_changed(outgoing, peer_asn, rib, announced, withdrawn, resync)
+                .await?;
+        }
+    }
 
-        if length > 65536 {
-            // Reasonable message size limit
-            return Err(BGPError::Protocol("Message too large".to_string()));
+    /// Send one UPDATE with those of `candidates` the peer may have and
+    /// the withdrawal of whatever it holds of the rest and of `withdrawn`,
+    /// or with `resync`, of anything it holds that is not a candidate
+    async fn advertise_changed(
+        &self,
+        outgoing: &Mutex<Outgoing<'_>>,
+        peer_asn: u32,
+        rib: &RwLock<Rib>,
+        candidates: Vec<RouteEntry>,
+        mut withdrawn: Vec<Prefix>,
+        resync: bool,
+    ) -> Result<(), BGPError> {
+        let announced = {
+            let rib = rib.read().await;
+            let mut announced = Vec::new();
+            for route in candidates {
+                let network = route.network;
+                match rib.export_to(route, peer_asn) {
+                    Some(route) => announced.push(route),
+                    None => withdrawn.push(network),
+                }
+            }
+            let held: Vec<Prefix> = rib
+                .advertised(peer_asn)
+                .map(|adj| adj.routes().iter().map(|route| route.network).collect())
+                .unwrap_or_default();
+            if resync {
+                withdrawn.extend(
+                    held.iter()
+                        .filter(|network| !announced.iter().any(|r| r.network == **network)),
+                );
+            }
+            withdrawn.retain(|network| held.contains(network));
+            withdrawn.sort();
+            withdrawn.dedup();
+            announced
+        };
+        if announced.is_empty() && withdrawn.is_empty() {
+            return Ok(());
         }
 
-        // Read message
-        let mut buffer = vec![0u8; length as usize];
-        stream.read_exact(&mut buffer).await?;
+        let update = self.update(announced.clone(), withdrawn.clone(), None);
+        self.send_on(&mut *outgoing.lock().await, &update).await?;
+        tracing::debug!(
+            "Advertised {} routes and withdrew {} to ASN {}",
+            announced.len(),
+            withdrawn.len(),
+            peer_asn
+        );
+        let mut rib = rib.write().await;
+        rib.record_withdrawn(peer_asn, &withdrawn);
+        rib.record_advertised(peer_asn, &announced)
+    }
+
+    /// `msg` as the (type, body) to frame
+    fn body(encoding: Encoding, msg: &BGPMessage) -> Result<(u8, Vec<u8>), BGPError> {
+        match encoding {
+            Encoding::Json => Ok((msg.message_type.code(), serde_json::to_vec(msg)?)),
+            Encoding::Binary(_) => wire::encode_vx0(msg),
+        }
+    }
 
-        let msg = serde_json::from_slice(&buffer)?;
-        Ok(msg)
+    /// `msg` as a complete framed message
+    fn encode(&self, encoding: Encoding, msg: &BGPMessage) -> Result<Vec<u8>, BGPError> {
+        let (msg_type, body) = Self::body(encoding, msg)?;
+        wire::encode_frame(msg_type, &body, self.transport.max_frame_len)
+    }
+
+    /// The message in a framed body of `msg_type`, which must agree with it
+    fn decode(
+        &self,
+        encoding: Encoding,
+        msg_type: u8,
+        body: &[u8],
+    ) -> Result<BGPMessage, BGPError> {
+        match encoding {
+            // An OPEN answering our offer of binary messages is binary
+            // itself; JSON opens with a brace, binary with the version
+            _ if msg_type == BGP_MSG_OPEN && body.first() != Some(&b'{') => {
+                wire::decode_vx0_open(body)
+            }
+            Encoding::Binary(peer) => wire::decode_vx0(msg_type, body, peer),
+            Encoding::Json => {
+                let msg: BGPMessage = serde_json::from_slice(body)?;
+                if msg.message_type.code() != msg_type {
+                    return Err(BGPError::Protocol {
+                        code: BGP_ERROR_MESSAGE_HEADER,
+                        subcode: wire::HEADER_BAD_MESSAGE_TYPE,
+                        reason: format!(
+                            "{:?} message framed as type {}",
+                            msg.message_type, msg_type
+                        ),
+                    });
+                }
+                Ok(msg)
+            }
+        }
+    }
+
+    async fn send_message<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        encoding: Encoding,
+        msg: &BGPMessage,
+    ) -> Result<(), BGPError> {
+        let (msg_type, body) = Self::body(encoding, msg)?;
+        wire::write_frame(
+            stream,
+            msg_type,
+            &body,
+            self.transport.max_frame_len,
+            self.transport.io_timeout,
+        )
+        .await
+    }
+
+    async fn send(&self, stream: &mut BGPStream, msg: &BGPMessage) -> Result<(), BGPError> {
+        let (_, mut outgoing) = stream.split();
+        self.send_on(&mut outgoing, msg).await
+    }
+
+    async fn send_on(&self, outgoing: &mut Outgoing<'_>, msg: &BGPMessage) -> Result<(), BGPError> {
+        match outgoing {
+            Outgoing::Plain(writer, encoding) => self.send_message(writer, *encoding, msg).await,
+            Outgoing::Tunneled(sender, encoding) => {
+                let frame = self.encode(*encoding, msg)?;
+                sender.send(&frame).await.map_err(BGPError::Tunnel)
+            }
+        }
+    }
+
+    async fn receive(&self, stream: &mut BGPStream) -> Result<BGPMessage, BGPError> {
+        let (mut incoming, _) = stream.split();
+        self.receive_on(&mut incoming).await
+    }
+
+    async fn receive_on(&self, incoming: &mut Incoming<'_>) -> Result<BGPMessage, BGPError> {
+        match incoming {
+            Incoming::Plain(reader, encoding) => self.receive_message(reader, *encoding).await,
+            Incoming::Tunneled(channel, encoding) => {
+                let Some(frame) = channel.recv().await.map_err(BGPError::Tunnel)? else {
+                    return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                };
+                let (msg_type, body) = wire::decode_frame(&frame, self.transport.max_frame_len)?;
+                self.decode(*encoding, msg_type, &body)
+            }
+        }
+    }
+
+    async fn receive_message<R: AsyncRead + Unpin>(
+        &self,
+        stream: &mut R,
+        encoding: Encoding,
+    ) -> Result<BGPMessage, BGPError> {
+        let frame = wire::read_frame(
+            stream,
+            self.transport.max_frame_len,
+            self.transport.io_timeout,
+        )
+        .await?;
+        match frame {
+            Some((msg_type, body)) => self.decode(encoding, msg_type, &body),
+            None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        }
     }
 
     pub async fn advertise_routes(
         &self,
-        stream: &mut TcpStream,
+        stream: &mut BGPStream,
         routes: Vec<RouteEntry>,
     ) -> Result<(), BGPError> {
-        let bgp_routes: Vec<BGPRoute> = routes
-            .into_iter()
-            .map(|route| BGPRoute {
-                network: route.network,
-                next_hop: route.next_hop,
-                as_path: route.as_path,
-                origin: route.origin,
-                local_pref: route.local_pref,
-                med: route.med,
-            })
-            .collect();
+        let update_msg = self.update(routes, vec![], None);
+        self.send(stream, &update_msg).await?;
+        tracing::info!("Advertised {} routes via BGP", update_msg.routes.len());
 
-        let update_msg = BGPMessage {
-            message_type: BGPMessageType::Update,
-            asn: self.local_asn,
-            router_id: self.router_id,
-            routes: bgp_routes,
-            timestamp: chrono::Utc::now(),
-        };
+        Ok(())
+    }
 
-        self.send_message(stream, &update_msg).await?;
-        tracing::info!("Advertised {} routes via BGP", update_msg.routes.len());
+    /// Advertise a route table to a peer holding it as of `since`: only
+    /// what changed after that version, or all of it if `since` is 0 or
+    /// older than the table's history
+    pub async fn advertise_table(
+        &self,
+        stream: &mut BGPStream,
+        table: &RouteTable,
+        since: u64,
+    ) -> Result<(), BGPError> {
+        let changes = (since > 0).then(|| table.changes_since(since)).flatten();
+        let update_msg = match changes {
+            Some((announced, withdrawn)) if announced.is_empty() && withdrawn.is_empty() => {
+                tracing::debug!("Peer already holds version {} of our routes", since);
+                return Ok(());
+            }
+            Some((announced, withdrawn)) => self.update(announced, withdrawn, Some(table.version)),
+            None => self.update(
+                table.routes.values().cloned().collect(),
+                vec![],
+                Some(table.version),
+            ),
+        };
+        self.send(stream, &update_msg).await?;
+        tracing::info!(
+            "Advertised {} routes and withdrew {} via BGP, up to table version {}",
+            update_msg.routes.len(),
+            update_msg.withdrawn.len(),
+            table.version
+        );
 
         Ok(())
     }
+
+    /// Send `table` as export policy lets `peer_asn` have it, in chunks
+    /// the peer acknowledges, starting where `resume` says an earlier sync
+    /// of the same table version stopped. Returns how many chunks were sent.
+    pub async fn advertise_table_in_chunks(
+        &self,
+        stream: &mut BGPStream,
+        table: &RouteTable,
+        policy: &RoutingPolicy,
+        peer_asn: u32,
+        resume: Option<SyncResume>,
+    ) -> Result<u32, BGPError> {
+        let config = self
+            .table_syncs
+            .as_ref()
+            .map(|syncs| syncs.config())
+            .unwrap_or_default();
+        let exported: Vec<BGPRoute> = table
+            .routes
+            .values()
+            .filter(|route| policy.should_advertise_route(route, peer_asn).allowed)
+            .map(bgp_route)
+            .collect();
+        let pages = TablePages::new(exported, table.version, config.chunk_routes)?;
+        let start = pages.start(resume);
+        if start > 0 {
+            tracing::info!(
+                "Resuming table sync to ASN {} at chunk {} of {}",
+                peer_asn,
+                start,
+                pages.total()
+            );
+        }
+
+        let window = config.window.max(1) as u32;
+        let (mut next, mut acked) = (start, start);
+        while acked < pages.total() {
+            if next < pages.total() && next - acked < window {
+                let (routes, chunk) = pages.chunk(next);
+                let mut update = self.message(BGPMessageType::Update);
+                update.routes = routes;
+                // The peer holds this version only once the last chunk is in
+                update.table_version = chunk.digest.is_some().then_some(table.version);
+                update.chunk = Some(chunk);
+                self.send(stream, &update).await?;
+                next += 1;
+                continue;
+            }
+            let msg = self.receive(stream).await?;
+            match (msg.message_type, msg.chunk_ack) {
+                (BGPMessageType::Keepalive, Some(seq)) if seq >= acked && seq < next => {
+                    acked = seq + 1;
+                }
+                (BGPMessageType::Notification, _) => {
+                    return Err(BGPError::Notification {
+                        peer: SocketAddr::new(stream.peer()?, 0),
+                        code: msg.notification.as_ref().map_or(0, |n| n.error_code),
+                        subcode: msg.notification.as_ref().map_or(0, |n| n.error_subcode),
+                    });
+                }
+                _ => {}
+            }
+        }
+        tracing::info!(
+            "Sent {} of {} table chunks to ASN {}, up to table version {}",
+            pages.total() - start,
+            pages.total(),
+            peer_asn,
+            table.version
+        );
+        Ok(pages.total() - start)
+    }
+
+    /// A message of `message_type` with nothing in it yet
+    fn message(&self, message_type: BGPMessageType) -> BGPMessage {
+        BGPMessage::new(message_type, self.local_asn, self.router_id)
+    }
+
+    fn update(
+        &self,
+        routes: Vec<RouteEntry>,
+        withdrawn: Vec<Prefix>,
+        table_version: Option<u64>,
+    ) -> BGPMessage {
+        BGPMessage {
+            routes: routes.iter().map(bgp_route).collect(),
+            withdrawn,
+            table_version,
+            ..self.message(BGPMessageType::Update)
+        }
+    }
+}
+
+fn bgp_route(route: &RouteEntry) -> BGPRoute {
+    BGPRoute {
+        network: route.network,
+        next_hop: route.next_hop,
+        as_path: route.as_path.to_vec(),
+        origin: route.origin.clone(),
+        local_pref: route.local_pref,
+        med: route.med,
+        originated_at: route.originated_at,
+    }
 }
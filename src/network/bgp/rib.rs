@@ -0,0 +1,1907 @@
+//! The three-RIB model: per-peer Adj-RIB-In holding routes exactly as
+//! received, the policy-selected Loc-RIB, and per-peer Adj-RIB-Out recording
+//! what has been advertised.
+//!
+//! The Loc-RIB is always derivable from the Adj-RIBs-In plus locally
+//! originated routes, so a policy change is applied by re-running selection
+//! instead of asking peers to resend.
+
+use crate::config::{HoldDownConfig, RejectionJournalConfig, RoutingConfig};
+use crate::monitoring::register_metric;
+use crate::network::acl::{Acl, Contact};
+use crate::network::bgp::communities::CommunityMap;
+use crate::network::bgp::explain::{
+    Explanation, PeerExplanation, PeerOutcome, Rejection, RejectionJournal, RejectionKind,
+};
+use crate::network::bgp::export::ExportPolicies;
+use crate::network::bgp::hold_down::{HoldDown, HoldDownEvent};
+use crate::network::bgp::multihoming::{Failover, Multihoming, ParentHealth};
+use crate::network::bgp::next_hop::{NextHopTunnels, Usability};
+use crate::network::bgp::peering::{Admission, PeeringGuard, PeeringStats};
+use crate::network::bgp::pins::{PinStore, RoutePin};
+use crate::network::bgp::policy::{DryRunReport, PolicyDecision, PolicyVerdict, Verdict};
+use crate::network::bgp::propagation::{PropagationStats, PropagationTracker};
+use crate::network::bgp::routing::RoutingPolicy;
+use crate::network::bgp::{BGPError, PeerRef, Prefix, RouteEntry, RouteTable};
+use crate::network::ike::tunnels::TunnelStatusChanged;
+use crate::node::quarantine::{Quarantine, Standing, ViolationKind};
+use crate::node::NodeId;
+use crate::storage::Namespace;
+use crate::util::clock::{self, SharedClock};
+use chrono::{DateTime, Utc};
+use prometheus::{IntCounterVec, Opts};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio::sync::broadcast;
+
+/// Per-peer prefix limit applied when none is configured for the peer
+pub const DEFAULT_MAX_PREFIXES: usize = 10_000;
+
+/// Loc-RIB changes buffered per subscriber before it is considered lagged
+const CHANGE_FEED_CAPACITY: usize = 4096;
+
+/// Routes refused from one UPDATE that count as a policy violation
+pub const REFUSALS_PER_VIOLATION: usize = 10;
+
+/// How far apart the clock readings of a peer's consecutive UPDATEs may
+/// stray before it counts as a violation
+pub const TIMESTAMP_TOLERANCE: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Routes import policy rejected, by peer, over the peer's current session
+pub fn rejected_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new(
+                "vx0net_bgp_routes_rejected_total",
+                "Routes received from a peer that import policy rejected",
+            ),
+            &["peer_asn"],
+        ))
+    })
+}
+
+/// A change to the Loc-RIB, as seen by outbound advertisers
+#[derive(Debug, Clone)]
+pub enum RibChange {
+    Advertise(RouteEntry),
+    Withdraw(Prefix),
+}
+
+/// Locally originated routes at one moment, to return to later
+#[derive(Debug, Clone, Default)]
+pub struct LocalRoutes(HashMap<Prefix, RouteEntry>);
+
+/// Routes exchanged with one peer, bounded by its max-prefix limit
+#[derive(Debug, Clone)]
+pub struct AdjRib {
+    routes: HashMap<Prefix, RouteEntry>,
+    max_prefixes: usize,
+}
+
+impl AdjRib {
+    pub fn new(max_prefixes: usize) -> Self {
+        AdjRib {
+            routes: HashMap::new(),
+            max_prefixes,
+        }
+    }
+
+    pub fn insert(&mut self, route: RouteEntry) -> Result<(), BGPError> {
+        route.check_address_family()?;
+        if !self.routes.contains_key(&route.network) && self.routes.len() >= self.max_prefixes {
+            return Err(BGPError::MaxPrefixExceeded {
+                limit: self.max_prefixes,
+            });
+        }
+        self.routes.insert(route.network, route);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, network: &Prefix) -> Option<RouteEntry> {
+        self.routes.remove(network)
+    }
+
+    pub fn get(&self, network: &Prefix) -> Option<&RouteEntry> {
+        self.routes.get(network)
+    }
+
+    pub fn routes(&self) -> Vec<&RouteEntry> {
+        self.routes.values().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct Rib {
+    policy: RoutingPolicy,
+    max_prefixes: HashMap<u32, usize>,
+    local: HashMap<Prefix, RouteEntry>,
+    adj_rib_in: HashMap<u32, AdjRib>,
+    adj_rib_out: HashMap<u32, AdjRib>,
+    loc_rib: RouteTable,
+    /// Current session with each peer, stamped on the routes it sends
+    sessions: HashMap<u32, PeerRef>,
+    /// Loc-RIB prefixes whose best path came from each peer
+    installed_from: HashMap<u32, HashSet<Prefix>>,
+    /// Sessions with each peer that ended in failure
+    flaps: HashMap<u32, u32>,
+    /// Routes import policy rejected as each peer's current session sent them
+    rejected: HashMap<u32, usize>,
+    changes: broadcast::Sender<RibChange>,
+    peering: PeeringGuard,
+    acl: Arc<Acl>,
+    hold_down: HoldDown,
+    journal: RejectionJournal,
+    next_hops: NextHopTunnels,
+    pins: PinStore,
+    communities: CommunityMap,
+    multihoming: Multihoming,
+    exports: ExportPolicies,
+    /// Origination stamps and the delays measured from them
+    propagation: PropagationTracker,
+    /// Scores peers' violations; demoted peers' routes are a last resort
+    quarantine: Arc<Quarantine>,
+    /// Peers whose routes were last selected as demoted
+    demoted: HashSet<u32>,
+    /// Times rate limits and flaps recorded as routes arrive and leave
+    clock: SharedClock,
+}
+
+impl Rib {
+    pub fn new(policy: RoutingPolicy) -> Self {
+        let (changes, _) = broadcast::channel(CHANGE_FEED_CAPACITY);
+        Rib {
+            changes,
+            policy,
+            max_prefixes: HashMap::new(),
+            local: HashMap::new(),
+            adj_rib_in: HashMap::new(),
+            adj_rib_out: HashMap::new(),
+            loc_rib: RouteTable::new(),
+            sessions: HashMap::new(),
+            installed_from: HashMap::new(),
+            flaps: HashMap::new(),
+            rejected: HashMap::new(),
+            peering: PeeringGuard::default(),
+            acl: Arc::new(Acl::default()),
+            hold_down: HoldDown::default(),
+            journal: RejectionJournal::default(),
+            next_hops: NextHopTunnels::default(),
+            pins: PinStore::default(),
+            communities: CommunityMap::default(),
+            multihoming: Multihoming::default(),
+            exports: ExportPolicies::default(),
+            propagation: PropagationTracker::default(),
+            quarantine: Arc::new(Quarantine::default()),
+            demoted: HashSet::new(),
+            clock: clock::system(),
+        }
+    }
+
+    pub fn local_asn(&self) -> u32 {
+        self.policy.local_asn
+    }
+
+    pub fn loc_rib(&self) -> &RouteTable {
+        &self.loc_rib
+    }
+
+    pub fn policy(&self) -> &RoutingPolicy {
+        &self.policy
+    }
+
+    /// Follow Loc-RIB changes from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<RibChange> {
+        self.changes.subscribe()
+    }
+
+    /// Limit how many prefixes a peer's Adj-RIBs may hold
+    pub fn set_max_prefixes(&mut self, peer_asn: u32, max_prefixes: usize) {
+        self.max_prefixes.insert(peer_asn, max_prefixes);
+    }
+
+    /// Replace the limits applied to routes from Edge peers
+    ///
+    /// Takes effect for announcements received from now on; routes already
+    /// accepted are only renormalized.
+    pub fn set_peering(&mut self, peering: PeeringGuard) -> Result<(), BGPError> {
+        self.peering = peering;
+        let networks: HashSet<Prefix> = self
+            .adj_rib_in
+            .iter()
+            .filter(|(peer_asn, _)| PeeringGuard::applies(&self.policy.node_tier, **peer_asn))
+            .flat_map(|(_, adj_in)| adj_in.routes.keys().copied())
+            .collect();
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the community mapping, reselecting every learned prefix
+    /// under the new one
+    pub fn set_communities(&mut self, communities: CommunityMap) -> Result<(), BGPError> {
+        self.communities = communities;
+        let networks: HashSet<Prefix> = self
+            .adj_rib_in
+            .values()
+            .flat_map(|adj_in| adj_in.routes.keys().copied())
+            .collect();
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(())
+    }
+
+    /// Refuse routes from peers and origins the ACL blocks
+    pub fn set_acl(&mut self, acl: Arc<Acl>) {
+        self.acl = acl;
+    }
+
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.clock = clock;
+    }
+
+    /// Score peers' violations in `quarantine`, and follow its standings
+    pub fn set_quarantine(&mut self, quarantine: Arc<Quarantine>) {
+        self.quarantine = quarantine;
+    }
+
+    pub fn quarantine(&self) -> &Quarantine {
+        &self.quarantine
+    }
+
+    pub fn is_quarantined(&self, peer_asn: u32) -> bool {
+        self.quarantine
+            .is_quarantined(peer_asn, self.clock.now_utc())
+    }
+
+    /// Count a violation by `peer_asn` toward its quarantine, returning its
+    /// standing when that changed. A demoted peer's routes become a last
+    /// resort, and a quarantined peer's are dropped.
+    pub fn report_violation(
+        &mut self,
+        peer_asn: u32,
+        kind: ViolationKind,
+        detail: impl Into<String>,
+    ) -> Result<Option<Standing>, BGPError> {
+        let changed = match self
+            .quarantine
+            .record(peer_asn, kind, detail, self.clock.now_utc())
+        {
+            Ok(changed) => changed,
+            // The violation still counts until the daemon restarts
+            Err(e) => {
+                tracing::warn!("{}", crate::error::Report(&e));
+                None
+            }
+        };
+        match changed {
+            Some(Standing::Quarantined { .. }) => self.peer_left(peer_asn)?,
+            Some(_) => self.follow_standing(peer_asn)?,
+            None => {}
+        }
+        Ok(changed)
+    }
+
+    /// Reselect a peer's routes if it was demoted or restored since they
+    /// were last selected
+    pub fn follow_standing(&mut self, peer_asn: u32) -> Result<(), BGPError> {
+        let demoted = self.quarantine.is_demoted(peer_asn, self.clock.now_utc());
+        let changed = if demoted {
+            self.demoted.insert(peer_asn)
+        } else {
+            self.demoted.remove(&peer_asn)
+        };
+        if !changed {
+            return Ok(());
+        }
+        let networks: Vec<Prefix> = self
+            .adj_rib_in
+            .get(&peer_asn)
+            .map(|adj_in| adj_in.routes.keys().copied().collect())
+            .unwrap_or_default();
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_hold_down(&mut self, config: HoldDownConfig) {
+        self.hold_down = HoldDown::new(config);
+    }
+
+    /// Apply the tunnel requirements on next hops, reselecting every
+    /// learned prefix under them
+    pub fn set_tunnel_requirements(&mut self, config: &RoutingConfig) -> Result<(), BGPError> {
+        self.next_hops.configure(config);
+        let networks: HashSet<Prefix> = self
+            .adj_rib_in
+            .values()
+            .flat_map(|adj_in| adj_in.routes.keys().copied())
+            .collect();
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(())
+    }
+
+    /// Follow a tunnel's new status, reselecting the prefixes routed via
+    /// its remote end if their usability changed; returns how many were
+    pub fn tunnel_changed(
+        &mut self,
+        change: &TunnelStatusChanged,
+        now: Instant,
+    ) -> Result<usize, BGPError> {
+        if !self.next_hops.update(change, now) {
+            return Ok(0);
+        }
+        tracing::info!(
+            "Tunnel to {} is {:?}; reselecting routes through it",
+            change.remote_addr,
+            change.status
+        );
+        self.reselect_via(&change.remote_addr)
+    }
+
+    /// Return routes via damped tunnels that have stayed up long enough
+    pub fn poll_tunnels(&mut self, now: Instant) -> Result<usize, BGPError> {
+        let mut reselected = 0;
+        for next_hop in self.next_hops.poll(now) {
+            reselected += self.reselect_via(&next_hop)?;
+        }
+        Ok(reselected)
+    }
+
+    fn reselect_via(&mut self, next_hop: &IpAddr) -> Result<usize, BGPError> {
+        let networks: HashSet<Prefix> = self
+            .adj_rib_in
+            .values()
+            .flat_map(|adj_in| adj_in.routes.values())
+            .filter(|route| route.next_hop == *next_hop)
+            .map(|route| route.network)
+            .collect();
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(networks.len())
+    }
+
+    pub fn set_rejection_journal(&mut self, config: RejectionJournalConfig) {
+        self.journal = RejectionJournal::new(config);
+    }
+
+    /// Stamp routes originated from now on, so receivers can tell how
+    /// long they took to arrive
+    pub fn set_propagation_tracing(&mut self, enabled: bool) {
+        self.propagation.set_stamping(enabled);
+    }
+
+    /// Note when an UPDATE from `peer_asn` was sent by its clock, as it
+    /// arrives, to correct the stamps on its routes
+    pub fn observe_peer_clock(
+        &mut self,
+        peer_asn: u32,
+        sent: DateTime<Utc>,
+    ) -> Result<(), BGPError> {
+        if self.is_quarantined(peer_asn) {
+            return Ok(());
+        }
+        let deviation = self
+            .propagation
+            .observe_clock(peer_asn, sent, self.clock.now_utc());
+        if deviation.abs() > TIMESTAMP_TOLERANCE {
+            let detail = format!(
+                "UPDATE clock {}s off the one before",
+                deviation.num_seconds()
+            );
+            self.report_violation(peer_asn, ViolationKind::TimestampAnomaly, detail)?;
+        }
+        Ok(())
+    }
+
+    pub fn propagation_stats(&self) -> PropagationStats {
+        self.propagation.stats()
+    }
+
+    pub fn held_down(&self) -> bool {
+        self.hold_down.is_active()
+    }
+
+    /// Export our own routes as a last resort until the hold-down ends
+    pub fn start_hold_down(&mut self, now: Instant, wall: DateTime<Utc>) {
+        self.hold_down.start(now, wall);
+        if self.hold_down.is_active() {
+            self.readvertise_local();
+        }
+    }
+
+    /// End the hold-down if its window passed cleanly, re-advertising our
+    /// routes with their normal attributes
+    pub fn poll_hold_down(&mut self, now: Instant, wall: DateTime<Utc>) -> Option<HoldDownEvent> {
+        let event = self.hold_down.poll(now, wall);
+        if event == Some(HoldDownEvent::Expired) {
+            tracing::info!("Hold-down over; advertising routes normally");
+            self.readvertise_local();
+        }
+        event
+    }
+
+    pub fn multihoming(&self) -> &Multihoming {
+        &self.multihoming
+    }
+
+    /// Rank and export by the parents' roles from now on
+    pub fn set_multihoming(&mut self, multihoming: Multihoming) -> Result<(), BGPError> {
+        self.multihoming = multihoming;
+        self.parents_changed()
+    }
+
+    pub fn designate_parents(&mut self, primary: u32, secondary: u32) -> Result<(), BGPError> {
+        self.multihoming.designate(primary, secondary)?;
+        self.parents_changed()
+    }
+
+    /// Fail over between the parents as their health calls for
+    pub fn observe_parents(
+        &mut self,
+        primary: ParentHealth,
+        secondary: ParentHealth,
+        now: Instant,
+        wall: DateTime<Utc>,
+    ) -> Result<Option<Failover>, BGPError> {
+        let failover = self.multihoming.observe(primary, secondary, now, wall);
+        if failover.is_some() {
+            self.parents_changed()?;
+        }
+        Ok(failover)
+    }
+
+    /// A route as exported to `peer_asn`, which may be one of our parents,
+    /// rewritten by the first export template matching it
+    pub fn export_for(&self, route: RouteEntry, peer_asn: u32) -> RouteEntry {
+        let route = self.multihoming.export(route, peer_asn);
+        self.exports.export(route, peer_asn)
+    }
+
+    /// Our own routes that the tier policy lets `peer_asn` have, as it
+    /// should receive them
+    pub fn exports_for(&self, peer_asn: u32) -> Vec<RouteEntry> {
+        self.local_exports()
+            .into_iter()
+            .filter(|route| self.policy.should_advertise_route(route, peer_asn).allowed)
+            .map(|route| self.export_for(route, peer_asn))
+            .collect()
+    }
+
+    /// `route` as `peer_asn` should receive it, with our ASN in front if
+    /// we learned it, or `None` if it came from that peer or the tier
+    /// policy keeps it from the peer
+    pub fn export_to(&self, route: RouteEntry, peer_asn: u32) -> Option<RouteEntry> {
+        if route.learned_from.is_some_and(|from| from.asn == peer_asn) {
+            return None;
+        }
+        if !self.policy.should_advertise_route(&route, peer_asn).allowed {
+            return None;
+        }
+        let learned = route.learned_from.is_some();
+        let mut route = self.export_for(route, peer_asn);
+        // Our own routes already start with our ASN
+        if learned {
+            route.as_path = route.as_path.prepended(self.policy.local_asn, 1);
+        }
+        Some(route)
+    }
+
+    /// Recompute the Adj-RIB-Out of our own routes for `peer_asn`, dropping
+    /// any it should no longer have, and return what it now holds
+    pub fn refresh_exports(&mut self, peer_asn: u32) -> Result<Vec<RouteEntry>, BGPError> {
+        let exports = self.exports_for(peer_asn);
+        let stale: Vec<Prefix> = self
+            .adj_rib_out
+            .get(&peer_asn)
+            .map(|adj_out| {
+                adj_out
+                    .routes
+                    .keys()
+                    .filter(|network| self.local.contains_key(network))
+                    .filter(|network| !exports.iter().any(|route| route.network == **network))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.record_withdrawn(peer_asn, &stale);
+        self.record_advertised(peer_asn, &exports)?;
+        Ok(exports)
+    }
+
+    /// Rewrite exports by `exports` from now on, refreshing the peers whose
+    /// Adj-RIB-Out changes as a result; returns those peers
+    pub fn set_exports(&mut self, exports: ExportPolicies) -> Result<Vec<u32>, BGPError> {
+        self.exports = exports;
+        let mut affected = Vec::new();
+        let peers: Vec<u32> = self.adj_rib_out.keys().copied().collect();
+        for peer_asn in peers {
+            let changed = {
+                let adj_out = &self.adj_rib_out[&peer_asn];
+                let exports = self.exports_for(peer_asn);
+                exports.len()
+                    != adj_out
+                        .routes
+                        .keys()
+                        .filter(|network| self.local.contains_key(network))
+                        .count()
+                    || exports.iter().any(|route| {
+                        adj_out
+                            .get(&route.network)
+                            .is_none_or(|sent| !same_attributes(sent, route))
+                    })
+            };
+            if changed {
+                self.refresh_exports(peer_asn)?;
+                affected.push(peer_asn);
+            }
+        }
+        if !affected.is_empty() {
+            affected.sort_unstable();
+            self.readvertise_local();
+        }
+        Ok(affected)
+    }
+
+    /// Reselect what the parents sent and re-advertise our own routes, so
+    /// both follow the parents' new roles
+    fn parents_changed(&mut self) -> Result<(), BGPError> {
+        if let Some((primary, secondary)) = self.multihoming.parents() {
+            let networks: HashSet<Prefix> = [primary, secondary]
+                .iter()
+                .filter_map(|asn| self.adj_rib_in.get(asn))
+                .flat_map(|adj_in| adj_in.routes.keys().copied())
+                .collect();
+            for network in &networks {
+                self.reselect(network)?;
+            }
+        }
+        self.readvertise_local();
+        Ok(())
+    }
+
+    /// Restore pins kept in `storage`, reselecting their prefixes
+    pub fn open_pins(
+        &mut self,
+        storage: Option<Namespace>,
+        legacy_file: Option<&Path>,
+    ) -> Result<(), BGPError> {
+        self.pins = PinStore::open(storage)?;
+        if let Some(path) = legacy_file {
+            let adopted = self.pins.adopt_legacy(path)?;
+            if adopted > 0 {
+                tracing::info!(
+                    "Moved {} route pins from {} into the store",
+                    adopted,
+                    path.display()
+                );
+            }
+        }
+        for pin in self.pins.list() {
+            self.reselect(&pin.network)?;
+        }
+        Ok(())
+    }
+
+    /// Prefer the path from `pin.via_asn` for its prefix, refusing
+    /// prefixes no RIB holds unless `allow_missing`
+    pub fn pin(&mut self, pin: RoutePin, allow_missing: bool) -> Result<(), BGPError> {
+        let network = pin.network;
+        let known = self.local.contains_key(&network)
+            || self.loc_rib.routes.contains_key(&network)
+            || self
+                .adj_rib_in
+                .values()
+                .any(|adj_in| adj_in.get(&network).is_some());
+        if !known && !allow_missing {
+            return Err(BGPError::UnknownPrefix { network });
+        }
+        self.pins.insert(pin)?;
+        self.reselect(&network)
+    }
+
+    pub fn unpin(&mut self, network: &Prefix) -> Result<Option<RoutePin>, BGPError> {
+        let removed = self.pins.remove(network)?;
+        if removed.is_some() {
+            self.reselect(network)?;
+        }
+        Ok(removed)
+    }
+
+    /// Remove pins that expired by `now`, returning prefixes to normal
+    /// selection
+    pub fn expire_pins(&mut self, now: DateTime<Utc>) -> Result<Vec<RoutePin>, BGPError> {
+        let expired = self.pins.take_expired(now)?;
+        for pin in &expired {
+            tracing::info!(
+                target: "audit",
+                "Pin of {} via AS{} expired",
+                pin.network,
+                pin.via_asn
+            );
+            self.reselect(&pin.network)?;
+        }
+        Ok(expired)
+    }
+
+    pub fn pins(&self) -> Vec<RoutePin> {
+        self.pins.list()
+    }
+
+    /// Our own routes as peers should receive them
+    pub fn local_exports(&self) -> Vec<RouteEntry> {
+        self.local
+            .values()
+            .map(|route| self.hold_down.apply(route.clone(), self.policy.local_asn))
+            .collect()
+    }
+
+    fn readvertise_local(&self) {
+        for route in self.local_exports() {
+            let _ = self.changes.send(RibChange::Advertise(route));
+        }
+    }
+
+    /// Peering agreement violations recorded for an Edge peer
+    pub fn peering_stats(&self, peer_asn: u32) -> Option<PeeringStats> {
+        self.peering.stats(peer_asn)
+    }
+
+    fn max_prefixes_for(&self, peer_asn: u32) -> usize {
+        self.max_prefixes
+            .get(&peer_asn)
+            .copied()
+            .unwrap_or(DEFAULT_MAX_PREFIXES)
+    }
+
+    /// Install a locally originated route, stamped when tracing propagation
+    pub fn originate(&mut self, mut route: RouteEntry) -> Result<(), BGPError> {
+        route.check_address_family()?;
+        self.propagation.stamp(&mut route);
+        let network = route.network;
+        self.local.insert(network, route);
+        self.reselect(&network)
+    }
+
+    pub fn withdraw_local(&mut self, network: &Prefix) -> Result<(), BGPError> {
+        self.local.remove(network);
+        self.reselect(network)
+    }
+
+    pub fn local_snapshot(&self) -> LocalRoutes {
+        LocalRoutes(self.local.clone())
+    }
+
+    /// Originate exactly the snapshot's routes again, reselecting only the
+    /// prefixes that changed since it was taken
+    pub fn restore_local(&mut self, snapshot: LocalRoutes) -> Result<(), BGPError> {
+        let changed: Vec<Prefix> = self
+            .local
+            .keys()
+            .chain(snapshot.0.keys())
+            .filter(|network| {
+                self.local.get(network).map(|route| route.timestamp)
+                    != snapshot.0.get(network).map(|route| route.timestamp)
+            })
+            .copied()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        self.local = snapshot.0;
+        for network in &changed {
+            self.reselect(network)?;
+        }
+        Ok(())
+    }
+
+    /// Start attributing routes from a peer to a new session
+    pub fn peer_up(&mut self, peer_asn: u32, node_id: Option<NodeId>) -> PeerRef {
+        let peer = PeerRef::new(peer_asn, node_id);
+        self.sessions.insert(peer_asn, peer);
+        peer
+    }
+
+    /// The session routes from a peer are currently attributed to
+    pub fn session(&self, peer_asn: u32) -> Option<PeerRef> {
+        self.sessions.get(&peer_asn).copied()
+    }
+
+    /// How many routes the peer has sent that we hold
+    pub fn routes_from(&self, peer_asn: u32) -> usize {
+        self.adj_rib_in.get(&peer_asn).map_or(0, AdjRib::len)
+    }
+
+    /// How many routes import policy rejected as the peer's current session
+    /// sent them
+    pub fn rejected_from(&self, peer_asn: u32) -> usize {
+        self.rejected.get(&peer_asn).copied().unwrap_or(0)
+    }
+
+    /// How many of the peer's sessions have ended in failure
+    pub fn flaps(&self, peer_asn: u32) -> u32 {
+        self.flaps.get(&peer_asn).copied().unwrap_or(0)
+    }
+
+    /// How many of the peer's routes are the best path in the Loc-RIB
+    pub fn installed_from(&self, peer_asn: u32) -> usize {
+        self.installed_from.get(&peer_asn).map_or(0, HashSet::len)
+    }
+
+    /// Apply an UPDATE from a peer: store it as received, then reselect
+    ///
+    /// Announcements from Edge peers that break the peering agreement, and
+    /// routes whose origin the ACL blocks, are dropped, withdrawing any
+    /// earlier version of the prefix. Updates from a blocked peer are
+    /// ignored entirely, as are those from a quarantined peer. Fails without
+    /// touching the Adj-RIB-In further once the peer exceeds its max-prefix
+    /// limit; the caller is expected to tear the session down.
+    ///
+    /// Many refusals in one UPDATE, sending through a peering cooldown and
+    /// exceeding max-prefix count toward the peer's quarantine.
+    pub fn receive(
+        &mut self,
+        peer_asn: u32,
+        announced: Vec<RouteEntry>,
+        withdrawn: &[Prefix],
+    ) -> Result<(), BGPError> {
+        if !self.acl.check(&Contact::asn(peer_asn), "UPDATE") {
+            return Ok(());
+        }
+        if self.is_quarantined(peer_asn) {
+            return Ok(());
+        }
+
+        let learned_from = *self
+            .sessions
+            .entry(peer_asn)
+            .or_insert_with(|| PeerRef::new(peer_asn, None));
+        let max_prefixes = self.max_prefixes_for(peer_asn);
+        let adj_in = self
+            .adj_rib_in
+            .entry(peer_asn)
+            .or_insert_with(|| AdjRib::new(max_prefixes));
+
+        let mut touched: HashSet<Prefix> = HashSet::new();
+        for network in withdrawn {
+            if adj_in.remove(network).is_some() {
+                touched.insert(*network);
+            }
+            self.propagation.withdrawn(peer_asn, network);
+        }
+
+        let enforce = PeeringGuard::applies(&self.policy.node_tier, peer_asn);
+        let now = self.clock.now_monotonic();
+        let mut result = Ok(());
+        let mut refused = 0;
+        let mut cooling_down = false;
+        let mut stored = Vec::new();
+        for mut route in announced {
+            route.learned_from = Some(learned_from);
+            let network = route.network;
+            let origin = route.as_path.last().copied().unwrap_or(peer_asn);
+            let refusal = if !self.acl.check_origin(&Contact::asn(origin), "route origin") {
+                Some((
+                    RejectionKind::Policy,
+                    PolicyVerdict::deny("acl", format!("origin AS{} is blocked", origin)),
+                ))
+            } else if enforce {
+                match self.peering.admit(peer_asn, &route, adj_in, now) {
+                    Admission::Accept => None,
+                    Admission::Reject(violation) => Some((
+                        RejectionKind::Policy,
+                        PolicyVerdict::deny("peering-agreement", violation.to_string()),
+                    )),
+                    Admission::CoolingDown => Some((
+                        RejectionKind::Damping,
+                        PolicyVerdict::deny(
+                            "peering-cooldown",
+                            format!(
+                                "AS{} is in cooldown after repeated peering violations",
+                                peer_asn
+                            ),
+                        ),
+                    )),
+                }
+            } else {
+                None
+            };
+            if let Some((kind, verdict)) = refusal {
+                refused += 1;
+                cooling_down |= kind == RejectionKind::Damping;
+                self.journal.record(network, peer_asn, kind, verdict, now);
+                if adj_in.remove(&network).is_some() {
+                    touched.insert(network);
+                }
+                continue;
+            }
+            self.propagation
+                .record(peer_asn, &mut route, self.clock.now_utc());
+            if let Err(e) = adj_in.insert(route) {
+                result = Err(e);
+                break;
+            }
+            touched.insert(network);
+            stored.push(network);
+        }
+
+        let rejected = stored
+            .iter()
+            .filter_map(|network| self.adj_rib_in.get(&peer_asn)?.get(network))
+            .filter(|route| !self.import(route, peer_asn).accepted())
+            .count();
+        if rejected > 0 {
+            *self.rejected.entry(peer_asn).or_default() += rejected;
+            rejected_counter()
+                .with_label_values(&[&peer_asn.to_string()])
+                .inc_by(rejected as u64);
+        }
+
+        for network in &touched {
+            self.reselect(network)?;
+        }
+        self.follow_standing(peer_asn)?;
+        if let Err(BGPError::MaxPrefixExceeded { limit }) = &result {
+            let detail = format!("more than {} prefixes", limit);
+            self.report_violation(peer_asn, ViolationKind::Flood, detail)?;
+        }
+        if cooling_down {
+            let detail = "announced during a peering cooldown";
+            self.report_violation(peer_asn, ViolationKind::RateLimited, detail)?;
+        }
+        if refused >= REFUSALS_PER_VIOLATION {
+            let detail = format!("{} routes refused in one UPDATE", refused);
+            self.report_violation(peer_asn, ViolationKind::PolicyRejections, detail)?;
+        }
+        result
+    }
+
+    /// Drop routes from a peer that were refused before reaching the RIB,
+    /// as by the external validator, withdrawing any earlier version and
+    /// journaling why
+    pub fn refuse(
+        &mut self,
+        peer_asn: u32,
+        refused: Vec<(Prefix, PolicyVerdict)>,
+    ) -> Result<(), BGPError> {
+        let now = self.clock.now_monotonic();
+        for (network, verdict) in refused {
+            self.journal
+                .record(network, peer_asn, RejectionKind::Policy, verdict, now);
+            let withdrawn = self
+                .adj_rib_in
+                .get_mut(&peer_asn)
+                .and_then(|adj_in| adj_in.remove(&network));
+            if withdrawn.is_some() {
+                self.reselect(&network)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record routes sent to a peer
+    pub fn record_advertised(
+        &mut self,
+        peer_asn: u32,
+        routes: &[RouteEntry],
+    ) -> Result<(), BGPError> {
+        let max_prefixes = self.max_prefixes_for(peer_asn);
+        let adj_out = self
+            .adj_rib_out
+            .entry(peer_asn)
+            .or_insert_with(|| AdjRib::new(max_prefixes));
+        for route in routes {
+            adj_out.insert(route.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn record_withdrawn(&mut self, peer_asn: u32, withdrawn: &[Prefix]) {
+        if let Some(adj_out) = self.adj_rib_out.get_mut(&peer_asn) {
+            for network in withdrawn {
+                adj_out.remove(network);
+            }
+        }
+    }
+
+    /// Routes as the peer sent them, before policy
+    pub fn received(&self, peer_asn: u32) -> Option<&AdjRib> {
+        self.adj_rib_in.get(&peer_asn)
+    }
+
+    /// Whether any peer currently offers `network`, chosen or not
+    pub fn offered(&self, network: &Prefix) -> bool {
+        self.adj_rib_in
+            .values()
+            .any(|routes| routes.get(network).is_some())
+    }
+
+    /// Routes we have told the peer about
+    pub fn advertised(&self, peer_asn: u32) -> Option<&AdjRib> {
+        self.adj_rib_out.get(&peer_asn)
+    }
+
+    /// Drop everything learned from and sent to a peer whose session failed,
+    /// counting it as a flap
+    pub fn peer_down(&mut self, peer_asn: u32) -> Result<(), BGPError> {
+        self.hold_down
+            .record_flap(self.clock.now_monotonic(), self.clock.now_utc());
+        *self.flaps.entry(peer_asn).or_default() += 1;
+        self.peer_left(peer_asn)
+    }
+
+    /// Drop everything learned from and sent to a peer that left on purpose
+    ///
+    /// Only the prefixes the peer was the best path for need reselecting;
+    /// its other routes never reached the Loc-RIB.
+    pub fn peer_left(&mut self, peer_asn: u32) -> Result<(), BGPError> {
+        self.sessions.remove(&peer_asn);
+        if self.rejected.remove(&peer_asn).is_some() {
+            let _ = rejected_counter().remove_label_values(&[&peer_asn.to_string()]);
+        }
+        self.propagation.peer_gone(peer_asn);
+        self.demoted.remove(&peer_asn);
+        self.adj_rib_out.remove(&peer_asn);
+        self.adj_rib_in.remove(&peer_asn);
+        if let Some(networks) = self.installed_from.remove(&peer_asn) {
+            for network in &networks {
+                self.reselect(network)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep what a peer sent after it moved, pointing routes that used its
+    /// old address at the new one and attributing them to its current
+    /// session; returns how many next hops were refreshed
+    pub fn rehome_peer(
+        &mut self,
+        peer_asn: u32,
+        old: IpAddr,
+        new: IpAddr,
+    ) -> Result<usize, BGPError> {
+        let session = self.session(peer_asn);
+        let Some(adj_in) = self.adj_rib_in.get_mut(&peer_asn) else {
+            return Ok(0);
+        };
+        let mut moved = 0;
+        for route in adj_in.routes.values_mut() {
+            if route.next_hop == old {
+                route.next_hop = new;
+                moved += 1;
+            }
+            if session.is_some() {
+                route.learned_from = session;
+            }
+        }
+        let networks: Vec<Prefix> = adj_in.routes.keys().copied().collect();
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(moved)
+    }
+
+    /// Swap in a new policy and re-derive the Loc-RIB from what peers sent
+    pub fn set_policy(&mut self, policy: RoutingPolicy) -> Result<(), BGPError> {
+        self.policy = policy;
+
+        let mut networks: HashSet<Prefix> = self.local.keys().copied().collect();
+        for adj_in in self.adj_rib_in.values() {
+            networks.extend(adj_in.routes.keys().copied());
+        }
+        networks.extend(self.loc_rib.routes.keys().copied());
+
+        for network in &networks {
+            self.reselect(network)?;
+        }
+        Ok(())
+    }
+
+    /// Classify routes under a candidate policy without changing anything
+    ///
+    /// Uses the peer's Adj-RIB-In when given, otherwise the learned routes
+    /// in the Loc-RIB attributed to the neighbor they came from.
+    pub fn dry_run(
+        &self,
+        candidate: &RoutingPolicy,
+        peer_asn: Option<u32>,
+    ) -> Result<DryRunReport, BGPError> {
+        match peer_asn {
+            Some(peer_asn) => {
+                let adj_in = self
+                    .received(peer_asn)
+                    .ok_or(BGPError::UnknownPeer { peer_asn })?;
+                Ok(candidate.dry_run(adj_in.routes().into_iter().map(|route| (peer_asn, route))))
+            }
+            None => {
+                let local_asn = self.policy.local_asn;
+                Ok(
+                    candidate.dry_run(self.loc_rib.routes.values().filter_map(|route| {
+                        route
+                            .as_path
+                            .first()
+                            .copied()
+                            .filter(|neighbor| *neighbor != local_asn)
+                            .map(|neighbor| (neighbor, route))
+                    })),
+                )
+            }
+        }
+    }
+
+    /// Why `network` is or is not in the Loc-RIB
+    pub fn explain(&self, network: &Prefix, now: Instant) -> Explanation {
+        let installed = self.loc_rib.routes.get(network).cloned();
+        let installed_from = installed
+            .as_ref()
+            .and_then(|route| route.learned_from)
+            .map(|peer| peer.asn);
+        let mut journal: HashMap<u32, Rejection> =
+            self.journal.for_prefix(network, now).into_iter().collect();
+
+        let mut peers: Vec<PeerExplanation> = self
+            .adj_rib_in
+            .iter()
+            .filter_map(|(peer_asn, adj_in)| adj_in.get(network).map(|route| (*peer_asn, route)))
+            .map(|(peer_asn, route)| {
+                let recorded = journal.remove(&peer_asn);
+                let mut changes = Vec::new();
+                let outcome = if installed_from == Some(peer_asn) {
+                    changes = self.ingest(route, peer_asn).changes;
+                    PeerOutcome::Installed
+                } else if route.unresolved_next_hop() {
+                    PeerOutcome::UnresolvedNextHop {
+                        next_hop: route.next_hop,
+                    }
+                } else if self.next_hops.usability(&route.next_hop) == Usability::Unusable {
+                    PeerOutcome::NoTunnel {
+                        next_hop: route.next_hop,
+                    }
+                } else {
+                    let decision = self.ingest(route, peer_asn);
+                    if decision.accepted() {
+                        changes = decision.changes.clone();
+                    }
+                    match recorded {
+                        Some(rejection) if !decision.accepted() => rejection.into(),
+                        // Evicted from the journal, or it is disabled
+                        None if !decision.accepted() => PeerOutcome::Rejected {
+                            verdict: decision.to_verdict(),
+                            age_secs: None,
+                        },
+                        _ => PeerOutcome::NotBest,
+                    }
+                };
+                PeerExplanation {
+                    peer_asn,
+                    route: Some(route.clone()),
+                    changes,
+                    outcome,
+                }
+            })
+            .collect();
+
+        // Whatever is left was refused before reaching the Adj-RIB-In
+        peers.extend(
+            journal
+                .into_iter()
+                .map(|(peer_asn, rejection)| PeerExplanation {
+                    peer_asn,
+                    route: None,
+                    changes: Vec::new(),
+                    outcome: rejection.into(),
+                }),
+        );
+        peers.sort_by_key(|peer| peer.peer_asn);
+
+        Explanation {
+            network: *network,
+            installed,
+            peers,
+            propagation: self.propagation.sample(network).cloned().map(Box::new),
+        }
+    }
+
+    /// Import policy plus loop detection, as applied during selection
+    fn import(&self, route: &RouteEntry, peer_asn: u32) -> PolicyDecision {
+        let local_asn = self.policy.local_asn;
+        if route.as_path.contains(&local_asn) {
+            return PolicyDecision {
+                verdict: Verdict::Reject,
+                rule: "as-path-loop".to_string(),
+                details: format!("AS path already contains our AS{}", local_asn),
+                route: route.clone(),
+                changes: Vec::new(),
+            };
+        }
+        self.policy.evaluate_import(route, peer_asn)
+    }
+
+    /// A route as selection sees it: through import policy, then, once
+    /// accepted, the Edge peering limits and the community mapping
+    fn ingest(&self, route: &RouteEntry, peer_asn: u32) -> PolicyDecision {
+        let mut decision = self.import(route, peer_asn);
+        if !decision.accepted() {
+            return decision;
+        }
+        if PeeringGuard::applies(&self.policy.node_tier, peer_asn) {
+            decision.route = self.peering.normalize(decision.route);
+        }
+        let changes = self.communities.apply(&mut decision.route, peer_asn);
+        decision.changes.extend(changes);
+        decision
+            .changes
+            .extend(self.multihoming.import(&mut decision.route, peer_asn));
+        decision
+    }
+
+    fn reselect(&mut self, network: &Prefix) -> Result<(), BGPError> {
+        let local = self.local.contains_key(network);
+        let best = match self.local.get(network) {
+            // Locally originated routes always win
+            Some(route) => Some(route.clone()),
+            None => {
+                let now = self.clock.now_monotonic();
+                let mut candidates = Vec::new();
+                let mut last_resort = Vec::new();
+                for (peer_asn, adj_in) in &self.adj_rib_in {
+                    let Some(route) = adj_in.get(network) else {
+                        continue;
+                    };
+                    // Not a policy decision; explain() reports these itself
+                    let usability = self.next_hops.usability(&route.next_hop);
+                    if route.unresolved_next_hop() || usability == Usability::Unusable {
+                        continue;
+                    }
+                    let decision = self.ingest(route, *peer_asn);
+                    if !decision.accepted() {
+                        let verdict = decision.to_verdict();
+                        self.journal.record(
+                            *network,
+                            *peer_asn,
+                            RejectionKind::Policy,
+                            verdict,
+                            now,
+                        );
+                        continue;
+                    }
+                    self.journal.clear(network, *peer_asn);
+                    let route = decision.route;
+                    if usability == Usability::LastResort || self.demoted.contains(peer_asn) {
+                        last_resort.push(route);
+                    } else {
+                        candidates.push(route);
+                    }
+                }
+                let pinned = self.pins.get(network).map(|pin| pin.via_asn);
+                let pinned_route = pinned.and_then(|via_asn| {
+                    candidates
+                        .iter()
+                        .chain(&last_resort)
+                        .find(|route| route.learned_from.map(|peer| peer.asn) == Some(via_asn))
+                        .cloned()
+                });
+                if let Some(via_asn) = pinned {
+                    if self.pins.note_met(network, pinned_route.is_some()) {
+                        tracing::warn!(
+                            target: "audit",
+                            "{} is pinned via AS{}, which has no usable path; selecting normally",
+                            network,
+                            via_asn
+                        );
+                    }
+                }
+                pinned_route
+                    .or_else(|| self.policy.select_best_route(&candidates))
+                    .or_else(|| self.policy.select_best_route(&last_resort))
+            }
+        };
+
+        let previous = self
+            .loc_rib
+            .routes
+            .get(network)
+            .and_then(|route| route.learned_from);
+        if let Some(peer) = previous {
+            if let Some(networks) = self.installed_from.get_mut(&peer.asn) {
+                networks.remove(network);
+                if networks.is_empty() {
+                    self.installed_from.remove(&peer.asn);
+                }
+            }
+        }
+        if let Some(peer) = best.as_ref().and_then(|route| route.learned_from) {
+            self.installed_from
+                .entry(peer.asn)
+                .or_default()
+                .insert(*network);
+        }
+
+        // Sending only fails when nobody is subscribed
+        match best {
+            Some(route) => {
+                self.loc_rib.add_route(route.clone())?;
+                let exported = if local {
+                    self.hold_down.apply(route, self.policy.local_asn)
+                } else {
+                    route
+                };
+                let _ = self.changes.send(RibChange::Advertise(exported));
+                Ok(())
+            }
+            None => {
+                if self.loc_rib.remove_route(network).is_some() {
+                    let _ = self.changes.send(RibChange::Withdraw(*network));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<Rejection> for PeerOutcome {
+    fn from(rejection: Rejection) -> Self {
+        let age_secs = Some(rejection.age.as_secs());
+        match rejection.kind {
+            RejectionKind::Policy => PeerOutcome::Rejected {
+                verdict: rejection.verdict,
+                age_secs,
+            },
+            RejectionKind::Damping => PeerOutcome::Damped {
+                verdict: rejection.verdict,
+                age_secs,
+            },
+        }
+    }
+}
+
+/// Whether a peer holding `sent` already has `route` as advertised
+fn same_attributes(sent: &RouteEntry, route: &RouteEntry) -> bool {
+    sent.next_hop == route.next_hop
+        && sent.as_path == route.as_path
+        && sent.med == route.med
+        && sent.communities == route.communities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::testing;
+    use crate::node::NodeTier;
+    use uuid::Uuid;
+
+    fn route(network: &str, as_path: Vec<u32>) -> RouteEntry {
+        testing::route(network, "172.16.0.1", &as_path)
+    }
+
+    #[test]
+    fn test_views_diverge_under_filter() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        let peer = 65001;
+
+        rib.receive(
+            peer,
+            vec![
+                route("10.10.0.0/16", vec![65001]),
+                route("10.20.0.0/16", vec![65001]),
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(rib.loc_rib().routes.len(), 2);
+
+        // Soft reconfiguration: the rejected prefix leaves the Loc-RIB but
+        // stays in the Adj-RIB-In exactly as received
+        let mut policy = RoutingPolicy::new(65000, NodeTier::Backbone);
+        policy.deny_prefix("10.20.0.0/16".parse().unwrap());
+        rib.set_policy(policy).unwrap();
+
+        assert_eq!(rib.received(peer).unwrap().len(), 2);
+        assert_eq!(rib.loc_rib().routes.len(), 1);
+        assert!(rib
+            .loc_rib()
+            .get_route(&"10.20.0.0/16".parse().unwrap())
+            .is_none());
+
+        let to_advertise: Vec<RouteEntry> = rib.loc_rib().routes.values().cloned().collect();
+        rib.record_advertised(65002, &to_advertise).unwrap();
+        assert_eq!(rib.advertised(65002).unwrap().len(), 1);
+        assert!(rib.advertised(peer).is_none());
+
+        // Lifting the filter restores it without the peer resending
+        rib.set_policy(RoutingPolicy::new(65000, NodeTier::Backbone))
+            .unwrap();
+        assert_eq!(rib.loc_rib().routes.len(), 2);
+    }
+
+    #[test]
+    fn test_teardown_frees_peer_ribs() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        rib.originate(route("10.0.0.0/8", vec![65000])).unwrap();
+        rib.receive(65001, vec![route("10.10.0.0/16", vec![65001])], &[])
+            .unwrap();
+        rib.record_advertised(65001, &[route("10.0.0.0/8", vec![65000])])
+            .unwrap();
+
+        rib.peer_down(65001).unwrap();
+
+        assert!(rib.received(65001).is_none());
+        assert!(rib.advertised(65001).is_none());
+        assert_eq!(rib.loc_rib().routes.len(), 1);
+    }
+
+    #[test]
+    fn test_restore_local_undoes_originations() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        rib.originate(route("10.0.0.0/8", vec![65000])).unwrap();
+        rib.receive(65001, vec![route("10.20.0.0/16", vec![65001])], &[])
+            .unwrap();
+        let snapshot = rib.local_snapshot();
+
+        // Shadow a peer's route, add one and drop the original
+        rib.originate(route("10.20.0.0/16", vec![65000])).unwrap();
+        rib.originate(route("10.30.0.0/16", vec![65000])).unwrap();
+        rib.withdraw_local(&"10.0.0.0/8".parse().unwrap()).unwrap();
+
+        rib.restore_local(snapshot).unwrap();
+        let mut networks: Vec<String> = rib
+            .loc_rib()
+            .routes
+            .keys()
+            .map(ToString::to_string)
+            .collect();
+        networks.sort();
+        assert_eq!(networks, ["10.0.0.0/8", "10.20.0.0/16"]);
+        let shadowed = rib
+            .loc_rib()
+            .get_route(&"10.20.0.0/16".parse().unwrap())
+            .unwrap();
+        assert_eq!(shadowed.learned_from.map(|peer| peer.asn), Some(65001));
+    }
+
+    #[test]
+    fn test_max_prefix_limit() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        rib.set_max_prefixes(65001, 1);
+
+        let result = rib.receive(
+            65001,
+            vec![
+                route("10.10.0.0/16", vec![65001]),
+                route("10.20.0.0/16", vec![65001]),
+            ],
+            &[],
+        );
+        assert!(result.is_err());
+        assert_eq!(rib.received(65001).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_policy_dry_run() {
+        use crate::network::bgp::policy::{DryRunOutcome, PolicyFragment};
+
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        rib.originate(route("10.0.0.0/8", vec![65000])).unwrap();
+        rib.receive(
+            65001,
+            vec![
+                route("10.1.1.0/24", vec![65001]),
+                route("10.2.0.0/16", vec![65001]),
+            ],
+            &[],
+        )
+        .unwrap();
+        rib.receive(65002, vec![route("10.3.3.0/24", vec![65002])], &[])
+            .unwrap();
+        let version = rib.loc_rib().version;
+
+        let fragment: PolicyFragment = toml::from_str(
+            r#"
+            [[import_policy]]
+            name = "no-24s-from-65001"
+            action = "reject"
+            match = { peer_asn = 65001, prefix_len_min = 24 }
+
+            [[peers]]
+            address = "10.0.0.2"
+            asn = 65002
+
+            [[peers.import_policy]]
+            name = "prefer-65002"
+            action = "accept"
+            set_local_pref = 200
+            "#,
+        )
+        .unwrap();
+        let mut candidate = rib.policy().clone();
+        candidate.set_import_rules(&fragment);
+
+        let report = rib.dry_run(&candidate, None).unwrap();
+        let classified = |outcome| {
+            report
+                .with_outcome(outcome)
+                .iter()
+                .map(|entry| (entry.network.to_string(), entry.rule.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            classified(DryRunOutcome::Reject),
+            vec![("10.1.1.0/24".to_string(), "no-24s-from-65001".to_string())]
+        );
+        assert_eq!(
+            classified(DryRunOutcome::Accept),
+            vec![("10.2.0.0/16".to_string(), "tier:full-table".to_string())]
+        );
+        assert_eq!(
+            classified(DryRunOutcome::Modified),
+            vec![("10.3.3.0/24".to_string(), "prefer-65002".to_string())]
+        );
+        assert_eq!(
+            report.with_outcome(DryRunOutcome::Modified)[0].changes,
+            vec!["local_pref 100 -> 200".to_string()]
+        );
+
+        // Per-peer runs look only at that peer's Adj-RIB-In
+        assert_eq!(
+            rib.dry_run(&candidate, Some(65001)).unwrap().entries.len(),
+            2
+        );
+        assert!(rib.dry_run(&candidate, Some(65009)).is_err());
+
+        // Nothing was installed
+        assert_eq!(rib.loc_rib().version, version);
+        assert_eq!(rib.loc_rib().routes.len(), 4);
+    }
+
+    #[test]
+    fn test_edge_rejects_a_full_table_that_backbone_keeps() {
+        let table: Vec<RouteEntry> = (0..5)
+            .map(|i| route(&format!("10.{}.0.0/16", 100 + i), vec![65101, 65002, 65103]))
+            .chain([route("10.0.0.0/8", vec![65101, 65001])])
+            .collect();
+        let mut edge = Rib::new(RoutingPolicy::new(66001, NodeTier::Edge));
+        let mut backbone = Rib::new(RoutingPolicy::new(65001, NodeTier::Backbone));
+        edge.receive(65101, table.clone(), &[]).unwrap();
+        backbone.receive(65101, table, &[]).unwrap();
+
+        // Only the default gets past the Edge policy
+        assert_eq!(edge.routes_from(65101), 6);
+        assert_eq!(edge.rejected_from(65101), 5);
+        assert_eq!(edge.installed_from(65101), 1);
+        // The default loops through the Backbone node's own AS
+        assert_eq!(backbone.rejected_from(65101), 1);
+        assert_eq!(backbone.installed_from(65101), 5);
+        assert_eq!(edge.rejected_from(65102), 0);
+
+        // Sent again, the table is rejected again; the count starts over
+        // with the next session
+        edge.receive(65101, vec![route("10.100.0.0/16", vec![65101, 65103])], &[])
+            .unwrap();
+        assert_eq!(edge.rejected_from(65101), 6);
+        edge.peer_down(65101).unwrap();
+        assert_eq!(edge.rejected_from(65101), 0);
+    }
+
+    #[test]
+    fn test_edge_routes_are_policed_and_normalized() {
+        use crate::config::PeeringConfig;
+        use crate::network::bgp::Community;
+
+        let mut rib = Rib::new(RoutingPolicy::new(65100, NodeTier::Regional));
+        let allowed = Community {
+            asn: 65100,
+            value: 10,
+        };
+        rib.set_peering(PeeringGuard::new(
+            PeeringConfig {
+                edge_allowed_communities: vec![allowed.clone()],
+                ..PeeringConfig::default()
+            },
+            200,
+        ))
+        .unwrap();
+        let mut changes = rib.subscribe();
+        let edge = 66001;
+
+        let mut service = route("10.8.1.0/24", vec![edge]);
+        service.local_pref = 900;
+        service.communities = vec![allowed.clone(), Community { asn: 1, value: 1 }];
+        let mut transit = route("10.9.1.0/24", vec![edge, 66002]);
+        transit.local_pref = 900;
+        rib.receive(
+            edge,
+            vec![service, transit, route("10.0.0.0/8", vec![edge])],
+            &[],
+        )
+        .unwrap();
+
+        // Only the compliant service route is stored and selected
+        assert_eq!(rib.received(edge).unwrap().len(), 1);
+        assert_eq!(rib.peering_stats(edge).unwrap().violations, 2);
+
+        // What goes upward carries our local_pref and only allowed communities
+        let Ok(RibChange::Advertise(propagated)) = changes.try_recv() else {
+            panic!("expected the service route to be advertised");
+        };
+        assert_eq!(propagated.network.to_string(), "10.8.1.0/24");
+        assert_eq!(propagated.local_pref, 200);
+        assert_eq!(propagated.communities, vec![allowed]);
+        assert!(changes.try_recv().is_err());
+
+        // Regional peers are not subject to Edge limits
+        rib.receive(65101, vec![route("10.0.0.0/8", vec![65101])], &[])
+            .unwrap();
+        assert!(rib.peering_stats(65101).is_none());
+    }
+
+    #[test]
+    fn test_blocked_origins_and_peers_are_dropped() {
+        use crate::config::AclConfig;
+        use crate::network::acl::AclEntry;
+
+        let mut rib = Rib::new(RoutingPolicy::new(65100, NodeTier::Regional));
+        rib.set_acl(Arc::new(
+            Acl::open(&AclConfig {
+                blocked: vec![AclEntry::Asn(65666), AclEntry::Asn(65002)],
+                state_file: None,
+                ..AclConfig::default()
+            })
+            .unwrap(),
+        ));
+
+        rib.receive(
+            65001,
+            vec![
+                route("10.10.0.0/16", vec![65001]),
+                route("10.20.0.0/16", vec![65001, 65666]),
+            ],
+            &[],
+        )
+        .unwrap();
+        rib.receive(65002, vec![route("10.30.0.0/16", vec![65002])], &[])
+            .unwrap();
+
+        assert_eq!(rib.received(65001).unwrap().len(), 1);
+        assert!(rib.received(65002).is_none());
+        assert_eq!(rib.loc_rib().routes.len(), 1);
+    }
+
+    #[test]
+    fn test_newly_joined_node_is_last_resort_until_hold_down_ends() {
+        use crate::config::HoldDownConfig;
+        use crate::network::bgp::hold_down::NEWLY_JOINED;
+        use std::time::{Duration, Instant};
+
+        let (new_asn, prefix) = (65101, "10.60.0.0/16");
+        let mut joined = Rib::new(RoutingPolicy::new(new_asn, NodeTier::Regional));
+        joined.set_hold_down(HoldDownConfig {
+            duration_secs: 600,
+            prepend: 3,
+        });
+        let mut exports = joined.subscribe();
+        joined.originate(route(prefix, vec![new_asn])).unwrap();
+        let (t0, w0) = (Instant::now(), chrono::Utc::now());
+        joined.start_hold_down(t0, w0);
+        let mut latest = || {
+            let mut latest = None;
+            while let Ok(RibChange::Advertise(route)) = exports.try_recv() {
+                latest = Some(route);
+            }
+            latest.unwrap()
+        };
+
+        // A backbone with two established paths to the same prefix
+        let mut observer = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        observer
+            .receive(65001, vec![route(prefix, vec![65001, 65060])], &[])
+            .unwrap();
+        observer
+            .receive(65002, vec![route(prefix, vec![65002, 65003, 65060])], &[])
+            .unwrap();
+        let best = |observer: &Rib| {
+            observer
+                .loc_rib()
+                .get_route(&prefix.parse().unwrap())
+                .unwrap()
+                .as_path[0]
+        };
+
+        let held = latest();
+        assert_eq!(held.as_path, vec![new_asn; 4]);
+        assert_eq!(held.med, u32::MAX);
+        assert!(held.communities.contains(&NEWLY_JOINED));
+        observer.receive(new_asn, vec![held], &[]).unwrap();
+        assert_eq!(best(&observer), 65001);
+
+        // Still established paths first until the window is over
+        assert_eq!(
+            joined.poll_hold_down(
+                t0 + Duration::from_secs(300),
+                w0 + chrono::Duration::seconds(300)
+            ),
+            None
+        );
+        assert_eq!(
+            joined.poll_hold_down(
+                t0 + Duration::from_secs(601),
+                w0 + chrono::Duration::seconds(601)
+            ),
+            Some(HoldDownEvent::Expired)
+        );
+        let normal = latest();
+        assert_eq!(normal.as_path, vec![new_asn]);
+        observer.receive(new_asn, vec![normal], &[]).unwrap();
+        assert_eq!(best(&observer), new_asn);
+    }
+
+    #[test]
+    fn test_dropping_a_session_removes_only_its_routes() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        let first = rib.peer_up(65001, Some(Uuid::new_v4()));
+        let mut own = route("10.0.0.0/16", vec![]);
+        own.next_hop = "10.0.0.1".parse().unwrap();
+        rib.originate(own).unwrap();
+        rib.receive(
+            65001,
+            vec![
+                route("10.1.0.0/16", vec![65001]),
+                route("10.12.0.0/16", vec![65001]),
+            ],
+            &[],
+        )
+        .unwrap();
+        // The second peer's path to the shared prefix is longer, so it is
+        // only a backup
+        rib.receive(
+            65002,
+            vec![
+                route("10.2.0.0/16", vec![65002]),
+                route("10.12.0.0/16", vec![65002, 65009]),
+            ],
+            &[],
+        )
+        .unwrap();
+
+        let attributed = |rib: &Rib, asn: u32| {
+            rib.loc_rib()
+                .routes
+                .values()
+                .filter(|route| route.learned_from.map(|peer| peer.asn) == Some(asn))
+                .count()
+        };
+        let shared: Prefix = "10.12.0.0/16".parse().unwrap();
+        assert_eq!(rib.loc_rib().routes[&shared].learned_from, Some(first));
+        assert_eq!((rib.routes_from(65001), rib.installed_from(65001)), (2, 2));
+        assert_eq!((rib.routes_from(65002), rib.installed_from(65002)), (2, 1));
+        assert_eq!(attributed(&rib, 65001), rib.installed_from(65001));
+
+        rib.peer_down(65001).unwrap();
+
+        let mut networks: Vec<String> = rib
+            .loc_rib()
+            .routes
+            .keys()
+            .map(|network| network.to_string())
+            .collect();
+        networks.sort();
+        assert_eq!(networks, ["10.0.0.0/16", "10.12.0.0/16", "10.2.0.0/16"]);
+        let own: Prefix = "10.0.0.0/16".parse().unwrap();
+        assert_eq!(rib.loc_rib().routes[&own].learned_from, None);
+        assert_eq!(
+            rib.loc_rib().routes[&shared]
+                .learned_from
+                .map(|peer| peer.asn),
+            Some(65002)
+        );
+        assert_eq!((rib.routes_from(65001), rib.installed_from(65001)), (0, 0));
+        assert_eq!((rib.routes_from(65002), rib.installed_from(65002)), (2, 2));
+        assert_eq!(attributed(&rib, 65002), rib.installed_from(65002));
+        assert_eq!(attributed(&rib, 65001), 0);
+    }
+
+    #[test]
+    fn test_moved_peer_keeps_its_routes_with_new_next_hop() {
+        let mut rib = Rib::new(RoutingPolicy::new(65101, NodeTier::Regional));
+        let (old, new): (IpAddr, IpAddr) =
+            ("172.16.0.1".parse().unwrap(), "172.16.9.9".parse().unwrap());
+        rib.receive(
+            65001,
+            vec![
+                route("10.70.0.0/16", vec![65001]),
+                route("10.71.0.0/16", vec![65001, 65060]),
+            ],
+            &[],
+        )
+        .unwrap();
+        let mut changes = rib.subscribe();
+
+        assert_eq!(rib.rehome_peer(65001, old, new).unwrap(), 2);
+        assert_eq!(rib.loc_rib().get_all_routes().len(), 2);
+        for route in rib.loc_rib().get_all_routes() {
+            assert_eq!(route.next_hop, new);
+        }
+        // Re-advertised rather than withdrawn
+        for _ in 0..2 {
+            assert!(matches!(
+                changes.try_recv(),
+                Ok(RibChange::Advertise(route)) if route.next_hop == new
+            ));
+        }
+        assert_eq!(rib.rehome_peer(65002, old, new).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_explain_each_outcome() {
+        use crate::config::PeeringConfig;
+        use crate::network::bgp::explain::Summary;
+
+        let mut rib = Rib::new(RoutingPolicy::new(65100, NodeTier::Regional));
+        rib.set_peering(PeeringGuard::new(
+            PeeringConfig {
+                violation_threshold: 1,
+                ..PeeringConfig::default()
+            },
+            100,
+        ))
+        .unwrap();
+        let explain =
+            |rib: &Rib, network: &str| rib.explain(&network.parse().unwrap(), Instant::now());
+
+        // Installed from a backbone peer, with a second path standing by
+        rib.receive(65001, vec![route("10.10.0.0/16", vec![65001])], &[])
+            .unwrap();
+        rib.receive(65002, vec![route("10.10.0.0/16", vec![65002, 65003])], &[])
+            .unwrap();
+        let installed = explain(&rib, "10.10.0.0/16");
+        assert_eq!(installed.summary(), Summary::Installed);
+        assert_eq!(installed.peers[0].outcome, PeerOutcome::Installed);
+        assert_eq!(installed.peers[1].outcome, PeerOutcome::NotBest);
+        assert!(installed.to_string().contains("from AS65001"));
+
+        // Held in the Adj-RIB-In but refused by the regional path limit
+        rib.receive(
+            65101,
+            vec![route("10.20.0.0/16", vec![65101, 65102, 65103, 65104])],
+            &[],
+        )
+        .unwrap();
+        let rejected = explain(&rib, "10.20.0.0/16");
+        assert_eq!(rejected.summary(), Summary::Rejected);
+        assert!(rejected.peers[0].route.is_some());
+        assert!(matches!(
+            &rejected.peers[0].outcome,
+            PeerOutcome::Rejected { verdict, age_secs: Some(_) }
+                if verdict.rule == "tier:regional-filter" && !verdict.allowed
+        ));
+        assert!(rejected.to_string().contains("rule tier:regional-filter"));
+        // As sent to the CLI
+        let sent: Explanation =
+            serde_json::from_value(serde_json::to_value(&rejected).unwrap()).unwrap();
+        assert_eq!(sent.peers[0].outcome, rejected.peers[0].outcome);
+
+        // A violation puts the Edge peer in cooldown, suppressing what follows
+        rib.receive(66001, vec![route("10.40.0.0/16", vec![66001])], &[])
+            .unwrap();
+        rib.receive(66001, vec![route("10.50.1.0/24", vec![66001])], &[])
+            .unwrap();
+        let refused = explain(&rib, "10.40.0.0/16");
+        assert_eq!(refused.summary(), Summary::Rejected);
+        assert!(refused.peers[0].route.is_none());
+        assert!(refused.to_string().contains("rule peering-agreement"));
+        let damped = explain(&rib, "10.50.1.0/24");
+        assert_eq!(damped.summary(), Summary::Damped);
+        assert!(damped.to_string().contains("rule peering-cooldown"));
+
+        let mut unresolvable = route("10.30.0.0/16", vec![65001]);
+        unresolvable.next_hop = "0.0.0.0".parse().unwrap();
+        rib.receive(65001, vec![unresolvable], &[]).unwrap();
+        let unresolved = explain(&rib, "10.30.0.0/16");
+        assert_eq!(unresolved.summary(), Summary::UnresolvedNextHop);
+        assert!(unresolved
+            .to_string()
+            .contains("0.0.0.0 cannot be resolved"));
+
+        let never = explain(&rib, "10.60.0.0/16");
+        assert_eq!(never.summary(), Summary::NeverReceived);
+        assert!(never.to_string().contains("never received"));
+
+        // Accepting the route later clears its rejection
+        rib.set_policy(RoutingPolicy::new(65100, NodeTier::Backbone))
+            .unwrap();
+        assert_eq!(explain(&rib, "10.20.0.0/16").summary(), Summary::Installed);
+        assert!(rib
+            .journal
+            .for_prefix(&"10.20.0.0/16".parse().unwrap(), Instant::now())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_pinned_path_wins_until_the_pin_expires() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        let network: Prefix = "10.40.0.0/16".parse().unwrap();
+        rib.receive(65001, vec![route("10.40.0.0/16", vec![65001])], &[])
+            .unwrap();
+        rib.receive(65002, vec![route("10.40.0.0/16", vec![65002, 65010])], &[])
+            .unwrap();
+        let selected = |rib: &Rib| {
+            rib.loc_rib()
+                .get_route(&network)
+                .and_then(|route| route.learned_from)
+                .map(|peer| peer.asn)
+        };
+        assert_eq!(selected(&rib), Some(65001));
+
+        // Pinning the longer path selects and advertises it
+        let mut changes = rib.subscribe();
+        let now = Utc::now();
+        let until = now + chrono::Duration::hours(2);
+        rib.pin(RoutePin::new(network, 65002, now).with_expiry(until), false)
+            .unwrap();
+        assert_eq!(selected(&rib), Some(65002));
+        match changes.try_recv().unwrap() {
+            RibChange::Advertise(route) => {
+                assert_eq!(route.network, network);
+                assert_eq!(route.learned_from.map(|peer| peer.asn), Some(65002));
+            }
+            other => panic!("expected an advertisement, got {:?}", other),
+        }
+
+        // Without a path from the pinned peer, selection falls back
+        rib.peer_down(65002).unwrap();
+        assert_eq!(selected(&rib), Some(65001));
+        rib.receive(65002, vec![route("10.40.0.0/16", vec![65002, 65010])], &[])
+            .unwrap();
+        assert_eq!(selected(&rib), Some(65002));
+
+        assert!(rib.expire_pins(now).unwrap().is_empty());
+        let expired = rib.expire_pins(until).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert!(rib.pins().is_empty());
+        assert_eq!(selected(&rib), Some(65001));
+        let reverted = std::iter::from_fn(|| changes.try_recv().ok()).last();
+        assert!(matches!(
+            reverted,
+            Some(RibChange::Advertise(route)) if route.learned_from.map(|peer| peer.asn) == Some(65001)
+        ));
+    }
+
+    #[test]
+    fn test_pins_refused_for_unknown_prefixes() {
+        let mut rib = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        let network: Prefix = "10.41.0.0/16".parse().unwrap();
+        let pin = RoutePin::new(network, 65002, Utc::now());
+        assert!(matches!(
+            rib.pin(pin.clone(), false),
+            Err(BGPError::UnknownPrefix { .. })
+        ));
+        rib.pin(pin, true).unwrap();
+        assert!(rib.loc_rib().get_route(&network).is_none());
+
+        // Takes effect once the pinned peer sends the prefix
+        rib.receive(65001, vec![route("10.41.0.0/16", vec![65001])], &[])
+            .unwrap();
+        rib.receive(65002, vec![route("10.41.0.0/16", vec![65002, 65010])], &[])
+            .unwrap();
+        let installed = rib.loc_rib().get_route(&network).unwrap();
+        assert_eq!(installed.learned_from.map(|peer| peer.asn), Some(65002));
+        assert!(rib.unpin(&network).unwrap().is_some());
+        let installed = rib.loc_rib().get_route(&network).unwrap();
+        assert_eq!(installed.learned_from.map(|peer| peer.asn), Some(65001));
+    }
+}
@@ -1,14 +1,50 @@
-use crate::network::bgp::{BGPOrigin, RouteEntry, RouteTable};
+use crate::network::bgp::default_route::{VX0_IPV4_SUPERNET, VX0_IPV6_SUPERNET};
+use crate::network::bgp::hold_down::is_last_resort;
+use crate::network::bgp::policy::{
+    self, DryRunEntry, DryRunOutcome, DryRunReport, PolicyDecision, PolicyFragment, PolicyRule,
+    PolicyVerdict, Verdict,
+};
+use crate::network::bgp::{AsPath, BGPOrigin, Prefix, RouteEntry, RouteTable};
+use crate::network::prefix_list::PrefixList;
 use crate::node::{NodeTier, RoutePolicy};
 use ipnet::IpNet;
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
+use std::sync::OnceLock;
+
+/// Longest AS path a regional node accepts from another regional
+const MAX_REGIONAL_PATH_LEN: usize = 3;
+
+/// What Edge nodes take as a default route: a real default or the VX0
+/// supernet of either family
+fn default_routes() -> &'static PrefixList {
+    static LIST: OnceLock<PrefixList> = OnceLock::new();
+    LIST.get_or_init(|| {
+        PrefixList::parse(["0.0.0.0/0", "::/0", VX0_IPV4_SUPERNET, VX0_IPV6_SUPERNET])
+            .expect("valid prefix list")
+    })
+}
+
+/// Routes short enough to advertise to backbone as aggregates
+fn aggregates() -> &'static PrefixList {
+    static LIST: OnceLock<PrefixList> = OnceLock::new();
+    LIST.get_or_init(|| {
+        PrefixList::parse(["0.0.0.0/0 le 16", "::/0 le 48"]).expect("valid prefix list")
+    })
+}
 
+#[derive(Debug, Clone)]
 pub struct RoutingPolicy {
     pub local_asn: u32,
     pub node_tier: NodeTier,
     pub route_policy: RoutePolicy,
     pub default_local_pref: u32,
     pub default_med: u32,
+    pub denied_prefixes: HashSet<Prefix>,
+    /// Configured import rules, checked before the tier policy
+    pub import_rules: Vec<PolicyRule>,
+    /// Per-peer import rules, checked before the global ones
+    pub peer_import_rules: HashMap<u32, Vec<PolicyRule>>,
 }
 
 impl RoutingPolicy {
@@ -21,17 +57,120 @@ impl RoutingPolicy {
             route_policy,
             default_local_pref: 100,
             default_med: 0,
+            denied_prefixes: HashSet::new(),
+            import_rules: Vec::new(),
+            peer_import_rules: HashMap::new(),
         }
     }
 
+    /// Reject a prefix from every peer regardless of tier policy
+    pub fn deny_prefix(&mut self, network: Prefix) {
+        self.denied_prefixes.insert(network);
+    }
+
+    /// Replace the configured import rules with those in `fragment`
+    pub fn set_import_rules(&mut self, fragment: &PolicyFragment) {
+        self.import_rules = fragment.import_policy.clone();
+        self.peer_import_rules = fragment
+            .peers
+            .iter()
+            .map(|peer| (peer.asn, peer.import_policy.clone()))
+            .collect();
+    }
+
     /// Check if we should accept a route based on our tier policy
-    pub fn should_accept_route(&self, route: &RouteEntry, peer_asn: u32) -> bool {
-        let peer_tier = Self::asn_to_tier(peer_asn);
+    pub fn should_accept_route(&self, route: &RouteEntry, peer_asn: u32) -> PolicyVerdict {
+        self.evaluate_import(route, peer_asn).to_verdict()
+    }
+
+    /// Run a route through import policy, naming the rule that decided it
+    pub fn evaluate_import(&self, route: &RouteEntry, peer_asn: u32) -> PolicyDecision {
+        let mut route = route.clone();
+        let mut changes = Vec::new();
+
+        let (verdict, rule, details) = if self.denied_prefixes.contains(&route.network) {
+            (
+                Verdict::Reject,
+                "deny-prefix".to_string(),
+                format!("{} is a denied prefix", route.network),
+            )
+        } else {
+            let rules = self
+                .peer_import_rules
+                .get(&peer_asn)
+                .into_iter()
+                .flatten()
+                .chain(&self.import_rules);
+            match policy::evaluate_rules(rules, &mut route, peer_asn, &mut changes) {
+                Some((verdict, rule)) => {
+                    let details = format!("import rule {} matched", rule);
+                    (verdict, rule, details)
+                }
+                None => {
+                    let tier = self.tier_verdict(&route, peer_asn);
+                    let verdict = if tier.allowed {
+                        Verdict::Accept
+                    } else {
+                        Verdict::Reject
+                    };
+                    (verdict, tier.rule, tier.details)
+                }
+            }
+        };
+
+        PolicyDecision {
+            verdict,
+            rule,
+            details,
+            route,
+            changes,
+        }
+    }
+
+    /// Classify routes, given with the ASN they were learned from, without
+    /// installing anything
+    pub fn dry_run<'a>(
+        &self,
+        routes: impl IntoIterator<Item = (u32, &'a RouteEntry)>,
+    ) -> DryRunReport {
+        let mut entries: Vec<DryRunEntry> = routes
+            .into_iter()
+            .map(|(peer_asn, route)| {
+                let decision = self.evaluate_import(route, peer_asn);
+                let outcome = match decision.verdict {
+                    Verdict::Reject => DryRunOutcome::Reject,
+                    Verdict::Accept if decision.changes.is_empty() => DryRunOutcome::Accept,
+                    Verdict::Accept => DryRunOutcome::Modified,
+                };
+                DryRunEntry {
+                    network: route.network,
+                    peer_asn,
+                    outcome,
+                    rule: decision.rule,
+                    changes: decision.changes,
+                }
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.network, entry.peer_asn));
+        DryRunReport { entries }
+    }
+
+    fn tier_rule_name(&self) -> &'static str {
+        match self.route_policy {
+            RoutePolicy::FullTable => "tier:full-table",
+            RoutePolicy::RegionalFilter => "tier:regional-filter",
+            RoutePolicy::DefaultOnly => "tier:default-only",
+        }
+    }
+
+    fn tier_verdict(&self, route: &RouteEntry, peer_asn: u32) -> PolicyVerdict {
+        let peer_tier = NodeTier::from_asn(peer_asn);
+        let rule = self.tier_rule_name();
 
         match &self.route_policy {
             RoutePolicy::FullTable => {
                 // Backbone nodes accept all routes from valid peers
-                true
+                PolicyVerdict::allow(rule, "full table accepts every route")
             }
             RoutePolicy::RegionalFilter => {
                 // Regional nodes have more restrictive policies
@@ -39,19 +178,36 @@ impl RoutingPolicy {
             }
             RoutePolicy::DefaultOnly => {
                 // Edge nodes only accept default routes and local announcements
-                self.is_default_route(route) || self.is_local_announcement(route, peer_asn)
+                if self.is_default_route(route) {
+                    PolicyVerdict::allow(rule, "default route")
+                } else if self.is_local_announcement(route, peer_asn) {
+                    PolicyVerdict::allow(rule, format!("announced directly by AS{}", peer_asn))
+                } else {
+                    PolicyVerdict::deny(
+                        rule,
+                        format!(
+                            "neither a default route nor announced directly by AS{} (path {:?})",
+                            peer_asn, route.as_path
+                        ),
+                    )
+                }
             }
         }
     }
 
     /// Check if we should advertise a route to a peer
-    pub fn should_advertise_route(&self, route: &RouteEntry, peer_asn: u32) -> bool {
-        let peer_tier = Self::asn_to_tier(peer_asn);
+    pub fn should_advertise_route(&self, route: &RouteEntry, peer_asn: u32) -> PolicyVerdict {
+        let peer_tier = NodeTier::from_asn(peer_asn);
+        let rule = self.tier_rule_name();
 
         match &self.route_policy {
             RoutePolicy::FullTable => {
                 // Backbone advertises all routes (with loop prevention)
-                !self.has_asn_loop(route, peer_asn)
+                if self.has_asn_loop(route, peer_asn) {
+                    PolicyVerdict::deny(rule, format!("AS path already contains AS{}", peer_asn))
+                } else {
+                    PolicyVerdict::allow(rule, "full table advertises every loop-free route")
+                }
             }
             RoutePolicy::RegionalFilter => {
                 // Regional nodes filter what they advertise
@@ -59,54 +215,100 @@ impl RoutingPolicy {
             }
             RoutePolicy::DefaultOnly => {
                 // Edge nodes only advertise local services
-                self.is_local_route(route)
+                if self.is_local_route(route) {
+                    PolicyVerdict::allow(rule, "originated here")
+                } else {
+                    PolicyVerdict::deny(rule, "edge nodes advertise only their own routes")
+                }
             }
         }
     }
 
-    fn apply_regional_filter(&self, route: &RouteEntry, peer_tier: NodeTier) -> bool {
+    fn apply_regional_filter(&self, route: &RouteEntry, peer_tier: NodeTier) -> PolicyVerdict {
+        let rule = self.tier_rule_name();
         match peer_tier {
-            NodeTier::Backbone => true, // Accept all from backbone
+            NodeTier::Backbone => PolicyVerdict::allow(rule, "accepts everything from backbone"),
             NodeTier::Regional => {
-                // Accept regional routes and local services
-                route.as_path.len() <= 3 // Limit path length
+                // Accept regional routes and local services, limiting path length
+                let hops = route.as_path.len();
+                if hops <= MAX_REGIONAL_PATH_LEN {
+                    PolicyVerdict::allow(rule, format!("AS path of {} hops", hops))
+                } else {
+                    PolicyVerdict::deny(
+                        rule,
+                        format!(
+                            "AS path of {} hops exceeds {} allowed from regional peers",
+                            hops, MAX_REGIONAL_PATH_LEN
+                        ),
+                    )
+                }
             }
             NodeTier::Edge => {
                 // Only accept local service announcements from edge
-                self.is_local_service_route(route)
+                if self.is_local_service_route(route) {
+                    PolicyVerdict::allow(rule, "service route from edge")
+                } else {
+                    PolicyVerdict::deny(
+                        rule,
+                        format!(
+                            "/{} is shorter than the /{} service routes accepted from edge peers",
+                            route.network.prefix_len(),
+                            Self::service_prefix_len(&route.network)
+                        ),
+                    )
+                }
             }
         }
     }
 
-    fn apply_regional_advertisement_filter(&self, route: &RouteEntry, peer_tier: NodeTier) -> bool {
+    fn apply_regional_advertisement_filter(
+        &self,
+        route: &RouteEntry,
+        peer_tier: NodeTier,
+    ) -> PolicyVerdict {
+        let rule = self.tier_rule_name();
         match peer_tier {
             NodeTier::Backbone => {
                 // Advertise aggregated routes to backbone
-                self.is_aggregatable_route(route)
+                if self.is_aggregatable_route(route) {
+                    PolicyVerdict::allow(rule, "aggregate toward backbone")
+                } else {
+                    PolicyVerdict::deny(
+                        rule,
+                        format!(
+                            "/{} is too specific to advertise to backbone",
+                            route.network.prefix_len()
+                        ),
+                    )
+                }
             }
             NodeTier::Regional => {
                 // Share routes with other regionals
-                !self.has_asn_loop(route, 0) // General loop prevention
+                if self.has_asn_loop(route, 0) {
+                    // General loop prevention
+                    PolicyVerdict::deny(rule, "AS path contains AS0")
+                } else {
+                    PolicyVerdict::allow(rule, "shared with regional peers")
+                }
             }
             NodeTier::Edge => {
                 // Send default route + reachable services to edge
-                self.is_default_route(route) || self.is_reachable_service(route)
+                if self.is_default_route(route) {
+                    PolicyVerdict::allow(rule, "default route toward edge")
+                } else if self.is_reachable_service(route) {
+                    PolicyVerdict::allow(rule, "reachable service toward edge")
+                } else {
+                    PolicyVerdict::deny(
+                        rule,
+                        "edge peers get only the default route and reachable services",
+                    )
+                }
             }
         }
     }
 
-    fn asn_to_tier(asn: u32) -> NodeTier {
-        match asn {
-            65000..=65099 => NodeTier::Backbone,
-            65100..=65999 => NodeTier::Regional,
-            66000..=69999 => NodeTier::Edge,
-            _ => NodeTier::Edge,
-        }
-    }
-
     fn is_default_route(&self, route: &RouteEntry) -> bool {
-        route.network == "0.0.0.0/0".parse().unwrap()
-            || route.network == "10.0.0.0/8".parse().unwrap() // VX0 default
+        default_routes().matches(&route.network)
     }
 
     fn is_local_route(&self, route: &RouteEntry) -> bool {
@@ -114,22 +316,28 @@ impl RoutingPolicy {
     }
 
     fn is_local_announcement(&self, route: &RouteEntry, peer_asn: u32) -> bool {
-        route.as_path == vec![peer_asn] // Direct announcement from peer
+        *route.as_path == [peer_asn] // Direct announcement from peer
     }
 
     fn is_local_service_route(&self, route: &RouteEntry) -> bool {
-        // Check if this is a service route (typically /32 or small subnets)
-        route.network.prefix_len() >= 24
+        // Check if this is a service route (a single LAN or smaller)
+        route.network.prefix_len() >= Self::service_prefix_len(&route.network)
     }
 
     fn is_aggregatable_route(&self, route: &RouteEntry) -> bool {
-        // Routes that can be aggregated for backbone advertisement
-        route.network.prefix_len() <= 16 // Only larger prefixes
+        aggregates().matches(&route.network)
     }
 
     fn is_reachable_service(&self, route: &RouteEntry) -> bool {
         // Services that should be advertised to edge nodes
-        route.network.prefix_len() >= 24 && route.local_pref >= 100
+        self.is_local_service_route(route) && route.local_pref >= 100
+    }
+
+    fn service_prefix_len(network: &IpNet) -> u8 {
+        match network {
+            IpNet::V4(_) => 24,
+            IpNet::V6(_) => 64,
+        }
     }
 
     fn has_asn_loop(&self, route: &RouteEntry, peer_asn: u32) -> bool {
@@ -164,11 +372,20 @@ impl RoutingPolicy {
             return None;
         }
 
+        // Paths through nodes still in hold-down are only a last resort;
+        // lower MED breaks ties
+        let rank = |route: &RouteEntry| {
+            (
+                !is_last_resort(route),
+                self.evaluate_route(route),
+                std::cmp::Reverse(route.med),
+            )
+        };
         let mut best_route = &routes[0];
-        let mut best_preference = self.evaluate_route(best_route);
+        let mut best_preference = rank(best_route);
 
         for route in routes.iter().skip(1) {
-            let preference = self.evaluate_route(route);
+            let preference = rank(route);
             if preference > best_preference {
                 best_route = route;
                 best_preference = preference;
@@ -181,16 +398,21 @@ impl RoutingPolicy {
 
 impl RouteTable {
     pub fn find_best_route(&self, destination: &IpAddr) -> Option<&RouteEntry> {
-        // Find the most specific route (longest prefix match)
-        let mut best_route = None;
-        let mut best_prefix_len = 0;
+        // Find the most specific route (longest prefix match) within the
+        // destination's address family
+        let mut best_route: Option<&RouteEntry> = None;
 
         for (network, route) in &self.routes {
-            if network.contains(destination) {
-                let prefix_len = network.prefix_len();
-                if prefix_len > best_prefix_len {
+            let same_family = matches!(
+                (network.net(), destination),
+                (IpNet::V4(_), IpAddr::V4(_)) | (IpNet::V6(_), IpAddr::V6(_))
+            );
+            if same_family && network.contains(destination) {
+                let more_specific = best_route
+                    .map(|best| network.prefix_len() > best.network.prefix_len())
+                    .unwrap_or(true);
+                if more_specific {
                     best_route = Some(route);
-                    best_prefix_len = prefix_len;
                 }
             }
         }
@@ -198,7 +420,7 @@ impl RouteTable {
         best_route
     }
 
-    pub fn get_routes_for_prefix(&self, network: &IpNet) -> Vec<&RouteEntry> {
+    pub fn get_routes_for_prefix(&self, network: &Prefix) -> Vec<&RouteEntry> {
         self.routes
             .values()
             .filter(|route| route.network == *network)
@@ -207,18 +429,20 @@ impl RouteTable {
 
     pub fn announce_vx0_network(
         &mut self,
-        vx0_network: IpNet,
+        vx0_network: Prefix,
         local_asn: u32,
     ) -> Result<(), crate::network::bgp::BGPError> {
         let route = RouteEntry {
             network: vx0_network,
             next_hop: "10.0.0.1".parse().unwrap(), // VX0 gateway
-            as_path: vec![local_asn],
+            as_path: AsPath::new(&[local_asn]),
             origin: BGPOrigin::IGP,
             local_pref: 200, // High preference for VX0 routes
             med: 0,
             communities: vec![],
             timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
         };
 
         self.add_route(route)?;
@@ -230,6 +454,7 @@ impl RouteTable {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::network::bgp::testing;
 
     #[test]
     fn test_route_evaluation() {
@@ -238,18 +463,75 @@ mod tests {
         let route = RouteEntry {
             network: "10.0.0.0/24".parse().unwrap(),
             next_hop: "192.168.1.1".parse().unwrap(),
-            as_path: vec![65001, 65002],
+            as_path: vec![65001, 65002].into(),
             origin: BGPOrigin::IGP,
             local_pref: 100,
             med: 0,
             communities: vec![],
             timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
         };
 
         let preference = policy.evaluate_route(&route);
         assert!(preference > 0);
     }
 
+    fn route(network: &str, next_hop: &str) -> RouteEntry {
+        testing::route(network, next_hop, &[65002])
+    }
+
+    #[test]
+    fn test_family_aware_policy() {
+        let policy = RoutingPolicy::new(65100, crate::node::NodeTier::Regional);
+
+        // /48 aggregates toward backbone in v6 like /16 does in v4
+        let advertise = |network: &str, next_hop: &str, peer_asn: u32| {
+            policy
+                .should_advertise_route(&route(network, next_hop), peer_asn)
+                .allowed
+        };
+        assert!(advertise("fd00:7830:1::/48", "fd00::1", 65000));
+        assert!(!advertise("fd00:7830:1:1::/64", "fd00::1", 65000));
+        assert!(advertise("10.1.0.0/16", "10.0.0.1", 65000));
+
+        // VX0 v6 supernet is a default route toward edge
+        assert!(advertise("fd00:7830::/32", "fd00::1", 66001));
+        assert!(advertise("::/0", "fd00::1", 66001));
+    }
+
+    #[test]
+    fn test_find_best_route_per_family() {
+        let mut table = RouteTable::new();
+        table.add_route(route("0.0.0.0/0", "10.0.0.1")).unwrap();
+        table
+            .add_route(route("fd00:7830::/32", "fd00:7830::1"))
+            .unwrap();
+        table
+            .add_route(route("fd00:7830:2::/48", "fd00:7830:2::1"))
+            .unwrap();
+
+        let v6: IpAddr = "fd00:7830:2:1::1".parse().unwrap();
+        assert_eq!(
+            table.find_best_route(&v6).unwrap().network,
+            "fd00:7830:2::/48".parse::<Prefix>().unwrap()
+        );
+
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            table.find_best_route(&v4).unwrap().network,
+            "0.0.0.0/0".parse::<Prefix>().unwrap()
+        );
+        assert!(table
+            .find_best_route(&"2001:db8::1".parse().unwrap())
+            .is_none());
+
+        // Mixed-family next hop is refused
+        assert!(table
+            .add_route(route("fd00:7830:3::/48", "10.0.0.1"))
+            .is_err());
+    }
+
     #[test]
     fn test_best_route_selection() {
         let policy = RoutingPolicy::new(65001, crate::node::NodeTier::Edge);
@@ -257,23 +539,27 @@ mod tests {
         let route1 = RouteEntry {
             network: "10.0.0.0/24".parse().unwrap(),
             next_hop: "192.168.1.1".parse().unwrap(),
-            as_path: vec![65001, 65002],
+            as_path: vec![65001, 65002].into(),
             origin: BGPOrigin::IGP,
             local_pref: 100,
             med: 0,
             communities: vec![],
             timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
         };
 
         let route2 = RouteEntry {
             network: "10.0.0.0/24".parse().unwrap(),
             next_hop: "192.168.1.2".parse().unwrap(),
-            as_path: vec![65001, 65003, 65004],
+            as_path: vec![65001, 65003, 65004].into(),
             origin: BGPOrigin::EGP,
             local_pref: 150,
             med: 0,
             communities: vec![],
             timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
         };
 
         let routes = vec![route1, route2];
@@ -1,21 +1,22 @@
+use crate::monitoring::{crash, Subsystem};
 use crate::network::bgp::{BGPError, BGPSession, BGPSessionState};
 use tokio::time::{interval, Duration};
 
 impl BGPSession {
     pub async fn start_keepalive(&self) -> Result<(), BGPError> {
         if !matches!(self.state, BGPSessionState::Established) {
-            return Err(BGPError::Protocol("Session not established".to_string()));
+            return Err(BGPError::SessionNotEstablished { peer: self.peer_ip });
         }
 
         let peer_ip = self.peer_ip;
         let keepalive_interval = self.keepalive_time;
 
-        tokio::spawn(async move {
+        crash::spawn(Subsystem::Bgp, "bgp-keepalive", async move {
             let mut interval = interval(Duration::from_secs(keepalive_interval as u64));
 
             loop {
                 interval.tick().await;
-                tracing::debug!("Sending BGP keepalive to {}", peer_ip);
+                crate::sampled!(tracing::debug!("Sending BGP keepalive to {}", peer_ip));
 
                 // In a real implementation, we would send actual BGP keepalive messages
                 // For now, just log the keepalive
@@ -30,7 +31,7 @@ impl BGPSession {
         _routes: Vec<crate::network::bgp::RouteEntry>,
     ) -> Result<(), BGPError> {
         if !matches!(self.state, BGPSessionState::Established) {
-            return Err(BGPError::Protocol("Session not established".to_string()));
+            return Err(BGPError::SessionNotEstablished { peer: self.peer_ip });
         }
 
         tracing::debug!("Sending BGP update to {}", self.peer_ip);
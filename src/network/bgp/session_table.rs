@@ -0,0 +1,309 @@
+//! Inbound BGP connections and the sessions on them.
+//!
+//! An accepted connection holds an entry from accept until its stream
+//! closes, keyed by its remote address so that a peer reconnecting never
+//! shares an entry with its earlier connection. Entries are capped:
+//! connections beyond the cap are refused and counted. Entries that never
+//! establish their session within the setup timeout, such as port scanners
+//! that never send OPEN, are swept along with their connection.
+
+use crate::config::SessionLimitsConfig;
+use crate::monitoring::register_metric;
+use crate::network::bgp::{BGPSession, BGPSessionState};
+use prometheus::{IntCounter, Opts};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::task::AbortHandle;
+
+/// How often entries are checked against the setup timeout, at most
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Inbound connections refused because the table was full
+pub fn rejected_counter() -> &'static IntCounter {
+    static COUNTER: OnceLock<IntCounter> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounter::with_opts(Opts::new(
+            "vx0net_bgp_connections_rejected_total",
+            "Inbound BGP connections refused at the session limit",
+        )))
+    })
+}
+
+struct Entry {
+    /// None until the peer's OPEN is accepted
+    session: Option<BGPSession>,
+    /// When the entry reached its current state
+    since: Instant,
+    /// The task serving the connection, ended with the entry
+    task: Option<AbortHandle>,
+}
+
+impl Entry {
+    fn established(&self) -> bool {
+        self.session.as_ref().is_some_and(|session| {
+            matches!(
+                session.state,
+                BGPSessionState::Established | BGPSessionState::Suspended
+            )
+        })
+    }
+
+    fn end(self) -> Option<BGPSession> {
+        if let Some(task) = self.task {
+            task.abort();
+        }
+        self.session
+    }
+}
+
+pub struct SessionTable {
+    limits: SessionLimitsConfig,
+    entries: HashMap<SocketAddr, Entry>,
+    rejected: u64,
+}
+
+impl Default for SessionTable {
+    fn default() -> Self {
+        SessionTable::new(SessionLimitsConfig::default())
+    }
+}
+
+impl SessionTable {
+    pub fn new(limits: SessionLimitsConfig) -> Self {
+        SessionTable {
+            limits,
+            entries: HashMap::new(),
+            rejected: 0,
+        }
+    }
+
+    pub fn limits(&self) -> SessionLimitsConfig {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: SessionLimitsConfig) {
+        self.limits = limits;
+    }
+
+    /// Track a newly accepted connection, or refuse it when the table is full
+    pub fn admit(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.entries.len() >= self.limits.max_sessions {
+            self.rejected += 1;
+            rejected_counter().inc();
+            return false;
+        }
+        self.entries.insert(
+            addr,
+            Entry {
+                session: None,
+                since: now,
+                task: None,
+            },
+        );
+        true
+    }
+
+    /// Note the task serving a connection, so ending the entry ends it
+    pub fn attach(&mut self, addr: SocketAddr, task: AbortHandle) {
+        match self.entries.get_mut(&addr) {
+            Some(entry) => entry.task = Some(task),
+            None => task.abort(),
+        }
+    }
+
+    /// Record the session a connection's OPEN started. An older connection
+    /// from the same peer loses the collision and is closed.
+    pub fn open(&mut self, addr: SocketAddr, session: BGPSession, now: Instant) {
+        let peer_asn = session.peer_asn;
+        let collided: Vec<SocketAddr> = self
+            .entries
+            .iter()
+            .filter(|(other, entry)| {
+                **other != addr
+                    && entry
+                        .session
+                        .as_ref()
+                        .is_some_and(|session| session.peer_asn == peer_asn)
+            })
+            .map(|(other, _)| *other)
+            .collect();
+        for other in collided {
+            if let Some(entry) = self.entries.remove(&other) {
+                tracing::info!(
+                    "BGP connection from {} replaces AS{}'s connection from {}",
+                    addr,
+                    peer_asn,
+                    other
+                );
+                entry.end();
+            }
+        }
+
+        let entry = self.entries.entry(addr).or_insert(Entry {
+            session: None,
+            since: now,
+            task: None,
+        });
+        entry.session = Some(session);
+        entry.since = now;
+    }
+
+    pub fn set_state(&mut self, addr: SocketAddr, state: BGPSessionState, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&addr) {
+            if let Some(session) = &mut entry.session {
+                session.state = state;
+                entry.since = now;
+            }
+        }
+    }
+
+    /// Forget a connection whose stream closed
+    pub fn close(&mut self, addr: SocketAddr) -> Option<BGPSession> {
+        self.entries.remove(&addr).and_then(|entry| entry.session)
+    }
+
+    /// End the sessions `keep` refuses, closing their connections, and
+    /// return them
+    pub fn end_where(&mut self, mut keep: impl FnMut(&BGPSession) -> bool) -> Vec<BGPSession> {
+        let ending: Vec<SocketAddr> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.session.as_ref().is_some_and(|s| !keep(s)))
+            .map(|(addr, _)| *addr)
+            .collect();
+        ending
+            .into_iter()
+            .filter_map(|addr| self.entries.remove(&addr).and_then(Entry::end))
+            .collect()
+    }
+
+    /// End connections that went longer than the setup timeout without an
+    /// established session; returns how many
+    pub fn sweep(&mut self, now: Instant) -> usize {
+        let timeout = self.limits.setup_timeout.get();
+        let stale: Vec<SocketAddr> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                !entry.established() && now.saturating_duration_since(entry.since) >= timeout
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &stale {
+            if let Some(entry) = self.entries.remove(addr) {
+                tracing::debug!("BGP connection from {} never established; closing it", addr);
+                entry.end();
+            }
+        }
+        stale.len()
+    }
+
+    /// Addresses of peers whose OPEN was accepted on a live connection
+    pub fn peers(&self) -> Vec<IpAddr> {
+        let mut peers: Vec<IpAddr> = self.sessions().map(|session| session.peer_ip).collect();
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+
+    /// Peers with an Established session on a live connection
+    pub fn established(&self) -> usize {
+        let mut peers: Vec<IpAddr> = self
+            .sessions()
+            .filter(|session| session.state == BGPSessionState::Established)
+            .map(|session| session.peer_ip)
+            .collect();
+        peers.sort();
+        peers.dedup();
+        peers.len()
+    }
+
+    /// Where the latest session from `peer` stands
+    pub fn state(&self, peer: IpAddr) -> Option<BGPSessionState> {
+        self.entries
+            .values()
+            .filter_map(|entry| Some((entry.since, entry.session.as_ref()?)))
+            .filter(|(_, session)| session.peer_ip == peer)
+            .max_by_key(|(since, _)| *since)
+            .map(|(_, session)| session.state)
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &BGPSession> {
+        self.entries
+            .values()
+            .filter_map(|entry| entry.session.as_ref())
+    }
+
+    /// Connections tracked, with or without a session
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Connections refused at the limit
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::units::ConfigDuration;
+    use crate::network::bgp::rib::Rib;
+    use crate::network::bgp::routing::RoutingPolicy;
+    use crate::node::NodeTier;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn session(peer_asn: u32, addr: SocketAddr) -> BGPSession {
+        let rib = Rib::new(RoutingPolicy::new(65001, NodeTier::Backbone));
+        let mut session = BGPSession::new(65001, peer_asn, addr.ip(), Arc::new(RwLock::new(rib)));
+        session.state = BGPSessionState::OpenSent;
+        session
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_limit_and_sweep() {
+        let mut table = SessionTable::new(SessionLimitsConfig {
+            max_sessions: 2,
+            setup_timeout: ConfigDuration::from_secs(30),
+        });
+        let start = Instant::now();
+        assert!(table.admit(addr(1), start));
+        assert!(table.admit(addr(2), start));
+        assert!(!table.admit(addr(3), start));
+        assert_eq!((table.len(), table.rejected()), (2, 1));
+
+        // One completes its session; the other never sends OPEN
+        table.open(addr(1), session(65002, addr(1)), start);
+        table.set_state(addr(1), BGPSessionState::Established, start);
+        assert_eq!(table.sweep(start + Duration::from_secs(29)), 0);
+        assert_eq!(table.sweep(start + Duration::from_secs(30)), 1);
+        assert_eq!(table.peers(), vec![addr(1).ip()]);
+        assert!(table.close(addr(1)).is_some());
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn test_newer_connection_wins_a_collision() {
+        let mut table = SessionTable::default();
+        let now = Instant::now();
+        table.admit(addr(1), now);
+        table.admit(addr(2), now);
+        table.open(addr(1), session(65002, addr(1)), now);
+        table.open(addr(2), session(65002, addr(2)), now);
+        assert_eq!(table.len(), 1);
+        assert!(table.close(addr(1)).is_none());
+        assert!(table.close(addr(2)).is_some());
+    }
+}
@@ -0,0 +1,388 @@
+//! Chunked, resumable exchange of the full table a session starts with.
+//!
+//! A Backbone node joining the network is owed tens of thousands of routes.
+//! Instead of one UPDATE holding all of them, the sender pages its
+//! policy-filtered table, ordered by prefix, into numbered chunks and keeps
+//! at most `window` of them unacknowledged; the last one carries a digest
+//! over every chunk. The receiver applies each chunk as it arrives,
+//! acknowledges it and persists how far it got, so a session dropped midway
+//! picks up at the next chunk: the receiver's OPEN reply names the table
+//! version and chunk it needs. Until the digest checks out, the peer's
+//! routes are used but reported as partial.
+
+use crate::config::TableSyncConfig;
+use crate::network::bgp::protocol::BGPRoute;
+use crate::network::bgp::BGPError;
+use crate::storage::Namespace;
+use crate::util::sync::lock;
+use ring::digest;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Store namespace holding receivers' cursors, one key per peer ASN
+pub const NAMESPACE: &str = "table_sync";
+
+/// Marks an UPDATE as one chunk of a table sync
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableChunk {
+    /// Version of the sender's table being paged through
+    pub table_version: u64,
+    pub seq: u32,
+    pub total: u32,
+    /// On the last chunk only: the digest of every chunk's digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+/// Where a receiver stopped, sent in its OPEN reply so the sync resumes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncResume {
+    pub table_version: u64,
+    pub next_chunk: u32,
+}
+
+/// How far the table sync from a peer has come
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct SyncProgress {
+    /// Chunks applied
+    pub received: u32,
+    pub total: u32,
+    /// The final digest matched; until then the peer's routes are partial
+    pub complete: bool,
+}
+
+/// A receiver's progress, persisted after every chunk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncCursor {
+    table_version: u64,
+    total: u32,
+    /// One per chunk applied, in order
+    chunk_digests: Vec<String>,
+}
+
+impl SyncCursor {
+    fn progress(&self) -> SyncProgress {
+        SyncProgress {
+            received: self.chunk_digests.len() as u32,
+            total: self.total,
+            complete: false,
+        }
+    }
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Digest of one chunk's routes as they travel
+pub fn chunk_digest(routes: &[BGPRoute]) -> Result<String, BGPError> {
+    Ok(hex_digest(&serde_json::to_vec(routes)?))
+}
+
+/// Digest of a whole table from its chunks' digests, in order
+pub fn table_digest(chunk_digests: &[String]) -> String {
+    hex_digest(chunk_digests.concat().as_bytes())
+}
+
+/// A table paged into chunks for one peer
+#[derive(Debug)]
+pub struct TablePages {
+    table_version: u64,
+    chunks: Vec<Vec<BGPRoute>>,
+    digests: Vec<String>,
+}
+
+impl TablePages {
+    /// Page `routes` by prefix; an empty table is one empty chunk, so the
+    /// peer still gets the digest
+    pub fn new(
+        mut routes: Vec<BGPRoute>,
+        table_version: u64,
+        chunk_routes: usize,
+    ) -> Result<Self, BGPError> {
+        routes.sort_by_key(|route| route.network);
+        let mut chunks: Vec<Vec<BGPRoute>> = routes
+            .chunks(chunk_routes.max(1))
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+        let digests = chunks
+            .iter()
+            .map(|chunk| chunk_digest(chunk))
+            .collect::<Result<_, _>>()?;
+        Ok(TablePages {
+            table_version,
+            chunks,
+            digests,
+        })
+    }
+
+    pub fn total(&self) -> u32 {
+        self.chunks.len() as u32
+    }
+
+    /// The first chunk to send: where the peer stopped if it was paging
+    /// this same version, else the beginning
+    pub fn start(&self, resume: Option<SyncResume>) -> u32 {
+        resume
+            .filter(|resume| {
+                resume.table_version == self.table_version && resume.next_chunk < self.total()
+            })
+            .map_or(0, |resume| resume.next_chunk)
+    }
+
+    pub fn chunk(&self, seq: u32) -> (Vec<BGPRoute>, TableChunk) {
+        let last = seq + 1 == self.total();
+        (
+            self.chunks[seq as usize].clone(),
+            TableChunk {
+                table_version: self.table_version,
+                seq,
+                total: self.total(),
+                digest: last.then(|| table_digest(&self.digests)),
+            },
+        )
+    }
+}
+
+/// Table syncs received from peers
+#[derive(Debug, Default)]
+pub struct TableSyncs {
+    config: TableSyncConfig,
+    cursors: Mutex<HashMap<u32, SyncCursor>>,
+    /// Peers whose sync completed since the daemon started
+    complete: Mutex<HashMap<u32, SyncProgress>>,
+    storage: Option<Namespace>,
+}
+
+impl TableSyncs {
+    /// Restore the cursors of syncs a restart interrupted from `storage`
+    pub fn open(config: TableSyncConfig, storage: Option<Namespace>) -> Result<Self, BGPError> {
+        let cursors = match &storage {
+            Some(storage) => storage
+                .iter_prefix::<SyncCursor>("")
+                .map_err(BGPError::SyncPersist)?
+                .into_iter()
+                .filter_map(|(key, cursor)| Some((key.parse().ok()?, cursor)))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(TableSyncs {
+            config,
+            cursors: Mutex::new(cursors),
+            complete: Mutex::new(HashMap::new()),
+            storage,
+        })
+    }
+
+    pub fn config(&self) -> TableSyncConfig {
+        self.config
+    }
+
+    /// Where to resume the sync from `peer_asn`, if one was interrupted
+    pub fn resume_point(&self, peer_asn: u32) -> Option<SyncResume> {
+        lock(&self.cursors).get(&peer_asn).map(|cursor| SyncResume {
+            table_version: cursor.table_version,
+            next_chunk: cursor.chunk_digests.len() as u32,
+        })
+    }
+
+    pub fn progress(&self, peer_asn: u32) -> Option<SyncProgress> {
+        if let Some(cursor) = lock(&self.cursors).get(&peer_asn) {
+            return Some(cursor.progress());
+        }
+        lock(&self.complete).get(&peer_asn).copied()
+    }
+
+    /// Check a chunk is the one expected before its routes are applied; a
+    /// first chunk starts the sync over
+    pub fn admit(&self, peer_asn: u32, chunk: &TableChunk) -> Result<(), BGPError> {
+        let mut cursors = lock(&self.cursors);
+        if chunk.seq == 0 {
+            cursors.insert(
+                peer_asn,
+                SyncCursor {
+                    table_version: chunk.table_version,
+                    total: chunk.total,
+                    chunk_digests: Vec::new(),
+                },
+            );
+            lock(&self.complete).remove(&peer_asn);
+            return Ok(());
+        }
+        match cursors.get(&peer_asn) {
+            Some(cursor)
+                if cursor.table_version == chunk.table_version
+                    && cursor.total == chunk.total
+                    && cursor.chunk_digests.len() as u32 == chunk.seq =>
+            {
+                Ok(())
+            }
+            Some(cursor) => Err(BGPError::TableSync {
+                peer_asn,
+                reason: format!(
+                    "expected chunk {} of table version {}, got chunk {} of version {}",
+                    cursor.chunk_digests.len(),
+                    cursor.table_version,
+                    chunk.seq,
+                    chunk.table_version
+                ),
+            }),
+            None => Err(BGPError::TableSync {
+                peer_asn,
+                reason: format!("chunk {} arrived without a sync under way", chunk.seq),
+            }),
+        }
+    }
+
+    /// Record an admitted chunk as applied and persist the cursor; the last
+    /// one completes the sync if the table digest matches
+    pub fn applied(
+        &self,
+        peer_asn: u32,
+        chunk: &TableChunk,
+        routes: &[BGPRoute],
+    ) -> Result<SyncProgress, BGPError> {
+        let digest = chunk_digest(routes)?;
+        let mut cursors = lock(&self.cursors);
+        let Some(cursor) = cursors.get_mut(&peer_asn) else {
+            return Err(BGPError::TableSync {
+                peer_asn,
+                reason: format!("chunk {} was not admitted", chunk.seq),
+            });
+        };
+        cursor.chunk_digests.push(digest);
+        if chunk.seq + 1 < chunk.total {
+            let progress = cursor.progress();
+            if let Some(storage) = &self.storage {
+                storage
+                    .put(&peer_asn.to_string(), cursor)
+                    .map_err(BGPError::SyncPersist)?;
+            }
+            return Ok(progress);
+        }
+
+        // The last chunk: the sync is over either way
+        let expected = table_digest(&cursor.chunk_digests);
+        let progress = cursor.progress();
+        cursors.remove(&peer_asn);
+        if let Some(storage) = &self.storage {
+            storage
+                .delete(&peer_asn.to_string())
+                .map_err(BGPError::SyncPersist)?;
+        }
+        if chunk.digest.as_deref() != Some(expected.as_str()) {
+            return Err(BGPError::TableSync {
+                peer_asn,
+                reason: "the table digest does not match the chunks received".to_string(),
+            });
+        }
+        let progress = SyncProgress {
+            complete: true,
+            ..progress
+        };
+        lock(&self.complete).insert(peer_asn, progress);
+        Ok(progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::BGPOrigin;
+    use crate::storage::Store;
+
+    fn sample(count: u8) -> Vec<BGPRoute> {
+        (0..count)
+            .rev()
+            .map(|i| BGPRoute {
+                network: format!("10.70.{}.0/24", i).parse().unwrap(),
+                next_hop: "10.3.0.1".parse().unwrap(),
+                as_path: vec![65001],
+                origin: BGPOrigin::IGP,
+                local_pref: 100,
+                med: 0,
+                originated_at: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pages_are_ordered_and_resume_in_place() {
+        let pages = TablePages::new(sample(10), 7, 4).unwrap();
+        assert_eq!(pages.total(), 3);
+        let (first, chunk) = pages.chunk(0);
+        assert_eq!(first[0].network, "10.70.0.0/24".parse().unwrap());
+        assert_eq!((chunk.seq, chunk.total, chunk.digest), (0, 3, None));
+        assert!(pages.chunk(2).1.digest.is_some());
+
+        let resume = |table_version, next_chunk| {
+            Some(SyncResume {
+                table_version,
+                next_chunk,
+            })
+        };
+        assert_eq!(pages.start(resume(7, 2)), 2);
+        // Another version, or a chunk past the end, starts over
+        assert_eq!(pages.start(resume(6, 2)), 0);
+        assert_eq!(pages.start(resume(7, 3)), 0);
+        assert_eq!(TablePages::new(vec![], 1, 4).unwrap().total(), 1);
+    }
+
+    #[test]
+    fn test_cursor_survives_a_restart_and_checks_the_digest() {
+        let root = std::env::temp_dir().join(format!("vx0-table-sync-{}", uuid::Uuid::new_v4()));
+        let storage = || Some(Store::open(&root).unwrap().namespace(NAMESPACE));
+        let pages = TablePages::new(sample(9), 3, 3).unwrap();
+
+        let syncs = TableSyncs::open(TableSyncConfig::default(), storage()).unwrap();
+        for seq in 0..2 {
+            let (routes, chunk) = pages.chunk(seq);
+            syncs.admit(65001, &chunk).unwrap();
+            syncs.applied(65001, &chunk, &routes).unwrap();
+        }
+        // Out of order is refused
+        assert!(matches!(
+            syncs.admit(65001, &pages.chunk(1).1),
+            Err(BGPError::TableSync { .. })
+        ));
+        let reopened = TableSyncs::open(TableSyncConfig::default(), storage()).unwrap();
+        assert_eq!(
+            reopened.resume_point(65001),
+            Some(SyncResume {
+                table_version: 3,
+                next_chunk: 2
+            })
+        );
+
+        let (routes, chunk) = pages.chunk(2);
+        reopened.admit(65001, &chunk).unwrap();
+        let progress = reopened.applied(65001, &chunk, &routes).unwrap();
+        assert_eq!(
+            progress,
+            SyncProgress {
+                received: 3,
+                total: 3,
+                complete: true
+            }
+        );
+        assert_eq!(reopened.resume_point(65001), None);
+
+        // A tampered chunk fails the digest
+        let tampered = TablePages::new(sample(9), 4, 9).unwrap();
+        let (mut routes, chunk) = tampered.chunk(0);
+        routes.pop();
+        reopened.admit(65002, &chunk).unwrap();
+        assert!(reopened.applied(65002, &chunk, &routes).is_err());
+        assert_eq!(reopened.progress(65002), None);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,20 @@
+//! Route fixtures shared by the unit tests.
+
+use super::{AsPath, BGPOrigin, RouteEntry};
+
+/// A route to `network` through `next_hop` along `as_path`, with default
+/// attributes, as a peer would announce it
+pub(crate) fn route(network: &str, next_hop: &str, as_path: &[u32]) -> RouteEntry {
+    RouteEntry {
+        network: network.parse().unwrap(),
+        next_hop: next_hop.parse().unwrap(),
+        as_path: AsPath::new(as_path),
+        origin: BGPOrigin::IGP,
+        local_pref: 100,
+        med: 0,
+        communities: vec![],
+        timestamp: chrono::Utc::now(),
+        learned_from: None,
+        originated_at: None,
+    }
+}
@@ -0,0 +1,154 @@
+//! Sessions that exchange routes only inside an encrypted tunnel.
+//!
+//! With `network.bgp.require_tunnel`, a session exchanges OPENs and then
+//! stays in OpenSent until a tunnel to the peer is established; each end
+//! says in its OPEN that it requires one, so a peer left waiting can log
+//! why. If both ends also set `bgp_over_tunnel`, every message after the
+//! OPENs is sealed by that tunnel through a [`TunnelChannel`].
+//!
+//! An established session whose tunnel fails is suspended: the peer's
+//! routes are held, stale, until a tunnel comes back, or flushed once the
+//! suspension outlasts `tunnel_suspend_timeout_secs`.
+//!
+//! A peer that reconnects soon after a session ended may skip the wait by
+//! resuming: see [`crate::network::ike::resumption`].
+
+use crate::network::bgp::protocol::BGPStream;
+use crate::network::bgp::BGPError;
+use crate::network::ike::channel::TunnelChannel;
+use crate::network::ike::resumption::{self, PeerIdentity, ResumptionCache, Transcript};
+use crate::network::ike::tunnels::{TunnelId, TunnelManager};
+use crate::node::capabilities::Capabilities;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Used unless configured otherwise
+pub const DEFAULT_SUSPEND_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct TunnelGate {
+    tunnels: Arc<TunnelManager>,
+    over_tunnel: bool,
+    suspend_timeout: Duration,
+}
+
+impl TunnelGate {
+    pub fn new(tunnels: Arc<TunnelManager>) -> Self {
+        TunnelGate {
+            tunnels,
+            over_tunnel: false,
+            suspend_timeout: DEFAULT_SUSPEND_TIMEOUT,
+        }
+    }
+
+    /// Seal messages after the OPENs with the tunnel, if the peer does too
+    pub fn with_bgp_over_tunnel(mut self, enabled: bool) -> Self {
+        self.over_tunnel = enabled;
+        self
+    }
+
+    /// Flush a suspended session's routes after this long
+    pub fn with_suspend_timeout(mut self, timeout: Duration) -> Self {
+        self.suspend_timeout = timeout;
+        self
+    }
+
+    pub fn suspend_timeout(&self) -> Duration {
+        self.suspend_timeout
+    }
+
+    pub fn resumption(&self) -> Option<&ResumptionCache> {
+        self.tunnels.resumption()
+    }
+
+    /// Install the tunnel to `peer` resumed from a ticket's secret, so
+    /// `admit` lets the session through at once
+    pub async fn resume(
+        &self,
+        local: IpAddr,
+        peer: SocketAddr,
+        secret: &[u8],
+        initiator_nonce: &[u8],
+        responder_nonce: &[u8],
+    ) -> Result<TunnelId, BGPError> {
+        let shared_secret = resumption::tunnel_secret(secret, initiator_nonce, responder_nonce);
+        self.tunnels
+            .resume_tunnel(local, peer.ip(), peer, shared_secret)
+            .await
+            .map_err(BGPError::Tunnel)
+    }
+
+    /// Derive the ticket a session just admitted can later resume with
+    pub async fn keep_ticket(
+        &self,
+        peer: IpAddr,
+        transcript: &Transcript<'_>,
+        theirs: PeerIdentity,
+    ) {
+        if !self.tunnels.keep_ticket(peer, transcript, theirs).await {
+            tracing::debug!("No tunnel to {} to derive a resumption ticket from", peer);
+        }
+    }
+
+    /// Let a peer whose routes were flushed resume no more
+    pub fn forget(&self, peer_asn: u32) {
+        if let Some(resumption) = self.resumption() {
+            resumption.forget(peer_asn);
+        }
+    }
+
+    /// Wait in OpenSent for a tunnel to `peer`, giving up after `hold`
+    pub async fn admit(&self, peer: SocketAddr, hold: Duration) -> Result<TunnelId, BGPError> {
+        if let Some(tunnel_id) = self.tunnels.tunnel_to(peer.ip()).await {
+            return Ok(tunnel_id);
+        }
+        tracing::info!(
+            "Holding BGP session with {} until a tunnel to it is established",
+            peer
+        );
+        tokio::time::timeout(hold, self.tunnels.wait_established(peer.ip()))
+            .await
+            .map_err(|_| BGPError::NoTunnel { peer, hold })
+    }
+
+    /// The stream to carry the rest of the session on: sealed by the
+    /// tunnel if both ends asked for that, otherwise as it is
+    pub fn stream(&self, stream: TcpStream, peer: IpAddr, theirs: &Capabilities) -> BGPStream {
+        if self.over_tunnel && theirs.bgp_over_tunnel {
+            BGPStream::tunneled(TunnelChannel::new(stream, Arc::clone(&self.tunnels), peer))
+        } else {
+            BGPStream::plain(stream)
+        }
+    }
+
+    /// Wait until no tunnel to `peer` is left
+    pub async fn lost(&self, peer: IpAddr) {
+        self.tunnels.wait_lost(peer).await
+    }
+
+    /// Wait for a tunnel to `peer` to be established, up to the suspend
+    /// timeout; returns whether one was
+    pub async fn recovered(&self, peer: IpAddr) -> bool {
+        tokio::time::timeout(self.suspend_timeout, self.tunnels.wait_established(peer))
+            .await
+            .is_ok()
+    }
+
+    /// Hold an UPDATE from `peer` until a tunnel to it is established
+    pub async fn hold_update(&self, peer: IpAddr) {
+        self.tunnels.wait_established(peer).await;
+    }
+}
+
+/// Say why a session may stall if the peer requires a tunnel we will not
+/// wait for
+pub fn note_requirement(peer: SocketAddr, theirs: &Capabilities, gated: bool) {
+    if theirs.requires_tunnel && !gated {
+        tracing::info!(
+            "BGP peer {} exchanges routes only once a tunnel to it is established",
+            peer
+        );
+    }
+}
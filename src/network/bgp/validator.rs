@@ -0,0 +1,418 @@
+//! Route acceptance decided by an external system.
+//!
+//! Operators can have newly learned routes checked by something this crate
+//! does not know about, such as an RPKI validator or a shared hijack
+//! blocklist, configured under `network.bgp.validator`. Routes are checked
+//! in batches, one per UPDATE, before they reach the Adj-RIB-In. A rejected
+//! route is dropped like one refused by import policy, and the journal
+//! records the validator's reason for `vx0net routes explain`.
+//!
+//! # Interface, version 1
+//!
+//! The validator is given one JSON request per batch: on stdin for an
+//! executable, which is run once per batch and must exit 0, or as the body
+//! of a `POST` to an `http://` URL, which must answer `200`:
+//!
+//! ```json
+//! {"version": 1, "peer_asn": 65001, "routes": [
+//!     {"prefix": "10.1.0.0/16", "origin_asn": 65001, "route": {...}}
+//! ]}
+//! ```
+//!
+//! `route` is the full route as `vx0net routes` shows it. The validator
+//! answers, on stdout or in the response body, with a verdict per prefix
+//! and origin AS:
+//!
+//! ```json
+//! {"version": 1, "verdicts": [
+//!     {"prefix": "10.1.0.0/16", "origin_asn": 65001, "accept": false,
+//!      "reason": "origin not authorised"}
+//! ]}
+//! ```
+//!
+//! A verdict is reused for `cache_ttl_secs`. Routes the validator could not
+//! judge, because it failed, timed out, answered with another version or
+//! left them out, are accepted or rejected per `on_failure` and asked about
+//! again with the next batch. `scripts/reference-validator.py` implements
+//! both transports.
+
+use crate::config::{ValidatorConfig, ValidatorFailure};
+use crate::network::bgp::policy::PolicyVerdict;
+use crate::network::bgp::{BGPError, Prefix, RouteEntry};
+use crate::util::sync::lock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+/// Version of the request and verdict format
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Largest answer read from a validator
+const MAX_RESPONSE_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Rule name journal entries for the validator's rejections carry
+pub const RULE: &str = "validator";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ValidatorError {
+    #[error("Cannot run validator")]
+    Spawn(#[source] std::io::Error),
+    #[error("Validator exited with {status}: {stderr}")]
+    Exit { status: String, stderr: String },
+    #[error("Validator did not answer within {0:?}")]
+    Timeout(Duration),
+    #[error("Cannot reach validator at {url}")]
+    Unreachable {
+        url: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Validator answered HTTP {status}")]
+    Status { status: String },
+    #[error("Validator answered in version {got}, expected {PROTOCOL_VERSION}")]
+    Version { got: u32 },
+    #[error("Malformed answer from validator")]
+    Malformed(#[source] serde_json::Error),
+}
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    version: u32,
+    peer_asn: u32,
+    routes: Vec<Candidate<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Candidate<'a> {
+    prefix: Prefix,
+    origin_asn: u32,
+    route: &'a RouteEntry,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    version: u32,
+    verdicts: Vec<Verdict>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Verdict {
+    prefix: Prefix,
+    origin_asn: u32,
+    accept: bool,
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Debug, Clone)]
+enum Transport {
+    Exec {
+        program: String,
+        args: Vec<String>,
+    },
+    Http {
+        addr: String,
+        host: String,
+        path: String,
+        url: String,
+    },
+}
+
+type CacheKey = (Prefix, u32);
+
+/// Routes of one batch the validator accepted, and those it refused
+#[derive(Debug, Default)]
+pub struct Screened {
+    pub accepted: Vec<RouteEntry>,
+    pub refused: Vec<(Prefix, PolicyVerdict)>,
+}
+
+#[derive(Debug)]
+pub struct RouteValidator {
+    transport: Transport,
+    timeout: Duration,
+    on_failure: ValidatorFailure,
+    ttl: Duration,
+    cache: Mutex<HashMap<CacheKey, (Verdict, Instant)>>,
+}
+
+impl RouteValidator {
+    /// Exactly one of `command` and `url` must be set
+    pub fn from_config(config: &ValidatorConfig) -> Result<Self, BGPError> {
+        let transport = match (config.command.split_first(), &config.url) {
+            (Some((program, args)), None) => Transport::Exec {
+                program: program.clone(),
+                args: args.to_vec(),
+            },
+            (None, Some(url)) => http_transport(url)?,
+            _ => {
+                return Err(BGPError::Configuration(
+                    "network.bgp.validator needs either command or url".to_string(),
+                ))
+            }
+        };
+        Ok(RouteValidator {
+            transport,
+            timeout: Duration::from_millis(config.timeout_ms),
+            on_failure: config.on_failure,
+            ttl: Duration::from_secs(config.cache_ttl_secs),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Split a batch learned from `peer_asn` by the validator's verdicts,
+    /// asking it only about prefixes and origins without a cached verdict
+    pub async fn screen(&self, peer_asn: u32, routes: Vec<RouteEntry>) -> Screened {
+        let now = Instant::now();
+        let mut known: HashMap<CacheKey, Verdict> = HashMap::new();
+        let mut unknown: Vec<&RouteEntry> = Vec::new();
+        {
+            let mut cache = lock(&self.cache);
+            cache.retain(|_, (_, at)| now.duration_since(*at) < self.ttl);
+            for route in &routes {
+                let key = (route.network, origin(route, peer_asn));
+                match cache.get(&key) {
+                    Some((verdict, _)) => {
+                        known.insert(key, verdict.clone());
+                    }
+                    None => unknown.push(route),
+                }
+            }
+        }
+
+        let mut failure = None;
+        if !unknown.is_empty() {
+            match self.ask(peer_asn, &unknown).await {
+                Ok(verdicts) => {
+                    let mut cache = lock(&self.cache);
+                    for verdict in verdicts {
+                        let key = (verdict.prefix, verdict.origin_asn);
+                        cache.insert(key, (verdict.clone(), now));
+                        known.insert(key, verdict);
+                    }
+                }
+                Err(e) => {
+                    crate::sampled!(tracing::warn!(
+                        "Route validator failed for {} routes from AS{}, failing {}: {}",
+                        unknown.len(),
+                        peer_asn,
+                        match self.on_failure {
+                            ValidatorFailure::Open => "open",
+                            ValidatorFailure::Closed => "closed",
+                        },
+                        crate::error::Report(&e)
+                    ));
+                    failure = Some(e.to_string());
+                }
+            }
+        }
+
+        let mut screened = Screened::default();
+        for route in routes {
+            let network = route.network;
+            match known.get(&(network, origin(&route, peer_asn))) {
+                Some(verdict) if verdict.accept => screened.accepted.push(route),
+                Some(verdict) => screened
+                    .refused
+                    .push((network, PolicyVerdict::deny(RULE, verdict.reason.clone()))),
+                None if self.on_failure == ValidatorFailure::Open => screened.accepted.push(route),
+                None => screened.refused.push((
+                    network,
+                    PolicyVerdict::deny(
+                        RULE,
+                        format!(
+                            "no verdict: {}",
+                            failure.as_deref().unwrap_or("route left out of the answer")
+                        ),
+                    ),
+                )),
+            }
+        }
+        screened
+    }
+
+    /// Cached verdicts, expired or not
+    pub fn cached(&self) -> usize {
+        lock(&self.cache).len()
+    }
+
+    async fn ask(
+        &self,
+        peer_asn: u32,
+        routes: &[&RouteEntry],
+    ) -> Result<Vec<Verdict>, ValidatorError> {
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            peer_asn,
+            routes: routes
+                .iter()
+                .map(|route| Candidate {
+                    prefix: route.network,
+                    origin_asn: origin(route, peer_asn),
+                    route,
+                })
+                .collect(),
+        };
+        let body = serde_json::to_vec(&request).map_err(ValidatorError::Malformed)?;
+        let answer = tokio::time::timeout(self.timeout, self.send(&body))
+            .await
+            .map_err(|_| ValidatorError::Timeout(self.timeout))??;
+        let response: Response =
+            serde_json::from_slice(&answer).map_err(ValidatorError::Malformed)?;
+        if response.version != PROTOCOL_VERSION {
+            return Err(ValidatorError::Version {
+                got: response.version,
+            });
+        }
+        Ok(response.verdicts)
+    }
+
+    async fn send(&self, body: &[u8]) -> Result<Vec<u8>, ValidatorError> {
+        match &self.transport {
+            Transport::Exec { program, args } => {
+                let mut child = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    // Killed if the batch times out
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(ValidatorError::Spawn)?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(body).await.map_err(ValidatorError::Spawn)?;
+                }
+                let output = child
+                    .wait_with_output()
+                    .await
+                    .map_err(ValidatorError::Spawn)?;
+                if !output.status.success() {
+                    return Err(ValidatorError::Exit {
+                        status: output.status.to_string(),
+                        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                    });
+                }
+                Ok(output.stdout)
+            }
+            Transport::Http {
+                addr,
+                host,
+                path,
+                url,
+            } => {
+                let unreachable = |source| ValidatorError::Unreachable {
+                    url: url.clone(),
+                    source,
+                };
+                let mut stream = TcpStream::connect(addr).await.map_err(unreachable)?;
+                let head = format!(
+                    "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    path,
+                    host,
+                    body.len()
+                );
+                stream
+                    .write_all(head.as_bytes())
+                    .await
+                    .map_err(unreachable)?;
+                stream.write_all(body).await.map_err(unreachable)?;
+                let mut answer = Vec::new();
+                stream
+                    .take(MAX_RESPONSE_BYTES)
+                    .read_to_end(&mut answer)
+                    .await
+                    .map_err(unreachable)?;
+
+                let split = answer
+                    .windows(4)
+                    .position(|window| window == b"\r\n\r\n")
+                    .unwrap_or(answer.len());
+                let head = String::from_utf8_lossy(&answer[..split]);
+                // "HTTP/1.0 200 OK"
+                let status = head
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or_default()
+                    .to_string();
+                if status != "200" {
+                    return Err(ValidatorError::Status { status });
+                }
+                Ok(answer[(split + 4).min(answer.len())..].to_vec())
+            }
+        }
+    }
+}
+
+/// The AS a route originates from, the peer's own if the path is empty
+fn origin(route: &RouteEntry, peer_asn: u32) -> u32 {
+    route.as_path.last().copied().unwrap_or(peer_asn)
+}
+
+/// `http://host[:port][/path]`; TLS is not supported, so the validator
+/// should run on this host or a trusted network
+fn http_transport(url: &str) -> Result<Transport, BGPError> {
+    let invalid =
+        || BGPError::Configuration(format!("validator url {:?} is not an http:// URL", url));
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(invalid());
+    }
+    let addr = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+    {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok(Transport::Http {
+        addr,
+        host: authority.to_string(),
+        path: path.to_string(),
+        url: url.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_needs_one_transport() {
+        let config = |command: &[&str], url: Option<&str>| ValidatorConfig {
+            command: command.iter().map(|arg| arg.to_string()).collect(),
+            url: url.map(str::to_string),
+            ..ValidatorConfig::default()
+        };
+        assert!(RouteValidator::from_config(&config(&[], None)).is_err());
+        assert!(RouteValidator::from_config(&config(&["check"], Some("http://v/"))).is_err());
+        assert!(RouteValidator::from_config(&config(&[], Some("https://v/"))).is_err());
+
+        let exec = RouteValidator::from_config(&config(&["check", "--strict"], None)).unwrap();
+        assert!(matches!(
+            exec.transport,
+            Transport::Exec { ref program, ref args } if program == "check" && args == &["--strict"]
+        ));
+        let http =
+            RouteValidator::from_config(&config(&[], Some("http://127.0.0.1:8323/v1"))).unwrap();
+        assert!(matches!(
+            http.transport,
+            Transport::Http { ref addr, ref path, .. } if addr == "127.0.0.1:8323" && path == "/v1"
+        ));
+        let default_port =
+            RouteValidator::from_config(&config(&[], Some("http://validator"))).unwrap();
+        assert!(matches!(
+            default_port.transport,
+            Transport::Http { ref addr, ref path, .. } if addr == "validator:80" && path == "/"
+        ));
+    }
+}
@@ -0,0 +1,2179 @@
+//! RFC 4271 binary encoding for BGP messages.
+//!
+//! Used by sessions configured with `wire = "rfc4271"` so a VX0 node can
+//! exchange routes with ordinary BGP speakers. Only IPv4 unicast NLRI is
+//! supported; 4-octet AS numbers are negotiated through the RFC 6793
+//! capability and carried in AS_PATH accordingly.
+//!
+//! VX0 sessions frame their messages with the same 19-byte header, through
+//! [`write_frame`] and [`read_frame`], up to RFC 8654's extended message
+//! size. Once both OPENs agree on it, their bodies are RFC 4271 messages
+//! too, built by [`encode_vx0`]: resumption, table sync and the other VX0
+//! extensions ride in a private-use OPEN capability and in optional path
+//! attributes. Nodes that predate that exchange JSON bodies instead.
+
+use crate::config::HostBitsPolicy;
+use crate::network::bgp::messages::{
+    AttributeValue, BGPMessage, NotificationMessage, OpenMessage, OptionalParameter, PathAttribute,
+    UpdateMessage, BGP_ATTR_AS_PATH, BGP_ATTR_COMMUNITIES, BGP_ATTR_LOCAL_PREF,
+    BGP_ATTR_MULTI_EXIT_DISC, BGP_ATTR_NEXT_HOP, BGP_ATTR_ORIGIN, BGP_ERROR_CEASE,
+    BGP_ERROR_MESSAGE_HEADER, BGP_ERROR_OPEN_MESSAGE, BGP_ERROR_UPDATE_MESSAGE,
+};
+use crate::network::bgp::protocol::{self, BGPMessageType, BGPRoute};
+use crate::network::bgp::table_sync::{SyncResume, TableChunk};
+use crate::network::bgp::{AsPath, BGPError, BGPOrigin, Community, Prefix, RouteEntry};
+use crate::network::ike::resumption::{PeerIdentity, ResumeAccepted, ResumeHello, ResumeOffer};
+use crate::network::transport::{self, Codec, MessageTag, TransportConfig};
+use crate::node::capabilities::Capabilities;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const BGP_MARKER: [u8; 16] = [0xff; 16];
+pub const BGP_HEADER_LEN: usize = 19;
+pub const BGP_MAX_MESSAGE_LEN: usize = 4096;
+/// Largest message RFC 8654 allows once extended messages are agreed;
+/// VX0 sessions always use it
+pub const BGP_MAX_EXTENDED_MESSAGE_LEN: usize = 65535;
+
+// Message types
+pub const BGP_MSG_OPEN: u8 = 1;
+pub const BGP_MSG_UPDATE: u8 = 2;
+pub const BGP_MSG_NOTIFICATION: u8 = 3;
+pub const BGP_MSG_KEEPALIVE: u8 = 4;
+
+// Optional parameter and capability codes
+pub const BGP_OPT_PARAM_CAPABILITY: u8 = 2;
+pub const BGP_CAP_MULTIPROTOCOL: u8 = 1;
+pub const BGP_CAP_FOUR_OCTET_AS: u8 = 65;
+
+/// Placeholder ASN used in the 2-octet OPEN field when our ASN does not fit
+pub const AS_TRANS: u16 = 23456;
+
+/// Capability for the VX0 extensions in a binary VX0 OPEN, from the range
+/// RFC 5492 leaves for private use
+pub const BGP_CAP_VX0: u8 = 0xf0;
+
+/// RFC 4760's MP_UNREACH_NLRI, which withdraws IPv6 prefixes
+pub const BGP_ATTR_MP_UNREACH_NLRI: u8 = 15;
+// Optional attributes that only VX0 nodes send each other, once both ends
+// agreed on binary messages. Routes go in VX0_ROUTES rather than the NLRI
+// field, since each has its own attributes and may be IPv6.
+pub const BGP_ATTR_VX0_ROUTES: u8 = 0xf0;
+pub const BGP_ATTR_VX0_EXTENSIONS: u8 = 0xf1;
+/// An IPv6 next hop, inside a VX0 route
+pub const BGP_ATTR_VX0_NEXT_HOP: u8 = 0xf2;
+pub const BGP_ATTR_VX0_ORIGINATED_AT: u8 = 0xf3;
+
+const AFI_IPV4: u16 = 1;
+const AFI_IPV6: u16 = 2;
+const SAFI_UNICAST: u8 = 1;
+
+/// The hold time a binary VX0 OPEN states, which is what `BGPSession`
+/// holds every VX0 session to
+const VX0_HOLD_TIME: u16 = 90;
+
+// VX0 extension types, in the VX0 capability and VX0_EXTENSIONS
+const EXT_TIMESTAMP: u8 = 1;
+const EXT_CAPABILITIES: u8 = 2;
+const EXT_TUNNEL_MTU: u8 = 3;
+const EXT_RESUME_NONCE: u8 = 4;
+const EXT_RESUME_OFFER: u8 = 5;
+const EXT_RESUME_ACCEPTED: u8 = 6;
+const EXT_OBSERVED_ADDR: u8 = 7;
+const EXT_SYNC_RESUME: u8 = 8;
+const EXT_TABLE_VERSION: u8 = 9;
+const EXT_CHUNK: u8 = 10;
+const EXT_CHUNK_ACK: u8 = 11;
+
+// AS_PATH segment types
+const AS_SET: u8 = 1;
+const AS_SEQUENCE: u8 = 2;
+
+// Attribute flags
+const ATTR_FLAG_OPTIONAL: u8 = 0x80;
+const ATTR_FLAG_TRANSITIVE: u8 = 0x40;
+const ATTR_FLAG_EXTENDED_LENGTH: u8 = 0x10;
+
+// Error subcodes (RFC 4271 section 6)
+pub const HEADER_CONNECTION_NOT_SYNCHRONIZED: u8 = 1;
+pub const HEADER_BAD_MESSAGE_LENGTH: u8 = 2;
+pub const HEADER_BAD_MESSAGE_TYPE: u8 = 3;
+pub const OPEN_UNSUPPORTED_VERSION: u8 = 1;
+pub const OPEN_BAD_PEER_AS: u8 = 2;
+pub const OPEN_BAD_BGP_IDENTIFIER: u8 = 3;
+pub const UPDATE_MALFORMED_ATTRIBUTE_LIST: u8 = 1;
+pub const UPDATE_MISSING_WELL_KNOWN_ATTRIBUTE: u8 = 3;
+pub const UPDATE_ATTRIBUTE_LENGTH_ERROR: u8 = 5;
+pub const UPDATE_INVALID_ORIGIN: u8 = 6;
+pub const UPDATE_INVALID_NEXT_HOP: u8 = 8;
+pub const UPDATE_OPTIONAL_ATTRIBUTE_ERROR: u8 = 9;
+pub const UPDATE_INVALID_NETWORK_FIELD: u8 = 10;
+pub const UPDATE_MALFORMED_AS_PATH: u8 = 11;
+
+fn malformed(code: u8, subcode: u8, reason: impl Into<String>) -> BGPError {
+    BGPError::Protocol {
+        code,
+        subcode,
+        reason: reason.into(),
+    }
+}
+
+/// Bounds-checked cursor over a received message body
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    code: u8,
+    subcode: u8,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], code: u8, subcode: u8) -> Self {
+        Reader {
+            buf,
+            pos: 0,
+            code,
+            subcode,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BGPError> {
+        if self.remaining() < len {
+            return Err(malformed(
+                self.code,
+                self.subcode,
+                format!("truncated: needed {} bytes, {} left", len, self.remaining()),
+            ));
+        }
+        let slice = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, BGPError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BGPError> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, BGPError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, BGPError> {
+        let b = self.take(8)?;
+        Ok(u64::from_be_bytes([
+            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        ]))
+    }
+
+    /// Whatever is left
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+}
+
+/// Encode a message including the 19-byte header
+pub fn encode(msg: &BGPMessage) -> Result<Vec<u8>, BGPError> {
+    let (msg_type, body) = match msg {
+        BGPMessage::Open(open) => (BGP_MSG_OPEN, encode_open(open)?),
+        BGPMessage::Update(update) => (BGP_MSG_UPDATE, encode_update(update)?),
+        BGPMessage::Notification(n) => (BGP_MSG_NOTIFICATION, encode_notification(n)),
+        BGPMessage::Keepalive => (BGP_MSG_KEEPALIVE, Vec::new()),
+    };
+
+    encode_frame(msg_type, &body, BGP_MAX_MESSAGE_LEN)
+}
+
+/// Put the 19-byte header for `msg_type` in front of `body`, refusing
+/// messages longer than `max_len`
+pub fn encode_frame(msg_type: u8, body: &[u8], max_len: usize) -> Result<Vec<u8>, BGPError> {
+    Ok(transport::encode_frame(
+        Codec::Bgp,
+        MessageTag::Bgp,
+        &payload(msg_type, body),
+        &TransportConfig::default().with_max_frame_len(max_len),
+    )?)
+}
+
+/// What the transport carries of a message: its type, then its body
+fn payload(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + body.len());
+    payload.push(msg_type);
+    payload.extend_from_slice(body);
+    payload
+}
+
+/// A VX0 session message's (type, body) from what the transport carried;
+/// the type must be one of RFC 4271's four. Bodies are checked by whoever
+/// decodes them, so the RFC's per-type lengths do not apply.
+pub fn split_payload(mut payload: Vec<u8>) -> Result<(u8, Vec<u8>), BGPError> {
+    let msg_type = payload.first().copied().unwrap_or_default();
+    if !(BGP_MSG_OPEN..=BGP_MSG_KEEPALIVE).contains(&msg_type) {
+        return Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_BAD_MESSAGE_TYPE,
+            format!("unknown message type {}", msg_type),
+        ));
+    }
+    payload.remove(0);
+    Ok((msg_type, payload))
+}
+
+/// Decode a complete message including its header
+pub fn decode(data: &[u8]) -> Result<BGPMessage, BGPError> {
+    let (msg_type, length) = decode_header(data)?;
+    if data.len() != length {
+        return Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_BAD_MESSAGE_LENGTH,
+            format!("header says {} bytes, got {}", length, data.len()),
+        ));
+    }
+    decode_body(msg_type, &data[BGP_HEADER_LEN..])
+}
+
+/// The marker checked, a header's (type, total length) as it states them
+fn split_header(data: &[u8]) -> Result<(u8, usize), BGPError> {
+    if data.len() < BGP_HEADER_LEN {
+        return Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_BAD_MESSAGE_LENGTH,
+            "message shorter than header",
+        ));
+    }
+    if data[..16] != BGP_MARKER {
+        return Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_CONNECTION_NOT_SYNCHRONIZED,
+            "invalid marker",
+        ));
+    }
+    Ok((data[18], u16::from_be_bytes([data[16], data[17]]) as usize))
+}
+
+/// Validate a header and return (type, total length)
+pub fn decode_header(data: &[u8]) -> Result<(u8, usize), BGPError> {
+    let (msg_type, length) = split_header(data)?;
+
+    let min_len = match msg_type {
+        BGP_MSG_OPEN => 29,
+        BGP_MSG_UPDATE => 23,
+        BGP_MSG_NOTIFICATION => 21,
+        BGP_MSG_KEEPALIVE => BGP_HEADER_LEN,
+        other => {
+            return Err(malformed(
+                BGP_ERROR_MESSAGE_HEADER,
+                HEADER_BAD_MESSAGE_TYPE,
+                format!("unknown message type {}", other),
+            ))
+        }
+    };
+
+    if length < min_len
+        || length > BGP_MAX_MESSAGE_LEN
+        || (msg_type == BGP_MSG_KEEPALIVE && length != BGP_HEADER_LEN)
+    {
+        return Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_BAD_MESSAGE_LENGTH,
+            format!("bad length {} for message type {}", length, msg_type),
+        ));
+    }
+
+    Ok((msg_type, length))
+}
+
+/// Split a complete VX0 session message into (type, body)
+pub fn decode_frame(data: &[u8], max_len: usize) -> Result<(u8, Vec<u8>), BGPError> {
+    let config = TransportConfig::default().with_max_frame_len(max_len);
+    let frame = transport::decode_frame(Codec::Bgp, MessageTag::Bgp, data, &config)?;
+    split_payload(frame.payload)
+}
+
+/// Write one VX0 session message: `body` behind the header for `msg_type`
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg_type: u8,
+    body: &[u8],
+    max_len: usize,
+    io_timeout: Option<Duration>,
+) -> Result<(), BGPError> {
+    let config = TransportConfig::default()
+        .with_max_frame_len(max_len)
+        .with_io_timeout(io_timeout);
+    let payload = payload(msg_type, body);
+    Ok(transport::write_frame(writer, Codec::Bgp, MessageTag::Bgp, &payload, &config).await?)
+}
+
+/// Read one VX0 session message as (type, body), or `None` if the stream
+/// ended before it started. Waiting for a message is not timed; once its
+/// first byte arrived, the rest must follow within `io_timeout`.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    io_timeout: Option<Duration>,
+) -> Result<Option<(u8, Vec<u8>)>, BGPError> {
+    let config = TransportConfig::default()
+        .with_max_frame_len(max_len)
+        .with_io_timeout(io_timeout);
+    match transport::read_frame(reader, Codec::Bgp, MessageTag::Bgp, &config).await? {
+        Some(frame) => split_payload(frame.payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn decode_body(msg_type: u8, body: &[u8]) -> Result<BGPMessage, BGPError> {
+    match msg_type {
+        BGP_MSG_OPEN => decode_open(body).map(BGPMessage::Open),
+        BGP_MSG_UPDATE => decode_update(body).map(BGPMessage::Update),
+        BGP_MSG_NOTIFICATION => Ok(BGPMessage::Notification(NotificationMessage {
+            error_code: body[0],
+            error_subcode: body[1],
+            data: body[2..].to_vec(),
+        })),
+        _ => Ok(BGPMessage::Keepalive),
+    }
+}
+
+/// Read one message from a stream, validating the header before the body
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<BGPMessage, BGPError> {
+    let mut header = [0u8; BGP_HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let (msg_type, length) = decode_header(&header)?;
+
+    let mut body = vec![0u8; length - BGP_HEADER_LEN];
+    reader.read_exact(&mut body).await?;
+    decode_body(msg_type, &body)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &BGPMessage,
+) -> Result<(), BGPError> {
+    let data = encode(msg)?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+fn ipv4_identifier(addr: &IpAddr) -> Result<Ipv4Addr, BGPError> {
+    match addr {
+        IpAddr::V4(v4) => Ok(*v4),
+        IpAddr::V6(_) => Err(BGPError::Configuration(
+            "RFC 4271 BGP identifier must be an IPv4 address".to_string(),
+        )),
+    }
+}
+
+fn encode_open(open: &OpenMessage) -> Result<Vec<u8>, BGPError> {
+    let mut body = Vec::new();
+    body.push(open.version);
+    let short_asn = u16::try_from(open.my_asn).unwrap_or(AS_TRANS);
+    body.extend_from_slice(&short_asn.to_be_bytes());
+    body.extend_from_slice(&open.hold_time.to_be_bytes());
+    body.extend_from_slice(&ipv4_identifier(&open.bgp_identifier)?.octets());
+
+    let mut params = Vec::new();
+    for param in &open.optional_parameters {
+        if param.parameter_value.len() > u8::MAX as usize {
+            return Err(malformed(
+                BGP_ERROR_OPEN_MESSAGE,
+                0,
+                "optional parameter too long",
+            ));
+        }
+        params.push(param.parameter_type);
+        params.push(param.parameter_value.len() as u8);
+        params.extend_from_slice(&param.parameter_value);
+    }
+    if params.len() > u8::MAX as usize {
+        return Err(malformed(
+            BGP_ERROR_OPEN_MESSAGE,
+            0,
+            "optional parameters too long",
+        ));
+    }
+    body.push(params.len() as u8);
+    body.extend_from_slice(&params);
+    Ok(body)
+}
+
+fn decode_open(body: &[u8]) -> Result<OpenMessage, BGPError> {
+    let mut r = Reader::new(body, BGP_ERROR_OPEN_MESSAGE, 0);
+    let version = r.u8()?;
+    if version != 4 {
+        return Err(malformed(
+            BGP_ERROR_OPEN_MESSAGE,
+            OPEN_UNSUPPORTED_VERSION,
+            format!("unsupported BGP version {}", version),
+        ));
+    }
+    let short_asn = r.u16()?;
+    let hold_time = r.u16()?;
+    if hold_time == 1 || hold_time == 2 {
+        return Err(malformed(
+            BGP_ERROR_OPEN_MESSAGE,
+            6, // Unacceptable Hold Time
+            format!("unacceptable hold time {}", hold_time),
+        ));
+    }
+    let identifier = Ipv4Addr::from(r.u32()?);
+    if identifier.is_unspecified() {
+        return Err(malformed(
+            BGP_ERROR_OPEN_MESSAGE,
+            OPEN_BAD_BGP_IDENTIFIER,
+            "BGP identifier is 0.0.0.0",
+        ));
+    }
+
+    let params_len = r.u8()? as usize;
+    if params_len != r.remaining() {
+        return Err(malformed(
+            BGP_ERROR_OPEN_MESSAGE,
+            0,
+            "optional parameter length mismatch",
+        ));
+    }
+
+    let mut optional_parameters = Vec::new();
+    while r.remaining() > 0 {
+        let parameter_type = r.u8()?;
+        let parameter_length = r.u8()?;
+        let parameter_value = r.take(parameter_length as usize)?.to_vec();
+        optional_parameters.push(OptionalParameter {
+            parameter_type,
+            parameter_length,
+            parameter_value,
+        });
+    }
+
+    let my_asn = four_octet_asn(&optional_parameters)?.unwrap_or(short_asn as u32);
+
+    Ok(OpenMessage {
+        version,
+        my_asn,
+        hold_time,
+        bgp_identifier: IpAddr::V4(identifier),
+        optional_parameters,
+    })
+}
+
+/// Find the RFC 6793 capability among the OPEN optional parameters
+fn four_octet_asn(params: &[OptionalParameter]) -> Result<Option<u32>, BGPError> {
+    for param in params {
+        if param.parameter_type != BGP_OPT_PARAM_CAPABILITY {
+            continue;
+        }
+        let mut r = Reader::new(&param.parameter_value, BGP_ERROR_OPEN_MESSAGE, 0);
+        while r.remaining() > 0 {
+            let code = r.u8()?;
+            let len = r.u8()? as usize;
+            let value = r.take(len)?;
+            if code == BGP_CAP_FOUR_OCTET_AS {
+                if len != 4 {
+                    return Err(malformed(
+                        BGP_ERROR_OPEN_MESSAGE,
+                        OPEN_BAD_PEER_AS,
+                        "4-octet AS capability has wrong length",
+                    ));
+                }
+                return Ok(Some(u32::from_be_bytes([
+                    value[0], value[1], value[2], value[3],
+                ])));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn encode_notification(n: &NotificationMessage) -> Vec<u8> {
+    let mut body = vec![n.error_code, n.error_subcode];
+    body.extend_from_slice(&n.data);
+    body
+}
+
+fn encode_prefix(out: &mut Vec<u8>, prefix: &IpNet) -> Result<(), BGPError> {
+    match prefix {
+        IpNet::V4(net) => {
+            let len = net.prefix_len();
+            out.push(len);
+            let octets = net.network().octets();
+            out.extend_from_slice(&octets[..(len as usize).div_ceil(8)]);
+            Ok(())
+        }
+        IpNet::V6(_) => Err(BGPError::Route(format!(
+            "{} is not IPv4 unicast; only IPv4 NLRI is supported on RFC 4271 sessions",
+            prefix
+        ))),
+    }
+}
+
+fn decode_prefixes(data: &[u8]) -> Result<Vec<IpNet>, BGPError> {
+    let mut r = Reader::new(data, BGP_ERROR_UPDATE_MESSAGE, UPDATE_INVALID_NETWORK_FIELD);
+    let mut prefixes = Vec::new();
+    while r.remaining() > 0 {
+        let len = r.u8()?;
+        if len > 32 {
+            return Err(malformed(
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_INVALID_NETWORK_FIELD,
+                format!("prefix length {} exceeds 32", len),
+            ));
+        }
+        let bytes = r.take((len as usize).div_ceil(8))?;
+        let mut octets = [0u8; 4];
+        octets[..bytes.len()].copy_from_slice(bytes);
+        let net = Ipv4Net::new(Ipv4Addr::from(octets), len).map_err(|e| {
+            malformed(
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_INVALID_NETWORK_FIELD,
+                e.to_string(),
+            )
+        })?;
+        prefixes.push(IpNet::V4(net));
+    }
+    Ok(prefixes)
+}
+
+fn encode_attribute_value(value: &AttributeValue) -> Result<Vec<u8>, BGPError> {
+    let mut out = Vec::new();
+    match value {
+        AttributeValue::Origin(origin) => out.push(origin.clone() as u8),
+        AttributeValue::AsPath(path) => {
+            for segment in path.chunks(u8::MAX as usize) {
+                out.push(AS_SEQUENCE);
+                out.push(segment.len() as u8);
+                for asn in segment {
+                    out.extend_from_slice(&asn.to_be_bytes());
+                }
+            }
+        }
+        AttributeValue::NextHop(addr) => {
+            out.extend_from_slice(&ipv4_identifier(addr)?.octets());
+        }
+        AttributeValue::MultiExitDisc(v) | AttributeValue::LocalPref(v) => {
+            out.extend_from_slice(&v.to_be_bytes())
+        }
+        AttributeValue::Communities(list) => {
+            for c in list {
+                out.extend_from_slice(&c.to_be_bytes());
+            }
+        }
+        AttributeValue::Unknown(raw) => out.extend_from_slice(raw),
+    }
+    Ok(out)
+}
+
+/// Path attributes one after another, each behind its flags, type and
+/// length, with the extended length where the value needs it
+fn encode_attributes(attributes: &[PathAttribute]) -> Result<Vec<u8>, BGPError> {
+    let mut attrs = Vec::new();
+    for attr in attributes {
+        let value = encode_attribute_value(&attr.value)?;
+        let length = u16::try_from(value.len()).map_err(|_| {
+            malformed(
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_ATTRIBUTE_LENGTH_ERROR,
+                format!(
+                    "{}-byte value for attribute type {} is too long",
+                    value.len(),
+                    attr.type_code
+                ),
+            )
+        })?;
+        let extended = attr.flags & ATTR_FLAG_EXTENDED_LENGTH != 0 || value.len() > 255;
+        if extended {
+            attrs.push(attr.flags | ATTR_FLAG_EXTENDED_LENGTH);
+            attrs.push(attr.type_code);
+            attrs.extend_from_slice(&length.to_be_bytes());
+        } else {
+            attrs.push(attr.flags);
+            attrs.push(attr.type_code);
+            attrs.push(length as u8);
+        }
+        attrs.extend_from_slice(&value);
+    }
+    Ok(attrs)
+}
+
+fn encode_update(update: &UpdateMessage) -> Result<Vec<u8>, BGPError> {
+    let mut withdrawn = Vec::new();
+    for prefix in &update.withdrawn_routes {
+        encode_prefix(&mut withdrawn, prefix)?;
+    }
+
+    let attrs = encode_attributes(&update.path_attributes)?;
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(withdrawn.len() as u16).to_be_bytes());
+    body.extend_from_slice(&withdrawn);
+    body.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    body.extend_from_slice(&attrs);
+    for prefix in &update.network_layer_reachability_info {
+        encode_prefix(&mut body, prefix)?;
+    }
+    Ok(body)
+}
+
+fn decode_update(body: &[u8]) -> Result<UpdateMessage, BGPError> {
+    let mut r = Reader::new(
+        body,
+        BGP_ERROR_UPDATE_MESSAGE,
+        UPDATE_MALFORMED_ATTRIBUTE_LIST,
+    );
+    let withdrawn_len = r.u16()? as usize;
+    let withdrawn_routes = decode_prefixes(r.take(withdrawn_len)?)?;
+    let attrs_len = r.u16()? as usize;
+    let path_attributes = decode_attributes(r.take(attrs_len)?)?;
+    let network_layer_reachability_info = decode_prefixes(r.take(r.remaining())?)?;
+
+    Ok(UpdateMessage {
+        withdrawn_routes,
+        path_attributes,
+        network_layer_reachability_info,
+    })
+}
+
+fn attribute_length_error(type_code: u8) -> BGPError {
+    malformed(
+        BGP_ERROR_UPDATE_MESSAGE,
+        UPDATE_ATTRIBUTE_LENGTH_ERROR,
+        format!("bad length for attribute type {}", type_code),
+    )
+}
+
+fn decode_attributes(data: &[u8]) -> Result<Vec<PathAttribute>, BGPError> {
+    let mut r = Reader::new(
+        data,
+        BGP_ERROR_UPDATE_MESSAGE,
+        UPDATE_MALFORMED_ATTRIBUTE_LIST,
+    );
+    let mut attributes = Vec::new();
+
+    while r.remaining() > 0 {
+        let flags = r.u8()?;
+        let type_code = r.u8()?;
+        let length = if flags & ATTR_FLAG_EXTENDED_LENGTH != 0 {
+            r.u16()?
+        } else {
+            r.u8()? as u16
+        };
+        let raw = r.take(length as usize)?;
+
+        let value = match type_code {
+            BGP_ATTR_ORIGIN => {
+                if raw.len() != 1 {
+                    return Err(attribute_length_error(type_code));
+                }
+                AttributeValue::Origin(match raw[0] {
+                    0 => BGPOrigin::IGP,
+                    1 => BGPOrigin::EGP,
+                    2 => BGPOrigin::Incomplete,
+                    other => {
+                        return Err(malformed(
+                            BGP_ERROR_UPDATE_MESSAGE,
+                            UPDATE_INVALID_ORIGIN,
+                            format!("invalid ORIGIN value {}", other),
+                        ))
+                    }
+                })
+            }
+            BGP_ATTR_AS_PATH => AttributeValue::AsPath(decode_as_path(raw)?),
+            BGP_ATTR_NEXT_HOP => {
+                if raw.len() != 4 {
+                    return Err(attribute_length_error(type_code));
+                }
+                let addr = Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3]);
+                if addr.is_unspecified() || addr.is_broadcast() {
+                    return Err(malformed(
+                        BGP_ERROR_UPDATE_MESSAGE,
+                        UPDATE_INVALID_NEXT_HOP,
+                        format!("invalid NEXT_HOP {}", addr),
+                    ));
+                }
+                AttributeValue::NextHop(IpAddr::V4(addr))
+            }
+            BGP_ATTR_MULTI_EXIT_DISC | BGP_ATTR_LOCAL_PREF => {
+                if raw.len() != 4 {
+                    return Err(attribute_length_error(type_code));
+                }
+                let v = u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]);
+                if type_code == BGP_ATTR_MULTI_EXIT_DISC {
+                    AttributeValue::MultiExitDisc(v)
+                } else {
+                    AttributeValue::LocalPref(v)
+                }
+            }
+            BGP_ATTR_COMMUNITIES => {
+                if raw.len() % 4 != 0 {
+                    return Err(attribute_length_error(type_code));
+                }
+                AttributeValue::Communities(
+                    raw.chunks_exact(4)
+                        .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                        .collect(),
+                )
+            }
+            _ => AttributeValue::Unknown(raw.to_vec()),
+        };
+
+        attributes.push(PathAttribute {
+            flags,
+            type_code,
+            length,
+            value,
+        });
+    }
+
+    Ok(attributes)
+}
+
+fn decode_as_path(raw: &[u8]) -> Result<Vec<u32>, BGPError> {
+    let mut r = Reader::new(raw, BGP_ERROR_UPDATE_MESSAGE, UPDATE_MALFORMED_AS_PATH);
+    let mut path = Vec::new();
+    while r.remaining() > 0 {
+        let segment_type = r.u8()?;
+        if segment_type != AS_SEQUENCE && segment_type != AS_SET {
+            return Err(malformed(
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_MALFORMED_AS_PATH,
+                format!("unknown AS_PATH segment type {}", segment_type),
+            ));
+        }
+        let count = r.u8()?;
+        for _ in 0..count {
+            path.push(r.u32()?);
+        }
+    }
+    Ok(path)
+}
+
+/// Build an OPEN advertising IPv4 unicast and 4-octet AS support
+pub fn open_with_capabilities(asn: u32, hold_time: u16, router_id: Ipv4Addr) -> BGPMessage {
+    let mut caps = vec![BGP_CAP_MULTIPROTOCOL, 4, 0, 1, 0, 1]; // AFI 1 (IPv4), SAFI 1 (unicast)
+    caps.extend_from_slice(&[BGP_CAP_FOUR_OCTET_AS, 4]);
+    caps.extend_from_slice(&asn.to_be_bytes());
+
+    BGPMessage::Open(OpenMessage {
+        version: 4,
+        my_asn: asn,
+        hold_time,
+        bgp_identifier: IpAddr::V4(router_id),
+        optional_parameters: vec![OptionalParameter {
+            parameter_type: BGP_OPT_PARAM_CAPABILITY,
+            parameter_length: caps.len() as u8,
+            parameter_value: caps,
+        }],
+    })
+}
+
+impl UpdateMessage {
+    /// Map announced NLRI onto route entries using the message's attributes,
+    /// handling prefixes with host bits set as the peer's policy says
+    pub fn route_entries(
+        &self,
+        host_bits: HostBitsPolicy,
+        peer_asn: u32,
+    ) -> Result<Vec<RouteEntry>, BGPError> {
+        if self.network_layer_reachability_info.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut origin = None;
+        let mut as_path = None;
+        let mut next_hop = None;
+        let mut med = 0;
+        let mut local_pref = 100;
+        let mut communities = Vec::new();
+
+        for attr in &self.path_attributes {
+            match &attr.value {
+                AttributeValue::Origin(o) => origin = Some(o.clone()),
+                AttributeValue::AsPath(p) => as_path = Some(p.clone()),
+                AttributeValue::NextHop(nh) => next_hop = Some(*nh),
+                AttributeValue::MultiExitDisc(v) => med = *v,
+                AttributeValue::LocalPref(v) => local_pref = *v,
+                AttributeValue::Communities(list) => {
+                    communities = list
+                        .iter()
+                        .map(|c| Community {
+                            asn: (c >> 16) as u16,
+                            value: (c & 0xffff) as u16,
+                        })
+                        .collect()
+                }
+                AttributeValue::Unknown(_) => {}
+            }
+        }
+
+        let missing = |name: &str| {
+            malformed(
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_MISSING_WELL_KNOWN_ATTRIBUTE,
+                format!("UPDATE without {}", name),
+            )
+        };
+        let origin = origin.ok_or_else(|| missing("ORIGIN"))?;
+        let as_path = AsPath::from(as_path.ok_or_else(|| missing("AS_PATH"))?);
+        let next_hop = next_hop.ok_or_else(|| missing("NEXT_HOP"))?;
+        let timestamp = chrono::Utc::now();
+
+        Ok(self
+            .network_layer_reachability_info
+            .iter()
+            .filter_map(|network| Prefix::from_peer(*network, host_bits, peer_asn))
+            .map(|network| RouteEntry {
+                network,
+                next_hop,
+                as_path: as_path.clone(),
+                origin: origin.clone(),
+                local_pref,
+                med,
+                communities: communities.clone(),
+                timestamp,
+                learned_from: None,
+                originated_at: None,
+            })
+            .collect())
+    }
+
+    /// Withdrawn prefixes, normalized. Withdrawals are never refused: a
+    /// peer withdrawing `10.2.1.5/24` means the route it sent for
+    /// `10.2.1.0/24`.
+    pub fn withdrawn(&self, host_bits: HostBitsPolicy, peer_asn: u32) -> Vec<Prefix> {
+        let host_bits = match host_bits {
+            HostBitsPolicy::Reject => HostBitsPolicy::Warn,
+            policy => policy,
+        };
+        self.withdrawn_routes
+            .iter()
+            .filter_map(|network| Prefix::from_peer(*network, host_bits, peer_asn))
+            .collect()
+    }
+}
+
+/// Build UPDATE messages for routes, grouping prefixes that share attributes.
+///
+/// When `next_hop_self` is set the given address replaces each route's next hop,
+/// as required when advertising to an eBGP neighbor.
+pub fn updates_for_routes(
+    routes: &[RouteEntry],
+    next_hop_self: Option<Ipv4Addr>,
+    ibgp: bool,
+) -> Vec<UpdateMessage> {
+    let mut updates: Vec<UpdateMessage> = Vec::new();
+
+    for route in routes {
+        if !matches!(route.network.net(), IpNet::V4(_)) {
+            continue;
+        }
+
+        let next_hop = next_hop_self.map(IpAddr::V4).unwrap_or(route.next_hop);
+        let mut attributes = vec![
+            PathAttribute {
+                flags: ATTR_FLAG_TRANSITIVE,
+                type_code: BGP_ATTR_ORIGIN,
+                length: 1,
+                value: AttributeValue::Origin(route.origin.clone()),
+            },
+            PathAttribute {
+                flags: ATTR_FLAG_TRANSITIVE,
+                type_code: BGP_ATTR_AS_PATH,
+                length: (2 + route.as_path.len() * 4) as u16,
+                value: AttributeValue::AsPath(route.as_path.to_vec()),
+            },
+            PathAttribute {
+                flags: ATTR_FLAG_TRANSITIVE,
+                type_code: BGP_ATTR_NEXT_HOP,
+                length: 4,
+                value: AttributeValue::NextHop(next_hop),
+            },
+        ];
+        if route.med != 0 {
+            attributes.push(PathAttribute {
+                flags: ATTR_FLAG_OPTIONAL,
+                type_code: BGP_ATTR_MULTI_EXIT_DISC,
+                length: 4,
+                value: AttributeValue::MultiExitDisc(route.med),
+            });
+        }
+        // LOCAL_PREF is only meaningful inside an AS
+        if ibgp {
+            attributes.push(PathAttribute {
+                flags: ATTR_FLAG_TRANSITIVE,
+                type_code: BGP_ATTR_LOCAL_PREF,
+                length: 4,
+                value: AttributeValue::LocalPref(route.local_pref),
+            });
+        }
+        if !route.communities.is_empty() {
+            attributes.push(PathAttribute {
+                flags: ATTR_FLAG_OPTIONAL | ATTR_FLAG_TRANSITIVE,
+                type_code: BGP_ATTR_COMMUNITIES,
+                length: (route.communities.len() * 4) as u16,
+                value: AttributeValue::Communities(
+                    route
+                        .communities
+                        .iter()
+                        .map(|c| ((c.asn as u32) << 16) | c.value as u32)
+                        .collect(),
+                ),
+            });
+        }
+
+        if let Some(update) = updates.iter_mut().find(|u| {
+            u.path_attributes == attributes && u.network_layer_reachability_info.len() < 500
+        }) {
+            update
+                .network_layer_reachability_info
+                .push(route.network.net());
+        } else {
+            updates.push(UpdateMessage {
+                withdrawn_routes: vec![],
+                path_attributes: attributes,
+                network_layer_reachability_info: vec![route.network.net()],
+            });
+        }
+    }
+
+    updates
+}
+
+/// A VX0 session message as an RFC 4271 (type, body), for sessions whose
+/// OPENs agreed on binary messages. The VX0 extensions ride in a
+/// private-use capability in OPEN and in optional path attributes in
+/// UPDATE, so the ASN and router ID go in OPEN only. A KEEPALIVE that
+/// acknowledges a table sync chunk is sent as an UPDATE holding just the
+/// acknowledgement.
+pub fn encode_vx0(msg: &protocol::BGPMessage) -> Result<(u8, Vec<u8>), BGPError> {
+    match msg.message_type {
+        BGPMessageType::Open => Ok((BGP_MSG_OPEN, encode_vx0_open(msg)?)),
+        BGPMessageType::Update => Ok((BGP_MSG_UPDATE, encode_vx0_update(msg)?)),
+        BGPMessageType::Keepalive if msg.chunk_ack.is_some() => {
+            Ok((BGP_MSG_UPDATE, encode_vx0_update(msg)?))
+        }
+        BGPMessageType::Keepalive => Ok((BGP_MSG_KEEPALIVE, Vec::new())),
+        BGPMessageType::Notification => {
+            let cease = NotificationMessage {
+                error_code: BGP_ERROR_CEASE,
+                error_subcode: 0,
+                data: vec![],
+            };
+            let n = msg.notification.as_ref().unwrap_or(&cease);
+            Ok((BGP_MSG_NOTIFICATION, encode_notification(n)))
+        }
+    }
+}
+
+/// A VX0 session message from an RFC 4271 (type, body). Only OPEN names
+/// its sender, so every other message is taken to come from `peer`.
+pub fn decode_vx0(
+    msg_type: u8,
+    body: &[u8],
+    peer: PeerIdentity,
+) -> Result<protocol::BGPMessage, BGPError> {
+    let message = |message_type| protocol::BGPMessage::new(message_type, peer.asn, peer.router_id);
+    match msg_type {
+        BGP_MSG_OPEN => decode_vx0_open(body),
+        BGP_MSG_UPDATE => decode_vx0_update(body, message(BGPMessageType::Update)),
+        BGP_MSG_NOTIFICATION => {
+            if body.len() < 2 {
+                return Err(malformed(
+                    BGP_ERROR_MESSAGE_HEADER,
+                    HEADER_BAD_MESSAGE_LENGTH,
+                    format!("{}-byte NOTIFICATION", body.len()),
+                ));
+            }
+            Ok(protocol::BGPMessage {
+                notification: Some(NotificationMessage {
+                    error_code: body[0],
+                    error_subcode: body[1],
+                    data: body[2..].to_vec(),
+                }),
+                ..message(BGPMessageType::Notification)
+            })
+        }
+        BGP_MSG_KEEPALIVE if body.is_empty() => Ok(message(BGPMessageType::Keepalive)),
+        BGP_MSG_KEEPALIVE => Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_BAD_MESSAGE_LENGTH,
+            format!("KEEPALIVE with a {}-byte body", body.len()),
+        )),
+        other => Err(malformed(
+            BGP_ERROR_MESSAGE_HEADER,
+            HEADER_BAD_MESSAGE_TYPE,
+            format!("unknown message type {}", other),
+        )),
+    }
+}
+
+fn encode_vx0_open(msg: &protocol::BGPMessage) -> Result<Vec<u8>, BGPError> {
+    let extensions = encode_extensions(msg, BGP_ERROR_OPEN_MESSAGE)?;
+    let mut caps = vec![BGP_CAP_FOUR_OCTET_AS, 4];
+    caps.extend_from_slice(&msg.asn.to_be_bytes());
+    caps.push(BGP_CAP_VX0);
+    caps.push(
+        u8::try_from(extensions.len())
+            .map_err(|_| malformed(BGP_ERROR_OPEN_MESSAGE, 0, "VX0 capability too long"))?,
+    );
+    caps.extend_from_slice(&extensions);
+
+    encode_open(&OpenMessage {
+        version: 4,
+        my_asn: msg.asn,
+        hold_time: VX0_HOLD_TIME,
+        bgp_identifier: msg.router_id,
+        optional_parameters: vec![OptionalParameter {
+            parameter_type: BGP_OPT_PARAM_CAPABILITY,
+            parameter_length: caps.len() as u8,
+            parameter_value: caps,
+        }],
+    })
+}
+
+/// A binary OPEN from a VX0 node; sending it at all says the node speaks
+/// binary messages
+pub fn decode_vx0_open(body: &[u8]) -> Result<protocol::BGPMessage, BGPError> {
+    let open = decode_open(body)?;
+    let mut msg = protocol::BGPMessage::new(BGPMessageType::Open, open.my_asn, open.bgp_identifier);
+    msg.binary_messages = true;
+    for param in &open.optional_parameters {
+        if param.parameter_type != BGP_OPT_PARAM_CAPABILITY {
+            continue;
+        }
+        let mut r = Reader::new(&param.parameter_value, BGP_ERROR_OPEN_MESSAGE, 0);
+        while r.remaining() > 0 {
+            let code = r.u8()?;
+            let len = r.u8()? as usize;
+            let value = r.take(len)?;
+            if code == BGP_CAP_VX0 {
+                decode_extensions(value, BGP_ERROR_OPEN_MESSAGE, 0, &mut msg)?;
+            }
+        }
+    }
+    Ok(msg)
+}
+
+fn encode_vx0_update(msg: &protocol::BGPMessage) -> Result<Vec<u8>, BGPError> {
+    let (withdrawn_routes, withdrawn_v6): (Vec<IpNet>, Vec<IpNet>) = msg
+        .withdrawn
+        .iter()
+        .map(Prefix::net)
+        .partition(|net| matches!(net, IpNet::V4(_)));
+
+    let mut path_attributes = Vec::new();
+    if !withdrawn_v6.is_empty() {
+        let mut value = AFI_IPV6.to_be_bytes().to_vec();
+        value.push(SAFI_UNICAST);
+        for net in &withdrawn_v6 {
+            put_prefix(&mut value, net);
+        }
+        path_attributes.push(optional_attribute(BGP_ATTR_MP_UNREACH_NLRI, value));
+    }
+    if !msg.routes.is_empty() {
+        let mut value = Vec::new();
+        for route in &msg.routes {
+            encode_vx0_route(&mut value, route)?;
+        }
+        path_attributes.push(optional_attribute(BGP_ATTR_VX0_ROUTES, value));
+    }
+    path_attributes.push(optional_attribute(
+        BGP_ATTR_VX0_EXTENSIONS,
+        encode_extensions(msg, BGP_ERROR_UPDATE_MESSAGE)?,
+    ));
+
+    encode_update(&UpdateMessage {
+        withdrawn_routes,
+        path_attributes,
+        network_layer_reachability_info: vec![],
+    })
+}
+
+fn decode_vx0_update(
+    body: &[u8],
+    mut msg: protocol::BGPMessage,
+) -> Result<protocol::BGPMessage, BGPError> {
+    let update = decode_update(body)?;
+    if !update.network_layer_reachability_info.is_empty() {
+        return Err(malformed(
+            BGP_ERROR_UPDATE_MESSAGE,
+            UPDATE_INVALID_NETWORK_FIELD,
+            "NLRI outside the VX0 routes attribute",
+        ));
+    }
+    msg.withdrawn = update
+        .withdrawn_routes
+        .into_iter()
+        .map(Prefix::new)
+        .collect();
+
+    for attr in update.path_attributes {
+        let AttributeValue::Unknown(raw) = attr.value else {
+            continue;
+        };
+        match attr.type_code {
+            BGP_ATTR_MP_UNREACH_NLRI => {
+                let mut r = Reader::new(
+                    &raw,
+                    BGP_ERROR_UPDATE_MESSAGE,
+                    UPDATE_OPTIONAL_ATTRIBUTE_ERROR,
+                );
+                let afi = r.u16()?;
+                let _safi = r.u8()?;
+                while r.remaining() > 0 {
+                    msg.withdrawn.push(Prefix::new(take_prefix(&mut r, afi)?));
+                }
+            }
+            BGP_ATTR_VX0_ROUTES => msg.routes = decode_vx0_routes(&raw)?,
+            BGP_ATTR_VX0_EXTENSIONS => decode_extensions(
+                &raw,
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_OPTIONAL_ATTRIBUTE_ERROR,
+                &mut msg,
+            )?,
+            _ => {}
+        }
+    }
+
+    // Nothing but a chunk acknowledgement: the KEEPALIVE that carried it
+    if msg.chunk_ack.is_some() && msg.routes.is_empty() && msg.withdrawn.is_empty() {
+        msg.message_type = BGPMessageType::Keepalive;
+    }
+    Ok(msg)
+}
+
+/// One route of the VX0 routes attribute: its AFI and prefix, then its own
+/// path attributes behind a two-byte length
+fn encode_vx0_route(out: &mut Vec<u8>, route: &BGPRoute) -> Result<(), BGPError> {
+    let net = route.network.net();
+    let afi = match net {
+        IpNet::V4(_) => AFI_IPV4,
+        IpNet::V6(_) => AFI_IPV6,
+    };
+    out.extend_from_slice(&afi.to_be_bytes());
+    put_prefix(out, &net);
+
+    let next_hop = match route.next_hop {
+        IpAddr::V4(_) => PathAttribute {
+            flags: ATTR_FLAG_TRANSITIVE,
+            type_code: BGP_ATTR_NEXT_HOP,
+            length: 4,
+            value: AttributeValue::NextHop(route.next_hop),
+        },
+        IpAddr::V6(addr) => optional_attribute(BGP_ATTR_VX0_NEXT_HOP, addr.octets().to_vec()),
+    };
+    let mut attributes = vec![
+        PathAttribute {
+            flags: ATTR_FLAG_TRANSITIVE,
+            type_code: BGP_ATTR_ORIGIN,
+            length: 1,
+            value: AttributeValue::Origin(route.origin.clone()),
+        },
+        PathAttribute {
+            flags: ATTR_FLAG_TRANSITIVE,
+            type_code: BGP_ATTR_AS_PATH,
+            length: (2 + route.as_path.len() * 4) as u16,
+            value: AttributeValue::AsPath(route.as_path.clone()),
+        },
+        next_hop,
+        PathAttribute {
+            flags: ATTR_FLAG_OPTIONAL,
+            type_code: BGP_ATTR_MULTI_EXIT_DISC,
+            length: 4,
+            value: AttributeValue::MultiExitDisc(route.med),
+        },
+        PathAttribute {
+            flags: ATTR_FLAG_TRANSITIVE,
+            type_code: BGP_ATTR_LOCAL_PREF,
+            length: 4,
+            value: AttributeValue::LocalPref(route.local_pref),
+        },
+    ];
+    if let Some(at) = &route.originated_at {
+        attributes.push(optional_attribute(
+            BGP_ATTR_VX0_ORIGINATED_AT,
+            timestamp_bytes(at),
+        ));
+    }
+
+    let attrs = encode_attributes(&attributes)?;
+    let length = u16::try_from(attrs.len()).map_err(|_| {
+        malformed(
+            BGP_ERROR_UPDATE_MESSAGE,
+            UPDATE_OPTIONAL_ATTRIBUTE_ERROR,
+            format!("attributes of {} too long", route.network),
+        )
+    })?;
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(&attrs);
+    Ok(())
+}
+
+fn decode_vx0_routes(raw: &[u8]) -> Result<Vec<BGPRoute>, BGPError> {
+    let mut r = Reader::new(
+        raw,
+        BGP_ERROR_UPDATE_MESSAGE,
+        UPDATE_OPTIONAL_ATTRIBUTE_ERROR,
+    );
+    let mut routes = Vec::new();
+    while r.remaining() > 0 {
+        let afi = r.u16()?;
+        let network = Prefix::new(take_prefix(&mut r, afi)?);
+        let attrs_len = r.u16()? as usize;
+
+        let mut origin = None;
+        let mut as_path = None;
+        let mut next_hop = None;
+        let mut med = 0;
+        let mut local_pref = 100;
+        let mut originated_at = None;
+        for attr in decode_attributes(r.take(attrs_len)?)? {
+            match (attr.type_code, attr.value) {
+                (_, AttributeValue::Origin(o)) => origin = Some(o),
+                (_, AttributeValue::AsPath(p)) => as_path = Some(p),
+                (_, AttributeValue::NextHop(nh)) => next_hop = Some(nh),
+                (_, AttributeValue::MultiExitDisc(v)) => med = v,
+                (_, AttributeValue::LocalPref(v)) => local_pref = v,
+                (BGP_ATTR_VX0_NEXT_HOP, AttributeValue::Unknown(raw)) => {
+                    next_hop = Some(decode_addr(&raw, UPDATE_INVALID_NEXT_HOP)?)
+                }
+                (BGP_ATTR_VX0_ORIGINATED_AT, AttributeValue::Unknown(raw)) => {
+                    let mut r = Reader::new(
+                        &raw,
+                        BGP_ERROR_UPDATE_MESSAGE,
+                        UPDATE_OPTIONAL_ATTRIBUTE_ERROR,
+                    );
+                    originated_at = Some(take_timestamp(&mut r)?);
+                }
+                _ => {}
+            }
+        }
+
+        let missing = |name: &str| {
+            malformed(
+                BGP_ERROR_UPDATE_MESSAGE,
+                UPDATE_MISSING_WELL_KNOWN_ATTRIBUTE,
+                format!("route to {} without {}", network, name),
+            )
+        };
+        routes.push(BGPRoute {
+            network,
+            next_hop: next_hop.ok_or_else(|| missing("NEXT_HOP"))?,
+            as_path: as_path.ok_or_else(|| missing("AS_PATH"))?,
+            origin: origin.ok_or_else(|| missing("ORIGIN"))?,
+            local_pref,
+            med,
+            originated_at,
+        });
+    }
+    Ok(routes)
+}
+
+fn optional_attribute(type_code: u8, value: Vec<u8>) -> PathAttribute {
+    PathAttribute {
+        flags: ATTR_FLAG_OPTIONAL,
+        type_code,
+        length: value.len() as u16,
+        value: AttributeValue::Unknown(value),
+    }
+}
+
+/// A prefix of either family as NLRI encodes it: its length, then only the
+/// octets that length covers
+fn put_prefix(out: &mut Vec<u8>, net: &IpNet) {
+    let len = net.prefix_len();
+    out.push(len);
+    let octets = match net.network() {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    };
+    out.extend_from_slice(&octets[..(len as usize).div_ceil(8)]);
+}
+
+fn take_prefix(r: &mut Reader<'_>, afi: u16) -> Result<IpNet, BGPError> {
+    let invalid = |reason: String| {
+        malformed(
+            BGP_ERROR_UPDATE_MESSAGE,
+            UPDATE_INVALID_NETWORK_FIELD,
+            reason,
+        )
+    };
+    let len = r.u8()?;
+    let bytes = r.take((len as usize).div_ceil(8))?;
+    let net = match afi {
+        AFI_IPV4 => {
+            let mut octets = [0u8; 4];
+            octets
+                .get_mut(..bytes.len())
+                .ok_or_else(|| invalid(format!("prefix length {} exceeds 32", len)))?
+                .copy_from_slice(bytes);
+            Ipv4Net::new(Ipv4Addr::from(octets), len).map(IpNet::V4)
+        }
+        AFI_IPV6 => {
+            let mut octets = [0u8; 16];
+            octets
+                .get_mut(..bytes.len())
+                .ok_or_else(|| invalid(format!("prefix length {} exceeds 128", len)))?
+                .copy_from_slice(bytes);
+            Ipv6Net::new(Ipv6Addr::from(octets), len).map(IpNet::V6)
+        }
+        other => return Err(invalid(format!("unsupported AFI {}", other))),
+    };
+    net.map_err(|e| invalid(e.to_string()))
+}
+
+fn decode_addr(raw: &[u8], subcode: u8) -> Result<IpAddr, BGPError> {
+    match raw.len() {
+        4 => Ok(IpAddr::V4(Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(raw);
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        len => Err(malformed(
+            BGP_ERROR_UPDATE_MESSAGE,
+            subcode,
+            format!("{}-byte address", len),
+        )),
+    }
+}
+
+fn timestamp_bytes(at: &chrono::DateTime<chrono::Utc>) -> Vec<u8> {
+    let mut out = at.timestamp().to_be_bytes().to_vec();
+    out.extend_from_slice(&at.timestamp_subsec_nanos().to_be_bytes());
+    out
+}
+
+fn take_timestamp(r: &mut Reader<'_>) -> Result<chrono::DateTime<chrono::Utc>, BGPError> {
+    let secs = r.u64()? as i64;
+    let nanos = r.u32()?;
+    chrono::DateTime::from_timestamp(secs, nanos).ok_or_else(|| {
+        malformed(
+            r.code,
+            r.subcode,
+            format!("timestamp {}.{:09} out of range", secs, nanos),
+        )
+    })
+}
+
+fn put_extension(out: &mut Vec<u8>, ext_type: u8, value: &[u8], code: u8) -> Result<(), BGPError> {
+    let len = u8::try_from(value.len()).map_err(|_| {
+        malformed(
+            code,
+            0,
+            format!("{}-byte VX0 extension {}", value.len(), ext_type),
+        )
+    })?;
+    out.push(ext_type);
+    out.push(len);
+    out.extend_from_slice(value);
+    Ok(())
+}
+
+/// The VX0 extensions `msg` carries, each behind a one-byte type and a
+/// one-byte length; `code` is the error code for values too long to fit
+fn encode_extensions(msg: &protocol::BGPMessage, code: u8) -> Result<Vec<u8>, BGPError> {
+    let mut out = Vec::new();
+    put_extension(
+        &mut out,
+        EXT_TIMESTAMP,
+        &timestamp_bytes(&msg.timestamp),
+        code,
+    )?;
+    if let Some(capabilities) = &msg.capabilities {
+        put_extension(
+            &mut out,
+            EXT_CAPABILITIES,
+            &capabilities.flags().to_be_bytes(),
+            code,
+        )?;
+        if let Some(mtu) = capabilities.tunnel_mtu {
+            put_extension(&mut out, EXT_TUNNEL_MTU, &mtu.to_be_bytes(), code)?;
+        }
+    }
+    if let Some(hello) = &msg.resume {
+        put_extension(&mut out, EXT_RESUME_NONCE, &hello.nonce, code)?;
+        if let Some(offer) = &hello.offer {
+            let value = [&offer.token[..], &offer.proof].concat();
+            put_extension(&mut out, EXT_RESUME_OFFER, &value, code)?;
+        }
+        if let Some(accepted) = &hello.accepted {
+            let value = [&accepted.table_version.to_be_bytes()[..], &accepted.proof].concat();
+            put_extension(&mut out, EXT_RESUME_ACCEPTED, &value, code)?;
+        }
+    }
+    if let Some(addr) = msg.observed_addr {
+        let octets = match addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        };
+        put_extension(&mut out, EXT_OBSERVED_ADDR, &octets, code)?;
+    }
+    if let Some(resume) = &msg.sync_resume {
+        let mut value = resume.table_version.to_be_bytes().to_vec();
+        value.extend_from_slice(&resume.next_chunk.to_be_bytes());
+        put_extension(&mut out, EXT_SYNC_RESUME, &value, code)?;
+    }
+    if let Some(version) = msg.table_version {
+        put_extension(&mut out, EXT_TABLE_VERSION, &version.to_be_bytes(), code)?;
+    }
+    if let Some(chunk) = &msg.chunk {
+        let mut value = chunk.table_version.to_be_bytes().to_vec();
+        value.extend_from_slice(&chunk.seq.to_be_bytes());
+        value.extend_from_slice(&chunk.total.to_be_bytes());
+        if let Some(digest) = &chunk.digest {
+            value.extend_from_slice(digest.as_bytes());
+        }
+        put_extension(&mut out, EXT_CHUNK, &value, code)?;
+    }
+    if let Some(seq) = msg.chunk_ack {
+        put_extension(&mut out, EXT_CHUNK_ACK, &seq.to_be_bytes(), code)?;
+    }
+    Ok(out)
+}
+
+/// Fill `msg` from the VX0 extensions in `raw`, skipping types we do not
+/// know so newer nodes can add their own
+fn decode_extensions(
+    raw: &[u8],
+    code: u8,
+    subcode: u8,
+    msg: &mut protocol::BGPMessage,
+) -> Result<(), BGPError> {
+    let mut r = Reader::new(raw, code, subcode);
+    let mut flags = None;
+    let mut tunnel_mtu = None;
+    let mut nonce = None;
+    let mut offer = None;
+    let mut accepted = None;
+    while r.remaining() > 0 {
+        let ext_type = r.u8()?;
+        let len = r.u8()? as usize;
+        let mut v = Reader::new(r.take(len)?, code, subcode);
+        match ext_type {
+            EXT_TIMESTAMP => msg.timestamp = take_timestamp(&mut v)?,
+            EXT_CAPABILITIES => flags = Some(v.u32()?),
+            EXT_TUNNEL_MTU => tunnel_mtu = Some(v.u16()?),
+            EXT_RESUME_NONCE => nonce = Some(v.rest().to_vec()),
+            EXT_RESUME_OFFER => {
+                let mut token = [0u8; 16];
+                token.copy_from_slice(v.take(16)?);
+                offer = Some(ResumeOffer {
+                    token,
+                    proof: v.rest().to_vec(),
+                });
+            }
+            EXT_RESUME_ACCEPTED => {
+                let table_version = v.u64()?;
+                accepted = Some(ResumeAccepted {
+                    proof: v.rest().to_vec(),
+                    table_version,
+                });
+            }
+            EXT_OBSERVED_ADDR => msg.observed_addr = Some(decode_addr(v.rest(), subcode)?),
+            EXT_SYNC_RESUME => {
+                msg.sync_resume = Some(SyncResume {
+                    table_version: v.u64()?,
+                    next_chunk: v.u32()?,
+                })
+            }
+            EXT_TABLE_VERSION => msg.table_version = Some(v.u64()?),
+            EXT_CHUNK => {
+                let table_version = v.u64()?;
+                let seq = v.u32()?;
+                let total = v.u32()?;
+                let digest = v.rest();
+                let digest = (!digest.is_empty())
+                    .then(|| String::from_utf8(digest.to_vec()))
+                    .transpose()
+                    .map_err(|_| malformed(code, subcode, "table chunk digest is not UTF-8"))?;
+                msg.chunk = Some(TableChunk {
+                    table_version,
+                    seq,
+                    total,
+                    digest,
+                });
+            }
+            EXT_CHUNK_ACK => msg.chunk_ack = Some(v.u32()?),
+            _ => continue,
+        }
+        if v.remaining() > 0 {
+            return Err(malformed(
+                code,
+                subcode,
+                format!(
+                    "{} bytes left over in VX0 extension {}",
+                    v.remaining(),
+                    ext_type
+                ),
+            ));
+        }
+    }
+    if let Some(flags) = flags {
+        msg.capabilities = Some(Capabilities::from_flags(flags, tunnel_mtu));
+    }
+    msg.resume = nonce.map(|nonce| ResumeHello {
+        nonce,
+        offer,
+        accepted,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_header(msg_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = BGP_MARKER.to_vec();
+        out.extend_from_slice(&((BGP_HEADER_LEN + body.len()) as u16).to_be_bytes());
+        out.push(msg_type);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// OPEN as sent by FRR 8.x for `router bgp 65002`, hold 180, id 192.0.2.2.
+    /// Capabilities: MP IPv4 unicast, route refresh (old + new), 4-octet AS,
+    /// ADD-PATH, FQDN "frr", graceful restart, each in its own parameter.
+    const FRR_OPEN: &[u8] = &[
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x00, 0x4c, 0x01, // header, length 76, OPEN
+        0x04, 0xfd, 0xea, 0x00, 0xb4, 0xc0, 0x00, 0x02, 0x02, // v4, AS 65002, hold 180, id
+        0x2f, // optional parameters length 47
+        0x02, 0x06, 0x01, 0x04, 0x00, 0x01, 0x00, 0x01, // MP: IPv4 unicast
+        0x02, 0x02, 0x80, 0x00, // route refresh (Cisco)
+        0x02, 0x02, 0x02, 0x00, // route refresh
+        0x02, 0x06, 0x41, 0x04, 0x00, 0x00, 0xfd, 0xea, // 4-octet AS 65002
+        0x02, 0x06, 0x45, 0x04, 0x00, 0x01, 0x01, 0x01, // ADD-PATH receive
+        0x02, 0x07, 0x49, 0x05, 0x03, b'f', b'r', b'r', 0x00, // FQDN
+        0x02, 0x04, 0x40, 0x02, 0x00, 0x78, // graceful restart, 120s
+    ];
+
+    /// UPDATE announcing 10.2.0.0/16 and 10.3.1.0/24 from AS 65002 with
+    /// next hop 192.0.2.2, MED 50 and community 65002:100.
+    const FRR_UPDATE: &[u8] = &[
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x00, 0x41, 0x02, // header, length 65, UPDATE
+        0x00, 0x00, // no withdrawn routes
+        0x00, 0x23, // path attributes length 35
+        0x40, 0x01, 0x01, 0x00, // ORIGIN IGP
+        0x50, 0x02, 0x00, 0x06, 0x02, 0x01, 0x00, 0x00, 0xfd, 0xea, // AS_PATH (ext len)
+        0x40, 0x03, 0x04, 0xc0, 0x00, 0x02, 0x02, // NEXT_HOP
+        0x80, 0x04, 0x04, 0x00, 0x00, 0x00, 0x32, // MED 50
+        0xc0, 0x08, 0x04, 0xfd, 0xea, 0x00, 0x64, // COMMUNITIES 65002:100
+        0x10, 0x0a, 0x02, // 10.2.0.0/16
+        0x18, 0x0a, 0x03, 0x01, // 10.3.1.0/24
+    ];
+
+    /// UPDATE withdrawing 10.3.1.0/24
+    const FRR_WITHDRAW: &[u8] = &[
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x00, 0x1b, 0x02, 0x00, 0x04, 0x18, 0x0a, 0x03, 0x01, 0x00, 0x00,
+    ];
+
+    const FRR_KEEPALIVE: &[u8] = &[
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x00, 0x13, 0x04,
+    ];
+
+    /// NOTIFICATION Cease / Administrative Shutdown
+    const FRR_CEASE: &[u8] = &[
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0x00, 0x15, 0x03, 0x06, 0x02,
+    ];
+
+    #[test]
+    fn test_decode_frr_open() {
+        let msg = decode(FRR_OPEN).unwrap();
+        let BGPMessage::Open(open) = msg else {
+            panic!("expected OPEN");
+        };
+        assert_eq!(open.version, 4);
+        assert_eq!(open.my_asn, 65002);
+        assert_eq!(open.hold_time, 180);
+        assert_eq!(open.bgp_identifier, "192.0.2.2".parse::<IpAddr>().unwrap());
+        assert_eq!(open.optional_parameters.len(), 7);
+    }
+
+    #[test]
+    fn test_decode_frr_update_to_routes() {
+        let BGPMessage::Update(update) = decode(FRR_UPDATE).unwrap() else {
+            panic!("expected UPDATE");
+        };
+        let routes = update.route_entries(HostBitsPolicy::Warn, 65002).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].network, "10.2.0.0/16".parse::<Prefix>().unwrap());
+        assert_eq!(routes[1].network, "10.3.1.0/24".parse::<Prefix>().unwrap());
+        assert_eq!(routes[0].as_path, vec![65002]);
+        assert_eq!(routes[0].next_hop, "192.0.2.2".parse::<IpAddr>().unwrap());
+        assert_eq!(routes[0].med, 50);
+        assert_eq!(routes[0].local_pref, 100);
+        assert_eq!(routes[0].origin, BGPOrigin::IGP);
+        assert_eq!(
+            routes[0].communities,
+            vec![Community {
+                asn: 65002,
+                value: 100
+            }]
+        );
+    }
+
+    #[test]
+    fn test_decode_withdraw_keepalive_notification() {
+        let BGPMessage::Update(update) = decode(FRR_WITHDRAW).unwrap() else {
+            panic!("expected UPDATE");
+        };
+        assert_eq!(
+            update.withdrawn_routes,
+            vec!["10.3.1.0/24".parse::<IpNet>().unwrap()]
+        );
+        assert!(update
+            .route_entries(HostBitsPolicy::Warn, 65002)
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(decode(FRR_KEEPALIVE).unwrap(), BGPMessage::Keepalive);
+
+        let BGPMessage::Notification(n) = decode(FRR_CEASE).unwrap() else {
+            panic!("expected NOTIFICATION");
+        };
+        assert_eq!((n.error_code, n.error_subcode), (6, 2));
+    }
+
+    #[test]
+    fn test_fixtures_reencode_byte_identical() {
+        for fixture in [FRR_OPEN, FRR_UPDATE, FRR_WITHDRAW, FRR_KEEPALIVE, FRR_CEASE] {
+            let msg = decode(fixture).unwrap();
+            assert_eq!(encode(&msg).unwrap(), fixture);
+        }
+    }
+
+    #[test]
+    fn test_open_round_trip_with_large_asn() {
+        let open = open_with_capabilities(4_200_000_001, 90, Ipv4Addr::new(10, 0, 0, 1));
+        let bytes = encode(&open).unwrap();
+        // 2-octet field carries AS_TRANS
+        assert_eq!(&bytes[20..22], &AS_TRANS.to_be_bytes());
+        assert_eq!(decode(&bytes).unwrap(), open);
+    }
+
+    #[test]
+    fn test_routes_round_trip_through_update() {
+        let route = RouteEntry {
+            network: "10.20.0.0/16".parse().unwrap(),
+            next_hop: "10.0.0.1".parse().unwrap(),
+            as_path: vec![65001, 65100].into(),
+            origin: BGPOrigin::Incomplete,
+            local_pref: 100,
+            med: 7,
+            communities: vec![Community {
+                asn: 65001,
+                value: 1,
+            }],
+            timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
+        };
+        let updates = updates_for_routes(
+            std::slice::from_ref(&route),
+            Some(Ipv4Addr::new(192, 0, 2, 9)),
+            false,
+        );
+        assert_eq!(updates.len(), 1);
+
+        let bytes = encode(&BGPMessage::Update(updates[0].clone())).unwrap();
+        let BGPMessage::Update(decoded) = decode(&bytes).unwrap() else {
+            panic!("expected UPDATE");
+        };
+        let routes = decoded.route_entries(HostBitsPolicy::Warn, 65002).unwrap();
+        assert_eq!(routes[0].network, route.network);
+        assert_eq!(routes[0].as_path, route.as_path);
+        assert_eq!(routes[0].med, 7);
+        assert_eq!(routes[0].communities, route.communities);
+        assert_eq!(routes[0].next_hop, "192.0.2.9".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_host_bits_do_not_split_routes() {
+        use crate::network::bgp::rib::Rib;
+        use crate::network::bgp::routing::RoutingPolicy;
+        use crate::node::NodeTier;
+
+        let route = RouteEntry {
+            network: "10.2.0.0/23".parse().unwrap(),
+            next_hop: "192.0.2.2".parse().unwrap(),
+            as_path: vec![65002].into(),
+            origin: BGPOrigin::IGP,
+            local_pref: 100,
+            med: 0,
+            communities: vec![],
+            timestamp: chrono::Utc::now(),
+            learned_from: None,
+            originated_at: None,
+        };
+        let template = updates_for_routes(&[route], None, false).remove(0);
+        // A /23 is sent as three octets, so a peer can set a host bit in
+        // the third; our encoder never does, so patch the bytes
+        let mut bytes = encode(&BGPMessage::Update(template.clone())).unwrap();
+        *bytes.last_mut().unwrap() |= 1;
+        let BGPMessage::Update(decoded) = decode(&bytes).unwrap() else {
+            panic!("expected UPDATE");
+        };
+        assert_eq!(
+            decoded.network_layer_reachability_info,
+            vec!["10.2.1.0/23".parse::<IpNet>().unwrap()]
+        );
+
+        let over_wire = |announced: &[&str], withdrawn: &[&str]| {
+            let mut update = template.clone();
+            update.network_layer_reachability_info =
+                announced.iter().map(|n| n.parse().unwrap()).collect();
+            update.withdrawn_routes = withdrawn.iter().map(|n| n.parse().unwrap()).collect();
+            if announced.is_empty() {
+                update.path_attributes.clear();
+            }
+            update
+        };
+        let receive = |rib: &mut Rib, update: UpdateMessage, policy: HostBitsPolicy| {
+            let routes = update.route_entries(policy, 65002).unwrap();
+            rib.receive(65002, routes, &update.withdrawn(policy, 65002))
+                .unwrap();
+        };
+        let contents = |rib: &Rib| {
+            let mut routes: Vec<(Prefix, Vec<u32>, IpAddr)> = rib
+                .loc_rib()
+                .get_all_routes()
+                .into_iter()
+                .map(|route| (route.network, route.as_path.to_vec(), route.next_hop))
+                .collect();
+            routes.sort();
+            routes
+        };
+
+        let mut sloppy = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        let mut tidy = Rib::new(RoutingPolicy::new(65000, NodeTier::Backbone));
+        receive(&mut sloppy, decoded, HostBitsPolicy::Warn);
+        receive(
+            &mut tidy,
+            over_wire(&["10.2.0.0/23"], &[]),
+            HostBitsPolicy::Warn,
+        );
+        assert_eq!(contents(&sloppy).len(), 1);
+        assert_eq!(contents(&sloppy), contents(&tidy));
+
+        // Withdrawing the normalized form removes the route installed with
+        // host bits, and the other way round
+        receive(
+            &mut sloppy,
+            over_wire(&[], &["10.2.0.0/23"]),
+            HostBitsPolicy::Warn,
+        );
+        assert!(contents(&sloppy).is_empty());
+        assert_eq!(sloppy.routes_from(65002), 0);
+        receive(
+            &mut tidy,
+            over_wire(&[], &["10.2.1.0/23"]),
+            HostBitsPolicy::Reject,
+        );
+        assert!(contents(&tidy).is_empty());
+
+        // A peer held to the strict policy has such announcements ignored
+        receive(
+            &mut tidy,
+            over_wire(&["10.2.1.0/23"], &[]),
+            HostBitsPolicy::Reject,
+        );
+        assert!(contents(&tidy).is_empty());
+    }
+
+    #[test]
+    fn test_malformed_input_reports_error_codes() {
+        let mut bad_marker = FRR_KEEPALIVE.to_vec();
+        bad_marker[3] = 0;
+        assert!(matches!(
+            decode(&bad_marker),
+            Err(BGPError::Protocol {
+                code: BGP_ERROR_MESSAGE_HEADER,
+                subcode: HEADER_CONNECTION_NOT_SYNCHRONIZED,
+                ..
+            })
+        ));
+
+        let mut bad_length = FRR_KEEPALIVE.to_vec();
+        bad_length[17] = 0x14;
+        assert!(matches!(
+            decode_header(&bad_length),
+            Err(BGPError::Protocol {
+                subcode: HEADER_BAD_MESSAGE_LENGTH,
+                ..
+            })
+        ));
+
+        let bad_type = with_header(9, &[]);
+        assert!(matches!(
+            decode(&bad_type),
+            Err(BGPError::Protocol {
+                subcode: HEADER_BAD_MESSAGE_TYPE,
+                ..
+            })
+        ));
+
+        // ORIGIN value 3 is invalid
+        let bad_origin = with_header(BGP_MSG_UPDATE, &[0, 0, 0, 4, 0x40, 0x01, 0x01, 0x03]);
+        assert!(matches!(
+            decode(&bad_origin),
+            Err(BGPError::Protocol {
+                code: BGP_ERROR_UPDATE_MESSAGE,
+                subcode: UPDATE_INVALID_ORIGIN,
+                ..
+            })
+        ));
+
+        // Attribute claims more bytes than present
+        let truncated = with_header(BGP_MSG_UPDATE, &[0, 0, 0, 4, 0x40, 0x01, 0x05, 0x00]);
+        assert!(decode(&truncated).is_err());
+    }
+
+    /// 2026-01-01T00:00:00Z, as VX0 extensions carry it: seconds, then
+    /// nanoseconds
+    const VX0_TIMESTAMP: [u8; 12] = [0, 0, 0, 0, 0x69, 0x55, 0xb9, 0x00, 0, 0, 0, 0];
+
+    fn vx0_timestamp() -> chrono::DateTime<chrono::Utc> {
+        "2026-01-01T00:00:00Z".parse().unwrap()
+    }
+
+    fn vx0_peer() -> PeerIdentity {
+        PeerIdentity {
+            asn: 66001,
+            router_id: "10.0.0.1".parse().unwrap(),
+        }
+    }
+
+    /// OPEN from AS 66001, id 10.0.0.1, serving DNS and carrying BGP over
+    /// its tunnels with a 1400-byte MTU, to a peer it saw at 10.0.0.2
+    fn vx0_open_body() -> Vec<u8> {
+        let mut body = vec![
+            0x04, 0x5b, 0xa0, 0x00, 0x5a, 0x0a, 0x00, 0x00, 0x01, // v4, AS_TRANS, hold 90, id
+            0x28, 0x02, 0x26, // optional parameters, one capability parameter
+            0x41, 0x04, 0x00, 0x01, 0x01, 0xd1, // 4-octet AS 66001
+            0xf0, 0x1e, // VX0 capability
+            0x01, 0x0c, // timestamp
+        ];
+        body.extend_from_slice(&VX0_TIMESTAMP);
+        body.extend_from_slice(&[
+            0x02, 0x04, 0x00, 0x00, 0x08, 0x01, // capabilities: dns, bgp-over-tunnel
+            0x03, 0x02, 0x05, 0x78, // tunnel MTU 1400
+            0x07, 0x04, 0x0a, 0x00, 0x00, 0x02, // observed address
+        ]);
+        body
+    }
+
+    /// UPDATE announcing 10.60.1.0/24 via 10.0.0.1 and withdrawing
+    /// 10.60.2.0/24 and fd00::/8, up to table version 7
+    fn vx0_update_body() -> Vec<u8> {
+        let mut body = vec![
+            0x00, 0x04, 0x18, 0x0a, 0x3c, 0x02, // withdrawn 10.60.2.0/24
+            0x00, 0x50, // path attributes length 80
+            0x80, 0x0f, 0x05, 0x00, 0x02, 0x01, 0x08, 0xfd, // MP_UNREACH_NLRI fd00::/8
+            0x80, 0xf0, 0x2a, // VX0_ROUTES
+            0x00, 0x01, 0x18, 0x0a, 0x3c, 0x01, 0x00, 0x22, // 10.60.1.0/24
+            0x40, 0x01, 0x01, 0x00, // ORIGIN IGP
+            0x40, 0x02, 0x06, 0x02, 0x01, 0x00, 0x01, 0x01, 0xd1, // AS_PATH 66001
+            0x40, 0x03, 0x04, 0x0a, 0x00, 0x00, 0x01, // NEXT_HOP
+            0x80, 0x04, 0x04, 0x00, 0x00, 0x00, 0x00, // MED 0
+            0x40, 0x05, 0x04, 0x00, 0x00, 0x00, 0x64, // LOCAL_PREF 100
+            0x80, 0xf1, 0x18, // VX0_EXTENSIONS
+            0x01, 0x0c, // timestamp
+        ];
+        body.extend_from_slice(&VX0_TIMESTAMP);
+        body.extend_from_slice(&[0x09, 0x08, 0, 0, 0, 0, 0, 0, 0, 0x07]); // table version 7
+        body
+    }
+
+    #[test]
+    fn test_vx0_messages_match_fixtures() {
+        let peer = vx0_peer();
+        let open = protocol::BGPMessage {
+            timestamp: vx0_timestamp(),
+            capabilities: Some(Capabilities {
+                serves_dns: true,
+                bgp_over_tunnel: true,
+                tunnel_mtu: Some(1400),
+                ..Capabilities::default()
+            }),
+            observed_addr: Some("10.0.0.2".parse().unwrap()),
+            binary_messages: true,
+            ..protocol::BGPMessage::new(BGPMessageType::Open, peer.asn, peer.router_id)
+        };
+        assert_eq!(encode_vx0(&open).unwrap(), (BGP_MSG_OPEN, vx0_open_body()));
+        let decoded = decode_vx0(BGP_MSG_OPEN, &vx0_open_body(), peer).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&open).unwrap()
+        );
+
+        let update = protocol::BGPMessage {
+            timestamp: vx0_timestamp(),
+            routes: vec![BGPRoute {
+                network: "10.60.1.0/24".parse().unwrap(),
+                next_hop: peer.router_id,
+                as_path: vec![66001],
+                origin: BGPOrigin::IGP,
+                local_pref: 100,
+                med: 0,
+                originated_at: None,
+            }],
+            withdrawn: vec!["10.60.2.0/24".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            table_version: Some(7),
+            ..protocol::BGPMessage::new(BGPMessageType::Update, peer.asn, peer.router_id)
+        };
+        assert_eq!(
+            encode_vx0(&update).unwrap(),
+            (BGP_MSG_UPDATE, vx0_update_body())
+        );
+        let decoded = decode_vx0(BGP_MSG_UPDATE, &vx0_update_body(), peer).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&update).unwrap()
+        );
+
+        // The same bytes decode as any RFC 4271 UPDATE: no NLRI, one
+        // withdrawal, and attributes a plain speaker would skip
+        let BGPMessage::Update(plain) =
+            decode(&with_header(BGP_MSG_UPDATE, &vx0_update_body())).unwrap()
+        else {
+            panic!("expected UPDATE");
+        };
+        assert!(plain.network_layer_reachability_info.is_empty());
+        assert_eq!(plain.withdrawn_routes.len(), 1);
+
+        assert_eq!(
+            encode_vx0(&protocol::BGPMessage::new(
+                BGPMessageType::Keepalive,
+                peer.asn,
+                peer.router_id
+            ))
+            .unwrap(),
+            (BGP_MSG_KEEPALIVE, vec![])
+        );
+        let cease = decode_vx0(BGP_MSG_NOTIFICATION, &FRR_CEASE[BGP_HEADER_LEN..], peer).unwrap();
+        assert_eq!(cease.message_type, BGPMessageType::Notification);
+        assert_eq!(cease.asn, 66001);
+        let n = cease.notification.unwrap();
+        assert_eq!((n.error_code, n.error_subcode), (6, 2));
+    }
+
+    #[test]
+    fn test_vx0_extensions_round_trip() {
+        let peer = vx0_peer();
+        let open = protocol::BGPMessage {
+            resume: Some(ResumeHello {
+                nonce: vec![7; 32],
+                offer: Some(ResumeOffer {
+                    token: [3; 16],
+                    proof: vec![9; 32],
+                }),
+                accepted: Some(ResumeAccepted {
+                    proof: vec![5; 32],
+                    table_version: 41,
+                }),
+            }),
+            sync_resume: Some(SyncResume {
+                table_version: 40,
+                next_chunk: 2,
+            }),
+            observed_addr: Some("fd00::2".parse().unwrap()),
+            binary_messages: true,
+            ..protocol::BGPMessage::new(BGPMessageType::Open, peer.asn, peer.router_id)
+        };
+        let (msg_type, body) = encode_vx0(&open).unwrap();
+        let decoded = decode_vx0(msg_type, &body, peer).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&open).unwrap()
+        );
+
+        let chunk = protocol::BGPMessage {
+            routes: vec![BGPRoute {
+                network: "fd00:60::/32".parse().unwrap(),
+                next_hop: "fd00::1".parse().unwrap(),
+                as_path: vec![66001, 65001],
+                origin: BGPOrigin::Incomplete,
+                local_pref: 200,
+                med: 10,
+                originated_at: Some(vx0_timestamp()),
+            }],
+            chunk: Some(TableChunk {
+                table_version: 41,
+                seq: 2,
+                total: 3,
+                digest: Some("ab".repeat(32)),
+            }),
+            ..protocol::BGPMessage::new(BGPMessageType::Update, peer.asn, peer.router_id)
+        };
+        let (msg_type, body) = encode_vx0(&chunk).unwrap();
+        let decoded = decode_vx0(msg_type, &body, peer).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&chunk).unwrap()
+        );
+
+        // Acknowledging a chunk takes an UPDATE, read back as the KEEPALIVE
+        let ack = protocol::BGPMessage {
+            chunk_ack: Some(2),
+            ..protocol::BGPMessage::new(BGPMessageType::Keepalive, peer.asn, peer.router_id)
+        };
+        let (msg_type, body) = encode_vx0(&ack).unwrap();
+        assert_eq!(msg_type, BGP_MSG_UPDATE);
+        let decoded = decode_vx0(msg_type, &body, peer).unwrap();
+        assert_eq!(decoded.message_type, BGPMessageType::Keepalive);
+        assert_eq!(decoded.chunk_ack, Some(2));
+    }
+
+    #[test]
+    fn test_vx0_errors_carry_rfc_4271_codes() {
+        let peer = vx0_peer();
+        // Routes belong in VX0_ROUTES, not the NLRI field
+        let mut nlri = vx0_update_body();
+        nlri.extend_from_slice(&[0x18, 0x0a, 0x3c, 0x03]);
+        assert!(matches!(
+            decode_vx0(BGP_MSG_UPDATE, &nlri, peer),
+            Err(BGPError::Protocol {
+                code: BGP_ERROR_UPDATE_MESSAGE,
+                subcode: UPDATE_INVALID_NETWORK_FIELD,
+                ..
+            })
+        ));
+
+        // The VX0 capability claims more extension bytes than it holds
+        let mut open = vx0_open_body();
+        open[21] = 0x0d;
+        assert!(matches!(
+            decode_vx0(BGP_MSG_OPEN, &open, peer),
+            Err(BGPError::Protocol {
+                code: BGP_ERROR_OPEN_MESSAGE,
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            decode_vx0(BGP_MSG_KEEPALIVE, &[0], peer),
+            Err(BGPError::Protocol {
+                code: BGP_ERROR_MESSAGE_HEADER,
+                subcode: HEADER_BAD_MESSAGE_LENGTH,
+                ..
+            })
+        ));
+        assert!(matches!(
+            decode_vx0(BGP_MSG_NOTIFICATION, &[6], peer),
+            Err(BGPError::Protocol {
+                subcode: HEADER_BAD_MESSAGE_LENGTH,
+                ..
+            })
+        ));
+
+        // A route without a next hop
+        let mut missing = vx0_update_body();
+        missing[41] = 0xee;
+        assert!(matches!(
+            decode_vx0(BGP_MSG_UPDATE, &missing, peer),
+            Err(BGPError::Protocol {
+                subcode: UPDATE_MISSING_WELL_KNOWN_ATTRIBUTE,
+                ..
+            })
+        ));
+    }
+
+    async fn connected() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (receiver, _) = listener.accept().await.unwrap();
+        (sender, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip_over_a_socket() {
+        let (mut sender, mut receiver) = connected().await;
+        let limit = Some(Duration::from_secs(5));
+
+        let large = vec![b'x'; BGP_MAX_MESSAGE_LEN * 4];
+        let frames: [(u8, &[u8]); 4] = [
+            (BGP_MSG_OPEN, b"{\"asn\":66001}"),
+            (BGP_MSG_UPDATE, &large),
+            (BGP_MSG_NOTIFICATION, b"{}"),
+            (BGP_MSG_KEEPALIVE, b""),
+        ];
+        for (msg_type, body) in frames {
+            write_frame(
+                &mut sender,
+                msg_type,
+                body,
+                BGP_MAX_EXTENDED_MESSAGE_LEN,
+                limit,
+            )
+            .await
+            .unwrap();
+        }
+        for (msg_type, body) in frames {
+            let frame = read_frame(&mut receiver, BGP_MAX_EXTENDED_MESSAGE_LEN, limit).await;
+            assert_eq!(frame.unwrap(), Some((msg_type, body.to_vec())));
+        }
+
+        // Nothing longer than the limit is sent, nor read past its header
+        assert!(write_frame(
+            &mut sender,
+            BGP_MSG_UPDATE,
+            &large,
+            BGP_MAX_MESSAGE_LEN,
+            limit
+        )
+        .await
+        .is_err());
+        write_frame(
+            &mut sender,
+            BGP_MSG_UPDATE,
+            &large,
+            BGP_MAX_EXTENDED_MESSAGE_LEN,
+            limit,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            read_frame(&mut receiver, BGP_MAX_MESSAGE_LEN, limit).await,
+            Err(BGPError::Protocol {
+                subcode: HEADER_BAD_MESSAGE_LENGTH,
+                ..
+            })
+        ));
+
+        let (mut sender, mut receiver) = connected().await;
+        sender.write_all(&with_header(9, b"{}")).await.unwrap();
+        assert!(matches!(
+            read_frame(&mut receiver, BGP_MAX_EXTENDED_MESSAGE_LEN, limit).await,
+            Err(BGPError::Protocol {
+                subcode: HEADER_BAD_MESSAGE_TYPE,
+                ..
+            })
+        ));
+
+        // A stream closed between messages is not an error
+        let (sender, mut receiver) = connected().await;
+        drop(sender);
+        let frame = read_frame(&mut receiver, BGP_MAX_EXTENDED_MESSAGE_LEN, limit).await;
+        assert_eq!(frame.unwrap(), None);
+    }
+}
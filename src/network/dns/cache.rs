@@ -0,0 +1,419 @@
+//! Answers the local DNS server gave, kept for reuse.
+//!
+//! Answers are cached by name and query type until their TTL runs out:
+//! addresses, or a negative answer (no such name, or no records of the
+//! type) for `negative_ttl`. Each entry notes where the answer came from:
+//! this node's own records, records synced from other nodes, or the rest of
+//! the network. Zone changes drop the entries for the names they touch, so
+//! only answers from the network go stale before their TTL runs out, and
+//! negative ones are the usual culprit; `vx0net dns cache flush` drops
+//! them. A lookup under way during a flush is unaffected and caches what
+//! it found once it completes.
+//!
+//! Flushes and listings take a `NamePattern`: an exact name, or `*.` and a
+//! suffix for every name under it.
+
+use crate::network::dns::DNSError;
+use crate::util::sync::lock;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long negative answers are cached unless configured
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// Where a cached answer came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheSource {
+    /// Records this node registered
+    Local,
+    /// Records synced from other nodes
+    Synced,
+    /// The rest of the VX0 network or a clearnet gateway
+    Forwarded,
+}
+
+impl fmt::Display for CacheSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CacheSource::Local => "local",
+            CacheSource::Synced => "synced",
+            CacheSource::Forwarded => "forwarded",
+        })
+    }
+}
+
+/// What a query was answered with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", content = "addresses", rename_all = "snake_case")]
+pub enum CachedAnswer {
+    Addresses(Vec<IpAddr>),
+    /// The name exists, without records of the queried type
+    NoData,
+    /// No such name
+    NxDomain,
+}
+
+impl CachedAnswer {
+    pub fn is_negative(&self) -> bool {
+        !matches!(self, CachedAnswer::Addresses(_))
+    }
+}
+
+/// Names a flush or listing applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamePattern {
+    Exact(String),
+    /// Every name ending in `.<suffix>`, not the suffix itself
+    Suffix(String),
+}
+
+impl NamePattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = normalize(name);
+        match self {
+            NamePattern::Exact(exact) => name == *exact,
+            NamePattern::Suffix(suffix) => name
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|rest| rest.ends_with('.') && rest.len() > 1),
+        }
+    }
+}
+
+impl FromStr for NamePattern {
+    type Err = DNSError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let pattern = normalize(pattern);
+        let (wildcard, name) = match pattern.strip_prefix("*.") {
+            Some(suffix) => (true, suffix.to_string()),
+            None => (false, pattern),
+        };
+        if name.is_empty() || name.contains('*') || name.split('.').any(str::is_empty) {
+            return Err(DNSError::InvalidDomain(format!(
+                "{} is not a name or *.suffix pattern",
+                name
+            )));
+        }
+        Ok(if wildcard {
+            NamePattern::Suffix(name)
+        } else {
+            NamePattern::Exact(name)
+        })
+    }
+}
+
+impl fmt::Display for NamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamePattern::Exact(name) => f.write_str(name),
+            NamePattern::Suffix(suffix) => write!(f, "*.{}", suffix),
+        }
+    }
+}
+
+/// Which entries a listing or flush covers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheFilter {
+    /// Every name when left out
+    pub pattern: Option<NamePattern>,
+    /// Only negative answers
+    pub negative_only: bool,
+}
+
+impl CacheFilter {
+    /// Every entry, or those of names matching `pattern`
+    pub fn new(pattern: Option<&str>, negative_only: bool) -> Result<Self, DNSError> {
+        Ok(CacheFilter {
+            pattern: pattern.map(str::parse).transpose()?,
+            negative_only,
+        })
+    }
+
+    fn matches(&self, name: &str, answer: &CachedAnswer) -> bool {
+        (!self.negative_only || answer.is_negative())
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.matches(name))
+    }
+}
+
+/// A cached answer as listed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct CacheEntryInfo {
+    pub name: String,
+    /// Query type number, e.g. 1 for A and 28 for AAAA
+    pub qtype: u16,
+    pub answer: CachedAnswer,
+    pub source: CacheSource,
+    pub ttl_remaining_secs: u64,
+    /// Times the answer was reused
+    pub hits: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub negative_entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries pushed out to make room
+    pub evictions: u64,
+    /// Entries dropped once their TTL ran out
+    pub expired: u64,
+    /// Entries dropped by flushes
+    pub flushed: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    answer: CachedAnswer,
+    source: CacheSource,
+    expires: Instant,
+    last_used: Instant,
+    hits: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<(String, u16), Entry>,
+    stats: CacheStats,
+}
+
+#[derive(Debug)]
+pub struct ResolverCache {
+    /// 0 turns caching off
+    max_entries: usize,
+    negative_ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Default for ResolverCache {
+    fn default() -> Self {
+        ResolverCache::new(1000, DEFAULT_NEGATIVE_TTL)
+    }
+}
+
+impl ResolverCache {
+    pub fn new(max_entries: usize, negative_ttl: Duration) -> Self {
+        ResolverCache {
+            max_entries,
+            negative_ttl,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn negative_ttl(&self) -> Duration {
+        self.negative_ttl
+    }
+
+    /// A cached answer and how long it has left, counting a hit or miss
+    pub fn get(&self, name: &str, qtype: u16) -> Option<(CachedAnswer, Duration)> {
+        let now = Instant::now();
+        let key = (normalize(name), qtype);
+        let mut inner = self.lock();
+        let Inner { entries, stats } = &mut *inner;
+        match entries.get_mut(&key) {
+            Some(entry) if entry.expires > now => {
+                entry.hits += 1;
+                entry.last_used = now;
+                stats.hits += 1;
+                Some((entry.answer.clone(), entry.expires - now))
+            }
+            Some(_) => {
+                entries.remove(&key);
+                stats.expired += 1;
+                stats.misses += 1;
+                None
+            }
+            None => {
+                stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache an answer for `ttl`, or `negative_ttl` if it is negative
+    pub fn insert(
+        &self,
+        name: &str,
+        qtype: u16,
+        answer: CachedAnswer,
+        source: CacheSource,
+        ttl: Duration,
+    ) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let ttl = if answer.is_negative() {
+            self.negative_ttl
+        } else {
+            ttl
+        };
+        let key = (normalize(name), qtype);
+        let mut inner = self.lock();
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.max_entries {
+            let before = inner.entries.len();
+            inner.entries.retain(|_, entry| entry.expires > now);
+            inner.stats.expired += (before - inner.entries.len()) as u64;
+        }
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.max_entries {
+            // Least recently used goes first
+            if let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            {
+                inner.entries.remove(&oldest);
+                inner.stats.evictions += 1;
+            }
+        }
+        inner.entries.insert(
+            key,
+            Entry {
+                answer,
+                source,
+                expires: now + ttl,
+                last_used: now,
+                hits: 0,
+            },
+        );
+    }
+
+    /// Drop every answer for `name`, after the records behind it changed
+    pub fn invalidate(&self, name: &str) {
+        let name = normalize(name);
+        self.lock().entries.retain(|(cached, _), _| *cached != name);
+    }
+
+    /// Drop the answers for every name under `zone`, and `zone` itself
+    pub fn invalidate_zone(&self, zone: &str) {
+        let zone = normalize(zone);
+        let suffix = format!(".{}", zone);
+        self.lock()
+            .entries
+            .retain(|(cached, _), _| *cached != zone && !cached.ends_with(&suffix));
+    }
+
+    /// Live entries `filter` covers, by name
+    pub fn list(&self, filter: &CacheFilter) -> Vec<CacheEntryInfo> {
+        let now = Instant::now();
+        let inner = self.lock();
+        let mut listed: Vec<CacheEntryInfo> = inner
+            .entries
+            .iter()
+            .filter(|((name, _), entry)| entry.expires > now && filter.matches(name, &entry.answer))
+            .map(|((name, qtype), entry)| CacheEntryInfo {
+                name: name.clone(),
+                qtype: *qtype,
+                answer: entry.answer.clone(),
+                source: entry.source,
+                ttl_remaining_secs: (entry.expires - now).as_secs(),
+                hits: entry.hits,
+            })
+            .collect();
+        listed.sort_by(|a, b| a.name.cmp(&b.name).then(a.qtype.cmp(&b.qtype)));
+        listed
+    }
+
+    /// Drop the entries `filter` covers, returning how many there were
+    pub fn flush(&self, filter: &CacheFilter) -> usize {
+        let mut inner = self.lock();
+        let before = inner.entries.len();
+        inner
+            .entries
+            .retain(|(name, _), entry| !filter.matches(name, &entry.answer));
+        let flushed = before - inner.entries.len();
+        inner.stats.flushed += flushed as u64;
+        flushed
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let now = Instant::now();
+        let inner = self.lock();
+        let live = || inner.entries.values().filter(|entry| entry.expires > now);
+        CacheStats {
+            entries: live().count(),
+            negative_entries: live().filter(|entry| entry.answer.is_negative()).count(),
+            ..inner.stats.clone()
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        lock(&self.inner)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: u16 = 1;
+
+    fn address(name: &str) -> CachedAnswer {
+        CachedAnswer::Addresses(vec![name.parse().unwrap()])
+    }
+
+    #[test]
+    fn test_patterns() {
+        let suffix: NamePattern = "*.community1.vx0".parse().unwrap();
+        assert!(suffix.matches("www.community1.vx0"));
+        assert!(suffix.matches("a.b.Community1.vx0."));
+        assert!(!suffix.matches("community1.vx0"));
+        assert!(!suffix.matches("xcommunity1.vx0"));
+
+        let exact: NamePattern = "Shop.vx0.".parse().unwrap();
+        assert_eq!(exact, NamePattern::Exact("shop.vx0".to_string()));
+        assert!(exact.matches("shop.vx0"));
+        assert!(!exact.matches("www.shop.vx0"));
+
+        for bad in ["", "*.", "www*.vx0", "*.*.vx0", "a..vx0"] {
+            assert!(bad.parse::<NamePattern>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = ResolverCache::new(2, DEFAULT_NEGATIVE_TTL);
+        let ttl = Duration::from_secs(60);
+        cache.insert("a.vx0", A, address("10.0.0.1"), CacheSource::Local, ttl);
+        cache.insert("b.vx0", A, address("10.0.0.2"), CacheSource::Local, ttl);
+        assert!(cache.get("a.vx0", A).is_some());
+        cache.insert("c.vx0", A, address("10.0.0.3"), CacheSource::Local, ttl);
+
+        assert!(cache.get("b.vx0", A).is_none());
+        assert!(cache.get("a.vx0", A).is_some());
+        let stats = cache.stats();
+        assert_eq!(
+            (stats.entries, stats.evictions, stats.hits, stats.misses),
+            (2, 1, 2, 1)
+        );
+    }
+
+    #[test]
+    fn test_negative_answers_use_their_own_ttl() {
+        let cache = ResolverCache::new(10, Duration::ZERO);
+        cache.insert(
+            "gone.vx0",
+            A,
+            CachedAnswer::NxDomain,
+            CacheSource::Forwarded,
+            Duration::from_secs(60),
+        );
+        assert!(cache.get("gone.vx0", A).is_none());
+        assert_eq!(cache.stats().expired, 1);
+    }
+}
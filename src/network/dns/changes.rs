@@ -0,0 +1,176 @@
+//! Change notifications from the node's directory.
+//!
+//! The daemon keeps a single directory, a [`SharedDns`](super::SharedDns),
+//! and hands the same handle to the DNS server and its resolver, the
+//! service registry, the node and zone sync, so whatever one of them
+//! registers the others see at once. Every change to a zone, whether
+//! committed here (bumping the zone's serial) or applied from a primary's
+//! transfer, is announced to subscribers with the serial it left the zone
+//! at; zone sync follows the announcements to notify secondaries. A
+//! subscriber that falls more than `CHANGE_QUEUE` changes behind misses
+//! the oldest, but the next change it sees carries the zone's latest serial.
+
+use crate::network::dns::cache::CacheSource;
+use crate::network::dns::zone::ZoneChange;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use tokio::sync::broadcast;
+
+/// Changes kept for subscribers that have not caught up
+const CHANGE_QUEUE: usize = 256;
+
+/// A zone moved to a new serial
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DnsChange {
+    pub zone: String,
+    pub serial: u32,
+    /// Names whose records were added or removed, sorted
+    pub names: Vec<String>,
+    /// `Local` when this node made the change, `Synced` when a transfer did
+    pub source: CacheSource,
+}
+
+impl DnsChange {
+    pub(crate) fn new(
+        zone: impl Into<String>,
+        serial: u32,
+        names: BTreeSet<String>,
+        source: CacheSource,
+    ) -> Self {
+        DnsChange {
+            zone: zone.into(),
+            serial,
+            names: names.into_iter().collect(),
+            source,
+        }
+    }
+}
+
+/// Names the records in `changes` belong to
+pub(crate) fn names_of<'a>(changes: impl IntoIterator<Item = &'a ZoneChange>) -> BTreeSet<String> {
+    changes
+        .into_iter()
+        .map(|change| match change {
+            ZoneChange::Add { record } | ZoneChange::Remove { record } => record.name.clone(),
+        })
+        .collect()
+}
+
+/// Where changes are announced; clones announce to the same subscribers
+#[derive(Debug, Clone)]
+pub struct ChangeFeed(broadcast::Sender<DnsChange>);
+
+impl ChangeFeed {
+    pub fn subscribe(&self) -> broadcast::Receiver<DnsChange> {
+        self.0.subscribe()
+    }
+
+    pub(crate) fn announce(&self, change: DnsChange) {
+        // Nobody listening is fine
+        let _ = self.0.send(change);
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        ChangeFeed(broadcast::channel(CHANGE_QUEUE).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::dns::zone::{self, ZoneTransfer};
+    use crate::network::dns::Vx0DNS;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    const SERVICES: u8 = 64;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_parallel_registrations_are_neither_lost_nor_unannounced() {
+        let dns = Vx0DNS::new().into_shared();
+        let mut changes = dns.read().await.subscribe();
+        let start = dns.read().await.serial("vx0").unwrap();
+
+        // Registrations race each other and a stream of resolutions
+        let reader = {
+            let dns = Arc::clone(&dns);
+            tokio::spawn(async move {
+                for _ in 0..SERVICES {
+                    let address = dns.read().await.resolve_vx0_domain("gateway.vx0").await;
+                    assert_eq!(address, Some(IpAddr::from([10, 0, 0, 1])));
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+        let writers: Vec<_> = (0..SERVICES)
+            .map(|i| {
+                let dns = Arc::clone(&dns);
+                tokio::spawn(async move {
+                    let name = format!("svc{}.vx0", i);
+                    let address = IpAddr::from([10, 9, 0, i]);
+                    dns.write()
+                        .await
+                        .register_service(name.clone(), address)
+                        .unwrap();
+                    dns.read().await.resolve_vx0_domain(&name).await
+                })
+            })
+            .collect();
+        for (i, writer) in writers.into_iter().enumerate() {
+            assert_eq!(
+                writer.await.unwrap(),
+                Some(IpAddr::from([10, 9, 0, i as u8]))
+            );
+        }
+        reader.await.unwrap();
+
+        let dns = dns.read().await;
+        for i in 0..SERVICES {
+            assert!(dns.get_records(&format!("svc{}.vx0", i)).is_some());
+        }
+        // One serial bump and one announcement per registration, in order
+        let mut serial = start;
+        for _ in 0..SERVICES {
+            let change = changes.try_recv().unwrap();
+            assert_eq!(change.source, CacheSource::Local);
+            assert_eq!(change.names.len(), 1);
+            assert!(zone::serial_newer(change.serial, serial));
+            serial = change.serial;
+        }
+        assert!(changes.try_recv().is_err());
+        assert_eq!(dns.serial("vx0"), Some(serial));
+        match dns.transfer_since("vx0", start).unwrap() {
+            ZoneTransfer::Incremental { entries, .. } => {
+                assert_eq!(entries.len(), SERVICES as usize)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfers_are_announced_as_synced() {
+        let mut primary = Vx0DNS::new();
+        primary
+            .register_service("wiki.vx0".to_string(), IpAddr::from([10, 9, 0, 1]))
+            .unwrap();
+        let mut secondary = Vx0DNS::new();
+        let mut changes = secondary.subscribe();
+
+        secondary
+            .apply_transfer(primary.transfer_since("vx0", 1).unwrap())
+            .unwrap();
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.source, CacheSource::Synced);
+        assert_eq!(change.names, ["wiki.vx0"]);
+        assert_eq!(Some(change.serial), primary.serial("vx0"));
+
+        // Nothing new to apply, nothing announced
+        let serial = secondary.serial("vx0").unwrap();
+        secondary
+            .apply_transfer(primary.transfer_since("vx0", serial).unwrap())
+            .unwrap();
+        assert!(changes.try_recv().is_err());
+    }
+}
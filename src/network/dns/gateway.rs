@@ -0,0 +1,506 @@
+//! Opt-in clearnet gateways.
+//!
+//! VX0 resolvers never resolve non-.vx0 names themselves. A node configured
+//! as a gateway answers queries for an explicit allowlist of external
+//! domains using the system resolver, and advertises itself under
+//! `_gateway.vx0` with that allowlist. Resolvers that opt in forward
+//! matching queries to a gateway; everything else stays blocked.
+//!
+//! The gateway listens on the node's VX0 address and answers only its
+//! current peers, each query and reply one bounded JSON message sealed by
+//! the tunnel to the peer (see [`TunnelChannel`]). A caller is whichever
+//! peer holds the tunnel that opened its query, not the address it dials
+//! from, so adverts name the gateway's node as well as where it listens.
+
+use crate::config::GatewayConfig;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::dns::DNSError;
+use crate::network::ike::channel::TunnelChannel;
+use crate::node::{NodeId, Vx0Node};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+
+/// Directory name gateway nodes register under
+pub const GATEWAY_RECORD: &str = "_gateway.vx0";
+
+const GATEWAY_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest query or reply; a query carries one domain name, a reply its
+/// addresses
+const MAX_GATEWAY_MESSAGE_LEN: usize = 4096;
+
+/// External domains a gateway is willing to resolve
+///
+/// An entry covers the domain itself and all of its subdomains.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GatewayAllowlist {
+    domains: Vec<String>,
+}
+
+impl GatewayAllowlist {
+    pub fn new(domains: &[String]) -> Self {
+        GatewayAllowlist {
+            domains: domains
+                .iter()
+                .map(|domain| normalize(domain))
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn allows(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+        if domain.ends_with(".vx0") || domain == "vx0" {
+            return false;
+        }
+        self.domains.iter().any(|allowed| {
+            domain == *allowed
+                || domain
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// A gateway as registered in the directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GatewayAdvert {
+    pub address: SocketAddr,
+    pub allowlist: GatewayAllowlist,
+    /// The gateway's node, whose tunnel seals queries to it; adverts from
+    /// before queries were sealed have none and are not used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<NodeId>,
+}
+
+/// One lookup, one per message. Strict: a query carrying options this gateway
+/// doesn't know is refused rather than answered as a plain lookup
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayQuery {
+    pub domain: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum GatewayReply {
+    Resolved { addresses: Vec<IpAddr> },
+    Refused { reason: String },
+}
+
+/// Where an answer came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolutionSource {
+    Vx0,
+    /// Resolved on the clearnet by this gateway
+    Gateway(SocketAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    pub address: IpAddr,
+    pub source: ResolutionSource,
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            ResolutionSource::Vx0 => write!(f, "{}", self.address),
+            ResolutionSource::Gateway(gateway) => {
+                write!(f, "{} [gateway-resolved via {}]", self.address, gateway)
+            }
+        }
+    }
+}
+
+/// Serves allowlisted clearnet lookups to other VX0 nodes
+pub struct GatewayService {
+    allowlist: Arc<GatewayAllowlist>,
+}
+
+impl GatewayService {
+    pub fn new(config: &GatewayConfig) -> Self {
+        GatewayService {
+            allowlist: Arc::new(GatewayAllowlist::new(&config.allowlist)),
+        }
+    }
+
+    pub fn allowlist(&self) -> &GatewayAllowlist {
+        &self.allowlist
+    }
+
+    /// Listen for gateway queries from `node`'s peers, returning the bound
+    /// address
+    pub async fn start(
+        &self,
+        bind_addr: SocketAddr,
+        node: Arc<Vx0Node>,
+    ) -> Result<SocketAddr, DNSError> {
+        if bind_addr.ip().is_unspecified() {
+            return Err(DNSError::Network(format!(
+                "Refusing to serve the gateway on every interface ({})",
+                bind_addr
+            )));
+        }
+        let listener = TcpListener::bind(bind_addr).await?;
+        let local_addr = listener.local_addr()?;
+        tracing::info!("Clearnet gateway listening on {}", local_addr);
+
+        let allowlist = Arc::clone(&self.allowlist);
+        crash::spawn(Subsystem::Dns, "gateway", async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, caller)) => {
+                        let allowlist = Arc::clone(&allowlist);
+                        let node = Arc::clone(&node);
+                        crash::spawn(Subsystem::Dns, "gateway-client", async move {
+                            // Only a peer's tunnel can open its queries
+                            let Some(tunnel_id) = node.tunnel_manager.tunnel_to(caller.ip()).await
+                            else {
+                                tracing::warn!(
+                                    target: "audit",
+                                    "Refused gateway query from {}, no tunnel",
+                                    caller
+                                );
+                                return;
+                            };
+                            let Some(peer_id) = node
+                                .admits_tunnel_peer(tunnel_id, caller.ip(), "gateway query")
+                                .await
+                            else {
+                                return;
+                            };
+                            let channel = TunnelChannel::new(
+                                stream,
+                                Arc::clone(&node.tunnel_manager),
+                                caller.ip(),
+                            );
+                            if let Err(e) = Self::handle_client(channel, &allowlist).await {
+                                tracing::debug!("Gateway session with {} ended: {}", peer_id, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        tracing::error!("Gateway accept error: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    async fn handle_client(
+        mut channel: TunnelChannel,
+        allowlist: &GatewayAllowlist,
+    ) -> Result<(), DNSError> {
+        while let Some(query) = receive(&mut channel).await? {
+            let reply = match serde_json::from_slice::<GatewayQuery>(&query) {
+                Ok(query) => Self::answer(&query.domain, allowlist).await,
+                Err(e) => GatewayReply::Refused {
+                    reason: format!("Invalid query: {}", e),
+                },
+            };
+            send(&mut channel, &reply).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn answer(domain: &str, allowlist: &GatewayAllowlist) -> GatewayReply {
+        if !allowlist.allows(domain) {
+            tracing::warn!("Gateway refused non-allowlisted domain: {}", domain);
+            return GatewayReply::Refused {
+                reason: format!("{} is not on this gateway's allowlist", domain),
+            };
+        }
+
+        match tokio::net::lookup_host((domain, 0)).await {
+            Ok(addrs) => {
+                let mut addresses: Vec<IpAddr> = addrs.map(|addr| addr.ip()).collect();
+                addresses.dedup();
+                tracing::info!("Gateway resolved {} to {:?}", domain, addresses);
+                GatewayReply::Resolved { addresses }
+            }
+            Err(e) => GatewayReply::Refused {
+                reason: format!("Failed to resolve {}: {}", domain, e),
+            },
+        }
+    }
+}
+
+/// The next message on `channel`, if it arrives in time and is no larger
+/// than any query or reply
+async fn receive(channel: &mut TunnelChannel) -> Result<Option<Vec<u8>>, DNSError> {
+    let message = timeout(GATEWAY_QUERY_TIMEOUT, channel.recv())
+        .await
+        .map_err(|_| DNSError::Network(format!("{} went quiet", channel.peer())))?
+        .map_err(|e| DNSError::Network(e.to_string()))?;
+    match message {
+        Some(message) if message.len() > MAX_GATEWAY_MESSAGE_LEN => Err(DNSError::Protocol(
+            format!("{}-byte gateway message", message.len()),
+        )),
+        message => Ok(message),
+    }
+}
+
+async fn send<T: Serialize>(channel: &mut TunnelChannel, message: &T) -> Result<(), DNSError> {
+    let message = serde_json::to_vec(message)?;
+    timeout(GATEWAY_QUERY_TIMEOUT, channel.send(&message))
+        .await
+        .map_err(|_| DNSError::Network(format!("{} went quiet", channel.peer())))?
+        .map_err(|e| DNSError::Network(e.to_string()))
+}
+
+/// Ask a gateway to resolve an external domain, over the tunnel to its
+/// node; only gateways that are peers of `node` can be asked
+pub async fn query_gateway(
+    node: &Vx0Node,
+    gateway: &GatewayAdvert,
+    domain: &str,
+) -> Result<Vec<IpAddr>, DNSError> {
+    let Some(node_id) = gateway.node_id else {
+        return Err(DNSError::Network(format!(
+            "Gateway {} does not say which node it is",
+            gateway.address
+        )));
+    };
+    let peer = match node.get_peer(&node_id).await {
+        Some(handle) => handle
+            .snapshot()
+            .await
+            .map_err(|e| DNSError::Network(e.to_string()))?,
+        None => {
+            return Err(DNSError::Network(format!(
+                "Gateway {} is not a peer",
+                node_id
+            )))
+        }
+    };
+    let exchange = async {
+        let stream = tokio::net::TcpStream::connect(gateway.address).await?;
+        let mut channel =
+            TunnelChannel::new(stream, Arc::clone(&node.tunnel_manager), peer.peer_addr);
+        send(
+            &mut channel,
+            &GatewayQuery {
+                domain: domain.to_string(),
+            },
+        )
+        .await?;
+        let reply = receive(&mut channel).await?.ok_or_else(|| {
+            DNSError::Network(format!("Gateway {} closed connection", gateway.address))
+        })?;
+        Ok::<_, DNSError>(serde_json::from_slice::<GatewayReply>(&reply)?)
+    };
+
+    match timeout(GATEWAY_QUERY_TIMEOUT, exchange).await {
+        Ok(Ok(GatewayReply::Resolved { addresses })) => Ok(addresses),
+        Ok(Ok(GatewayReply::Refused { reason })) => Err(DNSError::RecordNotFound(reason)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(DNSError::Network(format!(
+            "Gateway {} did not answer in time",
+            gateway.address
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::acl::AclEntry;
+    use crate::network::dns::resolver::Vx0Resolver;
+    use crate::node::{testing, NodeTier, PeerConnection};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn gateway() -> GatewayService {
+        GatewayService::new(&GatewayConfig {
+            enabled: true,
+            allowlist: vec!["localhost".to_string()],
+            ..GatewayConfig::default()
+        })
+    }
+
+    fn localhost() -> IpAddr {
+        "127.0.0.1".parse().unwrap()
+    }
+
+    /// A gateway node and an edge node, each the other's peer over a tunnel
+    /// on this host
+    async fn peered() -> [Arc<Vx0Node>; 2] {
+        let nodes = [
+            testing::node(NodeTier::Regional, |config| {
+                config.network.acl.state_file = None;
+            }),
+            testing::node(NodeTier::Edge, |_| {}),
+        ];
+        for (node, peer) in [(&nodes[0], &nodes[1]), (&nodes[1], &nodes[0])] {
+            node.add_peer(PeerConnection::new(peer.node_id, peer.asn, localhost()))
+                .await
+                .unwrap();
+            node.tunnel_manager
+                .create_tunnel(
+                    localhost(),
+                    localhost(),
+                    "127.0.0.1:4500".parse().unwrap(),
+                    b"gateway-psk",
+                )
+                .await
+                .unwrap();
+        }
+        nodes
+    }
+
+    #[test]
+    fn test_allowlist_matching() {
+        let allowlist = GatewayAllowlist::new(&["Example.org.".to_string()]);
+
+        assert!(allowlist.allows("example.org"));
+        assert!(allowlist.allows("wiki.example.org"));
+        assert!(!allowlist.allows("badexample.org"));
+        assert!(!allowlist.allows("example.com"));
+        assert!(!allowlist.allows("example.org.vx0"));
+    }
+
+    #[tokio::test]
+    async fn test_isolation_is_default() {
+        let resolver = Vx0Resolver::new(vec![]);
+        resolver
+            .register_gateway(GatewayAdvert {
+                address: "127.0.0.1:1".parse().unwrap(),
+                allowlist: GatewayAllowlist::new(&["localhost".to_string()]),
+                node_id: None,
+            })
+            .await;
+
+        // A registered gateway is ignored until the resolver opts in
+        assert_eq!(resolver.resolve("localhost").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_round_trip() {
+        let [gateway_node, client] = peered().await;
+        let address = gateway()
+            .start("127.0.0.1:0".parse().unwrap(), Arc::clone(&gateway_node))
+            .await
+            .unwrap();
+
+        let mut resolver = Vx0Resolver::new(vec![]).with_node(Arc::clone(&client));
+        resolver.set_use_gateways(true);
+        // The resolver is told a wider allowlist than the gateway enforces
+        let advert = GatewayAdvert {
+            address,
+            allowlist: GatewayAllowlist::new(&["localhost".to_string(), "example.org".to_string()]),
+            node_id: Some(gateway_node.node_id),
+        };
+        resolver.register_gateway(advert.clone()).await;
+
+        let resolution = resolver.resolve_tagged("localhost").await.unwrap().unwrap();
+        assert!(resolution.address.is_loopback());
+        assert_eq!(resolution.source, ResolutionSource::Gateway(address));
+        assert!(resolution.to_string().contains("gateway-resolved"));
+        // The query and its answer were sealed by the tunnels
+        let sealed = &client.tunnel_manager.list_tunnels().await[0].traffic_stats;
+        assert_eq!((sealed.packets_out, sealed.packets_in), (1, 1));
+
+        // The gateway enforces its own allowlist regardless
+        assert!(query_gateway(&client, &advert, "example.org")
+            .await
+            .is_err());
+        // Domains no gateway offers stay blocked without a round trip
+        assert_eq!(resolver.resolve("example.com").await.unwrap(), None);
+        // .vx0 names never leave the VX0 resolver
+        let vx0 = resolver.resolve_tagged("node1.vx0").await.unwrap().unwrap();
+        assert_eq!(vx0.source, ResolutionSource::Vx0);
+        // Gateways that are not peers cannot be asked
+        let stranger = GatewayAdvert {
+            node_id: Some(uuid::Uuid::new_v4()),
+            ..advert
+        };
+        assert!(query_gateway(&client, &stranger, "localhost")
+            .await
+            .is_err());
+    }
+
+    /// What the gateway at `address` sends back to `request` before closing
+    async fn exchange(address: SocketAddr, request: &[u8]) -> Vec<u8> {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply).await;
+        reply
+    }
+
+    /// `message` as sealed by `node`'s tunnel to this host
+    async fn sealed(node: &Vx0Node, message: &[u8]) -> Vec<u8> {
+        let mut framed = Vec::new();
+        crate::network::ike::channel::write_sealed(
+            &mut framed,
+            &node.tunnel_manager,
+            localhost(),
+            message,
+        )
+        .await
+        .unwrap();
+        framed
+    }
+
+    #[tokio::test]
+    async fn test_strangers_and_oversized_queries_are_refused() {
+        let [gateway_node, client] = peered().await;
+        assert!(gateway()
+            .start("0.0.0.0:0".parse().unwrap(), Arc::clone(&gateway_node))
+            .await
+            .is_err());
+        let address = gateway()
+            .start("127.0.0.1:0".parse().unwrap(), Arc::clone(&gateway_node))
+            .await
+            .unwrap();
+        let query = sealed(&client, b"{\"domain\":\"localhost\"}").await;
+        assert!(!exchange(address, &query).await.is_empty());
+
+        // A query in the clear is never opened as one
+        let clear = b"{\"domain\":\"localhost\"}\n";
+        assert!(exchange(address, clear).await.is_empty());
+        // A message longer than any query ends the session unanswered
+        let oversized = vec![b'a'; MAX_GATEWAY_MESSAGE_LEN * 2];
+        let oversized = sealed(&client, &oversized).await;
+        assert!(exchange(address, &oversized).await.is_empty());
+
+        // Without a tunnel there is no peer to answer
+        let tunnel = gateway_node.tunnel_manager.tunnel_to(localhost()).await;
+        gateway_node
+            .tunnel_manager
+            .close_tunnel(&tunnel.unwrap())
+            .await
+            .unwrap();
+        assert!(exchange(address, &query).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_peers_the_acl_blocks_are_refused() {
+        let [gateway_node, client] = peered().await;
+        let address = gateway()
+            .start("127.0.0.1:0".parse().unwrap(), Arc::clone(&gateway_node))
+            .await
+            .unwrap();
+        let query = sealed(&client, b"{\"domain\":\"localhost\"}").await;
+
+        // Blocked by node, whatever address it dials from
+        gateway_node
+            .acl
+            .block(AclEntry::Node(client.node_id))
+            .unwrap();
+        assert!(exchange(address, &query).await.is_empty());
+        assert_eq!(gateway_node.acl.denied(), 1);
+    }
+}
@@ -0,0 +1,159 @@
+//! Ordering answers for names hosted on several nodes.
+//!
+//! When a name has more than one address, clients mostly try the first.
+//! Each address is scored at answer time from the Loc-RIB and peer
+//! metrics: addresses behind a usable route come first, lowest next-hop
+//! peer latency leading, and addresses nothing routes to go last or are
+//! left out. Scores are cached briefly so a burst of queries costs one RIB
+//! lookup per address, and answers that score the same take turns at the
+//! front to spread load.
+
+use crate::config::DNSConfig;
+use crate::network::bgp::BGPDaemon;
+use crate::node::{ConnectionStatus, Vx0Node};
+use crate::util::sync::lock;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// Covered by a usable route; `latency_ms` is that of the peer it was
+    /// learned from, zero for this node's own addresses, and unset when
+    /// not measured
+    Reachable { latency_ms: Option<u64> },
+    /// No route, only an aggregate, a route from a failed or departed peer,
+    /// or one whose next hop cannot be forwarded to
+    Unreachable,
+}
+
+impl Health {
+    /// Lower sorts first
+    fn rank(&self) -> (bool, bool, u64) {
+        match self {
+            Health::Reachable { latency_ms } => {
+                (false, latency_ms.is_none(), latency_ms.unwrap_or(0))
+            }
+            Health::Unreachable => (true, false, 0),
+        }
+    }
+}
+
+pub struct AnswerRanking {
+    bgp: Arc<BGPDaemon>,
+    node: Option<Arc<Vx0Node>>,
+    omit_unreachable: bool,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<IpAddr, (Instant, Health)>>,
+    rotation: AtomicUsize,
+}
+
+impl AnswerRanking {
+    pub fn new(bgp: Arc<BGPDaemon>, config: &DNSConfig) -> Self {
+        AnswerRanking {
+            bgp,
+            node: None,
+            omit_unreachable: config.omit_unreachable,
+            cache_ttl: Duration::from_millis(config.health_cache_ms),
+            cache: Mutex::new(HashMap::new()),
+            rotation: AtomicUsize::new(0),
+        }
+    }
+
+    /// Judge routes by the state and latency of the peers they came from
+    pub fn with_node(mut self, node: Arc<Vx0Node>) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// `addresses` healthiest first, without unreachable ones when
+    /// `dns.omit_unreachable` is set
+    pub async fn order(&self, addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+        let mut scored = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let health = self.health(address).await;
+            if self.omit_unreachable && health == Health::Unreachable {
+                continue;
+            }
+            scored.push((health.rank(), address));
+        }
+        // Keep the record order within a rank so rotation is stable
+        scored.sort_by_key(|(rank, _)| *rank);
+
+        let turn = self.rotation.fetch_add(1, Ordering::Relaxed);
+        for group in scored.chunk_by_mut(|(a, _), (b, _)| a == b) {
+            let len = group.len();
+            group.rotate_left(turn % len);
+        }
+        scored.into_iter().map(|(_, address)| address).collect()
+    }
+
+    pub async fn health(&self, address: IpAddr) -> Health {
+        if let Some((at, health)) = lock(&self.cache).get(&address) {
+            if at.elapsed() < self.cache_ttl {
+                return *health;
+            }
+        }
+
+        let health = self.score(address).await;
+        lock(&self.cache).insert(address, (Instant::now(), health));
+        health
+    }
+
+    async fn score(&self, address: IpAddr) -> Health {
+        if let Some(node) = &self.node {
+            if address == IpAddr::V4(node.ipv4_addr) || address == IpAddr::V6(node.ipv6_addr) {
+                return Health::Reachable {
+                    latency_ms: Some(0),
+                };
+            }
+        }
+
+        let Some(route) = self.bgp.find_best_route(&address).await else {
+            return Health::Unreachable;
+        };
+        if route.unresolved_next_hop() {
+            return Health::Unreachable;
+        }
+        let Some(peer) = route.learned_from else {
+            // Our own host routes lead here; a locally originated aggregate
+            // says nothing about whether anyone answers behind it
+            return if route.network.prefix_len() == route.network.max_prefix_len() {
+                Health::Reachable {
+                    latency_ms: Some(0),
+                }
+            } else {
+                Health::Unreachable
+            };
+        };
+
+        let Some(node) = &self.node else {
+            return Health::Reachable { latency_ms: None };
+        };
+        let connection = node
+            .list_peers()
+            .await
+            .into_iter()
+            .find(|connection| connection.peer_asn == peer.asn);
+        match connection {
+            Some(connection)
+                if matches!(
+                    connection.status,
+                    ConnectionStatus::Failed
+                        | ConnectionStatus::Departed
+                        | ConnectionStatus::AdminDown
+                        | ConnectionStatus::Quarantined
+                ) =>
+            {
+                Health::Unreachable
+            }
+            Some(connection) if connection.metrics.latency_ms > 0 => Health::Reachable {
+                latency_ms: Some(connection.metrics.latency_ms),
+            },
+            // External routers are not node peers and have no metrics
+            _ => Health::Reachable { latency_ms: None },
+        }
+    }
+}
@@ -0,0 +1,421 @@
+//! Who registered what under a name, and when.
+//!
+//! Every change to a name's records, whether committed here or applied
+//! from a primary's transfer, is noted in the name's history with the node
+//! it came from and the records before and after. Changes committed here
+//! are attested by this node; those from transfers are only as good as the
+//! primary's word and the registering node's clock, and are marked remote.
+//! When a change takes away records another node registered, those records
+//! are kept in the entry, so a dispute over a name can be settled from the
+//! history rather than from whichever registration happens to be held now.
+//!
+//! TXT records are left out: they hold the node and service directories
+//! and node metadata, which change as nodes come and go rather than as
+//! anyone registers a name. Each name keeps its latest `per_domain`
+//! entries and the history as a whole its latest `max_entries`, dropping
+//! the oldest first; it is persisted with the records.
+
+use crate::config::DnsHistoryConfig;
+use crate::network::dns::{DNSRecord, RecordType};
+use crate::node::NodeId;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryEvent {
+    Registered,
+    Updated,
+    Deregistered,
+}
+
+impl fmt::Display for HistoryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HistoryEvent::Registered => "registered",
+            HistoryEvent::Updated => "updated",
+            HistoryEvent::Deregistered => "deregistered",
+        })
+    }
+}
+
+/// Who vouches for an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Attestation {
+    /// This node made the change
+    Local,
+    /// A primary's transfer carried it
+    Remote,
+}
+
+impl fmt::Display for Attestation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Attestation::Local => "local",
+            Attestation::Remote => "remote-attested",
+        })
+    }
+}
+
+/// One record as some node registered it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Registration {
+    /// `None` for records registered before origins were stamped
+    pub origin: Option<NodeId>,
+    /// Record type and data, e.g. "A 10.0.0.1"
+    pub data: String,
+    pub registered_at: DateTime<Utc>,
+}
+
+/// A change to one name's records
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct HistoryEntry {
+    /// Order the entry was noted in here
+    pub seq: u64,
+    /// When this node made the change, or when the registering node says
+    /// it registered what the change added
+    pub at: DateTime<Utc>,
+    pub domain: String,
+    pub event: HistoryEvent,
+    /// Node that made the change; `None` when a transfer only removed
+    /// records and so does not say who did
+    pub origin: Option<NodeId>,
+    pub attestation: Attestation,
+    /// Records held before and after, as type and data
+    pub old: Vec<String>,
+    pub new: Vec<String>,
+    /// Records of other nodes this change took away
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub displaced: Vec<Registration>,
+}
+
+/// Bounded per-name history of registrations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrationHistory {
+    domains: HashMap<String, VecDeque<HistoryEntry>>,
+    next_seq: u64,
+    len: usize,
+    per_domain: usize,
+    max_entries: usize,
+}
+
+impl Default for RegistrationHistory {
+    fn default() -> Self {
+        RegistrationHistory::new(&DnsHistoryConfig::default())
+    }
+}
+
+impl RegistrationHistory {
+    pub fn new(config: &DnsHistoryConfig) -> Self {
+        RegistrationHistory {
+            domains: HashMap::new(),
+            next_seq: 0,
+            len: 0,
+            per_domain: config.per_domain.max(1),
+            max_entries: config.max_entries.max(1),
+        }
+    }
+
+    pub fn set_limits(&mut self, config: &DnsHistoryConfig) {
+        self.per_domain = config.per_domain.max(1);
+        self.max_entries = config.max_entries.max(1);
+        let domains: Vec<String> = self.domains.keys().cloned().collect();
+        for domain in domains {
+            self.trim_domain(&domain);
+        }
+        self.trim();
+    }
+
+    /// Note how a change left `domain`, going from `old` to `new` records.
+    /// Records naming no origin count as `local`'s, this node's.
+    pub(crate) fn note(
+        &mut self,
+        domain: &str,
+        old: &[DNSRecord],
+        new: &[DNSRecord],
+        attestation: Attestation,
+        local: Option<NodeId>,
+    ) {
+        let old: Vec<&DNSRecord> = old.iter().filter(|r| tracked(r)).collect();
+        let new: Vec<&DNSRecord> = new.iter().filter(|r| tracked(r)).collect();
+        let (old_data, new_data) = (describe_all(&old), describe_all(&new));
+        if old_data == new_data {
+            // Refreshes and re-registrations change nothing worth noting
+            return;
+        }
+
+        let held = |records: &[&DNSRecord], record: &DNSRecord| {
+            records
+                .iter()
+                .any(|r| r.record_type == record.record_type && r.data == record.data)
+        };
+        let added: Vec<&DNSRecord> = new.iter().copied().filter(|r| !held(&old, r)).collect();
+        let removed: Vec<&DNSRecord> = old.iter().copied().filter(|r| !held(&new, r)).collect();
+        let owner = |record: &DNSRecord| record.origin.or(local);
+        let (origin, at) = match attestation {
+            Attestation::Local => (local, Utc::now()),
+            Attestation::Remote => (
+                added.iter().find_map(|r| owner(r)),
+                added
+                    .iter()
+                    .map(|r| r.timestamp)
+                    .max()
+                    .unwrap_or_else(Utc::now),
+            ),
+        };
+        let displaced = removed
+            .iter()
+            .filter(|r| origin.is_some() && owner(r) != origin)
+            .map(|r| Registration {
+                origin: owner(r),
+                data: describe(r),
+                registered_at: r.timestamp,
+            })
+            .collect();
+        let event = if old_data.is_empty() {
+            HistoryEvent::Registered
+        } else if new_data.is_empty() {
+            HistoryEvent::Deregistered
+        } else {
+            HistoryEvent::Updated
+        };
+
+        let entry = HistoryEntry {
+            seq: self.next_seq,
+            at,
+            domain: domain.to_string(),
+            event,
+            origin,
+            attestation,
+            old: old_data,
+            new: new_data,
+            displaced,
+        };
+        self.next_seq += 1;
+        self.len += 1;
+        self.domains
+            .entry(domain.to_string())
+            .or_default()
+            .push_back(entry);
+        self.trim_domain(domain);
+        self.trim();
+    }
+
+    /// Entries for `domain`, oldest first
+    pub fn for_domain(&self, domain: &str) -> Vec<HistoryEntry> {
+        self.domains
+            .get(domain)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Entries for every name this node changed itself, oldest first
+    pub fn attested_locally(&self) -> Vec<HistoryEntry> {
+        let mut entries: Vec<HistoryEntry> = self
+            .domains
+            .values()
+            .filter(|entries| {
+                entries
+                    .iter()
+                    .any(|entry| entry.attestation == Attestation::Local)
+            })
+            .flatten()
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| entry.seq);
+        entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn trim_domain(&mut self, domain: &str) {
+        if let Some(entries) = self.domains.get_mut(domain) {
+            while entries.len() > self.per_domain {
+                entries.pop_front();
+                self.len -= 1;
+            }
+        }
+    }
+
+    /// Drop the oldest entries, whichever names they belong to, until the
+    /// history is back within `max_entries`
+    fn trim(&mut self) {
+        while self.len > self.max_entries {
+            let Some(oldest) = self
+                .domains
+                .iter()
+                .filter_map(|(domain, entries)| Some((entries.front()?.seq, domain)))
+                .min()
+                .map(|(_, domain)| domain.clone())
+            else {
+                break;
+            };
+            let entries = self.domains.get_mut(&oldest).expect("domain just found");
+            entries.pop_front();
+            self.len -= 1;
+            if entries.is_empty() {
+                self.domains.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn tracked(record: &DNSRecord) -> bool {
+    record.record_type != RecordType::TXT
+}
+
+fn describe(record: &DNSRecord) -> String {
+    format!("{:?} {}", record.record_type, record.data)
+}
+
+fn describe_all(records: &[&DNSRecord]) -> Vec<String> {
+    let mut described: Vec<String> = records.iter().map(|r| describe(r)).collect();
+    described.sort();
+    described.dedup();
+    described
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::dns::Vx0DNS;
+    use std::net::IpAddr;
+    use uuid::Uuid;
+
+    fn record(data: &str, origin: NodeId) -> DNSRecord {
+        DNSRecord {
+            name: "forum.community1.vx0".to_string(),
+            record_type: RecordType::A,
+            data: data.to_string(),
+            ttl: 300,
+            timestamp: Utc::now(),
+            origin: Some(origin),
+        }
+    }
+
+    #[test]
+    fn test_bounds_drop_the_oldest_first() {
+        let mut history = RegistrationHistory::new(&DnsHistoryConfig {
+            per_domain: 2,
+            max_entries: 3,
+        });
+        let origin = Uuid::new_v4();
+        let a = [record("10.0.0.1", origin)];
+        let b = [record("10.0.0.2", origin)];
+        let mut note = |domain, old: &[DNSRecord], new: &[DNSRecord]| {
+            history.note(domain, old, new, Attestation::Local, Some(origin))
+        };
+        for _ in 0..2 {
+            note("one.vx0", &[], &a);
+            note("one.vx0", &a, &b);
+        }
+        note("two.vx0", &[], &a);
+        note("three.vx0", &[], &a);
+
+        // Four changes to one name, the latest two kept until the other
+        // names push the oldest of those out too
+        assert_eq!(history.len(), 3);
+        let kept = history.for_domain("one.vx0");
+        assert_eq!(kept.iter().map(|e| e.seq).collect::<Vec<_>>(), [3]);
+        assert_eq!(history.for_domain("three.vx0")[0].seq, 5);
+    }
+
+    #[test]
+    fn test_overwrite_from_another_origin_keeps_both_registrations() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let domain = "forum.community1.vx0";
+        let mut first = Vx0DNS::new();
+        first.set_origin(b);
+        first
+            .register_service(domain.to_string(), IpAddr::from([10, 0, 0, 2]))
+            .unwrap();
+
+        // Synced here, then taken over
+        let mut second = Vx0DNS::new();
+        second.set_origin(a);
+        let serial = second.serial("vx0").unwrap();
+        second
+            .apply_transfer(first.transfer_since("vx0", serial).unwrap())
+            .unwrap();
+        second.deregister_service(domain).unwrap();
+        second
+            .register_service(domain.to_string(), IpAddr::from([10, 0, 0, 1]))
+            .unwrap();
+
+        let history = second.history(domain);
+        let summary: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.event, entry.origin, entry.attestation))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (HistoryEvent::Registered, Some(b), Attestation::Remote),
+                (HistoryEvent::Deregistered, Some(a), Attestation::Local),
+                (HistoryEvent::Registered, Some(a), Attestation::Local),
+            ]
+        );
+        assert_eq!(history[0].new, ["A 10.0.0.2"]);
+        assert_eq!(history[1].displaced.len(), 1);
+        assert_eq!(history[1].displaced[0].origin, Some(b));
+        assert_eq!(history[1].displaced[0].data, "A 10.0.0.2");
+        assert_eq!(history[2].new, ["A 10.0.0.1"]);
+        assert_eq!(second.local_history().len(), 3);
+
+        // The first registrant hears of the takeover, vouched for by the
+        // primary only
+        let serial = first.serial("vx0").unwrap();
+        first
+            .apply_transfer(second.transfer_since("vx0", serial).unwrap())
+            .unwrap();
+        let history = first.history(domain);
+        assert_eq!(history[0].origin, Some(b));
+        assert_eq!(history[0].attestation, Attestation::Local);
+        let last = history.last().unwrap();
+        assert_eq!(
+            (last.event, last.origin, last.attestation),
+            (HistoryEvent::Registered, Some(a), Attestation::Remote)
+        );
+
+        // Kept with the records
+        let path = std::env::temp_dir().join(format!("vx0net-dns-{}.json", Uuid::new_v4()));
+        second.save(&path).unwrap();
+        let restored = Vx0DNS::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.history(domain), second.history(domain));
+    }
+
+    #[test]
+    fn test_refreshes_and_text_records_are_not_noted() {
+        let mut history = RegistrationHistory::default();
+        let origin = Uuid::new_v4();
+        let a = record("10.0.0.1", origin);
+        let refreshed = DNSRecord {
+            timestamp: a.timestamp + chrono::Duration::seconds(60),
+            ..a.clone()
+        };
+        let text = DNSRecord {
+            record_type: RecordType::TXT,
+            ..a.clone()
+        };
+        history.note(
+            "one.vx0",
+            &[a],
+            &[refreshed],
+            Attestation::Local,
+            Some(origin),
+        );
+        history.note("one.vx0", &[], &[text], Attestation::Local, Some(origin));
+        assert!(history.is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+//! Node metadata for clients that only speak DNS.
+//!
+//! Every node publishes TXT records under `_node.<short hostname>.nodes.vx0`,
+//! one `key=value` pair each: its ASN, tier, software version, how many
+//! services it hosts and, only if the operator lists it, a contact. Backbone
+//! nodes also publish `_stats.vx0` with how many nodes of each tier the
+//! directory holds. Both are committed like any record this node registers,
+//! so zone sync carries them with this node as their origin, and monitoring
+//! scripts can ask `TXT _node.alice.nodes.vx0` without the peer protocol.
+
+use crate::build_info;
+use crate::config::{MetadataKey, NodeMetadataConfig};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::dns::{SharedDns, NODE_NAME_DOMAIN};
+use crate::node::capabilities::NodeDirectoryEntry;
+use crate::node::{NodeTier, Vx0Node};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+/// Label in front of a node's short hostname naming its metadata
+pub const NODE_METADATA_PREFIX: &str = "_node";
+
+/// Network-wide tier counts, published by Backbone nodes
+pub const STATS_RECORD: &str = "_stats.vx0";
+
+/// How often the node is checked for changes worth republishing
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the node called `hostname` publishes its metadata
+pub fn node_metadata_name(hostname: &str) -> String {
+    format!(
+        "{}.{}.{}",
+        NODE_METADATA_PREFIX,
+        crate::config::hostname::short_name(hostname),
+        NODE_NAME_DOMAIN
+    )
+}
+
+/// Whether the DNS server answers TXT queries for `name` from these records
+pub fn is_metadata_name(name: &str) -> bool {
+    name == STATS_RECORD
+        || name
+            .strip_prefix(NODE_METADATA_PREFIX)
+            .and_then(|rest| rest.strip_prefix('.'))
+            .and_then(|rest| rest.strip_suffix(NODE_NAME_DOMAIN))
+            .is_some_and(|host| host.len() > 1 && host.ends_with('.'))
+}
+
+/// What a node says about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeMetadata {
+    pub asn: u32,
+    pub tier: NodeTier,
+    pub version: String,
+    pub services: usize,
+}
+
+impl NodeMetadata {
+    pub async fn of(node: &Vx0Node) -> Self {
+        NodeMetadata {
+            asn: node.asn,
+            tier: node.tier.clone(),
+            version: build_info::VERSION.to_string(),
+            services: node.services.read().await.len(),
+        }
+    }
+
+    /// `key=value` texts for the keys `config` publishes, in its order
+    pub fn texts(&self, config: &NodeMetadataConfig) -> Vec<String> {
+        config
+            .keys
+            .iter()
+            .filter_map(|key| {
+                let value = match key {
+                    MetadataKey::Asn => self.asn.to_string(),
+                    MetadataKey::Tier => tier_label(&self.tier).to_string(),
+                    MetadataKey::Version => self.version.clone(),
+                    MetadataKey::Services => self.services.to_string(),
+                    MetadataKey::Contact if config.contact.is_empty() => return None,
+                    MetadataKey::Contact => config.contact.clone(),
+                };
+                Some(format!("{}={}", key.as_str(), value))
+            })
+            .collect()
+    }
+}
+
+/// `_stats.vx0` texts: nodes per tier in `nodes`, and their total
+pub fn network_stats(nodes: &[NodeDirectoryEntry]) -> Vec<String> {
+    let mut tiers: HashMap<_, NodeTier> = HashMap::new();
+    for node in nodes {
+        tiers.insert(node.node_id, NodeTier::from_asn(node.asn));
+    }
+    let mut texts: Vec<String> = [NodeTier::Backbone, NodeTier::Regional, NodeTier::Edge]
+        .iter()
+        .map(|tier| {
+            let count = tiers.values().filter(|node| *node == tier).count();
+            format!("{}={}", tier_label(tier), count)
+        })
+        .collect();
+    texts.push(format!("total={}", tiers.len()));
+    texts
+}
+
+fn tier_label(tier: &NodeTier) -> &'static str {
+    match tier {
+        NodeTier::Backbone => "backbone",
+        NodeTier::Regional => "regional",
+        NodeTier::Edge => "edge",
+    }
+}
+
+/// Keep this node's metadata, and on Backbone nodes the network stats,
+/// published as the node and the directory change
+pub fn start_publishing(node: Arc<Vx0Node>, dns: SharedDns, config: NodeMetadataConfig) {
+    if !config.enabled {
+        return;
+    }
+    crash::spawn_restartable(Subsystem::Dns, "node-metadata", move || {
+        let node = Arc::clone(&node);
+        let dns = Arc::clone(&dns);
+        let config = config.clone();
+        async move {
+            let name = node_metadata_name(&node.hostname);
+            let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let texts = NodeMetadata::of(&node).await.texts(&config);
+                let mut dns = dns.write().await;
+                if dns.publish_texts(&name, &texts) {
+                    tracing::debug!("Published {}: {}", name, texts.join(" "));
+                }
+                if node.tier == NodeTier::Backbone {
+                    let stats = network_stats(&dns.nodes());
+                    dns.publish_texts(STATS_RECORD, &stats);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> NodeMetadata {
+        NodeMetadata {
+            asn: 66001,
+            tier: NodeTier::Edge,
+            version: "0.1.0".to_string(),
+            services: 2,
+        }
+    }
+
+    #[test]
+    fn test_texts_follow_the_published_keys() {
+        let mut config = NodeMetadataConfig {
+            contact: "ops@alice.vx0".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            metadata().texts(&config),
+            ["asn=66001", "tier=edge", "version=0.1.0", "services=2"]
+        );
+
+        // The contact is private until listed
+        config.keys = vec![MetadataKey::Tier, MetadataKey::Contact];
+        assert_eq!(
+            metadata().texts(&config),
+            ["tier=edge", "contact=ops@alice.vx0"]
+        );
+        config.contact.clear();
+        assert_eq!(metadata().texts(&config), ["tier=edge"]);
+    }
+
+    #[test]
+    fn test_metadata_names() {
+        let name = node_metadata_name("Alice.example.org");
+        assert_eq!(name, "_node.alice.nodes.vx0");
+        assert!(is_metadata_name(&name));
+        assert!(is_metadata_name(STATS_RECORD));
+        assert!(!is_metadata_name("_node.nodes.vx0"));
+        assert!(!is_metadata_name("alice.nodes.vx0"));
+    }
+}
@@ -1,15 +1,76 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Arc;
 use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, RwLock};
 
+use crate::config::{DnsHistoryConfig, SyncQuotaConfig};
+use crate::node::capabilities::NodeDirectoryEntry;
+use crate::node::search::{ServiceFilter, ServiceSummary};
+use crate::node::NodeId;
+use cache::{CacheSource, ResolverCache};
+use changes::{ChangeFeed, DnsChange};
+use gateway::{GatewayAdvert, GATEWAY_RECORD};
+use history::{Attestation, HistoryEntry, RegistrationHistory};
+use quota::{OriginUsage, ResolutionLog, SyncQuotas, UNATTRIBUTED};
+use zone::{JournalEntry, ZoneChange, ZoneJournal, ZoneTransfer};
+
+pub mod cache;
+pub mod changes;
+pub mod gateway;
+#[cfg(feature = "dns-server")]
+pub mod health;
+pub mod history;
+pub mod metadata;
+pub mod quota;
 pub mod resolver;
+#[cfg(feature = "dns-server")]
 pub mod server;
+pub mod sync;
+pub mod wire;
+pub mod zone;
+
+/// TTL for records registered without an explicit one
+pub const DEFAULT_RECORD_TTL: u32 = 300;
+
+/// Directory of nodes and the capabilities they advertise
+pub const NODE_RECORD: &str = "_nodes.vx0";
+
+/// Directory of public services by type and tags
+pub const SERVICE_RECORD: &str = "_services.vx0";
+
+/// Domain under which nodes publish their short hostnames
+pub const NODE_NAME_DOMAIN: &str = "nodes.vx0";
+
+/// Prefix of the TXT record naming the node a `nodes.vx0` name belongs to
+const OWNER_PREFIX: &str = "owner=";
+
+/// The one directory a daemon keeps, shared by the DNS server, resolver,
+/// service registry, node and zone sync (see [`changes`])
+pub type SharedDns = Arc<RwLock<Vx0DNS>>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Vx0DNS {
     pub zones: HashMap<String, DNSZone>,
     pub records: HashMap<String, Vec<DNSRecord>>,
+    /// This node; records it registered carry it and are exempt from quotas
+    #[serde(default)]
+    origin: Option<NodeId>,
+    #[serde(default)]
+    quotas: SyncQuotas,
+    /// Who registered what under each name
+    #[serde(default)]
+    history: RegistrationHistory,
+    #[serde(skip)]
+    resolved: ResolutionLog,
+    /// Answers the DNS server gave; clones share it
+    #[serde(skip)]
+    cache: Arc<ResolverCache>,
+    /// Announces every zone change; clones share it
+    #[serde(skip)]
+    changes: ChangeFeed,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +78,8 @@ pub struct DNSZone {
     pub name: String,
     pub soa: SOARecord,
     pub ns_records: Vec<String>,
+    #[serde(default)]
+    pub journal: ZoneJournal,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,9 +100,61 @@ pub struct DNSRecord {
     pub data: String,
     pub ttl: u32,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Node that registered the record; stamped when it is committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<NodeId>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl DNSRecord {
+    /// A record past its TTL is never served, even before it is swept
+    pub fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.timestamp + chrono::Duration::seconds(self.ttl as i64) <= now
+    }
+}
+
+/// Where one service under a name answers: the data of an SRV record,
+/// kept as "priority weight port target"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+impl SrvTarget {
+    pub fn new(port: u16, target: &str) -> Self {
+        SrvTarget {
+            priority: 0,
+            weight: 0,
+            port,
+            target: target.to_string(),
+        }
+    }
+
+    fn parse(data: &str) -> Option<Self> {
+        let mut fields = data.split_whitespace();
+        let target = SrvTarget {
+            priority: fields.next()?.parse().ok()?,
+            weight: fields.next()?.parse().ok()?,
+            port: fields.next()?.parse().ok()?,
+            target: fields.next()?.to_string(),
+        };
+        fields.next().is_none().then_some(target)
+    }
+}
+
+impl std::fmt::Display for SrvTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.priority, self.weight, self.port, self.target
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RecordType {
     A,
     AAAA,
@@ -60,8 +175,17 @@ pub enum DNSError {
     Network(String),
     #[error("Protocol error: {0}")]
     Protocol(String),
+    #[error("{name} is already claimed by node {owner}")]
+    Claimed {
+        name: String,
+        owner: crate::node::NodeId,
+    },
     #[error("IO error: {0}")]
     IO(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Transport(#[from] crate::network::transport::TransportError),
 }
 
 impl Vx0DNS {
@@ -69,6 +193,12 @@ impl Vx0DNS {
         let mut dns = Vx0DNS {
             zones: HashMap::new(),
             records: HashMap::new(),
+            origin: None,
+            quotas: SyncQuotas::default(),
+            history: RegistrationHistory::default(),
+            resolved: ResolutionLog::default(),
+            cache: Arc::default(),
+            changes: ChangeFeed::default(),
         };
 
         // Create the root VX0 zone
@@ -76,6 +206,67 @@ impl Vx0DNS {
         dns
     }
 
+    /// Make this the daemon's directory, to be shared rather than copied
+    pub fn into_shared(self) -> SharedDns {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// Follow changes to every zone from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<DnsChange> {
+        self.changes.subscribe()
+    }
+
+    /// Register records as `origin` from now on. Records registered under
+    /// the previous origin, e.g. before a restart, are carried over.
+    pub fn set_origin(&mut self, origin: NodeId) {
+        let previous = self.origin.replace(origin);
+        for record in self.records.values_mut().flatten() {
+            if record.origin == previous {
+                record.origin = Some(origin);
+            }
+        }
+    }
+
+    pub fn set_sync_quota(&mut self, config: SyncQuotaConfig) {
+        self.quotas.config = config;
+    }
+
+    pub fn set_history_limits(&mut self, config: &DnsHistoryConfig) {
+        self.history.set_limits(config);
+    }
+
+    /// Changes to `domain`'s records, oldest first (see [`history`])
+    pub fn history(&self, domain: &str) -> Vec<HistoryEntry> {
+        self.history.for_domain(domain)
+    }
+
+    /// Changes to every name this node registered or changed itself
+    pub fn local_history(&self) -> Vec<HistoryEntry> {
+        self.history.attested_locally()
+    }
+
+    /// The records held for each of `names` now, to note in the history
+    /// how a change leaves them
+    fn snapshot(&self, names: &BTreeSet<String>) -> Vec<(String, Vec<DNSRecord>)> {
+        names
+            .iter()
+            .map(|name| {
+                (
+                    name.clone(),
+                    self.records.get(name).cloned().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    fn note_history(&mut self, before: Vec<(String, Vec<DNSRecord>)>, attestation: Attestation) {
+        for (name, old) in before {
+            let new = self.records.get(&name).cloned().unwrap_or_default();
+            self.history
+                .note(&name, &old, &new, attestation, self.origin);
+        }
+    }
+
     fn create_vx0_zone(&mut self) {
         let vx0_zone = DNSZone {
             name: "vx0".to_string(),
@@ -89,6 +280,7 @@ impl Vx0DNS {
                 minimum: 86400,
             },
             ns_records: vec!["ns1.vx0".to_string(), "ns2.vx0".to_string()],
+            journal: ZoneJournal::default(),
         };
 
         self.zones.insert("vx0".to_string(), vx0_zone);
@@ -100,6 +292,7 @@ impl Vx0DNS {
             data: "10.0.0.1".to_string(),
             ttl: 300,
             timestamp: chrono::Utc::now(),
+            origin: None,
         });
 
         self.add_record(DNSRecord {
@@ -108,6 +301,7 @@ impl Vx0DNS {
             data: "10.0.0.2".to_string(),
             ttl: 300,
             timestamp: chrono::Utc::now(),
+            origin: None,
         });
 
         self.add_record(DNSRecord {
@@ -116,6 +310,7 @@ impl Vx0DNS {
             data: "10.0.0.3".to_string(),
             ttl: 300,
             timestamp: chrono::Utc::now(),
+            origin: None,
         });
 
         // Add vx0.network record
@@ -125,6 +320,7 @@ impl Vx0DNS {
             data: "10.0.1.1".to_string(),
             ttl: 300,
             timestamp: chrono::Utc::now(),
+            origin: None,
         });
     }
 
@@ -135,15 +331,11 @@ impl Vx0DNS {
             return None;
         }
 
-        // Query internal DNS records
-        if let Some(records) = self.records.get(domain) {
-            for record in records {
-                if matches!(record.record_type, RecordType::A) {
-                    if let Ok(ip) = record.data.parse::<IpAddr>() {
-                        tracing::info!("Resolved {} to {}", domain, ip);
-                        return Some(ip);
-                    }
-                }
+        // Query internal DNS records, preferring A over AAAA
+        for record_type in [RecordType::A, RecordType::AAAA] {
+            if let Some(ip) = self.lookup_address(domain, &record_type) {
+                tracing::info!("Resolved {} to {}", domain, ip);
+                return Some(ip);
             }
         }
 
@@ -151,6 +343,41 @@ impl Vx0DNS {
         self.query_distributed_dns(domain).await
     }
 
+    /// Resolve only AAAA records
+    pub async fn resolve_vx0_domain_v6(&self, domain: &str) -> Option<Ipv6Addr> {
+        if !domain.ends_with(".vx0") && domain != "vx0.network" {
+            return None;
+        }
+
+        match self.lookup_address(domain, &RecordType::AAAA) {
+            Some(IpAddr::V6(ip)) => Some(ip),
+            _ => None,
+        }
+    }
+
+    fn lookup_address(&self, domain: &str, record_type: &RecordType) -> Option<IpAddr> {
+        self.lookup_addresses(domain, record_type)
+            .into_iter()
+            .next()
+    }
+
+    /// Every unexpired address of one type held for `domain`, in record order
+    pub fn lookup_addresses(&self, domain: &str, record_type: &RecordType) -> Vec<IpAddr> {
+        let now = chrono::Utc::now();
+        let addresses: Vec<IpAddr> = self
+            .records
+            .get(domain)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == *record_type && !record.is_expired(now))
+            .filter_map(|record| record.data.parse::<IpAddr>().ok())
+            .collect();
+        if !addresses.is_empty() {
+            self.resolved.note(domain);
+        }
+        addresses
+    }
+
     async fn query_distributed_dns(&self, domain: &str) -> Option<IpAddr> {
         tracing::debug!("Querying distributed DNS for {}", domain);
 
@@ -164,29 +391,924 @@ impl Vx0DNS {
     }
 
     pub fn register_service(&mut self, domain: String, ip: IpAddr) -> Result<(), DNSError> {
+        self.register_service_with_ttl(domain, ip, DEFAULT_RECORD_TTL)
+    }
+
+    /// Register a service record that lapses after `ttl` seconds unless refreshed
+    pub fn register_service_with_ttl(
+        &mut self,
+        domain: String,
+        ip: IpAddr,
+        ttl: u32,
+    ) -> Result<(), DNSError> {
         if !domain.ends_with(".vx0") && domain != "vx0.network" {
             return Err(DNSError::InvalidDomain(domain));
         }
 
-        let record = DNSRecord {
-            name: domain.clone(),
-            record_type: RecordType::A,
-            data: ip.to_string(),
-            ttl: 300,
-            timestamp: chrono::Utc::now(),
+        let record_type = match ip {
+            IpAddr::V4(_) => RecordType::A,
+            IpAddr::V6(_) => RecordType::AAAA,
         };
+        let data = ip.to_string();
+
+        // Registering again replaces the record rather than duplicating it
+        let mut changes: Vec<ZoneChange> = self
+            .records
+            .get(&domain)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == record_type && record.data == data)
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        changes.push(ZoneChange::Add {
+            record: DNSRecord {
+                name: domain.clone(),
+                record_type,
+                data,
+                ttl,
+                timestamp: chrono::Utc::now(),
+                origin: None,
+            },
+        });
+
+        self.commit(&domain, changes);
+        tracing::info!("Registered service {} -> {} (ttl {}s)", domain, ip, ttl);
+
+        Ok(())
+    }
+
+    /// Restart the TTL of every record for `domain`
+    ///
+    /// The refresh is journaled like any other change, so secondaries see it
+    /// on their next sync and keep serving the records too.
+    pub fn refresh_service(
+        &mut self,
+        domain: &str,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DNSError> {
+        let records = self
+            .records
+            .get(domain)
+            .cloned()
+            .ok_or_else(|| DNSError::RecordNotFound(domain.to_string()))?;
+
+        let mut changes = Vec::with_capacity(records.len() * 2);
+        for record in records {
+            let refreshed = DNSRecord {
+                timestamp: now,
+                ..record.clone()
+            };
+            changes.push(ZoneChange::Remove { record });
+            changes.push(ZoneChange::Add { record: refreshed });
+        }
+        self.commit(domain, changes);
+        tracing::debug!("Refreshed records for {}", domain);
+
+        Ok(())
+    }
+
+    pub fn deregister_service(&mut self, domain: &str) -> Result<(), DNSError> {
+        let records = self
+            .records
+            .get(domain)
+            .cloned()
+            .ok_or_else(|| DNSError::RecordNotFound(domain.to_string()))?;
 
-        self.add_record(record);
-        tracing::info!("Registered service {} -> {}", domain, ip);
+        let changes = records
+            .into_iter()
+            .map(|record| ZoneChange::Remove { record })
+            .collect();
+        self.commit(domain, changes);
+        tracing::info!("Deregistered service {}", domain);
 
         Ok(())
     }
 
+    /// Register an SRV record at `name` pointing at one service, next to
+    /// any others already there
+    pub fn register_srv(
+        &mut self,
+        name: String,
+        target: SrvTarget,
+        ttl: u32,
+    ) -> Result<(), DNSError> {
+        if !name.ends_with(".vx0") {
+            return Err(DNSError::InvalidDomain(name));
+        }
+        let data = target.to_string();
+        let mut changes: Vec<ZoneChange> = self
+            .srv_records(&name, &data)
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        changes.push(ZoneChange::Add {
+            record: DNSRecord {
+                name: name.clone(),
+                record_type: RecordType::SRV,
+                data,
+                ttl,
+                timestamp: chrono::Utc::now(),
+                origin: None,
+            },
+        });
+        self.commit(&name, changes);
+        tracing::info!("Registered {} -> {} (ttl {}s)", name, target, ttl);
+
+        Ok(())
+    }
+
+    /// Restart the TTL of the one SRV record at `name` pointing at `target`,
+    /// leaving those of other services alone
+    pub fn refresh_srv(
+        &mut self,
+        name: &str,
+        target: &SrvTarget,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), DNSError> {
+        let data = target.to_string();
+        let changes: Vec<ZoneChange> = self
+            .srv_records(name, &data)
+            .flat_map(|record| {
+                let refreshed = DNSRecord {
+                    timestamp: now,
+                    ..record.clone()
+                };
+                [
+                    ZoneChange::Remove {
+                        record: record.clone(),
+                    },
+                    ZoneChange::Add { record: refreshed },
+                ]
+            })
+            .collect();
+        if changes.is_empty() {
+            return Err(DNSError::RecordNotFound(format!("{} {}", name, data)));
+        }
+        self.commit(name, changes);
+        Ok(())
+    }
+
+    /// Remove the SRV record at `name` pointing at `target`; false if there
+    /// was none, e.g. because it lapsed
+    pub fn deregister_srv(&mut self, name: &str, target: &SrvTarget) -> bool {
+        let data = target.to_string();
+        let changes: Vec<ZoneChange> = self
+            .srv_records(name, &data)
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        if changes.is_empty() {
+            return false;
+        }
+        self.commit(name, changes);
+        tracing::info!("Deregistered {} -> {}", name, target);
+        true
+    }
+
+    /// Unexpired SRV records at `name`, lowest priority first
+    pub fn lookup_srv(&self, name: &str) -> Vec<SrvTarget> {
+        let now = chrono::Utc::now();
+        let mut targets: Vec<SrvTarget> = self
+            .records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == RecordType::SRV && !record.is_expired(now))
+            .filter_map(|record| SrvTarget::parse(&record.data))
+            .collect();
+        targets.sort_by_key(|target| (target.priority, std::cmp::Reverse(target.weight)));
+        targets
+    }
+
+    fn srv_records<'a>(&'a self, name: &str, data: &'a str) -> impl Iterator<Item = &'a DNSRecord> {
+        self.records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(move |record| record.record_type == RecordType::SRV && record.data == data)
+    }
+
+    /// Drop records whose TTL has run out, returning how many were removed
+    pub fn expire_records(&mut self, now: chrono::DateTime<chrono::Utc>) -> usize {
+        let mut expired: HashMap<String, Vec<ZoneChange>> = HashMap::new();
+        for record in self.records.values().flatten() {
+            if record.is_expired(now) {
+                let zone = self.zone_for(&record.name).unwrap_or_default();
+                expired.entry(zone).or_default().push(ZoneChange::Remove {
+                    record: record.clone(),
+                });
+            }
+        }
+
+        let mut removed = 0;
+        for (zone, changes) in expired {
+            removed += changes.len();
+            self.commit(&zone, changes);
+        }
+        removed
+    }
+
+    /// Zone a name belongs to: the longest matching zone, falling back to
+    /// the root vx0 zone for names such as vx0.network
+    pub fn zone_for(&self, name: &str) -> Option<String> {
+        self.zones
+            .keys()
+            .filter(|zone| name == zone.as_str() || name.ends_with(&format!(".{}", zone)))
+            .max_by_key(|zone| zone.len())
+            .cloned()
+            .or_else(|| self.zones.contains_key("vx0").then(|| "vx0".to_string()))
+    }
+
+    /// Apply changes to the zone owning `name`, bumping its serial and
+    /// journaling them for secondaries and announcing them
+    fn commit(&mut self, name: &str, mut changes: Vec<ZoneChange>) {
+        if changes.is_empty() {
+            return;
+        }
+        for change in &mut changes {
+            if let ZoneChange::Add { record } = change {
+                record.origin = record.origin.or(self.origin);
+            }
+        }
+        let names = changes::names_of(&changes);
+        let before = self.snapshot(&names);
+        for change in &changes {
+            self.apply_change(change);
+        }
+        self.note_history(before, Attestation::Local);
+
+        let Some(zone) = self
+            .zone_for(name)
+            .and_then(|zone| self.zones.get_mut(&zone))
+        else {
+            return;
+        };
+        let from_serial = zone.soa.serial;
+        zone.soa.serial = zone::next_serial(from_serial, chrono::Utc::now().date_naive());
+        zone.journal.record(JournalEntry {
+            from_serial,
+            to_serial: zone.soa.serial,
+            changes,
+        });
+        let change = DnsChange::new(&zone.name, zone.soa.serial, names, CacheSource::Local);
+        self.changes.announce(change);
+    }
+
+    fn apply_change(&mut self, change: &ZoneChange) {
+        match change {
+            ZoneChange::Add { record } => self.add_record(record.clone()),
+            ZoneChange::Remove { record } => {
+                if let Some(records) = self.records.get_mut(&record.name) {
+                    records.retain(|existing| {
+                        existing.record_type != record.record_type || existing.data != record.data
+                    });
+                    if records.is_empty() {
+                        self.records.remove(&record.name);
+                    }
+                }
+                self.cache.invalidate(&record.name);
+            }
+        }
+    }
+
+    pub fn serial(&self, zone: &str) -> Option<u32> {
+        self.zones.get(zone).map(|zone| zone.soa.serial)
+    }
+
+    /// Limit how many changes each zone's journal keeps
+    pub fn set_journal_limit(&mut self, max_entries: usize) {
+        for zone in self.zones.values_mut() {
+            zone.journal.set_max_entries(max_entries);
+        }
+    }
+
+    /// Changes a secondary at `serial` needs, incremental when the journal allows
+    pub fn transfer_since(&self, zone_name: &str, serial: u32) -> Result<ZoneTransfer, DNSError> {
+        let zone = self
+            .zones
+            .get(zone_name)
+            .ok_or_else(|| DNSError::InvalidDomain(zone_name.to_string()))?;
+
+        if serial == zone.soa.serial {
+            return Ok(ZoneTransfer::UpToDate {
+                zone: zone_name.to_string(),
+                serial,
+            });
+        }
+
+        if let Some(entries) = zone.journal.since(serial) {
+            return Ok(ZoneTransfer::Incremental {
+                zone: zone_name.to_string(),
+                entries,
+            });
+        }
+
+        let records = self
+            .records
+            .values()
+            .flatten()
+            .filter(|record| self.zone_for(&record.name).as_deref() == Some(zone_name))
+            .cloned()
+            .collect();
+        Ok(ZoneTransfer::Full {
+            zone: zone_name.to_string(),
+            serial: zone.soa.serial,
+            records,
+        })
+    }
+
+    /// Apply a transfer from the primary, leaving this copy at its serial,
+    /// and announce the names it touched
+    pub fn apply_transfer(&mut self, transfer: ZoneTransfer) -> Result<(), DNSError> {
+        let zone_name = transfer.zone().to_string();
+        if !self.zones.contains_key(&zone_name) {
+            return Err(DNSError::InvalidDomain(zone_name));
+        }
+
+        let mut refused = HashMap::new();
+        let mut changed: Option<BTreeSet<String>> = None;
+        match transfer {
+            ZoneTransfer::UpToDate { .. } => {}
+            ZoneTransfer::Incremental { entries, .. } => {
+                let mut usage = self.synced_usage();
+                for mut entry in entries {
+                    // Refused records are not passed on to our own secondaries
+                    entry.changes.retain_mut(|change| match change {
+                        ZoneChange::Add { record } => self.admit(record, &mut usage, &mut refused),
+                        ZoneChange::Remove { record } => {
+                            self.release(record, &mut usage);
+                            true
+                        }
+                    });
+                    let names = changes::names_of(&entry.changes);
+                    let before = self.snapshot(&names);
+                    for change in &entry.changes {
+                        self.apply_change(change);
+                    }
+                    self.note_history(before, Attestation::Remote);
+                    changed.get_or_insert_default().extend(names);
+                    if let Some(zone) = self.zones.get_mut(&zone_name) {
+                        zone.soa.serial = entry.to_serial;
+                        zone.journal.record(entry);
+                    }
+                }
+            }
+            ZoneTransfer::Full {
+                serial, records, ..
+            } => {
+                let names: Vec<String> = self
+                    .records
+                    .keys()
+                    .filter(|name| self.zone_for(name).as_deref() == Some(zone_name.as_str()))
+                    .cloned()
+                    .collect();
+                let mut before: BTreeSet<String> = names.iter().cloned().collect();
+                before.extend(records.iter().map(|record| record.name.clone()));
+                let before = self.snapshot(&before);
+                for name in &names {
+                    self.records.remove(name);
+                }
+                self.cache.invalidate_zone(&zone_name);
+                let mut usage = self.synced_usage();
+                let mut replaced: BTreeSet<String> = names.into_iter().collect();
+                for mut record in records {
+                    if self.admit(&mut record, &mut usage, &mut refused) {
+                        replaced.insert(record.name.clone());
+                        self.add_record(record);
+                    }
+                }
+                self.note_history(before, Attestation::Remote);
+                changed = Some(replaced);
+                if let Some(zone) = self.zones.get_mut(&zone_name) {
+                    zone.soa.serial = serial;
+                    // Older history no longer describes this copy
+                    zone.journal.clear();
+                }
+            }
+        }
+
+        for (origin, count) in refused {
+            *self.quotas.rejected.entry(origin).or_default() += count;
+            tracing::warn!(
+                target: "audit",
+                origin = %origin,
+                refused = count,
+                zone = %zone_name,
+                "Refused synced DNS records over the origin's quota"
+            );
+        }
+        self.enforce_sync_limit(chrono::Utc::now());
+        if let (Some(names), Some(serial)) = (changed, self.serial(&zone_name)) {
+            let change = DnsChange::new(zone_name, serial, names, CacheSource::Synced);
+            self.changes.announce(change);
+        }
+        Ok(())
+    }
+
+    /// Registered by this node rather than learned from a primary
+    /// Whether the records held for `domain` are this node's own or synced
+    /// from others, if any are held
+    pub fn source_of(&self, domain: &str) -> Option<CacheSource> {
+        let records = self.records.get(domain)?;
+        Some(if records.iter().all(|record| self.is_local(record)) {
+            CacheSource::Local
+        } else {
+            CacheSource::Synced
+        })
+    }
+
+    fn is_local(&self, record: &DNSRecord) -> bool {
+        record.origin.is_none() || record.origin == self.origin
+    }
+
+    /// What each origin other than this node has stored here
+    fn synced_usage(&self) -> HashMap<NodeId, OriginUsage> {
+        let mut usage: HashMap<NodeId, OriginUsage> = HashMap::new();
+        for record in self.records.values().flatten() {
+            if let Some(origin) = record.origin.filter(|_| !self.is_local(record)) {
+                let entry = usage.entry(origin).or_insert_with(|| OriginUsage {
+                    origin,
+                    ..Default::default()
+                });
+                entry.records += 1;
+                entry.bytes += quota::record_size(record);
+            }
+        }
+        usage
+    }
+
+    /// Charge a synced record to its origin, refusing it when that would
+    /// take the origin over its quota. Records naming no origin are
+    /// charged to `UNATTRIBUTED`.
+    fn admit(
+        &self,
+        record: &mut DNSRecord,
+        usage: &mut HashMap<NodeId, OriginUsage>,
+        refused: &mut HashMap<NodeId, u64>,
+    ) -> bool {
+        let origin = *record.origin.get_or_insert(UNATTRIBUTED);
+        if self.is_local(record) {
+            return true;
+        }
+        let config = &self.quotas.config;
+        let entry = usage.entry(origin).or_insert_with(|| OriginUsage {
+            origin,
+            ..Default::default()
+        });
+        let size = quota::record_size(record);
+        if entry.records + 1 > config.max_records_per_origin
+            || entry.bytes + size > config.max_bytes_per_origin.get() as usize
+        {
+            *refused.entry(origin).or_default() += 1;
+            return false;
+        }
+        entry.records += 1;
+        entry.bytes += size;
+        true
+    }
+
+    fn release(&self, record: &DNSRecord, usage: &mut HashMap<NodeId, OriginUsage>) {
+        let held = self.records.get(&record.name).is_some_and(|records| {
+            records
+                .iter()
+                .any(|r| r.record_type == record.record_type && r.data == record.data)
+        });
+        let origin = record.origin.unwrap_or(UNATTRIBUTED);
+        if let Some(entry) = usage.get_mut(&origin).filter(|_| held) {
+            entry.records = entry.records.saturating_sub(1);
+            entry.bytes = entry.bytes.saturating_sub(quota::record_size(record));
+        }
+    }
+
+    /// Keep synced records under `max_synced_records`: expired ones go
+    /// first, then the least recently resolved records of whichever origin
+    /// is furthest into its quota. Evictions are not journaled; the records
+    /// come back with the next full transfer if there is room for them.
+    fn enforce_sync_limit(&mut self, now: chrono::DateTime<chrono::Utc>) -> usize {
+        let limit = self.quotas.config.max_synced_records;
+        let synced = |dns: &Self| {
+            dns.records
+                .values()
+                .flatten()
+                .filter(|record| !dns.is_local(record))
+                .count()
+        };
+        let mut excess = synced(self).saturating_sub(limit);
+        if excess == 0 {
+            return 0;
+        }
+
+        let mut evicted = 0;
+        let expired: Vec<DNSRecord> = self
+            .records
+            .values()
+            .flatten()
+            .filter(|record| !self.is_local(record) && record.is_expired(now))
+            .cloned()
+            .collect();
+        for record in expired {
+            self.evict(&record);
+            evicted += 1;
+        }
+        excess = excess.saturating_sub(evicted);
+
+        // Per origin, least recently resolved last so it pops first;
+        // never resolved counts as least recent
+        let mut candidates: HashMap<NodeId, Vec<DNSRecord>> = HashMap::new();
+        for record in self.records.values().flatten() {
+            if let Some(origin) = record.origin.filter(|_| !self.is_local(record)) {
+                candidates.entry(origin).or_default().push(record.clone());
+            }
+        }
+        for records in candidates.values_mut() {
+            records.sort_by_key(|record| {
+                std::cmp::Reverse((self.resolved.last(&record.name), record.timestamp))
+            });
+        }
+        let mut usage = self.synced_usage();
+        let config = self.quotas.config.clone();
+        while excess > 0 {
+            let Some(origin) = usage
+                .values()
+                .filter(|usage| usage.records > 0)
+                .max_by(|a, b| a.fill(&config).total_cmp(&b.fill(&config)))
+                .map(|usage| usage.origin)
+            else {
+                break;
+            };
+            let Some(record) = candidates.get_mut(&origin).and_then(Vec::pop) else {
+                usage.remove(&origin);
+                continue;
+            };
+            if let Some(entry) = usage.get_mut(&origin) {
+                entry.records -= 1;
+                entry.bytes = entry.bytes.saturating_sub(quota::record_size(&record));
+            }
+            self.evict(&record);
+            evicted += 1;
+            excess -= 1;
+        }
+
+        tracing::warn!(
+            target: "audit",
+            evicted,
+            limit,
+            "Evicted synced DNS records over the global limit"
+        );
+        evicted
+    }
+
+    fn evict(&mut self, record: &DNSRecord) {
+        self.apply_change(&ZoneChange::Remove {
+            record: record.clone(),
+        });
+        if !self.records.contains_key(&record.name) {
+            self.resolved.forget(&record.name);
+        }
+    }
+
+    /// Usage of every origin whose records are synced here or were
+    /// refused, by node ID
+    pub fn origin_usage(&self) -> Vec<OriginUsage> {
+        let mut usage = self.synced_usage();
+        for (origin, rejected) in &self.quotas.rejected {
+            usage
+                .entry(*origin)
+                .or_insert_with(|| OriginUsage {
+                    origin: *origin,
+                    ..Default::default()
+                })
+                .rejected = *rejected;
+        }
+        let mut usage: Vec<OriginUsage> = usage.into_values().collect();
+        usage.sort_by_key(|usage| usage.origin);
+        usage
+    }
+
+    /// Origins that had records refused or hold more than their quota
+    pub fn over_quota(&self) -> Vec<OriginUsage> {
+        self.origin_usage()
+            .into_iter()
+            .filter(|usage| usage.over_quota(&self.quotas.config))
+            .collect()
+    }
+
+    /// Persist records, serials and journals
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), DNSError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, DNSError> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Advertise a clearnet gateway in the directory
+    pub fn register_gateway(&mut self, advert: &GatewayAdvert) -> Result<(), DNSError> {
+        let data = serde_json::to_string(advert).map_err(|e| DNSError::Protocol(e.to_string()))?;
+
+        let mut changes: Vec<ZoneChange> = self
+            .records
+            .get(GATEWAY_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| {
+                serde_json::from_str::<GatewayAdvert>(&record.data)
+                    .is_ok_and(|existing| existing.address == advert.address)
+            })
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        changes.push(ZoneChange::Add {
+            record: DNSRecord {
+                name: GATEWAY_RECORD.to_string(),
+                record_type: RecordType::TXT,
+                data,
+                ttl: 300,
+                timestamp: chrono::Utc::now(),
+                origin: None,
+            },
+        });
+        self.commit(GATEWAY_RECORD, changes);
+
+        tracing::info!("Registered clearnet gateway {}", advert.address);
+        Ok(())
+    }
+
+    /// List a node and what it offers in the directory, replacing its previous listing
+    pub fn register_node(&mut self, entry: &NodeDirectoryEntry) -> Result<(), DNSError> {
+        let data = serde_json::to_string(entry).map_err(|e| DNSError::Protocol(e.to_string()))?;
+
+        let mut changes: Vec<ZoneChange> = self
+            .records
+            .get(NODE_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| {
+                serde_json::from_str::<NodeDirectoryEntry>(&record.data)
+                    .is_ok_and(|existing| existing.node_id == entry.node_id)
+            })
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        changes.push(ZoneChange::Add {
+            record: DNSRecord {
+                name: NODE_RECORD.to_string(),
+                record_type: RecordType::TXT,
+                data,
+                ttl: DEFAULT_RECORD_TTL,
+                timestamp: chrono::Utc::now(),
+                origin: None,
+            },
+        });
+        self.commit(NODE_RECORD, changes);
+
+        tracing::debug!(
+            "Listed node {} (ASN {}) offering {}",
+            entry.hostname,
+            entry.asn,
+            entry.capabilities
+        );
+        Ok(())
+    }
+
+    /// List a service in the directory for `ttl` seconds, replacing its
+    /// previous listing
+    pub fn list_service(&mut self, summary: &ServiceSummary, ttl: u32) -> Result<(), DNSError> {
+        let data = serde_json::to_string(summary).map_err(|e| DNSError::Protocol(e.to_string()))?;
+
+        let mut changes: Vec<ZoneChange> = self
+            .records
+            .get(SERVICE_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| {
+                serde_json::from_str::<ServiceSummary>(&record.data)
+                    .is_ok_and(|existing| existing.service_id == summary.service_id)
+            })
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        changes.push(ZoneChange::Add {
+            record: DNSRecord {
+                name: SERVICE_RECORD.to_string(),
+                record_type: RecordType::TXT,
+                data,
+                ttl,
+                timestamp: summary.updated,
+                origin: None,
+            },
+        });
+        self.commit(SERVICE_RECORD, changes);
+
+        tracing::debug!(
+            "Listed {} service {} ({:?})",
+            summary.service_type,
+            summary.domain,
+            summary.status
+        );
+        Ok(())
+    }
+
+    /// Take a service out of the directory
+    pub fn unlist_service(&mut self, service_id: uuid::Uuid) {
+        let changes: Vec<ZoneChange> = self
+            .records
+            .get(SERVICE_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| {
+                serde_json::from_str::<ServiceSummary>(&record.data)
+                    .is_ok_and(|existing| existing.service_id == service_id)
+            })
+            .map(|record| ZoneChange::Remove {
+                record: record.clone(),
+            })
+            .collect();
+        if !changes.is_empty() {
+            self.commit(SERVICE_RECORD, changes);
+        }
+    }
+
+    /// Point `<short hostname>.nodes.vx0` at a node, returning the name.
+    /// A name another node still holds is refused rather than taken over;
+    /// it frees up once the holder stops refreshing it.
+    pub fn claim_node_name(&mut self, entry: &NodeDirectoryEntry) -> Result<String, DNSError> {
+        let name = format!(
+            "{}.{}",
+            crate::config::hostname::short_name(&entry.hostname),
+            NODE_NAME_DOMAIN
+        );
+        let now = chrono::Utc::now();
+        let existing = self.records.get(&name).cloned().unwrap_or_default();
+        let claimant = existing
+            .iter()
+            .filter(|record| record.record_type == RecordType::TXT && !record.is_expired(now))
+            .find_map(|record| record.data.strip_prefix(OWNER_PREFIX)?.parse().ok());
+        if let Some(owner) = claimant.filter(|owner| *owner != entry.node_id) {
+            return Err(DNSError::Claimed { name, owner });
+        }
+
+        let mut changes: Vec<ZoneChange> = existing
+            .into_iter()
+            .map(|record| ZoneChange::Remove { record })
+            .collect();
+        for (record_type, data) in [
+            (RecordType::A, entry.address.to_string()),
+            (
+                RecordType::TXT,
+                format!("{}{}", OWNER_PREFIX, entry.node_id),
+            ),
+        ] {
+            changes.push(ZoneChange::Add {
+                record: DNSRecord {
+                    name: name.clone(),
+                    record_type,
+                    data,
+                    ttl: DEFAULT_RECORD_TTL,
+                    timestamp: now,
+                    origin: None,
+                },
+            });
+        }
+        self.commit(&name, changes);
+
+        tracing::debug!("Claimed {} for node {}", name, entry.node_id);
+        Ok(name)
+    }
+
+    /// Publish `texts` as this node's TXT records for `name`, returning
+    /// whether they changed. Unchanged records are re-committed only once
+    /// half their TTL has passed, keeping them alive without flooding sync.
+    pub fn publish_texts(&mut self, name: &str, texts: &[String]) -> bool {
+        let now = chrono::Utc::now();
+        let own: Vec<DNSRecord> = self
+            .records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == RecordType::TXT && record.origin == self.origin)
+            .cloned()
+            .collect();
+        let changed = own.len() != texts.len()
+            || own
+                .iter()
+                .zip(texts)
+                .any(|(record, text)| record.data != *text);
+        let fresh = own.iter().all(|record| {
+            record.timestamp + chrono::Duration::seconds(DEFAULT_RECORD_TTL as i64 / 2) > now
+        });
+        if !changed && fresh {
+            return false;
+        }
+
+        let mut changes: Vec<ZoneChange> = own
+            .into_iter()
+            .map(|record| ZoneChange::Remove { record })
+            .collect();
+        for text in texts {
+            changes.push(ZoneChange::Add {
+                record: DNSRecord {
+                    name: name.to_string(),
+                    record_type: RecordType::TXT,
+                    data: text.clone(),
+                    ttl: DEFAULT_RECORD_TTL,
+                    timestamp: now,
+                    origin: None,
+                },
+            });
+        }
+        self.commit(name, changes);
+        changed
+    }
+
+    /// TXT data for `name`, from whichever node published it most recently
+    pub fn texts(&self, name: &str) -> Vec<String> {
+        let now = chrono::Utc::now();
+        let live: Vec<&DNSRecord> = self
+            .records
+            .get(name)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == RecordType::TXT && !record.is_expired(now))
+            .collect();
+        let Some(latest) = live.iter().max_by_key(|record| record.timestamp) else {
+            return Vec::new();
+        };
+        live.iter()
+            .filter(|record| record.origin == latest.origin)
+            .map(|record| record.data.clone())
+            .collect()
+    }
+
+    /// Nodes currently in the directory
+    pub fn nodes(&self) -> Vec<NodeDirectoryEntry> {
+        self.records
+            .get(NODE_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == RecordType::TXT)
+            .filter_map(|record| serde_json::from_str(&record.data).ok())
+            .collect()
+    }
+
+    /// Services currently listed in the directory
+    pub fn services(&self) -> Vec<ServiceSummary> {
+        let now = chrono::Utc::now();
+        self.records
+            .get(SERVICE_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == RecordType::TXT && !record.is_expired(now))
+            .filter_map(|record| serde_json::from_str(&record.data).ok())
+            .collect()
+    }
+
+    /// Listed services matching `filter`
+    pub fn find_services(&self, filter: &ServiceFilter) -> Vec<ServiceSummary> {
+        self.services()
+            .into_iter()
+            .filter(|service| filter.matches(service))
+            .collect()
+    }
+
+    /// Gateways currently in the directory
+    pub fn gateways(&self) -> Vec<GatewayAdvert> {
+        self.records
+            .get(GATEWAY_RECORD)
+            .into_iter()
+            .flatten()
+            .filter(|record| record.record_type == RecordType::TXT)
+            .filter_map(|record| serde_json::from_str(&record.data).ok())
+            .collect()
+    }
+
     fn add_record(&mut self, record: DNSRecord) {
+        self.cache.invalidate(&record.name);
         let domain = record.name.clone();
         self.records.entry(domain).or_default().push(record);
     }
 
+    /// Answers the DNS server gave, dropped as the records behind them change
+    pub fn cache(&self) -> &Arc<ResolverCache> {
+        &self.cache
+    }
+
+    /// Replace the answer cache, e.g. to size it from the configuration
+    pub fn set_cache(&mut self, cache: ResolverCache) {
+        self.cache = Arc::new(cache);
+    }
+
     pub fn get_records(&self, domain: &str) -> Option<&Vec<DNSRecord>> {
         self.records.get(domain)
     }
@@ -200,7 +1322,7 @@ impl Vx0DNS {
         loop {
             match socket.recv_from(&mut buf).await {
                 Ok((size, addr)) => {
-                    tracing::debug!("DNS query from {} ({} bytes)", addr, size);
+                    crate::sampled!(tracing::debug!("DNS query from {} ({} bytes)", addr, size));
 
                     // In a real implementation, we would parse the DNS query
                     // and respond with appropriate DNS records
@@ -0,0 +1,214 @@
+//! Bounds on what other nodes can make this one store.
+//!
+//! Zone sync copies every record a primary holds, so without limits a
+//! single hostile or buggy node could register names until every
+//! secondary runs out of memory. Records carry the node ID of the node that
+//! registered them; each origin may place only so many records and bytes
+//! here, and the synced records as a whole are capped, with expired ones
+//! evicted first and then the least recently resolved records of the
+//! origins furthest over their share. Records this node registered itself
+//! are never counted or evicted.
+
+use crate::config::SyncQuotaConfig;
+use crate::network::dns::DNSRecord;
+use crate::node::NodeId;
+use crate::util::sync::lock;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Origin charged for synced records that name none, e.g. from primaries
+/// predating origins
+pub const UNATTRIBUTED: NodeId = Uuid::nil();
+
+/// Bytes a record is charged for
+pub fn record_size(record: &DNSRecord) -> usize {
+    record.name.len() + record.data.len()
+}
+
+/// What one origin has stored here and had refused
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct OriginUsage {
+    pub origin: NodeId,
+    pub records: usize,
+    pub bytes: usize,
+    /// Records refused because the origin was at its quota
+    pub rejected: u64,
+}
+
+impl OriginUsage {
+    /// Refused records at some point, or holding more than its share now
+    pub fn over_quota(&self, config: &SyncQuotaConfig) -> bool {
+        self.rejected > 0
+            || self.records > config.max_records_per_origin
+            || self.bytes > config.max_bytes_per_origin.get() as usize
+    }
+
+    /// How far into its quota the origin is, 1.0 being exactly at it
+    pub fn fill(&self, config: &SyncQuotaConfig) -> f64 {
+        let records = self.records as f64 / config.max_records_per_origin.max(1) as f64;
+        let bytes = self.bytes as f64 / config.max_bytes_per_origin.get().max(1) as f64;
+        records.max(bytes)
+    }
+}
+
+/// Quota settings and what was refused so far; persisted with the records
+/// so a restart does not clear an origin's history
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncQuotas {
+    #[serde(skip)]
+    pub config: SyncQuotaConfig,
+    #[serde(default)]
+    pub rejected: HashMap<NodeId, u64>,
+}
+
+/// When each name was last resolved, for eviction; not persisted
+#[derive(Debug, Default)]
+pub struct ResolutionLog(Mutex<HashMap<String, Instant>>);
+
+impl ResolutionLog {
+    pub fn note(&self, name: &str) {
+        lock(&self.0).insert(name.to_string(), Instant::now());
+    }
+
+    pub fn last(&self, name: &str) -> Option<Instant> {
+        lock(&self.0).get(name).copied()
+    }
+
+    pub fn forget(&self, name: &str) {
+        lock(&self.0).remove(name);
+    }
+}
+
+impl Clone for ResolutionLog {
+    fn clone(&self) -> Self {
+        ResolutionLog(Mutex::new(lock(&self.0).clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::dns::zone::ZoneTransfer;
+    use crate::network::dns::{RecordType, Vx0DNS};
+    use std::net::IpAddr;
+
+    fn record(name: &str, origin: NodeId, age_secs: i64, ttl: u32) -> DNSRecord {
+        DNSRecord {
+            name: name.to_string(),
+            record_type: RecordType::A,
+            data: "10.1.0.1".to_string(),
+            ttl,
+            timestamp: chrono::Utc::now() - chrono::Duration::seconds(age_secs),
+            origin: Some(origin),
+        }
+    }
+
+    fn full(records: Vec<DNSRecord>) -> ZoneTransfer {
+        ZoneTransfer::Full {
+            zone: "vx0".to_string(),
+            serial: 1,
+            records,
+        }
+    }
+
+    fn names(dns: &Vx0DNS, origin: NodeId) -> Vec<String> {
+        let mut names: Vec<String> = dns
+            .records
+            .values()
+            .flatten()
+            .filter(|record| record.origin == Some(origin))
+            .map(|record| record.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_flooding_origin_is_capped_and_others_untouched() {
+        let (local, flooder, neighbour) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut dns = Vx0DNS::new();
+        dns.set_origin(local);
+        dns.set_sync_quota(SyncQuotaConfig {
+            max_records_per_origin: 10,
+            ..Default::default()
+        });
+
+        let mut records: Vec<DNSRecord> = (0..30)
+            .map(|i| record(&format!("flood{}.vx0", i), flooder, 0, 300))
+            .collect();
+        records.extend((0..5).map(|i| record(&format!("web{}.vx0", i), neighbour, 0, 300)));
+        dns.apply_transfer(full(records)).unwrap();
+
+        assert_eq!(names(&dns, flooder).len(), 10);
+        assert_eq!(names(&dns, neighbour).len(), 5);
+        let over = dns.over_quota();
+        assert_eq!(over.len(), 1);
+        assert_eq!(over[0].origin, flooder);
+        assert_eq!((over[0].records, over[0].rejected), (10, 20));
+
+        // Our own registrations are never charged
+        for i in 0..20 {
+            let ip: IpAddr = format!("10.2.0.{}", i + 1).parse().unwrap();
+            dns.register_service(format!("svc{}.vx0", i), ip).unwrap();
+        }
+        assert_eq!(names(&dns, local).len(), 20);
+        assert!(dns.origin_usage().iter().all(|usage| usage.origin != local));
+
+        // Refusals are remembered across a restart
+        let path = std::env::temp_dir().join(format!("vx0net-dns-{}.json", Uuid::new_v4()));
+        dns.save(&path).unwrap();
+        let restored = Vx0DNS::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored.quotas.rejected.get(&flooder), Some(&20));
+        assert_eq!(names(&restored, local).len(), 20);
+    }
+
+    #[test]
+    fn test_eviction_takes_expired_then_least_resolved_of_fullest_origin() {
+        let (hog, modest) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut dns = Vx0DNS::new();
+        dns.set_origin(Uuid::new_v4());
+        dns.set_sync_quota(SyncQuotaConfig {
+            max_records_per_origin: 10,
+            ..Default::default()
+        });
+
+        // Older names have older timestamps, breaking ties in resolution
+        let mut records: Vec<DNSRecord> = (0..10)
+            .map(|i| record(&format!("hog{}.vx0", i), hog, 100 - i, 300))
+            .collect();
+        records.extend((0..4).map(|i| record(&format!("modest{}.vx0", i), modest, 0, 300)));
+        records.push(record("stale.vx0", modest, 3600, 60));
+        dns.apply_transfer(full(records)).unwrap();
+        assert_eq!(names(&dns, hog).len() + names(&dns, modest).len(), 15);
+
+        for i in [0, 3, 4, 5, 6, 7, 8, 9] {
+            assert!(!dns
+                .lookup_addresses(&format!("hog{}.vx0", i), &RecordType::A)
+                .is_empty());
+        }
+
+        dns.set_sync_quota(SyncQuotaConfig {
+            max_records_per_origin: 10,
+            max_synced_records: 11,
+            ..Default::default()
+        });
+        assert_eq!(dns.enforce_sync_limit(chrono::Utc::now()), 4);
+
+        // The expired record, then the never-resolved hog names, then the
+        // one resolved longest ago
+        let hog_names = names(&dns, hog);
+        for gone in ["hog0.vx0", "hog1.vx0", "hog2.vx0"] {
+            assert!(!hog_names.contains(&gone.to_string()), "{}", gone);
+        }
+        assert_eq!(hog_names.len(), 7);
+        assert_eq!(
+            names(&dns, modest),
+            vec!["modest0.vx0", "modest1.vx0", "modest2.vx0", "modest3.vx0"]
+        );
+    }
+}
@@ -1,32 +1,73 @@
-use crate::network::dns::{DNSError, Vx0DNS};
+use crate::network::dns::gateway::{self, GatewayAdvert, Resolution, ResolutionSource};
+use crate::network::dns::{DNSError, SharedDns, Vx0DNS};
+use crate::node::capabilities::{self, NodeDirectoryEntry};
+use crate::node::Vx0Node;
 use std::net::IpAddr;
+use std::sync::Arc;
 use tokio::net::UdpSocket;
 
 pub struct Vx0Resolver {
-    dns: Vx0DNS,
-    #[allow(dead_code)]
+    dns: SharedDns,
     vx0_dns_servers: Vec<String>, // Only VX0 internal DNS servers
+    use_gateways: bool,
+    /// Whose tunnels gateway queries are sealed with
+    node: Option<Arc<Vx0Node>>,
 }
 
 impl Vx0Resolver {
     pub fn new(vx0_dns_servers: Vec<String>) -> Self {
         Vx0Resolver {
-            dns: Vx0DNS::new(),
+            dns: Vx0DNS::new().into_shared(),
             vx0_dns_servers,
+            use_gateways: false,
+            node: None,
         }
     }
 
+    /// Resolve from `dns` rather than a directory of the resolver's own
+    pub fn with_directory(mut self, dns: SharedDns) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    /// Reach gateways over `node`'s tunnels to them
+    pub fn with_node(mut self, node: Arc<Vx0Node>) -> Self {
+        self.node = Some(node);
+        self
+    }
+
+    /// Forward allowlisted non-VX0 queries to clearnet gateways; they are
+    /// only reached once the resolver has a node to reach them through
+    pub fn set_use_gateways(&mut self, use_gateways: bool) {
+        self.use_gateways = use_gateways;
+    }
+
     pub async fn resolve(&self, domain: &str) -> Result<Option<IpAddr>, DNSError> {
+        Ok(self
+            .resolve_tagged(domain)
+            .await?
+            .map(|resolution| resolution.address))
+    }
+
+    /// Resolve a domain, reporting whether the answer came from a gateway
+    pub async fn resolve_tagged(&self, domain: &str) -> Result<Option<Resolution>, DNSError> {
         tracing::debug!("Resolving domain: {}", domain);
 
         // First, try to resolve VX0 domains internally
         if domain.ends_with(".vx0") || domain == "vx0.network" {
-            if let Some(ip) = self.dns.resolve_vx0_domain(domain).await {
-                return Ok(Some(ip));
-            }
+            let address = match self.dns.read().await.resolve_vx0_domain(domain).await {
+                Some(ip) => Some(ip),
+                // If not found in local cache, query VX0 network
+                None => self.query_vx0_network(domain).await?,
+            };
+            return Ok(address.map(|address| Resolution {
+                address,
+                source: ResolutionSource::Vx0,
+            }));
+        }
 
-            // If not found in local cache, query VX0 network
-            return self.query_vx0_network(domain).await;
+        if self.use_gateways {
+            return self.query_gateways(domain).await;
         }
 
         // IMPORTANT: Non-VX0 domains are NOT resolved (network isolation)
@@ -35,6 +76,55 @@ impl Vx0Resolver {
         Ok(None)
     }
 
+    async fn query_gateways(&self, domain: &str) -> Result<Option<Resolution>, DNSError> {
+        let gateways: Vec<GatewayAdvert> = self
+            .dns
+            .read()
+            .await
+            .gateways()
+            .into_iter()
+            .filter(|gateway| gateway.allowlist.allows(domain))
+            .collect();
+
+        if gateways.is_empty() {
+            tracing::warn!(
+                "Attempted to resolve non-VX0 domain: {} - BLOCKED (no gateway allows it)",
+                domain
+            );
+            return Ok(None);
+        }
+        let Some(node) = &self.node else {
+            tracing::warn!(
+                "Attempted to resolve non-VX0 domain: {} - BLOCKED (no node to reach gateways)",
+                domain
+            );
+            return Ok(None);
+        };
+
+        for advert in gateways {
+            match gateway::query_gateway(node, &advert, domain).await {
+                Ok(addresses) => {
+                    if let Some(address) = addresses.first() {
+                        tracing::info!(
+                            "Resolved {} via clearnet gateway {}",
+                            domain,
+                            advert.address
+                        );
+                        return Ok(Some(Resolution {
+                            address: *address,
+                            source: ResolutionSource::Gateway(advert.address),
+                        }));
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("Gateway {} failed for {}: {}", advert.address, domain, e);
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
     async fn query_vx0_network(&self, domain: &str) -> Result<Option<IpAddr>, DNSError> {
         tracing::debug!("Querying VX0 network for {}", domain);
 
@@ -57,7 +147,7 @@ impl Vx0Resolver {
     async fn query_vx0_dns_servers(&self, domain: &str) -> Result<Option<IpAddr>, DNSError> {
         tracing::debug!("Querying VX0 DNS servers for {}", domain);
 
-        for vx0_server in &self.vx0_dns_servers {
+        for vx0_server in &self.forwarding_targets().await {
             match self.query_server(vx0_server, domain).await {
                 Ok(Some(ip)) => {
                     tracing::info!("Resolved {} via VX0 DNS server {}", domain, vx0_server);
@@ -88,8 +178,41 @@ impl Vx0Resolver {
         Ok(None)
     }
 
-    pub fn register_vx0_service(&mut self, domain: String, ip: IpAddr) -> Result<(), DNSError> {
-        self.dns.register_service(domain, ip)
+    /// Servers VX0 queries are forwarded to: nodes advertising DNS service,
+    /// falling back to the configured servers when none do
+    pub async fn forwarding_targets(&self) -> Vec<String> {
+        let advertised: Vec<String> = self
+            .dns
+            .read()
+            .await
+            .nodes()
+            .into_iter()
+            .filter(|node| node.capabilities.serves_dns)
+            .map(|node| format!("{}:53", node.address))
+            .collect();
+        if advertised.is_empty() {
+            return self.vx0_dns_servers.clone();
+        }
+
+        // Configured servers that also advertise DNS are tried first
+        capabilities::prefer_capable(advertised, |target| self.vx0_dns_servers.contains(target))
+    }
+
+    /// Record a node's directory listing, e.g. from its announcement
+    pub async fn register_node(&self, entry: &NodeDirectoryEntry) {
+        if let Err(e) = self.dns.write().await.register_node(entry) {
+            tracing::warn!("Failed to list node {}: {}", entry.hostname, e);
+        }
+    }
+
+    pub async fn register_gateway(&self, advert: GatewayAdvert) {
+        if let Err(e) = self.dns.write().await.register_gateway(&advert) {
+            tracing::warn!("Failed to register gateway {}: {}", advert.address, e);
+        }
+    }
+
+    pub async fn register_vx0_service(&self, domain: String, ip: IpAddr) -> Result<(), DNSError> {
+        self.dns.write().await.register_service(domain, ip)
     }
 
     pub async fn start_resolver_service(&self, bind_addr: &str) -> Result<(), DNSError> {
@@ -101,7 +224,11 @@ impl Vx0Resolver {
         loop {
             match socket.recv_from(&mut buf).await {
                 Ok((size, addr)) => {
-                    tracing::debug!("DNS resolver query from {} ({} bytes)", addr, size);
+                    crate::sampled!(tracing::debug!(
+                        "DNS resolver query from {} ({} bytes)",
+                        addr,
+                        size
+                    ));
 
                     // In a real implementation, we would:
                     // 1. Parse the DNS query
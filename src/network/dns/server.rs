@@ -1,103 +1,349 @@
-use crate::network::dns::{DNSError, DNSRecord, RecordType, Vx0DNS};
-use std::net::SocketAddr;
+//! Local DNS service for applications on the host.
+//!
+//! Point the OS resolver at this and `.vx0` names resolve like any other:
+//! they are answered from the node's directory, falling back to the VX0
+//! network. Other names are refused, or passed unchanged to an upstream
+//! resolver when `dns.proxy_clearnet` is set, so the daemon can be the
+//! machine's only resolver. Only clients in `dns.allow_from` (loopback and
+//! private ranges by default) get answers; the rest are ignored. Names
+//! hosted on several nodes get every address, healthiest first (see
+//! [`health`](crate::network::dns::health)). Answers are reused until their
+//! TTL runs out (see [`cache`](crate::network::dns::cache)). TXT queries
+//! for node metadata are answered from the records themselves (see
+//! [`metadata`](crate::network::dns::metadata)), and SRV queries, one record
+//! per service sharing a name, likewise.
+
+use crate::config::{self, DNSConfig};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::dns::cache::{CacheSource, CachedAnswer};
+use crate::network::dns::health::AnswerRanking;
+use crate::network::dns::metadata;
+use crate::network::dns::resolver::Vx0Resolver;
+use crate::network::dns::wire::{self, Query, Rcode, TYPE_A, TYPE_AAAA, TYPE_SRV, TYPE_TXT};
+use crate::network::dns::{DNSError, DNSRecord, RecordType, SharedDns, Vx0DNS};
+use crate::network::prefix_list::PrefixList;
+use crate::node::Vx0Node;
+use crate::util::backoff::{self, RetryError, RetryPolicy};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// TTL on answers for VX0 names
+const ANSWER_TTL: u32 = 60;
+/// How long the upstream resolver gets before the client is told SERVFAIL
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_MESSAGE: usize = 4096;
 
 pub struct Vx0DNSServer {
-    dns: Vx0DNS,
+    dns: SharedDns,
+    resolver: Vx0Resolver,
     bind_addr: SocketAddr,
+    /// Where non-VX0 queries go when clearnet proxying is on
+    upstream: Option<SocketAddr>,
+    /// How failed relays to `upstream` are retried
+    forward_retry: RetryPolicy,
+    allow_from: PrefixList,
+    /// Orders names with several addresses; without it they are given in
+    /// record order
+    ranking: Option<Arc<AnswerRanking>>,
 }
 
 impl Vx0DNSServer {
     pub fn new(bind_addr: SocketAddr) -> Self {
+        let dns = Vx0DNS::new().into_shared();
         Vx0DNSServer {
-            dns: Vx0DNS::new(),
+            resolver: Vx0Resolver::new(Vec::new()).with_directory(Arc::clone(&dns)),
+            dns,
             bind_addr,
+            upstream: None,
+            forward_retry: RetryPolicy::DNS_FORWARD,
+            allow_from: config::default_dns_allow_from(),
+            ranking: None,
         }
     }
 
-    pub async fn start(&mut self) -> Result<(), DNSError> {
-        let socket = UdpSocket::bind(self.bind_addr).await?;
-        tracing::info!("VX0 DNS server started on {}", self.bind_addr);
+    /// Serve the daemon's directory on the configured port; the resolver
+    /// behind it reads the same directory
+    pub fn from_config(config: &DNSConfig, dns: SharedDns) -> Self {
+        let mut resolver =
+            Vx0Resolver::new(config.vx0_dns_servers.clone()).with_directory(Arc::clone(&dns));
+        resolver.set_use_gateways(config.use_gateways);
+        Vx0DNSServer {
+            dns,
+            resolver,
+            bind_addr: SocketAddr::from(([0, 0, 0, 0], config.listen_port)),
+            upstream: config.proxy_clearnet.then_some(config.upstream),
+            forward_retry: RetryPolicy::DNS_FORWARD,
+            allow_from: config.allow_from.clone(),
+            ranking: None,
+        }
+    }
 
-        let mut buf = [0; 512];
+    /// Pass non-VX0 queries to `upstream` instead of refusing them
+    pub fn with_upstream(mut self, upstream: Option<SocketAddr>) -> Self {
+        self.upstream = upstream;
+        self
+    }
 
-        loop {
-            match socket.recv_from(&mut buf).await {
-                Ok((size, client_addr)) => {
-                    tracing::debug!("DNS query from {} ({} bytes)", client_addr, size);
+    pub fn with_forward_retry(mut self, policy: RetryPolicy) -> Self {
+        self.forward_retry = policy;
+        self
+    }
+
+    /// Reach clearnet gateways over `node`'s tunnels to them
+    pub fn with_node(mut self, node: Arc<Vx0Node>) -> Self {
+        self.resolver = self.resolver.with_node(node);
+        self
+    }
 
-                    if let Err(e) = self.handle_query(&socket, &buf[..size], client_addr).await {
-                        tracing::error!("Error handling DNS query: {}", e);
+    /// Order multi-address answers by route health
+    pub fn with_ranking(mut self, ranking: AnswerRanking) -> Self {
+        self.ranking = Some(Arc::new(ranking));
+        self
+    }
+
+    /// Answer queries in the background, returning the bound address
+    pub async fn start(self) -> Result<SocketAddr, DNSError> {
+        let socket = UdpSocket::bind(self.bind_addr).await?;
+        let local_addr = socket.local_addr()?;
+        tracing::info!("VX0 DNS server started on {}", local_addr);
+
+        let socket = Arc::new(socket);
+        let server = Arc::new(self);
+        crash::spawn(Subsystem::Dns, "dns-server", async move {
+            let mut buf = [0; MAX_MESSAGE];
+            loop {
+                let (size, client_addr) = match socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::error!("DNS server socket error: {}", e);
+                        continue;
                     }
-                }
+                };
+                crate::sampled!(tracing::debug!(
+                    "DNS query from {} ({} bytes)",
+                    client_addr,
+                    size
+                ));
+
+                // Upstream round trips must not hold up other clients
+                let packet = buf[..size].to_vec();
+                let server = Arc::clone(&server);
+                let socket = Arc::clone(&socket);
+                crash::spawn(Subsystem::Dns, "dns-query", async move {
+                    if let Some(reply) = server.handle_query(&packet, client_addr).await {
+                        if let Err(e) = socket.send_to(&reply, client_addr).await {
+                            tracing::debug!("Failed to answer {}: {}", client_addr, e);
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    /// The reply to send a client, if any
+    async fn handle_query(&self, packet: &[u8], client_addr: SocketAddr) -> Option<Vec<u8>> {
+        if !self.allows(client_addr.ip()) {
+            crate::sampled!(tracing::debug!(
+                "Ignoring DNS query from {}, not in allow_from",
+                client_addr
+            ));
+            return None;
+        }
+
+        let query = match Query::parse(packet) {
+            Ok(query) => query,
+            Err(e) => {
+                tracing::debug!("Malformed DNS query from {}: {}", client_addr, e);
+                return wire::error_reply(packet, Rcode::FormErr);
+            }
+        };
+        if query.opcode() != 0 {
+            return Some(query.reply(Rcode::NotImp, &[], 0));
+        }
+
+        if is_vx0_name(&query.name) {
+            return Some(self.answer_vx0(&query).await);
+        }
+        match self.upstream {
+            Some(upstream) => Some(match self.forward(packet, upstream).await {
+                Ok(reply) => reply,
                 Err(e) => {
-                    tracing::error!("DNS server socket error: {}", e);
-                    break;
+                    tracing::warn!(
+                        "Upstream resolver {} failed for {}: {}",
+                        upstream,
+                        query.name,
+                        e
+                    );
+                    query.reply(Rcode::ServFail, &[], 0)
                 }
+            }),
+            None => {
+                crate::sampled!(tracing::debug!("Refused non-VX0 name {}", query.name));
+                Some(query.reply(Rcode::Refused, &[], 0))
             }
         }
+    }
 
-        Ok(())
+    fn allows(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        self.allow_from.covers_addr(client)
     }
 
-    async fn handle_query(
-        &self,
-        socket: &UdpSocket,
-        query: &[u8],
-        client_addr: SocketAddr,
-    ) -> Result<(), DNSError> {
-        // Simplified DNS query handling
-        // In a real implementation, we would parse the DNS packet format
-
-        let query_str = String::from_utf8_lossy(query);
-        tracing::debug!("DNS query content: {}", query_str);
-
-        // For testing purposes, let's simulate some common queries
-        let response = if query_str.contains("vx0.network") {
-            self.create_response("vx0.network", "10.0.1.1")
-        } else if query_str.contains("gateway.vx0") {
-            self.create_response("gateway.vx0", "10.0.0.1")
-        } else if query_str.contains("node1.vx0") {
-            self.create_response("node1.vx0", "10.0.2.1")
-        } else if query_str.contains("node2.vx0") {
-            self.create_response("node2.vx0", "10.0.2.2")
-        } else {
-            // Return NXDOMAIN response
-            b"NXDOMAIN".to_vec()
+    async fn answer_vx0(&self, query: &Query) -> Vec<u8> {
+        if query.qtype == TYPE_TXT && metadata::is_metadata_name(&query.name) {
+            let texts = self.dns.read().await.texts(&query.name);
+            let rcode = if texts.is_empty() {
+                Rcode::NxDomain
+            } else {
+                Rcode::NoError
+            };
+            return query.reply_texts(rcode, &texts, ANSWER_TTL);
+        }
+        if query.qtype == TYPE_SRV {
+            let targets = self.dns.read().await.lookup_srv(&query.name);
+            let rcode = if targets.is_empty() {
+                Rcode::NxDomain
+            } else {
+                Rcode::NoError
+            };
+            return query.reply_srv(rcode, &targets, ANSWER_TTL);
+        }
+        let cache = Arc::clone(self.dns.read().await.cache());
+        let (answer, ttl) = match cache.get(&query.name, query.qtype) {
+            Some((answer, left)) => (answer, left.as_secs().max(1) as u32),
+            None => {
+                let (answer, source) = self.lookup(query).await;
+                let ttl = Duration::from_secs(ANSWER_TTL.into());
+                cache.insert(&query.name, query.qtype, answer.clone(), source, ttl);
+                (answer, ANSWER_TTL)
+            }
         };
+        match answer {
+            CachedAnswer::Addresses(addresses) => {
+                let addresses = match &self.ranking {
+                    Some(ranking) => ranking.order(addresses).await,
+                    None => addresses,
+                };
+                query.reply(Rcode::NoError, &addresses, ttl)
+            }
+            CachedAnswer::NoData => query.reply(Rcode::NoError, &[], ttl),
+            CachedAnswer::NxDomain => query.reply(Rcode::NxDomain, &[], ttl),
+        }
+    }
 
-        socket.send_to(&response, client_addr).await?;
-        tracing::debug!("Sent DNS response to {}", client_addr);
+    /// The answer to a query the cache could not give, and where it came from
+    async fn lookup(&self, query: &Query) -> (CachedAnswer, CacheSource) {
+        if let Some((addresses, source)) = self.addresses(query).await {
+            return (CachedAnswer::Addresses(addresses), source);
+        }
+        // The name exists, just not with this record type
+        if matches!(query.qtype, TYPE_A | TYPE_AAAA) {
+            if let Some((_, source)) = self.resolve(&query.name).await {
+                return (CachedAnswer::NoData, source);
+            }
+        }
+        (CachedAnswer::NxDomain, CacheSource::Forwarded)
+    }
 
-        Ok(())
+    /// Every directory address of the queried type, or else whatever the
+    /// rest of the network has
+    async fn addresses(&self, query: &Query) -> Option<(Vec<IpAddr>, CacheSource)> {
+        let record_type = match query.qtype {
+            TYPE_A => Some(RecordType::A),
+            TYPE_AAAA => Some(RecordType::AAAA),
+            _ => None,
+        };
+        if let Some(record_type) = &record_type {
+            let dns = self.dns.read().await;
+            let addresses = dns.lookup_addresses(&query.name, record_type);
+            if !addresses.is_empty() {
+                let source = dns.source_of(&query.name).unwrap_or(CacheSource::Local);
+                return Some((addresses, source));
+            }
+        }
+        if query.qtype == TYPE_AAAA {
+            return None;
+        }
+        self.resolve(&query.name)
+            .await
+            .map(|(address, source)| (vec![address], source))
+    }
+
+    /// The directory first, then the rest of the VX0 network
+    async fn resolve(&self, name: &str) -> Option<(IpAddr, CacheSource)> {
+        {
+            let dns = self.dns.read().await;
+            if let Some(address) = dns.resolve_vx0_domain(name).await {
+                let source = dns.source_of(name).unwrap_or(CacheSource::Forwarded);
+                return Some((address, source));
+            }
+        }
+        match self.resolver.resolve(name).await {
+            Ok(address) => address.map(|address| (address, CacheSource::Forwarded)),
+            Err(e) => {
+                tracing::warn!("VX0 resolution of {} failed: {}", name, e);
+                None
+            }
+        }
     }
 
-    fn create_response(&self, domain: &str, ip: &str) -> Vec<u8> {
-        // This is a simplified response - in a real implementation,
-        // we would create proper DNS response packets
-        format!("{} IN A {}", domain, ip).into_bytes()
+    /// Relay a query, retrying per the forwarding policy
+    async fn forward(&self, packet: &[u8], upstream: SocketAddr) -> Result<Vec<u8>, DNSError> {
+        backoff::retry_with(
+            &self.forward_retry,
+            &CancellationToken::new(),
+            |_| true,
+            || self.proxy(packet, upstream),
+        )
+        .await
+        .map_err(RetryError::into_inner)
     }
 
-    pub fn register_service(
-        &mut self,
+    /// Relay a query unchanged and return the upstream's reply
+    async fn proxy(&self, packet: &[u8], upstream: SocketAddr) -> Result<Vec<u8>, DNSError> {
+        let bind: SocketAddr = if upstream.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(upstream).await?;
+        socket.send(packet).await?;
+
+        let mut buf = [0; MAX_MESSAGE];
+        let size = timeout(UPSTREAM_TIMEOUT, socket.recv(&mut buf))
+            .await
+            .map_err(|_| DNSError::Network(format!("No answer within {:?}", UPSTREAM_TIMEOUT)))??;
+        Ok(buf[..size].to_vec())
+    }
+
+    pub async fn register_service(
+        &self,
         domain: String,
         ip: std::net::IpAddr,
     ) -> Result<(), DNSError> {
-        self.dns.register_service(domain, ip)
+        self.dns.write().await.register_service(domain, ip)
     }
 
-    pub fn add_record(&mut self, record: DNSRecord) {
+    pub async fn add_record(&self, record: DNSRecord) {
         let domain = record.name.clone();
-        self.dns.records.entry(domain).or_default().push(record);
+        let mut dns = self.dns.write().await;
+        dns.cache().invalidate(&domain);
+        dns.records.entry(domain).or_default().push(record);
     }
 
-    pub fn get_records(&self, domain: &str) -> Option<&Vec<DNSRecord>> {
-        self.dns.get_records(domain)
+    pub async fn get_records(&self, domain: &str) -> Option<Vec<DNSRecord>> {
+        self.dns.read().await.get_records(domain).cloned()
     }
 
-    pub fn create_vx0_network_record(&mut self) -> Result<(), DNSError> {
+    pub async fn create_vx0_network_record(&self) -> Result<(), DNSError> {
         // Check if vx0.network record already exists
-        if self.get_records("vx0.network").is_some() {
+        if self.get_records("vx0.network").await.is_some() {
             tracing::debug!("vx0.network DNS record already exists");
             return Ok(());
         }
@@ -108,14 +354,15 @@ impl Vx0DNSServer {
             data: "10.0.1.1".to_string(),
             ttl: 300,
             timestamp: chrono::Utc::now(),
+            origin: None,
         };
 
-        self.add_record(record);
+        self.add_record(record).await;
         tracing::info!("Created vx0.network DNS record");
         Ok(())
     }
 
-    pub fn create_node_records(&mut self, node_count: u8) -> Result<(), DNSError> {
+    pub async fn create_node_records(&self, node_count: u8) -> Result<(), DNSError> {
         for i in 1..=node_count {
             let record = DNSRecord {
                 name: format!("node{}.vx0", i),
@@ -123,9 +370,10 @@ impl Vx0DNSServer {
                 data: format!("10.0.2.{}", i),
                 ttl: 300,
                 timestamp: chrono::Utc::now(),
+                origin: None,
             };
 
-            self.add_record(record);
+            self.add_record(record).await;
             tracing::info!("Created node{}.vx0 DNS record", i);
         }
 
@@ -133,37 +381,50 @@ impl Vx0DNSServer {
     }
 }
 
+fn is_vx0_name(name: &str) -> bool {
+    name == "vx0" || name.ends_with(".vx0") || name == "vx0.network"
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::{IpAddr, Ipv4Addr};
+    use crate::network::bgp::testing;
+    use crate::network::bgp::{BGPDaemon, RouteEntry};
+    use crate::network::dns::wire::Response;
+    use crate::node::{NodeId, PeerConnection, Vx0Node};
+    use std::net::Ipv4Addr;
 
     #[test]
     fn test_dns_server_creation() {
         let addr = "127.0.0.1:53".parse().unwrap();
         let server = Vx0DNSServer::new(addr);
         assert_eq!(server.bind_addr, addr);
+        assert!(server.allows("192.168.1.20".parse().unwrap()));
+        assert!(server.allows("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!server.allows("203.0.113.9".parse().unwrap()));
     }
 
-    #[test]
-    fn test_record_creation() {
-        let mut server = Vx0DNSServer::new("127.0.0.1:53".parse().unwrap());
+    #[tokio::test]
+    async fn test_record_creation() {
+        let server = Vx0DNSServer::new("127.0.0.1:53".parse().unwrap());
 
-        let result = server.register_service(
-            "test.vx0".to_string(),
-            IpAddr::V4(Ipv4Addr::new(10, 0, 3, 1)),
-        );
+        let result = server
+            .register_service(
+                "test.vx0".to_string(),
+                IpAddr::V4(Ipv4Addr::new(10, 0, 3, 1)),
+            )
+            .await;
 
         assert!(result.is_ok());
     }
 
-    #[test]
-    fn test_vx0_network_record() {
-        let mut server = Vx0DNSServer::new("127.0.0.1:53".parse().unwrap());
-        let result = server.create_vx0_network_record();
+    #[tokio::test]
+    async fn test_vx0_network_record() {
+        let server = Vx0DNSServer::new("127.0.0.1:53".parse().unwrap());
+        let result = server.create_vx0_network_record().await;
         assert!(result.is_ok());
 
-        let records = server.get_records("vx0.network");
+        let records = server.get_records("vx0.network").await;
         assert!(records.is_some());
 
         if let Some(records) = records {
@@ -171,4 +432,163 @@ mod tests {
             assert_eq!(records[0].data, "10.0.1.1");
         }
     }
+
+    /// Stands in for 1.1.1.1, answering every A query with one address
+    async fn fake_upstream(answer: IpAddr) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; MAX_MESSAGE];
+            while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                let query = Query::parse(&buf[..size]).unwrap();
+                let reply = query.reply(Rcode::NoError, &[answer], 300);
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn ask(server: SocketAddr, id: u16, name: &str) -> Response {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&Query::new(id, name, TYPE_A).to_bytes(), server)
+            .await
+            .unwrap();
+        let mut buf = [0; MAX_MESSAGE];
+        let size = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        Response::parse(&buf[..size]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_local_resolution_over_the_wire() {
+        let dns = Vx0DNS::new().into_shared();
+        dns.write()
+            .await
+            .register_service(
+                "forum.community1.vx0".to_string(),
+                "10.0.3.7".parse().unwrap(),
+            )
+            .unwrap();
+        let config: crate::config::Vx0Config =
+            toml::from_str(include_str!("../../../config/edge-node.toml")).unwrap();
+        let clearnet: IpAddr = "93.184.216.34".parse().unwrap();
+        let upstream = fake_upstream(clearnet).await;
+
+        let start = |upstream| {
+            let server = Vx0DNSServer {
+                bind_addr: "127.0.0.1:0".parse().unwrap(),
+                ..Vx0DNSServer::from_config(&config.network.dns, Arc::clone(&dns))
+            };
+            server.with_upstream(upstream).start()
+        };
+        let refusing = start(None).await.unwrap();
+        let proxying = start(Some(upstream)).await.unwrap();
+
+        // .vx0 names come from the directory whatever the clearnet setting
+        for server in [refusing, proxying] {
+            let answer = ask(server, 1, "Forum.Community1.vx0").await;
+            assert_eq!(answer.id, 1);
+            assert_eq!(answer.rcode, Some(Rcode::NoError));
+            assert_eq!(
+                answer.addresses,
+                vec!["10.0.3.7".parse::<IpAddr>().unwrap()]
+            );
+        }
+        let missing = ask(refusing, 2, "nowhere.vx0").await;
+        assert_eq!(missing.rcode, Some(Rcode::NxDomain));
+
+        // Clearnet names are refused unless proxying is on
+        let refused = ask(refusing, 3, "example.com").await;
+        assert_eq!(refused.rcode, Some(Rcode::Refused));
+        assert!(refused.addresses.is_empty());
+
+        let proxied = ask(proxying, 4, "example.com").await;
+        assert_eq!(proxied.id, 4);
+        assert_eq!(proxied.rcode, Some(Rcode::NoError));
+        assert_eq!(proxied.addresses, vec![clearnet]);
+    }
+
+    fn route(network: &str, peer_asn: u32) -> RouteEntry {
+        testing::route(network, "10.0.0.1", &[peer_asn])
+    }
+
+    #[tokio::test]
+    async fn test_answers_follow_route_health() {
+        let mut config: crate::config::Vx0Config =
+            toml::from_str(include_str!("../../../config/edge-node.toml")).unwrap();
+        config.network.dns.health_cache_ms = 0;
+        let near: IpAddr = "10.2.0.5".parse().unwrap();
+        let far: IpAddr = "10.1.0.5".parse().unwrap();
+
+        let dns = Vx0DNS::new().into_shared();
+        for address in [far, near] {
+            dns.write()
+                .await
+                .register_service("forum.community1.vx0".to_string(), address)
+                .unwrap();
+        }
+        let node = Arc::new(Vx0Node::new(config.clone()).unwrap());
+        let bgp = Arc::new(BGPDaemon::new(66001, "10.2.0.1".parse().unwrap(), 0));
+        for (asn, latency_ms, network) in [(65101, 40, "10.1.0.0/16"), (65102, 10, "10.2.0.0/16")] {
+            let mut peer = PeerConnection::new(NodeId::new_v4(), asn, "10.9.0.1".parse().unwrap());
+            peer.update_metrics(latency_ms, 0.0);
+            node.add_peer(peer).await.unwrap();
+            bgp.receive_update(asn, vec![route(network, asn)], &[])
+                .await
+                .unwrap();
+        }
+
+        let start = |dns_config: &DNSConfig| {
+            let ranking =
+                AnswerRanking::new(Arc::clone(&bgp), dns_config).with_node(Arc::clone(&node));
+            let server = Vx0DNSServer {
+                bind_addr: "127.0.0.1:0".parse().unwrap(),
+                ..Vx0DNSServer::from_config(dns_config, Arc::clone(&dns))
+            };
+            server.with_ranking(ranking).start()
+        };
+        let ordering = start(&config.network.dns).await.unwrap();
+        let omitting = start(&DNSConfig {
+            omit_unreachable: true,
+            ..config.network.dns.clone()
+        })
+        .await
+        .unwrap();
+
+        // Both reachable: the lower latency peer's host leads
+        assert_eq!(
+            ask(ordering, 1, "forum.community1.vx0").await.addresses,
+            vec![near, far]
+        );
+        assert_eq!(
+            ask(omitting, 2, "forum.community1.vx0").await.addresses,
+            vec![near, far]
+        );
+
+        // Withdrawing its route sends it to the back, or out of the answer
+        bgp.receive_update(65102, vec![], &["10.2.0.0/16".parse().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(
+            ask(ordering, 3, "forum.community1.vx0").await.addresses,
+            vec![far, near]
+        );
+        assert_eq!(
+            ask(omitting, 4, "forum.community1.vx0").await.addresses,
+            vec![far]
+        );
+
+        // Equally healthy answers take turns at the front
+        bgp.receive_update(65102, vec![route("10.2.0.0/16", 65102)], &[])
+            .await
+            .unwrap();
+        let unmeasured = AnswerRanking::new(Arc::clone(&bgp), &config.network.dns);
+        let first = unmeasured.order(vec![far, near]).await;
+        let second = unmeasured.order(vec![far, near]).await;
+        assert_eq!(first, vec![far, near]);
+        assert_eq!(second, vec![near, far]);
+    }
 }
@@ -0,0 +1,587 @@
+//! NOTIFY-style zone synchronization between DNS-serving nodes.
+//!
+//! After a change the primary sends its new serial to each secondary. A
+//! secondary that is behind connects back and asks for everything after its
+//! own serial, receiving journal entries when the primary still has them and
+//! a full copy of the zone otherwise. The daemon sends these as each change
+//! is committed, following its directory's
+//! [`changes`](crate::network::dns::changes).
+//!
+//! A primary that needs to know a change landed names each secondary in its
+//! NOTIFY. The secondary then reports the serial it holds with `Stored` once
+//! it has pulled, and the primary keeps the highest serial each secondary
+//! reported, so a late acknowledgement of an older serial never confirms a
+//! newer change.
+//!
+//! Peers also answer service searches from their copy of the directory, so
+//! a searching node can see listings that have not reached it yet.
+
+use crate::monitoring::capture::{Capture, CapturedEvent};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::dns::cache::CacheSource;
+use crate::network::dns::zone::{self, ZoneTransfer};
+use crate::network::dns::{DNSError, SharedDns};
+use crate::node::search::{ServiceFilter, ServiceSummary};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ZoneMessage {
+    /// The sender's copy of `zone` is now at `serial`; pull from `primary`
+    ///
+    /// When `secondary` is set the receiver reports back with `Stored`,
+    /// quoting it as its own address.
+    Notify {
+        zone: String,
+        serial: u32,
+        primary: SocketAddr,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        secondary: Option<SocketAddr>,
+    },
+    /// The secondary known to the primary as `secondary` holds `zone` at `serial`
+    Stored {
+        zone: String,
+        serial: u32,
+        secondary: SocketAddr,
+    },
+    /// Ask for whatever is needed to move from `serial` to current
+    Request {
+        zone: String,
+        serial: u32,
+    },
+    Transfer {
+        transfer: ZoneTransfer,
+    },
+    /// Listed services matching `filter`, answered from the receiver's copy
+    FindServices {
+        filter: ServiceFilter,
+    },
+    Services {
+        services: Vec<ServiceSummary>,
+    },
+    Ack,
+    Error {
+        message: String,
+    },
+}
+
+/// Highest serial each secondary reported storing, by zone and secondary
+type StoredSerials = HashMap<(String, SocketAddr), u32>;
+
+/// Which secondaries confirmed holding a zone at (or past) a serial
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Propagation {
+    pub zone: String,
+    pub serial: u32,
+    pub confirmed: Vec<SocketAddr>,
+    pub pending: Vec<SocketAddr>,
+}
+
+#[derive(Clone)]
+pub struct ZoneSyncService {
+    dns: SharedDns,
+    stored: Arc<watch::Sender<StoredSerials>>,
+    /// Records what pulls bring back, when capturing
+    capture: Option<Arc<Capture>>,
+}
+
+impl ZoneSyncService {
+    pub fn new(dns: SharedDns) -> Self {
+        ZoneSyncService {
+            dns,
+            stored: Arc::new(watch::Sender::new(HashMap::new())),
+            capture: None,
+        }
+    }
+
+    /// Record every transfer pulled from a primary to `capture`
+    pub fn with_capture(mut self, capture: Arc<Capture>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Serve transfer requests and notifications, returning the bound address
+    pub async fn start(&self, bind_addr: SocketAddr) -> Result<SocketAddr, DNSError> {
+        let listener = TcpListener::bind(bind_addr).await?;
+        let local_addr = listener.local_addr()?;
+        tracing::info!("Zone sync listening on {}", local_addr);
+
+        let service = self.clone();
+        crash::spawn(Subsystem::Dns, "zone-sync", async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => {
+                        let service = service.clone();
+                        crash::spawn(Subsystem::Dns, "zone-sync-peer", async move {
+                            if let Err(e) = service.handle_peer(stream).await {
+                                tracing::debug!("Zone sync with {} ended: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        tracing::error!("Zone sync accept error: {}", e);
+                    }
+                }
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    async fn handle_peer(&self, stream: TcpStream) -> Result<(), DNSError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let reply = match serde_json::from_str::<ZoneMessage>(&line)? {
+                ZoneMessage::Notify {
+                    zone,
+                    serial,
+                    primary,
+                    secondary,
+                } => {
+                    self.handle_notify(zone, serial, primary, secondary).await;
+                    ZoneMessage::Ack
+                }
+                ZoneMessage::Stored {
+                    zone,
+                    serial,
+                    secondary,
+                } => {
+                    self.record_stored(&zone, secondary, serial);
+                    ZoneMessage::Ack
+                }
+                ZoneMessage::Request { zone, serial } => {
+                    match self.dns.read().await.transfer_since(&zone, serial) {
+                        Ok(transfer) => ZoneMessage::Transfer { transfer },
+                        Err(e) => ZoneMessage::Error {
+                            message: e.to_string(),
+                        },
+                    }
+                }
+                ZoneMessage::FindServices { filter } => ZoneMessage::Services {
+                    services: self.dns.read().await.find_services(&filter),
+                },
+                other => ZoneMessage::Error {
+                    message: format!("Unexpected message: {:?}", other),
+                },
+            };
+            write_message(&mut writer, &reply).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_notify(
+        &self,
+        zone: String,
+        serial: u32,
+        primary: SocketAddr,
+        secondary: Option<SocketAddr>,
+    ) {
+        let Some(ours) = self.dns.read().await.serial(&zone) else {
+            return;
+        };
+        if !zone::serial_newer(serial, ours) {
+            // Already current; confirming again is harmless
+            if let Some(secondary) = secondary {
+                crash::spawn(
+                    Subsystem::Dns,
+                    "zone-sync-stored",
+                    report_stored(primary, zone, ours, secondary),
+                );
+            }
+            return;
+        }
+
+        tracing::debug!(
+            "Zone {} at serial {} on {} is newer than ours; pulling",
+            zone,
+            serial,
+            primary
+        );
+        let service = self.clone();
+        crash::spawn(Subsystem::Dns, "zone-sync-pull", async move {
+            if let Err(e) = service.pull_from(primary, &zone).await {
+                tracing::warn!("Failed to sync zone {} from {}: {}", zone, primary, e);
+                return;
+            }
+            if let Some(secondary) = secondary {
+                if let Some(serial) = service.dns.read().await.serial(&zone) {
+                    report_stored(primary, zone, serial, secondary).await;
+                }
+            }
+        });
+    }
+
+    /// Remember that `secondary` holds `zone` at `serial`, ignoring reports
+    /// older than one already seen. Returns whether anything changed.
+    pub fn record_stored(&self, zone: &str, secondary: SocketAddr, serial: u32) -> bool {
+        self.stored
+            .send_if_modified(|stored| match stored.get(&(zone.to_string(), secondary)) {
+                Some(known) if !zone::serial_newer(serial, *known) => false,
+                _ => {
+                    stored.insert((zone.to_string(), secondary), serial);
+                    true
+                }
+            })
+    }
+
+    /// Highest serial of `zone` that `secondary` reported storing
+    pub fn stored_serial(&self, zone: &str, secondary: SocketAddr) -> Option<u32> {
+        self.stored
+            .borrow()
+            .get(&(zone.to_string(), secondary))
+            .copied()
+    }
+
+    /// Notify `secondaries` of our current serial for `zone`, then wait until
+    /// `quorum` of them report storing it or `wait` runs out
+    pub async fn propagate(
+        &self,
+        zone: &str,
+        primary: SocketAddr,
+        secondaries: &[SocketAddr],
+        quorum: usize,
+        wait: Duration,
+    ) -> Result<Propagation, DNSError> {
+        let serial = self
+            .dns
+            .read()
+            .await
+            .serial(zone)
+            .ok_or_else(|| DNSError::InvalidDomain(zone.to_string()))?;
+
+        let deadline = Instant::now() + wait;
+        let mut stored = self.stored.subscribe();
+        for secondary in secondaries {
+            let notify = ZoneMessage::Notify {
+                zone: zone.to_string(),
+                serial,
+                primary,
+                secondary: Some(*secondary),
+            };
+            let secondary = *secondary;
+            crash::spawn(Subsystem::Dns, "zone-sync-notify", async move {
+                if let Err(e) = exchange(secondary, &notify).await {
+                    tracing::debug!("Failed to notify {}: {}", secondary, e);
+                }
+            });
+        }
+
+        loop {
+            let (confirmed, pending): (Vec<SocketAddr>, Vec<SocketAddr>) = {
+                let stored = stored.borrow_and_update();
+                secondaries.iter().partition(|secondary| {
+                    stored
+                        .get(&(zone.to_string(), **secondary))
+                        .is_some_and(|held| !zone::serial_newer(serial, *held))
+                })
+            };
+            let settled = confirmed.len() >= quorum || pending.is_empty();
+            if settled || !matches!(timeout_at(deadline, stored.changed()).await, Ok(Ok(()))) {
+                return Ok(Propagation {
+                    zone: zone.to_string(),
+                    serial,
+                    confirmed,
+                    pending,
+                });
+            }
+        }
+    }
+
+    /// Bring our copy of `zone` up to date from `primary`, returning what was applied
+    pub async fn pull_from(
+        &self,
+        primary: SocketAddr,
+        zone: &str,
+    ) -> Result<ZoneTransfer, DNSError> {
+        let serial = self
+            .dns
+            .read()
+            .await
+            .serial(zone)
+            .ok_or_else(|| DNSError::InvalidDomain(zone.to_string()))?;
+
+        let request = ZoneMessage::Request {
+            zone: zone.to_string(),
+            serial,
+        };
+        let transfer = match exchange(primary, &request).await? {
+            ZoneMessage::Transfer { transfer } => transfer,
+            ZoneMessage::Error { message } => return Err(DNSError::Protocol(message)),
+            other => {
+                return Err(DNSError::Protocol(format!(
+                    "Unexpected reply to transfer request: {:?}",
+                    other
+                )))
+            }
+        };
+
+        if let Some(capture) = &self.capture {
+            capture.record(CapturedEvent::ZoneTransfer {
+                zone: zone.to_string(),
+                primary,
+                transfer: transfer.clone(),
+            });
+        }
+        self.dns.write().await.apply_transfer(transfer.clone())?;
+        tracing::info!(
+            "Synced zone {} from {} ({})",
+            zone,
+            primary,
+            match &transfer {
+                ZoneTransfer::UpToDate { .. } => "up to date",
+                ZoneTransfer::Incremental { .. } => "incremental",
+                ZoneTransfer::Full { .. } => "full transfer",
+            }
+        );
+        Ok(transfer)
+    }
+
+    /// Notify secondaries of every change this node makes to its directory
+    /// as it is made, asking `secondaries` who they are each time
+    pub async fn follow_changes<F, Fut>(&self, primary: SocketAddr, secondaries: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Vec<SocketAddr>> + Send,
+    {
+        let mut changes = self.dns.read().await.subscribe();
+        let service = self.clone();
+        crash::spawn(Subsystem::Dns, "zone-sync-changes", async move {
+            loop {
+                let change = match changes.recv().await {
+                    Ok(change) => change,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        // The next change carries the latest serial anyway
+                        tracing::debug!("Zone sync missed {} directory changes", missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                // What we pulled, our primary has already told its secondaries
+                if change.source != CacheSource::Local {
+                    continue;
+                }
+                let peers = secondaries().await;
+                if !peers.is_empty() {
+                    service.notify_peers(&change.zone, primary, &peers).await;
+                }
+            }
+        });
+    }
+
+    /// Tell secondaries our serial for `zone`; `primary` is where they pull from
+    pub async fn notify_peers(&self, zone: &str, primary: SocketAddr, peers: &[SocketAddr]) {
+        let Some(serial) = self.dns.read().await.serial(zone) else {
+            return;
+        };
+
+        let notify = ZoneMessage::Notify {
+            zone: zone.to_string(),
+            serial,
+            primary,
+            secondary: None,
+        };
+        for peer in peers {
+            if let Err(e) = exchange(*peer, &notify).await {
+                tracing::warn!("Failed to notify {} of zone {} change: {}", peer, zone, e);
+            }
+        }
+    }
+}
+
+/// Ask the peer serving zone sync at `peer` for its listings matching `filter`
+pub async fn find_services(
+    peer: SocketAddr,
+    filter: &ServiceFilter,
+) -> Result<Vec<ServiceSummary>, DNSError> {
+    let request = ZoneMessage::FindServices {
+        filter: filter.clone(),
+    };
+    match exchange(peer, &request).await? {
+        ZoneMessage::Services { services } => Ok(services),
+        ZoneMessage::Error { message } => Err(DNSError::Protocol(message)),
+        other => Err(DNSError::Protocol(format!(
+            "Unexpected reply to service search: {:?}",
+            other
+        ))),
+    }
+}
+
+/// Tell `primary` which serial of `zone` we now hold
+async fn report_stored(primary: SocketAddr, zone: String, serial: u32, secondary: SocketAddr) {
+    let stored = ZoneMessage::Stored {
+        zone,
+        serial,
+        secondary,
+    };
+    if let Err(e) = exchange(primary, &stored).await {
+        tracing::debug!("Failed to confirm sync to {}: {}", primary, e);
+    }
+}
+
+async fn exchange(peer: SocketAddr, message: &ZoneMessage) -> Result<ZoneMessage, DNSError> {
+    let exchange = async {
+        let stream = TcpStream::connect(peer).await?;
+        let (reader, mut writer) = stream.into_split();
+        write_message(&mut writer, message).await?;
+
+        let line = BufReader::new(reader)
+            .lines()
+            .next_line()
+            .await?
+            .ok_or_else(|| DNSError::Network(format!("{} closed the connection", peer)))?;
+        Ok(serde_json::from_str(&line)?)
+    };
+
+    timeout(SYNC_TIMEOUT, exchange)
+        .await
+        .map_err(|_| DNSError::Network(format!("Zone sync with {} timed out", peer)))?
+}
+
+async fn write_message<W>(writer: &mut W, message: &ZoneMessage) -> Result<(), DNSError>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let mut encoded = serde_json::to_vec(message)?;
+    encoded.push(b'\n');
+    writer.write_all(&encoded).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::dns::Vx0DNS;
+
+    fn zone_contents(dns: &Vx0DNS) -> Vec<(String, String)> {
+        let mut contents: Vec<(String, String)> = dns
+            .records
+            .values()
+            .flatten()
+            .map(|record| (record.name.clone(), record.data.clone()))
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    async fn converged(primary: &SharedDns, secondary: &SharedDns) -> bool {
+        for _ in 0..100 {
+            if secondary.read().await.serial("vx0") == primary.read().await.serial("vx0") {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_secondaries_converge() {
+        let primary_dns = Vx0DNS::new().into_shared();
+        let secondary_dns = Vx0DNS::new().into_shared();
+        let primary = ZoneSyncService::new(Arc::clone(&primary_dns));
+        let secondary = ZoneSyncService::new(Arc::clone(&secondary_dns));
+        let primary_addr = primary.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let secondary_addr = secondary
+            .start("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        {
+            let mut dns = primary_dns.write().await;
+            let before = dns.serial("vx0").unwrap();
+            dns.register_service("wiki.vx0".to_string(), "10.1.0.5".parse().unwrap())
+                .unwrap();
+            dns.register_service("chat.vx0".to_string(), "10.1.0.6".parse().unwrap())
+                .unwrap();
+            dns.deregister_service("chat.vx0").unwrap();
+            assert!(zone::serial_newer(dns.serial("vx0").unwrap(), before));
+        }
+
+        // NOTIFY makes the secondary pull the journal
+        primary
+            .notify_peers("vx0", primary_addr, &[secondary_addr])
+            .await;
+        assert!(converged(&primary_dns, &secondary_dns).await);
+        assert_eq!(
+            zone_contents(&*secondary_dns.read().await),
+            zone_contents(&*primary_dns.read().await)
+        );
+        assert!(matches!(
+            secondary.pull_from(primary_addr, "vx0").await.unwrap(),
+            ZoneTransfer::UpToDate { .. }
+        ));
+
+        // Once the journal no longer covers the gap a full copy is sent
+        {
+            let mut dns = primary_dns.write().await;
+            dns.set_journal_limit(2);
+            for i in 0..5 {
+                dns.register_service(format!("host{}.vx0", i), "10.1.0.7".parse().unwrap())
+                    .unwrap();
+            }
+        }
+        let transfer = secondary.pull_from(primary_addr, "vx0").await.unwrap();
+        assert!(matches!(transfer, ZoneTransfer::Full { .. }));
+        assert_eq!(
+            secondary_dns.read().await.serial("vx0"),
+            primary_dns.read().await.serial("vx0")
+        );
+        assert_eq!(
+            zone_contents(&*secondary_dns.read().await),
+            zone_contents(&*primary_dns.read().await)
+        );
+
+        // Serial and journal survive a restart
+        let path = std::env::temp_dir().join(format!("vx0net-zone-{}.json", uuid::Uuid::new_v4()));
+        primary_dns.read().await.save(&path).unwrap();
+        let restored = Vx0DNS::load(&path).unwrap();
+        assert_eq!(
+            restored.serial("vx0"),
+            primary_dns.read().await.serial("vx0")
+        );
+        assert_eq!(restored.zones["vx0"].journal.len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_stale_acks_do_not_confirm_newer_serials() {
+        let dns = Vx0DNS::new().into_shared();
+        let sync = ZoneSyncService::new(Arc::clone(&dns));
+        let secondary: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let old = dns.read().await.serial("vx0").unwrap();
+        dns.write()
+            .await
+            .register_service("wiki.vx0".to_string(), "10.1.0.5".parse().unwrap())
+            .unwrap();
+        let new = dns.read().await.serial("vx0").unwrap();
+
+        assert!(sync.record_stored("vx0", secondary, new));
+        // Repeated and out-of-order acknowledgements change nothing
+        assert!(!sync.record_stored("vx0", secondary, new));
+        assert!(!sync.record_stored("vx0", secondary, old));
+        assert_eq!(sync.stored_serial("vx0", secondary), Some(new));
+
+        dns.write()
+            .await
+            .register_service("chat.vx0".to_string(), "10.1.0.6".parse().unwrap())
+            .unwrap();
+        let propagation = sync
+            .propagate("vx0", secondary, &[secondary], 1, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(zone::serial_newer(propagation.serial, new));
+        assert_eq!(propagation.pending, vec![secondary]);
+    }
+}
@@ -0,0 +1,418 @@
+//! DNS messages in RFC 1035 wire format.
+//!
+//! Only what the local resolver needs: reading a single-question query,
+//! answering it with address, text or service records or an error code, and
+//! reading such an answer back.
+
+use crate::network::dns::{DNSError, SrvTarget};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+/// Pointers followed while reading one name, more than any real message uses
+const MAX_POINTERS: usize = 16;
+/// Longest character-string; longer TXT data is split across several
+const MAX_CHARACTER_STRING: usize = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rcode {
+    NoError = 0,
+    FormErr = 1,
+    ServFail = 2,
+    NxDomain = 3,
+    NotImp = 4,
+    Refused = 5,
+}
+
+impl Rcode {
+    fn from_bits(bits: u16) -> Option<Self> {
+        match bits & 0x000f {
+            0 => Some(Rcode::NoError),
+            1 => Some(Rcode::FormErr),
+            2 => Some(Rcode::ServFail),
+            3 => Some(Rcode::NxDomain),
+            4 => Some(Rcode::NotImp),
+            5 => Some(Rcode::Refused),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub id: u16,
+    pub flags: u16,
+    /// Lowercased, without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl Query {
+    pub fn new(id: u16, name: &str, qtype: u16) -> Self {
+        Query {
+            id,
+            flags: FLAG_RECURSION_DESIRED,
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            qtype,
+            qclass: CLASS_IN,
+        }
+    }
+
+    pub fn parse(packet: &[u8]) -> Result<Self, DNSError> {
+        let header = Header::parse(packet)?;
+        if header.flags & FLAG_RESPONSE != 0 {
+            return Err(DNSError::Protocol(
+                "Expected a query, got a response".into(),
+            ));
+        }
+        if header.qdcount != 1 {
+            return Err(DNSError::Protocol(format!(
+                "Expected one question, got {}",
+                header.qdcount
+            )));
+        }
+        let (name, offset) = read_name(packet, HEADER_LEN)?;
+        let qtype = read_u16(packet, offset)?;
+        let qclass = read_u16(packet, offset + 2)?;
+        Ok(Query {
+            id: header.id,
+            flags: header.flags,
+            name,
+            qtype,
+            qclass,
+        })
+    }
+
+    /// Opcode 0 is a standard query; nothing else is supported
+    pub fn opcode(&self) -> u16 {
+        (self.flags >> 11) & 0x000f
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + self.name.len() + 6);
+        for field in [self.id, self.flags, 1, 0, 0, 0] {
+            packet.extend_from_slice(&field.to_be_bytes());
+        }
+        self.write_question(&mut packet);
+        packet
+    }
+
+    /// The answer to this query, echoing its question; addresses of the
+    /// wrong family for the question are left out
+    pub fn reply(&self, rcode: Rcode, addresses: &[IpAddr], ttl: u32) -> Vec<u8> {
+        let records: Vec<Vec<u8>> = addresses
+            .iter()
+            .filter_map(|address| match (address, self.qtype) {
+                (IpAddr::V4(v4), TYPE_A) => Some(v4.octets().to_vec()),
+                (IpAddr::V6(v6), TYPE_AAAA) => Some(v6.octets().to_vec()),
+                _ => None,
+            })
+            .collect();
+        self.answer(rcode, records, ttl)
+    }
+
+    /// The answer to a TXT query, one record per text
+    pub fn reply_texts(&self, rcode: Rcode, texts: &[String], ttl: u32) -> Vec<u8> {
+        let records = if self.qtype == TYPE_TXT {
+            texts.iter().map(|text| txt_data(text)).collect()
+        } else {
+            Vec::new()
+        };
+        self.answer(rcode, records, ttl)
+    }
+
+    /// The answer to an SRV query, one record per service
+    pub fn reply_srv(&self, rcode: Rcode, targets: &[SrvTarget], ttl: u32) -> Vec<u8> {
+        let records = if self.qtype == TYPE_SRV {
+            targets.iter().map(srv_data).collect()
+        } else {
+            Vec::new()
+        };
+        self.answer(rcode, records, ttl)
+    }
+
+    fn answer(&self, rcode: Rcode, records: Vec<Vec<u8>>, ttl: u32) -> Vec<u8> {
+        let flags = FLAG_RESPONSE
+            | (self.flags & (0x7800 | FLAG_RECURSION_DESIRED))
+            | FLAG_RECURSION_AVAILABLE
+            | rcode as u16;
+        let mut packet = Vec::new();
+        for field in [self.id, flags, 1, records.len() as u16, 0, 0] {
+            packet.extend_from_slice(&field.to_be_bytes());
+        }
+        self.write_question(&mut packet);
+        for data in records {
+            // The name points back at the question
+            packet.extend_from_slice(&0xc00cu16.to_be_bytes());
+            packet.extend_from_slice(&self.qtype.to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&data);
+        }
+        packet
+    }
+
+    fn write_question(&self, packet: &mut Vec<u8>) {
+        write_name(packet, &self.name);
+        packet.extend_from_slice(&self.qtype.to_be_bytes());
+        packet.extend_from_slice(&self.qclass.to_be_bytes());
+    }
+}
+
+/// An error answer to a packet too broken to parse as a query, if it at
+/// least has a header to echo the id from
+pub fn error_reply(packet: &[u8], rcode: Rcode) -> Option<Vec<u8>> {
+    let header = Header::parse(packet).ok()?;
+    let flags = FLAG_RESPONSE | (header.flags & 0x7800) | rcode as u16;
+    let mut reply = Vec::with_capacity(HEADER_LEN);
+    for field in [header.id, flags, 0, 0, 0, 0] {
+        reply.extend_from_slice(&field.to_be_bytes());
+    }
+    Some(reply)
+}
+
+/// TXT record data: the text as length-prefixed character-strings
+fn txt_data(text: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(text.len() + 1);
+    for chunk in text.as_bytes().chunks(MAX_CHARACTER_STRING) {
+        data.push(chunk.len() as u8);
+        data.extend_from_slice(chunk);
+    }
+    if data.is_empty() {
+        data.push(0);
+    }
+    data
+}
+
+/// SRV record data: priority, weight and port, then the target uncompressed
+fn srv_data(target: &SrvTarget) -> Vec<u8> {
+    let mut data = Vec::with_capacity(8 + target.target.len());
+    for field in [target.priority, target.weight, target.port] {
+        data.extend_from_slice(&field.to_be_bytes());
+    }
+    write_name(&mut data, &target.target);
+    data
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+/// The text in TXT record data, its character-strings joined
+fn read_txt(data: &[u8]) -> Result<String, DNSError> {
+    let mut text = Vec::with_capacity(data.len());
+    let mut offset = 0;
+    while offset < data.len() {
+        let len = data[offset] as usize;
+        let chunk = data
+            .get(offset + 1..offset + 1 + len)
+            .ok_or_else(|| DNSError::Protocol("Truncated character-string".into()))?;
+        text.extend_from_slice(chunk);
+        offset += 1 + len;
+    }
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: u16,
+    /// `None` for codes beyond those this module knows
+    pub rcode: Option<Rcode>,
+    pub addresses: Vec<IpAddr>,
+    /// One per TXT record
+    pub texts: Vec<String>,
+    /// One per SRV record
+    pub services: Vec<SrvTarget>,
+}
+
+impl Response {
+    pub fn parse(packet: &[u8]) -> Result<Self, DNSError> {
+        let header = Header::parse(packet)?;
+        if header.flags & FLAG_RESPONSE == 0 {
+            return Err(DNSError::Protocol(
+                "Expected a response, got a query".into(),
+            ));
+        }
+
+        let mut offset = HEADER_LEN;
+        for _ in 0..header.qdcount {
+            offset = read_name(packet, offset)?.1 + 4;
+        }
+        let mut addresses = Vec::new();
+        let mut texts = Vec::new();
+        let mut services = Vec::new();
+        for _ in 0..header.ancount {
+            offset = read_name(packet, offset)?.1;
+            let rtype = read_u16(packet, offset)?;
+            let rdlength = read_u16(packet, offset + 8)? as usize;
+            let start = offset + 10;
+            let data = packet
+                .get(start..start + rdlength)
+                .ok_or_else(|| DNSError::Protocol("Truncated record".into()))?;
+            match (rtype, data.len()) {
+                (TYPE_A, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                ))),
+                (TYPE_AAAA, 16) => {
+                    let octets: [u8; 16] = data.try_into().expect("length checked");
+                    addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+                (TYPE_TXT, _) => texts.push(read_txt(data)?),
+                (TYPE_SRV, 7..) => services.push(SrvTarget {
+                    priority: read_u16(data, 0)?,
+                    weight: read_u16(data, 2)?,
+                    port: read_u16(data, 4)?,
+                    // The target may point back into the message
+                    target: read_name(packet, start + 6)?.0,
+                }),
+                _ => {}
+            }
+            offset = start + rdlength;
+        }
+
+        Ok(Response {
+            id: header.id,
+            rcode: Rcode::from_bits(header.flags),
+            addresses,
+            texts,
+            services,
+        })
+    }
+}
+
+struct Header {
+    id: u16,
+    flags: u16,
+    qdcount: u16,
+    ancount: u16,
+}
+
+impl Header {
+    fn parse(packet: &[u8]) -> Result<Self, DNSError> {
+        if packet.len() < HEADER_LEN {
+            return Err(DNSError::Protocol(format!(
+                "Message of {} bytes is shorter than a header",
+                packet.len()
+            )));
+        }
+        Ok(Header {
+            id: read_u16(packet, 0)?,
+            flags: read_u16(packet, 2)?,
+            qdcount: read_u16(packet, 4)?,
+            ancount: read_u16(packet, 6)?,
+        })
+    }
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16, DNSError> {
+    packet
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| DNSError::Protocol("Truncated message".into()))
+}
+
+/// Read the name at `offset`, returning it and where the data after it
+/// starts
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(String, usize), DNSError> {
+    let truncated = || DNSError::Protocol("Truncated name".into());
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *packet.get(offset).ok_or_else(truncated)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                let end = end.unwrap_or(offset + 1);
+                return Ok((labels.join("."), end));
+            }
+            0x00 => {
+                let label = packet
+                    .get(offset + 1..offset + 1 + len)
+                    .ok_or_else(truncated)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len;
+            }
+            0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(DNSError::Protocol("Name compression loops".into()));
+                }
+                let target = read_u16(packet, offset)? & 0x3fff;
+                end.get_or_insert(offset + 2);
+                offset = target as usize;
+            }
+            _ => return Err(DNSError::Protocol("Unsupported label type".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_and_reply_round_trip() {
+        let query = Query::new(0x1234, "Forum.Community1.VX0.", TYPE_A);
+        let parsed = Query::parse(&query.to_bytes()).unwrap();
+        assert_eq!(parsed, query);
+        assert_eq!(parsed.name, "forum.community1.vx0");
+
+        let addresses = ["10.0.3.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+        let response = Response::parse(&parsed.reply(Rcode::NoError, &addresses, 300)).unwrap();
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode, Some(Rcode::NoError));
+        // Only the family asked for is answered
+        assert_eq!(response.addresses, vec![addresses[0]]);
+
+        let refused = Response::parse(&parsed.reply(Rcode::Refused, &[], 0)).unwrap();
+        assert_eq!(refused.rcode, Some(Rcode::Refused));
+        assert!(refused.addresses.is_empty());
+
+        assert!(Query::parse(&[0; 5]).is_err());
+        let formerr = error_reply(&[0xab, 0xcd, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0], Rcode::FormErr);
+        let formerr = Response::parse(&formerr.unwrap()).unwrap();
+        assert_eq!((formerr.id, formerr.rcode), (0xabcd, Some(Rcode::FormErr)));
+    }
+
+    #[test]
+    fn test_txt_reply_round_trip() {
+        let query = Query::parse(&Query::new(7, "_stats.vx0", TYPE_TXT).to_bytes()).unwrap();
+        let long = format!("contact={}", "x".repeat(300));
+        let texts = vec!["backbone=3".to_string(), long, String::new()];
+        let response = Response::parse(&query.reply_texts(Rcode::NoError, &texts, 60)).unwrap();
+        assert_eq!(response.texts, texts);
+        assert!(response.addresses.is_empty());
+
+        // Texts only answer TXT questions
+        let query = Query::new(8, "_stats.vx0", TYPE_A);
+        let response = Response::parse(&query.reply_texts(Rcode::NoError, &texts, 60)).unwrap();
+        assert!(response.texts.is_empty());
+    }
+
+    #[test]
+    fn test_srv_reply_round_trip() {
+        let query = Query::new(9, "_chat._tcp.community1.vx0", TYPE_SRV);
+        let targets = vec![
+            SrvTarget::new(6667, "community1.vx0"),
+            SrvTarget::new(6697, "community1.vx0"),
+        ];
+        let response = Response::parse(&query.reply_srv(Rcode::NoError, &targets, 60)).unwrap();
+        assert_eq!(response.services, targets);
+        assert!(response.addresses.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! SOA serials and the per-zone change journal used for incremental sync.
+//!
+//! Serials follow the usual YYYYMMDDnn convention. Every mutation of a zone
+//! bumps its serial and appends a journal entry; a secondary at serial S can
+//! be brought current with the entries after S as long as the journal still
+//! reaches back that far, and needs a full transfer otherwise.
+
+use crate::network::dns::DNSRecord;
+use chrono::{Datelike, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Journal entries kept per zone unless configured otherwise
+pub const DEFAULT_JOURNAL_LEN: usize = 256;
+
+/// Next serial after `current` using the date-plus-counter convention
+pub fn next_serial(current: u32, today: NaiveDate) -> u32 {
+    let base = today.year() as u32 * 1_000_000 + today.month() * 10_000 + today.day() * 100;
+    if serial_newer(base, current) {
+        base
+    } else {
+        current.wrapping_add(1)
+    }
+}
+
+/// RFC 1982 serial comparison: true if `a` is newer than `b`
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ZoneChange {
+    Add { record: DNSRecord },
+    Remove { record: DNSRecord },
+}
+
+/// Changes that took a zone from one serial to the next
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub from_serial: u32,
+    pub to_serial: u32,
+    pub changes: Vec<ZoneChange>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneJournal {
+    entries: VecDeque<JournalEntry>,
+    max_entries: usize,
+}
+
+impl Default for ZoneJournal {
+    fn default() -> Self {
+        ZoneJournal::new(DEFAULT_JOURNAL_LEN)
+    }
+}
+
+impl ZoneJournal {
+    pub fn new(max_entries: usize) -> Self {
+        ZoneJournal {
+            entries: VecDeque::new(),
+            max_entries: max_entries.max(1),
+        }
+    }
+
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries.max(1);
+        self.trim();
+    }
+
+    pub fn record(&mut self, entry: JournalEntry) {
+        self.entries.push_back(entry);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Entries taking a zone from `serial` to the newest one, or `None` when
+    /// the journal no longer reaches back to `serial`
+    pub fn since(&self, serial: u32) -> Option<Vec<JournalEntry>> {
+        let start = self
+            .entries
+            .iter()
+            .position(|entry| entry.from_serial == serial)?;
+        Some(self.entries.iter().skip(start).cloned().collect())
+    }
+}
+
+/// What a secondary at some serial needs to become current
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ZoneTransfer {
+    UpToDate {
+        zone: String,
+        serial: u32,
+    },
+    Incremental {
+        zone: String,
+        entries: Vec<JournalEntry>,
+    },
+    Full {
+        zone: String,
+        serial: u32,
+        records: Vec<DNSRecord>,
+    },
+}
+
+impl ZoneTransfer {
+    pub fn zone(&self) -> &str {
+        match self {
+            ZoneTransfer::UpToDate { zone, .. }
+            | ZoneTransfer::Incremental { zone, .. }
+            | ZoneTransfer::Full { zone, .. } => zone,
+        }
+    }
+}
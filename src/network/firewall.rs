@@ -0,0 +1,957 @@
+//! Allow rules for the daemon's listen ports in the host firewall.
+//!
+//! With `network.firewall.manage`, startup opens exactly the ports peers
+//! reach the node on (BGP, IKE, zone sync, and discovery, the ASN registry
+//! and the gateway when they run), each for its own protocol, and shutdown
+//! closes them again. Ports only this host talks to, such as the local DNS
+//! server, metrics and the control socket, stay closed.
+//!
+//! Every rule carries a `vx0net:<config key>` comment (the rule name under
+//! netsh), which is how the daemon tells its rules from the operator's.
+//! Before inserting anything, tagged rules a crashed run left behind are
+//! reconciled: those still wanted are kept, the rest removed.
+//!
+//! Backends only build command lines, so what each one runs can be checked
+//! without touching a firewall. A command that fails is logged with the
+//! command to run by hand, and the daemon carries on.
+
+use crate::config::ports::{self, Endpoint, Transport};
+use crate::config::{FirewallConfig, Vx0Config};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+/// What every rule's comment starts with
+pub const TAG: &str = "vx0net:";
+
+/// Listeners only this host connects to, never opened
+const LOCAL_ONLY: &[&str] = &[
+    "network.dns.listen_port",
+    "monitoring.metrics_port",
+    "control.tcp_listen",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum FirewallError {
+    #[error("Cannot run {program}")]
+    Spawn {
+        program: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("`{command}` failed: {reason}")]
+    Command { command: String, reason: String },
+    #[error("No supported firewall found (tried ufw, nft and iptables)")]
+    NoBackend,
+}
+
+/// Which firewall to write to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendChoice {
+    #[default]
+    Auto,
+    Nft,
+    Iptables,
+    Ufw,
+    Netsh,
+}
+
+/// One allow rule: inbound traffic of `protocol` to `port`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+pub struct FirewallRule {
+    pub protocol: Transport,
+    pub port: u16,
+    /// Config key of the listener it opens
+    pub key: String,
+}
+
+impl FirewallRule {
+    fn comment(&self) -> String {
+        format!("{}{}", TAG, self.key)
+    }
+}
+
+/// A tagged rule as listed by the firewall; `handle` is how nft names it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledRule {
+    pub rule: FirewallRule,
+    pub handle: Option<u64>,
+}
+
+/// The allow rules for every listener peers reach, one per port and protocol
+pub fn wanted_rules(config: &Vx0Config) -> Vec<FirewallRule> {
+    let mut rules: Vec<FirewallRule> = Vec::new();
+    for listener in ports::planned_listeners(config) {
+        if LOCAL_ONLY.contains(&listener.key) {
+            continue;
+        }
+        let Endpoint::Socket(protocol, addr) = listener.endpoint else {
+            continue;
+        };
+        if addr.ip().is_loopback() || addr.port() == 0 {
+            continue;
+        }
+        let rule = FirewallRule {
+            protocol,
+            port: addr.port(),
+            key: listener.key.to_string(),
+        };
+        if !rules
+            .iter()
+            .any(|other| other.protocol == rule.protocol && other.port == rule.port)
+        {
+            rules.push(rule);
+        }
+    }
+    rules
+}
+
+/// A firewall and the command lines that change it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// Rules go into an existing chain, given as family, table and chain
+    Nft {
+        family: String,
+        table: String,
+        chain: String,
+    },
+    Iptables {
+        chain: String,
+    },
+    Ufw,
+    Netsh,
+}
+
+impl Backend {
+    /// The backend `config` names; `None` when it is left to detection
+    pub fn configured(config: &FirewallConfig) -> Option<Backend> {
+        match config.backend {
+            BackendChoice::Auto => None,
+            BackendChoice::Nft => Some(Backend::nft(&config.nft_chain)),
+            BackendChoice::Iptables => Some(Backend::Iptables {
+                chain: config.iptables_chain.clone(),
+            }),
+            BackendChoice::Ufw => Some(Backend::Ufw),
+            BackendChoice::Netsh => Some(Backend::Netsh),
+        }
+    }
+
+    /// "inet filter input", or just a chain in the inet filter table
+    fn nft(chain: &str) -> Backend {
+        let parts: Vec<&str> = chain.split_whitespace().collect();
+        let (family, table, chain) = match parts.as_slice() {
+            [family, table, chain] => (*family, *table, *chain),
+            [table, chain] => ("inet", *table, *chain),
+            _ => ("inet", "filter", chain.trim()),
+        };
+        Backend::Nft {
+            family: family.to_string(),
+            table: table.to_string(),
+            chain: chain.to_string(),
+        }
+    }
+
+    /// The configured backend, or else the firewall this host uses: netsh
+    /// on Windows; elsewhere ufw if it is active, then nft, then iptables
+    pub async fn detect(
+        config: &FirewallConfig,
+        runner: &dyn CommandRunner,
+    ) -> Result<Backend, FirewallError> {
+        if let Some(backend) = Backend::configured(config) {
+            return Ok(backend);
+        }
+        #[cfg(windows)]
+        {
+            let _ = runner;
+            Ok(Backend::Netsh)
+        }
+        #[cfg(not(windows))]
+        {
+            if let Ok(status) = runner.run(&argv(&["ufw", "status"])).await {
+                if status.contains("Status: active") {
+                    return Ok(Backend::Ufw);
+                }
+            }
+            if runner.run(&argv(&["nft", "--version"])).await.is_ok() {
+                return Ok(Backend::nft(&config.nft_chain));
+            }
+            if runner.run(&argv(&["iptables", "--version"])).await.is_ok() {
+                return Ok(Backend::Iptables {
+                    chain: config.iptables_chain.clone(),
+                });
+            }
+            Err(FirewallError::NoBackend)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Nft { .. } => "nft",
+            Backend::Iptables { .. } => "iptables",
+            Backend::Ufw => "ufw",
+            Backend::Netsh => "netsh",
+        }
+    }
+
+    pub fn insert(&self, rule: &FirewallRule) -> Vec<String> {
+        let port = rule.port.to_string();
+        match self {
+            Backend::Nft {
+                family,
+                table,
+                chain,
+            } => argv(&[
+                "nft",
+                "insert",
+                "rule",
+                family,
+                table,
+                chain,
+                rule.protocol.as_str(),
+                "dport",
+                &port,
+                "accept",
+                "comment",
+                &format!("\"{}\"", rule.comment()),
+            ]),
+            Backend::Iptables { chain } => iptables("-I", chain, rule),
+            Backend::Ufw => argv(&[
+                "ufw",
+                "allow",
+                &format!("{}/{}", port, rule.protocol.as_str()),
+                "comment",
+                &rule.comment(),
+            ]),
+            Backend::Netsh => argv(&[
+                "netsh",
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                &format!("name={}", rule.comment()),
+                "dir=in",
+                "action=allow",
+                &format!("protocol={}", rule.protocol.as_str().to_ascii_uppercase()),
+                &format!("localport={}", port),
+            ]),
+        }
+    }
+
+    pub fn remove(&self, installed: &InstalledRule) -> Vec<String> {
+        let rule = &installed.rule;
+        match self {
+            Backend::Nft {
+                family,
+                table,
+                chain,
+            } => argv(&[
+                "nft",
+                "delete",
+                "rule",
+                family,
+                table,
+                chain,
+                "handle",
+                &installed.handle.unwrap_or_default().to_string(),
+            ]),
+            Backend::Iptables { chain } => iptables("-D", chain, rule),
+            Backend::Ufw => argv(&[
+                "ufw",
+                "delete",
+                "allow",
+                &format!("{}/{}", rule.port, rule.protocol.as_str()),
+                "comment",
+                &rule.comment(),
+            ]),
+            Backend::Netsh => argv(&[
+                "netsh",
+                "advfirewall",
+                "firewall",
+                "delete",
+                "rule",
+                &format!("name={}", rule.comment()),
+                &format!("protocol={}", rule.protocol.as_str().to_ascii_uppercase()),
+                &format!("localport={}", rule.port),
+            ]),
+        }
+    }
+
+    /// The command whose output `parse` reads
+    pub fn list(&self) -> Vec<String> {
+        match self {
+            Backend::Nft {
+                family,
+                table,
+                chain,
+            } => argv(&["nft", "-a", "list", "chain", family, table, chain]),
+            Backend::Iptables { chain } => argv(&["iptables", "-S", chain]),
+            Backend::Ufw => argv(&["ufw", "status"]),
+            Backend::Netsh => argv(&[
+                "netsh",
+                "advfirewall",
+                "firewall",
+                "show",
+                "rule",
+                "name=all",
+                "dir=in",
+            ]),
+        }
+    }
+
+    /// The tagged rules in a listing
+    pub fn parse(&self, output: &str) -> Vec<InstalledRule> {
+        let mut rules: Vec<InstalledRule> = match self {
+            Backend::Nft { .. } => output.lines().filter_map(parse_nft).collect(),
+            Backend::Iptables { .. } => output.lines().filter_map(parse_iptables).collect(),
+            Backend::Ufw => output.lines().filter_map(parse_ufw).collect(),
+            Backend::Netsh => parse_netsh(output),
+        };
+        // ufw lists IPv4 and IPv6 separately for one rule
+        let mut seen = Vec::new();
+        rules.retain(|installed| {
+            let new = !seen.contains(installed);
+            if new {
+                seen.push(installed.clone());
+            }
+            new
+        });
+        rules
+    }
+}
+
+fn argv(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn iptables(operation: &str, chain: &str, rule: &FirewallRule) -> Vec<String> {
+    argv(&[
+        "iptables",
+        operation,
+        chain,
+        "-p",
+        rule.protocol.as_str(),
+        "--dport",
+        &rule.port.to_string(),
+        "-m",
+        "comment",
+        "--comment",
+        &rule.comment(),
+        "-j",
+        "ACCEPT",
+    ])
+}
+
+fn protocol(name: &str) -> Option<Transport> {
+    match name.to_ascii_lowercase().as_str() {
+        "tcp" => Some(Transport::Tcp),
+        "udp" => Some(Transport::Udp),
+        _ => None,
+    }
+}
+
+fn tagged(comment: &str) -> Option<String> {
+    comment
+        .trim_matches('"')
+        .strip_prefix(TAG)
+        .map(str::to_string)
+}
+
+/// `tcp dport 179 accept comment "vx0net:network.bgp.listen_port" # handle 12`
+fn parse_nft(line: &str) -> Option<InstalledRule> {
+    let (rule, handle) = line.split_once("# handle ")?;
+    let words: Vec<&str> = rule.split_whitespace().collect();
+    let position = |word: &str| words.iter().position(|w| *w == word);
+    let dport = position("dport")?;
+    let comment = words.get(position("comment")? + 1)?;
+    Some(InstalledRule {
+        rule: FirewallRule {
+            protocol: protocol(words.get(dport.checked_sub(1)?)?)?,
+            port: words.get(dport + 1)?.parse().ok()?,
+            key: tagged(comment)?,
+        },
+        handle: handle.trim().parse().ok(),
+    })
+}
+
+/// `-A INPUT -p tcp -m tcp --dport 179 -m comment --comment vx0net:... -j ACCEPT`
+fn parse_iptables(line: &str) -> Option<InstalledRule> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let after = |flag: &str| {
+        words
+            .iter()
+            .position(|w| *w == flag)
+            .and_then(|i| words.get(i + 1))
+    };
+    Some(InstalledRule {
+        rule: FirewallRule {
+            protocol: protocol(after("-p")?)?,
+            port: after("--dport")?.parse().ok()?,
+            key: tagged(after("--comment")?)?,
+        },
+        handle: None,
+    })
+}
+
+/// `179/tcp (v6)               ALLOW       Anywhere (v6)   # vx0net:...`
+fn parse_ufw(line: &str) -> Option<InstalledRule> {
+    let (rule, comment) = line.split_once('#')?;
+    let (port, proto) = rule.split_whitespace().next()?.split_once('/')?;
+    Some(InstalledRule {
+        rule: FirewallRule {
+            protocol: protocol(proto)?,
+            port: port.parse().ok()?,
+            key: tagged(comment.trim())?,
+        },
+        handle: None,
+    })
+}
+
+/// Blocks of `Field: value` lines, each starting with `Rule Name:`
+fn parse_netsh(output: &str) -> Vec<InstalledRule> {
+    let mut rules = Vec::new();
+    let mut current: Option<(String, Option<Transport>, Option<u16>)> = None;
+    let mut finish = |current: Option<(String, Option<Transport>, Option<u16>)>| {
+        if let Some((key, Some(protocol), Some(port))) = current {
+            rules.push(InstalledRule {
+                rule: FirewallRule {
+                    protocol,
+                    port,
+                    key,
+                },
+                handle: None,
+            });
+        }
+    };
+    for line in output.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match field.trim() {
+            "Rule Name" => {
+                finish(current.take());
+                current = tagged(value).map(|key| (key, None, None));
+            }
+            "Protocol" => {
+                if let Some(current) = &mut current {
+                    current.1 = protocol(value);
+                }
+            }
+            "LocalPort" => {
+                if let Some(current) = &mut current {
+                    current.2 = value.parse().ok();
+                }
+            }
+            _ => {}
+        }
+    }
+    finish(current);
+    rules
+}
+
+/// A command line as it would be typed, for logs and manual fixes
+pub fn command_line(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'$`\\".contains(c))
+            {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs firewall commands; the system's in production, a stand-in in tests
+#[async_trait]
+pub trait CommandRunner: Send + Sync {
+    /// Run `argv`, returning its standard output
+    async fn run(&self, argv: &[String]) -> Result<String, FirewallError>;
+}
+
+pub struct SystemRunner;
+
+#[async_trait]
+impl CommandRunner for SystemRunner {
+    async fn run(&self, argv: &[String]) -> Result<String, FirewallError> {
+        let (program, args) = argv.split_first().ok_or(FirewallError::NoBackend)?;
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|source| FirewallError::Spawn {
+                program: program.clone(),
+                source,
+            })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(FirewallError::Command {
+                command: command_line(argv),
+                reason: if stderr.is_empty() {
+                    output.status.to_string()
+                } else {
+                    stderr
+                },
+            });
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// A rule that could not be put in place, and how to do it by hand
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FailedRule {
+    pub rule: FirewallRule,
+    pub error: String,
+    pub manual: String,
+}
+
+/// The rules the daemon believes it owns
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FirewallStatus {
+    pub manage: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    pub rules: Vec<FirewallRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<FailedRule>,
+}
+
+/// What one reconciliation changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FirewallOutcome {
+    /// Stale tagged rules removed
+    pub removed: usize,
+    pub inserted: usize,
+    /// Wanted rules already in place
+    pub kept: usize,
+    pub failed: usize,
+}
+
+#[derive(Default)]
+struct Owned {
+    rules: Vec<FirewallRule>,
+    failed: Vec<FailedRule>,
+}
+
+/// Keeps the firewall holding exactly the wanted allow rules
+pub struct FirewallManager {
+    backend: Backend,
+    runner: Arc<dyn CommandRunner>,
+    wanted: Vec<FirewallRule>,
+    owned: Mutex<Owned>,
+}
+
+impl FirewallManager {
+    pub fn new(
+        backend: Backend,
+        runner: Arc<dyn CommandRunner>,
+        wanted: Vec<FirewallRule>,
+    ) -> Self {
+        FirewallManager {
+            backend,
+            runner,
+            wanted,
+            owned: Mutex::new(Owned::default()),
+        }
+    }
+
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    async fn installed(&self) -> Vec<InstalledRule> {
+        match self.runner.run(&self.backend.list()).await {
+            Ok(output) => self.backend.parse(&output),
+            Err(e) => {
+                tracing::warn!("Cannot list firewall rules: {}; assuming none are ours", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Remove tagged rules not wanted, left by an earlier run, then insert
+    /// the wanted rules not already there
+    pub async fn apply(&self) -> FirewallOutcome {
+        let mut owned = self.owned.lock().await;
+        let installed = self.installed().await;
+        let mut outcome = FirewallOutcome::default();
+
+        for stale in installed
+            .iter()
+            .filter(|installed| !self.wanted.contains(&installed.rule))
+        {
+            let command = self.backend.remove(stale);
+            match self.runner.run(&command).await {
+                Ok(_) => {
+                    tracing::info!(
+                        target: "audit",
+                        "Removed stale firewall rule: {}",
+                        command_line(&command)
+                    );
+                    outcome.removed += 1;
+                }
+                Err(e) => {
+                    tracing::warn!("Leaving stale firewall rule: {}", e);
+                    outcome.failed += 1;
+                }
+            }
+        }
+
+        owned.rules.clear();
+        owned.failed.clear();
+        for rule in &self.wanted {
+            if installed.iter().any(|installed| installed.rule == *rule) {
+                outcome.kept += 1;
+                owned.rules.push(rule.clone());
+                continue;
+            }
+            let command = self.backend.insert(rule);
+            match self.runner.run(&command).await {
+                Ok(_) => {
+                    tracing::info!(
+                        target: "audit",
+                        "Allowed {}/{} for {}: {}",
+                        rule.port,
+                        rule.protocol.as_str(),
+                        rule.key,
+                        command_line(&command)
+                    );
+                    outcome.inserted += 1;
+                    owned.rules.push(rule.clone());
+                }
+                Err(e) => {
+                    let manual = command_line(&command);
+                    tracing::warn!(
+                        "Could not open {}/{} for {} ({}); run by hand: {}",
+                        rule.port,
+                        rule.protocol.as_str(),
+                        rule.key,
+                        e,
+                        manual
+                    );
+                    outcome.failed += 1;
+                    owned.failed.push(FailedRule {
+                        rule: rule.clone(),
+                        error: e.to_string(),
+                        manual,
+                    });
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Remove the rules we put in place, on shutdown; returns how many
+    /// were removed
+    pub async fn clear(&self) -> usize {
+        let mut owned = self.owned.lock().await;
+        let mut removed = 0;
+        for installed in self.installed().await {
+            if !owned.rules.contains(&installed.rule) {
+                continue;
+            }
+            let command = self.backend.remove(&installed);
+            match self.runner.run(&command).await {
+                Ok(_) => {
+                    tracing::info!(
+                        target: "audit",
+                        "Removed firewall rule: {}",
+                        command_line(&command)
+                    );
+                    removed += 1;
+                }
+                Err(e) => tracing::warn!(
+                    "Leaving firewall rule behind ({}); remove by hand: {}",
+                    e,
+                    command_line(&command)
+                ),
+            }
+        }
+        owned.rules.clear();
+        removed
+    }
+
+    pub async fn status(&self) -> FirewallStatus {
+        let owned = self.owned.lock().await;
+        FirewallStatus {
+            manage: true,
+            backend: Some(self.backend.name().to_string()),
+            rules: owned.rules.clone(),
+            failed: owned.failed.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use std::sync::Mutex as StdMutex;
+
+    fn rule(protocol: Transport, port: u16, key: &str) -> FirewallRule {
+        FirewallRule {
+            protocol,
+            port,
+            key: key.to_string(),
+        }
+    }
+
+    fn bgp() -> FirewallRule {
+        rule(Transport::Tcp, 179, "network.bgp.listen_port")
+    }
+
+    fn ike() -> FirewallRule {
+        rule(Transport::Udp, 4500, "security.ike.listen_port")
+    }
+
+    fn nft() -> Backend {
+        Backend::nft("inet filter input")
+    }
+
+    #[test]
+    fn test_commands_per_backend() {
+        let cases = [
+            (
+                nft(),
+                r#"nft insert rule inet filter input tcp dport 179 accept comment '"vx0net:network.bgp.listen_port"'"#,
+            ),
+            (
+                Backend::Iptables {
+                    chain: "INPUT".to_string(),
+                },
+                "iptables -I INPUT -p tcp --dport 179 -m comment --comment vx0net:network.bgp.listen_port -j ACCEPT",
+            ),
+            (
+                Backend::Ufw,
+                "ufw allow 179/tcp comment vx0net:network.bgp.listen_port",
+            ),
+            (
+                Backend::Netsh,
+                "netsh advfirewall firewall add rule name=vx0net:network.bgp.listen_port dir=in action=allow protocol=TCP localport=179",
+            ),
+        ];
+        for (backend, expected) in cases {
+            assert_eq!(
+                command_line(&backend.insert(&bgp())),
+                expected,
+                "{}",
+                backend.name()
+            );
+        }
+
+        let removals = [
+            (nft(), Some(12), "nft delete rule inet filter input handle 12"),
+            (
+                Backend::Iptables {
+                    chain: "INPUT".to_string(),
+                },
+                None,
+                "iptables -D INPUT -p udp --dport 4500 -m comment --comment vx0net:security.ike.listen_port -j ACCEPT",
+            ),
+            (
+                Backend::Ufw,
+                None,
+                "ufw delete allow 4500/udp comment vx0net:security.ike.listen_port",
+            ),
+            (
+                Backend::Netsh,
+                None,
+                "netsh advfirewall firewall delete rule name=vx0net:security.ike.listen_port protocol=UDP localport=4500",
+            ),
+        ];
+        for (backend, handle, expected) in removals {
+            let installed = InstalledRule {
+                rule: if handle.is_some() { bgp() } else { ike() },
+                handle,
+            };
+            assert_eq!(command_line(&backend.remove(&installed)), expected);
+        }
+
+        assert_eq!(
+            Backend::nft("ip vx0 input"),
+            Backend::Nft {
+                family: "ip".to_string(),
+                table: "vx0".to_string(),
+                chain: "input".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_listings_parse_only_tagged_rules() {
+        let nft_listing = r#"table inet filter {
+	chain input { # handle 1
+		type filter hook input priority filter; policy drop;
+		tcp dport 22 accept # handle 4
+		udp dport 4500 accept comment "vx0net:security.ike.listen_port" # handle 9
+		tcp dport 179 accept comment "vx0net:network.bgp.listen_port" # handle 12
+	}
+}"#;
+        assert_eq!(
+            nft().parse(nft_listing),
+            vec![
+                InstalledRule {
+                    rule: ike(),
+                    handle: Some(9)
+                },
+                InstalledRule {
+                    rule: bgp(),
+                    handle: Some(12)
+                },
+            ]
+        );
+
+        let iptables_listing = "-P INPUT DROP\n\
+            -A INPUT -p tcp -m tcp --dport 22 -j ACCEPT\n\
+            -A INPUT -p tcp -m tcp --dport 179 -m comment --comment vx0net:network.bgp.listen_port -j ACCEPT\n";
+        let iptables = Backend::Iptables {
+            chain: "INPUT".to_string(),
+        };
+        assert_eq!(
+            iptables.parse(iptables_listing),
+            vec![InstalledRule {
+                rule: bgp(),
+                handle: None
+            }]
+        );
+
+        let ufw_listing = "Status: active\n\n\
+            To                         Action      From\n\
+            --                         ------      ----\n\
+            22/tcp                     ALLOW       Anywhere\n\
+            179/tcp                    ALLOW       Anywhere                   # vx0net:network.bgp.listen_port\n\
+            179/tcp (v6)               ALLOW       Anywhere (v6)              # vx0net:network.bgp.listen_port\n";
+        assert_eq!(
+            Backend::Ufw.parse(ufw_listing),
+            vec![InstalledRule {
+                rule: bgp(),
+                handle: None
+            }]
+        );
+
+        let netsh_listing = "\r\nRule Name:                            Remote Desktop\r\n\
+            ----------------------------------------------------------------------\r\n\
+            Protocol:                             TCP\r\n\
+            LocalPort:                            3389\r\n\r\n\
+            Rule Name:                            vx0net:security.ike.listen_port\r\n\
+            ----------------------------------------------------------------------\r\n\
+            Enabled:                              Yes\r\n\
+            Direction:                            In\r\n\
+            Protocol:                             UDP\r\n\
+            LocalPort:                            4500\r\n\
+            Action:                               Allow\r\n";
+        assert_eq!(
+            Backend::Netsh.parse(netsh_listing),
+            vec![InstalledRule {
+                rule: ike(),
+                handle: None
+            }]
+        );
+    }
+
+    #[test]
+    fn test_only_peer_facing_listeners_are_opened() {
+        let mut config = testing::config(NodeTier::Regional);
+        config.monitoring.enable_metrics = true;
+        let rules = wanted_rules(&config);
+        assert!(rules.contains(&rule(
+            Transport::Tcp,
+            config.network.bgp.listen_port,
+            "network.bgp.listen_port"
+        )));
+        assert!(rules.contains(&ike()));
+        assert!(rules
+            .iter()
+            .all(|rule| !LOCAL_ONLY.contains(&rule.key.as_str())));
+        assert!(!rules
+            .iter()
+            .any(|rule| rule.port == config.monitoring.metrics_port));
+    }
+
+    /// A firewall holding the rules listed in `listing`, recording what it
+    /// is told to run; commands containing `refuse` fail
+    #[derive(Default)]
+    struct FakeRunner {
+        listing: StdMutex<String>,
+        ran: StdMutex<Vec<String>>,
+        refuse: Option<&'static str>,
+    }
+
+    #[async_trait]
+    impl CommandRunner for FakeRunner {
+        async fn run(&self, argv: &[String]) -> Result<String, FirewallError> {
+            let command = command_line(argv);
+            if argv.get(1).map(String::as_str) == Some("-S") {
+                return Ok(self.listing.lock().unwrap().clone());
+            }
+            self.ran.lock().unwrap().push(command.clone());
+            if self.refuse.is_some_and(|refuse| command.contains(refuse)) {
+                return Err(FirewallError::Command {
+                    command,
+                    reason: "Permission denied".to_string(),
+                });
+            }
+            Ok(String::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_removes_stale_rules_and_keeps_wanted_ones() {
+        // A crashed run left the BGP rule and one for a port since moved
+        let runner = Arc::new(FakeRunner {
+            refuse: Some("--dport 5356"),
+            ..Default::default()
+        });
+        *runner.listing.lock().unwrap() = "-A INPUT -p tcp -m tcp --dport 179 -m comment --comment vx0net:network.bgp.listen_port -j ACCEPT\n\
+            -A INPUT -p tcp -m tcp --dport 1179 -m comment --comment vx0net:network.dns.sync_port -j ACCEPT\n"
+            .to_string();
+        let registry = rule(Transport::Tcp, 5356, "joining.registry.listen_port");
+        let manager = FirewallManager::new(
+            Backend::Iptables {
+                chain: "INPUT".to_string(),
+            },
+            runner.clone(),
+            vec![bgp(), ike(), registry.clone()],
+        );
+
+        let outcome = manager.apply().await;
+        assert_eq!(
+            outcome,
+            FirewallOutcome {
+                removed: 1,
+                inserted: 1,
+                kept: 1,
+                failed: 1,
+            }
+        );
+        let ran = runner.ran.lock().unwrap().clone();
+        assert_eq!(ran.len(), 3);
+        assert!(ran[0].starts_with("iptables -D INPUT -p tcp --dport 1179"));
+        assert!(ran[1].starts_with("iptables -I INPUT -p udp --dport 4500"));
+
+        let status = manager.status().await;
+        assert_eq!(status.backend.as_deref(), Some("iptables"));
+        assert_eq!(status.rules, vec![bgp(), ike()]);
+        assert_eq!(status.failed.len(), 1);
+        assert_eq!(status.failed[0].rule, registry);
+        assert!(status.failed[0].manual.starts_with("iptables -I INPUT"));
+
+        // Shutdown removes only what is ours and in place
+        *runner.listing.lock().unwrap() = "-A INPUT -p tcp -m tcp --dport 179 -m comment --comment vx0net:network.bgp.listen_port -j ACCEPT\n\
+            -A INPUT -p udp -m udp --dport 4500 -m comment --comment vx0net:security.ike.listen_port -j ACCEPT\n"
+            .to_string();
+        assert_eq!(manager.clear().await, 2);
+        assert!(manager.status().await.rules.is_empty());
+    }
+}
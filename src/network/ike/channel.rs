@@ -0,0 +1,199 @@
+//! Messages sealed by the tunnel to a peer, carried over a TCP stream.
+//!
+//! Control protocols that must not travel in the clear, BGP with
+//! `network.bgp.bgp_over_tunnel` for one, hand each message to a
+//! [`TunnelChannel`]. It is encrypted by whichever tunnel to the peer is
+//! established at the time and sent behind a four-byte big-endian length.
+//! While no tunnel is up, sending and opening a received message wait for
+//! one, so a channel carries on over the tunnel that replaces a failed one.
+//!
+//! Messages are written by a [`SendQueue`] attached to the tunnel, so a
+//! keepalive is not held behind a burst of route updates.
+
+use crate::network::ike::queue::{QueueConfig, SealedSink, SendQueue, TrafficClass};
+use crate::network::ike::tunnels::{TunnelId, TunnelManager};
+use crate::network::ike::IKEError;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::TcpStream;
+
+/// Largest sealed message accepted: a 64 KiB payload with room for
+/// padding and the tunnel's own overhead
+pub const MAX_SEALED_LEN: u32 = 128 * 1024;
+
+pub struct TunnelChannel {
+    stream: OwnedReadHalf,
+    sender: ChannelSender,
+    tunnels: Arc<TunnelManager>,
+    peer: IpAddr,
+}
+
+/// Sends on a [`TunnelChannel`] while it is read elsewhere; clones share
+/// its queue
+#[derive(Clone)]
+pub struct ChannelSender {
+    queue: SendQueue,
+    /// The tunnel the queue was last attached to
+    attached: Option<TunnelId>,
+    tunnels: Arc<TunnelManager>,
+    peer: IpAddr,
+}
+
+impl TunnelChannel {
+    pub fn new(stream: TcpStream, tunnels: Arc<TunnelManager>, peer: IpAddr) -> Self {
+        let (stream, writer) = stream.into_split();
+        let sink = SealedSink::new(writer, Arc::clone(&tunnels), peer);
+        TunnelChannel {
+            stream,
+            sender: ChannelSender {
+                queue: SendQueue::spawn(sink, QueueConfig::default()),
+                attached: None,
+                tunnels: Arc::clone(&tunnels),
+                peer,
+            },
+            tunnels,
+            peer,
+        }
+    }
+
+    pub fn peer(&self) -> IpAddr {
+        self.peer
+    }
+
+    pub fn sender(&self) -> ChannelSender {
+        self.sender.clone()
+    }
+
+    /// Queue one message to be sealed and sent, ahead of any of a less
+    /// urgent [`TrafficClass`]
+    pub async fn send(&mut self, payload: &[u8]) -> Result<(), IKEError> {
+        self.sender.send(payload).await
+    }
+
+    /// The next message, opened, or `None` once the peer closed the stream;
+    /// peer messages, such as goodbyes, go to the tunnel manager's
+    /// subscribers instead
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, IKEError> {
+        loop {
+            let length = match self.stream.read_u32().await {
+                Ok(length) => length,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if length > MAX_SEALED_LEN {
+                return Err(IKEError::Protocol(format!(
+                    "{}-byte sealed message from {} is too large",
+                    length, self.peer
+                )));
+            }
+            let mut sealed = vec![0u8; length as usize];
+            self.stream.read_exact(&mut sealed).await?;
+            let tunnel_id = self.tunnels.wait_established(self.peer).await;
+            let payload = self.tunnels.receive_packet(&tunnel_id, &sealed).await?;
+            if let Some(payload) = self.tunnels.route_payload(tunnel_id, self.peer, payload) {
+                return Ok(Some(payload));
+            }
+        }
+    }
+}
+
+impl ChannelSender {
+    /// As [`TunnelChannel::send`]
+    pub async fn send(&mut self, payload: &[u8]) -> Result<(), IKEError> {
+        if let Some(tunnel_id) = self.tunnels.tunnel_to(self.peer).await {
+            // A tunnel closed in between is replaced before the queue sends
+            if self.attached != Some(tunnel_id)
+                && self
+                    .tunnels
+                    .attach_queue(&tunnel_id, &self.queue)
+                    .await
+                    .is_ok()
+            {
+                self.attached = Some(tunnel_id);
+            }
+        }
+        self.queue
+            .send(TrafficClass::of(payload), payload.to_vec())
+            .await
+    }
+}
+
+/// Seal `payload` with the tunnel to `peer`, once there is one, and write
+/// it behind its length
+pub async fn write_sealed<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    tunnels: &TunnelManager,
+    peer: IpAddr,
+    payload: &[u8],
+) -> Result<(), IKEError> {
+    let tunnel_id = tunnels.wait_established(peer).await;
+    let sealed = tunnels.send_packet(&tunnel_id, payload).await?;
+    let length = u32::try_from(sealed.len())
+        .ok()
+        .filter(|length| *length <= MAX_SEALED_LEN)
+        .ok_or_else(|| {
+            IKEError::Protocol(format!("{}-byte sealed message is too large", sealed.len()))
+        })?;
+    writer.write_u32(length).await?;
+    writer.write_all(&sealed).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_messages_are_sealed_by_the_tunnel() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let (ours, theirs) = (
+            Arc::new(TunnelManager::new()),
+            Arc::new(TunnelManager::new()),
+        );
+        for tunnels in [&ours, &theirs] {
+            tunnels
+                .create_tunnel(
+                    localhost,
+                    localhost,
+                    "127.0.0.1:4500".parse().unwrap(),
+                    b"psk",
+                )
+                .await
+                .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let mut sender = TunnelChannel::new(client.unwrap(), Arc::clone(&ours), localhost);
+        let mut receiver = TunnelChannel::new(accepted.unwrap().0, Arc::clone(&theirs), localhost);
+
+        sender.send(b"routes").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap().unwrap(), b"routes");
+        let tunnel = &ours.list_tunnels().await[0];
+        assert_eq!(tunnel.traffic_stats.packets_out, 1);
+
+        // Peer messages are taken out of the stream for the node
+        let mut messages = theirs.subscribe_peer_messages();
+        sender
+            .send(&crate::network::ike::tunnels::peer_message(b"goodbye"))
+            .await
+            .unwrap();
+        sender.send(b"more routes").await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap().unwrap(), b"more routes");
+        // Each frame is counted before the next is written
+        let stats = ours.get_tunnel_stats(&tunnel.tunnel_id).await.unwrap();
+        assert_eq!(stats.queues[TrafficClass::Control as usize].sent, 1);
+        assert!(stats.queues[TrafficClass::Bulk as usize].sent >= 1);
+        let message = messages.try_recv().unwrap();
+        assert_eq!(message.body, b"goodbye");
+        assert_eq!(message.from, localhost);
+
+        drop(sender);
+        assert!(receiver.recv().await.unwrap().is_none());
+    }
+}
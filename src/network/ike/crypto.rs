@@ -44,9 +44,9 @@ impl IKECrypto {
             EncryptionAlgorithm::ChaCha20Poly1305 => {
                 self.chacha20_poly1305_encrypt(key, plaintext, nonce)
             }
-            _ => Err(IKEError::Crypto(
-                "Unsupported encryption algorithm".to_string(),
-            )),
+            ref other => Err(IKEError::UnsupportedAlgorithm {
+                algorithm: format!("{:?}", other),
+            }),
         }
     }
 
@@ -61,9 +61,9 @@ impl IKECrypto {
             EncryptionAlgorithm::ChaCha20Poly1305 => {
                 self.chacha20_poly1305_decrypt(key, ciphertext, nonce)
             }
-            _ => Err(IKEError::Crypto(
-                "Unsupported encryption algorithm".to_string(),
-            )),
+            ref other => Err(IKEError::UnsupportedAlgorithm {
+                algorithm: format!("{:?}", other),
+            }),
         }
     }
 
@@ -74,7 +74,11 @@ impl IKECrypto {
         nonce: &[u8],
     ) -> Result<Vec<u8>, IKEError> {
         if key.len() != 32 {
-            return Err(IKEError::Crypto("Invalid key size for AES-256".to_string()));
+            return Err(IKEError::InvalidKeySize {
+                algorithm: "AES-256",
+                expected: 32,
+                got: key.len(),
+            });
         }
 
         let unbound_key = aead::UnboundKey::new(&aead::AES_256_GCM, key)
@@ -83,12 +87,12 @@ impl IKECrypto {
         let sealing_key = aead::LessSafeKey::new(unbound_key);
 
         let mut in_out = plaintext.to_vec();
-        let nonce = aead::Nonce::try_assume_unique_for_key(nonce)
-            .map_err(|_| IKEError::Crypto("Invalid nonce".to_string()))?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| IKEError::InvalidNonce)?;
 
         sealing_key
             .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut in_out)
-            .map_err(|_| IKEError::Crypto("Encryption failed".to_string()))?;
+            .map_err(|_| IKEError::EncryptionFailed)?;
 
         Ok(in_out)
     }
@@ -100,7 +104,11 @@ impl IKECrypto {
         nonce: &[u8],
     ) -> Result<Vec<u8>, IKEError> {
         if key.len() != 32 {
-            return Err(IKEError::Crypto("Invalid key size for AES-256".to_string()));
+            return Err(IKEError::InvalidKeySize {
+                algorithm: "AES-256",
+                expected: 32,
+                got: key.len(),
+            });
         }
 
         let unbound_key = aead::UnboundKey::new(&aead::AES_256_GCM, key)
@@ -109,12 +117,12 @@ impl IKECrypto {
         let opening_key = aead::LessSafeKey::new(unbound_key);
 
         let mut in_out = ciphertext.to_vec();
-        let nonce = aead::Nonce::try_assume_unique_for_key(nonce)
-            .map_err(|_| IKEError::Crypto("Invalid nonce".to_string()))?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| IKEError::InvalidNonce)?;
 
         let plaintext = opening_key
             .open_in_place(nonce, aead::Aad::empty(), &mut in_out)
-            .map_err(|_| IKEError::Crypto("Decryption failed".to_string()))?;
+            .map_err(|_| IKEError::DecryptionFailed { tunnel: None })?;
 
         Ok(plaintext.to_vec())
     }
@@ -126,9 +134,11 @@ impl IKECrypto {
         nonce: &[u8],
     ) -> Result<Vec<u8>, IKEError> {
         if key.len() != 32 {
-            return Err(IKEError::Crypto(
-                "Invalid key size for ChaCha20-Poly1305".to_string(),
-            ));
+            return Err(IKEError::InvalidKeySize {
+                algorithm: "ChaCha20-Poly1305",
+                expected: 32,
+                got: key.len(),
+            });
         }
 
         let unbound_key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)
@@ -137,12 +147,12 @@ impl IKECrypto {
         let sealing_key = aead::LessSafeKey::new(unbound_key);
 
         let mut in_out = plaintext.to_vec();
-        let nonce = aead::Nonce::try_assume_unique_for_key(nonce)
-            .map_err(|_| IKEError::Crypto("Invalid nonce".to_string()))?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| IKEError::InvalidNonce)?;
 
         sealing_key
             .seal_in_place_append_tag(nonce, aead::Aad::empty(), &mut in_out)
-            .map_err(|_| IKEError::Crypto("Encryption failed".to_string()))?;
+            .map_err(|_| IKEError::EncryptionFailed)?;
 
         Ok(in_out)
     }
@@ -154,9 +164,11 @@ impl IKECrypto {
         nonce: &[u8],
     ) -> Result<Vec<u8>, IKEError> {
         if key.len() != 32 {
-            return Err(IKEError::Crypto(
-                "Invalid key size for ChaCha20-Poly1305".to_string(),
-            ));
+            return Err(IKEError::InvalidKeySize {
+                algorithm: "ChaCha20-Poly1305",
+                expected: 32,
+                got: key.len(),
+            });
         }
 
         let unbound_key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)
@@ -165,12 +177,12 @@ impl IKECrypto {
         let opening_key = aead::LessSafeKey::new(unbound_key);
 
         let mut in_out = ciphertext.to_vec();
-        let nonce = aead::Nonce::try_assume_unique_for_key(nonce)
-            .map_err(|_| IKEError::Crypto("Invalid nonce".to_string()))?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(nonce).map_err(|_| IKEError::InvalidNonce)?;
 
         let plaintext = opening_key
             .open_in_place(nonce, aead::Aad::empty(), &mut in_out)
-            .map_err(|_| IKEError::Crypto("Decryption failed".to_string()))?;
+            .map_err(|_| IKEError::DecryptionFailed { tunnel: None })?;
 
         Ok(plaintext.to_vec())
     }
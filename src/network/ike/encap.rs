@@ -0,0 +1,194 @@
+//! Tunnel datagrams carried over TCP, for networks that allow one port.
+//!
+//! In single-port mode the BGP listener also takes tunnel streams. A tunnel
+//! stream opens with [`STREAM_PREFIX`], as in RFC 8229, then carries each
+//! encrypted fragment of a datagram behind a two-byte big-endian length.
+//! BGP messages open with the all-ones marker of the RFC 4271 header, so
+//! one peeked byte tells the two apart.
+//!
+//! Datagrams keep their order and wait behind any lost segment, so tunnels
+//! carried this way are counted under their own transport label in
+//! `vx0net_tunnel_bytes_total`.
+
+use crate::network::ike::tunnels::{TunnelId, TunnelManager, TunnelTransport};
+use crate::network::ike::IKEError;
+use crate::node::capabilities::Capabilities;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+
+/// Sent first on every tunnel stream
+pub const STREAM_PREFIX: &[u8; 6] = b"IKETCP";
+
+/// Decrypted payloads buffered per subscriber before it is considered lagged
+const DELIVERY_CAPACITY: usize = 1024;
+
+/// What a connection to the shared port carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    Bgp,
+    Tunnel,
+}
+
+/// Tell a BGP session from a tunnel stream without consuming any BGP
+/// bytes; a tunnel stream's prefix is read and checked
+pub async fn classify(stream: &mut TcpStream) -> Result<StreamKind, IKEError> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first).await? == 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    if first[0] != STREAM_PREFIX[0] {
+        return Ok(StreamKind::Bgp);
+    }
+    let mut prefix = [0u8; STREAM_PREFIX.len()];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix != STREAM_PREFIX {
+        return Err(IKEError::Protocol(format!(
+            "bad tunnel stream prefix {:?}",
+            String::from_utf8_lossy(&prefix)
+        )));
+    }
+    Ok(StreamKind::Tunnel)
+}
+
+/// The sending end of a tunnel stream
+#[derive(Debug)]
+pub struct EncapStream {
+    stream: TcpStream,
+}
+
+impl EncapStream {
+    pub async fn connect(addr: SocketAddr) -> Result<Self, IKEError> {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        stream.write_all(STREAM_PREFIX).await?;
+        Ok(EncapStream { stream })
+    }
+
+    /// Send one encrypted fragment, as returned by `TunnelManager::send_datagram`
+    pub async fn send(&mut self, datagram: &[u8]) -> Result<(), IKEError> {
+        let length = u16::try_from(datagram.len()).map_err(|_| {
+            IKEError::Protocol(format!("{}-byte datagram is too large", datagram.len()))
+        })?;
+        self.stream.write_u16(length).await?;
+        self.stream.write_all(datagram).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// The next datagram, or `None` once the peer closed the stream
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, IKEError> {
+        read_datagram(&mut self.stream).await
+    }
+}
+
+async fn read_datagram(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, IKEError> {
+    let length = match stream.read_u16().await {
+        Ok(length) => length,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut datagram = vec![0u8; length as usize];
+    stream.read_exact(&mut datagram).await?;
+    Ok(Some(datagram))
+}
+
+/// A payload that arrived decrypted over a tunnel stream
+#[derive(Debug, Clone)]
+pub struct Delivered {
+    pub tunnel_id: TunnelId,
+    pub payload: Vec<u8>,
+}
+
+/// Decrypts tunnel streams handed over by the shared listener
+pub struct EncapEndpoint {
+    tunnels: Arc<TunnelManager>,
+    delivered: broadcast::Sender<Delivered>,
+}
+
+impl EncapEndpoint {
+    pub fn new(tunnels: Arc<TunnelManager>) -> Self {
+        EncapEndpoint {
+            tunnels,
+            delivered: broadcast::channel(DELIVERY_CAPACITY).0,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Delivered> {
+        self.delivered.subscribe()
+    }
+
+    /// Decrypt and reassemble datagrams from `peer` until it closes the
+    /// stream. They belong to our established tunnel to that address, which
+    /// is carried over TCP from now on. Peer messages go to the tunnel
+    /// manager's subscribers rather than ours.
+    pub async fn serve(&self, mut stream: TcpStream, peer: SocketAddr) -> Result<(), IKEError> {
+        let tunnel_id = self.tunnels.tunnel_to(peer.ip()).await.ok_or_else(|| {
+            IKEError::Protocol(format!("tunnel stream from {} without a tunnel", peer))
+        })?;
+        self.tunnels
+            .set_transport(&tunnel_id, TunnelTransport::TcpEncap)
+            .await?;
+        while let Some(fragment) = read_datagram(&mut stream).await? {
+            // With nobody listening the payload is dropped
+            if let Some(payload) = self
+                .tunnels
+                .deliver(tunnel_id, peer.ip(), &fragment)
+                .await?
+            {
+                let _ = self.delivered.send(Delivered { tunnel_id, payload });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where to reach a peer's tunnels: over TCP on its BGP port if it takes
+/// tunnel streams and we asked for TCP (`prefer_tcp`), over UDP on its BGP
+/// port if it shares one port, otherwise over UDP on its IKE port
+pub fn tunnel_endpoint(
+    peer: IpAddr,
+    bgp_port: u16,
+    ike_port: u16,
+    capabilities: &Capabilities,
+    prefer_tcp: bool,
+) -> (SocketAddr, TunnelTransport) {
+    if capabilities.tcp_encapsulation && prefer_tcp {
+        (SocketAddr::new(peer, bgp_port), TunnelTransport::TcpEncap)
+    } else if capabilities.single_port {
+        (SocketAddr::new(peer, bgp_port), TunnelTransport::Udp)
+    } else {
+        (SocketAddr::new(peer, ike_port), TunnelTransport::Udp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_endpoint_follows_capabilities() {
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        let plain = Capabilities::default();
+        assert_eq!(
+            tunnel_endpoint(peer, 1179, 500, &plain, true),
+            ((peer, 500).into(), TunnelTransport::Udp)
+        );
+
+        let single = Capabilities {
+            single_port: true,
+            tcp_encapsulation: true,
+            ..Capabilities::default()
+        };
+        assert_eq!(
+            tunnel_endpoint(peer, 1179, 500, &single, false),
+            ((peer, 1179).into(), TunnelTransport::Udp)
+        );
+        assert_eq!(
+            tunnel_endpoint(peer, 1179, 500, &single, true),
+            ((peer, 1179).into(), TunnelTransport::TcpEncap)
+        );
+    }
+}
@@ -0,0 +1,418 @@
+//! Write-ahead journal of tunnel nonce counters.
+//!
+//! AES-GCM and ChaCha20-Poly1305 fail catastrophically if a nonce is ever
+//! used twice under one key. Each tunnel's sender counts nonces up from
+//! zero, and before using a counter it must have journaled a high-water
+//! mark above it. Counters are reserved in blocks of `margin`, one mark per
+//! block, so a busy tunnel writes at most once per `margin` packets, and a
+//! periodic flush tops up reservations for every tunnel in one write so the
+//! send path rarely has to wait on the disk.
+//!
+//! A write is planned as a [`Reservation`] under the journal's lock, made
+//! durable without it, and only then committed, so the lock is never held
+//! across an fsync. Writers must be serialized by the caller, or an older
+//! reservation could land on disk after a newer one.
+//!
+//! A session resumed with the same keys after a crash starts at the
+//! journaled mark, strictly above every counter the previous run could
+//! have used. If the journal is missing, unreadable, or holds no mark for
+//! those keys, the tunnel must be rekeyed before any data flows.
+
+use crate::config::NonceJournalConfig;
+use crate::network::ike::tunnels::TunnelId;
+use crate::network::ike::IKEError;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Bytes in an AEAD nonce built from a counter
+pub const NONCE_LEN: usize = 12;
+
+/// Identifies the keys a counter belongs to without storing them
+pub fn key_id(encryption_key: &[u8]) -> u64 {
+    let hash = digest::digest(&digest::SHA256, encryption_key);
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&hash.as_ref()[..8]);
+    u64::from_be_bytes(id)
+}
+
+/// The AEAD nonce for a counter: four zero bytes, then the counter
+/// big-endian
+pub fn nonce(counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// What a tunnel resuming existing keys must do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Send from this counter on; nothing below it is safe
+    From(u64),
+    /// No trustworthy mark for these keys; negotiate new ones first
+    Rekey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Mark {
+    key_id: u64,
+    high_water: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct JournalFile {
+    marks: HashMap<TunnelId, Mark>,
+}
+
+#[derive(Debug)]
+struct Counter {
+    key_id: u64,
+    next: u64,
+    /// Journaled mark; counters at or above it may not be used yet
+    reserved: u64,
+}
+
+/// Marks to make durable before the counters below them may be used
+#[derive(Debug)]
+pub struct Reservation {
+    path: PathBuf,
+    data: Vec<u8>,
+    marks: HashMap<TunnelId, Mark>,
+}
+
+impl Reservation {
+    /// Write the marks durably; blocks on the disk
+    pub fn write(&self) -> Result<(), IKEError> {
+        write_durable(&self.path, &self.data)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct NonceJournal {
+    path: PathBuf,
+    margin: u64,
+    flush_interval: Duration,
+    counters: HashMap<TunnelId, Counter>,
+    /// Marks left by the previous run, until their tunnels resume
+    recovered: HashMap<TunnelId, Mark>,
+    last_flush: Instant,
+    writes: u64,
+}
+
+impl NonceJournal {
+    /// Read the marks the previous run left; `None` when journaling is off
+    pub fn open(config: &NonceJournalConfig) -> Option<Self> {
+        if config.path.is_empty() {
+            return None;
+        }
+        let path = PathBuf::from(&config.path);
+        Some(NonceJournal {
+            recovered: read_marks(&path),
+            path,
+            margin: config.margin.max(1),
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            counters: HashMap::new(),
+            last_flush: Instant::now(),
+            writes: 0,
+        })
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        self.flush_interval
+    }
+
+    /// Count a tunnel's nonces from zero under freshly negotiated keys
+    pub fn start(&mut self, tunnel_id: TunnelId, key_id: u64) {
+        self.recovered.remove(&tunnel_id);
+        self.counters.insert(
+            tunnel_id,
+            Counter {
+                key_id,
+                next: 0,
+                reserved: 0,
+            },
+        );
+    }
+
+    /// Pick up a tunnel's counter after a restart that kept its keys
+    pub fn resume(&mut self, tunnel_id: TunnelId, key_id: u64) -> Resume {
+        match self.recovered.remove(&tunnel_id) {
+            Some(mark) if mark.key_id == key_id => {
+                self.counters.insert(
+                    tunnel_id,
+                    Counter {
+                        key_id,
+                        next: mark.high_water,
+                        reserved: mark.high_water,
+                    },
+                );
+                Resume::From(mark.high_water)
+            }
+            Some(_) => {
+                tracing::warn!("Tunnel {} journaled other keys; rekeying", tunnel_id);
+                Resume::Rekey
+            }
+            None => {
+                tracing::warn!("No journaled nonce mark for tunnel {}; rekeying", tunnel_id);
+                Resume::Rekey
+            }
+        }
+    }
+
+    pub fn forget(&mut self, tunnel_id: &TunnelId) {
+        self.counters.remove(tunnel_id);
+        self.recovered.remove(tunnel_id);
+    }
+
+    /// The next counter to send with, or `None` when the tunnel's block is
+    /// used up and a [`Reservation`] must be committed first
+    pub fn try_next_nonce(&mut self, tunnel_id: &TunnelId) -> Result<Option<u64>, IKEError> {
+        let counter = self
+            .counters
+            .get_mut(tunnel_id)
+            .ok_or(IKEError::RekeyRequired { tunnel: *tunnel_id })?;
+        if counter.next >= counter.reserved {
+            return Ok(None);
+        }
+        let next = counter.next;
+        counter.next += 1;
+        Ok(Some(next))
+    }
+
+    pub fn flush_due(&self, now: Instant) -> bool {
+        now.duration_since(self.last_flush) >= self.flush_interval
+            && self
+                .counters
+                .values()
+                .any(|counter| counter.reserved - counter.next < self.margin)
+    }
+
+    /// Plan a block a full margin ahead on every tunnel that has used any of
+    /// its reservation, all in one write
+    pub fn reserve(&self) -> Result<Reservation, IKEError> {
+        let mut file = JournalFile {
+            marks: self.recovered.clone(),
+        };
+        for (tunnel_id, counter) in &self.counters {
+            let reserved = counter.reserved.max(counter.next + self.margin);
+            file.marks.insert(
+                *tunnel_id,
+                Mark {
+                    key_id: counter.key_id,
+                    high_water: reserved,
+                },
+            );
+        }
+        let data = serde_json::to_vec(&file)
+            .map_err(|e| IKEError::Crypto(format!("Nonce journal encoding failed: {}", e)))?;
+        Ok(Reservation {
+            path: self.path.clone(),
+            data,
+            marks: file.marks,
+        })
+    }
+
+    /// Raise reservations to a written [`Reservation`]'s marks; tunnels
+    /// rekeyed since it was planned keep counting from their own
+    pub fn commit(&mut self, reservation: Reservation) {
+        for (tunnel_id, counter) in self.counters.iter_mut() {
+            if let Some(mark) = reservation.marks.get(tunnel_id) {
+                if mark.key_id == counter.key_id {
+                    counter.reserved = counter.reserved.max(mark.high_water);
+                }
+            }
+        }
+        self.last_flush = Instant::now();
+        self.writes += 1;
+    }
+}
+
+fn read_marks(path: &Path) -> HashMap<TunnelId, Mark> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            tracing::warn!("Cannot read nonce journal {}: {}", path.display(), e);
+            return HashMap::new();
+        }
+    };
+    match serde_json::from_slice::<JournalFile>(&data) {
+        Ok(file) => file.marks,
+        Err(e) => {
+            tracing::warn!(
+                "Nonce journal {} is corrupt, every tunnel will rekey: {}",
+                path.display(),
+                e
+            );
+            HashMap::new()
+        }
+    }
+}
+
+/// Replace the journal so a crash leaves either the old or the new marks,
+/// and both are on disk before returning
+fn write_durable(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// Reserve, write and commit in one go, as the tunnel manager does
+    fn flush(journal: &mut NonceJournal) {
+        let reservation = journal.reserve().unwrap();
+        reservation.write().unwrap();
+        journal.commit(reservation);
+    }
+
+    fn next_nonce(journal: &mut NonceJournal, tunnel_id: &TunnelId) -> Result<u64, IKEError> {
+        loop {
+            if let Some(next) = journal.try_next_nonce(tunnel_id)? {
+                return Ok(next);
+            }
+            flush(journal);
+        }
+    }
+
+    fn config(margin: u64) -> NonceJournalConfig {
+        let dir = std::env::temp_dir().join(format!("vx0net-nonces-{}", Uuid::new_v4()));
+        NonceJournalConfig {
+            path: dir.join("nonces.json").to_string_lossy().into_owned(),
+            margin,
+            flush_interval_ms: 60_000,
+        }
+    }
+
+    #[test]
+    fn test_resume_after_crash_never_reuses_a_nonce() {
+        let config = config(100);
+        let tunnel = Uuid::new_v4();
+        let key = key_id(b"session-key");
+
+        let mut journal = NonceJournal::open(&config).unwrap();
+        journal.start(tunnel, key);
+        let used: Vec<u64> = (0..250)
+            .map(|_| next_nonce(&mut journal, &tunnel).unwrap())
+            .collect();
+        // Batched: one write per margin, not per packet
+        assert_eq!(journal.writes, 3);
+        // Crash between using nonces and any further flush
+        drop(journal);
+
+        let mut journal = NonceJournal::open(&config).unwrap();
+        let Resume::From(resumed) = journal.resume(tunnel, key) else {
+            panic!("journaled keys must resume");
+        };
+        assert!(resumed > *used.iter().max().unwrap());
+        assert_eq!(next_nonce(&mut journal, &tunnel).unwrap(), resumed);
+        assert_ne!(nonce(resumed), nonce(249));
+    }
+
+    #[test]
+    fn test_periodic_flush_reserves_ahead_of_the_send_path() {
+        let config = config(10);
+        let tunnel = Uuid::new_v4();
+        let mut journal = NonceJournal::open(&config).unwrap();
+        journal.start(tunnel, 7);
+        assert!(!journal.flush_due(Instant::now()));
+        assert!(journal.flush_due(Instant::now() + Duration::from_secs(60)));
+
+        flush(&mut journal);
+        for _ in 0..5 {
+            next_nonce(&mut journal, &tunnel).unwrap();
+        }
+        flush(&mut journal);
+        // Topped up to a full margin past what was used, so the next ten
+        // sends need no write
+        for _ in 0..10 {
+            next_nonce(&mut journal, &tunnel).unwrap();
+        }
+        assert_eq!(journal.writes, 2);
+
+        let mut journal = NonceJournal::open(&config).unwrap();
+        assert_eq!(journal.resume(tunnel, 7), Resume::From(15));
+    }
+
+    #[test]
+    fn test_missing_corrupt_or_stale_journal_forces_rekey() {
+        let config = config(10);
+        let tunnel = Uuid::new_v4();
+
+        // Nothing journaled yet
+        let mut journal = NonceJournal::open(&config).unwrap();
+        assert_eq!(journal.resume(tunnel, 1), Resume::Rekey);
+        assert!(matches!(
+            next_nonce(&mut journal, &tunnel),
+            Err(IKEError::RekeyRequired { .. })
+        ));
+
+        journal.start(tunnel, 1);
+        next_nonce(&mut journal, &tunnel).unwrap();
+
+        // The mark belongs to other keys
+        let mut reopened = NonceJournal::open(&config).unwrap();
+        assert_eq!(reopened.resume(tunnel, 2), Resume::Rekey);
+
+        // Torn or damaged file
+        std::fs::write(&config.path, b"{\"marks\": {").unwrap();
+        let mut journal = NonceJournal::open(&config).unwrap();
+        assert_eq!(journal.resume(tunnel, 1), Resume::Rekey);
+
+        // Fresh keys after the rekey count from zero again
+        journal.start(tunnel, 3);
+        assert_eq!(next_nonce(&mut journal, &tunnel).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_unresumed_marks_survive_other_tunnels_writing() {
+        let config = config(10);
+        let (idle, busy) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut journal = NonceJournal::open(&config).unwrap();
+        journal.start(idle, 1);
+        next_nonce(&mut journal, &idle).unwrap();
+
+        let mut journal = NonceJournal::open(&config).unwrap();
+        journal.start(busy, 2);
+        next_nonce(&mut journal, &busy).unwrap();
+
+        let mut journal = NonceJournal::open(&config).unwrap();
+        assert_eq!(journal.resume(idle, 1), Resume::From(10));
+        assert_eq!(journal.resume(busy, 2), Resume::From(10));
+    }
+
+    #[test]
+    fn test_reservation_planned_before_a_rekey_does_not_cover_new_keys() {
+        let config = config(10);
+        let tunnel = Uuid::new_v4();
+        let mut journal = NonceJournal::open(&config).unwrap();
+        journal.start(tunnel, 1);
+
+        // Planned for the old keys, written while the tunnel rekeys
+        let reservation = journal.reserve().unwrap();
+        journal.start(tunnel, 2);
+        reservation.write().unwrap();
+        journal.commit(reservation);
+        assert_eq!(journal.try_next_nonce(&tunnel).unwrap(), None);
+
+        flush(&mut journal);
+        assert_eq!(journal.try_next_nonce(&tunnel).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_disabled_without_a_path() {
+        assert!(NonceJournal::open(&NonceJournalConfig::default()).is_none());
+    }
+}
@@ -3,10 +3,18 @@ use ring::{hmac, rand};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
+pub mod channel;
 pub mod crypto;
+pub mod encap;
+pub mod journal;
+pub mod mtu;
+pub mod queue;
+pub mod resumption;
 pub mod session;
 pub mod tunnels;
 
+use tunnels::TunnelId;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IKESession {
     pub local_spi: u64,
@@ -17,6 +25,10 @@ pub struct IKESession {
     pub state: IKEState,
     pub peer_addr: SocketAddr,
     pub dh_group: u8,
+    /// IKE messages sent and received to establish the session; none for
+    /// one resumed from a ticket
+    #[serde(default)]
+    pub handshake_messages: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -116,18 +128,77 @@ pub struct AuthPayload {
 pub enum IKEError {
     #[error("Crypto error: {0}")]
     Crypto(String),
+    #[error("{algorithm} needs a {expected}-byte key, got {got} bytes")]
+    InvalidKeySize {
+        algorithm: &'static str,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Unsupported encryption algorithm {algorithm}")]
+    UnsupportedAlgorithm { algorithm: String },
+    #[error("Invalid nonce")]
+    InvalidNonce,
+    #[error("Encryption failed")]
+    EncryptionFailed,
+    #[error("Decryption failed{}", tunnel.map(|t| format!(" on tunnel {}", t)).unwrap_or_default())]
+    DecryptionFailed { tunnel: Option<TunnelId> },
+    #[error("Tunnel {tunnel} not found")]
+    TunnelNotFound { tunnel: TunnelId },
+    #[error("Tunnel {tunnel} is not established")]
+    TunnelNotEstablished { tunnel: TunnelId },
+    #[error("Tunnel {tunnel} has no safe nonce counter and must be rekeyed")]
+    RekeyRequired { tunnel: TunnelId },
+    #[error("IKE session with {peer} is not established")]
+    SessionNotEstablished { peer: SocketAddr },
+    #[error("Expected IKE message from {expected}, got one from {got}")]
+    UnexpectedSender {
+        expected: SocketAddr,
+        got: SocketAddr,
+    },
     #[error("Protocol error: {0}")]
     Protocol(String),
     #[error("Authentication failed")]
     AuthenticationFailed,
-    #[error("Network error: {0}")]
-    Network(String),
-    #[error("Configuration error: {0}")]
-    Configuration(String),
-    #[error("IO error: {0}")]
+    #[error("Malformed padded frame on tunnel {tunnel}")]
+    BadFrame {
+        tunnel: TunnelId,
+        #[source]
+        source: crate::network::obfuscation::FrameError,
+    },
+    #[error("{size}-byte datagram does not fit the {mtu}-byte MTU of tunnel {tunnel}")]
+    DatagramTooLarge {
+        tunnel: TunnelId,
+        size: usize,
+        mtu: usize,
+    },
+    #[error("Malformed fragment on tunnel {tunnel}")]
+    BadFragment {
+        tunnel: TunnelId,
+        #[source]
+        source: mtu::FragmentError,
+    },
+    #[error("Tunnel stream for tunnel {tunnel} timed out")]
+    StreamTimeout { tunnel: TunnelId },
+    #[error("Tunnel send queue for {class} traffic is full")]
+    QueueFull { class: &'static str },
+    #[error("Tunnel send queue is closed")]
+    QueueClosed,
+    #[error(transparent)]
     IO(#[from] std::io::Error),
 }
 
+impl IKEError {
+    /// Attribute a decryption failure to the tunnel it happened on
+    pub fn on_tunnel(self, tunnel_id: TunnelId) -> Self {
+        match self {
+            IKEError::DecryptionFailed { tunnel: None } => IKEError::DecryptionFailed {
+                tunnel: Some(tunnel_id),
+            },
+            other => other,
+        }
+    }
+}
+
 impl IKESession {
     pub fn new(peer_addr: SocketAddr, dh_group: u8) -> Result<Self, IKEError> {
         let rng = rand::SystemRandom::new();
@@ -144,9 +215,24 @@ impl IKESession {
             state: IKEState::Initial,
             peer_addr,
             dh_group,
+            handshake_messages: 0,
         })
     }
 
+    /// A session resumed from a ticket, keyed from `shared_secret` without
+    /// any exchange of its own
+    pub fn resumed(
+        peer_addr: SocketAddr,
+        dh_group: u8,
+        shared_secret: Vec<u8>,
+    ) -> Result<Self, IKEError> {
+        let mut session = IKESession::new(peer_addr, dh_group)?;
+        session.shared_secret = shared_secret;
+        session.derive_keys()?;
+        session.state = IKEState::Established;
+        Ok(session)
+    }
+
     pub async fn establish_tunnel(&mut self, psk: &[u8]) -> Result<(), IKEError> {
         tracing::info!("Establishing IKE tunnel to {}", self.peer_addr);
 
@@ -198,6 +284,7 @@ impl IKESession {
 
         // For now, just simulate the exchange
         tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        self.handshake_messages += 2;
 
         // Simulate receiving response and computing shared secret
         self.shared_secret = vec![0x42; 32]; // Placeholder
@@ -231,6 +318,7 @@ impl IKESession {
 
         // Simulate the exchange
         tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        self.handshake_messages += 2;
 
         Ok(())
     }
@@ -0,0 +1,330 @@
+//! How large a datagram each tunnel can carry.
+//!
+//! Underlays differ: PPPoE, a WireGuard hop or a mobile link each take bytes
+//! off the path, and a datagram too large for it is fragmented or dropped
+//! somewhere we cannot see, which shows up as large packets failing between
+//! particular peers. Every node configures the largest encrypted datagram
+//! it sends (`security.ike.mtu`) and advertises it as a capability. A tunnel
+//! uses the smaller of both ends' values, lowered further by whatever a
+//! probe found the path carries: a binary search of padded echoes over the
+//! established tunnel. Probe results are cached per peer address and the
+//! path is probed again once they are older than `mtu_revalidate_secs`.
+//!
+//! Datagrams sent with
+//! [`TunnelManager::send_datagram`](super::tunnels::TunnelManager::send_datagram)
+//! open with a [`FRAGMENT_HEADER_LEN`]-byte header: a big-endian datagram ID,
+//! then the fragment's index and the number of fragments. One too large for
+//! its tunnel is split to fit and put back together by the receiver. Where
+//! the kernel carries the data plane, it is refused instead, and the tunnel
+//! interface's MTU should be lowered to the tunnel's.
+
+use crate::util::sync::lock;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// No tunnel is taken below the IPv4 minimum datagram size
+pub const MIN_TUNNEL_MTU: usize = 576;
+
+/// Leaves room for PPPoE and one layer of encapsulation under 1500
+pub const DEFAULT_TUNNEL_MTU: usize = 1400;
+
+/// Datagram ID, fragment index and fragment count
+pub const FRAGMENT_HEADER_LEN: usize = 6;
+
+/// Partly received datagrams kept per tunnel; the oldest is dropped for
+/// another
+const MAX_PARTIAL_DATAGRAMS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FragmentError {
+    #[error("Fragment of {len} bytes is shorter than its header")]
+    Truncated { len: usize },
+    #[error("Fragment {index} of {count} is out of range")]
+    BadIndex { index: u8, count: u8 },
+    #[error("{len}-byte datagram needs more than {max} fragments of {room} bytes", max = u8::MAX)]
+    TooLarge { len: usize, room: usize },
+}
+
+/// Sends padded echoes over the tunnel to a peer
+#[async_trait]
+pub trait PathProbe: Send + Sync {
+    /// Whether an echo padded to `size` encrypted bytes came back
+    async fn echo(&self, peer: IpAddr, size: usize) -> bool;
+}
+
+/// The largest size in `floor..=ceiling` the path to `peer` echoes, found
+/// by binary search; `floor` itself is never sent
+pub async fn probe(path: &dyn PathProbe, peer: IpAddr, floor: usize, ceiling: usize) -> usize {
+    let (mut passed, mut failed) = (floor, ceiling.max(floor) + 1);
+    while failed - passed > 1 {
+        let size = passed + (failed - passed) / 2;
+        if path.echo(peer, size).await {
+            passed = size;
+        } else {
+            failed = size;
+        }
+    }
+    tracing::debug!("Path to {} carries {}-byte datagrams", peer, passed);
+    passed
+}
+
+/// Probed path MTUs by peer address
+#[derive(Debug)]
+pub struct ProbeCache {
+    revalidate: Duration,
+    probed: Mutex<HashMap<IpAddr, (usize, Instant)>>,
+}
+
+impl ProbeCache {
+    pub fn new(revalidate: Duration) -> Self {
+        ProbeCache {
+            revalidate,
+            probed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The last probe's result, however old; it stands until the next one
+    pub fn get(&self, peer: IpAddr) -> Option<usize> {
+        lock(&self.probed).get(&peer).map(|&(mtu, _)| mtu)
+    }
+
+    pub fn record(&self, peer: IpAddr, mtu: usize, now: Instant) {
+        lock(&self.probed).insert(peer, (mtu, now));
+    }
+
+    /// Whether the path to `peer` was never probed or is due again
+    pub fn due(&self, peer: IpAddr, now: Instant) -> bool {
+        lock(&self.probed)
+            .get(&peer)
+            .is_none_or(|&(_, at)| now.saturating_duration_since(at) >= self.revalidate)
+    }
+
+    pub fn revalidate_after(&self) -> Duration {
+        self.revalidate
+    }
+}
+
+/// Split `datagram` into fragments of at most `room` bytes, headers
+/// included; one that fits is sent as a single fragment
+pub fn fragment(id: u32, datagram: &[u8], room: usize) -> Result<Vec<Vec<u8>>, FragmentError> {
+    let chunk = room.saturating_sub(FRAGMENT_HEADER_LEN);
+    let too_large = FragmentError::TooLarge {
+        len: datagram.len(),
+        room,
+    };
+    if chunk == 0 {
+        return Err(too_large);
+    }
+    let count = datagram.len().div_ceil(chunk).max(1);
+    let count = u8::try_from(count).map_err(|_| too_large)?;
+    let chunks = datagram.chunks(chunk).chain(
+        // An empty datagram still takes one fragment
+        datagram.is_empty().then_some(&[][..]),
+    );
+    Ok(chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            fragment.extend_from_slice(&id.to_be_bytes());
+            fragment.push(index as u8);
+            fragment.push(count);
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+struct Partial {
+    started: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+
+/// Puts one tunnel's fragmented datagrams back together
+#[derive(Debug)]
+pub struct Reassembler {
+    /// Partial datagrams older than this are dropped
+    timeout: Duration,
+    partial: HashMap<u32, Partial>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Reassembler {
+            timeout,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// The datagram `fragment` completes, if it completes one
+    pub fn accept(
+        &mut self,
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        if fragment.len() < FRAGMENT_HEADER_LEN {
+            return Err(FragmentError::Truncated {
+                len: fragment.len(),
+            });
+        }
+        let id = u32::from_be_bytes([fragment[0], fragment[1], fragment[2], fragment[3]]);
+        let (index, count) = (fragment[4], fragment[5]);
+        if index >= count {
+            return Err(FragmentError::BadIndex { index, count });
+        }
+        let body = &fragment[FRAGMENT_HEADER_LEN..];
+        if count == 1 {
+            return Ok(Some(body.to_vec()));
+        }
+
+        self.partial
+            .retain(|_, partial| now.saturating_duration_since(partial.started) < self.timeout);
+        if !self.partial.contains_key(&id) && self.partial.len() >= MAX_PARTIAL_DATAGRAMS {
+            if let Some(oldest) = self
+                .partial
+                .iter()
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(&id, _)| id)
+            {
+                self.partial.remove(&oldest);
+            }
+        }
+        let partial = self.partial.entry(id).or_insert_with(|| Partial {
+            started: now,
+            parts: vec![None; usize::from(count)],
+            missing: usize::from(count),
+        });
+        // A reused ID with another count starts over
+        if partial.parts.len() != usize::from(count) {
+            *partial = Partial {
+                started: now,
+                parts: vec![None; usize::from(count)],
+                missing: usize::from(count),
+            };
+        }
+        let part = &mut partial.parts[usize::from(index)];
+        if part.is_none() {
+            *part = Some(body.to_vec());
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+        let parts = self.partial.remove(&id).map(|partial| partial.parts);
+        Ok(parts.map(|parts| parts.into_iter().flatten().flatten().collect()))
+    }
+
+    /// Datagrams still waiting for fragments
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Drops every echo larger than `cutoff`
+    struct Cutoff {
+        cutoff: usize,
+        sent: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl PathProbe for Cutoff {
+        async fn echo(&self, _peer: IpAddr, size: usize) -> bool {
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            size <= self.cutoff
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_converges_on_the_cutoff() {
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        for cutoff in [576, 577, 1280, 1371, 1399, 1400, 9000] {
+            let path = Cutoff {
+                cutoff,
+                sent: AtomicUsize::new(0),
+            };
+            let found = probe(&path, peer, MIN_TUNNEL_MTU, DEFAULT_TUNNEL_MTU).await;
+            assert_eq!(found, cutoff.min(DEFAULT_TUNNEL_MTU), "cutoff {}", cutoff);
+            // A binary search over the 825 candidates
+            assert!(path.sent.load(Ordering::Relaxed) <= 10);
+        }
+    }
+
+    #[test]
+    fn test_probe_results_are_revalidated() {
+        let cache = ProbeCache::new(Duration::from_secs(600));
+        let peer: IpAddr = "10.1.0.1".parse().unwrap();
+        let now = Instant::now();
+        assert!(cache.due(peer, now));
+        cache.record(peer, 1280, now);
+        assert_eq!(cache.get(peer), Some(1280));
+        assert!(!cache.due(peer, now + Duration::from_secs(599)));
+        assert!(cache.due(peer, now + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_fragments_reassemble_in_any_order() {
+        let datagram: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let mut fragments = fragment(7, &datagram, 1000).unwrap();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|f| f.len() <= 1000));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        fragments.swap(0, 3);
+        let last = fragments.pop().unwrap();
+        for fragment in &fragments {
+            assert_eq!(reassembler.accept(fragment, now), Ok(None));
+        }
+        // A repeated fragment changes nothing
+        assert_eq!(reassembler.accept(&fragments[0], now), Ok(None));
+        assert_eq!(reassembler.accept(&last, now), Ok(Some(datagram)));
+        assert_eq!(reassembler.pending(), 0);
+
+        // Small and empty datagrams travel whole
+        let whole = fragment(8, b"small", 1000).unwrap();
+        assert_eq!(whole.len(), 1);
+        assert_eq!(
+            reassembler.accept(&whole[0], now),
+            Ok(Some(b"small".to_vec()))
+        );
+        let empty = fragment(9, b"", 1000).unwrap();
+        assert_eq!(reassembler.accept(&empty[0], now), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn test_incomplete_datagrams_expire_and_bad_fragments_are_refused() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(5));
+        let fragments = fragment(1, &[1u8; 100], 40).unwrap();
+        assert_eq!(reassembler.accept(&fragments[0], now), Ok(None));
+        let later = now + Duration::from_secs(6);
+        assert_eq!(reassembler.accept(&fragments[1], later), Ok(None));
+        assert_eq!(reassembler.accept(&fragments[2], later), Ok(None));
+        assert_eq!(reassembler.pending(), 1);
+
+        assert_eq!(
+            reassembler.accept(&[0, 0, 0, 1, 3, 3], now),
+            Err(FragmentError::BadIndex { index: 3, count: 3 })
+        );
+        assert_eq!(
+            reassembler.accept(&[0, 0, 0], now),
+            Err(FragmentError::Truncated { len: 3 })
+        );
+        assert_eq!(
+            fragment(1, &[0u8; 4000], 10),
+            Err(FragmentError::TooLarge {
+                len: 4000,
+                room: 10
+            })
+        );
+    }
+}
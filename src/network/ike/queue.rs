@@ -0,0 +1,685 @@
+//! Prioritised send queues in front of a tunnel.
+//!
+//! Everything sent to a peer shares its tunnel, and a tunnel busy with bulk
+//! data would otherwise hold keepalives and route updates behind it until
+//! they time out, failing sessions exactly when the link is busiest. A
+//! [`SendQueue`] keeps one bounded queue per [`TrafficClass`] and a task
+//! draining them into a [`FrameSink`]:
+//!
+//! - control frames always go first, and are never dropped; senders wait
+//!   for room instead;
+//! - routing and bulk frames share what is left by weighted round robin,
+//!   four routing frames to each bulk frame by default;
+//! - a class whose queue is full either makes senders wait or drops the
+//!   frame, as configured; bulk waits by default.
+//!
+//! With a rate limit, each class draws on its own token bucket holding its
+//! share of the rate, so bulk traffic emptying its bucket never delays a
+//! control frame. Queue depths and drops are exported per class and show
+//! in the tunnel's [`TrafficStats`](super::tunnels::TrafficStats) once the
+//! queue is attached with
+//! [`TunnelManager::attach_queue`](super::tunnels::TunnelManager::attach_queue).
+//!
+//! Each peer's datagrams go through a queue on its tunnel, drained by a
+//! [`DatagramSink`], and each [`TunnelChannel`](super::channel::TunnelChannel)
+//! writes through one drained by a [`SealedSink`]; both sort what they are
+//! given with [`TrafficClass::of`].
+
+use crate::monitoring::register_metric;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::bgp::wire::{BGP_HEADER_LEN, BGP_MARKER, BGP_MSG_KEEPALIVE};
+use crate::network::ike::channel;
+use crate::network::ike::tunnels::{TunnelId, TunnelManager, PEER_MESSAGE_PREFIX};
+use crate::network::ike::IKEError;
+use crate::network::transport::MessageTag;
+use async_trait::async_trait;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::sync::{mpsc, watch};
+
+/// Kinds of traffic on a tunnel, most urgent first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    /// Keepalives, echoes, dead peer probes and other liveness traffic
+    Control = 0,
+    /// Route updates, joins and zone sync
+    Routing = 1,
+    /// User data
+    Bulk = 2,
+}
+
+impl TrafficClass {
+    pub const ALL: [TrafficClass; 3] = [
+        TrafficClass::Control,
+        TrafficClass::Routing,
+        TrafficClass::Bulk,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TrafficClass::Control => "control",
+            TrafficClass::Routing => "routing",
+            TrafficClass::Bulk => "bulk",
+        }
+    }
+
+    /// The class of a payload handed to a tunnel: BGP messages by their
+    /// RFC 4271 header, peer messages such as goodbyes as control, and
+    /// anything else, the data plane's packets, as bulk
+    pub fn of(payload: &[u8]) -> Self {
+        if payload.starts_with(PEER_MESSAGE_PREFIX) {
+            return TrafficClass::from(MessageTag::Control);
+        }
+        if payload.len() >= BGP_HEADER_LEN && payload[..16] == BGP_MARKER {
+            return match payload[18] {
+                BGP_MSG_KEEPALIVE => TrafficClass::from(MessageTag::Keepalive),
+                _ => TrafficClass::from(MessageTag::Bgp),
+            };
+        }
+        TrafficClass::Bulk
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl From<MessageTag> for TrafficClass {
+    fn from(tag: MessageTag) -> Self {
+        match tag {
+            MessageTag::Keepalive | MessageTag::Control => TrafficClass::Control,
+            MessageTag::Bgp | MessageTag::Join | MessageTag::ZoneSync => TrafficClass::Routing,
+        }
+    }
+}
+
+/// What a full queue does with another frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The sender waits for room
+    Backpressure,
+    /// The frame is refused and counted as dropped
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassConfig {
+    /// Frames queued before the class overflows
+    pub depth: usize,
+    pub overflow: Overflow,
+    /// Frames sent per round; control ignores it, going first regardless
+    pub weight: u32,
+    /// Percentage of a rate limit this class may use
+    pub rate_share: u8,
+}
+
+/// Bytes per second sent through the tunnel, and how many may go at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    pub burst: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    classes: [ClassConfig; 3],
+    rate_limit: Option<RateLimit>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            classes: [
+                ClassConfig {
+                    depth: 256,
+                    overflow: Overflow::Backpressure,
+                    weight: 1,
+                    rate_share: 10,
+                },
+                ClassConfig {
+                    depth: 256,
+                    overflow: Overflow::Backpressure,
+                    weight: 4,
+                    rate_share: 30,
+                },
+                ClassConfig {
+                    depth: 64,
+                    overflow: Overflow::Backpressure,
+                    weight: 1,
+                    rate_share: 60,
+                },
+            ],
+            rate_limit: None,
+        }
+    }
+}
+
+impl QueueConfig {
+    pub fn class(&self, class: TrafficClass) -> &ClassConfig {
+        &self.classes[class.index()]
+    }
+
+    pub fn with_depth(mut self, class: TrafficClass, depth: usize) -> Self {
+        self.classes[class.index()].depth = depth.max(1);
+        self
+    }
+
+    /// Control frames are never dropped, so control keeps backpressure
+    pub fn with_overflow(mut self, class: TrafficClass, overflow: Overflow) -> Self {
+        if class != TrafficClass::Control {
+            self.classes[class.index()].overflow = overflow;
+        }
+        self
+    }
+
+    pub fn with_weight(mut self, class: TrafficClass, weight: u32) -> Self {
+        self.classes[class.index()].weight = weight.max(1);
+        self
+    }
+
+    pub fn with_rate_share(mut self, class: TrafficClass, percent: u8) -> Self {
+        self.classes[class.index()].rate_share = percent.min(100);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+}
+
+/// Tunnel frames queued per class, across every tunnel
+pub fn depth_gauge() -> &'static IntGaugeVec {
+    static GAUGE: OnceLock<IntGaugeVec> = OnceLock::new();
+    GAUGE.get_or_init(|| {
+        register_metric(IntGaugeVec::new(
+            Opts::new(
+                "vx0net_tunnel_queue_depth",
+                "Frames waiting to be sent through tunnels, by traffic class",
+            ),
+            &["class"],
+        ))
+    })
+}
+
+/// Tunnel frames dropped by full queues, per class
+pub fn drops_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new(
+                "vx0net_tunnel_queue_drops_total",
+                "Frames dropped because their tunnel's queue was full, by traffic class",
+            ),
+            &["class"],
+        ))
+    })
+}
+
+/// One class of a queue, as of when it was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassStats {
+    pub class: TrafficClass,
+    pub depth: usize,
+    pub sent: u64,
+    pub dropped: u64,
+}
+
+/// Counts kept by a queue, shared with whoever reports on it
+#[derive(Debug, Default)]
+pub struct QueueCounters {
+    depth: [AtomicUsize; 3],
+    sent: [AtomicU64; 3],
+    dropped: [AtomicU64; 3],
+}
+
+impl QueueCounters {
+    pub fn snapshot(&self) -> Vec<ClassStats> {
+        Self::combined([self])
+    }
+
+    /// The counts of several queues on one tunnel, added up per class
+    pub fn combined<'a>(queues: impl IntoIterator<Item = &'a QueueCounters>) -> Vec<ClassStats> {
+        let mut stats: Vec<ClassStats> = TrafficClass::ALL
+            .iter()
+            .map(|&class| ClassStats {
+                class,
+                depth: 0,
+                sent: 0,
+                dropped: 0,
+            })
+            .collect();
+        for queue in queues {
+            for stats in &mut stats {
+                let index = stats.class.index();
+                stats.depth += queue.depth[index].load(Ordering::Relaxed);
+                stats.sent += queue.sent[index].load(Ordering::Relaxed);
+                stats.dropped += queue.dropped[index].load(Ordering::Relaxed);
+            }
+        }
+        stats
+    }
+
+    fn queued(&self, class: TrafficClass) {
+        self.depth[class.index()].fetch_add(1, Ordering::Relaxed);
+        depth_gauge().with_label_values(&[class.label()]).inc();
+    }
+
+    fn dequeued(&self, class: TrafficClass) {
+        self.depth[class.index()].fetch_sub(1, Ordering::Relaxed);
+        depth_gauge().with_label_values(&[class.label()]).dec();
+    }
+}
+
+/// Where a queue's frames go, in the order it picks them
+#[async_trait]
+pub trait FrameSink: Send + 'static {
+    async fn send_frame(&mut self, class: TrafficClass, frame: &[u8]) -> Result<(), IKEError>;
+}
+
+/// Seals each frame with the tunnel to `peer` and writes it as
+/// [`TunnelChannel`](super::channel::TunnelChannel) does
+pub struct SealedSink<W> {
+    writer: W,
+    tunnels: Arc<TunnelManager>,
+    peer: IpAddr,
+}
+
+impl<W> SealedSink<W> {
+    pub fn new(writer: W, tunnels: Arc<TunnelManager>, peer: IpAddr) -> Self {
+        SealedSink {
+            writer,
+            tunnels,
+            peer,
+        }
+    }
+}
+
+#[async_trait]
+impl<W: AsyncWrite + Unpin + Send + 'static> FrameSink for SealedSink<W> {
+    async fn send_frame(&mut self, _class: TrafficClass, frame: &[u8]) -> Result<(), IKEError> {
+        channel::write_sealed(&mut self.writer, &self.tunnels, self.peer, frame).await
+    }
+}
+
+/// Sends each frame as a datagram over the carrier of tunnel `tunnel_id`,
+/// UDP or a tunnel stream, as [`TunnelManager::transmit`] sends it
+pub struct DatagramSink {
+    tunnels: Arc<TunnelManager>,
+    tunnel_id: TunnelId,
+}
+
+impl DatagramSink {
+    pub fn new(tunnels: Arc<TunnelManager>, tunnel_id: TunnelId) -> Self {
+        DatagramSink { tunnels, tunnel_id }
+    }
+}
+
+#[async_trait]
+impl FrameSink for DatagramSink {
+    async fn send_frame(&mut self, class: TrafficClass, frame: &[u8]) -> Result<(), IKEError> {
+        match self.tunnels.transmit(&self.tunnel_id, frame).await {
+            // One datagram that cannot go, too large say, stops no others
+            Err(e @ IKEError::TunnelNotFound { .. }) => Err(e),
+            Err(e) => {
+                tracing::debug!(
+                    "Dropped a {} datagram on tunnel {}: {}",
+                    class.label(),
+                    self.tunnel_id,
+                    e
+                );
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, share: u8) -> Self {
+        let share = f64::from(share) / 100.0;
+        let capacity = (limit.burst as f64 * share).max(1.0);
+        Bucket {
+            tokens: capacity,
+            capacity,
+            per_sec: (limit.bytes_per_sec as f64 * share).max(1.0),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.refilled = now;
+    }
+
+    /// How long until `bytes` may be sent; frames larger than the bucket
+    /// go once it is full
+    fn wait(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.refill(now);
+        let needed = (bytes as f64).min(self.capacity);
+        if self.tokens >= needed {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((needed - self.tokens) / self.per_sec)
+        }
+    }
+
+    fn take(&mut self, bytes: usize) {
+        self.tokens -= (bytes as f64).min(self.capacity);
+    }
+}
+
+/// Sends frames to a tunnel by class; clones share the queue, which stops
+/// once every clone is dropped and what was queued is sent
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    senders: [mpsc::Sender<Vec<u8>>; 3],
+    config: QueueConfig,
+    counters: Arc<QueueCounters>,
+    /// Set once the drain stops
+    stopped: watch::Receiver<bool>,
+}
+
+impl SendQueue {
+    /// Start draining into `sink`
+    pub fn spawn<S: FrameSink>(sink: S, config: QueueConfig) -> Self {
+        let counters = Arc::new(QueueCounters::default());
+        let (control, control_rx) = mpsc::channel(config.class(TrafficClass::Control).depth);
+        let (routing, routing_rx) = mpsc::channel(config.class(TrafficClass::Routing).depth);
+        let (bulk, bulk_rx) = mpsc::channel(config.class(TrafficClass::Bulk).depth);
+        let drain = Drain {
+            receivers: [control_rx, routing_rx, bulk_rx],
+            open: [true; 3],
+            pending: [None, None, None],
+            credits: [0; 3],
+            buckets: config.rate_limit.map(|limit| {
+                TrafficClass::ALL.map(|class| Bucket::new(limit, config.class(class).rate_share))
+            }),
+            config,
+            counters: Arc::clone(&counters),
+        };
+        let (stop, stopped) = watch::channel(false);
+        crash::spawn(Subsystem::Ike, "tunnel-send-queue", async move {
+            drain.run(sink).await;
+            stop.send_replace(true);
+        });
+        SendQueue {
+            senders: [control, routing, bulk],
+            config,
+            counters,
+            stopped,
+        }
+    }
+
+    /// Queue `frame`; with room, or a class that applies backpressure, this
+    /// waits only for room
+    pub async fn send(&self, class: TrafficClass, frame: Vec<u8>) -> Result<(), IKEError> {
+        let sender = &self.senders[class.index()];
+        self.counters.queued(class);
+        let result = match self.config.class(class).overflow {
+            Overflow::Backpressure => sender.send(frame).await.map_err(|_| IKEError::QueueClosed),
+            Overflow::Drop => sender.try_send(frame).map_err(|e| match e {
+                mpsc::error::TrySendError::Full(_) => {
+                    self.counters.dropped[class.index()].fetch_add(1, Ordering::Relaxed);
+                    drops_counter().with_label_values(&[class.label()]).inc();
+                    IKEError::QueueFull {
+                        class: class.label(),
+                    }
+                }
+                mpsc::error::TrySendError::Closed(_) => IKEError::QueueClosed,
+            }),
+        };
+        if result.is_err() {
+            self.counters.dequeued(class);
+        }
+        result
+    }
+
+    /// Stop taking frames and wait for those queued to be sent; other
+    /// clones keep the queue going
+    pub async fn close(self) {
+        let mut stopped = self.stopped.clone();
+        drop(self);
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+
+    pub fn counters(&self) -> Arc<QueueCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    pub fn stats(&self) -> Vec<ClassStats> {
+        self.counters.snapshot()
+    }
+}
+
+/// The task taking frames off the queues
+struct Drain {
+    receivers: [mpsc::Receiver<Vec<u8>>; 3],
+    /// Whether a class's senders are still around
+    open: [bool; 3],
+    /// The head frame of each class, taken off its queue
+    pending: [Option<Vec<u8>>; 3],
+    /// Routing and bulk frames left in this round
+    credits: [u32; 3],
+    buckets: Option<[Bucket; 3]>,
+    config: QueueConfig,
+    counters: Arc<QueueCounters>,
+}
+
+impl Drain {
+    async fn run<S: FrameSink>(mut self, mut sink: S) {
+        loop {
+            for class in TrafficClass::ALL {
+                let index = class.index();
+                if self.pending[index].is_none() && self.open[index] {
+                    match self.receivers[index].try_recv() {
+                        Ok(frame) => self.pending[index] = Some(frame),
+                        Err(mpsc::error::TryRecvError::Disconnected) => self.open[index] = false,
+                        Err(mpsc::error::TryRecvError::Empty) => {}
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            match self.next(now) {
+                Ok(class) => {
+                    let frame = self.pending[class.index()].take().unwrap_or_default();
+                    self.counters.dequeued(class);
+                    if let Some(buckets) = &mut self.buckets {
+                        buckets[class.index()].take(frame.len());
+                    }
+                    if let Err(e) = sink.send_frame(class, &frame).await {
+                        tracing::debug!("Tunnel send queue stopped: {}", e);
+                        break;
+                    }
+                    self.counters.sent[class.index()].fetch_add(1, Ordering::Relaxed);
+                }
+                Err(wait) => {
+                    if !self.open.iter().any(|open| *open)
+                        && self.pending.iter().all(Option::is_none)
+                    {
+                        break;
+                    }
+                    self.wait(wait).await;
+                }
+            }
+        }
+        // Frames still queued will not be sent
+        for class in TrafficClass::ALL {
+            let index = class.index();
+            let left = usize::from(self.pending[index].is_some()) + self.receivers[index].len();
+            for _ in 0..left {
+                self.counters.dequeued(class);
+            }
+        }
+    }
+
+    /// The class to send from now, or how long until one may send when
+    /// only rate-limited frames are pending
+    fn next(&mut self, now: Instant) -> Result<TrafficClass, Option<Duration>> {
+        let mut earliest: Option<Duration> = None;
+        let mut ready = |drain: &mut Drain, class: TrafficClass| -> bool {
+            let Some(frame) = &drain.pending[class.index()] else {
+                return false;
+            };
+            let wait = match &mut drain.buckets {
+                Some(buckets) => buckets[class.index()].wait(frame.len(), now),
+                None => Duration::ZERO,
+            };
+            if wait.is_zero() {
+                return true;
+            }
+            earliest = Some(earliest.map_or(wait, |earliest| earliest.min(wait)));
+            false
+        };
+
+        if ready(self, TrafficClass::Control) {
+            return Ok(TrafficClass::Control);
+        }
+        let shared = [TrafficClass::Routing, TrafficClass::Bulk];
+        let ready: Vec<TrafficClass> = shared
+            .into_iter()
+            .filter(|&class| ready(self, class))
+            .collect();
+        if ready.is_empty() {
+            return Err(earliest);
+        }
+        if ready.iter().all(|class| self.credits[class.index()] == 0) {
+            for class in shared {
+                self.credits[class.index()] = self.config.class(class).weight;
+            }
+        }
+        let class = ready
+            .into_iter()
+            .find(|class| self.credits[class.index()] > 0)
+            .unwrap_or(TrafficClass::Routing);
+        self.credits[class.index()] = self.credits[class.index()].saturating_sub(1);
+        Ok(class)
+    }
+
+    /// Until a frame arrives on a class with none pending, or `wait` passes
+    async fn wait(&mut self, wait: Option<Duration>) {
+        let [control, routing, bulk] = &mut self.receivers;
+        let [control_pending, routing_pending, bulk_pending] = &mut self.pending;
+        let [control_open, routing_open, bulk_open] = &mut self.open;
+        let sleep = tokio::time::sleep(wait.unwrap_or_default());
+        tokio::select! {
+            biased;
+            frame = control.recv(), if *control_open && control_pending.is_none() => match frame {
+                Some(frame) => *control_pending = Some(frame),
+                None => *control_open = false,
+            },
+            frame = routing.recv(), if *routing_open && routing_pending.is_none() => match frame {
+                Some(frame) => *routing_pending = Some(frame),
+                None => *routing_open = false,
+            },
+            frame = bulk.recv(), if *bulk_open && bulk_pending.is_none() => match frame {
+                Some(frame) => *bulk_pending = Some(frame),
+                None => *bulk_open = false,
+            },
+            _ = sleep, if wait.is_some() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the order frames were sent in
+    struct Recorder(mpsc::UnboundedSender<(TrafficClass, Vec<u8>)>);
+
+    #[async_trait]
+    impl FrameSink for Recorder {
+        async fn send_frame(&mut self, class: TrafficClass, frame: &[u8]) -> Result<(), IKEError> {
+            // Slow enough for the queues to fill behind it
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let _ = self.0.send((class, frame.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_control_goes_first_and_routing_outweighs_bulk() {
+        let (sent, mut order) = mpsc::unbounded_channel();
+        let queue = SendQueue::spawn(Recorder(sent), QueueConfig::default());
+        // Nothing is taken off the queues before the test yields
+        for i in 0..4 {
+            let frame = format!("b{}", i).into_bytes();
+            queue.send(TrafficClass::Bulk, frame).await.unwrap();
+        }
+        for i in 0..8 {
+            let frame = format!("r{}", i).into_bytes();
+            queue.send(TrafficClass::Routing, frame).await.unwrap();
+        }
+        queue
+            .send(TrafficClass::Control, b"c".to_vec())
+            .await
+            .unwrap();
+        drop(queue);
+
+        let mut classes = Vec::new();
+        while let Some((class, _)) = order.recv().await {
+            classes.push(class);
+        }
+        use TrafficClass::*;
+        assert_eq!(
+            classes,
+            [
+                Control, Routing, Routing, Routing, Routing, Bulk, Routing, Routing, Routing,
+                Routing, Bulk, Bulk, Bulk
+            ]
+        );
+    }
+
+    #[test]
+    fn test_payloads_are_classed_by_what_they_carry() {
+        let keepalive = crate::network::bgp::wire::encode_frame(BGP_MSG_KEEPALIVE, &[], 4096);
+        let update = crate::network::bgp::wire::encode_frame(2, b"{}", 4096);
+        assert_eq!(TrafficClass::of(&keepalive.unwrap()), TrafficClass::Control);
+        assert_eq!(TrafficClass::of(&update.unwrap()), TrafficClass::Routing);
+        let goodbye = crate::network::ike::tunnels::peer_message(b"goodbye");
+        assert_eq!(TrafficClass::of(&goodbye), TrafficClass::Control);
+        assert_eq!(TrafficClass::of(&[0x45, 0, 0, 20]), TrafficClass::Bulk);
+        assert_eq!(TrafficClass::of(&[0xff; 8]), TrafficClass::Bulk);
+    }
+
+    #[tokio::test]
+    async fn test_full_class_drops_only_when_told_to() {
+        let (sent, _order) = mpsc::unbounded_channel();
+        let config = QueueConfig::default()
+            .with_depth(TrafficClass::Bulk, 1)
+            .with_overflow(TrafficClass::Bulk, Overflow::Drop)
+            .with_overflow(TrafficClass::Control, Overflow::Drop);
+        assert_eq!(
+            config.class(TrafficClass::Control).overflow,
+            Overflow::Backpressure
+        );
+        let queue = SendQueue::spawn(Recorder(sent), config);
+        let mut dropped = 0u64;
+        for _ in 0..20 {
+            if let Err(IKEError::QueueFull { class }) =
+                queue.send(TrafficClass::Bulk, vec![0; 64]).await
+            {
+                assert_eq!(class, "bulk");
+                dropped += 1;
+            }
+        }
+        assert!(dropped > 0);
+        let bulk = &queue.stats()[TrafficClass::Bulk.index()];
+        assert_eq!(bulk.dropped, dropped);
+    }
+}
@@ -0,0 +1,493 @@
+//! Resuming a tunnel after a short disconnect without a full handshake.
+//!
+//! Once a BGP session with a tunnel gate is established, both ends derive
+//! the same ticket from the tunnel's current keys, the nonces in both OPENs
+//! and both identities (AS number and router ID). A peer reconnecting
+//! within `security.ike.resumption.validity_secs` presents the ticket's
+//! token in its OPEN with a fresh nonce and proof that it holds the
+//! ticket's secret. If the responder has the ticket, and it was derived
+//! with that identity, it answers in its own OPEN with a nonce and a proof
+//! of its own, and both ends install a tunnel keyed from the secret and the
+//! two nonces instead of running IKE_SA_INIT and IKE_AUTH.
+//!
+//! Tickets are single use: each end drops its copy as soon as it is
+//! presented, and a token presented again is refused as a replay. Whatever
+//! goes wrong, the session carries on with the full handshake.
+
+use crate::network::ike::journal;
+use crate::network::ike::IKEError;
+use crate::util::sync::lock;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Used unless configured otherwise
+pub const DEFAULT_VALIDITY: Duration = Duration::from_secs(600);
+
+/// Bytes of the nonce each end puts in its OPEN
+pub const NONCE_LEN: usize = 32;
+
+pub type TokenId = [u8; 16];
+
+/// Who a ticket was derived with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PeerIdentity {
+    pub asn: u32,
+    pub router_id: IpAddr,
+}
+
+/// Carried in OPEN by each end that can resume sessions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeHello {
+    pub nonce: Vec<u8>,
+    /// Sent by the initiator when it holds a ticket for the peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offer: Option<ResumeOffer>,
+    /// Sent by the responder when it accepted the offer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted: Option<ResumeAccepted>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeOffer {
+    pub token: TokenId,
+    pub proof: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeAccepted {
+    pub proof: Vec<u8>,
+    /// Version of the initiator's route table the responder still holds
+    pub table_version: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ResumeRefused {
+    #[error("no ticket for that token")]
+    Unknown,
+    #[error("token was already used")]
+    Replayed,
+    #[error("ticket expired")]
+    Expired,
+    #[error("ticket was issued to AS{} ({})", .0.asn, .0.router_id)]
+    WrongPeer(PeerIdentity),
+    #[error("proof does not match the ticket")]
+    BadProof,
+}
+
+/// Both ends of a session as their OPENs described them
+#[derive(Debug, Clone, Copy)]
+pub struct Transcript<'a> {
+    pub initiator: PeerIdentity,
+    pub initiator_nonce: &'a [u8],
+    pub responder: PeerIdentity,
+    pub responder_nonce: &'a [u8],
+}
+
+impl Transcript<'_> {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (identity, nonce) in [
+            (self.initiator, self.initiator_nonce),
+            (self.responder, self.responder_nonce),
+        ] {
+            bytes.extend_from_slice(&identity.asn.to_be_bytes());
+            bytes.extend_from_slice(identity.router_id.to_string().as_bytes());
+            bytes.extend_from_slice(nonce);
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Ticket {
+    secret: Vec<u8>,
+    peer: PeerIdentity,
+    peer_addr: IpAddr,
+    /// The tunnel keys it was derived from
+    epoch: u64,
+    issued: Instant,
+    /// Version of the peer's route table we hold
+    table_version: u64,
+}
+
+/// A ticket the initiator has offered, kept until the answer arrives
+#[derive(Debug)]
+pub struct Resuming {
+    token: TokenId,
+    secret: Vec<u8>,
+}
+
+/// Tickets for resuming sessions with peers, ours and theirs alike
+#[derive(Debug)]
+pub struct ResumptionCache {
+    validity: Duration,
+    tickets: Mutex<HashMap<TokenId, Ticket>>,
+    /// Tokens presented already, kept until they would have expired
+    spent: Mutex<HashMap<TokenId, Instant>>,
+}
+
+impl ResumptionCache {
+    pub fn new(validity: Duration) -> Self {
+        ResumptionCache {
+            validity,
+            tickets: Mutex::new(HashMap::new()),
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn validity(&self) -> Duration {
+        self.validity
+    }
+
+    /// Derive the ticket for a session just established over a tunnel
+    /// keyed with `encryption_key`, replacing any earlier one for the peer
+    pub fn derive(
+        &self,
+        encryption_key: &[u8],
+        transcript: &Transcript<'_>,
+        peer: PeerIdentity,
+        peer_addr: IpAddr,
+    ) {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, encryption_key);
+        let transcript = transcript.bytes();
+        let token_tag = hmac::sign(
+            &key,
+            &[b"vx0 resumption token".as_slice(), &transcript].concat(),
+        );
+        let mut token = TokenId::default();
+        token.copy_from_slice(&token_tag.as_ref()[..std::mem::size_of::<TokenId>()]);
+        let secret = hmac::sign(
+            &key,
+            &[b"vx0 resumption secret".as_slice(), &transcript].concat(),
+        );
+
+        let mut tickets = lock(&self.tickets);
+        tickets.retain(|_, ticket| ticket.peer != peer);
+        tickets.insert(
+            token,
+            Ticket {
+                secret: secret.as_ref().to_vec(),
+                peer,
+                peer_addr,
+                epoch: journal::key_id(encryption_key),
+                issued: Instant::now(),
+                table_version: 0,
+            },
+        );
+    }
+
+    /// Offer the ticket held for the peer at `peer_addr`, giving it up
+    pub fn offer(
+        &self,
+        peer_addr: IpAddr,
+        peer_asn: u32,
+        ours: PeerIdentity,
+        nonce: &[u8],
+    ) -> Option<(ResumeOffer, Resuming)> {
+        let (token, ticket) = {
+            let mut tickets = lock(&self.tickets);
+            let token = tickets
+                .iter()
+                .find(|(_, ticket)| ticket.peer_addr == peer_addr && ticket.peer.asn == peer_asn)
+                .map(|(token, _)| *token)?;
+            (token, tickets.remove(&token)?)
+        };
+        self.spend(token);
+        if ticket.issued.elapsed() >= self.validity {
+            return None;
+        }
+        let proof = offer_proof(&ticket.secret, &token, nonce, ours);
+        Some((
+            ResumeOffer { token, proof },
+            Resuming {
+                token,
+                secret: ticket.secret,
+            },
+        ))
+    }
+
+    /// Check an offer from `from`, giving up the ticket if it is good;
+    /// returns our answer and the ticket's secret
+    pub fn accept(
+        &self,
+        offer: &ResumeOffer,
+        from: PeerIdentity,
+        their_nonce: &[u8],
+        our_nonce: &[u8],
+    ) -> Result<(ResumeAccepted, Vec<u8>), ResumeRefused> {
+        let mut tickets = lock(&self.tickets);
+        let Some(ticket) = tickets.get(&offer.token) else {
+            return Err(if self.is_spent(&offer.token) {
+                ResumeRefused::Replayed
+            } else {
+                ResumeRefused::Unknown
+            });
+        };
+        if ticket.peer != from {
+            return Err(ResumeRefused::WrongPeer(ticket.peer));
+        }
+        let expected = offer_proof(&ticket.secret, &offer.token, their_nonce, from);
+        if !constant_time_eq(&expected, &offer.proof) {
+            return Err(ResumeRefused::BadProof);
+        }
+        let ticket = tickets
+            .remove(&offer.token)
+            .expect("ticket was found above");
+        drop(tickets);
+        self.spend(offer.token);
+        if ticket.issued.elapsed() >= self.validity {
+            return Err(ResumeRefused::Expired);
+        }
+
+        tracing::debug!(
+            "Resuming session with AS{} from a ticket for keys {:016x}",
+            from.asn,
+            ticket.epoch
+        );
+        let accepted = ResumeAccepted {
+            proof: accepted_proof(&ticket.secret, &offer.token, their_nonce, our_nonce),
+            table_version: ticket.table_version,
+        };
+        Ok((accepted, ticket.secret))
+    }
+
+    /// Note the version of the peer's route table we now hold
+    pub fn note_table_version(&self, peer_addr: IpAddr, peer_asn: u32, version: u64) {
+        let mut tickets = lock(&self.tickets);
+        for ticket in tickets.values_mut() {
+            if ticket.peer_addr == peer_addr && ticket.peer.asn == peer_asn {
+                ticket.table_version = version;
+            }
+        }
+    }
+
+    /// Version of the peer's route table a resumed session would pick up
+    /// from, if we hold a ticket for it
+    pub fn table_version(&self, peer_addr: IpAddr, peer_asn: u32) -> Option<u64> {
+        lock(&self.tickets)
+            .values()
+            .find(|ticket| ticket.peer_addr == peer_addr && ticket.peer.asn == peer_asn)
+            .map(|ticket| ticket.table_version)
+    }
+
+    /// Drop the tickets for a peer, e.g. once its routes were flushed
+    pub fn forget(&self, peer_asn: u32) {
+        lock(&self.tickets).retain(|_, ticket| ticket.peer.asn != peer_asn);
+    }
+
+    /// Tickets held, expired or not
+    pub fn len(&self) -> usize {
+        lock(&self.tickets).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn spend(&self, token: TokenId) {
+        let mut spent = lock(&self.spent);
+        spent.retain(|_, at| at.elapsed() < self.validity);
+        spent.insert(token, Instant::now());
+    }
+
+    fn is_spent(&self, token: &TokenId) -> bool {
+        lock(&self.spent).contains_key(token)
+    }
+}
+
+impl Resuming {
+    /// Check the responder's answer; returns the ticket's secret
+    pub fn finish(
+        self,
+        accepted: &ResumeAccepted,
+        our_nonce: &[u8],
+        their_nonce: &[u8],
+    ) -> Result<Vec<u8>, ResumeRefused> {
+        let expected = accepted_proof(&self.secret, &self.token, our_nonce, their_nonce);
+        if constant_time_eq(&expected, &accepted.proof) {
+            Ok(self.secret)
+        } else {
+            Err(ResumeRefused::BadProof)
+        }
+    }
+}
+
+/// Shared secret for the resumed tunnel, fresh because both nonces are
+pub fn tunnel_secret(secret: &[u8], initiator_nonce: &[u8], responder_nonce: &[u8]) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::sign(
+        &key,
+        &[
+            b"vx0 resumed tunnel".as_slice(),
+            initiator_nonce,
+            responder_nonce,
+        ]
+        .concat(),
+    )
+    .as_ref()
+    .to_vec()
+}
+
+/// A nonce for our OPEN
+pub fn nonce() -> Result<Vec<u8>, IKEError> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|e| IKEError::Crypto(format!("Nonce generation failed: {:?}", e)))?;
+    Ok(nonce)
+}
+
+fn offer_proof(secret: &[u8], token: &TokenId, nonce: &[u8], from: PeerIdentity) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    let mut input = b"vx0 resume offer".to_vec();
+    input.extend_from_slice(token);
+    input.extend_from_slice(nonce);
+    input.extend_from_slice(&from.asn.to_be_bytes());
+    input.extend_from_slice(from.router_id.to_string().as_bytes());
+    hmac::sign(&key, &input).as_ref().to_vec()
+}
+
+fn accepted_proof(
+    secret: &[u8],
+    token: &TokenId,
+    initiator_nonce: &[u8],
+    responder_nonce: &[u8],
+) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hmac::sign(
+        &key,
+        &[
+            b"vx0 resume accepted".as_slice(),
+            token,
+            initiator_nonce,
+            responder_nonce,
+        ]
+        .concat(),
+    )
+    .as_ref()
+    .to_vec()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = &[0x42; 32];
+
+    fn identity(asn: u32, router_id: &str) -> PeerIdentity {
+        PeerIdentity {
+            asn,
+            router_id: router_id.parse().unwrap(),
+        }
+    }
+
+    /// Initiator and responder caches holding the tickets of one session
+    fn session(
+        ours_validity: Duration,
+        theirs_validity: Duration,
+    ) -> (ResumptionCache, ResumptionCache, PeerIdentity) {
+        let (client, server) = (identity(66001, "10.3.0.1"), identity(65101, "10.2.0.1"));
+        let transcript = Transcript {
+            initiator: client,
+            initiator_nonce: &[1; NONCE_LEN],
+            responder: server,
+            responder_nonce: &[2; NONCE_LEN],
+        };
+        let (ours, theirs) = (
+            ResumptionCache::new(ours_validity),
+            ResumptionCache::new(theirs_validity),
+        );
+        ours.derive(KEY, &transcript, server, "10.2.0.1".parse().unwrap());
+        theirs.derive(KEY, &transcript, client, "10.3.0.1".parse().unwrap());
+        (ours, theirs, client)
+    }
+
+    #[test]
+    fn test_both_ends_resume_with_the_same_secret() {
+        let (ours, theirs, client) = session(DEFAULT_VALIDITY, DEFAULT_VALIDITY);
+        let (initiator_nonce, responder_nonce) = ([3; NONCE_LEN], [4; NONCE_LEN]);
+        let (offer, resuming) = ours
+            .offer("10.2.0.1".parse().unwrap(), 65101, client, &initiator_nonce)
+            .unwrap();
+        theirs.note_table_version("10.3.0.1".parse().unwrap(), 66001, 17);
+        let (accepted, secret) = theirs
+            .accept(&offer, client, &initiator_nonce, &responder_nonce)
+            .unwrap();
+        assert_eq!(accepted.table_version, 17);
+        assert_eq!(
+            resuming
+                .finish(&accepted, &initiator_nonce, &responder_nonce)
+                .unwrap(),
+            secret
+        );
+        assert!(ours.is_empty() && theirs.is_empty());
+
+        // The tunnel secret is fresh for every pair of nonces
+        assert_ne!(
+            tunnel_secret(&secret, &initiator_nonce, &responder_nonce),
+            tunnel_secret(&secret, &initiator_nonce, &[5; NONCE_LEN])
+        );
+    }
+
+    #[test]
+    fn test_tokens_are_single_use_and_bound_to_the_peer() {
+        let (ours, theirs, client) = session(DEFAULT_VALIDITY, DEFAULT_VALIDITY);
+        let nonce = [3; NONCE_LEN];
+        let (offer, _) = ours
+            .offer("10.2.0.1".parse().unwrap(), 65101, client, &nonce)
+            .unwrap();
+        assert!(ours
+            .offer("10.2.0.1".parse().unwrap(), 65101, client, &nonce)
+            .is_none());
+
+        // Someone else presenting the token, or a forged proof, is refused
+        // without using the ticket up
+        let impostor = identity(66002, "10.3.0.9");
+        assert_eq!(
+            theirs.accept(&offer, impostor, &nonce, &[4; NONCE_LEN]),
+            Err(ResumeRefused::WrongPeer(client))
+        );
+        let forged = ResumeOffer {
+            proof: vec![0; 32],
+            ..offer.clone()
+        };
+        assert_eq!(
+            theirs.accept(&forged, client, &nonce, &[4; NONCE_LEN]),
+            Err(ResumeRefused::BadProof)
+        );
+
+        assert!(theirs
+            .accept(&offer, client, &nonce, &[4; NONCE_LEN])
+            .is_ok());
+        assert_eq!(
+            theirs.accept(&offer, client, &nonce, &[5; NONCE_LEN]),
+            Err(ResumeRefused::Replayed)
+        );
+    }
+
+    #[test]
+    fn test_expired_ticket_is_refused() {
+        let (ours, _, client) = session(Duration::ZERO, DEFAULT_VALIDITY);
+        assert!(ours
+            .offer("10.2.0.1".parse().unwrap(), 65101, client, &[3; NONCE_LEN])
+            .is_none());
+
+        let (ours, theirs, client) = session(DEFAULT_VALIDITY, Duration::ZERO);
+        let (offer, _) = ours
+            .offer("10.2.0.1".parse().unwrap(), 65101, client, &[3; NONCE_LEN])
+            .unwrap();
+        assert_eq!(
+            theirs.accept(&offer, client, &[3; NONCE_LEN], &[4; NONCE_LEN]),
+            Err(ResumeRefused::Expired)
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use crate::monitoring::{crash, Subsystem};
+use crate::network::acl::{Acl, Contact};
+use crate::network::ike::tunnels::TunnelManager;
 use crate::network::ike::{IKEError, IKESession, IKEState};
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -6,6 +9,8 @@ use tokio::net::UdpSocket;
 pub struct IKEDaemon {
     listen_addr: SocketAddr,
     socket: Option<Arc<UdpSocket>>,
+    acl: Arc<Acl>,
+    tunnels: Option<Arc<TunnelManager>>,
 }
 
 impl IKEDaemon {
@@ -13,9 +18,31 @@ impl IKEDaemon {
         IKEDaemon {
             listen_addr,
             socket: None,
+            acl: Arc::new(Acl::default()),
+            tunnels: None,
         }
     }
 
+    /// Drop packets from addresses the ACL refuses before parsing them
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Carry the UDP tunnels of `tunnels` on the IKE socket: what they send
+    /// leaves from it, and datagrams from their peers are handed to them
+    pub fn with_tunnels(mut self, tunnels: Arc<TunnelManager>) -> Self {
+        self.tunnels = Some(tunnels);
+        self
+    }
+
+    /// Address the socket is bound to, once started
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok())
+    }
+
     pub async fn start(&mut self) -> Result<(), IKEError> {
         let socket = UdpSocket::bind(self.listen_addr).await?;
         tracing::info!("IKE daemon listening on {}", self.listen_addr);
@@ -23,20 +50,58 @@ impl IKEDaemon {
         let socket = Arc::new(socket);
         self.socket = Some(Arc::clone(&socket));
 
+        if let Some(tunnels) = &self.tunnels {
+            tunnels.set_udp_socket(Arc::clone(&socket));
+        }
         let listen_socket = Arc::clone(&socket);
-        tokio::spawn(async move {
-            Self::listen_loop(listen_socket).await;
+        let acl = Arc::clone(&self.acl);
+        let tunnels = self.tunnels.clone();
+        crash::spawn_restartable(Subsystem::Ike, "ike-listener", move || {
+            Self::listen_loop(
+                Arc::clone(&listen_socket),
+                Arc::clone(&acl),
+                tunnels.clone(),
+            )
         });
 
         Ok(())
     }
 
-    async fn listen_loop(socket: Arc<UdpSocket>) {
+    async fn listen_loop(
+        socket: Arc<UdpSocket>,
+        acl: Arc<Acl>,
+        tunnels: Option<Arc<TunnelManager>>,
+    ) {
         let mut buf = [0; 4096];
 
         loop {
             match socket.recv_from(&mut buf).await {
+                Ok((_, addr)) if !acl.check(&Contact::address(addr.ip()), "IKE packet") => {}
                 Ok((size, addr)) => {
+                    // A peer with an established tunnel sends datagrams
+                    // on it, but rekeys and dead peer probes come here
+                    // too; whatever the tunnel cannot open is IKE's
+                    if let Some(tunnels) = &tunnels {
+                        if let Some(tunnel_id) = tunnels.tunnel_to(addr.ip()).await {
+                            match tunnels.deliver(tunnel_id, addr.ip(), &buf[..size]).await {
+                                Ok(Some(payload)) => {
+                                    tracing::trace!(
+                                        "Dropped a {}-byte datagram from {}: no data plane",
+                                        payload.len(),
+                                        addr
+                                    );
+                                    continue;
+                                }
+                                Ok(None) => continue,
+                                Err(e) => tracing::trace!(
+                                    "Not a tunnel datagram from {}, trying IKE: {}",
+                                    addr,
+                                    e
+                                ),
+                            }
+                        }
+                    }
+
                     tracing::debug!("Received IKE packet from {} ({} bytes)", addr, size);
 
                     if let Err(e) = Self::handle_packet(&buf[..size], addr).await {
@@ -88,7 +153,10 @@ impl IKESession {
         let (size, addr) = socket.recv_from(&mut buf).await?;
 
         if addr != self.peer_addr {
-            return Err(IKEError::Protocol("Unexpected sender address".to_string()));
+            return Err(IKEError::UnexpectedSender {
+                expected: self.peer_addr,
+                got: addr,
+            });
         }
 
         Ok(buf[..size].to_vec())
@@ -96,7 +164,9 @@ impl IKESession {
 
     pub fn encrypt_payload(&self, plaintext: &[u8]) -> Result<Vec<u8>, IKEError> {
         if !self.is_established() {
-            return Err(IKEError::Protocol("Session not established".to_string()));
+            return Err(IKEError::SessionNotEstablished {
+                peer: self.peer_addr,
+            });
         }
 
         // For now, just return the plaintext (no encryption)
@@ -106,7 +176,9 @@ impl IKESession {
 
     pub fn decrypt_payload(&self, ciphertext: &[u8]) -> Result<Vec<u8>, IKEError> {
         if !self.is_established() {
-            return Err(IKEError::Protocol("Session not established".to_string()));
+            return Err(IKEError::SessionNotEstablished {
+                peer: self.peer_addr,
+            });
         }
 
         // For now, just return the ciphertext (no decryption)
@@ -116,7 +188,9 @@ impl IKESession {
 
     pub async fn rekey(&mut self) -> Result<(), IKEError> {
         if !self.is_established() {
-            return Err(IKEError::Protocol("Session not established".to_string()));
+            return Err(IKEError::SessionNotEstablished {
+                peer: self.peer_addr,
+            });
         }
 
         tracing::info!("Starting IKE rekey process");
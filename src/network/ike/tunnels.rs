@@ -1,12 +1,57 @@
+use crate::monitoring::capture::{Capture, CapturedEvent};
+use crate::monitoring::register_metric;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::ike::encap::EncapStream;
+use crate::network::ike::journal::{self, NonceJournal};
+use crate::network::ike::mtu::{self, PathProbe, ProbeCache, Reassembler};
+use crate::network::ike::queue::{ClassStats, QueueCounters, SendQueue};
+use crate::network::ike::resumption::{PeerIdentity, ResumptionCache, Transcript};
 use crate::network::ike::{IKEError, IKESession};
+use crate::network::obfuscation::{self, FrameKind, Padding};
+use crate::util::clock::{self, SharedClock};
+use crate::util::sync::lock;
+use prometheus::{IntCounterVec, Opts};
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, RwLock};
 use uuid::Uuid;
 
 pub type TunnelId = Uuid;
 
+/// Status changes buffered per subscriber before it is considered lagged
+const STATUS_FEED_CAPACITY: usize = 1024;
+
+/// How long the fragments of one datagram may take to arrive
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long opening a tunnel stream, or writing a datagram to it, may take
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens a payload meant for the node itself rather than for the protocol
+/// or traffic the tunnel carries; no IP packet or BGP message starts so
+pub const PEER_MESSAGE_PREFIX: &[u8; 8] = b"VX0PEER1";
+
+/// Peer messages buffered per subscriber before it is considered lagged
+const PEER_MESSAGE_CAPACITY: usize = 256;
+
+/// Frame `body` as a peer message, to be sent over the peer's tunnel
+pub fn peer_message(body: &[u8]) -> Vec<u8> {
+    [&PEER_MESSAGE_PREFIX[..], body].concat()
+}
+
+/// A message a peer sent the node over a tunnel, such as a goodbye
+#[derive(Debug, Clone)]
+pub struct PeerMessage {
+    pub tunnel_id: TunnelId,
+    pub from: IpAddr,
+    /// With the prefix removed
+    pub body: Vec<u8>,
+}
+
 #[derive(Debug, Clone)]
 pub struct IPSecTunnel {
     pub tunnel_id: TunnelId,
@@ -16,9 +61,58 @@ pub struct IPSecTunnel {
     pub status: TunnelStatus,
     pub traffic_stats: TrafficStats,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    /// When its keys were last negotiated, on the manager's monotonic clock
+    pub keyed_at: Instant,
+    /// Last payload sent or received, on the manager's monotonic clock
+    pub active_at: Instant,
+    /// Payloads are padded; only once both ends support it
+    pub padded: bool,
+    /// UDP unless the peer is only reachable through TCP encapsulation
+    pub transport: TunnelTransport,
+    /// Counts from the send queues writing to the tunnel: its peer's, and
+    /// one per channel it seals; each is dropped once its queue is
+    pub send_queues: Vec<Weak<QueueCounters>>,
+    /// Largest encrypted datagram sent over it: the smaller of both ends'
+    /// MTUs and whatever the path was probed to carry
+    pub mtu: usize,
+    /// As advertised by the peer; `None` from peers that do not say
+    pub peer_mtu: Option<usize>,
 }
 
-#[derive(Debug, Clone)]
+/// How a tunnel's packets travel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TunnelTransport {
+    #[default]
+    Udp,
+    /// Inside a TCP stream on the peer's BGP port; packets queue behind
+    /// any lost segment, so throughput is counted apart from UDP tunnels
+    TcpEncap,
+}
+
+impl TunnelTransport {
+    pub fn label(&self) -> &'static str {
+        match self {
+            TunnelTransport::Udp => "udp",
+            TunnelTransport::TcpEncap => "tcp_encap",
+        }
+    }
+}
+
+/// Tunnel bytes carried, by transport and direction
+pub fn throughput_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new(
+                "vx0net_tunnel_bytes_total",
+                "Encrypted tunnel bytes, by transport and direction",
+            ),
+            &["transport", "direction"],
+        ))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TunnelStatus {
     Negotiating,
     Established,
@@ -27,25 +121,398 @@ pub enum TunnelStatus {
     Closed,
 }
 
+/// A tunnel moved to a new status; the routing layer follows these to
+/// stop using next hops it can no longer reach
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelStatusChanged {
+    pub tunnel_id: TunnelId,
+    pub remote_addr: IpAddr,
+    pub status: TunnelStatus,
+}
+
 #[derive(Debug, Clone)]
 pub struct TrafficStats {
     pub bytes_in: u64,
     pub bytes_out: u64,
     pub packets_in: u64,
     pub packets_out: u64,
+    /// Padding, framing and cover traffic, included in `bytes_in`
+    pub overhead_bytes_in: u64,
+    /// Padding, framing and cover traffic, included in `bytes_out`
+    pub overhead_bytes_out: u64,
+    /// Last payload sent or received; cover traffic does not count
     pub last_activity: chrono::DateTime<chrono::Utc>,
+    /// Send queue depths and drops per traffic class; empty without a queue
+    pub queues: Vec<ClassStats>,
 }
 
 #[derive(Debug)]
 pub struct TunnelManager {
     tunnels: Arc<RwLock<HashMap<TunnelId, IPSecTunnel>>>,
+    /// Offered to peers; `None` when padding is off
+    padding: Option<Padding>,
+    /// Outbound nonce counters; `None` when journaling is off
+    journal: Option<Mutex<NonceJournal>>,
+    /// Held from planning a journal write until it is committed, so marks
+    /// reach the disk in the order they were planned
+    journal_writes: tokio::sync::Mutex<()>,
+    /// Tickets for resuming sessions; `None` when resumption is off
+    resumption: Option<ResumptionCache>,
+    /// Sent while the tunnel table is locked, so `follow_status` never
+    /// misses or repeats one
+    status_changes: broadcast::Sender<TunnelStatusChanged>,
+    /// Peer messages taken out of whatever carried them
+    peer_messages: broadcast::Sender<PeerMessage>,
+    /// Records decrypted payloads, when capturing them is enabled
+    capture: OnceLock<Arc<Capture>>,
+    /// Times idleness and key age; the system clock unless set
+    clock: OnceLock<SharedClock>,
+    /// How long keys are used before the tunnel is rekeyed; `None` never
+    rekey_after: Option<Duration>,
+    /// Largest encrypted datagram we send to any peer
+    mtu: usize,
+    /// Path MTUs found by probing, by peer address
+    probed: ProbeCache,
+    /// Datagrams too large for their tunnel are split; otherwise refused
+    fragmenting: bool,
+    next_datagram: AtomicU32,
+    /// Fragments received so far, by tunnel
+    reassembly: Mutex<HashMap<TunnelId, Reassembler>>,
+    /// Carries UDP tunnels' datagrams; the IKE daemon's socket once it
+    /// started, so they leave from the port peers expect
+    udp: OnceLock<Arc<UdpSocket>>,
+    /// Tunnel streams to peers carried over TCP, by tunnel; each locked on
+    /// its own so a slow peer holds up only its own datagrams
+    streams: Mutex<HashMap<TunnelId, Arc<tokio::sync::Mutex<Option<EncapStream>>>>>,
 }
 
 impl TunnelManager {
     pub fn new() -> Self {
         TunnelManager {
             tunnels: Arc::new(RwLock::new(HashMap::new())),
+            padding: None,
+            journal: None,
+            journal_writes: tokio::sync::Mutex::new(()),
+            resumption: None,
+            status_changes: broadcast::channel(STATUS_FEED_CAPACITY).0,
+            peer_messages: broadcast::channel(PEER_MESSAGE_CAPACITY).0,
+            capture: OnceLock::new(),
+            clock: OnceLock::new(),
+            rekey_after: None,
+            mtu: mtu::DEFAULT_TUNNEL_MTU,
+            probed: ProbeCache::new(Duration::from_secs(600)),
+            fragmenting: true,
+            next_datagram: AtomicU32::new(0),
+            reassembly: Mutex::new(HashMap::new()),
+            udp: OnceLock::new(),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send UDP tunnels' datagrams from `socket`; only the first one set is
+    /// used
+    pub fn set_udp_socket(&self, socket: Arc<UdpSocket>) {
+        if self.udp.set(socket).is_err() {
+            tracing::warn!("Tunnel UDP socket already set");
+        }
+    }
+
+    /// Time tunnels on `clock`; only takes effect before the first tunnel
+    pub fn set_clock(&self, clock: SharedClock) {
+        if self.clock.set(clock).is_err() {
+            tracing::warn!("Tunnel clock already set");
+        }
+    }
+
+    fn clock(&self) -> &SharedClock {
+        self.clock.get_or_init(clock::system)
+    }
+
+    /// Record what arrives over tunnels, after decryption, if `capture`
+    /// wants tunnel payloads
+    pub fn set_capture(&self, capture: Arc<Capture>) {
+        if capture.tunnel_payloads() && self.capture.set(capture).is_err() {
+            tracing::warn!("Tunnel capture already set");
+        }
+    }
+
+    /// Every tunnel's current status and every change from then on, taken
+    /// together so none is missed in between
+    pub async fn follow_status(
+        &self,
+    ) -> (
+        Vec<TunnelStatusChanged>,
+        broadcast::Receiver<TunnelStatusChanged>,
+    ) {
+        let tunnels = self.tunnels.read().await;
+        let current = tunnels.values().map(IPSecTunnel::status_change).collect();
+        (current, self.status_changes.subscribe())
+    }
+
+    /// Follow the peer messages arriving over any tunnel from now on
+    pub fn subscribe_peer_messages(&self) -> broadcast::Receiver<PeerMessage> {
+        self.peer_messages.subscribe()
+    }
+
+    /// Pass a decrypted payload on to peer message subscribers if it is
+    /// one, or give it back for the channel that carried it
+    pub fn route_payload(
+        &self,
+        tunnel_id: TunnelId,
+        from: IpAddr,
+        payload: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let Some(body) = payload.strip_prefix(&PEER_MESSAGE_PREFIX[..]) else {
+            return Some(payload);
+        };
+        let message = PeerMessage {
+            tunnel_id,
+            from,
+            body: body.to_vec(),
+        };
+        if self.peer_messages.send(message).is_err() {
+            tracing::debug!("Dropped a message from {}: nobody follows them", from);
+        }
+        None
+    }
+
+    fn set_status(&self, tunnel: &mut IPSecTunnel, status: TunnelStatus) {
+        if tunnel.status != status {
+            tunnel.status = status;
+            // Sending only fails when nobody is subscribed
+            let _ = self.status_changes.send(tunnel.status_change());
+        }
+    }
+
+    /// Record that a tunnel stopped carrying traffic, e.g. after its peer
+    /// went silent; it is removed by the next `cleanup_failed_tunnels`
+    pub async fn mark_failed(&self, tunnel_id: &TunnelId) -> Result<(), IKEError> {
+        let mut tunnels = self.tunnels.write().await;
+        let tunnel = tunnels
+            .get_mut(tunnel_id)
+            .ok_or(IKEError::TunnelNotFound { tunnel: *tunnel_id })?;
+        tracing::warn!("Tunnel {} to {} failed", tunnel_id, tunnel.remote_addr);
+        self.set_status(tunnel, TunnelStatus::Failed);
+        Ok(())
+    }
+
+    /// Pad payloads on tunnels to peers that also pad
+    pub fn with_padding(mut self, padding: Option<Padding>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Journal outbound nonce counters so a restart never reuses one
+    pub fn with_nonce_journal(mut self, journal: Option<NonceJournal>) -> Self {
+        self.journal = journal.map(Mutex::new);
+        self
+    }
+
+    /// Let peers that disconnect resume within `validity` without a full
+    /// handshake
+    pub fn with_resumption(mut self, validity: Option<Duration>) -> Self {
+        self.resumption = validity.map(ResumptionCache::new);
+        self
+    }
+
+    /// Rekey established tunnels once their keys are `interval` old
+    pub fn with_rekey_interval(mut self, interval: Option<Duration>) -> Self {
+        self.rekey_after = interval;
+        self
+    }
+
+    /// Send datagrams of at most `mtu` bytes, probing paths again once
+    /// their results are `revalidate` old
+    pub fn with_mtu(mut self, mtu: usize, revalidate: Duration) -> Self {
+        self.mtu = mtu.max(mtu::MIN_TUNNEL_MTU);
+        self.probed = ProbeCache::new(revalidate);
+        self
+    }
+
+    /// Split datagrams too large for their tunnel, rather than refusing
+    /// them for the sender to send smaller
+    pub fn with_fragmentation(mut self, fragmenting: bool) -> Self {
+        self.fragmenting = fragmenting;
+        self
+    }
+
+    /// What this node advertises as its MTU
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn resumption(&self) -> Option<&ResumptionCache> {
+        self.resumption.as_ref()
+    }
+
+    /// Count the tunnel's nonces from zero under its current keys
+    fn start_nonces(&self, tunnel_id: TunnelId, session: &IKESession) {
+        if let Some(journal) = &self.journal {
+            lock(journal).start(tunnel_id, journal::key_id(&session.encryption_key));
+        }
+    }
+
+    /// Reserve the tunnel's next nonce counter, if nonces are journaled,
+    /// writing a new block first if its reservation is used up
+    async fn next_nonce(&self, tunnel_id: &TunnelId) -> Result<Option<u64>, IKEError> {
+        let Some(journal) = &self.journal else {
+            return Ok(None);
+        };
+        loop {
+            if let Some(next) = lock(journal).try_next_nonce(tunnel_id)? {
+                return Ok(Some(next));
+            }
+            self.write_nonce_journal().await?;
+        }
+    }
+
+    fn forget_nonces(&self, tunnel_id: &TunnelId) {
+        if let Some(journal) = &self.journal {
+            lock(journal).forget(tunnel_id);
+        }
+    }
+
+    /// Reserve a block ahead for every tunnel, syncing it to disk on the
+    /// blocking pool rather than under the journal's lock
+    async fn write_nonce_journal(&self) -> Result<(), IKEError> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let _writing = self.journal_writes.lock().await;
+        let reservation = lock(journal).reserve()?;
+        let reservation = tokio::task::spawn_blocking(move || {
+            reservation.write()?;
+            Ok::<_, IKEError>(reservation)
+        })
+        .await
+        .map_err(|e| IKEError::Crypto(format!("Nonce journal write did not finish: {}", e)))??;
+        lock(journal).commit(reservation);
+        Ok(())
+    }
+
+    /// Pad the tunnel's payloads if we and the peer both support it,
+    /// returning whether it is now padded
+    pub async fn negotiate_padding(&self, tunnel_id: &TunnelId, peer_supports: bool) -> bool {
+        let mut tunnels = self.tunnels.write().await;
+        let Some(tunnel) = tunnels.get_mut(tunnel_id) else {
+            return false;
+        };
+        let padded = self.padding.is_some() && peer_supports;
+        if tunnel.padded != padded {
+            tracing::debug!(
+                "Tunnel {} to {} is {} padded",
+                tunnel_id,
+                tunnel.remote_addr,
+                if padded { "now" } else { "no longer" }
+            );
+            tunnel.padded = padded;
         }
+        padded
+    }
+
+    /// The MTU of a tunnel to `remote_addr`, given what the peer advertised
+    fn path_mtu(&self, remote_addr: IpAddr, peer_mtu: Option<usize>) -> usize {
+        [Some(self.mtu), peer_mtu, self.probed.get(remote_addr)]
+            .into_iter()
+            .flatten()
+            .min()
+            .unwrap_or(self.mtu)
+            .max(mtu::MIN_TUNNEL_MTU)
+    }
+
+    /// Send datagrams no larger than the smaller of our MTU and the peer's,
+    /// returning the tunnel's MTU now
+    pub async fn negotiate_mtu(&self, tunnel_id: &TunnelId, peer_mtu: Option<usize>) -> usize {
+        let mut tunnels = self.tunnels.write().await;
+        let Some(tunnel) = tunnels.get_mut(tunnel_id) else {
+            return self.mtu;
+        };
+        tunnel.peer_mtu = peer_mtu;
+        self.settle_mtu(tunnel);
+        tunnel.mtu
+    }
+
+    fn settle_mtu(&self, tunnel: &mut IPSecTunnel) {
+        let mtu = self.path_mtu(tunnel.remote_addr, tunnel.peer_mtu);
+        if tunnel.mtu != mtu {
+            tracing::info!(
+                "Tunnel {} to {} now carries datagrams of up to {} bytes",
+                tunnel.tunnel_id,
+                tunnel.remote_addr,
+                mtu
+            );
+            tunnel.mtu = mtu;
+        }
+    }
+
+    /// Probe how large a datagram the path under a tunnel carries, up to
+    /// the MTU both ends advertise, and use the result from now on
+    pub async fn probe_mtu(
+        &self,
+        tunnel_id: &TunnelId,
+        path: &dyn PathProbe,
+    ) -> Result<usize, IKEError> {
+        let (remote_addr, ceiling) = {
+            let tunnels = self.tunnels.read().await;
+            let tunnel = tunnels
+                .get(tunnel_id)
+                .ok_or(IKEError::TunnelNotFound { tunnel: *tunnel_id })?;
+            let ceiling = tunnel.peer_mtu.map_or(self.mtu, |peer| peer.min(self.mtu));
+            (tunnel.remote_addr, ceiling)
+        };
+        let found = mtu::probe(path, remote_addr, mtu::MIN_TUNNEL_MTU, ceiling).await;
+        self.probed
+            .record(remote_addr, found, self.clock().now_monotonic());
+
+        let mut tunnels = self.tunnels.write().await;
+        for tunnel in tunnels
+            .values_mut()
+            .filter(|tunnel| tunnel.remote_addr == remote_addr)
+        {
+            self.settle_mtu(tunnel);
+        }
+        Ok(tunnels.get(tunnel_id).map_or(found, |tunnel| tunnel.mtu))
+    }
+
+    /// Probe every established tunnel whose path was never probed or was
+    /// probed too long ago, returning how many were probed
+    pub async fn revalidate_mtus(&self, path: &dyn PathProbe) -> usize {
+        let now = self.clock().now_monotonic();
+        let due: Vec<TunnelId> = self
+            .tunnels
+            .read()
+            .await
+            .values()
+            .filter(|tunnel| {
+                matches!(tunnel.status, TunnelStatus::Established)
+                    && self.probed.due(tunnel.remote_addr, now)
+            })
+            .map(|tunnel| tunnel.tunnel_id)
+            .collect();
+        let mut probed = 0;
+        for tunnel_id in due {
+            if self.probe_mtu(&tunnel_id, path).await.is_ok() {
+                probed += 1;
+            }
+        }
+        probed
+    }
+
+    /// Probe tunnels' paths in the background as their results age out
+    pub fn spawn_mtu_probing(self: &Arc<Self>, path: Arc<dyn PathProbe>) {
+        let manager = Arc::clone(self);
+        crash::spawn(Subsystem::Ike, "tunnel-mtu-probe", async move {
+            let period = (manager.probed.revalidate_after() / 10)
+                .clamp(Duration::from_millis(10), Duration::from_secs(60));
+            let mut ticker = clock::interval(manager.clock(), period);
+            loop {
+                ticker.tick().await;
+                let probed = manager.revalidate_mtus(path.as_ref()).await;
+                if probed > 0 {
+                    tracing::trace!("Probed the paths of {} tunnels", probed);
+                }
+            }
+        });
     }
 
     pub async fn create_tunnel(
@@ -61,36 +528,181 @@ impl TunnelManager {
 
         let mut ike_session = IKESession::new(peer_addr, 14)?; // DH Group 14
         ike_session.establish_tunnel(psk).await?;
+        self.start_nonces(tunnel_id, &ike_session);
+        let now = self.clock().now_monotonic();
 
-        let tunnel = IPSecTunnel {
+        let mut tunnel = IPSecTunnel {
             tunnel_id,
             local_addr,
             remote_addr,
             ike_session,
-            status: TunnelStatus::Established,
+            status: TunnelStatus::Negotiating,
             traffic_stats: TrafficStats::new(),
-            created_at: chrono::Utc::now(),
+            created_at: self.clock().now_utc(),
+            keyed_at: now,
+            active_at: now,
+            padded: false,
+            transport: TunnelTransport::Udp,
+            send_queues: Vec::new(),
+            mtu: self.path_mtu(remote_addr, None),
+            peer_mtu: None,
         };
 
         let mut tunnels = self.tunnels.write().await;
+        self.set_status(&mut tunnel, TunnelStatus::Established);
         tunnels.insert(tunnel_id, tunnel);
 
         tracing::info!("IPSec tunnel {} established successfully", tunnel_id);
         Ok(tunnel_id)
     }
 
+    /// Install a tunnel resumed from a ticket, keyed from `shared_secret`
+    pub async fn resume_tunnel(
+        &self,
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
+        peer_addr: SocketAddr,
+        shared_secret: Vec<u8>,
+    ) -> Result<TunnelId, IKEError> {
+        let tunnel_id = Uuid::new_v4();
+        let ike_session = IKESession::resumed(peer_addr, 14, shared_secret)?;
+        self.start_nonces(tunnel_id, &ike_session);
+        let now = self.clock().now_monotonic();
+
+        let mut tunnel = IPSecTunnel {
+            tunnel_id,
+            local_addr,
+            remote_addr,
+            ike_session,
+            status: TunnelStatus::Negotiating,
+            traffic_stats: TrafficStats::new(),
+            created_at: self.clock().now_utc(),
+            keyed_at: now,
+            active_at: now,
+            padded: false,
+            transport: TunnelTransport::Udp,
+            send_queues: Vec::new(),
+            mtu: self.path_mtu(remote_addr, None),
+            peer_mtu: None,
+        };
+
+        let mut tunnels = self.tunnels.write().await;
+        self.set_status(&mut tunnel, TunnelStatus::Established);
+        tunnels.insert(tunnel_id, tunnel);
+
+        tracing::info!("Resumed IPSec tunnel {} to {}", tunnel_id, remote_addr);
+        Ok(tunnel_id)
+    }
+
+    /// Derive the ticket for a session with `peer` from the keys of the
+    /// tunnel to it; returns whether there was one to derive it from
+    pub async fn keep_ticket(
+        &self,
+        remote_addr: IpAddr,
+        transcript: &Transcript<'_>,
+        peer: PeerIdentity,
+    ) -> bool {
+        let Some(resumption) = &self.resumption else {
+            return false;
+        };
+        let tunnels = self.tunnels.read().await;
+        let Some(tunnel) = tunnels.values().find(|tunnel| {
+            tunnel.remote_addr == remote_addr && tunnel.status == TunnelStatus::Established
+        }) else {
+            return false;
+        };
+        resumption.derive(
+            &tunnel.ike_session.encryption_key,
+            transcript,
+            peer,
+            remote_addr,
+        );
+        true
+    }
+
     pub async fn close_tunnel(&self, tunnel_id: &TunnelId) -> Result<(), IKEError> {
         let mut tunnels = self.tunnels.write().await;
 
+        self.forget_nonces(tunnel_id);
+        lock(&self.reassembly).remove(tunnel_id);
+        lock(&self.streams).remove(tunnel_id);
         if let Some(mut tunnel) = tunnels.remove(tunnel_id) {
+            self.set_status(&mut tunnel, TunnelStatus::Closed);
             tunnel.ike_session.close().await?;
-            tunnel.status = TunnelStatus::Closed;
             tracing::info!("Closed tunnel {}", tunnel_id);
         }
 
         Ok(())
     }
 
+    /// Carry the tunnel's packets over `transport` from now on
+    pub async fn set_transport(
+        &self,
+        tunnel_id: &TunnelId,
+        transport: TunnelTransport,
+    ) -> Result<(), IKEError> {
+        let mut tunnels = self.tunnels.write().await;
+        let tunnel = tunnels
+            .get_mut(tunnel_id)
+            .ok_or(IKEError::TunnelNotFound { tunnel: *tunnel_id })?;
+        if tunnel.transport != transport {
+            tracing::info!(
+                "Tunnel {} to {} now carried over {}",
+                tunnel_id,
+                tunnel.remote_addr,
+                transport.label()
+            );
+            tunnel.transport = transport;
+        }
+        Ok(())
+    }
+
+    /// The established tunnel to a peer address, if any
+    pub async fn tunnel_to(&self, remote_addr: IpAddr) -> Option<TunnelId> {
+        let tunnels = self.tunnels.read().await;
+        tunnels
+            .values()
+            .find(|tunnel| {
+                tunnel.remote_addr == remote_addr && tunnel.status == TunnelStatus::Established
+            })
+            .map(|tunnel| tunnel.tunnel_id)
+    }
+
+    /// Wait until a tunnel to a peer address is established
+    pub async fn wait_established(&self, remote_addr: IpAddr) -> TunnelId {
+        let mut changes = self.status_changes.subscribe();
+        loop {
+            if let Some(tunnel_id) = self.tunnel_to(remote_addr).await {
+                return tunnel_id;
+            }
+            Self::next_change_to(&mut changes, remote_addr).await;
+        }
+    }
+
+    /// Wait until no established tunnel to a peer address is left
+    pub async fn wait_lost(&self, remote_addr: IpAddr) {
+        let mut changes = self.status_changes.subscribe();
+        while self.tunnel_to(remote_addr).await.is_some() {
+            Self::next_change_to(&mut changes, remote_addr).await;
+        }
+    }
+
+    /// Wait for a status change on a tunnel to `remote_addr`, or for changes
+    /// to have been missed; never returns once nothing can change any more
+    async fn next_change_to(
+        changes: &mut broadcast::Receiver<TunnelStatusChanged>,
+        remote_addr: IpAddr,
+    ) {
+        loop {
+            match changes.recv().await {
+                Ok(change) if change.remote_addr == remote_addr => return,
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => return,
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+
     pub async fn get_tunnel(&self, tunnel_id: &TunnelId) -> Option<IPSecTunnel> {
         let tunnels = self.tunnels.read().await;
         tunnels.get(tunnel_id).cloned()
@@ -101,33 +713,60 @@ impl TunnelManager {
         tunnels.values().cloned().collect()
     }
 
-    pub async fn send_packet(&self, tunnel_id: &TunnelId, packet: &[u8]) -> Result<(), IKEError> {
+    /// Encrypt and send a packet, returning what went on the wire
+    pub async fn send_packet(
+        &self,
+        tunnel_id: &TunnelId,
+        packet: &[u8],
+    ) -> Result<Vec<u8>, IKEError> {
+        match self.tunnels.read().await.get(tunnel_id) {
+            Some(tunnel) if !matches!(tunnel.status, TunnelStatus::Established) => {
+                return Err(IKEError::TunnelNotEstablished { tunnel: *tunnel_id });
+            }
+            Some(_) => {}
+            None => return Err(IKEError::TunnelNotFound { tunnel: *tunnel_id }),
+        }
+        // Taken before the table is locked, as it may wait on the journal
+        let nonce = self.next_nonce(tunnel_id).await?;
         let mut tunnels = self.tunnels.write().await;
 
         if let Some(tunnel) = tunnels.get_mut(tunnel_id) {
             if !matches!(tunnel.status, TunnelStatus::Established) {
-                return Err(IKEError::Protocol("Tunnel not established".to_string()));
+                return Err(IKEError::TunnelNotEstablished { tunnel: *tunnel_id });
             }
 
-            // Encrypt the packet
-            let encrypted_packet = tunnel.ike_session.encrypt_payload(packet)?;
+            // Pad inside the encryption so the pad length stays hidden
+            let encrypted_packet = match (&self.padding, tunnel.padded) {
+                (Some(padding), true) => {
+                    let frame = padding.frame(FrameKind::Data, packet);
+                    let encrypted = tunnel.ike_session.encrypt_payload(&frame)?;
+                    tunnel.traffic_stats.overhead_bytes_out +=
+                        (encrypted.len() - packet.len()) as u64;
+                    encrypted
+                }
+                _ => tunnel.ike_session.encrypt_payload(packet)?,
+            };
 
             // In a real implementation, we would send this through a raw socket or TUN interface
             tracing::debug!(
-                "Sending encrypted packet through tunnel {} ({} bytes)",
+                "Sending encrypted packet through tunnel {} ({} bytes, nonce {:?})",
                 tunnel_id,
-                encrypted_packet.len()
+                encrypted_packet.len(),
+                nonce
             );
 
             // Update traffic stats
+            throughput_counter()
+                .with_label_values(&[tunnel.transport.label(), "out"])
+                .inc_by(encrypted_packet.len() as u64);
             tunnel.traffic_stats.bytes_out += encrypted_packet.len() as u64;
             tunnel.traffic_stats.packets_out += 1;
-            tunnel.traffic_stats.last_activity = chrono::Utc::now();
+            tunnel.traffic_stats.last_activity = self.clock().now_utc();
+            tunnel.active_at = self.clock().now_monotonic();
+            Ok(encrypted_packet)
         } else {
-            return Err(IKEError::Protocol("Tunnel not found".to_string()));
+            Err(IKEError::TunnelNotFound { tunnel: *tunnel_id })
         }
-
-        Ok(())
     }
 
     pub async fn receive_packet(
@@ -139,35 +778,211 @@ impl TunnelManager {
 
         if let Some(tunnel) = tunnels.get_mut(tunnel_id) {
             if !matches!(tunnel.status, TunnelStatus::Established) {
-                return Err(IKEError::Protocol("Tunnel not established".to_string()));
+                return Err(IKEError::TunnelNotEstablished { tunnel: *tunnel_id });
             }
 
             // Decrypt the packet
-            let decrypted_packet = tunnel.ike_session.decrypt_payload(encrypted_packet)?;
+            let decrypted_packet = tunnel
+                .ike_session
+                .decrypt_payload(encrypted_packet)
+                .map_err(|e| e.on_tunnel(*tunnel_id))?;
+
+            let (kind, payload) = if tunnel.padded {
+                let (kind, payload) =
+                    obfuscation::unframe(&decrypted_packet).map_err(|source| {
+                        IKEError::BadFrame {
+                            tunnel: *tunnel_id,
+                            source,
+                        }
+                    })?;
+                (kind, payload.to_vec())
+            } else {
+                (FrameKind::Data, decrypted_packet)
+            };
 
             tracing::debug!(
                 "Received and decrypted packet through tunnel {} ({} bytes)",
                 tunnel_id,
-                decrypted_packet.len()
+                payload.len()
             );
 
             // Update traffic stats
-            tunnel.traffic_stats.bytes_in += encrypted_packet.len() as u64;
-            tunnel.traffic_stats.packets_in += 1;
-            tunnel.traffic_stats.last_activity = chrono::Utc::now();
+            throughput_counter()
+                .with_label_values(&[tunnel.transport.label(), "in"])
+                .inc_by(encrypted_packet.len() as u64);
+            let stats = &mut tunnel.traffic_stats;
+            stats.bytes_in += encrypted_packet.len() as u64;
+            stats.packets_in += 1;
+            stats.overhead_bytes_in += encrypted_packet.len().saturating_sub(payload.len()) as u64;
+            if kind == FrameKind::Data {
+                stats.last_activity = self.clock().now_utc();
+                tunnel.active_at = self.clock().now_monotonic();
+                if let Some(capture) = self.capture.get() {
+                    capture.record(CapturedEvent::TunnelPayload {
+                        tunnel: *tunnel_id,
+                        payload: payload.clone(),
+                    });
+                }
+            }
 
-            Ok(decrypted_packet)
+            // Cover traffic carries nothing for the caller
+            Ok(payload)
         } else {
-            Err(IKEError::Protocol("Tunnel not found".to_string()))
+            Err(IKEError::TunnelNotFound { tunnel: *tunnel_id })
         }
     }
 
+    /// Seal a data plane datagram, split to fit the tunnel's MTU when
+    /// fragmenting, returning each fragment as it goes on the wire;
+    /// [`transmit`](Self::transmit) sends them
+    ///
+    /// Unlike [`send_packet`](Self::send_packet), whose messages may travel
+    /// a stream, what is sent here must fit a single datagram.
+    pub async fn send_datagram(
+        &self,
+        tunnel_id: &TunnelId,
+        datagram: &[u8],
+    ) -> Result<Vec<Vec<u8>>, IKEError> {
+        let (mtu, room) = {
+            let tunnels = self.tunnels.read().await;
+            let tunnel = tunnels
+                .get(tunnel_id)
+                .ok_or(IKEError::TunnelNotFound { tunnel: *tunnel_id })?;
+            let room = match (&self.padding, tunnel.padded) {
+                (Some(padding), true) => padding.room(tunnel.mtu),
+                _ => tunnel.mtu,
+            };
+            (tunnel.mtu, room)
+        };
+        if !self.fragmenting && datagram.len() + mtu::FRAGMENT_HEADER_LEN > room {
+            return Err(IKEError::DatagramTooLarge {
+                tunnel: *tunnel_id,
+                size: datagram.len(),
+                mtu,
+            });
+        }
+        let id = self.next_datagram.fetch_add(1, Ordering::Relaxed);
+        let fragments =
+            mtu::fragment(id, datagram, room).map_err(|_| IKEError::DatagramTooLarge {
+                tunnel: *tunnel_id,
+                size: datagram.len(),
+                mtu,
+            })?;
+        let mut sent = Vec::with_capacity(fragments.len());
+        for fragment in fragments {
+            sent.push(self.send_packet(tunnel_id, &fragment).await?);
+        }
+        Ok(sent)
+    }
+
+    /// Open a datagram sent with [`send_datagram`](Self::send_datagram),
+    /// returning it once all its fragments are in
+    pub async fn receive_datagram(
+        &self,
+        tunnel_id: &TunnelId,
+        encrypted_packet: &[u8],
+    ) -> Result<Option<Vec<u8>>, IKEError> {
+        let fragment = self.receive_packet(tunnel_id, encrypted_packet).await?;
+        if fragment.is_empty() {
+            // Cover traffic
+            return Ok(None);
+        }
+        let now = self.clock().now_monotonic();
+        lock(&self.reassembly)
+            .entry(*tunnel_id)
+            .or_insert_with(|| Reassembler::new(REASSEMBLY_TIMEOUT))
+            .accept(&fragment, now)
+            .map_err(|source| IKEError::BadFragment {
+                tunnel: *tunnel_id,
+                source,
+            })
+    }
+
+    /// Send a datagram to the peer at the other end of the tunnel: its
+    /// fragments go over UDP to the tunnel's endpoint, or down a tunnel
+    /// stream to it for a tunnel carried over TCP
+    pub async fn transmit(&self, tunnel_id: &TunnelId, datagram: &[u8]) -> Result<(), IKEError> {
+        let (endpoint, transport) = {
+            let tunnels = self.tunnels.read().await;
+            let tunnel = tunnels
+                .get(tunnel_id)
+                .ok_or(IKEError::TunnelNotFound { tunnel: *tunnel_id })?;
+            (tunnel.ike_session.peer_addr, tunnel.transport)
+        };
+        let fragments = self.send_datagram(tunnel_id, datagram).await?;
+        match transport {
+            TunnelTransport::Udp => {
+                let socket = match self.udp.get() {
+                    Some(socket) => Arc::clone(socket),
+                    None => {
+                        let bound = Arc::new(UdpSocket::bind(unspecified(endpoint)).await?);
+                        // Another sender may have got there first; use theirs
+                        Arc::clone(self.udp.get_or_init(|| bound))
+                    }
+                };
+                for fragment in fragments {
+                    socket.send_to(&fragment, endpoint).await?;
+                }
+            }
+            TunnelTransport::TcpEncap => {
+                let slot = Arc::clone(lock(&self.streams).entry(*tunnel_id).or_default());
+                let mut stream = slot.lock().await;
+                let timed_out = |_| IKEError::StreamTimeout { tunnel: *tunnel_id };
+                if stream.is_none() {
+                    let connect = EncapStream::connect(endpoint);
+                    *stream = Some(
+                        tokio::time::timeout(STREAM_TIMEOUT, connect)
+                            .await
+                            .map_err(timed_out)??,
+                    );
+                }
+                let open = stream.as_mut().expect("tunnel stream was just opened");
+                let sent = tokio::time::timeout(STREAM_TIMEOUT, async {
+                    for fragment in &fragments {
+                        open.send(fragment).await?;
+                    }
+                    Ok(())
+                })
+                .await
+                .map_err(timed_out)
+                .and_then(|sent| sent);
+                if sent.is_err() {
+                    // The next datagram opens a new stream
+                    *stream = None;
+                }
+                sent?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take in a datagram that arrived over the tunnel from `from`: once all
+    /// its fragments are in, a peer message goes to the peer message
+    /// subscribers and anything else is returned for the data plane
+    pub async fn deliver(
+        &self,
+        tunnel_id: TunnelId,
+        from: IpAddr,
+        encrypted_packet: &[u8],
+    ) -> Result<Option<Vec<u8>>, IKEError> {
+        Ok(self
+            .receive_datagram(&tunnel_id, encrypted_packet)
+            .await?
+            .and_then(|datagram| self.route_payload(tunnel_id, from, datagram)))
+    }
+
     pub async fn rekey_tunnel(&self, tunnel_id: &TunnelId) -> Result<(), IKEError> {
         let mut tunnels = self.tunnels.write().await;
 
         if let Some(tunnel) = tunnels.get_mut(tunnel_id) {
+            // Routes through the tunnel stay usable while it rekeys
             tunnel.status = TunnelStatus::Rekeying;
-            tunnel.ike_session.rekey().await?;
+            if let Err(e) = tunnel.ike_session.rekey().await {
+                self.set_status(tunnel, TunnelStatus::Failed);
+                return Err(e);
+            }
+            self.start_nonces(*tunnel_id, &tunnel.ike_session);
+            tunnel.keyed_at = self.clock().now_monotonic();
             tunnel.status = TunnelStatus::Established;
 
             tracing::info!("Rekeyed tunnel {}", tunnel_id);
@@ -178,7 +993,184 @@ impl TunnelManager {
 
     pub async fn get_tunnel_stats(&self, tunnel_id: &TunnelId) -> Option<TrafficStats> {
         let tunnels = self.tunnels.read().await;
-        tunnels.get(tunnel_id).map(|t| t.traffic_stats.clone())
+        tunnels.get(tunnel_id).map(|t| {
+            let mut stats = t.traffic_stats.clone();
+            let queues: Vec<Arc<QueueCounters>> =
+                t.send_queues.iter().filter_map(Weak::upgrade).collect();
+            if !queues.is_empty() {
+                stats.queues = QueueCounters::combined(queues.iter().map(Arc::as_ref));
+            }
+            stats
+        })
+    }
+
+    /// Report the depths and drops of `queue` with the tunnel's stats, added
+    /// to those of any other queue writing to it
+    pub async fn attach_queue(
+        &self,
+        tunnel_id: &TunnelId,
+        queue: &SendQueue,
+    ) -> Result<(), IKEError> {
+        let mut tunnels = self.tunnels.write().await;
+        let tunnel = tunnels
+            .get_mut(tunnel_id)
+            .ok_or(IKEError::TunnelNotFound { tunnel: *tunnel_id })?;
+        let counters = Arc::downgrade(&queue.counters());
+        tunnel
+            .send_queues
+            .retain(|counters| counters.strong_count() > 0);
+        if !tunnel
+            .send_queues
+            .iter()
+            .any(|known| known.ptr_eq(&counters))
+        {
+            tunnel.send_queues.push(counters);
+        }
+        Ok(())
+    }
+
+    /// Send a cover frame on every padded tunnel that has carried nothing
+    /// for `idle`, returning how many were sent
+    pub async fn send_cover_traffic(&self, idle: Duration) -> usize {
+        let Some(padding) = &self.padding else {
+            return 0;
+        };
+        let now = self.clock().now_monotonic();
+        let mut tunnels = self.tunnels.write().await;
+        let mut sent = 0;
+        for tunnel in tunnels.values_mut() {
+            if !tunnel.padded
+                || !matches!(tunnel.status, TunnelStatus::Established)
+                || now.saturating_duration_since(tunnel.active_at) < idle
+            {
+                continue;
+            }
+            // Cover traffic never waits on the journal; a tunnel whose block
+            // is used up sends none until the next flush
+            if let Some(journal) = &self.journal {
+                match lock(journal).try_next_nonce(&tunnel.tunnel_id) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::debug!("No cover traffic on tunnel {}: {}", tunnel.tunnel_id, e);
+                        continue;
+                    }
+                }
+            }
+            let frame = padding.frame(FrameKind::Cover, &[]);
+            match tunnel.ike_session.encrypt_payload(&frame) {
+                Ok(encrypted) => {
+                    let stats = &mut tunnel.traffic_stats;
+                    stats.bytes_out += encrypted.len() as u64;
+                    stats.overhead_bytes_out += encrypted.len() as u64;
+                    stats.packets_out += 1;
+                    sent += 1;
+                }
+                Err(e) => {
+                    tracing::debug!("No cover traffic on tunnel {}: {}", tunnel.tunnel_id, e)
+                }
+            }
+        }
+        sent
+    }
+
+    /// Keep idle padded tunnels sending cover frames every `interval`
+    pub fn spawn_cover_traffic(self: &Arc<Self>, interval: Duration) {
+        let manager = Arc::clone(self);
+        crash::spawn(Subsystem::Ike, "tunnel-cover-traffic", async move {
+            let mut ticker = clock::interval(manager.clock(), interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let sent = manager.send_cover_traffic(interval).await;
+                if sent > 0 {
+                    tracing::trace!("Sent cover traffic on {} idle tunnels", sent);
+                }
+            }
+        });
+    }
+
+    /// Rekey every established tunnel whose keys have been in use for the
+    /// rekey interval, returning how many were rekeyed
+    pub async fn rekey_due(&self) -> usize {
+        let Some(rekey_after) = self.rekey_after else {
+            return 0;
+        };
+        let now = self.clock().now_monotonic();
+        let due: Vec<TunnelId> = self
+            .tunnels
+            .read()
+            .await
+            .values()
+            .filter(|tunnel| {
+                matches!(tunnel.status, TunnelStatus::Established)
+                    && now.saturating_duration_since(tunnel.keyed_at) >= rekey_after
+            })
+            .map(|tunnel| tunnel.tunnel_id)
+            .collect();
+
+        let mut rekeyed = 0;
+        for tunnel_id in due {
+            match self.rekey_tunnel(&tunnel_id).await {
+                Ok(()) => rekeyed += 1,
+                Err(e) => tracing::warn!("Scheduled rekey of tunnel {} failed: {}", tunnel_id, e),
+            }
+        }
+        rekeyed
+    }
+
+    /// Rekey tunnels in the background as their keys age out; nothing
+    /// without a rekey interval
+    pub fn spawn_rekeying(self: &Arc<Self>) {
+        let Some(rekey_after) = self.rekey_after else {
+            return;
+        };
+        let manager = Arc::clone(self);
+        crash::spawn(Subsystem::Ike, "tunnel-rekey", async move {
+            // Tunnels are rekeyed at most a tenth of the interval late
+            let period =
+                (rekey_after / 10).clamp(Duration::from_millis(10), Duration::from_secs(60));
+            let mut ticker = clock::interval(manager.clock(), period);
+            loop {
+                ticker.tick().await;
+                manager.rekey_due().await;
+            }
+        });
+    }
+
+    /// Journal a fresh reservation for every tunnel now, e.g. before a
+    /// controlled stop
+    pub async fn flush_nonce_journal(&self) -> Result<(), IKEError> {
+        self.write_nonce_journal().await
+    }
+
+    /// Top up nonce reservations in the background so sends rarely wait
+    /// on the journal
+    pub fn spawn_nonce_journal_flush(self: &Arc<Self>) {
+        let Some(interval) = self
+            .journal
+            .as_ref()
+            .map(|journal| lock(journal).flush_interval())
+        else {
+            return;
+        };
+        let manager = Arc::clone(self);
+        crash::spawn(Subsystem::Ike, "nonce-journal-flush", async move {
+            let mut ticker =
+                clock::interval(manager.clock(), interval.max(Duration::from_millis(10)));
+            loop {
+                ticker.tick().await;
+                let Some(journal) = &manager.journal else {
+                    return;
+                };
+                let due = lock(journal).flush_due(manager.clock().now_monotonic());
+                if due {
+                    if let Err(e) = manager.write_nonce_journal().await {
+                        tracing::warn!("Failed to write nonce journal: {}", e);
+                    }
+                }
+            }
+        });
     }
 
     pub async fn cleanup_failed_tunnels(&self) {
@@ -195,12 +1187,32 @@ impl TunnelManager {
             .collect();
 
         for tunnel_id in failed_tunnels {
+            self.forget_nonces(&tunnel_id);
+            lock(&self.reassembly).remove(&tunnel_id);
             tunnels.remove(&tunnel_id);
             tracing::info!("Cleaned up failed tunnel {}", tunnel_id);
         }
     }
 }
 
+/// Any local address of the family that reaches `endpoint`
+fn unspecified(endpoint: SocketAddr) -> SocketAddr {
+    match endpoint {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    }
+}
+
+impl IPSecTunnel {
+    fn status_change(&self) -> TunnelStatusChanged {
+        TunnelStatusChanged {
+            tunnel_id: self.tunnel_id,
+            remote_addr: self.remote_addr,
+            status: self.status,
+        }
+    }
+}
+
 impl Default for TrafficStats {
     fn default() -> Self {
         Self::new()
@@ -214,7 +1226,10 @@ impl TrafficStats {
             bytes_out: 0,
             packets_in: 0,
             packets_out: 0,
+            overhead_bytes_in: 0,
+            overhead_bytes_out: 0,
             last_activity: chrono::Utc::now(),
+            queues: Vec::new(),
         }
     }
 }
@@ -224,3 +1239,221 @@ impl Default for TunnelManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ike::journal::Resume;
+
+    async fn tunnel(manager: &TunnelManager) -> TunnelId {
+        manager
+            .create_tunnel(
+                "10.2.0.1".parse().unwrap(),
+                "10.1.0.1".parse().unwrap(),
+                "10.1.0.1:4500".parse().unwrap(),
+                b"test-psk",
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_status_changes_are_followed() {
+        let manager = TunnelManager::new();
+        let first = tunnel(&manager).await;
+        let (current, mut changes) = manager.follow_status().await;
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].status, TunnelStatus::Established);
+
+        let second = tunnel(&manager).await;
+        manager.mark_failed(&first).await.unwrap();
+        manager.close_tunnel(&first).await.unwrap();
+        let seen: Vec<(TunnelId, TunnelStatus)> = (0..3)
+            .map(|_| changes.try_recv().unwrap())
+            .map(|change| (change.tunnel_id, change.status))
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                (second, TunnelStatus::Established),
+                (first, TunnelStatus::Failed),
+                (first, TunnelStatus::Closed),
+            ]
+        );
+        assert!(changes.try_recv().is_err());
+    }
+
+    fn padded() -> TunnelManager {
+        TunnelManager::new().with_padding(Padding::new(vec![256, 512, 1024, 1400]))
+    }
+
+    #[tokio::test]
+    async fn test_padded_tunnel_roundtrip_and_overhead() {
+        let (sender, receiver) = (padded(), padded());
+        let (out, into) = (tunnel(&sender).await, tunnel(&receiver).await);
+        assert!(sender.negotiate_padding(&out, true).await);
+        assert!(receiver.negotiate_padding(&into, true).await);
+
+        let payload = vec![7u8; 300];
+        let wire = sender.send_packet(&out, &payload).await.unwrap();
+        assert_eq!(wire.len(), 512);
+        assert_eq!(
+            receiver.receive_packet(&into, &wire).await.unwrap(),
+            payload
+        );
+
+        let sent = sender.get_tunnel_stats(&out).await.unwrap();
+        assert_eq!((sent.bytes_out, sent.overhead_bytes_out), (512, 212));
+        let received = receiver.get_tunnel_stats(&into).await.unwrap();
+        assert_eq!((received.bytes_in, received.overhead_bytes_in), (512, 212));
+
+        // Cover frames are counted as overhead and handed to nobody
+        assert_eq!(sender.send_cover_traffic(Duration::ZERO).await, 1);
+        let sent = sender.get_tunnel_stats(&out).await.unwrap();
+        assert_eq!((sent.bytes_out, sent.overhead_bytes_out), (768, 468));
+        let cover = Padding::new(vec![256])
+            .unwrap()
+            .frame(FrameKind::Cover, &[]);
+        let last_activity = received.last_activity;
+        assert!(receiver
+            .receive_packet(&into, &cover)
+            .await
+            .unwrap()
+            .is_empty());
+        let received = receiver.get_tunnel_stats(&into).await.unwrap();
+        assert_eq!(received.overhead_bytes_in, 212 + 256);
+        assert_eq!(received.last_activity, last_activity);
+
+        // A busy tunnel gets no cover traffic
+        assert_eq!(sender.send_cover_traffic(Duration::from_secs(60)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sends_are_journaled_and_rekey_restarts_the_count() {
+        let dir = std::env::temp_dir().join(format!("vx0net-tunnels-{}", Uuid::new_v4()));
+        let config = crate::config::NonceJournalConfig {
+            path: dir.join("nonces.json").to_string_lossy().into_owned(),
+            margin: 8,
+            flush_interval_ms: 60_000,
+        };
+        let manager =
+            Arc::new(TunnelManager::new().with_nonce_journal(NonceJournal::open(&config)));
+        let id = tunnel(&manager).await;
+        for _ in 0..3 {
+            manager.send_packet(&id, b"data").await.unwrap();
+        }
+        assert_eq!(manager.next_nonce(&id).await.unwrap(), Some(3));
+
+        // Sends racing across several blocks each get a counter of their own
+        let sends: Vec<_> = (0..20)
+            .map(|_| {
+                let manager = Arc::clone(&manager);
+                tokio::spawn(async move { manager.next_nonce(&id).await.unwrap().unwrap() })
+            })
+            .collect();
+        let mut counters = Vec::new();
+        for send in sends {
+            counters.push(send.await.unwrap());
+        }
+        counters.sort_unstable();
+        assert_eq!(counters, (4..24).collect::<Vec<u64>>());
+        let mut reopened = NonceJournal::open(&config).unwrap();
+        let session = manager.get_tunnel(&id).await.unwrap().ike_session;
+        let Resume::From(resumed) = reopened.resume(id, journal::key_id(&session.encryption_key))
+        else {
+            panic!("the journal must cover the tunnel's keys");
+        };
+        assert!(resumed >= 24);
+
+        manager.rekey_tunnel(&id).await.unwrap();
+        assert_eq!(manager.next_nonce(&id).await.unwrap(), Some(0));
+
+        manager.close_tunnel(&id).await.unwrap();
+        assert!(matches!(
+            manager.next_nonce(&id).await,
+            Err(IKEError::RekeyRequired { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_peer_without_padding_gets_plain_payloads() {
+        let (ours, theirs) = (padded(), TunnelManager::new());
+        let (out, into) = (tunnel(&ours).await, tunnel(&theirs).await);
+        assert!(!ours.negotiate_padding(&out, false).await);
+        assert!(!theirs.negotiate_padding(&into, true).await);
+
+        let wire = ours.send_packet(&out, b"hello").await.unwrap();
+        assert_eq!(wire, b"hello");
+        assert_eq!(theirs.receive_packet(&into, &wire).await.unwrap(), b"hello");
+        assert_eq!(ours.send_cover_traffic(Duration::ZERO).await, 0);
+        assert_eq!(
+            ours.get_tunnel_stats(&out)
+                .await
+                .unwrap()
+                .overhead_bytes_out,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_takes_the_smaller_mtu_and_fragments_above_it() {
+        let sender = TunnelManager::new().with_mtu(1400, Duration::from_secs(600));
+        let receiver = TunnelManager::new().with_mtu(1280, Duration::from_secs(600));
+        let (out, into) = (tunnel(&sender).await, tunnel(&receiver).await);
+        assert_eq!(sender.negotiate_mtu(&out, Some(1280)).await, 1280);
+        assert_eq!(receiver.negotiate_mtu(&into, Some(1400)).await, 1280);
+        // A peer that does not advertise one leaves ours
+        let other = tunnel(&sender).await;
+        assert_eq!(sender.negotiate_mtu(&other, None).await, 1400);
+
+        let datagram: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let wire = sender.send_datagram(&out, &datagram).await.unwrap();
+        assert_eq!(wire.len(), 3);
+        assert!(wire.iter().all(|fragment| fragment.len() <= 1280));
+        let mut delivered = Vec::new();
+        for fragment in wire.iter().rev() {
+            delivered.extend(receiver.receive_datagram(&into, fragment).await.unwrap());
+        }
+        assert_eq!(delivered, vec![datagram]);
+
+        // Without fragmentation, oversized datagrams are refused
+        let strict = TunnelManager::new()
+            .with_mtu(1400, Duration::from_secs(600))
+            .with_fragmentation(false);
+        let id = tunnel(&strict).await;
+        strict.negotiate_mtu(&id, Some(1280)).await;
+        assert!(matches!(
+            strict.send_datagram(&id, &[0u8; 1275]).await,
+            Err(IKEError::DatagramTooLarge { mtu: 1280, .. })
+        ));
+        assert_eq!(
+            strict.send_datagram(&id, &[0u8; 1274]).await.unwrap().len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probed_path_lowers_the_tunnel_mtu() {
+        struct Cutoff(usize);
+
+        #[async_trait::async_trait]
+        impl PathProbe for Cutoff {
+            async fn echo(&self, _peer: IpAddr, size: usize) -> bool {
+                size <= self.0
+            }
+        }
+
+        let manager = TunnelManager::new().with_mtu(1400, Duration::from_secs(600));
+        let id = tunnel(&manager).await;
+        manager.negotiate_mtu(&id, Some(1380)).await;
+        assert_eq!(manager.probe_mtu(&id, &Cutoff(1312)).await.unwrap(), 1312);
+        // Renegotiating keeps the probed limit, and fragments stay within it
+        assert_eq!(manager.negotiate_mtu(&id, Some(1400)).await, 1312);
+        let wire = manager.send_datagram(&id, &[1u8; 2000]).await.unwrap();
+        assert!(wire.iter().all(|fragment| fragment.len() <= 1312));
+
+        // A fresh result is not probed again until it ages out
+        assert_eq!(manager.revalidate_mtus(&Cutoff(1400)).await, 0);
+        assert_eq!(manager.get_tunnel(&id).await.unwrap().mtu, 1312);
+    }
+}
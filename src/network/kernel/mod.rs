@@ -0,0 +1,441 @@
+//! Learned routes in the Linux routing table.
+//!
+//! A node acting as the gateway for a LAN can install the Loc-RIB's best
+//! routes into a dedicated kernel table, so hosts behind it reach .vx0
+//! services without running the daemon. Only routes whose next hop is behind
+//! one of the configured tunnel interfaces are installed, as device routes
+//! on that interface. Every entry carries our route protocol marker, which is
+//! how a restart finds and removes what a crashed run left behind.
+//!
+//! The kernel table is a convenience layered on the overlay: a route that
+//! cannot be installed (no permission, interface missing) is logged and
+//! skipped, and overlay routing carries on regardless.
+
+use crate::config::KernelRoutesConfig;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::bgp::rib::RibChange;
+use crate::network::bgp::{BGPDaemon, Prefix, RouteEntry};
+use async_trait::async_trait;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+#[cfg(feature = "kernel_routes")]
+pub mod netlink;
+
+/// An entry in our kernel table
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KernelRoute {
+    pub destination: Prefix,
+    /// Tunnel interface the destination is reached over
+    pub interface: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KernelError {
+    #[error("Cannot open a netlink socket")]
+    Unavailable(#[source] std::io::Error),
+    #[error("Interface {interface} does not exist")]
+    InterfaceNotFound { interface: String },
+    #[error("Kernel refused to {operation} {destination}: {reason}")]
+    Refused {
+        operation: &'static str,
+        destination: Prefix,
+        reason: String,
+    },
+    #[error("Cannot list kernel routes: {0}")]
+    List(String),
+}
+
+/// Where kernel routes are written; netlink in production, a recording
+/// stand-in in tests
+#[async_trait]
+pub trait RouteSink: Send + Sync {
+    /// Entries in our table that carry our protocol marker
+    async fn list(&self) -> Result<Vec<KernelRoute>, KernelError>;
+
+    /// Install `route`, replacing any entry for the same destination
+    async fn replace(&self, route: &KernelRoute) -> Result<(), KernelError>;
+
+    async fn remove(&self, route: &KernelRoute) -> Result<(), KernelError>;
+}
+
+impl KernelRoutesConfig {
+    /// The kernel entry for a Loc-RIB route, if it should have one: learned
+    /// routes whose next hop is behind a configured tunnel interface
+    pub fn translate(&self, route: &RouteEntry) -> Option<KernelRoute> {
+        // Our own prefixes are already reachable from this host
+        if route.as_path.is_empty() {
+            return None;
+        }
+        let interface = self.interfaces.iter().find(|interface| {
+            let same_family = matches!(
+                (interface.network, route.next_hop),
+                (IpNet::V4(_), IpAddr::V4(_)) | (IpNet::V6(_), IpAddr::V6(_))
+            );
+            same_family && interface.network.contains(&route.next_hop)
+        })?;
+        Some(KernelRoute {
+            destination: route.network,
+            interface: interface.name.clone(),
+        })
+    }
+}
+
+/// What one reconciliation changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Stale entries removed, including any left by an earlier run
+    pub removed: usize,
+    pub installed: usize,
+    /// Entries that could not be installed or removed
+    pub failed: usize,
+}
+
+/// Keeps the kernel table in step with the Loc-RIB
+pub struct KernelRouteSync {
+    config: KernelRoutesConfig,
+    sink: Arc<dyn RouteSink>,
+    /// What we installed, by destination; also serializes kernel writes
+    installed: Mutex<HashMap<Prefix, KernelRoute>>,
+}
+
+impl KernelRouteSync {
+    pub fn new(config: KernelRoutesConfig, sink: Arc<dyn RouteSink>) -> Self {
+        KernelRouteSync {
+            config,
+            sink,
+            installed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn installed(&self) -> Vec<KernelRoute> {
+        self.installed.lock().await.values().cloned().collect()
+    }
+
+    /// Make our table hold exactly the entries `routes` translate to,
+    /// removing anything else carrying our marker
+    pub async fn reconcile(&self, routes: &[RouteEntry]) -> Reconciliation {
+        let mut installed = self.installed.lock().await;
+        let present = match self.sink.list().await {
+            Ok(present) => present,
+            Err(e) => {
+                tracing::warn!("{}; assuming only our own entries are present", e);
+                installed.values().cloned().collect()
+            }
+        };
+        let wanted: HashMap<Prefix, KernelRoute> = routes
+            .iter()
+            .filter_map(|route| self.config.translate(route))
+            .map(|route| (route.destination, route))
+            .collect();
+
+        let mut outcome = Reconciliation::default();
+        for route in &present {
+            if wanted.get(&route.destination) == Some(route) {
+                continue;
+            }
+            match self.sink.remove(route).await {
+                Ok(()) => outcome.removed += 1,
+                Err(e) => {
+                    tracing::warn!("Leaving stale kernel route: {}", e);
+                    outcome.failed += 1;
+                }
+            }
+        }
+
+        installed.clear();
+        for (destination, route) in wanted {
+            if !present.contains(&route) {
+                if let Err(e) = self.sink.replace(&route).await {
+                    tracing::warn!("Not installing kernel route: {}", e);
+                    outcome.failed += 1;
+                    continue;
+                }
+                outcome.installed += 1;
+            }
+            installed.insert(destination, route);
+        }
+        outcome
+    }
+
+    /// Follow one Loc-RIB change
+    pub async fn apply(&self, change: &RibChange) {
+        let mut installed = self.installed.lock().await;
+        let (destination, wanted) = match change {
+            RibChange::Advertise(route) => (route.network, self.config.translate(route)),
+            RibChange::Withdraw(network) => (*network, None),
+        };
+        let current = installed.get(&destination);
+        if current == wanted.as_ref() {
+            return;
+        }
+        match wanted {
+            Some(route) => match self.sink.replace(&route).await {
+                Ok(()) => {
+                    installed.insert(destination, route);
+                }
+                Err(e) => {
+                    tracing::warn!("Not installing kernel route: {}", e);
+                    // Whatever was there before now points the wrong way
+                    if let Some(stale) = installed.remove(&destination) {
+                        let _ = self.sink.remove(&stale).await;
+                    }
+                }
+            },
+            None => {
+                if let Some(stale) = installed.remove(&destination) {
+                    if let Err(e) = self.sink.remove(&stale).await {
+                        tracing::warn!("Leaving stale kernel route: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove everything we installed, on shutdown; returns how many
+    /// entries were removed
+    pub async fn clear(&self) -> usize {
+        let mut installed = self.installed.lock().await;
+        let mut removed = 0;
+        for (_, route) in installed.drain() {
+            match self.sink.remove(&route).await {
+                Ok(()) => removed += 1,
+                Err(e) => tracing::warn!("Leaving kernel route behind: {}", e),
+            }
+        }
+        removed
+    }
+
+    /// Reconcile against the Loc-RIB, then follow its changes until the
+    /// returned task is aborted
+    pub fn start(self: Arc<Self>, bgp: Arc<BGPDaemon>) -> JoinHandle<Option<()>> {
+        crash::spawn(Subsystem::Kernel, "kernel-routes", async move {
+            let (routes, mut changes) = bgp.follow_routes().await;
+            let outcome = self.reconcile(&routes).await;
+            tracing::info!(
+                "Kernel table {}: {} routes installed, {} stale removed, {} failed",
+                self.config.table,
+                outcome.installed,
+                outcome.removed,
+                outcome.failed
+            );
+            loop {
+                match changes.recv().await {
+                    Ok(change) => self.apply(&change).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(
+                            "Missed {} RIB changes for the kernel table; resyncing",
+                            missed
+                        );
+                        self.reconcile(&bgp.get_routes().await).await;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KernelInterface;
+    use crate::network::bgp::testing::route;
+    use std::collections::HashSet;
+    use std::sync::Mutex as StdMutex;
+
+    /// A kernel table in memory; destinations in `refuse` cannot be written
+    #[derive(Default)]
+    struct FakeKernel {
+        table: StdMutex<HashSet<KernelRoute>>,
+        refuse: StdMutex<HashSet<Prefix>>,
+    }
+
+    impl FakeKernel {
+        fn routes(&self) -> HashSet<KernelRoute> {
+            self.table.lock().unwrap().clone()
+        }
+
+        fn refused(&self, route: &KernelRoute) -> Result<(), KernelError> {
+            if self.refuse.lock().unwrap().contains(&route.destination) {
+                return Err(KernelError::InterfaceNotFound {
+                    interface: route.interface.clone(),
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl RouteSink for FakeKernel {
+        async fn list(&self) -> Result<Vec<KernelRoute>, KernelError> {
+            Ok(self.routes().into_iter().collect())
+        }
+
+        async fn replace(&self, route: &KernelRoute) -> Result<(), KernelError> {
+            self.refused(route)?;
+            let mut table = self.table.lock().unwrap();
+            table.retain(|entry| entry.destination != route.destination);
+            table.insert(route.clone());
+            Ok(())
+        }
+
+        async fn remove(&self, route: &KernelRoute) -> Result<(), KernelError> {
+            self.refused(route)?;
+            self.table.lock().unwrap().remove(route);
+            Ok(())
+        }
+    }
+
+    fn config() -> KernelRoutesConfig {
+        KernelRoutesConfig {
+            enabled: true,
+            interfaces: vec![
+                KernelInterface {
+                    name: "vx0tun0".to_string(),
+                    network: "10.100.0.0/24".parse().unwrap(),
+                },
+                KernelInterface {
+                    name: "vx0tun1".to_string(),
+                    network: "fd00:100::/64".parse().unwrap(),
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn entry(destination: &str, interface: &str) -> KernelRoute {
+        KernelRoute {
+            destination: destination.parse().unwrap(),
+            interface: interface.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_translation_picks_tunnel_interface() {
+        let config = config();
+        assert_eq!(
+            config.translate(&route("10.20.0.0/16", "10.100.0.7", &[65002])),
+            Some(entry("10.20.0.0/16", "vx0tun0"))
+        );
+        assert_eq!(
+            config.translate(&route("fd00:20::/48", "fd00:100::7", &[65002])),
+            Some(entry("fd00:20::/48", "vx0tun1"))
+        );
+        // Next hop behind no tunnel interface, or our own prefix
+        assert_eq!(
+            config.translate(&route("10.30.0.0/16", "192.168.1.1", &[65002])),
+            None
+        );
+        assert_eq!(
+            config.translate(&route("10.40.0.0/16", "10.100.0.1", &[])),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_removes_stale_and_installs_missing() {
+        let kernel = Arc::new(FakeKernel::default());
+        // Left by a crashed run: one still wanted, two not
+        for stale in [
+            entry("10.20.0.0/16", "vx0tun0"),
+            entry("10.99.0.0/16", "vx0tun0"),
+            entry("10.21.0.0/16", "vx0tun9"),
+        ] {
+            kernel.table.lock().unwrap().insert(stale);
+        }
+        let sync = KernelRouteSync::new(config(), kernel.clone());
+
+        let outcome = sync
+            .reconcile(&[
+                route("10.20.0.0/16", "10.100.0.7", &[65002]),
+                route("10.21.0.0/16", "10.100.0.8", &[65003]),
+                route("10.22.0.0/16", "10.100.0.8", &[65003]),
+                route("10.30.0.0/16", "192.168.1.1", &[65004]),
+            ])
+            .await;
+        assert_eq!(
+            outcome,
+            Reconciliation {
+                removed: 2,
+                installed: 2,
+                failed: 0
+            }
+        );
+        let expected: HashSet<KernelRoute> = [
+            entry("10.20.0.0/16", "vx0tun0"),
+            entry("10.21.0.0/16", "vx0tun0"),
+            entry("10.22.0.0/16", "vx0tun0"),
+        ]
+        .into();
+        assert_eq!(kernel.routes(), expected);
+        assert_eq!(sync.installed().await.len(), 3);
+
+        // Nothing to do the second time round
+        let again = sync
+            .reconcile(&[
+                route("10.20.0.0/16", "10.100.0.7", &[65002]),
+                route("10.21.0.0/16", "10.100.0.8", &[65003]),
+                route("10.22.0.0/16", "10.100.0.8", &[65003]),
+            ])
+            .await;
+        assert_eq!(again, Reconciliation::default());
+    }
+
+    #[tokio::test]
+    async fn test_changes_followed_and_cleared_on_shutdown() {
+        let kernel = Arc::new(FakeKernel::default());
+        let sync = KernelRouteSync::new(config(), kernel.clone());
+
+        sync.apply(&RibChange::Advertise(route(
+            "10.20.0.0/16",
+            "10.100.0.7",
+            &[65002],
+        )))
+        .await;
+        sync.apply(&RibChange::Advertise(route(
+            "10.21.0.0/16",
+            "10.100.0.7",
+            &[65002],
+        )))
+        .await;
+        assert_eq!(kernel.routes().len(), 2);
+
+        // Replaced by a path whose next hop is off the tunnels
+        sync.apply(&RibChange::Advertise(route(
+            "10.21.0.0/16",
+            "192.168.1.1",
+            &[65004],
+        )))
+        .await;
+        sync.apply(&RibChange::Withdraw("10.20.0.0/16".parse().unwrap()))
+            .await;
+        assert!(kernel.routes().is_empty());
+
+        // A refused install is skipped without disturbing the rest
+        kernel
+            .refuse
+            .lock()
+            .unwrap()
+            .insert("10.22.0.0/16".parse().unwrap());
+        for network in ["10.22.0.0/16", "10.23.0.0/16", "10.24.0.0/16"] {
+            sync.apply(&RibChange::Advertise(route(
+                network,
+                "10.100.0.7",
+                &[65002],
+            )))
+            .await;
+        }
+        assert_eq!(kernel.routes().len(), 2);
+        assert_eq!(sync.installed().await.len(), 2);
+
+        assert_eq!(sync.clear().await, 2);
+        assert!(kernel.routes().is_empty());
+        assert!(sync.installed().await.is_empty());
+    }
+}
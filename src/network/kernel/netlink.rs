@@ -0,0 +1,228 @@
+//! Kernel routes over rtnetlink.
+
+use super::{KernelError, KernelRoute, RouteSink};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::bgp::Prefix;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use netlink_packet_route::nlas::link::Nla as LinkNla;
+use netlink_packet_route::nlas::route::Nla;
+use netlink_packet_route::{
+    RouteMessage, AF_INET, AF_INET6, RTN_UNICAST, RT_SCOPE_LINK, RT_TABLE_COMPAT,
+};
+use rtnetlink::{Handle, IpVersion};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub struct NetlinkSink {
+    handle: Handle,
+    table: u32,
+    protocol: u8,
+}
+
+impl NetlinkSink {
+    /// Open a netlink socket; entries go into `table` marked with `protocol`
+    pub fn connect(table: u32, protocol: u8) -> Result<Self, KernelError> {
+        let (connection, handle, _) =
+            rtnetlink::new_connection().map_err(KernelError::Unavailable)?;
+        crash::spawn(Subsystem::Kernel, "netlink", connection);
+        Ok(NetlinkSink {
+            handle,
+            table,
+            protocol,
+        })
+    }
+
+    async fn interface_index(&self, name: &str) -> Result<u32, KernelError> {
+        let link = self
+            .handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute()
+            .try_next()
+            .await;
+        match link {
+            Ok(Some(link)) => Ok(link.header.index),
+            _ => Err(KernelError::InterfaceNotFound {
+                interface: name.to_string(),
+            }),
+        }
+    }
+
+    async fn interface_names(&self) -> Result<HashMap<u32, String>, KernelError> {
+        let links: Vec<_> = self
+            .handle
+            .link()
+            .get()
+            .execute()
+            .try_collect()
+            .await
+            .map_err(|e| KernelError::List(e.to_string()))?;
+        Ok(links
+            .into_iter()
+            .filter_map(|link| {
+                link.nlas.iter().find_map(|nla| match nla {
+                    LinkNla::IfName(name) => Some((link.header.index, name.clone())),
+                    _ => None,
+                })
+            })
+            .collect())
+    }
+}
+
+/// The netlink message for a device route to `route.destination`
+pub fn route_message(route: &KernelRoute, index: u32, table: u32, protocol: u8) -> RouteMessage {
+    let mut message = RouteMessage::default();
+    let (family, destination) = match route.destination.net() {
+        IpNet::V4(net) => (AF_INET, net.network().octets().to_vec()),
+        IpNet::V6(net) => (AF_INET6, net.network().octets().to_vec()),
+    };
+    message.header.address_family = family as u8;
+    message.header.destination_prefix_length = route.destination.prefix_len();
+    message.header.protocol = protocol;
+    message.header.scope = RT_SCOPE_LINK;
+    message.header.kind = RTN_UNICAST;
+    if table > 255 {
+        message.header.table = RT_TABLE_COMPAT;
+        message.nlas.push(Nla::Table(table));
+    } else {
+        message.header.table = table as u8;
+    }
+    message.nlas.push(Nla::Destination(destination));
+    message.nlas.push(Nla::Oif(index));
+    message
+}
+
+/// Our entry a listed message describes, if it is one
+pub fn kernel_route(
+    message: &RouteMessage,
+    names: &HashMap<u32, String>,
+    table: u32,
+    protocol: u8,
+) -> Option<KernelRoute> {
+    let in_table = message
+        .nlas
+        .iter()
+        .find_map(|nla| match nla {
+            Nla::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(u32::from(message.header.table));
+    if in_table != table || message.header.protocol != protocol {
+        return None;
+    }
+    // A default route has no destination attribute
+    let length = message.header.destination_prefix_length;
+    let (address, length) = message.destination_prefix().unwrap_or_else(|| {
+        if u16::from(message.header.address_family) == AF_INET6 {
+            (IpAddr::V6(Ipv6Addr::UNSPECIFIED), length)
+        } else {
+            (IpAddr::V4(Ipv4Addr::UNSPECIFIED), length)
+        }
+    });
+    let destination = IpNet::new(address, length).ok()?;
+    // An interface that has since gone away still names the entry, so it
+    // can be removed
+    let index = message.output_interface()?;
+    Some(KernelRoute {
+        destination: Prefix::new(destination),
+        interface: names
+            .get(&index)
+            .cloned()
+            .unwrap_or_else(|| format!("#{}", index)),
+    })
+}
+
+#[async_trait]
+impl RouteSink for NetlinkSink {
+    async fn list(&self) -> Result<Vec<KernelRoute>, KernelError> {
+        let names = self.interface_names().await?;
+        let mut routes = Vec::new();
+        for version in [IpVersion::V4, IpVersion::V6] {
+            let messages: Vec<RouteMessage> = self
+                .handle
+                .route()
+                .get(version)
+                .execute()
+                .try_collect()
+                .await
+                .map_err(|e| KernelError::List(e.to_string()))?;
+            routes.extend(
+                messages
+                    .iter()
+                    .filter_map(|message| kernel_route(message, &names, self.table, self.protocol)),
+            );
+        }
+        Ok(routes)
+    }
+
+    async fn replace(&self, route: &KernelRoute) -> Result<(), KernelError> {
+        let index = self.interface_index(&route.interface).await?;
+        let mut request = self.handle.route().add().replace();
+        *request.message_mut() = route_message(route, index, self.table, self.protocol);
+        request.execute().await.map_err(|e| KernelError::Refused {
+            operation: "install",
+            destination: route.destination,
+            reason: e.to_string(),
+        })
+    }
+
+    async fn remove(&self, route: &KernelRoute) -> Result<(), KernelError> {
+        // Without the interface the kernel still matches on destination,
+        // table and protocol
+        let index = self.interface_index(&route.interface).await.unwrap_or(0);
+        let mut message = route_message(route, index, self.table, self.protocol);
+        if index == 0 {
+            message.nlas.retain(|nla| !matches!(nla, Nla::Oif(_)));
+        }
+        self.handle
+            .route()
+            .del(message)
+            .execute()
+            .await
+            .map_err(|e| KernelError::Refused {
+                operation: "remove",
+                destination: route.destination,
+                reason: e.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_messages_round_trip_and_foreign_entries_ignored() {
+        let names = HashMap::from([(7, "vx0tun0".to_string())]);
+        for (destination, table) in [
+            ("10.20.0.0/16", 200),
+            ("fd00:20::/48", 4200),
+            ("0.0.0.0/0", 4200),
+        ] {
+            let route = KernelRoute {
+                destination: destination.parse().unwrap(),
+                interface: "vx0tun0".to_string(),
+            };
+            let mut message = route_message(&route, 7, table, 190);
+            if route.destination.prefix_len() == 0 {
+                // As the kernel lists a default route
+                message
+                    .nlas
+                    .retain(|nla| !matches!(nla, Nla::Destination(_)));
+            }
+            assert_eq!(
+                kernel_route(&message, &names, table, 190),
+                Some(route.clone())
+            );
+            assert_eq!(kernel_route(&message, &names, table, 4), None);
+            assert_eq!(kernel_route(&message, &names, 254, 190), None);
+            assert_eq!(
+                kernel_route(&message, &HashMap::new(), table, 190).map(|r| r.interface),
+                Some("#7".to_string())
+            );
+        }
+    }
+}
@@ -1,3 +1,10 @@
+pub mod acl;
+pub mod adaptive;
 pub mod bgp;
 pub mod dns;
+pub mod firewall;
 pub mod ike;
+pub mod kernel;
+pub mod obfuscation;
+pub mod prefix_list;
+pub mod transport;
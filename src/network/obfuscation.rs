@@ -0,0 +1,258 @@
+//! Making tunnel traffic harder to fingerprint.
+//!
+//! Fixed keepalive intervals and exact packet sizes let an observer tell
+//! what a tunnel carries without decrypting it. Three independent measures,
+//! all off by default, blur that: keepalive timers are jittered by a random
+//! fraction each round, tunnel payloads are padded up to the next of a few
+//! bucket sizes, and idle tunnels can send cover frames that the receiver
+//! drops. Padding changes the tunnel framing, so it is only used once both
+//! ends advertise the `padding` capability; a tunnel to a peer that does not
+//! carries payloads exactly as before.
+//!
+//! A padded frame is a 3-byte header (frame kind, then the pad length as a
+//! big-endian u16), the payload, and that many zero bytes. The header is
+//! framed before encryption so the pad length is never visible on the wire.
+
+use crate::config::ObfuscationConfig;
+use rand::Rng;
+use std::time::Duration;
+
+/// Largest share of an interval that jitter may add or take away; beyond
+/// this a jittered keepalive could outlast the hold time
+pub const MAX_JITTER_PERCENT: u8 = 50;
+
+/// Bytes of framing in front of every padded payload
+pub const FRAME_HEADER_LEN: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FrameError {
+    #[error("Frame of {len} bytes is shorter than its header")]
+    Truncated { len: usize },
+    #[error("Unknown frame kind {kind}")]
+    UnknownKind { kind: u8 },
+    #[error("Frame of {len} bytes cannot hold {pad} bytes of padding")]
+    BadPadLength { len: usize, pad: usize },
+}
+
+/// Randomizes timer intervals by up to a fixed fraction either way
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Jitter {
+    fraction: f64,
+}
+
+impl Jitter {
+    /// Up to `percent` either way, capped at `MAX_JITTER_PERCENT`
+    pub fn percent(percent: u8) -> Self {
+        Jitter {
+            fraction: f64::from(percent.min(MAX_JITTER_PERCENT)) / 100.0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.fraction > 0.0
+    }
+
+    /// `base` moved by a uniformly random amount within the jitter range
+    pub fn apply(&self, base: Duration) -> Duration {
+        if !self.is_enabled() {
+            return base;
+        }
+        let factor = 1.0 + rand::thread_rng().gen_range(-self.fraction..=self.fraction);
+        base.mul_f64(factor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Data = 0,
+    /// Cover traffic; dropped by the receiver
+    Cover = 1,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = FrameError;
+
+    fn try_from(kind: u8) -> Result<Self, FrameError> {
+        match kind {
+            0 => Ok(FrameKind::Data),
+            1 => Ok(FrameKind::Cover),
+            kind => Err(FrameError::UnknownKind { kind }),
+        }
+    }
+}
+
+/// Pads tunnel payloads up to bucket sizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Padding {
+    /// Ascending frame sizes
+    buckets: Vec<usize>,
+}
+
+impl Padding {
+    /// `None` when no buckets are configured, i.e. padding is off
+    pub fn new(mut buckets: Vec<usize>) -> Option<Self> {
+        buckets.retain(|&size| size > FRAME_HEADER_LEN);
+        buckets.sort_unstable();
+        buckets.dedup();
+        (!buckets.is_empty()).then_some(Padding { buckets })
+    }
+
+    /// Size of the frame carrying `payload_len` bytes: the smallest bucket
+    /// it fits in, or no padding at all when it fits none
+    pub fn bucket_for(&self, payload_len: usize) -> usize {
+        let framed = payload_len + FRAME_HEADER_LEN;
+        self.buckets
+            .iter()
+            .copied()
+            .find(|&bucket| bucket >= framed && bucket - framed <= usize::from(u16::MAX))
+            .unwrap_or(framed)
+    }
+
+    /// Largest payload whose frame is no longer than `limit`; 0 when every
+    /// bucket is longer
+    pub fn room(&self, limit: usize) -> usize {
+        if self.buckets.iter().all(|&bucket| bucket <= limit) {
+            // Payloads too large for every bucket go unpadded
+            return limit.saturating_sub(FRAME_HEADER_LEN);
+        }
+        self.buckets
+            .iter()
+            .rev()
+            .find(|&&bucket| bucket <= limit)
+            .map_or(0, |bucket| bucket - FRAME_HEADER_LEN)
+    }
+
+    /// Smallest frame, used for cover traffic
+    pub fn smallest(&self) -> usize {
+        self.buckets[0]
+    }
+
+    pub fn frame(&self, kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+        let size = match kind {
+            FrameKind::Data => self.bucket_for(payload.len()),
+            FrameKind::Cover => self.smallest().max(payload.len() + FRAME_HEADER_LEN),
+        };
+        let pad = size - payload.len() - FRAME_HEADER_LEN;
+        let mut frame = Vec::with_capacity(size);
+        frame.push(kind as u8);
+        frame.extend_from_slice(&(pad as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.resize(size, 0);
+        frame
+    }
+}
+
+/// The kind and payload of a padded frame
+pub fn unframe(frame: &[u8]) -> Result<(FrameKind, &[u8]), FrameError> {
+    if frame.len() < FRAME_HEADER_LEN {
+        return Err(FrameError::Truncated { len: frame.len() });
+    }
+    let kind = FrameKind::try_from(frame[0])?;
+    let pad = usize::from(u16::from_be_bytes([frame[1], frame[2]]));
+    let end = frame
+        .len()
+        .checked_sub(pad)
+        .filter(|&end| end >= FRAME_HEADER_LEN)
+        .ok_or(FrameError::BadPadLength {
+            len: frame.len(),
+            pad,
+        })?;
+    Ok((kind, &frame[FRAME_HEADER_LEN..end]))
+}
+
+impl ObfuscationConfig {
+    pub fn keepalive_jitter(&self) -> Jitter {
+        Jitter::percent(self.keepalive_jitter_percent)
+    }
+
+    pub fn padding(&self) -> Option<Padding> {
+        Padding::new(self.padding_buckets.clone())
+    }
+
+    /// How long a padded tunnel may sit idle before cover traffic is sent
+    pub fn cover_traffic_interval(&self) -> Option<Duration> {
+        (self.cover_traffic_interval_secs > 0)
+            .then(|| Duration::from_secs(self.cover_traffic_interval_secs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buckets() -> Padding {
+        Padding::new(vec![1400, 256, 1024, 512]).unwrap()
+    }
+
+    #[test]
+    fn test_bucket_selection() {
+        let padding = buckets();
+        assert_eq!(padding.bucket_for(0), 256);
+        assert_eq!(padding.bucket_for(253), 256);
+        assert_eq!(padding.bucket_for(254), 512);
+        assert_eq!(padding.bucket_for(1000), 1024);
+        assert_eq!(padding.bucket_for(1397), 1400);
+        // Too big for any bucket: sent with a header and no padding
+        assert_eq!(padding.bucket_for(1500), 1503);
+
+        // The largest payload whose frame fits a limit
+        assert_eq!(padding.room(1300), 1021);
+        assert_eq!(padding.room(1400), 1397);
+        assert_eq!(padding.room(1500), 1497);
+        assert_eq!(padding.room(200), 0);
+        assert!(padding.bucket_for(padding.room(1300)) <= 1300);
+        assert_eq!(Padding::new(vec![]), None);
+        assert_eq!(ObfuscationConfig::default().padding(), None);
+    }
+
+    #[test]
+    fn test_padding_roundtrip() {
+        let padding = buckets();
+        for len in [0, 1, 100, 253, 254, 1397, 1398, 4000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = padding.frame(FrameKind::Data, &payload);
+            assert_eq!(frame.len(), padding.bucket_for(len));
+            assert_eq!(unframe(&frame).unwrap(), (FrameKind::Data, &payload[..]));
+        }
+
+        let cover = padding.frame(FrameKind::Cover, &[]);
+        assert_eq!(cover.len(), 256);
+        assert_eq!(unframe(&cover).unwrap(), (FrameKind::Cover, &[][..]));
+
+        assert_eq!(unframe(&[0, 0]), Err(FrameError::Truncated { len: 2 }));
+        assert_eq!(
+            unframe(&[7, 0, 0]),
+            Err(FrameError::UnknownKind { kind: 7 })
+        );
+        assert_eq!(
+            unframe(&[0, 0, 9, 1, 2]),
+            Err(FrameError::BadPadLength { len: 5, pad: 9 })
+        );
+    }
+
+    #[test]
+    fn test_jitter_stays_in_range_and_spreads() {
+        let base = Duration::from_secs(30);
+        assert_eq!(Jitter::default().apply(base), base);
+        assert_eq!(Jitter::percent(0).apply(base), base);
+        assert_eq!(Jitter::percent(90), Jitter::percent(MAX_JITTER_PERCENT));
+
+        let jitter = Jitter::percent(20);
+        let samples: Vec<f64> = (0..2000)
+            .map(|_| jitter.apply(base).as_secs_f64())
+            .collect();
+        assert!(samples.iter().all(|&s| (24.0..=36.0).contains(&s)));
+
+        // Spread across the whole range, centred on the base interval
+        let below = samples.iter().filter(|&&s| s < 27.0).count();
+        let above = samples.iter().filter(|&&s| s > 33.0).count();
+        assert!(
+            below > 300 && above > 300,
+            "{} below, {} above",
+            below,
+            above
+        );
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        assert!((mean - 30.0).abs() < 0.5, "mean {}", mean);
+    }
+}
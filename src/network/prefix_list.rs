@@ -0,0 +1,564 @@
+//! Prefix lists, as routers write them.
+//!
+//! One type answers every "is this prefix on the list" question (DNS
+//! clients, tier policy, address plan checks) so they all agree on what a
+//! match is. An entry is a prefix with optional length qualifiers:
+//!
+//! - `10.2.0.0/16` matches exactly `10.2.0.0/16`;
+//! - `10.2.0.0/16 le 24` matches it and anything inside it up to /24;
+//! - `10.0.0.0/8 ge 24` matches anything inside it from /24 down to hosts;
+//! - `10.0.0.0/8 ge 16 le 24` matches what is inside it from /16 to /24.
+//!
+//! [`PrefixList::matches`] applies the qualifiers. [`PrefixList::covers`]
+//! ignores them and asks only whether a prefix or address lies inside an
+//! entry, and [`PrefixList::within`] lists the entries inside a prefix.
+//! An empty list matches nothing; [`PrefixList::any`] is the list that
+//! matches everything, written out as `0.0.0.0/0 le 32` and `::/0 le 128`.
+//!
+//! Entries are kept in a binary trie per address family, so a lookup walks
+//! at most one node per bit of the prefix however long the list is.
+
+use crate::network::bgp::prefix::{has_host_bits, HostBitsSet};
+use crate::network::bgp::Prefix;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PrefixListError {
+    #[error("Invalid prefix in \"{entry}\"")]
+    Prefix {
+        entry: String,
+        #[source]
+        source: ipnet::AddrParseError,
+    },
+    #[error(transparent)]
+    HostBits(#[from] HostBitsSet),
+    #[error("Unexpected \"{word}\" in \"{entry}\"; expected ge or le and a length")]
+    Syntax { entry: String, word: String },
+    #[error("\"{entry}\" needs {prefix_len} <= ge <= le <= {max}")]
+    Lengths {
+        entry: String,
+        prefix_len: u8,
+        max: u8,
+    },
+}
+
+/// A prefix and the lengths of routes inside it that match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PrefixEntry {
+    pub prefix: Prefix,
+    pub ge: Option<u8>,
+    pub le: Option<u8>,
+}
+
+impl PrefixEntry {
+    /// An entry matching `prefix` alone
+    pub fn exact(prefix: Prefix) -> Self {
+        PrefixEntry {
+            prefix,
+            ge: None,
+            le: None,
+        }
+    }
+
+    /// Shortest and longest prefix lengths that match
+    pub fn lengths(&self) -> (u8, u8) {
+        let own = self.prefix.prefix_len();
+        match (self.ge, self.le) {
+            (None, None) => (own, own),
+            (ge, le) => (
+                ge.unwrap_or(own),
+                le.unwrap_or(self.prefix.max_prefix_len()),
+            ),
+        }
+    }
+
+    pub fn matches(&self, prefix: &Prefix) -> bool {
+        let (shortest, longest) = self.lengths();
+        (shortest..=longest).contains(&prefix.prefix_len()) && self.prefix.contains(&prefix.net())
+    }
+}
+
+impl FromStr for PrefixEntry {
+    type Err = PrefixListError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let net: IpNet =
+            words
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .map_err(|source| PrefixListError::Prefix {
+                    entry: s.to_string(),
+                    source,
+                })?;
+        if has_host_bits(&net) {
+            return Err(HostBitsSet { network: net }.into());
+        }
+        let mut entry = PrefixEntry::exact(Prefix::new(net));
+        while let Some(word) = words.next() {
+            let slot = match word {
+                "ge" if entry.ge.is_none() => &mut entry.ge,
+                "le" if entry.le.is_none() => &mut entry.le,
+                _ => {
+                    return Err(PrefixListError::Syntax {
+                        entry: s.to_string(),
+                        word: word.to_string(),
+                    })
+                }
+            };
+            let length = words.next().unwrap_or_default();
+            *slot = Some(length.parse().map_err(|_| PrefixListError::Syntax {
+                entry: s.to_string(),
+                word: length.to_string(),
+            })?);
+        }
+        let (shortest, longest) = entry.lengths();
+        let (own, max) = (net.prefix_len(), net.max_prefix_len());
+        if !(own <= shortest && shortest <= longest && longest <= max) {
+            return Err(PrefixListError::Lengths {
+                entry: s.to_string(),
+                prefix_len: own,
+                max,
+            });
+        }
+        Ok(entry)
+    }
+}
+
+impl fmt::Display for PrefixEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.prefix)?;
+        if let Some(ge) = self.ge {
+            write!(f, " ge {}", ge)?;
+        }
+        if let Some(le) = self.le {
+            write!(f, " le {}", le)?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for PrefixEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrefixEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node<T> {
+    children: [Option<u32>; 2],
+    values: Vec<T>,
+}
+
+/// Values stored under prefixes of one address family, found by walking
+/// the prefix's bits from the most significant
+#[derive(Debug, Clone)]
+pub struct PrefixTrie<T> {
+    nodes: Vec<Node<T>>,
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        PrefixTrie {
+            nodes: vec![Node {
+                children: [None, None],
+                values: Vec::new(),
+            }],
+        }
+    }
+}
+
+/// A prefix's address as a left-aligned 128-bit key
+fn key(net: &IpNet) -> u128 {
+    match net {
+        IpNet::V4(net) => u128::from(u32::from(net.network())) << 96,
+        IpNet::V6(net) => u128::from(net.network()),
+    }
+}
+
+fn bit(key: u128, depth: u8) -> usize {
+    ((key >> (127 - depth)) & 1) as usize
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn insert(&mut self, prefix: &Prefix, value: T) {
+        let key = key(&prefix.net());
+        let mut node = 0;
+        for depth in 0..prefix.prefix_len() {
+            let branch = bit(key, depth);
+            node = match self.nodes[node].children[branch] {
+                Some(child) => child as usize,
+                None => {
+                    self.nodes.push(Node {
+                        children: [None, None],
+                        values: Vec::new(),
+                    });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[branch] = Some(child as u32);
+                    child
+                }
+            };
+        }
+        self.nodes[node].values.push(value);
+    }
+
+    /// Values stored under `prefix` or any prefix containing it, shortest
+    /// prefix first
+    pub fn covering<'a>(&'a self, prefix: &Prefix) -> Covering<'a, T> {
+        Covering {
+            trie: self,
+            key: key(&prefix.net()),
+            len: prefix.prefix_len(),
+            depth: 0,
+            node: Some(0),
+            values: [].iter(),
+        }
+    }
+
+    /// Values stored under `prefix` or any prefix inside it
+    pub fn within(&self, prefix: &Prefix) -> Vec<&T> {
+        let key = key(&prefix.net());
+        let mut node = 0;
+        for depth in 0..prefix.prefix_len() {
+            match self.nodes[node].children[bit(key, depth)] {
+                Some(child) => node = child as usize,
+                None => return Vec::new(),
+            }
+        }
+        let mut found = Vec::new();
+        let mut pending = vec![node];
+        while let Some(node) = pending.pop() {
+            found.extend(self.nodes[node].values.iter());
+            pending.extend(
+                self.nodes[node]
+                    .children
+                    .iter()
+                    .flatten()
+                    .map(|&c| c as usize),
+            );
+        }
+        found
+    }
+}
+
+/// Iterator over [`PrefixTrie::covering`], walking down one node at a time
+pub struct Covering<'a, T> {
+    trie: &'a PrefixTrie<T>,
+    key: u128,
+    len: u8,
+    depth: u8,
+    node: Option<usize>,
+    values: std::slice::Iter<'a, T>,
+}
+
+impl<'a, T> Iterator for Covering<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        loop {
+            if let Some(value) = self.values.next() {
+                return Some(value);
+            }
+            let node = &self.trie.nodes[self.node?];
+            self.values = node.values.iter();
+            self.node = if self.depth < self.len {
+                let child = node.children[bit(self.key, self.depth)];
+                self.depth += 1;
+                child.map(|child| child as usize)
+            } else {
+                None
+            };
+        }
+    }
+}
+
+/// Prefix entries in the order given, with a trie per family to look them
+/// up by
+#[derive(Debug, Clone, Default)]
+pub struct PrefixList {
+    entries: Vec<PrefixEntry>,
+    v4: PrefixTrie<usize>,
+    v6: PrefixTrie<usize>,
+}
+
+impl PrefixList {
+    pub fn new(entries: impl IntoIterator<Item = PrefixEntry>) -> Self {
+        let mut list = PrefixList::default();
+        for entry in entries {
+            let index = list.entries.len();
+            list.trie_mut(&entry.prefix).insert(&entry.prefix, index);
+            list.entries.push(entry);
+        }
+        list
+    }
+
+    /// The list that matches every prefix of either family
+    pub fn any() -> Self {
+        Self::parse(["0.0.0.0/0 le 32", "::/0 le 128"]).expect("valid entries")
+    }
+
+    /// Entries from config strings such as `"10.2.0.0/16 le 24"`
+    pub fn parse<S: AsRef<str>>(
+        entries: impl IntoIterator<Item = S>,
+    ) -> Result<Self, PrefixListError> {
+        let entries = entries
+            .into_iter()
+            .map(|entry| entry.as_ref().parse())
+            .collect::<Result<Vec<PrefixEntry>, _>>()?;
+        Ok(PrefixList::new(entries))
+    }
+
+    fn trie(&self, prefix: &Prefix) -> &PrefixTrie<usize> {
+        match prefix.net() {
+            IpNet::V4(_) => &self.v4,
+            IpNet::V6(_) => &self.v6,
+        }
+    }
+
+    fn trie_mut(&mut self, prefix: &Prefix) -> &mut PrefixTrie<usize> {
+        match prefix.net() {
+            IpNet::V4(_) => &mut self.v4,
+            IpNet::V6(_) => &mut self.v6,
+        }
+    }
+
+    pub fn entries(&self) -> &[PrefixEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The first entry, in list order, whose prefix and lengths match
+    pub fn first_match(&self, prefix: &Prefix) -> Option<&PrefixEntry> {
+        self.trie(prefix)
+            .covering(prefix)
+            .copied()
+            .filter(|&index| self.entries[index].matches(prefix))
+            .min()
+            .map(|index| &self.entries[index])
+    }
+
+    /// Whether an entry matches `prefix`, length qualifiers included
+    pub fn matches(&self, prefix: &Prefix) -> bool {
+        self.trie(prefix)
+            .covering(prefix)
+            .any(|&index| self.entries[index].matches(prefix))
+    }
+
+    /// Whether `prefix` is itself an entry's prefix
+    pub fn exact(&self, prefix: &Prefix) -> bool {
+        self.trie(prefix)
+            .covering(prefix)
+            .any(|&index| self.entries[index].prefix == *prefix)
+    }
+
+    /// Whether `prefix` lies inside an entry's prefix, whatever its length
+    pub fn covers(&self, prefix: &Prefix) -> bool {
+        self.trie(prefix).covering(prefix).next().is_some()
+    }
+
+    /// Whether `addr` lies inside an entry's prefix
+    pub fn covers_addr(&self, addr: IpAddr) -> bool {
+        self.covers(&Prefix::from(addr))
+    }
+
+    /// Entries whose prefix lies inside `prefix`, `prefix` itself included
+    pub fn within(&self, prefix: &Prefix) -> Vec<&PrefixEntry> {
+        let mut indices = self.trie(prefix).within(prefix);
+        indices.sort_unstable();
+        indices
+            .into_iter()
+            .map(|&index| &self.entries[index])
+            .collect()
+    }
+}
+
+impl PartialEq for PrefixList {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl Eq for PrefixList {}
+
+impl FromIterator<PrefixEntry> for PrefixList {
+    fn from_iter<I: IntoIterator<Item = PrefixEntry>>(entries: I) -> Self {
+        PrefixList::new(entries)
+    }
+}
+
+impl fmt::Display for PrefixList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.entries.is_empty() {
+            return write!(f, "(none)");
+        }
+        let entries: Vec<String> = self.entries.iter().map(ToString::to_string).collect();
+        write!(f, "{}", entries.join(", "))
+    }
+}
+
+impl Serialize for PrefixList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrefixList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<PrefixEntry>::deserialize(deserializer).map(PrefixList::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Prefix {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_qualifiers_select_lengths() {
+        let list = PrefixList::parse([
+            "10.1.0.0/16",
+            "10.2.0.0/16 le 24",
+            "10.3.0.0/16 ge 24",
+            "10.4.0.0/16 ge 20 le 24",
+        ])
+        .unwrap();
+        let cases = [
+            ("10.1.0.0/16", true),
+            ("10.1.1.0/24", false),
+            ("10.2.0.0/16", true),
+            ("10.2.5.0/24", true),
+            ("10.2.5.0/25", false),
+            ("10.3.0.0/16", false),
+            ("10.3.5.0/24", true),
+            ("10.3.5.5/32", true),
+            ("10.4.0.0/16", false),
+            ("10.4.0.0/19", false),
+            ("10.4.16.0/20", true),
+            ("10.4.5.0/24", true),
+            ("10.4.5.0/25", false),
+            ("10.0.0.0/8", false),
+        ];
+        for (candidate, expected) in cases {
+            assert_eq!(list.matches(&prefix(candidate)), expected, "{}", candidate);
+        }
+
+        // Covering ignores the qualifiers; exact ignores what is inside
+        assert!(list.covers(&prefix("10.1.1.0/24")));
+        assert!(!list.covers(&prefix("10.0.0.0/8")));
+        assert!(list.covers_addr("10.4.200.1".parse().unwrap()));
+        assert_eq!(
+            list.first_match(&prefix("10.2.5.0/24")),
+            Some(&list.entries()[1])
+        );
+        assert!(list.exact(&prefix("10.3.0.0/16")));
+        assert!(!list.exact(&prefix("10.3.5.0/24")));
+        let inside: Vec<String> = list
+            .within(&prefix("10.0.0.0/13"))
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(inside.len(), 4);
+        assert_eq!(inside[1], "10.2.0.0/16 le 24");
+    }
+
+    #[test]
+    fn test_boundary_lengths_and_ipv6() {
+        let list = PrefixList::parse([
+            "0.0.0.0/0",
+            "192.0.2.1/32",
+            "fd00:7830::/32 ge 48 le 64",
+            "::/0 ge 128",
+        ])
+        .unwrap();
+        assert!(list.matches(&prefix("0.0.0.0/0")));
+        assert!(!list.matches(&prefix("0.0.0.0/1")));
+        assert!(list.matches(&prefix("192.0.2.1/32")));
+        assert!(!list.matches(&prefix("192.0.2.2/32")));
+        assert!(list.matches(&prefix("fd00:7830:1::/48")));
+        assert!(list.matches(&prefix("fd00:7830:1:2::/64")));
+        assert!(!list.matches(&prefix("fd00:7830::/32")));
+        assert!(!list.matches(&prefix("fd00:7831::/48")));
+        assert!(list.matches(&prefix("2001:db8::1/128")));
+        assert!(!list.matches(&prefix("::/0")));
+        // Families never cross
+        assert!(!PrefixList::parse(["10.0.0.0/8 le 32"])
+            .unwrap()
+            .covers(&prefix("::a00:0/104")));
+    }
+
+    #[test]
+    fn test_empty_matches_nothing_and_any_matches_everything() {
+        let none = PrefixList::default();
+        assert!(none.is_empty());
+        let any = PrefixList::any();
+        for candidate in [
+            "0.0.0.0/0",
+            "10.1.2.0/24",
+            "10.1.2.3/32",
+            "::/0",
+            "fd00::1/128",
+        ] {
+            assert!(!none.matches(&prefix(candidate)));
+            assert!(!none.covers(&prefix(candidate)));
+            assert!(any.matches(&prefix(candidate)), "{}", candidate);
+        }
+        assert_eq!(none.to_string(), "(none)");
+        assert_eq!(any.to_string(), "0.0.0.0/0 le 32, ::/0 le 128");
+    }
+
+    #[test]
+    fn test_config_strings_are_validated() {
+        let list: PrefixList =
+            serde_json::from_str(r#"["10.2.0.0/16 le 24", "fd00::/8 ge 16"]"#).unwrap();
+        assert_eq!(
+            serde_json::to_string(&list).unwrap(),
+            r#"["10.2.0.0/16 le 24","fd00::/8 ge 16"]"#
+        );
+        assert_eq!(
+            "10.0.0.0/8 ge 16 le 24".parse::<PrefixEntry>().unwrap(),
+            PrefixEntry {
+                prefix: prefix("10.0.0.0/8"),
+                ge: Some(16),
+                le: Some(24),
+            }
+        );
+
+        for bad in [
+            "10.2.1.0/16",
+            "10.2.0.0/16 le 8",
+            "10.2.0.0/16 ge 24 le 20",
+            "10.2.0.0/16 le 33",
+            "10.2.0.0/16 ge",
+            "10.2.0.0/16 le 24 le 25",
+            "10.2.0.0/16 eq 24",
+            "10.2.0.0",
+            "",
+        ] {
+            assert!(bad.parse::<PrefixEntry>().is_err(), "{}", bad);
+        }
+        let err = "10.2.0.0/16 le 8".parse::<PrefixEntry>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\"10.2.0.0/16 le 8\" needs 16 <= ge <= le <= 32"
+        );
+    }
+}
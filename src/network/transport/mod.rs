@@ -0,0 +1,664 @@
+//! Framed message streams shared by the daemon's TCP protocols.
+//!
+//! Joining, zone sync and the daemon's other services exchange whole
+//! messages over TCP. A [`MessageStream`] frames them with one of three
+//! codecs:
+//!
+//! - [`Codec::Bgp`]: RFC 4271's header, a marker of all ones, the two-byte
+//!   length of the whole message and its type, then the message body. BGP
+//!   sessions are framed this way; see [`wire`](crate::network::bgp::wire).
+//! - [`Codec::Lines`]: one message per newline-terminated line, the framing
+//!   join requests and answers have always used.
+//! - [`Codec::Tagged`]: a four-byte length, a [`MessageTag`] byte and a
+//!   flags byte, then the message, deflated when it is large enough to be
+//!   worth it. Tags let several protocols share one connection to a peer;
+//!   see [`pool`].
+//!
+//! Every frame's length is checked against `max_frame_len` before anything
+//! is allocated. Writes, and reads once a frame's first byte has arrived,
+//! give up after `io_timeout`, so a stalled peer cannot hold a task. Over a
+//! tunnel, [`TunnelChannel`](crate::network::ike::channel::TunnelChannel)
+//! seals one whole frame per message, from [`encode_frame`] and read back
+//! with [`decode_frame`].
+
+use crate::network::bgp::wire::{BGP_HEADER_LEN, BGP_MARKER, BGP_MAX_EXTENDED_MESSAGE_LEN};
+use crate::network::ike::IKEError;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+pub mod pool;
+
+/// Largest message accepted by default
+pub const DEFAULT_MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Set in a tagged frame's flags when the message is deflated
+const FLAG_DEFLATED: u8 = 0x01;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("Cannot connect to {peer}")]
+    Connect {
+        peer: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Timed out connecting to {peer}")]
+    ConnectTimeout { peer: SocketAddr },
+    #[error("Timed out {during}")]
+    Timeout { during: &'static str },
+    #[error("{len}-byte message exceeds the {max}-byte limit")]
+    FrameTooLarge { len: usize, max: usize },
+    #[error("Message header has a bad marker")]
+    BadMarker,
+    #[error("Message header gives a length of {len}")]
+    BadLength { len: usize },
+    #[error("Unknown message tag {tag}")]
+    UnknownTag { tag: u8 },
+    #[error("Another channel to {peer} already carries {tag:?} messages")]
+    TagInUse { peer: SocketAddr, tag: MessageTag },
+    #[error("Connection to {peer} is closed")]
+    Closed { peer: SocketAddr },
+    #[error("Cannot inflate message")]
+    Inflate(#[source] std::io::Error),
+    #[error(transparent)]
+    Tunnel(#[from] IKEError),
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+}
+
+impl TransportError {
+    /// Whether the same exchange could succeed later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            TransportError::Connect { .. }
+                | TransportError::ConnectTimeout { .. }
+                | TransportError::Timeout { .. }
+                | TransportError::Closed { .. }
+                | TransportError::IO(_)
+        )
+    }
+}
+
+/// Which protocol a tagged frame belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum MessageTag {
+    /// Carries nothing; keeps idle pooled connections open
+    Keepalive = 0,
+    Bgp = 1,
+    Join = 2,
+    ZoneSync = 3,
+    Control = 4,
+}
+
+impl MessageTag {
+    pub fn from_u8(tag: u8) -> Option<Self> {
+        Some(match tag {
+            0 => MessageTag::Keepalive,
+            1 => MessageTag::Bgp,
+            2 => MessageTag::Join,
+            3 => MessageTag::ZoneSync,
+            4 => MessageTag::Control,
+            _ => return None,
+        })
+    }
+}
+
+/// How messages are delimited on a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Behind the RFC 4271 header, untagged; the payload is the message
+    /// type and the body after it
+    Bgp,
+    /// Newline-terminated; the join protocol's framing
+    Lines,
+    /// Length-prefixed with a tag and flags; for shared connections
+    Tagged,
+}
+
+/// One message and the protocol it belongs to. Untagged codecs report the
+/// tag the stream was opened for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub tag: MessageTag,
+    pub payload: Vec<u8>,
+}
+
+/// Timeouts, limits and keepalives every protocol on the transport shares
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportConfig {
+    pub connect_timeout: Duration,
+    /// Longest a frame may take to write, or to arrive once it started
+    pub io_timeout: Option<Duration>,
+    /// How often idle pooled connections send an empty frame
+    pub keepalive: Option<Duration>,
+    pub max_frame_len: usize,
+    /// Tagged messages at least this large are deflated
+    pub compress_above: Option<usize>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        TransportConfig {
+            connect_timeout: Duration::from_secs(10),
+            io_timeout: Some(Duration::from_secs(30)),
+            keepalive: Some(Duration::from_secs(30)),
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            compress_above: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    pub fn with_connect_timeout(mut self, limit: Duration) -> Self {
+        self.connect_timeout = limit;
+        self
+    }
+
+    pub fn with_io_timeout(mut self, limit: Option<Duration>) -> Self {
+        self.io_timeout = limit;
+        self
+    }
+
+    pub fn with_keepalive(mut self, every: Option<Duration>) -> Self {
+        self.keepalive = every;
+        self
+    }
+
+    pub fn with_max_frame_len(mut self, max: usize) -> Self {
+        self.max_frame_len = max;
+        self
+    }
+
+    pub fn with_compression(mut self, above: Option<usize>) -> Self {
+        self.compress_above = above;
+        self
+    }
+}
+
+/// Open a TCP connection within the configured connect timeout
+pub async fn connect_tcp(
+    peer: SocketAddr,
+    config: &TransportConfig,
+) -> Result<TcpStream, TransportError> {
+    let stream = timeout(config.connect_timeout, TcpStream::connect(peer))
+        .await
+        .map_err(|_| TransportError::ConnectTimeout { peer })?
+        .map_err(|source| TransportError::Connect { peer, source })?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Connect to `peer` and frame the connection with `codec`
+pub async fn connect(
+    peer: SocketAddr,
+    codec: Codec,
+    tag: MessageTag,
+    config: TransportConfig,
+) -> Result<MessageStream<TcpStream>, TransportError> {
+    let stream = connect_tcp(peer, &config).await?;
+    Ok(MessageStream::new(stream, codec, tag, config))
+}
+
+/// Take the next connection from `listener` and frame it with `codec`
+pub async fn accept(
+    listener: &TcpListener,
+    codec: Codec,
+    tag: MessageTag,
+    config: TransportConfig,
+) -> Result<(MessageStream<TcpStream>, SocketAddr), TransportError> {
+    let (stream, peer) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+    Ok((MessageStream::new(stream, codec, tag, config), peer))
+}
+
+/// A stream carrying whole messages
+pub struct MessageStream<S> {
+    stream: S,
+    codec: Codec,
+    /// Reported for frames of untagged codecs, and used to send them
+    tag: MessageTag,
+    config: TransportConfig,
+    /// Bytes read past the last line, with `Codec::Lines`
+    pending: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MessageStream<S> {
+    pub fn new(stream: S, codec: Codec, tag: MessageTag, config: TransportConfig) -> Self {
+        MessageStream {
+            stream,
+            codec,
+            tag,
+            config,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The underlying stream; with `Codec::Lines`, bytes already read past
+    /// the last message are lost
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    pub async fn send(&mut self, tag: MessageTag, payload: &[u8]) -> Result<(), TransportError> {
+        write_frame(&mut self.stream, self.codec, tag, payload, &self.config).await
+    }
+
+    /// The next message, or `None` once the peer closed the stream between
+    /// messages
+    pub async fn recv(&mut self) -> Result<Option<Frame>, TransportError> {
+        match self.codec {
+            Codec::Lines => {
+                read_line(&mut self.stream, &mut self.pending, self.tag, &self.config).await
+            }
+            codec => read_frame(&mut self.stream, codec, self.tag, &self.config).await,
+        }
+    }
+
+    pub async fn send_json<T: Serialize>(&mut self, message: &T) -> Result<(), TransportError> {
+        let tag = self.tag;
+        self.send(tag, &serde_json::to_vec(message)?).await
+    }
+
+    /// The next message parsed as JSON, skipping keepalives
+    pub async fn recv_json<T: DeserializeOwned>(&mut self) -> Result<Option<T>, TransportError> {
+        loop {
+            match self.recv().await? {
+                Some(frame) if frame.tag == MessageTag::Keepalive => continue,
+                Some(frame) => return Ok(Some(serde_json::from_slice(&frame.payload)?)),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// A frame's body as `codec` puts it behind the length: the message itself,
+/// or tag, flags and the possibly deflated message
+pub fn encode_body(
+    codec: Codec,
+    tag: MessageTag,
+    payload: &[u8],
+    config: &TransportConfig,
+) -> Result<Vec<u8>, TransportError> {
+    let body = match codec {
+        Codec::Bgp => {
+            if payload.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "BGP messages start with their type",
+                )
+                .into());
+            }
+            payload.to_vec()
+        }
+        Codec::Lines => {
+            if payload.contains(&b'\n') {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "line-framed messages cannot contain newlines",
+                )
+                .into());
+            }
+            let mut line = payload.to_vec();
+            line.push(b'\n');
+            line
+        }
+        Codec::Tagged => {
+            let deflate = config
+                .compress_above
+                .is_some_and(|above| payload.len() >= above);
+            let mut body = vec![tag as u8, 0];
+            if deflate {
+                body[1] |= FLAG_DEFLATED;
+                let mut encoder = DeflateEncoder::new(body, Compression::fast());
+                encoder.write_all(payload)?;
+                encoder.finish()?
+            } else {
+                body.extend_from_slice(payload);
+                body
+            }
+        }
+    };
+    if body.len() > config.max_frame_len {
+        return Err(TransportError::FrameTooLarge {
+            len: body.len(),
+            max: config.max_frame_len,
+        });
+    }
+    Ok(body)
+}
+
+/// The message in a frame body from [`encode_body`]; `tag` stands in for
+/// untagged codecs
+pub fn decode_body(
+    codec: Codec,
+    tag: MessageTag,
+    body: Vec<u8>,
+    config: &TransportConfig,
+) -> Result<Frame, TransportError> {
+    if body.len() > config.max_frame_len {
+        return Err(TransportError::FrameTooLarge {
+            len: body.len(),
+            max: config.max_frame_len,
+        });
+    }
+    match codec {
+        Codec::Bgp => Ok(Frame { tag, payload: body }),
+        Codec::Lines => {
+            let mut payload = body;
+            if payload.last() == Some(&b'\n') {
+                payload.pop();
+            }
+            Ok(Frame { tag, payload })
+        }
+        Codec::Tagged => {
+            let [tag, flags, ..] = body[..] else {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            };
+            let tag = MessageTag::from_u8(tag).ok_or(TransportError::UnknownTag { tag })?;
+            let payload = if flags & FLAG_DEFLATED != 0 {
+                // Inflated no further than the limit, so small frames cannot
+                // unpack into huge messages
+                let limit = config.max_frame_len as u64 + 1;
+                let mut payload = Vec::new();
+                DeflateDecoder::new(&body[2..])
+                    .take(limit)
+                    .read_to_end(&mut payload)
+                    .map_err(TransportError::Inflate)?;
+                if payload.len() > config.max_frame_len {
+                    return Err(TransportError::FrameTooLarge {
+                        len: payload.len(),
+                        max: config.max_frame_len,
+                    });
+                }
+                payload
+            } else {
+                body[2..].to_vec()
+            };
+            Ok(Frame { tag, payload })
+        }
+    }
+}
+
+/// One message framed as `codec` writes it, its length or header included
+pub fn encode_frame(
+    codec: Codec,
+    tag: MessageTag,
+    payload: &[u8],
+    config: &TransportConfig,
+) -> Result<Vec<u8>, TransportError> {
+    let body = encode_body(codec, tag, payload, config)?;
+    let mut frame = match codec {
+        Codec::Lines => return Ok(body),
+        Codec::Bgp => {
+            let len = BGP_MARKER.len() + 2 + body.len();
+            if len > BGP_MAX_EXTENDED_MESSAGE_LEN.min(config.max_frame_len) {
+                return Err(TransportError::FrameTooLarge {
+                    len,
+                    max: BGP_MAX_EXTENDED_MESSAGE_LEN.min(config.max_frame_len),
+                });
+            }
+            let mut header = BGP_MARKER.to_vec();
+            header.extend_from_slice(&(len as u16).to_be_bytes());
+            header
+        }
+        Codec::Tagged => (body.len() as u32).to_be_bytes().to_vec(),
+    };
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// The message in one whole frame from [`encode_frame`]
+pub fn decode_frame(
+    codec: Codec,
+    tag: MessageTag,
+    frame: &[u8],
+    config: &TransportConfig,
+) -> Result<Frame, TransportError> {
+    let body = match codec {
+        Codec::Lines => frame,
+        Codec::Bgp => {
+            let len = bgp_length(frame, config)?;
+            if frame.len() != len {
+                return Err(TransportError::BadLength { len });
+            }
+            &frame[BGP_MARKER.len() + 2..]
+        }
+        Codec::Tagged => {
+            let Some((length, body)) = frame.split_first_chunk::<4>() else {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            };
+            let len = u32::from_be_bytes(*length) as usize;
+            if len != body.len() {
+                return Err(TransportError::BadLength { len });
+            }
+            body
+        }
+    };
+    decode_body(codec, tag, body.to_vec(), config)
+}
+
+/// The length of a whole message as the RFC 4271 header at the start of
+/// `header` gives it, once the marker and the length are checked
+fn bgp_length(header: &[u8], config: &TransportConfig) -> Result<usize, TransportError> {
+    let marker_len = BGP_MARKER.len();
+    if header.len() < marker_len + 2 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    if header[..marker_len] != BGP_MARKER {
+        return Err(TransportError::BadMarker);
+    }
+    let len = u16::from_be_bytes([header[marker_len], header[marker_len + 1]]) as usize;
+    if len < BGP_HEADER_LEN {
+        return Err(TransportError::BadLength { len });
+    }
+    if len > config.max_frame_len {
+        return Err(TransportError::FrameTooLarge {
+            len,
+            max: config.max_frame_len,
+        });
+    }
+    Ok(len)
+}
+
+/// Frame one message onto `writer`
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    codec: Codec,
+    tag: MessageTag,
+    payload: &[u8],
+    config: &TransportConfig,
+) -> Result<(), TransportError> {
+    let frame = encode_frame(codec, tag, payload, config)?;
+    let write = async {
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    };
+    within(config.io_timeout, "writing a message", write).await
+}
+
+/// Read one length-prefixed frame, or `None` if the stream ended before it
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    codec: Codec,
+    tag: MessageTag,
+    config: &TransportConfig,
+) -> Result<Option<Frame>, TransportError> {
+    // Waiting for a frame to start is not timed; finishing it is
+    let mut prefix = match codec {
+        Codec::Bgp => vec![0u8; BGP_MARKER.len() + 2],
+        _ => vec![0u8; 4],
+    };
+    if reader.read(&mut prefix[..1]).await? == 0 {
+        return Ok(None);
+    }
+    let read_rest = async {
+        reader.read_exact(&mut prefix[1..]).await?;
+        let length = match codec {
+            Codec::Bgp => match bgp_length(&prefix, config) {
+                Ok(length) => length - prefix.len(),
+                Err(e) => return Ok(Err(e)),
+            },
+            _ => u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize,
+        };
+        if length > config.max_frame_len {
+            return Ok(Err(TransportError::FrameTooLarge {
+                len: length,
+                max: config.max_frame_len,
+            }));
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).await?;
+        Ok(Ok(body))
+    };
+    let body = within(config.io_timeout, "reading a message", read_rest).await??;
+    decode_body(codec, tag, body, config).map(Some)
+}
+
+async fn read_line<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    pending: &mut Vec<u8>,
+    tag: MessageTag,
+    config: &TransportConfig,
+) -> Result<Option<Frame>, TransportError> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let rest = pending.split_off(end + 1);
+            let line = std::mem::replace(pending, rest);
+            return decode_body(Codec::Lines, tag, line, config).map(Some);
+        }
+        if pending.len() > config.max_frame_len {
+            return Err(TransportError::FrameTooLarge {
+                len: pending.len(),
+                max: config.max_frame_len,
+            });
+        }
+        // A line that has started must finish in time
+        let read = if pending.is_empty() {
+            reader.read(&mut chunk).await?
+        } else {
+            within(
+                config.io_timeout,
+                "reading a message",
+                reader.read(&mut chunk),
+            )
+            .await?
+        };
+        if read == 0 {
+            if pending.is_empty() {
+                return Ok(None);
+            }
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        pending.extend_from_slice(&chunk[..read]);
+    }
+}
+
+async fn within<T>(
+    limit: Option<Duration>,
+    during: &'static str,
+    io: impl std::future::Future<Output = std::io::Result<T>>,
+) -> Result<T, TransportError> {
+    match limit {
+        Some(limit) => timeout(limit, io)
+            .await
+            .map_err(|_| TransportError::Timeout { during })?
+            .map_err(TransportError::from),
+        None => io.await.map_err(TransportError::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_codecs_round_trip() {
+        let config = TransportConfig::default().with_compression(Some(64));
+        let large = vec![b'x'; 4096];
+        for codec in [Codec::Bgp, Codec::Lines, Codec::Tagged] {
+            let (ours, theirs) = duplex(64 * 1024);
+            let mut ours = MessageStream::new(ours, codec, MessageTag::Bgp, config);
+            let mut theirs = MessageStream::new(theirs, codec, MessageTag::Bgp, config);
+            ours.send(MessageTag::Bgp, b"{\"asn\":65101}")
+                .await
+                .unwrap();
+            ours.send(MessageTag::Bgp, &large).await.unwrap();
+            drop(ours);
+
+            let first = theirs.recv().await.unwrap().unwrap();
+            assert_eq!(first.payload, b"{\"asn\":65101}", "{:?}", codec);
+            assert_eq!(theirs.recv().await.unwrap().unwrap().payload, large);
+            assert_eq!(theirs.recv().await.unwrap(), None, "{:?}", codec);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bgp_frames_are_behind_the_rfc_4271_header() {
+        let config = TransportConfig::default();
+        let keepalive = encode_frame(Codec::Bgp, MessageTag::Bgp, &[4], &config).unwrap();
+        let mut expected = BGP_MARKER.to_vec();
+        expected.extend_from_slice(&[0, 19, 4]);
+        assert_eq!(keepalive, expected);
+        let frame = decode_frame(Codec::Bgp, MessageTag::Bgp, &keepalive, &config).unwrap();
+        assert_eq!(frame.tag, MessageTag::Bgp);
+        assert_eq!(frame.payload, [4]);
+
+        let (mut ours, theirs) = duplex(1024);
+        let mut marker = BGP_MARKER;
+        marker[3] = 0;
+        ours.write_all(&marker).await.unwrap();
+        ours.write_u16(19).await.unwrap();
+        ours.write_u8(4).await.unwrap();
+        let mut theirs = MessageStream::new(theirs, Codec::Bgp, MessageTag::Bgp, config);
+        assert!(matches!(
+            theirs.recv().await,
+            Err(TransportError::BadMarker)
+        ));
+        assert!(matches!(
+            decode_frame(Codec::Bgp, MessageTag::Bgp, &expected[..18], &config),
+            Err(TransportError::BadLength { len: 19 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_and_deflated_frames_are_refused() {
+        let config = TransportConfig::default().with_max_frame_len(1024);
+        let (mut ours, theirs) = duplex(4096);
+        ours.write_all(&BGP_MARKER).await.unwrap();
+        ours.write_u16(4096).await.unwrap();
+        let mut theirs = MessageStream::new(theirs, Codec::Bgp, MessageTag::Bgp, config);
+        assert!(matches!(
+            theirs.recv().await,
+            Err(TransportError::FrameTooLarge { len: 4096, .. })
+        ));
+
+        // Small on the wire, too large once inflated
+        let bomb = encode_body(
+            Codec::Tagged,
+            MessageTag::ZoneSync,
+            &vec![0u8; 64 * 1024],
+            &TransportConfig::default().with_compression(Some(0)),
+        )
+        .unwrap();
+        assert!(bomb.len() < 1024);
+        assert!(matches!(
+            decode_body(Codec::Tagged, MessageTag::Bgp, bomb, &config),
+            Err(TransportError::FrameTooLarge { .. })
+        ));
+    }
+}
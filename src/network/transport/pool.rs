@@ -0,0 +1,379 @@
+//! One connection per peer, shared by every protocol that talks to it.
+//!
+//! A [`TransportPool`] keeps a tagged connection to each peer address it
+//! was asked for. [`TransportPool::channel`] hands out a [`Channel`] for
+//! one [`MessageTag`] on it, connecting only if no live connection to that
+//! address exists; a task per connection reads frames and routes them to
+//! the channel for their tag. Connections accepted from peers are adopted
+//! with [`TransportPool::adopt`], which yields a channel for each tag the
+//! peer starts using. Idle connections send empty keepalive frames, less
+//! often on stable links when the pool has adaptive timers.
+
+use super::{
+    connect_tcp, read_frame, write_frame, Codec, MessageTag, TransportConfig, TransportError,
+};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::adaptive::{self, AdaptiveTimers, Probe, ECHO_BYTES};
+use crate::util::sync::lock;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+/// Messages queued per channel before the connection's reader waits
+const CHANNEL_QUEUE: usize = 64;
+
+/// A shared connection to one peer
+#[derive(Debug)]
+struct Link {
+    peer: SocketAddr,
+    config: TransportConfig,
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    routes: Mutex<HashMap<MessageTag, mpsc::Sender<Vec<u8>>>>,
+    closed: AtomicBool,
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+impl Link {
+    /// Start reading `stream`; frames for tags without a channel become
+    /// new channels on `incoming`, or are dropped without it
+    fn spawn(
+        stream: TcpStream,
+        peer: SocketAddr,
+        config: TransportConfig,
+        incoming: Option<mpsc::Sender<Channel>>,
+        adaptive: Option<Arc<AdaptiveTimers>>,
+    ) -> Arc<Self> {
+        let (mut reader, writer) = stream.into_split();
+        let link = Arc::new(Link {
+            peer,
+            config,
+            writer: tokio::sync::Mutex::new(writer),
+            routes: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            tasks: Mutex::new(Vec::new()),
+        });
+
+        let reading = Arc::clone(&link);
+        let failing = adaptive.clone();
+        let reader_task = crash::spawn(Subsystem::Node, "transport-reader", async move {
+            loop {
+                let frame =
+                    match read_frame(&mut reader, Codec::Tagged, MessageTag::Keepalive, &config)
+                        .await
+                    {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        // The frame was read in full; the next one is intact
+                        Err(TransportError::UnknownTag { tag }) => {
+                            tracing::debug!("Skipping message with tag {} from {}", tag, peer);
+                            continue;
+                        }
+                        Err(e) => {
+                            tracing::debug!("Connection to {} failed: {}", peer, e);
+                            if let Some(adaptive) = &failing {
+                                adaptive.flap(peer.ip());
+                            }
+                            break;
+                        }
+                    };
+                if frame.tag == MessageTag::Keepalive {
+                    continue;
+                }
+                let route = lock(&reading.routes).get(&frame.tag).cloned();
+                let route = match (route, &incoming) {
+                    (Some(route), _) => route,
+                    (None, Some(incoming)) => {
+                        let Ok(channel) = reading.open(frame.tag) else {
+                            continue;
+                        };
+                        let route = lock(&reading.routes).get(&frame.tag).cloned();
+                        if incoming.send(channel).await.is_err() {
+                            break;
+                        }
+                        match route {
+                            Some(route) => route,
+                            None => continue,
+                        }
+                    }
+                    (None, None) => {
+                        tracing::debug!(
+                            "Dropping {:?} message from {}; nothing listens",
+                            frame.tag,
+                            peer
+                        );
+                        continue;
+                    }
+                };
+                // A channel dropped by its owner frees the tag
+                if route.send(frame.payload).await.is_err() {
+                    lock(&reading.routes).remove(&frame.tag);
+                }
+            }
+            reading.close();
+        });
+        lock(&link.tasks).push(reader_task.abort_handle());
+
+        if let Some(every) = config.keepalive {
+            let keeping = Arc::clone(&link);
+            let keepalive_task = crash::spawn(Subsystem::Node, "transport-keepalive", async move {
+                let Some(adaptive) = adaptive else {
+                    let mut interval = tokio::time::interval(every);
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        if keeping.send(MessageTag::Keepalive, &[]).await.is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                };
+                loop {
+                    adaptive.wait(peer.ip(), Probe::Echo, every).await;
+                    if keeping.send(MessageTag::Keepalive, &[]).await.is_err() {
+                        break;
+                    }
+                    let now = adaptive.clock().now_monotonic();
+                    adaptive::record_traffic(Probe::Echo, ECHO_BYTES, now);
+                }
+            });
+            lock(&link.tasks).push(keepalive_task.abort_handle());
+        }
+        link
+    }
+
+    fn open(self: &Arc<Self>, tag: MessageTag) -> Result<Channel, TransportError> {
+        if self.is_closed() {
+            return Err(TransportError::Closed { peer: self.peer });
+        }
+        let mut routes = lock(&self.routes);
+        if routes.get(&tag).is_some_and(|route| !route.is_closed()) {
+            return Err(TransportError::TagInUse {
+                peer: self.peer,
+                tag,
+            });
+        }
+        let (sender, inbound) = mpsc::channel(CHANNEL_QUEUE);
+        routes.insert(tag, sender);
+        Ok(Channel {
+            tag,
+            link: Arc::clone(self),
+            inbound,
+        })
+    }
+
+    async fn send(&self, tag: MessageTag, payload: &[u8]) -> Result<(), TransportError> {
+        if self.is_closed() {
+            return Err(TransportError::Closed { peer: self.peer });
+        }
+        let mut writer = self.writer.lock().await;
+        let result = write_frame(&mut *writer, Codec::Tagged, tag, payload, &self.config).await;
+        if matches!(
+            result,
+            Err(TransportError::IO(_) | TransportError::Timeout { .. })
+        ) {
+            self.close();
+        }
+        result
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Stop the connection's tasks; channels on it see it end
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        lock(&self.routes).clear();
+        for task in lock(&self.tasks).drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Messages of one protocol on a shared connection
+#[derive(Debug)]
+pub struct Channel {
+    tag: MessageTag,
+    link: Arc<Link>,
+    inbound: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Channel {
+    pub fn tag(&self) -> MessageTag {
+        self.tag
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.link.peer
+    }
+
+    pub async fn send(&self, payload: &[u8]) -> Result<(), TransportError> {
+        self.link.send(self.tag, payload).await
+    }
+
+    /// The next message, or `None` once the connection is gone
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inbound.recv().await
+    }
+
+    pub async fn send_json<T: Serialize>(&self, message: &T) -> Result<(), TransportError> {
+        self.send(&serde_json::to_vec(message)?).await
+    }
+
+    pub async fn recv_json<T: DeserializeOwned>(&mut self) -> Result<Option<T>, TransportError> {
+        match self.recv().await {
+            Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TransportPool {
+    config: TransportConfig,
+    /// Connections we opened, by the address we opened them to
+    links: tokio::sync::Mutex<HashMap<SocketAddr, Arc<Link>>>,
+    /// Connections peers opened to us
+    adopted: Mutex<Vec<Arc<Link>>>,
+    shut_down: AtomicBool,
+    /// Stretches keepalive frames on stable links, when set
+    adaptive: Option<Arc<AdaptiveTimers>>,
+}
+
+impl TransportPool {
+    pub fn new(config: TransportConfig) -> Self {
+        TransportPool {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Send keepalive frames as often as each link has earned rather than
+    /// every `keepalive`; without a `keepalive` none are sent either way
+    pub fn with_adaptive_timers(mut self, adaptive: Arc<AdaptiveTimers>) -> Self {
+        self.adaptive = Some(adaptive);
+        self
+    }
+
+    /// A channel for `tag` to `peer`, over the pooled connection when one is
+    /// up and a new one otherwise
+    pub async fn channel(
+        &self,
+        peer: SocketAddr,
+        tag: MessageTag,
+    ) -> Result<Channel, TransportError> {
+        if self.shut_down.load(Ordering::Acquire) {
+            return Err(TransportError::Closed { peer });
+        }
+        // Held while connecting, so two protocols cannot race to connect
+        let mut links = self.links.lock().await;
+        if let Some(link) = links.get(&peer).filter(|link| !link.is_closed()) {
+            return link.open(tag);
+        }
+        let stream = connect_tcp(peer, &self.config).await?;
+        let link = Link::spawn(stream, peer, self.config, None, self.adaptive.clone());
+        links.insert(peer, Arc::clone(&link));
+        tracing::debug!("Opened pooled connection to {}", peer);
+        link.open(tag)
+    }
+
+    /// Share a connection a peer opened; each tag it sends on first opens a
+    /// channel, yielded here
+    pub fn adopt(&self, stream: TcpStream, peer: SocketAddr) -> mpsc::Receiver<Channel> {
+        let (incoming, channels) = mpsc::channel(CHANNEL_QUEUE);
+        if self.shut_down.load(Ordering::Acquire) {
+            return channels;
+        }
+        let link = Link::spawn(
+            stream,
+            peer,
+            self.config,
+            Some(incoming),
+            self.adaptive.clone(),
+        );
+        let mut adopted = lock(&self.adopted);
+        adopted.retain(|link| !link.is_closed());
+        adopted.push(link);
+        channels
+    }
+
+    /// Live connections, opened and adopted
+    pub async fn connections(&self) -> usize {
+        let opened = self
+            .links
+            .lock()
+            .await
+            .values()
+            .filter(|link| !link.is_closed())
+            .count();
+        let adopted = lock(&self.adopted)
+            .iter()
+            .filter(|link| !link.is_closed())
+            .count();
+        opened + adopted
+    }
+
+    /// Close every connection after flushing what was written; channels
+    /// see their connection end and no new ones are handed out
+    pub async fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::Release);
+        let links: Vec<Arc<Link>> = self
+            .links
+            .lock()
+            .await
+            .drain()
+            .map(|(_, link)| link)
+            .chain(lock(&self.adopted).drain(..))
+            .collect();
+        for link in links {
+            {
+                use tokio::io::AsyncWriteExt;
+                let mut writer = link.writer.lock().await;
+                let _ = writer.shutdown().await;
+            }
+            link.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_tags_share_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = TransportPool::default();
+        let client = TransportPool::default();
+
+        let mut bgp = client.channel(addr, MessageTag::Bgp).await.unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let mut incoming = server.adopt(stream, peer);
+
+        bgp.send(b"open").await.unwrap();
+        let mut theirs = incoming.recv().await.unwrap();
+        assert_eq!(theirs.tag(), MessageTag::Bgp);
+        assert_eq!(theirs.recv().await.unwrap(), b"open");
+        theirs.send(b"open reply").await.unwrap();
+        assert_eq!(bgp.recv().await.unwrap(), b"open reply");
+
+        assert!(matches!(
+            client.channel(addr, MessageTag::Bgp).await,
+            Err(TransportError::TagInUse { .. })
+        ));
+        let _sync = client.channel(addr, MessageTag::ZoneSync).await.unwrap();
+        assert_eq!(client.connections().await, 1);
+        let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(accepted.is_err(), "a second connection was opened");
+    }
+}
@@ -0,0 +1,272 @@
+//! Peers taken down administratively.
+//!
+//! Deleting a peer to stop exchanging routes with it loses its
+//! configuration and history. A peer that is down administratively keeps
+//! its place in the peer table, with its metrics, but its tunnel is closed
+//! after a goodbye, its routes are withdrawn, and reconnection, discovery
+//! and peer selection leave it alone; sessions it opens to us are refused.
+//! Its announcements still update the directory.
+//!
+//! Peers are taken down by ASN, either in the config file (`enabled =
+//! false` on a `network.bgp.peers` entry) or at runtime with `vx0net peers
+//! disable`. Runtime entries are kept in the store so they survive
+//! restarts; entries from the config file can only be lifted there.
+
+use crate::config::Vx0Config;
+use crate::node::goodbye::GoodbyeReason;
+use crate::node::{NodeError, PeerEvent, Vx0Node};
+use crate::storage::{Namespace, StorageError};
+use crate::util::sync::{read, write};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::sync::RwLock;
+
+/// Store namespace holding peers taken down at runtime
+pub const NAMESPACE: &str = "admin_down";
+
+/// Who took a peer down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminSource {
+    /// `enabled = false` in the config file
+    Config,
+    /// `vx0net peers disable`
+    Operator,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AdminDown {
+    pub asn: u32,
+    pub source: AdminSource,
+    pub since: DateTime<Utc>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("AS{asn} is disabled in the config file; set enabled = true there")]
+    Configured { asn: u32 },
+    #[error("Cannot keep the administrative state of AS{asn}")]
+    Persist {
+        asn: u32,
+        #[source]
+        source: StorageError,
+    },
+    #[error("Cannot restore peers taken down administratively")]
+    Restore(#[source] StorageError),
+}
+
+#[derive(Debug, Default)]
+pub struct AdminRegistry {
+    down: RwLock<BTreeMap<u32, AdminDown>>,
+    storage: RwLock<Option<Namespace>>,
+}
+
+impl AdminRegistry {
+    /// Peers the config file disables
+    pub fn from_config(config: &Vx0Config) -> Self {
+        let since = Utc::now();
+        let down = config
+            .network
+            .bgp
+            .peers
+            .iter()
+            .filter(|peer| !peer.enabled)
+            .map(|peer| {
+                let entry = AdminDown {
+                    asn: peer.asn,
+                    source: AdminSource::Config,
+                    since,
+                };
+                (peer.asn, entry)
+            })
+            .collect();
+        AdminRegistry {
+            down: RwLock::new(down),
+            storage: RwLock::new(None),
+        }
+    }
+
+    /// Restore the peers taken down at runtime from `storage`, and keep
+    /// later changes there; returns how many were restored
+    pub fn open(&self, storage: Namespace) -> Result<usize, AdminError> {
+        let stored = storage
+            .iter_prefix::<AdminDown>("")
+            .map_err(AdminError::Restore)?;
+        let mut down = write(&self.down);
+        let mut restored = 0;
+        for (_, entry) in stored {
+            if let Entry::Vacant(slot) = down.entry(entry.asn) {
+                slot.insert(entry);
+                restored += 1;
+            }
+        }
+        *write(&self.storage) = Some(storage);
+        Ok(restored)
+    }
+
+    pub fn is_down(&self, asn: u32) -> bool {
+        read(&self.down).contains_key(&asn)
+    }
+
+    /// Every peer taken down, by ASN
+    pub fn list(&self) -> Vec<AdminDown> {
+        read(&self.down).values().cloned().collect()
+    }
+
+    /// Take a peer down; returns whether it was up
+    pub fn disable(&self, asn: u32) -> Result<bool, AdminError> {
+        let mut down = write(&self.down);
+        if down.contains_key(&asn) {
+            return Ok(false);
+        }
+        let entry = AdminDown {
+            asn,
+            source: AdminSource::Operator,
+            since: Utc::now(),
+        };
+        if let Some(storage) = &*read(&self.storage) {
+            storage
+                .put(&asn.to_string(), &entry)
+                .map_err(|source| AdminError::Persist { asn, source })?;
+        }
+        down.insert(asn, entry);
+        Ok(true)
+    }
+
+    /// Bring a peer taken down at runtime back up; returns whether it was
+    /// down
+    pub fn enable(&self, asn: u32) -> Result<bool, AdminError> {
+        let mut down = write(&self.down);
+        match down.get(&asn).map(|entry| entry.source) {
+            None => return Ok(false),
+            Some(AdminSource::Config) => return Err(AdminError::Configured { asn }),
+            Some(AdminSource::Operator) => {}
+        }
+        if let Some(storage) = &*read(&self.storage) {
+            storage
+                .delete(&asn.to_string())
+                .map_err(|source| AdminError::Persist { asn, source })?;
+        }
+        down.remove(&asn);
+        Ok(true)
+    }
+}
+
+impl Vx0Node {
+    /// Stop exchanging routes with the peers on `asn` while keeping them:
+    /// say goodbye, close their tunnels and withdraw their routes. Returns
+    /// how many peers were taken down.
+    pub async fn disable_peer(&self, asn: u32) -> Result<usize, NodeError> {
+        let newly = self.admin.disable(asn)?;
+        let mut taken_down = 0;
+        for handle in self.peer_handles().await {
+            if handle.peer_asn() != asn {
+                continue;
+            }
+            let peer_id = handle.peer_id();
+            if let Err(e) = self
+                .say_goodbye(peer_id, GoodbyeReason::PeerRemoved, None)
+                .await
+            {
+                tracing::debug!("No goodbye for peer {}: {}", peer_id, e);
+            }
+            handle.set_admin_down(true).await?;
+            taken_down += 1;
+        }
+        // Sessions can exist without a peer entry, so this goes out regardless
+        let _ = self
+            .peer_events
+            .send(PeerEvent::AdminDown { peer_asn: asn });
+        if newly {
+            tracing::warn!(
+                target: "audit",
+                "Took AS{} down administratively ({} peer(s))",
+                asn,
+                taken_down
+            );
+        }
+        Ok(taken_down)
+    }
+
+    /// Let the peers on `asn` reconnect as usual; returns how many there are
+    pub async fn enable_peer(&self, asn: u32) -> Result<usize, NodeError> {
+        let was_down = self.admin.enable(asn)?;
+        let mut brought_up = 0;
+        for handle in self.peer_handles().await {
+            if handle.peer_asn() == asn {
+                handle.set_admin_down(false).await?;
+                brought_up += 1;
+            }
+        }
+        if was_down {
+            tracing::warn!(
+                target: "audit",
+                "Brought AS{} back up ({} peer(s))",
+                asn,
+                brought_up
+            );
+        }
+        Ok(brought_up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BGPPeerConfig;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use crate::storage::Store;
+
+    fn config(disabled: &[u32]) -> Vx0Config {
+        let mut config = testing::config(NodeTier::Edge);
+        config.network.bgp.peers = disabled
+            .iter()
+            .map(|&asn| {
+                toml::from_str::<BGPPeerConfig>(&format!(
+                    "address = \"10.1.0.1\"\nasn = {}\nenabled = false",
+                    asn
+                ))
+                .unwrap()
+            })
+            .collect();
+        config
+    }
+
+    #[test]
+    fn test_config_entries_are_lifted_only_there() {
+        let admin = AdminRegistry::from_config(&config(&[65101]));
+        assert!(admin.is_down(65101));
+        assert!(matches!(
+            admin.enable(65101),
+            Err(AdminError::Configured { asn: 65101 })
+        ));
+        assert!(!admin.enable(65102).unwrap());
+        assert!(admin.disable(65102).unwrap());
+        assert!(!admin.disable(65102).unwrap());
+        let sources: Vec<AdminSource> = admin.list().iter().map(|entry| entry.source).collect();
+        assert_eq!(sources, vec![AdminSource::Config, AdminSource::Operator]);
+    }
+
+    #[test]
+    fn test_runtime_entries_survive_reopening() {
+        let root = std::env::temp_dir().join(format!("vx0net-admin-{}", uuid::Uuid::new_v4()));
+        let namespace = || Store::open(&root).unwrap().namespace(NAMESPACE);
+
+        let admin = AdminRegistry::default();
+        assert_eq!(admin.open(namespace()).unwrap(), 0);
+        admin.disable(65101).unwrap();
+        admin.disable(65102).unwrap();
+        admin.enable(65102).unwrap();
+
+        // The config entry for the same ASN stands in for the stored one
+        let reopened = AdminRegistry::from_config(&config(&[65103]));
+        assert_eq!(reopened.open(namespace()).unwrap(), 1);
+        assert!(reopened.is_down(65101));
+        assert!(!reopened.is_down(65102));
+        assert!(reopened.is_down(65103));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,908 @@
+//! Cooperative ASN registry.
+//!
+//! A self-assigned ASN is only as unique as the conflict checks of the
+//! entry points a node happens to join through. Backbone nodes can instead
+//! run a registry: a joiner asks it for an ASN, and it grants the lowest one
+//! free in the joiner's tier, signed with the registry's Ed25519 key.
+//! Registries pull each other's assignment logs and keep, for each ASN, the
+//! grant with the latest signed timestamp.
+//!
+//! Every node keeps the grants it has seen from trusted registries: its own,
+//! its replicas' and those joiners present. A joiner holding a grant wins an
+//! ASN conflict against nodes that picked the ASN themselves, which are told
+//! to pick another.
+//!
+//! Node ids do not survive restarts, so joiners sign their requests with
+//! an Ed25519 holder key kept in their store instead. A node asking again
+//! with the same key gets its ASN back, granted anew to its current node
+//! id; a grant is never handed to a different key, whatever hostname it
+//! claims.
+//!
+//! Any caller the ACL admits may ask for a grant, a few times a minute per
+//! address. Only this host, peers and configured replicas may read the
+//! assignment log, which is sent a page at a time so that every message
+//! fits in a bounded transport frame.
+
+use crate::config::AsnRegistryConfig;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::acl::Contact;
+use crate::network::transport::{
+    self, Codec, MessageStream, MessageTag, TransportConfig, TransportError,
+};
+use crate::node::{NodeId, NodeTier, Vx0Node};
+use crate::storage::{Namespace, StorageError};
+use crate::util::sync::{lock, read, write};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+/// Store namespace holding the signing key and the assignment log
+pub const NAMESPACE: &str = "asn_registry";
+const SIGNING_KEY: &str = "signing_key";
+const HOLDER_KEY: &str = "holder_key";
+const GRANT_PREFIX: &str = "grant/";
+
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Grants each caller address may ask for within `GRANT_WINDOW`
+const GRANTS_PER_WINDOW: usize = 4;
+const GRANT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Grants sent per page of the assignment log, keeping pages well within
+/// the transport's frame limit
+const LOG_PAGE: usize = 64;
+
+fn registry_transport() -> TransportConfig {
+    TransportConfig::default().with_max_frame_len(transport::DEFAULT_MAX_FRAME_LEN)
+}
+
+/// An ASN assigned to a node, as signed by the registry that assigned it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AsnGrant {
+    pub asn: u32,
+    pub node_id: NodeId,
+    pub hostname: String,
+    /// The joiner's holder key; asking again with it gets the ASN back
+    pub holder_key: Vec<u8>,
+    /// Later grants for the same ASN replace earlier ones
+    pub granted_at: DateTime<Utc>,
+    /// Hostname of the registry that signed
+    pub registry: String,
+    /// The registry's Ed25519 public key
+    pub registry_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl AsnGrant {
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "vx0-asn-grant\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            self.asn,
+            self.node_id,
+            self.hostname,
+            hex(&self.holder_key),
+            self.granted_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            self.registry,
+            hex(&self.registry_key)
+        )
+        .into_bytes()
+    }
+
+    /// Whether the signature matches the key the grant names
+    pub fn verify(&self) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, &self.registry_key)
+            .verify(&self.signed_bytes(), &self.signature)
+            .is_ok()
+    }
+
+    pub fn registry_key_hex(&self) -> String {
+        hex(&self.registry_key)
+    }
+
+    /// Last writer wins, ties broken by key so every replica agrees
+    fn supersedes(&self, other: &AsnGrant) -> bool {
+        (self.granted_at, &self.registry_key) > (other.granted_at, &other.registry_key)
+    }
+}
+
+/// What a joiner asks a registry for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrantRequest {
+    pub node_id: NodeId,
+    pub hostname: String,
+    pub tier: NodeTier,
+    /// Granted if free, e.g. the ASN in the joiner's config file
+    #[serde(default)]
+    pub preferred: Option<u32>,
+    /// Set by [`AsnRegistry::sign_request`]
+    #[serde(default)]
+    pub holder_key: Vec<u8>,
+    #[serde(default)]
+    pub signature: Vec<u8>,
+}
+
+impl GrantRequest {
+    pub fn new(node_id: NodeId, hostname: &str, tier: NodeTier, preferred: Option<u32>) -> Self {
+        GrantRequest {
+            node_id,
+            hostname: hostname.to_string(),
+            tier,
+            preferred,
+            holder_key: Vec::new(),
+            signature: Vec::new(),
+        }
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "vx0-asn-grant-request\n{}\n{}\n{:?}\n{}\n{}",
+            self.node_id,
+            self.hostname,
+            self.tier,
+            self.preferred
+                .map_or_else(String::new, |asn| asn.to_string()),
+            hex(&self.holder_key)
+        )
+        .into_bytes()
+    }
+
+    /// Whether the signature matches the holder key the request names
+    pub fn verify(&self) -> bool {
+        UnparsedPublicKey::new(&signature::ED25519, &self.holder_key)
+            .verify(&self.signed_bytes(), &self.signature)
+            .is_ok()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RegistryMessage {
+    Allocate {
+        request: GrantRequest,
+    },
+    Granted {
+        grant: AsnGrant,
+    },
+    /// Ask for the assignment log, from the first ASN above `after`
+    Log {
+        #[serde(default)]
+        after: Option<u32>,
+    },
+    /// One page of the log; `more` when later pages follow
+    Entries {
+        grants: Vec<AsnGrant>,
+        #[serde(default)]
+        more: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("This node does not run an ASN registry")]
+    NotRunning,
+    #[error("No free ASN left in the {tier:?} range")]
+    Exhausted { tier: NodeTier },
+    #[error("Trusted registry key {key:?} is not a hex Ed25519 public key")]
+    BadKey { key: String },
+    #[error("Cannot create or load the registry's signing key")]
+    SigningKey,
+    #[error("Grant request for {hostname} is not signed by the key it names")]
+    Unsigned { hostname: String },
+    #[error("Too many grant requests from {caller}; try again in a minute")]
+    RateLimited { caller: IpAddr },
+    #[error("{caller} is neither a peer nor a replica of this registry")]
+    NotReplica { caller: IpAddr },
+    #[error("Cannot keep the registry's signing key")]
+    PersistKey(#[source] StorageError),
+    #[error("Cannot keep the grant for ASN {asn}")]
+    Persist {
+        asn: u32,
+        #[source]
+        source: StorageError,
+    },
+    #[error("Cannot restore the ASN assignment log")]
+    Restore(#[source] StorageError),
+    #[error("Registry {registry} refused: {message}")]
+    Refused {
+        registry: SocketAddr,
+        message: String,
+    },
+    #[error("Registry {registry} did not answer within {timeout:?}")]
+    Timeout {
+        registry: SocketAddr,
+        timeout: Duration,
+    },
+    #[error("Registry {registry} sent an unexpected reply")]
+    UnexpectedReply { registry: SocketAddr },
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+}
+
+/// The grants a node knows of, and its signing key when it runs a registry
+#[derive(Debug, Default)]
+pub struct AsnRegistry {
+    /// Keys whose grants are honoured; ours joins them once signing
+    trusted: RwLock<HashSet<Vec<u8>>>,
+    /// Grants by ASN; also serializes allocations
+    grants: RwLock<BTreeMap<u32, AsnGrant>>,
+    signer: OnceLock<Ed25519KeyPair>,
+    /// The key this node holds its own grants under
+    holder: OnceLock<Ed25519KeyPair>,
+    storage: RwLock<Option<Namespace>>,
+    /// When each caller address last asked for grants
+    requests: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+impl AsnRegistry {
+    /// Honour grants signed with the configured keys
+    pub fn from_config(config: &AsnRegistryConfig) -> Result<Self, RegistryError> {
+        let trusted = config
+            .trusted_keys
+            .iter()
+            .map(|key| {
+                parse_hex(key)
+                    .filter(|bytes| bytes.len() == 32)
+                    .ok_or_else(|| RegistryError::BadKey { key: key.clone() })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AsnRegistry {
+            trusted: RwLock::new(trusted),
+            ..Default::default()
+        })
+    }
+
+    /// Restore the grants kept in `storage`, and keep later ones there;
+    /// returns how many were restored
+    pub fn open(&self, storage: Namespace) -> Result<usize, RegistryError> {
+        // Our own grants stay trusted before signing resumes
+        let key = storage
+            .get::<Vec<u8>>(SIGNING_KEY)
+            .map_err(RegistryError::Restore)?;
+        if let Some(signer) = key.and_then(|pkcs8| Ed25519KeyPair::from_pkcs8(&pkcs8).ok()) {
+            let key = signer.public_key().as_ref().to_vec();
+            write(&self.trusted).insert(key);
+        }
+        let stored = storage
+            .iter_prefix::<AsnGrant>(GRANT_PREFIX)
+            .map_err(RegistryError::Restore)?;
+        let restored = self.merge(stored.into_iter().map(|(_, grant)| grant));
+        *write(&self.storage) = Some(storage);
+        Ok(restored)
+    }
+
+    /// The key kept in the store under `name`, made and kept there if
+    /// there is none yet
+    fn stored_key(&self, name: &str) -> Result<Ed25519KeyPair, RegistryError> {
+        let storage = read(&self.storage).clone();
+        let stored = match &storage {
+            Some(storage) => storage
+                .get::<Vec<u8>>(name)
+                .map_err(RegistryError::Restore)?,
+            None => None,
+        };
+        let pkcs8 = match stored {
+            Some(pkcs8) => pkcs8,
+            None => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                    .map_err(|_| RegistryError::SigningKey)?
+                    .as_ref()
+                    .to_vec();
+                if let Some(storage) = &storage {
+                    storage
+                        .put(name, &pkcs8)
+                        .map_err(RegistryError::PersistKey)?;
+                }
+                pkcs8
+            }
+        };
+        Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| RegistryError::SigningKey)
+    }
+
+    /// Start signing grants, with the key kept in the store if there is one
+    pub fn enable_signing(&self, hostname: &str) -> Result<(), RegistryError> {
+        if self.signer.get().is_some() {
+            return Ok(());
+        }
+        let signer = self.stored_key(SIGNING_KEY)?;
+        let key = signer.public_key().as_ref().to_vec();
+        write(&self.trusted).insert(key.clone());
+        let _ = self.signer.set(signer);
+        tracing::info!("ASN registry {} signs with key {}", hostname, hex(&key));
+        Ok(())
+    }
+
+    /// Our public key, when running a registry
+    pub fn key(&self) -> Option<Vec<u8>> {
+        self.signer
+            .get()
+            .map(|signer| signer.public_key().as_ref().to_vec())
+    }
+
+    /// Our public key in hex, as other nodes list it in `trusted_keys`
+    pub fn key_hex(&self) -> Option<String> {
+        self.key().map(|key| hex(&key))
+    }
+
+    /// Sign `request` with the key this node holds its grants under, kept in
+    /// the store so that the node gets its ASN back after a restart
+    pub fn sign_request(&self, mut request: GrantRequest) -> Result<GrantRequest, RegistryError> {
+        if self.holder.get().is_none() {
+            let _ = self.holder.set(self.stored_key(HOLDER_KEY)?);
+        }
+        let holder = self.holder.get().ok_or(RegistryError::SigningKey)?;
+        request.holder_key = holder.public_key().as_ref().to_vec();
+        request.signature = holder.sign(&request.signed_bytes()).as_ref().to_vec();
+        Ok(request)
+    }
+
+    /// Count a grant request from `caller` at `now`, refusing it when the
+    /// caller already asked `GRANTS_PER_WINDOW` times within `GRANT_WINDOW`
+    pub fn admit_request(&self, caller: IpAddr, now: Instant) -> Result<(), RegistryError> {
+        let mut requests = lock(&self.requests);
+        requests.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|at| now.saturating_duration_since(*at) >= GRANT_WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = requests.entry(caller).or_default();
+        if times.len() >= GRANTS_PER_WINDOW {
+            return Err(RegistryError::RateLimited { caller });
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    /// Whether `grant` is signed by a registry we trust
+    pub fn is_trusted(&self, grant: &AsnGrant) -> bool {
+        read(&self.trusted).contains(&grant.registry_key) && grant.verify()
+    }
+
+    /// Take in trusted grants newer than what we hold for their ASNs;
+    /// returns how many were taken
+    pub fn merge(&self, grants: impl IntoIterator<Item = AsnGrant>) -> usize {
+        let mut held = write(&self.grants);
+        let mut taken = 0;
+        for grant in grants {
+            if !self.is_trusted(&grant)
+                || held
+                    .get(&grant.asn)
+                    .is_some_and(|current| !grant.supersedes(current))
+            {
+                continue;
+            }
+            if let Err(e) = self.persist(&grant) {
+                tracing::warn!("{}", crate::error::Report(&e));
+            }
+            held.insert(grant.asn, grant);
+            taken += 1;
+        }
+        taken
+    }
+
+    /// Who holds `asn` by grant
+    pub fn holder(&self, asn: u32) -> Option<AsnGrant> {
+        read(&self.grants).get(&asn).cloned()
+    }
+
+    /// Every grant known, by ASN
+    pub fn list(&self) -> Vec<AsnGrant> {
+        read(&self.grants).values().cloned().collect()
+    }
+
+    /// Grant the joiner the ASN its holder key was granted before, else its
+    /// preferred one if free, else the lowest free one in its tier; `in_use`
+    /// are ASNs taken without a grant
+    pub fn allocate(
+        &self,
+        registry: &str,
+        request: &GrantRequest,
+        in_use: &HashSet<u32>,
+    ) -> Result<AsnGrant, RegistryError> {
+        let signer = self.signer.get().ok_or(RegistryError::NotRunning)?;
+        if !request.verify() {
+            return Err(RegistryError::Unsigned {
+                hostname: request.hostname.clone(),
+            });
+        }
+        let (low, high) = request.tier.get_asn_range();
+        let mut held = write(&self.grants);
+
+        let earlier = held
+            .values()
+            .find(|grant| {
+                grant.holder_key == request.holder_key && (low..=high).contains(&grant.asn)
+            })
+            .map(|grant| grant.asn);
+        let free = |asn: &u32| !held.contains_key(asn) && !in_use.contains(asn);
+        let asn = earlier
+            .or(request
+                .preferred
+                .filter(|asn| (low..=high).contains(asn) && free(asn)))
+            .or_else(|| (low..=high).find(free))
+            .ok_or(RegistryError::Exhausted {
+                tier: request.tier.clone(),
+            })?;
+
+        let mut grant = AsnGrant {
+            asn,
+            node_id: request.node_id,
+            hostname: request.hostname.clone(),
+            holder_key: request.holder_key.clone(),
+            // Never older than what it replaces, even across clock steps
+            granted_at: held.get(&asn).map_or_else(Utc::now, |current| {
+                Utc::now().max(current.granted_at + chrono::Duration::microseconds(1))
+            }),
+            registry: registry.to_string(),
+            registry_key: signer.public_key().as_ref().to_vec(),
+            signature: Vec::new(),
+        };
+        grant.signature = signer.sign(&grant.signed_bytes()).as_ref().to_vec();
+        self.persist(&grant)?;
+        held.insert(asn, grant.clone());
+        tracing::info!(
+            target: "audit",
+            "Granted ASN {} to {} ({})",
+            asn,
+            request.hostname,
+            request.node_id
+        );
+        Ok(grant)
+    }
+
+    fn persist(&self, grant: &AsnGrant) -> Result<(), RegistryError> {
+        match &*read(&self.storage) {
+            Some(storage) => storage
+                .put(&format!("{}{}", GRANT_PREFIX, grant.asn), grant)
+                .map_err(|source| RegistryError::Persist {
+                    asn: grant.asn,
+                    source,
+                }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Serve grants and the assignment log on `bind_addr`, returning the bound
+/// address
+pub async fn start(node: Arc<Vx0Node>, bind_addr: SocketAddr) -> Result<SocketAddr, RegistryError> {
+    node.asn_registry.enable_signing(&node.hostname)?;
+    let listener = TcpListener::bind(bind_addr).await?;
+    let local_addr = listener.local_addr()?;
+    tracing::info!("ASN registry listening on {}", local_addr);
+
+    crash::spawn(Subsystem::Node, "asn-registry", async move {
+        loop {
+            let accepted = transport::accept(
+                &listener,
+                Codec::Lines,
+                MessageTag::Join,
+                registry_transport(),
+            )
+            .await;
+            match accepted {
+                Ok((stream, peer)) => {
+                    let node = Arc::clone(&node);
+                    crash::spawn(Subsystem::Node, "asn-registry-client", async move {
+                        let caller = peer.ip();
+                        if !node
+                            .acl
+                            .check(&Contact::address(caller), "ASN registry request")
+                        {
+                            return;
+                        }
+                        if let Err(e) = handle_client(&node, stream, caller).await {
+                            tracing::debug!("ASN registry client {} left: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => tracing::error!("ASN registry accept error: {}", e),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+async fn handle_client(
+    node: &Vx0Node,
+    mut stream: MessageStream<TcpStream>,
+    caller: IpAddr,
+) -> Result<(), RegistryError> {
+    while let Some(message) = stream.recv_json::<RegistryMessage>().await? {
+        let reply = match message {
+            RegistryMessage::Allocate { request } => match allocate(node, &request, caller).await {
+                Ok(grant) => RegistryMessage::Granted { grant },
+                Err(e) => RegistryMessage::Error {
+                    message: e.to_string(),
+                },
+            },
+            RegistryMessage::Log { after } => {
+                if is_replica(node, caller).await {
+                    log_page(&node.asn_registry, after)
+                } else {
+                    RegistryMessage::Error {
+                        message: RegistryError::NotReplica { caller }.to_string(),
+                    }
+                }
+            }
+            other => RegistryMessage::Error {
+                message: format!("Unexpected message: {:?}", other),
+            },
+        };
+        stream.send_json(&reply).await?;
+    }
+    Ok(())
+}
+
+async fn allocate(
+    node: &Vx0Node,
+    request: &GrantRequest,
+    caller: IpAddr,
+) -> Result<AsnGrant, RegistryError> {
+    node.asn_registry
+        .admit_request(caller, node.clock.now_monotonic())?;
+    // Peers that picked their own ASN keep it unless a grant says otherwise
+    let mut in_use: HashSet<u32> = node
+        .list_peers()
+        .await
+        .iter()
+        .filter(|peer| peer.peer_id != request.node_id)
+        .map(|peer| peer.peer_asn)
+        .collect();
+    in_use.insert(node.asn);
+    node.asn_registry.allocate(&node.hostname, request, &in_use)
+}
+
+/// This host, peers and configured replicas may read the assignment log
+async fn is_replica(node: &Vx0Node, caller: IpAddr) -> bool {
+    let configured = node
+        .config
+        .joining
+        .registry
+        .replicas
+        .iter()
+        .filter_map(|replica| replica.parse::<SocketAddr>().ok())
+        .any(|replica| replica.ip() == caller);
+    configured || node.admits_caller(caller, "ASN registry log").await
+}
+
+/// The grants after `after`, at most a page of them
+fn log_page(registry: &AsnRegistry, after: Option<u32>) -> RegistryMessage {
+    let mut grants: Vec<AsnGrant> = registry
+        .list()
+        .into_iter()
+        .filter(|grant| after.is_none_or(|after| grant.asn > after))
+        .take(LOG_PAGE + 1)
+        .collect();
+    let more = grants.len() > LOG_PAGE;
+    grants.truncate(LOG_PAGE);
+    RegistryMessage::Entries { grants, more }
+}
+
+/// Send `message` on `stream` and take the answer, or the registry's refusal
+async fn exchange(
+    stream: &mut MessageStream<TcpStream>,
+    registry: SocketAddr,
+    message: &RegistryMessage,
+) -> Result<RegistryMessage, RegistryError> {
+    stream.send_json(message).await?;
+    match stream
+        .recv_json()
+        .await?
+        .ok_or(RegistryError::UnexpectedReply { registry })?
+    {
+        RegistryMessage::Error { message } => Err(RegistryError::Refused { registry, message }),
+        reply => Ok(reply),
+    }
+}
+
+async fn connect(
+    registry: SocketAddr,
+    limit: Duration,
+) -> Result<MessageStream<TcpStream>, RegistryError> {
+    let config = registry_transport().with_connect_timeout(limit);
+    Ok(transport::connect(registry, Codec::Lines, MessageTag::Join, config).await?)
+}
+
+/// Ask the registry at `registry` for an ASN
+pub async fn request_grant(
+    registry: SocketAddr,
+    request: &GrantRequest,
+    limit: Duration,
+) -> Result<AsnGrant, RegistryError> {
+    let message = RegistryMessage::Allocate {
+        request: request.clone(),
+    };
+    let ask = async {
+        let mut stream = connect(registry, limit).await?;
+        match exchange(&mut stream, registry, &message).await? {
+            RegistryMessage::Granted { grant } => Ok(grant),
+            _ => Err(RegistryError::UnexpectedReply { registry }),
+        }
+    };
+    timeout(limit, ask)
+        .await
+        .map_err(|_| RegistryError::Timeout {
+            registry,
+            timeout: limit,
+        })?
+}
+
+/// The assignment log of the registry at `registry`
+pub async fn fetch_log(registry: SocketAddr) -> Result<Vec<AsnGrant>, RegistryError> {
+    let fetch = async {
+        let mut stream = connect(registry, EXCHANGE_TIMEOUT).await?;
+        let mut log: Vec<AsnGrant> = Vec::new();
+        loop {
+            let after = log.last().map(|grant| grant.asn);
+            match exchange(&mut stream, registry, &RegistryMessage::Log { after }).await? {
+                RegistryMessage::Entries { grants, more } => {
+                    // Pages must move forward, or a registry could keep us here
+                    if grants.first().is_some_and(|grant| Some(grant.asn) <= after)
+                        || (more && grants.is_empty())
+                    {
+                        return Err(RegistryError::UnexpectedReply { registry });
+                    }
+                    log.extend(grants);
+                    if !more {
+                        return Ok(log);
+                    }
+                }
+                _ => return Err(RegistryError::UnexpectedReply { registry }),
+            }
+        }
+    };
+    timeout(EXCHANGE_TIMEOUT, fetch)
+        .await
+        .map_err(|_| RegistryError::Timeout {
+            registry,
+            timeout: EXCHANGE_TIMEOUT,
+        })?
+}
+
+/// Pull the assignment logs of the configured replicas and of Backbone
+/// peers every replication interval
+pub fn start_replication(node: Arc<Vx0Node>) {
+    crash::spawn_restartable(Subsystem::Node, "asn-registry-replication", move || {
+        let node = Arc::clone(&node);
+        async move {
+            let config = &node.config.joining.registry;
+            let mut interval = tokio::time::interval(config.replication_interval.get());
+            loop {
+                interval.tick().await;
+                for replica in replicas(&node).await {
+                    match fetch_log(replica).await {
+                        Ok(grants) => {
+                            let taken = node.asn_registry.merge(grants);
+                            if taken > 0 {
+                                tracing::info!("Took {} ASN grant(s) from {}", taken, replica);
+                            }
+                        }
+                        Err(e) => tracing::debug!("Failed to replicate from {}: {}", replica, e),
+                    }
+                }
+            }
+        }
+    });
+}
+
+async fn replicas(node: &Vx0Node) -> Vec<SocketAddr> {
+    let config = &node.config.joining.registry;
+    let mut replicas: Vec<SocketAddr> = config
+        .replicas
+        .iter()
+        .filter_map(|replica| match replica.parse() {
+            Ok(addr) => Some(addr),
+            Err(_) => {
+                tracing::warn!("Ignoring ASN registry replica {:?}: not ip:port", replica);
+                None
+            }
+        })
+        .collect();
+    for peer in node.list_peers().await {
+        let addr = SocketAddr::new(peer.peer_addr, config.listen_port);
+        if NodeTier::from_asn(peer.peer_asn) == NodeTier::Backbone && !replicas.contains(&addr) {
+            replicas.push(addr);
+        }
+    }
+    replicas
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A request from a freshly started node holding its grants under
+    /// `holder`'s key
+    fn request(holder: &AsnRegistry, hostname: &str, tier: NodeTier) -> GrantRequest {
+        holder
+            .sign_request(GrantRequest::new(
+                uuid::Uuid::new_v4(),
+                hostname,
+                tier,
+                None,
+            ))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_grants_replicate_last_writer_wins() {
+        let first = AsnRegistry::default();
+        first.enable_signing("backbone1").unwrap();
+        let second = AsnRegistry::default();
+        second.enable_signing("backbone2").unwrap();
+        let none = HashSet::new();
+
+        let edge2 = AsnRegistry::default();
+        let edge = request(&AsnRegistry::default(), "edge1", NodeTier::Edge);
+        let early = first.allocate("backbone1", &edge, &none).unwrap();
+        assert_eq!(early.asn, 66000);
+        assert!(early.verify());
+        let late = second
+            .allocate(
+                "backbone2",
+                &request(&edge2, "edge2", NodeTier::Edge),
+                &none,
+            )
+            .unwrap();
+        assert_eq!(late.asn, 66000);
+
+        // Neither trusts the other yet
+        assert_eq!(first.merge(second.list()), 0);
+        let trust = |registry: &AsnRegistry, key: Vec<u8>| {
+            registry.trusted.write().unwrap().insert(key);
+        };
+        trust(&first, second.key().unwrap());
+        trust(&second, first.key().unwrap());
+        assert_eq!(first.merge(second.list()), 1);
+        assert_eq!(second.merge(first.list()), 0);
+        assert_eq!(first.holder(66000), second.holder(66000));
+        assert_eq!(first.holder(66000).unwrap().hostname, "edge2");
+
+        // Tampering breaks the signature
+        let mut forged = late.clone();
+        forged.hostname = "mallory".to_string();
+        forged.granted_at += chrono::Duration::seconds(1);
+        assert!(!forged.verify());
+        assert_eq!(first.merge([forged]), 0);
+
+        // Asking again with the same key gives the same ASN back
+        let again = first
+            .allocate(
+                "backbone1",
+                &request(&edge2, "edge2", NodeTier::Edge),
+                &none,
+            )
+            .unwrap();
+        assert_eq!(again.asn, 66000);
+        assert!(again.supersedes(&late));
+
+        // Claiming the hostname, or the ASN, does not take it from its holder
+        let impostor = AsnRegistry::default();
+        let claimed = GrantRequest {
+            preferred: Some(66000),
+            ..GrantRequest::new(uuid::Uuid::new_v4(), "edge2", NodeTier::Edge, None)
+        };
+        let claimed = impostor.sign_request(claimed).unwrap();
+        let taken = first.allocate("backbone1", &claimed, &none).unwrap();
+        assert_ne!(taken.asn, 66000);
+        assert_eq!(first.holder(66000).unwrap().holder_key, again.holder_key);
+        // Nor does a request naming the holder's key without its signature
+        let mut stolen = request(&impostor, "edge2", NodeTier::Edge);
+        stolen.holder_key = again.holder_key.clone();
+        assert!(matches!(
+            first.allocate("backbone1", &stolen, &none),
+            Err(RegistryError::Unsigned { .. })
+        ));
+
+        let preferred = AsnRegistry::default()
+            .sign_request(GrantRequest::new(
+                uuid::Uuid::new_v4(),
+                "edge3",
+                NodeTier::Edge,
+                Some(66010),
+            ))
+            .unwrap();
+        let in_use = HashSet::from([66001]);
+        assert_eq!(
+            first
+                .allocate("backbone1", &preferred, &in_use)
+                .unwrap()
+                .asn,
+            66010
+        );
+        assert_eq!(
+            first
+                .allocate(
+                    "backbone1",
+                    &request(&AsnRegistry::default(), "edge4", NodeTier::Edge),
+                    &in_use
+                )
+                .unwrap()
+                .asn,
+            66002
+        );
+    }
+
+    #[test]
+    fn test_grant_requests_are_limited_per_caller() {
+        let registry = AsnRegistry::default();
+        let (caller, other) = ("10.9.0.1".parse().unwrap(), "10.9.0.2".parse().unwrap());
+        let start = Instant::now();
+        for _ in 0..GRANTS_PER_WINDOW {
+            registry.admit_request(caller, start).unwrap();
+        }
+        assert!(matches!(
+            registry.admit_request(caller, start),
+            Err(RegistryError::RateLimited { .. })
+        ));
+        registry.admit_request(other, start).unwrap();
+        registry
+            .admit_request(caller, start + GRANT_WINDOW)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_log_is_paged() {
+        let registry = AsnRegistry::default();
+        registry.enable_signing("backbone1").unwrap();
+        let none = HashSet::new();
+        for i in 0..LOG_PAGE + 1 {
+            let edge = request(
+                &AsnRegistry::default(),
+                &format!("edge{}", i),
+                NodeTier::Edge,
+            );
+            registry.allocate("backbone1", &edge, &none).unwrap();
+        }
+        let RegistryMessage::Entries { grants, more } = log_page(&registry, None) else {
+            panic!("expected a page");
+        };
+        assert_eq!((grants.len(), more), (LOG_PAGE, true));
+        let last = grants.last().map(|grant| grant.asn);
+        let RegistryMessage::Entries { grants, more } = log_page(&registry, last) else {
+            panic!("expected a page");
+        };
+        assert_eq!((grants.len(), more), (1, false));
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(parse_hex("0f0"), None);
+        assert_eq!(parse_hex("zz"), None);
+    }
+}
@@ -1,15 +1,80 @@
+use crate::build_info::BuildInfo;
 use crate::config::{BootstrapConfig, BootstrapNode};
+use crate::error::Report;
+use crate::monitoring::capacity::CapacityDigest;
+use crate::monitoring::{crash, Subsystem};
 use crate::network::bgp::protocol::BGPProtocol;
-use crate::node::{NodeError, PeerConnection, Vx0Node};
-use std::net::SocketAddr;
+use crate::node::capabilities::{self, Capabilities};
+use crate::node::joining::VX0_BGP_PORT;
+use crate::node::metadata::Visibility;
+use crate::node::{HostedService, NodeError, NodeTier, PeerConnection, ServiceStatus, Vx0Node};
+use crate::util::backoff::{self, RetryError};
+use crate::wire_limits::MAX_ANNOUNCEMENT_BYTES;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// How often bootstrap nodes are checked for being due a probe
+const HEALTH_PROBE_TICK: Duration = Duration::from_secs(60);
 
 pub struct BootstrapManager {
     node: Arc<Vx0Node>,
     bootstrap_config: Option<BootstrapConfig>,
 }
 
+impl Vx0Node {
+    /// Open a BGP session with a node and add it as a peer
+    pub async fn connect_peer(
+        &self,
+        peer_ip: IpAddr,
+        peer_asn: u32,
+    ) -> Result<PeerConnection, NodeError> {
+        // Check if this node can peer with the other node based on tier rules
+        let peer_tier = NodeTier::from_asn(peer_asn);
+        if !self.tier.can_peer_with(&peer_tier) {
+            return Err(NodeError::TierMismatch {
+                ours: self.tier.clone(),
+                theirs: peer_tier,
+            });
+        }
+        if self.admin.is_down(peer_asn) {
+            return Err(NodeError::AdminDown { asn: peer_asn });
+        }
+        if self
+            .quarantine
+            .is_quarantined(peer_asn, self.clock.now_utc())
+        {
+            return Err(NodeError::Quarantined { asn: peer_asn });
+        }
+
+        let bgp_protocol = BGPProtocol::new(self.asn, self.ipv4_addr.into(), self.tier.clone())
+            .with_capabilities(self.capabilities())
+            .with_keepalive_jitter(self.config.security.obfuscation.keepalive_jitter());
+        let bgp_session = bgp_protocol
+            .connect_to_peer(SocketAddr::new(peer_ip, VX0_BGP_PORT), peer_asn)
+            .await?;
+        tracing::info!(
+            "Established BGP session with {} (offers: {})",
+            peer_ip,
+            bgp_session.peer_capabilities
+        );
+        self.observe_address(peer_ip, bgp_session.observed_addr);
+
+        let mut peer = PeerConnection::new(
+            uuid::Uuid::new_v4(), // We'll get the real node ID later
+            peer_asn,
+            peer_ip,
+        );
+        peer.capabilities = bgp_session.peer_capabilities;
+        self.add_peer(peer.clone()).await?;
+        Ok(peer)
+    }
+}
+
 impl BootstrapManager {
     pub fn new(node: Arc<Vx0Node>, bootstrap_config: Option<BootstrapConfig>) -> Self {
         BootstrapManager {
@@ -25,12 +90,16 @@ impl BootstrapManager {
                 bootstrap.nodes.len()
             );
 
-            for bootstrap_node in &bootstrap.nodes {
-                if let Err(e) = self.connect_to_bootstrap_node(bootstrap_node).await {
+            let ranked = self
+                .node
+                .bootstrap
+                .rank(&bootstrap.nodes, chrono::Utc::now());
+            for bootstrap_node in &ranked {
+                if let Err(e) = self.connect_with_backoff(bootstrap_node).await {
                     tracing::warn!(
                         "Failed to connect to bootstrap node {}: {}",
                         bootstrap_node.hostname,
-                        e
+                        Report(&e)
                     );
                     continue;
                 }
@@ -45,6 +114,19 @@ impl BootstrapManager {
         Ok(())
     }
 
+    /// Retry failures that may clear up on their own; a tier or ASN mismatch won't
+    async fn connect_with_backoff(&self, bootstrap_node: &BootstrapNode) -> Result<(), NodeError> {
+        let policy = self.node.config.network.retry.bootstrap();
+        backoff::retry_with(
+            &policy,
+            &CancellationToken::new(),
+            NodeError::is_transient,
+            || self.connect_to_bootstrap_node(bootstrap_node),
+        )
+        .await
+        .map_err(RetryError::into_inner)
+    }
+
     async fn connect_to_bootstrap_node(
         &self,
         bootstrap_node: &BootstrapNode,
@@ -55,167 +137,174 @@ impl BootstrapManager {
             bootstrap_node.asn
         );
 
-        // Check if this node can peer with the bootstrap node based on tier rules
-        let bootstrap_tier = Self::asn_to_tier(bootstrap_node.asn);
-        if !self.node.tier.can_peer_with(&bootstrap_tier) {
-            return Err(NodeError::Network(format!(
-                "Cannot peer with {} - tier mismatch ({:?} cannot peer with {:?})",
-                bootstrap_node.hostname, self.node.tier, bootstrap_tier
-            )));
-        }
-
         // Parse bootstrap node address
-        let peer_addr: SocketAddr = format!("{}:{}", bootstrap_node.ip, 1179)
-            .parse()
-            .map_err(|e| NodeError::Network(format!("Invalid bootstrap address: {}", e)))?;
-
-        // Attempt BGP connection
-        let bgp_protocol = BGPProtocol::new(
-            self.node.asn,
-            self.node.ipv4_addr.into(),
-            self.node.tier.clone(),
-        );
-
-        match bgp_protocol
-            .connect_to_peer(peer_addr, bootstrap_node.asn)
-            .await
-        {
-            Ok(_bgp_session) => {
-                tracing::info!(
-                    "Successfully established BGP session with {}",
-                    bootstrap_node.hostname
-                );
-
-                // Create peer connection
-                let peer = PeerConnection::new(
-                    uuid::Uuid::new_v4(), // We'll get the real node ID later
-                    bootstrap_node.asn,
-                    bootstrap_node.ip.parse().unwrap(),
-                );
-
-                // Add peer to our node
-                self.node.add_peer(peer).await?;
-
-                tracing::info!("Added {} as peer", bootstrap_node.hostname);
-            }
-            Err(e) => {
-                tracing::error!(
-                    "Failed to establish BGP session with {}: {}",
-                    bootstrap_node.hostname,
-                    e
-                );
-                return Err(NodeError::BGP(format!("BGP connection failed: {}", e)));
+        let peer_ip: IpAddr =
+            bootstrap_node
+                .ip
+                .parse()
+                .map_err(|source| NodeError::InvalidAddress {
+                    kind: "bootstrap node",
+                    address: bootstrap_node.ip.clone(),
+                    source,
+                })?;
+
+        let start = Instant::now();
+        if let Err(e) = self.node.connect_peer(peer_ip, bootstrap_node.asn).await {
+            tracing::debug!(
+                "Failed to peer with {}: {}",
+                bootstrap_node.hostname,
+                Report(&e)
+            );
+            // Refusals say nothing about whether the node is up
+            if e.is_transient() {
+                self.node
+                    .bootstrap
+                    .record_failure(bootstrap_node, chrono::Utc::now());
             }
+            return Err(e);
         }
+        self.node
+            .bootstrap
+            .record_success(bootstrap_node, start.elapsed(), chrono::Utc::now());
+        tracing::info!("Added {} as peer", bootstrap_node.hostname);
 
         Ok(())
     }
 
-    fn asn_to_tier(asn: u32) -> crate::node::NodeTier {
-        match asn {
-            65000..=65099 => crate::node::NodeTier::Backbone,
-            65100..=65999 => crate::node::NodeTier::Regional,
-            66000..=69999 => crate::node::NodeTier::Edge,
-            _ => crate::node::NodeTier::Edge,
-        }
-    }
-
     pub async fn start_periodic_discovery(&self) {
         let bootstrap_config = self.bootstrap_config.clone();
         let node = Arc::clone(&self.node);
 
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(300)); // Every 5 minutes
-
-            loop {
-                interval.tick().await;
-
-                if let Some(bootstrap) = &bootstrap_config {
-                    // Check if we need more peers
-                    let current_peers = node.get_peer_count().await;
-                    let max_peers = node.tier.max_peers();
-
-                    if current_peers < max_peers / 2 {
-                        // If we have less than half our max peers
-                        tracing::info!(
-                            "Low peer count ({}/{}), attempting to discover more peers",
-                            current_peers,
-                            max_peers
-                        );
-
-                        // Try to connect to more bootstrap nodes
-                        for bootstrap_node in &bootstrap.nodes {
-                            if current_peers >= max_peers {
-                                break;
+        crash::spawn_restartable(Subsystem::Node, "periodic-discovery", move || {
+            let bootstrap_config = bootstrap_config.clone();
+            let node = Arc::clone(&node);
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(300)); // Every 5 minutes
+
+                loop {
+                    interval.tick().await;
+
+                    if let Some(bootstrap) = &bootstrap_config {
+                        // Check if we need more peers
+                        let current_peers = node.get_peer_count().await;
+                        let max_peers = node.tier.max_peers();
+
+                        if current_peers < max_peers / 2 {
+                            // If we have less than half our max peers
+                            tracing::info!(
+                                "Low peer count ({}/{}), attempting to discover more peers",
+                                current_peers,
+                                max_peers
+                            );
+
+                            // Try to connect to more bootstrap nodes
+                            let ranked = node.bootstrap.rank(&bootstrap.nodes, chrono::Utc::now());
+                            for bootstrap_node in &ranked {
+                                if current_peers >= max_peers {
+                                    break;
+                                }
+
+                                // Check if we're already connected to this node
+                                if Self::is_already_connected(&node, bootstrap_node).await {
+                                    continue;
+                                }
+
+                                let bootstrap_manager =
+                                    BootstrapManager::new(Arc::clone(&node), None);
+                                if let Err(e) = bootstrap_manager
+                                    .connect_to_bootstrap_node(bootstrap_node)
+                                    .await
+                                {
+                                    tracing::debug!(
+                                        "Periodic discovery connection failed: {}",
+                                        Report(&e)
+                                    );
+                                }
                             }
+                        }
+                    }
+                }
+            }
+        });
+    }
 
-                            // Check if we're already connected to this node
-                            if Self::is_already_connected(&node, bootstrap_node).await {
-                                continue;
-                            }
+    /// Probe bootstrap nodes as they fall due, keeping their health scores
+    /// current and giving quarantined ones a chance to recover
+    pub fn start_health_probes(&self) {
+        let node = Arc::clone(&self.node);
 
-                            let bootstrap_manager = BootstrapManager::new(Arc::clone(&node), None);
-                            if let Err(e) = bootstrap_manager
-                                .connect_to_bootstrap_node(bootstrap_node)
-                                .await
-                            {
-                                tracing::debug!("Periodic discovery connection failed: {}", e);
-                            }
-                        }
+        crash::spawn_restartable(Subsystem::Node, "bootstrap-health-probes", move || {
+            let node = Arc::clone(&node);
+            async move {
+                let limit = Duration::from_secs(node.config.joining.connect_timeout_secs);
+                let mut interval = tokio::time::interval(HEALTH_PROBE_TICK);
+                loop {
+                    interval.tick().await;
+                    let due = node.bootstrap.due(chrono::Utc::now());
+                    if due.is_empty() {
+                        continue;
+                    }
+                    let reached = node.bootstrap.probe(&due, limit).await;
+                    tracing::debug!(
+                        "Probed {} bootstrap nodes, {} reachable",
+                        due.len(),
+                        reached
+                    );
+                    if let Err(e) = node.bootstrap.save() {
+                        tracing::warn!("Failed to save bootstrap health: {}", Report(&e));
                     }
                 }
             }
         });
     }
 
-    async fn is_already_connected(node: &Arc<Vx0Node>, bootstrap_node: &BootstrapNode) -> bool {
-        let peers = node.peers.read().await;
-        for peer in peers.values() {
-            if peer.peer_asn == bootstrap_node.asn {
-                return true;
+    /// Re-announce this node whenever its capabilities change, coalescing
+    /// bursts of changes over the configured delay
+    pub fn start_capability_announcements(&self) {
+        let node = Arc::clone(&self.node);
+        let bootstrap_config = self.bootstrap_config.clone();
+
+        crash::spawn_restartable(Subsystem::Node, "capability-announcer", move || {
+            let manager = BootstrapManager::new(Arc::clone(&node), bootstrap_config.clone());
+            async move {
+                let mut changes = manager.node.subscribe_capabilities();
+                let delay = manager.node.capability_announce_delay();
+                while let Some(offered) = capabilities::next_change(&mut changes, delay).await {
+                    tracing::info!("Capabilities changed to {}; re-announcing", offered);
+                    if let Err(e) = manager.announce_to_network().await {
+                        tracing::warn!("Failed to re-announce capabilities: {}", e);
+                    }
+                }
             }
-        }
-        false
+        });
+    }
+
+    async fn is_already_connected(node: &Arc<Vx0Node>, bootstrap_node: &BootstrapNode) -> bool {
+        node.peer_handles()
+            .await
+            .iter()
+            .any(|peer| peer.peer_asn() == bootstrap_node.asn)
     }
 
     pub async fn announce_to_network(&self) -> Result<(), NodeError> {
         tracing::info!("Announcing node to VX0 network");
 
-        // Create announcement with our node information
-        let announcement = NodeAnnouncement {
-            node_id: self.node.node_id,
-            hostname: self.node.hostname.clone(),
-            asn: self.node.asn,
-            tier: self.node.tier.clone(),
-            ipv4_addr: self.node.ipv4_addr,
-            services: self.get_service_summary().await,
-            timestamp: chrono::Utc::now(),
-        };
+        let announcement = self.node.announcement().await;
 
         // Send announcement to all connected peers
-        let peers = self.node.peers.read().await;
-        for (peer_id, peer) in peers.iter() {
-            if let Err(e) = self.send_announcement_to_peer(&announcement, peer).await {
-                tracing::warn!("Failed to send announcement to peer {}: {}", peer_id, e);
+        for peer in self.node.list_peers().await {
+            if let Err(e) = self.send_announcement_to_peer(&announcement, &peer).await {
+                tracing::warn!(
+                    "Failed to send announcement to peer {}: {}",
+                    peer.peer_id,
+                    e
+                );
             }
         }
 
         Ok(())
     }
 
-    async fn get_service_summary(&self) -> Vec<ServiceSummary> {
-        let services = self.node.services.read().await;
-        services
-            .iter()
-            .map(|service| ServiceSummary {
-                name: service.name.clone(),
-                domain: service.domain.clone(),
-                service_type: service.service_type.clone(),
-                port: service.port,
-            })
-            .collect()
-    }
-
     async fn send_announcement_to_peer(
         &self,
         _announcement: &NodeAnnouncement,
@@ -227,7 +316,71 @@ impl BootstrapManager {
     }
 }
 
-#[derive(Debug, Clone)]
+impl Vx0Node {
+    /// What this node tells the network about itself. Public services are
+    /// summarized within the announcement budget, running ones first and
+    /// then the most recently registered; when some are left out the
+    /// announcement says so, and peers query for the full list.
+    pub async fn announcement(&self) -> NodeAnnouncement {
+        let services = self.services.read().await;
+        let mut public: Vec<(usize, &HostedService)> = services
+            .iter()
+            .enumerate()
+            .filter(|(_, service)| service.metadata.visibility == Visibility::Public)
+            .collect();
+        public.sort_by_key(|(registered, service)| {
+            (
+                service.status != ServiceStatus::Running,
+                Reverse(*registered),
+            )
+        });
+        let total_services = public.len();
+
+        let mut announcement = NodeAnnouncement {
+            node_id: self.node_id,
+            hostname: self.hostname.clone(),
+            asn: self.asn,
+            tier: self.tier.clone(),
+            ipv4_addr: self.public_ipv4(),
+            services: public
+                .into_iter()
+                .take(self.config.services.max_announced_services)
+                .map(|(_, service)| ServiceSummary {
+                    name: service.name.clone(),
+                    domain: service.domain.clone(),
+                    service_type: service.service_type.clone(),
+                    port: service.port,
+                })
+                .collect(),
+            total_services,
+            truncated: false,
+            capabilities: self.capabilities(),
+            build: BuildInfo::current(),
+            location: self.stated_location().to_string(),
+            // Edge nodes see too little of the directory to speak for it
+            network_health: match self.tier {
+                NodeTier::Edge => None,
+                _ => self.capacity.digest(),
+            },
+            timestamp: chrono::Utc::now(),
+        };
+        // Long names can still overrun the byte budget; drop from the end
+        while announcement.encoded_len() > MAX_ANNOUNCEMENT_BYTES
+            && announcement.services.pop().is_some()
+        {}
+        announcement.truncated = announcement.services.len() < total_services;
+        if announcement.truncated {
+            tracing::debug!(
+                "Announcing {} of {} services",
+                announcement.services.len(),
+                total_services
+            );
+        }
+        announcement
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeAnnouncement {
     pub node_id: uuid::Uuid,
     pub hostname: String,
@@ -235,10 +388,37 @@ pub struct NodeAnnouncement {
     pub tier: crate::node::NodeTier,
     pub ipv4_addr: std::net::Ipv4Addr,
     pub services: Vec<ServiceSummary>,
+    /// Public services the node hosts, counting any left out of `services`;
+    /// 0 from nodes that predate announcement budgets
+    #[serde(default)]
+    pub total_services: usize,
+    /// `services` is a selection; a service query for `node_id` returns
+    /// them all
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// Roles the node offers; absent from nodes that predate capabilities
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Lets the network spot version skew between nodes
+    #[serde(default)]
+    pub build: BuildInfo,
+    /// Where the operator says the node is; empty if they did not
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub location: String,
+    /// Per-tier statistics, from Backbone and Regional nodes only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_health: Option<CapacityDigest>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Clone)]
+impl NodeAnnouncement {
+    /// Bytes the announcement takes serialized
+    pub fn encoded_len(&self) -> usize {
+        serde_json::to_vec(self).map_or(usize::MAX, |encoded| encoded.len())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceSummary {
     pub name: String,
     pub domain: String,
@@ -0,0 +1,429 @@
+//! Health of the bootstrap nodes used as entry points.
+//!
+//! Bootstrap lists gather dead hosts over time, and every join or discovery
+//! round would otherwise spend a connect timeout on each. Every attempt is
+//! scored here: consecutive failures, the last success and the median
+//! connect latency. Attempts go to the best-scored nodes first, and nodes
+//! that have failed for longer than the quarantine period are skipped,
+//! except for a probe every `reprobe_interval_secs` so they can recover.
+//! Nodes learned from join answers join the same pool. Scores are kept in
+//! `state_file` across restarts.
+
+use crate::config::{BootstrapHealthConfig, BootstrapNode};
+use crate::node::joining::VX0_BGP_PORT;
+use crate::node::NodeError;
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Successful connects whose latency is kept for the median
+const LATENCY_SAMPLES: usize = 9;
+/// Nodes probed at once
+const PROBE_PARALLELISM: usize = 8;
+
+/// Where an entry came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BootstrapSource {
+    /// The public list compiled into the daemon
+    Builtin,
+    /// The `[bootstrap]` config section
+    Configured,
+    /// Suggested by another node while joining
+    Learned,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct BootstrapHealth {
+    pub consecutive_failures: u32,
+    /// When the current run of failures began
+    #[serde(default)]
+    pub failing_since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_success: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// Connect latencies of the most recent successes, oldest first
+    #[serde(default)]
+    pub latencies_ms: Vec<u64>,
+}
+
+impl BootstrapHealth {
+    pub fn median_latency_ms(&self) -> Option<u64> {
+        let mut sorted = self.latencies_ms.clone();
+        sorted.sort_unstable();
+        sorted.get(sorted.len() / 2).copied()
+    }
+
+    /// 0 to 100, higher is better: halved by each consecutive failure, then
+    /// up to 20 points off for latency; nodes never reached lose 10
+    pub fn score(&self) -> u32 {
+        let base = 100u32 >> self.consecutive_failures.min(7);
+        let penalty = self
+            .median_latency_ms()
+            .map_or(10, |latency| (latency / 50).min(20) as u32);
+        base.saturating_sub(penalty)
+    }
+
+    pub fn quarantined(&self, now: DateTime<Utc>, quarantine: chrono::Duration) -> bool {
+        self.failing_since
+            .is_some_and(|since| now - since > quarantine)
+    }
+
+    fn success(&mut self, latency: Duration, now: DateTime<Utc>) {
+        self.consecutive_failures = 0;
+        self.failing_since = None;
+        self.last_success = Some(now);
+        self.last_attempt = Some(now);
+        self.latencies_ms.push(latency.as_millis() as u64);
+        if self.latencies_ms.len() > LATENCY_SAMPLES {
+            self.latencies_ms.remove(0);
+        }
+    }
+
+    fn failure(&mut self, now: DateTime<Utc>) {
+        self.consecutive_failures += 1;
+        self.failing_since.get_or_insert(now);
+        self.last_attempt = Some(now);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BootstrapEntry {
+    node: BootstrapNode,
+    source: BootstrapSource,
+    health: BootstrapHealth,
+}
+
+impl BootstrapEntry {
+    fn is(&self, node: &BootstrapNode) -> bool {
+        self.node.ip == node.ip && self.node.asn == node.asn
+    }
+}
+
+/// A bootstrap node as `vx0net bootstrap list` shows it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct BootstrapStatus {
+    pub hostname: String,
+    pub ip: String,
+    pub asn: u32,
+    pub source: BootstrapSource,
+    pub score: u32,
+    /// Skipped until a probe reaches it again
+    pub quarantined: bool,
+    #[serde(flatten)]
+    pub health: BootstrapHealth,
+}
+
+#[derive(Debug)]
+pub struct BootstrapRegistry {
+    quarantine: chrono::Duration,
+    probe_interval: chrono::Duration,
+    reprobe_interval: chrono::Duration,
+    state_file: Option<PathBuf>,
+    entries: Mutex<Vec<BootstrapEntry>>,
+}
+
+impl BootstrapRegistry {
+    /// Restore scores kept in `state_file`; unreadable scores are dropped,
+    /// since they only order attempts
+    pub fn open(config: &BootstrapHealthConfig) -> Self {
+        let state_file = config.state_file.as_ref().map(PathBuf::from);
+        let entries = match &state_file {
+            Some(path) if path.exists() => std::fs::read(path)
+                .map_err(NodeError::from)
+                .and_then(|data| Ok(serde_json::from_slice(&data)?))
+                .unwrap_or_else(|e| {
+                    tracing::warn!("Ignoring bootstrap health in {}: {}", path.display(), e);
+                    Vec::new()
+                }),
+            _ => Vec::new(),
+        };
+        let secs = |secs: u64| chrono::Duration::seconds(secs as i64);
+        BootstrapRegistry {
+            quarantine: secs(config.quarantine_secs),
+            probe_interval: secs(config.probe_interval_secs),
+            reprobe_interval: secs(config.reprobe_interval_secs),
+            state_file,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, Vec<BootstrapEntry>> {
+        lock(&self.entries)
+    }
+
+    /// Score a node from now on; one already known keeps its score, and
+    /// is no longer counted as learned once an operator lists it
+    pub fn add(&self, node: BootstrapNode, source: BootstrapSource) {
+        let mut entries = self.entries();
+        match entries.iter_mut().find(|entry| entry.is(&node)) {
+            Some(entry) => {
+                if source != BootstrapSource::Learned {
+                    entry.source = source;
+                }
+                entry.node.hostname = node.hostname;
+            }
+            None => entries.push(BootstrapEntry {
+                node,
+                source,
+                health: BootstrapHealth::default(),
+            }),
+        }
+    }
+
+    pub fn record_success(&self, node: &BootstrapNode, latency: Duration, now: DateTime<Utc>) {
+        self.update(node, |health| health.success(latency, now));
+    }
+
+    pub fn record_failure(&self, node: &BootstrapNode, now: DateTime<Utc>) {
+        self.update(node, |health| health.failure(now));
+    }
+
+    /// Nodes not being scored are left alone
+    fn update(&self, node: &BootstrapNode, change: impl FnOnce(&mut BootstrapHealth)) {
+        if let Some(entry) = self.entries().iter_mut().find(|entry| entry.is(node)) {
+            change(&mut entry.health);
+        }
+    }
+
+    fn health(&self, node: &BootstrapNode) -> BootstrapHealth {
+        self.entries()
+            .iter()
+            .find(|entry| entry.is(node))
+            .map(|entry| entry.health.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether a quarantined node is due for another probe
+    fn due_for_reprobe(&self, health: &BootstrapHealth, now: DateTime<Utc>) -> bool {
+        health
+            .last_attempt
+            .is_none_or(|at| now - at >= self.reprobe_interval)
+    }
+
+    /// `nodes` in the order to try them: best score first, then
+    /// quarantined ones due for a probe; other quarantined ones are left out
+    pub fn rank(&self, nodes: &[BootstrapNode], now: DateTime<Utc>) -> Vec<BootstrapNode> {
+        let mut healthy = Vec::new();
+        let mut reprobe = Vec::new();
+        for node in nodes {
+            let health = self.health(node);
+            if !health.quarantined(now, self.quarantine) {
+                healthy.push((health.score(), node.clone()));
+            } else if self.due_for_reprobe(&health, now) {
+                reprobe.push(node.clone());
+            }
+        }
+        // Stable, so equal scores keep the caller's order
+        healthy.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        healthy
+            .into_iter()
+            .map(|(_, node)| node)
+            .chain(reprobe)
+            .collect()
+    }
+
+    /// Nodes to probe at `now`: healthy ones every `probe_interval_secs`,
+    /// quarantined ones every `reprobe_interval_secs`
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<BootstrapNode> {
+        self.entries()
+            .iter()
+            .filter(|entry| {
+                let interval = if entry.health.quarantined(now, self.quarantine) {
+                    self.reprobe_interval
+                } else {
+                    self.probe_interval
+                };
+                entry
+                    .health
+                    .last_attempt
+                    .is_none_or(|at| now - at >= interval)
+            })
+            .map(|entry| entry.node.clone())
+            .collect()
+    }
+
+    pub fn nodes(&self) -> Vec<BootstrapNode> {
+        self.entries()
+            .iter()
+            .map(|entry| entry.node.clone())
+            .collect()
+    }
+
+    /// Every node, best score first
+    pub fn status(&self, now: DateTime<Utc>) -> Vec<BootstrapStatus> {
+        let mut status: Vec<BootstrapStatus> = self
+            .entries()
+            .iter()
+            .map(|entry| BootstrapStatus {
+                hostname: entry.node.hostname.clone(),
+                ip: entry.node.ip.clone(),
+                asn: entry.node.asn,
+                source: entry.source,
+                score: entry.health.score(),
+                quarantined: entry.health.quarantined(now, self.quarantine),
+                health: entry.health.clone(),
+            })
+            .collect();
+        status.sort_by_key(|node| (node.quarantined, std::cmp::Reverse(node.score)));
+        status
+    }
+
+    /// Probe each node's BGP port, recording the outcomes; returns how many
+    /// answered
+    pub async fn probe(&self, nodes: &[BootstrapNode], limit: Duration) -> usize {
+        let outcomes: Vec<(BootstrapNode, Option<Duration>)> = stream::iter(nodes.to_vec())
+            .map(|node| async move {
+                let latency = probe_node(&node, limit).await;
+                (node, latency)
+            })
+            .buffer_unordered(PROBE_PARALLELISM)
+            .collect()
+            .await;
+        let now = Utc::now();
+        let mut reached = 0;
+        for (node, latency) in outcomes {
+            match latency {
+                Some(latency) => {
+                    reached += 1;
+                    self.record_success(&node, latency, now);
+                }
+                None => self.record_failure(&node, now),
+            }
+        }
+        reached
+    }
+
+    pub fn save(&self) -> Result<(), NodeError> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(&*self.entries())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Time for a node's BGP port to accept a connection, `None` if it did not
+/// within `limit`; nodes listed without a literal address are resolved
+pub async fn probe_node(node: &BootstrapNode, limit: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    let connect = async {
+        match node.ip.parse::<IpAddr>() {
+            Ok(ip) => TcpStream::connect((ip, VX0_BGP_PORT)).await,
+            Err(_) => TcpStream::connect((node.hostname.as_str(), VX0_BGP_PORT)).await,
+        }
+    };
+    match tokio::time::timeout(limit, connect).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(hostname: &str, ip: &str) -> BootstrapNode {
+        BootstrapNode {
+            hostname: hostname.to_string(),
+            ip: ip.to_string(),
+            asn: 65001,
+        }
+    }
+
+    fn config(state_file: Option<PathBuf>) -> BootstrapHealthConfig {
+        BootstrapHealthConfig {
+            state_file: state_file.map(|path| path.display().to_string()),
+            quarantine_secs: 3600,
+            probe_interval_secs: 60,
+            reprobe_interval_secs: 600,
+        }
+    }
+
+    #[test]
+    fn test_failing_node_is_quarantined_reprobed_slowly_and_recovers() {
+        let registry = BootstrapRegistry::open(&config(None));
+        let dead = node("dead.vx0.network", "192.0.2.1");
+        let alive = node("alive.vx0.network", "192.0.2.2");
+        registry.add(dead.clone(), BootstrapSource::Builtin);
+        registry.add(alive.clone(), BootstrapSource::Configured);
+        let start = Utc::now();
+        let minutes = |m: i64| start + chrono::Duration::minutes(m);
+        registry.record_success(&alive, Duration::from_millis(40), start);
+
+        // Failing, but not for long enough to be set aside
+        for m in 0..30 {
+            registry.record_failure(&dead, minutes(m));
+        }
+        let both = [dead.clone(), alive.clone()];
+        assert_eq!(
+            registry.rank(&both, minutes(30)),
+            vec![alive.clone(), dead.clone()]
+        );
+
+        // Past the quarantine period it is skipped between slow re-probes
+        for m in 30..62 {
+            registry.record_failure(&dead, minutes(m));
+        }
+        assert_eq!(registry.rank(&both, minutes(62)), vec![alive.clone()]);
+        assert!(!registry.due(minutes(62)).contains(&dead));
+        assert!(registry.due(minutes(62)).contains(&alive));
+        assert_eq!(
+            registry.rank(&both, minutes(71)),
+            vec![alive.clone(), dead.clone()]
+        );
+        assert!(registry.due(minutes(71)).contains(&dead));
+        let status = registry.status(minutes(71));
+        assert!(status[1].quarantined);
+        assert_eq!(status[1].health.consecutive_failures, 62);
+
+        // A re-probe that gets through returns it to the pool
+        registry.record_success(&dead, Duration::from_millis(20), minutes(71));
+        assert_eq!(registry.rank(&both, minutes(72)), vec![dead, alive]);
+        assert!(registry
+            .status(minutes(72))
+            .iter()
+            .all(|node| !node.quarantined));
+    }
+
+    #[test]
+    fn test_scores_survive_restart() {
+        let path =
+            std::env::temp_dir().join(format!("vx0net-bootstrap-{}.json", uuid::Uuid::new_v4()));
+        let registry = BootstrapRegistry::open(&config(Some(path.clone())));
+        let learned = node("learned.vx0.network", "192.0.2.3");
+        registry.add(learned.clone(), BootstrapSource::Learned);
+        registry.record_failure(&learned, Utc::now());
+        registry.save().unwrap();
+
+        let reopened = BootstrapRegistry::open(&config(Some(path.clone())));
+        let status = reopened.status(Utc::now());
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].source, BootstrapSource::Learned);
+        assert_eq!(status[0].health.consecutive_failures, 1);
+        assert_eq!(status[0].score, 40);
+
+        // Listing it later as configured keeps its score
+        reopened.add(learned, BootstrapSource::Configured);
+        assert_eq!(
+            reopened.status(Utc::now())[0].health.consecutive_failures,
+            1
+        );
+        assert_eq!(
+            reopened.status(Utc::now())[0].source,
+            BootstrapSource::Configured
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
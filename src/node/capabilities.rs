@@ -0,0 +1,732 @@
+//! Capabilities a node advertises to its peers.
+//!
+//! Nodes say which roles they take on (serving DNS, relaying, acting as a
+//! clearnet gateway, accepting new edge nodes) in their BGP OPEN and in node
+//! announcements. Peers record them per connection and in the `_nodes.vx0`
+//! directory, so resolvers and joiners can prefer nodes that actually offer
+//! what they need instead of relying only on static lists.
+
+use crate::config::Vx0Config;
+use crate::error::Report;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::acl::Contact;
+use crate::network::dns::{SharedDns, DEFAULT_RECORD_TTL};
+use crate::node::bootstrap::NodeAnnouncement;
+use crate::node::{NodeId, NodeTier, Vx0Node};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Capabilities {
+    pub serves_dns: bool,
+    pub offers_relay: bool,
+    pub offers_gateway: bool,
+    /// A regional node with room for more edge peers
+    pub accepts_new_edges: bool,
+    pub supports_compression: bool,
+    pub supports_channels: bool,
+    /// Pads tunnel payloads to bucket sizes; padding is used only when
+    /// both ends advertise it
+    pub supports_padding: bool,
+    /// Still in hold-down after joining; routes through it are a last resort
+    pub newly_joined: bool,
+    /// IKE answers on the BGP port. Left out when false, like
+    /// `tcp_encapsulation`, so OPENs read as they did before either existed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub single_port: bool,
+    /// Tunnels may arrive encapsulated in TCP on the BGP port
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub tcp_encapsulation: bool,
+    /// Exchanges no routes until a tunnel to the peer is established, so a
+    /// peer whose session stalls after OPEN can tell why
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub requires_tunnel: bool,
+    /// Carries BGP inside that tunnel; only when both ends advertise it
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub bgp_over_tunnel: bool,
+    /// Largest encrypted datagram the node sends over a tunnel; tunnels to
+    /// it use the smaller of this and our own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tunnel_mtu: Option<u16>,
+}
+
+impl Capabilities {
+    /// What a node offers given its configuration and current peer count
+    pub fn for_node(config: &Vx0Config, tier: &NodeTier, peer_count: usize) -> Self {
+        Capabilities {
+            serves_dns: config.node.capabilities.serves_dns,
+            offers_relay: config.node.capabilities.offers_relay,
+            offers_gateway: config.services.gateway.enabled,
+            accepts_new_edges: Self::accepts_edges(tier, peer_count),
+            supports_compression: false,
+            supports_channels: false,
+            supports_padding: config.security.obfuscation.padding().is_some(),
+            newly_joined: false,
+            single_port: config.network.single_port.enabled,
+            tcp_encapsulation: config.network.single_port.enabled,
+            requires_tunnel: config.network.bgp.require_tunnel,
+            bgp_over_tunnel: config.network.bgp.require_tunnel
+                && config.network.bgp.bgp_over_tunnel,
+            tunnel_mtu: Some(config.security.ike.mtu),
+        }
+    }
+
+    fn accepts_edges(tier: &NodeTier, peer_count: usize) -> bool {
+        matches!(tier, NodeTier::Regional) && peer_count < tier.max_peers()
+    }
+
+    /// The flags one bit each, in field order from the lowest bit, as a
+    /// binary OPEN carries them; `tunnel_mtu` travels apart
+    pub fn flags(&self) -> u32 {
+        self.offered()
+            .iter()
+            .enumerate()
+            .filter(|(_, (offered, _))| *offered)
+            .fold(0, |flags, (bit, _)| flags | 1 << bit)
+    }
+
+    /// Capabilities from [`Capabilities::flags`]; bits we do not know are
+    /// ignored
+    pub fn from_flags(flags: u32, tunnel_mtu: Option<u16>) -> Self {
+        let bit = |n: u32| flags & (1 << n) != 0;
+        Capabilities {
+            serves_dns: bit(0),
+            offers_relay: bit(1),
+            offers_gateway: bit(2),
+            accepts_new_edges: bit(3),
+            supports_compression: bit(4),
+            supports_channels: bit(5),
+            supports_padding: bit(6),
+            newly_joined: bit(7),
+            single_port: bit(8),
+            tcp_encapsulation: bit(9),
+            requires_tunnel: bit(10),
+            bgp_over_tunnel: bit(11),
+            tunnel_mtu,
+        }
+    }
+
+    fn offered(&self) -> [(bool, &'static str); 12] {
+        [
+            (self.serves_dns, "dns"),
+            (self.offers_relay, "relay"),
+            (self.offers_gateway, "gateway"),
+            (self.accepts_new_edges, "edges"),
+            (self.supports_compression, "compression"),
+            (self.supports_channels, "channels"),
+            (self.supports_padding, "padding"),
+            (self.newly_joined, "newly-joined"),
+            (self.single_port, "single-port"),
+            (self.tcp_encapsulation, "tcp-encap"),
+            (self.requires_tunnel, "tunnel-required"),
+            (self.bgp_over_tunnel, "bgp-over-tunnel"),
+        ]
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.offered()
+            .into_iter()
+            .filter_map(|(offered, name)| offered.then_some(name))
+            .collect()
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = self.names();
+        if names.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// A node as listed in the `_nodes.vx0` directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct NodeDirectoryEntry {
+    pub node_id: NodeId,
+    pub hostname: String,
+    pub asn: u32,
+    pub address: Ipv4Addr,
+    pub capabilities: Capabilities,
+    /// Where the operator says the node is; empty if they did not
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub location: String,
+    pub updated: DateTime<Utc>,
+}
+
+impl From<&NodeAnnouncement> for NodeDirectoryEntry {
+    fn from(announcement: &NodeAnnouncement) -> Self {
+        NodeDirectoryEntry {
+            node_id: announcement.node_id,
+            hostname: announcement.hostname.clone(),
+            asn: announcement.asn,
+            address: announcement.ipv4_addr,
+            capabilities: announcement.capabilities,
+            location: announcement.location.clone(),
+            updated: announcement.timestamp,
+        }
+    }
+}
+
+impl NodeDirectoryEntry {
+    /// Leading characters of the node ID, enough to tell apart nodes that
+    /// share a hostname
+    pub fn id_fragment(&self) -> String {
+        self.node_id.simple().to_string()[..8].to_string()
+    }
+
+    fn same_hostname(&self, other: &NodeDirectoryEntry) -> bool {
+        self.hostname.eq_ignore_ascii_case(&other.hostname)
+    }
+}
+
+/// Other nodes in the directory using `own`'s hostname
+pub fn hostname_conflicts<'a>(
+    own: &NodeDirectoryEntry,
+    nodes: &'a [NodeDirectoryEntry],
+) -> Vec<&'a NodeDirectoryEntry> {
+    nodes
+        .iter()
+        .filter(|node| node.node_id != own.node_id && node.same_hostname(own))
+        .collect()
+}
+
+/// Hostnames to show for `nodes`, with a node ID fragment appended to any
+/// that more than one node uses
+pub fn display_names(nodes: &[NodeDirectoryEntry]) -> Vec<String> {
+    nodes
+        .iter()
+        .map(|node| {
+            if hostname_conflicts(node, nodes).is_empty() {
+                node.hostname.clone()
+            } else {
+                format!("{}#{}", node.hostname, node.id_fragment())
+            }
+        })
+        .collect()
+}
+
+/// Warns once about each node seen using this node's hostname
+#[derive(Debug, Default)]
+pub struct HostnameAdvisory {
+    warned: HashSet<NodeId>,
+}
+
+impl HostnameAdvisory {
+    /// Nodes newly found sharing `own`'s hostname
+    pub fn check(
+        &mut self,
+        own: &NodeDirectoryEntry,
+        nodes: &[NodeDirectoryEntry],
+    ) -> Vec<NodeDirectoryEntry> {
+        let conflicts = hostname_conflicts(own, nodes);
+        let fresh: Vec<NodeDirectoryEntry> = conflicts
+            .iter()
+            .filter(|node| self.warned.insert(node.node_id))
+            .map(|node| (*node).clone())
+            .collect();
+        for node in &fresh {
+            tracing::warn!(
+                target: "audit",
+                "Hostname {} is also used by node {} (ASN {}, {}); this node shows as {}#{}",
+                own.hostname,
+                node.node_id,
+                node.asn,
+                node.address,
+                own.hostname,
+                own.id_fragment()
+            );
+        }
+        // Warn again should a node drop out and later come back
+        self.warned
+            .retain(|node_id| conflicts.iter().any(|node| node.node_id == *node_id));
+        fresh
+    }
+}
+
+/// Order candidates so those that are `capable` come first, otherwise
+/// keeping their original (configured) order
+pub fn prefer_capable<T>(candidates: Vec<T>, capable: impl Fn(&T) -> bool) -> Vec<T> {
+    let (mut preferred, rest): (Vec<T>, Vec<T>) = candidates.into_iter().partition(capable);
+    preferred.extend(rest);
+    preferred
+}
+
+/// Wait for the next capability change, then hold off for `delay` so a burst
+/// of changes goes out as one announcement. `None` once the node is gone.
+pub async fn next_change(
+    changes: &mut watch::Receiver<Capabilities>,
+    delay: Duration,
+) -> Option<Capabilities> {
+    changes.changed().await.ok()?;
+    tokio::time::sleep(delay).await;
+    Some(*changes.borrow_and_update())
+}
+
+impl Vx0Node {
+    pub fn capabilities(&self) -> Capabilities {
+        *self.capabilities.borrow()
+    }
+
+    pub fn subscribe_capabilities(&self) -> watch::Receiver<Capabilities> {
+        self.capabilities.subscribe()
+    }
+
+    /// Change what this node offers at runtime, returning whether anything changed
+    pub fn update_capabilities(&self, update: impl FnOnce(&mut Capabilities)) -> bool {
+        self.capabilities.send_if_modified(|capabilities| {
+            let before = *capabilities;
+            update(capabilities);
+            before != *capabilities
+        })
+    }
+
+    /// Advertise the BGP hold-down while it lasts, and stop offering relay
+    /// until the node has proven stable
+    pub fn follow_hold_down(self: &Arc<Self>, mut held_down: watch::Receiver<bool>) {
+        let node = Arc::clone(self);
+        crash::spawn(Subsystem::Node, "hold-down-capabilities", async move {
+            loop {
+                let held = *held_down.borrow_and_update();
+                let offers_relay = node.config.node.capabilities.offers_relay && !held;
+                node.update_capabilities(|capabilities| {
+                    capabilities.newly_joined = held;
+                    capabilities.offers_relay = offers_relay;
+                });
+                if held_down.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Re-derive the state-dependent capabilities after the peer set changed
+    pub(crate) async fn refresh_capabilities(&self) {
+        let accepts = Capabilities::accepts_edges(&self.tier, self.get_peer_count().await);
+        if self.update_capabilities(|capabilities| capabilities.accepts_new_edges = accepts) {
+            tracing::info!(
+                "{} accepting new edge nodes",
+                if accepts { "Now" } else { "No longer" }
+            );
+        }
+    }
+
+    /// How long capability changes are coalesced before being re-announced
+    pub fn capability_announce_delay(&self) -> Duration {
+        Duration::from_secs(self.config.node.capabilities.announce_delay_secs)
+    }
+
+    /// This node's own directory listing
+    pub fn directory_entry(&self) -> NodeDirectoryEntry {
+        NodeDirectoryEntry {
+            node_id: self.node_id,
+            hostname: self.hostname.clone(),
+            asn: self.asn,
+            address: self.public_ipv4(),
+            capabilities: self.capabilities(),
+            location: self.stated_location().to_string(),
+            updated: Utc::now(),
+        }
+    }
+
+    /// Zone sync addresses of the peers that serve DNS, the secondaries of
+    /// this node's zones
+    pub async fn dns_secondaries(&self) -> Vec<SocketAddr> {
+        let sync_port = self.config.network.dns.sync_port;
+        self.list_peers()
+            .await
+            .into_iter()
+            .filter(|peer| peer.capabilities.serves_dns)
+            .map(|peer| SocketAddr::new(peer.peer_addr, sync_port))
+            .collect()
+    }
+
+    /// Watch the directory for other nodes using this node's hostname and,
+    /// when `claim_name` is set, keep `<short hostname>.nodes.vx0` pointing
+    /// here for as long as no other node holds it
+    pub fn start_hostname_advisory(self: &Arc<Self>, dns: SharedDns, claim_name: bool) {
+        let node = Arc::clone(self);
+        crash::spawn_restartable(Subsystem::Node, "hostname-advisory", move || {
+            let node = Arc::clone(&node);
+            let dns = Arc::clone(&dns);
+            async move {
+                let mut advisory = HostnameAdvisory::default();
+                let mut refused = false;
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(DEFAULT_RECORD_TTL as u64 / 3));
+                loop {
+                    interval.tick().await;
+                    let own = node.directory_entry();
+                    let nodes = dns.read().await.nodes();
+                    advisory.check(&own, &nodes);
+
+                    if !claim_name {
+                        continue;
+                    }
+                    match dns.write().await.claim_node_name(&own) {
+                        Ok(name) if refused => {
+                            refused = false;
+                            tracing::info!("Claimed {} now that it is free", name);
+                        }
+                        Ok(_) => {}
+                        // Said once; the claim is retried quietly
+                        Err(e) if !refused => {
+                            refused = true;
+                            tracing::error!("Not registering hostname in DNS: {}", e);
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+        });
+    }
+
+    /// Record the capabilities a peer announced, returning whether it is one
+    /// of ours. Announcements from nodes the ACL refuses are ignored, as are
+    /// those from a known peer at a new address it cannot re-authenticate at.
+    pub async fn observe_announcement(&self, announcement: &NodeAnnouncement) -> bool {
+        let contact = Contact::node(
+            announcement.node_id,
+            announcement.asn,
+            announcement.ipv4_addr.into(),
+        );
+        if !self.acl.check(&contact, "announcement") {
+            return false;
+        }
+        // Judged by ASN rather than the tier the announcement claims
+        if let Some(digest) = &announcement.network_health {
+            if NodeTier::from_asn(announcement.asn) != NodeTier::Edge {
+                self.capacity
+                    .hear(announcement.node_id, &announcement.hostname, digest);
+            }
+        }
+
+        // Peers taken down stay down whatever they announce, and demoted
+        // peers' announcements are not acted on
+        if self.get_peer(&announcement.node_id).await.is_some()
+            && !self.admin.is_down(announcement.asn)
+            && !self
+                .quarantine
+                .is_demoted(announcement.asn, self.clock.now_utc())
+        {
+            if let Err(e) = self
+                .migrate_peer(announcement.node_id, announcement.ipv4_addr.into())
+                .await
+            {
+                tracing::warn!(
+                    "Ignoring announcement from peer {}: {}",
+                    announcement.node_id,
+                    Report(&e)
+                );
+                return false;
+            }
+            // A peer that said goodbye is back once it announces itself
+            if let Err(e) = self.welcome_back(announcement.node_id).await {
+                tracing::debug!(
+                    "Peer {} went away again: {}",
+                    announcement.node_id,
+                    Report(&e)
+                );
+            }
+        }
+
+        let mut observed = false;
+        for handle in self.peer_handles().await {
+            if handle.peer_asn() != announcement.asn {
+                continue;
+            }
+            if handle
+                .set_capabilities(announcement.capabilities)
+                .await
+                .is_ok()
+            {
+                observed = true;
+                if let Ok(Some(tunnel_id)) = handle.tunnel().await {
+                    self.tunnel_manager
+                        .negotiate_padding(&tunnel_id, announcement.capabilities.supports_padding)
+                        .await;
+                    self.tunnel_manager
+                        .negotiate_mtu(
+                            &tunnel_id,
+                            announcement.capabilities.tunnel_mtu.map(usize::from),
+                        )
+                        .await;
+                }
+            }
+        }
+        observed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_info::BuildInfo;
+    use crate::network::dns::resolver::Vx0Resolver;
+    use crate::network::dns::{DNSError, RecordType, Vx0DNS};
+    use crate::node::testing;
+    use crate::node::{NodeTier, PeerConnection};
+    use std::sync::Arc;
+
+    fn node(asn: u32, ip: &str) -> Arc<Vx0Node> {
+        testing::node(NodeTier::Edge, |config| {
+            config.node.asn = asn;
+            config.node.tier = "Regional".to_string();
+            config.node.ipv4_address = ip.to_string();
+        })
+    }
+
+    fn announcement(node: &Vx0Node) -> NodeAnnouncement {
+        NodeAnnouncement {
+            node_id: node.node_id,
+            hostname: node.hostname.clone(),
+            asn: node.asn,
+            tier: node.tier.clone(),
+            ipv4_addr: node.ipv4_addr,
+            services: vec![],
+            total_services: 0,
+            truncated: false,
+            capabilities: node.capabilities(),
+            build: BuildInfo::current(),
+            location: String::new(),
+            network_health: None,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capability_flip_reaches_peers_and_resolver() {
+        let regional = node(65101, "10.1.0.1");
+        let observer = node(65102, "10.1.0.2");
+        observer
+            .add_peer(PeerConnection::new(
+                regional.node_id,
+                regional.asn,
+                regional.ipv4_addr.into(),
+            ))
+            .await
+            .unwrap();
+        let resolver = Vx0Resolver::new(vec!["10.0.0.2:53".to_string()]);
+        assert_eq!(resolver.forwarding_targets().await, vec!["10.0.0.2:53"]);
+
+        let mut changes = regional.subscribe_capabilities();
+        assert!(regional.update_capabilities(|c| c.serves_dns = true));
+        let announced = next_change(&mut changes, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert!(announced.serves_dns);
+
+        // Deliver the re-announcement as the peer would receive it
+        let received = announcement(&regional);
+        assert!(observer.observe_announcement(&received).await);
+        resolver
+            .register_node(&NodeDirectoryEntry::from(&received))
+            .await;
+
+        let peers = observer.list_peers().await;
+        assert!(peers[0].capabilities.serves_dns);
+        assert_eq!(resolver.forwarding_targets().await, vec!["10.1.0.1:53"]);
+
+        // Withdrawing the capability sends the resolver back to its configured list
+        assert!(regional.update_capabilities(|c| c.serves_dns = false));
+        let received = announcement(&regional);
+        observer.observe_announcement(&received).await;
+        resolver
+            .register_node(&NodeDirectoryEntry::from(&received))
+            .await;
+        assert!(!observer.list_peers().await[0].capabilities.serves_dns);
+        assert_eq!(resolver.forwarding_targets().await, vec!["10.0.0.2:53"]);
+    }
+
+    #[tokio::test]
+    async fn test_accepts_new_edges_follows_peer_count() {
+        let regional = node(65101, "10.1.0.1");
+        assert!(regional.capabilities().accepts_new_edges);
+
+        let mut peers = Vec::new();
+        for i in 0..regional.tier.max_peers() as u32 {
+            let peer =
+                PeerConnection::new(uuid::Uuid::new_v4(), 66001 + i, "10.2.0.1".parse().unwrap());
+            peers.push(peer.peer_id);
+            regional.add_peer(peer).await.unwrap();
+        }
+        assert!(!regional.capabilities().accepts_new_edges);
+
+        regional.remove_peer(&peers[0]).await.unwrap();
+        assert!(regional.capabilities().accepts_new_edges);
+        assert_eq!(
+            regional.capabilities().to_string(),
+            "edges",
+            "only state-derived capabilities are on by default"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_peer_moving_address_takes_its_tunnel_and_session_along() {
+        use crate::network::bgp::BGPDaemon;
+        use crate::node::PeerEvent;
+
+        let regional = node(65101, "10.1.0.1");
+        let observer = node(65102, "10.1.0.2");
+        observer
+            .add_peer(PeerConnection::new(
+                regional.node_id,
+                regional.asn,
+                regional.ipv4_addr.into(),
+            ))
+            .await
+            .unwrap();
+        let old_tunnel = observer
+            .create_secure_tunnel(
+                regional.node_id,
+                "10.1.0.1:4500".parse().unwrap(),
+                &observer.tunnel_psk(),
+            )
+            .await
+            .unwrap();
+        observer
+            .send_secure_data(&regional.node_id, b"before")
+            .await
+            .unwrap();
+
+        let bgp = Arc::new(BGPDaemon::new(65102, "10.1.0.2".parse().unwrap(), 0));
+        bgp.follow_peer_events(observer.subscribe_peer_events());
+        let mut events = observer.subscribe_peer_events();
+
+        // The peer's residential address changed under it
+        let mut moved = announcement(&regional);
+        moved.ipv4_addr = "10.1.0.99".parse().unwrap();
+        assert!(observer.observe_announcement(&moved).await);
+        let new_addr: std::net::IpAddr = "10.1.0.99".parse().unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            PeerEvent::AddressChanged {
+                peer_id: regional.node_id,
+                peer_asn: 65101,
+                old: "10.1.0.1".parse().unwrap(),
+                new: new_addr,
+            }
+        );
+        assert_eq!(observer.list_peers().await[0].peer_addr, new_addr);
+
+        assert!(observer
+            .tunnel_manager
+            .get_tunnel(&old_tunnel)
+            .await
+            .is_none());
+        let (_, new_tunnel) = observer.list_active_tunnels().await[0];
+        let tunnel = observer
+            .tunnel_manager
+            .get_tunnel(&new_tunnel)
+            .await
+            .unwrap();
+        assert_eq!(tunnel.remote_addr, new_addr);
+        assert_eq!(tunnel.ike_session.peer_addr.port(), 4500);
+        observer
+            .send_secure_data(&regional.node_id, b"after")
+            .await
+            .unwrap();
+        let stats = observer.get_tunnel_stats(&regional.node_id).await.unwrap();
+        assert_eq!(stats.packets_out, 1);
+
+        // The BGP session follows the event
+        for _ in 0..50 {
+            if bgp.session_peers().await == vec![new_addr] {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(bgp.session_peers().await, vec![new_addr]);
+
+        // Announcing the same address again changes nothing
+        assert!(observer.observe_announcement(&moved).await);
+        assert!(events.try_recv().is_err());
+    }
+
+    fn listing(hostname: &str, address: &str) -> NodeDirectoryEntry {
+        NodeDirectoryEntry {
+            node_id: NodeId::new_v4(),
+            hostname: hostname.to_string(),
+            asn: 65201,
+            address: address.parse().unwrap(),
+            capabilities: Capabilities::default(),
+            location: String::new(),
+            updated: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_duplicate_hostname_advisory() {
+        let own = listing("vx0-node", "10.2.0.1");
+        let twin = listing("VX0-Node", "10.2.0.2");
+        let other = listing("edge7", "10.2.0.3");
+        let mut nodes = vec![own.clone(), other.clone()];
+
+        let mut advisory = HostnameAdvisory::default();
+        assert!(advisory.check(&own, &nodes).is_empty());
+
+        nodes.push(twin.clone());
+        let found = advisory.check(&own, &nodes);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].node_id, twin.node_id);
+        // Only the first sighting is reported
+        assert!(advisory.check(&own, &nodes).is_empty());
+
+        let names = display_names(&nodes);
+        assert_eq!(names[0], format!("vx0-node#{}", own.id_fragment()));
+        assert_eq!(names[1], "edge7");
+        assert_eq!(names[2], format!("VX0-Node#{}", twin.id_fragment()));
+        assert_ne!(names[0], names[2]);
+
+        // A node that leaves and returns is reported again
+        nodes.pop();
+        assert!(advisory.check(&own, &nodes).is_empty());
+        nodes.push(twin);
+        assert_eq!(advisory.check(&own, &nodes).len(), 1);
+    }
+
+    #[test]
+    fn test_contested_node_name_is_refused() {
+        let mut dns = Vx0DNS::new();
+        let first = listing("edge1.vx0.network", "10.2.0.1");
+        let name = dns.claim_node_name(&first).unwrap();
+        assert_eq!(name, "edge1.nodes.vx0");
+        // Refreshing our own claim is fine
+        assert_eq!(dns.claim_node_name(&first).unwrap(), name);
+
+        let mut rival = listing("Edge1", "10.2.0.9");
+        match dns.claim_node_name(&rival) {
+            Err(DNSError::Claimed {
+                name: claimed,
+                owner,
+            }) => {
+                assert_eq!(claimed, name);
+                assert_eq!(owner, first.node_id);
+            }
+            other => panic!("expected the claim to be refused, got {:?}", other),
+        }
+        let addresses: Vec<&str> = dns
+            .get_records(&name)
+            .unwrap()
+            .iter()
+            .filter(|record| record.record_type == RecordType::A)
+            .map(|record| record.data.as_str())
+            .collect();
+        assert_eq!(addresses, vec!["10.2.0.1"]);
+
+        // Once the holder's records lapse the name is free again
+        dns.expire_records(Utc::now() + chrono::Duration::seconds(2 * DEFAULT_RECORD_TTL as i64));
+        rival.hostname = "edge1".to_string();
+        assert_eq!(dns.claim_node_name(&rival).unwrap(), name);
+    }
+}
@@ -1,12 +1,55 @@
+//! Local peer discovery over UDP broadcast.
+//!
+//! How much an announcement reveals depends on `services.discovery_privacy`.
+//! `full` broadcasts hostname, ASN and addresses. `minimal` broadcasts only a
+//! random session ID and the discovery port; listeners then send a query
+//! carrying a nonce, and the announcer answers with its identity and an HMAC
+//! over both, keyed with the network PSK. `silent` sends nothing but still
+//! answers such queries sent to it directly.
+
+use crate::config::DiscoveryPrivacy;
+use crate::monitoring::capture::{Capture, CapturedEvent};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::adaptive::{self, AnnounceBackoff, Probe};
 use crate::node::{NodeId, PeerConnection, Vx0Node};
+use crate::util::clock::{self, SharedClock};
+use ring::hmac;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// How often the node announces itself, unless intervals adapt
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Hellos awaiting a response; further minimal announcements are ignored
+/// until some complete
+const MAX_PENDING_HELLOS: usize = 256;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiscoveryMessage {
     pub message_type: DiscoveryMessageType,
+    /// Random for each run; all a minimal announcement says about its sender
+    pub session_id: Uuid,
+    /// Where the sender answers queries
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<NodeIdentity>,
+    /// Challenge sent in a query and echoed in the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Vec<u8>>,
+    /// HMAC over the nonce and identity, proving the responder holds the PSK
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<Vec<u8>>,
+}
+
+/// Who a node is, as revealed in full announcements and hello responses
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeIdentity {
     pub node_id: NodeId,
     pub asn: u32,
     pub hostname: String,
@@ -14,7 +57,7 @@ pub struct DiscoveryMessage {
     pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DiscoveryMessageType {
     Announce,
     Query,
@@ -23,85 +66,508 @@ pub enum DiscoveryMessageType {
 
 pub struct PeerDiscovery {
     socket: UdpSocket,
-    known_peers: HashMap<NodeId, PeerConnection>,
+    port: u16,
+    session_id: Uuid,
+    node_id: NodeId,
+    asn: u32,
+    hostname: String,
+    addresses: Vec<IpAddr>,
+    key: hmac::Key,
+    privacy: watch::Receiver<DiscoveryPrivacy>,
+    /// Nonce sent to each minimally announced session we asked to identify
+    pending: HashMap<Uuid, Vec<u8>>,
+    known_peers: HashMap<NodeId, DiscoveredPeer>,
+    /// How long a peer is kept without announcing itself
+    expiry: Duration,
+    capture: Option<Arc<Capture>>,
+    clock: SharedClock,
+    /// Stretches announcements while the known peers stay the same
+    backoff: AnnounceBackoff,
+}
+
+/// A peer heard on the local network, with how fresh the sighting is
+#[derive(Debug, Clone)]
+pub struct DiscoveredPeer {
+    pub peer: PeerConnection,
+    /// Announcements heard from it, across any node ID changes
+    pub announcements: u64,
+    /// When it last announced itself, on the monotonic clock
+    pub heard: Instant,
 }
 
 impl PeerDiscovery {
-    pub async fn new(bind_addr: &str) -> Result<Self, std::io::Error> {
+    pub async fn new(
+        bind_addr: &str,
+        node: &Vx0Node,
+        privacy: watch::Receiver<DiscoveryPrivacy>,
+    ) -> Result<Self, std::io::Error> {
         let socket = UdpSocket::bind(bind_addr).await?;
         socket.set_broadcast(true)?;
+        let port = socket.local_addr()?.port();
 
+        let expiry = Duration::from_secs(node.config.services.discovery_expiry_secs);
         Ok(PeerDiscovery {
             socket,
-            known_peers: HashMap::new(),
-        })
-    }
-
-    pub async fn announce(&self, node: &Vx0Node) -> Result<(), Box<dyn std::error::Error>> {
-        let announcement = DiscoveryMessage {
-            message_type: DiscoveryMessageType::Announce,
+            port,
+            session_id: Uuid::new_v4(),
             node_id: node.node_id,
             asn: node.asn,
             hostname: node.hostname.clone(),
             addresses: vec![IpAddr::V4(node.ipv4_addr), IpAddr::V6(node.ipv6_addr)],
-            timestamp: chrono::Utc::now(),
-        };
+            key: hmac::Key::new(hmac::HMAC_SHA256, &node.tunnel_psk()),
+            privacy,
+            pending: HashMap::new(),
+            known_peers: HashMap::new(),
+            expiry,
+            capture: node.capture(),
+            clock: Arc::clone(&node.clock),
+            backoff: AnnounceBackoff::new(
+                &node.config.network.adaptive_timers,
+                &node.tier,
+                ANNOUNCE_INTERVAL,
+                expiry,
+            ),
+        })
+    }
 
+    fn identity(&self) -> NodeIdentity {
+        NodeIdentity {
+            node_id: self.node_id,
+            asn: self.asn,
+            hostname: self.hostname.clone(),
+            addresses: self.addresses.clone(),
+            timestamp: self.clock.now_utc(),
+        }
+    }
+
+    fn message(&self, message_type: DiscoveryMessageType) -> DiscoveryMessage {
+        DiscoveryMessage {
+            message_type,
+            session_id: self.session_id,
+            port: self.port,
+            identity: None,
+            nonce: None,
+            proof: None,
+        }
+    }
+
+    /// What to broadcast at the current privacy level, if anything
+    pub fn announcement(&self) -> Option<DiscoveryMessage> {
+        let mut announcement = self.message(DiscoveryMessageType::Announce);
+        match *self.privacy.borrow() {
+            DiscoveryPrivacy::Full => announcement.identity = Some(self.identity()),
+            DiscoveryPrivacy::Minimal => {}
+            DiscoveryPrivacy::Silent => return None,
+        }
+        Some(announcement)
+    }
+
+    pub async fn announce(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(announcement) = self.announcement() else {
+            return Ok(());
+        };
         let message = serde_json::to_vec(&announcement)?;
 
         // Broadcast to local network
         self.socket
-            .send_to(&message, "255.255.255.255:8080")
+            .send_to(
+                &message,
+                SocketAddr::from(([255, 255, 255, 255], self.port)),
+            )
             .await?;
+        adaptive::record_traffic(Probe::Announce, message.len(), self.clock.now_monotonic());
 
-        tracing::debug!("Announced node {} to network", node.node_id);
+        crate::sampled!(tracing::debug!(
+            "Announced node {} to network",
+            self.node_id
+        ));
         Ok(())
     }
 
-    pub async fn listen_for_peers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buf = [0; 1024];
+    /// Announce and answer peers in the background until the privacy
+    /// sender is dropped
+    pub fn start(self) {
+        crash::spawn(Subsystem::Node, "peer-discovery", self.run());
+    }
+
+    /// Announce periodically, and at once when the privacy level changes,
+    /// while answering whatever arrives. Announcements stretch while the
+    /// known peers are saturated and unchanged, and return to the floor as
+    /// soon as a new one is heard.
+    async fn run(mut self) {
+        let mut buf = [0; 2048];
+        let mut announce = clock::interval(&self.clock, self.backoff.interval());
 
         loop {
-            match self.socket.recv_from(&mut buf).await {
-                Ok((size, addr)) => {
-                    if let Ok(message) = serde_json::from_slice::<DiscoveryMessage>(&buf[..size]) {
-                        self.handle_discovery_message(message, addr.ip()).await;
+            tokio::select! {
+                _ = announce.tick() => {
+                    self.expire(self.clock.now_monotonic());
+                    if let Err(e) = self.announce().await {
+                        tracing::warn!("Failed to send discovery announcement: {}", e);
                     }
+                    announce.set_period(self.backoff.announced(self.known_peers.len()));
                 }
-                Err(e) => {
-                    tracing::error!("Error receiving discovery message: {}", e);
+                changed = self.privacy.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    let privacy = *self.privacy.borrow_and_update();
+                    tracing::info!("Discovery privacy is now {:?}", privacy);
+                    announce.reset_immediately();
                 }
+                result = self.socket.recv_from(&mut buf) => match result {
+                    Ok((size, from)) => {
+                        if let Err(e) = self.receive(&buf[..size], from).await {
+                            tracing::warn!("Failed to answer discovery message from {}: {}", from, e);
+                        }
+                        if announce.period() != self.backoff.interval() {
+                            announce.set_period(self.backoff.interval());
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!("Error receiving discovery message: {}", e);
+                    }
+                },
             }
         }
     }
 
-    async fn handle_discovery_message(&mut self, message: DiscoveryMessage, sender_addr: IpAddr) {
-        match message.message_type {
-            DiscoveryMessageType::Announce => {
+    async fn receive(&mut self, data: &[u8], from: SocketAddr) -> Result<(), std::io::Error> {
+        let Ok(message) = serde_json::from_slice::<DiscoveryMessage>(data) else {
+            return Ok(());
+        };
+        if let Some((reply, to)) = self.handle_discovery_message(message, from) {
+            self.socket
+                .send_to(&serde_json::to_vec(&reply)?, to)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Act on a message, returning the reply to send and where to
+    fn handle_discovery_message(
+        &mut self,
+        message: DiscoveryMessage,
+        from: SocketAddr,
+    ) -> Option<(DiscoveryMessage, SocketAddr)> {
+        // Our own broadcasts come back to us
+        if message.session_id == self.session_id {
+            return None;
+        }
+        if let Some(capture) = &self.capture {
+            capture.record(CapturedEvent::Announcement {
+                from,
+                message: message.clone(),
+            });
+        }
+        let sender = SocketAddr::new(from.ip(), message.port);
+
+        match (message.message_type, message.identity) {
+            (DiscoveryMessageType::Announce, Some(identity)) => {
                 tracing::info!(
                     "Discovered peer {} (ASN: {}) at {}",
-                    message.hostname,
-                    message.asn,
-                    sender_addr
+                    identity.hostname,
+                    identity.asn,
+                    from.ip()
                 );
-
-                if let std::collections::hash_map::Entry::Vacant(e) =
-                    self.known_peers.entry(message.node_id)
+                self.learn(&identity, from.ip());
+                None
+            }
+            (DiscoveryMessageType::Announce, None) => {
+                if self.pending.contains_key(&message.session_id)
+                    || self.pending.len() >= MAX_PENDING_HELLOS
                 {
-                    let peer = PeerConnection::new(message.node_id, message.asn, sender_addr);
-                    e.insert(peer);
+                    return None;
                 }
+                tracing::debug!("Asking minimally announced peer at {} to identify", sender);
+                let nonce = rand::random::<[u8; 16]>().to_vec();
+                self.pending.insert(message.session_id, nonce.clone());
+                let mut query = self.message(DiscoveryMessageType::Query);
+                query.nonce = Some(nonce);
+                Some((query, sender))
             }
-            DiscoveryMessageType::Query => {
-                tracing::debug!("Received peer query from {}", sender_addr);
+            (DiscoveryMessageType::Query, _) => {
+                tracing::debug!("Received peer query from {}", from);
+                // Answered at every privacy level, but only with proof
+                let nonce = message.nonce?;
+                let identity = self.identity();
+                let mut response = self.message(DiscoveryMessageType::Response);
+                response.proof = Some(
+                    hmac::sign(&self.key, &proof_input(&nonce, self.session_id, &identity))
+                        .as_ref()
+                        .to_vec(),
+                );
+                response.identity = Some(identity);
+                response.nonce = Some(nonce);
+                Some((response, from))
             }
-            DiscoveryMessageType::Response => {
-                tracing::debug!("Received peer response from {}", sender_addr);
+            (DiscoveryMessageType::Response, identity) => {
+                let expected = self.pending.remove(&message.session_id)?;
+                let (Some(identity), Some(nonce), Some(proof)) =
+                    (identity, message.nonce, message.proof)
+                else {
+                    tracing::warn!("Incomplete discovery response from {}", from);
+                    return None;
+                };
+                let input = proof_input(&nonce, message.session_id, &identity);
+                if nonce != expected || hmac::verify(&self.key, &input, &proof).is_err() {
+                    tracing::warn!("Discovery response from {} failed authentication", from);
+                    return None;
+                }
+                tracing::info!(
+                    "Identified peer {} (ASN: {}) at {}",
+                    identity.hostname,
+                    identity.asn,
+                    from.ip()
+                );
+                self.learn(&identity, from.ip());
+                None
             }
         }
     }
 
-    pub fn get_discovered_peers(&self) -> Vec<&PeerConnection> {
-        self.known_peers.values().collect()
+    fn learn(&mut self, identity: &NodeIdentity, addr: IpAddr) {
+        self.learn_at(identity, addr, self.clock.now_monotonic());
+    }
+
+    /// Record an announcement, folding in any entry for the same ASN and
+    /// address under another node ID: that is the same machine with a new
+    /// identity, not a second peer
+    fn learn_at(&mut self, identity: &NodeIdentity, addr: IpAddr, now: Instant) {
+        let previous = self
+            .known_peers
+            .iter()
+            .find(|(node_id, known)| {
+                **node_id != identity.node_id
+                    && known.peer.peer_asn == identity.asn
+                    && known.peer.peer_addr == addr
+            })
+            .map(|(node_id, _)| *node_id);
+        if let Some(previous) = previous {
+            let mut known = self
+                .known_peers
+                .remove(&previous)
+                .expect("found just above");
+            tracing::info!(
+                "Peer at {} (ASN: {}) changed node ID from {} to {}",
+                addr,
+                identity.asn,
+                previous,
+                identity.node_id
+            );
+            known.peer.peer_id = identity.node_id;
+            self.known_peers.insert(identity.node_id, known);
+        }
+
+        if previous.is_none() && !self.known_peers.contains_key(&identity.node_id) {
+            self.backoff.changed();
+        }
+        let known = self
+            .known_peers
+            .entry(identity.node_id)
+            .or_insert_with(|| DiscoveredPeer {
+                peer: PeerConnection::new(identity.node_id, identity.asn, addr),
+                announcements: 0,
+                heard: now,
+            });
+        known.peer.peer_asn = identity.asn;
+        known.peer.peer_addr = addr;
+        known.peer.last_seen = self.clock.now_utc();
+        known.heard = now;
+        known.announcements += 1;
+    }
+
+    /// Forget peers not heard from within the expiry period; returns how
+    /// many were dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.known_peers.len();
+        let expiry = self.expiry;
+        self.known_peers.retain(|node_id, known| {
+            let live = now.saturating_duration_since(known.heard) < expiry;
+            if !live {
+                tracing::info!(
+                    "Forgetting peer {} at {}, not announced since {}",
+                    node_id,
+                    known.peer.peer_addr,
+                    known.peer.last_seen
+                );
+            }
+            live
+        });
+        before - self.known_peers.len()
+    }
+
+    /// Peers heard on the local network, most recently seen first
+    pub fn get_discovered_peers(&self) -> Vec<&DiscoveredPeer> {
+        let mut peers: Vec<&DiscoveredPeer> = self.known_peers.values().collect();
+        peers.sort_by_key(|known| std::cmp::Reverse(known.heard));
+        peers
+    }
+}
+
+/// What a hello response's HMAC covers
+fn proof_input(nonce: &[u8], session_id: Uuid, identity: &NodeIdentity) -> Vec<u8> {
+    let addresses: Vec<String> = identity.addresses.iter().map(|a| a.to_string()).collect();
+    let mut input = nonce.to_vec();
+    input.extend_from_slice(session_id.as_bytes());
+    input.extend_from_slice(
+        format!(
+            "|{}|{}|{}|{}",
+            identity.node_id,
+            identity.asn,
+            identity.hostname,
+            addresses.join(",")
+        )
+        .as_bytes(),
+    );
+    input
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    async fn discovery(
+        hostname: &str,
+        privacy: DiscoveryPrivacy,
+    ) -> (PeerDiscovery, watch::Sender<DiscoveryPrivacy>) {
+        let mut config = testing::config(NodeTier::Edge);
+        config.node.hostname = hostname.to_string();
+        let node = Vx0Node::new(config).unwrap();
+        let (level, privacy) = watch::channel(privacy);
+        let discovery = PeerDiscovery::new("127.0.0.1:0", &node, privacy)
+            .await
+            .unwrap();
+        (discovery, level)
+    }
+
+    fn addr(discovery: &PeerDiscovery) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], discovery.port))
+    }
+
+    #[tokio::test]
+    async fn test_announcement_contents_follow_privacy_level() {
+        let (discovery, level) = discovery("edge-a", DiscoveryPrivacy::Full).await;
+        let wire = |discovery: &PeerDiscovery| {
+            discovery
+                .announcement()
+                .map(|announcement| serde_json::to_value(announcement).unwrap())
+        };
+
+        let full = wire(&discovery).unwrap();
+        assert_eq!(full["identity"]["hostname"], "edge-a");
+        assert_eq!(full["identity"]["asn"], 66001);
+        assert_eq!(full["identity"]["addresses"].as_array().unwrap().len(), 2);
+
+        // Levels change at runtime without restarting discovery
+        level.send_replace(DiscoveryPrivacy::Minimal);
+        let minimal = wire(&discovery).unwrap();
+        let mut keys: Vec<&str> = minimal
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["message_type", "port", "session_id"]);
+        assert!(!minimal.to_string().contains("edge-a"));
+
+        level.send_replace(DiscoveryPrivacy::Silent);
+        assert_eq!(wire(&discovery), None);
+    }
+
+    #[tokio::test]
+    async fn test_minimal_peer_is_identified_after_hello() {
+        let (mut shy, level) = discovery("edge-shy", DiscoveryPrivacy::Minimal).await;
+        let (mut listener, _) = discovery("edge-listener", DiscoveryPrivacy::Full).await;
+
+        let announcement = shy.announcement().unwrap();
+        let (query, to) = listener
+            .handle_discovery_message(announcement.clone(), addr(&shy))
+            .unwrap();
+        assert_eq!(to, addr(&shy));
+        assert!(listener.get_discovered_peers().is_empty());
+
+        // Silent nodes still answer direct queries
+        level.send_replace(DiscoveryPrivacy::Silent);
+        let (response, to) = shy
+            .handle_discovery_message(query, addr(&listener))
+            .unwrap();
+        assert_eq!(to, addr(&listener));
+
+        // A forged proof is refused and uses up the hello
+        let mut forged = response.clone();
+        forged.proof = Some(vec![0; 32]);
+        assert!(listener
+            .handle_discovery_message(forged, addr(&shy))
+            .is_none());
+        assert!(listener.get_discovered_peers().is_empty());
+
+        let (query, _) = listener
+            .handle_discovery_message(announcement, addr(&shy))
+            .unwrap();
+        let (response, _) = shy
+            .handle_discovery_message(query, addr(&listener))
+            .unwrap();
+        assert!(listener
+            .handle_discovery_message(response.clone(), addr(&shy))
+            .is_none());
+
+        let peers = listener.get_discovered_peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer.peer_id, shy.node_id);
+        assert_eq!(peers[0].peer.peer_asn, 66001);
+
+        // Replaying the response without a hello in flight does nothing
+        assert!(listener
+            .handle_discovery_message(response, addr(&shy))
+            .is_none());
+        assert_eq!(listener.get_discovered_peers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_changing_node_id_stays_one_entry() {
+        let (mut listener, _) = discovery("edge-listener", DiscoveryPrivacy::Full).await;
+        let start = Instant::now();
+        let addr: IpAddr = "192.168.1.20".parse().unwrap();
+        let identity = |node_id| NodeIdentity {
+            node_id,
+            asn: 66002,
+            hostname: "edge-flaky".to_string(),
+            addresses: vec![addr],
+            timestamp: chrono::Utc::now(),
+        };
+
+        // Each restart comes back with a fresh UUID, some announcements lost
+        let mut node_id = NodeId::new_v4();
+        for round in 0..6 {
+            if round % 2 == 0 {
+                node_id = NodeId::new_v4();
+            }
+            let now = start + Duration::from_secs(30 * round);
+            listener.learn_at(&identity(node_id), addr, now);
+
+            let live = listener.get_discovered_peers();
+            assert_eq!(live.len(), 1);
+            assert_eq!(live[0].peer.peer_id, node_id);
+            assert_eq!(live[0].heard, now);
+            assert_eq!(live[0].announcements, round + 1);
+        }
+
+        // A different machine on the same ASN is a separate peer
+        let other: IpAddr = "192.168.1.21".parse().unwrap();
+        let now = start + Duration::from_secs(180);
+        listener.learn_at(&identity(NodeId::new_v4()), other, now);
+        let peers = listener.get_discovered_peers();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].peer.peer_addr, other);
+
+        // The first address stops announcing and ages out after five minutes
+        assert_eq!(listener.expire(now + Duration::from_secs(280)), 1);
+        let peers = listener.get_discovered_peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer.peer_addr, other);
     }
 }
@@ -0,0 +1,385 @@
+//! Saying goodbye before leaving a peer.
+//!
+//! A node that disconnects on purpose tells the peer why first. The peer
+//! then treats the departure as intentional: routes are withdrawn at once
+//! without counting a flap, and it does not try to reconnect until the node
+//! announces itself again. After a shutdown it probes again after an hour;
+//! after maintenance with an expected return, at that time.
+//!
+//! Goodbyes travel as peer messages: framed with
+//! [`peer_message`](crate::network::ike::tunnels::peer_message), they leave
+//! over the peer's tunnel, by UDP or tunnel stream, and are taken out of
+//! it once reassembled to reach [`Vx0Node::handle_peer_message`] through
+//! [`Vx0Node::follow_peer_messages`].
+
+use crate::monitoring::{crash, Subsystem};
+use crate::network::ike::tunnels::{self, TunnelId};
+use crate::node::{ConnectionStatus, NodeError, NodeId, PeerEvent, Vx0Node};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+
+/// How long after a peer shuts down before probing it again
+const SHUTDOWN_PROBE_DELAY: chrono::Duration = chrono::Duration::hours(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum GoodbyeReason {
+    Shutdown,
+    Maintenance,
+    PeerRemoved,
+    TierChange,
+}
+
+impl fmt::Display for GoodbyeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GoodbyeReason::Shutdown => "shutdown",
+            GoodbyeReason::Maintenance => "maintenance",
+            GoodbyeReason::PeerRemoved => "peer removed",
+            GoodbyeReason::TierChange => "tier change",
+        })
+    }
+}
+
+/// Sent over the peer's tunnel just before the node closes it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GoodbyeMessage {
+    pub node_id: NodeId,
+    pub reason: GoodbyeReason,
+    /// When a node going into maintenance expects to be back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_return: Option<DateTime<Utc>>,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Why a peer left, recorded on its connection until it comes back
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Departure {
+    pub reason: GoodbyeReason,
+    pub at: DateTime<Utc>,
+    /// When to try the peer again even if it has not re-announced
+    pub probe_at: Option<DateTime<Utc>>,
+}
+
+impl Departure {
+    pub fn new(message: &GoodbyeMessage, now: DateTime<Utc>) -> Self {
+        let probe_at = match message.reason {
+            GoodbyeReason::Shutdown => Some(now + SHUTDOWN_PROBE_DELAY),
+            GoodbyeReason::Maintenance => message.expected_return,
+            GoodbyeReason::PeerRemoved | GoodbyeReason::TierChange => None,
+        };
+        Departure {
+            reason: message.reason,
+            at: now,
+            probe_at,
+        }
+    }
+
+    pub fn probe_due(&self, now: DateTime<Utc>) -> bool {
+        self.probe_at.is_some_and(|probe_at| now >= probe_at)
+    }
+}
+
+impl Vx0Node {
+    /// Tell a peer we are leaving and why; best effort, as the tunnel may
+    /// already be gone
+    pub async fn say_goodbye(
+        &self,
+        peer_id: NodeId,
+        reason: GoodbyeReason,
+        expected_return: Option<DateTime<Utc>>,
+    ) -> Result<(), NodeError> {
+        let message = GoodbyeMessage {
+            node_id: self.node_id,
+            reason,
+            expected_return,
+            timestamp: Utc::now(),
+        };
+        let data = serde_json::to_vec(&message)
+            .map_err(|e| NodeError::Network(format!("Cannot encode goodbye: {}", e)))?;
+        self.send_secure_data(&peer_id, &tunnels::peer_message(&data))
+            .await?;
+        tracing::info!("Said goodbye to peer {} ({})", peer_id, reason);
+        Ok(())
+    }
+
+    /// Leave one peer on purpose: say goodbye, then drop it
+    pub async fn disconnect_peer(
+        &self,
+        peer_id: NodeId,
+        reason: GoodbyeReason,
+    ) -> Result<(), NodeError> {
+        if let Err(e) = self.say_goodbye(peer_id, reason, None).await {
+            tracing::debug!("No goodbye for peer {}: {}", peer_id, e);
+        }
+        self.remove_peer(&peer_id).await
+    }
+
+    /// Leave every peer for maintenance, telling them when to expect us
+    /// back; returns how many peers were left. Redundancy partners are kept,
+    /// since they ask over their tunnel whether this node is still away.
+    pub async fn enter_maintenance(&self, expected_return: Option<DateTime<Utc>>) -> usize {
+        self.maintenance.send_replace(true);
+        self.redundancy.entered_maintenance(expected_return);
+        let mut left = 0;
+        for handle in self.peer_handles().await {
+            let peer_id = handle.peer_id();
+            if self.redundancy.is_partner(peer_id) {
+                continue;
+            }
+            if let Err(e) = self
+                .say_goodbye(peer_id, GoodbyeReason::Maintenance, expected_return)
+                .await
+            {
+                tracing::debug!("No goodbye for peer {}: {}", peer_id, e);
+            }
+            if self.remove_peer(&peer_id).await.is_ok() {
+                left += 1;
+            }
+        }
+        tracing::warn!(
+            target: "audit",
+            "Entered maintenance, leaving all peers but redundancy partners{}",
+            expected_return
+                .map(|at| format!("; expected back at {}", at))
+                .unwrap_or_default()
+        );
+        left
+    }
+
+    /// Between `enter_maintenance` and the next peer being admitted
+    pub fn in_maintenance(&self) -> bool {
+        *self.maintenance.borrow()
+    }
+
+    /// Act on the peer messages tunnel streams carry, for as long as the
+    /// node runs
+    pub fn follow_peer_messages(self: &Arc<Self>) {
+        let node = Arc::clone(self);
+        let mut messages = self.tunnel_manager.subscribe_peer_messages();
+        crash::spawn(Subsystem::Node, "peer-message-follower", async move {
+            loop {
+                let message = match messages.recv().await {
+                    Ok(message) => message,
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!("Missed {} peer messages", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let Some(peer_id) = node.peer_on_tunnel(message.tunnel_id, message.from).await
+                else {
+                    tracing::debug!(
+                        "Message over tunnel {} from no known peer",
+                        message.tunnel_id
+                    );
+                    continue;
+                };
+                if let Err(e) = node.handle_peer_message(peer_id, &message.body).await {
+                    tracing::warn!("Ignored a message from peer {}: {}", peer_id, e);
+                }
+            }
+        });
+    }
+
+    /// The peer that sent over `tunnel_id` from `from`: the one the tunnel
+    /// is attached to, or else the only one at that address
+    pub(crate) async fn peer_on_tunnel(&self, tunnel_id: TunnelId, from: IpAddr) -> Option<NodeId> {
+        let mut at_address = Vec::new();
+        for handle in self.peer_handles().await {
+            if handle.tunnel().await.ok().flatten() == Some(tunnel_id) {
+                return Some(handle.peer_id());
+            }
+            if let Ok(peer) = handle.snapshot().await {
+                if peer.peer_addr == from {
+                    at_address.push(peer.peer_id);
+                }
+            }
+        }
+        match at_address[..] {
+            [peer_id] => Some(peer_id),
+            _ => None,
+        }
+    }
+
+    /// Act on a message arriving from a peer over its tunnel
+    pub async fn handle_peer_message(&self, peer_id: NodeId, data: &[u8]) -> Result<(), NodeError> {
+        let message: GoodbyeMessage = serde_json::from_slice(data).map_err(|e| {
+            NodeError::Network(format!("Malformed message from {}: {}", peer_id, e))
+        })?;
+        self.receive_goodbye(peer_id, message).await
+    }
+
+    /// Record that a peer left on purpose, withdrawing what it sent
+    /// without treating it as a failure
+    pub async fn receive_goodbye(
+        &self,
+        peer_id: NodeId,
+        message: GoodbyeMessage,
+    ) -> Result<(), NodeError> {
+        if message.node_id != peer_id {
+            return Err(NodeError::Network(format!(
+                "Goodbye from peer {} claims to be from {}",
+                peer_id, message.node_id
+            )));
+        }
+        let handle = self
+            .get_peer(&peer_id)
+            .await
+            .ok_or(NodeError::UnknownPeer { peer: peer_id })?;
+
+        let departure = Departure::new(&message, Utc::now());
+        tracing::info!(
+            "Peer {} left ({}){}",
+            peer_id,
+            message.reason,
+            departure
+                .probe_at
+                .map(|at| format!("; will probe at {}", at))
+                .unwrap_or_else(|| "; waiting for it to announce itself".to_string())
+        );
+        handle.set_departure(Some(departure)).await?;
+        let _ = self.peer_events.send(PeerEvent::Departed {
+            peer_id,
+            peer_asn: handle.peer_asn(),
+            reason: message.reason,
+        });
+        Ok(())
+    }
+
+    /// Treat a departed peer as reachable again, as when it re-announces or
+    /// its probe time comes; returns whether it had departed
+    pub async fn welcome_back(&self, peer_id: NodeId) -> Result<bool, NodeError> {
+        let Some(handle) = self.get_peer(&peer_id).await else {
+            return Ok(false);
+        };
+        let peer = handle.snapshot().await?;
+        if !matches!(peer.status, ConnectionStatus::Departed) {
+            return Ok(false);
+        }
+        handle.set_departure(None).await?;
+        tracing::info!("Peer {} is back; reconnecting as usual", peer_id);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::bgp::{BGPDaemon, RouteEntry};
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use crate::node::PeerConnection;
+    use std::sync::Arc;
+    use tokio::time::Duration;
+
+    fn route(network: &str, peer_asn: u32) -> RouteEntry {
+        crate::network::bgp::testing::route(network, "10.1.0.1", &[peer_asn])
+    }
+
+    #[tokio::test]
+    async fn test_goodbye_withdraws_routes_without_counting_a_flap() {
+        let config = testing::config(NodeTier::Edge);
+        let node = Arc::new(Vx0Node::new(config).unwrap());
+        let bgp = Arc::new(BGPDaemon::new(66001, "10.2.0.1".parse().unwrap(), 0));
+        bgp.follow_peer_events(node.subscribe_peer_events());
+
+        let (leaving, crashing) = (NodeId::new_v4(), NodeId::new_v4());
+        for (peer_id, asn, addr) in [(leaving, 65101, "10.1.0.1"), (crashing, 65102, "10.1.0.2")] {
+            node.add_peer(PeerConnection::new(peer_id, asn, addr.parse().unwrap()))
+                .await
+                .unwrap();
+            bgp.receive_update(
+                asn,
+                vec![route(&format!("10.{}.0.0/16", asn - 65000), asn)],
+                &[],
+            )
+            .await
+            .unwrap();
+        }
+
+        node.receive_goodbye(
+            leaving,
+            GoodbyeMessage {
+                node_id: leaving,
+                reason: GoodbyeReason::Shutdown,
+                expected_return: None,
+                timestamp: Utc::now(),
+            },
+        )
+        .await
+        .unwrap();
+        // The BGP side hears about it over the peer event feed
+        for _ in 0..50 {
+            if bgp.routes_from(65101).await == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(bgp.routes_from(65101).await, 0);
+        assert_eq!(bgp.flaps(65101).await, 0);
+
+        let peer = node
+            .get_peer(&leaving)
+            .await
+            .unwrap()
+            .snapshot()
+            .await
+            .unwrap();
+        assert!(matches!(peer.status, ConnectionStatus::Departed));
+        let departure = peer.departure.unwrap();
+        assert!(!departure.probe_due(Utc::now()));
+        assert!(departure.probe_due(Utc::now() + chrono::Duration::hours(2)));
+
+        // A session that just drops takes the failure path
+        bgp.session_failed(65102).await.unwrap();
+        assert_eq!(bgp.routes_from(65102).await, 0);
+        assert_eq!(bgp.flaps(65102).await, 1);
+
+        // Announcing itself again brings the departed peer back
+        assert!(node.welcome_back(leaving).await.unwrap());
+        let peer = node
+            .get_peer(&leaving)
+            .await
+            .unwrap()
+            .snapshot()
+            .await
+            .unwrap();
+        assert!(matches!(peer.status, ConnectionStatus::Disconnected));
+        assert!(peer.departure.is_none());
+    }
+
+    #[test]
+    fn test_maintenance_probes_at_expected_return() {
+        let now = Utc::now();
+        let back = now + chrono::Duration::minutes(30);
+        let departure = Departure::new(
+            &GoodbyeMessage {
+                node_id: NodeId::new_v4(),
+                reason: GoodbyeReason::Maintenance,
+                expected_return: Some(back),
+                timestamp: now,
+            },
+            now,
+        );
+        assert_eq!(departure.probe_at, Some(back));
+        assert!(!departure.probe_due(back - chrono::Duration::seconds(1)));
+        assert!(departure.probe_due(back));
+
+        let removed = Departure::new(
+            &GoodbyeMessage {
+                node_id: NodeId::new_v4(),
+                reason: GoodbyeReason::PeerRemoved,
+                expected_return: None,
+                timestamp: now,
+            },
+            now,
+        );
+        assert!(!removed.probe_due(now + chrono::Duration::days(365)));
+    }
+}
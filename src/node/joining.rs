@@ -2,13 +2,26 @@
 ///
 /// This module implements an open joining mechanism that allows anyone to join and expand
 /// the VX0 network without requiring permission from existing nodes.
+use crate::build_info::BuildInfo;
 use crate::config::BootstrapNode;
+use crate::monitoring::capture::CapturedEvent;
+use crate::network::acl::Contact;
 use crate::network::bgp::protocol::BGPProtocol;
+use crate::network::ike::encap;
+use crate::network::transport::{self, Codec, MessageTag, TransportConfig};
+use crate::node::asn_registry::{self, AsnGrant, GrantRequest};
+use crate::node::bootstrap_health::BootstrapSource;
+use crate::node::capabilities::{self, NodeDirectoryEntry};
 use crate::node::{NodeError, NodeTier, PeerConnection, Vx0Node};
+use crate::util::clock::SharedClock;
+use crate::util::sync::lock;
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::time::{timeout, Duration};
 
 /// Public directory of known VX0 network entry points
@@ -25,6 +38,18 @@ pub const PUBLIC_BOOTSTRAP_NODES: &[(&str, &str, u32)] = &[
 /// Well-known ports for VX0 network discovery
 pub const VX0_DISCOVERY_PORT: u16 = 8080;
 pub const VX0_BGP_PORT: u16 = 1179;
+pub const VX0_REGISTRY_PORT: u16 = 5356;
+
+/// Entry points probed at once while choosing where to join
+const PROBE_PARALLELISM: usize = 8;
+/// How long a probe result is trusted before the peer is probed again
+const PROBE_TTL: Duration = Duration::from_secs(30);
+/// Join requests in flight at once
+const JOIN_PARALLELISM: usize = 4;
+/// How long joiners are told to wait while we are still joining ourselves
+const BUSY_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// Backbone entry points asked for an ASN grant before picking one ourselves
+const REGISTRY_ATTEMPTS: usize = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JoinRequest {
@@ -35,7 +60,12 @@ pub struct JoinRequest {
     pub public_ip: IpAddr,
     pub requested_services: Vec<String>,
     pub contact_info: Option<String>,
+    #[serde(default)]
+    pub build: BuildInfo,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// A registry's grant of `asn` to this node, when it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grant: Option<AsnGrant>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,7 +74,118 @@ pub struct JoinResponse {
     pub assigned_asn: Option<u32>,
     pub bootstrap_peers: Vec<BootstrapNode>,
     pub network_info: NetworkInfo,
+    /// The rejection's detail, for joiners that predate `rejection`; kept
+    /// for one protocol version
     pub rejection_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rejection: Option<JoinRejection>,
+    /// The address the join request came from, as the answering node saw it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_addr: Option<IpAddr>,
+}
+
+impl JoinResponse {
+    /// Turn a join request down, filling in the old reason field too
+    pub fn rejected(rejection: JoinRejection, network_info: NetworkInfo) -> Self {
+        JoinResponse {
+            accepted: false,
+            assigned_asn: None,
+            bootstrap_peers: Vec::new(),
+            network_info,
+            rejection_reason: Some(rejection.detail.clone()),
+            rejection: Some(rejection),
+            observed_addr: None,
+        }
+    }
+
+    /// Why we were turned down, in plain language
+    pub fn explain_rejection(&self) -> String {
+        match (&self.rejection, &self.rejection_reason) {
+            (Some(rejection), _) => rejection.explain(),
+            (None, Some(reason)) => reason.clone(),
+            (None, None) => "No reason given".to_string(),
+        }
+    }
+}
+
+/// Why an entry point turned a join request down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JoinRejectionCode {
+    /// No room for more peers of the joiner's tier; try `alternate_peers`
+    TierCapacity,
+    /// The ASN is taken or outside the tier's range; try `suggested_asn`
+    AsnConflict,
+    /// Operator policy or tier rules refuse the joiner; asking again won't help
+    PolicyDenied,
+    /// No protocol version in common
+    VersionIncompatible,
+    /// Ask again after `retry_after`
+    TemporarilyUnavailable,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinRejection {
+    pub code: JoinRejectionCode,
+    pub detail: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggested_asn: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternate_peers: Vec<BootstrapNode>,
+}
+
+impl JoinRejection {
+    pub fn new(code: JoinRejectionCode, detail: impl Into<String>) -> Self {
+        JoinRejection {
+            code,
+            detail: detail.into(),
+            retry_after: None,
+            suggested_asn: None,
+            alternate_peers: Vec::new(),
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    pub fn with_suggested_asn(mut self, asn: Option<u32>) -> Self {
+        self.suggested_asn = asn;
+        self
+    }
+
+    pub fn with_alternate_peers(mut self, peers: Vec<BootstrapNode>) -> Self {
+        self.alternate_peers = peers;
+        self
+    }
+
+    /// What happened and what to do about it, for the operator
+    pub fn explain(&self) -> String {
+        let advice = match self.code {
+            JoinRejectionCode::TierCapacity => match self.alternate_peers.len() {
+                0 => "the entry point is full; try another one later".to_string(),
+                n => format!("the entry point is full and suggested {} others", n),
+            },
+            JoinRejectionCode::AsnConflict => match self.suggested_asn {
+                Some(asn) => format!("pick another ASN, such as {}", asn),
+                None => "pick another ASN (see vx0net scan-asns)".to_string(),
+            },
+            JoinRejectionCode::PolicyDenied => {
+                "the entry point does not admit this node; asking again won't help".to_string()
+            }
+            JoinRejectionCode::VersionIncompatible => {
+                "this daemon and the entry point share no protocol version; upgrade vx0net"
+                    .to_string()
+            }
+            JoinRejectionCode::TemporarilyUnavailable => match self.retry_after {
+                Some(after) => format!("the entry point is busy; try again in {:?}", after),
+                None => "the entry point is busy; try again later".to_string(),
+            },
+        };
+        format!("{} ({})", self.detail, advice)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -65,13 +206,199 @@ pub struct RecommendedSettings {
     pub tunnel_rekey_interval_secs: u64,
 }
 
+/// How the joiner reaches other nodes, so discovery and selection can run
+/// against a scripted network in tests
+#[async_trait]
+pub trait JoinTransport: Send + Sync {
+    /// Whether the peer accepts BGP connections within `limit`
+    async fn test_connectivity(&self, peer: &BootstrapNode, limit: Duration) -> bool;
+
+    /// Send a join request and wait up to `limit` for the peer's decision
+    async fn request_join(
+        &self,
+        peer: &BootstrapNode,
+        request: &JoinRequest,
+        limit: Duration,
+    ) -> Result<JoinResponse, NodeError>;
+
+    /// ASNs already taken in a tier
+    async fn fetch_used_asns(&self, tier: &NodeTier) -> Result<HashSet<u32>, NodeError>;
+
+    /// Ask the ASN registry on `registry` for a grant; transports that
+    /// cannot reach registries never get one
+    async fn request_grant(
+        &self,
+        registry: &BootstrapNode,
+        _request: &GrantRequest,
+        _limit: Duration,
+    ) -> Result<AsnGrant, NodeError> {
+        Err(NodeError::Network(format!(
+            "No ASN registry reachable at {}",
+            registry.hostname
+        )))
+    }
+
+    /// Addresses a bootstrap hostname resolves to
+    async fn resolve_bootstrap_dns(&self, hostname: &str) -> Result<Vec<IpAddr>, NodeError>;
+}
+
+/// Joins over real sockets: TCP to the BGP and discovery ports, system DNS
+pub struct TcpJoinTransport;
+
+#[async_trait]
+impl JoinTransport for TcpJoinTransport {
+    async fn test_connectivity(&self, peer: &BootstrapNode, limit: Duration) -> bool {
+        let addr = format!("{}:{}", peer.ip, VX0_BGP_PORT);
+        let Ok(socket_addr) = addr.parse::<SocketAddr>() else {
+            return false;
+        };
+        let socket = match socket_addr {
+            SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4(),
+            SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6(),
+        };
+        match socket {
+            Ok(socket) => matches!(timeout(limit, socket.connect(socket_addr)).await, Ok(Ok(_))),
+            Err(e) => {
+                tracing::warn!("Failed to create socket to test {}: {}", addr, e);
+                false
+            }
+        }
+    }
+
+    async fn request_join(
+        &self,
+        peer: &BootstrapNode,
+        request: &JoinRequest,
+        limit: Duration,
+    ) -> Result<JoinResponse, NodeError> {
+        let peer_ip: IpAddr = peer
+            .ip
+            .parse()
+            .map_err(|source| NodeError::InvalidAddress {
+                kind: "peer",
+                address: peer.ip.clone(),
+                source,
+            })?;
+
+        // One JSON line each way on the discovery port
+        let exchange = async {
+            let peer = SocketAddr::new(peer_ip, VX0_DISCOVERY_PORT);
+            let config = TransportConfig::default().with_connect_timeout(limit);
+            let mut stream =
+                transport::connect(peer, Codec::Lines, MessageTag::Join, config).await?;
+            stream.send_json(request).await?;
+            stream.recv_json().await?.ok_or_else(|| {
+                NodeError::Network(format!("{} closed the connection without answering", peer))
+            })
+        };
+        timeout(limit, exchange).await.map_err(|_| {
+            NodeError::Network(format!(
+                "{} did not answer the join request within {:?}",
+                peer.hostname, limit
+            ))
+        })?
+    }
+
+    async fn fetch_used_asns(&self, _tier: &NodeTier) -> Result<HashSet<u32>, NodeError> {
+        // Only used without a registry grant, so any ASN in range is free
+        Ok(HashSet::new())
+    }
+
+    async fn request_grant(
+        &self,
+        registry: &BootstrapNode,
+        request: &GrantRequest,
+        limit: Duration,
+    ) -> Result<AsnGrant, NodeError> {
+        let ip: IpAddr = registry
+            .ip
+            .parse()
+            .map_err(|source| NodeError::InvalidAddress {
+                kind: "registry",
+                address: registry.ip.clone(),
+                source,
+            })?;
+        let addr = SocketAddr::new(ip, VX0_REGISTRY_PORT);
+        Ok(asn_registry::request_grant(addr, request, limit).await?)
+    }
+
+    async fn resolve_bootstrap_dns(&self, hostname: &str) -> Result<Vec<IpAddr>, NodeError> {
+        let addrs = tokio::net::lookup_host((hostname, VX0_BGP_PORT)).await?;
+        Ok(addrs.map(|addr| addr.ip()).collect())
+    }
+}
+
 pub struct NetworkJoiner {
     node: Arc<Vx0Node>,
+    /// Known nodes and their advertised capabilities
+    directory: Vec<NodeDirectoryEntry>,
+    /// Entry points tried first; ones without a literal address go through DNS
+    seeds: Vec<BootstrapNode>,
+    transport: Arc<dyn JoinTransport>,
+    /// Times the waits between join rounds
+    clock: SharedClock,
+    /// Recent probe results, with when they were taken
+    probes: Mutex<HashMap<ProbeKey, (Instant, Option<Duration>)>>,
+    dissenters: Mutex<Vec<Dissent>>,
+}
+
+/// What a probe result is kept under: the endpoint probed and the AS
+/// expected to answer there, so entry points sharing an address stay apart
+type ProbeKey = (SocketAddr, u32);
+
+fn probe_key(peer: &BootstrapNode) -> Option<ProbeKey> {
+    let ip: IpAddr = peer.ip.parse().ok()?;
+    Some((SocketAddr::new(ip, VX0_BGP_PORT), peer.asn))
+}
+
+/// An entry point whose join answer disagreed with the quorum
+#[derive(Debug, Clone)]
+pub struct Dissent {
+    pub responder: BootstrapNode,
+    pub answer: String,
 }
 
 impl NetworkJoiner {
     pub fn new(node: Arc<Vx0Node>) -> Self {
-        NetworkJoiner { node }
+        let clock = Arc::clone(&node.clock);
+        NetworkJoiner {
+            node,
+            directory: Vec::new(),
+            seeds: PUBLIC_BOOTSTRAP_NODES
+                .iter()
+                .map(|(hostname, ip, asn)| BootstrapNode {
+                    hostname: hostname.to_string(),
+                    ip: ip.to_string(),
+                    asn: *asn,
+                })
+                .collect(),
+            transport: Arc::new(TcpJoinTransport),
+            clock,
+            probes: Mutex::new(HashMap::new()),
+            dissenters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Prefer entry points the directory lists as accepting new edges
+    pub fn with_directory(mut self, directory: Vec<NodeDirectoryEntry>) -> Self {
+        self.directory = directory;
+        self
+    }
+
+    /// Replace the public bootstrap list
+    pub fn with_seeds(mut self, seeds: Vec<BootstrapNode>) -> Self {
+        self.seeds = seeds;
+        self
+    }
+
+    pub fn with_transport(mut self, transport: Arc<dyn JoinTransport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
     }
 
     /// Main entry point for joining the VX0 network
@@ -82,19 +409,7 @@ impl NetworkJoiner {
             self.node.hostname
         );
 
-        // Step 1: Auto-assign ASN if not already assigned
-        let assigned_asn = self.auto_assign_asn().await?;
-
-        // Step 2: Discover available entry points
-        let entry_points = self.discover_entry_points().await?;
-
-        // Step 3: Find suitable peers based on our tier
-        let suitable_peers = self.find_suitable_peers(&entry_points).await?;
-
-        // Step 4: Attempt to join through multiple peers
-        let join_response = self
-            .attempt_network_join(&suitable_peers, assigned_asn)
-            .await?;
+        let join_response = self.negotiate().await?;
 
         // Step 5: Establish initial connections
         if join_response.accepted {
@@ -103,13 +418,93 @@ impl NetworkJoiner {
 
             tracing::info!(
                 "✅ Successfully joined VX0 network with ASN {}",
-                assigned_asn.unwrap_or(self.node.asn)
+                join_response.assigned_asn.unwrap_or(self.node.asn)
             );
         }
 
         Ok(join_response)
     }
 
+    /// Pick an ASN, find entry points and ask them to admit us, without
+    /// connecting to anyone yet
+    pub async fn negotiate(&self) -> Result<JoinResponse, NodeError> {
+        // Step 1: Discover available entry points
+        let entry_points = self.discover_entry_points().await?;
+
+        // Step 2: Take an ASN from a registry, or pick one if none answers
+        let grant = self.request_grant(&entry_points).await;
+        let assigned_asn = match &grant {
+            Some(grant) => Some(grant.asn),
+            None => self.auto_assign_asn().await?,
+        };
+
+        // Step 3: Find suitable peers based on our tier
+        let suitable_peers = self.find_suitable_peers(&entry_points).await?;
+
+        // Step 4: Attempt to join through multiple peers
+        self.attempt_network_join(&suitable_peers, assigned_asn, grant)
+            .await
+    }
+
+    /// A grant from the first Backbone entry point running a registry
+    async fn request_grant(&self, entry_points: &[BootstrapNode]) -> Option<AsnGrant> {
+        if !self.node.config.joining.registry.request_grant {
+            return None;
+        }
+        let (low, high) = self.node.tier.get_asn_range();
+        let request = GrantRequest::new(
+            self.node.node_id,
+            &self.node.hostname,
+            self.node.tier.clone(),
+            Some(self.node.asn).filter(|asn| (low..=high).contains(asn)),
+        );
+        let request = match self.node.asn_registry.sign_request(request) {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::warn!("{}", crate::error::Report(&e));
+                return None;
+            }
+        };
+        let limit = Duration::from_secs(self.node.config.joining.connect_timeout_secs);
+
+        let mut asked = HashSet::new();
+        let registries = entry_points
+            .iter()
+            .filter(|entry_point| NodeTier::from_asn(entry_point.asn) == NodeTier::Backbone)
+            .filter(|entry_point| asked.insert(entry_point.ip.clone()))
+            .take(REGISTRY_ATTEMPTS);
+        for registry in registries {
+            match self
+                .transport
+                .request_grant(registry, &request, limit)
+                .await
+            {
+                Ok(grant)
+                    if grant.node_id == request.node_id
+                        && (low..=high).contains(&grant.asn)
+                        && grant.verify() =>
+                {
+                    tracing::info!(
+                        "Registry {} granted ASN {} to node {}",
+                        grant.registry,
+                        grant.asn,
+                        self.node.hostname
+                    );
+                    return Some(grant);
+                }
+                Ok(grant) => tracing::warn!(
+                    "Ignoring an invalid ASN grant from {}: ASN {} for {}",
+                    registry.hostname,
+                    grant.asn,
+                    grant.node_id
+                ),
+                Err(e) => tracing::debug!("No ASN grant from {}: {}", registry.hostname, e),
+            }
+        }
+        tracing::info!("No ASN registry answered; picking an ASN ourselves");
+        None
+    }
+
     /// Automatically assign an ASN based on the node's tier and availability
     async fn auto_assign_asn(&self) -> Result<Option<u32>, NodeError> {
         // If ASN is already assigned and valid, use it
@@ -131,7 +526,7 @@ impl NetworkJoiner {
         );
 
         // Discover used ASNs in the network
-        let used_asns = self.discover_used_asns().await?;
+        let used_asns = self.transport.fetch_used_asns(&self.node.tier).await?;
 
         // Find the next available ASN
         for candidate_asn in min_asn..=max_asn {
@@ -157,15 +552,20 @@ impl NetworkJoiner {
 
         let mut entry_points = Vec::new();
 
-        // Try public bootstrap nodes first
-        for (hostname, ip, asn) in PUBLIC_BOOTSTRAP_NODES {
-            if ip != &"YOUR_BACKBONE_IP" {
-                // Skip placeholder IPs
-                entry_points.push(BootstrapNode {
-                    hostname: hostname.to_string(),
+        // Seeds with a literal address are used as-is, the rest are
+        // resolved; healthiest first, long-failing ones only when due a probe
+        for seed in self.node.bootstrap.rank(&self.seeds, chrono::Utc::now()) {
+            if seed.ip.parse::<IpAddr>().is_ok() {
+                entry_points.push(seed);
+                continue;
+            }
+            match self.transport.resolve_bootstrap_dns(&seed.hostname).await {
+                Ok(addrs) => entry_points.extend(addrs.into_iter().map(|ip| BootstrapNode {
+                    hostname: seed.hostname.clone(),
                     ip: ip.to_string(),
-                    asn: *asn,
-                });
+                    asn: seed.asn,
+                })),
+                Err(e) => tracing::debug!("Could not resolve {}: {}", seed.hostname, e),
             }
         }
 
@@ -173,10 +573,6 @@ impl NetworkJoiner {
         let discovered_peers = self.discover_local_peers().await?;
         entry_points.extend(discovered_peers);
 
-        // Try DNS-based discovery for well-known VX0 domains
-        let dns_discovered = self.dns_discovery().await?;
-        entry_points.extend(dns_discovered);
-
         if entry_points.is_empty() {
             return Err(NodeError::Network("No entry points discovered. The VX0 network may not be reachable from this location.".to_string()));
         }
@@ -193,17 +589,16 @@ impl NetworkJoiner {
         &self,
         entry_points: &[BootstrapNode],
     ) -> Result<Vec<BootstrapNode>, NodeError> {
-        let mut suitable_peers = Vec::new();
-
-        for entry_point in entry_points {
-            let peer_tier = Self::asn_to_tier(entry_point.asn);
-            if self.node.tier.can_peer_with(&peer_tier) {
-                // Test connectivity
-                if self.test_connectivity(entry_point).await {
-                    suitable_peers.push(entry_point.clone());
-                }
-            }
-        }
+        let candidates: Vec<BootstrapNode> = entry_points
+            .iter()
+            .filter(|entry_point| {
+                self.node
+                    .tier
+                    .can_peer_with(&NodeTier::from_asn(entry_point.asn))
+            })
+            .cloned()
+            .collect();
+        let mut suitable_peers = self.rank_reachable(&candidates).await;
 
         if suitable_peers.is_empty() {
             return Err(NodeError::Network(
@@ -211,6 +606,15 @@ impl NetworkJoiner {
             ));
         }
 
+        // Edges go to regionals with room first; the rest keep their ranking
+        if matches!(self.node.tier, NodeTier::Edge) {
+            suitable_peers = capabilities::prefer_capable(suitable_peers, |peer| {
+                self.directory
+                    .iter()
+                    .any(|node| node.asn == peer.asn && node.capabilities.accepts_new_edges)
+            });
+        }
+
         tracing::info!(
             "🎯 Found {} suitable peers for {:?} tier node",
             suitable_peers.len(),
@@ -220,67 +624,275 @@ impl NetworkJoiner {
     }
 
     /// Attempt to join the network through discovered peers
+    ///
+    /// The best-ranked peers are asked at once, and their view of the
+    /// network is only trusted when a quorum of them agree on it.
     async fn attempt_network_join(
         &self,
         peers: &[BootstrapNode],
-        assigned_asn: Option<u32>,
+        mut assigned_asn: Option<u32>,
+        grant: Option<AsnGrant>,
     ) -> Result<JoinResponse, NodeError> {
-        let join_request = JoinRequest {
+        let settings = &self.node.config.joining;
+        let limit = Duration::from_secs(settings.connect_timeout_secs);
+        let quorum = settings.quorum.max(1);
+        lock(&self.dissenters).clear();
+        let mut join_request = JoinRequest {
             node_id: self.node.node_id,
             hostname: self.node.hostname.clone(),
             asn: assigned_asn.unwrap_or(self.node.asn),
             tier: self.node.tier.clone(),
-            public_ip: IpAddr::V4(self.node.ipv4_addr),
+            public_ip: IpAddr::V4(self.node.public_ipv4()),
             requested_services: vec!["routing".to_string()],
             contact_info: None,
+            build: BuildInfo::current(),
             timestamp: chrono::Utc::now(),
+            grant,
         };
 
-        // Try each peer until one accepts us
-        for peer in peers {
-            match self.request_join(peer, &join_request).await {
-                Ok(response) if response.accepted => {
-                    tracing::info!("✅ Accepted into network by {}", peer.hostname);
-                    return Ok(response);
+        // Peers are asked as soon as there is reason to: first the best
+        // ranked, then ones suggested in their place or asked again with
+        // another ASN. Peers that did not answer or asked us to come back
+        // later are asked again after a pause; ones that said no are not.
+        let mut asking: Vec<BootstrapNode> = peers
+            .iter()
+            .take(settings.join_fanout.max(quorum))
+            .cloned()
+            .collect();
+        let mut asked: HashSet<(String, u32)> = HashSet::new();
+        let mut tried_asns = HashSet::from([join_request.asn]);
+        let mut retry: Vec<BootstrapNode> = Vec::new();
+        let mut retry_after = Duration::ZERO;
+        let mut admitted: Vec<(BootstrapNode, JoinResponse)> = Vec::new();
+        let mut rejections: Vec<String> = Vec::new();
+        let mut rejection: Option<JoinRejection> = None;
+        let mut backoff = self.node.config.network.retry.join().backoff();
+        loop {
+            let agreed = find_consensus(&admitted, settings.stats_tolerance_pct).0;
+            if agreed.len() >= quorum {
+                break;
+            }
+            if asking.is_empty() {
+                if retry.is_empty() {
+                    break;
                 }
-                Ok(response) => {
-                    tracing::warn!(
-                        "❌ Rejected by {}: {}",
-                        peer.hostname,
-                        response
-                            .rejection_reason
-                            .unwrap_or("No reason given".to_string())
-                    );
+                let Some(delay) = backoff.next() else {
+                    break;
+                };
+                // Whichever is later: our schedule or what a peer asked for
+                self.clock.sleep(delay.max(retry_after)).await;
+                retry_after = Duration::ZERO;
+                asking = std::mem::take(&mut retry);
+            }
+            for peer in &asking {
+                asked.insert((peer.ip.clone(), peer.asn));
+            }
+
+            // Answers come back in ranking order so ties favour better peers
+            let answers: Vec<(BootstrapNode, Result<JoinResponse, NodeError>)> =
+                stream::iter(std::mem::take(&mut asking))
+                    .map(|peer| {
+                        let join_request = &join_request;
+                        async move {
+                            let answer = self.transport.request_join(&peer, join_request, limit);
+                            let answer = answer.await;
+                            (peer, answer)
+                        }
+                    })
+                    .buffered(JOIN_PARALLELISM)
+                    .collect()
+                    .await;
+
+            for (peer, answer) in answers {
+                if let (Ok(response), Ok(reporter)) = (&answer, peer.ip.parse()) {
+                    self.node.observe_address(reporter, response.observed_addr);
                 }
-                Err(e) => {
-                    tracing::warn!("Failed to contact {}: {}", peer.hostname, e);
+                let response = match answer {
+                    Ok(response) if response.accepted => {
+                        tracing::info!("✅ Accepted into network by {}", peer.hostname);
+                        for learned in &response.bootstrap_peers {
+                            self.node
+                                .bootstrap
+                                .add(learned.clone(), BootstrapSource::Learned);
+                        }
+                        admitted.push((peer, response));
+                        continue;
+                    }
+                    Ok(response) => response,
+                    Err(e) => {
+                        tracing::warn!("Failed to contact {}: {}", peer.hostname, e);
+                        retry.push(peer);
+                        continue;
+                    }
+                };
+                let explanation = response.explain_rejection();
+                tracing::warn!("❌ Rejected by {}: {}", peer.hostname, explanation);
+                rejections.push(format!("{}: {}", peer.hostname, explanation));
+                let Some(found) = response.rejection else {
+                    continue;
+                };
+                match found.code {
+                    JoinRejectionCode::TemporarilyUnavailable => {
+                        retry_after = retry_after.max(found.retry_after.unwrap_or_default());
+                        retry.push(peer);
+                    }
+                    JoinRejectionCode::AsnConflict => {
+                        // Each ASN is tried once, so suggestions can't bounce us
+                        // between entry points forever
+                        let (low, high) = self.node.tier.get_asn_range();
+                        if let Some(asn) = found
+                            .suggested_asn
+                            .filter(|asn| (low..=high).contains(asn) && tried_asns.insert(*asn))
+                        {
+                            tracing::info!(
+                                "{} suggested ASN {}; asking again with it",
+                                peer.hostname,
+                                asn
+                            );
+                            join_request.asn = asn;
+                            // A grant only covers the ASN it names
+                            join_request.grant = None;
+                            assigned_asn = Some(asn);
+                            // Admissions under the old ASN no longer count
+                            asking.extend(admitted.drain(..).map(|(peer, _)| peer));
+                            asking.push(peer);
+                        }
+                    }
+                    JoinRejectionCode::TierCapacity => {
+                        for alternate in &found.alternate_peers {
+                            self.node
+                                .bootstrap
+                                .add(alternate.clone(), BootstrapSource::Learned);
+                            if self
+                                .node
+                                .tier
+                                .can_peer_with(&NodeTier::from_asn(alternate.asn))
+                                && !asked.contains(&(alternate.ip.clone(), alternate.asn))
+                                && !asking.iter().any(|peer| {
+                                    peer.ip == alternate.ip && peer.asn == alternate.asn
+                                })
+                            {
+                                asking.push(alternate.clone());
+                            }
+                        }
+                    }
+                    JoinRejectionCode::PolicyDenied | JoinRejectionCode::VersionIncompatible => {}
                 }
+                rejection.get_or_insert(found);
+            }
+        }
+
+        let (agreed, dissenting) = find_consensus(&admitted, settings.stats_tolerance_pct);
+        if agreed.len() >= quorum {
+            for &i in &dissenting {
+                let (peer, response) = &admitted[i];
+                tracing::warn!(
+                    target: "audit",
+                    "Entry point {} ({}) disagrees with {} others: {}",
+                    peer.hostname,
+                    peer.ip,
+                    agreed.len(),
+                    describe_answer(response)
+                );
+                lock(&self.dissenters).push(Dissent {
+                    responder: peer.clone(),
+                    answer: describe_answer(response),
+                });
             }
+            let agreeing: Vec<&(BootstrapNode, JoinResponse)> =
+                agreed.iter().map(|&i| &admitted[i]).collect();
+            tracing::info!(
+                "{} entry points agree: {}",
+                agreeing.len(),
+                describe_answer(&agreeing[0].1)
+            );
+            return Ok(JoinResponse {
+                accepted: true,
+                assigned_asn: agreeing[0].1.assigned_asn,
+                bootstrap_peers: merge_bootstrap_peers(&agreeing),
+                network_info: median_network_info(&agreeing),
+                rejection_reason: None,
+                rejection: None,
+                observed_addr: None,
+            });
         }
 
-        // If no one accepted us, create a permissive response
-        // This allows the network to be truly open - anyone can join
+        if !admitted.is_empty() {
+            for (peer, response) in &admitted {
+                tracing::warn!(
+                    "Entry point {} ({}) answered: {}",
+                    peer.hostname,
+                    peer.ip,
+                    describe_answer(response)
+                );
+            }
+            // Degrade to trusting the best-ranked peer that admitted us
+            if settings.allow_unverified_join {
+                tracing::warn!(
+                    "Only {} of {} entry points needed agree; trusting {} unverified",
+                    agreed.len(),
+                    quorum,
+                    admitted[0].0.hostname
+                );
+                return Ok(admitted.swap_remove(0).1);
+            }
+            rejections.push(format!(
+                "only {} of {} entry points needed gave consistent answers",
+                agreed.len(),
+                quorum
+            ));
+        }
+
+        // Nobody decided in our favour. Peers that never answered may simply
+        // lack a join endpoint, so the network can be configured to stay open
+        if rejections.is_empty() && settings.allow_unverified_join {
+            tracing::warn!("No entry point answered the join request; proceeding unverified");
+            return Ok(JoinResponse {
+                accepted: true,
+                assigned_asn,
+                bootstrap_peers: peers.to_vec(),
+                network_info: NetworkInfo {
+                    total_nodes: 1,
+                    backbone_nodes: 0,
+                    regional_nodes: 0,
+                    edge_nodes: 1,
+                    network_version: "1.0.0".to_string(),
+                    recommended_settings: RecommendedSettings {
+                        max_peers: self.node.tier.max_peers(),
+                        update_interval_secs: 60,
+                        discovery_interval_secs: 300,
+                        tunnel_rekey_interval_secs: 3600,
+                    },
+                },
+                rejection_reason: None,
+                rejection: None,
+                observed_addr: None,
+            });
+        }
+
+        // The first structured rejection says what kind of no it was; the
+        // reason lists every answer
         Ok(JoinResponse {
-            accepted: true,
+            accepted: false,
             assigned_asn,
-            bootstrap_peers: peers.to_vec(),
-            network_info: NetworkInfo {
-                total_nodes: 1,
-                backbone_nodes: 0,
-                regional_nodes: 0,
-                edge_nodes: 1,
-                network_version: "1.0.0".to_string(),
-                recommended_settings: RecommendedSettings {
-                    max_peers: self.node.tier.max_peers(),
-                    update_interval_secs: 60,
-                    discovery_interval_secs: 300,
-                    tunnel_rekey_interval_secs: 3600,
-                },
-            },
-            rejection_reason: None,
+            bootstrap_peers: Vec::new(),
+            network_info: network_info(&self.node.tier),
+            rejection_reason: Some(if rejections.is_empty() {
+                "No entry point answered the join request".to_string()
+            } else {
+                rejections.join("; ")
+            }),
+            rejection,
+            observed_addr: None,
         })
     }
 
+    /// Entry points whose answers disagreed with the quorum during the
+    /// last join
+    pub fn dissenters(&self) -> Vec<Dissent> {
+        lock(&self.dissenters).clone()
+    }
+
     /// Establish initial connections after being accepted
     async fn establish_initial_connections(
         &self,
@@ -291,13 +903,27 @@ impl NetworkJoiner {
             response.bootstrap_peers.len()
         );
 
+        // Peers probed during selection are not probed again
+        let peers = self.rank_reachable(&response.bootstrap_peers).await;
         let mut connected_count = 0;
-        let target_connections = std::cmp::min(3, response.bootstrap_peers.len()); // Connect to at least 3 peers
+        let target_connections = std::cmp::min(3, peers.len()); // Connect to at least 3 peers
 
-        for peer in &response.bootstrap_peers {
+        for peer in &peers {
             if connected_count >= target_connections {
                 break;
             }
+            if self.node.admin.is_down(peer.asn) {
+                tracing::info!("Skipping AS{}; it is administratively down", peer.asn);
+                continue;
+            }
+            if self
+                .node
+                .quarantine
+                .is_quarantined(peer.asn, self.node.clock.now_utc())
+            {
+                tracing::info!("Skipping AS{}; it is quarantined", peer.asn);
+                continue;
+            }
 
             if let Ok(()) = self.establish_connection(peer).await {
                 connected_count += 1;
@@ -320,9 +946,15 @@ impl NetworkJoiner {
 
     /// Establish a connection to a specific peer
     async fn establish_connection(&self, peer: &BootstrapNode) -> Result<(), NodeError> {
-        let peer_addr: SocketAddr = format!("{}:{}", peer.ip, VX0_BGP_PORT)
+        let peer_ip: IpAddr = peer
+            .ip
             .parse()
-            .map_err(|e| NodeError::Network(format!("Invalid peer address: {}", e)))?;
+            .map_err(|source| NodeError::InvalidAddress {
+                kind: "peer",
+                address: peer.ip.clone(),
+                source,
+            })?;
+        let peer_addr = SocketAddr::new(peer_ip, VX0_BGP_PORT);
 
         // Create BGP connection
         let bgp_protocol = BGPProtocol::new(
@@ -331,101 +963,104 @@ impl NetworkJoiner {
             self.node.tier.clone(),
         );
 
-        let _bgp_session = bgp_protocol
-            .connect_to_peer(peer_addr, peer.asn)
-            .await
-            .map_err(|e| NodeError::BGP(format!("BGP connection failed: {}", e)))?;
+        let bgp_session = bgp_protocol.connect_to_peer(peer_addr, peer.asn).await?;
+        self.node
+            .observe_address(peer_ip, bgp_session.observed_addr);
 
-        // Create secure tunnel
-        let psk = self.get_default_psk(); // In production, use proper key exchange
-        let _tunnel_id = self
+        // Add as peer; the peer's task then owns the tunnel we create for it
+        let peer_id = uuid::Uuid::new_v4();
+        let peer_connection = PeerConnection::new(peer_id, peer.asn, peer_addr.ip());
+        self.node.add_peer(peer_connection).await?;
+
+        // Create secure tunnel, the way the peer said it can be reached
+        let (tunnel_addr, transport) = encap::tunnel_endpoint(
+            peer_ip,
+            VX0_BGP_PORT,
+            self.node.config.security.ike.listen_port,
+            &bgp_session.peer_capabilities,
+            self.node.config.network.single_port.prefer_tcp,
+        );
+        let psk = self.node.tunnel_psk();
+        let tunnel_id = match self
             .node
-            .create_secure_tunnel(
-                uuid::Uuid::new_v4(), // Temporary peer ID
-                peer_addr,
-                &psk,
-            )
+            .create_secure_tunnel(peer_id, tunnel_addr, &psk)
+            .await
+        {
+            Ok(tunnel_id) => tunnel_id,
+            Err(e) => {
+                self.node.remove_peer(&peer_id).await?;
+                return Err(e);
+            }
+        };
+        self.node
+            .tunnel_manager
+            .set_transport(&tunnel_id, transport)
             .await?;
 
-        // Add as peer
-        let peer_connection =
-            PeerConnection::new(uuid::Uuid::new_v4(), peer.asn, peer.ip.parse().unwrap());
-
-        self.node.add_peer(peer_connection).await?;
-
         Ok(())
     }
 
     // Helper methods
 
-    async fn discover_used_asns(&self) -> Result<HashSet<u32>, NodeError> {
-        // This would query the network to find out which ASNs are already in use
-        // For now, return an empty set to allow any ASN assignment
-        Ok(HashSet::new())
-    }
-
-    async fn discover_local_peers(&self) -> Result<Vec<BootstrapNode>, NodeError> {
-        // Try multicast/broadcast discovery on local networks
-        // This would be useful for local mesh networks
-        Ok(Vec::new())
+    /// Probe candidates concurrently and order the ones that answered: our
+    /// preferred upstream tier first, then the quickest to answer
+    async fn rank_reachable(&self, candidates: &[BootstrapNode]) -> Vec<BootstrapNode> {
+        let mut reachable: Vec<(u8, Duration, BootstrapNode)> = stream::iter(candidates)
+            .map(|peer| async move { (peer, self.probe(peer).await) })
+            .buffer_unordered(PROBE_PARALLELISM)
+            .filter_map(|(peer, latency)| async move {
+                let rank = upstream_rank(&self.node.tier, &NodeTier::from_asn(peer.asn));
+                latency.map(|latency| (rank, latency, peer.clone()))
+            })
+            .collect()
+            .await;
+        reachable.sort_by_key(|(rank, latency, _)| (*rank, *latency));
+        reachable.into_iter().map(|(_, _, peer)| peer).collect()
     }
 
-    async fn dns_discovery(&self) -> Result<Vec<BootstrapNode>, NodeError> {
-        // Try to resolve known VX0 DNS names
-        // This allows the network to be discovered via DNS
-        Ok(Vec::new())
-    }
+    /// Time for a peer's BGP port to accept a connection, `None` if it did
+    /// not; a recent result is reused instead of probing again
+    async fn probe(&self, peer: &BootstrapNode) -> Option<Duration> {
+        let key = probe_key(peer);
+        if let Some((at, latency)) = key.and_then(|key| lock(&self.probes).get(&key).copied()) {
+            if self.clock.now_monotonic().saturating_duration_since(at) < PROBE_TTL {
+                return latency;
+            }
+        }
 
-    async fn test_connectivity(&self, peer: &BootstrapNode) -> bool {
-        let addr = format!("{}:{}", peer.ip, VX0_BGP_PORT);
-        if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
-            timeout(
-                Duration::from_secs(5),
-                tokio::net::TcpSocket::new_v4()
-                    .unwrap()
-                    .connect(socket_addr),
-            )
-            .await
-            .is_ok()
-        } else {
-            false
+        let limit = Duration::from_secs(self.node.config.joining.connect_timeout_secs);
+        let start = self.clock.now_monotonic();
+        let answered = self.transport.test_connectivity(peer, limit).await;
+        let now = self.clock.now_monotonic();
+        let latency = answered.then(|| now.saturating_duration_since(start));
+        if let Some(key) = key {
+            lock(&self.probes).insert(key, (now, latency));
+        }
+        match latency {
+            Some(latency) => {
+                self.node
+                    .bootstrap
+                    .record_success(peer, latency, self.clock.now_utc())
+            }
+            None => self
+                .node
+                .bootstrap
+                .record_failure(peer, self.clock.now_utc()),
         }
+        latency
     }
 
-    async fn request_join(
-        &self,
-        peer: &BootstrapNode,
-        request: &JoinRequest,
-    ) -> Result<JoinResponse, NodeError> {
-        // In a real implementation, this would send a join request to the peer
-        // For now, simulate acceptance for open network joining
-        Ok(JoinResponse {
-            accepted: true,
-            assigned_asn: Some(request.asn),
-            bootstrap_peers: vec![peer.clone()],
-            network_info: NetworkInfo {
-                total_nodes: 10,
-                backbone_nodes: 2,
-                regional_nodes: 3,
-                edge_nodes: 5,
-                network_version: "1.0.0".to_string(),
-                recommended_settings: RecommendedSettings {
-                    max_peers: self.node.tier.max_peers(),
-                    update_interval_secs: 60,
-                    discovery_interval_secs: 300,
-                    tunnel_rekey_interval_secs: 3600,
-                },
-            },
-            rejection_reason: None,
-        })
+    async fn discover_local_peers(&self) -> Result<Vec<BootstrapNode>, NodeError> {
+        // Try multicast/broadcast discovery on local networks
+        // This would be useful for local mesh networks
+        Ok(Vec::new())
     }
 
     async fn announce_to_network(&self) -> Result<(), NodeError> {
         tracing::info!("📢 Announcing presence to VX0 network");
 
         // Broadcast our presence to all connected peers
-        let peers = self.node.peers.read().await;
-        for (peer_id, _peer) in peers.iter() {
+        for peer_id in self.node.peer_handles().await.iter().map(|p| p.peer_id()) {
             let announcement = format!(
                 "Node {} (ASN {}) has joined the network",
                 self.node.hostname, self.node.asn
@@ -433,7 +1068,7 @@ impl NetworkJoiner {
 
             if let Err(e) = self
                 .node
-                .send_secure_data(peer_id, announcement.as_bytes())
+                .send_secure_data(&peer_id, announcement.as_bytes())
                 .await
             {
                 tracing::debug!("Failed to announce to peer {}: {}", peer_id, e);
@@ -442,25 +1077,283 @@ impl NetworkJoiner {
 
         Ok(())
     }
+}
+
+/// 0 for the tier a node should hang off first, 1 for any other it may
+/// peer with
+fn upstream_rank(ours: &NodeTier, theirs: &NodeTier) -> u8 {
+    match (ours, theirs) {
+        (NodeTier::Edge, NodeTier::Regional)
+        | (NodeTier::Regional, NodeTier::Backbone)
+        | (NodeTier::Backbone, NodeTier::Backbone) => 0,
+        _ => 1,
+    }
+}
+
+/// Split admitting answers into the largest group that agrees and the rest,
+/// as indices. Answers agree when they assign the same ASN, report the same
+/// network version, and their node counts differ by at most `tolerance_pct`.
+fn find_consensus(
+    answers: &[(BootstrapNode, JoinResponse)],
+    tolerance_pct: u32,
+) -> (Vec<usize>, Vec<usize>) {
+    let agrees = |a: &JoinResponse, b: &JoinResponse| {
+        let (a_info, b_info) = (&a.network_info, &b.network_info);
+        let close = |x: u32, y: u32| {
+            u64::from(x.abs_diff(y)) * 100 <= u64::from(x.max(y)) * u64::from(tolerance_pct)
+        };
+        a.assigned_asn == b.assigned_asn
+            && a_info.network_version == b_info.network_version
+            && close(a_info.total_nodes, b_info.total_nodes)
+            && close(a_info.backbone_nodes, b_info.backbone_nodes)
+            && close(a_info.regional_nodes, b_info.regional_nodes)
+            && close(a_info.edge_nodes, b_info.edge_nodes)
+    };
+
+    // The answer most others agree with anchors the group; the earliest
+    // wins a tie
+    let Some(anchor) = (0..answers.len()).max_by_key(|&i| {
+        let support = answers
+            .iter()
+            .filter(|(_, other)| agrees(&answers[i].1, other))
+            .count();
+        (support, std::cmp::Reverse(i))
+    }) else {
+        return (Vec::new(), Vec::new());
+    };
+    (0..answers.len()).partition(|&i| agrees(&answers[anchor].1, &answers[i].1))
+}
 
-    fn asn_to_tier(asn: u32) -> NodeTier {
-        match asn {
-            65000..=65099 => NodeTier::Backbone,
-            65100..=65999 => NodeTier::Regional,
-            66000..=69999 => NodeTier::Edge,
-            _ => NodeTier::Edge,
+/// Bootstrap peers from every agreeing answer, those vouched for by the
+/// most responders first
+fn merge_bootstrap_peers(answers: &[&(BootstrapNode, JoinResponse)]) -> Vec<BootstrapNode> {
+    let mut merged: Vec<(usize, BootstrapNode)> = Vec::new();
+    for (_, response) in answers {
+        // A responder listing a peer twice still vouches for it once
+        let mut listed: HashSet<(&str, u32)> = HashSet::new();
+        for peer in &response.bootstrap_peers {
+            if !listed.insert((peer.ip.as_str(), peer.asn)) {
+                continue;
+            }
+            match merged
+                .iter_mut()
+                .find(|(_, known)| known.ip == peer.ip && known.asn == peer.asn)
+            {
+                Some((count, _)) => *count += 1,
+                None => merged.push((1, peer.clone())),
+            }
         }
     }
+    // Stable, so equally vouched peers keep the order they were listed in
+    merged.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+    merged.into_iter().map(|(_, peer)| peer).collect()
+}
+
+/// Per-field median of the agreeing answers' network statistics
+fn median_network_info(answers: &[&(BootstrapNode, JoinResponse)]) -> NetworkInfo {
+    let median = |field: fn(&NetworkInfo) -> u32| {
+        let mut values: Vec<u32> = answers
+            .iter()
+            .map(|(_, response)| field(&response.network_info))
+            .collect();
+        values.sort_unstable();
+        values[values.len() / 2]
+    };
+    NetworkInfo {
+        total_nodes: median(|info| info.total_nodes),
+        backbone_nodes: median(|info| info.backbone_nodes),
+        regional_nodes: median(|info| info.regional_nodes),
+        edge_nodes: median(|info| info.edge_nodes),
+        ..answers[0].1.network_info.clone()
+    }
+}
+
+fn describe_answer(response: &JoinResponse) -> String {
+    let info = &response.network_info;
+    format!(
+        "assigned ASN {}, {} nodes ({} backbone, {} regional, {} edge), version {}",
+        response
+            .assigned_asn
+            .map_or("none".to_string(), |asn| asn.to_string()),
+        info.total_nodes,
+        info.backbone_nodes,
+        info.regional_nodes,
+        info.edge_nodes,
+        info.network_version
+    )
+}
 
-    fn get_default_psk(&self) -> Vec<u8> {
-        // In production, this should use proper key exchange
-        // For now, use a default PSK that all nodes know
-        b"vx0-network-default-psk-change-in-production".to_vec()
+fn network_info(tier: &NodeTier) -> NetworkInfo {
+    NetworkInfo {
+        total_nodes: 10,
+        backbone_nodes: 2,
+        regional_nodes: 3,
+        edge_nodes: 5,
+        network_version: "1.0.0".to_string(),
+        recommended_settings: RecommendedSettings {
+            max_peers: tier.max_peers(),
+            update_interval_secs: 60,
+            discovery_interval_secs: 300,
+            tunnel_rekey_interval_secs: 3600,
+        },
     }
 }
 
 /// Utilities for easy network joining
 impl Vx0Node {
+    /// Decide on a join request sent to this node from `from`, offering
+    /// ourselves as the newcomer's first peer
+    pub async fn answer_join(&self, request: &JoinRequest, from: IpAddr) -> JoinResponse {
+        if let Some(capture) = self.capture() {
+            capture.record(CapturedEvent::Join {
+                from,
+                request: request.clone(),
+            });
+        }
+        let mut response = match self.check_join(request).await {
+            Some(rejection) => JoinResponse::rejected(rejection, network_info(&self.tier)),
+            None => JoinResponse {
+                accepted: true,
+                assigned_asn: Some(request.asn),
+                bootstrap_peers: vec![BootstrapNode {
+                    hostname: self.hostname.clone(),
+                    ip: self.ipv4_addr.to_string(),
+                    asn: self.asn,
+                }],
+                network_info: network_info(&self.tier),
+                rejection_reason: None,
+                rejection: None,
+                observed_addr: None,
+            },
+        };
+        response.observed_addr = Some(from);
+        response
+    }
+
+    /// Why a join request must be turned down, if it must
+    async fn check_join(&self, request: &JoinRequest) -> Option<JoinRejection> {
+        use JoinRejectionCode::*;
+
+        let contact = Contact::node(request.node_id, request.asn, request.public_ip);
+        if !self.acl.check(&contact, "join request") {
+            return Some(JoinRejection::new(
+                PolicyDenied,
+                "Refused by operator policy",
+            ));
+        }
+        // Empty build info predates version reporting and speaks protocol 1
+        let ours = BuildInfo::current();
+        if request.build.protocol_max != 0 && !ours.is_compatible_with(&request.build) {
+            return Some(JoinRejection::new(
+                VersionIncompatible,
+                format!(
+                    "Joiner speaks protocol {}-{}, we speak {}-{}",
+                    request.build.protocol_min,
+                    request.build.protocol_max,
+                    ours.protocol_min,
+                    ours.protocol_max
+                ),
+            ));
+        }
+        if !self.tier.can_peer_with(&request.tier) {
+            return Some(JoinRejection::new(
+                PolicyDenied,
+                format!(
+                    "{:?} nodes cannot join through a {:?} node",
+                    request.tier, self.tier
+                ),
+            ));
+        }
+
+        // A trusted grant settles who holds an ASN, even against peers that
+        // picked it themselves; a restarted node keeps its ASN by presenting
+        // the grant its holder key got it anew, never by its hostname alone
+        if let Some(grant) = request
+            .grant
+            .as_ref()
+            .filter(|grant| grant.asn == request.asn && grant.node_id == request.node_id)
+        {
+            self.asn_registry.merge([grant.clone()]);
+        }
+        let granted_elsewhere = |asn: u32| {
+            self.asn_registry
+                .holder(asn)
+                .is_some_and(|grant| grant.node_id != request.node_id)
+        };
+        let granted = self
+            .asn_registry
+            .holder(request.asn)
+            .is_some_and(|grant| grant.node_id == request.node_id);
+
+        let peers = self.list_peers().await;
+        let (low, high) = request.tier.get_asn_range();
+        let claimed = |asn: u32| {
+            peers
+                .iter()
+                .any(|peer| peer.peer_asn == asn && peer.peer_id != request.node_id)
+        };
+        let taken = |asn: u32| asn == self.asn || granted_elsewhere(asn) || claimed(asn);
+        let conflict = if !(low..=high).contains(&request.asn) {
+            Some(format!(
+                "ASN {} is outside the {:?} range {}-{}",
+                request.asn, request.tier, low, high
+            ))
+        } else if request.asn == self.asn
+            || granted_elsewhere(request.asn)
+            || (!granted && claimed(request.asn))
+        {
+            Some(format!("ASN {} is already in use", request.asn))
+        } else {
+            None
+        };
+        if let Some(detail) = conflict {
+            return Some(
+                JoinRejection::new(AsnConflict, detail)
+                    .with_suggested_asn((low..=high).find(|asn| !taken(*asn))),
+            );
+        }
+        if granted && claimed(request.asn) {
+            tracing::warn!(
+                target: "audit",
+                "ASN {} was picked by a peer, but {} ({}) holds a registry grant for it",
+                request.asn,
+                request.hostname,
+                request.node_id
+            );
+        }
+
+        // Still proving ourselves stable; a joiner is better off elsewhere
+        if self.capabilities().newly_joined {
+            return Some(
+                JoinRejection::new(TemporarilyUnavailable, "Entry point is still joining")
+                    .with_retry_after(BUSY_RETRY_AFTER),
+            );
+        }
+        if peers.len() >= self.tier.max_peers() {
+            let alternates = peers
+                .iter()
+                .filter(|peer| {
+                    let tier = NodeTier::from_asn(peer.peer_asn);
+                    tier.can_peer_with(&request.tier)
+                        && (request.tier != NodeTier::Edge || peer.capabilities.accepts_new_edges)
+                })
+                .map(|peer| BootstrapNode {
+                    hostname: peer.peer_addr.to_string(),
+                    ip: peer.peer_addr.to_string(),
+                    asn: peer.peer_asn,
+                })
+                .collect();
+            return Some(
+                JoinRejection::new(
+                    TierCapacity,
+                    format!("All {} peer slots are taken", self.tier.max_peers()),
+                )
+                .with_alternate_peers(alternates),
+            );
+        }
+        None
+    }
+
     /// Simple one-command network joining
     pub async fn join_vx0_network(&self) -> Result<(), NodeError> {
         let joiner = NetworkJoiner::new(Arc::new(self.clone()));
@@ -470,12 +1363,814 @@ impl Vx0Node {
             tracing::info!("🎉 Successfully joined VX0 network!");
             Ok(())
         } else {
-            Err(NodeError::Network(format!(
-                "Failed to join network: {}",
-                response
+            Err(NodeError::JoinRejected {
+                reason: response
                     .rejection_reason
-                    .unwrap_or("Unknown reason".to_string())
-            )))
+                    .unwrap_or("Unknown reason".to_string()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_info::PROTOCOL_VERSION_MAX;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use crate::util::clock::{Clock, ManualClock};
+    use crate::Vx0Config;
+
+    /// A network where every answer is decided up front
+    #[derive(Default)]
+    struct ScriptedNetwork {
+        /// Addresses whose BGP port is open
+        reachable: HashSet<String>,
+        /// How long probing an address takes, reachable or not
+        latency: HashMap<String, Duration>,
+        /// Times those probes; tokio's timer when unset
+        clock: Option<Arc<ManualClock>>,
+        /// Addresses probed, in order
+        probed: Mutex<Vec<String>>,
+        /// Join decisions by address: `None` admits, `Some` rejects;
+        /// addresses missing here never answer
+        answers: HashMap<String, Option<JoinRejection>>,
+        /// ASNs in use, with the one suggested in their place
+        taken: HashMap<u32, u32>,
+        /// ASNs we asked to join with, in order
+        requested_asns: Mutex<Vec<u32>>,
+        used_asns: HashSet<u32>,
+        dns: HashMap<String, Vec<IpAddr>>,
+        /// Addresses asked to admit us, in order
+        asked: Mutex<Vec<String>>,
+        /// Peers every admitting answer lists after the responder itself
+        vouched: Vec<BootstrapNode>,
+        /// Answers sent verbatim instead of the scripted decision
+        forged: HashMap<String, JoinResponse>,
+        /// The address each admitting peer says our request came from
+        observed: HashMap<String, IpAddr>,
+    }
+
+    #[async_trait]
+    impl JoinTransport for ScriptedNetwork {
+        async fn test_connectivity(&self, peer: &BootstrapNode, _limit: Duration) -> bool {
+            self.probed.lock().unwrap().push(peer.ip.clone());
+            if let Some(&latency) = self.latency.get(&peer.ip) {
+                match &self.clock {
+                    Some(clock) => clock.sleep(latency).await,
+                    None => tokio::time::sleep(latency).await,
+                }
+            }
+            self.reachable.contains(&peer.ip)
+        }
+
+        async fn request_join(
+            &self,
+            peer: &BootstrapNode,
+            request: &JoinRequest,
+            _limit: Duration,
+        ) -> Result<JoinResponse, NodeError> {
+            self.asked.lock().unwrap().push(peer.ip.clone());
+            self.requested_asns.lock().unwrap().push(request.asn);
+            if let Some(forged) = self.forged.get(&peer.ip) {
+                return Ok(forged.clone());
+            }
+            let Some(answer) = self.answers.get(&peer.ip) else {
+                return Err(NodeError::Network(format!("{} timed out", peer.ip)));
+            };
+            if let Some(&suggested) = self.taken.get(&request.asn) {
+                let rejection = JoinRejection::new(
+                    JoinRejectionCode::AsnConflict,
+                    format!("ASN {} is already in use", request.asn),
+                )
+                .with_suggested_asn(Some(suggested));
+                return Ok(JoinResponse::rejected(
+                    rejection,
+                    network_info(&request.tier),
+                ));
+            }
+            if let Some(rejection) = answer {
+                return Ok(JoinResponse::rejected(
+                    rejection.clone(),
+                    network_info(&request.tier),
+                ));
+            }
+            let mut bootstrap_peers = vec![peer.clone()];
+            bootstrap_peers.extend(self.vouched.iter().cloned());
+            Ok(JoinResponse {
+                accepted: true,
+                assigned_asn: Some(request.asn),
+                bootstrap_peers,
+                network_info: network_info(&request.tier),
+                rejection_reason: None,
+                rejection: None,
+                observed_addr: self.observed.get(&peer.ip).copied(),
+            })
+        }
+
+        async fn fetch_used_asns(&self, _tier: &NodeTier) -> Result<HashSet<u32>, NodeError> {
+            Ok(self.used_asns.clone())
+        }
+
+        async fn resolve_bootstrap_dns(&self, hostname: &str) -> Result<Vec<IpAddr>, NodeError> {
+            Ok(self.dns.get(hostname).cloned().unwrap_or_default())
+        }
+    }
+
+    /// Returns from every sleep at once, remembering how long it was asked for
+    #[derive(Debug, Default)]
+    struct RecordingClock {
+        slept: Mutex<Vec<Duration>>,
+    }
+
+    #[async_trait]
+    impl Clock for RecordingClock {
+        fn now_utc(&self) -> chrono::DateTime<chrono::Utc> {
+            chrono::Utc::now()
+        }
+
+        fn now_monotonic(&self) -> Instant {
+            Instant::now()
+        }
+
+        async fn sleep_until(&self, deadline: Instant) {
+            self.sleep(deadline.saturating_duration_since(Instant::now()))
+                .await;
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+        }
+    }
+
+    fn edge_node(configure: impl FnOnce(&mut Vx0Config)) -> Vx0Node {
+        let mut config = testing::config(NodeTier::Edge);
+        configure(&mut config);
+        Vx0Node::new(config).unwrap()
+    }
+
+    fn seed(hostname: &str, ip: &str, asn: u32) -> BootstrapNode {
+        BootstrapNode {
+            hostname: hostname.to_string(),
+            ip: ip.to_string(),
+            asn,
+        }
+    }
+
+    fn joiner(
+        node: Vx0Node,
+        seeds: Vec<BootstrapNode>,
+        network: &Arc<ScriptedNetwork>,
+        clock: &Arc<RecordingClock>,
+    ) -> NetworkJoiner {
+        NetworkJoiner::new(Arc::new(node))
+            .with_seeds(seeds)
+            .with_transport(Arc::clone(network) as Arc<dyn JoinTransport>)
+            .with_clock(Arc::clone(clock) as SharedClock)
+    }
+
+    fn asked(network: &ScriptedNetwork) -> Vec<String> {
+        network.asked.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn test_all_bootstraps_down() {
+        let network = Arc::new(ScriptedNetwork::default());
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("regional1", "10.1.0.1", 65101),
+            seed("regional2", "10.1.0.2", 65102),
+        ];
+
+        let result = joiner(edge_node(|_| {}), seeds, &network, &clock)
+            .negotiate()
+            .await;
+        assert!(matches!(result, Err(NodeError::Network(_))));
+        assert!(asked(&network).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_only_wrong_tier_peers_reachable() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.0.0.1", "172.20.0.11"].map(String::from).into(),
+            answers: HashMap::from([("10.0.0.1".to_string(), None)]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("backbone1", "10.0.0.1", 65001),
+            seed("edge2", "172.20.0.11", 66002),
+            seed("regional1", "10.1.0.1", 65101),
+        ];
+
+        let result = joiner(edge_node(|_| {}), seeds, &network, &clock)
+            .negotiate()
+            .await;
+        assert!(matches!(result, Err(NodeError::Network(_))));
+        assert!(asked(&network).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_asn_exhaustion() {
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![seed("regional1", "10.1.0.1", 65101)];
+        let unassigned = || {
+            let mut node = edge_node(|_| {});
+            node.asn = 0;
+            node
+        };
+
+        let full = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1".to_string()].into(),
+            answers: HashMap::from([("10.1.0.1".to_string(), None)]),
+            used_asns: (66000..=69999).collect(),
+            ..ScriptedNetwork::default()
+        });
+        let result = joiner(unassigned(), seeds.clone(), &full, &clock)
+            .negotiate()
+            .await;
+        assert!(matches!(result, Err(NodeError::Config(_))));
+
+        // The one free ASN is the one we ask to join with
+        let one_left = Arc::new(ScriptedNetwork {
+            reachable: full.reachable.clone(),
+            answers: full.answers.clone(),
+            used_asns: (66000..=69999).filter(|asn| *asn != 68123).collect(),
+            ..ScriptedNetwork::default()
+        });
+        let response = joiner(unassigned(), seeds, &one_left, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+        assert!(response.accepted);
+        assert_eq!(response.assigned_asn, Some(68123));
+    }
+
+    #[tokio::test]
+    async fn test_first_peer_rejects_second_accepts() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1", "10.1.0.2"].map(String::from).into(),
+            latency: HashMap::from([
+                ("10.1.0.1".to_string(), Duration::from_millis(5)),
+                ("10.1.0.2".to_string(), Duration::from_millis(30)),
+            ]),
+            answers: HashMap::from([
+                (
+                    "10.1.0.1".to_string(),
+                    Some(JoinRejection::new(
+                        JoinRejectionCode::TierCapacity,
+                        "Edge slots full",
+                    )),
+                ),
+                ("10.1.0.2".to_string(), None),
+            ]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("regional1", "10.1.0.1", 65101),
+            seed("regional2", "10.1.0.2", 65102),
+        ];
+
+        let response = joiner(edge_node(|_| {}), seeds, &network, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+        // Below quorum, so the one peer that admitted us is trusted alone
+        assert!(response.accepted);
+        assert_eq!(response.bootstrap_peers[0].ip, "10.1.0.2");
+        let mut asked = asked(&network);
+        asked.sort();
+        assert_eq!(asked, vec!["10.1.0.1", "10.1.0.2"]);
+        assert!(clock.slept.lock().unwrap().is_empty());
+
+        // Unless the node insists on a quorum
+        let strict = edge_node(|config| config.joining.allow_unverified_join = false);
+        let seeds = vec![
+            seed("regional1", "10.1.0.1", 65101),
+            seed("regional2", "10.1.0.2", 65102),
+        ];
+        let response = joiner(strict, seeds, &network, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+        assert!(!response.accepted);
+        assert!(response
+            .rejection_reason
+            .unwrap()
+            .contains("only 1 of 2 entry points"));
+    }
+
+    #[tokio::test]
+    async fn test_join_answers_report_our_address() {
+        let behind_nat: IpAddr = "198.51.100.7".parse().unwrap();
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1", "10.1.0.2"].map(String::from).into(),
+            answers: HashMap::from([
+                ("10.1.0.1".to_string(), None),
+                ("10.1.0.2".to_string(), None),
+            ]),
+            observed: HashMap::from([
+                ("10.1.0.1".to_string(), behind_nat),
+                ("10.1.0.2".to_string(), behind_nat),
+            ]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("regional1", "10.1.0.1", 65101),
+            seed("regional2", "10.1.0.2", 65102),
+        ];
+
+        let joiner = joiner(
+            edge_node(|config| config.node.auto_detect_public_address = true),
+            seeds,
+            &network,
+            &clock,
+        );
+        assert!(joiner.negotiate().await.unwrap().accepted);
+        assert_eq!(IpAddr::V4(joiner.node.public_ipv4()), behind_nat);
+        assert_ne!(joiner.node.ipv4_addr, joiner.node.public_ipv4());
+    }
+
+    #[tokio::test]
+    async fn test_quorum_outvotes_lying_responder() {
+        let honest = [("10.1.0.1", 65101), ("10.1.0.2", 65102)];
+        let trusted = seed("regional9", "10.1.0.9", 65109);
+        let mut liar = JoinResponse {
+            accepted: true,
+            // Claims our ASN is taken and hands out another
+            assigned_asn: Some(66999),
+            bootstrap_peers: vec![seed("evil", "10.66.6.6", 65166)],
+            network_info: network_info(&NodeTier::Edge),
+            rejection_reason: None,
+            rejection: None,
+            observed_addr: None,
+        };
+        liar.network_info.total_nodes = 4000;
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1", "10.1.0.2", "10.1.0.3"]
+                .map(String::from)
+                .into(),
+            latency: HashMap::from([
+                // The liar answers first
+                ("10.1.0.3".to_string(), Duration::from_millis(1)),
+                ("10.1.0.1".to_string(), Duration::from_millis(20)),
+                ("10.1.0.2".to_string(), Duration::from_millis(30)),
+            ]),
+            answers: honest
+                .iter()
+                .map(|(ip, _)| (ip.to_string(), None))
+                .collect(),
+            vouched: vec![trusted.clone()],
+            forged: HashMap::from([("10.1.0.3".to_string(), liar)]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let mut seeds: Vec<BootstrapNode> = honest
+            .iter()
+            .map(|(ip, asn)| seed("regional", ip, *asn))
+            .collect();
+        seeds.push(seed("regional3", "10.1.0.3", 65103));
+
+        let joiner = joiner(edge_node(|_| {}), seeds, &network, &clock);
+        let response = joiner.negotiate().await.unwrap();
+        assert!(response.accepted);
+        assert_eq!(response.assigned_asn, Some(66001));
+        assert_eq!(response.network_info.total_nodes, 10);
+        // Vouched for by both honest responders, so tried first
+        assert_eq!(response.bootstrap_peers[0].ip, trusted.ip);
+        assert!(!response
+            .bootstrap_peers
+            .iter()
+            .any(|peer| peer.ip == "10.66.6.6"));
+
+        let dissenters = joiner.dissenters();
+        assert_eq!(dissenters.len(), 1);
+        assert_eq!(dissenters[0].responder.ip, "10.1.0.3");
+        assert!(dissenters[0].answer.contains("assigned ASN 66999"));
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_join_retries_then_follows_config() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1".to_string()].into(),
+            ..ScriptedNetwork::default()
+        });
+        let seeds = vec![seed("regional1", "10.1.0.1", 65101)];
+
+        let clock = Arc::new(RecordingClock::default());
+        let strict = edge_node(|config| config.joining.allow_unverified_join = false);
+        let response = joiner(strict, seeds.clone(), &network, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+        assert!(!response.accepted);
+        assert_eq!(asked(&network).len(), 3);
+        assert_eq!(
+            *clock.slept.lock().unwrap(),
+            vec![Duration::from_secs(2), Duration::from_secs(4)]
+        );
+
+        // The compatible default still admits us when nobody answers
+        let response = joiner(edge_node(|_| {}), seeds, &network, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+        assert!(response.accepted);
+    }
+
+    fn rejection(code: JoinRejectionCode, detail: &str) -> Option<JoinRejection> {
+        Some(JoinRejection::new(code, detail))
+    }
+
+    #[tokio::test]
+    async fn test_busy_entry_point_is_asked_again_when_it_says() {
+        let busy = JoinRejection::new(JoinRejectionCode::TemporarilyUnavailable, "Busy")
+            .with_retry_after(Duration::from_secs(30));
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1".to_string()].into(),
+            answers: HashMap::from([("10.1.0.1".to_string(), Some(busy))]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let strict = edge_node(|config| config.joining.allow_unverified_join = false);
+        let response = joiner(
+            strict,
+            vec![seed("regional1", "10.1.0.1", 65101)],
+            &network,
+            &clock,
+        )
+        .negotiate()
+        .await
+        .unwrap();
+
+        assert!(!response.accepted);
+        assert_eq!(asked(&network).len(), 3);
+        // Longer than our own schedule, so the peer's wait wins
+        assert_eq!(
+            *clock.slept.lock().unwrap(),
+            vec![Duration::from_secs(30), Duration::from_secs(30)]
+        );
+        assert_eq!(
+            response.rejection.unwrap().code,
+            JoinRejectionCode::TemporarilyUnavailable
+        );
+    }
+
+    #[tokio::test]
+    async fn test_asn_conflict_adopts_suggested_asn() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1".to_string()].into(),
+            answers: HashMap::from([("10.1.0.1".to_string(), None)]),
+            taken: HashMap::from([(66001, 66007)]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let response = joiner(
+            edge_node(|_| {}),
+            vec![seed("regional1", "10.1.0.1", 65101)],
+            &network,
+            &clock,
+        )
+        .negotiate()
+        .await
+        .unwrap();
+
+        assert!(response.accepted);
+        assert_eq!(response.assigned_asn, Some(66007));
+        assert_eq!(*network.requested_asns.lock().unwrap(), vec![66001, 66007]);
+        assert!(clock.slept.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_full_entry_point_hands_over_to_alternates() {
+        let full = JoinRejection::new(JoinRejectionCode::TierCapacity, "Edge slots full")
+            .with_alternate_peers(vec![
+                seed("backbone9", "10.0.0.9", 65009),
+                seed("regional5", "10.1.0.5", 65105),
+            ]);
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1".to_string()].into(),
+            answers: HashMap::from([
+                ("10.1.0.1".to_string(), Some(full)),
+                ("10.1.0.5".to_string(), None),
+            ]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let response = joiner(
+            edge_node(|_| {}),
+            vec![seed("regional1", "10.1.0.1", 65101)],
+            &network,
+            &clock,
+        )
+        .negotiate()
+        .await
+        .unwrap();
+
+        assert!(response.accepted);
+        assert_eq!(response.bootstrap_peers[0].ip, "10.1.0.5");
+        // Edges can't join through a backbone, so it is never asked
+        assert_eq!(asked(&network), vec!["10.1.0.1", "10.1.0.5"]);
+        assert!(clock.slept.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refusals_are_not_retried_and_are_explained() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1", "10.1.0.2"].map(String::from).into(),
+            answers: HashMap::from([
+                (
+                    "10.1.0.1".to_string(),
+                    rejection(
+                        JoinRejectionCode::PolicyDenied,
+                        "Refused by operator policy",
+                    ),
+                ),
+                (
+                    "10.1.0.2".to_string(),
+                    rejection(
+                        JoinRejectionCode::VersionIncompatible,
+                        "Joiner speaks protocol 1-1, we speak 2-3",
+                    ),
+                ),
+            ]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("regional1", "10.1.0.1", 65101),
+            seed("regional2", "10.1.0.2", 65102),
+        ];
+        let response = joiner(edge_node(|_| {}), seeds, &network, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+
+        assert!(!response.accepted);
+        assert_eq!(asked(&network).len(), 2);
+        assert!(clock.slept.lock().unwrap().is_empty());
+        let reason = response.rejection_reason.unwrap();
+        assert!(reason.contains("regional1: Refused by operator policy"));
+        assert!(reason.contains("asking again won't help"));
+        assert!(reason.contains("upgrade vx0net"));
+    }
+
+    #[tokio::test]
+    async fn test_answer_join_reports_why() {
+        let regional = edge_node(|config| {
+            config.node.tier = "Regional".to_string();
+            config.node.asn = 65150;
+        });
+        let request = |asn: u32, tier: NodeTier| JoinRequest {
+            node_id: uuid::Uuid::new_v4(),
+            hostname: "joiner".to_string(),
+            asn,
+            tier,
+            public_ip: "10.9.0.1".parse().unwrap(),
+            requested_services: vec![],
+            contact_info: None,
+            build: BuildInfo::current(),
+            timestamp: chrono::Utc::now(),
+            grant: None,
+        };
+        let code = |response: &JoinResponse| response.rejection.as_ref().map(|r| r.code);
+        let joiner_ip: IpAddr = "10.9.0.1".parse().unwrap();
+
+        let accepted = regional
+            .answer_join(&request(66001, NodeTier::Edge), joiner_ip)
+            .await;
+        assert!(accepted.accepted);
+        assert!(accepted.rejection.is_none() && accepted.rejection_reason.is_none());
+        assert_eq!(accepted.observed_addr, Some(joiner_ip));
+
+        // Our own ASN is taken; the old field carries the detail
+        let taken = regional
+            .answer_join(&request(65150, NodeTier::Regional), joiner_ip)
+            .await;
+        assert_eq!(code(&taken), Some(JoinRejectionCode::AsnConflict));
+        assert_eq!(taken.rejection.as_ref().unwrap().suggested_asn, Some(65100));
+        assert_eq!(
+            taken.rejection_reason.as_deref(),
+            Some("ASN 65150 is already in use")
+        );
+        let outside = regional
+            .answer_join(&request(65001, NodeTier::Edge), joiner_ip)
+            .await;
+        assert_eq!(code(&outside), Some(JoinRejectionCode::AsnConflict));
+        assert_eq!(outside.rejection.unwrap().suggested_asn, Some(66000));
+
+        let mut newer = request(66001, NodeTier::Edge);
+        newer.build.protocol_min = PROTOCOL_VERSION_MAX + 1;
+        newer.build.protocol_max = PROTOCOL_VERSION_MAX + 1;
+        let newer = regional.answer_join(&newer, joiner_ip).await;
+        assert_eq!(code(&newer), Some(JoinRejectionCode::VersionIncompatible));
+
+        let edge = edge_node(|_| {});
+        let edge_to_edge = edge
+            .answer_join(&request(66002, NodeTier::Edge), joiner_ip)
+            .await;
+        assert_eq!(code(&edge_to_edge), Some(JoinRejectionCode::PolicyDenied));
+
+        regional.update_capabilities(|capabilities| capabilities.newly_joined = true);
+        let busy = regional
+            .answer_join(&request(66001, NodeTier::Edge), joiner_ip)
+            .await;
+        assert_eq!(code(&busy), Some(JoinRejectionCode::TemporarilyUnavailable));
+        assert_eq!(busy.rejection.unwrap().retry_after, Some(BUSY_RETRY_AFTER));
+        regional.update_capabilities(|capabilities| capabilities.newly_joined = false);
+
+        // Full, pointing at peers that still take edges
+        for i in 0..NodeTier::Regional.max_peers() {
+            let mut peer = PeerConnection::new(
+                uuid::Uuid::new_v4(),
+                65200 + i as u32,
+                format!("10.2.0.{}", i + 1).parse().unwrap(),
+            );
+            peer.capabilities.accepts_new_edges = i == 0;
+            regional.add_peer(peer).await.unwrap();
+        }
+        let full = regional
+            .answer_join(&request(66001, NodeTier::Edge), joiner_ip)
+            .await;
+        let rejection = full.rejection.unwrap();
+        assert_eq!(rejection.code, JoinRejectionCode::TierCapacity);
+        let alternates: Vec<&str> = rejection
+            .alternate_peers
+            .iter()
+            .map(|peer| peer.ip.as_str())
+            .collect();
+        assert_eq!(alternates, vec!["10.2.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_quarantined_seed_is_skipped_and_vouched_peers_are_scored() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.1", "10.1.0.2"].map(String::from).into(),
+            answers: HashMap::from([
+                ("10.1.0.1".to_string(), None),
+                ("10.1.0.2".to_string(), None),
+            ]),
+            vouched: vec![seed("regional7", "10.1.0.7", 65107)],
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let node = edge_node(|config| config.joining.bootstrap_health.state_file = None);
+        let dead = seed("regional1", "10.1.0.1", 65101);
+        node.bootstrap
+            .add(dead.clone(), BootstrapSource::Configured);
+        let now = chrono::Utc::now();
+        for hours in [30, 25, 1] {
+            node.bootstrap
+                .record_failure(&dead, now - chrono::Duration::hours(hours));
         }
+        let node = Arc::new(node);
+
+        let response = NetworkJoiner::new(Arc::clone(&node))
+            .with_seeds(vec![dead, seed("regional2", "10.1.0.2", 65102)])
+            .with_transport(Arc::clone(&network) as Arc<dyn JoinTransport>)
+            .with_clock(Arc::clone(&clock) as SharedClock)
+            .negotiate()
+            .await
+            .unwrap();
+        assert!(response.accepted);
+        assert_eq!(*network.probed.lock().unwrap(), vec!["10.1.0.2"]);
+
+        let status = node.bootstrap.status(now);
+        let scored = |ip: &str| status.iter().find(|node| node.ip == ip).cloned();
+        assert!(scored("10.1.0.1").unwrap().quarantined);
+        assert_eq!(scored("10.1.0.7").unwrap().source, BootstrapSource::Learned);
+    }
+
+    #[tokio::test]
+    async fn test_dns_supplies_the_only_entry_point() {
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.7".to_string()].into(),
+            answers: HashMap::from([("10.1.0.7".to_string(), None)]),
+            dns: HashMap::from([(
+                "regional1.vx0.network".to_string(),
+                vec!["10.1.0.7".parse().unwrap()],
+            )]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("regional1.vx0.network", "YOUR_REGIONAL_IP", 65101),
+            seed("regional2.vx0.network", "YOUR_REGIONAL2_IP", 65102),
+        ];
+
+        let response = joiner(edge_node(|_| {}), seeds, &network, &clock)
+            .negotiate()
+            .await
+            .unwrap();
+        assert!(response.accepted);
+        assert_eq!(asked(&network), vec!["10.1.0.7"]);
+    }
+
+    #[tokio::test]
+    async fn test_probes_overlap_and_fastest_answer_leads() {
+        let ms = Duration::from_millis;
+        let clock = Arc::new(ManualClock::new());
+        let network = Arc::new(ScriptedNetwork {
+            clock: Some(Arc::clone(&clock)),
+            reachable: ["10.1.0.1", "10.1.0.2"].map(String::from).into(),
+            latency: HashMap::from([
+                ("10.1.0.1".to_string(), ms(150)),
+                ("10.1.0.2".to_string(), ms(20)),
+                ("10.1.0.3".to_string(), ms(250)),
+                ("10.1.0.4".to_string(), ms(250)),
+                ("10.1.0.5".to_string(), ms(250)),
+            ]),
+            ..ScriptedNetwork::default()
+        });
+        let seeds = vec![
+            seed("slow", "10.1.0.1", 65101),
+            seed("fast", "10.1.0.2", 65102),
+            seed("dead1", "10.1.0.3", 65103),
+            seed("dead2", "10.1.0.4", 65104),
+            seed("dead3", "10.1.0.5", 65105),
+        ];
+        let joiner = joiner(edge_node(|_| {}), seeds.clone(), &network, &Arc::default())
+            .with_clock(Arc::clone(&clock) as SharedClock);
+
+        // Time moves on only while the search waits for it
+        let start = clock.now_monotonic();
+        let advance = async {
+            loop {
+                for _ in 0..10 {
+                    tokio::task::yield_now().await;
+                }
+                clock.advance(ms(10));
+            }
+        };
+        let peers = tokio::select! {
+            biased;
+            peers = joiner.find_suitable_peers(&seeds) => peers.unwrap(),
+            _ = advance => unreachable!(),
+        };
+
+        // Dead entries cost one timeout between them, not one each
+        assert_eq!(clock.now_monotonic() - start, ms(250));
+        let order: Vec<&str> = peers.iter().map(|peer| peer.ip.as_str()).collect();
+        assert_eq!(order, vec!["10.1.0.2", "10.1.0.1"]);
+    }
+
+    #[tokio::test]
+    async fn test_preferred_tier_outranks_latency_and_probes_are_reused() {
+        let ms = Duration::from_millis;
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.0.0.1", "10.1.0.2"].map(String::from).into(),
+            latency: HashMap::from([
+                ("10.0.0.1".to_string(), ms(60)),
+                ("10.1.0.2".to_string(), ms(5)),
+            ]),
+            ..ScriptedNetwork::default()
+        });
+        let clock = Arc::new(RecordingClock::default());
+        let seeds = vec![
+            seed("regional2", "10.1.0.2", 65102),
+            seed("backbone1", "10.0.0.1", 65001),
+        ];
+        let regional = edge_node(|config| {
+            config.node.tier = "Regional".to_string();
+            config.node.asn = 65150;
+        });
+        let joiner = joiner(regional, seeds.clone(), &network, &clock);
+
+        let peers = joiner.find_suitable_peers(&seeds).await.unwrap();
+        assert_eq!(peers[0].hostname, "backbone1");
+        assert_eq!(peers[1].hostname, "regional2");
+
+        // Connecting afterwards ranks the same peers from the cache
+        assert_eq!(joiner.rank_reachable(&seeds).await.len(), 2);
+        assert_eq!(network.probed.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_probe_results_are_kept_per_endpoint_until_they_expire() {
+        let clock = Arc::new(ManualClock::new());
+        let network = Arc::new(ScriptedNetwork {
+            reachable: ["10.1.0.2".to_string()].into(),
+            ..ScriptedNetwork::default()
+        });
+        // Two entry points behind one address
+        let seeds = vec![
+            seed("regional2", "10.1.0.2", 65102),
+            seed("regional3", "10.1.0.2", 65103),
+        ];
+        let joiner = joiner(edge_node(|_| {}), seeds.clone(), &network, &Arc::default())
+            .with_clock(Arc::clone(&clock) as SharedClock);
+
+        assert_eq!(joiner.rank_reachable(&seeds).await.len(), 2);
+        assert_eq!(network.probed.lock().unwrap().len(), 2);
+
+        clock.advance(PROBE_TTL - Duration::from_secs(1));
+        joiner.rank_reachable(&seeds).await;
+        assert_eq!(network.probed.lock().unwrap().len(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        joiner.rank_reachable(&seeds).await;
+        assert_eq!(network.probed.lock().unwrap().len(), 4);
     }
 }
@@ -1,6 +1,8 @@
+use crate::monitoring::{crash, Subsystem};
 use crate::node::{ConnectionStatus, NodeError, Vx0Node};
+use crate::util::clock;
 use std::sync::Arc;
-use tokio::time::{interval, Duration};
+use std::time::Duration;
 
 pub struct NodeManager {
     node: Arc<Vx0Node>,
@@ -16,23 +18,29 @@ impl NodeManager {
 
         // Start peer management task
         let peer_manager = Arc::clone(&node);
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(30));
-            loop {
-                interval.tick().await;
-                if let Err(e) = peer_manager.manage_peers().await {
-                    tracing::error!("Peer management error: {}", e);
+        crash::spawn_restartable(Subsystem::Node, "peer-manager", move || {
+            let peer_manager = Arc::clone(&peer_manager);
+            async move {
+                let mut interval = clock::interval(&peer_manager.clock, Duration::from_secs(30));
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = peer_manager.manage_peers().await {
+                        tracing::error!("Peer management error: {}", e);
+                    }
                 }
             }
         });
 
         // Start health monitoring task
         let health_monitor = Arc::clone(&node);
-        tokio::spawn(async move {
-            let mut interval = interval(Duration::from_secs(10));
-            loop {
-                interval.tick().await;
-                health_monitor.check_health().await;
+        crash::spawn_restartable(Subsystem::Node, "health-monitor", move || {
+            let health_monitor = Arc::clone(&health_monitor);
+            async move {
+                let mut interval = clock::interval(&health_monitor.clock, Duration::from_secs(10));
+                loop {
+                    interval.tick().await;
+                    health_monitor.check_health().await;
+                }
             }
         });
 
@@ -42,18 +50,45 @@ impl NodeManager {
 
 impl Vx0Node {
     async fn manage_peers(&self) -> Result<(), NodeError> {
-        let peers = self.peers.read().await;
+        let peers = self.list_peers().await;
         let peer_count = peers.len();
 
-        tracing::debug!("Managing {} peer connections", peer_count);
+        crate::sampled!(tracing::debug!("Managing {} peer connections", peer_count));
 
-        for (peer_id, peer) in peers.iter() {
+        for peer in &peers {
             match peer.status {
                 ConnectionStatus::Failed => {
-                    tracing::warn!("Peer {} connection failed, attempting reconnect", peer_id);
+                    tracing::warn!(
+                        "Peer {} connection failed, attempting reconnect",
+                        peer.peer_id
+                    );
                 }
                 ConnectionStatus::Disconnected => {
-                    tracing::debug!("Peer {} is disconnected", peer_id);
+                    crate::sampled!(tracing::debug!("Peer {} is disconnected", peer.peer_id));
+                }
+                // Left on purpose; wait for it to announce itself unless it
+                // said when to look again
+                ConnectionStatus::Departed => {
+                    let probe_due = peer
+                        .departure
+                        .as_ref()
+                        .is_some_and(|departure| departure.probe_due(self.clock.now_utc()));
+                    if probe_due {
+                        tracing::info!("Probing departed peer {}", peer.peer_id);
+                        self.welcome_back(peer.peer_id).await?;
+                    }
+                }
+                // Left alone until the operator enables it
+                ConnectionStatus::AdminDown => {}
+                // Let back in once the cooldown is over
+                ConnectionStatus::Quarantined
+                    if !self
+                        .quarantine
+                        .is_quarantined(peer.peer_asn, self.clock.now_utc()) =>
+                {
+                    if let Some(handle) = self.get_peer(&peer.peer_id).await {
+                        handle.set_quarantined(false).await?;
+                    }
                 }
                 _ => {}
             }
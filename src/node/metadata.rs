@@ -0,0 +1,441 @@
+//! Typed view of a hosted service's metadata.
+//!
+//! On the wire and in stored services metadata stays a flat string map, so
+//! older nodes keep reading it. Known keys are parsed into `ServiceMetadata`:
+//! strictly when a service is registered, so a typo is an error rather than a
+//! silently ignored setting, and leniently when a map is loaded, dropping
+//! malformed values with a warning. Unknown keys are kept as they are.
+//!
+//! Registration also bounds the map to the limits in `wire_limits`: values
+//! are cut short and keys beyond the count limit dropped, known keys kept
+//! first, each with a warning.
+
+use crate::wire_limits::{truncate_utf8, MAX_METADATA_KEYS, MAX_METADATA_VALUE_BYTES};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DESCRIPTION: &str = "description";
+pub const HEALTH_CHECK: &str = "health_check";
+pub const HEALTH_PATH: &str = "health_path";
+pub const HEALTH_INTERVAL: &str = "health_interval";
+pub const TAGS: &str = "tags";
+pub const OWNER_CONTACT: &str = "owner_contact";
+pub const VISIBILITY: &str = "visibility";
+
+const KNOWN_KEYS: [&str; 7] = [
+    DESCRIPTION,
+    HEALTH_CHECK,
+    HEALTH_PATH,
+    HEALTH_INTERVAL,
+    TAGS,
+    OWNER_CONTACT,
+    VISIBILITY,
+];
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum MetadataError {
+    #[error("Invalid service metadata {key}={value:?}: {reason}")]
+    Malformed {
+        key: &'static str,
+        value: String,
+        reason: &'static str,
+    },
+    #[error("Service metadata {key} requires {HEALTH_CHECK}")]
+    WithoutHealthCheck { key: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    /// The port accepts a connection
+    Tcp,
+    /// A GET of the path answers with a 2xx or 3xx status
+    Http,
+}
+
+impl FromStr for HealthCheckKind {
+    type Err = MetadataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(HealthCheckKind::Tcp),
+            "http" => Ok(HealthCheckKind::Http),
+            _ => Err(MetadataError::Malformed {
+                key: HEALTH_CHECK,
+                value: s.to_string(),
+                reason: "expected tcp or http",
+            }),
+        }
+    }
+}
+
+impl fmt::Display for HealthCheckKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HealthCheckKind::Tcp => "tcp",
+            HealthCheckKind::Http => "http",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheckSpec {
+    pub kind: HealthCheckKind,
+    /// Requested by HTTP checks; "/" when not given
+    pub path: Option<String>,
+    /// Least time between checks; every lifetime pass when not given
+    pub interval: Option<Duration>,
+}
+
+impl HealthCheckSpec {
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/")
+    }
+}
+
+impl fmt::Display for HealthCheckSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if self.kind == HealthCheckKind::Http {
+            write!(f, " {}", self.path())?;
+        }
+        if let Some(interval) = self.interval {
+            write!(f, " every {}s", interval.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Visibility {
+    /// Listed in node announcements
+    #[default]
+    Public,
+    /// Resolvable, but left out of node announcements and service searches
+    Unlisted,
+}
+
+impl FromStr for Visibility {
+    type Err = MetadataError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            _ => Err(MetadataError::Malformed {
+                key: VISIBILITY,
+                value: s.to_string(),
+                reason: "expected public or unlisted",
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "HashMap<String, String>", into = "HashMap<String, String>")]
+pub struct ServiceMetadata {
+    pub description: Option<String>,
+    pub health_check: Option<HealthCheckSpec>,
+    pub tags: Vec<String>,
+    pub owner_contact: Option<String>,
+    pub visibility: Visibility,
+    /// Keys this version does not know, carried through unchanged
+    pub extra: BTreeMap<String, String>,
+}
+
+impl ServiceMetadata {
+    /// Parse a map given at registration, rejecting malformed known keys
+    pub fn parse(map: &HashMap<String, String>) -> Result<Self, MetadataError> {
+        let mut errors = Vec::new();
+        let metadata = Self::read(&bounded(map), &mut errors);
+        match errors.into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(metadata),
+        }
+    }
+
+    /// Fill what the map holds, collecting what could not be parsed
+    fn read(map: &HashMap<String, String>, errors: &mut Vec<MetadataError>) -> Self {
+        let mut metadata = ServiceMetadata::default();
+        let mut kind = None;
+        for (key, value) in map {
+            match key.as_str() {
+                DESCRIPTION => metadata.description = Some(value.clone()),
+                OWNER_CONTACT => metadata.owner_contact = Some(value.clone()),
+                TAGS => {
+                    metadata.tags = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                }
+                VISIBILITY => match value.parse() {
+                    Ok(visibility) => metadata.visibility = visibility,
+                    Err(e) => errors.push(e),
+                },
+                HEALTH_CHECK => match value.parse() {
+                    Ok(parsed) => kind = Some(parsed),
+                    Err(e) => errors.push(e),
+                },
+                HEALTH_PATH | HEALTH_INTERVAL => {}
+                _ => {
+                    tracing::warn!("Unknown service metadata key {:?}", key);
+                    metadata.extra.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        let path = map.get(HEALTH_PATH).and_then(|path| {
+            if !path.starts_with('/') {
+                errors.push(MetadataError::Malformed {
+                    key: HEALTH_PATH,
+                    value: path.clone(),
+                    reason: "expected an absolute path",
+                });
+                return None;
+            }
+            Some(path.clone())
+        });
+        let interval = map
+            .get(HEALTH_INTERVAL)
+            .and_then(|secs| match secs.parse() {
+                Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+                _ => {
+                    errors.push(MetadataError::Malformed {
+                        key: HEALTH_INTERVAL,
+                        value: secs.clone(),
+                        reason: "expected a positive number of seconds",
+                    });
+                    None
+                }
+            });
+
+        match kind {
+            Some(kind) => {
+                if path.is_some() && kind != HealthCheckKind::Http {
+                    errors.push(MetadataError::Malformed {
+                        key: HEALTH_PATH,
+                        value: path.clone().unwrap_or_default(),
+                        reason: "only HTTP checks request a path",
+                    });
+                }
+                metadata.health_check = Some(HealthCheckSpec {
+                    kind,
+                    path: path.filter(|_| kind == HealthCheckKind::Http),
+                    interval,
+                });
+            }
+            None => {
+                for key in [HEALTH_PATH, HEALTH_INTERVAL] {
+                    if map.contains_key(key) && !map.contains_key(HEALTH_CHECK) {
+                        errors.push(MetadataError::WithoutHealthCheck { key });
+                    }
+                }
+            }
+        }
+        metadata
+    }
+
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map: HashMap<String, String> = self.extra.clone().into_iter().collect();
+        if let Some(description) = &self.description {
+            map.insert(DESCRIPTION.to_string(), description.clone());
+        }
+        if let Some(spec) = &self.health_check {
+            map.insert(HEALTH_CHECK.to_string(), spec.kind.to_string());
+            if let Some(path) = &spec.path {
+                map.insert(HEALTH_PATH.to_string(), path.clone());
+            }
+            if let Some(interval) = spec.interval {
+                map.insert(HEALTH_INTERVAL.to_string(), interval.as_secs().to_string());
+            }
+        }
+        if !self.tags.is_empty() {
+            map.insert(TAGS.to_string(), self.tags.join(","));
+        }
+        if let Some(owner) = &self.owner_contact {
+            map.insert(OWNER_CONTACT.to_string(), owner.clone());
+        }
+        if self.visibility != Visibility::Public {
+            map.insert(VISIBILITY.to_string(), self.visibility.to_string());
+        }
+        map
+    }
+}
+
+/// `map` within the metadata limits: known keys first and the rest by name
+/// up to `MAX_METADATA_KEYS`, each value cut to `MAX_METADATA_VALUE_BYTES`
+fn bounded(map: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort_by_key(|key| (!KNOWN_KEYS.contains(&key.as_str()), *key));
+    if keys.len() > MAX_METADATA_KEYS {
+        tracing::warn!(
+            "Dropping {} service metadata keys beyond the first {}: {:?}",
+            keys.len() - MAX_METADATA_KEYS,
+            MAX_METADATA_KEYS,
+            &keys[MAX_METADATA_KEYS..]
+        );
+        keys.truncate(MAX_METADATA_KEYS);
+    }
+    keys.into_iter()
+        .map(|key| {
+            let value = &map[key];
+            let kept = truncate_utf8(value, MAX_METADATA_VALUE_BYTES);
+            if kept.len() < value.len() {
+                tracing::warn!(
+                    "Cutting service metadata {:?} from {} to {} bytes",
+                    key,
+                    value.len(),
+                    kept.len()
+                );
+            }
+            (key.clone(), kept.to_string())
+        })
+        .collect()
+}
+
+/// Loading keeps whatever parses and warns about the rest
+impl From<HashMap<String, String>> for ServiceMetadata {
+    fn from(map: HashMap<String, String>) -> Self {
+        let mut errors = Vec::new();
+        let metadata = Self::read(&map, &mut errors);
+        for error in errors {
+            tracing::warn!("Dropping service metadata: {}", error);
+        }
+        metadata
+    }
+}
+
+impl From<ServiceMetadata> for HashMap<String, String> {
+    fn from(metadata: ServiceMetadata) -> Self {
+        metadata.to_map()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_through_the_wire_map() {
+        let raw = map(&[
+            (DESCRIPTION, "Community wiki"),
+            (HEALTH_CHECK, "http"),
+            (HEALTH_PATH, "/healthz"),
+            (HEALTH_INTERVAL, "30"),
+            (TAGS, "wiki, docs"),
+            (OWNER_CONTACT, "ops@wiki.vx0"),
+            (VISIBILITY, "unlisted"),
+            ("x-mirror", "wiki2.vx0"),
+        ]);
+        let metadata = ServiceMetadata::parse(&raw).unwrap();
+        assert_eq!(
+            metadata.health_check,
+            Some(HealthCheckSpec {
+                kind: HealthCheckKind::Http,
+                path: Some("/healthz".to_string()),
+                interval: Some(Duration::from_secs(30)),
+            })
+        );
+        assert_eq!(metadata.tags, vec!["wiki", "docs"]);
+        assert_eq!(metadata.visibility, Visibility::Unlisted);
+        assert_eq!(metadata.extra["x-mirror"], "wiki2.vx0");
+
+        // Serialized as the same flat map older nodes expect
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(json["tags"], "wiki,docs");
+        let back: ServiceMetadata = serde_json::from_value(json).unwrap();
+        assert_eq!(back, metadata);
+        assert_eq!(
+            serde_json::to_value(ServiceMetadata::default()).unwrap(),
+            serde_json::json!({})
+        );
+    }
+
+    #[test]
+    fn test_malformed_keys_rejected_at_registration_dropped_on_load() {
+        for (raw, expected) in [
+            (
+                map(&[(HEALTH_CHECK, "htp")]),
+                MetadataError::Malformed {
+                    key: HEALTH_CHECK,
+                    value: "htp".to_string(),
+                    reason: "expected tcp or http",
+                },
+            ),
+            (
+                map(&[(HEALTH_PATH, "/healthz")]),
+                MetadataError::WithoutHealthCheck { key: HEALTH_PATH },
+            ),
+            (
+                map(&[(HEALTH_CHECK, "tcp"), (HEALTH_INTERVAL, "often")]),
+                MetadataError::Malformed {
+                    key: HEALTH_INTERVAL,
+                    value: "often".to_string(),
+                    reason: "expected a positive number of seconds",
+                },
+            ),
+            (
+                map(&[(VISIBILITY, "secret")]),
+                MetadataError::Malformed {
+                    key: VISIBILITY,
+                    value: "secret".to_string(),
+                    reason: "expected public or unlisted",
+                },
+            ),
+        ] {
+            assert_eq!(ServiceMetadata::parse(&raw), Err(expected));
+        }
+
+        // A stored service keeps what still parses
+        let loaded = ServiceMetadata::from(map(&[
+            (DESCRIPTION, "Community wiki"),
+            (HEALTH_CHECK, "http"),
+            (HEALTH_PATH, "healthz"),
+        ]));
+        assert_eq!(loaded.description.as_deref(), Some("Community wiki"));
+        assert_eq!(loaded.health_check.unwrap().path(), "/");
+    }
+
+    #[test]
+    fn test_oversized_metadata_bounded_at_registration() {
+        let description = "w".repeat(MAX_METADATA_VALUE_BYTES + 100);
+        let mut raw = map(&[
+            (DESCRIPTION, description.as_str()),
+            (VISIBILITY, "unlisted"),
+        ]);
+        for index in 0..MAX_METADATA_KEYS + 10 {
+            raw.insert(format!("x-key-{:03}", index), "value".to_string());
+        }
+
+        let metadata = ServiceMetadata::parse(&raw).unwrap();
+        assert_eq!(
+            metadata.description.as_ref().map(String::len),
+            Some(MAX_METADATA_VALUE_BYTES)
+        );
+        // Known keys survive; the extras sorting last are dropped
+        assert_eq!(metadata.visibility, Visibility::Unlisted);
+        assert_eq!(metadata.to_map().len(), MAX_METADATA_KEYS);
+        assert!(metadata.extra.contains_key("x-key-000"));
+        assert!(!metadata.extra.contains_key("x-key-041"));
+    }
+}
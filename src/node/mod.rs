@@ -1,20 +1,63 @@
-use crate::config::Vx0Config;
+use crate::config::{BootstrapNode, Vx0Config};
+use crate::monitoring::capacity::CapacityMonitor;
+use crate::monitoring::capture::Capture;
+use crate::monitoring::readiness::ReadinessMonitor;
+use crate::network::acl::{Acl, AclError, Contact};
+use crate::network::adaptive::LinkTimers;
+use crate::network::bgp::table_sync::SyncProgress;
+use crate::network::bgp::BGPError;
+use crate::network::dns::{DNSError, SharedDns, SrvTarget, Vx0DNS};
+use crate::network::ike::journal::NonceJournal;
 use crate::network::ike::tunnels::{TunnelId, TunnelManager};
+use crate::network::ike::IKEError;
+use crate::network::transport::TransportError;
+use crate::node::redundancy::RedundancyGroup;
+use crate::util::clock::{self, SharedClock};
+use admin::{AdminError, AdminRegistry};
+use asn_registry::{AsnRegistry, RegistryError};
+use bootstrap_health::{BootstrapRegistry, BootstrapSource};
+use capabilities::Capabilities;
+use goodbye::{Departure, GoodbyeReason};
+use metadata::{MetadataError, ServiceMetadata};
+use observed::ObservedAddresses;
+use quarantine::{Quarantine, QuarantineError};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, Mutex, RwLock};
 use uuid::Uuid;
 
+pub use peer::{PeerEvent, PeerHandle};
+
+pub mod admin;
+pub mod asn_registry;
 pub mod bootstrap;
+pub mod bootstrap_health;
+pub mod capabilities;
+#[cfg(feature = "discovery")]
 pub mod discovery;
+pub mod goodbye;
 pub mod joining;
 pub mod manager;
+pub mod metadata;
+pub mod observed;
 pub mod peer;
+pub mod peer_selection;
+pub mod quarantine;
+pub mod redundancy;
+pub mod search;
+pub mod services;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tunnel;
 
 pub type NodeId = Uuid;
 
+const PEER_EVENT_QUEUE: usize = 64;
+
 #[derive(Debug, Clone)]
 pub struct Vx0Node {
     pub node_id: NodeId,
@@ -24,14 +67,50 @@ pub struct Vx0Node {
     pub ipv4_addr: Ipv4Addr,
     pub ipv6_addr: Ipv6Addr,
     pub hostname: String,
-    pub peers: Arc<RwLock<HashMap<NodeId, PeerConnection>>>,
+    /// Only ever locked long enough to look up or clone handles
+    peers: Arc<RwLock<HashMap<NodeId, PeerHandle>>>,
     pub services: Arc<RwLock<Vec<HostedService>>>,
+    /// When each hosted service was last registered or refreshed, on the
+    /// monotonic clock
+    service_leases: Arc<RwLock<HashMap<Uuid, Instant>>>,
+    /// The directory service searches read, once the daemon keeps one
+    directory: Arc<OnceLock<SharedDns>>,
+    /// Records join requests and announcements, when capturing
+    capture: Arc<OnceLock<Arc<Capture>>>,
+    /// What this node currently offers peers; changes trigger re-announcement
+    capabilities: Arc<watch::Sender<Capabilities>>,
+    /// Peers refused regardless of tier rules, shared with the daemons
+    pub acl: Arc<Acl>,
+    /// Peers taken down by the operator, shared with the BGP daemon
+    pub admin: Arc<AdminRegistry>,
+    /// Misbehaving peers' scores, fed by the BGP daemon
+    pub quarantine: Arc<Quarantine>,
+    /// Health of the bootstrap nodes, ordering connection attempts
+    pub bootstrap: Arc<BootstrapRegistry>,
+    /// ASN grants from trusted registries, and ours when running one
+    pub asn_registry: Arc<AsnRegistry>,
+    /// Peer changes the BGP daemon and others follow
+    peer_events: broadcast::Sender<PeerEvent>,
+    /// Tunnel establishments under way, for callers to join
+    establishing: Arc<Mutex<HashMap<NodeId, tunnel::InFlight>>>,
+    tunnel_timeout: Duration,
+    /// Per-tier statistics from the directory, and health heard from peers
+    pub capacity: Arc<CapacityMonitor>,
+    /// Whether the node is fit to carry traffic, beyond merely running
+    pub readiness: Arc<ReadinessMonitor>,
+    /// Set by `enter_maintenance` until the node peers again
+    maintenance: Arc<watch::Sender<bool>>,
+    /// Partners this node takes turns at maintenance with
+    pub redundancy: Arc<RedundancyGroup>,
+    /// Our address as peers report seeing it
+    pub observed: Arc<ObservedAddresses>,
     pub config: Vx0Config,
     pub tunnel_manager: Arc<TunnelManager>,
-    pub active_tunnels: Arc<RwLock<HashMap<NodeId, TunnelId>>>,
+    /// Times the node's timers and liveness checks
+    pub clock: SharedClock,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum NodeTier {
     Backbone, // Tier 1: Core routing infrastructure (ASN 65000-65099)
     Regional, // Tier 2: Regional distribution hubs (ASN 65100-65999)
@@ -39,6 +118,14 @@ pub enum NodeTier {
 }
 
 impl NodeTier {
+    pub fn from_asn(asn: u32) -> Self {
+        match asn {
+            65000..=65099 => NodeTier::Backbone,
+            65100..=65999 => NodeTier::Regional,
+            _ => NodeTier::Edge,
+        }
+    }
+
     pub fn get_asn_range(&self) -> (u32, u32) {
         match self {
             NodeTier::Backbone => (65000, 65099), // 100 backbone ASNs
@@ -96,7 +183,7 @@ pub struct GeographicLocation {
     pub longitude: f64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct PeerConnection {
     pub peer_id: NodeId,
     pub peer_asn: u32,
@@ -104,18 +191,30 @@ pub struct PeerConnection {
     pub status: ConnectionStatus,
     pub metrics: ConnectionMetrics,
     pub last_seen: chrono::DateTime<chrono::Utc>,
+    /// As last advertised by the peer
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// Set while the peer is away after saying goodbye
+    #[serde(default)]
+    pub departure: Option<Departure>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub enum ConnectionStatus {
     Disconnected,
     Connecting,
     Connected,
     Authenticated,
     Failed,
+    /// Left on purpose; not retried until it re-announces or its probe is due
+    Departed,
+    /// Taken down by the operator; not retried until enabled again
+    AdminDown,
+    /// Cut off for misbehaving; not retried until the cooldown ends
+    Quarantined,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ConnectionMetrics {
     pub latency_ms: u64,
     pub packet_loss: f32,
@@ -123,6 +222,17 @@ pub struct ConnectionMetrics {
     pub bytes_received: u64,
     pub routes_advertised: u32,
     pub routes_received: u32,
+    /// The peer's full table, when it was sent in chunks; routes are in
+    /// use but partial until it completes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub table_sync: Option<SyncProgress>,
+    /// Largest datagram the peer's tunnel carries, when it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_mtu: Option<usize>,
+    /// How often the link is probed now, when intervals adapt to its
+    /// stability
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timers: Option<LinkTimers>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -133,10 +243,30 @@ pub struct HostedService {
     pub domain: String,
     pub port: u16,
     pub status: ServiceStatus,
-    pub metadata: HashMap<String, String>,
+    pub metadata: ServiceMetadata,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl HostedService {
+    /// Where the SRV record pointing at this service lives; services under
+    /// one domain share its A and AAAA records but each has its own
+    pub fn srv_name(&self) -> String {
+        self.service_type.srv_name(&self.domain)
+    }
+
+    pub fn srv_target(&self) -> SrvTarget {
+        SrvTarget::new(self.port, &self.domain)
+    }
+
+    /// Whether both are the same (domain, type, port), which only one
+    /// service may hold
+    pub fn collides_with(&self, other: &HostedService) -> bool {
+        self.domain == other.domain
+            && self.port == other.port
+            && self.service_type.matches(&other.service_type)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum ServiceType {
     WebServer,
     EmailServer,
@@ -146,7 +276,7 @@ pub enum ServiceType {
     Custom(String),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum ServiceStatus {
     Starting,
     Running,
@@ -159,28 +289,113 @@ pub enum ServiceStatus {
 pub enum NodeError {
     #[error("Configuration error: {0}")]
     Config(String),
+    #[error("Invalid {kind} address {address:?}")]
+    InvalidAddress {
+        kind: &'static str,
+        address: String,
+        #[source]
+        source: std::net::AddrParseError,
+    },
+    #[error("ASN {asn} not valid for {tier:?} tier (valid range: {}-{})", range.0, range.1)]
+    AsnOutOfRange {
+        asn: u32,
+        tier: NodeTier,
+        range: (u32, u32),
+    },
+    #[error("{ours:?} nodes cannot peer with {theirs:?} nodes")]
+    TierMismatch { ours: NodeTier, theirs: NodeTier },
+    #[error("Maximum peer limit reached for {tier:?} tier ({limit})")]
+    PeerLimitReached { tier: NodeTier, limit: usize },
+    #[error("Peer {peer} is not registered")]
+    UnknownPeer { peer: NodeId },
+    #[error("Peer {peer} is no longer running")]
+    PeerGone { peer: NodeId },
+    #[error("No tunnel to peer {peer}")]
+    NoTunnel { peer: NodeId },
+    #[error("Tunnel to peer {peer} failed")]
+    Tunnel {
+        peer: NodeId,
+        #[source]
+        source: IKEError,
+    },
+    #[error("Tunnel to peer {peer} not established within {timeout:?}")]
+    TunnelEstablishTimeout { peer: NodeId, timeout: Duration },
+    #[error("Concurrent attempt to establish a tunnel to peer {peer} failed")]
+    TunnelEstablishFailed { peer: NodeId },
+    #[error("Network refused to admit this node: {reason}")]
+    JoinRejected { reason: String },
+    #[error("Peer {peer} is refused by the ACL")]
+    Blocked { peer: NodeId },
+    #[error("AS{asn} is administratively down")]
+    AdminDown { asn: u32 },
+    #[error("AS{asn} is quarantined")]
+    Quarantined { asn: u32 },
+    #[error(transparent)]
+    Admin(#[from] AdminError),
+    #[error(transparent)]
+    Quarantine(#[from] QuarantineError),
+    #[error(transparent)]
+    Acl(#[from] AclError),
+    #[error(transparent)]
+    Metadata(#[from] MetadataError),
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
     #[error("Network error: {0}")]
     Network(String),
-    #[error("BGP error: {0}")]
-    BGP(String),
-    #[error("IKE error: {0}")]
-    IKE(String),
+    #[error(transparent)]
+    BGP(#[from] BGPError),
+    #[error(transparent)]
+    IKE(#[from] IKEError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    DNS(#[from] DNSError),
+    #[error("{domain} already has a {service_type} service on port {port}")]
+    ServiceExists {
+        domain: String,
+        service_type: ServiceType,
+        port: u16,
+    },
     #[error("Service error: {0}")]
     Service(String),
-    #[error("IO error: {0}")]
+    #[error(transparent)]
     IO(#[from] std::io::Error),
-    #[error("Serialization error: {0}")]
+    #[error(transparent)]
     Serialization(#[from] serde_json::Error),
 }
 
+impl NodeError {
+    /// Whether retrying the same operation later could succeed, as opposed to
+    /// failures that won't change without a config or topology change
+    pub fn is_transient(&self) -> bool {
+        match self {
+            NodeError::BGP(e) => e.is_transient(),
+            NodeError::Transport(e) => e.is_transient(),
+            NodeError::PeerLimitReached { .. }
+            | NodeError::PeerGone { .. }
+            | NodeError::Network(_)
+            | NodeError::IO(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl Vx0Node {
     pub fn new(config: Vx0Config) -> Result<Self, NodeError> {
         let ipv4_addr = config
             .get_ipv4_addr()
-            .map_err(|e| NodeError::Config(format!("Invalid IPv4 address: {}", e)))?;
+            .map_err(|source| NodeError::InvalidAddress {
+                kind: "IPv4",
+                address: config.node.ipv4_address.clone(),
+                source,
+            })?;
         let ipv6_addr = config
             .get_ipv6_addr()
-            .map_err(|e| NodeError::Config(format!("Invalid IPv6 address: {}", e)))?;
+            .map_err(|source| NodeError::InvalidAddress {
+                kind: "IPv6",
+                address: config.node.ipv6_address.clone(),
+                source,
+            })?;
 
         let tier = match config.node.tier.as_str() {
             "Backbone" => NodeTier::Backbone,
@@ -195,10 +410,11 @@ impl Vx0Node {
         // Validate ASN is within tier range
         let (min_asn, max_asn) = tier.get_asn_range();
         if config.node.asn < min_asn || config.node.asn > max_asn {
-            return Err(NodeError::Config(format!(
-                "ASN {} not valid for {:?} tier (valid range: {}-{})",
-                config.node.asn, tier, min_asn, max_asn
-            )));
+            return Err(NodeError::AsnOutOfRange {
+                asn: config.node.asn,
+                tier,
+                range: (min_asn, max_asn),
+            });
         }
 
         let location = GeographicLocation {
@@ -209,8 +425,23 @@ impl Vx0Node {
             longitude: 0.0,
         };
 
+        let capabilities = Capabilities::for_node(&config, &tier, 0);
+        let acl = Acl::open(&config.network.acl)?;
+        let bootstrap = BootstrapRegistry::open(&config.joining.bootstrap_health);
+        for (hostname, ip, asn) in joining::PUBLIC_BOOTSTRAP_NODES {
+            let node = BootstrapNode {
+                hostname: hostname.to_string(),
+                ip: ip.to_string(),
+                asn: *asn,
+            };
+            bootstrap.add(node, BootstrapSource::Builtin);
+        }
+        for node in config.bootstrap.iter().flat_map(|config| &config.nodes) {
+            bootstrap.add(node.clone(), BootstrapSource::Configured);
+        }
+
         Ok(Vx0Node {
-            node_id: Uuid::new_v4(),
+            node_id: config.node.node_id.unwrap_or_else(Uuid::new_v4),
             asn: config.node.asn,
             tier,
             location,
@@ -219,15 +450,64 @@ impl Vx0Node {
             hostname: config.node.hostname.clone(),
             peers: Arc::new(RwLock::new(HashMap::new())),
             services: Arc::new(RwLock::new(Vec::new())),
+            service_leases: Arc::new(RwLock::new(HashMap::new())),
+            directory: Arc::new(OnceLock::new()),
+            capture: Arc::new(OnceLock::new()),
+            capabilities: Arc::new(watch::Sender::new(capabilities)),
+            acl: Arc::new(acl),
+            admin: Arc::new(AdminRegistry::from_config(&config)),
+            quarantine: Arc::new(Quarantine::new(config.network.quarantine.clone())),
+            bootstrap: Arc::new(bootstrap),
+            asn_registry: Arc::new(AsnRegistry::from_config(&config.joining.registry)?),
+            peer_events: broadcast::channel(PEER_EVENT_QUEUE).0,
+            establishing: Arc::new(Mutex::new(HashMap::new())),
+            tunnel_timeout: Duration::from_secs(config.security.ike.establish_timeout_secs),
+            capacity: Arc::new(CapacityMonitor::new(config.monitoring.capacity.clone())),
+            readiness: Arc::new(ReadinessMonitor::new(config.monitoring.readiness.clone())),
+            maintenance: Arc::new(watch::Sender::new(false)),
+            redundancy: Arc::new(RedundancyGroup::new(config.node.redundancy.clone())),
+            observed: Arc::new(ObservedAddresses::new(
+                ipv4_addr,
+                config.node.auto_detect_public_address,
+            )),
+            tunnel_manager: Arc::new(
+                TunnelManager::new()
+                    .with_padding(config.security.obfuscation.padding())
+                    .with_nonce_journal(NonceJournal::open(&config.security.ike.nonce_journal))
+                    .with_resumption(config.security.ike.resumption.validity())
+                    .with_rekey_interval(config.security.ike.rekey_interval())
+                    .with_mtu(
+                        usize::from(config.security.ike.mtu),
+                        config.security.ike.mtu_revalidate(),
+                    )
+                    // The kernel fragments for its tunnel interfaces, to
+                    // their MTU
+                    .with_fragmentation(!config.network.kernel_routes.enabled),
+            ),
+            clock: clock::system(),
             config,
-            tunnel_manager: Arc::new(TunnelManager::new()),
-            active_tunnels: Arc::new(RwLock::new(HashMap::new())),
         })
     }
 
+    /// Run the node's timers on `clock` rather than the system's
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.tunnel_manager.set_clock(Arc::clone(&clock));
+        self.clock = clock;
+        self
+    }
+
     pub async fn start(&self) -> Result<(), NodeError> {
         tracing::info!("Starting VX0 node {} (ASN: {})", self.hostname, self.asn);
 
+        let obfuscation = &self.config.security.obfuscation;
+        if let (Some(_), Some(interval)) =
+            (obfuscation.padding(), obfuscation.cover_traffic_interval())
+        {
+            self.tunnel_manager.spawn_cover_traffic(interval);
+        }
+        self.tunnel_manager.spawn_nonce_journal_flush();
+        self.tunnel_manager.spawn_rekeying();
+
         // Initialize services
         self.start_monitoring().await?;
         self.start_service_discovery().await?;
@@ -239,11 +519,25 @@ impl Vx0Node {
     pub async fn stop(&self) -> Result<(), NodeError> {
         tracing::info!("Stopping VX0 node {}", self.hostname);
 
-        // Close all peer connections
-        let mut peers = self.peers.write().await;
-        for (peer_id, peer) in peers.iter_mut() {
-            peer.status = ConnectionStatus::Disconnected;
-            tracing::debug!("Disconnected from peer {}", peer_id);
+        // Close all peer connections, telling each peer why first
+        for handle in self.peer_handles().await {
+            if let Err(e) = self
+                .say_goodbye(handle.peer_id(), GoodbyeReason::Shutdown, None)
+                .await
+            {
+                tracing::debug!("No goodbye for peer {}: {}", handle.peer_id(), e);
+            }
+            if handle
+                .set_status(ConnectionStatus::Disconnected)
+                .await
+                .is_ok()
+            {
+                tracing::debug!("Disconnected from peer {}", handle.peer_id());
+            }
+        }
+
+        if let Err(e) = self.tunnel_manager.flush_nonce_journal().await {
+            tracing::warn!("Failed to write nonce journal: {}", e);
         }
 
         tracing::info!("VX0 node stopped");
@@ -251,33 +545,78 @@ impl Vx0Node {
     }
 
     pub async fn add_peer(&self, peer: PeerConnection) -> Result<(), NodeError> {
-        // Check if we've reached max peer limit for our tier
-        let max_peers = self.tier.max_peers();
-        let current_peers = self.get_peer_count().await;
+        self.admit_peer(peer, None).await
+    }
+
+    /// Add a peer that takes over the slot of `replacing`, which is about to
+    /// be dropped; until it is, the node holds one peer over its limit
+    pub async fn add_peer_replacing(
+        &self,
+        peer: PeerConnection,
+        replacing: &NodeId,
+    ) -> Result<(), NodeError> {
+        self.admit_peer(peer, Some(replacing)).await
+    }
 
-        if current_peers >= max_peers {
-            return Err(NodeError::Network(format!(
-                "Maximum peer limit reached for {:?} tier ({}/{})",
-                self.tier, current_peers, max_peers
-            )));
+    async fn admit_peer(
+        &self,
+        mut peer: PeerConnection,
+        replacing: Option<&NodeId>,
+    ) -> Result<(), NodeError> {
+        let contact = Contact::node(peer.peer_id, peer.peer_asn, peer.peer_addr);
+        if !self.acl.check(&contact, "peering") {
+            return Err(NodeError::Blocked { peer: peer.peer_id });
         }
 
         // Determine peer tier from ASN
-        let peer_tier = Self::asn_to_tier(peer.peer_asn);
+        let peer_tier = NodeTier::from_asn(peer.peer_asn);
 
         // Check if this tier can peer with the other tier
         if !self.tier.can_peer_with(&peer_tier) {
-            return Err(NodeError::Network(format!(
-                "{:?} nodes cannot peer with {:?} nodes",
-                self.tier, peer_tier
-            )));
+            return Err(NodeError::TierMismatch {
+                ours: self.tier.clone(),
+                theirs: peer_tier,
+            });
         }
 
         let peer_id = peer.peer_id;
         let peer_asn = peer.peer_asn;
+        // Known, but left alone until the operator enables it
+        if self.admin.is_down(peer_asn) {
+            peer.status = ConnectionStatus::AdminDown;
+        } else if self
+            .quarantine
+            .is_quarantined(peer_asn, self.clock.now_utc())
+        {
+            peer.status = ConnectionStatus::Quarantined;
+        }
 
-        let mut peers = self.peers.write().await;
-        peers.insert(peer_id, peer);
+        let previous = {
+            // Check and insert under one lock so concurrent adds can't overshoot
+            let mut peers = self.peers.write().await;
+            let max_peers = self.tier.max_peers();
+            let leaving = replacing.is_some_and(|old| peers.contains_key(old));
+            if !peers.contains_key(&peer_id) && peers.len() - usize::from(leaving) >= max_peers {
+                return Err(NodeError::PeerLimitReached {
+                    tier: self.tier.clone(),
+                    limit: max_peers,
+                });
+            }
+            peers.insert(
+                peer_id,
+                PeerHandle::spawn(peer, Arc::clone(&self.tunnel_manager)),
+            )
+        };
+        // The old task would otherwise run on unseen, holding its tunnel
+        if let Some(previous) = previous {
+            let _ = previous.shutdown().await;
+            tracing::debug!("Replaced the running handle of peer {}", peer_id);
+        }
+        self.refresh_capabilities().await;
+        if self.maintenance.send_replace(false) {
+            self.redundancy.left_maintenance(self.clock.now_utc());
+            tracing::info!(target: "audit", "Left maintenance on peering again");
+        }
 
         tracing::info!(
             "Added {:?} peer (ASN {}) to {:?} node",
@@ -289,19 +628,172 @@ impl Vx0Node {
         Ok(())
     }
 
-    fn asn_to_tier(asn: u32) -> NodeTier {
-        match asn {
-            65000..=65099 => NodeTier::Backbone,
-            65100..=65999 => NodeTier::Regional,
-            66000..=69999 => NodeTier::Edge,
-            _ => NodeTier::Edge, // Default fallback
+    pub async fn remove_peer(&self, peer_id: &NodeId) -> Result<(), NodeError> {
+        let handle = self.peers.write().await.remove(peer_id);
+        if let Some(handle) = handle {
+            // The task may already have exited; either way the peer is gone
+            let _ = handle.shutdown().await;
+            let _ = self.peer_events.send(PeerEvent::Removed {
+                peer_id: *peer_id,
+                peer_asn: handle.peer_asn(),
+            });
         }
+        self.refresh_capabilities().await;
+        Ok(())
     }
 
-    pub async fn remove_peer(&self, peer_id: &NodeId) -> Result<(), NodeError> {
-        let mut peers = self.peers.write().await;
-        peers.remove(peer_id);
-        Ok(())
+    /// Drop every peer the ACL now refuses, closing their tunnels, and
+    /// return how many were torn down
+    pub async fn enforce_acl(&self) -> usize {
+        let mut torn_down = 0;
+        for peer in self.list_peers().await {
+            let contact = Contact::node(peer.peer_id, peer.peer_asn, peer.peer_addr);
+            if self.acl.permits(&contact) {
+                continue;
+            }
+            if self.remove_peer(&peer.peer_id).await.is_ok() {
+                tracing::warn!(
+                    target: "audit",
+                    "Tore down peer {} (AS{}, {}) refused by the ACL",
+                    peer.peer_id,
+                    peer.peer_asn,
+                    peer.peer_addr
+                );
+                torn_down += 1;
+            }
+        }
+        torn_down
+    }
+
+    /// Whether `addr` may use a service offered only to this host and its
+    /// peers: it must be loopback or a current peer's address, and pass the
+    /// ACL at `ingress`
+    pub async fn admits_caller(&self, addr: IpAddr, ingress: &str) -> bool {
+        let known = addr.is_loopback()
+            || self
+                .list_peers()
+                .await
+                .iter()
+                .any(|peer| peer.peer_addr == addr);
+        if !known {
+            tracing::warn!(target: "audit", "Refused {} from {}, not a peer", ingress, addr);
+            return false;
+        }
+        self.acl.check(&Contact::address(addr), ingress)
+    }
+
+    /// The peer holding `tunnel_id`, if it may use a service offered to
+    /// peers over their tunnels and passes the ACL at `ingress`; a caller on
+    /// a sealed channel is known by its tunnel, not the address it dials from
+    pub async fn admits_tunnel_peer(
+        &self,
+        tunnel_id: TunnelId,
+        from: IpAddr,
+        ingress: &str,
+    ) -> Option<NodeId> {
+        let Some(peer_id) = self.peer_on_tunnel(tunnel_id, from).await else {
+            tracing::warn!(
+                target: "audit",
+                "Refused {} over tunnel {} from {}, no peer's",
+                ingress,
+                tunnel_id,
+                from
+            );
+            return None;
+        };
+        let peer = self.get_peer(&peer_id).await?.snapshot().await.ok()?;
+        let contact = Contact::node(peer_id, peer.peer_asn, peer.peer_addr);
+        self.acl.check(&contact, ingress).then_some(peer_id)
+    }
+
+    pub fn subscribe_peer_events(&self) -> broadcast::Receiver<PeerEvent> {
+        self.peer_events.subscribe()
+    }
+
+    /// Record join requests, announcements and, if wanted, tunnel payloads
+    /// to `capture`
+    pub fn set_capture(&self, capture: Arc<Capture>) {
+        self.tunnel_manager.set_capture(Arc::clone(&capture));
+        if self.capture.set(capture).is_err() {
+            tracing::warn!("Node capture already set");
+        }
+    }
+
+    pub fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture.get().cloned()
+    }
+
+    /// Move a known peer to the address it reappeared from, returning
+    /// whether it had moved
+    ///
+    /// A claim of a known node_id proves nothing by itself, so the peer must
+    /// first complete a fresh IKE exchange at the new address. Only then is
+    /// the tunnel to the old address closed and the peer re-pointed; routes
+    /// it gave us are kept and follow through `PeerEvent::AddressChanged`.
+    pub async fn migrate_peer(&self, peer_id: NodeId, new_addr: IpAddr) -> Result<bool, NodeError> {
+        let handle = self
+            .get_peer(&peer_id)
+            .await
+            .ok_or(NodeError::UnknownPeer { peer: peer_id })?;
+        let current = handle.snapshot().await?;
+        let old_addr = current.peer_addr;
+        if old_addr == new_addr {
+            return Ok(false);
+        }
+
+        let contact = Contact::node(peer_id, current.peer_asn, new_addr);
+        if !self.acl.check(&contact, "address change") {
+            return Err(NodeError::Blocked { peer: peer_id });
+        }
+
+        // Keep the port the tunnel was negotiated on
+        let mut port = self.config.security.ike.listen_port;
+        if let Some(tunnel_id) = handle.tunnel().await? {
+            if let Some(tunnel) = self.tunnel_manager.get_tunnel(&tunnel_id).await {
+                port = tunnel.ike_session.peer_addr.port();
+            }
+        }
+
+        // Replaces, and closes, the tunnel to the old address
+        let psk = self.tunnel_psk();
+        if let Err(e) = self
+            .create_secure_tunnel(peer_id, SocketAddr::new(new_addr, port), &psk)
+            .await
+        {
+            tracing::warn!(
+                target: "audit",
+                "Kept peer {} (AS{}) at {}: it claimed {} but did not re-authenticate there",
+                peer_id,
+                current.peer_asn,
+                old_addr,
+                new_addr
+            );
+            return Err(e);
+        }
+        handle.set_address(new_addr).await?;
+
+        tracing::warn!(
+            target: "audit",
+            "Peer {} (AS{}) moved from {} to {}",
+            peer_id,
+            current.peer_asn,
+            old_addr,
+            new_addr
+        );
+        let _ = self.peer_events.send(PeerEvent::AddressChanged {
+            peer_id,
+            peer_asn: current.peer_asn,
+            old: old_addr,
+            new: new_addr,
+        });
+        Ok(true)
+    }
+
+    /// Key tunnels to peers are negotiated with
+    pub(crate) fn tunnel_psk(&self) -> Vec<u8> {
+        // In production, this should use proper key exchange
+        // For now, use a default PSK that all nodes know
+        b"vx0-network-default-psk-change-in-production".to_vec()
     }
 
     pub async fn get_peer_count(&self) -> usize {
@@ -309,6 +801,34 @@ impl Vx0Node {
         peers.len()
     }
 
+    pub async fn get_peer(&self, peer_id: &NodeId) -> Option<PeerHandle> {
+        let peers = self.peers.read().await;
+        peers.get(peer_id).cloned()
+    }
+
+    pub async fn peer_handles(&self) -> Vec<PeerHandle> {
+        let peers = self.peers.read().await;
+        peers.values().cloned().collect()
+    }
+
+    /// Snapshot of every peer's connection state
+    pub async fn list_peers(&self) -> Vec<PeerConnection> {
+        let mut connections = Vec::new();
+        for handle in self.peer_handles().await {
+            if let Ok(mut connection) = handle.snapshot().await {
+                if let Ok(Some(tunnel_id)) = handle.tunnel().await {
+                    connection.metrics.tunnel_mtu = self
+                        .tunnel_manager
+                        .get_tunnel(&tunnel_id)
+                        .await
+                        .map(|tunnel| tunnel.mtu);
+                }
+                connections.push(connection);
+            }
+        }
+        connections
+    }
+
     pub async fn register_service(&self, service: HostedService) -> Result<(), NodeError> {
         if !service.domain.ends_with(".vx0") {
             return Err(NodeError::Service(
@@ -316,11 +836,154 @@ impl Vx0Node {
             ));
         }
 
+        // Locked in the order expire_services takes them
+        let mut leases = self.service_leases.write().await;
         let mut services = self.services.write().await;
+        // Services share a domain as long as each has its own type or port
+        if services
+            .iter()
+            .any(|existing| existing.collides_with(&service))
+        {
+            return Err(NodeError::ServiceExists {
+                domain: service.domain,
+                service_type: service.service_type,
+                port: service.port,
+            });
+        }
+        leases.insert(service.service_id, self.clock.now_monotonic());
         services.push(service);
         Ok(())
     }
 
+    /// Stop hosting a service before its TTL runs out
+    pub async fn unregister_service(&self, service_id: Uuid) -> Option<HostedService> {
+        self.service_leases.write().await.remove(&service_id);
+        let mut services = self.services.write().await;
+        let index = services
+            .iter()
+            .position(|service| service.service_id == service_id)?;
+        Some(services.remove(index))
+    }
+
+    /// How long a service stays published without a refresh
+    pub fn service_ttl(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.config.services.service_ttl.as_secs() as i64)
+    }
+
+    /// Keep a service alive for another TTL
+    pub async fn refresh_service(&self, service_id: Uuid) -> Result<HostedService, NodeError> {
+        let service = self
+            .services
+            .read()
+            .await
+            .iter()
+            .find(|service| service.service_id == service_id)
+            .cloned()
+            .ok_or_else(|| NodeError::Service(format!("Unknown service {}", service_id)))?;
+
+        self.service_leases
+            .write()
+            .await
+            .insert(service_id, self.clock.now_monotonic());
+        Ok(service)
+    }
+
+    pub async fn find_service(&self, domain: &str) -> Option<HostedService> {
+        self.services
+            .read()
+            .await
+            .iter()
+            .find(|service| service.domain == domain)
+            .cloned()
+    }
+
+    /// Every service hosted under `domain`, in registration order
+    pub async fn services_at(&self, domain: &str) -> Vec<HostedService> {
+        self.services
+            .read()
+            .await
+            .iter()
+            .filter(|service| service.domain == domain)
+            .cloned()
+            .collect()
+    }
+
+    /// Drop services whose TTL lapsed without a refresh, returning them
+    pub async fn expire_services(&self) -> Vec<HostedService> {
+        let ttl = self.config.services.service_ttl.get();
+        let now = self.clock.now_monotonic();
+        let mut leases = self.service_leases.write().await;
+        let mut services = self.services.write().await;
+
+        let mut expired = Vec::new();
+        services.retain(|service| {
+            let live = leases
+                .get(&service.service_id)
+                .is_some_and(|refreshed| now.saturating_duration_since(*refreshed) < ttl);
+            if !live {
+                leases.remove(&service.service_id);
+                expired.push(service.clone());
+            }
+            live
+        });
+        expired
+    }
+
+    /// Publish a service's domain under the node's addresses: an A record for
+    /// the IPv4 address and an AAAA record when the IPv6 address is routable,
+    /// shared with other services under the domain, and an SRV record of its
+    /// own giving its port
+    pub fn publish_service_dns(
+        &self,
+        service: &HostedService,
+        dns: &mut Vx0DNS,
+    ) -> Result<(), NodeError> {
+        let ttl = self
+            .config
+            .services
+            .service_ttl
+            .as_secs()
+            .min(u32::MAX as u64) as u32;
+        dns.register_service_with_ttl(service.domain.clone(), IpAddr::V4(self.public_ipv4()), ttl)?;
+        dns.register_srv(service.srv_name(), service.srv_target(), ttl)?;
+
+        let link_local = (self.ipv6_addr.segments()[0] & 0xffc0) == 0xfe80;
+        if self.ipv6_addr.is_unspecified() || self.ipv6_addr.is_loopback() || link_local {
+            tracing::debug!(
+                "Not publishing AAAA for {}: {} is not routable",
+                service.domain,
+                self.ipv6_addr
+            );
+            return Ok(());
+        }
+
+        Ok(dns.register_service_with_ttl(
+            service.domain.clone(),
+            IpAddr::V6(self.ipv6_addr),
+            ttl,
+        )?)
+    }
+
+    /// The location from the configuration, or "" if none was given
+    pub fn stated_location(&self) -> &str {
+        match self.location.city.as_str() {
+            "Unknown" => "",
+            city => city,
+        }
+    }
+
+    /// The IPv4 address announcements and DNS registrations carry
+    pub fn public_ipv4(&self) -> Ipv4Addr {
+        self.observed.announced()
+    }
+
+    /// Note the address a peer at `reporter` says our connection came from
+    pub fn observe_address(&self, reporter: IpAddr, observed: Option<IpAddr>) {
+        if let Some(observed) = observed {
+            self.observed.record(reporter, observed);
+        }
+    }
+
     async fn start_monitoring(&self) -> Result<(), NodeError> {
         tracing::debug!("Starting monitoring for node {}", self.node_id);
         Ok(())
@@ -332,59 +995,25 @@ impl Vx0Node {
     }
 
     // Tunnel management methods
-    pub async fn create_secure_tunnel(
-        &self,
-        peer_id: NodeId,
-        peer_addr: SocketAddr,
-        psk: &[u8],
-    ) -> Result<TunnelId, NodeError> {
-        tracing::info!(
-            "Creating secure tunnel to peer {} at {}",
-            peer_id,
-            peer_addr
-        );
-
-        let tunnel_id = self
-            .tunnel_manager
-            .create_tunnel(IpAddr::V4(self.ipv4_addr), peer_addr.ip(), peer_addr, psk)
-            .await
-            .map_err(|e| NodeError::IKE(format!("Failed to create tunnel: {}", e)))?;
-
-        // Store the tunnel mapping
-        let mut tunnels = self.active_tunnels.write().await;
-        tunnels.insert(peer_id, tunnel_id);
-
-        tracing::info!(
-            "Secure tunnel {} established with peer {}",
-            tunnel_id,
-            peer_id
-        );
-        Ok(tunnel_id)
-    }
-
     pub async fn send_secure_data(&self, peer_id: &NodeId, data: &[u8]) -> Result<(), NodeError> {
-        let tunnels = self.active_tunnels.read().await;
-        if let Some(tunnel_id) = tunnels.get(peer_id) {
-            self.tunnel_manager
-                .send_packet(tunnel_id, data)
-                .await
-                .map_err(|e| NodeError::IKE(format!("Failed to send secure data: {}", e)))?;
-            Ok(())
-        } else {
-            Err(NodeError::IKE(format!(
-                "No tunnel found for peer {}",
-                peer_id
-            )))
+        match self.get_peer(peer_id).await {
+            Some(handle) => handle.send(data).await,
+            None => Err(NodeError::UnknownPeer { peer: *peer_id }),
         }
     }
 
     pub async fn close_tunnel(&self, peer_id: &NodeId) -> Result<(), NodeError> {
-        let mut tunnels = self.active_tunnels.write().await;
-        if let Some(tunnel_id) = tunnels.remove(peer_id) {
+        let Some(handle) = self.get_peer(peer_id).await else {
+            return Ok(());
+        };
+        if let Some(tunnel_id) = handle.detach_tunnel().await? {
             self.tunnel_manager
                 .close_tunnel(&tunnel_id)
                 .await
-                .map_err(|e| NodeError::IKE(format!("Failed to close tunnel: {}", e)))?;
+                .map_err(|source| NodeError::Tunnel {
+                    peer: *peer_id,
+                    source,
+                })?;
             tracing::info!("Closed tunnel to peer {}", peer_id);
         }
         Ok(())
@@ -394,34 +1023,35 @@ impl Vx0Node {
         &self,
         peer_id: &NodeId,
     ) -> Option<crate::network::ike::tunnels::TrafficStats> {
-        let tunnels = self.active_tunnels.read().await;
-        if let Some(tunnel_id) = tunnels.get(peer_id) {
-            self.tunnel_manager.get_tunnel_stats(tunnel_id).await
-        } else {
-            None
-        }
+        let handle = self.get_peer(peer_id).await?;
+        let tunnel_id = handle.tunnel().await.ok()??;
+        self.tunnel_manager.get_tunnel_stats(&tunnel_id).await
     }
 
     pub async fn list_active_tunnels(&self) -> Vec<(NodeId, TunnelId)> {
-        let tunnels = self.active_tunnels.read().await;
-        tunnels.iter().map(|(k, v)| (*k, *v)).collect()
+        let mut tunnels = Vec::new();
+        for handle in self.peer_handles().await {
+            if let Ok(Some(tunnel_id)) = handle.tunnel().await {
+                tunnels.push((handle.peer_id(), tunnel_id));
+            }
+        }
+        tunnels
     }
 
     pub async fn tunnel_health_check(&self) -> Result<HashMap<NodeId, bool>, NodeError> {
-        let tunnels = self.active_tunnels.read().await;
         let mut health_status = HashMap::new();
 
-        for (peer_id, tunnel_id) in tunnels.iter() {
-            if let Some(tunnel) = self.tunnel_manager.get_tunnel(tunnel_id).await {
+        for (peer_id, tunnel_id) in self.list_active_tunnels().await {
+            if let Some(tunnel) = self.tunnel_manager.get_tunnel(&tunnel_id).await {
                 health_status.insert(
-                    *peer_id,
+                    peer_id,
                     matches!(
                         tunnel.status,
                         crate::network::ike::tunnels::TunnelStatus::Established
                     ),
                 );
             } else {
-                health_status.insert(*peer_id, false);
+                health_status.insert(peer_id, false);
             }
         }
 
@@ -438,6 +1068,9 @@ impl Default for ConnectionMetrics {
             bytes_received: 0,
             routes_advertised: 0,
             routes_received: 0,
+            table_sync: None,
+            tunnel_mtu: None,
+            timers: None,
         }
     }
 }
@@ -0,0 +1,235 @@
+//! This node's public address as its peers see it.
+//!
+//! Nodes behind NAT, or whose operator mistyped `node.ipv4_address`,
+//! announce an address nobody can reach them at. Peers report the address
+//! each connection reached them from, in their OPEN replies and join
+//! answers, and those reports are tallied here per reporting peer. When
+//! most peers agree on an address other than the configured one, status
+//! warns about it; with `node.auto_detect_public_address` the node also
+//! announces and registers the observed address instead. A lone peer
+//! cannot move the address, and LAN peers reporting a private address are
+//! not listened to when the configured one is public.
+
+use crate::util::sync::lock;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Peers whose reports are kept; the oldest report makes way beyond this
+const MAX_REPORTERS: usize = 32;
+/// Peers that must agree before an observed address counts
+pub const MIN_AGREEING: usize = 2;
+
+/// Whether other networks could reach an address
+pub fn is_public(addr: Ipv4Addr) -> bool {
+    let shared = addr.octets()[0] == 100 && (addr.octets()[1] & 0xc0) == 64;
+    !(addr.is_private()
+        || addr.is_loopback()
+        || addr.is_link_local()
+        || addr.is_unspecified()
+        || addr.is_broadcast()
+        || shared)
+}
+
+/// One address and how many peers report seeing us at it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AddressTally {
+    pub address: Ipv4Addr,
+    pub peers: usize,
+}
+
+/// What the status command shows about our public address
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AddressReport {
+    pub configured: Ipv4Addr,
+    /// The address announcements and DNS registrations carry
+    pub announced: Ipv4Addr,
+    pub auto_detect: bool,
+    /// Most reported first
+    pub observations: Vec<AddressTally>,
+    /// The address most peers agree on, once at least `MIN_AGREEING` do
+    pub majority: Option<Ipv4Addr>,
+}
+
+impl AddressReport {
+    /// The address peers agree on, when it is not the configured one
+    pub fn mismatch(&self) -> Option<Ipv4Addr> {
+        self.majority.filter(|address| *address != self.configured)
+    }
+}
+
+#[derive(Debug)]
+pub struct ObservedAddresses {
+    configured: Ipv4Addr,
+    auto_detect: bool,
+    /// Reporting peer to the address it saw and when it said so
+    reports: Mutex<HashMap<IpAddr, (Ipv4Addr, Instant)>>,
+}
+
+impl ObservedAddresses {
+    pub fn new(configured: Ipv4Addr, auto_detect: bool) -> Self {
+        ObservedAddresses {
+            configured,
+            auto_detect,
+            reports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take note that `reporter` saw our connection come from `observed`.
+    /// Returns whether the report was kept.
+    pub fn record(&self, reporter: IpAddr, observed: IpAddr) -> bool {
+        // Announcements carry IPv4 only
+        let IpAddr::V4(observed) = observed else {
+            return false;
+        };
+        if observed.is_unspecified() || (is_public(self.configured) && !is_public(observed)) {
+            tracing::debug!(
+                "Ignoring {} reporting us at {}; the configured {} is public",
+                reporter,
+                observed,
+                self.configured
+            );
+            return false;
+        }
+
+        let mut reports = lock(&self.reports);
+        let before = majority(&reports);
+        if !reports.contains_key(&reporter) && reports.len() >= MAX_REPORTERS {
+            if let Some(oldest) = reports
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(peer, _)| *peer)
+            {
+                reports.remove(&oldest);
+            }
+        }
+        reports.insert(reporter, (observed, Instant::now()));
+        let after = majority(&reports);
+        drop(reports);
+
+        if after != before {
+            match after {
+                Some(address) if address != self.configured => tracing::warn!(
+                    "Peers see this node at {}, not the configured {}; {}",
+                    address,
+                    self.configured,
+                    if self.auto_detect {
+                        "announcing the observed address"
+                    } else {
+                        "check node.ipv4_address or set node.auto_detect_public_address"
+                    }
+                ),
+                Some(_) if before.is_some() => tracing::info!(
+                    "Peers agree with the configured address {} again",
+                    self.configured
+                ),
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// The address peers agree on, if enough of them do
+    pub fn majority(&self) -> Option<Ipv4Addr> {
+        majority(&lock(&self.reports))
+    }
+
+    /// The address to announce: the observed majority when auto-detecting,
+    /// the configured address otherwise
+    pub fn announced(&self) -> Ipv4Addr {
+        match self.majority() {
+            Some(address) if self.auto_detect => address,
+            _ => self.configured,
+        }
+    }
+
+    pub fn report(&self) -> AddressReport {
+        let reports = lock(&self.reports);
+        let majority = majority(&reports);
+        AddressReport {
+            configured: self.configured,
+            announced: match majority {
+                Some(address) if self.auto_detect => address,
+                _ => self.configured,
+            },
+            auto_detect: self.auto_detect,
+            observations: tally(&reports),
+            majority,
+        }
+    }
+}
+
+fn tally(reports: &HashMap<IpAddr, (Ipv4Addr, Instant)>) -> Vec<AddressTally> {
+    let mut counts: HashMap<Ipv4Addr, usize> = HashMap::new();
+    for (address, _) in reports.values() {
+        *counts.entry(*address).or_default() += 1;
+    }
+    let mut tallies: Vec<AddressTally> = counts
+        .into_iter()
+        .map(|(address, peers)| AddressTally { address, peers })
+        .collect();
+    tallies.sort_by(|a, b| b.peers.cmp(&a.peers).then(a.address.cmp(&b.address)));
+    tallies
+}
+
+/// More than half of the reporting peers, and at least `MIN_AGREEING`
+fn majority(reports: &HashMap<IpAddr, (Ipv4Addr, Instant)>) -> Option<Ipv4Addr> {
+    let leading = tally(reports).into_iter().next()?;
+    (leading.peers >= MIN_AGREEING && leading.peers * 2 > reports.len()).then_some(leading.address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_public_ranges() {
+        for private in [
+            "10.1.0.1",
+            "172.16.5.5",
+            "192.168.1.100",
+            "100.64.0.1",
+            "127.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["203.0.113.10", "100.128.0.1", "8.8.8.8"] {
+            assert!(is_public(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    fn test_majority_needs_more_than_half() {
+        let observed = ObservedAddresses::new("10.1.0.5".parse().unwrap(), true);
+        assert!(observed.record(ip("10.1.0.1"), ip("10.9.9.9")));
+        assert!(observed.record(ip("10.1.0.2"), ip("10.9.9.9")));
+        assert!(observed.record(ip("10.1.0.3"), ip("10.1.0.5")));
+        assert!(observed.record(ip("10.1.0.4"), ip("10.1.0.5")));
+        // Two against two is no majority
+        assert_eq!(observed.majority(), None);
+
+        // A peer changing its report counts once
+        observed.record(ip("10.1.0.4"), ip("10.9.9.9"));
+        assert_eq!(observed.majority(), Some("10.9.9.9".parse().unwrap()));
+        assert!(!observed.record(ip("10.1.0.5"), ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_oldest_report_makes_way() {
+        let observed = ObservedAddresses::new("10.1.0.5".parse().unwrap(), false);
+        for i in 0..MAX_REPORTERS + 3 {
+            observed.record(ip(&format!("10.2.0.{}", i)), ip("10.9.9.9"));
+        }
+        let report = observed.report();
+        assert_eq!(report.observations[0].peers, MAX_REPORTERS);
+        assert_eq!(report.announced, report.configured);
+        assert_eq!(report.mismatch(), Some("10.9.9.9".parse().unwrap()));
+    }
+}
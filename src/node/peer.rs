@@ -1,6 +1,13 @@
-use crate::node::{ConnectionMetrics, ConnectionStatus, NodeId, PeerConnection};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::ike::queue::{DatagramSink, QueueConfig, SendQueue, TrafficClass};
+use crate::network::ike::tunnels::{TunnelId, TunnelManager};
+use crate::node::capabilities::Capabilities;
+use crate::node::goodbye::{Departure, GoodbyeReason};
+use crate::node::{ConnectionMetrics, ConnectionStatus, NodeError, NodeId, PeerConnection};
 use std::net::IpAddr;
+use std::sync::Arc;
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::{timeout, Duration};
 
 impl PeerConnection {
@@ -12,6 +19,8 @@ impl PeerConnection {
             status: ConnectionStatus::Disconnected,
             metrics: ConnectionMetrics::default(),
             last_seen: chrono::Utc::now(),
+            capabilities: Capabilities::default(),
+            departure: None,
         }
     }
 
@@ -63,3 +72,336 @@ impl PeerConnection {
         self.last_seen = chrono::Utc::now();
     }
 }
+
+/// Something that happened to a peer that other subsystems act on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// A known peer reappeared from a new address and was re-authenticated
+    /// there; its tunnel already moved, sessions to `old` should follow
+    AddressChanged {
+        peer_id: NodeId,
+        peer_asn: u32,
+        old: IpAddr,
+        new: IpAddr,
+    },
+    /// The peer was dropped; routes learned from it should go too
+    Removed { peer_id: NodeId, peer_asn: u32 },
+    /// The peer said goodbye; its routes go at once, and its leaving is not
+    /// held against it
+    Departed {
+        peer_id: NodeId,
+        peer_asn: u32,
+        reason: GoodbyeReason,
+    },
+    /// A quicker peer took the place of the slowest one, which was sent
+    /// away with a goodbye; its `Removed` came first
+    Swapped {
+        old: NodeId,
+        new: NodeId,
+        old_rtt: Duration,
+        new_rtt: Duration,
+    },
+    /// The operator took the peer down; its routes go at once, while its
+    /// entry and metrics stay
+    AdminDown { peer_asn: u32 },
+    /// The peer crossed the quarantine threshold; its routes go at once
+    Quarantined { peer_asn: u32 },
+}
+
+/// Handle to the task that owns a single peer's connection state
+///
+/// The peer's connection, tunnel reference and metrics live on that task;
+/// every operation is a message send with a oneshot reply, so no caller
+/// ever holds a lock across another peer's work.
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    peer_id: NodeId,
+    peer_asn: u32,
+    commands: mpsc::Sender<PeerCommand>,
+}
+
+#[derive(Debug)]
+enum PeerCommand {
+    Snapshot(oneshot::Sender<PeerConnection>),
+    SetStatus(ConnectionStatus),
+    SetCapabilities(Capabilities),
+    SetAddress(IpAddr),
+    Depart(Option<Departure>, oneshot::Sender<()>),
+    AdminDown(bool, oneshot::Sender<()>),
+    Quarantine(bool, oneshot::Sender<()>),
+    AttachTunnel(TunnelId, oneshot::Sender<Option<TunnelId>>),
+    DetachTunnel(oneshot::Sender<Option<TunnelId>>),
+    Tunnel(oneshot::Sender<Option<TunnelId>>),
+    Send {
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<(), NodeError>>,
+    },
+    Shutdown(oneshot::Sender<()>),
+}
+
+struct PeerActor {
+    connection: PeerConnection,
+    tunnel: Option<TunnelId>,
+    /// Datagrams waiting for the tunnel, by traffic class
+    queue: Option<SendQueue>,
+    tunnel_manager: Arc<TunnelManager>,
+}
+
+const PEER_COMMAND_QUEUE: usize = 32;
+
+/// How long datagrams already queued may take to leave when the tunnel closes
+const QUEUE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl PeerHandle {
+    /// Spawn the task owning `connection` and return a handle to it
+    pub fn spawn(connection: PeerConnection, tunnel_manager: Arc<TunnelManager>) -> Self {
+        let (commands, rx) = mpsc::channel(PEER_COMMAND_QUEUE);
+        let handle = PeerHandle {
+            peer_id: connection.peer_id,
+            peer_asn: connection.peer_asn,
+            commands,
+        };
+
+        let actor = PeerActor {
+            connection,
+            tunnel: None,
+            queue: None,
+            tunnel_manager,
+        };
+        crash::spawn(Subsystem::Node, "peer-actor", actor.run(rx));
+
+        handle
+    }
+
+    pub fn peer_id(&self) -> NodeId {
+        self.peer_id
+    }
+
+    pub fn peer_asn(&self) -> u32 {
+        self.peer_asn
+    }
+
+    /// Current copy of the peer's connection state
+    pub async fn snapshot(&self) -> Result<PeerConnection, NodeError> {
+        self.request(PeerCommand::Snapshot).await
+    }
+
+    pub async fn set_status(&self, status: ConnectionStatus) -> Result<(), NodeError> {
+        self.commands
+            .send(PeerCommand::SetStatus(status))
+            .await
+            .map_err(|_| self.gone())
+    }
+
+    /// Record what the peer last advertised it offers
+    pub async fn set_capabilities(&self, capabilities: Capabilities) -> Result<(), NodeError> {
+        self.commands
+            .send(PeerCommand::SetCapabilities(capabilities))
+            .await
+            .map_err(|_| self.gone())
+    }
+
+    /// Record the address the peer is now reachable at
+    pub async fn set_address(&self, addr: IpAddr) -> Result<(), NodeError> {
+        self.commands
+            .send(PeerCommand::SetAddress(addr))
+            .await
+            .map_err(|_| self.gone())
+    }
+
+    /// Mark the peer as having left on purpose, closing its tunnel, or with
+    /// `None` as back and ready to reconnect
+    pub async fn set_departure(&self, departure: Option<Departure>) -> Result<(), NodeError> {
+        self.request(|reply| PeerCommand::Depart(departure, reply))
+            .await
+    }
+
+    /// Take the peer down administratively, closing its tunnel, or with
+    /// `false` bring it back up to reconnect as usual
+    pub async fn set_admin_down(&self, down: bool) -> Result<(), NodeError> {
+        self.request(|reply| PeerCommand::AdminDown(down, reply))
+            .await
+    }
+
+    /// Cut the peer off for misbehaving, closing its tunnel, or with `false`
+    /// let it reconnect once its quarantine is over
+    pub async fn set_quarantined(&self, quarantined: bool) -> Result<(), NodeError> {
+        self.request(|reply| PeerCommand::Quarantine(quarantined, reply))
+            .await
+    }
+
+    /// Record the tunnel carrying this peer's traffic, returning any it replaces
+    pub async fn attach_tunnel(&self, tunnel_id: TunnelId) -> Result<Option<TunnelId>, NodeError> {
+        self.request(|reply| PeerCommand::AttachTunnel(tunnel_id, reply))
+            .await
+    }
+
+    pub async fn detach_tunnel(&self) -> Result<Option<TunnelId>, NodeError> {
+        self.request(PeerCommand::DetachTunnel).await
+    }
+
+    pub async fn tunnel(&self) -> Result<Option<TunnelId>, NodeError> {
+        self.request(PeerCommand::Tunnel).await
+    }
+
+    /// Send data through the peer's secure tunnel
+    pub async fn send(&self, data: &[u8]) -> Result<(), NodeError> {
+        let data = data.to_vec();
+        self.request(|reply| PeerCommand::Send { data, reply })
+            .await?
+    }
+
+    /// Stop the peer task, closing its tunnel if it has one
+    pub async fn shutdown(&self) -> Result<(), NodeError> {
+        self.request(PeerCommand::Shutdown).await
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> PeerCommand,
+    ) -> Result<T, NodeError> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| self.gone())?;
+        response.await.map_err(|_| self.gone())
+    }
+
+    fn gone(&self) -> NodeError {
+        NodeError::PeerGone { peer: self.peer_id }
+    }
+}
+
+impl PeerActor {
+    async fn run(mut self, mut commands: mpsc::Receiver<PeerCommand>) {
+        while let Some(command) = commands.recv().await {
+            match command {
+                PeerCommand::Snapshot(reply) => {
+                    let _ = reply.send(self.connection.clone());
+                }
+                PeerCommand::SetStatus(status) => {
+                    self.connection.status = status;
+                }
+                PeerCommand::SetCapabilities(capabilities) => {
+                    self.connection.capabilities = capabilities;
+                    self.connection.last_seen = chrono::Utc::now();
+                }
+                PeerCommand::SetAddress(addr) => {
+                    self.connection.peer_addr = addr;
+                    self.connection.last_seen = chrono::Utc::now();
+                }
+                PeerCommand::Depart(departure, reply) => {
+                    match departure {
+                        Some(departure) => {
+                            self.close_tunnel().await;
+                            self.connection.status = ConnectionStatus::Departed;
+                            self.connection.departure = Some(departure);
+                        }
+                        None => {
+                            self.connection.status = ConnectionStatus::Disconnected;
+                            self.connection.departure = None;
+                        }
+                    }
+                    let _ = reply.send(());
+                }
+                PeerCommand::AdminDown(down, reply) => {
+                    if down {
+                        self.close_tunnel().await;
+                        self.connection.status = ConnectionStatus::AdminDown;
+                        self.connection.departure = None;
+                    } else if matches!(self.connection.status, ConnectionStatus::AdminDown) {
+                        self.connection.status = ConnectionStatus::Disconnected;
+                    }
+                    let _ = reply.send(());
+                }
+                PeerCommand::Quarantine(quarantined, reply) => {
+                    // Taking a peer down outranks quarantining it
+                    let admin_down = matches!(self.connection.status, ConnectionStatus::AdminDown);
+                    if quarantined && !admin_down {
+                        self.close_tunnel().await;
+                        self.connection.status = ConnectionStatus::Quarantined;
+                        self.connection.departure = None;
+                    } else if matches!(self.connection.status, ConnectionStatus::Quarantined) {
+                        self.connection.status = ConnectionStatus::Disconnected;
+                    }
+                    let _ = reply.send(());
+                }
+                PeerCommand::AttachTunnel(tunnel_id, reply) => {
+                    self.open_queue(tunnel_id).await;
+                    let _ = reply.send(self.tunnel.replace(tunnel_id));
+                }
+                PeerCommand::DetachTunnel(reply) => {
+                    self.queue = None;
+                    let _ = reply.send(self.tunnel.take());
+                }
+                PeerCommand::Tunnel(reply) => {
+                    let _ = reply.send(self.tunnel);
+                }
+                PeerCommand::Send { data, reply } => {
+                    let _ = reply.send(self.send(&data).await);
+                }
+                PeerCommand::Shutdown(reply) => {
+                    self.close().await;
+                    let _ = reply.send(());
+                    return;
+                }
+            }
+        }
+
+        // Every handle was dropped without an explicit shutdown
+        self.close().await;
+    }
+
+    /// Start the queue in front of the tunnel now carrying the peer's
+    /// datagrams; the previous tunnel's sends what it already holds
+    async fn open_queue(&mut self, tunnel_id: TunnelId) {
+        let sink = DatagramSink::new(Arc::clone(&self.tunnel_manager), tunnel_id);
+        let queue = SendQueue::spawn(sink, QueueConfig::default());
+        if let Err(e) = self.tunnel_manager.attach_queue(&tunnel_id, &queue).await {
+            tracing::debug!("No queue stats for tunnel {}: {}", tunnel_id, e);
+        }
+        self.queue = Some(queue);
+    }
+
+    async fn send(&mut self, data: &[u8]) -> Result<(), NodeError> {
+        let peer = self.connection.peer_id;
+        let queue = self.queue.as_ref().ok_or(NodeError::NoTunnel { peer })?;
+
+        queue
+            .send(TrafficClass::of(data), data.to_vec())
+            .await
+            .map_err(|source| NodeError::Tunnel { peer, source })?;
+
+        self.connection.metrics.bytes_sent += data.len() as u64;
+        Ok(())
+    }
+
+    async fn close(&mut self) {
+        self.connection.status = ConnectionStatus::Disconnected;
+        self.close_tunnel().await;
+        tracing::debug!("Peer task for {} stopped", self.connection.peer_id);
+    }
+
+    async fn close_tunnel(&mut self) {
+        // A goodbye sent just before goes out before the tunnel closes
+        if let Some(queue) = self.queue.take() {
+            if timeout(QUEUE_CLOSE_TIMEOUT, queue.close()).await.is_err() {
+                tracing::debug!(
+                    "Closing the tunnel to peer {} with datagrams still queued",
+                    self.connection.peer_id
+                );
+            }
+        }
+        if let Some(tunnel_id) = self.tunnel.take() {
+            if let Err(e) = self.tunnel_manager.close_tunnel(&tunnel_id).await {
+                tracing::warn!(
+                    "Failed to close tunnel {} for peer {}: {}",
+                    tunnel_id,
+                    self.connection.peer_id,
+                    e
+                );
+            }
+        }
+    }
+}
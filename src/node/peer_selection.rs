@@ -0,0 +1,512 @@
+//! Trading the slowest Regional peer for a quicker one.
+//!
+//! An Edge node keeps whichever Regionals it reached first, and with five
+//! peer slots that choice matters. Every `interval_secs` the node times a TCP
+//! connect to the BGP port of each Regional peer and of a sample of other
+//! Regionals from the directory that take new edges. A candidate that stays
+//! more than `margin_ms` quicker than the slowest peer for `sustain_secs`
+//! takes its place, make-before-break: the candidate is peered with and its
+//! tunnel established before the slowest peer is sent away with a goodbye.
+//! After a swap, or an attempt at one, none is tried for `cooldown_secs`.
+//!
+//! Regional nodes choose among their Regional peers the same way if
+//! `regional` is also set.
+
+use crate::config::PeerSelectionConfig;
+use crate::error::Report;
+use crate::monitoring::{crash, Subsystem};
+use crate::network::acl::Contact;
+use crate::network::bgp::protocol::BGPProtocol;
+use crate::network::ike::encap;
+use crate::network::ike::tunnels::TunnelId;
+use crate::node::goodbye::GoodbyeReason;
+use crate::node::joining::VX0_BGP_PORT;
+use crate::node::peer::PeerEvent;
+use crate::node::{ConnectionStatus, NodeError, NodeId, NodeTier, PeerConnection, Vx0Node};
+use crate::util::sync::lock;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+/// A peer or candidate and how quickly its BGP port accepted a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Measured {
+    pub node_id: NodeId,
+    pub asn: u32,
+    pub addr: IpAddr,
+    pub rtt: Duration,
+}
+
+/// The peer to drop and the candidate to take its place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Swap {
+    pub worst: Measured,
+    pub better: Measured,
+}
+
+/// Decides from rounds of measurements when a swap is due
+#[derive(Debug)]
+pub struct PeerSelector {
+    margin: Duration,
+    sustain: Duration,
+    cooldown: Duration,
+    /// Since when each candidate has been beating the slowest peer
+    leading_since: HashMap<NodeId, Instant>,
+    last_swap: Option<Instant>,
+}
+
+impl PeerSelector {
+    pub fn new(config: &PeerSelectionConfig) -> Self {
+        PeerSelector {
+            margin: Duration::from_millis(config.margin_ms),
+            sustain: Duration::from_secs(config.sustain_secs),
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            leading_since: HashMap::new(),
+            last_swap: None,
+        }
+    }
+
+    /// Weigh one round of measurements taken at `now`, returning the swap
+    /// to make if one is due
+    pub fn observe(
+        &mut self,
+        peers: &[Measured],
+        candidates: &[Measured],
+        now: Instant,
+    ) -> Option<Swap> {
+        let Some(worst) = peers.iter().max_by_key(|peer| peer.rtt) else {
+            self.leading_since.clear();
+            return None;
+        };
+        let leading: Vec<&Measured> = candidates
+            .iter()
+            .filter(|candidate| candidate.rtt + self.margin < worst.rtt)
+            .collect();
+
+        // A candidate that fell back starts over if it leads again
+        self.leading_since
+            .retain(|id, _| leading.iter().any(|candidate| candidate.node_id == *id));
+        for candidate in &leading {
+            self.leading_since.entry(candidate.node_id).or_insert(now);
+        }
+        if self.cooling_down(now) {
+            return None;
+        }
+
+        leading
+            .into_iter()
+            .filter(|candidate| {
+                now.duration_since(self.leading_since[&candidate.node_id]) >= self.sustain
+            })
+            .min_by_key(|candidate| candidate.rtt)
+            .map(|better| Swap {
+                worst: worst.clone(),
+                better: better.clone(),
+            })
+    }
+
+    pub fn cooling_down(&self, now: Instant) -> bool {
+        self.last_swap
+            .is_some_and(|at| now.duration_since(at) < self.cooldown)
+    }
+
+    /// Start the cooldown, after a swap or an attempt at one
+    pub fn record_swap(&mut self, now: Instant) {
+        self.last_swap = Some(now);
+        self.leading_since.clear();
+    }
+}
+
+/// Whether a node of `tier` chooses its peers by latency
+pub fn applies_to(config: &PeerSelectionConfig, tier: &NodeTier) -> bool {
+    config.enabled
+        && match tier {
+            NodeTier::Edge => true,
+            NodeTier::Regional => config.regional,
+            NodeTier::Backbone => false,
+        }
+}
+
+/// Time for `addr`'s BGP port to accept a connection, `None` if it did not
+/// within `limit`
+async fn probe(addr: IpAddr, limit: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    match tokio::time::timeout(
+        limit,
+        TcpStream::connect(SocketAddr::new(addr, VX0_BGP_PORT)),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        _ => None,
+    }
+}
+
+impl Vx0Node {
+    /// Probe peers and candidates every `interval_secs`, swapping the
+    /// slowest peer when a candidate has been quicker for long enough
+    pub fn start_peer_selection(self: &Arc<Self>) {
+        let config = self.config.network.peer_selection.clone();
+        if !applies_to(&config, &self.tier) {
+            return;
+        }
+        let node = Arc::clone(self);
+        let selector = Arc::new(Mutex::new(PeerSelector::new(&config)));
+
+        crash::spawn_restartable(Subsystem::Node, "peer-selection", move || {
+            let node = Arc::clone(&node);
+            let selector = Arc::clone(&selector);
+            let config = config.clone();
+            async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+                loop {
+                    interval.tick().await;
+                    node.select_peers(&selector, config.sample_size).await;
+                }
+            }
+        });
+    }
+
+    /// One round: measure, weigh, and swap if one is due
+    async fn select_peers(&self, selector: &Mutex<PeerSelector>, sample_size: usize) {
+        let limit = Duration::from_secs(self.config.joining.connect_timeout_secs);
+        let peers: Vec<Measured> = self
+            .list_peers()
+            .await
+            .into_iter()
+            .filter(|peer| {
+                NodeTier::from_asn(peer.peer_asn) == NodeTier::Regional
+                    && !matches!(
+                        peer.status,
+                        ConnectionStatus::Failed
+                            | ConnectionStatus::Departed
+                            | ConnectionStatus::AdminDown
+                            | ConnectionStatus::Quarantined
+                    )
+            })
+            .map(|peer| Measured {
+                node_id: peer.peer_id,
+                asn: peer.peer_asn,
+                addr: peer.peer_addr,
+                rtt: Duration::ZERO,
+            })
+            .collect();
+        let candidates = self.sample_candidates(&peers, sample_size).await;
+
+        let (peers, candidates) = tokio::join!(measure(peers, limit), measure(candidates, limit));
+        let swap = lock(selector).observe(&peers, &candidates, Instant::now());
+        let Some(swap) = swap else {
+            return;
+        };
+
+        lock(selector).record_swap(Instant::now());
+        if let Err(e) = self.swap_peer(&swap).await {
+            tracing::warn!(
+                "Could not swap peer {} for quicker {}: {}",
+                swap.worst.addr,
+                swap.better.addr,
+                Report(&e)
+            );
+        }
+    }
+
+    /// Up to `sample_size` Regionals from the directory, other than our
+    /// own peers, that we may peer with
+    async fn sample_candidates(&self, peers: &[Measured], sample_size: usize) -> Vec<Measured> {
+        let Some(dns) = self.directory.get() else {
+            return Vec::new();
+        };
+        let mut candidates: Vec<Measured> = dns
+            .read()
+            .await
+            .nodes()
+            .into_iter()
+            .filter(|entry| {
+                let addr = IpAddr::V4(entry.address);
+                entry.node_id != self.node_id
+                    && NodeTier::from_asn(entry.asn) == NodeTier::Regional
+                    && (self.tier != NodeTier::Edge || entry.capabilities.accepts_new_edges)
+                    && !self.admin.is_down(entry.asn)
+                    && !self.quarantine.is_demoted(entry.asn, self.clock.now_utc())
+                    && !peers
+                        .iter()
+                        .any(|peer| peer.node_id == entry.node_id || peer.addr == addr)
+                    && self
+                        .acl
+                        .permits(&Contact::node(entry.node_id, entry.asn, addr))
+            })
+            .map(|entry| Measured {
+                node_id: entry.node_id,
+                asn: entry.asn,
+                addr: IpAddr::V4(entry.address),
+                rtt: Duration::ZERO,
+            })
+            .collect();
+        candidates.shuffle(&mut rand::thread_rng());
+        candidates.truncate(sample_size);
+        candidates
+    }
+
+    /// Peer with the quicker candidate, then leave the slowest peer
+    pub async fn swap_peer(&self, swap: &Swap) -> Result<TunnelId, NodeError> {
+        if self.admin.is_down(swap.better.asn) {
+            return Err(NodeError::AdminDown {
+                asn: swap.better.asn,
+            });
+        }
+        if self
+            .quarantine
+            .is_quarantined(swap.better.asn, self.clock.now_utc())
+        {
+            return Err(NodeError::Quarantined {
+                asn: swap.better.asn,
+            });
+        }
+        let bgp_protocol = BGPProtocol::new(self.asn, self.ipv4_addr.into(), self.tier.clone())
+            .with_capabilities(self.capabilities())
+            .with_keepalive_jitter(self.config.security.obfuscation.keepalive_jitter());
+        let bgp_session = bgp_protocol
+            .connect_to_peer(
+                SocketAddr::new(swap.better.addr, VX0_BGP_PORT),
+                swap.better.asn,
+            )
+            .await?;
+        self.observe_address(swap.better.addr, bgp_session.observed_addr);
+
+        let mut peer = PeerConnection::new(swap.better.node_id, swap.better.asn, swap.better.addr);
+        peer.capabilities = bgp_session.peer_capabilities;
+        peer.metrics.latency_ms = u64::try_from(swap.better.rtt.as_millis()).unwrap_or(u64::MAX);
+        let (tunnel_addr, transport) = encap::tunnel_endpoint(
+            swap.better.addr,
+            VX0_BGP_PORT,
+            self.config.security.ike.listen_port,
+            &peer.capabilities,
+            self.config.network.single_port.prefer_tcp,
+        );
+        let tunnel_id = self.replace_peer(swap, peer, tunnel_addr).await?;
+        self.tunnel_manager
+            .set_transport(&tunnel_id, transport)
+            .await?;
+        Ok(tunnel_id)
+    }
+
+    /// Add `peer` in the slot of the swap's slowest peer and establish its
+    /// tunnel, and only then leave the slowest peer with a goodbye; if the
+    /// tunnel fails, the new peer is dropped and the old one kept
+    pub async fn replace_peer(
+        &self,
+        swap: &Swap,
+        peer: PeerConnection,
+        tunnel_addr: SocketAddr,
+    ) -> Result<TunnelId, NodeError> {
+        let (old, new) = (swap.worst.node_id, peer.peer_id);
+        self.add_peer_replacing(peer, &old).await?;
+        let tunnel_id = match self
+            .create_secure_tunnel(new, tunnel_addr, &self.tunnel_psk())
+            .await
+        {
+            Ok(tunnel_id) => tunnel_id,
+            Err(e) => {
+                self.remove_peer(&new).await?;
+                return Err(e);
+            }
+        };
+        self.disconnect_peer(old, GoodbyeReason::PeerRemoved)
+            .await?;
+
+        tracing::info!(
+            target: "audit",
+            "Swapped peer {} ({}, {:?}) for quicker peer {} ({}, {:?})",
+            old,
+            swap.worst.addr,
+            swap.worst.rtt,
+            new,
+            swap.better.addr,
+            swap.better.rtt
+        );
+        let _ = self.peer_events.send(PeerEvent::Swapped {
+            old,
+            new,
+            old_rtt: swap.worst.rtt,
+            new_rtt: swap.better.rtt,
+        });
+        Ok(tunnel_id)
+    }
+}
+
+/// Probe everything in `targets` at once, keeping those that answered
+async fn measure(targets: Vec<Measured>, limit: Duration) -> Vec<Measured> {
+    let probes = targets.into_iter().map(|mut target| async move {
+        target.rtt = probe(target.addr, limit).await?;
+        Some(target)
+    });
+    futures::future::join_all(probes)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn measured(last: u8, rtt_ms: u64) -> Measured {
+        Measured {
+            node_id: NodeId::from_u128(last as u128),
+            asn: 65100 + last as u32,
+            addr: IpAddr::from([10, 1, 0, last]),
+            rtt: Duration::from_millis(rtt_ms),
+        }
+    }
+
+    fn selector() -> PeerSelector {
+        PeerSelector::new(&PeerSelectionConfig {
+            margin_ms: 30,
+            sustain_secs: 600,
+            cooldown_secs: 3600,
+            ..PeerSelectionConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_swap_only_beyond_margin_and_sustain() {
+        let mut selector = selector();
+        let peers = [measured(1, 40), measured(2, 120)];
+        let start = Instant::now();
+
+        // 30ms quicker is not beyond the margin, however long it lasts
+        for minute in 0..20 {
+            let at = start + MINUTE * minute;
+            assert_eq!(selector.observe(&peers, &[measured(3, 90)], at), None);
+        }
+
+        // Quick enough, but not for long enough; falling back starts over
+        let quick = [measured(3, 50)];
+        for minute in 20..29 {
+            assert_eq!(
+                selector.observe(&peers, &quick, start + MINUTE * minute),
+                None
+            );
+        }
+        assert_eq!(
+            selector.observe(&peers, &[measured(3, 100)], start + MINUTE * 29),
+            None
+        );
+        for minute in 30..40 {
+            assert_eq!(
+                selector.observe(&peers, &quick, start + MINUTE * minute),
+                None
+            );
+        }
+
+        // Ten minutes ahead: the slowest peer goes for the quickest candidate
+        let swap = selector
+            .observe(
+                &peers,
+                &[measured(3, 50), measured(4, 20)],
+                start + MINUTE * 40,
+            )
+            .unwrap();
+        assert_eq!(swap.worst, measured(2, 120));
+        assert_eq!(swap.better, measured(3, 50));
+    }
+
+    #[test]
+    fn test_no_churn_within_cooldown() {
+        let mut selector = selector();
+        let start = Instant::now();
+        let peers = [measured(1, 40), measured(2, 120)];
+        let quick = [measured(3, 10)];
+        selector.observe(&peers, &quick, start);
+        assert!(selector
+            .observe(&peers, &quick, start + MINUTE * 10)
+            .is_some());
+        selector.record_swap(start + MINUTE * 10);
+
+        // After the swap, another candidate beats the new slowest peer
+        // throughout the cooldown without a second swap
+        let peers = [measured(1, 40), measured(3, 100)];
+        let quicker = [measured(4, 5)];
+        for minute in 11..70 {
+            assert!(selector.cooling_down(start + MINUTE * minute));
+            assert_eq!(
+                selector.observe(&peers, &quicker, start + MINUTE * minute),
+                None
+            );
+        }
+        let swap = selector
+            .observe(&peers, &quicker, start + MINUTE * 70)
+            .unwrap();
+        assert_eq!(swap.better, measured(4, 5));
+    }
+
+    #[test]
+    fn test_applies_to_edges_unless_disabled() {
+        let config = PeerSelectionConfig::default();
+        assert!(applies_to(&config, &NodeTier::Edge));
+        assert!(!applies_to(&config, &NodeTier::Regional));
+        assert!(!applies_to(&config, &NodeTier::Backbone));
+        let regional = PeerSelectionConfig {
+            regional: true,
+            ..PeerSelectionConfig::default()
+        };
+        assert!(applies_to(&regional, &NodeTier::Regional));
+        let disabled = PeerSelectionConfig {
+            enabled: false,
+            ..PeerSelectionConfig::default()
+        };
+        assert!(!applies_to(&disabled, &NodeTier::Edge));
+    }
+
+    #[tokio::test]
+    async fn test_replace_peer_keeps_within_max_peers() {
+        let config = testing::config(NodeTier::Edge);
+        let node = Vx0Node::new(config).unwrap();
+        for last in 1..=5 {
+            let peer = measured(last, 40);
+            node.add_peer(PeerConnection::new(peer.node_id, peer.asn, peer.addr))
+                .await
+                .unwrap();
+        }
+        let mut events = node.subscribe_peer_events();
+
+        let swap = Swap {
+            worst: measured(5, 120),
+            better: measured(6, 20),
+        };
+        let peer = PeerConnection::new(swap.better.node_id, swap.better.asn, swap.better.addr);
+        let addr = SocketAddr::new(swap.better.addr, 4500);
+        node.replace_peer(&swap, peer, addr).await.unwrap();
+
+        assert_eq!(node.get_peer_count().await, 5);
+        assert!(node.get_peer(&swap.worst.node_id).await.is_none());
+        assert_eq!(node.list_active_tunnels().await.len(), 1);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            PeerEvent::Removed { peer_id, .. } if peer_id == swap.worst.node_id
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            PeerEvent::Swapped { old, new, .. }
+                if old == swap.worst.node_id && new == swap.better.node_id
+        ));
+
+        // A sixth peer is still refused
+        let extra = measured(7, 10);
+        assert!(matches!(
+            node.add_peer(PeerConnection::new(extra.node_id, extra.asn, extra.addr))
+                .await,
+            Err(NodeError::PeerLimitReached { .. })
+        ));
+    }
+}
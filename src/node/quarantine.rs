@@ -0,0 +1,469 @@
+//! Peers cut off for misbehaving across subsystems.
+//!
+//! Wire limits, max-prefix, policy and peering checks each refuse what a
+//! peer sends on their own, and the peer gets a fresh chance from every one
+//! of them. Here their violations add up instead: each reported violation
+//! adds its kind's weight to the peer's score, and scores halve every
+//! `half_life`. Past `demote_score` the peer is demoted: its routes are only
+//! used when no other path exists, and its announcements are not acted on.
+//! Past `quarantine_score` it is quarantined for `cooldown`: its sessions and
+//! tunnel are closed, it is refused until the cooldown ends, and an audit
+//! entry is written and an event sent. `vx0net peers quarantine clear`
+//! lifts both at once.
+//!
+//! Scores are kept in the store, so a restart does not give a peer a clean
+//! slate.
+
+use crate::config::QuarantineConfig;
+use crate::error::Report;
+use crate::monitoring::{crash, Subsystem};
+use crate::node::{NodeError, PeerEvent, Vx0Node};
+use crate::storage::{Namespace, StorageError};
+use crate::util::sync::{read, write};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast;
+
+/// Store namespace holding peers' scores
+pub const NAMESPACE: &str = "quarantine";
+
+/// Violations kept per peer to show why it scored what it did
+pub const MAX_EVENTS: usize = 16;
+
+/// Scores below this are forgotten
+const FORGOTTEN_SCORE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ViolationKind {
+    /// A message that could not be decoded or broke a wire limit
+    Decode,
+    /// Many routes in one UPDATE refused by policy or the peering agreement
+    PolicyRejections,
+    /// A send time far off the clock the peer's UPDATE before showed
+    TimestampAnomaly,
+    /// Still sending while held back by a rate limit or cooldown
+    RateLimited,
+    /// More announcements than the peer is allowed, as past max-prefix
+    Flood,
+}
+
+impl ViolationKind {
+    /// Points a violation of this kind adds to the score
+    pub fn weight(self) -> f64 {
+        match self {
+            ViolationKind::Decode => 25.0,
+            ViolationKind::PolicyRejections => 10.0,
+            ViolationKind::TimestampAnomaly => 15.0,
+            ViolationKind::RateLimited => 5.0,
+            ViolationKind::Flood => 40.0,
+        }
+    }
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ViolationKind::Decode => "decode error",
+            ViolationKind::PolicyRejections => "policy rejections",
+            ViolationKind::TimestampAnomaly => "timestamp anomaly",
+            ViolationKind::RateLimited => "rate limited",
+            ViolationKind::Flood => "announcement flood",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub detail: String,
+    pub at: DateTime<Utc>,
+}
+
+/// What a peer's score currently costs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "standing", rename_all = "snake_case")]
+pub enum Standing {
+    Good,
+    /// Routes used only as a last resort; announcements not acted on
+    Demoted,
+    /// Sessions and tunnel closed, and refused until `until`
+    Quarantined {
+        until: DateTime<Utc>,
+    },
+}
+
+impl fmt::Display for Standing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Standing::Good => f.write_str("good"),
+            Standing::Demoted => f.write_str("demoted"),
+            Standing::Quarantined { until } => write!(
+                f,
+                "quarantined until {}",
+                until.format("%Y-%m-%d %H:%M:%S UTC")
+            ),
+        }
+    }
+}
+
+/// A peer's score as kept in the store, undecayed since `updated_at`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PeerScore {
+    asn: u32,
+    score: f64,
+    updated_at: DateTime<Utc>,
+    quarantined_until: Option<DateTime<Utc>>,
+    events: VecDeque<Violation>,
+}
+
+impl PeerScore {
+    fn decayed(&self, half_life: std::time::Duration, now: DateTime<Utc>) -> f64 {
+        let elapsed = (now - self.updated_at).to_std().unwrap_or_default();
+        if half_life.is_zero() {
+            return 0.0;
+        }
+        self.score * 0.5f64.powf(elapsed.as_secs_f64() / half_life.as_secs_f64())
+    }
+}
+
+/// A peer's score and standing, as shown to operators
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct QuarantineStatus {
+    pub asn: u32,
+    /// Decayed to now
+    pub score: f64,
+    #[serde(flatten)]
+    pub standing: Standing,
+    /// The most recent violations, oldest first
+    pub events: Vec<Violation>,
+}
+
+/// A peer's standing changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantineEvent {
+    pub asn: u32,
+    pub standing: Standing,
+    pub score: f64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuarantineError {
+    #[error("Cannot keep the quarantine score of AS{asn}")]
+    Persist {
+        asn: u32,
+        #[source]
+        source: StorageError,
+    },
+    #[error("Cannot restore quarantine scores")]
+    Restore(#[source] StorageError),
+}
+
+#[derive(Debug)]
+pub struct Quarantine {
+    config: RwLock<QuarantineConfig>,
+    scores: RwLock<BTreeMap<u32, PeerScore>>,
+    storage: RwLock<Option<Namespace>>,
+    events: broadcast::Sender<QuarantineEvent>,
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Quarantine::new(QuarantineConfig::default())
+    }
+}
+
+impl Quarantine {
+    pub fn new(config: QuarantineConfig) -> Self {
+        Quarantine {
+            config: RwLock::new(config),
+            scores: RwLock::new(BTreeMap::new()),
+            storage: RwLock::new(None),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    pub fn set_config(&self, config: QuarantineConfig) {
+        *write(&self.config) = config;
+    }
+
+    /// Restore scores from `storage`, and keep later changes there; returns
+    /// how many peers had one
+    pub fn open(&self, storage: Namespace) -> Result<usize, QuarantineError> {
+        let stored = storage
+            .iter_prefix::<PeerScore>("")
+            .map_err(QuarantineError::Restore)?;
+        let mut scores = write(&self.scores);
+        for (_, score) in stored {
+            scores.insert(score.asn, score);
+        }
+        *write(&self.storage) = Some(storage);
+        Ok(scores.len())
+    }
+
+    /// Changes of standing as violations arrive
+    pub fn subscribe(&self) -> broadcast::Receiver<QuarantineEvent> {
+        self.events.subscribe()
+    }
+
+    /// Add a violation by `asn` to its score, returning its standing when
+    /// that changed
+    pub fn record(
+        &self,
+        asn: u32,
+        kind: ViolationKind,
+        detail: impl Into<String>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Standing>, QuarantineError> {
+        let config = read(&self.config).clone();
+        if !config.enabled {
+            return Ok(None);
+        }
+        let detail = detail.into();
+        let mut scores = write(&self.scores);
+        let before = scores
+            .get(&asn)
+            .map_or(Standing::Good, |score| standing(&config, score, now));
+        let entry = scores.entry(asn).or_insert_with(|| PeerScore {
+            asn,
+            score: 0.0,
+            updated_at: now,
+            quarantined_until: None,
+            events: VecDeque::new(),
+        });
+        entry.score = entry.decayed(config.half_life.get(), now) + kind.weight();
+        entry.updated_at = now;
+        entry.events.push_back(Violation {
+            kind,
+            detail: detail.clone(),
+            at: now,
+        });
+        while entry.events.len() > MAX_EVENTS {
+            entry.events.pop_front();
+        }
+        let fresh = !matches!(before, Standing::Quarantined { .. });
+        if fresh && entry.score >= config.quarantine_score {
+            let cooldown =
+                chrono::Duration::from_std(config.cooldown.get()).unwrap_or(chrono::Duration::MAX);
+            entry.quarantined_until = Some(now + cooldown);
+        }
+        let after = standing(&config, entry, now);
+        let score = entry.score;
+        if let Some(storage) = &*read(&self.storage) {
+            storage
+                .put(&asn.to_string(), &*entry)
+                .map_err(|source| QuarantineError::Persist { asn, source })?;
+        }
+        drop(scores);
+
+        tracing::debug!("AS{}: {} ({}), score {:.0}", asn, kind, detail, score);
+        if after == before {
+            return Ok(None);
+        }
+        match after {
+            Standing::Quarantined { until } => tracing::warn!(
+                target: "audit",
+                "Quarantined AS{} until {} (score {:.0}, last: {})",
+                asn,
+                until.to_rfc3339(),
+                score,
+                detail
+            ),
+            _ => tracing::warn!("AS{} is now {} (score {:.0})", asn, after, score),
+        }
+        let _ = self.events.send(QuarantineEvent {
+            asn,
+            standing: after,
+            score,
+        });
+        Ok(Some(after))
+    }
+
+    pub fn standing(&self, asn: u32, now: DateTime<Utc>) -> Standing {
+        let config = read(&self.config);
+        read(&self.scores)
+            .get(&asn)
+            .map_or(Standing::Good, |score| standing(&config, score, now))
+    }
+
+    pub fn is_quarantined(&self, asn: u32, now: DateTime<Utc>) -> bool {
+        matches!(self.standing(asn, now), Standing::Quarantined { .. })
+    }
+
+    /// Demoted or quarantined
+    pub fn is_demoted(&self, asn: u32, now: DateTime<Utc>) -> bool {
+        self.standing(asn, now) != Standing::Good
+    }
+
+    /// Every peer with a score, by ASN; scores decayed below notice are
+    /// forgotten along the way
+    pub fn list(&self, now: DateTime<Utc>) -> Vec<QuarantineStatus> {
+        let config = read(&self.config).clone();
+        let mut scores = write(&self.scores);
+        let forgotten: Vec<u32> = scores
+            .values()
+            .filter(|score| {
+                score.decayed(config.half_life.get(), now) < FORGOTTEN_SCORE
+                    && standing(&config, score, now) == Standing::Good
+            })
+            .map(|score| score.asn)
+            .collect();
+        for asn in forgotten {
+            scores.remove(&asn);
+            if let Some(storage) = &*read(&self.storage) {
+                let _ = storage.delete(&asn.to_string());
+            }
+        }
+        scores
+            .values()
+            .map(|score| QuarantineStatus {
+                asn: score.asn,
+                score: score.decayed(config.half_life.get(), now),
+                standing: standing(&config, score, now),
+                events: score.events.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    /// Forget a peer's score, lifting any demotion or quarantine; returns
+    /// whether it had one
+    pub fn clear(&self, asn: u32) -> Result<bool, QuarantineError> {
+        let mut scores = write(&self.scores);
+        if let Some(storage) = &*read(&self.storage) {
+            storage
+                .delete(&asn.to_string())
+                .map_err(|source| QuarantineError::Persist { asn, source })?;
+        }
+        Ok(scores.remove(&asn).is_some())
+    }
+}
+
+fn standing(config: &QuarantineConfig, score: &PeerScore, now: DateTime<Utc>) -> Standing {
+    if let Some(until) = score.quarantined_until.filter(|until| *until > now) {
+        return Standing::Quarantined { until };
+    }
+    if score.decayed(config.half_life.get(), now) >= config.demote_score {
+        Standing::Demoted
+    } else {
+        Standing::Good
+    }
+}
+
+impl Vx0Node {
+    /// Close the tunnels of the peers on a quarantined ASN and tell
+    /// subscribers its routes should go; returns how many peers there were
+    pub async fn quarantine_peer(&self, asn: u32) -> Result<usize, NodeError> {
+        let mut closed = 0;
+        for handle in self.peer_handles().await {
+            if handle.peer_asn() == asn {
+                handle.set_quarantined(true).await?;
+                closed += 1;
+            }
+        }
+        let _ = self
+            .peer_events
+            .send(PeerEvent::Quarantined { peer_asn: asn });
+        Ok(closed)
+    }
+
+    /// Lift a peer's demotion or quarantine and let it reconnect; returns
+    /// whether it had a score
+    pub async fn clear_quarantine(&self, asn: u32) -> Result<bool, NodeError> {
+        let cleared = self.quarantine.clear(asn)?;
+        for handle in self.peer_handles().await {
+            if handle.peer_asn() == asn {
+                handle.set_quarantined(false).await?;
+            }
+        }
+        if cleared {
+            tracing::warn!(target: "audit", "Cleared the quarantine score of AS{}", asn);
+        }
+        Ok(cleared)
+    }
+
+    /// Act on peers crossing the quarantine threshold as their violations
+    /// are recorded
+    pub fn follow_quarantine(self: &Arc<Self>) {
+        let node = Arc::clone(self);
+        let mut events = self.quarantine.subscribe();
+        crash::spawn(Subsystem::Node, "quarantine-follower", async move {
+            loop {
+                match events.recv().await {
+                    Ok(QuarantineEvent {
+                        asn,
+                        standing: Standing::Quarantined { .. },
+                        ..
+                    }) => {
+                        if let Err(e) = node.quarantine_peer(asn).await {
+                            tracing::warn!(
+                                "Failed to cut off quarantined AS{}: {}",
+                                asn,
+                                Report(&e)
+                            );
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Missed {} quarantine events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Store;
+
+    #[test]
+    fn test_scores_decay_and_survive_reopening() {
+        let root = std::env::temp_dir().join(format!("vx0net-quarantine-{}", uuid::Uuid::new_v4()));
+        let namespace = || Store::open(&root).unwrap().namespace(NAMESPACE);
+        let start = Utc::now();
+
+        let quarantine = Quarantine::default();
+        quarantine.open(namespace()).unwrap();
+        for _ in 0..2 {
+            quarantine
+                .record(65101, ViolationKind::Decode, "bad frame", start)
+                .unwrap();
+        }
+        assert_eq!(quarantine.standing(65101, start), Standing::Demoted);
+
+        // A restart keeps the score, which halves every half-life
+        let reopened = Quarantine::default();
+        assert_eq!(reopened.open(namespace()).unwrap(), 1);
+        let half_life = chrono::Duration::minutes(10);
+        let status = &reopened.list(start + half_life)[0];
+        assert!((status.score - 25.0).abs() < 0.01, "{}", status.score);
+        assert_eq!(status.standing, Standing::Good);
+        assert_eq!(status.events.len(), 2);
+
+        assert!(reopened.list(start + half_life * 10).is_empty());
+        assert_eq!(Quarantine::default().open(namespace()).unwrap(), 0);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_disabled_scoring_records_nothing() {
+        let quarantine = Quarantine::new(QuarantineConfig {
+            enabled: false,
+            ..Default::default()
+        });
+        let now = Utc::now();
+        for _ in 0..10 {
+            quarantine
+                .record(66001, ViolationKind::Flood, "max-prefix", now)
+                .unwrap();
+        }
+        assert_eq!(quarantine.standing(66001, now), Standing::Good);
+    }
+}
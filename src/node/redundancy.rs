@@ -0,0 +1,626 @@
+//! Taking turns at maintenance within a redundancy group.
+//!
+//! Nodes deployed to stand in for each other, such as two Regional nodes
+//! at one site, list each other under `[node.redundancy]`. Before a member
+//! enters maintenance it asks every partner how it stands, one JSON message
+//! each way on the partner's redundancy port, sealed by the tunnel to it
+//! (see [`TunnelChannel`]), and is refused while another member is in
+//! maintenance, not ready, or within `hold_down` of leaving maintenance.
+//! `force` enters regardless, and says so in the audit log.
+//!
+//! A partner that cannot be asked does not hold maintenance up: during an
+//! outage, being able to work on the node that is left matters more. Two
+//! members asking at the same moment each report the other as entering
+//! maintenance, so both are refused rather than both let through.
+//!
+//! Partners are peers named by node ID. A query is answered only when the
+//! tunnel that opened it belongs to a listed partner, and an answer counts
+//! only when it came over the tunnel of the partner asked, whatever the
+//! addresses involved. No message may be longer than
+//! [`MAX_GROUP_MESSAGE_LEN`].
+
+use crate::config::{RedundancyConfig, RedundancyPartner};
+use crate::monitoring::{crash, Subsystem};
+use crate::network::ike::channel::TunnelChannel;
+use crate::network::ike::IKEError;
+use crate::node::{NodeId, Vx0Node};
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedundancyError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed redundancy message: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error(transparent)]
+    Tunnel(#[from] IKEError),
+    #[error("Partner {partner} is not a peer")]
+    NotPeer { partner: NodeId },
+    #[error("Partner address {address:?} is not ip:port")]
+    BadAddress {
+        address: String,
+        #[source]
+        source: AddrParseError,
+    },
+    #[error("No answer within {timeout:?}")]
+    Timeout { timeout: Duration },
+    #[error("Closed the connection without answering")]
+    NoAnswer,
+    #[error("Answered over the tunnel of {} in group {group:?}", .peer.map_or("no peer".to_string(), |peer| peer.to_string()))]
+    Mismatch { peer: Option<NodeId>, group: String },
+    #[error("{len}-byte message is longer than any query or answer")]
+    Oversized { len: usize },
+    #[error("Refused: {message}")]
+    Refused { message: String },
+    #[error("Group {group} is not ready for another member to go: {}", .members.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", "))]
+    Blocked {
+        group: String,
+        members: Vec<GroupMember>,
+    },
+}
+
+/// Longest message either side of a query may send
+pub const MAX_GROUP_MESSAGE_LEN: usize = 16 * 1024;
+
+/// How long a partner that asked has to send its next message
+const PARTNER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How a group member stands, as far as taking turns goes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum MemberState {
+    /// Ready to carry traffic
+    Active,
+    /// In maintenance, or about to enter it
+    Maintenance {
+        #[serde(default)]
+        expected_return: Option<DateTime<Utc>>,
+    },
+    /// Back from maintenance, but not for `hold_down` yet
+    HoldDown { until: DateTime<Utc> },
+    /// Running with readiness checks failing
+    NotReady { failing: Vec<String> },
+    /// Could not be asked; never holds maintenance up
+    Unreachable { reason: String },
+}
+
+impl MemberState {
+    /// Whether another member must wait for this one
+    pub fn blocks_maintenance(&self) -> bool {
+        matches!(
+            self,
+            MemberState::Maintenance { .. }
+                | MemberState::HoldDown { .. }
+                | MemberState::NotReady { .. }
+        )
+    }
+}
+
+impl fmt::Display for MemberState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemberState::Active => f.write_str("active"),
+            MemberState::Maintenance {
+                expected_return: Some(at),
+            } => write!(f, "in maintenance, back at {}", at),
+            MemberState::Maintenance {
+                expected_return: None,
+            } => f.write_str("in maintenance"),
+            MemberState::HoldDown { until } => write!(f, "held down until {}", until),
+            MemberState::NotReady { failing } => write!(f, "not ready ({})", failing.join(", ")),
+            MemberState::Unreachable { reason } => write!(f, "unreachable ({})", reason),
+        }
+    }
+}
+
+/// A partner and how it answered
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct GroupMember {
+    pub node_id: NodeId,
+    pub address: String,
+    #[serde(flatten)]
+    pub state: MemberState,
+}
+
+impl fmt::Display for GroupMember {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is {}", self.node_id, self.state)
+    }
+}
+
+/// This node's group and every member's state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct GroupStatus {
+    pub group: String,
+    pub local: MemberState,
+    pub members: Vec<GroupMember>,
+}
+
+/// Maintenance entered within a group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceEntry {
+    /// Peers left
+    pub peers: usize,
+    /// Partners that could not be asked
+    pub unreachable: Vec<GroupMember>,
+    /// Partners whose turn was overridden with `force`
+    pub overridden: Vec<GroupMember>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GroupMessage {
+    Query {
+        group: String,
+    },
+    State {
+        group: String,
+        #[serde(flatten)]
+        state: MemberState,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// This node's part in its redundancy group
+#[derive(Debug)]
+pub struct RedundancyGroup {
+    config: RedundancyConfig,
+    /// Held while partners are asked, so they see this node as going
+    claimed: AtomicBool,
+    expected_return: Mutex<Option<DateTime<Utc>>>,
+    left_maintenance: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl RedundancyGroup {
+    pub fn new(config: RedundancyConfig) -> Self {
+        RedundancyGroup {
+            config,
+            claimed: AtomicBool::new(false),
+            expected_return: Mutex::new(None),
+            left_maintenance: Mutex::new(None),
+        }
+    }
+
+    /// The group's name, when the node is in one
+    pub fn name(&self) -> Option<&str> {
+        self.config.group.as_deref()
+    }
+
+    pub fn partners(&self) -> &[RedundancyPartner] {
+        &self.config.partners
+    }
+
+    /// Whether `node_id` is a listed partner
+    pub fn is_partner(&self, node_id: NodeId) -> bool {
+        self.config
+            .partners
+            .iter()
+            .any(|partner| partner.node_id == node_id)
+    }
+
+    pub(crate) fn entered_maintenance(&self, expected_return: Option<DateTime<Utc>>) {
+        *lock(&self.expected_return) = expected_return;
+    }
+
+    pub(crate) fn left_maintenance(&self, at: DateTime<Utc>) {
+        *lock(&self.expected_return) = None;
+        *lock(&self.left_maintenance) = Some(at);
+    }
+
+    /// Until when partners still wait after this node's last maintenance
+    fn hold_down_until(&self) -> Option<DateTime<Utc>> {
+        let left = (*lock(&self.left_maintenance))?;
+        chrono::Duration::from_std(self.config.hold_down.get())
+            .ok()
+            .map(|hold_down| left + hold_down)
+    }
+
+    /// Ask every partner of `node` how it stands, all at once
+    pub async fn survey(&self, node: &Vx0Node) -> Vec<GroupMember> {
+        let Some(group) = self.name() else {
+            return Vec::new();
+        };
+        let limit = self.config.query_timeout.get();
+        futures::future::join_all(self.config.partners.iter().map(|partner| async move {
+            let state = ask(node, group, partner, limit).await.unwrap_or_else(|e| {
+                MemberState::Unreachable {
+                    reason: e.to_string(),
+                }
+            });
+            GroupMember {
+                node_id: partner.node_id,
+                address: partner.address.clone(),
+                state,
+            }
+        }))
+        .await
+    }
+}
+
+/// Ask one partner how it stands, over its tunnel
+async fn ask(
+    node: &Vx0Node,
+    group: &str,
+    partner: &RedundancyPartner,
+    limit: Duration,
+) -> Result<MemberState, RedundancyError> {
+    let address: SocketAddr =
+        partner
+            .address
+            .parse()
+            .map_err(|source| RedundancyError::BadAddress {
+                address: partner.address.clone(),
+                source,
+            })?;
+    let peer_addr = match node.get_peer(&partner.node_id).await {
+        Some(handle) => {
+            handle
+                .snapshot()
+                .await
+                .map_err(|_| RedundancyError::NotPeer {
+                    partner: partner.node_id,
+                })?
+                .peer_addr
+        }
+        None => {
+            return Err(RedundancyError::NotPeer {
+                partner: partner.node_id,
+            })
+        }
+    };
+    let exchange = async {
+        let stream = TcpStream::connect(address).await?;
+        let mut channel = TunnelChannel::new(stream, Arc::clone(&node.tunnel_manager), peer_addr);
+        send(
+            &mut channel,
+            &GroupMessage::Query {
+                group: group.to_string(),
+            },
+        )
+        .await?;
+        let answer = receive(&mut channel).await?;
+
+        // Whoever holds the tunnel that opened the answer gave it
+        let answered_by = match node.tunnel_manager.tunnel_to(peer_addr).await {
+            Some(tunnel_id) => node.peer_on_tunnel(tunnel_id, peer_addr).await,
+            None => None,
+        };
+        match answer.ok_or(RedundancyError::NoAnswer)? {
+            GroupMessage::State {
+                group: answered_group,
+                state,
+            } => {
+                if answered_group != group || answered_by != Some(partner.node_id) {
+                    return Err(RedundancyError::Mismatch {
+                        peer: answered_by,
+                        group: answered_group,
+                    });
+                }
+                Ok(state)
+            }
+            GroupMessage::Error { message } => Err(RedundancyError::Refused { message }),
+            GroupMessage::Query { .. } => Err(RedundancyError::NoAnswer),
+        }
+    };
+    timeout(limit, exchange)
+        .await
+        .map_err(|_| RedundancyError::Timeout { timeout: limit })?
+}
+
+/// The next message on `channel`, or `None` once the other end closed it
+async fn receive(channel: &mut TunnelChannel) -> Result<Option<GroupMessage>, RedundancyError> {
+    let Some(message) = channel.recv().await? else {
+        return Ok(None);
+    };
+    if message.len() > MAX_GROUP_MESSAGE_LEN {
+        return Err(RedundancyError::Oversized { len: message.len() });
+    }
+    Ok(Some(serde_json::from_slice(&message)?))
+}
+
+async fn send(channel: &mut TunnelChannel, message: &GroupMessage) -> Result<(), RedundancyError> {
+    Ok(channel.send(&serde_json::to_vec(message)?).await?)
+}
+
+impl Vx0Node {
+    /// How this node stands, as its partners are told
+    pub fn group_state(&self) -> MemberState {
+        let group = &self.redundancy;
+        if self.in_maintenance() || group.claimed.load(Ordering::SeqCst) {
+            return MemberState::Maintenance {
+                expected_return: *lock(&group.expected_return),
+            };
+        }
+        if let Some(until) = group.hold_down_until() {
+            if self.clock.now_utc() < until {
+                return MemberState::HoldDown { until };
+            }
+        }
+        let Some(report) = self.readiness.report() else {
+            return MemberState::NotReady {
+                failing: vec!["not checked yet".to_string()],
+            };
+        };
+        // Maintenance is known first-hand, and may have ended since
+        let failing: Vec<String> = report
+            .failing()
+            .into_iter()
+            .filter(|check| check.name != "maintenance")
+            .map(|check| format!("{}: {}", check.name, check.detail))
+            .collect();
+        if failing.is_empty() {
+            MemberState::Active
+        } else {
+            MemberState::NotReady { failing }
+        }
+    }
+
+    /// This node's group and its members' states, asking each partner
+    pub async fn group_status(&self) -> Option<GroupStatus> {
+        let group = self.redundancy.name()?.to_string();
+        Some(GroupStatus {
+            group,
+            local: self.group_state(),
+            members: self.redundancy.survey(self).await,
+        })
+    }
+
+    /// Enter maintenance once no partner is in maintenance, not ready or
+    /// held down after its own, unless `force`d. Partners that cannot be
+    /// asked are passed over with a warning.
+    pub async fn enter_maintenance_in_turn(
+        &self,
+        expected_return: Option<DateTime<Utc>>,
+        force: bool,
+    ) -> Result<MaintenanceEntry, RedundancyError> {
+        let group = &self.redundancy;
+        let Some(name) = group.name().map(str::to_string) else {
+            return Ok(MaintenanceEntry {
+                peers: self.enter_maintenance(expected_return).await,
+                unreachable: Vec::new(),
+                overridden: Vec::new(),
+            });
+        };
+
+        group.claimed.store(true, Ordering::SeqCst);
+        let members = group.survey(self).await;
+        let (blocking, others): (Vec<GroupMember>, Vec<GroupMember>) = members
+            .into_iter()
+            .partition(|member| member.state.blocks_maintenance());
+        if !blocking.is_empty() && !force {
+            group.claimed.store(false, Ordering::SeqCst);
+            return Err(RedundancyError::Blocked {
+                group: name,
+                members: blocking,
+            });
+        }
+
+        let unreachable: Vec<GroupMember> = others
+            .into_iter()
+            .filter(|member| matches!(member.state, MemberState::Unreachable { .. }))
+            .collect();
+        for member in &unreachable {
+            tracing::warn!(
+                "Entering maintenance without hearing from {} partner {}: {}",
+                name,
+                member.node_id,
+                member.state
+            );
+        }
+        if !blocking.is_empty() {
+            tracing::warn!(
+                target: "audit",
+                "Forcing maintenance out of turn in group {}: {}",
+                name,
+                blocking
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        let peers = self.enter_maintenance(expected_return).await;
+        group.claimed.store(false, Ordering::SeqCst);
+        Ok(MaintenanceEntry {
+            peers,
+            unreachable,
+            overridden: blocking,
+        })
+    }
+}
+
+/// Answer partners on `bind_addr`, returning the bound address
+pub async fn start(
+    node: Arc<Vx0Node>,
+    bind_addr: SocketAddr,
+) -> Result<SocketAddr, RedundancyError> {
+    let listener = TcpListener::bind(bind_addr).await?;
+    let local_addr = listener.local_addr()?;
+    serve(node, listener);
+    Ok(local_addr)
+}
+
+/// Answer partners connecting to `listener` until the task is aborted
+pub fn serve(node: Arc<Vx0Node>, listener: TcpListener) -> JoinHandle<Option<()>> {
+    if let Ok(addr) = listener.local_addr() {
+        tracing::info!(
+            "Redundancy group {} listening on {}",
+            node.redundancy.name().unwrap_or_default(),
+            addr
+        );
+    }
+    crash::spawn(Subsystem::Node, "redundancy", async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, caller)) => {
+                    let node = Arc::clone(&node);
+                    crash::spawn(Subsystem::Node, "redundancy-partner", async move {
+                        let Some(partner) = admits(&node, caller.ip()).await else {
+                            return;
+                        };
+                        let channel = TunnelChannel::new(
+                            stream,
+                            Arc::clone(&node.tunnel_manager),
+                            caller.ip(),
+                        );
+                        if let Err(e) = answer(&node, partner, channel).await {
+                            tracing::debug!("Redundancy partner {} left: {}", partner, e);
+                        }
+                    });
+                }
+                Err(e) => tracing::error!("Redundancy accept error: {}", e),
+            }
+        }
+    })
+}
+
+/// The partner calling from `addr`: a listed one whose tunnel the ACL lets
+/// in; only a partner's tunnel can open its queries
+async fn admits(node: &Vx0Node, addr: IpAddr) -> Option<NodeId> {
+    let Some(tunnel_id) = node.tunnel_manager.tunnel_to(addr).await else {
+        tracing::warn!(
+            target: "audit",
+            "Refused redundancy query from {}, no tunnel",
+            addr
+        );
+        return None;
+    };
+    let peer_id = node
+        .admits_tunnel_peer(tunnel_id, addr, "redundancy query")
+        .await?;
+    if !node.redundancy.is_partner(peer_id) {
+        tracing::warn!(
+            target: "audit",
+            "Refused redundancy query from peer {}, not a partner",
+            peer_id
+        );
+        return None;
+    }
+    Some(peer_id)
+}
+
+async fn answer(
+    node: &Vx0Node,
+    partner: NodeId,
+    mut channel: TunnelChannel,
+) -> Result<(), RedundancyError> {
+    let ours = node.redundancy.name().unwrap_or_default();
+
+    loop {
+        let message = timeout(PARTNER_IDLE_TIMEOUT, receive(&mut channel))
+            .await
+            .map_err(|_| RedundancyError::Timeout {
+                timeout: PARTNER_IDLE_TIMEOUT,
+            })??;
+        let Some(message) = message else {
+            return Ok(());
+        };
+        let reply = match message {
+            GroupMessage::Query { group } if group == ours => {
+                tracing::debug!("Partner {} asked how this node stands", partner);
+                GroupMessage::State {
+                    group,
+                    state: node.group_state(),
+                }
+            }
+            GroupMessage::Query { group } => GroupMessage::Error {
+                message: format!(
+                    "{} asked as a member of group {:?}, but this node is in {:?}",
+                    partner, group, ours
+                ),
+            },
+            other => GroupMessage::Error {
+                message: format!("Unexpected message: {:?}", other),
+            },
+        };
+        send(&mut channel, &reply).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitoring::readiness::ReadinessInputs;
+    use crate::node::testing;
+    use crate::node::{NodeTier, PeerConnection};
+    use crate::util::clock::ManualClock;
+
+    fn node(clock: &Arc<ManualClock>) -> Vx0Node {
+        let mut config = testing::config(NodeTier::Regional);
+        config.node.redundancy.group = Some("ams".to_string());
+        config.node.redundancy.hold_down = crate::config::units::ConfigDuration::from_secs(300);
+        Vx0Node::new(config).unwrap().with_clock(clock.clone())
+    }
+
+    fn ready(node: &Vx0Node, held_down: bool) {
+        node.readiness.observe(&ReadinessInputs {
+            tier: NodeTier::Regional,
+            established_peers: 8,
+            default_route: true,
+            pending: Vec::new(),
+            wall_clock: node.clock.now_utc(),
+            maintenance: node.in_maintenance(),
+            held_down,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_state_follows_maintenance_and_hold_down() {
+        let clock = Arc::new(ManualClock::new());
+        let node = node(&clock);
+        assert!(matches!(node.group_state(), MemberState::NotReady { .. }));
+        ready(&node, false);
+        assert_eq!(node.group_state(), MemberState::Active);
+
+        let back = node.clock.now_utc() + chrono::Duration::minutes(20);
+        node.enter_maintenance(Some(back)).await;
+        assert_eq!(
+            node.group_state(),
+            MemberState::Maintenance {
+                expected_return: Some(back)
+            }
+        );
+
+        // Peering again ends maintenance, and partners wait out the hold-down
+        node.add_peer(PeerConnection::new(
+            uuid::Uuid::new_v4(),
+            66001,
+            "10.3.0.1".parse().unwrap(),
+        ))
+        .await
+        .unwrap();
+        assert!(matches!(node.group_state(), MemberState::HoldDown { .. }));
+        clock.advance(Duration::from_secs(360));
+        assert_eq!(node.group_state(), MemberState::Active);
+
+        // The BGP hold-down after a restart is a readiness failure
+        ready(&node, true);
+        let MemberState::NotReady { failing } = node.group_state() else {
+            panic!("expected not ready");
+        };
+        assert!(failing[0].starts_with("hold_down"));
+        assert!(node.group_state().blocks_maintenance());
+    }
+
+    #[tokio::test]
+    async fn test_outside_a_group_maintenance_is_not_coordinated() {
+        let config = testing::config(NodeTier::Regional);
+        let node = Vx0Node::new(config).unwrap();
+        assert!(node.group_status().await.is_none());
+        let entry = node.enter_maintenance_in_turn(None, false).await.unwrap();
+        assert_eq!(entry.peers, 0);
+        assert!(node.in_maintenance());
+    }
+}
@@ -0,0 +1,340 @@
+//! Finding services by what they are rather than where they live.
+//!
+//! Every node lists its public services under `_services.vx0` with their
+//! type, tags and last known health, and the listing travels with zone sync
+//! like the rest of the directory. A search filters the local copy and can
+//! also ask directly connected DNS-serving peers, whose copies may be
+//! fresher; answers are merged by service ID, keeping the most recently
+//! updated listing. Peers only answer from their own copy, so a search never
+//! goes further than one hop.
+
+use crate::network::dns::{sync, SharedDns};
+use crate::node::{
+    ConnectionStatus, HostedService, NodeId, PeerConnection, ServiceStatus, ServiceType, Vx0Node,
+};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+/// Most peers a single search asks, closest first
+pub const FAN_OUT_PEERS: usize = 8;
+
+/// How long a search waits for peers' answers
+pub const FAN_OUT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A service as listed in the directory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ServiceSummary {
+    pub service_id: Uuid,
+    /// The node hosting the service
+    pub node_id: NodeId,
+    pub name: String,
+    pub service_type: ServiceType,
+    pub domain: String,
+    pub port: u16,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// `Failed` once the service's health check failed
+    pub status: ServiceStatus,
+    /// When the owner last listed it
+    pub updated: DateTime<Utc>,
+    /// Measured latency to the owning node, filled in by the searching node;
+    /// 0 for its own services
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+impl ServiceSummary {
+    pub fn new(service: &HostedService, node_id: NodeId, status: ServiceStatus) -> Self {
+        ServiceSummary {
+            service_id: service.service_id,
+            node_id,
+            name: service.name.clone(),
+            service_type: service.service_type.clone(),
+            domain: service.domain.clone(),
+            port: service.port,
+            tags: service.metadata.tags.clone(),
+            status,
+            updated: Utc::now(),
+            latency_ms: None,
+        }
+    }
+}
+
+/// What a search asks for; an empty filter matches every listed service
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceFilter {
+    #[serde(default)]
+    pub service_type: Option<ServiceType>,
+    /// Every one of these must be among the service's tags, ignoring case
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Order by latency to the owning node, unknown last
+    #[serde(default)]
+    pub nearby: bool,
+    /// Also ask directly connected DNS-serving peers
+    #[serde(default)]
+    pub fan_out: bool,
+    /// Only services hosted by this node, for the full list behind a
+    /// truncated announcement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<NodeId>,
+}
+
+impl ServiceFilter {
+    pub fn matches(&self, service: &ServiceSummary) -> bool {
+        let type_matches = self
+            .service_type
+            .as_ref()
+            .is_none_or(|wanted| wanted.matches(&service.service_type));
+        type_matches
+            && self
+                .node_id
+                .is_none_or(|node_id| node_id == service.node_id)
+            && self.tags.iter().all(|wanted| {
+                service
+                    .tags
+                    .iter()
+                    .any(|tag| tag.eq_ignore_ascii_case(wanted))
+            })
+    }
+}
+
+impl ServiceType {
+    /// Whether two types are the same, comparing custom names without regard
+    /// to case
+    pub fn matches(&self, other: &ServiceType) -> bool {
+        match (self, other) {
+            (ServiceType::Custom(a), ServiceType::Custom(b)) => a.eq_ignore_ascii_case(b),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+
+    /// The name of this type's SRV records under `domain`, such as
+    /// `_chat._tcp.community1.vx0`
+    pub fn srv_name(&self, domain: &str) -> String {
+        let label: String = self
+            .to_string()
+            .trim()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_lowercase()
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        format!("_{}._tcp.{}", label, domain)
+    }
+}
+
+/// Short names as typed on the command line; anything else is a custom type
+impl From<&str> for ServiceType {
+    fn from(s: &str) -> Self {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "web" | "webserver" => ServiceType::WebServer,
+            "email" | "emailserver" => ServiceType::EmailServer,
+            "file" | "fileserver" => ServiceType::FileServer,
+            "chat" | "chatserver" => ServiceType::ChatServer,
+            "database" | "db" => ServiceType::Database,
+            _ => ServiceType::Custom(s.trim().to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ServiceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ServiceType::WebServer => "web",
+            ServiceType::EmailServer => "email",
+            ServiceType::FileServer => "file",
+            ServiceType::ChatServer => "chat",
+            ServiceType::Database => "database",
+            ServiceType::Custom(name) => name,
+        })
+    }
+}
+
+impl Vx0Node {
+    /// Answer service searches from this directory
+    pub fn set_directory(&self, dns: SharedDns) {
+        if self.directory.set(dns).is_err() {
+            tracing::warn!("Node directory already set");
+        }
+    }
+
+    /// Listed services matching `filter`, one entry per service
+    pub async fn find_services(&self, filter: ServiceFilter) -> Vec<ServiceSummary> {
+        let mut listings = match self.directory.get() {
+            Some(dns) => dns.read().await.find_services(&filter),
+            None => Vec::new(),
+        };
+
+        let peers: Vec<PeerConnection> = self
+            .list_peers()
+            .await
+            .into_iter()
+            .filter(|peer| {
+                !matches!(
+                    peer.status,
+                    ConnectionStatus::Failed
+                        | ConnectionStatus::Departed
+                        | ConnectionStatus::AdminDown
+                        | ConnectionStatus::Quarantined
+                )
+            })
+            .collect();
+        if filter.fan_out {
+            listings.extend(self.ask_peers(&peers, &filter).await);
+        }
+
+        // A peer's answer is checked rather than trusted
+        let mut services = merge(
+            listings
+                .into_iter()
+                .filter(|service| filter.matches(service)),
+        );
+        for service in &mut services {
+            service.latency_ms = if service.node_id == self.node_id {
+                Some(0)
+            } else {
+                peers
+                    .iter()
+                    .find(|peer| peer.peer_id == service.node_id)
+                    .map(|peer| peer.metrics.latency_ms)
+                    .filter(|latency| *latency > 0)
+            };
+        }
+        if filter.nearby {
+            sort_nearby(&mut services);
+        }
+        services
+    }
+
+    /// What the closest DNS-serving peers list for `filter`; peers that do
+    /// not answer in time are skipped
+    async fn ask_peers(
+        &self,
+        peers: &[PeerConnection],
+        filter: &ServiceFilter,
+    ) -> Vec<ServiceSummary> {
+        let mut asked: Vec<&PeerConnection> = peers
+            .iter()
+            .filter(|peer| peer.capabilities.serves_dns)
+            .collect();
+        // Unmeasured latency reads as 0; ask those last
+        asked.sort_by_key(|peer| (peer.metrics.latency_ms == 0, peer.metrics.latency_ms));
+        asked.truncate(FAN_OUT_PEERS);
+
+        let sync_port = self.config.network.dns.sync_port;
+        let question = ServiceFilter {
+            nearby: false,
+            fan_out: false,
+            ..filter.clone()
+        };
+        let answers = futures::future::join_all(asked.into_iter().map(|peer| {
+            let address = SocketAddr::new(peer.peer_addr, sync_port);
+            let question = &question;
+            async move {
+                match timeout(FAN_OUT_TIMEOUT, sync::find_services(address, question)).await {
+                    Ok(Ok(services)) => services,
+                    Ok(Err(e)) => {
+                        tracing::debug!("Service search at {} failed: {}", address, e);
+                        Vec::new()
+                    }
+                    Err(_) => {
+                        tracing::debug!("Service search at {} timed out", address);
+                        Vec::new()
+                    }
+                }
+            }
+        }))
+        .await;
+        answers.into_iter().flatten().collect()
+    }
+}
+
+/// One entry per service, keeping the most recently updated listing
+pub fn merge(listings: impl IntoIterator<Item = ServiceSummary>) -> Vec<ServiceSummary> {
+    let mut merged: HashMap<Uuid, ServiceSummary> = HashMap::new();
+    for listing in listings {
+        match merged.get(&listing.service_id) {
+            Some(known) if known.updated >= listing.updated => {}
+            _ => {
+                merged.insert(listing.service_id, listing);
+            }
+        }
+    }
+    let mut services: Vec<ServiceSummary> = merged.into_values().collect();
+    services.sort_by(|a, b| {
+        a.domain
+            .cmp(&b.domain)
+            .then(a.service_id.cmp(&b.service_id))
+    });
+    services
+}
+
+/// Closest owners first, services whose owner's latency is unknown last
+pub fn sort_nearby(services: &mut [ServiceSummary]) {
+    services.sort_by_key(|service| (service.latency_ms.is_none(), service.latency_ms));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listing(service_type: ServiceType, tags: &[&str]) -> ServiceSummary {
+        ServiceSummary {
+            service_id: Uuid::new_v4(),
+            node_id: Uuid::new_v4(),
+            name: "svc".to_string(),
+            service_type,
+            domain: "svc.vx0".to_string(),
+            port: 80,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            status: ServiceStatus::Running,
+            updated: Utc::now(),
+            latency_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_filter_matches_type_and_tags_ignoring_case() {
+        let chat = listing(ServiceType::ChatServer, &["Community1", "irc"]);
+        let custom = listing(ServiceType::Custom("Matrix".to_string()), &["community1"]);
+
+        let by_type = |service_type: &str, tags: &[&str]| ServiceFilter {
+            service_type: Some(ServiceType::from(service_type)),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(by_type("chat", &["community1"]).matches(&chat));
+        assert!(by_type("CHAT", &["COMMUNITY1", "irc"]).matches(&chat));
+        assert!(!by_type("chat", &["community2"]).matches(&chat));
+        assert!(!by_type("web", &[]).matches(&chat));
+        assert!(by_type("matrix", &["community1"]).matches(&custom));
+        assert!(!by_type("matrix", &[]).matches(&chat));
+        assert!(!by_type("chat", &[]).matches(&custom));
+        assert!(ServiceFilter::default().matches(&custom));
+    }
+
+    #[test]
+    fn test_merge_keeps_newest_listing() {
+        let old = listing(ServiceType::WebServer, &[]);
+        let mut new = old.clone();
+        new.status = ServiceStatus::Failed;
+        new.updated = old.updated + chrono::Duration::seconds(5);
+        let other = listing(ServiceType::WebServer, &[]);
+
+        let merged = merge([old.clone(), new.clone(), old, other.clone()]);
+        assert_eq!(merged.len(), 2);
+        assert!(merged.contains(&new));
+        assert!(merged.contains(&other));
+    }
+}
@@ -0,0 +1,691 @@
+//! Lifetimes of hosted services.
+//!
+//! A published service lives for `service_ttl` seconds. Its DNS records carry
+//! that TTL, so resolvers (including secondaries that synced them) stop
+//! answering once it runs out, and the node's host route is withdrawn when no
+//! live service is left. Refreshing a service, by hand or because its health
+//! check passed, restarts the clock everywhere.
+//!
+//! Publishing can also wait for confirmation: directly connected peers that
+//! serve DNS are notified over zone sync and the call resolves once enough of
+//! them report storing the zone serial that carries the new records.
+//!
+//! Public services are also listed in the service directory, each refresh
+//! carrying the result of the latest health check, so searches elsewhere can
+//! tell a failing instance apart.
+//!
+//! Several services can share a domain, say web on 443 and chat on 6667
+//! under `community1.vx0`, as long as no two have the same type and port.
+//! They share its A and AAAA records while each has an SRV record of its
+//! own, which is checked, refreshed, listed and lapses with that service
+//! alone; the shared records go when the last service under the domain does.
+
+use crate::monitoring::{crash, Subsystem};
+use crate::network::bgp::{BGPDaemon, BGPOrigin, Prefix};
+use crate::network::dns::sync::ZoneSyncService;
+use crate::network::dns::SharedDns;
+use crate::node::metadata::{HealthCheckKind, HealthCheckSpec, Visibility};
+use crate::node::search::ServiceSummary;
+use crate::node::{HostedService, NodeError, NodeId, ServiceStatus, Vx0Node};
+use crate::util::clock;
+use crate::util::sync::lock;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Whether one DNS-serving peer confirmed storing a registration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PeerConfirmation {
+    pub peer_id: NodeId,
+    pub peer_asn: u32,
+    pub address: SocketAddr,
+    pub confirmed: bool,
+}
+
+/// Outcome of waiting for a registration to reach DNS-serving peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PropagationReport {
+    pub domain: String,
+    /// Zone serial the peers had to reach
+    pub serial: u32,
+    pub quorum: usize,
+    pub peers: Vec<PeerConfirmation>,
+}
+
+impl PropagationReport {
+    pub fn confirmed(&self) -> usize {
+        self.peers.iter().filter(|peer| peer.confirmed).count()
+    }
+
+    pub fn reached_quorum(&self) -> bool {
+        self.confirmed() >= self.quorum
+    }
+}
+
+pub struct ServiceRegistry {
+    node: Arc<Vx0Node>,
+    dns: SharedDns,
+    /// Set when host routes are originated for live services
+    bgp: Option<Arc<BGPDaemon>>,
+    /// Zone sync and the address peers pull from, for confirmed registration
+    sync: Option<(ZoneSyncService, SocketAddr)>,
+    /// When each service was last checked and whether it passed
+    checks: Mutex<HashMap<Uuid, (std::time::Instant, bool)>>,
+}
+
+impl ServiceRegistry {
+    pub fn new(node: Arc<Vx0Node>, dns: SharedDns) -> Self {
+        ServiceRegistry {
+            node,
+            dns,
+            bgp: None,
+            sync: None,
+            checks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Originate the node's host route while it has live services
+    pub fn with_host_routes(mut self, bgp: Arc<BGPDaemon>) -> Self {
+        self.bgp = Some(bgp);
+        self
+    }
+
+    /// Allow waiting for registrations to propagate; `primary` is where
+    /// `sync` serves transfers
+    pub fn with_propagation(mut self, sync: ZoneSyncService, primary: SocketAddr) -> Self {
+        self.sync = Some((sync, primary));
+        self
+    }
+
+    pub fn node(&self) -> &Arc<Vx0Node> {
+        &self.node
+    }
+
+    /// Every service this node hosts, listed or not, by domain and port
+    pub async fn hosted(&self) -> Vec<ServiceSummary> {
+        let mut hosted: Vec<ServiceSummary> = self
+            .node
+            .services
+            .read()
+            .await
+            .iter()
+            .map(|service| {
+                let mut summary =
+                    ServiceSummary::new(service, self.node.node_id, service.status.clone());
+                summary.latency_ms = Some(0);
+                summary
+            })
+            .collect();
+        hosted.sort_by(|a, b| (&a.domain, a.port).cmp(&(&b.domain, b.port)));
+        hosted
+    }
+
+    pub fn ttl(&self) -> chrono::Duration {
+        self.node.service_ttl()
+    }
+
+    fn host_route(&self) -> Prefix {
+        Prefix::from(IpAddr::V4(self.node.ipv4_addr))
+    }
+
+    /// List a public service in the service directory for another TTL
+    async fn list(&self, service: &HostedService) -> Result<(), NodeError> {
+        if service.metadata.visibility == Visibility::Unlisted {
+            return Ok(());
+        }
+        let summary = ServiceSummary::new(service, self.node.node_id, service.status.clone());
+        Ok(self
+            .dns
+            .write()
+            .await
+            .list_service(&summary, self.listing_ttl())?)
+    }
+
+    fn listing_ttl(&self) -> u32 {
+        self.node
+            .config
+            .services
+            .service_ttl
+            .as_secs()
+            .min(u32::MAX as u64) as u32
+    }
+
+    /// Register a service and publish its records (and host route)
+    pub async fn publish(&self, service: HostedService) -> Result<(), NodeError> {
+        self.node.register_service(service.clone()).await?;
+        self.node
+            .publish_service_dns(&service, &mut *self.dns.write().await)?;
+        self.list(&service).await?;
+
+        if let Some(bgp) = &self.bgp {
+            bgp.add_route(
+                self.host_route(),
+                IpAddr::V4(self.node.ipv4_addr),
+                BGPOrigin::IGP,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Undo `publish`: drop the service, its records and listing, and the
+    /// host route once no services are left
+    pub async fn unpublish(&self, service_id: Uuid) -> Result<(), NodeError> {
+        let service = self
+            .node
+            .unregister_service(service_id)
+            .await
+            .ok_or_else(|| NodeError::Service(format!("Unknown service {}", service_id)))?;
+        lock(&self.checks).remove(&service_id);
+        {
+            let mut dns = self.dns.write().await;
+            dns.deregister_srv(&service.srv_name(), &service.srv_target());
+            // Other live services may share the domain's addresses
+            if self.node.find_service(&service.domain).await.is_none() {
+                dns.deregister_service(&service.domain)?;
+            }
+            dns.unlist_service(service_id);
+        }
+
+        if let Some(bgp) = &self.bgp {
+            if self.node.services.read().await.is_empty() {
+                bgp.withdraw_route(&self.host_route()).await?;
+            }
+        }
+        tracing::info!("Unpublished service {}", service.domain);
+        Ok(())
+    }
+
+    /// Publish a service, then wait until `quorum` directly connected
+    /// DNS-serving peers confirm storing its records or `timeout` passes.
+    /// The report says which peers confirmed either way.
+    pub async fn register_service_confirmed(
+        &self,
+        service: HostedService,
+        quorum: usize,
+        timeout: Duration,
+    ) -> Result<PropagationReport, NodeError> {
+        let (sync, primary) = self
+            .sync
+            .as_ref()
+            .ok_or_else(|| NodeError::Service("Zone sync is not running".to_string()))?;
+
+        let domain = service.domain.clone();
+        self.publish(service).await?;
+        let zone = self
+            .dns
+            .read()
+            .await
+            .zone_for(&domain)
+            .ok_or_else(|| NodeError::Service(format!("No zone holds {}", domain)))?;
+
+        let sync_port = self.node.config.network.dns.sync_port;
+        let peers: Vec<_> = self
+            .node
+            .list_peers()
+            .await
+            .into_iter()
+            .filter(|peer| peer.capabilities.serves_dns)
+            .map(|peer| {
+                (
+                    peer.peer_id,
+                    peer.peer_asn,
+                    SocketAddr::new(peer.peer_addr, sync_port),
+                )
+            })
+            .collect();
+        let secondaries: Vec<SocketAddr> = peers.iter().map(|(_, _, address)| *address).collect();
+
+        let propagation = sync
+            .propagate(&zone, *primary, &secondaries, quorum, timeout)
+            .await?;
+        let report = PropagationReport {
+            domain,
+            serial: propagation.serial,
+            quorum,
+            peers: peers
+                .into_iter()
+                .map(|(peer_id, peer_asn, address)| PeerConfirmation {
+                    peer_id,
+                    peer_asn,
+                    address,
+                    confirmed: propagation.confirmed.contains(&address),
+                })
+                .collect(),
+        };
+        tracing::info!(
+            "{} confirmed by {}/{} DNS peers (quorum {})",
+            report.domain,
+            report.confirmed(),
+            report.peers.len(),
+            quorum
+        );
+        Ok(report)
+    }
+
+    /// Keep a service, its SRV record, the addresses of its domain and the
+    /// host route alive for another TTL; other services under the domain
+    /// keep their own clocks
+    pub async fn refresh(&self, service_id: Uuid) -> Result<HostedService, NodeError> {
+        let service = self.node.refresh_service(service_id).await?;
+        {
+            let now = self.node.clock.now_utc();
+            let mut dns = self.dns.write().await;
+            dns.refresh_service(&service.domain, now)?;
+            dns.refresh_srv(&service.srv_name(), &service.srv_target(), now)?;
+        }
+        self.list(&service).await?;
+        Ok(service)
+    }
+
+    /// Refresh every service under a domain
+    pub async fn refresh_domain(&self, domain: &str) -> Result<Vec<HostedService>, NodeError> {
+        let services = self.node.services_at(domain).await;
+        if services.is_empty() {
+            return Err(NodeError::Service(format!("No live service at {}", domain)));
+        }
+        let mut refreshed = Vec::with_capacity(services.len());
+        for service in services {
+            refreshed.push(self.refresh(service.service_id).await?);
+        }
+        Ok(refreshed)
+    }
+
+    /// Retire services whose TTL lapsed, returning them; `now` only dates
+    /// the DNS records removed, leases lapse on the node's monotonic clock
+    pub async fn expire(&self, now: DateTime<Utc>) -> Vec<HostedService> {
+        let expired = self.node.expire_services().await;
+        if expired.is_empty() {
+            return expired;
+        }
+
+        lock(&self.checks).retain(|id, _| !expired.iter().any(|service| service.service_id == *id));
+
+        // Journaled removal, so secondaries drop the records on their next sync
+        let removed = self.dns.write().await.expire_records(now);
+        for service in &expired {
+            tracing::info!(
+                "Service {} expired after {}s without refresh",
+                service.domain,
+                self.node.service_ttl().num_seconds()
+            );
+        }
+        tracing::debug!("Removed {} expired DNS records", removed);
+
+        if let Some(bgp) = &self.bgp {
+            if self.node.services.read().await.is_empty() {
+                if let Err(e) = bgp.withdraw_route(&self.host_route()).await {
+                    tracing::warn!("Failed to withdraw host route: {}", e);
+                }
+            }
+        }
+        expired
+    }
+
+    /// Refresh every running service that passes its health check, by
+    /// default answering on its port
+    pub async fn refresh_healthy(&self) {
+        let services = self.node.services.read().await.clone();
+        for service in services {
+            if !matches!(service.status, ServiceStatus::Running) {
+                continue;
+            }
+            let spec = service.metadata.health_check.as_ref();
+            // Within its interval a service keeps the result of its last check
+            let recent = lock(&self.checks)
+                .get(&service.service_id)
+                .copied()
+                .filter(|(at, _)| {
+                    spec.and_then(|spec| spec.interval)
+                        .is_some_and(|interval| at.elapsed() < interval)
+                })
+                .map(|(_, healthy)| healthy);
+            let healthy = match recent {
+                Some(healthy) => healthy,
+                None => {
+                    let addr = SocketAddr::new(IpAddr::V4(self.node.ipv4_addr), service.port);
+                    let healthy = timeout(HEALTH_CHECK_TIMEOUT, check_health(spec, addr, &service))
+                        .await
+                        .unwrap_or(false);
+                    lock(&self.checks)
+                        .insert(service.service_id, (std::time::Instant::now(), healthy));
+                    healthy
+                }
+            };
+            if !healthy {
+                tracing::debug!("Health check failed for {}; not refreshing", service.domain);
+                self.list_failed(&service).await;
+                continue;
+            }
+            if let Err(e) = self.refresh(service.service_id).await {
+                tracing::warn!("Failed to refresh {}: {}", service.domain, e);
+            }
+        }
+    }
+
+    /// Mark a service that failed its health check in the service directory,
+    /// without extending its listing's lifetime
+    async fn list_failed(&self, service: &HostedService) {
+        let listed = self
+            .dns
+            .read()
+            .await
+            .services()
+            .into_iter()
+            .find(|listing| listing.service_id == service.service_id);
+        let Some(listed) = listed.filter(|listing| listing.status != ServiceStatus::Failed) else {
+            return;
+        };
+        let ttl = self.listing_ttl();
+        let remaining = (listed.updated + chrono::Duration::seconds(ttl as i64) - Utc::now())
+            .num_seconds()
+            .clamp(0, ttl as i64) as u32;
+        let failed = ServiceSummary::new(service, self.node.node_id, ServiceStatus::Failed);
+        if let Err(e) = self.dns.write().await.list_service(&failed, remaining) {
+            tracing::warn!("Failed to list {} as failed: {}", service.domain, e);
+        }
+    }
+
+    /// Run health-check refreshes and expiry in the background
+    pub fn start(self: Arc<Self>) {
+        // Check often enough that a service is never a full TTL stale
+        let period =
+            Duration::from_secs((self.node.config.services.service_ttl.as_secs() / 3).max(1));
+        crash::spawn_restartable(Subsystem::Services, "service-lifetimes", move || {
+            let registry = Arc::clone(&self);
+            async move {
+                let clock = Arc::clone(&registry.node.clock);
+                let mut interval = clock::interval(&clock, period);
+                loop {
+                    interval.tick().await;
+                    registry.refresh_healthy().await;
+                    registry.expire(clock.now_utc()).await;
+                }
+            }
+        });
+    }
+}
+
+/// Whether a service answers the way its spec asks
+async fn check_health(
+    spec: Option<&HealthCheckSpec>,
+    addr: SocketAddr,
+    service: &HostedService,
+) -> bool {
+    let Ok(mut stream) = tokio::net::TcpStream::connect(addr).await else {
+        return false;
+    };
+    if !matches!(spec, Some(spec) if spec.kind == HealthCheckKind::Http) {
+        return true;
+    }
+
+    let path = spec.map(HealthCheckSpec::path).unwrap_or("/");
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, service.domain
+    );
+    if stream.write_all(request.as_bytes()).await.is_err() {
+        return false;
+    }
+    let mut status_line = String::new();
+    if BufReader::new(stream)
+        .read_line(&mut status_line)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    // "HTTP/1.1 204 No Content"
+    matches!(
+        status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok()),
+        Some(200..=399)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::units::ConfigDuration;
+    use crate::network::dns::sync::ZoneSyncService;
+    use crate::network::dns::Vx0DNS;
+    use crate::node::metadata::ServiceMetadata;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use crate::node::{PeerConnection, ServiceType};
+
+    fn node(service_ttl: u64) -> Arc<Vx0Node> {
+        testing::node(NodeTier::Edge, |config| {
+            config.services.service_ttl = ConfigDuration::from_secs(service_ttl);
+        })
+    }
+
+    fn service(domain: &str) -> HostedService {
+        HostedService {
+            service_id: Uuid::new_v4(),
+            name: "wiki".to_string(),
+            service_type: ServiceType::WebServer,
+            domain: domain.to_string(),
+            port: 8080,
+            status: ServiceStatus::Running,
+            metadata: ServiceMetadata::default(),
+        }
+    }
+
+    async fn sync(remote: &ZoneSyncService, primary: SocketAddr) {
+        remote.pull_from(primary, "vx0").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_service_lapses_without_refresh() {
+        let node = node(2);
+        let dns = Vx0DNS::new().into_shared();
+        let bgp = Arc::new(BGPDaemon::new(node.asn, node.ipv4_addr.into(), 0));
+        let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns))
+            .with_host_routes(Arc::clone(&bgp));
+
+        let primary_addr = ZoneSyncService::new(Arc::clone(&dns))
+            .start("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let remote_dns = Vx0DNS::new().into_shared();
+        let remote = ZoneSyncService::new(Arc::clone(&remote_dns));
+
+        let host: IpAddr = node.ipv4_addr.into();
+        registry.publish(service("wiki.vx0")).await.unwrap();
+        sync(&remote, primary_addr).await;
+        assert_eq!(
+            remote_dns.read().await.resolve_vx0_domain("wiki.vx0").await,
+            Some(host)
+        );
+        assert!(bgp.find_best_route(&host).await.is_some());
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        // Stale answers stop even before anything is swept or synced
+        assert_eq!(
+            remote_dns.read().await.resolve_vx0_domain("wiki.vx0").await,
+            None
+        );
+
+        assert_eq!(registry.expire(Utc::now()).await.len(), 1);
+        sync(&remote, primary_addr).await;
+        assert_eq!(dns.read().await.resolve_vx0_domain("wiki.vx0").await, None);
+        assert!(remote_dns.read().await.get_records("wiki.vx0").is_none());
+        assert!(bgp.find_best_route(&host).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_keeps_service_alive() {
+        let node = node(2);
+        let dns = Vx0DNS::new().into_shared();
+        let bgp = Arc::new(BGPDaemon::new(node.asn, node.ipv4_addr.into(), 0));
+        let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns))
+            .with_host_routes(Arc::clone(&bgp));
+
+        let primary_addr = ZoneSyncService::new(Arc::clone(&dns))
+            .start("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let remote_dns = Vx0DNS::new().into_shared();
+        let remote = ZoneSyncService::new(Arc::clone(&remote_dns));
+
+        let host: IpAddr = node.ipv4_addr.into();
+        registry.publish(service("chat.vx0")).await.unwrap();
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(1200)).await;
+            registry.refresh_domain("chat.vx0").await.unwrap();
+            sync(&remote, primary_addr).await;
+            assert!(registry.expire(Utc::now()).await.is_empty());
+        }
+
+        assert_eq!(
+            dns.read().await.resolve_vx0_domain("chat.vx0").await,
+            Some(host)
+        );
+        assert_eq!(
+            remote_dns.read().await.resolve_vx0_domain("chat.vx0").await,
+            Some(host)
+        );
+        assert!(bgp.find_best_route(&host).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_services_sharing_a_domain_lapse_alone() {
+        let node = node(2);
+        let dns = Vx0DNS::new().into_shared();
+        let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns));
+
+        let web = service("community1.vx0");
+        let mut chat = service("community1.vx0");
+        chat.service_type = ServiceType::ChatServer;
+        chat.port = 6667;
+        registry.publish(web.clone()).await.unwrap();
+        registry.publish(chat.clone()).await.unwrap();
+
+        // Only the web service keeps being refreshed
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(1200)).await;
+            registry.refresh(web.service_id).await.unwrap();
+        }
+        let expired = registry.expire(Utc::now()).await;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].service_id, chat.service_id);
+
+        let dns = dns.read().await;
+        assert!(dns.lookup_srv(&chat.srv_name()).is_empty());
+        assert_eq!(dns.lookup_srv(&web.srv_name()), vec![web.srv_target()]);
+        assert_eq!(
+            dns.resolve_vx0_domain("community1.vx0").await,
+            Some(node.ipv4_addr.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_registration_names_unconfirmed_peers() {
+        // The up peer serves zone sync on 127.0.0.2; the down one would use
+        // the same port on 127.0.0.3, where nothing listens
+        let up_dns = Vx0DNS::new().into_shared();
+        let up_addr = ZoneSyncService::new(Arc::clone(&up_dns))
+            .start("127.0.0.2:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut config = testing::config(NodeTier::Edge);
+        config.network.dns.sync_port = up_addr.port();
+        let node = Arc::new(Vx0Node::new(config).unwrap());
+        let mut up = PeerConnection::new(Uuid::new_v4(), 65101, up_addr.ip());
+        let mut down = PeerConnection::new(Uuid::new_v4(), 65102, "127.0.0.3".parse().unwrap());
+        up.capabilities.serves_dns = true;
+        down.capabilities.serves_dns = true;
+        let (up_id, down_id) = (up.peer_id, down.peer_id);
+        node.add_peer(up).await.unwrap();
+        node.add_peer(down).await.unwrap();
+
+        let dns = Vx0DNS::new().into_shared();
+        let sync = ZoneSyncService::new(Arc::clone(&dns));
+        let primary = sync.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns))
+            .with_propagation(sync, primary);
+
+        let report = registry
+            .register_service_confirmed(service("wiki.vx0"), 2, Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert!(!report.reached_quorum());
+        assert_eq!(report.confirmed(), 1);
+        let confirmed: HashMap<NodeId, bool> = report
+            .peers
+            .iter()
+            .map(|peer| (peer.peer_id, peer.confirmed))
+            .collect();
+        assert!(confirmed[&up_id]);
+        assert!(!confirmed[&down_id]);
+        assert_eq!(up_dns.read().await.serial("vx0"), Some(report.serial));
+        assert!(up_dns.read().await.get_records("wiki.vx0").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_health_checks_follow_the_typed_spec() {
+        use crate::node::metadata::{HealthCheckKind, HealthCheckSpec};
+        use tokio::io::AsyncReadExt;
+
+        // Answers /up with 200 and anything else with 503
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 512];
+                let read = stream.read(&mut request).await.unwrap_or(0);
+                let status = if request[..read].starts_with(b"GET /up ") {
+                    "200 OK"
+                } else {
+                    "503 Service Unavailable"
+                };
+                let reply = format!("HTTP/1.0 {}\r\n\r\n", status);
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+
+        let mut config = testing::config(NodeTier::Edge);
+        config.node.ipv4_address = "127.0.0.1".to_string();
+        let node = Arc::new(Vx0Node::new(config).unwrap());
+        let registry = ServiceRegistry::new(Arc::clone(&node), Vx0DNS::new().into_shared());
+
+        let checked = |domain: &str, kind, path: Option<&str>| {
+            let mut service = service(domain);
+            service.port = port;
+            service.metadata.health_check = Some(HealthCheckSpec {
+                kind,
+                path: path.map(str::to_string),
+                interval: None,
+            });
+            service
+        };
+        let mut ids = Vec::new();
+        for service in [
+            checked("up.vx0", HealthCheckKind::Http, Some("/up")),
+            checked("down.vx0", HealthCheckKind::Http, Some("/down")),
+            checked("open.vx0", HealthCheckKind::Tcp, None),
+        ] {
+            ids.push(service.service_id);
+            registry.publish(service).await.unwrap();
+        }
+        let before = node.service_leases.read().await.clone();
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        registry.refresh_healthy().await;
+        let after = node.service_leases.read().await.clone();
+        let refreshed: Vec<bool> = ids.iter().map(|id| after[id] > before[id]).collect();
+        assert_eq!(refreshed, vec![true, false, true]);
+    }
+}
@@ -0,0 +1,23 @@
+//! Node fixtures shared by the unit tests.
+
+use super::{NodeTier, Vx0Node};
+use crate::config::Vx0Config;
+use std::sync::Arc;
+
+/// The configuration shipped in `config/` for a node of `tier`
+pub(crate) fn config(tier: NodeTier) -> Vx0Config {
+    let shipped = match tier {
+        NodeTier::Backbone => include_str!("../../config/backbone-node.toml"),
+        NodeTier::Regional => include_str!("../../config/regional-node.toml"),
+        NodeTier::Edge => include_str!("../../config/edge-node.toml"),
+    };
+    toml::from_str(shipped).unwrap()
+}
+
+/// A node of `tier` built from its shipped configuration once `configure`
+/// has adjusted it
+pub(crate) fn node(tier: NodeTier, configure: impl FnOnce(&mut Vx0Config)) -> Arc<Vx0Node> {
+    let mut config = config(tier);
+    configure(&mut config);
+    Arc::new(Vx0Node::new(config).unwrap())
+}
@@ -0,0 +1,325 @@
+//! Establishing tunnels to peers.
+//!
+//! Only one establishment per peer runs at a time: callers arriving while
+//! one is under way wait for it and get the same tunnel. When the peer
+//! initiated a tunnel toward us as well, the one initiated by the lower node
+//! ID is kept and the other closed, so both ends settle on the same tunnel.
+
+use crate::network::ike::tunnels::TunnelId;
+use crate::node::{NodeError, NodeId, NodeTier, PeerHandle, Vx0Node};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How an establishment ended, as seen by the callers that joined it
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Established {
+    Tunnel(TunnelId),
+    TimedOut,
+    Failed,
+}
+
+/// Settles once the establishment it belongs to finishes
+pub(crate) type InFlight = watch::Receiver<Option<Established>>;
+
+impl Vx0Node {
+    /// Give up on peers that do not complete negotiation within `timeout`
+    pub fn with_tunnel_timeout(mut self, timeout: Duration) -> Self {
+        self.tunnel_timeout = timeout;
+        self
+    }
+
+    /// Negotiate a tunnel to a peer, replacing any it already has
+    ///
+    /// Joins an establishment to the same peer that is already under way
+    /// rather than starting a second one.
+    pub async fn create_secure_tunnel(
+        &self,
+        peer_id: NodeId,
+        peer_addr: SocketAddr,
+        psk: &[u8],
+    ) -> Result<TunnelId, NodeError> {
+        let handle = self
+            .get_peer(&peer_id)
+            .await
+            .ok_or(NodeError::UnknownPeer { peer: peer_id })?;
+
+        let done = {
+            let mut establishing = self.establishing.lock().await;
+            match establishing.get(&peer_id) {
+                // A sender that is gone belongs to a caller that was cancelled
+                Some(in_flight) if in_flight.has_changed().is_ok() => Err(in_flight.clone()),
+                _ => {
+                    let (done, in_flight) = watch::channel(None);
+                    establishing.insert(peer_id, in_flight);
+                    Ok(done)
+                }
+            }
+        };
+        let done = match done {
+            Ok(done) => done,
+            Err(in_flight) => {
+                tracing::debug!("Joining tunnel establishment to peer {}", peer_id);
+                return self.join(peer_id, in_flight).await;
+            }
+        };
+
+        tracing::info!(
+            "Creating secure tunnel to peer {} at {}",
+            peer_id,
+            peer_addr
+        );
+        let created = tokio::time::timeout(
+            self.tunnel_timeout,
+            self.tunnel_manager.create_tunnel(
+                IpAddr::V4(self.ipv4_addr),
+                peer_addr.ip(),
+                peer_addr,
+                psk,
+            ),
+        )
+        .await;
+
+        // Installing the tunnel and retiring the guard happen together, so
+        // nobody sees the peer without either
+        let mut establishing = self.establishing.lock().await;
+        let result = match created {
+            Ok(Ok(tunnel_id)) => self.install(&handle, peer_id, tunnel_id).await,
+            Ok(Err(source)) => Err(NodeError::Tunnel {
+                peer: peer_id,
+                source,
+            }),
+            Err(_) => Err(NodeError::TunnelEstablishTimeout {
+                peer: peer_id,
+                timeout: self.tunnel_timeout,
+            }),
+        };
+        establishing.remove(&peer_id);
+        let _ = done.send(Some(match &result {
+            Ok(tunnel_id) => Established::Tunnel(*tunnel_id),
+            Err(NodeError::TunnelEstablishTimeout { .. }) => Established::TimedOut,
+            Err(_) => Established::Failed,
+        }));
+
+        if let Ok(tunnel_id) = &result {
+            tracing::info!(
+                "Secure tunnel {} established with peer {}",
+                tunnel_id,
+                peer_id
+            );
+        }
+        result
+    }
+
+    /// Take a tunnel the peer initiated toward us, returning the one kept
+    ///
+    /// If we have a tunnel of our own to the peer, the one initiated by the
+    /// lower node ID survives and the other is closed.
+    pub async fn accept_peer_tunnel(
+        &self,
+        peer_id: NodeId,
+        theirs: TunnelId,
+    ) -> Result<TunnelId, NodeError> {
+        let handle = self
+            .get_peer(&peer_id)
+            .await
+            .ok_or(NodeError::UnknownPeer { peer: peer_id })?;
+
+        // Tunnels we answer are held to the tier rules like sessions are
+        let peer_tier = NodeTier::from_asn(handle.snapshot().await?.peer_asn);
+        if !self.tier.can_peer_with(&peer_tier) {
+            tracing::warn!(
+                "Refusing tunnel {} from {:?} peer {}",
+                theirs,
+                peer_tier,
+                peer_id
+            );
+            let _ = self.tunnel_manager.close_tunnel(&theirs).await;
+            return Err(NodeError::TierMismatch {
+                ours: self.tier.clone(),
+                theirs: peer_tier,
+            });
+        }
+
+        // Let our own establishment finish so there is one tunnel to compare
+        let _establishing = loop {
+            let establishing = self.establishing.lock().await;
+            match establishing.get(&peer_id) {
+                Some(in_flight) if in_flight.has_changed().is_ok() => {
+                    let mut in_flight = in_flight.clone();
+                    drop(establishing);
+                    let _ = in_flight.wait_for(Option::is_some).await;
+                }
+                _ => break establishing,
+            }
+        };
+
+        match handle.tunnel().await? {
+            Some(ours) if ours != theirs && self.node_id < peer_id => {
+                tracing::info!(
+                    "Closing duplicate tunnel {} from peer {}; keeping ours",
+                    theirs,
+                    peer_id
+                );
+                let _ = self.tunnel_manager.close_tunnel(&theirs).await;
+                Ok(ours)
+            }
+            _ => self.install(&handle, peer_id, theirs).await,
+        }
+    }
+
+    async fn join(&self, peer_id: NodeId, mut in_flight: InFlight) -> Result<TunnelId, NodeError> {
+        let outcome = in_flight
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|outcome| *outcome);
+        match outcome {
+            Some(Established::Tunnel(tunnel_id)) => Ok(tunnel_id),
+            Some(Established::TimedOut) => Err(NodeError::TunnelEstablishTimeout {
+                peer: peer_id,
+                timeout: self.tunnel_timeout,
+            }),
+            Some(Established::Failed) | None => {
+                Err(NodeError::TunnelEstablishFailed { peer: peer_id })
+            }
+        }
+    }
+
+    /// Attach a tunnel to the peer, closing whichever it replaces
+    async fn install(
+        &self,
+        handle: &PeerHandle,
+        peer_id: NodeId,
+        tunnel_id: TunnelId,
+    ) -> Result<TunnelId, NodeError> {
+        let theirs = handle
+            .snapshot()
+            .await
+            .map(|peer| peer.capabilities)
+            .unwrap_or_default();
+        self.tunnel_manager
+            .negotiate_padding(&tunnel_id, theirs.supports_padding)
+            .await;
+        self.tunnel_manager
+            .negotiate_mtu(&tunnel_id, theirs.tunnel_mtu.map(usize::from))
+            .await;
+        match handle.attach_tunnel(tunnel_id).await {
+            Ok(Some(previous)) if previous != tunnel_id => {
+                tracing::info!(
+                    "Tunnel {} to peer {} replaces {}",
+                    tunnel_id,
+                    peer_id,
+                    previous
+                );
+                let _ = self.tunnel_manager.close_tunnel(&previous).await;
+                Ok(tunnel_id)
+            }
+            Ok(_) => Ok(tunnel_id),
+            Err(e) => {
+                // Peer was removed while the tunnel was being negotiated
+                let _ = self.tunnel_manager.close_tunnel(&tunnel_id).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::testing;
+    use crate::node::NodeTier;
+    use crate::node::PeerConnection;
+    use std::sync::Arc;
+
+    async fn with_peer(timeout: Duration) -> (Arc<Vx0Node>, NodeId) {
+        let config = testing::config(NodeTier::Edge);
+        let node = Vx0Node::new(config).unwrap().with_tunnel_timeout(timeout);
+        let peer_id = NodeId::new_v4();
+        node.add_peer(PeerConnection::new(
+            peer_id,
+            65101,
+            "10.1.0.1".parse().unwrap(),
+        ))
+        .await
+        .unwrap();
+        (Arc::new(node), peer_id)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_creates_share_one_tunnel() {
+        let (node, peer_id) = with_peer(Duration::from_secs(30)).await;
+        let addr: SocketAddr = "10.1.0.1:4500".parse().unwrap();
+
+        let calls: Vec<_> = (0..16)
+            .map(|_| {
+                let node = Arc::clone(&node);
+                tokio::spawn(async move {
+                    node.create_secure_tunnel(peer_id, addr, &node.tunnel_psk())
+                        .await
+                })
+            })
+            .collect();
+        let mut ids = Vec::new();
+        for call in calls {
+            ids.push(call.await.unwrap().unwrap());
+        }
+        ids.dedup();
+        assert_eq!(ids.len(), 1);
+        assert_eq!(node.tunnel_manager.list_tunnels().await.len(), 1);
+        assert_eq!(node.list_active_tunnels().await, vec![(peer_id, ids[0])]);
+
+        // The peer opened one toward us at the same time; both ends keep the
+        // tunnel initiated by the lower node ID
+        let theirs = node
+            .tunnel_manager
+            .create_tunnel(
+                "10.1.0.1".parse().unwrap(),
+                IpAddr::V4(node.ipv4_addr),
+                addr,
+                &node.tunnel_psk(),
+            )
+            .await
+            .unwrap();
+        let kept = node.accept_peer_tunnel(peer_id, theirs).await.unwrap();
+        let expected = if node.node_id < peer_id {
+            ids[0]
+        } else {
+            theirs
+        };
+        assert_eq!(kept, expected);
+        let tunnels = node.tunnel_manager.list_tunnels().await;
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].tunnel_id, kept);
+        assert_eq!(node.list_active_tunnels().await, vec![(peer_id, kept)]);
+    }
+
+    #[tokio::test]
+    async fn test_unresponsive_peer_times_out_cleanly() {
+        // IKE is simulated, so a silent peer is one slower than the deadline
+        let (node, peer_id) = with_peer(Duration::from_millis(1)).await;
+        let addr: SocketAddr = "10.1.0.1:4500".parse().unwrap();
+
+        let psk = node.tunnel_psk();
+        let (first, second) = tokio::join!(
+            node.create_secure_tunnel(peer_id, addr, &psk),
+            node.create_secure_tunnel(peer_id, addr, &psk),
+        );
+        for result in [first, second] {
+            assert!(matches!(
+                result,
+                Err(NodeError::TunnelEstablishTimeout { peer, .. }) if peer == peer_id
+            ));
+        }
+        assert!(node.tunnel_manager.list_tunnels().await.is_empty());
+        assert!(node.list_active_tunnels().await.is_empty());
+
+        // Nothing is left holding the peer's guard
+        let retry = node.create_secure_tunnel(peer_id, addr, &psk).await;
+        assert!(matches!(
+            retry,
+            Err(NodeError::TunnelEstablishTimeout { .. })
+        ));
+    }
+}
@@ -0,0 +1,483 @@
+//! One place for the daemon's runtime state on disk.
+//!
+//! The store is a directory with one subdirectory per namespace and one
+//! JSON file per key. Every write goes to a temporary file that is synced
+//! and then renamed over the old one, and the directory is synced after,
+//! so a crash at any point leaves either the old value or the new one.
+//! Temporary files a crash left behind are ignored and swept on open.
+//!
+//! Keys are free-form strings; characters that cannot appear in a file
+//! name are escaped, so a prefix like `10.0.0.0/16` is a valid key. Keys
+//! are listed in byte order of the key itself, not of its file name.
+
+use crate::util::sync::{lock, read, write};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+const VALUE_EXTENSION: &str = "json";
+const TMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Cannot access {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Stored value for {key:?} in namespace {namespace} is unreadable")]
+    Corrupt {
+        namespace: String,
+        key: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> StorageError + '_ {
+    move |source| StorageError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Called with the store's total size in bytes after every change
+pub type UsageHook = Arc<dyn Fn(u64) + Send + Sync>;
+
+struct Inner {
+    root: PathBuf,
+    /// Bytes held by every value in every namespace
+    usage: AtomicU64,
+    hook: RwLock<Option<UsageHook>>,
+    /// Serializes writers so usage stays exact
+    writing: Mutex<()>,
+    #[cfg(test)]
+    fail_before_rename: std::sync::atomic::AtomicBool,
+}
+
+/// Handle to the store; clones share it
+#[derive(Clone)]
+pub struct Store {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store")
+            .field("root", &self.inner.root)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+impl Store {
+    /// Open the store under `root`, which is created on the first write
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, StorageError> {
+        let root = root.into();
+        let mut usage = 0;
+        if root.exists() {
+            for namespace in read_dir(&root)? {
+                if !namespace.is_dir() {
+                    continue;
+                }
+                for path in read_dir(&namespace)? {
+                    if has_extension(&path, TMP_EXTENSION) {
+                        // Left by a write that never reached its rename
+                        if let Err(e) = std::fs::remove_file(&path) {
+                            tracing::debug!("Cannot remove {}: {}", path.display(), e);
+                        }
+                    } else if has_extension(&path, VALUE_EXTENSION) {
+                        usage += std::fs::metadata(&path).map_err(io_error(&path))?.len();
+                    }
+                }
+            }
+        }
+        Ok(Store {
+            inner: Arc::new(Inner {
+                root,
+                usage: AtomicU64::new(usage),
+                hook: RwLock::new(None),
+                writing: Mutex::new(()),
+                #[cfg(test)]
+                fail_before_rename: std::sync::atomic::AtomicBool::new(false),
+            }),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.inner.root
+    }
+
+    pub fn namespace(&self, name: &str) -> Namespace {
+        Namespace {
+            store: self.clone(),
+            dir: self.inner.root.join(encode(name)),
+            name: name.to_string(),
+        }
+    }
+
+    /// Names of the namespaces holding anything
+    pub fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        if !self.inner.root.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names: Vec<String> = read_dir(&self.inner.root)?
+            .into_iter()
+            .filter(|path| path.is_dir())
+            .filter_map(|path| decode(path.file_name()?.to_str()?))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Bytes held by every value in the store
+    pub fn usage(&self) -> u64 {
+        self.inner.usage.load(Ordering::Relaxed)
+    }
+
+    /// Have `hook` told the total size after every change, so resource
+    /// limits can account for the store
+    pub fn set_usage_hook(&self, hook: UsageHook) {
+        *write(&self.inner.hook) = Some(hook);
+    }
+
+    fn adjust_usage(&self, before: u64, after: u64) {
+        let usage = if after >= before {
+            self.inner
+                .usage
+                .fetch_add(after - before, Ordering::Relaxed)
+                + (after - before)
+        } else {
+            self.inner
+                .usage
+                .fetch_sub(before - after, Ordering::Relaxed)
+                - (before - after)
+        };
+        let hook = read(&self.inner.hook).clone();
+        if let Some(hook) = hook {
+            hook(usage);
+        }
+    }
+
+    /// Make the next write fail after its temporary file is written, as a
+    /// crash before the rename would
+    #[cfg(test)]
+    fn fail_before_rename(&self) {
+        self.inner.fail_before_rename.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Keys and values under one name, kept apart from every other namespace
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    store: Store,
+    name: String,
+    dir: PathBuf,
+}
+
+impl Namespace {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", encode(key), VALUE_EXTENSION))
+    }
+
+    /// Store `value` under `key`, replacing what was there
+    pub fn put<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<(), StorageError> {
+        let data = serde_json::to_vec_pretty(value)?;
+        let path = self.path(key);
+        let _writing = lock(&self.store.inner.writing);
+        std::fs::create_dir_all(&self.dir).map_err(io_error(&self.dir))?;
+        let before = size(&path);
+
+        let tmp = path.with_extension(TMP_EXTENSION);
+        let mut file = File::create(&tmp).map_err(io_error(&tmp))?;
+        file.write_all(&data)
+            .and_then(|()| file.sync_all())
+            .map_err(io_error(&tmp))?;
+        drop(file);
+
+        #[cfg(test)]
+        if self
+            .store
+            .inner
+            .fail_before_rename
+            .swap(false, Ordering::SeqCst)
+        {
+            return Err(StorageError::Io {
+                path: tmp,
+                source: std::io::Error::other("injected failure before rename"),
+            });
+        }
+
+        std::fs::rename(&tmp, &path).map_err(io_error(&path))?;
+        sync_dir(&self.dir)?;
+        self.store.adjust_usage(before, data.len() as u64);
+        Ok(())
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StorageError> {
+        let path = self.path(key);
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => return Err(StorageError::Io { path, source }),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|source| StorageError::Corrupt {
+                namespace: self.name.clone(),
+                key: key.to_string(),
+                source,
+            })
+    }
+
+    /// Remove `key`; false when it was not there
+    pub fn delete(&self, key: &str) -> Result<bool, StorageError> {
+        let path = self.path(key);
+        let _writing = lock(&self.store.inner.writing);
+        let before = size(&path);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(source) => return Err(StorageError::Io { path, source }),
+        }
+        sync_dir(&self.dir)?;
+        self.store.adjust_usage(before, 0);
+        Ok(true)
+    }
+
+    /// Move a JSON file written before its state lived in the store under
+    /// `key`, removing the file; false when there was no such file
+    pub fn adopt_file(&self, key: &str, path: &Path) -> Result<bool, StorageError> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(source) => {
+                return Err(StorageError::Io {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        let value: serde_json::Value = serde_json::from_slice(&data)?;
+        self.put(key, &value)?;
+        std::fs::remove_file(path).map_err(io_error(path))?;
+        Ok(true)
+    }
+
+    /// Every key starting with `prefix`, in key order
+    pub fn keys(&self, prefix: &str) -> Result<Vec<String>, StorageError> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut keys: Vec<String> = read_dir(&self.dir)?
+            .into_iter()
+            .filter(|path| has_extension(path, VALUE_EXTENSION))
+            .filter_map(|path| decode(path.file_stem()?.to_str()?))
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Every entry whose key starts with `prefix`, in key order
+    pub fn iter_prefix<T: DeserializeOwned>(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, T)>, StorageError> {
+        let mut entries = Vec::new();
+        for key in self.keys(prefix)? {
+            // Deleted since it was listed
+            if let Some(value) = self.get(&key)? {
+                entries.push((key, value));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    std::fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect()
+        })
+        .map_err(io_error(dir))
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext == extension)
+}
+
+fn size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0)
+}
+
+/// Make a rename or removal in `dir` durable
+fn sync_dir(dir: &Path) -> Result<(), StorageError> {
+    #[cfg(unix)]
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(io_error(dir))?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// File name for `key`: anything but ASCII letters, digits, `-`, `_` and a
+/// `.` that is not leading becomes `%XX`
+fn encode(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for (index, byte) in key.bytes().enumerate() {
+        let plain = byte.is_ascii_alphanumeric()
+            || byte == b'-'
+            || byte == b'_'
+            || (byte == b'.' && index > 0);
+        if plain {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn decode(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> Store {
+        let root = std::env::temp_dir().join(format!("vx0net-store-{}", uuid::Uuid::new_v4()));
+        Store::open(root).unwrap()
+    }
+
+    #[test]
+    fn test_crash_before_rename_keeps_the_old_value() {
+        let store = temp_store();
+        let peers = store.namespace("peers");
+        peers.put("65001", &10u32).unwrap();
+        let usage = store.usage();
+
+        store.fail_before_rename();
+        assert!(peers.put("65001", &20u32).is_err());
+        // The half-finished write is invisible, and swept on reopening
+        assert_eq!(peers.get::<u32>("65001").unwrap(), Some(10));
+        assert_eq!(peers.keys("").unwrap(), vec!["65001"]);
+        let reopened = Store::open(store.root()).unwrap();
+        assert_eq!(reopened.usage(), usage);
+        let leftovers = read_dir(&peers.dir)
+            .unwrap()
+            .into_iter()
+            .filter(|path| has_extension(path, TMP_EXTENSION))
+            .count();
+        assert_eq!(leftovers, 0);
+        assert_eq!(
+            reopened.namespace("peers").get::<u32>("65001").unwrap(),
+            Some(10)
+        );
+
+        // A new key that never got past its temporary file does not exist
+        store.fail_before_rename();
+        assert!(peers.put("65002", &30u32).is_err());
+        assert_eq!(peers.get::<u32>("65002").unwrap(), None);
+        std::fs::remove_dir_all(store.root()).unwrap();
+    }
+
+    #[test]
+    fn test_namespaces_are_isolated() {
+        let store = temp_store();
+        let peers = store.namespace("peers");
+        let routes = store.namespace("routes");
+        peers.put("shared", &"peer").unwrap();
+        routes.put("shared", &"route").unwrap();
+
+        assert_eq!(peers.get::<String>("shared").unwrap().unwrap(), "peer");
+        assert_eq!(routes.get::<String>("shared").unwrap().unwrap(), "route");
+        assert!(routes.delete("shared").unwrap());
+        assert!(!routes.delete("shared").unwrap());
+        assert_eq!(peers.get::<String>("shared").unwrap().unwrap(), "peer");
+        assert!(routes.keys("").unwrap().is_empty());
+        assert_eq!(store.namespaces().unwrap(), vec!["peers", "routes"]);
+        std::fs::remove_dir_all(store.root()).unwrap();
+    }
+
+    #[test]
+    fn test_iteration_follows_key_order() {
+        let store = temp_store();
+        let totals = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&totals);
+        store.set_usage_hook(Arc::new(move |usage| seen.lock().unwrap().push(usage)));
+
+        let routes = store.namespace("routes");
+        // Escaped characters sort by the key, not by their file names
+        for key in [
+            "10.2.0.0/16",
+            "10.10.0.0/16",
+            "10.1.0.0/24",
+            "10.1.0.0/16",
+            "9.0.0.0/8",
+        ] {
+            routes.put(key, &key.len()).unwrap();
+        }
+        let keys: Vec<String> = routes
+            .iter_prefix::<usize>("10.1")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["10.1.0.0/16", "10.1.0.0/24", "10.10.0.0/16"]);
+        assert_eq!(routes.keys("").unwrap().len(), 5);
+        assert_eq!(routes.keys("").unwrap()[4], "9.0.0.0/8");
+
+        // The hook saw every write, ending at the total
+        let totals = totals.lock().unwrap();
+        assert_eq!(totals.len(), 5);
+        assert_eq!(*totals.last().unwrap(), store.usage());
+        std::fs::remove_dir_all(store.root()).unwrap();
+    }
+
+    #[test]
+    fn test_keys_round_trip_through_file_names() {
+        for key in [
+            "plain",
+            ".hidden",
+            "10.0.0.0/16",
+            "a%b",
+            "fe80::1",
+            "ünïcode",
+        ] {
+            let encoded = encode(key);
+            assert!(!encoded.starts_with('.'));
+            assert!(!encoded.contains('/'));
+            assert_eq!(decode(&encoded).as_deref(), Some(key));
+        }
+    }
+}
@@ -0,0 +1,559 @@
+//! Retry schedules shared by every subsystem that retries.
+//!
+//! A `RetryPolicy` says how long to wait between tries: an initial delay
+//! multiplied on each retry up to a cap, optionally jittered, for a bounded
+//! or unbounded number of tries. Each use has a named policy under
+//! `[network.retry]` with defaults of its own, so operators tune peer
+//! reconnects, bootstrap, joining, hook delivery and DNS forwarding in one
+//! vocabulary. Retries scheduled and schedules given up on are counted per
+//! policy.
+//!
+//! A `Backoff` walks one schedule. It is also an iterator of delays, for
+//! callers that sleep on their own clock; `retry_with` runs the whole loop
+//! for an async operation and stops early when cancelled, and `retry_on`
+//! does the same with its waits on a given `Clock`.
+
+use crate::config::{RetryConfig, RetryPolicyConfig};
+use crate::monitoring::register_metric;
+use crate::util::clock::{Clock, SystemClock};
+use prometheus::{IntCounterVec, Opts};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How much of each delay is randomized, so peers that failed together
+/// don't all retry together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JitterMode {
+    /// Exactly the computed delay
+    #[default]
+    None,
+    /// Anywhere from zero to the computed delay
+    Full,
+    /// At least half the computed delay, the rest random
+    Equal,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Label for metrics and logs
+    pub name: &'static str,
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    pub jitter: JitterMode,
+    /// Tries in all, the first one included; `None` retries forever
+    pub max_attempts: Option<u32>,
+    /// A success lasting this long starts the schedule over
+    pub reset_after: Duration,
+}
+
+impl RetryPolicy {
+    pub const PEER_RECONNECT: RetryPolicy = RetryPolicy {
+        name: "peer_reconnect",
+        initial_delay: Duration::from_secs(1),
+        multiplier: 2.0,
+        max_delay: Duration::from_secs(300),
+        jitter: JitterMode::Full,
+        max_attempts: None,
+        reset_after: Duration::from_secs(60),
+    };
+
+    pub const BOOTSTRAP: RetryPolicy = RetryPolicy {
+        name: "bootstrap",
+        initial_delay: Duration::from_millis(500),
+        multiplier: 2.0,
+        max_delay: Duration::from_secs(30),
+        jitter: JitterMode::None,
+        max_attempts: Some(3),
+        reset_after: Duration::ZERO,
+    };
+
+    pub const JOIN: RetryPolicy = RetryPolicy {
+        name: "join",
+        initial_delay: Duration::from_secs(2),
+        multiplier: 2.0,
+        max_delay: Duration::from_secs(60),
+        jitter: JitterMode::None,
+        max_attempts: Some(3),
+        reset_after: Duration::ZERO,
+    };
+
+    pub const HOOKS: RetryPolicy = RetryPolicy {
+        name: "hooks",
+        initial_delay: Duration::from_secs(1),
+        multiplier: 2.0,
+        max_delay: Duration::from_secs(60),
+        jitter: JitterMode::Equal,
+        max_attempts: Some(5),
+        reset_after: Duration::ZERO,
+    };
+
+    pub const DNS_FORWARD: RetryPolicy = RetryPolicy {
+        name: "dns_forward",
+        initial_delay: Duration::from_millis(50),
+        multiplier: 2.0,
+        max_delay: Duration::from_secs(1),
+        jitter: JitterMode::Full,
+        max_attempts: Some(3),
+        reset_after: Duration::ZERO,
+    };
+
+    /// This policy with whatever the config sets overriding it
+    pub fn configured(mut self, config: &RetryPolicyConfig) -> Self {
+        if let Some(ms) = config.initial_delay_ms {
+            self.initial_delay = Duration::from_millis(ms);
+        }
+        if let Some(multiplier) = config.multiplier {
+            self.multiplier = multiplier.max(1.0);
+        }
+        if let Some(ms) = config.max_delay_ms {
+            self.max_delay = Duration::from_millis(ms);
+        }
+        if let Some(jitter) = config.jitter {
+            self.jitter = jitter;
+        }
+        if let Some(attempts) = config.max_attempts {
+            self.max_attempts = (attempts > 0).then_some(attempts);
+        }
+        if let Some(secs) = config.reset_after_secs {
+            self.reset_after = Duration::from_secs(secs);
+        }
+        self
+    }
+
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.clone(), StdRng::from_entropy())
+    }
+
+    /// A schedule whose jitter is the same on every run
+    pub fn seeded(&self, seed: u64) -> Backoff {
+        Backoff::new(self.clone(), StdRng::seed_from_u64(seed))
+    }
+
+    /// Delay before retry number `retry`, counting from zero, unjittered
+    fn base_delay(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.powi(retry.min(i32::MAX as u32) as i32);
+        Duration::try_from_secs_f64(self.initial_delay.as_secs_f64() * factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
+
+impl RetryConfig {
+    pub fn peer_reconnect(&self) -> RetryPolicy {
+        RetryPolicy::PEER_RECONNECT.configured(&self.peer_reconnect)
+    }
+
+    pub fn bootstrap(&self) -> RetryPolicy {
+        RetryPolicy::BOOTSTRAP.configured(&self.bootstrap)
+    }
+
+    pub fn join(&self) -> RetryPolicy {
+        RetryPolicy::JOIN.configured(&self.join)
+    }
+
+    pub fn hooks(&self) -> RetryPolicy {
+        RetryPolicy::HOOKS.configured(&self.hooks)
+    }
+
+    pub fn dns_forward(&self) -> RetryPolicy {
+        RetryPolicy::DNS_FORWARD.configured(&self.dns_forward)
+    }
+}
+
+/// One run through a retry schedule
+#[derive(Debug)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    retries: u32,
+    rng: StdRng,
+    /// When the operation last succeeded, until the next failure
+    up_since: Option<Instant>,
+    exhausted: bool,
+}
+
+impl Backoff {
+    fn new(policy: RetryPolicy, rng: StdRng) -> Self {
+        Backoff {
+            policy,
+            retries: 0,
+            rng,
+            up_since: None,
+            exhausted: false,
+        }
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Retries scheduled since the schedule last started over
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The operation worked; if it keeps working past the reset window,
+    /// the next failure starts the schedule over
+    pub fn succeeded(&mut self, at: Instant) {
+        self.up_since = Some(at);
+    }
+
+    /// How long to wait before trying again after a failure at `now`, or
+    /// `None` once the policy's tries are used up
+    pub fn next_delay(&mut self, now: Instant) -> Option<Duration> {
+        if let Some(up_since) = self.up_since.take() {
+            if now.saturating_duration_since(up_since) >= self.policy.reset_after {
+                self.retries = 0;
+                self.exhausted = false;
+            }
+        }
+        if self.exhausted {
+            return None;
+        }
+        if self
+            .policy
+            .max_attempts
+            .is_some_and(|max| self.retries + 1 >= max)
+        {
+            self.exhausted = true;
+            exhausted_counter()
+                .with_label_values(&[self.policy.name])
+                .inc();
+            return None;
+        }
+
+        let delay = self.policy.base_delay(self.retries);
+        let delay = match self.policy.jitter {
+            JitterMode::None => delay,
+            JitterMode::Full => delay.mul_f64(self.rng.gen::<f64>()),
+            JitterMode::Equal => delay / 2 + (delay / 2).mul_f64(self.rng.gen::<f64>()),
+        };
+        self.retries += 1;
+        attempts_counter()
+            .with_label_values(&[self.policy.name])
+            .inc();
+        Some(delay)
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        self.next_delay(Instant::now())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RetryError<E> {
+    /// The last failure, once it was not worth retrying or the policy's
+    /// tries ran out
+    #[error(transparent)]
+    Failed(E),
+    /// Cancelled while waiting to retry after this failure
+    #[error("Cancelled while waiting to retry: {0}")]
+    Cancelled(E),
+}
+
+impl<E> RetryError<E> {
+    /// The last failure, however the retries ended
+    pub fn into_inner(self) -> E {
+        match self {
+            RetryError::Failed(e) | RetryError::Cancelled(e) => e,
+        }
+    }
+}
+
+/// Run `op` until it succeeds, fails in a way `retryable` rejects, runs out
+/// of tries, or `cancel` fires while waiting between tries
+pub async fn retry_with<T, E, Fut>(
+    policy: &RetryPolicy,
+    cancel: &CancellationToken,
+    retryable: impl Fn(&E) -> bool,
+    op: impl FnMut() -> Fut,
+) -> Result<T, RetryError<E>>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_on(&SystemClock, policy, cancel, retryable, op).await
+}
+
+/// `retry_with`, waiting between tries on `clock`
+pub async fn retry_on<T, E, Fut>(
+    clock: &dyn Clock,
+    policy: &RetryPolicy,
+    cancel: &CancellationToken,
+    retryable: impl Fn(&E) -> bool,
+    mut op: impl FnMut() -> Fut,
+) -> Result<T, RetryError<E>>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut backoff = policy.backoff();
+    loop {
+        let error = match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if !retryable(&e) => return Err(RetryError::Failed(e)),
+            Err(e) => e,
+        };
+        let Some(delay) = backoff.next_delay(clock.now_monotonic()) else {
+            return Err(RetryError::Failed(error));
+        };
+        tracing::debug!(
+            "Retry {} of {} in {:?}",
+            backoff.retries(),
+            policy.name,
+            delay
+        );
+        tokio::select! {
+            _ = cancel.cancelled() => return Err(RetryError::Cancelled(error)),
+            _ = clock.sleep(delay) => {}
+        }
+    }
+}
+
+/// Retries scheduled, by policy
+pub fn attempts_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new("vx0net_retry_attempts_total", "Retries scheduled"),
+            &["policy"],
+        ))
+    })
+}
+
+/// Schedules that ran out of tries, by policy
+pub fn exhausted_counter() -> &'static IntCounterVec {
+    static COUNTER: OnceLock<IntCounterVec> = OnceLock::new();
+    COUNTER.get_or_init(|| {
+        register_metric(IntCounterVec::new(
+            Opts::new(
+                "vx0net_retry_exhausted_total",
+                "Retry schedules that ran out of tries",
+            ),
+            &["policy"],
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(jitter: JitterMode) -> RetryPolicy {
+        RetryPolicy {
+            name: "test",
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(500),
+            jitter,
+            max_attempts: Some(6),
+            reset_after: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_delays_grow_to_the_cap_and_stop_at_max_attempts() {
+        let ms = Duration::from_millis;
+        let delays: Vec<Duration> = policy(JitterMode::None).seeded(0).collect();
+        assert_eq!(delays, vec![ms(100), ms(200), ms(400), ms(500), ms(500)]);
+
+        let forever = RetryPolicy {
+            max_attempts: None,
+            ..policy(JitterMode::None)
+        };
+        assert_eq!(forever.seeded(0).nth(1000), Some(ms(500)));
+    }
+
+    #[test]
+    fn test_jitter_is_bounded_and_repeatable_with_a_seed() {
+        let full: Vec<Duration> = policy(JitterMode::Full).seeded(7).collect();
+        assert_eq!(full, policy(JitterMode::Full).seeded(7).collect::<Vec<_>>());
+        assert_ne!(full, policy(JitterMode::Full).seeded(8).collect::<Vec<_>>());
+
+        let equal: Vec<Duration> = policy(JitterMode::Equal).seeded(7).collect();
+        let plain: Vec<Duration> = policy(JitterMode::None).seeded(7).collect();
+        for ((full, equal), plain) in full.iter().zip(&equal).zip(&plain) {
+            assert!(full <= plain);
+            assert!(*equal >= *plain / 2 && equal <= plain);
+        }
+    }
+
+    #[test]
+    fn test_only_a_lasting_success_starts_the_schedule_over() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut backoff = policy(JitterMode::None).seeded(0);
+        assert_eq!(backoff.next_delay(start), Some(ms(100)));
+        assert_eq!(backoff.next_delay(start), Some(ms(200)));
+
+        // Up briefly, then down again: keep backing off
+        backoff.succeeded(start);
+        assert_eq!(backoff.next_delay(start + ms(5_000)), Some(ms(400)));
+
+        // Up past the window: back to the first delay
+        backoff.succeeded(start);
+        assert_eq!(
+            backoff.next_delay(start + Duration::from_secs(30)),
+            Some(ms(100))
+        );
+        assert_eq!(backoff.retries(), 1);
+
+        // Even an exhausted schedule starts over after a lasting success
+        let mut backoff = RetryPolicy {
+            max_attempts: Some(2),
+            ..policy(JitterMode::None)
+        }
+        .seeded(0);
+        assert_eq!(backoff.next_delay(start), Some(ms(100)));
+        assert_eq!(backoff.next_delay(start), None);
+        assert_eq!(backoff.next_delay(start), None);
+        backoff.succeeded(start);
+        assert_eq!(
+            backoff.next_delay(start + Duration::from_secs(60)),
+            Some(ms(100))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_stops_on_success_permanent_errors_and_exhaustion() {
+        let quick = RetryPolicy {
+            name: "test_retry_with",
+            initial_delay: Duration::from_millis(1),
+            max_attempts: Some(3),
+            ..policy(JitterMode::None)
+        };
+        let cancel = CancellationToken::new();
+        let tries = AtomicU32::new(0);
+
+        let result = retry_with(
+            &quick,
+            &cancel,
+            |_: &&str| true,
+            || async {
+                match tries.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err("flaky"),
+                    n => Ok(n),
+                }
+            },
+        )
+        .await;
+        assert_eq!(result.unwrap(), 1);
+
+        tries.store(0, Ordering::SeqCst);
+        let result: Result<(), _> = retry_with(
+            &quick,
+            &cancel,
+            |e: &&str| *e != "fatal",
+            || {
+                tries.fetch_add(1, Ordering::SeqCst);
+                async { Err("fatal") }
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(RetryError::Failed("fatal"))));
+        assert_eq!(tries.load(Ordering::SeqCst), 1);
+
+        let exhausted = exhausted_counter().with_label_values(&["test_retry_with"]);
+        let before = exhausted.get();
+        tries.store(0, Ordering::SeqCst);
+        let result: Result<(), _> = retry_with(
+            &quick,
+            &cancel,
+            |_: &&str| true,
+            || {
+                tries.fetch_add(1, Ordering::SeqCst);
+                async { Err("down") }
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(RetryError::Failed("down"))));
+        assert_eq!(tries.load(Ordering::SeqCst), 3);
+        assert_eq!(exhausted.get(), before + 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_interrupts_the_wait() {
+        let slow = RetryPolicy {
+            initial_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(60),
+            ..policy(JitterMode::None)
+        };
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let result: Result<(), RetryError<&str>> =
+            retry_with(&slow, &cancel, |_| true, || async { Err("down") }).await;
+        assert!(matches!(result, Err(RetryError::Cancelled("down"))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_named_policies_parse_with_per_use_defaults() {
+        let config: RetryConfig = toml::from_str(
+            r#"
+            [peer_reconnect]
+            max_delay_ms = 120000
+            reset_after_secs = 10
+
+            [bootstrap]
+            max_attempts = 5
+
+            [join]
+            initial_delay_ms = 1000
+            jitter = "equal"
+
+            [hooks]
+            max_attempts = 0
+
+            [dns_forward]
+            multiplier = 3.0
+            jitter = "none"
+            "#,
+        )
+        .unwrap();
+
+        let reconnect = config.peer_reconnect();
+        assert_eq!(reconnect.max_delay, Duration::from_secs(120));
+        assert_eq!(reconnect.reset_after, Duration::from_secs(10));
+        assert_eq!(reconnect.initial_delay, Duration::from_secs(1));
+
+        let bootstrap = config.bootstrap();
+        assert_eq!(bootstrap.max_attempts, Some(5));
+        assert_eq!(bootstrap.initial_delay, Duration::from_millis(500));
+
+        let join = config.join();
+        assert_eq!(join.initial_delay, Duration::from_secs(1));
+        assert_eq!(join.jitter, JitterMode::Equal);
+        assert_eq!(join.max_attempts, Some(3));
+
+        assert_eq!(config.hooks().max_attempts, None);
+        assert_eq!(config.hooks().jitter, JitterMode::Equal);
+
+        let dns = config.dns_forward();
+        assert_eq!(dns.multiplier, 3.0);
+        assert_eq!(dns.jitter, JitterMode::None);
+        assert_eq!(dns.name, "dns_forward");
+
+        // Nothing configured: every use keeps its own defaults
+        let defaults = RetryConfig::default();
+        assert_eq!(defaults.peer_reconnect(), RetryPolicy::PEER_RECONNECT);
+        assert_eq!(defaults.bootstrap(), RetryPolicy::BOOTSTRAP);
+        assert_eq!(defaults.join(), RetryPolicy::JOIN);
+        assert_eq!(defaults.hooks(), RetryPolicy::HOOKS);
+        assert_eq!(defaults.dns_forward(), RetryPolicy::DNS_FORWARD);
+    }
+}
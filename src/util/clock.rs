@@ -0,0 +1,212 @@
+//! Where every timer in the daemon gets the time.
+//!
+//! A `Clock` gives two readings: UTC for timestamps that are logged, shown
+//! or sent to peers, and a monotonic instant for anything measuring how
+//! long something took or how long ago it happened. Liveness decisions —
+//! expiry, hold and setup timeouts, rekey and idle ages — are made on the
+//! monotonic reading only, so an NTP step or an operator changing the
+//! system time never makes a peer look silent or a key look fresh.
+//!
+//! Subsystems take a `SharedClock` and default to `SystemClock`. Tests give
+//! them a `ManualClock` instead and move time along themselves: sleeps and
+//! intervals on it wake only when `advance` passes their deadline, and
+//! `step_wall` moves the UTC reading alone, as a wall-clock jump would.
+
+use crate::util::sync::lock;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+#[async_trait]
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Wall-clock time, for timestamps only
+    fn now_utc(&self) -> DateTime<Utc>;
+
+    /// Never goes backwards; for measuring durations
+    fn now_monotonic(&self) -> Instant;
+
+    async fn sleep_until(&self, deadline: Instant);
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now_monotonic() + duration).await;
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The operating system's clocks, with tokio's timers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn now_monotonic(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// The system clock, shared
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Time that only moves when told to
+#[derive(Debug)]
+pub struct ManualClock {
+    monotonic: watch::Sender<Instant>,
+    wall: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    /// Starting at the current time
+    pub fn new() -> Self {
+        ManualClock {
+            monotonic: watch::Sender::new(Instant::now()),
+            wall: Mutex::new(Utc::now()),
+        }
+    }
+
+    /// Move both readings forward, waking whatever is due
+    pub fn advance(&self, by: Duration) {
+        *lock(&self.wall) += chrono::Duration::from_std(by).unwrap_or(chrono::Duration::MAX);
+        self.monotonic.send_modify(|now| *now += by);
+    }
+
+    /// Move the wall clock alone, forwards or back, as NTP or an operator
+    /// setting the time would
+    pub fn step_wall(&self, by: chrono::Duration) {
+        *lock(&self.wall) += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for ManualClock {
+    fn now_utc(&self) -> DateTime<Utc> {
+        *lock(&self.wall)
+    }
+
+    fn now_monotonic(&self) -> Instant {
+        *self.monotonic.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut now = self.monotonic.subscribe();
+        // The sender lives as long as the clock, which outlives this borrow
+        let _ = now.wait_for(|now| *now >= deadline).await;
+    }
+}
+
+/// Ticks every `period` on `clock`, the first at once; ticks missed while
+/// the owner was busy are skipped, not caught up
+pub fn interval(clock: &SharedClock, period: Duration) -> Interval {
+    Interval {
+        clock: Arc::clone(clock),
+        period: period.max(Duration::from_millis(1)),
+        next: clock.now_monotonic(),
+    }
+}
+
+#[derive(Debug)]
+pub struct Interval {
+    clock: SharedClock,
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    /// Wait for the next tick, returning when it was due
+    pub async fn tick(&mut self) -> Instant {
+        let due = self.next;
+        self.clock.sleep_until(due).await;
+        let now = self.clock.now_monotonic();
+        self.next = due + self.period;
+        if self.next <= now {
+            self.next = now + self.period;
+        }
+        due
+    }
+
+    /// Make the next tick due now
+    pub fn reset_immediately(&mut self) {
+        self.next = self.clock.now_monotonic();
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Tick every `period` from now on, the next one `period` after the
+    /// last, which may make it due at once
+    pub fn set_period(&mut self, period: Duration) {
+        let period = period.max(Duration::from_millis(1));
+        let last = self.next.checked_sub(self.period).unwrap_or(self.next);
+        self.next = last + period;
+        self.period = period;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_sleep_wakes_only_when_advanced_past() {
+        let clock = Arc::new(ManualClock::new());
+        let sleeper = {
+            let clock = Arc::clone(&clock);
+            tokio::spawn(async move { clock.sleep(Duration::from_secs(60)).await })
+        };
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(59));
+        clock.step_wall(chrono::Duration::days(2));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        tokio::time::timeout(Duration::from_secs(5), sleeper)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interval_skips_missed_ticks() {
+        let manual = Arc::new(ManualClock::new());
+        let clock: SharedClock = manual.clone();
+        let start = clock.now_monotonic();
+        let mut ticks = interval(&clock, Duration::from_secs(10));
+        assert_eq!(ticks.tick().await, start);
+
+        manual.advance(Duration::from_secs(35));
+        assert_eq!(ticks.tick().await, start + Duration::from_secs(10));
+        // The ticks due at 20s and 30s were missed; the next is 10s from now
+        let next = tokio::spawn(async move { ticks.tick().await });
+        tokio::task::yield_now().await;
+        manual.advance(Duration::from_secs(9));
+        tokio::task::yield_now().await;
+        assert!(!next.is_finished());
+        manual.advance(Duration::from_secs(1));
+        assert_eq!(next.await.unwrap(), start + Duration::from_secs(45));
+    }
+}
@@ -0,0 +1,150 @@
+//! Detaching from the terminal for `vx0net start --daemon`.
+//!
+//! The classic double fork: the first child starts a new session and forks
+//! again so the daemon can never reacquire a controlling terminal. The
+//! daemon then sets its umask and working directory and points stdin at
+//! /dev/null and stdout and stderr at the log file. The original process
+//! waits on a pipe until the daemon reports ready (or dies trying), so the
+//! shell that ran `vx0net start --daemon` sees a meaningful exit status.
+//!
+//! This must run before any threads exist, i.e. before the Tokio runtime is
+//! built: only the forking thread survives a fork.
+
+use crate::config::DaemonConfig;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DaemonError {
+    #[error("cannot open log file {path}: {source}")]
+    LogFile { path: String, source: io::Error },
+    #[error("cannot use working directory {path}: {source}")]
+    WorkingDirectory { path: String, source: io::Error },
+    #[error("{call} failed: {source}")]
+    Sys {
+        call: &'static str,
+        source: io::Error,
+    },
+}
+
+/// Which side of the fork this process ended up on
+#[derive(Debug)]
+pub enum Daemonized {
+    /// The process that was started; `pid` is the daemon's once it reported
+    /// ready, `None` if it exited first
+    Parent { pid: Option<u32> },
+    /// The detached daemon. Write the pid to the pipe once ready, see
+    /// `monitoring::notify::Readiness::with_parent`
+    Daemon(File),
+}
+
+pub fn daemonize(config: &DaemonConfig) -> Result<Daemonized, DaemonError> {
+    // Fail while the operator can still see the error
+    let log = open_log(&config.log_file)?;
+    let dir = Path::new(&config.working_directory);
+    if !dir.is_dir() {
+        return Err(DaemonError::WorkingDirectory {
+            path: config.working_directory.clone(),
+            source: io::Error::from(io::ErrorKind::NotFound),
+        });
+    }
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .open("/dev/null")
+        .map_err(sys("open /dev/null"))?;
+    let (mut ready_rx, ready_tx) = pipe()?;
+
+    // SAFETY: called before any other thread is started
+    match unsafe { libc::fork() } {
+        -1 => return Err(sys("fork")(io::Error::last_os_error())),
+        0 => {}
+        child => {
+            drop(ready_tx);
+            // The first child exits as soon as it has forked the daemon
+            unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+            return Ok(Daemonized::Parent {
+                pid: wait_ready(&mut ready_rx),
+            });
+        }
+    }
+    drop(ready_rx);
+
+    // SAFETY: plain syscalls in the single-threaded child
+    if unsafe { libc::setsid() } == -1 {
+        child_exit();
+    }
+    match unsafe { libc::fork() } {
+        -1 => child_exit(),
+        0 => {}
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    unsafe { libc::umask(config.umask as libc::mode_t) };
+    if let Err(source) = std::env::set_current_dir(dir) {
+        return Err(DaemonError::WorkingDirectory {
+            path: config.working_directory.clone(),
+            source,
+        });
+    }
+    redirect(&dev_null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)?;
+    Ok(Daemonized::Daemon(ready_tx))
+}
+
+/// Tell the waiting parent the daemon is up
+pub fn report_ready(mut pipe: File) -> io::Result<()> {
+    writeln!(pipe, "{}", std::process::id())
+}
+
+fn open_log(path: &str) -> Result<File, DaemonError> {
+    let error = |source| DaemonError::LogFile {
+        path: path.to_string(),
+        source,
+    };
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent).map_err(error)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(error)
+}
+
+/// Read the daemon's pid, or `None` on EOF: every copy of the write end is
+/// closed without a report, so the daemon failed during startup
+fn wait_ready(pipe: &mut File) -> Option<u32> {
+    let mut report = String::new();
+    pipe.read_to_string(&mut report).ok()?;
+    report.trim().parse().ok()
+}
+
+fn pipe() -> Result<(File, File), DaemonError> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for both ends
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(sys("pipe")(io::Error::last_os_error()));
+    }
+    // SAFETY: both descriptors were just created and are owned by nobody else
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn redirect(file: &File, target: libc::c_int) -> Result<(), DaemonError> {
+    // SAFETY: both descriptors are open
+    if unsafe { libc::dup2(file.as_raw_fd(), target) } == -1 {
+        return Err(sys("dup2")(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn sys(call: &'static str) -> impl Fn(io::Error) -> DaemonError {
+    move |source| DaemonError::Sys { call, source }
+}
+
+/// Leave an intermediate child; the parent sees EOF and reports failure
+fn child_exit() -> ! {
+    unsafe { libc::_exit(1) }
+}
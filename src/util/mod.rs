@@ -0,0 +1,4 @@
+pub mod backoff;
+pub mod clock;
+pub mod daemonize;
+pub mod sync;
@@ -0,0 +1,19 @@
+//! Locking helpers shared across the daemon.
+
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Lock `mutex`, carrying on with the data a panicking holder left behind;
+/// the state guarded this way is always left consistent between statements
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Share `rwlock` for reading, tolerating poison as [`lock`] does
+pub fn read<T>(rwlock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    rwlock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Hold `rwlock` for writing, tolerating poison as [`lock`] does
+pub fn write<T>(rwlock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    rwlock.write().unwrap_or_else(|e| e.into_inner())
+}
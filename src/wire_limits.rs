@@ -0,0 +1,46 @@
+//! Size budgets for what nodes send each other about themselves.
+//!
+//! A node announces a bounded selection of its services rather than all of
+//! them, and marks the announcement truncated when it left some out; peers
+//! that want the rest ask for them with a service query naming the node.
+//! Service metadata is bounded when a service is registered, so listings
+//! built from it stay small too.
+
+/// Serialized size a node announcement is kept under
+pub const MAX_ANNOUNCEMENT_BYTES: usize = 16 * 1024;
+
+/// Service summaries an announcement carries unless configured otherwise
+pub const DEFAULT_MAX_ANNOUNCED_SERVICES: usize = 50;
+
+/// Longest metadata value kept, in bytes; longer ones are cut short
+pub const MAX_METADATA_VALUE_BYTES: usize = 1024;
+
+/// Most metadata keys kept per service; the rest are dropped
+pub const MAX_METADATA_KEYS: usize = 32;
+
+/// The longest prefix of `value` that fits in `max` bytes without splitting
+/// a character
+pub fn truncate_utf8(value: &str, max: usize) -> &str {
+    if value.len() <= max {
+        return value;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_utf8_keeps_whole_characters() {
+        assert_eq!(truncate_utf8("wiki", 8), "wiki");
+        assert_eq!(truncate_utf8("wiki", 2), "wi");
+        // 'é' takes two bytes; cutting inside it backs off to before it
+        assert_eq!(truncate_utf8("café", 4), "caf");
+        assert_eq!(truncate_utf8("café", 5), "café");
+    }
+}
@@ -0,0 +1,125 @@
+//! A node hosting more services than an announcement carries announces a
+//! selection within budget, and a peer's service query for that node still
+//! returns every one of them.
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use vx0net_daemon::network::dns::sync::{self, ZoneSyncService};
+use vx0net_daemon::network::dns::Vx0DNS;
+use vx0net_daemon::node::search::ServiceFilter;
+use vx0net_daemon::node::services::ServiceRegistry;
+use vx0net_daemon::node::{HostedService, NodeTier, ServiceStatus, ServiceType, Vx0Node};
+use vx0net_daemon::wire_limits::{DEFAULT_MAX_ANNOUNCED_SERVICES, MAX_ANNOUNCEMENT_BYTES};
+
+const HOSTED: usize = 200;
+
+#[tokio::test]
+async fn test_truncated_announcement_and_full_service_query() {
+    let config = common::config(NodeTier::Backbone);
+    let node = Arc::new(Vx0Node::new(config).unwrap());
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(node.node_id);
+    let dns = Arc::new(RwLock::new(dns));
+    node.set_directory(Arc::clone(&dns));
+    let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns));
+
+    // The ten most recent registrations are stopped, so announce last
+    for index in 0..HOSTED {
+        let service = HostedService {
+            service_id: Uuid::new_v4(),
+            name: format!("community service {index:03}"),
+            service_type: ServiceType::WebServer,
+            domain: format!("service-{index:03}.community.vx0"),
+            port: 8000 + index as u16,
+            status: if index < HOSTED - 10 {
+                ServiceStatus::Running
+            } else {
+                ServiceStatus::Stopped
+            },
+            metadata: Default::default(),
+        };
+        registry.publish(service).await.unwrap();
+    }
+
+    let announcement = node.announcement().await;
+    assert!(announcement.encoded_len() <= MAX_ANNOUNCEMENT_BYTES);
+    assert!(announcement.truncated);
+    assert_eq!(announcement.total_services, HOSTED);
+    assert_eq!(announcement.services.len(), DEFAULT_MAX_ANNOUNCED_SERVICES);
+    let domains: Vec<&str> = announcement
+        .services
+        .iter()
+        .map(|service| service.domain.as_str())
+        .collect();
+    assert_eq!(domains[0], "service-189.community.vx0");
+    assert_eq!(domains[49], "service-140.community.vx0");
+
+    // The flag survives the trip to a peer
+    let encoded = serde_json::to_value(&announcement).unwrap();
+    assert_eq!(encoded["truncated"], true);
+    assert_eq!(encoded["total_services"], HOSTED);
+
+    // A peer asks the node's zone sync for everything it hosts
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+    ZoneSyncService::new(Arc::clone(&dns))
+        .start(address)
+        .await
+        .unwrap();
+    let everything = sync::find_services(
+        address,
+        &ServiceFilter {
+            node_id: Some(announcement.node_id),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(everything.len(), HOSTED);
+    assert!(everything
+        .iter()
+        .all(|service| service.node_id == announcement.node_id));
+
+    let elsewhere = sync::find_services(
+        address,
+        &ServiceFilter {
+            node_id: Some(Uuid::new_v4()),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert!(elsewhere.is_empty());
+}
+
+#[tokio::test]
+async fn test_small_announcements_are_complete() {
+    let config = common::config(NodeTier::Backbone);
+    let node = Vx0Node::new(config).unwrap();
+    node.register_service(HostedService {
+        service_id: Uuid::new_v4(),
+        name: "wiki".to_string(),
+        service_type: ServiceType::WebServer,
+        domain: "wiki.vx0".to_string(),
+        port: 80,
+        status: ServiceStatus::Running,
+        metadata: Default::default(),
+    })
+    .await
+    .unwrap();
+
+    let announcement = node.announcement().await;
+    assert!(!announcement.truncated);
+    assert_eq!(announcement.total_services, 1);
+    // Peers that predate the flag see the same message as before
+    let encoded = serde_json::to_value(&announcement).unwrap();
+    assert!(encoded.get("truncated").is_none());
+}
@@ -0,0 +1,87 @@
+//! Interned AS paths must cost far less than a vector per route on a large
+//! table with few distinct paths, and must not change what goes on the wire.
+
+mod common;
+
+use common::route;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use vx0net_daemon::network::bgp::as_path::AsPathInterner;
+use vx0net_daemon::network::bgp::{AsPath, RouteEntry};
+
+/// Tracks bytes currently allocated by the whole test binary
+struct Counting;
+
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size() as isize, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const ROUTES: u32 = 50_000;
+const DISTINCT_PATHS: u32 = 200;
+
+/// Paths of two to seven hops, as a mesh a few tiers deep would produce
+fn path(i: u32) -> Vec<u32> {
+    let id = i % DISTINCT_PATHS;
+    let hops = 2 + id % 6;
+    (0..hops).map(|hop| 65000 + id * 10 + hop).collect()
+}
+
+fn measure<T>(build: impl FnOnce() -> T) -> (T, isize) {
+    let before = LIVE.load(Ordering::SeqCst);
+    let built = build();
+    (built, LIVE.load(Ordering::SeqCst) - before)
+}
+
+#[test]
+fn test_interned_paths_use_less_memory_and_serialize_unchanged() {
+    let (plain, plain_bytes) = measure(|| (0..ROUTES).map(path).collect::<Vec<Vec<u32>>>());
+    let (interned, interned_bytes) = measure(|| {
+        (0..ROUTES)
+            .map(|i| AsPath::new(&path(i)))
+            .collect::<Vec<AsPath>>()
+    });
+    println!(
+        "{} routes over {} paths: {} bytes as vectors, {} interned",
+        ROUTES, DISTINCT_PATHS, plain_bytes, interned_bytes
+    );
+    assert!(
+        interned_bytes * 2 < plain_bytes,
+        "interning saved too little: {} vs {} bytes",
+        interned_bytes,
+        plain_bytes
+    );
+
+    // Same contents, and routes sharing a path share its allocation
+    for (i, (vector, path)) in plain.iter().zip(&interned).enumerate() {
+        assert_eq!(path, vector);
+        assert_eq!(*path, interned[i % DISTINCT_PATHS as usize]);
+    }
+    assert!(AsPathInterner::global().len() >= DISTINCT_PATHS as usize);
+
+    // A route serializes exactly as it did with a plain vector path
+    let route = route("10.7.0.0/16", "10.1.0.1", &path(7));
+    let json = serde_json::to_value(&route).unwrap();
+    assert_eq!(json["as_path"], serde_json::json!(path(7)));
+    let read: RouteEntry = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(read.as_path, route.as_path);
+    assert_eq!(serde_json::to_value(&read).unwrap(), json);
+
+    // Once no route holds them, the paths are released
+    drop((interned, route, read));
+    assert!((0..DISTINCT_PATHS).all(|i| !AsPathInterner::global().contains(&path(i))));
+    assert!(AsPathInterner::global().is_empty());
+}
@@ -0,0 +1,235 @@
+//! Joiners taking ASNs from a Backbone node's registry: concurrent joiners
+//! get distinct ASNs, and a node that picked its ASN itself gives it up to
+//! one holding a grant for it.
+
+mod common;
+
+use common::node;
+
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use vx0net_daemon::config::BootstrapNode;
+use vx0net_daemon::node::asn_registry::{self, AsnGrant, GrantRequest};
+use vx0net_daemon::node::joining::{
+    JoinRejectionCode, JoinRequest, JoinResponse, JoinTransport, NetworkJoiner,
+};
+use vx0net_daemon::node::{NodeError, NodeTier, PeerConnection, Vx0Node};
+use vx0net_daemon::util::clock::Clock;
+
+const REGIONAL_IP: &str = "10.1.0.1";
+const BACKBONE_IP: &str = "10.0.0.1";
+
+/// A Backbone node serving grants on a local port
+async fn registry() -> (Arc<Vx0Node>, SocketAddr) {
+    let backbone = node(NodeTier::Backbone, |_| {});
+    let addr = asn_registry::start(Arc::clone(&backbone), "127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    (backbone, addr)
+}
+
+/// A Regional entry point honouring the registry's grants
+fn regional(registry: &Vx0Node) -> Arc<Vx0Node> {
+    let key = registry.asn_registry.key_hex().unwrap();
+    node(NodeTier::Regional, |config| {
+        config.joining.registry.trusted_keys = vec![key];
+    })
+}
+
+fn edge(hostname: &str, asn: u32) -> Arc<Vx0Node> {
+    node(NodeTier::Edge, |config| {
+        config.node.hostname = hostname.to_string();
+        config.node.asn = asn;
+        config.joining.quorum = 1;
+    })
+}
+
+/// Join requests go to the Regional node's answer, grant requests to the
+/// registry when it is reachable
+struct Network {
+    entry_point: Arc<Vx0Node>,
+    registry: Option<SocketAddr>,
+}
+
+#[async_trait]
+impl JoinTransport for Network {
+    async fn test_connectivity(&self, _peer: &BootstrapNode, _limit: Duration) -> bool {
+        true
+    }
+
+    async fn request_join(
+        &self,
+        _peer: &BootstrapNode,
+        request: &JoinRequest,
+        _limit: Duration,
+    ) -> Result<JoinResponse, NodeError> {
+        Ok(self
+            .entry_point
+            .answer_join(request, request.public_ip)
+            .await)
+    }
+
+    async fn fetch_used_asns(&self, _tier: &NodeTier) -> Result<HashSet<u32>, NodeError> {
+        Ok(HashSet::new())
+    }
+
+    async fn resolve_bootstrap_dns(&self, _hostname: &str) -> Result<Vec<IpAddr>, NodeError> {
+        Ok(Vec::new())
+    }
+
+    async fn request_grant(
+        &self,
+        _registry: &BootstrapNode,
+        request: &GrantRequest,
+        limit: Duration,
+    ) -> Result<AsnGrant, NodeError> {
+        match self.registry {
+            Some(addr) => Ok(asn_registry::request_grant(addr, request, limit).await?),
+            None => Err(NodeError::Network("registry unreachable".to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NoWait;
+
+#[async_trait]
+impl Clock for NoWait {
+    fn now_utc(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now()
+    }
+
+    fn now_monotonic(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+
+    async fn sleep_until(&self, _deadline: std::time::Instant) {}
+}
+
+fn joiner(node: &Arc<Vx0Node>, network: Arc<Network>) -> NetworkJoiner {
+    let seed = |hostname: &str, ip: &str, asn: u32| BootstrapNode {
+        hostname: hostname.to_string(),
+        ip: ip.to_string(),
+        asn,
+    };
+    NetworkJoiner::new(Arc::clone(node))
+        .with_seeds(vec![
+            seed("backbone", BACKBONE_IP, 65001),
+            seed("regional", REGIONAL_IP, 65101),
+        ])
+        .with_transport(network)
+        .with_clock(Arc::new(NoWait))
+}
+
+/// The joiner is connected to the entry point under `asn`
+async fn connect(entry_point: &Vx0Node, joiner: &Vx0Node, asn: u32) {
+    entry_point
+        .add_peer(PeerConnection::new(
+            joiner.node_id,
+            asn,
+            IpAddr::V4(joiner.public_ipv4()),
+        ))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrent_joiners_get_distinct_asns() {
+    let (backbone, addr) = registry().await;
+    let network = Arc::new(Network {
+        entry_point: regional(&backbone),
+        registry: Some(addr),
+    });
+    // Both are configured with the same ASN
+    let first = edge("edge-a.vx0", 66001);
+    let second = edge("edge-b.vx0", 66001);
+
+    let first_joiner = joiner(&first, Arc::clone(&network));
+    let second_joiner = joiner(&second, Arc::clone(&network));
+    let (a, b) = tokio::join!(first_joiner.negotiate(), second_joiner.negotiate());
+    let (a, b) = (a.unwrap(), b.unwrap());
+    assert!(a.accepted && b.accepted);
+    let (a, b) = (a.assigned_asn.unwrap(), b.assigned_asn.unwrap());
+    assert_ne!(a, b);
+    assert!([a, b].iter().all(|asn| (66000..=69999).contains(asn)));
+
+    // The registry logged both, and the entry point learned both grants
+    let logged: Vec<(u32, String)> = backbone
+        .asn_registry
+        .list()
+        .into_iter()
+        .map(|grant| (grant.asn, grant.hostname))
+        .collect();
+    assert_eq!(logged.len(), 2);
+    for grant in backbone.asn_registry.list() {
+        assert!(grant.verify());
+        assert_eq!(
+            network.entry_point.asn_registry.holder(grant.asn),
+            Some(grant)
+        );
+    }
+    assert_eq!(asn_registry::fetch_log(addr).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_self_assigned_node_yields_to_a_grant() {
+    let (backbone, addr) = registry().await;
+    let entry_point = regional(&backbone);
+    let asn = 66001;
+
+    // With the registry out of reach, a node joins on the ASN in its config
+    let squatter = edge("squatter.vx0", asn);
+    let offline = Arc::new(Network {
+        entry_point: Arc::clone(&entry_point),
+        registry: None,
+    });
+    let admitted = joiner(&squatter, Arc::clone(&offline))
+        .negotiate()
+        .await
+        .unwrap();
+    assert!(admitted.accepted);
+    assert_eq!(admitted.assigned_asn, Some(asn));
+    connect(&entry_point, &squatter, asn).await;
+
+    // A node the registry granted that ASN is admitted regardless
+    let holder = edge("holder.vx0", asn);
+    let online = Arc::new(Network {
+        entry_point: Arc::clone(&entry_point),
+        registry: Some(addr),
+    });
+    let granted = joiner(&holder, online).negotiate().await.unwrap();
+    assert!(granted.accepted, "{}", granted.explain_rejection());
+    assert_eq!(granted.assigned_asn, Some(asn));
+    assert_eq!(
+        entry_point.asn_registry.holder(asn).unwrap().node_id,
+        holder.node_id
+    );
+    connect(&entry_point, &holder, asn).await;
+
+    // The self-assigned node is turned away from it, and moves on
+    let request = JoinRequest {
+        node_id: squatter.node_id,
+        hostname: squatter.hostname.clone(),
+        asn,
+        tier: NodeTier::Edge,
+        public_ip: IpAddr::V4(squatter.public_ipv4()),
+        requested_services: vec![],
+        contact_info: None,
+        build: Default::default(),
+        timestamp: chrono::Utc::now(),
+        grant: None,
+    };
+    let refused = entry_point.answer_join(&request, request.public_ip).await;
+    let rejection = refused.rejection.unwrap();
+    assert_eq!(rejection.code, JoinRejectionCode::AsnConflict);
+    assert_ne!(rejection.suggested_asn, Some(asn));
+
+    let moved = joiner(&squatter, offline).negotiate().await.unwrap();
+    assert!(moved.accepted);
+    let moved = moved.assigned_asn.unwrap();
+    assert_ne!(moved, asn);
+    assert_eq!(Some(moved), rejection.suggested_asn);
+}
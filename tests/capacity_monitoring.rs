@@ -0,0 +1,270 @@
+//! Per-tier statistics computed from synthetic directories at various
+//! distributions, the alerts their thresholds raise and clear, the alert
+//! command that receives them, and the digest Regional nodes announce to
+//! Edge nodes.
+
+mod common;
+
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use vx0net_daemon::config::CapacityConfig;
+use vx0net_daemon::monitoring::capacity::{
+    CapacityAlert, CapacityEvent, CapacityMonitor, CapacityReport,
+};
+use vx0net_daemon::network::dns::Vx0DNS;
+use vx0net_daemon::node::capabilities::NodeDirectoryEntry;
+use vx0net_daemon::node::{NodeTier, Vx0Node};
+use vx0net_daemon::util::backoff::RetryPolicy;
+
+fn listing(asn: u32, location: &str) -> NodeDirectoryEntry {
+    NodeDirectoryEntry {
+        node_id: Uuid::new_v4(),
+        hostname: format!("as{}.vx0", asn),
+        asn,
+        address: "10.2.0.1".parse().unwrap(),
+        capabilities: Default::default(),
+        location: location.to_string(),
+        updated: Utc::now(),
+    }
+}
+
+/// `backbones`, `regionals` and `edges` nodes, each on its own ASN
+fn network(backbones: u32, regionals: u32, edges: u32) -> Vec<NodeDirectoryEntry> {
+    (0..backbones)
+        .map(|i| listing(65000 + i, ""))
+        .chain((0..regionals).map(|i| listing(65100 + i, "")))
+        .chain((0..edges).map(|i| listing(66000 + i, "")))
+        .collect()
+}
+
+fn alerts(nodes: &[NodeDirectoryEntry]) -> Vec<CapacityAlert> {
+    CapacityReport::compute(nodes, Utc::now()).alerts(&CapacityConfig::default())
+}
+
+#[test]
+fn test_balanced_network_raises_nothing() {
+    let nodes = network(2, 20, 500);
+    let report = CapacityReport::compute(&nodes, Utc::now());
+
+    let backbone = report.tier(&NodeTier::Backbone);
+    assert_eq!(
+        (backbone.nodes, backbone.asns_used, backbone.range_size),
+        (2, 2, 100)
+    );
+    let edge = report.tier(&NodeTier::Edge);
+    assert_eq!((edge.nodes, edge.range_size), (500, 4000));
+    assert_eq!(edge.used_percent(), 12.5);
+    assert_eq!(report.edges_per_regional, Some(25.0));
+    assert_eq!(report.regionals_per_backbone, Some(10.0));
+    assert_eq!(report.concentration, None);
+    assert!(report.alerts(&CapacityConfig::default()).is_empty());
+}
+
+#[test]
+fn test_nodes_sharing_an_asn_use_it_once() {
+    let mut nodes = network(1, 1, 10);
+    nodes.push(listing(66000, ""));
+    let report = CapacityReport::compute(&nodes, Utc::now());
+    let edge = report.tier(&NodeTier::Edge);
+    assert_eq!((edge.nodes, edge.asns_used), (11, 10));
+}
+
+#[test]
+fn test_edge_range_nearly_exhausted() {
+    // 3200 of 4000 Edge ASNs is exactly the 80% threshold; 40 Regionals
+    // keep the ratio at 80 per Regional
+    let nodes = network(4, 40, 3200);
+    assert_eq!(
+        alerts(&nodes),
+        vec![CapacityAlert::RangeFilling {
+            tier: NodeTier::Edge,
+            asns_used: 3200,
+            range_size: 4000,
+            threshold_percent: 80.0,
+        }]
+    );
+    assert!(alerts(&network(4, 40, 3199)).is_empty());
+}
+
+#[test]
+fn test_thin_regional_layer() {
+    // The hierarchy collapses with 4000 Edge nodes on 3 Regionals
+    let nodes = network(1, 3, 4000);
+    let raised = alerts(&nodes);
+    assert!(raised.contains(&CapacityAlert::ThinRegionals {
+        edges: 4000,
+        regionals: 3,
+        threshold: 100.0,
+    }));
+    assert!(raised.iter().any(|alert| matches!(
+        alert,
+        CapacityAlert::RangeFilling {
+            tier: NodeTier::Edge,
+            ..
+        }
+    )));
+
+    // Edges without any Regional are as thin as it gets; a lone Regional
+    // without a Backbone too
+    let raised = alerts(&network(0, 1, 5).into_iter().skip(1).collect::<Vec<_>>());
+    assert_eq!(
+        raised,
+        vec![CapacityAlert::ThinRegionals {
+            edges: 5,
+            regionals: 0,
+            threshold: 100.0,
+        }]
+    );
+    assert!(matches!(
+        &alerts(&network(0, 1, 0))[..],
+        [CapacityAlert::ThinBackbone {
+            regionals: 1,
+            backbones: 0,
+            ..
+        }]
+    ));
+    assert!(alerts(&network(1, 50, 0)).is_empty());
+    assert!(!alerts(&network(1, 51, 0)).is_empty());
+}
+
+#[test]
+fn test_geographic_concentration() {
+    let mut nodes = network(1, 1, 0);
+    nodes.extend((0..6).map(|i| listing(66000 + i, "Frankfurt")));
+    nodes.extend((0..3).map(|i| listing(66100 + i, "Toronto")));
+    nodes.extend((0..3).map(|i| listing(66200 + i, "Osaka")));
+    let report = CapacityReport::compute(&nodes, Utc::now());
+    let concentration = report.concentration.clone().unwrap();
+    assert_eq!(concentration.location, "Frankfurt");
+    assert_eq!((concentration.nodes, report.located), (6, 12));
+    assert_eq!(concentration.percent, 50.0);
+    assert_eq!(
+        report.alerts(&CapacityConfig::default()),
+        vec![CapacityAlert::Concentrated {
+            location: "Frankfurt".to_string(),
+            nodes: 6,
+            located: 12,
+            threshold_percent: 50.0,
+        }]
+    );
+
+    // Too few located nodes to judge
+    let few: Vec<_> = nodes.iter().take(8).cloned().collect();
+    assert!(alerts(&few).is_empty());
+}
+
+fn events_file() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("vx0net-capacity-{}.jsonl", Uuid::new_v4()))
+}
+
+fn hooks() -> RetryPolicy {
+    RetryPolicy {
+        initial_delay: Duration::from_millis(50),
+        ..RetryPolicy::HOOKS
+    }
+}
+
+async fn read_events(path: &std::path::Path, count: usize) -> Vec<CapacityEvent> {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let events: Vec<CapacityEvent> = std::fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            if events.len() >= count {
+                return events;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("alert command ran")
+}
+
+#[tokio::test]
+async fn test_directory_readings_reach_the_alert_command() {
+    let path = events_file();
+    let config = CapacityConfig {
+        interval_secs: 1,
+        alert_command: vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("cat >> {}; echo >> {}", path.display(), path.display()),
+        ],
+        ..CapacityConfig::default()
+    };
+    let dns = Arc::new(RwLock::new(Vx0DNS::new()));
+    for node in network(1, 2, 250) {
+        dns.write().await.register_node(&node).unwrap();
+    }
+    let monitor = Arc::new(CapacityMonitor::new(config));
+    monitor.start(Arc::clone(&dns), hooks());
+
+    let events = read_events(&path, 1).await;
+    assert_eq!(
+        events,
+        vec![CapacityEvent::Raised(CapacityAlert::ThinRegionals {
+            edges: 250,
+            regionals: 2,
+            threshold: 100.0,
+        })]
+    );
+    let report = monitor.report().unwrap();
+    assert_eq!(report.edges_per_regional, Some(125.0));
+    assert_eq!(monitor.alerts().len(), 1);
+
+    // A third Regional brings the ratio back under the threshold
+    dns.write()
+        .await
+        .register_node(&listing(65102, ""))
+        .unwrap();
+    let events = read_events(&path, 2).await;
+    assert!(matches!(
+        &events[1],
+        CapacityEvent::Cleared(CapacityAlert::ThinRegionals { regionals: 2, .. })
+    ));
+    assert!(monitor.alerts().is_empty());
+    let _ = std::fs::remove_file(&path);
+}
+
+fn node(tier: NodeTier, asn: u32) -> Arc<Vx0Node> {
+    common::node(tier, |config| {
+        config.node.asn = asn;
+        config.node.location = "Frankfurt".to_string();
+    })
+}
+
+#[tokio::test]
+async fn test_regional_announcements_carry_a_digest_to_edges() {
+    let regional = node(NodeTier::Regional, 65101);
+    let edge = node(NodeTier::Edge, 66001);
+    assert!(regional.announcement().await.network_health.is_none());
+
+    regional.capacity.observe(&network(1, 3, 4000));
+    let announcement = regional.announcement().await;
+    assert_eq!(announcement.location, "Frankfurt");
+    let digest = announcement.network_health.clone().unwrap();
+    assert_eq!(digest.asns_used, [1, 3, 4000]);
+    assert!(digest.alerts.contains(&"thin_regionals".to_string()));
+    assert!(digest.alerts.contains(&"range_filling:edge".to_string()));
+
+    edge.observe_announcement(&announcement).await;
+    let heard = edge.capacity.heard().unwrap();
+    assert_eq!(heard.node_id, regional.node_id);
+    assert_eq!(heard.hostname, regional.hostname);
+    assert_eq!(heard.digest, digest);
+
+    // Edge nodes announce none, and one claiming to is not listened to
+    edge.capacity.observe(&network(1, 3, 10));
+    let mut claimed = edge.announcement().await;
+    assert!(claimed.network_health.is_none());
+    claimed.network_health = Some(edge.capacity.digest().unwrap());
+    claimed.timestamp = Utc::now();
+    let other = node(NodeTier::Edge, 66002);
+    other.observe_announcement(&claimed).await;
+    assert!(other.capacity.heard().is_none());
+}
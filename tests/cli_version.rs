@@ -0,0 +1,16 @@
+//! `vx0net version --json` must print nothing but the build metadata.
+
+use std::process::Command;
+use vx0net_daemon::build_info::BuildInfo;
+
+#[test]
+fn version_json_parses() {
+    let output = Command::new(env!("CARGO_BIN_EXE_vx0net"))
+        .args(["version", "--json"])
+        .output()
+        .expect("vx0net runs");
+    assert!(output.status.success());
+
+    let build: BuildInfo = serde_json::from_slice(&output.stdout).expect("stdout is JSON");
+    assert_eq!(build, BuildInfo::current());
+}
@@ -0,0 +1,346 @@
+//! A frontend driving a Regional node through `DaemonClient`: every
+//! command answered in its typed form, a daemon speaking another schema
+//! refused at connect, and an event stream that survives its connection
+//! being cut.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use vx0net_daemon::client::{ClientError, DaemonClient, EventStream, NewService};
+use vx0net_daemon::config::ControlConfig;
+use vx0net_daemon::control::batch::{BatchCommand, BatchOutcome};
+use vx0net_daemon::control::events::DaemonEvent;
+use vx0net_daemon::control::schema::{ControlSchema, SCHEMA_VERSION};
+use vx0net_daemon::control::{
+    ControlErrorCode, ControlReply, ControlRequest, ControlResponse, ControlServer,
+};
+use vx0net_daemon::network::bgp::{BGPDaemon, Prefix};
+use vx0net_daemon::network::dns::Vx0DNS;
+use vx0net_daemon::node::services::ServiceRegistry;
+use vx0net_daemon::node::{NodeTier, Vx0Node};
+
+const ROUTER_ID: &str = "10.2.0.1";
+
+fn temp_socket(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("vx0net-{}-{}.sock", name, Uuid::new_v4()))
+}
+
+/// A Regional node's control socket, served in-process as the daemon does
+async fn daemon() -> (Arc<BGPDaemon>, PathBuf) {
+    let mut config = common::config(NodeTier::Regional);
+    config.network.acl.state_file = None;
+    let node = Arc::new(Vx0Node::new(config).unwrap());
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(node.node_id);
+    let dns = dns.into_shared();
+    node.set_directory(Arc::clone(&dns));
+    let bgp = Arc::new(BGPDaemon::new(65101, ROUTER_ID.parse().unwrap(), 0));
+
+    let socket_path = temp_socket("client");
+    let control = ControlServer::new(
+        ControlConfig {
+            socket_path: socket_path.display().to_string(),
+            ..ControlConfig::default()
+        },
+        Arc::clone(&bgp),
+    );
+    control.set_node(Arc::clone(&node));
+    control.set_directory(Arc::clone(&dns));
+    control.set_services(Arc::new(ServiceRegistry::new(node, dns)));
+    control.start().await.unwrap();
+    (bgp, socket_path)
+}
+
+fn refused(result: Result<impl std::fmt::Debug, ClientError>, expected: ControlErrorCode) {
+    match result {
+        Err(e) => assert_eq!(e.code(), Some(expected), "{}", e),
+        Ok(reply) => panic!("expected {:?}, got {:?}", expected, reply),
+    }
+}
+
+#[tokio::test]
+async fn test_every_command_answers_in_its_typed_form() {
+    let (_bgp, socket_path) = daemon().await;
+    let client = DaemonClient::unix(&socket_path).await.unwrap();
+    assert_eq!(client.daemon(), &client.version().await.unwrap());
+    assert_eq!(
+        client.schema().await.unwrap().schema_version,
+        SCHEMA_VERSION
+    );
+
+    // Routes
+    let network: Prefix = "10.20.0.0/16".parse().unwrap();
+    let version = client
+        .announce_route(network, ROUTER_ID.parse().unwrap(), Vec::new())
+        .await
+        .unwrap();
+    let table = client.routes().await.unwrap();
+    assert_eq!(table.routes.len(), 1);
+    assert_eq!(table.routes[0].network, network);
+    refused(
+        client.routes_received(65002).await,
+        ControlErrorCode::NotFound,
+    );
+    refused(
+        client.routes_advertised(65002).await,
+        ControlErrorCode::NotFound,
+    );
+    client.explain_route(network).await.unwrap();
+    client.propagation_stats().await.unwrap();
+    client.policy_test("", None).await.unwrap();
+    let pinned = client
+        .pin_route(network, 65002, Some(Duration::from_secs(60)), true)
+        .await
+        .unwrap();
+    assert!(pinned >= version);
+    assert_eq!(client.routes().await.unwrap().pins.len(), 1);
+    client.unpin_route(network).await.unwrap();
+    assert!(client.withdraw_route(network).await.unwrap() > version);
+    assert!(client.routes().await.unwrap().routes.is_empty());
+
+    // Services and the directory
+    let registration = client
+        .register_service(NewService::new("wiki", "wiki.vx0", 8080).with_service_type("web"))
+        .await
+        .unwrap();
+    assert_eq!(registration.domain, "wiki.vx0");
+    assert!(registration.propagation.is_none());
+    let listed = client.list_services().await.unwrap();
+    assert_eq!(listed[0].service_id, registration.service_id);
+    assert_eq!(
+        client.refresh_service("wiki.vx0").await.unwrap(),
+        registration.ttl
+    );
+    let found = client
+        .find_services(Some("web".to_string()), Vec::new(), false, false)
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert!(!client
+        .dns_history(Some("wiki.vx0".to_string()))
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(!client.dns_history(None).await.unwrap().is_empty());
+    assert!(client.nodes().await.unwrap().local.is_some());
+    let gone = client
+        .unregister_service("wiki.vx0", None, None)
+        .await
+        .unwrap();
+    assert_eq!((gone.service.name.as_str(), gone.remaining), ("wiki", 0));
+    refused(
+        client.unregister_service("wiki.vx0", None, None).await,
+        ControlErrorCode::NotFound,
+    );
+    client.dns_cache(None, false).await.unwrap();
+    client.dns_cache_flush(None, true).await.unwrap();
+    client.dns_cache_stats().await.unwrap();
+
+    // Peers
+    let blocked = client.block("65099").await.unwrap();
+    assert!(blocked.changed);
+    assert_eq!(blocked.blocked, ["AS65099"]);
+    assert!(!client.block("65099").await.unwrap().changed);
+    assert!(client.unblock("65099").await.unwrap().blocked.is_empty());
+    assert!(client.peers().await.unwrap().peers.is_empty());
+    let disabled = client.peer_disable(65099).await.unwrap();
+    assert!(disabled.admin_down && disabled.peers == 0);
+    assert_eq!(client.peers().await.unwrap().admin_down.len(), 1);
+    assert!(!client.peer_enable(65099).await.unwrap().admin_down);
+    assert!(client.quarantine_list().await.unwrap().is_empty());
+    assert!(!client.quarantine_clear(65099).await.unwrap());
+    refused(
+        client.disconnect("10.9.9.9".parse().unwrap()).await,
+        ControlErrorCode::NotFound,
+    );
+    refused(
+        client.connect("127.0.0.1".parse().unwrap(), 65099).await,
+        ControlErrorCode::Failed,
+    );
+    assert!(client.group().await.unwrap().is_none());
+
+    // What the daemon reports about itself
+    client.bootstrap_list().await.unwrap();
+    client.bootstrap_probe().await.unwrap();
+    client.asn_list().await.unwrap();
+    client.tasks().await.unwrap();
+    client.startup().await.unwrap();
+    assert!(client.capacity().await.unwrap().report.is_none());
+    client.readiness().await.unwrap();
+    client.public_address().await.unwrap();
+    assert!(!client.firewall_status().await.unwrap().manage);
+    refused(client.reload().await, ControlErrorCode::Failed);
+
+    let results = client
+        .batch(
+            vec![
+                BatchCommand {
+                    line: 1,
+                    request: ControlRequest::Routes,
+                },
+                BatchCommand {
+                    line: 2,
+                    request: ControlRequest::Subscribe,
+                },
+            ],
+            false,
+        )
+        .await
+        .unwrap();
+    assert!(matches!(results[0].outcome, BatchOutcome::Applied { .. }));
+    assert!(matches!(
+        results[1].outcome,
+        BatchOutcome::Failed {
+            code: ControlErrorCode::BadRequest,
+            ..
+        }
+    ));
+
+    // Last, since it leaves the node in maintenance
+    let departure = client
+        .maintenance(Some(Duration::from_secs(600)), false)
+        .await
+        .unwrap();
+    assert_eq!(departure.peers, 0);
+    assert!(departure.expected_return.is_some());
+
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+/// Answer every request on `socket_path` as a daemon a schema version
+/// ahead would
+async fn newer_daemon(socket_path: &Path) {
+    let listener = UnixListener::bind(socket_path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let envelope: serde_json::Value = serde_json::from_str(&line).unwrap();
+                let mut schema = ControlSchema::current();
+                schema.schema_version = SCHEMA_VERSION + 1;
+                let reply = ControlReply {
+                    request_id: serde_json::from_value(envelope["request_id"].clone()).unwrap(),
+                    response: ControlResponse::Schema {
+                        schema: Box::new(schema),
+                    },
+                };
+                let mut line = serde_json::to_vec(&reply).unwrap();
+                line.push(b'\n');
+                writer.write_all(&line).await.unwrap();
+            }
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_other_schema_version_is_refused() {
+    let socket_path = temp_socket("newer");
+    newer_daemon(&socket_path).await;
+    match DaemonClient::unix(&socket_path).await {
+        Err(ClientError::SchemaMismatch { daemon, client }) => {
+            assert_eq!((daemon, client), (SCHEMA_VERSION + 1, SCHEMA_VERSION));
+        }
+        other => panic!("expected a schema mismatch, got {:?}", other.map(|_| ())),
+    }
+    let _ = std::fs::remove_file(&socket_path);
+}
+
+/// Forwards connections to the daemon's socket until told to cut them
+struct Proxy {
+    socket_path: PathBuf,
+    links: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Proxy {
+    fn start(target: PathBuf) -> Self {
+        let socket_path = temp_socket("proxy");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let links: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+        let accepted = Arc::clone(&links);
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let target = target.clone();
+                accepted.lock().unwrap().push(tokio::spawn(async move {
+                    let mut daemon = UnixStream::connect(target).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut daemon).await;
+                }));
+            }
+        });
+        Proxy { socket_path, links }
+    }
+
+    /// Drop every connection through the proxy, as a daemon restart would
+    fn cut(&self) {
+        for link in self.links.lock().unwrap().drain(..) {
+            link.abort();
+        }
+    }
+}
+
+async fn next_event(events: &mut EventStream) -> DaemonEvent {
+    tokio::time::timeout(Duration::from_secs(5), events.next())
+        .await
+        .expect("no event within 5s")
+        .unwrap()
+}
+
+/// The next route installed, skipping other events
+async fn next_route(events: &mut EventStream) -> Prefix {
+    loop {
+        if let DaemonEvent::RouteInstalled { route } = next_event(events).await {
+            return route.network;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_events_follow_through_a_reconnect() {
+    let (_bgp, socket_path) = daemon().await;
+    let proxy = Proxy::start(socket_path.clone());
+    let client = DaemonClient::unix(&socket_path).await.unwrap();
+    let mut events = DaemonClient::unix(&proxy.socket_path)
+        .await
+        .unwrap()
+        .subscribe_events()
+        .await
+        .unwrap();
+
+    let first: Prefix = "10.21.0.0/16".parse().unwrap();
+    client
+        .announce_route(first, ROUTER_ID.parse().unwrap(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(next_route(&mut events).await, first);
+
+    client
+        .register_service(NewService::new("chat", "chat.vx0", 6667))
+        .await
+        .unwrap();
+    loop {
+        if let DaemonEvent::ZoneChanged { names, .. } = next_event(&mut events).await {
+            assert!(names.iter().any(|name| name == "chat.vx0"));
+            break;
+        }
+    }
+
+    // Whatever was still in flight may arrive first
+    proxy.cut();
+    while !matches!(next_event(&mut events).await, DaemonEvent::Resubscribed) {}
+
+    let second: Prefix = "10.22.0.0/16".parse().unwrap();
+    client
+        .announce_route(second, ROUTER_ID.parse().unwrap(), Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(next_route(&mut events).await, second);
+
+    let _ = std::fs::remove_file(&socket_path);
+    let _ = std::fs::remove_file(&proxy.socket_path);
+}
@@ -0,0 +1,123 @@
+//! Timers driven by a manual clock: moving it forward rekeys tunnels,
+//! lapses service leases and closes stalled BGP connections at once, while
+//! stepping the wall clock, backwards or forwards, trips none of them.
+
+mod common;
+
+use common::wait_for;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use uuid::Uuid;
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::network::ike::tunnels::TunnelManager;
+use vx0net_daemon::node::{HostedService, NodeTier, ServiceStatus, ServiceType, Vx0Node};
+use vx0net_daemon::util::clock::{Clock, ManualClock, SharedClock};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Long enough for woken tasks to act, if they were going to
+async fn settle() {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+fn manual() -> (Arc<ManualClock>, SharedClock) {
+    let clock = Arc::new(ManualClock::new());
+    (Arc::clone(&clock), clock)
+}
+
+#[tokio::test]
+async fn test_tunnels_rekey_as_the_clock_passes_the_interval() {
+    let (clock, shared) = manual();
+    let manager = Arc::new(TunnelManager::new().with_rekey_interval(Some(HOUR)));
+    manager.set_clock(shared);
+    let id = manager
+        .create_tunnel(
+            "10.2.0.1".parse().unwrap(),
+            "10.1.0.1".parse().unwrap(),
+            "10.1.0.1:4500".parse().unwrap(),
+            b"test-psk",
+        )
+        .await
+        .unwrap();
+    let keyed_at = manager.get_tunnel(&id).await.unwrap().keyed_at;
+    manager.spawn_rekeying();
+
+    // Neither a wall-clock jump either way nor most of the interval
+    // makes the keys old
+    clock.step_wall(chrono::Duration::hours(-24));
+    clock.step_wall(chrono::Duration::hours(48));
+    clock.advance(HOUR - Duration::from_secs(60));
+    settle().await;
+    assert_eq!(manager.get_tunnel(&id).await.unwrap().keyed_at, keyed_at);
+
+    clock.advance(Duration::from_secs(120));
+    wait_for("the scheduled rekey", || async {
+        manager.get_tunnel(&id).await.unwrap().keyed_at > keyed_at
+    })
+    .await;
+    let tunnel = manager.get_tunnel(&id).await.unwrap();
+    assert_eq!(tunnel.keyed_at, clock.now_monotonic());
+    assert_eq!(manager.rekey_due().await, 0);
+}
+
+#[tokio::test]
+async fn test_service_leases_lapse_on_the_monotonic_clock() {
+    let (clock, shared) = manual();
+    let config = common::config(NodeTier::Edge);
+    let ttl = config.services.service_ttl.get();
+    let node = Vx0Node::new(config).unwrap().with_clock(shared);
+    node.register_service(HostedService {
+        service_id: Uuid::new_v4(),
+        name: "wiki".to_string(),
+        service_type: ServiceType::WebServer,
+        domain: "wiki.vx0".to_string(),
+        port: 80,
+        status: ServiceStatus::Running,
+        metadata: Default::default(),
+    })
+    .await
+    .unwrap();
+
+    clock.step_wall(chrono::Duration::days(-1));
+    assert!(node.expire_services().await.is_empty());
+    clock.step_wall(chrono::Duration::days(7));
+    assert!(node.expire_services().await.is_empty());
+
+    clock.advance(ttl - Duration::from_secs(1));
+    assert!(node.expire_services().await.is_empty());
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(node.expire_services().await.len(), 1);
+    assert!(node.services.read().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_stalled_bgp_connection_is_closed_when_the_clock_passes_setup() {
+    let (clock, shared) = manual();
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    let mut bgp = BGPDaemon::new(65001, localhost, 0);
+    bgp.set_clock(shared).await;
+    bgp.start().await.unwrap();
+    let addr = SocketAddr::new(localhost, bgp.local_addr().unwrap().port());
+    let setup_timeout = Duration::from_secs(120);
+
+    // Connects and never sends an OPEN
+    let _stalled = TcpStream::connect(addr).await.unwrap();
+    wait_for("the connection", || async {
+        bgp.connection_count().await == 1
+    })
+    .await;
+
+    clock.step_wall(chrono::Duration::hours(-6));
+    clock.advance(setup_timeout - Duration::from_secs(1));
+    settle().await;
+    clock.step_wall(chrono::Duration::hours(12));
+    settle().await;
+    assert_eq!(bgp.connection_count().await, 1);
+
+    // The sweep runs every few seconds of clock time
+    clock.advance(Duration::from_secs(10));
+    wait_for("the sweep", || async { bgp.connection_count().await == 0 }).await;
+}
@@ -0,0 +1,61 @@
+//! Helpers shared by the integration tests.
+
+// Each test binary uses only some of these
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use vx0net_daemon::config::Vx0Config;
+use vx0net_daemon::network::bgp::{AsPath, BGPOrigin, RouteEntry};
+use vx0net_daemon::node::{NodeTier, Vx0Node};
+
+/// Poll `check` until it holds, failing the test after ten seconds
+pub async fn wait_for<F, Fut>(what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !check().await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+}
+
+/// A route to `network` through `next_hop` along `as_path`, with default
+/// attributes, as a peer would announce it
+pub fn route(network: &str, next_hop: &str, as_path: &[u32]) -> RouteEntry {
+    RouteEntry {
+        network: network.parse().unwrap(),
+        next_hop: next_hop.parse().unwrap(),
+        as_path: AsPath::new(as_path),
+        origin: BGPOrigin::IGP,
+        local_pref: 100,
+        med: 0,
+        communities: vec![],
+        timestamp: chrono::Utc::now(),
+        learned_from: None,
+        originated_at: None,
+    }
+}
+
+/// The configuration shipped in `config/` for a node of `tier`
+pub fn config(tier: NodeTier) -> Vx0Config {
+    let shipped = match tier {
+        NodeTier::Backbone => include_str!("../../config/backbone-node.toml"),
+        NodeTier::Regional => include_str!("../../config/regional-node.toml"),
+        NodeTier::Edge => include_str!("../../config/edge-node.toml"),
+    };
+    toml::from_str(shipped).unwrap()
+}
+
+/// A node of `tier` built from its shipped configuration once `configure`
+/// has adjusted it
+pub fn node(tier: NodeTier, configure: impl FnOnce(&mut Vx0Config)) -> Arc<Vx0Node> {
+    let mut config = config(tier);
+    configure(&mut config);
+    Arc::new(Vx0Node::new(config).unwrap())
+}
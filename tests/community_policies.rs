@@ -0,0 +1,106 @@
+//! Paths tagged with the well-known prefer and backup communities are
+//! ranked by the receiver's community mapping, and a reloaded mapping
+//! reselects the paths already learned.
+
+mod common;
+
+use std::sync::Arc;
+use tokio::sync::watch;
+use vx0net_daemon::config::reload::Reloader;
+use vx0net_daemon::network::bgp::communities::{self, CommunityPolicy};
+use vx0net_daemon::network::bgp::{BGPDaemon, Community, Prefix, RouteEntry};
+use vx0net_daemon::node::NodeTier;
+
+const PREFIX: &str = "10.60.0.0/16";
+const PREFERRED_ASN: u32 = 65001;
+const BACKUP_ASN: u32 = 65002;
+
+fn route(peer_asn: u32, community: Community) -> RouteEntry {
+    RouteEntry {
+        communities: vec![community],
+        ..common::route(PREFIX, "172.16.0.1", &[peer_asn])
+    }
+}
+
+async fn selected(bgp: &BGPDaemon) -> Option<u32> {
+    let network: Prefix = PREFIX.parse().unwrap();
+    bgp.get_routes()
+        .await
+        .into_iter()
+        .find(|route| route.network == network)
+        .and_then(|route| route.learned_from)
+        .map(|peer| peer.asn)
+}
+
+#[tokio::test]
+async fn test_selection_follows_the_community_mapping() {
+    let config = common::config(NodeTier::Backbone);
+    assert_eq!(
+        config.network.bgp.community_policies,
+        communities::well_known_policies()
+    );
+    let bgp = Arc::new(BGPDaemon::new(65000, "10.2.0.1".parse().unwrap(), 0));
+    bgp.set_community_policies(config.network.bgp.community_policies.clone())
+        .await
+        .unwrap();
+
+    // Announced with the backup path first, so arrival order cannot decide
+    bgp.receive_update(
+        BACKUP_ASN,
+        vec![route(BACKUP_ASN, communities::BACKUP)],
+        &[],
+    )
+    .await
+    .unwrap();
+    bgp.receive_update(
+        PREFERRED_ASN,
+        vec![route(PREFERRED_ASN, communities::PREFER)],
+        &[],
+    )
+    .await
+    .unwrap();
+    assert_eq!(selected(&bgp).await, Some(PREFERRED_ASN));
+
+    let network: Prefix = PREFIX.parse().unwrap();
+    let explanation = bgp.explain_route(&network).await;
+    assert_eq!(explanation.installed.as_ref().unwrap().local_pref, 200);
+    let shown = explanation.to_string();
+    assert!(shown.contains("community 65535:512: local_pref 100 -> 200"));
+    assert!(shown.contains("community 65535:514: local_pref 100 -> 50"));
+
+    // The operator decides the backup path should carry traffic after all
+    let privacy = watch::Sender::new(config.services.discovery_privacy);
+    let reloader = Reloader::new(config.clone(), Arc::clone(&bgp), privacy);
+    let mut reloaded = config.clone();
+    reloaded.network.bgp.community_policies = vec![
+        CommunityPolicy::new(communities::PREFER).with_local_pref(200),
+        CommunityPolicy::new(communities::BACKUP).with_local_pref(300),
+    ];
+    let report = reloader.reload(&reloaded).await.unwrap();
+    assert_eq!(report.applied(), vec!["network.bgp.community_policies"]);
+    assert_eq!(selected(&bgp).await, Some(BACKUP_ASN));
+    let shown = bgp.explain_route(&network).await.to_string();
+    assert!(shown.contains("community 65535:514: local_pref 100 -> 300"));
+
+    // A community mapped twice is refused and the mapping in effect stays
+    let mut duplicated = reloaded.clone();
+    duplicated
+        .network
+        .bgp
+        .community_policies
+        .push(CommunityPolicy::new(communities::BACKUP).with_local_pref(10));
+    let report = reloader.reload(&duplicated).await.unwrap();
+    assert_eq!(report.invalid(), vec!["network.bgp.community_policies"]);
+    assert_eq!(selected(&bgp).await, Some(BACKUP_ASN));
+}
+
+#[test]
+fn test_communities_parse_by_name_or_number() {
+    assert_eq!("backup".parse::<Community>().unwrap(), communities::BACKUP);
+    assert_eq!(
+        "65535:512".parse::<Community>().unwrap(),
+        communities::PREFER
+    );
+    assert!("65535".parse::<Community>().is_err());
+    assert!("70000:1".parse::<Community>().is_err());
+}
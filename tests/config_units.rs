@@ -0,0 +1,235 @@
+//! Durations and sizes in config files: every accepted form, bare numbers
+//! from older files, the humane form written on save, range errors naming
+//! the key, and warnings for values probably in the wrong unit.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use uuid::Uuid;
+use vx0net_daemon::config::units::{ByteSize, ConfigDuration};
+use vx0net_daemon::Vx0Config;
+
+struct ConfigFile(PathBuf);
+
+impl ConfigFile {
+    fn new(content: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("vx0net-units-{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        ConfigFile(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    fn load(&self) -> Result<Vx0Config, config::ConfigError> {
+        Vx0Config::load_file(self.path())
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn with(bgp: &str, services: &str, recorder: &str) -> ConfigFile {
+    ConfigFile::new(&format!(
+        "[network.bgp]\n{}\n[services]\n{}\n[monitoring.recorder]\n{}\n",
+        bgp, services, recorder
+    ))
+}
+
+#[test]
+fn test_humane_and_bare_forms_load_alike() {
+    let humane = with(
+        "hold_time = \"1m30s\"\nkeepalive_time = \"30s\"",
+        "service_ttl = \"5m\"",
+        "max_size_bytes = \"16MB\"",
+    )
+    .load()
+    .unwrap();
+    let bare = with(
+        "hold_time = 90\nkeepalive_time = 30",
+        "service_ttl = 300",
+        "max_size_bytes = 16777216",
+    )
+    .load()
+    .unwrap();
+
+    for config in [&humane, &bare] {
+        assert_eq!(config.network.bgp.hold_time.get(), Duration::from_secs(90));
+        assert_eq!(config.network.bgp.hold_time_secs(), 90);
+        assert_eq!(config.network.bgp.keepalive_time.as_secs(), 30);
+        assert_eq!(config.services.service_ttl.as_secs(), 300);
+        assert_eq!(config.monitoring.recorder.max_size_bytes.get(), 16 << 20);
+        assert!(config.unit_warnings().is_empty());
+    }
+
+    // Compound forms, and a number quoted as a string as environment
+    // variables deliver them
+    let config = with("hold_time = \"1h 30m\"", "service_ttl = \"300\"", "")
+        .load()
+        .unwrap();
+    assert_eq!(config.network.bgp.hold_time.as_secs(), 5400);
+    assert_eq!(config.services.service_ttl.as_secs(), 300);
+}
+
+#[test]
+fn test_save_writes_the_humane_form_back() {
+    let config = with(
+        "hold_time = 90\nkeepalive_time = 30",
+        "service_ttl = 7200",
+        "max_size_bytes = 1048576",
+    )
+    .load()
+    .unwrap();
+    let saved = ConfigFile::new("");
+    config.save(saved.path()).unwrap();
+
+    let written = std::fs::read_to_string(saved.path()).unwrap();
+    assert!(written.contains("hold_time = \"1m30s\""), "{}", written);
+    assert!(written.contains("service_ttl = \"2h\""), "{}", written);
+    assert!(written.contains("max_size_bytes = \"1MB\""), "{}", written);
+    assert!(
+        written.contains("max_bytes_per_origin = \"256KB\""),
+        "{}",
+        written
+    );
+
+    let reloaded = saved.load().unwrap();
+    assert_eq!(reloaded.network.bgp.hold_time, config.network.bgp.hold_time);
+    assert_eq!(
+        reloaded.services.service_ttl,
+        ConfigDuration::from_secs(7200)
+    );
+    assert_eq!(
+        reloaded.monitoring.recorder.max_size_bytes,
+        ByteSize::mib(1)
+    );
+    assert_eq!(
+        toml::to_string(&reloaded).unwrap(),
+        toml::to_string(&config).unwrap()
+    );
+}
+
+fn load_error(file: ConfigFile) -> String {
+    file.load().unwrap_err().to_string()
+}
+
+#[test]
+fn test_errors_name_the_key_and_the_accepted_forms() {
+    let error = load_error(with("hold_time = \"90 parsecs\"", "", ""));
+    assert!(error.contains("hold_time"), "{}", error);
+    assert!(error.contains("90s, 5m, 1h30m"), "{}", error);
+
+    let error = load_error(with("", "", "max_size_bytes = \"16 megs\""));
+    assert!(error.contains("max_size_bytes"), "{}", error);
+    assert!(error.contains("64KB, 10MB"), "{}", error);
+
+    let error = load_error(with("hold_time = -5", "", ""));
+    assert!(error.contains("hold_time"), "{}", error);
+
+    // Forms that parse but fall outside the key's range
+    let error = load_error(with("hold_time = 2", "", ""));
+    assert!(
+        error.contains("network.bgp.hold_time = 2s is outside 3s..=18h12m15s"),
+        "{}",
+        error
+    );
+    let error = load_error(with("hold_time = 90000", "", ""));
+    assert!(error.contains("network.bgp.hold_time = 1d1h"), "{}", error);
+    let error = load_error(with("", "service_ttl = \"0s\"", ""));
+    assert!(error.contains("services.service_ttl"), "{}", error);
+    let error = load_error(with("", "", "max_size_bytes = 100"));
+    assert!(
+        error
+            .contains("monitoring.recorder.max_size_bytes = 100B is under the least accepted, 1KB"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_unit_suspicious_values_warn() {
+    // Written as milliseconds, but loaded as seconds
+    let config = with("hold_time = 9000\nkeepalive_time = 3000", "", "")
+        .load()
+        .unwrap();
+    let warnings: Vec<String> = config
+        .unit_warnings()
+        .iter()
+        .map(|warning| warning.to_string())
+        .collect();
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert_eq!(
+        warnings[0],
+        "network.bgp.hold_time = 2h30m: unusually long, over 1h; bare numbers are seconds, \
+         so write \"9s\" if 9000 milliseconds were meant"
+    );
+    assert!(warnings[1].starts_with("network.bgp.keepalive_time = 50m: unusually long"));
+
+    // A keepalive that cannot fit three times into the hold time
+    let config = with("hold_time = 90\nkeepalive_time = 60", "", "")
+        .load()
+        .unwrap();
+    let warnings = config.unit_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].key, "network.bgp.keepalive_time");
+    assert!(warnings[0].reason.contains("third of hold_time (1m30s)"));
+
+    // Megabytes written as a bare number of bytes
+    let config = with("", "service_ttl = \"2d\"", "max_size_bytes = 16384")
+        .load()
+        .unwrap();
+    let keys: Vec<&str> = config
+        .unit_warnings()
+        .iter()
+        .map(|warning| warning.key)
+        .collect();
+    assert_eq!(
+        keys,
+        vec!["services.service_ttl", "monitoring.recorder.max_size_bytes"]
+    );
+}
+
+#[test]
+fn test_check_config_command_reports_warnings() {
+    let check = |file: &ConfigFile| {
+        Command::new(env!("CARGO_BIN_EXE_vx0net"))
+            .args(["check-config", "--file", file.path()])
+            .output()
+            .expect("vx0net runs")
+    };
+
+    // Builds without every subsystem also warn about the settings they ignore
+    let file = with("hold_time = 9000", "", "");
+    let missing = file.load().unwrap().feature_warnings().len();
+    let output = check(&file);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("network.bgp.hold_time = 2h30m"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains(&format!("{} warning(s)", 1 + missing)),
+        "{}",
+        stdout
+    );
+
+    let output = check(&with("hold_time = \"1m30s\"", "", ""));
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if missing == 0 {
+        assert!(stdout.contains("valid"), "{}", stdout);
+    } else {
+        assert!(!stdout.contains("hold_time"), "{}", stdout);
+    }
+
+    let output = check(&with("hold_time = 1", "", ""));
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("network.bgp.hold_time = 1s"), "{}", stderr);
+}
@@ -0,0 +1,63 @@
+//! `vx0net start --daemon` detaches, returns once the daemon is ready and
+//! leaves it running in its own session. Binds the lab profile's ports, so
+//! it only runs on request: `cargo test --test daemon_mode -- --ignored`.
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+fn pid_alive(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+#[test]
+#[ignore]
+fn start_daemon_detaches_and_reports_ready() {
+    let dir = std::env::temp_dir().join(format!("vx0net-daemon-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("daemon.log");
+    std::fs::write(
+        dir.join("vx0net.toml"),
+        format!(
+            "profile = \"lab\"\n[monitoring.daemon]\nlog_file = {:?}\n",
+            log.display().to_string()
+        ),
+    )
+    .unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_vx0net"))
+        .args(["start", "--daemon"])
+        .current_dir(&dir)
+        .env_remove("NOTIFY_SOCKET")
+        .output()
+        .expect("vx0net runs");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let pid: i32 = stdout
+        .split("pid ")
+        .nth(1)
+        .and_then(|rest| rest.split(')').next())
+        .and_then(|pid| pid.parse().ok())
+        .unwrap_or_else(|| panic!("no pid in {stdout:?}"));
+    assert!(pid_alive(pid));
+    // A session of its own, which it doesn't lead, so it can never
+    // acquire a controlling terminal
+    let session = unsafe { libc::getsid(pid) };
+    assert_ne!(session, unsafe { libc::getsid(0) });
+    assert_ne!(session, pid);
+    let logged = std::fs::read_to_string(&log).unwrap();
+    assert!(logged.contains("All subsystems started"), "{logged}");
+
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while pid_alive(pid) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!pid_alive(pid), "daemon ignored SIGTERM");
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,329 @@
+//! The answer cache behind the local DNS server: what queries put in it,
+//! where each answer came from, flushing by name pattern with negative
+//! answers listed and flushed on their own, and flushes racing lookups.
+
+#![cfg(feature = "dns-server")]
+
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use vx0net_daemon::network::dns::cache::{
+    CacheFilter, CacheSource, CachedAnswer, ResolverCache, DEFAULT_NEGATIVE_TTL,
+};
+use vx0net_daemon::network::dns::server::Vx0DNSServer;
+use vx0net_daemon::network::dns::wire::{Query, Rcode, Response, TYPE_A, TYPE_AAAA};
+use vx0net_daemon::network::dns::Vx0DNS;
+use vx0net_daemon::node::NodeTier;
+
+const TTL: Duration = Duration::from_secs(60);
+
+fn address(address: &str) -> CachedAnswer {
+    CachedAnswer::Addresses(vec![address.parse().unwrap()])
+}
+
+fn filter(pattern: Option<&str>, negative_only: bool) -> CacheFilter {
+    CacheFilter::new(pattern, negative_only).unwrap()
+}
+
+/// Names under community1.vx0 and elsewhere, positive and negative
+fn populated() -> ResolverCache {
+    let cache = ResolverCache::new(100, DEFAULT_NEGATIVE_TTL);
+    let entries = [
+        (
+            "forum.community1.vx0",
+            TYPE_A,
+            address("10.0.3.7"),
+            CacheSource::Local,
+        ),
+        (
+            "wiki.community1.vx0",
+            TYPE_A,
+            address("10.0.3.8"),
+            CacheSource::Synced,
+        ),
+        (
+            "wiki.community1.vx0",
+            TYPE_AAAA,
+            CachedAnswer::NoData,
+            CacheSource::Synced,
+        ),
+        (
+            "gone.community1.vx0",
+            TYPE_A,
+            CachedAnswer::NxDomain,
+            CacheSource::Forwarded,
+        ),
+        (
+            "community1.vx0",
+            TYPE_A,
+            address("10.0.3.1"),
+            CacheSource::Local,
+        ),
+        (
+            "shop.vx0",
+            TYPE_A,
+            address("10.0.4.2"),
+            CacheSource::Forwarded,
+        ),
+        (
+            "nowhere.vx0",
+            TYPE_A,
+            CachedAnswer::NxDomain,
+            CacheSource::Forwarded,
+        ),
+    ];
+    for (name, qtype, answer, source) in entries {
+        cache.insert(name, qtype, answer, source, TTL);
+    }
+    cache
+}
+
+fn names(cache: &ResolverCache, filter: &CacheFilter) -> Vec<(String, u16)> {
+    cache
+        .list(filter)
+        .into_iter()
+        .map(|entry| (entry.name, entry.qtype))
+        .collect()
+}
+
+#[test]
+fn test_flush_by_suffix_leaves_other_names() {
+    let cache = populated();
+    assert!(cache.get("forum.community1.vx0", TYPE_A).is_some());
+    assert!(cache.get("forum.community1.vx0", TYPE_AAAA).is_none());
+
+    let suffix = filter(Some("*.community1.vx0"), false);
+    let listed = cache.list(&suffix);
+    assert_eq!(listed.len(), 4);
+    let forum = &listed[0];
+    assert_eq!(forum.name, "forum.community1.vx0");
+    assert_eq!((forum.source, forum.hits), (CacheSource::Local, 1));
+    assert!(forum.ttl_remaining_secs > 50 && forum.ttl_remaining_secs <= 60);
+
+    assert_eq!(cache.flush(&suffix), 4);
+    assert!(cache.list(&suffix).is_empty());
+    assert_eq!(
+        names(&cache, &CacheFilter::default()),
+        vec![
+            ("community1.vx0".to_string(), TYPE_A),
+            ("nowhere.vx0".to_string(), TYPE_A),
+            ("shop.vx0".to_string(), TYPE_A),
+        ]
+    );
+
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.negative_entries), (3, 1));
+    assert_eq!((stats.hits, stats.misses, stats.flushed), (1, 1, 4));
+}
+
+#[test]
+fn test_negative_answers_flush_on_their_own() {
+    let cache = populated();
+    let negative = filter(None, true);
+    assert_eq!(
+        names(&cache, &negative),
+        vec![
+            ("gone.community1.vx0".to_string(), TYPE_A),
+            ("nowhere.vx0".to_string(), TYPE_A),
+            ("wiki.community1.vx0".to_string(), TYPE_AAAA),
+        ]
+    );
+
+    // Only the negatives under the suffix, then the rest of them
+    assert_eq!(cache.flush(&filter(Some("*.community1.vx0"), true)), 2);
+    assert!(cache.get("wiki.community1.vx0", TYPE_A).is_some());
+    assert!(cache.get("gone.community1.vx0", TYPE_A).is_none());
+    assert_eq!(cache.flush(&negative), 1);
+    assert!(cache.list(&negative).is_empty());
+
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.negative_entries), (4, 0));
+    assert_eq!(stats.flushed, 3);
+}
+
+#[test]
+fn test_flush_of_one_name() {
+    let cache = populated();
+    // Both query types of the name, and no other name
+    assert_eq!(cache.flush(&filter(Some("Wiki.Community1.vx0."), false)), 2);
+    assert_eq!(cache.stats().entries, 5);
+    assert_eq!(cache.flush(&filter(Some("wiki.community1.vx0"), false)), 0);
+
+    assert!(CacheFilter::new(Some("wiki*.vx0"), false).is_err());
+    assert!(CacheFilter::new(Some("*."), false).is_err());
+}
+
+#[test]
+fn test_full_cache_evicts_and_counts() {
+    let cache = ResolverCache::new(3, DEFAULT_NEGATIVE_TTL);
+    for i in 0..5 {
+        cache.insert(
+            &format!("host{}.vx0", i),
+            TYPE_A,
+            address(&format!("10.0.5.{}", i)),
+            CacheSource::Forwarded,
+            TTL,
+        );
+    }
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.evictions), (3, 2));
+    assert!(cache.get("host0.vx0", TYPE_A).is_none());
+    assert!(cache.get("host4.vx0", TYPE_A).is_some());
+
+    // Size 0 turns caching off
+    let off = ResolverCache::new(0, DEFAULT_NEGATIVE_TTL);
+    off.insert(
+        "host.vx0",
+        TYPE_A,
+        address("10.0.5.1"),
+        CacheSource::Local,
+        TTL,
+    );
+    assert_eq!(off.stats().entries, 0);
+}
+
+async fn serve(dns: &Arc<RwLock<Vx0DNS>>) -> SocketAddr {
+    let mut config = common::config(NodeTier::Edge);
+    config.network.dns.listen_port = 0;
+    config.network.dns.vx0_dns_servers.clear();
+    let bound = Vx0DNSServer::from_config(&config.network.dns, Arc::clone(dns))
+        .start()
+        .await
+        .unwrap();
+    SocketAddr::from(([127, 0, 0, 1], bound.port()))
+}
+
+async fn ask(server: SocketAddr, name: &str) -> Response {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client
+        .send_to(&Query::new(7, name, TYPE_A).to_bytes(), server)
+        .await
+        .unwrap();
+    let mut buf = [0; 4096];
+    let size = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    Response::parse(&buf[..size]).unwrap()
+}
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_queries_fill_the_cache_and_changes_clear_it() {
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(Uuid::new_v4());
+    dns.register_service("forum.community1.vx0".to_string(), ip("10.0.3.7"))
+        .unwrap();
+    let dns = Arc::new(RwLock::new(dns));
+    let cache = Arc::clone(dns.read().await.cache());
+    let server = serve(&dns).await;
+
+    for _ in 0..3 {
+        assert_eq!(
+            ask(server, "forum.community1.vx0").await.addresses,
+            vec![ip("10.0.3.7")]
+        );
+    }
+    let missing = ask(server, "wiki.community1.vx0").await;
+    assert_eq!(missing.rcode, Some(Rcode::NxDomain));
+
+    let listed = cache.list(&CacheFilter::default());
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].name, "forum.community1.vx0");
+    assert_eq!((listed[0].source, listed[0].hits), (CacheSource::Local, 2));
+    assert_eq!(listed[1].answer, CachedAnswer::NxDomain);
+    assert_eq!(listed[1].source, CacheSource::Forwarded);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (2, 2));
+
+    // Registering the missing name drops its negative answer at once
+    dns.write()
+        .await
+        .register_service("wiki.community1.vx0".to_string(), ip("10.0.3.8"))
+        .unwrap();
+    assert!(cache
+        .list(&CacheFilter::new(None, true).unwrap())
+        .is_empty());
+    assert_eq!(
+        ask(server, "wiki.community1.vx0").await.addresses,
+        vec![ip("10.0.3.8")]
+    );
+
+    // Records synced from another node are told apart from our own
+    let mut remote = Vx0DNS::new();
+    remote.set_origin(Uuid::new_v4());
+    remote
+        .register_service("shop.vx0".to_string(), ip("10.0.4.2"))
+        .unwrap();
+    let transfer = remote.transfer_since("vx0", 0).unwrap();
+    dns.write().await.apply_transfer(transfer).unwrap();
+    assert_eq!(
+        ask(server, "shop.vx0").await.addresses,
+        vec![ip("10.0.4.2")]
+    );
+    let shop = cache.list(&CacheFilter::new(Some("shop.vx0"), false).unwrap());
+    assert_eq!(shop[0].source, CacheSource::Synced);
+}
+
+#[tokio::test]
+async fn test_lookups_racing_a_flush_repopulate_it() {
+    let dns = Arc::new(RwLock::new(Vx0DNS::new()));
+    for i in 0..20 {
+        dns.write()
+            .await
+            .register_service(
+                format!("host{}.community1.vx0", i),
+                ip(&format!("10.0.6.{}", i)),
+            )
+            .unwrap();
+    }
+    let cache = Arc::clone(dns.read().await.cache());
+    let server = serve(&dns).await;
+    let everything = CacheFilter::new(Some("*.community1.vx0"), false).unwrap();
+
+    let lookups: Vec<_> = (0..20)
+        .map(|i| {
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    let reply = ask(server, &format!("host{}.community1.vx0", i)).await;
+                    assert_eq!(reply.addresses, vec![ip(&format!("10.0.6.{}", i))]);
+                }
+            })
+        })
+        .collect();
+    let flushing = {
+        let cache = Arc::clone(&cache);
+        let everything = everything.clone();
+        tokio::spawn(async move {
+            let mut flushed = 0;
+            for _ in 0..50 {
+                flushed += cache.flush(&everything);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            flushed
+        })
+    };
+    for lookup in lookups {
+        lookup.await.unwrap();
+    }
+    let flushed = flushing.await.unwrap();
+
+    // Every query was answered correctly and counted once
+    let stats = cache.stats();
+    assert_eq!(stats.hits + stats.misses, 200);
+    assert_eq!(stats.flushed, flushed as u64);
+
+    // Whatever the flushes left, the next lookups cache their answers again
+    for i in 0..20 {
+        ask(server, &format!("host{}.community1.vx0", i)).await;
+    }
+    assert_eq!(cache.list(&everything).len(), 20);
+}
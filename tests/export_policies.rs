@@ -0,0 +1,144 @@
+//! Export templates shaping how an Edge node's routes look to its two
+//! Regional parents: prepending toward the backup makes the Backbone above
+//! them prefer the primary, and moving the template at runtime refreshes
+//! both parents and flips the preference.
+
+mod common;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::watch;
+use vx0net_daemon::config::reload::Reloader;
+use vx0net_daemon::network::bgp::export::{ExportMatch, ExportPolicy};
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin, RouteEntry};
+use vx0net_daemon::node::NodeTier;
+
+const EDGE_ASN: u32 = 66001;
+const PRIMARY: u32 = 65101;
+const BACKUP: u32 = 65102;
+const BACKBONE_ASN: u32 = 65001;
+const PREFIX: &str = "10.66.1.0/24";
+
+fn prepend_toward(peer_asn: u32) -> ExportPolicy {
+    let toward = ExportMatch {
+        peer_asn: Some(peer_asn),
+        prefix_list: vec!["10.66.0.0/16".parse().unwrap()],
+        ..Default::default()
+    };
+    ExportPolicy::new("backup-transit", toward).with_prepend(2)
+}
+
+/// The Edge node, its parents and the Backbone, with routes carried
+/// between them as their sessions would
+struct Network {
+    edge: Arc<BGPDaemon>,
+    parents: Vec<(u32, BGPDaemon)>,
+    backbone: BGPDaemon,
+}
+
+impl Network {
+    async fn new() -> Self {
+        let edge = Arc::new(BGPDaemon::new(EDGE_ASN, "10.66.1.1".parse().unwrap(), 0));
+        edge.add_route(
+            PREFIX.parse().unwrap(),
+            "10.66.1.1".parse().unwrap(),
+            BGPOrigin::IGP,
+        )
+        .await
+        .unwrap();
+        let parents = [PRIMARY, BACKUP]
+            .into_iter()
+            .map(|asn| {
+                let router_id: IpAddr = format!("10.1.0.{}", asn - 65100).parse().unwrap();
+                (asn, BGPDaemon::new(asn, router_id, 0))
+            })
+            .collect();
+        Network {
+            edge,
+            parents,
+            backbone: BGPDaemon::new(BACKBONE_ASN, "10.0.0.1".parse().unwrap(), 0),
+        }
+    }
+
+    /// Advertise the Edge node's routes to each parent, and the parents'
+    /// paths on to the Backbone
+    async fn pump(&self) {
+        for (asn, parent) in &self.parents {
+            let exports = self.edge.advertise_to(*asn).await.unwrap();
+            parent.receive_update(EDGE_ASN, exports, &[]).await.unwrap();
+            let upward: Vec<RouteEntry> = parent
+                .get_routes()
+                .await
+                .into_iter()
+                .filter(|route| route.network == PREFIX.parse().unwrap())
+                .map(|mut route| {
+                    route.as_path = route.as_path.prepended(*asn, 1);
+                    route
+                })
+                .collect();
+            self.backbone
+                .receive_update(*asn, upward, &[])
+                .await
+                .unwrap();
+        }
+    }
+
+    /// The parent the Backbone reaches the Edge node through
+    async fn backbone_via(&self) -> Option<u32> {
+        let route = self
+            .backbone
+            .find_best_route(&"10.66.1.5".parse().unwrap())
+            .await?;
+        route.as_path.first().copied()
+    }
+
+    /// Length of the path the Edge node advertised to `peer_asn`
+    async fn advertised_path_len(&self, peer_asn: u32) -> usize {
+        let advertised = self.edge.get_advertised_routes(peer_asn).await.unwrap();
+        assert_eq!(advertised.len(), 1);
+        advertised[0].as_path.len()
+    }
+}
+
+#[tokio::test]
+async fn test_prepending_toward_the_backup_steers_the_backbone() {
+    let mut config = common::config(NodeTier::Edge);
+    config.network.bgp.export_policies = vec![prepend_toward(BACKUP)];
+    let network = Network::new().await;
+    network
+        .edge
+        .set_export_policies(
+            config.network.bgp.export_policies.clone(),
+            config.network.bgp.peer_groups(),
+        )
+        .await
+        .unwrap();
+
+    network.pump().await;
+    assert_eq!(network.backbone_via().await, Some(PRIMARY));
+    // `routes advertised --peer` shows what each parent was sent
+    assert_eq!(network.advertised_path_len(PRIMARY).await, 1);
+    assert_eq!(network.advertised_path_len(BACKUP).await, 3);
+
+    // The operator moves the prepending over to the other parent
+    let privacy = watch::Sender::new(config.services.discovery_privacy);
+    let reloader = Reloader::new(config.clone(), Arc::clone(&network.edge), privacy);
+    let mut reloaded = config.clone();
+    reloaded.network.bgp.export_policies = vec![prepend_toward(PRIMARY)];
+    let report = reloader.reload(&reloaded).await.unwrap();
+    assert_eq!(report.applied(), vec!["network.bgp.export_policies"]);
+
+    // Both parents' Adj-RIB-Out was refreshed by the reload itself
+    assert_eq!(network.advertised_path_len(PRIMARY).await, 3);
+    assert_eq!(network.advertised_path_len(BACKUP).await, 1);
+    network.pump().await;
+    assert_eq!(network.backbone_via().await, Some(BACKUP));
+
+    // Reloading what is already in effect refreshes nobody
+    let refreshed = network
+        .edge
+        .set_export_policies(vec![prepend_toward(PRIMARY)], Default::default())
+        .await
+        .unwrap();
+    assert!(refreshed.is_empty());
+}
@@ -0,0 +1,69 @@
+//! Opening ports in a real host firewall.
+//!
+//! Skipped unless `VX0_FIREWALL_CHAIN` names an empty chain to put rules
+//! in, as "iptables CHAIN" or "nft FAMILY TABLE CHAIN"; needs root, e.g.
+//! `iptables -N vx0test && VX0_FIREWALL_CHAIN="iptables vx0test" cargo test --test firewall`
+//! or `nft add table inet vx0test && nft add chain inet vx0test input &&
+//! VX0_FIREWALL_CHAIN="nft inet vx0test input" cargo test --test firewall`.
+
+use std::sync::Arc;
+use vx0net_daemon::config::ports::Transport;
+use vx0net_daemon::config::FirewallConfig;
+use vx0net_daemon::network::firewall::{
+    Backend, BackendChoice, CommandRunner, FirewallManager, FirewallRule, SystemRunner,
+};
+
+fn rule(protocol: Transport, port: u16, key: &str) -> FirewallRule {
+    FirewallRule {
+        protocol,
+        port,
+        key: key.to_string(),
+    }
+}
+
+#[tokio::test]
+async fn test_apply_and_clear_in_host_firewall() {
+    let Ok(chain) = std::env::var("VX0_FIREWALL_CHAIN") else {
+        eprintln!("VX0_FIREWALL_CHAIN not set, skipping host firewall test");
+        return;
+    };
+    let config = match chain.split_once(' ') {
+        Some(("iptables", chain)) => FirewallConfig {
+            manage: true,
+            backend: BackendChoice::Iptables,
+            iptables_chain: chain.to_string(),
+            ..Default::default()
+        },
+        Some(("nft", chain)) => FirewallConfig {
+            manage: true,
+            backend: BackendChoice::Nft,
+            nft_chain: chain.to_string(),
+            ..Default::default()
+        },
+        _ => {
+            panic!("VX0_FIREWALL_CHAIN should be \"iptables CHAIN\" or \"nft FAMILY TABLE CHAIN\"")
+        }
+    };
+    let runner: Arc<dyn CommandRunner> = Arc::new(SystemRunner);
+    let backend = Backend::detect(&config, runner.as_ref()).await.unwrap();
+    let listed = || async { backend.parse(&runner.run(&backend.list()).await.unwrap()) };
+
+    let wanted = vec![
+        rule(Transport::Tcp, 47179, "network.bgp.listen_port"),
+        rule(Transport::Udp, 47500, "security.ike.listen_port"),
+    ];
+    let firewall = FirewallManager::new(backend.clone(), Arc::clone(&runner), wanted.clone());
+    let outcome = firewall.apply().await;
+    assert_eq!((outcome.inserted, outcome.failed), (2, 0));
+    assert_eq!(listed().await.len(), 2);
+
+    // A later run that no longer listens on one port closes it
+    let restarted =
+        FirewallManager::new(backend.clone(), Arc::clone(&runner), wanted[..1].to_vec());
+    let outcome = restarted.apply().await;
+    assert_eq!((outcome.removed, outcome.kept, outcome.inserted), (1, 1, 0));
+    assert_eq!(restarted.status().await.rules, wanted[..1].to_vec());
+
+    assert_eq!(restarted.clear().await, 1);
+    assert!(listed().await.is_empty());
+}
@@ -0,0 +1,70 @@
+//! Interop check against a real FRR (or BIRD) instance.
+//!
+//! Skipped unless `VX0_FRR_PEER` names a reachable router, e.g.
+//! `VX0_FRR_PEER=172.20.0.5:179 VX0_FRR_ASN=65002 cargo test --features external-bgp`.
+//! The router must be configured with a neighbor for this host in ASN 65001.
+#![cfg(feature = "external-bgp")]
+
+mod common;
+
+use common::route;
+
+use std::time::Duration;
+use vx0net_daemon::config::{BGPPeerConfig, HostBitsPolicy, WireFormat};
+use vx0net_daemon::network::bgp::external::ExternalPeer;
+use vx0net_daemon::network::bgp::messages::BGPMessage;
+
+#[tokio::test]
+async fn test_session_with_frr() {
+    let Ok(target) = std::env::var("VX0_FRR_PEER") else {
+        eprintln!("VX0_FRR_PEER not set, skipping FRR interop test");
+        return;
+    };
+    let asn: u32 = std::env::var("VX0_FRR_ASN")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(65002);
+    let router_id = std::env::var("VX0_LOCAL_ROUTER_ID")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| "10.0.0.1".parse().unwrap());
+
+    let (address, port) = target.rsplit_once(':').expect("VX0_FRR_PEER is host:port");
+    let peer = BGPPeerConfig {
+        address: address.to_string(),
+        port: port.parse().expect("valid port"),
+        asn,
+        wire: WireFormat::Rfc4271,
+        import_policy: Vec::new(),
+        mrai_secs: None,
+        host_bits: HostBitsPolicy::Reject,
+        enabled: true,
+        group: None,
+    };
+
+    let mut session = ExternalPeer::connect(65001, router_id, 90, &peer)
+        .await
+        .expect("session with FRR should establish");
+    assert_eq!(session.peer_asn, asn);
+
+    session
+        .advertise(&[route("10.250.0.0/24", &router_id.to_string(), &[65001])])
+        .await
+        .unwrap();
+
+    // FRR answers with its own table (possibly empty) and keepalives
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while let Ok(msg) = tokio::time::timeout_at(deadline, session.recv()).await {
+        match msg.expect("FRR sent a decodable message") {
+            BGPMessage::Notification(n) => panic!("FRR sent NOTIFICATION {:?}", n),
+            BGPMessage::Update(update) => {
+                update
+                    .route_entries(HostBitsPolicy::Reject, asn)
+                    .expect("UPDATE maps to routes");
+            }
+            _ => {}
+        }
+    }
+
+    session.close(2).await.unwrap();
+}
@@ -0,0 +1,113 @@
+//! A goodbye leaving one node over the tunnel to its peer, whichever way
+//! the tunnel is carried, and the peer recording the departure.
+
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::network::ike::encap::EncapEndpoint;
+use vx0net_daemon::network::ike::session::IKEDaemon;
+use vx0net_daemon::network::ike::tunnels::TunnelTransport;
+use vx0net_daemon::node::goodbye::GoodbyeReason;
+use vx0net_daemon::node::{ConnectionStatus, NodeTier, PeerConnection, PeerEvent, Vx0Node};
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+fn node(hostname: &str, asn: u32) -> Arc<Vx0Node> {
+    let node = common::node(NodeTier::Regional, |config| {
+        config.node.hostname = hostname.to_string();
+        config.node.asn = asn;
+    });
+    node.follow_peer_messages();
+    node
+}
+
+/// The IKE daemon carrying `node`'s UDP tunnels on a local port
+async fn ike(node: &Vx0Node) -> (IKEDaemon, SocketAddr) {
+    let mut ike = IKEDaemon::new(SocketAddr::new(localhost(), 0))
+        .with_tunnels(Arc::clone(&node.tunnel_manager));
+    ike.start().await.unwrap();
+    let addr = ike.local_addr().unwrap();
+    (ike, addr)
+}
+
+/// `node` peered with `peer` through a tunnel to `endpoint`
+async fn peer_with(node: &Vx0Node, peer: &Vx0Node, endpoint: SocketAddr) {
+    node.add_peer(PeerConnection::new(peer.node_id, peer.asn, localhost()))
+        .await
+        .unwrap();
+    node.create_secure_tunnel(peer.node_id, endpoint, b"goodbye-psk")
+        .await
+        .unwrap();
+}
+
+/// What `remote` heard once `local` said goodbye, and its record of it
+async fn departure(
+    local: &Vx0Node,
+    remote: &Vx0Node,
+    reason: GoodbyeReason,
+    expected_return: Option<chrono::DateTime<chrono::Utc>>,
+) -> PeerConnection {
+    let mut events = remote.subscribe_peer_events();
+    local
+        .say_goodbye(remote.node_id, reason, expected_return)
+        .await
+        .unwrap();
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("the goodbye never arrived")
+        .unwrap();
+    assert_eq!(
+        event,
+        PeerEvent::Departed {
+            peer_id: local.node_id,
+            peer_asn: local.asn,
+            reason,
+        }
+    );
+    let peer = remote.get_peer(&local.node_id).await.unwrap();
+    peer.snapshot().await.unwrap()
+}
+
+#[tokio::test]
+async fn test_goodbye_crosses_a_udp_tunnel() {
+    let (local, remote) = (node("regional-a", 65101), node("regional-b", 65102));
+    let (_local_ike, local_addr) = ike(&local).await;
+    let (_remote_ike, remote_addr) = ike(&remote).await;
+    peer_with(&local, &remote, remote_addr).await;
+    peer_with(&remote, &local, local_addr).await;
+
+    let peer = departure(&local, &remote, GoodbyeReason::Shutdown, None).await;
+    assert!(matches!(peer.status, ConnectionStatus::Departed));
+    assert_eq!(peer.departure.unwrap().reason, GoodbyeReason::Shutdown);
+}
+
+#[tokio::test]
+async fn test_goodbye_crosses_a_tunnel_stream() {
+    let (local, remote) = (node("regional-a", 65101), node("regional-b", 65102));
+    // The remote node takes tunnel streams on its BGP port
+    let mut bgp = BGPDaemon::new(remote.asn, localhost(), 0);
+    bgp.set_encapsulation(Arc::new(EncapEndpoint::new(Arc::clone(
+        &remote.tunnel_manager,
+    ))));
+    bgp.start().await.unwrap();
+    let shared = SocketAddr::new(localhost(), bgp.local_addr().unwrap().port());
+    peer_with(&local, &remote, shared).await;
+    let tunnel = local.tunnel_manager.tunnel_to(localhost()).await.unwrap();
+    local
+        .tunnel_manager
+        .set_transport(&tunnel, TunnelTransport::TcpEncap)
+        .await
+        .unwrap();
+    let (_local_ike, local_addr) = ike(&local).await;
+    peer_with(&remote, &local, local_addr).await;
+
+    let back = chrono::Utc::now() + chrono::Duration::minutes(30);
+    let peer = departure(&local, &remote, GoodbyeReason::Maintenance, Some(back)).await;
+    assert!(matches!(peer.status, ConnectionStatus::Departed));
+    assert_eq!(peer.departure.unwrap().probe_at, Some(back));
+}
@@ -0,0 +1,60 @@
+//! Installing routes into a real kernel table.
+//!
+//! Skipped unless `VX0_KERNEL_IFACE` names an interface to route over; needs
+//! root (or CAP_NET_ADMIN), e.g.
+//! `ip link add vx0test type dummy && ip link set vx0test up &&
+//! VX0_KERNEL_IFACE=vx0test cargo test --features kernel_routes --test kernel_routes`.
+//! Uses table 4242, which should be empty.
+#![cfg(feature = "kernel_routes")]
+
+mod common;
+
+use common::route;
+
+use std::sync::Arc;
+use vx0net_daemon::config::{KernelInterface, KernelRoutesConfig};
+use vx0net_daemon::network::kernel::netlink::NetlinkSink;
+use vx0net_daemon::network::kernel::{KernelRouteSync, RouteSink};
+
+const TABLE: u32 = 4242;
+
+#[tokio::test]
+async fn test_install_and_reconcile_in_kernel() {
+    let Ok(interface) = std::env::var("VX0_KERNEL_IFACE") else {
+        eprintln!("VX0_KERNEL_IFACE not set, skipping kernel route test");
+        return;
+    };
+    let config = KernelRoutesConfig {
+        enabled: true,
+        table: TABLE,
+        interfaces: vec![KernelInterface {
+            name: interface,
+            network: "10.254.0.0/24".parse().unwrap(),
+        }],
+        ..Default::default()
+    };
+    let sink = Arc::new(NetlinkSink::connect(config.table, config.protocol).unwrap());
+
+    let sync = KernelRouteSync::new(config.clone(), sink.clone());
+    let outcome = sync
+        .reconcile(&[
+            route("10.251.0.0/16", "10.254.0.2", &[65002]),
+            route("10.252.0.0/16", "10.254.0.2", &[65002]),
+        ])
+        .await;
+    assert_eq!((outcome.installed, outcome.failed), (2, 0));
+    assert_eq!(sink.list().await.unwrap().len(), 2);
+
+    // A later run that no longer has one of the routes cleans it up
+    let restarted = KernelRouteSync::new(config, sink.clone());
+    let outcome = restarted
+        .reconcile(&[route("10.252.0.0/16", "10.254.0.2", &[65002])])
+        .await;
+    assert_eq!((outcome.removed, outcome.installed), (1, 0));
+    let present = sink.list().await.unwrap();
+    assert_eq!(present.len(), 1);
+    assert_eq!(present[0].destination, "10.252.0.0/16".parse().unwrap());
+
+    assert_eq!(restarted.clear().await, 1);
+    assert!(sink.list().await.unwrap().is_empty());
+}
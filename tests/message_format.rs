@@ -0,0 +1,108 @@
+//! Nodes that both offer RFC 4271 binary messages in their OPENs switch to
+//! them; a node that never offers them keeps JSON. Routes flow both ways
+//! either way, so old and new nodes still peer.
+
+mod common;
+
+use common::route;
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use vx0net_daemon::network::bgp::protocol::{BGPProtocol, Encoding};
+use vx0net_daemon::network::bgp::rib::Rib;
+use vx0net_daemon::network::bgp::routing::RoutingPolicy;
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin};
+use vx0net_daemon::network::ike::resumption::PeerIdentity;
+use vx0net_daemon::NodeTier;
+
+const CLIENT_ASN: u32 = 65101;
+const DAEMON_ASN: u32 = 65001;
+
+async fn listening() -> (BGPDaemon, SocketAddr) {
+    let daemon = BGPDaemon::new(DAEMON_ASN, "10.2.0.1".parse().unwrap(), 0);
+    daemon
+        .add_route(
+            "10.70.0.0/16".parse().unwrap(),
+            "10.2.0.1".parse().unwrap(),
+            BGPOrigin::IGP,
+        )
+        .await
+        .unwrap();
+    daemon.start().await.unwrap();
+    let addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        daemon.local_addr().unwrap().port(),
+    );
+    (daemon, addr)
+}
+
+fn client() -> BGPProtocol {
+    BGPProtocol::new(
+        CLIENT_ASN,
+        "10.1.0.1".parse().unwrap(),
+        NodeTier::from_asn(CLIENT_ASN),
+    )
+}
+
+/// Wait up to five seconds for `done` to hold
+async fn eventually<F: Future<Output = bool>>(mut done: impl FnMut() -> F) {
+    for _ in 0..500 {
+        if done().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("gave up waiting");
+}
+
+/// Open a session from `client` to the daemon, announce a route over it
+/// and learn the daemon's, returning how the session's messages went
+async fn exchange(client: BGPProtocol, daemon: &BGPDaemon, addr: SocketAddr) -> Encoding {
+    let (_session, mut stream) = client.open_session(addr, DAEMON_ASN).await.unwrap();
+    let encoding = stream.encoding();
+    client
+        .advertise_routes(
+            &mut stream,
+            vec![route("10.60.1.0/24", "10.1.0.1", &[CLIENT_ASN])],
+        )
+        .await
+        .unwrap();
+
+    let rib = Arc::new(RwLock::new(Rib::new(RoutingPolicy::new(
+        CLIENT_ASN,
+        NodeTier::from_asn(CLIENT_ASN),
+    ))));
+    let receiving = tokio::spawn({
+        let rib = Arc::clone(&rib);
+        async move { client.receive_updates(&mut stream, DAEMON_ASN, &rib).await }
+    });
+
+    let announced: IpAddr = "10.60.1.5".parse().unwrap();
+    eventually(|| async { daemon.find_best_route(&announced).await.is_some() }).await;
+    eventually(|| async { rib.read().await.routes_from(DAEMON_ASN) == 1 }).await;
+    receiving.abort();
+    encoding
+}
+
+#[tokio::test]
+async fn test_nodes_that_both_offer_binary_messages_use_them() {
+    let (daemon, addr) = listening().await;
+    let encoding = exchange(client(), &daemon, addr).await;
+    assert_eq!(
+        encoding,
+        Encoding::Binary(PeerIdentity {
+            asn: DAEMON_ASN,
+            router_id: "10.2.0.1".parse().unwrap(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_nodes_without_binary_messages_still_peer() {
+    let (daemon, addr) = listening().await;
+    let encoding = exchange(client().with_binary_messages(false), &daemon, addr).await;
+    assert_eq!(encoding, Encoding::Json);
+}
@@ -0,0 +1,249 @@
+//! An Edge node homed to two Regional parents: the backbone reaches it
+//! through the primary while it is healthy, through the secondary within
+//! the failover budget once the primary is lost, and back through the
+//! primary only after it has held up for the recovery hold, switching once.
+
+mod common;
+
+use common::route;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vx0net_daemon::config::units::ConfigDuration;
+use vx0net_daemon::config::MultihomingConfig;
+use vx0net_daemon::network::bgp::communities::BACKUP;
+use vx0net_daemon::network::bgp::default_route::vx0_default;
+use vx0net_daemon::network::bgp::multihoming::{self, ParentHealth, ParentReport};
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin, RouteEntry};
+
+const EDGE_ASN: u32 = 66001;
+const PRIMARY: u32 = 65101;
+const SECONDARY: u32 = 65102;
+const BACKBONE_ASN: u32 = 65001;
+const PREFIX: &str = "10.66.1.0/24";
+const FAILOVER_TIME: Duration = Duration::from_millis(100);
+const RECOVERY_HOLD: Duration = Duration::from_secs(1);
+
+fn default_from(parent: u32) -> RouteEntry {
+    let next_hop = format!("10.1.0.{}", parent - 65100);
+    route(&vx0_default().to_string(), &next_hop, &[parent])
+}
+
+struct Parent {
+    asn: u32,
+    daemon: BGPDaemon,
+    alive: bool,
+}
+
+/// The Edge node, its parents and a Backbone node above them, with routes
+/// carried between them as their sessions would
+struct Network {
+    edge: Arc<BGPDaemon>,
+    parents: Vec<Parent>,
+    backbone: BGPDaemon,
+    health: Arc<Mutex<HashMap<u32, ParentHealth>>>,
+}
+
+impl Network {
+    async fn new() -> Self {
+        let edge = Arc::new(BGPDaemon::new(EDGE_ASN, "10.66.1.1".parse().unwrap(), 0));
+        edge.set_multihoming(MultihomingConfig {
+            enabled: true,
+            primary: Some(PRIMARY),
+            secondary: Some(SECONDARY),
+            failover_time: ConfigDuration::from(FAILOVER_TIME),
+            recovery_hold: ConfigDuration::from(RECOVERY_HOLD),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        edge.add_route(
+            PREFIX.parse().unwrap(),
+            "10.66.1.1".parse().unwrap(),
+            BGPOrigin::IGP,
+        )
+        .await
+        .unwrap();
+
+        let mut parents = Vec::new();
+        for asn in [PRIMARY, SECONDARY] {
+            let router_id: IpAddr = format!("10.1.0.{}", asn - 65100).parse().unwrap();
+            parents.push(Parent {
+                asn,
+                daemon: BGPDaemon::new(asn, router_id, 0),
+                alive: true,
+            });
+            edge.receive_update(asn, vec![default_from(asn)], &[])
+                .await
+                .unwrap();
+        }
+        let health = Arc::new(Mutex::new(HashMap::from([
+            (PRIMARY, ParentHealth::UP),
+            (SECONDARY, ParentHealth::UP),
+        ])));
+
+        let probe_health = Arc::clone(&health);
+        multihoming::start_monitoring(Arc::clone(&edge), move || {
+            let reports: Vec<ParentReport> = probe_health
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(asn, health)| ParentReport {
+                    asn: *asn,
+                    latency_ms: 10,
+                    health: *health,
+                })
+                .collect();
+            async move { reports }
+        });
+
+        Network {
+            edge,
+            parents,
+            backbone: BGPDaemon::new(BACKBONE_ASN, "10.0.0.1".parse().unwrap(), 0),
+            health,
+        }
+    }
+
+    /// Send the Edge node's routes up to each live parent, and the
+    /// parents' paths on to the backbone
+    async fn pump(&self) {
+        for parent in self.parents.iter().filter(|parent| parent.alive) {
+            let exports = self.edge.exports_for(parent.asn).await;
+            parent
+                .daemon
+                .receive_update(EDGE_ASN, exports, &[])
+                .await
+                .unwrap();
+            let upward: Vec<RouteEntry> = parent
+                .daemon
+                .get_routes()
+                .await
+                .into_iter()
+                .filter(|route| route.network == PREFIX.parse().unwrap())
+                .map(|mut route| {
+                    route.as_path = route.as_path.prepended(parent.asn, 1);
+                    route
+                })
+                .collect();
+            self.backbone
+                .receive_update(parent.asn, upward, &[])
+                .await
+                .unwrap();
+        }
+    }
+
+    /// The backbone's path to the Edge node: the parent it goes through,
+    /// and whether it carries backup attributes
+    async fn backbone_path(&self) -> Option<(u32, bool)> {
+        let route = self
+            .backbone
+            .find_best_route(&"10.66.1.5".parse().unwrap())
+            .await?;
+        let backup = route.med > 0 || route.communities.contains(&BACKUP);
+        Some((route.as_path.first().copied()?, backup))
+    }
+
+    async fn until_backbone_path(&self, expected: (u32, bool)) -> Duration {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            self.pump().await;
+            if self.backbone_path().await == Some(expected) {
+                return start.elapsed();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "backbone never used {:?}, has {:?}",
+            expected,
+            self.backbone_path().await
+        );
+    }
+
+    fn set_health(&self, asn: u32, health: ParentHealth) {
+        self.health.lock().unwrap().insert(asn, health);
+    }
+
+    fn parent(&mut self, asn: u32) -> &mut Parent {
+        self.parents
+            .iter_mut()
+            .find(|parent| parent.asn == asn)
+            .unwrap()
+    }
+}
+
+async fn edge_default_via(edge: &BGPDaemon) -> Option<u32> {
+    edge.find_best_route(&"10.200.0.1".parse().unwrap())
+        .await
+        .and_then(|route| route.learned_from)
+        .map(|peer| peer.asn)
+}
+
+#[tokio::test]
+async fn test_primary_loss_fails_over_and_recovers_without_flapping() {
+    let mut network = Network::new().await;
+
+    // Both parents carry the prefix; the standby's copy ranks below
+    network.until_backbone_path((PRIMARY, false)).await;
+    let standby = network
+        .backbone
+        .get_received_routes(SECONDARY)
+        .await
+        .unwrap();
+    assert!(!standby.is_empty() && standby.iter().all(|route| route.med > 0));
+    assert_eq!(edge_default_via(&network.edge).await, Some(PRIMARY));
+
+    // The primary dies
+    network.parent(PRIMARY).alive = false;
+    network.set_health(
+        PRIMARY,
+        ParentHealth {
+            session: false,
+            ..ParentHealth::UP
+        },
+    );
+    network.backbone.session_failed(PRIMARY).await.unwrap();
+    network.edge.session_failed(PRIMARY).await.unwrap();
+    let failed_over = network.until_backbone_path((SECONDARY, false)).await;
+    assert!(
+        failed_over <= FAILOVER_TIME + Duration::from_millis(500),
+        "{failed_over:?}"
+    );
+    let status = network.edge.multihoming_status().await.unwrap();
+    assert_eq!(status.active, SECONDARY);
+    let failover = status.last_failover.unwrap();
+    assert_eq!((failover.from, failover.to), (PRIMARY, SECONDARY));
+    assert_eq!(failover.reason, "session down");
+    assert_eq!(edge_default_via(&network.edge).await, Some(SECONDARY));
+
+    // It comes back; traffic stays put until the hold passes, then moves
+    // back once
+    network.parent(PRIMARY).alive = true;
+    network
+        .edge
+        .receive_update(PRIMARY, vec![default_from(PRIMARY)], &[])
+        .await
+        .unwrap();
+    network.set_health(PRIMARY, ParentHealth::UP);
+    let recovered = Instant::now();
+    let mut seen = vec![network.backbone_path().await];
+    while recovered.elapsed() < RECOVERY_HOLD + Duration::from_secs(1) {
+        network.pump().await;
+        let path = network.backbone_path().await;
+        if seen.last() != Some(&path) {
+            if path.is_some_and(|(parent, _)| parent == PRIMARY) {
+                assert!(recovered.elapsed() >= RECOVERY_HOLD - FAILOVER_TIME);
+            }
+            seen.push(path);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(seen, vec![Some((SECONDARY, false)), Some((PRIMARY, false))]);
+    assert_eq!(
+        network.edge.multihoming_status().await.unwrap().active,
+        PRIMARY
+    );
+    assert_eq!(edge_default_via(&network.edge).await, Some(PRIMARY));
+}
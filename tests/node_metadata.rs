@@ -0,0 +1,182 @@
+//! Node metadata over DNS: a node's TXT records follow its state, keep the
+//! contact private unless listed, travel in the zone with the node as their
+//! origin, and answer wire-format TXT queries, as do a Backbone node's
+//! network stats.
+
+#![cfg(feature = "dns-server")]
+
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use vx0net_daemon::config::{MetadataKey, NodeMetadataConfig, Vx0Config};
+use vx0net_daemon::network::dns::metadata::{
+    self, network_stats, node_metadata_name, NodeMetadata, STATS_RECORD,
+};
+use vx0net_daemon::network::dns::server::Vx0DNSServer;
+use vx0net_daemon::network::dns::wire::{Query, Rcode, Response, TYPE_A, TYPE_TXT};
+use vx0net_daemon::network::dns::Vx0DNS;
+use vx0net_daemon::node::{HostedService, NodeTier, ServiceStatus, ServiceType, Vx0Node};
+
+fn config(tier: NodeTier) -> Vx0Config {
+    let mut config = common::config(tier);
+    config.network.dns.listen_port = 0;
+    config.network.dns.vx0_dns_servers.clear();
+    config
+}
+
+async fn serve(config: &Vx0Config, dns: &Arc<RwLock<Vx0DNS>>) -> SocketAddr {
+    let bound = Vx0DNSServer::from_config(&config.network.dns, Arc::clone(dns))
+        .start()
+        .await
+        .unwrap();
+    SocketAddr::from(([127, 0, 0, 1], bound.port()))
+}
+
+async fn ask(server: SocketAddr, name: &str, qtype: u16) -> Response {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client
+        .send_to(&Query::new(7, name, qtype).to_bytes(), server)
+        .await
+        .unwrap();
+    let mut buf = [0; 4096];
+    let size = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    Response::parse(&buf[..size]).unwrap()
+}
+
+fn service(domain: &str) -> HostedService {
+    HostedService {
+        service_id: Uuid::new_v4(),
+        name: domain.to_string(),
+        service_type: ServiceType::WebServer,
+        domain: domain.to_string(),
+        port: 80,
+        status: ServiceStatus::Running,
+        metadata: Default::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_node_metadata_follows_the_node_over_the_wire() {
+    let mut config = config(NodeTier::Edge);
+    config.network.dns.node_metadata.contact = "ops@example.org".to_string();
+    let node = Arc::new(Vx0Node::new(config.clone()).unwrap());
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(node.node_id);
+    let dns = Arc::new(RwLock::new(dns));
+    let server = serve(&config, &dns).await;
+    let name = node_metadata_name(&node.hostname);
+
+    // Published as soon as the node starts
+    metadata::start_publishing(
+        Arc::clone(&node),
+        Arc::clone(&dns),
+        config.network.dns.node_metadata.clone(),
+    );
+    let mut answer = ask(server, &name, TYPE_TXT).await;
+    for _ in 0..50 {
+        if answer.rcode == Some(Rcode::NoError) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        answer = ask(server, &name, TYPE_TXT).await;
+    }
+    let version = format!("version={}", env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        answer.texts,
+        [
+            format!("asn={}", node.asn),
+            "tier=edge".to_string(),
+            version.clone(),
+            "services=0".to_string(),
+        ]
+    );
+    // The contact is configured but not listed, so it stays private
+    assert!(!answer.texts.iter().any(|text| text.starts_with("contact=")));
+
+    // Carried by zone sync as this node's
+    let zone = dns.read().await.zone_for(&name).unwrap();
+    let serial = dns.read().await.serial(&zone).unwrap();
+    let records = dns.read().await.get_records(&name).cloned().unwrap();
+    assert!(records
+        .iter()
+        .all(|record| record.origin == Some(node.node_id)));
+
+    // A new service changes the record; republishing the same state does not
+    node.register_service(service("wiki.vx0")).await.unwrap();
+    let texts = NodeMetadata::of(&node)
+        .await
+        .texts(&config.network.dns.node_metadata);
+    assert!(dns.write().await.publish_texts(&name, &texts));
+    assert!(!dns.write().await.publish_texts(&name, &texts));
+    assert!(dns.read().await.serial(&zone).unwrap() > serial);
+    let answer = ask(server, &name, TYPE_TXT).await;
+    assert!(answer.texts.contains(&"services=1".to_string()));
+    assert_eq!(answer.texts.len(), 4);
+
+    // Listing the contact publishes it
+    let listed = NodeMetadataConfig {
+        keys: vec![MetadataKey::Tier, MetadataKey::Contact],
+        ..config.network.dns.node_metadata.clone()
+    };
+    let texts = NodeMetadata::of(&node).await.texts(&listed);
+    dns.write().await.publish_texts(&name, &texts);
+    assert_eq!(
+        ask(server, &name, TYPE_TXT).await.texts,
+        ["tier=edge", "contact=ops@example.org"]
+    );
+
+    // Address queries for the name are not answered with text
+    let address = ask(server, &name, TYPE_A).await;
+    assert!(address.texts.is_empty() && address.addresses.is_empty());
+    let missing = ask(server, "_node.nobody.nodes.vx0", TYPE_TXT).await;
+    assert_eq!(missing.rcode, Some(Rcode::NxDomain));
+}
+
+#[tokio::test]
+async fn test_backbone_serves_network_stats() {
+    let config = config(NodeTier::Backbone);
+    let node = Arc::new(Vx0Node::new(config.clone()).unwrap());
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(node.node_id);
+    let dns = Arc::new(RwLock::new(dns));
+    let server = serve(&config, &dns).await;
+
+    let mut entry = node.directory_entry();
+    dns.write().await.register_node(&entry).unwrap();
+    for asn in [65101, 66001, 66002] {
+        entry.node_id = Uuid::new_v4();
+        entry.asn = asn;
+        dns.write().await.register_node(&entry).unwrap();
+    }
+    let nodes = dns.read().await.nodes();
+    assert_eq!(
+        network_stats(&nodes),
+        ["backbone=1", "regional=1", "edge=2", "total=4"]
+    );
+
+    metadata::start_publishing(
+        Arc::clone(&node),
+        Arc::clone(&dns),
+        config.network.dns.node_metadata.clone(),
+    );
+    let mut answer = ask(server, STATS_RECORD, TYPE_TXT).await;
+    for _ in 0..50 {
+        if answer.rcode == Some(Rcode::NoError) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        answer = ask(server, STATS_RECORD, TYPE_TXT).await;
+    }
+    assert_eq!(
+        answer.texts,
+        ["backbone=1", "regional=1", "edge=2", "total=4"]
+    );
+}
@@ -0,0 +1,117 @@
+//! Nodes learning their public address from peers: the address a listener
+//! reports in its OPEN reply, peers agreeing on another address moving the
+//! announced one under `node.auto_detect_public_address`, and outliers and
+//! LAN peers failing to.
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use uuid::Uuid;
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::network::dns::{RecordType, Vx0DNS};
+use vx0net_daemon::node::{HostedService, NodeTier, ServiceStatus, ServiceType, Vx0Node};
+
+const CONFIGURED: &str = "203.0.113.10";
+const BEHIND_NAT: &str = "198.51.100.7";
+
+fn node(address: &str, auto_detect: bool) -> Arc<Vx0Node> {
+    common::node(NodeTier::Edge, |config| {
+        config.node.ipv4_address = address.to_string();
+        config.node.auto_detect_public_address = auto_detect;
+    })
+}
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+fn v4(address: &str) -> Ipv4Addr {
+    address.parse().unwrap()
+}
+
+/// Peers 10.1.0.1, 10.1.0.2, … each reporting one address
+fn reports(node: &Vx0Node, observed: &[&str]) {
+    for (i, address) in observed.iter().enumerate() {
+        node.observe_address(ip(&format!("10.1.0.{}", i + 1)), Some(ip(address)));
+    }
+}
+
+#[tokio::test]
+async fn test_peers_agreeing_flip_the_announced_address() {
+    let detecting = node(CONFIGURED, true);
+    reports(&detecting, &[BEHIND_NAT, BEHIND_NAT, BEHIND_NAT]);
+    assert_eq!(detecting.public_ipv4(), v4(BEHIND_NAT));
+    assert_eq!(detecting.announcement().await.ipv4_addr, v4(BEHIND_NAT));
+    assert_eq!(detecting.directory_entry().address, v4(BEHIND_NAT));
+    // Sessions keep the configured address as router id
+    assert_eq!(detecting.ipv4_addr, v4(CONFIGURED));
+
+    // Service records point where peers can reach us
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(detecting.node_id);
+    let service = HostedService {
+        service_id: Uuid::new_v4(),
+        name: "forum".to_string(),
+        service_type: ServiceType::WebServer,
+        domain: "forum.community1.vx0".to_string(),
+        port: 8080,
+        status: ServiceStatus::Running,
+        metadata: Default::default(),
+    };
+    detecting.publish_service_dns(&service, &mut dns).unwrap();
+    assert_eq!(
+        dns.lookup_addresses("forum.community1.vx0", &RecordType::A),
+        vec![ip(BEHIND_NAT)]
+    );
+
+    // Without the flag the mismatch is only reported
+    let configured = node(CONFIGURED, false);
+    reports(&configured, &[BEHIND_NAT, BEHIND_NAT, BEHIND_NAT]);
+    assert_eq!(configured.announcement().await.ipv4_addr, v4(CONFIGURED));
+    let report = configured.observed.report();
+    assert_eq!(report.mismatch(), Some(v4(BEHIND_NAT)));
+    assert_eq!(report.announced, v4(CONFIGURED));
+    assert_eq!(report.observations[0].peers, 3);
+}
+
+#[tokio::test]
+async fn test_single_outlier_does_not_move_the_address() {
+    let lone = node(CONFIGURED, true);
+    reports(&lone, &[BEHIND_NAT]);
+    assert_eq!(lone.observed.majority(), None);
+    assert_eq!(lone.announcement().await.ipv4_addr, v4(CONFIGURED));
+
+    let outvoted = node(CONFIGURED, true);
+    reports(&outvoted, &[CONFIGURED, BEHIND_NAT, CONFIGURED, CONFIGURED]);
+    let report = outvoted.observed.report();
+    assert_eq!(report.majority, Some(v4(CONFIGURED)));
+    assert_eq!(report.mismatch(), None);
+    assert_eq!(report.observations.len(), 2);
+    assert_eq!(outvoted.announcement().await.ipv4_addr, v4(CONFIGURED));
+}
+
+#[tokio::test]
+async fn test_lan_peers_cannot_override_a_public_address() {
+    let public = node(CONFIGURED, true);
+    reports(&public, &["192.168.1.20", "192.168.1.20", "192.168.1.20"]);
+    assert!(public.observed.report().observations.is_empty());
+    assert_eq!(public.announcement().await.ipv4_addr, v4(CONFIGURED));
+
+    // A private configured address is exactly what NAT hides
+    let private = node("192.168.1.100", true);
+    reports(&private, &[BEHIND_NAT, BEHIND_NAT]);
+    assert_eq!(private.announcement().await.ipv4_addr, v4(BEHIND_NAT));
+}
+
+#[tokio::test]
+async fn test_open_reply_reports_the_connecting_address() {
+    let daemon = BGPDaemon::new(65101, ip("10.0.0.1"), 0);
+    daemon.start().await.unwrap();
+    let addr = SocketAddr::new(ip("127.0.0.1"), daemon.local_addr().unwrap().port());
+
+    let initiator = BGPProtocol::new(66001, ip("10.0.0.2"), NodeTier::Edge);
+    let session = initiator.connect_to_peer(addr, 65101).await.unwrap();
+    assert_eq!(session.observed_addr, Some(ip("127.0.0.1")));
+}
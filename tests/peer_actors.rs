@@ -0,0 +1,127 @@
+//! Concurrent add/remove/send across many peers must neither deadlock nor
+//! lose track of how many peers the node holds.
+
+mod common;
+
+use common::node;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use vx0net_daemon::node::{NodeTier, PeerConnection, Vx0Node};
+
+fn backbone_peer(i: u32) -> PeerConnection {
+    PeerConnection::new(
+        uuid::Uuid::new_v4(),
+        65000 + i % 100,
+        IpAddr::V4(Ipv4Addr::new(10, 1, (i / 256) as u8, (i % 256) as u8)),
+    )
+}
+
+async fn add_concurrently(node: &Arc<Vx0Node>, peers: Vec<PeerConnection>) -> HashSet<uuid::Uuid> {
+    let tasks: Vec<_> = peers
+        .into_iter()
+        .map(|peer| {
+            let node = Arc::clone(node);
+            tokio::spawn(async move {
+                let id = peer.peer_id;
+                node.add_peer(peer).await.ok().map(|_| id)
+            })
+        })
+        .collect();
+
+    let mut added = HashSet::new();
+    for task in tasks {
+        if let Some(id) = task.await.unwrap() {
+            added.insert(id);
+        }
+    }
+    added
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_peer_churn_stays_consistent() {
+    let node = node(NodeTier::Backbone, |_| {});
+    let max_peers = node.tier.max_peers();
+
+    let churn = async {
+        // Far more adds than the tier allows: the limit must hold exactly
+        let added = add_concurrently(&node, (0..400).map(backbone_peer).collect()).await;
+        assert_eq!(added.len(), max_peers);
+        assert_eq!(node.get_peer_count().await, max_peers);
+
+        // Remove half while sending to every peer and racing new adds
+        let (to_remove, kept): (Vec<_>, Vec<_>) = added
+            .iter()
+            .copied()
+            .enumerate()
+            .partition(|(i, _)| i % 2 == 0);
+
+        let mut tasks = Vec::new();
+        for (_, id) in &to_remove {
+            let node = Arc::clone(&node);
+            let id = *id;
+            tasks.push(tokio::spawn(async move {
+                node.remove_peer(&id).await.unwrap();
+            }));
+        }
+        for &id in &added {
+            for _ in 0..4 {
+                let node = Arc::clone(&node);
+                tasks.push(tokio::spawn(async move {
+                    // No tunnels exist, so sends fail; they must still return
+                    assert!(node.send_secure_data(&id, b"ping").await.is_err());
+                }));
+            }
+        }
+        let node_for_adds = Arc::clone(&node);
+        let late_adds = tokio::spawn(async move {
+            add_concurrently(&node_for_adds, (400..700).map(backbone_peer).collect()).await
+        });
+        for _ in 0..50 {
+            let node = Arc::clone(&node);
+            tasks.push(tokio::spawn(async move {
+                node.list_peers().await;
+                node.list_active_tunnels().await;
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let late = late_adds.await.unwrap();
+
+        let count = node.get_peer_count().await;
+        assert!(count <= max_peers);
+        assert_eq!(count, kept.len() + late.len());
+
+        let listed: HashSet<_> = node.list_peers().await.iter().map(|p| p.peer_id).collect();
+        assert_eq!(listed.len(), count);
+        for (_, id) in &to_remove {
+            assert!(!listed.contains(id));
+        }
+        for (_, id) in &kept {
+            assert!(listed.contains(id));
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(30), churn)
+        .await
+        .expect("peer operations deadlocked");
+}
+
+#[tokio::test]
+async fn adding_a_peer_again_stops_its_old_task() {
+    let node = node(NodeTier::Backbone, |_| {});
+    let peer = backbone_peer(1);
+    let id = peer.peer_id;
+    node.add_peer(peer.clone()).await.unwrap();
+    let old = node.get_peer(&id).await.unwrap();
+
+    node.add_peer(peer).await.unwrap();
+    assert_eq!(node.get_peer_count().await, 1);
+    assert!(old.snapshot().await.is_err());
+    let current = node.get_peer(&id).await.unwrap();
+    assert!(current.snapshot().await.is_ok());
+}
@@ -0,0 +1,152 @@
+//! Taking peers down administratively: a disabled peer keeps its entry but
+//! loses its routes and is not reconnected, enabling it lets it back, and
+//! the state outlives a restart of the node.
+
+mod common;
+
+use common::{node, route};
+
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::node::admin::{self, AdminSource};
+use vx0net_daemon::node::{ConnectionStatus, NodeError, NodeTier, PeerConnection, Vx0Node};
+use vx0net_daemon::storage::Store;
+
+const DISABLED: u32 = 65101;
+const UNTOUCHED: u32 = 65102;
+
+async fn status(node: &Vx0Node, asn: u32) -> ConnectionStatus {
+    node.list_peers()
+        .await
+        .into_iter()
+        .find(|peer| peer.peer_asn == asn)
+        .unwrap()
+        .status
+}
+
+async fn add_peers(node: &Vx0Node, bgp: &BGPDaemon) {
+    for (asn, addr) in [(DISABLED, "10.1.0.1"), (UNTOUCHED, "10.1.0.2")] {
+        let mut peer = PeerConnection::new(Uuid::new_v4(), asn, addr.parse().unwrap());
+        peer.status = ConnectionStatus::Authenticated;
+        peer.metrics.latency_ms = 12;
+        node.add_peer(peer).await.unwrap();
+        bgp.receive_update(
+            asn,
+            vec![route(
+                &format!("10.{}.0.0/16", asn - 65000),
+                "10.1.0.1",
+                &[asn],
+            )],
+            &[],
+        )
+        .await
+        .unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_disabled_peer_is_withdrawn_and_left_alone() {
+    let node = node(NodeTier::Edge, |_| {});
+    let bgp = Arc::new(BGPDaemon::new(66001, "10.2.0.1".parse().unwrap(), 0));
+    bgp.follow_peer_events(node.subscribe_peer_events());
+    add_peers(&node, &bgp).await;
+
+    assert_eq!(node.disable_peer(DISABLED).await.unwrap(), 1);
+    for _ in 0..50 {
+        if bgp.routes_from(DISABLED).await == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(bgp.routes_from(DISABLED).await, 0);
+    assert_eq!(bgp.routes_from(UNTOUCHED).await, 1);
+
+    // Still listed, with its metrics, but down
+    let peers = node.list_peers().await;
+    assert_eq!(peers.len(), 2);
+    let disabled = peers.iter().find(|peer| peer.peer_asn == DISABLED).unwrap();
+    assert!(matches!(disabled.status, ConnectionStatus::AdminDown));
+    assert_eq!(disabled.metrics.latency_ms, 12);
+
+    // Nothing reconnects it
+    assert!(matches!(
+        node.connect_peer("10.1.0.1".parse().unwrap(), DISABLED)
+            .await,
+        Err(NodeError::AdminDown { asn: DISABLED })
+    ));
+    // Disabling twice changes nothing
+    assert_eq!(node.disable_peer(DISABLED).await.unwrap(), 1);
+    assert_eq!(node.admin.list().len(), 1);
+}
+
+#[tokio::test]
+async fn test_enabled_peer_reconnects_as_usual() {
+    let node = node(NodeTier::Edge, |_| {});
+    let bgp = Arc::new(BGPDaemon::new(66001, "10.2.0.1".parse().unwrap(), 0));
+    add_peers(&node, &bgp).await;
+
+    node.disable_peer(DISABLED).await.unwrap();
+    assert_eq!(node.enable_peer(DISABLED).await.unwrap(), 1);
+    assert!(!node.admin.is_down(DISABLED));
+    assert!(matches!(
+        status(&node, DISABLED).await,
+        ConnectionStatus::Disconnected
+    ));
+    // Peers that were never down are not touched
+    assert!(matches!(
+        status(&node, UNTOUCHED).await,
+        ConnectionStatus::Authenticated
+    ));
+    // Enabling a peer that is up changes nothing
+    assert_eq!(node.enable_peer(UNTOUCHED).await.unwrap(), 1);
+    assert!(matches!(
+        status(&node, UNTOUCHED).await,
+        ConnectionStatus::Authenticated
+    ));
+}
+
+#[tokio::test]
+async fn test_disabled_peer_stays_down_across_restart() {
+    let root = std::env::temp_dir().join(format!("vx0net-peer-admin-{}", Uuid::new_v4()));
+
+    let before = node(NodeTier::Edge, |_| {});
+    before
+        .admin
+        .open(Store::open(&root).unwrap().namespace(admin::NAMESPACE))
+        .unwrap();
+    before.disable_peer(DISABLED).await.unwrap();
+    drop(before);
+
+    let after = node(NodeTier::Edge, |_| {});
+    let restored = after
+        .admin
+        .open(Store::open(&root).unwrap().namespace(admin::NAMESPACE))
+        .unwrap();
+    assert_eq!(restored, 1);
+    assert_eq!(after.admin.list()[0].source, AdminSource::Operator);
+
+    // Rejoining peers on that ASN come back down
+    after
+        .add_peer(PeerConnection::new(
+            Uuid::new_v4(),
+            DISABLED,
+            "10.1.0.1".parse().unwrap(),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(
+        status(&after, DISABLED).await,
+        ConnectionStatus::AdminDown
+    ));
+
+    after.enable_peer(DISABLED).await.unwrap();
+    let again = node(NodeTier::Edge, |_| {});
+    let restored = again
+        .admin
+        .open(Store::open(&root).unwrap().namespace(admin::NAMESPACE))
+        .unwrap();
+    assert_eq!(restored, 0);
+    let _ = std::fs::remove_dir_all(&root);
+}
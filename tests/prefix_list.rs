@@ -0,0 +1,115 @@
+//! A `PrefixList` answers the same as scanning its entries one by one, and
+//! much faster once lists grow. The timing comparison only runs on request:
+//! `cargo test --release --test prefix_list -- --ignored --nocapture`.
+
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+use vx0net_daemon::config::Vx0Config;
+use vx0net_daemon::network::bgp::Prefix;
+use vx0net_daemon::network::prefix_list::{PrefixEntry, PrefixList};
+
+/// Deterministic spread of /16 to /28 prefixes under 10.0.0.0/8
+fn entries(count: u32) -> Vec<IpNet> {
+    let mut state = 0x2545_f491_u32;
+    (0..count)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let len = 16 + (state % 13) as u8;
+            let addr = Ipv4Addr::from(0x0a00_0000 | (state >> 8));
+            IpNet::new(IpAddr::V4(addr), len).unwrap().trunc()
+        })
+        .collect()
+}
+
+fn queries(count: u32) -> Vec<IpAddr> {
+    (0..count)
+        .map(|i| {
+            IpAddr::V4(Ipv4Addr::from(
+                0x0a00_0000 | i.wrapping_mul(0x9e37_79b9) >> 8,
+            ))
+        })
+        .collect()
+}
+
+fn list(nets: &[IpNet]) -> PrefixList {
+    nets.iter()
+        .map(|&net| PrefixEntry::exact(Prefix::new(net)))
+        .collect()
+}
+
+#[test]
+fn trie_agrees_with_a_naive_scan() {
+    let nets = entries(10_000);
+    let list = list(&nets);
+    for addr in queries(5_000) {
+        let naive = nets.iter().any(|net| net.contains(&addr));
+        assert_eq!(list.covers_addr(addr), naive, "{}", addr);
+    }
+    for net in nets.iter().step_by(7) {
+        let prefix = Prefix::new(*net);
+        assert!(list.exact(&prefix));
+        assert!(list.covers(&prefix));
+        let supernet = Prefix::new(net.supernet().unwrap());
+        let naive = nets.iter().any(|net| net.contains(&supernet.net()));
+        assert_eq!(list.covers(&supernet), naive, "{}", supernet);
+    }
+}
+
+#[test]
+fn dns_allow_from_is_a_prefix_list() {
+    let config: Vx0Config = toml::from_str(&include_str!("../config/edge-node.toml").replace(
+        "[network.dns]",
+        "[network.dns]\nallow_from = [\"127.0.0.0/8\", \"192.168.0.0/16 le 24\"]",
+    ))
+    .unwrap();
+    let allow = &config.network.dns.allow_from;
+    assert_eq!(allow.to_string(), "127.0.0.0/8, 192.168.0.0/16 le 24");
+    assert!(allow.covers_addr("192.168.4.2".parse().unwrap()));
+    assert!(!allow.covers_addr("10.1.0.1".parse().unwrap()));
+
+    let bad = include_str!("../config/edge-node.toml").replace(
+        "[network.dns]",
+        "[network.dns]\nallow_from = [\"192.168.1.0/16\"]",
+    );
+    assert!(toml::from_str::<Vx0Config>(&bad).is_err());
+}
+
+#[test]
+#[ignore]
+fn trie_outpaces_a_naive_scan() {
+    let nets = entries(10_000);
+    let list = list(&nets);
+    let queries = queries(100_000);
+
+    let start = Instant::now();
+    let naive = queries
+        .iter()
+        .filter(|addr| nets.iter().any(|net| net.contains(*addr)))
+        .count();
+    let scanned = start.elapsed();
+
+    let start = Instant::now();
+    let trie = queries
+        .iter()
+        .filter(|&&addr| list.covers_addr(addr))
+        .count();
+    let walked = start.elapsed();
+
+    println!(
+        "{} queries against {} entries: scan {:?}, trie {:?}",
+        queries.len(),
+        nets.len(),
+        scanned,
+        walked
+    );
+    assert_eq!(naive, trie);
+    assert!(
+        walked * 10 < scanned,
+        "trie {:?}, scan {:?}",
+        walked,
+        scanned
+    );
+}
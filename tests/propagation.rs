@@ -0,0 +1,181 @@
+//! A prefix originated on an Edge node whose clock runs ahead reaches the
+//! Backbone through a Regional node, each hop paced as its sessions pace
+//! UPDATEs. The Backbone measures a delay that is positive and within what
+//! pacing allows, once the Edge node's skew is corrected for; routes from
+//! nodes that do not stamp are counted and otherwise left alone.
+
+mod common;
+
+use common::route;
+
+use std::time::{Duration, Instant};
+use vx0net_daemon::network::bgp::pacing::{default_mrai, AdvertisementPacer, PacerOutput};
+use vx0net_daemon::network::bgp::protocol::{BGPMessage, BGPRoute};
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin, Prefix, RouteEntry};
+use vx0net_daemon::node::NodeTier;
+
+const EDGE_ASN: u32 = 66001;
+const QUIET_EDGE_ASN: u32 = 66002;
+const REGIONAL_ASN: u32 = 65101;
+const BACKBONE_ASN: u32 = 65001;
+const PREFIX: &str = "10.66.1.0/24";
+const QUIET_PREFIX: &str = "10.66.2.0/24";
+/// How far the Edge node's clock runs ahead of everyone else's
+const EDGE_CLOCK_AHEAD: i64 = 30;
+/// Scheduling and processing on top of the pacing intervals
+const SLACK: Duration = Duration::from_millis(500);
+
+/// An UPDATE as `asn` would send it, timestamped `ahead` of our clock along
+/// with the stamps of the routes it carries
+fn update(asn: u32, router_id: &str, routes: &[RouteEntry], ahead: chrono::Duration) -> BGPMessage {
+    let routes: Vec<BGPRoute> = routes
+        .iter()
+        .map(|route| BGPRoute {
+            network: route.network,
+            next_hop: route.next_hop,
+            as_path: route.as_path.to_vec(),
+            origin: route.origin.clone(),
+            local_pref: route.local_pref,
+            med: route.med,
+            originated_at: route.originated_at.map(|at| at + ahead),
+        })
+        .collect();
+    serde_json::from_value(serde_json::json!({
+        "message_type": "Update",
+        "asn": asn,
+        "router_id": router_id,
+        "routes": routes,
+        "timestamp": chrono::Utc::now() + ahead,
+    }))
+    .unwrap()
+}
+
+/// Queue `routes` toward a peer and wait for the pacer to let them go
+async fn paced(pacer: &mut AdvertisementPacer, routes: Vec<RouteEntry>) -> Vec<RouteEntry> {
+    for route in routes {
+        pacer.announce(route);
+    }
+    loop {
+        if let Some(PacerOutput::Update(batch)) = pacer.poll(Instant::now()) {
+            return batch.announced;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// A pacer toward `peer_asn` that has just flushed, as on a session that
+/// has been up for a while
+fn busy_pacer(peer_asn: u32) -> AdvertisementPacer {
+    let mut pacer = AdvertisementPacer::new(default_mrai(peer_asn), 1024);
+    pacer.announce(route("10.99.0.0/24", "10.99.0.1", &[peer_asn]));
+    assert!(pacer.poll(Instant::now()).is_some());
+    pacer
+}
+
+fn prefix(network: &str) -> Prefix {
+    network.parse().unwrap()
+}
+
+#[tokio::test]
+async fn backbone_measures_edge_routes_within_the_pacing_bound() {
+    let edge = BGPDaemon::new(EDGE_ASN, "10.66.1.1".parse().unwrap(), 0);
+    edge.set_propagation_tracing(true).await;
+    let quiet_edge = BGPDaemon::new(QUIET_EDGE_ASN, "10.66.2.1".parse().unwrap(), 0);
+    let regional = BGPDaemon::new(REGIONAL_ASN, "10.1.1.1".parse().unwrap(), 0);
+    let backbone = BGPDaemon::new(BACKBONE_ASN, "10.0.1.1".parse().unwrap(), 0);
+    let ahead = chrono::Duration::seconds(EDGE_CLOCK_AHEAD);
+
+    // Sessions exchange keepalives and earlier UPDATEs before the prefix
+    // appears, which is what the skew is measured from
+    for _ in 0..4 {
+        regional
+            .receive_message(EDGE_ASN, &update(EDGE_ASN, "10.66.1.1", &[], ahead))
+            .await
+            .unwrap();
+    }
+
+    edge.add_route(prefix(PREFIX), "10.66.1.1".parse().unwrap(), BGPOrigin::IGP)
+        .await
+        .unwrap();
+    quiet_edge
+        .add_route(
+            prefix(QUIET_PREFIX),
+            "10.66.2.1".parse().unwrap(),
+            BGPOrigin::IGP,
+        )
+        .await
+        .unwrap();
+
+    // Edge to Regional, paced for a core peer
+    let mut toward_regional = busy_pacer(REGIONAL_ASN);
+    let sent = paced(&mut toward_regional, edge.exports_for(REGIONAL_ASN).await).await;
+    assert!(sent[0].originated_at.is_some());
+    regional
+        .receive_message(EDGE_ASN, &update(EDGE_ASN, "10.66.1.1", &sent, ahead))
+        .await
+        .unwrap();
+    regional
+        .receive_update(
+            QUIET_EDGE_ASN,
+            quiet_edge.exports_for(REGIONAL_ASN).await,
+            &[],
+        )
+        .await
+        .unwrap();
+
+    // Regional to Backbone, with its own path prepended as its session would
+    let upward: Vec<RouteEntry> = regional
+        .get_routes()
+        .await
+        .into_iter()
+        .map(|mut route| {
+            route.as_path = route.as_path.prepended(REGIONAL_ASN, 1);
+            route
+        })
+        .collect();
+    let mut toward_backbone = busy_pacer(BACKBONE_ASN);
+    let sent = paced(&mut toward_backbone, upward).await;
+    backbone
+        .receive_message(
+            REGIONAL_ASN,
+            &update(REGIONAL_ASN, "10.1.1.1", &sent, chrono::Duration::zero()),
+        )
+        .await
+        .unwrap();
+
+    let bound = default_mrai(REGIONAL_ASN) + default_mrai(BACKBONE_ASN) + SLACK;
+    let explanation = backbone.explain_route(&prefix(PREFIX)).await;
+    let sample = explanation.propagation.clone().unwrap();
+    assert_eq!(sample.origin_asn, EDGE_ASN);
+    assert_eq!(sample.origin_tier, NodeTier::Edge);
+    assert_eq!(sample.peer_asn, REGIONAL_ASN);
+    assert!(sample.delay_ms > 0, "{:?}", sample);
+    assert!(
+        Duration::from_millis(sample.delay_ms) < bound,
+        "{}ms, bound {:?}",
+        sample.delay_ms,
+        bound
+    );
+    assert!(explanation.to_string().contains("propagation delay: "));
+
+    // The Edge node's 30s lead was taken out at the Regional hop
+    let regional_sample = regional
+        .explain_route(&prefix(PREFIX))
+        .await
+        .propagation
+        .unwrap();
+    assert!(regional_sample.skew_ms <= -EDGE_CLOCK_AHEAD * 1000 + 1000);
+    assert!(Duration::from_millis(regional_sample.delay_ms) < default_mrai(REGIONAL_ASN) + SLACK);
+
+    // The route from the Edge node that does not stamp is still installed
+    let quiet = backbone.explain_route(&prefix(QUIET_PREFIX)).await;
+    assert!(quiet.installed.is_some());
+    assert!(quiet.propagation.is_none());
+
+    let stats = backbone.propagation_stats().await;
+    assert_eq!(stats.unstamped, 1);
+    assert_eq!(stats.prefixes, 1);
+    assert_eq!(stats.tiers.len(), 1);
+    assert_eq!(stats.tiers[0].tier, NodeTier::Edge);
+    assert_eq!(stats.tiers[0].p95_ms, sample.delay_ms);
+}
@@ -0,0 +1,738 @@
+//! Property tests for the code that parses what peers send and the tables
+//! it feeds: encodings round-trip, decoders never panic on arbitrary input,
+//! the route table keeps longest-prefix match, and policy is deterministic.
+//!
+//! Runs a bounded number of cases under `cargo test`. Set
+//! `VX0_PROPTEST_UNBOUNDED=1` to keep generating cases until one fails, or
+//! `PROPTEST_CASES` to pick a count. The binary decoders also have cargo-fuzz
+//! targets under `fuzz/`.
+
+use chrono::{DateTime, Utc};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use proptest::prelude::*;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use vx0net_daemon::network::bgp::messages::NotificationMessage;
+use vx0net_daemon::network::bgp::protocol::{BGPMessage, BGPMessageType, BGPRoute};
+use vx0net_daemon::network::bgp::routing::RoutingPolicy;
+use vx0net_daemon::network::bgp::wire::{decode_vx0, encode_vx0};
+use vx0net_daemon::network::bgp::{BGPOrigin, Community, PeerRef, Prefix, RouteEntry, RouteTable};
+use vx0net_daemon::network::dns::wire::{Query, Response};
+use vx0net_daemon::network::ike::resumption::PeerIdentity;
+use vx0net_daemon::network::ike::{
+    AuthPayload, ExchangeType, IKEMessage, IKEPayload, KeyExchangePayload, NoncePayload,
+    NotificationPayload, SAPayload, SAProposal, Transform, TransformAttribute,
+};
+use vx0net_daemon::NodeTier;
+
+/// Cases per property under `cargo test`
+const BOUNDED_CASES: u32 = 256;
+
+fn config() -> ProptestConfig {
+    let cases = if std::env::var_os("VX0_PROPTEST_UNBOUNDED").is_some() {
+        u32::MAX
+    } else {
+        std::env::var("PROPTEST_CASES")
+            .ok()
+            .and_then(|cases| cases.parse().ok())
+            .unwrap_or(BOUNDED_CASES)
+    };
+    ProptestConfig::with_cases(cases)
+}
+
+fn ipv4() -> impl Strategy<Value = Ipv4Addr> {
+    any::<u32>().prop_map(Ipv4Addr::from)
+}
+
+fn ipv6() -> impl Strategy<Value = Ipv6Addr> {
+    any::<u128>().prop_map(Ipv6Addr::from)
+}
+
+fn prefix() -> impl Strategy<Value = Prefix> {
+    prop_oneof![
+        3 => (ipv4(), 0..=32u8)
+            .prop_map(|(addr, len)| Prefix::new(IpNet::V4(Ipv4Net::new(addr, len).unwrap()))),
+        1 => (ipv6(), 0..=128u8)
+            .prop_map(|(addr, len)| Prefix::new(IpNet::V6(Ipv6Net::new(addr, len).unwrap()))),
+    ]
+}
+
+/// Prefixes clustered in a few /8s so tables have real overlaps
+fn nested_prefix() -> impl Strategy<Value = Prefix> {
+    (0..4u8, any::<[u8; 3]>(), 0..=32u8).prop_map(|(first, rest, len)| {
+        let addr = Ipv4Addr::new(10 + first, rest[0], rest[1], rest[2]);
+        Prefix::new(IpNet::V4(Ipv4Net::new(addr, len).unwrap()))
+    })
+}
+
+fn origin() -> impl Strategy<Value = BGPOrigin> {
+    prop_oneof![
+        Just(BGPOrigin::IGP),
+        Just(BGPOrigin::EGP),
+        Just(BGPOrigin::Incomplete)
+    ]
+}
+
+fn timestamp() -> impl Strategy<Value = DateTime<Utc>> {
+    (0..4_102_444_800i64, 0..1_000_000_000u32)
+        .prop_map(|(secs, nanos)| DateTime::from_timestamp(secs, nanos).unwrap())
+}
+
+fn tier() -> impl Strategy<Value = NodeTier> {
+    prop_oneof![
+        Just(NodeTier::Backbone),
+        Just(NodeTier::Regional),
+        Just(NodeTier::Edge)
+    ]
+}
+
+/// A route whose next hop is in its prefix's family
+fn route_in(network: impl Strategy<Value = Prefix>) -> impl Strategy<Value = RouteEntry> {
+    (
+        network,
+        ipv4(),
+        ipv6(),
+        prop::collection::vec(any::<u32>(), 0..12),
+        origin(),
+        any::<u32>(),
+        any::<u32>(),
+        prop::collection::vec(any::<(u16, u16)>(), 0..4),
+        timestamp(),
+        prop::option::of((any::<u32>(), any::<bool>())),
+    )
+        .prop_map(
+            |(network, v4, v6, path, origin, local_pref, med, communities, timestamp, peer)| {
+                let next_hop = match network.net() {
+                    IpNet::V4(_) => IpAddr::V4(v4),
+                    IpNet::V6(_) => IpAddr::V6(v6),
+                };
+                RouteEntry {
+                    network,
+                    next_hop,
+                    as_path: path.into(),
+                    origin,
+                    local_pref,
+                    med,
+                    communities: communities
+                        .into_iter()
+                        .map(|(asn, value)| Community { asn, value })
+                        .collect(),
+                    timestamp,
+                    learned_from: peer.map(|(asn, with_node)| {
+                        PeerRef::new(asn, with_node.then(uuid::Uuid::new_v4))
+                    }),
+                    originated_at: None,
+                }
+            },
+        )
+}
+
+fn route() -> impl Strategy<Value = RouteEntry> {
+    route_in(prefix())
+}
+
+fn overlay_message() -> impl Strategy<Value = BGPMessage> {
+    (
+        prop_oneof![
+            Just(BGPMessageType::Open),
+            Just(BGPMessageType::Update),
+            Just(BGPMessageType::Keepalive),
+            Just(BGPMessageType::Notification),
+        ],
+        any::<u32>(),
+        prop_oneof![ipv4().prop_map(IpAddr::V4), ipv6().prop_map(IpAddr::V6)],
+        prop::collection::vec(route(), 0..6),
+        timestamp(),
+        any::<bool>(),
+        prop::collection::vec(prefix(), 0..4),
+        prop::option::of(any::<u64>()),
+    )
+        .prop_map(
+            |(
+                message_type,
+                asn,
+                router_id,
+                routes,
+                timestamp,
+                with_caps,
+                withdrawn,
+                table_version,
+            )| BGPMessage {
+                message_type,
+                asn,
+                router_id,
+                routes: routes
+                    .into_iter()
+                    .map(|route| BGPRoute {
+                        network: route.network,
+                        next_hop: route.next_hop,
+                        as_path: route.as_path.to_vec(),
+                        origin: route.origin,
+                        local_pref: route.local_pref,
+                        med: route.med,
+                        originated_at: route.originated_at,
+                    })
+                    .collect(),
+                timestamp,
+                capabilities: with_caps.then(Default::default),
+                notification: None,
+                resume: None,
+                withdrawn,
+                table_version,
+                observed_addr: None,
+                chunk: None,
+                chunk_ack: None,
+                sync_resume: None,
+                binary_messages: false,
+            },
+        )
+}
+
+fn bytes(max: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 0..max)
+}
+
+fn ike_payload() -> impl Strategy<Value = IKEPayload> {
+    let transform = (
+        any::<u8>(),
+        any::<u16>(),
+        prop::collection::vec((any::<u16>(), bytes(8)), 0..3),
+    )
+        .prop_map(|(transform_type, transform_id, attributes)| Transform {
+            transform_type,
+            transform_id,
+            attributes: attributes
+                .into_iter()
+                .map(|(attribute_type, attribute_value)| TransformAttribute {
+                    attribute_type,
+                    attribute_value,
+                })
+                .collect(),
+        });
+    let proposal = (
+        any::<u8>(),
+        any::<u8>(),
+        bytes(8),
+        prop::collection::vec(transform, 0..4),
+    )
+        .prop_map(|(proposal_num, protocol_id, spi, transforms)| SAProposal {
+            proposal_num,
+            protocol_id,
+            spi,
+            transforms,
+        });
+    prop_oneof![
+        prop::collection::vec(proposal, 0..3)
+            .prop_map(|proposals| IKEPayload::SA(SAPayload { proposals })),
+        (any::<u16>(), bytes(64)).prop_map(|(dh_group, key_exchange_data)| {
+            IKEPayload::KeyExchange(KeyExchangePayload {
+                dh_group,
+                key_exchange_data,
+            })
+        }),
+        bytes(32).prop_map(|nonce_data| IKEPayload::Nonce(NoncePayload { nonce_data })),
+        (any::<u8>(), any::<u8>(), any::<u16>(), bytes(8), bytes(16)).prop_map(
+            |(protocol_id, spi_size, notify_message_type, spi, notification_data)| {
+                IKEPayload::Notification(NotificationPayload {
+                    protocol_id,
+                    spi_size,
+                    notify_message_type,
+                    spi,
+                    notification_data,
+                })
+            }
+        ),
+        (any::<u8>(), bytes(32)).prop_map(|(auth_method, auth_data)| {
+            IKEPayload::Authentication(AuthPayload {
+                auth_method,
+                auth_data,
+            })
+        }),
+        (any::<u8>(), bytes(16))
+            .prop_map(|(payload_type, data)| IKEPayload::Unknown { payload_type, data }),
+    ]
+}
+
+fn ike_message() -> impl Strategy<Value = IKEMessage> {
+    (
+        any::<(u64, u64, u8, u8, u8, u32, u32)>(),
+        prop_oneof![
+            Just(ExchangeType::IkeSaInit),
+            Just(ExchangeType::IkeAuth),
+            Just(ExchangeType::CreateChildSa),
+            Just(ExchangeType::Informational),
+        ],
+        prop::collection::vec(ike_payload(), 0..6),
+    )
+        .prop_map(
+            |(
+                (initiator_spi, responder_spi, next_payload, version, flags, message_id, length),
+                exchange_type,
+                payloads,
+            )| IKEMessage {
+                initiator_spi,
+                responder_spi,
+                next_payload,
+                version,
+                exchange_type,
+                flags,
+                message_id,
+                length,
+                payloads,
+            },
+        )
+}
+
+/// Names made of valid labels, in any case, with or without a final dot
+fn dns_name() -> impl Strategy<Value = String> {
+    (
+        prop::collection::vec("[a-zA-Z0-9]([a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?", 1..5),
+        any::<bool>(),
+    )
+        .prop_map(|(labels, dot)| {
+            let mut name = labels.join(".");
+            if dot {
+                name.push('.');
+            }
+            name
+        })
+}
+
+/// Serde types here have no `PartialEq`; compare what they serialize to
+fn same_json<T: serde::Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+}
+
+/// What a binary round trip keeps of `message`: OPEN carries no routes and
+/// says it is binary, UPDATE withdraws IPv4 prefixes before IPv6 ones, and
+/// KEEPALIVE and NOTIFICATION carry nothing but the notification, which
+/// defaults to Cease. `decoded` gives the timestamps that were not sent.
+fn binary_view(message: BGPMessage, decoded: &BGPMessage) -> BGPMessage {
+    match message.message_type {
+        BGPMessageType::Open => BGPMessage {
+            routes: vec![],
+            withdrawn: vec![],
+            binary_messages: true,
+            ..message
+        },
+        BGPMessageType::Update => {
+            let mut withdrawn = message.withdrawn.clone();
+            withdrawn.sort_by_key(|prefix| matches!(prefix.net(), IpNet::V6(_)));
+            BGPMessage {
+                withdrawn,
+                ..message
+            }
+        }
+        BGPMessageType::Keepalive => BGPMessage {
+            timestamp: decoded.timestamp,
+            ..BGPMessage::new(BGPMessageType::Keepalive, message.asn, message.router_id)
+        },
+        BGPMessageType::Notification => BGPMessage {
+            timestamp: decoded.timestamp,
+            notification: Some(NotificationMessage {
+                error_code: 6,
+                error_subcode: 0,
+                data: vec![],
+            }),
+            ..BGPMessage::new(BGPMessageType::Notification, message.asn, message.router_id)
+        },
+    }
+}
+
+/// The longest installed prefix covering `addr`, by brute force
+fn longest_match(routes: &[&RouteEntry], addr: IpAddr) -> Option<u8> {
+    routes
+        .iter()
+        .filter(|route| {
+            matches!(
+                (route.network.net(), addr),
+                (IpNet::V4(_), IpAddr::V4(_)) | (IpNet::V6(_), IpAddr::V6(_))
+            ) && route.network.contains(&addr)
+        })
+        .map(|route| route.network.prefix_len())
+        .max()
+}
+
+#[derive(Debug, Clone)]
+enum TableOp {
+    Add(RouteEntry),
+    Remove(Prefix),
+    /// Several adds, then several removes, as an UPDATE would apply them
+    Batch(Vec<RouteEntry>, Vec<Prefix>),
+}
+
+fn table_op() -> impl Strategy<Value = TableOp> {
+    prop_oneof![
+        4 => route_in(nested_prefix()).prop_map(TableOp::Add),
+        1 => (route_in(nested_prefix()), ipv6()).prop_map(|(mut route, v6)| {
+            // A next hop in the wrong family, which the table must refuse
+            route.next_hop = IpAddr::V6(v6);
+            TableOp::Add(route)
+        }),
+        2 => nested_prefix().prop_map(TableOp::Remove),
+        1 => (
+            prop::collection::vec(route_in(nested_prefix()), 0..8),
+            prop::collection::vec(nested_prefix(), 0..4)
+        )
+            .prop_map(|(adds, removes)| TableOp::Batch(adds, removes)),
+    ]
+}
+
+proptest! {
+    #![proptest_config(config())]
+
+    #[test]
+    fn route_entry_serde_round_trips(route in route()) {
+        let json = serde_json::to_vec(&route).unwrap();
+        let decoded: RouteEntry = serde_json::from_slice(&json).unwrap();
+        prop_assert!(same_json(&decoded, &route));
+        prop_assert_eq!(decoded.as_path.to_vec(), route.as_path.to_vec());
+    }
+
+    #[test]
+    fn overlay_message_serde_round_trips(message in overlay_message()) {
+        let json = serde_json::to_vec(&message).unwrap();
+        let decoded: BGPMessage = serde_json::from_slice(&json).unwrap();
+        prop_assert!(same_json(&decoded, &message));
+    }
+
+    #[test]
+    fn overlay_message_binary_round_trips(message in overlay_message()) {
+        // RFC 4271 refuses these as next hops and BGP identifiers
+        prop_assume!(message.routes.iter().all(|route| match route.next_hop {
+            IpAddr::V4(addr) => !addr.is_unspecified() && !addr.is_broadcast(),
+            IpAddr::V6(_) => true,
+        }));
+        prop_assume!(
+            message.message_type != BGPMessageType::Open
+                || matches!(message.router_id, IpAddr::V4(id) if !id.is_unspecified())
+        );
+        let peer = PeerIdentity {
+            asn: message.asn,
+            router_id: message.router_id,
+        };
+        let (msg_type, body) = encode_vx0(&message).unwrap();
+        let decoded = decode_vx0(msg_type, &body, peer).unwrap();
+        prop_assert!(same_json(&decoded, &binary_view(message, &decoded)));
+    }
+
+    #[test]
+    fn overlay_decoder_never_panics(data in bytes(512)) {
+        let _ = serde_json::from_slice::<BGPMessage>(&data);
+        let _ = serde_json::from_slice::<RouteEntry>(&data);
+        let peer = PeerIdentity { asn: 65001, router_id: IpAddr::V4(Ipv4Addr::LOCALHOST) };
+        if let Some((&msg_type, body)) = data.split_first() {
+            let _ = decode_vx0(msg_type, body, peer);
+        }
+    }
+
+    #[test]
+    fn ike_payload_trees_round_trip(message in ike_message()) {
+        let json = serde_json::to_vec(&message).unwrap();
+        let decoded: IKEMessage = serde_json::from_slice(&json).unwrap();
+        prop_assert!(same_json(&decoded, &message));
+    }
+
+    #[test]
+    fn dns_query_round_trips(id in any::<u16>(), name in dns_name(), qtype in any::<u16>()) {
+        let query = Query::new(id, &name, qtype);
+        let parsed = Query::parse(&query.to_bytes()).unwrap();
+        prop_assert_eq!(&parsed, &query);
+        prop_assert_eq!(parsed.name, name.trim_end_matches('.').to_ascii_lowercase());
+    }
+
+    #[test]
+    fn dns_decoders_never_panic(data in bytes(512)) {
+        let _ = Query::parse(&data);
+        let _ = Response::parse(&data);
+        let _ = vx0net_daemon::network::dns::wire::error_reply(
+            &data,
+            vx0net_daemon::network::dns::wire::Rcode::FormErr,
+        );
+    }
+
+    #[test]
+    fn route_table_keeps_longest_prefix_match(
+        ops in prop::collection::vec(table_op(), 0..40),
+        probes in prop::collection::vec((0..4u8, any::<[u8; 3]>()), 1..16),
+    ) {
+        let mut table = RouteTable::new();
+        for op in ops {
+            match op {
+                TableOp::Add(route) => {
+                    let network = route.network;
+                    let before = table.get_route(&network).map(|r| r.next_hop);
+                    let family_ok = matches!(
+                        (network.net(), route.next_hop),
+                        (IpNet::V4(_), IpAddr::V4(_)) | (IpNet::V6(_), IpAddr::V6(_))
+                    );
+                    prop_assert_eq!(table.add_route(route).is_ok(), family_ok);
+                    if !family_ok {
+                        prop_assert_eq!(table.get_route(&network).map(|r| r.next_hop), before);
+                    }
+                }
+                TableOp::Remove(network) => {
+                    let had = table.get_route(&network).is_some();
+                    prop_assert_eq!(table.remove_route(&network).is_some(), had);
+                    prop_assert!(table.get_route(&network).is_none());
+                }
+                TableOp::Batch(adds, removes) => {
+                    for route in adds {
+                        let _ = table.add_route(route);
+                    }
+                    for network in removes {
+                        table.remove_route(&network);
+                    }
+                }
+            }
+            // Every route is keyed by its own normalized prefix
+            for (key, route) in &table.routes {
+                prop_assert_eq!(*key, route.network);
+                prop_assert_eq!(key.net(), key.net().trunc());
+            }
+        }
+
+        let routes = table.get_all_routes();
+        for (first, rest) in probes {
+            let addr = IpAddr::V4(Ipv4Addr::new(10 + first, rest[0], rest[1], rest[2]));
+            let best = table.find_best_route(&addr);
+            prop_assert_eq!(best.map(|r| r.network.prefix_len()), longest_match(&routes, addr));
+            if let Some(best) = best {
+                prop_assert!(best.network.contains(&addr));
+            }
+        }
+    }
+
+    #[test]
+    fn route_table_is_insertion_order_independent(
+        routes in prop::collection::vec(route_in(nested_prefix()), 0..30)
+            .prop_flat_map(|routes| {
+                // One route per prefix, or the last writer would differ
+                let mut seen = HashSet::new();
+                let unique: Vec<RouteEntry> = routes
+                    .into_iter()
+                    .filter(|route| seen.insert(route.network))
+                    .collect();
+                (Just(unique.clone()), Just(unique).prop_shuffle())
+            }),
+        probes in prop::collection::vec((0..4u8, any::<[u8; 3]>()), 1..16),
+    ) {
+        let (ordered, shuffled) = routes;
+        let build = |routes: &[RouteEntry]| {
+            let mut table = RouteTable::new();
+            for route in routes {
+                table.add_route(route.clone()).unwrap();
+            }
+            table
+        };
+        let (a, b) = (build(&ordered), build(&shuffled));
+
+        let contents = |table: &RouteTable| {
+            let mut routes: Vec<String> = table
+                .get_all_routes()
+                .into_iter()
+                .map(|route| serde_json::to_string(route).unwrap())
+                .collect();
+            routes.sort();
+            routes
+        };
+        prop_assert_eq!(contents(&a), contents(&b));
+        prop_assert_eq!(a.version, b.version);
+        for (first, rest) in probes {
+            let addr = IpAddr::V4(Ipv4Addr::new(10 + first, rest[0], rest[1], rest[2]));
+            prop_assert_eq!(
+                a.find_best_route(&addr).map(|r| r.network),
+                b.find_best_route(&addr).map(|r| r.network)
+            );
+        }
+    }
+
+    #[test]
+    fn policy_is_deterministic(
+        local_asn in any::<u32>(),
+        tier in tier(),
+        route in route(),
+        peer_asn in any::<u32>(),
+        denied in prop::collection::vec(prefix(), 0..3),
+    ) {
+        let mut policy = RoutingPolicy::new(local_asn, tier);
+        for network in denied {
+            policy.deny_prefix(network);
+        }
+        let again = policy.clone();
+
+        prop_assert_eq!(
+            policy.should_accept_route(&route, peer_asn),
+            again.should_accept_route(&route, peer_asn)
+        );
+        prop_assert_eq!(
+            policy.should_advertise_route(&route, peer_asn),
+            again.should_advertise_route(&route, peer_asn)
+        );
+        prop_assert_eq!(policy.evaluate_route(&route), again.evaluate_route(&route));
+        let best = policy.select_best_route(std::slice::from_ref(&route));
+        prop_assert!(best.is_some_and(|best| best.network == route.network));
+    }
+}
+
+#[cfg(feature = "external-bgp")]
+mod wire {
+    use super::*;
+    use vx0net_daemon::network::bgp::messages::{
+        self, AttributeValue, NotificationMessage, PathAttribute, UpdateMessage,
+    };
+    use vx0net_daemon::network::bgp::wire;
+
+    fn ipv4_prefix() -> impl Strategy<Value = IpNet> {
+        (ipv4(), 0..=32u8)
+            .prop_map(|(addr, len)| IpNet::V4(Ipv4Net::new(addr, len).unwrap().trunc()))
+    }
+
+    /// Attributes as the decoder produces them: type code, flags and
+    /// length consistent with the value
+    fn attribute() -> impl Strategy<Value = PathAttribute> {
+        let next_hop = ipv4().prop_filter("usable next hop", |addr| {
+            !addr.is_unspecified() && !addr.is_broadcast()
+        });
+        prop_oneof![
+            origin().prop_map(|origin| (messages::BGP_ATTR_ORIGIN, AttributeValue::Origin(origin))),
+            prop::collection::vec(any::<u32>(), 0..300)
+                .prop_map(|path| (messages::BGP_ATTR_AS_PATH, AttributeValue::AsPath(path))),
+            next_hop.prop_map(|addr| {
+                (
+                    messages::BGP_ATTR_NEXT_HOP,
+                    AttributeValue::NextHop(IpAddr::V4(addr)),
+                )
+            }),
+            any::<u32>().prop_map(|med| {
+                (
+                    messages::BGP_ATTR_MULTI_EXIT_DISC,
+                    AttributeValue::MultiExitDisc(med),
+                )
+            }),
+            any::<u32>().prop_map(|pref| (
+                messages::BGP_ATTR_LOCAL_PREF,
+                AttributeValue::LocalPref(pref)
+            )),
+            prop::collection::vec(any::<u32>(), 0..8).prop_map(|communities| {
+                (
+                    messages::BGP_ATTR_COMMUNITIES,
+                    AttributeValue::Communities(communities),
+                )
+            }),
+            (20..=255u8, bytes(32)).prop_map(|(code, raw)| (code, AttributeValue::Unknown(raw))),
+        ]
+        .prop_map(|(type_code, value)| {
+            let length = match &value {
+                AttributeValue::Origin(_) => 1,
+                AttributeValue::AsPath(path) => path.len() * 4 + path.len().div_ceil(255) * 2,
+                AttributeValue::Communities(list) => list.len() * 4,
+                AttributeValue::Unknown(raw) => raw.len(),
+                _ => 4,
+            };
+            let flags = if length > 255 { 0x50 } else { 0x40 };
+            PathAttribute {
+                flags,
+                type_code,
+                length: length as u16,
+                value,
+            }
+        })
+    }
+
+    /// Prefixes decode with any host bits a peer sent, for its `host_bits`
+    /// policy to judge, but are always encoded as their network
+    fn without_host_bits(message: messages::BGPMessage) -> messages::BGPMessage {
+        match message {
+            messages::BGPMessage::Update(mut update) => {
+                for net in update
+                    .withdrawn_routes
+                    .iter_mut()
+                    .chain(&mut update.network_layer_reachability_info)
+                {
+                    *net = net.trunc();
+                }
+                messages::BGPMessage::Update(update)
+            }
+            message => message,
+        }
+    }
+
+    fn wire_message() -> impl Strategy<Value = messages::BGPMessage> {
+        prop_oneof![
+            Just(messages::BGPMessage::Keepalive),
+            (
+                any::<u32>(),
+                any::<u16>().prop_filter("acceptable hold time", |t| *t != 1 && *t != 2),
+                ipv4().prop_filter("nonzero identifier", |addr| !addr.is_unspecified()),
+            )
+                .prop_map(|(asn, hold_time, id)| wire::open_with_capabilities(asn, hold_time, id)),
+            (any::<u8>(), any::<u8>(), bytes(64)).prop_map(|(error_code, error_subcode, data)| {
+                messages::BGPMessage::Notification(NotificationMessage {
+                    error_code,
+                    error_subcode,
+                    data,
+                })
+            }),
+            (
+                prop::collection::vec(ipv4_prefix(), 0..20),
+                prop::collection::vec(attribute(), 0..6),
+                prop::collection::vec(ipv4_prefix(), 0..20),
+            )
+                .prop_map(|(withdrawn_routes, path_attributes, nlri)| {
+                    messages::BGPMessage::Update(UpdateMessage {
+                        withdrawn_routes,
+                        path_attributes,
+                        network_layer_reachability_info: nlri,
+                    })
+                }),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(config())]
+
+        #[test]
+        fn wire_messages_round_trip(message in wire_message()) {
+            // Messages past the 4096-byte limit are refused, not truncated
+            let Ok(encoded) = wire::encode(&message) else {
+                prop_assert!(matches!(message, messages::BGPMessage::Update(_)));
+                return Ok(());
+            };
+            prop_assert!(encoded.len() <= wire::BGP_MAX_MESSAGE_LEN);
+            prop_assert_eq!(wire::decode(&encoded).unwrap(), message);
+        }
+
+        #[test]
+        fn wire_decoder_never_panics(data in bytes(4200)) {
+            let _ = wire::decode(&data);
+            let _ = wire::decode_header(&data);
+        }
+
+        #[test]
+        fn wire_decoder_survives_corruption(
+            message in wire_message(),
+            flips in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            keep in any::<prop::sample::Index>(),
+        ) {
+            let Ok(mut encoded) = wire::encode(&message) else {
+                return Ok(());
+            };
+            // Leave the marker and header alone half the time so the body
+            // parsers see the damage
+            for (at, byte) in flips {
+                let at = at.index(encoded.len());
+                if at >= wire::BGP_HEADER_LEN || byte & 1 == 0 {
+                    encoded[at] ^= byte;
+                }
+            }
+            // Whatever still decodes can be sent back, less any host bits
+            if let Ok(decoded) = wire::decode(&encoded) {
+                if let Ok(reencoded) = wire::encode(&decoded) {
+                    prop_assert_eq!(wire::decode(&reencoded).ok(), Some(without_host_bits(decoded)));
+                }
+            }
+            let _ = wire::decode(&encoded[..keep.index(encoded.len() + 1)]);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Per-peer quarantine: a peer whose UPDATEs keep arriving with send times
+//! jumping back and forth is first demoted, its routes used only when
+//! nothing else reaches the prefix, recovers as its score decays, and when
+//! it keeps at it is quarantined, losing its routes and its place until an
+//! operator clears it. Scores outlive a restart.
+
+mod common;
+
+use common::route;
+
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use vx0net_daemon::network::bgp::protocol::BGPMessage;
+use vx0net_daemon::network::bgp::{BGPDaemon, Prefix, RouteEntry};
+use vx0net_daemon::node::quarantine::{self, Quarantine, Standing, ViolationKind};
+use vx0net_daemon::node::{ConnectionStatus, NodeError, NodeTier, PeerConnection, Vx0Node};
+use vx0net_daemon::storage::Store;
+use vx0net_daemon::util::clock::{Clock, ManualClock, SharedClock};
+
+const NOISY: u32 = 65101;
+const STEADY: u32 = 65102;
+const PREFIX: &str = "10.50.0.0/16";
+
+struct Lab {
+    clock: Arc<ManualClock>,
+    node: Arc<Vx0Node>,
+    bgp: Arc<BGPDaemon>,
+}
+
+async fn lab() -> Lab {
+    let clock = Arc::new(ManualClock::new());
+    let shared: SharedClock = clock.clone();
+    let config = common::config(NodeTier::Edge);
+    let node = Arc::new(
+        Vx0Node::new(config)
+            .unwrap()
+            .with_clock(Arc::clone(&shared)),
+    );
+    let mut bgp = BGPDaemon::new(66001, "10.2.0.1".parse().unwrap(), 0);
+    bgp.set_clock(shared).await;
+    bgp.set_quarantine(Arc::clone(&node.quarantine)).await;
+    let bgp = Arc::new(bgp);
+    bgp.follow_peer_events(node.subscribe_peer_events());
+    node.follow_quarantine();
+
+    for (asn, addr) in [(NOISY, "10.1.0.1"), (STEADY, "10.1.0.2")] {
+        let mut peer = PeerConnection::new(Uuid::new_v4(), asn, addr.parse().unwrap());
+        peer.status = ConnectionStatus::Authenticated;
+        node.add_peer(peer).await.unwrap();
+    }
+    let lab = Lab { clock, node, bgp };
+    // The noisy peer's route has the lower MED, so it is preferred while
+    // in good standing
+    lab.update(NOISY, chrono::Duration::zero()).await;
+    lab.update(STEADY, chrono::Duration::zero()).await;
+    assert_eq!(lab.best().await, Some(NOISY));
+    lab
+}
+
+impl Lab {
+    /// An UPDATE from `asn` announcing the prefix, sent `off` our clock
+    async fn update(&self, asn: u32, off: chrono::Duration) {
+        let route = RouteEntry {
+            med: if asn == NOISY { 0 } else { 50 },
+            ..route(PREFIX, "10.1.0.1", &[asn])
+        };
+        let msg: BGPMessage = serde_json::from_value(serde_json::json!({
+            "message_type": "Update",
+            "asn": asn,
+            "router_id": "10.1.0.1",
+            "routes": [{
+                "network": route.network,
+                "next_hop": route.next_hop,
+                "as_path": route.as_path.to_vec(),
+                "origin": route.origin,
+                "local_pref": route.local_pref,
+                "med": route.med,
+            }],
+            "timestamp": self.clock.now_utc() + off,
+        }))
+        .unwrap();
+        self.bgp.receive_message(asn, &msg).await.unwrap();
+    }
+
+    /// UPDATEs from the noisy peer alternating between ten minutes ahead
+    /// of its clock and back, each one off from the one before; ends back
+    /// on its clock when `violations` is even
+    async fn misbehave(&self, violations: usize) {
+        for i in 0..violations {
+            let off = if i % 2 == 0 {
+                chrono::Duration::minutes(10)
+            } else {
+                chrono::Duration::zero()
+            };
+            self.update(NOISY, off).await;
+        }
+    }
+
+    async fn best(&self) -> Option<u32> {
+        self.bgp
+            .explain_route(&prefix())
+            .await
+            .installed
+            .and_then(|route| route.learned_from)
+            .map(|peer| peer.asn)
+    }
+
+    async fn status(&self, asn: u32) -> ConnectionStatus {
+        self.node
+            .list_peers()
+            .await
+            .into_iter()
+            .find(|peer| peer.peer_asn == asn)
+            .unwrap()
+            .status
+    }
+
+    fn standing(&self) -> Standing {
+        self.node.quarantine.standing(NOISY, self.clock.now_utc())
+    }
+}
+
+fn prefix() -> Prefix {
+    PREFIX.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_violations_demote_then_quarantine() {
+    let lab = lab().await;
+
+    // Four anomalies at 15 points each pass the demotion score of 50
+    lab.misbehave(2).await;
+    assert_eq!(lab.standing(), Standing::Good);
+    lab.misbehave(2).await;
+    assert_eq!(lab.standing(), Standing::Demoted);
+    assert_eq!(lab.best().await, Some(STEADY));
+    // Demoted, the peer still connects and its routes are still held
+    assert_eq!(lab.bgp.routes_from(NOISY).await, 1);
+
+    // Three more pass the quarantine score of 100
+    lab.misbehave(3).await;
+    let Standing::Quarantined { until } = lab.standing() else {
+        panic!("{:?}", lab.standing());
+    };
+    assert_eq!(until, lab.clock.now_utc() + chrono::Duration::hours(1));
+    for _ in 0..50 {
+        if matches!(lab.status(NOISY).await, ConnectionStatus::Quarantined) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(matches!(
+        lab.status(NOISY).await,
+        ConnectionStatus::Quarantined
+    ));
+    assert_eq!(lab.bgp.routes_from(NOISY).await, 0);
+    assert!(matches!(
+        lab.status(STEADY).await,
+        ConnectionStatus::Authenticated
+    ));
+
+    // Refused, and what it sends is ignored
+    assert!(matches!(
+        lab.node
+            .connect_peer("10.1.0.1".parse().unwrap(), NOISY)
+            .await,
+        Err(NodeError::Quarantined { asn: NOISY })
+    ));
+    lab.update(NOISY, chrono::Duration::zero()).await;
+    assert_eq!(lab.bgp.routes_from(NOISY).await, 0);
+
+    // The violations behind it are shown, latest last
+    let status = &lab.node.quarantine.list(lab.clock.now_utc())[0];
+    assert_eq!(status.asn, NOISY);
+    assert_eq!(status.events.len(), 7);
+    assert!(status
+        .events
+        .iter()
+        .all(|violation| violation.kind == ViolationKind::TimestampAnomaly));
+}
+
+#[tokio::test]
+async fn test_demoted_peer_recovers_as_its_score_decays() {
+    let lab = lab().await;
+    lab.misbehave(4).await;
+    assert_eq!(lab.standing(), Standing::Demoted);
+
+    // One half-life brings 60 points down to 30
+    lab.clock.advance(Duration::from_secs(600));
+    assert_eq!(lab.standing(), Standing::Good);
+    lab.bgp.follow_standing(NOISY).await.unwrap();
+    assert_eq!(lab.best().await, Some(NOISY));
+
+    // Scores decayed to nothing are forgotten
+    lab.clock.advance(Duration::from_secs(6000));
+    assert!(lab.node.quarantine.list(lab.clock.now_utc()).is_empty());
+}
+
+#[tokio::test]
+async fn test_clearing_lifts_quarantine() {
+    let lab = lab().await;
+    lab.misbehave(7).await;
+    assert!(matches!(lab.standing(), Standing::Quarantined { .. }));
+    for _ in 0..50 {
+        if matches!(lab.status(NOISY).await, ConnectionStatus::Quarantined) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert!(lab.node.clear_quarantine(NOISY).await.unwrap());
+    assert!(!lab.node.clear_quarantine(NOISY).await.unwrap());
+    lab.bgp.follow_standing(NOISY).await.unwrap();
+    assert_eq!(lab.standing(), Standing::Good);
+    assert!(matches!(
+        lab.status(NOISY).await,
+        ConnectionStatus::Disconnected
+    ));
+
+    // Back in good standing, its routes are preferred again
+    lab.update(NOISY, chrono::Duration::zero()).await;
+    assert_eq!(lab.best().await, Some(NOISY));
+}
+
+#[tokio::test]
+async fn test_scores_survive_restart() {
+    let root = std::env::temp_dir().join(format!("vx0net-quarantine-{}", Uuid::new_v4()));
+    let namespace = || Store::open(&root).unwrap().namespace(quarantine::NAMESPACE);
+    let now = chrono::Utc::now();
+
+    let before = Quarantine::default();
+    before.open(namespace()).unwrap();
+    for _ in 0..3 {
+        before
+            .record(NOISY, ViolationKind::Flood, "max-prefix exceeded", now)
+            .unwrap();
+    }
+    assert!(before.is_quarantined(NOISY, now));
+    drop(before);
+
+    let after = Quarantine::default();
+    assert_eq!(after.open(namespace()).unwrap(), 1);
+    assert!(after.is_quarantined(NOISY, now));
+    // The cooldown runs out, by when the score has decayed away
+    let later = now + chrono::Duration::hours(1);
+    assert!(!after.is_quarantined(NOISY, later));
+    assert_eq!(after.standing(NOISY, later), Standing::Good);
+
+    after.clear(NOISY).unwrap();
+    assert_eq!(Quarantine::default().open(namespace()).unwrap(), 0);
+    let _ = std::fs::remove_dir_all(&root);
+}
@@ -0,0 +1,181 @@
+//! Readiness as an orchestrator probes it: `/readyz` and `status --ready`
+//! follow a Regional node gaining its first BGP session, entering
+//! maintenance and losing every peer, and each change between ready and
+//! not ready reaches subscribers and the transition command.
+
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use common::wait_for;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use vx0net_daemon::config::ControlConfig;
+use vx0net_daemon::control::{ControlRequest, ControlResponse, ControlServer};
+use vx0net_daemon::monitoring::http;
+use vx0net_daemon::monitoring::readiness::{ReadinessEvent, ReadinessReport};
+use vx0net_daemon::monitoring::MonitoringError;
+use vx0net_daemon::network::bgp::protocol::{BGPProtocol, BGPStream};
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::node::{NodeTier, PeerConnection, Vx0Node};
+
+const REGIONAL_ASN: u32 = 65101;
+const EDGE_ASN: u32 = 66001;
+
+/// GET `path`, returning the status code and the body
+async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).await.unwrap();
+    let (head, body) = answer.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+async fn readyz(addr: SocketAddr) -> (u16, Vec<String>) {
+    let (status, body) = get(addr, "/readyz").await;
+    let report: ReadinessReport = serde_json::from_str(&body).unwrap();
+    assert_eq!(report.ready, status == 200);
+    let failing = report.failing().iter().map(|c| c.name.clone()).collect();
+    (status, failing)
+}
+
+/// What `vx0net status --ready` is told by the daemon
+async fn status_ready(control: &ControlServer) -> ReadinessReport {
+    match control.handle(ControlRequest::Readiness).await {
+        ControlResponse::Readiness { report } => report,
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+async fn edge_session(addr: SocketAddr) -> BGPStream {
+    let edge = BGPProtocol::new(EDGE_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Edge);
+    let (_session, stream) = edge.open_session(addr, REGIONAL_ASN).await.unwrap();
+    stream
+}
+
+#[tokio::test]
+async fn test_readiness_follows_peers_and_maintenance() {
+    let transitions =
+        std::env::temp_dir().join(format!("vx0-readiness-{}.jsonl", uuid::Uuid::new_v4()));
+    let mut config = common::config(NodeTier::Regional);
+    config.monitoring.readiness.interval_secs = 3600;
+    config.monitoring.readiness.transition_command = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        format!(
+            "cat >> {}; echo >> {}",
+            transitions.display(),
+            transitions.display()
+        ),
+    ];
+    let hooks = config.network.retry.hooks();
+    let node = Arc::new(Vx0Node::new(config).unwrap());
+    node.readiness.expect(&["bgp"]);
+
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    let bgp = BGPDaemon::new(REGIONAL_ASN, localhost, 0);
+    bgp.start().await.unwrap();
+    let bgp = Arc::new(bgp);
+    let bgp_addr = SocketAddr::new(localhost, bgp.local_addr().unwrap().port());
+    let probes = http::serve(
+        SocketAddr::new(localhost, 0),
+        Arc::clone(&node),
+        Arc::clone(&bgp),
+    )
+    .await
+    .unwrap();
+    let control = ControlServer::new(ControlConfig::default(), Arc::clone(&bgp));
+    control.set_node(Arc::clone(&node));
+    let mut events = node.readiness.subscribe();
+    node.readiness
+        .start(Arc::clone(&node), Arc::clone(&bgp), hooks);
+
+    // Alive from the start, but not ready without peers or a started BGP
+    assert_eq!(get(probes, "/livez").await.0, 200);
+    assert_eq!(
+        readyz(probes).await,
+        (503, vec!["peers".into(), "subsystems".into()])
+    );
+    node.readiness.started("bgp");
+    let (status, body) = get(probes, "/metrics").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("vx0net_ready 0"));
+
+    let stream = edge_session(bgp_addr).await;
+    wait_for("the session", || async {
+        bgp.established_sessions().await == 1
+    })
+    .await;
+    assert_eq!(readyz(probes).await, (200, vec![]));
+    assert_eq!(status_ready(&control).await.exit_code(), 0);
+    assert_eq!(events.recv().await.unwrap(), ReadinessEvent::Ready);
+
+    // Maintenance alone takes the node out of rotation
+    node.enter_maintenance(None).await;
+    assert_eq!(readyz(probes).await, (503, vec!["maintenance".into()]));
+    match events.recv().await.unwrap() {
+        ReadinessEvent::NotReady { failing } => {
+            assert_eq!(failing.len(), 1);
+            assert_eq!(failing[0].name, "maintenance");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // Losing the last peer as well is no new transition
+    drop(stream);
+    wait_for("the session to close", || async {
+        bgp.established_sessions().await == 0
+    })
+    .await;
+    assert_eq!(
+        readyz(probes).await,
+        (503, vec!["peers".into(), "maintenance".into()])
+    );
+    let report = status_ready(&control).await;
+    assert_eq!(report.exit_code(), 1);
+    assert!(matches!(
+        report.check(),
+        Err(MonitoringError::NotReady { failing }) if failing.len() == 2
+    ));
+    assert!(events.try_recv().is_err());
+
+    // Peering again ends maintenance, and a session makes the node ready
+    let edge_node = Vx0Node::new(common::config(NodeTier::Edge)).unwrap();
+    node.add_peer(PeerConnection::new(
+        edge_node.node_id,
+        EDGE_ASN,
+        "10.3.0.1".parse().unwrap(),
+    ))
+    .await
+    .unwrap();
+    assert!(!node.in_maintenance());
+    let _stream = edge_session(bgp_addr).await;
+    wait_for("the new session", || async {
+        bgp.established_sessions().await == 1
+    })
+    .await;
+    assert_eq!(readyz(probes).await, (200, vec![]));
+    assert_eq!(events.recv().await.unwrap(), ReadinessEvent::Ready);
+
+    // The transition command saw all three changes, in order
+    wait_for("the transition command", || async {
+        std::fs::read_to_string(&transitions).is_ok_and(|logged| logged.lines().count() == 3)
+    })
+    .await;
+    let logged: Vec<String> = std::fs::read_to_string(&transitions)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let event: serde_json::Value = serde_json::from_str(line).unwrap();
+            event["event"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(logged, ["ready", "not_ready", "ready"]);
+    let _ = std::fs::remove_file(&transitions);
+}
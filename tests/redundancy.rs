@@ -0,0 +1,212 @@
+//! Two Regional nodes in one redundancy group taking turns at maintenance:
+//! one waits while the other is away, either may force its way past, and a
+//! partner that cannot be asked does not hold the other back.
+
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+use vx0net_daemon::config::units::ConfigDuration;
+use vx0net_daemon::config::RedundancyPartner;
+use vx0net_daemon::monitoring::readiness::ReadinessInputs;
+use vx0net_daemon::network::acl::AclEntry;
+use vx0net_daemon::network::ike::channel;
+use vx0net_daemon::node::redundancy::{self, MemberState, RedundancyError};
+use vx0net_daemon::node::{NodeId, NodeTier, PeerConnection, Vx0Node};
+
+const GROUP: &str = "ams";
+
+struct Member {
+    node: Arc<Vx0Node>,
+    addr: SocketAddr,
+    server: JoinHandle<Option<()>>,
+}
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+fn node(node_id: NodeId, partner: (NodeId, &TcpListener)) -> Arc<Vx0Node> {
+    let node = common::node(NodeTier::Regional, |config| {
+        config.node.node_id = Some(node_id);
+        config.node.redundancy.group = Some(GROUP.to_string());
+        config.node.redundancy.hold_down = ConfigDuration::from_secs(0);
+        config.node.redundancy.partners = vec![RedundancyPartner {
+            node_id: partner.0,
+            address: partner.1.local_addr().unwrap().to_string(),
+        }];
+        config.network.acl.state_file = None;
+    });
+    node.readiness.observe(&ReadinessInputs {
+        tier: NodeTier::Regional,
+        established_peers: 8,
+        default_route: true,
+        pending: Vec::new(),
+        wall_clock: node.clock.now_utc(),
+        maintenance: false,
+        held_down: false,
+    });
+    node
+}
+
+/// `node` peered with `peer` over a tunnel on this host
+async fn peer_with(node: &Vx0Node, peer: &Vx0Node) {
+    node.add_peer(PeerConnection::new(peer.node_id, peer.asn, localhost()))
+        .await
+        .unwrap();
+    node.tunnel_manager
+        .create_tunnel(
+            localhost(),
+            localhost(),
+            "127.0.0.1:4500".parse().unwrap(),
+            b"redundancy-psk",
+        )
+        .await
+        .unwrap();
+}
+
+/// Both partners, peered and each answering the other on a local port
+async fn pair() -> (Member, Member) {
+    let listener_a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener_b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (id_a, id_b) = (Uuid::new_v4(), Uuid::new_v4());
+    let a = node(id_a, (id_b, &listener_b));
+    let b = node(id_b, (id_a, &listener_a));
+    peer_with(&a, &b).await;
+    peer_with(&b, &a).await;
+    let a = Member {
+        addr: listener_a.local_addr().unwrap(),
+        server: redundancy::serve(Arc::clone(&a), listener_a),
+        node: a,
+    };
+    let b = Member {
+        addr: listener_b.local_addr().unwrap(),
+        server: redundancy::serve(Arc::clone(&b), listener_b),
+        node: b,
+    };
+    (a, b)
+}
+
+/// Peering again is what ends maintenance
+async fn come_back(node: &Vx0Node) {
+    node.add_peer(PeerConnection::new(
+        Uuid::new_v4(),
+        66001,
+        "10.3.0.1".parse().unwrap(),
+    ))
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_partners_take_turns() {
+    let (a, b) = pair().await;
+    let entry = a.node.enter_maintenance_in_turn(None, false).await.unwrap();
+    assert!(entry.unreachable.is_empty());
+    assert!(a.node.in_maintenance());
+
+    let Err(RedundancyError::Blocked { group, members }) =
+        b.node.enter_maintenance_in_turn(None, false).await
+    else {
+        panic!("expected to wait for partner a");
+    };
+    assert_eq!(group, GROUP);
+    assert_eq!(members[0].node_id, a.node.node_id);
+    assert!(matches!(members[0].state, MemberState::Maintenance { .. }));
+    assert!(!b.node.in_maintenance());
+
+    come_back(&a.node).await;
+    let entry = b.node.enter_maintenance_in_turn(None, false).await.unwrap();
+    assert!(entry.unreachable.is_empty() && entry.overridden.is_empty());
+    assert!(b.node.in_maintenance());
+
+    let status = a.node.group_status().await.unwrap();
+    assert_eq!(status.local, MemberState::Active);
+    assert!(matches!(
+        status.members[0].state,
+        MemberState::Maintenance { .. }
+    ));
+}
+
+#[tokio::test]
+async fn test_force_goes_past_a_partner_in_maintenance() {
+    let (a, b) = pair().await;
+    a.node.enter_maintenance_in_turn(None, false).await.unwrap();
+
+    let entry = b.node.enter_maintenance_in_turn(None, true).await.unwrap();
+    assert_eq!(entry.overridden.len(), 1);
+    assert_eq!(entry.overridden[0].node_id, a.node.node_id);
+    assert!(b.node.in_maintenance());
+}
+
+#[tokio::test]
+async fn test_simultaneous_entry_lets_at_most_one_through() {
+    let (a, b) = pair().await;
+    let (from_a, from_b) = tokio::join!(
+        a.node.enter_maintenance_in_turn(None, false),
+        b.node.enter_maintenance_in_turn(None, false)
+    );
+    assert!(from_a.is_err() || from_b.is_err());
+    assert_eq!(from_a.is_ok(), a.node.in_maintenance());
+    assert_eq!(from_b.is_ok(), b.node.in_maintenance());
+}
+
+#[tokio::test]
+async fn test_unreachable_partner_does_not_block() {
+    let (a, b) = pair().await;
+    a.server.abort();
+    let _ = a.server.await;
+
+    let entry = b.node.enter_maintenance_in_turn(None, false).await.unwrap();
+    assert_eq!(entry.unreachable.len(), 1);
+    assert_eq!(entry.unreachable[0].node_id, a.node.node_id);
+    assert!(matches!(
+        entry.unreachable[0].state,
+        MemberState::Unreachable { .. }
+    ));
+    assert!(b.node.in_maintenance());
+}
+
+/// What the member serving on `server` sends back to `request` before
+/// closing
+async fn exchange(server: SocketAddr, request: &[u8]) -> Vec<u8> {
+    let mut stream = tokio::net::TcpStream::connect(server).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut reply = Vec::new();
+    let _ = stream.read_to_end(&mut reply).await;
+    reply
+}
+
+/// `message` as `node` seals it for its tunnel to this host
+async fn sealed(node: &Vx0Node, message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::new();
+    channel::write_sealed(&mut framed, &node.tunnel_manager, localhost(), message)
+        .await
+        .unwrap();
+    framed
+}
+
+#[tokio::test]
+async fn test_refused_and_oversized_queries_go_unanswered() {
+    let (a, b) = pair().await;
+    assert!(a.node.redundancy.is_partner(b.node.node_id));
+    assert!(!a.node.redundancy.is_partner(Uuid::new_v4()));
+
+    let query = format!("{{\"type\":\"query\",\"group\":\"{}\"}}", GROUP);
+    let sealed_query = sealed(&b.node, query.as_bytes()).await;
+    assert!(!exchange(a.addr, &sealed_query).await.is_empty());
+    assert!(exchange(a.addr, query.as_bytes()).await.is_empty());
+
+    let oversized = vec![b'a'; redundancy::MAX_GROUP_MESSAGE_LEN * 2];
+    let oversized = sealed(&b.node, &oversized).await;
+    assert!(exchange(a.addr, &oversized).await.is_empty());
+
+    a.node.acl.block(AclEntry::Node(b.node.node_id)).unwrap();
+    assert!(exchange(a.addr, &sealed_query).await.is_empty());
+    assert_eq!(a.node.acl.denied(), 1);
+}
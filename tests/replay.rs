@@ -0,0 +1,190 @@
+//! A node captures a scripted exchange with a peer, over a BGP session and
+//! its control socket; replaying the capture against a fresh, isolated
+//! node ends with the same routing table.
+
+mod common;
+
+use common::{route, wait_for};
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use vx0net_daemon::config::{CaptureConfig, ControlConfig};
+use vx0net_daemon::control::{ControlRequest, ControlResponse, ControlServer};
+use vx0net_daemon::monitoring::capture::{Capture, CaptureFile, CapturedEvent};
+use vx0net_daemon::monitoring::replay::{Replay, Speed};
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::{BGPDaemon, RouteTable};
+use vx0net_daemon::node::NodeTier;
+
+const CAPTURED_ASN: u32 = 65002;
+const PEER_ASN: u32 = 65001;
+
+/// Everything about the Loc-RIB but when and over which session each route
+/// was learned
+async fn table(bgp: &BGPDaemon) -> Vec<String> {
+    let mut routes: Vec<String> = bgp
+        .get_routes()
+        .await
+        .into_iter()
+        .map(|route| {
+            format!(
+                "{} via {} path {:?} {:?} pref {} med {} {:?} from {:?}",
+                route.network,
+                route.next_hop,
+                route.as_path.iter().collect::<Vec<_>>(),
+                route.origin,
+                route.local_pref,
+                route.med,
+                route.communities,
+                route.learned_from.map(|peer| peer.asn)
+            )
+        })
+        .collect();
+    routes.sort();
+    routes
+}
+
+async fn control(server: &ControlServer, request: ControlRequest) {
+    let response = server.handle(request).await;
+    assert!(
+        !matches!(response, ControlResponse::Error { .. }),
+        "{response:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_replay_reproduces_the_captured_route_table() {
+    let path = std::env::temp_dir().join(format!("vx0-replay-{}.jsonl", uuid::Uuid::new_v4()));
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    let capture = Arc::new(
+        Capture::create(
+            &path,
+            &CaptureConfig {
+                enabled: true,
+                ..Default::default()
+            },
+            CAPTURED_ASN,
+            localhost,
+        )
+        .unwrap(),
+    );
+
+    let mut captured = BGPDaemon::new(CAPTURED_ASN, localhost, 0);
+    captured.set_capture(Arc::clone(&capture));
+    captured.start().await.unwrap();
+    let captured = Arc::new(captured);
+    let addr = SocketAddr::new(localhost, captured.local_addr().unwrap().port());
+    let operator = ControlServer::new(ControlConfig::default(), Arc::clone(&captured));
+    operator.set_capture(Arc::clone(&capture));
+
+    // The peer sends its table, the operator originates and pins routes,
+    // and the peer withdraws and replaces part of what it sent
+    let peer = BGPProtocol::new(PEER_ASN, "10.0.0.1".parse().unwrap(), NodeTier::Backbone);
+    let (_session, mut stream) = peer.open_session(addr, CAPTURED_ASN).await.unwrap();
+    let mut sent = RouteTable::new();
+    for third in 0..6 {
+        sent.add_route(route(
+            &format!("10.70.{}.0/24", third),
+            "10.0.0.1",
+            &[PEER_ASN],
+        ))
+        .unwrap();
+    }
+    sent.add_route(route("10.80.0.0/24", "10.0.0.1", &[PEER_ASN, 65003]))
+        .unwrap();
+    peer.advertise_table(&mut stream, &sent, 0).await.unwrap();
+    wait_for("the peer's table", || async {
+        captured.routes_from(PEER_ASN).await == 7
+    })
+    .await;
+
+    control(
+        &operator,
+        ControlRequest::AnnounceRoute {
+            network: "10.90.0.0/24".parse().unwrap(),
+            next_hop: localhost,
+            communities: vec![],
+        },
+    )
+    .await;
+    control(
+        &operator,
+        ControlRequest::AnnounceRoute {
+            network: "10.70.1.0/24".parse().unwrap(),
+            next_hop: localhost,
+            communities: vec![],
+        },
+    )
+    .await;
+    control(
+        &operator,
+        ControlRequest::PinRoute {
+            network: "10.70.1.0/24".parse().unwrap(),
+            via_asn: PEER_ASN,
+            duration_secs: None,
+            allow_missing: false,
+        },
+    )
+    .await;
+
+    let since = sent.version;
+    sent.remove_route(&"10.70.2.0/24".parse().unwrap());
+    sent.remove_route(&"10.70.3.0/24".parse().unwrap());
+    sent.add_route(route("10.80.0.0/24", "10.0.0.1", &[PEER_ASN]))
+        .unwrap();
+    peer.advertise_table(&mut stream, &sent, since)
+        .await
+        .unwrap();
+    wait_for("the withdrawals", || async {
+        captured.routes_from(PEER_ASN).await == 5
+    })
+    .await;
+    control(
+        &operator,
+        ControlRequest::WithdrawRoute {
+            network: "10.90.0.0/24".parse().unwrap(),
+        },
+    )
+    .await;
+    let expected = table(&captured).await;
+
+    let file = CaptureFile::read(&path).unwrap();
+    assert_eq!(file.header.asn, CAPTURED_ASN);
+    assert!(!file.full);
+    let kinds: Vec<&str> = file
+        .records
+        .iter()
+        .map(|record| match &record.event {
+            CapturedEvent::Bgp { .. } => "bgp",
+            CapturedEvent::Control { .. } => "control",
+            other => panic!("unexpected capture {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        ["bgp", "control", "control", "control", "bgp", "control"]
+    );
+    assert!(file
+        .records
+        .windows(2)
+        .all(|pair| pair[0].seq + 1 == pair[1].seq && pair[0].offset_ms <= pair[1].offset_ms));
+
+    let replay = Replay::new(file.header.clone(), None)
+        .await
+        .unwrap()
+        .with_speed(Speed::Times(100.0));
+    let report = replay.run(&file.records, None).await;
+    assert_eq!((report.applied, report.skipped), (6, 0));
+    assert!(report.failed.is_empty(), "{:?}", report.failed);
+    assert_eq!(table(replay.bgp()).await, expected);
+    assert!(expected
+        .iter()
+        .any(|route| route.starts_with("10.70.1.0/24")));
+
+    // Stopping partway leaves the node as it was at that point
+    let partial = Replay::new(file.header.clone(), None).await.unwrap();
+    partial.run(&file.records, Some(0)).await;
+    assert_eq!(partial.bgp().routes_from(PEER_ASN).await, 7);
+    assert!(partial.bgp().get_routes().await.len() >= 7);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,224 @@
+//! Learned routes are judged by `scripts/reference-validator.py`, run once
+//! per batch or served over HTTP. Rejections are journaled with the
+//! validator's reason, verdicts are cached, and a validator that does not
+//! answer in time fails open or closed as configured.
+
+mod common;
+
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use vx0net_daemon::config::{ValidatorConfig, ValidatorFailure};
+use vx0net_daemon::network::bgp::explain::PeerOutcome;
+use vx0net_daemon::network::bgp::validator::{RouteValidator, RULE};
+use vx0net_daemon::network::bgp::{BGPDaemon, Prefix, RouteEntry};
+
+const PEER_ASN: u32 = 65101;
+const HIJACKER_ASN: u32 = 64512;
+const SCRIPT: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/scripts/reference-validator.py"
+);
+
+fn python() -> bool {
+    let found = Command::new("python3")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !found {
+        eprintln!("python3 not found, skipping");
+    }
+    found
+}
+
+fn exec(args: &[&str]) -> ValidatorConfig {
+    let mut command = vec!["python3".to_string(), SCRIPT.to_string()];
+    command.extend(args.iter().map(|arg| arg.to_string()));
+    ValidatorConfig {
+        command,
+        ..ValidatorConfig::default()
+    }
+}
+
+/// Kills the HTTP validator when the test ends
+struct Served(Child);
+
+impl Drop for Served {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn serve(args: &[&str]) -> (Served, ValidatorConfig) {
+    let mut child = Command::new("python3")
+        .arg(SCRIPT)
+        .args(["--http", "0"])
+        .args(args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut port = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut port)
+        .unwrap();
+    let config = ValidatorConfig {
+        url: Some(format!("http://127.0.0.1:{}/v1", port.trim())),
+        ..ValidatorConfig::default()
+    };
+    (Served(child), config)
+}
+
+fn log_file() -> PathBuf {
+    std::env::temp_dir().join(format!("vx0net-validator-{}.log", uuid::Uuid::new_v4()))
+}
+
+/// Batches the validator was asked about
+fn batches(log: &PathBuf) -> usize {
+    std::fs::read_to_string(log)
+        .map(|log| log.lines().count())
+        .unwrap_or(0)
+}
+
+fn route(prefix: &str, origin: u32) -> RouteEntry {
+    common::route(prefix, "10.3.0.1", &[PEER_ASN, origin])
+}
+
+fn daemon(config: &ValidatorConfig) -> BGPDaemon {
+    let mut daemon = BGPDaemon::new(65001, "10.2.0.1".parse().unwrap(), 0);
+    daemon.set_validator(RouteValidator::from_config(config).unwrap());
+    daemon
+}
+
+async fn installed(daemon: &BGPDaemon, address: &str) -> bool {
+    daemon
+        .find_best_route(&address.parse().unwrap())
+        .await
+        .is_some()
+}
+
+/// The validator's reason for refusing `prefix`, if it did
+async fn refusal(daemon: &BGPDaemon, prefix: &str) -> Option<String> {
+    let network: Prefix = prefix.parse().unwrap();
+    let explanation = daemon.explain_route(&network).await;
+    explanation
+        .peers
+        .into_iter()
+        .find_map(|peer| match peer.outcome {
+            PeerOutcome::Rejected { verdict, .. } if verdict.rule == RULE => Some(verdict.details),
+            _ => None,
+        })
+}
+
+async fn hijack_is_refused(daemon: &BGPDaemon) {
+    daemon
+        .receive_update(
+            PEER_ASN,
+            vec![
+                route("10.60.1.0/24", 65201),
+                route("10.60.2.0/24", HIJACKER_ASN),
+            ],
+            &[],
+        )
+        .await
+        .unwrap();
+    assert!(installed(daemon, "10.60.1.1").await);
+    assert!(!installed(daemon, "10.60.2.1").await);
+    assert_eq!(
+        refusal(daemon, "10.60.2.0/24").await.as_deref(),
+        Some("origin AS64512 is denied")
+    );
+    assert_eq!(refusal(daemon, "10.60.1.0/24").await, None);
+}
+
+#[tokio::test]
+async fn test_exec_validator_refuses_with_its_reason() {
+    if !python() {
+        return;
+    }
+    let daemon = daemon(&exec(&["--deny-origin", "64512"]));
+    hijack_is_refused(&daemon).await;
+}
+
+#[tokio::test]
+async fn test_http_validator_refuses_with_its_reason() {
+    if !python() {
+        return;
+    }
+    let (_served, config) = serve(&["--deny-origin", "64512"]);
+    let daemon = daemon(&config);
+    hijack_is_refused(&daemon).await;
+}
+
+#[tokio::test]
+async fn test_slow_validator_fails_open_or_closed() {
+    if !python() {
+        return;
+    }
+    let slow = |on_failure| ValidatorConfig {
+        timeout_ms: 200,
+        on_failure,
+        ..exec(&["--delay-ms", "2000"])
+    };
+
+    let open = daemon(&slow(ValidatorFailure::Open));
+    open.receive_update(PEER_ASN, vec![route("10.60.1.0/24", 65201)], &[])
+        .await
+        .unwrap();
+    assert!(installed(&open, "10.60.1.1").await);
+
+    let closed = daemon(&slow(ValidatorFailure::Closed));
+    let started = std::time::Instant::now();
+    closed
+        .receive_update(PEER_ASN, vec![route("10.60.1.0/24", 65201)], &[])
+        .await
+        .unwrap();
+    assert!(started.elapsed() < std::time::Duration::from_millis(1500));
+    assert!(!installed(&closed, "10.60.1.1").await);
+    let reason = refusal(&closed, "10.60.1.0/24").await.unwrap();
+    assert!(reason.contains("did not answer"), "{}", reason);
+}
+
+#[tokio::test]
+async fn test_verdicts_are_cached_per_prefix_and_origin() {
+    if !python() {
+        return;
+    }
+    let log = log_file();
+    let log_arg = log.to_str().unwrap();
+    let daemon = daemon(&exec(&["--log", log_arg, "--deny-origin", "64512"]));
+
+    let update = |routes| daemon.receive_update(PEER_ASN, routes, &[]);
+    update(vec![route("10.60.1.0/24", 65201)]).await.unwrap();
+    update(vec![route("10.60.1.0/24", 65201)]).await.unwrap();
+    assert_eq!(batches(&log), 1);
+
+    // The same prefix from another origin is asked about, and its refusal
+    // replaces the path accepted before
+    update(vec![
+        route("10.60.1.0/24", HIJACKER_ASN),
+        route("10.60.2.0/24", 65201),
+    ])
+    .await
+    .unwrap();
+    update(vec![route("10.60.1.0/24", HIJACKER_ASN)])
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "1\n2\n");
+    assert!(!installed(&daemon, "10.60.1.1").await);
+    assert!(installed(&daemon, "10.60.2.1").await);
+
+    // Without a TTL nothing is reused
+    let uncached = RouteValidator::from_config(&ValidatorConfig {
+        cache_ttl_secs: 0,
+        ..exec(&["--log", log_arg])
+    })
+    .unwrap();
+    for _ in 0..2 {
+        uncached
+            .screen(PEER_ASN, vec![route("10.60.1.0/24", 65201)])
+            .await;
+    }
+    assert_eq!(batches(&log), 4);
+    let _ = std::fs::remove_file(&log);
+}
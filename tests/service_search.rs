@@ -0,0 +1,235 @@
+//! Searching for services by type and tag across three nodes that serve
+//! zone sync on 127.0.0.1-3, all on the same port.
+
+mod common;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use uuid::Uuid;
+use vx0net_daemon::network::dns::sync::ZoneSyncService;
+use vx0net_daemon::network::dns::Vx0DNS;
+use vx0net_daemon::node::metadata::{ServiceMetadata, Visibility};
+use vx0net_daemon::node::search::{ServiceFilter, ServiceSummary};
+use vx0net_daemon::node::services::ServiceRegistry;
+use vx0net_daemon::node::{
+    HostedService, NodeTier, PeerConnection, ServiceStatus, ServiceType, Vx0Node,
+};
+
+struct TestNode {
+    node: Arc<Vx0Node>,
+    dns: Arc<RwLock<Vx0DNS>>,
+    sync: ZoneSyncService,
+    registry: ServiceRegistry,
+    address: IpAddr,
+}
+
+impl TestNode {
+    async fn start(index: u8, asn: u32, port: u16) -> TestNode {
+        let mut config = common::config(NodeTier::Backbone);
+        config.node.asn = asn;
+        config.network.dns.sync_port = port;
+        let node = Arc::new(Vx0Node::new(config).unwrap());
+
+        let mut dns = Vx0DNS::new();
+        dns.set_origin(node.node_id);
+        let dns = Arc::new(RwLock::new(dns));
+        node.set_directory(Arc::clone(&dns));
+
+        let address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, index));
+        let sync = ZoneSyncService::new(Arc::clone(&dns));
+        sync.start(SocketAddr::new(address, port)).await.unwrap();
+        TestNode {
+            registry: ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns)),
+            node,
+            dns,
+            sync,
+            address,
+        }
+    }
+
+    fn sync_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.node.config.network.dns.sync_port)
+    }
+
+    async fn host(&self, domain: &str, service_type: ServiceType, tags: &[&str]) -> Uuid {
+        self.host_with(domain, service_type, tags, Visibility::Public)
+            .await
+    }
+
+    async fn host_with(
+        &self,
+        domain: &str,
+        service_type: ServiceType,
+        tags: &[&str],
+        visibility: Visibility,
+    ) -> Uuid {
+        let service = HostedService {
+            service_id: Uuid::new_v4(),
+            name: domain.trim_end_matches(".vx0").to_string(),
+            service_type,
+            domain: domain.to_string(),
+            port: 6667,
+            status: ServiceStatus::Running,
+            metadata: ServiceMetadata {
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                visibility,
+                ..Default::default()
+            },
+        };
+        let id = service.service_id;
+        self.registry.publish(service).await.unwrap();
+        id
+    }
+
+    async fn peer_with(&self, other: &TestNode, latency_ms: u64) {
+        let mut peer = PeerConnection::new(other.node.node_id, other.node.asn, other.address);
+        peer.capabilities.serves_dns = true;
+        peer.update_metrics(latency_ms, 0.0);
+        self.node.add_peer(peer).await.unwrap();
+    }
+}
+
+fn domains(services: &[ServiceSummary]) -> Vec<&str> {
+    services
+        .iter()
+        .map(|service| service.domain.as_str())
+        .collect()
+}
+
+fn chat_in(tag: &str) -> ServiceFilter {
+    ServiceFilter {
+        service_type: Some(ServiceType::from("chat")),
+        tags: vec![tag.to_string()],
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_find_services_across_peers() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let alpha = TestNode::start(1, 65001, port).await;
+    let beta = TestNode::start(2, 65002, port).await;
+    let gamma = TestNode::start(3, 65003, port).await;
+
+    alpha
+        .host("alpha-chat.vx0", ServiceType::ChatServer, &["community1"])
+        .await;
+    alpha
+        .host("alpha-wiki.vx0", ServiceType::WebServer, &["community1"])
+        .await;
+    gamma
+        .host("gamma-chat.vx0", ServiceType::ChatServer, &["community2"])
+        .await;
+    let gamma_irc = gamma
+        .host("gamma-irc.vx0", ServiceType::ChatServer, &["Community1"])
+        .await;
+
+    // Beta holds a copy of gamma's listings from before gamma's IRC
+    // service started failing
+    beta.sync.pull_from(gamma.sync_addr(), "vx0").await.unwrap();
+    beta.host("beta-chat.vx0", ServiceType::ChatServer, &["community1"])
+        .await;
+    beta.host(
+        "beta-matrix.vx0",
+        ServiceType::Custom("Matrix".to_string()),
+        &["community1"],
+    )
+    .await;
+    beta.host_with(
+        "beta-private.vx0",
+        ServiceType::ChatServer,
+        &["community1"],
+        Visibility::Unlisted,
+    )
+    .await;
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    let mut failing = gamma
+        .dns
+        .read()
+        .await
+        .services()
+        .into_iter()
+        .find(|service| service.service_id == gamma_irc)
+        .unwrap();
+    failing.status = ServiceStatus::Failed;
+    failing.updated = chrono::Utc::now();
+    gamma.dns.write().await.list_service(&failing, 300).unwrap();
+
+    alpha.peer_with(&beta, 40).await;
+    alpha.peer_with(&gamma, 10).await;
+
+    // Without fan-out only what alpha's own directory holds
+    let local = alpha.node.find_services(chat_in("community1")).await;
+    assert_eq!(domains(&local), ["alpha-chat.vx0"]);
+    assert_eq!(local[0].latency_ms, Some(0));
+
+    // Gamma's IRC service is listed by both beta and gamma, once, with
+    // gamma's fresher status
+    let everywhere = alpha
+        .node
+        .find_services(ServiceFilter {
+            fan_out: true,
+            ..chat_in("community1")
+        })
+        .await;
+    assert_eq!(
+        domains(&everywhere),
+        ["alpha-chat.vx0", "beta-chat.vx0", "gamma-irc.vx0"]
+    );
+    let irc = everywhere
+        .iter()
+        .find(|service| service.service_id == gamma_irc)
+        .unwrap();
+    assert_eq!(irc.status, ServiceStatus::Failed);
+    assert_eq!(irc.node_id, gamma.node.node_id);
+
+    // Closest owner first: alpha itself, then gamma at 10ms, beta at 40ms
+    let nearby = alpha
+        .node
+        .find_services(ServiceFilter {
+            fan_out: true,
+            nearby: true,
+            ..chat_in("community1")
+        })
+        .await;
+    assert_eq!(
+        domains(&nearby),
+        ["alpha-chat.vx0", "gamma-irc.vx0", "beta-chat.vx0"]
+    );
+    let latencies: Vec<Option<u64>> = nearby.iter().map(|service| service.latency_ms).collect();
+    assert_eq!(latencies, [Some(0), Some(10), Some(40)]);
+
+    // Custom types match regardless of case; tags narrow further
+    let matrix = alpha
+        .node
+        .find_services(ServiceFilter {
+            service_type: Some(ServiceType::from("MATRIX")),
+            fan_out: true,
+            ..Default::default()
+        })
+        .await;
+    assert_eq!(domains(&matrix), ["beta-matrix.vx0"]);
+    let elsewhere = alpha
+        .node
+        .find_services(ServiceFilter {
+            fan_out: true,
+            ..chat_in("community2")
+        })
+        .await;
+    assert_eq!(domains(&elsewhere), ["gamma-chat.vx0"]);
+
+    // Everything listed anywhere, still once each
+    let everything = alpha
+        .node
+        .find_services(ServiceFilter {
+            fan_out: true,
+            ..Default::default()
+        })
+        .await;
+    assert_eq!(everything.len(), 6);
+}
@@ -0,0 +1,98 @@
+//! The BGP listener tracks connections only while they are open: no more
+//! than its limit at once, with the rest refused and counted, and those
+//! that never send an OPEN closed after the setup timeout.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use vx0net_daemon::config::units::ConfigDuration;
+use vx0net_daemon::config::SessionLimitsConfig;
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPSessionState};
+use vx0net_daemon::node::NodeTier;
+
+const LIMIT: usize = 8;
+const SETUP_TIMEOUT: Duration = Duration::from_millis(300);
+
+async fn listener() -> (BGPDaemon, SocketAddr) {
+    let daemon = BGPDaemon::new(65001, "10.0.0.1".parse().unwrap(), 0);
+    daemon
+        .set_session_limits(SessionLimitsConfig {
+            max_sessions: LIMIT,
+            setup_timeout: ConfigDuration::from(SETUP_TIMEOUT),
+        })
+        .await;
+    daemon.start().await.unwrap();
+    let addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        daemon.local_addr().unwrap().port(),
+    );
+    (daemon, addr)
+}
+
+async fn until(what: &str, mut check: impl AsyncFnMut() -> bool) {
+    for _ in 0..300 {
+        if check().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for {}", what);
+}
+
+#[tokio::test]
+async fn test_abandoned_connections_stay_bounded_and_are_swept() {
+    let (daemon, addr) = listener().await;
+
+    // Connections that never send an OPEN, as from a port scanner
+    let mut abandoned = Vec::new();
+    for _ in 0..20 {
+        abandoned.push(TcpStream::connect(addr).await.unwrap());
+    }
+    until(
+        "the connections beyond the limit to be refused",
+        async || daemon.connections_rejected().await == 12,
+    )
+    .await;
+    assert_eq!(daemon.connection_count().await, LIMIT);
+    assert!(daemon.session_peers().await.is_empty());
+
+    until("the abandoned connections to be swept", async || {
+        daemon.connection_count().await == 0
+    })
+    .await;
+    // And the sweep closed them
+    for stream in &mut abandoned {
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+}
+
+#[tokio::test]
+async fn test_session_is_tracked_until_its_connection_closes() {
+    let (daemon, addr) = listener().await;
+    let peer: IpAddr = "127.0.0.1".parse().unwrap();
+    let client = BGPProtocol::new(65101, "10.0.0.2".parse().unwrap(), NodeTier::Regional);
+
+    let (_session, stream) = client.open_session(addr, 65001).await.unwrap();
+    until("the session to be established", async || {
+        daemon.session_state(peer).await == Some(BGPSessionState::Established)
+    })
+    .await;
+
+    // Established sessions outlive the setup timeout
+    tokio::time::sleep(SETUP_TIMEOUT * 2).await;
+    assert_eq!(daemon.session_peers().await, vec![peer]);
+    assert_eq!(daemon.connection_count().await, 1);
+
+    drop(stream);
+    until("the closed session to be forgotten", async || {
+        daemon.connection_count().await == 0
+    })
+    .await;
+    assert!(daemon.session_peers().await.is_empty());
+    assert_eq!(daemon.session_state(peer).await, None);
+    assert_eq!(daemon.connections_rejected().await, 0);
+}
@@ -0,0 +1,346 @@
+//! A peer reconnecting soon after a session ended resumes it from a ticket:
+//! no IKE handshake, and only the routes that changed in the meantime. A
+//! replayed ticket is refused, and a peer the responder no longer holds a
+//! ticket for falls back to the full handshake.
+
+mod common;
+
+use common::{route, wait_for};
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::tunnel_gate::TunnelGate;
+use vx0net_daemon::network::bgp::{wire, BGPDaemon, BGPSessionState, RouteTable};
+use vx0net_daemon::network::ike::resumption::DEFAULT_VALIDITY;
+use vx0net_daemon::network::ike::tunnels::{TunnelId, TunnelManager, TunnelStatus};
+use vx0net_daemon::node::capabilities::Capabilities;
+use vx0net_daemon::node::NodeTier;
+
+const SERVER_ASN: u32 = 65101;
+const CLIENT_ASN: u32 = 65102;
+const PSK: &[u8] = b"session-resumption-psk";
+const ROUTES: u8 = 40;
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+fn requires_tunnel() -> Capabilities {
+    Capabilities {
+        requires_tunnel: true,
+        ..Capabilities::default()
+    }
+}
+
+fn tunnels() -> Arc<TunnelManager> {
+    Arc::new(TunnelManager::new().with_resumption(Some(DEFAULT_VALIDITY)))
+}
+
+/// One message seen on the wire: its RFC 4271 header and JSON body
+#[derive(Debug, Clone)]
+struct Frame {
+    from_client: bool,
+    header: [u8; wire::BGP_HEADER_LEN],
+    body: Vec<u8>,
+}
+
+/// Messages of one connection
+type Frames = Arc<Mutex<Vec<Frame>>>;
+
+/// Relays connections to the server, recording every message
+#[derive(Clone)]
+struct Proxy {
+    addr: SocketAddr,
+    /// Messages of each connection, in the order they were accepted
+    connections: Arc<Mutex<Vec<Frames>>>,
+}
+
+impl Proxy {
+    async fn start(server: SocketAddr) -> Self {
+        let listener = TcpListener::bind((localhost(), 0)).await.unwrap();
+        let proxy = Proxy {
+            addr: listener.local_addr().unwrap(),
+            connections: Arc::default(),
+        };
+        let connections = Arc::clone(&proxy.connections);
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let server = TcpStream::connect(server).await.unwrap();
+                let frames = Arc::new(Mutex::new(Vec::new()));
+                connections.lock().unwrap().push(Arc::clone(&frames));
+                let (client_read, client_write) = client.into_split();
+                let (server_read, server_write) = server.into_split();
+                tokio::spawn(relay(client_read, server_write, true, Arc::clone(&frames)));
+                tokio::spawn(relay(server_read, client_write, false, frames));
+            }
+        });
+        proxy
+    }
+
+    fn frames(&self, connection: usize) -> Vec<Frame> {
+        self.connections.lock().unwrap()[connection]
+            .lock()
+            .unwrap()
+            .clone()
+    }
+}
+
+async fn relay(
+    mut from: tokio::net::tcp::OwnedReadHalf,
+    mut to: tokio::net::tcp::OwnedWriteHalf,
+    from_client: bool,
+    frames: Frames,
+) {
+    let mut header = [0u8; wire::BGP_HEADER_LEN];
+    while from.read_exact(&mut header).await.is_ok() {
+        let length = u16::from_be_bytes([header[16], header[17]]) as usize;
+        let mut body = vec![0u8; length - wire::BGP_HEADER_LEN];
+        if from.read_exact(&mut body).await.is_err() {
+            break;
+        }
+        if to.write_all(&header).await.is_err() || to.write_all(&body).await.is_err() {
+            break;
+        }
+        frames.lock().unwrap().push(Frame {
+            from_client,
+            header,
+            body,
+        });
+    }
+}
+
+/// Messages exchanged to bring a session up and the peer's routes up to
+/// date: BGP on the wire, and IKE for the client's tunnel
+struct Cost {
+    messages: usize,
+    bytes: usize,
+}
+
+async fn cost(proxy: &Proxy, connection: usize, tunnels: &TunnelManager, tunnel: TunnelId) -> Cost {
+    let frames = proxy.frames(connection);
+    let ike = tunnels.get_tunnel(&tunnel).await.unwrap();
+    Cost {
+        messages: frames.len() + ike.ike_session.handshake_messages as usize,
+        bytes: frames
+            .iter()
+            .map(|frame| frame.header.len() + frame.body.len())
+            .sum(),
+    }
+}
+
+async fn tunnel(tunnels: &TunnelManager) -> TunnelId {
+    let peer = SocketAddr::new(localhost(), 4500);
+    tunnels
+        .create_tunnel(localhost(), localhost(), peer, PSK)
+        .await
+        .unwrap()
+}
+
+async fn fail_tunnels(tunnels: &TunnelManager) {
+    for tunnel in tunnels.list_tunnels().await {
+        if tunnel.status == TunnelStatus::Established {
+            tunnels.mark_failed(&tunnel.tunnel_id).await.unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_resumed_session_skips_the_handshake_and_sends_deltas() {
+    let (server_tunnels, client_tunnels) = (tunnels(), tunnels());
+    let mut server = BGPDaemon::new(SERVER_ASN, "10.2.0.1".parse().unwrap(), 0);
+    server.set_capabilities(requires_tunnel());
+    server.set_tunnel_gate(TunnelGate::new(Arc::clone(&server_tunnels)));
+    server.start().await.unwrap();
+    let proxy = Proxy::start(server.local_addr().unwrap()).await;
+    let client = BGPProtocol::new(CLIENT_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Regional)
+        .with_capabilities(requires_tunnel())
+        .with_tunnel_gate(Arc::new(TunnelGate::new(Arc::clone(&client_tunnels))));
+
+    let mut table = RouteTable::new();
+    for i in 0..ROUTES {
+        table
+            .add_route(route(
+                &format!("10.60.{}.0/24", i),
+                "10.3.0.1",
+                &[CLIENT_ASN],
+            ))
+            .unwrap();
+    }
+
+    // First contact: the session waits for a full IKE handshake, then the
+    // whole table is sent
+    let bring_up = async {
+        wait_for("the session to reach OpenSent", || async {
+            server.session_state(localhost()).await == Some(BGPSessionState::OpenSent)
+        })
+        .await;
+        tunnel(&server_tunnels).await;
+        tunnel(&client_tunnels).await
+    };
+    let (opened, first_tunnel) =
+        tokio::join!(client.open_session(proxy.addr, SERVER_ASN), bring_up);
+    let (session, mut stream) = opened.unwrap();
+    assert!(!session.resumed);
+    client
+        .advertise_table(&mut stream, &table, session.peer_table_version)
+        .await
+        .unwrap();
+    wait_for("the server to hold the table", || async {
+        server_tunnels
+            .resumption()
+            .unwrap()
+            .table_version(localhost(), CLIENT_ASN)
+            == Some(table.version)
+    })
+    .await;
+    assert_eq!(server.routes_from(CLIENT_ASN).await, ROUTES as usize);
+    let full = cost(&proxy, 0, &client_tunnels, first_tunnel).await;
+
+    // A blip: the connection and both tunnels are lost, and the table
+    // changes in the meantime
+    drop(stream);
+    fail_tunnels(&client_tunnels).await;
+    fail_tunnels(&server_tunnels).await;
+    table
+        .add_route(route("10.61.0.0/24", "10.3.0.1", &[CLIENT_ASN]))
+        .unwrap();
+    table.remove_route(&"10.60.0.0/24".parse().unwrap());
+
+    // Reconnecting resumes at once, without anyone creating a tunnel
+    let (session, mut stream) = tokio::time::timeout(
+        Duration::from_secs(5),
+        client.open_session(proxy.addr, SERVER_ASN),
+    )
+    .await
+    .expect("a resumed session does not wait for a handshake")
+    .unwrap();
+    assert!(session.resumed);
+    assert_eq!(session.peer_table_version, u64::from(ROUTES));
+    client
+        .advertise_table(&mut stream, &table, session.peer_table_version)
+        .await
+        .unwrap();
+    wait_for("the changes to arrive", || async {
+        server
+            .find_best_route(&"10.61.0.1".parse().unwrap())
+            .await
+            .is_some()
+    })
+    .await;
+    assert!(server
+        .find_best_route(&"10.60.0.1".parse().unwrap())
+        .await
+        .is_none());
+    assert_eq!(server.routes_from(CLIENT_ASN).await, ROUTES as usize);
+
+    // Both ends hold the same fresh keys
+    let (ours, theirs) = (
+        client_tunnels.tunnel_to(localhost()).await.unwrap(),
+        server_tunnels.tunnel_to(localhost()).await.unwrap(),
+    );
+    let sealed = client_tunnels.send_packet(&ours, b"routes").await.unwrap();
+    assert_eq!(
+        server_tunnels
+            .receive_packet(&theirs, &sealed)
+            .await
+            .unwrap(),
+        b"routes"
+    );
+
+    let resumed = cost(&proxy, 1, &client_tunnels, ours).await;
+    assert_eq!(full.messages, 2 + 1 + 4);
+    assert_eq!(resumed.messages, 2 + 1);
+    assert!(resumed.messages < full.messages);
+    assert!(
+        resumed.bytes * 2 < full.bytes,
+        "{} vs {}",
+        resumed.bytes,
+        full.bytes
+    );
+
+    // The resumed OPEN, replayed, is refused: the server answers without
+    // accepting it
+    let replayed = proxy
+        .frames(1)
+        .into_iter()
+        .find(|frame| frame.from_client)
+        .unwrap();
+    let mut attacker = TcpStream::connect(server.local_addr().unwrap())
+        .await
+        .unwrap();
+    attacker.write_all(&replayed.header).await.unwrap();
+    attacker.write_all(&replayed.body).await.unwrap();
+    let (_, answer) = wire::read_frame(&mut attacker, wire::BGP_MAX_EXTENDED_MESSAGE_LEN, None)
+        .await
+        .unwrap()
+        .unwrap();
+    // Both ends offer binary messages, so the answer is a binary OPEN
+    let answer = wire::decode_vx0_open(&answer).unwrap();
+    let hello = answer.resume.expect("the server still resumes sessions");
+    assert!(hello.accepted.is_none());
+}
+
+#[tokio::test]
+async fn test_unknown_ticket_falls_back_to_the_full_handshake() {
+    let (server_tunnels, client_tunnels) = (tunnels(), tunnels());
+    let mut server = BGPDaemon::new(SERVER_ASN, "10.2.0.1".parse().unwrap(), 0);
+    server.set_capabilities(requires_tunnel());
+    server.set_tunnel_gate(TunnelGate::new(Arc::clone(&server_tunnels)));
+    server.start().await.unwrap();
+    let addr = SocketAddr::new(localhost(), server.local_addr().unwrap().port());
+    let client = BGPProtocol::new(CLIENT_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Regional)
+        .with_capabilities(requires_tunnel())
+        .with_tunnel_gate(Arc::new(TunnelGate::new(Arc::clone(&client_tunnels))));
+    let table = {
+        let mut table = RouteTable::new();
+        table
+            .add_route(route("10.60.1.0/24", "10.3.0.1", &[CLIENT_ASN]))
+            .unwrap();
+        table
+    };
+
+    let bring_up = || async {
+        wait_for("the session to reach OpenSent", || async {
+            server.session_state(localhost()).await == Some(BGPSessionState::OpenSent)
+        })
+        .await;
+        tunnel(&server_tunnels).await;
+        tunnel(&client_tunnels).await
+    };
+    let (opened, _) = tokio::join!(client.open_session(addr, SERVER_ASN), bring_up());
+    let (_, mut stream) = opened.unwrap();
+    client
+        .advertise_table(&mut stream, &table, 0)
+        .await
+        .unwrap();
+    wait_for("the route to arrive", || async {
+        server.routes_from(CLIENT_ASN).await == 1
+    })
+    .await;
+
+    // The server dropped the peer, routes and ticket alike, so the client's
+    // offer is turned down and it negotiates afresh
+    drop(stream);
+    server.drop_peer(CLIENT_ASN).await.unwrap();
+    assert!(server_tunnels.resumption().unwrap().is_empty());
+    assert_eq!(client_tunnels.resumption().unwrap().len(), 1);
+    fail_tunnels(&client_tunnels).await;
+    fail_tunnels(&server_tunnels).await;
+
+    let (opened, _) = tokio::join!(client.open_session(addr, SERVER_ASN), bring_up());
+    let (session, mut stream) = opened.unwrap();
+    assert!(!session.resumed);
+    assert_eq!(session.peer_table_version, 0);
+    client
+        .advertise_table(&mut stream, &table, session.peer_table_version)
+        .await
+        .unwrap();
+    wait_for("the full table to arrive again", || async {
+        server.routes_from(CLIENT_ASN).await == 1
+    })
+    .await;
+}
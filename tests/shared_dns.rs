@@ -0,0 +1,123 @@
+//! One directory wired as the daemon wires it: a service the node publishes
+//! resolves through the running DNS server, is found by the node's own
+//! searches and reaches a DNS-serving peer's server, with nothing registered
+//! twice and no manual NOTIFY.
+
+#![cfg(feature = "dns-server")]
+
+mod common;
+
+use common::wait_for;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use uuid::Uuid;
+use vx0net_daemon::config::DNSConfig;
+use vx0net_daemon::network::dns::changes::DnsChange;
+use vx0net_daemon::network::dns::server::Vx0DNSServer;
+use vx0net_daemon::network::dns::sync::ZoneSyncService;
+use vx0net_daemon::network::dns::wire::{Query, Response, TYPE_A};
+use vx0net_daemon::network::dns::{SharedDns, Vx0DNS};
+use vx0net_daemon::node::metadata::ServiceMetadata;
+use vx0net_daemon::node::search::ServiceFilter;
+use vx0net_daemon::node::services::ServiceRegistry;
+use vx0net_daemon::node::{
+    HostedService, NodeTier, PeerConnection, ServiceStatus, ServiceType, Vx0Node,
+};
+
+async fn serve(config: &DNSConfig, dns: &SharedDns) -> SocketAddr {
+    let mut config = config.clone();
+    config.listen_port = 0;
+    config.vx0_dns_servers.clear();
+    let bound = Vx0DNSServer::from_config(&config, Arc::clone(dns))
+        .start()
+        .await
+        .unwrap();
+    SocketAddr::from(([127, 0, 0, 1], bound.port()))
+}
+
+async fn ask(server: SocketAddr, name: &str) -> Vec<IpAddr> {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client
+        .send_to(&Query::new(7, name, TYPE_A).to_bytes(), server)
+        .await
+        .unwrap();
+    let mut buf = [0; 4096];
+    let size = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    Response::parse(&buf[..size]).unwrap().addresses
+}
+
+#[tokio::test]
+async fn test_published_service_resolves_everywhere_from_one_directory() {
+    // The DNS-serving peer: its own directory, server and zone sync
+    let peer_dns = Vx0DNS::new().into_shared();
+    let peer_sync = ZoneSyncService::new(Arc::clone(&peer_dns))
+        .start("127.0.0.2:0".parse().unwrap())
+        .await
+        .unwrap();
+
+    let mut config = common::config(NodeTier::Edge);
+    config.network.dns.sync_port = peer_sync.port();
+    let peer_server = serve(&config.network.dns, &peer_dns).await;
+    let node = Arc::new(Vx0Node::new(config.clone()).unwrap());
+    let mut peer = PeerConnection::new(Uuid::new_v4(), 65101, peer_sync.ip());
+    peer.capabilities.serves_dns = true;
+    node.add_peer(peer).await.unwrap();
+
+    // This node's directory, handed to everything as start_daemon does
+    let mut dns = Vx0DNS::new();
+    dns.set_origin(node.node_id);
+    let dns = dns.into_shared();
+    let mut changes = dns.read().await.subscribe();
+    node.set_directory(Arc::clone(&dns));
+    let sync = ZoneSyncService::new(Arc::clone(&dns));
+    let primary = sync.start("127.0.0.1:0".parse().unwrap()).await.unwrap();
+    let secondaries = Arc::clone(&node);
+    sync.follow_changes(primary, move || {
+        let node = Arc::clone(&secondaries);
+        async move { node.dns_secondaries().await }
+    })
+    .await;
+    let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns));
+    let server = serve(&config.network.dns, &dns).await;
+    assert!(ask(server, "wiki.vx0").await.is_empty());
+
+    registry
+        .publish(HostedService {
+            service_id: Uuid::new_v4(),
+            name: "wiki".to_string(),
+            service_type: ServiceType::WebServer,
+            domain: "wiki.vx0".to_string(),
+            port: 8080,
+            status: ServiceStatus::Running,
+            metadata: ServiceMetadata::default(),
+        })
+        .await
+        .unwrap();
+
+    // The server answers from the directory the registry wrote to
+    let address = IpAddr::V4(node.public_ipv4());
+    assert_eq!(ask(server, "wiki.vx0").await, vec![address]);
+    let DnsChange { names, .. } = changes.recv().await.unwrap();
+    assert_eq!(names, ["wiki.vx0"]);
+
+    // So do the node's searches
+    let found = node.find_services(ServiceFilter::default()).await;
+    assert!(found.iter().any(|service| service.domain == "wiki.vx0"));
+
+    // And the change reached the peer on its own
+    wait_for("the peer to pull the service", || async {
+        peer_dns.read().await.get_records("wiki.vx0").is_some()
+    })
+    .await;
+    assert_eq!(ask(peer_server, "wiki.vx0").await, vec![address]);
+    assert_eq!(
+        peer_dns.read().await.serial("vx0"),
+        dns.read().await.serial("vx0")
+    );
+}
@@ -0,0 +1,143 @@
+//! Several services under one domain: web, chat and files on
+//! `community1.vx0`, each on its own port. Each is found through its own
+//! SRV record while all share the domain's address; dropping one leaves
+//! the others and the address in place, on this node and on a secondary,
+//! until the last one goes.
+
+#![cfg(feature = "dns-server")]
+
+mod common;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use uuid::Uuid;
+use vx0net_daemon::config::DNSConfig;
+use vx0net_daemon::network::dns::server::Vx0DNSServer;
+use vx0net_daemon::network::dns::sync::ZoneSyncService;
+use vx0net_daemon::network::dns::wire::{Query, Rcode, Response, TYPE_A, TYPE_SRV};
+use vx0net_daemon::network::dns::{SharedDns, SrvTarget, Vx0DNS};
+use vx0net_daemon::node::metadata::ServiceMetadata;
+use vx0net_daemon::node::services::ServiceRegistry;
+use vx0net_daemon::node::{
+    HostedService, NodeError, NodeTier, ServiceStatus, ServiceType, Vx0Node,
+};
+
+const DOMAIN: &str = "community1.vx0";
+
+async fn serve(config: &DNSConfig, dns: &SharedDns) -> SocketAddr {
+    let mut config = config.clone();
+    config.listen_port = 0;
+    config.vx0_dns_servers.clear();
+    let bound = Vx0DNSServer::from_config(&config, Arc::clone(dns))
+        .start()
+        .await
+        .unwrap();
+    SocketAddr::from(([127, 0, 0, 1], bound.port()))
+}
+
+async fn ask(server: SocketAddr, name: &str, qtype: u16) -> Response {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client
+        .send_to(&Query::new(7, name, qtype).to_bytes(), server)
+        .await
+        .unwrap();
+    let mut buf = [0; 4096];
+    let size = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    Response::parse(&buf[..size]).unwrap()
+}
+
+fn service(name: &str, service_type: ServiceType, port: u16) -> HostedService {
+    HostedService {
+        service_id: Uuid::new_v4(),
+        name: name.to_string(),
+        service_type,
+        domain: DOMAIN.to_string(),
+        port,
+        status: ServiceStatus::Running,
+        metadata: ServiceMetadata::default(),
+    }
+}
+
+/// The ports answered for a type's SRV name, in order
+async fn ports(server: SocketAddr, service_type: &ServiceType) -> Vec<u16> {
+    let response = ask(server, &service_type.srv_name(DOMAIN), TYPE_SRV).await;
+    response
+        .services
+        .iter()
+        .map(|target| {
+            assert_eq!(target.target, DOMAIN);
+            target.port
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_services_share_a_domain_until_the_last_goes() {
+    let config = common::config(NodeTier::Edge);
+    let node = Arc::new(Vx0Node::new(config.clone()).unwrap());
+    let dns = Vx0DNS::new().into_shared();
+    let server = serve(&config.network.dns, &dns).await;
+    let registry = ServiceRegistry::new(Arc::clone(&node), Arc::clone(&dns));
+    let primary = ZoneSyncService::new(Arc::clone(&dns))
+        .start("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let secondary_dns = Vx0DNS::new().into_shared();
+    let secondary = ZoneSyncService::new(Arc::clone(&secondary_dns));
+
+    let web = service("forum", ServiceType::WebServer, 443);
+    let chat = service("irc", ServiceType::ChatServer, 6667);
+    let files = service("share", ServiceType::FileServer, 8443);
+    for service in [&web, &chat, &files] {
+        registry.publish(service.clone()).await.unwrap();
+    }
+    let host = IpAddr::V4(node.public_ipv4());
+    // One address record, however many services share it
+    assert_eq!(ask(server, DOMAIN, TYPE_A).await.addresses, vec![host]);
+    assert_eq!(ports(server, &ServiceType::WebServer).await, vec![443]);
+    assert_eq!(ports(server, &ServiceType::ChatServer).await, vec![6667]);
+    assert_eq!(ports(server, &ServiceType::FileServer).await, vec![8443]);
+
+    // Only the same type on the same port collides
+    assert!(matches!(
+        registry
+            .publish(service("forum2", ServiceType::WebServer, 443))
+            .await,
+        Err(NodeError::ServiceExists { port: 443, .. })
+    ));
+    assert_eq!(node.services_at(DOMAIN).await.len(), 3);
+
+    // Dropping chat drops its SRV record alone
+    registry.unpublish(chat.service_id).await.unwrap();
+    let chat_reply = ask(server, &chat.srv_name(), TYPE_SRV).await;
+    assert_eq!(chat_reply.rcode, Some(Rcode::NxDomain));
+    assert_eq!(ports(server, &ServiceType::WebServer).await, vec![443]);
+    assert_eq!(ports(server, &ServiceType::FileServer).await, vec![8443]);
+    assert_eq!(ask(server, DOMAIN, TYPE_A).await.addresses, vec![host]);
+
+    // A secondary sees the same, record by record
+    secondary.pull_from(primary, "vx0").await.unwrap();
+    {
+        let secondary_dns = secondary_dns.read().await;
+        assert!(secondary_dns.lookup_srv(&chat.srv_name()).is_empty());
+        assert_eq!(
+            secondary_dns.lookup_srv(&files.srv_name()),
+            vec![SrvTarget::new(8443, DOMAIN)]
+        );
+        assert_eq!(secondary_dns.resolve_vx0_domain(DOMAIN).await, Some(host));
+    }
+
+    registry.unpublish(web.service_id).await.unwrap();
+    assert_eq!(ask(server, DOMAIN, TYPE_A).await.addresses, vec![host]);
+    registry.unpublish(files.service_id).await.unwrap();
+    assert!(dns.read().await.get_records(DOMAIN).is_none());
+    assert!(dns.read().await.lookup_srv(&files.srv_name()).is_empty());
+
+    secondary.pull_from(primary, "vx0").await.unwrap();
+    assert!(secondary_dns.read().await.get_records(DOMAIN).is_none());
+}
@@ -0,0 +1,117 @@
+//! Single-port mode: a BGP session and a TCP-encapsulated tunnel both reach
+//! the node through its one listening port, with IKE's UDP socket bound to
+//! the same port number.
+
+mod common;
+
+use common::route;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::network::ike::encap::{EncapEndpoint, EncapStream};
+use vx0net_daemon::network::ike::session::IKEDaemon;
+use vx0net_daemon::network::ike::tunnels::{throughput_counter, TunnelManager, TunnelTransport};
+use vx0net_daemon::node::capabilities::Capabilities;
+use vx0net_daemon::node::NodeTier;
+
+const SERVER_ASN: u32 = 65101;
+const CLIENT_ASN: u32 = 66001;
+const PSK: &[u8] = b"single-port-psk";
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_bgp_and_encapsulated_tunnel_share_one_port() {
+    let server_tunnels = Arc::new(TunnelManager::new());
+    let endpoint = Arc::new(EncapEndpoint::new(Arc::clone(&server_tunnels)));
+    let mut delivered = endpoint.subscribe();
+
+    let mut server = BGPDaemon::new(SERVER_ASN, "10.2.0.1".parse().unwrap(), 0);
+    server.set_capabilities(Capabilities {
+        single_port: true,
+        tcp_encapsulation: true,
+        ..Capabilities::default()
+    });
+    server.set_encapsulation(endpoint);
+    server.start().await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    let shared = SocketAddr::new(localhost(), port);
+
+    // Native IKE takes the same port number over UDP
+    let mut ike = IKEDaemon::new(SocketAddr::new("0.0.0.0".parse().unwrap(), port));
+    ike.start().await.unwrap();
+    assert_eq!(ike.local_addr().unwrap().port(), port);
+
+    // Routes over a BGP session on the shared port
+    let client = BGPProtocol::new(CLIENT_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Edge);
+    let (session, mut stream) = client.open_session(shared, SERVER_ASN).await.unwrap();
+    assert!(session.peer_capabilities.single_port);
+    assert!(session.peer_capabilities.tcp_encapsulation);
+    client
+        .advertise_routes(
+            &mut stream,
+            vec![route("10.60.1.0/24", "10.3.0.1", &[CLIENT_ASN])],
+        )
+        .await
+        .unwrap();
+    let destination: IpAddr = "10.60.1.1".parse().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.find_best_route(&destination).await.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("route never arrived over the shared port");
+
+    // Encrypted payloads over a tunnel stream on the same port
+    let client_tunnels = TunnelManager::new();
+    let client_tunnel = client_tunnels
+        .create_tunnel("10.3.0.1".parse().unwrap(), localhost(), shared, PSK)
+        .await
+        .unwrap();
+    client_tunnels
+        .set_transport(&client_tunnel, TunnelTransport::TcpEncap)
+        .await
+        .unwrap();
+    let server_tunnel = server_tunnels
+        .create_tunnel("10.2.0.1".parse().unwrap(), localhost(), shared, PSK)
+        .await
+        .unwrap();
+
+    let before = throughput_counter()
+        .with_label_values(&["tcp_encap", "in"])
+        .get();
+    let mut encap = EncapStream::connect(shared).await.unwrap();
+    for payload in [&b"first payload"[..], &b"second payload"[..]] {
+        for fragment in client_tunnels
+            .send_datagram(&client_tunnel, payload)
+            .await
+            .unwrap()
+        {
+            encap.send(&fragment).await.unwrap();
+        }
+
+        let received = tokio::time::timeout(Duration::from_secs(5), delivered.recv())
+            .await
+            .expect("payload never arrived over the tunnel stream")
+            .unwrap();
+        assert_eq!(received.tunnel_id, server_tunnel);
+        assert_eq!(received.payload, payload);
+    }
+
+    let tunnel = server_tunnels.get_tunnel(&server_tunnel).await.unwrap();
+    assert_eq!(tunnel.transport, TunnelTransport::TcpEncap);
+    assert_eq!(tunnel.traffic_stats.packets_in, 2);
+    // Counted apart from UDP tunnels; other tests may run concurrently
+    assert!(
+        throughput_counter()
+            .with_label_values(&["tcp_encap", "in"])
+            .get()
+            >= before + tunnel.traffic_stats.bytes_in
+    );
+}
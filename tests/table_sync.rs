@@ -0,0 +1,151 @@
+//! A full table sent to a new Backbone peer in chunks: a connection cut
+//! halfway leaves the routes received so far in use but partial, and the
+//! next session resumes with only the remaining chunks, ending with the
+//! peer holding exactly the table our export policy lets it have.
+
+mod common;
+
+use common::wait_for;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use vx0net_daemon::config::TableSyncConfig;
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::routing::RoutingPolicy;
+use vx0net_daemon::network::bgp::table_sync::{SyncProgress, TableSyncs};
+use vx0net_daemon::network::bgp::{wire, BGPDaemon, Prefix, RouteEntry, RouteTable};
+use vx0net_daemon::node::NodeTier;
+use vx0net_daemon::storage::Store;
+
+const SERVER_ASN: u32 = 65002;
+const CLIENT_ASN: u32 = 65001;
+const ROUTES: usize = 4000;
+const MAX: usize = wire::BGP_MAX_EXTENDED_MESSAGE_LEN;
+
+fn route(index: usize) -> RouteEntry {
+    // Every tenth route came through the server; it is not sent back
+    let path: &[u32] = if index.is_multiple_of(10) {
+        &[CLIENT_ASN, SERVER_ASN]
+    } else {
+        &[CLIENT_ASN]
+    };
+    common::route(
+        &format!("10.{}.{}.0/24", 64 + index / 256, index % 256),
+        "10.3.0.1",
+        path,
+    )
+}
+
+/// Relays one connection to `server`, cutting it once `updates` UPDATEs
+/// from the client went through
+async fn cutting_proxy(server: SocketAddr, updates: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (client, _) = listener.accept().await.unwrap();
+        let upstream = TcpStream::connect(server).await.unwrap();
+        let (mut from_client, mut to_client) = client.into_split();
+        let (mut from_server, mut to_server) = upstream.into_split();
+        let back = tokio::spawn(async move {
+            let _ = tokio::io::copy(&mut from_server, &mut to_client).await;
+        });
+        let mut forwarded = 0;
+        while forwarded < updates {
+            let Ok(Some((msg_type, body))) = wire::read_frame(&mut from_client, MAX, None).await
+            else {
+                break;
+            };
+            wire::write_frame(&mut to_server, msg_type, &body, MAX, None)
+                .await
+                .unwrap();
+            if msg_type == wire::BGP_MSG_UPDATE {
+                forwarded += 1;
+            }
+        }
+        back.abort();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_interrupted_sync_resumes_with_the_remainder() {
+    let root = std::env::temp_dir().join(format!("vx0-table-sync-{}", uuid::Uuid::new_v4()));
+    let store = Store::open(&root).unwrap();
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    let mut server = BGPDaemon::new(SERVER_ASN, localhost, 0);
+    server
+        .open_table_syncs(TableSyncConfig::default(), &store)
+        .unwrap();
+    server.start().await.unwrap();
+    let server_addr = SocketAddr::new(localhost, server.local_addr().unwrap().port());
+
+    let config = TableSyncConfig {
+        chunk_routes: 400,
+        window: 2,
+    };
+    let client = BGPProtocol::new(CLIENT_ASN, localhost, NodeTier::Backbone)
+        .with_table_syncs(Arc::new(TableSyncs::open(config, None).unwrap()));
+    let policy = RoutingPolicy::new(CLIENT_ASN, NodeTier::Backbone);
+    let mut table = RouteTable::new();
+    for index in 0..ROUTES {
+        table.add_route(route(index)).unwrap();
+    }
+    let exported: HashSet<Prefix> = (0..ROUTES)
+        .filter(|index| !index.is_multiple_of(10))
+        .map(|index| route(index).network)
+        .collect();
+    // 3600 routes in 9 chunks
+
+    // The connection drops after four chunks
+    let proxy = cutting_proxy(server_addr, 4).await;
+    let (session, mut stream) = client.open_session(proxy, SERVER_ASN).await.unwrap();
+    assert_eq!(session.table_sync_resume, None);
+    assert!(client
+        .advertise_table_in_chunks(&mut stream, &table, &policy, SERVER_ASN, None)
+        .await
+        .is_err());
+    let partial = SyncProgress {
+        received: 4,
+        total: 9,
+        complete: false,
+    };
+    wait_for("the first chunks to be applied", || async {
+        server.table_sync_progress(CLIENT_ASN) == Some(partial)
+    })
+    .await;
+    // What arrived is already in use
+    assert_eq!(server.routes_from(CLIENT_ASN).await, 1600);
+
+    // Reconnecting picks up at the fifth chunk
+    let (session, mut stream) = client.open_session(server_addr, SERVER_ASN).await.unwrap();
+    let resume = session
+        .table_sync_resume
+        .expect("the server resumes the sync");
+    assert_eq!(
+        (resume.table_version, resume.next_chunk),
+        (table.version, 4)
+    );
+    let sent = client
+        .advertise_table_in_chunks(&mut stream, &table, &policy, SERVER_ASN, Some(resume))
+        .await
+        .unwrap();
+    assert_eq!(sent, 5);
+
+    wait_for("the sync to complete", || async {
+        server
+            .table_sync_progress(CLIENT_ASN)
+            .is_some_and(|progress| progress.complete)
+    })
+    .await;
+    let received: HashSet<Prefix> = server
+        .get_received_routes(CLIENT_ASN)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|route| route.network)
+        .collect();
+    assert_eq!(received, exported);
+    let _ = std::fs::remove_dir_all(&root);
+}
@@ -0,0 +1,62 @@
+//! A listener must refuse OPENs from tiers it may not peer with before any
+//! session exists, and tell the initiator why.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPError};
+use vx0net_daemon::node::NodeTier;
+
+async fn listener(asn: u32) -> (BGPDaemon, SocketAddr) {
+    let daemon = BGPDaemon::new(asn, "10.0.0.1".parse().unwrap(), 0);
+    daemon.start().await.unwrap();
+    let addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        daemon.local_addr().unwrap().port(),
+    );
+    (daemon, addr)
+}
+
+fn initiator(asn: u32) -> BGPProtocol {
+    BGPProtocol::new(asn, "10.0.0.2".parse().unwrap(), NodeTier::from_asn(asn))
+}
+
+async fn assert_refused(listener_asn: u32, initiator_asn: u32) {
+    let (daemon, addr) = listener(listener_asn).await;
+    let err = initiator(initiator_asn)
+        .connect_to_peer(addr, listener_asn)
+        .await
+        .unwrap_err();
+    match err {
+        BGPError::TierRefused { ours, theirs, .. } => {
+            assert_eq!(ours, NodeTier::from_asn(initiator_asn));
+            assert_eq!(theirs, NodeTier::from_asn(listener_asn));
+        }
+        other => panic!("expected a tier refusal, got {other:?}"),
+    }
+    assert!(daemon.session_peers().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_edge_to_edge_is_refused() {
+    assert_refused(66001, 66002).await;
+}
+
+#[tokio::test]
+async fn test_edge_to_backbone_is_refused() {
+    assert_refused(65001, 66002).await;
+}
+
+#[tokio::test]
+async fn test_regional_to_backbone_is_accepted() {
+    let (daemon, addr) = listener(65001).await;
+    // The session lasts as long as its connection
+    let (_session, _stream) = initiator(65101).open_session(addr, 65001).await.unwrap();
+    for _ in 0..100 {
+        if !daemon.session_peers().await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(daemon.session_peers().await.len(), 1);
+}
@@ -0,0 +1,208 @@
+//! The reference hierarchy generated from `config/topologies/hierarchical.toml`
+//! matches the one `hierarchical_test` used to build by hand: the same
+//! identities, addresses, peerings and services once booted in-process, and
+//! configs that load as the daemon would load them.
+
+use config::FileFormat;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use vx0net_daemon::config::topology::{Manifest, Topology, TopologyError, PORT_MAP_FILE};
+use vx0net_daemon::config::{profiles, Vx0Config};
+use vx0net_daemon::node::{HostedService, PeerConnection, ServiceStatus, ServiceType};
+use vx0net_daemon::Vx0Node;
+
+const MANIFEST: &str = include_str!("../config/topologies/hierarchical.toml");
+
+/// What a node converged to: identity, addresses, peers and services
+#[derive(Debug, PartialEq, Eq)]
+struct Converged {
+    asn: u32,
+    tier: String,
+    ipv4: IpAddr,
+    ipv6: IpAddr,
+    peers: BTreeSet<(u32, IpAddr)>,
+    services: BTreeSet<String>,
+}
+
+async fn converged<'a>(
+    nodes: impl IntoIterator<Item = &'a Arc<Vx0Node>>,
+) -> BTreeMap<String, Converged> {
+    let mut state = BTreeMap::new();
+    for node in nodes {
+        let peers = node
+            .list_peers()
+            .await
+            .into_iter()
+            .map(|peer| (peer.peer_asn, peer.peer_addr))
+            .collect();
+        let services = node
+            .services
+            .read()
+            .await
+            .iter()
+            .map(|service| service.domain.clone())
+            .collect();
+        state.insert(
+            node.hostname.clone(),
+            Converged {
+                asn: node.asn,
+                tier: format!("{:?}", node.tier),
+                ipv4: node.ipv4_addr.into(),
+                ipv6: node.ipv6_addr.into(),
+                peers,
+                services,
+            },
+        );
+    }
+    state
+}
+
+/// The topology as `hierarchical_test` wrote it out node by node
+async fn hand_built() -> Vec<Arc<Vx0Node>> {
+    let nodes = [
+        ("backbone1.vx0", 65001, "10.0.1.1", "Backbone"),
+        ("backbone2.vx0", 65002, "10.0.1.2", "Backbone"),
+        ("regional1.vx0", 65101, "10.1.1.1", "Regional"),
+        ("regional2.vx0", 65102, "10.1.2.1", "Regional"),
+        ("edge1.vx0", 66001, "10.2.1.1", "Edge"),
+        ("edge2.vx0", 66002, "10.2.1.2", "Edge"),
+        ("edge3.vx0", 66003, "10.2.2.1", "Edge"),
+    ];
+    let mut built = Vec::new();
+    for (hostname, asn, ip, tier) in nodes {
+        let mut config: Vx0Config = profiles::named(tier).unwrap();
+        let octets: Vec<&str> = ip.split('.').collect();
+        config.node.hostname = hostname.to_string();
+        config.node.asn = asn;
+        config.node.tier = tier.to_string();
+        config.node.ipv4_address = ip.to_string();
+        config.node.ipv6_address = format!("fd00:7830:{}:{}::{}", octets[1], octets[2], octets[3]);
+        config.network.bgp.router_id = ip.to_string();
+        let node = Arc::new(Vx0Node::new(config).unwrap());
+        node.start().await.unwrap();
+        built.push(node);
+    }
+
+    let peerings = [(0, 1), (0, 2), (1, 3), (2, 3), (2, 4), (2, 5), (3, 6)];
+    for (a, b) in peerings {
+        for (from, to) in [(a, b), (b, a)] {
+            let peer = &built[to];
+            built[from]
+                .add_peer(PeerConnection::new(
+                    peer.node_id,
+                    peer.asn,
+                    peer.ipv4_addr.into(),
+                ))
+                .await
+                .unwrap();
+        }
+    }
+
+    let services = [
+        (
+            4,
+            "chat",
+            ServiceType::ChatServer,
+            "chat.community1.vx0",
+            6667,
+        ),
+        (
+            5,
+            "forum",
+            ServiceType::WebServer,
+            "forum.community1.vx0",
+            80,
+        ),
+        (
+            6,
+            "files",
+            ServiceType::FileServer,
+            "files.community2.vx0",
+            443,
+        ),
+    ];
+    for (node, name, service_type, domain, port) in services {
+        built[node]
+            .register_service(HostedService {
+                service_id: uuid::Uuid::new_v4(),
+                name: name.to_string(),
+                service_type,
+                domain: domain.to_string(),
+                port,
+                status: ServiceStatus::Running,
+                metadata: Default::default(),
+            })
+            .await
+            .unwrap();
+    }
+    built
+}
+
+#[tokio::test]
+async fn manifest_topology_converges_like_the_hand_built_one() {
+    let topology = Topology::resolve(Manifest::parse(MANIFEST, FileFormat::Toml).unwrap()).unwrap();
+    let lab = topology.boot().await.unwrap();
+    let from_manifest = converged(lab.nodes().map(|(_, node)| node)).await;
+    let by_hand = converged(&hand_built().await).await;
+
+    assert_eq!(from_manifest.len(), 7);
+    assert_eq!(from_manifest, by_hand);
+    let edge1 = lab.node("edge1").unwrap();
+    let chat = edge1.services.read().await[0].clone();
+    assert_eq!(chat.service_type, ServiceType::ChatServer);
+    lab.stop().await;
+}
+
+#[test]
+fn generated_configs_load_and_ports_do_not_collide() {
+    let topology = Topology::resolve(Manifest::parse(MANIFEST, FileFormat::Toml).unwrap()).unwrap();
+    let dir = std::env::temp_dir().join(format!("vx0net-topology-{}", uuid::Uuid::new_v4()));
+    let written = topology.generate(&dir).unwrap();
+    assert_eq!(written.len(), 8);
+
+    for node in topology.nodes() {
+        let path = dir.join(format!("{}.toml", node.name));
+        let config = Vx0Config::load_file(&path.to_string_lossy()).unwrap();
+        assert_eq!(config.node.asn, node.asn);
+        assert_eq!(config.psk.unwrap().default, "vx0-hierarchical-lab");
+        for peer in &config.network.bgp.peers {
+            let peer_node = topology
+                .nodes()
+                .iter()
+                .find(|other| other.asn == peer.asn)
+                .unwrap();
+            assert_eq!(peer.address, peer_node.ipv4_address.to_string());
+        }
+    }
+
+    let ports = topology.port_map().unwrap();
+    let host_ports: BTreeSet<u16> = ports.iter().map(|mapping| mapping.host_port).collect();
+    assert_eq!(host_ports.len(), ports.len());
+    let compose = std::fs::read_to_string(dir.join(PORT_MAP_FILE)).unwrap();
+    assert!(compose.contains("  edge3:\n"));
+    assert!(compose.contains("ipv4_address: 10.2.2.1"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn yaml_manifests_are_read_too() {
+    let dir = std::env::temp_dir().join(format!("vx0net-topology-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("lab.yaml");
+    std::fs::write(
+        &path,
+        "psk: lab\n\
+         peerings:\n  - [r1, e1]\n  - [e1, e2]\n\
+         nodes:\n  - name: r1\n    tier: Regional\n  - name: e1\n    tier: Edge\n\
+         \x20 - name: e2\n    tier: Edge\n",
+    )
+    .unwrap();
+    let manifest = Manifest::load(&path).unwrap();
+    assert_eq!(manifest.nodes.len(), 3);
+    assert!(matches!(
+        Topology::resolve(manifest),
+        Err(TopologyError::TierViolation { .. })
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,221 @@
+//! The shared transport: BGP sessions framing their messages with the RFC
+//! 4271 header beside it, a second protocol reusing a pooled connection,
+//! stalled peers timing out, and the pool shutting down cleanly.
+
+mod common;
+
+use common::route;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use vx0net_daemon::network::bgp::messages::BGP_ERROR_MESSAGE_HEADER;
+use vx0net_daemon::network::bgp::protocol::{BGPMessage, BGPMessageType, BGPProtocol};
+use vx0net_daemon::network::bgp::rib::Rib;
+use vx0net_daemon::network::bgp::routing::RoutingPolicy;
+use vx0net_daemon::network::bgp::{wire, BGPError};
+use vx0net_daemon::network::transport::pool::TransportPool;
+use vx0net_daemon::network::transport::{self, Codec, MessageTag, TransportConfig, TransportError};
+use vx0net_daemon::node::NodeTier;
+
+async fn listener() -> (TcpListener, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+}
+
+/// One message as a peer framing by hand reads it: the RFC 4271 header,
+/// checked byte by byte, and the JSON body it covers
+async fn read_message(stream: &mut TcpStream) -> (u8, BGPMessage) {
+    let mut header = [0u8; wire::BGP_HEADER_LEN];
+    stream.read_exact(&mut header).await.unwrap();
+    assert_eq!(header[..16], wire::BGP_MARKER);
+    let length = u16::from_be_bytes([header[16], header[17]]) as usize;
+    let mut body = vec![0u8; length - wire::BGP_HEADER_LEN];
+    stream.read_exact(&mut body).await.unwrap();
+    (header[18], serde_json::from_slice(&body).unwrap())
+}
+
+async fn write_message(stream: &mut TcpStream, marker: [u8; 16], msg: &BGPMessage) {
+    let body = serde_json::to_vec(msg).unwrap();
+    let mut frame = marker.to_vec();
+    frame.extend_from_slice(&((wire::BGP_HEADER_LEN + body.len()) as u16).to_be_bytes());
+    frame.push(msg.message_type.code());
+    frame.extend_from_slice(&body);
+    stream.write_all(&frame).await.unwrap();
+}
+
+fn client() -> BGPProtocol {
+    BGPProtocol::new(66001, "10.0.0.2".parse().unwrap(), NodeTier::Edge)
+}
+
+#[tokio::test]
+async fn test_bgp_sessions_frame_with_the_rfc_4271_header() {
+    let (listener, addr) = listener().await;
+    let peer = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (msg_type, mut open) = read_message(&mut stream).await;
+        assert_eq!(msg_type, wire::BGP_MSG_OPEN);
+        assert_eq!(open.message_type, BGPMessageType::Open);
+        assert_eq!(open.asn, 66001);
+        assert!(open.binary_messages);
+
+        // Answered as a node that predates binary messages, so the session
+        // stays JSON
+        open.asn = 65101;
+        open.binary_messages = false;
+        write_message(&mut stream, wire::BGP_MARKER, &open).await;
+
+        let (msg_type, update) = read_message(&mut stream).await;
+        assert_eq!(msg_type, wire::BGP_MSG_UPDATE);
+        assert_eq!(update.routes.len(), 1);
+        assert_eq!(update.routes[0].network, "10.60.1.0/24".parse().unwrap());
+
+        let mut keepalive = open.clone();
+        keepalive.message_type = BGPMessageType::Keepalive;
+        write_message(&mut stream, wire::BGP_MARKER, &keepalive).await;
+        let mut goodbye = open;
+        goodbye.message_type = BGPMessageType::Notification;
+        write_message(&mut stream, wire::BGP_MARKER, &goodbye).await;
+    });
+
+    let client = client();
+    let (session, mut stream) = client.open_session(addr, 65101).await.unwrap();
+    assert_eq!(session.peer_asn, 65101);
+    client
+        .advertise_routes(
+            &mut stream,
+            vec![route("10.60.1.0/24", "10.0.0.2", &[66001])],
+        )
+        .await
+        .unwrap();
+    // The KEEPALIVE is taken in stride and the NOTIFICATION ends the session
+    let rib = tokio::sync::RwLock::new(Rib::new(RoutingPolicy::new(66001, NodeTier::Edge)));
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        client.receive_updates(&mut stream, 65101, &rib),
+    )
+    .await
+    .expect("the session outlived the NOTIFICATION")
+    .unwrap();
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_bgp_sessions_refuse_a_bad_marker() {
+    let (listener, addr) = listener().await;
+    let peer = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (_, mut open) = read_message(&mut stream).await;
+        open.asn = 65101;
+        let mut marker = wire::BGP_MARKER;
+        marker[3] = 0;
+        write_message(&mut stream, marker, &open).await;
+    });
+
+    let refused = client().connect_to_peer(addr, 65101).await;
+    assert!(matches!(
+        refused,
+        Err(BGPError::Protocol {
+            code: BGP_ERROR_MESSAGE_HEADER,
+            subcode: wire::HEADER_CONNECTION_NOT_SYNCHRONIZED,
+            ..
+        })
+    ));
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_second_protocol_reuses_the_connection() {
+    let (listener, addr) = listener().await;
+    let config = TransportConfig::default().with_keepalive(Some(Duration::from_millis(10)));
+    let client = TransportPool::new(config);
+    let server = TransportPool::new(config);
+
+    let mut bgp = client.channel(addr, MessageTag::Bgp).await.unwrap();
+    let (stream, from) = listener.accept().await.unwrap();
+    let mut incoming = server.adopt(stream, from);
+    bgp.send(b"open").await.unwrap();
+    let mut their_bgp = incoming.recv().await.unwrap();
+    assert_eq!(their_bgp.recv().await.unwrap(), b"open");
+
+    // Keepalives flow meanwhile and are never delivered
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut join = client.channel(addr, MessageTag::Join).await.unwrap();
+    join.send_json(&"hello").await.unwrap();
+    let mut their_join = incoming.recv().await.unwrap();
+    assert_eq!(their_join.tag(), MessageTag::Join);
+    assert_eq!(
+        their_join.recv_json::<String>().await.unwrap().unwrap(),
+        "hello"
+    );
+
+    their_bgp.send(b"update").await.unwrap();
+    their_join.send(b"welcome").await.unwrap();
+    assert_eq!(join.recv().await.unwrap(), b"welcome");
+    assert_eq!(bgp.recv().await.unwrap(), b"update");
+
+    assert_eq!(client.connections().await, 1);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), listener.accept())
+            .await
+            .is_err(),
+        "the second protocol opened its own connection"
+    );
+}
+
+#[tokio::test]
+async fn test_stalled_peer_times_out() {
+    let (listener, addr) = listener().await;
+    let stalling = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // Half a message, then nothing
+        stream.write_all(&wire::BGP_MARKER).await.unwrap();
+        stream.write_u16(100).await.unwrap();
+        stream.write_all(&[wire::BGP_MSG_UPDATE; 10]).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    });
+
+    let config = TransportConfig::default().with_io_timeout(Some(Duration::from_millis(100)));
+    let mut stream = transport::connect(addr, Codec::Bgp, MessageTag::Bgp, config)
+        .await
+        .unwrap();
+    let started = std::time::Instant::now();
+    assert!(matches!(
+        stream.recv().await,
+        Err(TransportError::Timeout { .. })
+    ));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    stalling.abort();
+}
+
+#[tokio::test]
+async fn test_pool_shuts_down_gracefully() {
+    let (listener, addr) = listener().await;
+    let client = TransportPool::default();
+    let server = TransportPool::default();
+
+    let mut bgp = client.channel(addr, MessageTag::Bgp).await.unwrap();
+    let (stream, from) = listener.accept().await.unwrap();
+    let mut incoming = server.adopt(stream, from);
+    bgp.send(b"last words").await.unwrap();
+
+    client.shutdown().await;
+    // What was written before the shutdown still arrives
+    let mut theirs = incoming.recv().await.unwrap();
+    assert_eq!(theirs.recv().await.unwrap(), b"last words");
+    assert_eq!(theirs.recv().await, None);
+
+    assert_eq!(bgp.recv().await, None);
+    assert!(matches!(
+        bgp.send(b"too late").await,
+        Err(TransportError::Closed { .. })
+    ));
+    assert!(matches!(
+        client.channel(addr, MessageTag::Bgp).await,
+        Err(TransportError::Closed { .. })
+    ));
+    assert_eq!(client.connections().await, 0);
+}
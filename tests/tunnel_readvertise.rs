@@ -0,0 +1,135 @@
+//! Routes must follow tunnel state: when the tunnel to the preferred next
+//! hop fails, the middle of a three-node chain re-advertises the alternative
+//! within its pacing interval, and goes back once the tunnel returns.
+
+mod common;
+
+use common::route;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use vx0net_daemon::network::bgp::pacing::{self, AdvertisementPacer, PacerOutput};
+use vx0net_daemon::network::bgp::rib::RibChange;
+use vx0net_daemon::network::bgp::BGPDaemon;
+use vx0net_daemon::network::ike::tunnels::{TunnelId, TunnelManager};
+
+const MIDDLE_ASN: u32 = 65101;
+const DOWNSTREAM_ASN: u32 = 65102;
+const PREFIX: &str = "10.50.0.0/16";
+
+async fn tunnel(manager: &TunnelManager, remote: &str) -> TunnelId {
+    let remote: IpAddr = remote.parse().unwrap();
+    manager
+        .create_tunnel(
+            "10.2.0.1".parse().unwrap(),
+            remote,
+            (remote, 4500).into(),
+            b"test-psk",
+        )
+        .await
+        .unwrap()
+}
+
+/// Carries the middle node's Loc-RIB changes to the downstream node the way
+/// an outbound session would: through the pacer, with our ASN prepended
+struct Relay {
+    changes: broadcast::Receiver<RibChange>,
+    pacer: AdvertisementPacer,
+    downstream: BGPDaemon,
+}
+
+impl Relay {
+    async fn pump(&mut self) {
+        while let Ok(change) = self.changes.try_recv() {
+            match change {
+                RibChange::Advertise(route) => self.pacer.announce(route),
+                RibChange::Withdraw(network) => self.pacer.withdraw(network),
+            }
+        }
+        match self.pacer.poll(Instant::now()) {
+            Some(PacerOutput::Update(batch)) => {
+                let announced = batch
+                    .announced
+                    .into_iter()
+                    .map(|mut route| {
+                        route.as_path = route.as_path.prepended(MIDDLE_ASN, 1);
+                        route.next_hop = "10.2.0.1".parse().unwrap();
+                        route
+                    })
+                    .collect();
+                self.downstream
+                    .receive_update(MIDDLE_ASN, announced, &batch.withdrawn)
+                    .await
+                    .unwrap();
+            }
+            Some(PacerOutput::Resync) => panic!("queue overflowed"),
+            None => {}
+        }
+    }
+
+    /// Pump until the downstream path is `expected`, returning how long
+    /// that took
+    async fn until_path(&mut self, expected: &[u32]) -> Duration {
+        let start = Instant::now();
+        let destination: IpAddr = "10.50.0.5".parse().unwrap();
+        while start.elapsed() < Duration::from_secs(10) {
+            self.pump().await;
+            let path = self
+                .downstream
+                .find_best_route(&destination)
+                .await
+                .map(|route| route.as_path);
+            if path.as_ref().is_some_and(|path| *path == *expected) {
+                return start.elapsed();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("downstream never saw path {:?}", expected);
+    }
+}
+
+#[tokio::test]
+async fn test_tunnel_failure_switches_downstream_path_and_recovers() {
+    let middle = Arc::new(BGPDaemon::new(MIDDLE_ASN, "10.2.0.1".parse().unwrap(), 0));
+    let tunnels = TunnelManager::new();
+    let preferred = tunnel(&tunnels, "10.1.0.1").await;
+    tunnel(&tunnels, "10.1.0.2").await;
+    let (current, events) = tunnels.follow_status().await;
+    middle.follow_tunnel_events(current, events).await.unwrap();
+
+    let (_, changes) = middle.follow_routes().await;
+    let interval = pacing::default_mrai(DOWNSTREAM_ASN);
+    let mut relay = Relay {
+        changes,
+        pacer: AdvertisementPacer::new(interval, pacing::DEFAULT_MAX_QUEUE),
+        downstream: BGPDaemon::new(DOWNSTREAM_ASN, "10.3.0.1".parse().unwrap(), 0),
+    };
+
+    // Shorter path via 10.1.0.1, alternative via 10.1.0.2
+    middle
+        .receive_update(65001, vec![route(PREFIX, "10.1.0.1", &[65001])], &[])
+        .await
+        .unwrap();
+    middle
+        .receive_update(65002, vec![route(PREFIX, "10.1.0.2", &[65002, 65003])], &[])
+        .await
+        .unwrap();
+    relay.until_path(&[MIDDLE_ASN, 65001]).await;
+
+    tunnels.mark_failed(&preferred).await.unwrap();
+    let switched = relay.until_path(&[MIDDLE_ASN, 65002, 65003]).await;
+    assert!(
+        switched <= interval + Duration::from_millis(500),
+        "{switched:?}"
+    );
+
+    tunnels.cleanup_failed_tunnels().await;
+    tunnel(&tunnels, "10.1.0.1").await;
+    let recovered = relay.until_path(&[MIDDLE_ASN, 65001]).await;
+    assert!(
+        recovered <= interval + Duration::from_millis(500),
+        "{recovered:?}"
+    );
+}
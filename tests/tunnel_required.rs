@@ -0,0 +1,179 @@
+//! With `require_tunnel`, a BGP session waits after OPEN until a tunnel to
+//! the peer is established. With `bgp_over_tunnel` as well, routes travel
+//! sealed by that tunnel, and a tunnel failure suspends the session rather
+//! than dropping it.
+
+mod common;
+
+use common::{route, wait_for};
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use vx0net_daemon::network::bgp::protocol::BGPProtocol;
+use vx0net_daemon::network::bgp::tunnel_gate::TunnelGate;
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPSessionState};
+use vx0net_daemon::network::ike::tunnels::{TunnelId, TunnelManager};
+use vx0net_daemon::node::capabilities::Capabilities;
+use vx0net_daemon::node::NodeTier;
+
+const SERVER_ASN: u32 = 65101;
+const CLIENT_ASN: u32 = 66001;
+const PSK: &[u8] = b"tunnel-required-psk";
+
+fn requires_tunnel() -> Capabilities {
+    Capabilities {
+        requires_tunnel: true,
+        bgp_over_tunnel: true,
+        ..Capabilities::default()
+    }
+}
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+async fn gated_server(tunnels: Arc<TunnelManager>, suspend: Duration) -> (BGPDaemon, SocketAddr) {
+    let mut server = BGPDaemon::new(SERVER_ASN, "10.2.0.1".parse().unwrap(), 0);
+    server.set_capabilities(requires_tunnel());
+    server.set_tunnel_gate(
+        TunnelGate::new(tunnels)
+            .with_bgp_over_tunnel(true)
+            .with_suspend_timeout(suspend),
+    );
+    server.start().await.unwrap();
+    let port = server.local_addr().unwrap().port();
+    (server, SocketAddr::new(localhost(), port))
+}
+
+async fn tunnel(tunnels: &TunnelManager) -> TunnelId {
+    let peer = SocketAddr::new(localhost(), 4500);
+    tunnels
+        .create_tunnel(localhost(), localhost(), peer, PSK)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_session_without_a_tunnel_never_establishes() {
+    let (server, addr) =
+        gated_server(Arc::new(TunnelManager::new()), Duration::from_secs(60)).await;
+
+    // A peer that does not wait learns from the OPEN why nothing happens
+    let eager = BGPProtocol::new(CLIENT_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Edge);
+    let (session, mut stream) = eager.open_session(addr, SERVER_ASN).await.unwrap();
+    assert!(session.peer_capabilities.requires_tunnel);
+    assert!(session
+        .peer_capabilities
+        .to_string()
+        .contains("tunnel-required"));
+    eager
+        .advertise_routes(
+            &mut stream,
+            vec![route("10.60.1.0/24", "10.3.0.1", &[CLIENT_ASN])],
+        )
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(
+        server.session_state(localhost()).await,
+        Some(BGPSessionState::OpenSent)
+    );
+    assert!(server
+        .find_best_route(&"10.60.1.1".parse().unwrap())
+        .await
+        .is_none());
+    assert_eq!(server.routes_from(CLIENT_ASN).await, 0);
+
+    // A peer that requires a tunnel too stays in OpenSent as well
+    let gated = BGPProtocol::new(CLIENT_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Edge)
+        .with_capabilities(requires_tunnel())
+        .with_tunnel_gate(Arc::new(TunnelGate::new(Arc::new(TunnelManager::new()))));
+    assert!(tokio::time::timeout(
+        Duration::from_millis(300),
+        gated.open_session(addr, SERVER_ASN)
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_routes_cross_the_tunnel_and_outlive_its_failure() {
+    let server_tunnels = Arc::new(TunnelManager::new());
+    let (server, addr) = gated_server(Arc::clone(&server_tunnels), Duration::from_secs(2)).await;
+    let client_tunnels = Arc::new(TunnelManager::new());
+    let client = BGPProtocol::new(CLIENT_ASN, "10.3.0.1".parse().unwrap(), NodeTier::Edge)
+        .with_capabilities(requires_tunnel())
+        .with_tunnel_gate(Arc::new(
+            TunnelGate::new(Arc::clone(&client_tunnels)).with_bgp_over_tunnel(true),
+        ));
+
+    // The session waits in OpenSent until both ends have the tunnel
+    let bring_up = async {
+        wait_for("the session to reach OpenSent", || async {
+            server.session_state(localhost()).await == Some(BGPSessionState::OpenSent)
+        })
+        .await;
+        tunnel(&client_tunnels).await;
+        tunnel(&server_tunnels).await
+    };
+    let (opened, mut server_tunnel) = tokio::join!(client.open_session(addr, SERVER_ASN), bring_up);
+    let (session, mut stream) = opened.unwrap();
+    assert!(session.is_established());
+    assert!(stream.is_tunneled());
+    wait_for("the session to establish", || async {
+        server.session_state(localhost()).await == Some(BGPSessionState::Established)
+    })
+    .await;
+
+    client
+        .advertise_routes(
+            &mut stream,
+            vec![route("10.60.1.0/24", "10.3.0.1", &[CLIENT_ASN])],
+        )
+        .await
+        .unwrap();
+    wait_for("the route to arrive", || async {
+        server
+            .find_best_route(&"10.60.1.1".parse().unwrap())
+            .await
+            .is_some()
+    })
+    .await;
+    let sealed = server_tunnels.get_tunnel(&server_tunnel).await.unwrap();
+    assert!(sealed.traffic_stats.packets_in >= 1);
+
+    // A failed tunnel suspends the session and its routes are held
+    server_tunnels.mark_failed(&server_tunnel).await.unwrap();
+    wait_for("the session to be suspended", || async {
+        server.session_state(localhost()).await == Some(BGPSessionState::Suspended)
+    })
+    .await;
+    assert_eq!(server.routes_from(CLIENT_ASN).await, 1);
+
+    // A new tunnel resumes it, and routes flow through the new tunnel
+    server_tunnel = tunnel(&server_tunnels).await;
+    wait_for("the session to resume", || async {
+        server.session_state(localhost()).await == Some(BGPSessionState::Established)
+    })
+    .await;
+    client
+        .advertise_routes(
+            &mut stream,
+            vec![route("10.60.2.0/24", "10.3.0.1", &[CLIENT_ASN])],
+        )
+        .await
+        .unwrap();
+    wait_for("the route to arrive after recovery", || async {
+        server.routes_from(CLIENT_ASN).await == 2
+    })
+    .await;
+
+    // Down for longer than the suspend timeout, the routes are flushed
+    server_tunnels.mark_failed(&server_tunnel).await.unwrap();
+    wait_for("the routes to be flushed", || async {
+        server.routes_from(CLIENT_ASN).await == 0
+    })
+    .await;
+    assert_eq!(server.session_state(localhost()).await, None);
+}
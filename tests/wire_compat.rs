@@ -0,0 +1,236 @@
+//! Messages other nodes read must stay readable across releases.
+//!
+//! `tests/fixtures/wire/v<N>/` holds messages exactly as protocol version N
+//! writes them, one file per case, named `<type>.<case>.json`. Every
+//! version this build still speaks must deserialize into today's types, and
+//! the current version must re-serialize byte for byte. A field added
+//! without `#[serde(default)]`, a renamed field or a changed enum tagging
+//! fails here; if the break is intended, bump `PROTOCOL_VERSION_MAX` and
+//! add the new shape under the next version's directory.
+//!
+//! Cases named `<type>.<case>.unknown.json` carry fields from some later
+//! version. Tolerant types must read them and drop the extras; strict ones
+//! (`deny_unknown_fields`) must refuse them.
+//!
+//! Discovery messages are among the wire types, so the check runs in
+//! builds with discovery.
+
+#![cfg(feature = "discovery")]
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use vx0net_daemon::build_info::{PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN};
+use vx0net_daemon::network::bgp::{protocol, RouteEntry};
+use vx0net_daemon::network::dns::gateway::{GatewayAdvert, GatewayQuery, GatewayReply};
+use vx0net_daemon::network::dns::sync::ZoneMessage;
+use vx0net_daemon::network::dns::DNSRecord;
+use vx0net_daemon::node::capabilities::NodeDirectoryEntry;
+use vx0net_daemon::node::discovery::DiscoveryMessage;
+use vx0net_daemon::node::goodbye::GoodbyeMessage;
+use vx0net_daemon::node::joining::{JoinRequest, JoinResponse};
+use vx0net_daemon::node::search::ServiceSummary;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Unknown {
+    /// Fields from newer versions are ignored
+    Tolerated,
+    /// Fields from newer versions are an error
+    Refused,
+}
+
+struct WireType {
+    name: &'static str,
+    unknown: Unknown,
+    /// Deserialize, then serialize the way the daemon sends it
+    round_trip: fn(&str) -> Result<String, serde_json::Error>,
+}
+
+fn round_trip<T: Serialize + DeserializeOwned>(json: &str) -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(&serde_json::from_str::<T>(json)?).map(|json| json + "\n")
+}
+
+fn wire<T: Serialize + DeserializeOwned>(name: &'static str, unknown: Unknown) -> WireType {
+    WireType {
+        name,
+        unknown,
+        round_trip: round_trip::<T>,
+    }
+}
+
+/// Every type serialized with serde and read by another node. A fixture for
+/// a type missing here fails the suite, as does a type without fixtures.
+fn wire_types() -> Vec<WireType> {
+    use Unknown::*;
+    vec![
+        wire::<protocol::BGPMessage>("bgp_message", Tolerated),
+        wire::<JoinRequest>("join_request", Tolerated),
+        wire::<JoinResponse>("join_response", Tolerated),
+        wire::<DiscoveryMessage>("discovery_message", Tolerated),
+        wire::<GoodbyeMessage>("goodbye_message", Tolerated),
+        wire::<RouteEntry>("route_entry", Tolerated),
+        wire::<DNSRecord>("dns_record", Tolerated),
+        wire::<ZoneMessage>("zone_message", Tolerated),
+        wire::<NodeDirectoryEntry>("node_directory_entry", Tolerated),
+        wire::<ServiceSummary>("service_summary", Tolerated),
+        wire::<GatewayAdvert>("gateway_advert", Tolerated),
+        wire::<GatewayQuery>("gateway_query", Refused),
+        wire::<GatewayReply>("gateway_reply", Tolerated),
+    ]
+}
+
+struct Fixture {
+    path: PathBuf,
+    version: u16,
+    type_name: String,
+    unknown_fields: bool,
+    json: String,
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wire");
+    let mut fixtures = Vec::new();
+    for version in PROTOCOL_VERSION_MIN..=PROTOCOL_VERSION_MAX {
+        let dir = root.join(format!("v{version}"));
+        let entries = std::fs::read_dir(&dir).unwrap_or_else(|e| {
+            panic!(
+                "no fixtures for protocol v{version} in {}: {e}",
+                dir.display()
+            )
+        });
+        for entry in entries {
+            let path = entry.unwrap().path();
+            let file = path.file_name().unwrap().to_string_lossy().into_owned();
+            let Some(stem) = file.strip_suffix(".json") else {
+                continue;
+            };
+            let (stem, unknown_fields) = match stem.strip_suffix(".unknown") {
+                Some(stem) => (stem, true),
+                None => (stem, false),
+            };
+            let type_name = stem.split('.').next().unwrap().to_string();
+            let json = std::fs::read_to_string(&path).unwrap();
+            fixtures.push(Fixture {
+                path,
+                version,
+                type_name,
+                unknown_fields,
+                json,
+            });
+        }
+    }
+    fixtures.sort_by(|a, b| a.path.cmp(&b.path));
+    fixtures
+}
+
+fn bump_advice() -> String {
+    format!(
+        "This breaks mixed-version networks. If the change is intended, bump \
+         PROTOCOL_VERSION_MAX in src/build_info.rs and add fixtures under \
+         tests/fixtures/wire/v{}; otherwise give new fields #[serde(default)] \
+         (and skip_serializing_if for optional ones) and keep names and enum \
+         tagging as they were.",
+        PROTOCOL_VERSION_MAX + 1
+    )
+}
+
+/// Whether `written` kept every value `fixture` set for a field it knows;
+/// fields it added are defaults, fields it left out were unknown
+fn kept_known_fields(written: &Value, fixture: &Value) -> bool {
+    match (written, fixture) {
+        (Value::Object(written), Value::Object(fixture)) => written.iter().all(|(key, written)| {
+            fixture
+                .get(key)
+                .is_none_or(|fixture| kept_known_fields(written, fixture))
+        }),
+        (Value::Array(written), Value::Array(fixture)) => {
+            written.len() == fixture.len()
+                && written
+                    .iter()
+                    .zip(fixture)
+                    .all(|(written, fixture)| kept_known_fields(written, fixture))
+        }
+        _ => written == fixture,
+    }
+}
+
+#[test]
+fn fixtures_still_read_and_current_version_writes_them_unchanged() {
+    let types = wire_types();
+    let mut failures = Vec::new();
+    for fixture in fixtures() {
+        let path = fixture.path.display();
+        let Some(wire) = types.iter().find(|wire| wire.name == fixture.type_name) else {
+            failures.push(format!(
+                "{path}: no wire type {:?}; register it in wire_types()",
+                fixture.type_name
+            ));
+            continue;
+        };
+        let written = (wire.round_trip)(&fixture.json);
+        if fixture.unknown_fields {
+            match (wire.unknown, written) {
+                (Unknown::Tolerated, Err(e)) => failures.push(format!(
+                    "{path}: {} must ignore fields from newer versions: {e}",
+                    wire.name
+                )),
+                (Unknown::Tolerated, Ok(written)) => {
+                    let written: Value = serde_json::from_str(&written).unwrap();
+                    let fixture: Value = serde_json::from_str(&fixture.json).unwrap();
+                    if !kept_known_fields(&written, &fixture) {
+                        failures.push(format!(
+                            "{path}: {} changed known fields while dropping unknown ones:\n{written:#}",
+                            wire.name
+                        ));
+                    }
+                }
+                (Unknown::Refused, Ok(_)) => failures.push(format!(
+                    "{path}: {} is strict but accepted unknown fields",
+                    wire.name
+                )),
+                (Unknown::Refused, Err(_)) => {}
+            }
+            continue;
+        }
+        match written {
+            Err(e) => failures.push(format!(
+                "{path}: {} can no longer read protocol v{} messages: {e}. {}",
+                wire.name,
+                fixture.version,
+                bump_advice()
+            )),
+            Ok(written) if fixture.version == PROTOCOL_VERSION_MAX && written != fixture.json => {
+                failures.push(format!(
+                    "{path}: {} no longer writes protocol v{} messages as before. {}\nNow writes:\n{written}",
+                    wire.name,
+                    fixture.version,
+                    bump_advice()
+                ))
+            }
+            Ok(_) => {}
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+fn every_wire_type_has_current_fixtures() {
+    let fixtures = fixtures();
+    for wire in wire_types() {
+        let cases: Vec<&Fixture> = fixtures
+            .iter()
+            .filter(|f| f.type_name == wire.name && f.version == PROTOCOL_VERSION_MAX)
+            .collect();
+        assert!(
+            cases.iter().any(|f| !f.unknown_fields),
+            "{} has no fixture under tests/fixtures/wire/v{PROTOCOL_VERSION_MAX}",
+            wire.name
+        );
+        assert!(
+            cases.iter().any(|f| f.unknown_fields),
+            "{} has no .unknown.json fixture showing how it treats fields from newer versions",
+            wire.name
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! Withdrawals travel like announcements: a prefix the origin stops
+//! announcing leaves the middle of a three-node chain and, through it, the
+//! far end, over real sessions, while withdrawals of anything else change
+//! nothing.
+
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use vx0net_daemon::network::bgp::{BGPDaemon, BGPOrigin, Prefix};
+
+const ORIGIN_ASN: u32 = 65101;
+const MIDDLE_ASN: u32 = 65001;
+const FAR_ASN: u32 = 65002;
+const PREFIX: &str = "10.60.0.0/16";
+
+async fn listening(asn: u32, router_id: &str) -> (BGPDaemon, SocketAddr) {
+    let daemon = BGPDaemon::new(asn, router_id.parse().unwrap(), 0);
+    daemon.start().await.unwrap();
+    let addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        daemon.local_addr().unwrap().port(),
+    );
+    (daemon, addr)
+}
+
+/// Wait up to five seconds for `done` to hold
+async fn eventually<F: Future<Output = bool>>(mut done: impl FnMut() -> F) {
+    for _ in 0..500 {
+        if done().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("gave up waiting");
+}
+
+fn destination() -> IpAddr {
+    "10.60.0.5".parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_withdrawal_propagates_along_the_chain() {
+    let origin = BGPDaemon::new(ORIGIN_ASN, "10.1.0.1".parse().unwrap(), 0);
+    let (middle, middle_addr) = listening(MIDDLE_ASN, "10.2.0.1").await;
+    let (far, far_addr) = listening(FAR_ASN, "10.3.0.1").await;
+    let network: Prefix = PREFIX.parse().unwrap();
+
+    // Announced before the session, so it goes with the initial table
+    origin
+        .add_route(network, "10.1.0.1".parse().unwrap(), BGPOrigin::IGP)
+        .await
+        .unwrap();
+    origin.connect_peer(middle_addr, MIDDLE_ASN).await.unwrap();
+    middle.connect_peer(far_addr, FAR_ASN).await.unwrap();
+
+    eventually(|| async { far.find_best_route(&destination()).await.is_some() }).await;
+    let learned = far.find_best_route(&destination()).await.unwrap();
+    assert_eq!(learned.as_path.to_vec(), vec![MIDDLE_ASN, ORIGIN_ASN]);
+    assert_eq!(middle.routes_from(ORIGIN_ASN).await, 1);
+
+    let (middle_version, far_version) = (middle.rib_version().await, far.rib_version().await);
+    origin.withdraw_route(&network).await.unwrap();
+
+    eventually(|| async { far.find_best_route(&destination()).await.is_none() }).await;
+    assert!(middle.find_best_route(&destination()).await.is_none());
+    assert_eq!(middle.routes_from(ORIGIN_ASN).await, 0);
+    assert_eq!(far.routes_from(MIDDLE_ASN).await, 0);
+    assert!(middle.rib_version().await > middle_version);
+    assert!(far.rib_version().await > far_version);
+
+    // Announced again while the sessions are up, it follows the same way
+    origin
+        .add_route(network, "10.1.0.1".parse().unwrap(), BGPOrigin::IGP)
+        .await
+        .unwrap();
+    eventually(|| async { far.find_best_route(&destination()).await.is_some() }).await;
+}
+
+#[tokio::test]
+async fn test_withdrawals_of_unknown_or_local_prefixes_change_nothing() {
+    let middle = BGPDaemon::new(MIDDLE_ASN, "10.2.0.1".parse().unwrap(), 0);
+    let network: Prefix = PREFIX.parse().unwrap();
+    middle
+        .add_route(network, "10.2.0.1".parse().unwrap(), BGPOrigin::IGP)
+        .await
+        .unwrap();
+    let version = middle.rib_version().await;
+
+    // Never announced by this peer, and one we never heard of at all
+    let unknown: Prefix = "10.61.0.0/16".parse().unwrap();
+    middle
+        .receive_update(ORIGIN_ASN, vec![], &[network, unknown])
+        .await
+        .unwrap();
+
+    let route = middle.find_best_route(&destination()).await.unwrap();
+    assert_eq!(route.as_path.to_vec(), vec![MIDDLE_ASN]);
+    assert_eq!(middle.rib_version().await, version);
+}